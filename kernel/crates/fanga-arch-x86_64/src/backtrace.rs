@@ -0,0 +1,147 @@
+//! Stack Backtraces
+//!
+//! The kernel is built with frame pointers, so every function starts with
+//! `push rbp; mov rbp, rsp`. Each frame therefore holds the caller's frame
+//! pointer at `[rbp]` and the return address at `[rbp + 8]`, and the chain
+//! can be followed up to the entry point, where RBP is 0.
+//!
+//! Exception handlers pass the faulting RIP and frame pointer to `report`,
+//! which hands them to the reporter the kernel registered (it resolves
+//! symbols and prints to the console) or prints raw addresses to serial.
+
+use crate::serial_println;
+
+/// Maximum number of frames printed
+pub const MAX_FRAMES: usize = 32;
+
+/// Lowest kernel address; frames below it belong to user space
+const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// Furthest above the stack pointer `walk_stack` looks for frames
+pub const MAX_STACK_SPAN: u64 = 64 * 1024;
+
+/// Prints a backtrace starting at a RIP and frame pointer
+pub type Reporter = fn(u64, u64);
+
+static mut REPORTER: Option<Reporter> = None;
+
+/// Register the function that prints backtraces
+///
+/// # Safety
+/// Must be called during init, before exceptions can race with it.
+pub unsafe fn set_reporter(reporter: Reporter) {
+    REPORTER = Some(reporter);
+}
+
+/// Read this function's frame pointer
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Check that a frame at `rbp` lies in kernel memory
+fn is_kernel_frame(rbp: u64) -> bool {
+    (KERNEL_SPACE_START..=u64::MAX - 16).contains(&rbp)
+}
+
+/// Follow the frame pointer chain from `rbp`, storing return addresses
+///
+/// Stops at a null, misaligned or non-kernel frame pointer, or when the
+/// chain stops growing towards the stack base.
+///
+/// # Returns
+/// The number of return addresses stored in `frames`
+pub fn walk(rbp: u64, frames: &mut [u64]) -> usize {
+    walk_with(rbp, frames, is_kernel_frame)
+}
+
+/// Follow the frame pointer chain of code interrupted with stack pointer
+/// `rsp`, keeping to the stack above it
+///
+/// For code that may have been stopped anywhere, such as by an NMI: a
+/// frame pointer that code was not maintaining is never followed off its
+/// stack.
+pub fn walk_stack(rbp: u64, rsp: u64, frames: &mut [u64]) -> usize {
+    let end = rsp.saturating_add(MAX_STACK_SPAN);
+    walk_with(rbp, frames, |fp| is_kernel_frame(fp) && (rsp..end).contains(&fp))
+}
+
+fn walk_with(mut rbp: u64, frames: &mut [u64], valid: impl Fn(u64) -> bool) -> usize {
+    let mut count = 0;
+    while count < frames.len() && rbp != 0 && rbp.is_multiple_of(8) && valid(rbp) {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        frames[count] = ret;
+        count += 1;
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    count
+}
+
+/// Print a backtrace of code interrupted at `rip` with frame pointer `rbp`
+pub fn report(rip: u64, rbp: u64) {
+    if let Some(reporter) = unsafe { REPORTER } {
+        reporter(rip, rbp);
+        return;
+    }
+
+    let mut frames = [0u64; MAX_FRAMES];
+    let count = walk(rbp, &mut frames);
+    serial_println!("      Backtrace:");
+    serial_println!("        #0  0x{:016x}", rip);
+    for (i, addr) in frames[..count].iter().enumerate() {
+        serial_println!("        #{:<2} 0x{:016x}", i + 1, addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a fake stack of `[next rbp, return address]` pairs
+    fn fake_stack(stack: &mut [u64; 8], returns: &[u64]) -> u64 {
+        let base = stack.as_ptr() as u64;
+        for (i, &ret) in returns.iter().enumerate() {
+            let next = if i + 1 < returns.len() { base + (i as u64 + 1) * 16 } else { 0 };
+            stack[i * 2] = next;
+            stack[i * 2 + 1] = ret;
+        }
+        base
+    }
+
+    #[test]
+    fn test_walk_frame_chain() {
+        let mut stack = [0u64; 8];
+        let rbp = fake_stack(&mut stack, &[0x1000, 0x2000, 0x3000]);
+
+        let mut frames = [0u64; 8];
+        assert_eq!(walk_with(rbp, &mut frames, |_| true), 3);
+        assert_eq!(&frames[..3], &[0x1000, 0x2000, 0x3000]);
+
+        let mut short = [0u64; 2];
+        assert_eq!(walk_with(rbp, &mut short, |_| true), 2);
+    }
+
+    #[test]
+    fn test_walk_stops_on_bad_frames() {
+        let mut frames = [0u64; 4];
+        assert_eq!(walk(0, &mut frames), 0);
+        assert_eq!(walk(0x7FFF_0000, &mut frames), 0);
+        assert_eq!(walk_with(0x1003, &mut frames, |_| true), 0);
+
+        // A chain pointing back down the stack ends the walk
+        let mut stack = [0u64; 8];
+        let rbp = fake_stack(&mut stack, &[0x1000, 0x2000]);
+        stack[0] = rbp;
+        assert_eq!(walk_with(rbp, &mut frames, |_| true), 1);
+    }
+}
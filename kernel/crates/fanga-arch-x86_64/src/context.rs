@@ -10,6 +10,8 @@ use core::arch::asm;
 /// # Arguments
 /// * `old_context` - Pointer to save the old task's context
 /// * `new_context` - Pointer to load the new task's context
+/// * `old_on_cpu` - Flag cleared once the old context is saved, after
+///   which another CPU may load it
 ///
 /// # Safety
 /// This function is unsafe because it:
@@ -17,10 +19,10 @@ use core::arch::asm;
 /// - Changes the stack pointer
 /// - Assumes the contexts are valid and properly aligned
 #[unsafe(naked)]
-pub unsafe extern "C" fn switch_context(old_context: *mut TaskContext, new_context: *const TaskContext) {
+pub unsafe extern "C" fn switch_context(old_context: *mut TaskContext, new_context: *const TaskContext, old_on_cpu: *mut bool) {
     core::arch::naked_asm!(
         // Save old context (callee-saved registers + rip/rsp)
-        // rdi = old_context, rsi = new_context
+        // rdi = old_context, rsi = new_context, rdx = old_on_cpu
         
         // Save general purpose registers
         "mov [rdi + 0x00], rax",     // rax
@@ -58,6 +60,10 @@ pub unsafe extern "C" fn switch_context(old_context: *mut TaskContext, new_conte
         "mov ax, ss",
         "mov [rdi + 0x98], rax",     // ss
         
+        // The old context is saved: release it to other CPUs. Stores are
+        // not reordered, and the old stack is not touched again.
+        "mov byte ptr [rdx], 0",
+        
         // Load new context
         // Restore general purpose registers
         "mov rax, [rsi + 0x00]",     // rax
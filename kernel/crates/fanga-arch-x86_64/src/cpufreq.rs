@@ -0,0 +1,269 @@
+//! CPU Frequency Control
+//!
+//! Three hardware interfaces set how fast a core runs:
+//! - Intel HWP (hardware P-states, CPUID.06H:EAX[7]): the OS gives a
+//!   performance range and an energy/performance preference in
+//!   IA32_HWP_REQUEST, and the CPU picks the frequency itself
+//! - Intel Enhanced SpeedStep (CPUID.01H:ECX[7]): the OS writes a bus
+//!   ratio to IA32_PERF_CTL
+//! - AMD hardware P-states (CPUID.80000007H:EDX[7]): the OS writes a
+//!   P-state number to the P-state control MSR; each P-state's frequency
+//!   is defined by its own MSR
+//!
+//! Intel levels are bus ratios, multiples of `BUS_CLOCK_MHZ`; AMD levels
+//! are P-state numbers. The MSRs are per core: a write only affects the
+//! CPU that makes it.
+//!
+//! This module provides:
+//! - Interface detection
+//! - Discovery of the available frequencies
+//! - Level selection and the current level
+
+use crate::cpuid::{cpu_features, Feature, Vendor};
+
+/// Intel MSRs
+const MSR_PLATFORM_INFO: u32 = 0xCE;
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_PERF_CTL: u32 = 0x199;
+const IA32_PM_ENABLE: u32 = 0x770;
+const IA32_HWP_CAPABILITIES: u32 = 0x771;
+const IA32_HWP_REQUEST: u32 = 0x774;
+
+/// AMD MSRs
+const MSR_AMD_PSTATE_LIMIT: u32 = 0xC001_0061;
+const MSR_AMD_PSTATE_CTL: u32 = 0xC001_0062;
+const MSR_AMD_PSTATE_STATUS: u32 = 0xC001_0063;
+const MSR_AMD_PSTATE_DEF: u32 = 0xC001_0064;
+
+/// IA32_PERF_CTL/IA32_PERF_STATUS: target ratio in bits 15:8
+const PERF_RATIO_SHIFT: u64 = 8;
+const PERF_RATIO_MASK: u64 = 0xFF << PERF_RATIO_SHIFT;
+
+/// AMD P-state definition: the P-state may be used
+const AMD_PSTATE_ENABLED: u64 = 1 << 63;
+
+/// Clock the Intel bus ratios multiply
+pub const BUS_CLOCK_MHZ: u32 = 100;
+
+/// AMD CPUs define at most eight P-states
+pub const AMD_MAX_PSTATES: usize = 8;
+
+/// HWP energy/performance preference: performance first
+pub const EPP_PERFORMANCE: u8 = 0x00;
+/// HWP energy/performance preference: balanced
+pub const EPP_BALANCED: u8 = 0x80;
+/// HWP energy/performance preference: energy saving first
+pub const EPP_POWERSAVE: u8 = 0xFF;
+
+/// Frequency control interface of the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// Intel hardware-managed P-states
+    Hwp,
+    /// Intel Enhanced SpeedStep
+    Speedstep,
+    /// AMD hardware P-states
+    AmdPstate,
+}
+
+impl Interface {
+    /// Short name for status output
+    pub const fn name(self) -> &'static str {
+        match self {
+            Interface::Hwp => "Intel HWP",
+            Interface::Speedstep => "Intel SpeedStep",
+            Interface::AmdPstate => "AMD P-states",
+        }
+    }
+}
+
+/// Performance range reported by IA32_HWP_CAPABILITIES, in bus ratios
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwpCapabilities {
+    /// Highest performance, turbo included
+    pub highest: u8,
+    /// Performance that can be sustained
+    pub guaranteed: u8,
+    /// Performance with the best energy efficiency
+    pub most_efficient: u8,
+    pub lowest: u8,
+}
+
+impl HwpCapabilities {
+    /// Decode the MSR value
+    pub const fn from_msr(value: u64) -> Self {
+        Self {
+            highest: value as u8,
+            guaranteed: (value >> 8) as u8,
+            most_efficient: (value >> 16) as u8,
+            lowest: (value >> 24) as u8,
+        }
+    }
+}
+
+/// An IA32_HWP_REQUEST value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwpRequest {
+    pub min: u8,
+    pub max: u8,
+    /// Requested performance, 0 to let the CPU choose within the range
+    pub desired: u8,
+    /// Energy/performance preference (`EPP_*`)
+    pub epp: u8,
+}
+
+impl HwpRequest {
+    /// Encode the MSR value
+    pub const fn to_msr(&self) -> u64 {
+        self.min as u64 | (self.max as u64) << 8 | (self.desired as u64) << 16 | (self.epp as u64) << 24
+    }
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// The frequency control interface of this CPU, if it has one
+///
+/// HWP is preferred over SpeedStep when a CPU has both.
+pub fn detect() -> Option<Interface> {
+    let cpu = cpu_features();
+    match cpu.vendor() {
+        Vendor::Intel if cpu.has(Feature::Hwp) => Some(Interface::Hwp),
+        Vendor::Intel if cpu.has(Feature::Est) => Some(Interface::Speedstep),
+        Vendor::Amd if cpu.has(Feature::HwPstate) => Some(Interface::AmdPstate),
+        _ => None,
+    }
+}
+
+/// Lowest and highest non-turbo bus ratios, from MSR_PLATFORM_INFO
+pub fn platform_ratios() -> (u8, u8) {
+    let info = rdmsr(MSR_PLATFORM_INFO);
+    ((info >> 40) as u8, (info >> 8) as u8)
+}
+
+/// Current bus ratio, from IA32_PERF_STATUS
+pub fn current_ratio() -> u8 {
+    ((rdmsr(IA32_PERF_STATUS) & PERF_RATIO_MASK) >> PERF_RATIO_SHIFT) as u8
+}
+
+/// Request a bus ratio through IA32_PERF_CTL
+///
+/// # Safety
+/// The ratio must be one the CPU supports.
+pub unsafe fn set_ratio(ratio: u8) {
+    let ctl = rdmsr(IA32_PERF_CTL) & !PERF_RATIO_MASK;
+    wrmsr(IA32_PERF_CTL, ctl | (ratio as u64) << PERF_RATIO_SHIFT);
+}
+
+/// Performance range of this CPU under HWP
+pub fn hwp_capabilities() -> HwpCapabilities {
+    HwpCapabilities::from_msr(rdmsr(IA32_HWP_CAPABILITIES))
+}
+
+/// Hand P-state control to the hardware
+///
+/// # Safety
+/// The CPU must support HWP. Once enabled, HWP stays on until reset and
+/// IA32_PERF_CTL is ignored.
+pub unsafe fn enable_hwp() {
+    wrmsr(IA32_PM_ENABLE, 1);
+}
+
+/// Set this CPU's HWP performance range and preference
+///
+/// # Safety
+/// HWP must be enabled.
+pub unsafe fn set_hwp_request(request: HwpRequest) {
+    wrmsr(IA32_HWP_REQUEST, request.to_msr());
+}
+
+/// Frequency in MHz of an AMD P-state definition, None if disabled
+pub fn decode_amd_pstate(family: u32, def: u64) -> Option<u32> {
+    if def & AMD_PSTATE_ENABLED == 0 {
+        return None;
+    }
+    let mhz = match family {
+        // Zen 5: CpuFid[11:0] in 5 MHz steps
+        0x1A.. => (def & 0xFFF) * 5,
+        // Zen to Zen 4: CpuFid[7:0] * 200 MHz / CpuDfsId[13:8]
+        0x17..=0x19 => {
+            let dfs = (def >> 8) & 0x3F;
+            if dfs == 0 {
+                return None;
+            }
+            (def & 0xFF) * 200 / dfs
+        }
+        // Families 10h-16h: 100 MHz * (CpuFid[5:0] + 10h) / 2^CpuDid[8:6]
+        _ => (100 * ((def & 0x3F) + 0x10)) >> ((def >> 6) & 0x7),
+    };
+    (mhz != 0).then_some(mhz as u32)
+}
+
+/// Frequencies of the AMD P-states the OS may use, P0 first
+///
+/// P-states past the limit the firmware set, and disabled ones, are None.
+pub fn amd_pstates() -> [Option<u32>; AMD_MAX_PSTATES] {
+    let family = cpu_features().family();
+    let max = ((rdmsr(MSR_AMD_PSTATE_LIMIT) >> 4) & 0x7) as usize;
+    core::array::from_fn(|i| {
+        if i > max {
+            return None;
+        }
+        decode_amd_pstate(family, rdmsr(MSR_AMD_PSTATE_DEF + i as u32))
+    })
+}
+
+/// Current AMD P-state number
+pub fn current_amd_pstate() -> u8 {
+    (rdmsr(MSR_AMD_PSTATE_STATUS) & 0x7) as u8
+}
+
+/// Switch to an AMD P-state
+///
+/// # Safety
+/// The P-state must be enabled and within the firmware's limit.
+pub unsafe fn set_amd_pstate(pstate: u8) {
+    wrmsr(MSR_AMD_PSTATE_CTL, (pstate & 0x7) as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hwp_registers() {
+        let caps = HwpCapabilities::from_msr(0x0108_1E2A);
+        assert_eq!(caps, HwpCapabilities { highest: 0x2A, guaranteed: 0x1E, most_efficient: 0x08, lowest: 0x01 });
+
+        let request = HwpRequest { min: 1, max: 0x2A, desired: 0, epp: EPP_BALANCED };
+        assert_eq!(request.to_msr(), 0x8000_2A01);
+    }
+
+    #[test]
+    fn test_decode_amd_pstate() {
+        // Zen 2 P0: FID 0x8C, DfsId 8 -> 3500 MHz
+        assert_eq!(decode_amd_pstate(0x17, AMD_PSTATE_ENABLED | 0x088C), Some(3500));
+        // Family 15h: FID 0x10, DID 1 -> 1600 MHz
+        assert_eq!(decode_amd_pstate(0x15, AMD_PSTATE_ENABLED | 1 << 6 | 0x10), Some(1600));
+        // Zen 5: FID 800 -> 4000 MHz
+        assert_eq!(decode_amd_pstate(0x1A, AMD_PSTATE_ENABLED | 800), Some(4000));
+
+        assert_eq!(decode_amd_pstate(0x17, 0x088C), None);
+        assert_eq!(decode_amd_pstate(0x17, AMD_PSTATE_ENABLED | 0x8C), None);
+    }
+}
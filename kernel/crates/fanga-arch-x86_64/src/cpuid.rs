@@ -0,0 +1,569 @@
+//! CPUID Feature Detection
+//!
+//! The CPUID leaves the kernel cares about are read once, on first use,
+//! and cached; `cpu_features()` then answers feature queries without
+//! executing CPUID again, which is slow and traps to the hypervisor in a
+//! VM. The boot CPU's answers are taken to hold for every CPU.
+//!
+//! This module provides:
+//! - The cached `CpuFeatures` of the boot CPU
+//! - Feature flags (`Feature`) and their CPUID locations
+//! - Vendor, family/model/stepping, hypervisor and address size info
+
+use core::arch::x86_64::{CpuidResult, __cpuid_count};
+use core::fmt;
+use spin::Once;
+
+/// Basic and extended leaves
+const LEAF_VENDOR: u32 = 0x0000_0000;
+const LEAF_FEATURES: u32 = 0x0000_0001;
+const LEAF_MONITOR: u32 = 0x0000_0005;
+const LEAF_THERMAL_POWER: u32 = 0x0000_0006;
+const LEAF_EXTENDED_FEATURES: u32 = 0x0000_0007;
+const LEAF_PERFMON: u32 = 0x0000_000A;
+const LEAF_TSC: u32 = 0x0000_0015;
+const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+const LEAF_EXT_MAX: u32 = 0x8000_0000;
+const LEAF_EXT_FEATURES: u32 = 0x8000_0001;
+const LEAF_EXT_POWER: u32 = 0x8000_0007;
+const LEAF_EXT_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+/// Feature register a flag lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    /// CPUID.01H:ECX
+    Leaf1Ecx = 0,
+    /// CPUID.01H:EDX
+    Leaf1Edx = 1,
+    /// CPUID.(EAX=07H,ECX=0):EBX
+    Leaf7Ebx = 2,
+    /// CPUID.(EAX=07H,ECX=0):ECX
+    Leaf7Ecx = 3,
+    /// CPUID.(EAX=07H,ECX=0):EDX
+    Leaf7Edx = 4,
+    /// CPUID.80000001H:ECX
+    Ext1Ecx = 5,
+    /// CPUID.80000001H:EDX
+    Ext1Edx = 6,
+    /// CPUID.80000007H:EDX
+    Ext7Edx = 7,
+    /// CPUID.80000008H:EBX
+    Ext8Ebx = 8,
+    /// CPUID.06H:EAX
+    Leaf6Eax = 9,
+}
+
+const NUM_REGISTERS: usize = 10;
+
+/// A CPU feature flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Msr,
+    Apic,
+    Pat,
+    Acpi,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Monitor,
+    Est,
+    Pcid,
+    X2apic,
+    TscDeadline,
+    Xsave,
+    Osxsave,
+    Avx,
+    Rdrand,
+    Hypervisor,
+    Dtherm,
+    Pts,
+    Hwp,
+    Fsgsbase,
+    Smep,
+    Avx2,
+    Invpcid,
+    Avx512f,
+    Rdseed,
+    Smap,
+    Umip,
+    La57,
+    SpecCtrl,
+    Stibp,
+    ArchCapabilities,
+    Ssbd,
+    PerfCtrCore,
+    Nx,
+    Pdpe1gb,
+    Rdtscp,
+    HwPstate,
+    InvariantTsc,
+    AmdIbpb,
+    AmdIbrs,
+    AmdStibp,
+    AmdSsbd,
+}
+
+impl Feature {
+    /// Every feature, in CPUID order
+    pub const ALL: [Feature; 49] = [
+        Feature::Fpu,
+        Feature::Tsc,
+        Feature::Msr,
+        Feature::Apic,
+        Feature::Pat,
+        Feature::Acpi,
+        Feature::Fxsr,
+        Feature::Sse,
+        Feature::Sse2,
+        Feature::Sse3,
+        Feature::Ssse3,
+        Feature::Sse41,
+        Feature::Sse42,
+        Feature::Monitor,
+        Feature::Est,
+        Feature::Pcid,
+        Feature::X2apic,
+        Feature::TscDeadline,
+        Feature::Xsave,
+        Feature::Osxsave,
+        Feature::Avx,
+        Feature::Rdrand,
+        Feature::Hypervisor,
+        Feature::Dtherm,
+        Feature::Pts,
+        Feature::Hwp,
+        Feature::Fsgsbase,
+        Feature::Smep,
+        Feature::Avx2,
+        Feature::Invpcid,
+        Feature::Avx512f,
+        Feature::Rdseed,
+        Feature::Smap,
+        Feature::Umip,
+        Feature::La57,
+        Feature::SpecCtrl,
+        Feature::Stibp,
+        Feature::ArchCapabilities,
+        Feature::Ssbd,
+        Feature::PerfCtrCore,
+        Feature::Nx,
+        Feature::Pdpe1gb,
+        Feature::Rdtscp,
+        Feature::HwPstate,
+        Feature::InvariantTsc,
+        Feature::AmdIbpb,
+        Feature::AmdIbrs,
+        Feature::AmdStibp,
+        Feature::AmdSsbd,
+    ];
+
+    /// Register and bit that report the feature
+    const fn location(self) -> (Register, u32) {
+        use Register::*;
+        match self {
+            Feature::Fpu => (Leaf1Edx, 0),
+            Feature::Tsc => (Leaf1Edx, 4),
+            Feature::Msr => (Leaf1Edx, 5),
+            Feature::Apic => (Leaf1Edx, 9),
+            Feature::Pat => (Leaf1Edx, 16),
+            Feature::Acpi => (Leaf1Edx, 22),
+            Feature::Fxsr => (Leaf1Edx, 24),
+            Feature::Sse => (Leaf1Edx, 25),
+            Feature::Sse2 => (Leaf1Edx, 26),
+            Feature::Sse3 => (Leaf1Ecx, 0),
+            Feature::Monitor => (Leaf1Ecx, 3),
+            Feature::Est => (Leaf1Ecx, 7),
+            Feature::Ssse3 => (Leaf1Ecx, 9),
+            Feature::Pcid => (Leaf1Ecx, 17),
+            Feature::Sse41 => (Leaf1Ecx, 19),
+            Feature::Sse42 => (Leaf1Ecx, 20),
+            Feature::X2apic => (Leaf1Ecx, 21),
+            Feature::TscDeadline => (Leaf1Ecx, 24),
+            Feature::Xsave => (Leaf1Ecx, 26),
+            Feature::Osxsave => (Leaf1Ecx, 27),
+            Feature::Avx => (Leaf1Ecx, 28),
+            Feature::Rdrand => (Leaf1Ecx, 30),
+            Feature::Hypervisor => (Leaf1Ecx, 31),
+            Feature::Dtherm => (Leaf6Eax, 0),
+            Feature::Pts => (Leaf6Eax, 6),
+            Feature::Hwp => (Leaf6Eax, 7),
+            Feature::Fsgsbase => (Leaf7Ebx, 0),
+            Feature::Avx2 => (Leaf7Ebx, 5),
+            Feature::Smep => (Leaf7Ebx, 7),
+            Feature::Invpcid => (Leaf7Ebx, 10),
+            Feature::Avx512f => (Leaf7Ebx, 16),
+            Feature::Rdseed => (Leaf7Ebx, 18),
+            Feature::Smap => (Leaf7Ebx, 20),
+            Feature::Umip => (Leaf7Ecx, 2),
+            Feature::La57 => (Leaf7Ecx, 16),
+            Feature::SpecCtrl => (Leaf7Edx, 26),
+            Feature::Stibp => (Leaf7Edx, 27),
+            Feature::ArchCapabilities => (Leaf7Edx, 29),
+            Feature::Ssbd => (Leaf7Edx, 31),
+            Feature::PerfCtrCore => (Ext1Ecx, 23),
+            Feature::Nx => (Ext1Edx, 20),
+            Feature::Pdpe1gb => (Ext1Edx, 26),
+            Feature::Rdtscp => (Ext1Edx, 27),
+            Feature::HwPstate => (Ext7Edx, 7),
+            Feature::InvariantTsc => (Ext7Edx, 8),
+            Feature::AmdIbpb => (Ext8Ebx, 12),
+            Feature::AmdIbrs => (Ext8Ebx, 14),
+            Feature::AmdStibp => (Ext8Ebx, 15),
+            Feature::AmdSsbd => (Ext8Ebx, 24),
+        }
+    }
+
+    /// Lowercase name, as in `/proc/cpuinfo`
+    pub const fn name(self) -> &'static str {
+        match self {
+            Feature::Fpu => "fpu",
+            Feature::Tsc => "tsc",
+            Feature::Msr => "msr",
+            Feature::Apic => "apic",
+            Feature::Pat => "pat",
+            Feature::Acpi => "acpi",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Sse3 => "sse3",
+            Feature::Ssse3 => "ssse3",
+            Feature::Sse41 => "sse4_1",
+            Feature::Sse42 => "sse4_2",
+            Feature::Monitor => "monitor",
+            Feature::Est => "est",
+            Feature::Pcid => "pcid",
+            Feature::X2apic => "x2apic",
+            Feature::TscDeadline => "tsc_deadline_timer",
+            Feature::Xsave => "xsave",
+            Feature::Osxsave => "osxsave",
+            Feature::Avx => "avx",
+            Feature::Rdrand => "rdrand",
+            Feature::Hypervisor => "hypervisor",
+            Feature::Dtherm => "dtherm",
+            Feature::Pts => "pts",
+            Feature::Hwp => "hwp",
+            Feature::Fsgsbase => "fsgsbase",
+            Feature::Smep => "smep",
+            Feature::Avx2 => "avx2",
+            Feature::Invpcid => "invpcid",
+            Feature::Avx512f => "avx512f",
+            Feature::Rdseed => "rdseed",
+            Feature::Smap => "smap",
+            Feature::Umip => "umip",
+            Feature::La57 => "la57",
+            Feature::SpecCtrl => "spec_ctrl",
+            Feature::Stibp => "intel_stibp",
+            Feature::ArchCapabilities => "arch_capabilities",
+            Feature::Ssbd => "spec_ctrl_ssbd",
+            Feature::PerfCtrCore => "perfctr_core",
+            Feature::Nx => "nx",
+            Feature::Pdpe1gb => "pdpe1gb",
+            Feature::Rdtscp => "rdtscp",
+            Feature::HwPstate => "hw_pstate",
+            Feature::InvariantTsc => "constant_tsc",
+            Feature::AmdIbpb => "ibpb",
+            Feature::AmdIbrs => "ibrs",
+            Feature::AmdStibp => "amd_stibp",
+            Feature::AmdSsbd => "amd_ssbd",
+        }
+    }
+}
+
+/// CPU vendor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+/// Processor identification and features from CPUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    max_leaf: u32,
+    max_ext_leaf: u32,
+    vendor_id: [u8; 12],
+    /// CPUID.01H:EAX (stepping, model, family)
+    signature: u32,
+    registers: [u32; NUM_REGISTERS],
+    /// CPUID.40000000H:EBX/ECX/EDX, zero without a hypervisor
+    hypervisor_id: [u8; 12],
+    /// CPUID.05H:ECX/EDX, zero if the leaf is missing
+    mwait: (u32, u32),
+    /// CPUID.0AH:EAX/EBX, zero if the leaf is missing
+    perfmon: (u32, u32),
+    /// CPUID.15H:EAX/EBX/ECX, zero if the leaf is missing
+    tsc_ratio: (u32, u32, u32),
+    /// CPUID.80000008H:EAX, zero if the leaf is missing
+    address_sizes: u32,
+}
+
+/// Execute CPUID
+///
+/// For values that differ between CPUs, such as the APIC ID, which
+/// `cpu_features()` must not cache.
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    __cpuid_count(leaf, subleaf)
+}
+
+/// Twelve ASCII bytes from three registers, in register order
+fn id_string(a: u32, b: u32, c: u32) -> [u8; 12] {
+    let mut id = [0u8; 12];
+    id[0..4].copy_from_slice(&a.to_le_bytes());
+    id[4..8].copy_from_slice(&b.to_le_bytes());
+    id[8..12].copy_from_slice(&c.to_le_bytes());
+    id
+}
+
+impl CpuFeatures {
+    /// Read the leaves from this CPU
+    pub fn read() -> Self {
+        let leaf0 = cpuid(LEAF_VENDOR, 0);
+        let max_leaf = leaf0.eax;
+        let max_ext_leaf = cpuid(LEAF_EXT_MAX, 0).eax;
+        let basic = |leaf: u32| (max_leaf >= leaf).then(|| cpuid(leaf, 0));
+        let extended = |leaf: u32| (max_ext_leaf >= leaf).then(|| cpuid(leaf, 0));
+
+        let mut features = Self {
+            max_leaf,
+            max_ext_leaf,
+            // The vendor string is in EBX, EDX, ECX order
+            vendor_id: id_string(leaf0.ebx, leaf0.edx, leaf0.ecx),
+            signature: 0,
+            registers: [0; NUM_REGISTERS],
+            hypervisor_id: [0; 12],
+            mwait: (0, 0),
+            perfmon: (0, 0),
+            tsc_ratio: (0, 0, 0),
+            address_sizes: 0,
+        };
+
+        if let Some(leaf) = basic(LEAF_FEATURES) {
+            features.signature = leaf.eax;
+            features.registers[Register::Leaf1Ecx as usize] = leaf.ecx;
+            features.registers[Register::Leaf1Edx as usize] = leaf.edx;
+        }
+        if let Some(leaf) = basic(LEAF_MONITOR) {
+            features.mwait = (leaf.ecx, leaf.edx);
+        }
+        if let Some(leaf) = basic(LEAF_THERMAL_POWER) {
+            features.registers[Register::Leaf6Eax as usize] = leaf.eax;
+        }
+        if let Some(leaf) = basic(LEAF_EXTENDED_FEATURES) {
+            features.registers[Register::Leaf7Ebx as usize] = leaf.ebx;
+            features.registers[Register::Leaf7Ecx as usize] = leaf.ecx;
+            features.registers[Register::Leaf7Edx as usize] = leaf.edx;
+        }
+        if let Some(leaf) = basic(LEAF_PERFMON) {
+            features.perfmon = (leaf.eax, leaf.ebx);
+        }
+        if let Some(leaf) = basic(LEAF_TSC) {
+            features.tsc_ratio = (leaf.eax, leaf.ebx, leaf.ecx);
+        }
+        if let Some(leaf) = extended(LEAF_EXT_FEATURES) {
+            features.registers[Register::Ext1Ecx as usize] = leaf.ecx;
+            features.registers[Register::Ext1Edx as usize] = leaf.edx;
+        }
+        if let Some(leaf) = extended(LEAF_EXT_POWER) {
+            features.registers[Register::Ext7Edx as usize] = leaf.edx;
+        }
+        if let Some(leaf) = extended(LEAF_EXT_ADDRESS_SIZES) {
+            features.address_sizes = leaf.eax;
+            features.registers[Register::Ext8Ebx as usize] = leaf.ebx;
+        }
+        // The hypervisor leaves only exist when the hypervisor bit is set
+        if features.has(Feature::Hypervisor) {
+            let leaf = cpuid(LEAF_HYPERVISOR, 0);
+            features.hypervisor_id = id_string(leaf.ebx, leaf.ecx, leaf.edx);
+        }
+        features
+    }
+
+    /// Check whether the CPU has a feature
+    pub fn has(&self, feature: Feature) -> bool {
+        let (register, bit) = feature.location();
+        self.registers[register as usize] & (1 << bit) != 0
+    }
+
+    /// Features the CPU has
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL.into_iter().filter(|&feature| self.has(feature))
+    }
+
+    /// Highest basic leaf
+    pub fn max_leaf(&self) -> u32 {
+        self.max_leaf
+    }
+
+    /// Highest extended leaf
+    pub fn max_ext_leaf(&self) -> u32 {
+        self.max_ext_leaf
+    }
+
+    /// Vendor ID string, such as `GenuineIntel`
+    pub fn vendor_id(&self) -> &str {
+        core::str::from_utf8(&self.vendor_id).unwrap_or("unknown")
+    }
+
+    /// CPU vendor
+    pub fn vendor(&self) -> Vendor {
+        match &self.vendor_id {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other,
+        }
+    }
+
+    /// Display family, including the extended family
+    pub fn family(&self) -> u32 {
+        let family = (self.signature >> 8) & 0xF;
+        if family == 0xF {
+            family + ((self.signature >> 20) & 0xFF)
+        } else {
+            family
+        }
+    }
+
+    /// Display model, including the extended model
+    pub fn model(&self) -> u32 {
+        let model = (self.signature >> 4) & 0xF;
+        let family = (self.signature >> 8) & 0xF;
+        if family == 0x6 || family == 0xF {
+            model | ((self.signature >> 16) & 0xF) << 4
+        } else {
+            model
+        }
+    }
+
+    /// Stepping
+    pub fn stepping(&self) -> u32 {
+        self.signature & 0xF
+    }
+
+    /// Hypervisor ID string, such as `KVMKVMKVM`, if running in a VM
+    pub fn hypervisor_id(&self) -> Option<&str> {
+        if !self.has(Feature::Hypervisor) {
+            return None;
+        }
+        core::str::from_utf8(&self.hypervisor_id)
+            .ok()
+            .map(|id| id.trim_end_matches('\0'))
+    }
+
+    /// Physical and linear address widths in bits
+    ///
+    /// Defaults to 36 and 48 bits when the CPU does not report them.
+    pub fn address_bits(&self) -> (u8, u8) {
+        if self.address_sizes == 0 {
+            return (36, 48);
+        }
+        (self.address_sizes as u8, (self.address_sizes >> 8) as u8)
+    }
+
+    /// CPUID.05H ECX and EDX (MWAIT extensions and C-state sub-states), if present
+    pub fn mwait(&self) -> Option<(u32, u32)> {
+        (self.max_leaf >= LEAF_MONITOR).then_some(self.mwait)
+    }
+
+    /// CPUID.0AH EAX and EBX (architectural performance monitoring), if present
+    pub fn perfmon(&self) -> Option<(u32, u32)> {
+        (self.max_leaf >= LEAF_PERFMON).then_some(self.perfmon)
+    }
+
+    /// CPUID.15H EAX, EBX and ECX (TSC/crystal ratio), if present
+    pub fn tsc_ratio(&self) -> Option<(u32, u32, u32)> {
+        (self.max_leaf >= LEAF_TSC).then_some(self.tsc_ratio)
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    /// Space-separated feature names
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(feature.name())?;
+        }
+        Ok(())
+    }
+}
+
+static FEATURES: Once<CpuFeatures> = Once::new();
+
+/// Features of the boot CPU, read on the first call
+pub fn cpu_features() -> &'static CpuFeatures {
+    FEATURES.call_once(CpuFeatures::read)
+}
+
+/// Check whether the CPU has a feature
+#[inline]
+pub fn has(feature: Feature) -> bool {
+    cpu_features().has(feature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(signature: u32, registers: [u32; NUM_REGISTERS]) -> CpuFeatures {
+        CpuFeatures {
+            max_leaf: 0xD,
+            max_ext_leaf: 0x8000_0008,
+            vendor_id: *b"GenuineIntel",
+            signature,
+            registers,
+            hypervisor_id: *b"KVMKVMKVM\0\0\0",
+            mwait: (0, 0),
+            perfmon: (0, 0),
+            tsc_ratio: (0, 0, 0),
+            address_sizes: 0x3027,
+        }
+    }
+
+    #[test]
+    fn test_feature_bits() {
+        let mut registers = [0; NUM_REGISTERS];
+        registers[Register::Leaf1Ecx as usize] = 1 << 30 | 1 << 31;
+        registers[Register::Leaf7Ecx as usize] = 1 << 16;
+        registers[Register::Ext1Edx as usize] = 1 << 20;
+        let cpu = features(0, registers);
+
+        assert!(cpu.has(Feature::Rdrand) && cpu.has(Feature::La57) && cpu.has(Feature::Nx));
+        assert!(!cpu.has(Feature::Avx) && !cpu.has(Feature::Smep));
+        assert_eq!(cpu.iter().count(), 4);
+        assert_eq!(cpu.hypervisor_id(), Some("KVMKVMKVM"));
+        assert_eq!(cpu.address_bits(), (39, 48));
+        assert_eq!(cpu.perfmon(), Some((0, 0)));
+        assert_eq!(cpu.mwait(), Some((0, 0)));
+        assert_eq!(cpu.tsc_ratio(), None);
+    }
+
+    #[test]
+    fn test_signature() {
+        // Family 6, extended model 9, model 0xE, stepping 0xA (Kaby Lake)
+        let cpu = features(0x0009_06EA, [0; NUM_REGISTERS]);
+        assert_eq!((cpu.family(), cpu.model(), cpu.stepping()), (6, 0x9E, 0xA));
+        assert_eq!(cpu.vendor(), Vendor::Intel);
+        assert_eq!(cpu.hypervisor_id(), None);
+
+        // Family 0xF + extended family 8 (Zen)
+        let zen = features(0x0080_0F11, [0; NUM_REGISTERS]);
+        assert_eq!((zen.family(), zen.model()), (0x17, 0x01));
+    }
+
+    #[test]
+    fn test_read_host() {
+        // Every x86_64 CPU has SSE2 and reports a vendor
+        let cpu = cpu_features();
+        assert!(cpu.has(Feature::Sse2));
+        assert_eq!(cpu.vendor_id().len(), 12);
+    }
+}
@@ -134,21 +134,26 @@ pub struct Gdtr {
 /// 0: Null descriptor
 /// 1: Kernel code segment
 /// 2: Kernel data segment
-/// 3: User code segment (for future use)
-/// 4: User data segment (for future use)
+/// 3: User data segment
+/// 4: User code segment
 /// 5-6: TSS descriptor (takes 2 entries in 64-bit mode)
+///
+/// User data comes before user code because SYSRET loads SS and CS from
+/// one base selector in that order (see `SYSRET_SELECTOR_BASE`).
+///
+/// Every CPU has its own copy so its TSS descriptor can point at its own
+/// TSS (a loaded TSS descriptor is marked busy and cannot be shared).
 #[repr(C, align(16))]
 struct GdtTable {
     null: GdtEntry,
     kernel_code: GdtEntry,
     kernel_data: GdtEntry,
-    user_code: GdtEntry,
     user_data: GdtEntry,
+    user_code: GdtEntry,
     tss: TssEntry,
 }
 
-// Static GDT and TSS
-static mut GDT: GdtTable = GdtTable {
+const GDT_TEMPLATE: GdtTable = GdtTable {
     null: GdtEntry::null(),
     // Kernel Code: base=0, limit=0xFFFFF, access=0x9A (present, DPL=0, code, readable)
     // granularity=0xA0 (4KB granularity, 64-bit mode)
@@ -156,28 +161,55 @@ static mut GDT: GdtTable = GdtTable {
     // Kernel Data: base=0, limit=0xFFFFF, access=0x92 (present, DPL=0, data, writable)
     // granularity=0xC0 (4KB granularity, 32-bit)
     kernel_data: GdtEntry::new(0, 0xFFFFF, 0x92, 0xC0),
-    // User Code: access=0xFA (present, DPL=3, code, readable)
-    user_code: GdtEntry::new(0, 0xFFFFF, 0xFA, 0xA0),
     // User Data: access=0xF2 (present, DPL=3, data, writable)
     user_data: GdtEntry::new(0, 0xFFFFF, 0xF2, 0xC0),
+    // User Code: access=0xFA (present, DPL=3, code, readable)
+    user_code: GdtEntry::new(0, 0xFFFFF, 0xFA, 0xA0),
     tss: TssEntry::null(),
 };
 
-static mut TSS: Tss = Tss::new();
+/// Maximum number of CPUs with their own GDT and TSS
+pub const MAX_CPUS: usize = 256;
 
+// Per-CPU GDTs and TSSs
+static mut GDTS: [GdtTable; MAX_CPUS] = [GDT_TEMPLATE; MAX_CPUS];
+static mut TSSES: [Tss; MAX_CPUS] = [const { Tss::new() }; MAX_CPUS];
+
+/// Size of an IST stack allocated for an application processor
+pub const IST_STACK_SIZE: usize = 4 * 4096;
+
+// IST stacks of the boot CPU, which comes up before the heap.
 // Double fault stack - 128KB should be enough for double fault handling
 const DOUBLE_FAULT_STACK_SIZE: usize = 32 * 4096;
 static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+static mut NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut MACHINE_CHECK_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
 /// GDT segment selectors
 pub const KERNEL_CODE_SELECTOR: u16 = 0x08; // offset 1 * 8
 pub const KERNEL_DATA_SELECTOR: u16 = 0x10; // offset 2 * 8
-pub const USER_CODE_SELECTOR: u16 = 0x18; // offset 3 * 8
-pub const USER_DATA_SELECTOR: u16 = 0x20; // offset 4 * 8
+pub const USER_DATA_SELECTOR: u16 = 0x18; // offset 3 * 8
+pub const USER_CODE_SELECTOR: u16 = 0x20; // offset 4 * 8
 pub const TSS_SELECTOR: u16 = 0x28; // offset 5 * 8
 
+/// Base selector SYSRET loads user segments from (IA32_STAR[63:48]): SS
+/// is base + 8 and CS base + 16, both with RPL 3
+pub const SYSRET_SELECTOR_BASE: u16 = KERNEL_DATA_SELECTOR;
+
 /// IST index for double fault (1-based)
 pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+/// IST index for NMI
+pub const NMI_IST_INDEX: u8 = 2;
+/// IST index for machine check
+pub const MACHINE_CHECK_IST_INDEX: u8 = 3;
+
+/// Top addresses of a CPU's IST stacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IstStacks {
+    pub double_fault: u64,
+    pub nmi: u64,
+    pub machine_check: u64,
+}
 
 #[inline(always)]
 unsafe fn lgdt(gdtr: &Gdtr) {
@@ -189,59 +221,179 @@ unsafe fn ltr(selector: u16) {
     asm!("ltr {:x}", in(reg) selector, options(nostack, preserves_flags));
 }
 
+/// Load the GDT and TSS of the boot CPU
 pub fn init() {
+    // The stacks grow downward, so we need the address after the last byte
+    let stacks = IstStacks {
+        double_fault: &raw const DOUBLE_FAULT_STACK as u64 + DOUBLE_FAULT_STACK_SIZE as u64,
+        nmi: &raw const NMI_STACK as u64 + IST_STACK_SIZE as u64,
+        machine_check: &raw const MACHINE_CHECK_STACK as u64 + IST_STACK_SIZE as u64,
+    };
+
+    match unsafe { init_cpu(0, &stacks) } {
+        Ok(()) => crate::serial_println!("[GDT] loaded with TSS ✅"),
+        Err(e) => crate::serial_println!("[GDT] {}", e),
+    }
+}
+
+/// Load the GDT and TSS of a CPU
+///
+/// # Safety
+/// Must be called once, on CPU `cpu` itself. The IST stacks must stay
+/// valid for as long as the CPU runs.
+pub unsafe fn init_cpu(cpu: usize, stacks: &IstStacks) -> Result<(), &'static str> {
+    let gdt = setup_cpu_tables(cpu, stacks)?;
+    load_gdt(gdt);
+    ltr(TSS_SELECTOR);
+    Ok(())
+}
+
+/// Load the GDT and TSS of a CPU again after a wake from ACPI sleep
+///
+/// The TSS descriptor is still marked busy from the first `ltr`, which
+/// would fault, so it is written afresh first. The IST stacks are kept.
+///
+/// # Safety
+/// Must be called on CPU `cpu` itself, after `init_cpu`.
+pub unsafe fn reload_cpu(cpu: usize) -> Result<(), &'static str> {
+    if cpu >= MAX_CPUS {
+        return Err("CPU index out of range");
+    }
+    let tss_ptr = &raw const TSSES[cpu];
+    let gdt_ptr = &raw mut GDTS[cpu];
+    (*gdt_ptr).tss = TssEntry::new(tss_ptr as u64, size_of::<Tss>() as u32 - 1);
+    load_gdt(gdt_ptr);
+    ltr(TSS_SELECTOR);
+    Ok(())
+}
+
+/// Fill in the TSS of a CPU and point its GDT's TSS descriptor at it
+fn setup_cpu_tables(cpu: usize, stacks: &IstStacks) -> Result<*const GdtTable, &'static str> {
+    if cpu >= MAX_CPUS {
+        return Err("CPU index out of range");
+    }
     unsafe {
-        // Set up the double fault stack in IST1
-        // The stack grows downward, so we need the address after the last byte
-        let stack_start = &raw const DOUBLE_FAULT_STACK as *const u8 as u64;
-        let stack_top = stack_start + DOUBLE_FAULT_STACK_SIZE as u64;
-        
-        // Use raw pointer to write to TSS
-        let tss_ptr = &raw mut TSS;
-        (*tss_ptr).ist1 = stack_top;
-
-        // Create TSS descriptor
-        let tss_addr = tss_ptr as *const _ as u64;
+        // Use raw pointers to avoid references to the static muts
+        let tss_ptr = &raw mut TSSES[cpu];
+        (*tss_ptr).ist1 = stacks.double_fault;
+        (*tss_ptr).ist2 = stacks.nmi;
+        (*tss_ptr).ist3 = stacks.machine_check;
+
         let tss_limit = size_of::<Tss>() as u32 - 1;
-        
-        // Use raw pointer to write to GDT
-        let gdt_ptr = &raw mut GDT;
-        (*gdt_ptr).tss = TssEntry::new(tss_addr, tss_limit);
-
-        // Load GDT
-        let gdtr = Gdtr {
-            limit: (size_of::<GdtTable>() - 1) as u16,
-            base: gdt_ptr as *const _ as u64,
-        };
-        lgdt(&gdtr);
-
-        // Reload segment registers
-        // CS is reloaded via far return
-        // Note: This modifies RSP by pushing/popping values
-        asm!(
-            "push {sel}",
-            "lea {tmp}, [rip + 2f]",
-            "push {tmp}",
-            "retfq",
-            "2:",
-            sel = in(reg) KERNEL_CODE_SELECTOR as u64,
-            tmp = lateout(reg) _,
-        );
-
-        // Reload data segments
-        asm!(
-            "mov ds, {0:x}",
-            "mov es, {0:x}",
-            "mov fs, {0:x}",
-            "mov gs, {0:x}",
-            "mov ss, {0:x}",
-            in(reg) KERNEL_DATA_SELECTOR,
-            options(nostack, preserves_flags),
-        );
-
-        // Load TSS
-        ltr(TSS_SELECTOR);
+        let gdt_ptr = &raw mut GDTS[cpu];
+        (*gdt_ptr).tss = TssEntry::new(tss_ptr as u64, tss_limit);
+        Ok(gdt_ptr)
     }
+}
 
-    crate::serial_println!("[GDT] loaded with TSS ✅");
+/// Index of the running CPU, found from the address of its GDT
+pub fn current_cpu_index() -> Option<usize> {
+    let mut gdtr = Gdtr { limit: 0, base: 0 };
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags));
+    }
+    let base = gdtr.base;
+    let offset = base.checked_sub(&raw const GDTS as u64)?;
+    let size = size_of::<GdtTable>() as u64;
+    let index = (offset / size) as usize;
+    (offset % size == 0 && index < MAX_CPUS).then_some(index)
+}
+
+/// Set the stack loaded on entry from user mode (TSS.RSP0) of a CPU
+///
+/// System calls switch to the same stack.
+pub fn set_kernel_stack(cpu: usize, stack_top: u64) {
+    if cpu < MAX_CPUS {
+        unsafe {
+            TSSES[cpu].rsp0 = stack_top;
+        }
+        crate::percpu::set_syscall_stack(cpu, stack_top);
+    }
+}
+
+/// Get the user-mode entry stack (TSS.RSP0) of a CPU
+pub fn kernel_stack(cpu: usize) -> u64 {
+    if cpu < MAX_CPUS {
+        unsafe { TSSES[cpu].rsp0 }
+    } else {
+        0
+    }
+}
+
+/// Load the GDT and reload the segment registers
+unsafe fn load_gdt(gdt_ptr: *const GdtTable) {
+    let gdtr = Gdtr {
+        limit: (size_of::<GdtTable>() - 1) as u16,
+        base: gdt_ptr as *const _ as u64,
+    };
+    lgdt(&gdtr);
+
+    // Reload segment registers
+    // CS is reloaded via far return
+    // Note: This modifies RSP by pushing/popping values
+    asm!(
+        "push {sel}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        sel = in(reg) KERNEL_CODE_SELECTOR as u64,
+        tmp = lateout(reg) _,
+    );
+
+    // Reload data segments
+    asm!(
+        "mov ds, {0:x}",
+        "mov es, {0:x}",
+        "mov fs, {0:x}",
+        "mov gs, {0:x}",
+        "mov ss, {0:x}",
+        in(reg) KERNEL_DATA_SELECTOR,
+        options(nostack, preserves_flags),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tss_descriptor() {
+        let entry = TssEntry::new(0xFFFF_8000_1234_5678, 0x67);
+        let bytes: [u8; 16] = unsafe { core::mem::transmute(entry) };
+        assert_eq!(u16::from_le_bytes([bytes[0], bytes[1]]), 0x67);
+        assert_eq!(bytes[5], 0x89);
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 0x5678);
+        assert_eq!(bytes[4], 0x34);
+        assert_eq!(bytes[7], 0x12);
+        assert_eq!(u32::from_le_bytes(bytes[8..12].try_into().unwrap()), 0xFFFF_8000);
+    }
+
+    #[test]
+    fn test_per_cpu_tables() {
+        let stacks = IstStacks { double_fault: 0x1000, nmi: 0x2000, machine_check: 0x3000 };
+        let a = setup_cpu_tables(5, &stacks).unwrap();
+        let b = setup_cpu_tables(6, &stacks).unwrap();
+        assert_ne!(a, b);
+        assert!(setup_cpu_tables(MAX_CPUS, &stacks).is_err());
+
+        unsafe {
+            let tss = &raw const TSSES[5];
+            assert_eq!({ (*tss).ist2 }, 0x2000);
+            assert_eq!({ (*tss).ist3 }, 0x3000);
+        }
+
+        set_kernel_stack(6, 0x9000);
+        assert_eq!(kernel_stack(6), 0x9000);
+        assert_eq!(kernel_stack(5), 0);
+    }
+
+    #[test]
+    fn test_sysret_selectors() {
+        assert_eq!(SYSRET_SELECTOR_BASE + 8, USER_DATA_SELECTOR);
+        assert_eq!(SYSRET_SELECTOR_BASE + 16, USER_CODE_SELECTOR);
+        assert_eq!(core::mem::offset_of!(GdtTable, user_data), USER_DATA_SELECTOR as usize);
+        assert_eq!(core::mem::offset_of!(GdtTable, user_code), USER_CODE_SELECTOR as usize);
+        assert_eq!(core::mem::offset_of!(GdtTable, tss), TSS_SELECTOR as usize);
+    }
 }
@@ -6,27 +6,45 @@
 
 use crate::serial_println;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Once;
 
 /// APIC base address (typically 0xFEE00000)
 const APIC_BASE_MSR: u32 = 0x1B;
 
+/// IA32_APIC_BASE: x2APIC mode and global enable bits
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// x2APIC registers are MSRs starting here (xAPIC offset >> 4)
+const X2APIC_MSR_BASE: u32 = 0x800;
+
 /// Local APIC register offsets
 const APIC_ID: u32 = 0x020;
+const APIC_ICR: u32 = 0x300; // Interrupt Command (one 64-bit MSR in x2APIC mode)
 const APIC_EOI: u32 = 0x0B0; // End of Interrupt
-const APIC_TIMER_INIT: u32 = 0x380; // Initial Count
-const APIC_TIMER_CURRENT: u32 = 0x390; // Current Count
-const APIC_TIMER_DIV: u32 = 0x3E0; // Divide Configuration
+pub(super) const APIC_TIMER_INIT: u32 = 0x380; // Initial Count
+pub(super) const APIC_TIMER_CURRENT: u32 = 0x390; // Current Count
+pub(super) const APIC_TIMER_DIV: u32 = 0x3E0; // Divide Configuration
 
 // These will be used when APIC MMIO is properly mapped
 #[allow(dead_code)]
 const APIC_VERSION: u32 = 0x030;
 #[allow(dead_code)]
 const APIC_TPR: u32 = 0x080; // Task Priority Register
-#[allow(dead_code)]
 const APIC_SPURIOUS: u32 = 0x0F0; // Spurious Interrupt Vector Register
-#[allow(dead_code)]
-const APIC_LVT_TIMER: u32 = 0x320; // Local Vector Table Timer
+
+/// Spurious vector register: APIC software enable
+const SVR_ENABLE: u64 = 1 << 8;
+
+/// ICR fields
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_ALL_INCLUDING_SELF: u64 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u64 = 0b11 << 18;
+
+/// Whether the local APICs run in x2APIC mode
+static X2APIC: AtomicBool = AtomicBool::new(false);
+pub(super) const APIC_LVT_TIMER: u32 = 0x320; // Local Vector Table Timer
 #[allow(dead_code)]
 const APIC_LVT_LINT0: u32 = 0x350; // Local Vector Table LINT0
 #[allow(dead_code)]
@@ -51,20 +69,7 @@ impl Apic {
 
     /// Check if APIC is supported by the CPU
     pub fn is_supported() -> bool {
-        let cpuid_result: u32;
-        unsafe {
-            asm!(
-                "mov eax, 1",
-                "cpuid",
-                "mov {0:e}, edx",
-                out(reg) cpuid_result,
-                out("eax") _,
-                out("ecx") _,
-                out("edx") _,
-            );
-        }
-        // Bit 9 of EDX indicates APIC support
-        (cpuid_result & (1 << 9)) != 0
+        crate::cpuid::has(crate::cpuid::Feature::Apic)
     }
 
     /// Read the APIC base address from MSR
@@ -111,6 +116,12 @@ impl Apic {
         serial_println!("[APIC] Enabled in MSR: {}", apic_enabled);
         serial_println!("[APIC] Note: MMIO access disabled until memory mapping is implemented");
 
+        // x2APIC registers are MSRs and need no mapping; IPIs use them
+        if apic_enabled && enable_x2apic() {
+            X2APIC.store(true, Ordering::Relaxed);
+            serial_println!("[APIC] x2APIC mode enabled");
+        }
+
         // TODO: Map APIC memory region before accessing registers
         // For now, we just detect APIC but don't enable it
         // This requires identity mapping or page table setup for 0xFEE00000
@@ -146,6 +157,99 @@ impl Apic {
     }
 }
 
+pub(super) fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+pub(super) unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+pub(super) const fn x2apic_msr(offset: u32) -> u32 {
+    X2APIC_MSR_BASE + (offset >> 4)
+}
+
+/// Check CPUID.1:ECX bit 21 for x2APIC support
+pub fn x2apic_supported() -> bool {
+    crate::cpuid::has(crate::cpuid::Feature::X2apic)
+}
+
+/// Switch this CPU's local APIC to x2APIC mode and software-enable it
+fn enable_x2apic() -> bool {
+    if !x2apic_supported() {
+        return false;
+    }
+    unsafe {
+        let base = rdmsr(APIC_BASE_MSR);
+        wrmsr(APIC_BASE_MSR, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+
+        let svr = rdmsr(x2apic_msr(APIC_SPURIOUS));
+        let vector = crate::interrupts::idt::VEC_APIC_SPURIOUS as u64;
+        wrmsr(x2apic_msr(APIC_SPURIOUS), (svr & !0xFF) | SVR_ENABLE | vector);
+    }
+    true
+}
+
+/// Check whether IPIs can be sent
+pub fn ipis_available() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+/// Send a fixed IPI to the CPU with local APIC ID `apic_id`
+pub fn send_ipi(apic_id: u32, vector: u8) -> Result<(), &'static str> {
+    if !ipis_available() {
+        return Err("IPIs need x2APIC mode");
+    }
+    unsafe {
+        wrmsr(x2apic_msr(APIC_ICR), ((apic_id as u64) << 32) | ICR_LEVEL_ASSERT | vector as u64);
+    }
+    Ok(())
+}
+
+/// Send a fixed IPI to every CPU, optionally including this one
+pub fn broadcast_ipi(vector: u8, include_self: bool) -> Result<(), &'static str> {
+    if !ipis_available() {
+        return Err("IPIs need x2APIC mode");
+    }
+    let shorthand = if include_self { ICR_ALL_INCLUDING_SELF } else { ICR_ALL_EXCLUDING_SELF };
+    unsafe {
+        wrmsr(x2apic_msr(APIC_ICR), shorthand | ICR_LEVEL_ASSERT | vector as u64);
+    }
+    Ok(())
+}
+
+/// Task priority that only lets IPIs (vectors 0xF0 and up) through
+pub const TPR_IPIS_ONLY: u8 = 0xE0;
+
+/// Set this CPU's task priority
+///
+/// Interrupts whose vector class (`vector >> 4`) is at or below that of
+/// `priority` are held back; 0 accepts everything.
+pub fn set_task_priority(priority: u8) {
+    if ipis_available() {
+        unsafe { wrmsr(x2apic_msr(APIC_TPR), priority as u64) };
+    }
+}
+
+/// Signal end of interrupt to this CPU's local APIC
+///
+/// Used for interrupts that only the local APIC delivers, such as IPIs.
+pub fn local_eoi() {
+    if ipis_available() {
+        unsafe { wrmsr(x2apic_msr(APIC_EOI), 0) };
+    }
+}
+
 /// Global APIC instance
 static LOCAL_APIC: Once<Apic> = Once::new();
 
@@ -157,6 +261,15 @@ pub fn init() -> Result<(), &'static str> {
     result
 }
 
+/// Initialize the Local APIC of an application processor
+///
+/// Each CPU has its own Local APIC at the same physical address; the
+/// global instance describes the boot CPU's. Switches this CPU to x2APIC
+/// mode as well so it can take IPIs.
+pub fn init_cpu() -> Result<(), &'static str> {
+    Apic::new().init()
+}
+
 /// Get a reference to the Local APIC
 pub fn local_apic() -> Option<&'static Apic> {
     LOCAL_APIC.get()
@@ -167,10 +280,35 @@ pub fn is_available() -> bool {
     LOCAL_APIC.get().map_or(false, |apic| apic.is_enabled())
 }
 
-/// Send EOI using APIC (if available) or fall back to PIC
+/// Send EOI for a device IRQ to whichever controller delivered it
+///
+/// With IOAPIC routing the local APIC takes the EOI, otherwise the PIC.
 pub fn eoi(irq: u8) {
+    if crate::interrupts::ioapic::is_active() {
+        local_eoi();
+        return;
+    }
     match LOCAL_APIC.get() {
         Some(apic) if apic.is_enabled() => apic.eoi(),
         _ => unsafe { crate::interrupts::pic::eoi(irq); }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_x2apic_msr() {
+        assert_eq!(x2apic_msr(APIC_ID), 0x802);
+        assert_eq!(x2apic_msr(APIC_EOI), 0x80B);
+        assert_eq!(x2apic_msr(APIC_SPURIOUS), 0x80F);
+        assert_eq!(x2apic_msr(APIC_ICR), 0x830);
+    }
+
+    #[test]
+    fn test_ipis_need_x2apic() {
+        assert!(send_ipi(1, 0xF0).is_err());
+        assert!(broadcast_ipi(0xF0, false).is_err());
+    }
+}
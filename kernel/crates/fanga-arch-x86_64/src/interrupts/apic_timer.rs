@@ -0,0 +1,305 @@
+/// Local APIC timer
+///
+/// Every CPU has its own APIC timer, so once calibrated it replaces the
+/// PIT as the scheduler tick and each CPU takes its own tick interrupts.
+/// The timer counts down at the (undocumented) bus frequency, which is
+/// measured at boot against PIT channel 2; the TSC is measured in the same
+/// window. Where the CPU supports it the tick runs in TSC-deadline mode,
+/// otherwise in periodic mode, and a stopped tick is replaced by a
+/// one-shot interrupt in either case.
+///
+/// The APIC registers are only reachable through x2APIC MSRs, so without
+/// x2APIC the PIT stays the tick source.
+///
+/// This module provides:
+/// - Calibration against the PIT
+/// - Per-CPU tick start, one-shot programming and tick restart
+/// - The tick handler hook deciding which CPU keeps time
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use super::apic::{
+    ipis_available, rdmsr, wrmsr, x2apic_msr, APIC_LVT_TIMER, APIC_TIMER_CURRENT, APIC_TIMER_DIV,
+    APIC_TIMER_INIT,
+};
+use crate::gdt::MAX_CPUS;
+use crate::tsc::rdtsc;
+
+/// Length of one tick in milliseconds (same 100 Hz rate as the PIT)
+pub const TICK_MS: u32 = 10;
+
+/// Length of the PIT reference window used for calibration
+pub const CALIBRATION_MS: u32 = 10;
+
+/// IA32_TSC_DEADLINE: arms the timer in TSC-deadline mode
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT timer fields
+const LVT_MASKED: u64 = 1 << 16;
+const LVT_ONESHOT: u64 = 0b00 << 17;
+const LVT_PERIODIC: u64 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u64 = 0b10 << 17;
+
+/// Divide configuration register value for divide-by-16
+const DIVIDE_BY_16: u64 = 0b0011;
+
+/// How the tick is generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    /// Not calibrated, the PIT drives the tick
+    Disabled = 0,
+    /// Periodic count-down mode
+    Periodic = 1,
+    /// One interrupt per programmed TSC deadline
+    TscDeadline = 2,
+}
+
+impl TimerMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TimerMode::Periodic,
+            2 => TimerMode::TscDeadline,
+            _ => TimerMode::Disabled,
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(TimerMode::Disabled as u8);
+
+/// APIC timer counts per millisecond at divide-by-16
+static COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// TSC cycles per millisecond
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Next TSC deadline of each CPU's tick, 0 while the tick is stopped
+static NEXT_DEADLINE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Rate of a counter that advanced `elapsed` over `us` microseconds, per ms
+pub fn rate_per_ms(elapsed: u64, us: u64) -> u64 {
+    if us == 0 {
+        return 0;
+    }
+    elapsed * 1000 / us
+}
+
+/// Count-down value for an interval of `ms` milliseconds
+///
+/// Saturates at the 32-bit counter's range; never returns 0, which would
+/// stop the timer.
+pub fn count_for_ms(ms: u32, counts_per_ms: u32) -> u32 {
+    (ms as u64 * counts_per_ms as u64).clamp(1, u32::MAX as u64) as u32
+}
+
+/// Milliseconds left on a count-down value
+pub fn count_to_ms(count: u32, counts_per_ms: u32) -> u32 {
+    if counts_per_ms == 0 {
+        return 0;
+    }
+    count / counts_per_ms
+}
+
+/// Get the active tick mode
+pub fn mode() -> TimerMode {
+    TimerMode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+/// Check whether the APIC timers drive the tick
+pub fn is_active() -> bool {
+    mode() != TimerMode::Disabled
+}
+
+/// Calibrated APIC timer frequency in counts per millisecond
+pub fn counts_per_ms() -> u32 {
+    COUNTS_PER_MS.load(Ordering::Relaxed)
+}
+
+/// TSC frequency measured during calibration, in cycles per millisecond
+pub fn tsc_per_ms() -> u64 {
+    TSC_PER_MS.load(Ordering::Relaxed)
+}
+
+/// Check CPUID.1:ECX bit 24 for TSC-deadline mode
+pub fn tsc_deadline_supported() -> bool {
+    crate::cpuid::has(crate::cpuid::Feature::TscDeadline)
+}
+
+/// Longest one-shot interval that can be programmed, in milliseconds
+pub fn max_oneshot_ms() -> u32 {
+    match mode() {
+        TimerMode::Disabled => 0,
+        TimerMode::Periodic => u32::MAX / counts_per_ms().max(1),
+        TimerMode::TscDeadline => u32::MAX,
+    }
+}
+
+fn cpu_index() -> usize {
+    crate::gdt::current_cpu_index().unwrap_or(0)
+}
+
+/// Measure the APIC timer and TSC against the PIT on this CPU
+///
+/// # Safety
+/// Must run with interrupts disabled and x2APIC enabled.
+unsafe fn calibrate() -> (u64, u64) {
+    wrmsr(x2apic_msr(APIC_TIMER_DIV), DIVIDE_BY_16);
+    wrmsr(x2apic_msr(APIC_LVT_TIMER), LVT_MASKED | LVT_ONESHOT);
+    wrmsr(x2apic_msr(APIC_TIMER_INIT), u32::MAX as u64);
+    let tsc_start = rdtsc();
+
+    let us = super::pit::calibration_wait(CALIBRATION_MS);
+
+    let current = rdmsr(x2apic_msr(APIC_TIMER_CURRENT));
+    let tsc_end = rdtsc();
+    wrmsr(x2apic_msr(APIC_TIMER_INIT), 0);
+
+    let counts = rate_per_ms(u32::MAX as u64 - current, us);
+    let tsc = rate_per_ms(tsc_end - tsc_start, us);
+    (counts, tsc)
+}
+
+/// Calibrate the APIC timer and make it the tick source
+///
+/// Runs on the boot CPU, which starts its own timer; application
+/// processors call `start_cpu`. The caller stops the PIT tick afterwards.
+///
+/// # Safety
+/// Must run once, with interrupts disabled.
+pub unsafe fn init() -> Result<TimerMode, &'static str> {
+    if !ipis_available() {
+        return Err("APIC timer needs x2APIC mode");
+    }
+
+    let (counts, tsc) = calibrate();
+    if counts == 0 || counts > u32::MAX as u64 {
+        return Err("APIC timer calibration failed");
+    }
+    COUNTS_PER_MS.store(counts as u32, Ordering::Relaxed);
+    TSC_PER_MS.store(tsc, Ordering::Relaxed);
+
+    let mode = if tsc_deadline_supported() && tsc != 0 {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::Periodic
+    };
+    MODE.store(mode as u8, Ordering::Relaxed);
+
+    start_cpu();
+    Ok(mode)
+}
+
+/// Start the periodic tick on this CPU
+pub fn start_cpu() {
+    let vector = crate::interrupts::idt::VEC_APIC_TIMER as u64;
+    unsafe {
+        match mode() {
+            TimerMode::Disabled => {}
+            TimerMode::Periodic => {
+                wrmsr(x2apic_msr(APIC_TIMER_DIV), DIVIDE_BY_16);
+                wrmsr(x2apic_msr(APIC_LVT_TIMER), LVT_PERIODIC | vector);
+                wrmsr(x2apic_msr(APIC_TIMER_INIT), count_for_ms(TICK_MS, counts_per_ms()) as u64);
+            }
+            TimerMode::TscDeadline => {
+                wrmsr(x2apic_msr(APIC_LVT_TIMER), LVT_TSC_DEADLINE | vector);
+                let deadline = rdtsc() + TICK_MS as u64 * tsc_per_ms();
+                NEXT_DEADLINE[cpu_index()].store(deadline, Ordering::Relaxed);
+                wrmsr(IA32_TSC_DEADLINE, deadline);
+            }
+        }
+    }
+}
+
+/// Stop the tick and fire a single interrupt after `ms` milliseconds
+///
+/// The interval is clamped to `max_oneshot_ms`. `start_cpu` resumes the
+/// periodic tick.
+///
+/// # Returns
+/// The interval actually programmed, in milliseconds
+pub fn set_oneshot(ms: u32) -> u32 {
+    let vector = crate::interrupts::idt::VEC_APIC_TIMER as u64;
+    let ms = ms.clamp(1, max_oneshot_ms().max(1));
+    unsafe {
+        match mode() {
+            TimerMode::Disabled => return 0,
+            TimerMode::Periodic => {
+                wrmsr(x2apic_msr(APIC_LVT_TIMER), LVT_ONESHOT | vector);
+                wrmsr(x2apic_msr(APIC_TIMER_INIT), count_for_ms(ms, counts_per_ms()) as u64);
+            }
+            TimerMode::TscDeadline => {
+                NEXT_DEADLINE[cpu_index()].store(0, Ordering::Relaxed);
+                wrmsr(IA32_TSC_DEADLINE, rdtsc() + ms as u64 * tsc_per_ms());
+            }
+        }
+    }
+    ms
+}
+
+/// Milliseconds left before this CPU's one-shot interrupt
+pub fn remaining_ms() -> u32 {
+    match mode() {
+        TimerMode::Disabled => 0,
+        TimerMode::Periodic => {
+            let current = rdmsr(x2apic_msr(APIC_TIMER_CURRENT)) as u32;
+            count_to_ms(current, counts_per_ms())
+        }
+        TimerMode::TscDeadline => {
+            let deadline = rdmsr(IA32_TSC_DEADLINE);
+            (deadline.saturating_sub(rdtsc()) / tsc_per_ms().max(1)) as u32
+        }
+    }
+}
+
+/// Handle a tick on this CPU
+///
+/// Arms the next deadline in TSC-deadline mode. Only the boot CPU keeps
+/// time, so every other CPU's tick leaves the global tick count alone.
+///
+/// # Returns
+/// Whether this tick advances the system tick count
+pub fn on_tick() -> bool {
+    let cpu = cpu_index();
+    super::nmi_watchdog::tick(cpu);
+    if mode() == TimerMode::TscDeadline {
+        let next = &NEXT_DEADLINE[cpu];
+        let deadline = next.load(Ordering::Relaxed);
+        // A deadline of 0 means the tick is stopped and this was a one-shot
+        if deadline != 0 {
+            let period = TICK_MS as u64 * tsc_per_ms();
+            // Skip ticks that were missed rather than firing them back to back
+            let deadline = (deadline + period).max(rdtsc() + period / 2);
+            next.store(deadline, Ordering::Relaxed);
+            unsafe { wrmsr(IA32_TSC_DEADLINE, deadline) };
+        }
+    }
+    cpu == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_per_ms() {
+        // 10ms measured by the PIT as 9999us
+        assert_eq!(rate_per_ms(625_000, 9999), 62_506);
+        assert_eq!(rate_per_ms(1000, 0), 0);
+    }
+
+    #[test]
+    fn test_count_conversions() {
+        assert_eq!(count_for_ms(10, 62_500), 625_000);
+        assert_eq!(count_for_ms(0, 62_500), 1);
+        assert_eq!(count_for_ms(u32::MAX, 62_500), u32::MAX);
+        assert_eq!(count_to_ms(625_000, 62_500), 10);
+        assert_eq!(count_to_ms(625_000, 0), 0);
+    }
+
+    #[test]
+    fn test_uncalibrated() {
+        assert_eq!(mode(), TimerMode::Disabled);
+        assert!(!is_active());
+        assert_eq!(max_oneshot_ms(), 0);
+        assert_eq!(set_oneshot(10), 0);
+    }
+}
@@ -0,0 +1,147 @@
+//! Fault Handler Hooks
+//!
+//! The kernel crate decides what a fault means: a page fault may only
+//! need a page allocated or a CoW page copied, while a fault in user mode
+//! usually ends the task. It registers a handler per fault kind here; the
+//! exception entry points call it before falling back to reporting the
+//! fault and halting.
+//!
+//! This module provides:
+//! - Fault descriptions passed to the handlers
+//! - Handler registration for #PF, #GP and #UD
+//! - Dispatch from the exception entry points
+
+use super::idt::InterruptStackFrame;
+
+/// Faults the kernel can handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Page fault (#PF)
+    PageFault = 0,
+    /// General protection fault (#GP)
+    GeneralProtection = 1,
+    /// Invalid opcode (#UD)
+    InvalidOpcode = 2,
+}
+
+const NUM_FAULT_KINDS: usize = 3;
+
+/// Page fault error code bits
+pub const PF_PRESENT: u64 = 1 << 0;
+pub const PF_WRITE: u64 = 1 << 1;
+pub const PF_USER: u64 = 1 << 2;
+pub const PF_RESERVED: u64 = 1 << 3;
+pub const PF_INSTRUCTION: u64 = 1 << 4;
+
+/// A fault, as seen by a handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInfo {
+    pub kind: FaultKind,
+    /// Error code pushed by the CPU (0 for #UD)
+    pub error_code: u64,
+    /// Faulting address from CR2 (page faults only, 0 otherwise)
+    pub address: u64,
+}
+
+impl FaultInfo {
+    /// Check whether the fault was a write access (page faults only)
+    pub fn is_write(&self) -> bool {
+        self.kind == FaultKind::PageFault && self.error_code & PF_WRITE != 0
+    }
+
+    /// Check whether the page was present, so the fault is a protection
+    /// violation rather than a missing mapping (page faults only)
+    pub fn is_protection_violation(&self) -> bool {
+        self.kind == FaultKind::PageFault && self.error_code & PF_PRESENT != 0
+    }
+}
+
+/// What a handler did with a fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The cause was fixed; retry the faulting instruction
+    Resolved,
+    /// A fatal signal was queued for the current task, which the
+    /// return-to-user path delivers (only valid for user-mode faults)
+    KillTask,
+    /// Not handled; report the fault and halt
+    Unhandled,
+}
+
+/// Fault handler, which may change the saved frame (for instance to
+/// resume at a fixup address)
+pub type FaultHandler = fn(&FaultInfo, &mut InterruptStackFrame) -> FaultResolution;
+
+static mut FAULT_HANDLERS: [Option<FaultHandler>; NUM_FAULT_KINDS] = [None; NUM_FAULT_KINDS];
+
+/// Register the handler for a kind of fault
+///
+/// # Safety
+/// Must be called during init, before the fault can occur. The handler
+/// runs in exception context and must not take locks a faulting context
+/// might hold.
+pub unsafe fn set_fault_handler(kind: FaultKind, handler: FaultHandler) {
+    FAULT_HANDLERS[kind as usize] = Some(handler);
+}
+
+/// Check whether a frame belongs to user mode
+fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+/// Decide whether the exception entry point can return
+///
+/// A task can only be killed by returning to user mode, so `KillTask`
+/// for a kernel-mode fault counts as unhandled.
+fn should_return(resolution: FaultResolution, user: bool) -> bool {
+    match resolution {
+        FaultResolution::Resolved => true,
+        FaultResolution::KillTask => user,
+        FaultResolution::Unhandled => false,
+    }
+}
+
+/// Run the registered handler for a fault
+///
+/// Called by the exception entry points.
+///
+/// # Returns
+/// `true` if the entry point should return to the interrupted context,
+/// `false` if it should report the fault and halt
+pub(super) fn dispatch(info: &FaultInfo, frame: &mut InterruptStackFrame) -> bool {
+    let handler = unsafe { FAULT_HANDLERS[info.kind as usize] };
+    let Some(handler) = handler else {
+        return false;
+    };
+    let user = from_user(frame);
+    let resolution = handler(info, frame);
+    if !should_return(resolution, user) {
+        return false;
+    }
+    if resolution == FaultResolution::KillTask {
+        crate::user_return::irq_exit_to_user(frame);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault_info() {
+        let info = FaultInfo { kind: FaultKind::PageFault, error_code: PF_PRESENT | PF_WRITE | PF_USER, address: 0x1000 };
+        assert!(info.is_write() && info.is_protection_violation());
+
+        let gp = FaultInfo { kind: FaultKind::GeneralProtection, error_code: PF_WRITE, address: 0 };
+        assert!(!gp.is_write());
+    }
+
+    #[test]
+    fn test_should_return() {
+        assert!(should_return(FaultResolution::Resolved, false));
+        assert!(should_return(FaultResolution::KillTask, true));
+        assert!(!should_return(FaultResolution::KillTask, false));
+        assert!(!should_return(FaultResolution::Unhandled, true));
+    }
+}
@@ -5,7 +5,7 @@
 /// specific interrupt vectors.
 
 use crate::interrupts::idt::{InterruptStackFrame, PIC1_OFFSET, PIC2_OFFSET};
-use crate::interrupts::pic;
+use crate::interrupts::{ioapic, pic};
 
 /// Type alias for interrupt handler functions
 pub type InterruptHandler = fn(InterruptStackFrame);
@@ -144,14 +144,22 @@ pub unsafe fn unregister_irq_handler(irq: u8, handler: InterruptHandler) -> Resu
     }
 }
 
-/// Enable an IRQ by unmasking it in the PIC
+/// Enable an IRQ by unmasking it in the IOAPIC, or the PIC before IOAPIC routing
 pub unsafe fn enable_irq(irq: u8) {
-    pic::unmask_irq(irq);
+    if ioapic::is_active() {
+        let _ = ioapic::unmask(ioapic::isa_gsi(irq));
+    } else {
+        pic::unmask_irq(irq);
+    }
 }
 
-/// Disable an IRQ by masking it in the PIC
+/// Disable an IRQ by masking it in the IOAPIC, or the PIC before IOAPIC routing
 pub unsafe fn disable_irq(irq: u8) {
-    pic::mask_irq(irq);
+    if ioapic::is_active() {
+        let _ = ioapic::mask(ioapic::isa_gsi(irq));
+    } else {
+        pic::mask_irq(irq);
+    }
 }
 
 /// Get the number of registered handlers for a vector
@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+use crate::interrupts::fault::{self, FaultInfo, FaultKind};
+use crate::interrupts::nmi_watchdog::NmiKind;
 use crate::interrupts::pic;
 use crate::serial_println;
 
@@ -51,6 +53,12 @@ pub const IRQ_FPU: u8 = 13;
 pub const IRQ_PRIMARY_ATA: u8 = 14;
 pub const IRQ_SECONDARY_ATA: u8 = 15;
 
+// Local APIC vectors
+pub const VEC_APIC_TIMER: u8 = 0xEF;
+pub const VEC_IPI_TLB_FLUSH: u8 = 0xF0;
+pub const VEC_IPI_RESCHEDULE: u8 = 0xF1;
+pub const VEC_APIC_SPURIOUS: u8 = 0xFF;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IdtEntry {
@@ -128,11 +136,31 @@ unsafe fn read_cr2() -> u64 {
     value
 }
 
+/// Frame pointer of the code a handler interrupted
+///
+/// Must be inlined into the handler itself: the handler's frame then
+/// holds the interrupted code's frame pointer.
+#[inline(always)]
+fn interrupted_frame_pointer() -> u64 {
+    unsafe { *(crate::backtrace::frame_pointer() as *const u64) }
+}
+
+/// Print a backtrace of the code an exception interrupted
+///
+/// Must be inlined into the handler itself, like
+/// `interrupted_frame_pointer`.
+#[inline(always)]
+fn report_backtrace(frame: &InterruptStackFrame) {
+    crate::backtrace::report(frame.rip, interrupted_frame_pointer());
+}
+
 // --------- Exception Handlers (x86-interrupt ABI) ---------
 
 extern "x86-interrupt" fn divide_error_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Divide Error (#DE)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -141,22 +169,45 @@ extern "x86-interrupt" fn divide_error_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn debug_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Debug Exception (#DB)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
 }
 
 extern "x86-interrupt" fn nmi_handler(frame: InterruptStackFrame) {
-    serial_println!("[IDT] Non-Maskable Interrupt (NMI)");
-    serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    let _gs = crate::percpu::ParanoidGs::enter();
+    // All counters share the LVT entry, so one NMI may be for several
+    let overflowed = crate::interrupts::pmu::on_nmi();
+    let sampled = crate::interrupts::nmi_sampler::on_nmi(overflowed, &frame, interrupted_frame_pointer());
+    match crate::interrupts::nmi_watchdog::on_nmi(overflowed, frame.rflags) {
+        NmiKind::Watchdog => {}
+        NmiKind::HardLockup => {
+            serial_println!(
+                "[WATCHDOG] Hard lockup on CPU {}: no tick for {}ms with interrupts disabled",
+                crate::gdt::current_cpu_index().unwrap_or(0),
+                crate::interrupts::nmi_watchdog::THRESHOLD_MS
+            );
+            serial_println!("      rip=0x{:x} rsp=0x{:x} rflags=0x{:x}", frame.rip, frame.rsp, frame.rflags);
+            report_backtrace(&frame);
+        }
+        NmiKind::Other if sampled || overflowed != 0 => {}
+        NmiKind::Other => {
+            serial_println!("[IDT] Non-Maskable Interrupt (NMI)");
+            serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+        }
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Breakpoint (#BP)");
 }
 
 extern "x86-interrupt" fn overflow_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Overflow (#OF)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -165,8 +216,10 @@ extern "x86-interrupt" fn overflow_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn bound_range_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Bound Range Exceeded (#BR)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -174,9 +227,15 @@ extern "x86-interrupt" fn bound_range_handler(frame: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(frame: InterruptStackFrame) {
+extern "x86-interrupt" fn invalid_opcode_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
+    let info = FaultInfo { kind: FaultKind::InvalidOpcode, error_code: 0, address: 0 };
+    if fault::dispatch(&info, &mut frame) {
+        return;
+    }
     serial_println!("[IDT] Invalid Opcode (#UD)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -185,8 +244,10 @@ extern "x86-interrupt" fn invalid_opcode_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn device_not_available_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Device Not Available (#NM)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -195,8 +256,10 @@ extern "x86-interrupt" fn device_not_available_handler(frame: InterruptStackFram
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Invalid TSS (#TS) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -205,8 +268,10 @@ extern "x86-interrupt" fn invalid_tss_handler(frame: InterruptStackFrame, error_
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Segment Not Present (#NP) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -215,8 +280,10 @@ extern "x86-interrupt" fn segment_not_present_handler(frame: InterruptStackFrame
 }
 
 extern "x86-interrupt" fn stack_fault_handler(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Stack Fault (#SS) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -224,9 +291,15 @@ extern "x86-interrupt" fn stack_fault_handler(frame: InterruptStackFrame, error_
     }
 }
 
-extern "x86-interrupt" fn gp_fault_handler(frame: InterruptStackFrame, error_code: u64) {
+extern "x86-interrupt" fn gp_fault_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
+    let info = FaultInfo { kind: FaultKind::GeneralProtection, error_code, address: 0 };
+    if fault::dispatch(&info, &mut frame) {
+        return;
+    }
     serial_println!("[IDT] General Protection Fault (#GP) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -235,8 +308,10 @@ extern "x86-interrupt" fn gp_fault_handler(frame: InterruptStackFrame, error_cod
 }
 
 extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error_code: u64) -> ! {
+    let _gs = crate::percpu::ParanoidGs::enter();
     serial_println!("[IDT] Double Fault (#DF) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -244,9 +319,15 @@ extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, error_code: u64) {
+extern "x86-interrupt" fn page_fault_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     let cr2 = unsafe { read_cr2() };
 
+    let info = FaultInfo { kind: FaultKind::PageFault, error_code, address: cr2 };
+    if fault::dispatch(&info, &mut frame) {
+        return;
+    }
+
     serial_println!(
         "[IDT] Page Fault (#PF) ec=0x{:x} cr2=0x{:x}",
         error_code,
@@ -266,6 +347,7 @@ extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, error_c
     );
 
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -274,8 +356,10 @@ extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, error_c
 }
 
 extern "x86-interrupt" fn x87_fpu_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] x87 FPU Exception (#MF)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -284,8 +368,10 @@ extern "x86-interrupt" fn x87_fpu_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn alignment_check_handler(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Alignment Check (#AC) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -294,8 +380,10 @@ extern "x86-interrupt" fn alignment_check_handler(frame: InterruptStackFrame, er
 }
 
 extern "x86-interrupt" fn machine_check_handler(frame: InterruptStackFrame) -> ! {
+    let _gs = crate::percpu::ParanoidGs::enter();
     serial_println!("[IDT] Machine Check (#MC)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -304,8 +392,10 @@ extern "x86-interrupt" fn machine_check_handler(frame: InterruptStackFrame) -> !
 }
 
 extern "x86-interrupt" fn simd_fp_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] SIMD Floating Point (#XM/#XF)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -314,8 +404,10 @@ extern "x86-interrupt" fn simd_fp_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn virtualization_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Virtualization Exception (#VE)");
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -324,8 +416,10 @@ extern "x86-interrupt" fn virtualization_handler(frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn control_protection_handler(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     serial_println!("[IDT] Control Protection Exception (#CP) ec=0x{:x}", error_code);
     serial_println!("      rip=0x{:x} rflags=0x{:x}", frame.rip, frame.rflags);
+    report_backtrace(&frame);
     loop {
         unsafe {
             asm!("cli; hlt");
@@ -337,10 +431,39 @@ extern "x86-interrupt" fn control_protection_handler(frame: InterruptStackFrame,
 
 use core::sync::atomic::{AtomicU64, Ordering};
 
+/// Type alias for the IRQ exit hook
+pub type IrqExitHook = fn(from_user: bool);
+
+/// Optional hook run on the way out of every IRQ and reschedule IPI
+static mut IRQ_EXIT_HOOK: Option<IrqExitHook> = None;
+
+/// Register a hook run at the end of each IRQ and reschedule IPI handler
+///
+/// It runs after the EOI and is told whether the interrupt came from user
+/// mode. It may switch to another task: the interrupted one resumes from
+/// the hook when it is switched back in, then returns through IRETQ.
+///
+/// # Safety
+/// Same requirements as `set_timer_callback`.
+pub unsafe fn set_irq_exit_hook(hook: IrqExitHook) {
+    IRQ_EXIT_HOOK = Some(hook);
+}
+
+/// Common end of the IRQ handlers, after the EOI
+fn irq_exit(frame: &mut InterruptStackFrame) {
+    unsafe {
+        if let Some(hook) = IRQ_EXIT_HOOK {
+            hook(frame.cs & 3 == 3);
+        }
+    }
+    crate::user_return::irq_exit_to_user(frame);
+}
+
 static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
 
-/// Type alias for timer interrupt callback
-pub type TimerCallback = fn();
+/// Type alias for timer interrupt callback; told whether the tick
+/// interrupted user mode
+pub type TimerCallback = fn(from_user: bool);
 
 /// Optional callback to invoke on each timer tick
 static mut TIMER_CALLBACK: Option<TimerCallback> = None;
@@ -356,25 +479,45 @@ pub unsafe fn set_timer_callback(callback: TimerCallback) {
     TIMER_CALLBACK = Some(callback);
 }
 
-extern "x86-interrupt" fn timer_irq_handler(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_irq_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
     
     // Send EOI early to ensure timely interrupt acknowledgment
     // This allows nested timer interrupts if needed
-    unsafe {
-        pic::eoi(IRQ_TIMER);
-    }
+    crate::interrupts::apic::eoi(IRQ_TIMER);
+    
+    run_timer_callback(&frame);
     
+    irq_exit(&mut frame);
+}
+
+fn run_timer_callback(frame: &InterruptStackFrame) {
     // Call registered callback if present
     // Note: Callback should complete quickly to avoid blocking other interrupts
     unsafe {
         if let Some(callback) = TIMER_CALLBACK {
-            callback();
+            callback(frame.cs & 3 == 3);
         }
     }
 }
 
-extern "x86-interrupt" fn keyboard_irq_handler(_frame: InterruptStackFrame) {
+/// Tick from the local APIC timer, taken by every CPU
+extern "x86-interrupt" fn apic_timer_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
+    if crate::interrupts::apic_timer::on_tick() {
+        TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    crate::interrupts::nmi_sampler::on_tick(&frame, interrupted_frame_pointer());
+
+    crate::interrupts::apic::local_eoi();
+    run_timer_callback(&frame);
+
+    irq_exit(&mut frame);
+}
+
+extern "x86-interrupt" fn keyboard_irq_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     // Read scancode from PS/2 data port 0x60
     let kbd = crate::keyboard::keyboard();
     let scancode = kbd.read_scancode();
@@ -384,30 +527,147 @@ extern "x86-interrupt" fn keyboard_irq_handler(_frame: InterruptStackFrame) {
         crate::keyboard::dispatch_event(event, kbd);
     }
     
-    unsafe {
-        pic::eoi(IRQ_KEYBOARD);
-    }
+    crate::interrupts::apic::eoi(IRQ_KEYBOARD);
+    
+    irq_exit(&mut frame);
 }
 
-extern "x86-interrupt" fn mouse_irq_handler(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn mouse_irq_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
     // Read byte from PS/2 data port 0x60
-    let mouse = crate::mouse::mouse();
-    let byte = unsafe { crate::port::inb(0x60) };
-    
-    if let Some(packet) = mouse.process_byte(byte) {
-        // Dispatch to callback if registered
-        crate::mouse::dispatch_packet(packet);
+    if crate::mouse::aux_data_pending() {
+        let mouse = crate::mouse::mouse();
+        let byte = unsafe { crate::port::inb(0x60) };
+
+        if let Some(packet) = mouse.process_byte(byte) {
+            // Dispatch to callback if registered
+            crate::mouse::dispatch_packet(packet);
+        }
     }
     
+    crate::interrupts::apic::eoi(IRQ_PS2_MOUSE);
+    
+    irq_exit(&mut frame);
+}
+
+// --------- Inter-processor interrupts ---------
+
+/// Type alias for the IPI callback, called with the IPI vector
+pub type IpiCallback = fn(u8);
+
+/// Optional callback to invoke on each IPI
+static mut IPI_CALLBACK: Option<IpiCallback> = None;
+
+/// Register a callback to be invoked on each inter-processor interrupt
+///
+/// # Safety
+/// Same requirements as `set_timer_callback`.
+pub unsafe fn set_ipi_callback(callback: IpiCallback) {
+    IPI_CALLBACK = Some(callback);
+}
+
+fn dispatch_ipi(vector: u8) {
     unsafe {
-        pic::eoi(IRQ_PS2_MOUSE);
+        if let Some(callback) = IPI_CALLBACK {
+            callback(vector);
+        }
     }
+    // IPIs always come from the local APIC, never the PIC
+    crate::interrupts::apic::local_eoi();
 }
 
-// Generic spurious IRQ handler
-extern "x86-interrupt" fn spurious_irq_handler(_frame: InterruptStackFrame) {
-    serial_println!("[IRQ] Spurious interrupt detected");
-    // Note: Don't send EOI for spurious interrupts from PIC
+extern "x86-interrupt" fn tlb_flush_ipi_handler(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
+    dispatch_ipi(VEC_IPI_TLB_FLUSH);
+}
+
+extern "x86-interrupt" fn reschedule_ipi_handler(mut frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(frame.cs);
+    dispatch_ipi(VEC_IPI_RESCHEDULE);
+    irq_exit(&mut frame);
+}
+
+// Local APIC spurious interrupts need no EOI
+extern "x86-interrupt" fn apic_spurious_handler(_frame: InterruptStackFrame) {}
+
+// --------- Device IRQs ---------
+
+/// Type alias for the device IRQ callback, called with the IRQ line
+pub type IrqCallback = fn(u8);
+
+/// Optional callback to invoke on each device IRQ
+static mut IRQ_CALLBACK: Option<IrqCallback> = None;
+
+/// Register a callback to be invoked on each device interrupt
+///
+/// Device IRQs are the ISA lines without a built-in handler (all but the
+/// timer, keyboard, cascade and mouse lines).
+///
+/// # Safety
+/// Same requirements as `set_timer_callback`.
+pub unsafe fn set_irq_callback(callback: IrqCallback) {
+    IRQ_CALLBACK = Some(callback);
+}
+
+fn dispatch_device_irq(irq: u8, frame: &mut InterruptStackFrame) {
+    // The PIC raises IRQ 7 or 15 for interrupts that went away; no EOI,
+    // except to the master for a spurious IRQ from the slave
+    if (irq == IRQ_LPT1 || irq == IRQ_SECONDARY_ATA)
+        && !crate::interrupts::ioapic::is_active()
+        && unsafe { pic::is_spurious(irq) }
+    {
+        serial_println!("[IRQ] Spurious interrupt detected");
+        if irq == IRQ_SECONDARY_ATA {
+            unsafe { pic::eoi(IRQ_CASCADE) };
+        }
+        return;
+    }
+
+    unsafe {
+        crate::interrupts::handlers::dispatch_handlers(PIC1_OFFSET + irq, *frame);
+        if let Some(callback) = IRQ_CALLBACK {
+            callback(irq);
+        }
+    }
+    // After the handlers, so a level-triggered line was quiesced first
+    crate::interrupts::apic::eoi(irq);
+
+    irq_exit(frame);
+}
+
+macro_rules! device_irq_handlers {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(mut frame: InterruptStackFrame) {
+                let _gs = crate::percpu::KernelGs::enter(frame.cs);
+                dispatch_device_irq($irq, &mut frame);
+            }
+        )*
+
+        /// Entry points of the device IRQ lines
+        const DEVICE_IRQ_HANDLERS: &[(u8, extern "x86-interrupt" fn(InterruptStackFrame))] =
+            &[$(($irq, $name)),*];
+    };
+}
+
+device_irq_handlers! {
+    irq3_handler => IRQ_COM2,
+    irq4_handler => IRQ_COM1,
+    irq5_handler => IRQ_LPT2,
+    irq6_handler => IRQ_FLOPPY,
+    irq7_handler => IRQ_LPT1,
+    irq8_handler => IRQ_RTC,
+    irq9_handler => IRQ_FREE1,
+    irq10_handler => IRQ_FREE2,
+    irq11_handler => IRQ_FREE3,
+    irq13_handler => IRQ_FPU,
+    irq14_handler => IRQ_PRIMARY_ATA,
+    irq15_handler => IRQ_SECONDARY_ATA,
+}
+
+/// Check whether an IRQ line is a device IRQ (see `set_irq_callback`)
+pub fn is_device_irq(irq: u8) -> bool {
+    DEVICE_IRQ_HANDLERS.iter().any(|&(line, _)| line == irq)
 }
 
 // --------- Public init ---------
@@ -430,7 +690,7 @@ pub fn init() {
         // CPU Exceptions (0-21)
         (*idt_ptr)[VEC_DIVIDE_ERROR as usize].set_handler(divide_error_handler as u64);
         (*idt_ptr)[VEC_DEBUG as usize].set_handler(debug_handler as u64);
-        (*idt_ptr)[VEC_NMI as usize].set_handler(nmi_handler as u64);
+        (*idt_ptr)[VEC_NMI as usize].set_handler_with_ist(nmi_handler as u64, crate::gdt::NMI_IST_INDEX);
         (*idt_ptr)[VEC_BREAKPOINT as usize].set_handler(breakpoint_handler as u64);
         (*idt_ptr)[VEC_OVERFLOW as usize].set_handler(overflow_handler as u64);
         (*idt_ptr)[VEC_BOUND_RANGE as usize].set_handler(bound_range_handler as u64);
@@ -447,7 +707,10 @@ pub fn init() {
         (*idt_ptr)[VEC_PAGE_FAULT as usize].set_handler(page_fault_handler as u64);
         (*idt_ptr)[VEC_X87_FPU as usize].set_handler(x87_fpu_handler as u64);
         (*idt_ptr)[VEC_ALIGNMENT_CHECK as usize].set_handler(alignment_check_handler as u64);
-        (*idt_ptr)[VEC_MACHINE_CHECK as usize].set_handler(machine_check_handler as u64);
+        (*idt_ptr)[VEC_MACHINE_CHECK as usize].set_handler_with_ist(
+            machine_check_handler as u64,
+            crate::gdt::MACHINE_CHECK_IST_INDEX,
+        );
         (*idt_ptr)[VEC_SIMD_FP as usize].set_handler(simd_fp_handler as u64);
         (*idt_ptr)[VEC_VIRTUALIZATION as usize].set_handler(virtualization_handler as u64);
         (*idt_ptr)[VEC_CONTROL_PROTECTION as usize].set_handler(control_protection_handler as u64);
@@ -455,8 +718,9 @@ pub fn init() {
         // PIC remap + enable timer/keyboard only
         pic::remap(PIC1_OFFSET, PIC2_OFFSET);
         // Mask bits: 1 = masked(disabled). Enable IRQ0, IRQ1, IRQ12 (mouse) => mask others.
-        // IRQ12 is on PIC2, so we unmask bit 4 (12-8=4) on PIC2
-        pic::set_masks(0b1111_1100, 0b1110_1111);
+        // IRQ12 is on PIC2, so we unmask bit 4 (12-8=4) on PIC2 and the
+        // cascade (IRQ2) on PIC1 that PIC2 signals through
+        pic::set_masks(0b1111_1000, 0b1110_1111);
         
         // Initialize PIT timer
         crate::interrupts::pit::init(crate::interrupts::pit::PIT_DEFAULT_FREQ);
@@ -467,9 +731,16 @@ pub fn init() {
         (*idt_ptr)[(PIC1_OFFSET + IRQ_KEYBOARD) as usize].set_handler(keyboard_irq_handler as u64);
         (*idt_ptr)[(PIC2_OFFSET + IRQ_PS2_MOUSE - 8) as usize].set_handler(mouse_irq_handler as u64);
         
-        // Set spurious IRQ handler for PIC1 IRQ7 and PIC2 IRQ15
-        (*idt_ptr)[(PIC1_OFFSET + 7) as usize].set_handler(spurious_irq_handler as u64);
-        (*idt_ptr)[(PIC2_OFFSET + 15) as usize].set_handler(spurious_irq_handler as u64);
+        // Device IRQs, also taking the PIC's spurious IRQ7 and IRQ15
+        for &(irq, handler) in DEVICE_IRQ_HANDLERS {
+            (*idt_ptr)[(PIC1_OFFSET + irq) as usize].set_handler(handler as u64);
+        }
+
+        // Local APIC vectors
+        (*idt_ptr)[VEC_APIC_TIMER as usize].set_handler(apic_timer_handler as u64);
+        (*idt_ptr)[VEC_IPI_TLB_FLUSH as usize].set_handler(tlb_flush_ipi_handler as u64);
+        (*idt_ptr)[VEC_IPI_RESCHEDULE as usize].set_handler(reschedule_ipi_handler as u64);
+        (*idt_ptr)[VEC_APIC_SPURIOUS as usize].set_handler(apic_spurious_handler as u64);
 
         let idtr = Idtr {
             limit: (core::mem::size_of::<[IdtEntry; IDT_LEN]>() - 1) as u16,
@@ -482,14 +753,46 @@ pub fn init() {
     serial_println!("[IDT] loaded with {} exception handlers ✅", 16);
 }
 
+/// Load the already populated IDT on an application processor
+pub fn load() {
+    unsafe {
+        let idtr = Idtr {
+            limit: (core::mem::size_of::<[IdtEntry; IDT_LEN]>() - 1) as u16,
+            base: &raw const IDT as u64,
+        };
+        lidt(&idtr);
+    }
+}
+
+/// Move the system tick from the PIT to the local APIC timers
+///
+/// Calibrates the APIC timer against the PIT, starts it on this (the
+/// boot) CPU and masks the PIT interrupt. The PIT stays programmed so it
+/// can serve as a reference again.
+pub fn enable_apic_tick() -> Result<crate::interrupts::apic_timer::TimerMode, &'static str> {
+    crate::interrupts::without_interrupts(|| unsafe {
+        let mode = crate::interrupts::apic_timer::init()?;
+        pic::mask_irq(IRQ_TIMER);
+        serial_println!("[APIC] Timer: {} counts/ms, {:?} tick", crate::interrupts::apic_timer::counts_per_ms(), mode);
+        Ok(mode)
+    })
+}
+
 /// Get the current timer tick count
 pub fn timer_ticks() -> u64 {
     TIMER_TICKS.load(Ordering::Relaxed)
 }
 
+/// Account for timer ticks that were skipped while the tick was stopped
+///
+/// Used by tickless idle after the timer ran in one-shot mode.
+pub fn advance_ticks(ticks: u64) {
+    TIMER_TICKS.fetch_add(ticks, Ordering::Relaxed);
+}
+
 /// Get system uptime in milliseconds
 /// 
-/// Based on the configured PIT frequency (100 Hz = 10ms per tick)
+/// Based on the 100 Hz tick (10ms per tick) of the PIT or APIC timer
 pub fn uptime_ms() -> u64 {
     let ticks = timer_ticks();
     // With 100 Hz timer, each tick is 10ms
@@ -0,0 +1,299 @@
+/// I/O Advanced Programmable Interrupt Controller (IOAPIC)
+///
+/// Each IOAPIC turns a range of interrupt lines, numbered by global system
+/// interrupt (GSI), into messages to local APICs. Unlike the 8259 PIC it
+/// supports level-triggered and active-low lines, which PCI devices use.
+/// Each line has a redirection entry selecting its vector, trigger mode,
+/// polarity and destination CPU.
+///
+/// The registers are reached through an index/data window at the
+/// IOAPIC's MMIO base, which the kernel maps before registering it. Once
+/// `activate` runs the 8259 PIC is fully masked and device interrupts are
+/// acknowledged at the local APIC.
+///
+/// This module provides:
+/// - Redirection entry encoding
+/// - Register access for each IOAPIC
+/// - Routing, masking and unmasking by GSI
+/// - Saving and restoring the redirection entries across S3
+/// - The ISA IRQ to GSI map
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+
+/// Maximum number of IOAPICs supported
+pub const MAX_IOAPICS: usize = 8;
+
+/// Register select (index) and data window offsets
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+/// IOAPIC registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// Redirection entry fields
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+
+/// A redirection table entry (fixed delivery, physical destination)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    /// Vector delivered to the CPU
+    pub vector: u8,
+    /// APIC ID of the destination CPU
+    pub dest: u8,
+    /// Level-triggered (true) or edge-triggered (false)
+    pub level: bool,
+    /// Active-low (true) or active-high (false)
+    pub active_low: bool,
+    /// Whether the line is masked
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    /// Entry for an ISA interrupt: edge-triggered, active-high
+    pub const fn isa(vector: u8, dest: u8) -> Self {
+        Self { vector, dest, level: false, active_low: false, masked: false }
+    }
+
+    /// Entry for a PCI interrupt pin: level-triggered, active-low
+    pub const fn pci(vector: u8, dest: u8) -> Self {
+        Self { vector, dest, level: true, active_low: true, masked: false }
+    }
+
+    /// Encode as the 64-bit register value
+    pub fn encode(&self) -> u64 {
+        let mut value = self.vector as u64 | (self.dest as u64) << REDIR_DEST_SHIFT;
+        if self.active_low {
+            value |= REDIR_ACTIVE_LOW;
+        }
+        if self.level {
+            value |= REDIR_LEVEL;
+        }
+        if self.masked {
+            value |= REDIR_MASKED;
+        }
+        value
+    }
+}
+
+/// One IOAPIC
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    /// Virtual address of the mapped registers
+    base: u64,
+    /// IOAPIC ID from the MADT
+    id: u8,
+    /// First GSI handled by this IOAPIC
+    gsi_base: u32,
+    /// Number of redirection entries
+    entries: u32,
+}
+
+impl IoApic {
+    /// Set up an IOAPIC whose registers are mapped at `base`
+    ///
+    /// # Safety
+    /// `base` must map the IOAPIC's registers uncached.
+    pub unsafe fn new(base: u64, id: u8, gsi_base: u32) -> Self {
+        let mut ioapic = Self { base, id, gsi_base, entries: 0 };
+        // Bits 16-23 of the version register hold the last entry's index
+        ioapic.entries = ((ioapic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    /// IOAPIC ID
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// GSIs handled by this IOAPIC
+    pub fn gsi_range(&self) -> core::ops::Range<u32> {
+        self.gsi_base..self.gsi_base + self.entries
+    }
+
+    /// Read the redirection entry of pin `pin`
+    pub fn entry(&self, pin: u32) -> u64 {
+        unsafe {
+            let low = self.read(IOREDTBL + pin * 2) as u64;
+            let high = self.read(IOREDTBL + pin * 2 + 1) as u64;
+            high << 32 | low
+        }
+    }
+
+    /// Write the redirection entry of pin `pin`
+    ///
+    /// The entry is masked while being rewritten so no interrupt is sent
+    /// with half of it updated.
+    pub fn set_entry(&self, pin: u32, value: u64) {
+        unsafe {
+            self.write(IOREDTBL + pin * 2, REDIR_MASKED as u32);
+            self.write(IOREDTBL + pin * 2 + 1, (value >> 32) as u32);
+            self.write(IOREDTBL + pin * 2, value as u32);
+        }
+    }
+
+    /// Mask every pin
+    pub fn mask_all(&self) {
+        for pin in 0..self.entries {
+            let entry = self.entry(pin);
+            self.set_entry(pin, entry | REDIR_MASKED);
+        }
+    }
+}
+
+static IOAPICS: Mutex<[Option<IoApic>; MAX_IOAPICS]> = Mutex::new([None; MAX_IOAPICS]);
+
+/// Whether device interrupts arrive through the IOAPICs
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// GSI each ISA IRQ is connected to; identity unless the MADT overrides it
+static ISA_GSI: [AtomicU32; 16] = {
+    let mut map = [const { AtomicU32::new(0) }; 16];
+    let mut irq = 0;
+    while irq < 16 {
+        map[irq] = AtomicU32::new(irq as u32);
+        irq += 1;
+    }
+    map
+};
+
+/// Record that ISA IRQ `irq` is connected to `gsi`
+pub fn set_isa_gsi(irq: u8, gsi: u32) {
+    if let Some(slot) = ISA_GSI.get(irq as usize) {
+        slot.store(gsi, Ordering::Relaxed);
+    }
+}
+
+/// GSI an ISA IRQ is connected to
+pub fn isa_gsi(irq: u8) -> u32 {
+    ISA_GSI.get(irq as usize).map_or(irq as u32, |slot| slot.load(Ordering::Relaxed))
+}
+
+/// Register an IOAPIC, masking all its pins
+pub fn register(ioapic: IoApic) -> Result<(), &'static str> {
+    let mut ioapics = IOAPICS.lock();
+    let slot = ioapics.iter_mut().find(|slot| slot.is_none()).ok_or("Too many IOAPICs")?;
+    ioapic.mask_all();
+    *slot = Some(ioapic);
+    Ok(())
+}
+
+/// Run `f` on the IOAPIC and pin handling `gsi`
+fn with_pin<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, &'static str> {
+    let ioapics = IOAPICS.lock();
+    let ioapic = ioapics
+        .iter()
+        .flatten()
+        .find(|ioapic| ioapic.gsi_range().contains(&gsi))
+        .ok_or("No IOAPIC handles this GSI")?;
+    Ok(f(ioapic, gsi - ioapic.gsi_base))
+}
+
+/// Program the redirection entry of a GSI
+pub fn route(gsi: u32, entry: RedirectionEntry) -> Result<(), &'static str> {
+    with_pin(gsi, |ioapic, pin| ioapic.set_entry(pin, entry.encode()))
+}
+
+/// Mask a GSI
+pub fn mask(gsi: u32) -> Result<(), &'static str> {
+    with_pin(gsi, |ioapic, pin| ioapic.set_entry(pin, ioapic.entry(pin) | REDIR_MASKED))
+}
+
+/// Unmask a GSI
+pub fn unmask(gsi: u32) -> Result<(), &'static str> {
+    with_pin(gsi, |ioapic, pin| ioapic.set_entry(pin, ioapic.entry(pin) & !REDIR_MASKED))
+}
+
+/// Pass the redirection entry of every pin to `save`, IOAPIC by IOAPIC
+///
+/// The IOAPICs lose their entries in S3; `restore_entries` writes them
+/// back in the same order.
+pub fn save_entries(mut save: impl FnMut(u64)) {
+    for ioapic in IOAPICS.lock().iter().flatten() {
+        for pin in 0..ioapic.entries {
+            save(ioapic.entry(pin));
+        }
+    }
+}
+
+/// Write back entries passed out by `save_entries`
+pub fn restore_entries(mut saved: impl Iterator<Item = u64>) {
+    for ioapic in IOAPICS.lock().iter().flatten() {
+        for pin in 0..ioapic.entries {
+            let Some(entry) = saved.next() else {
+                return;
+            };
+            ioapic.set_entry(pin, entry);
+        }
+    }
+}
+
+/// Switch device interrupts from the 8259 PIC to the IOAPICs
+///
+/// Masks every PIC line; the PIC stays remapped so a stray interrupt
+/// still lands on a known vector. Interrupts are then acknowledged at the
+/// local APIC, which is only reachable in x2APIC mode.
+pub fn activate() -> Result<(), &'static str> {
+    if !super::apic::ipis_available() {
+        return Err("IOAPIC routing needs x2APIC mode");
+    }
+    if IOAPICS.lock().iter().all(|slot| slot.is_none()) {
+        return Err("No IOAPIC registered");
+    }
+    unsafe { super::pic::set_masks(0xFF, 0xFF) };
+    ACTIVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Check whether device interrupts arrive through the IOAPICs
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirection_entry_encode() {
+        assert_eq!(RedirectionEntry::isa(0x21, 0).encode(), 0x21);
+
+        let pci = RedirectionEntry::pci(0x30, 2).encode();
+        assert_eq!(pci & 0xFF, 0x30);
+        assert_eq!(pci >> 56, 2);
+        assert_ne!(pci & REDIR_LEVEL, 0);
+        assert_ne!(pci & REDIR_ACTIVE_LOW, 0);
+
+        let masked = RedirectionEntry { masked: true, ..RedirectionEntry::isa(0x20, 0) };
+        assert_ne!(masked.encode() & REDIR_MASKED, 0);
+    }
+
+    #[test]
+    fn test_isa_gsi_map() {
+        assert_eq!(isa_gsi(1), 1);
+        set_isa_gsi(0, 2);
+        assert_eq!(isa_gsi(0), 2);
+        set_isa_gsi(0, 0);
+        assert_eq!(isa_gsi(42), 42);
+    }
+
+    #[test]
+    fn test_routing_needs_ioapic() {
+        assert!(route(1, RedirectionEntry::isa(0x21, 0)).is_err());
+        assert!(activate().is_err());
+        assert!(!is_active());
+    }
+}
@@ -1,5 +1,59 @@
 pub mod apic;
+pub mod apic_timer;
+pub mod fault;
 pub mod handlers;
 pub mod idt;
+pub mod ioapic;
+pub mod nmi_sampler;
+pub mod nmi_watchdog;
 pub mod pic;
 pub mod pit;
+pub mod pmu;
+
+/// RFLAGS interrupt enable flag
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Enable interrupts on the current CPU
+#[inline]
+pub fn enable() {
+    unsafe {
+        core::arch::asm!("sti", options(nomem, nostack));
+    }
+}
+
+/// Disable interrupts on the current CPU
+#[inline]
+pub fn disable() {
+    unsafe {
+        core::arch::asm!("cli", options(nomem, nostack));
+    }
+}
+
+/// Check whether interrupts are enabled on the current CPU
+#[inline]
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Run a closure with interrupts disabled, restoring the previous state afterwards
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let was_enabled = are_enabled();
+    if was_enabled {
+        disable();
+    }
+
+    let result = f();
+
+    if was_enabled {
+        enable();
+    }
+    result
+}
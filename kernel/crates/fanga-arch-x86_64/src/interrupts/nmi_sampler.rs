@@ -0,0 +1,237 @@
+//! NMI sampling
+//!
+//! Finds out where the CPUs spend their time, for the profiler.
+//! The sampler counter (`pmu::SAMPLER_COUNTER`) of every CPU counts
+//! unhalted core cycles, next to the watchdog's, and raises an NMI through
+//! the same local APIC LVT entry each time it overflows. NMIs are taken even where interrupts
+//! are disabled, so no code hides from the samples. Each sample holds the
+//! interrupted RIP and stack pointer and a short frame pointer backtrace,
+//! and is handed to the hook the kernel registered.
+//!
+//! Without a free counter, samples come from the local APIC timer tick
+//! instead: at the tick rate, and only where interrupts are enabled.
+//!
+//! `start` and `stop` take effect on the calling CPU at once and on the
+//! others at their next tick. The hook runs in NMI context: it must not
+//! take locks or allocate.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use super::apic::ipis_available;
+use super::idt::InterruptStackFrame;
+use super::pmu::{self, Event, SAMPLER_COUNTER};
+use crate::gdt::MAX_CPUS;
+
+/// Return addresses kept per sample
+pub const SAMPLE_DEPTH: usize = 16;
+
+/// Where samples come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Not sampling
+    Off,
+    /// NMIs from the sampler counter
+    Counter,
+    /// The local APIC timer tick
+    Tick,
+}
+
+impl Mode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Mode::Counter,
+            2 => Mode::Tick,
+            _ => Mode::Off,
+        }
+    }
+}
+
+/// Where a CPU was interrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub rip: u64,
+    pub rsp: u64,
+    /// The CPU was running user code
+    pub user: bool,
+    /// Return addresses, innermost first; only the first `depth` are valid
+    pub frames: [u64; SAMPLE_DEPTH],
+    pub depth: usize,
+}
+
+impl Sample {
+    /// The return addresses
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.depth]
+    }
+}
+
+/// Takes a sample; runs in NMI or interrupt context
+pub type SampleHook = fn(&Sample);
+
+static mut HOOK: Option<SampleHook> = None;
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+
+/// Bumped by every `start` and `stop`
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Generation each CPU last applied
+static APPLIED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether each CPU's counter is running
+static RUNNING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Event select of the cycles event
+static SELECT: AtomicU64 = AtomicU64::new(0);
+
+/// Cycles between samples
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+fn cpu_index() -> usize {
+    crate::gdt::current_cpu_index().unwrap_or(0)
+}
+
+/// Register the function that takes samples
+///
+/// # Safety
+/// Must be called during init, before sampling starts.
+pub unsafe fn set_hook(hook: SampleHook) {
+    HOOK = Some(hook);
+}
+
+/// Find the sampler counter and the event select that counts cycles on it
+///
+/// # Returns
+/// The event select and the longest period, if usable
+fn detect_counter() -> Option<(u64, u64)> {
+    let pmu = pmu::info().filter(|pmu| pmu.counters as usize > SAMPLER_COUNTER)?;
+    Some((pmu.event_select(Event::Cycles)?, pmu.max_period()))
+}
+
+/// Counter period for `frequency_hz` samples per second of busy time, at
+/// most `max`
+fn period_cycles(cycles_per_ms: u64, frequency_hz: u32, max: u64) -> u64 {
+    (cycles_per_ms.saturating_mul(1000) / frequency_hz.max(1) as u64).clamp(1, max)
+}
+
+/// Start sampling about `frequency_hz` times per second of busy time
+///
+/// Uses the performance counter if the CPU has one free and x2APIC mode
+/// is on, the timer tick otherwise.
+pub fn start(frequency_hz: u32) -> Mode {
+    let cycles_per_ms = super::apic_timer::tsc_per_ms();
+    let mode = match detect_counter() {
+        Some((select, max_period)) if ipis_available() && cycles_per_ms > 0 => {
+            SELECT.store(select, Ordering::Relaxed);
+            PERIOD.store(period_cycles(cycles_per_ms, frequency_hz, max_period), Ordering::Relaxed);
+            Mode::Counter
+        }
+        _ => Mode::Tick,
+    };
+    MODE.store(mode as u8, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+    sync_cpu();
+    mode
+}
+
+/// Stop sampling
+pub fn stop() {
+    MODE.store(Mode::Off as u8, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+    sync_cpu();
+}
+
+/// Where samples come from now
+pub fn mode() -> Mode {
+    Mode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+/// Start or stop this CPU's counter to match the mode, if it has not yet
+fn sync_cpu() {
+    let cpu = cpu_index();
+    let generation = GENERATION.load(Ordering::Acquire);
+    if APPLIED[cpu].swap(generation, Ordering::Relaxed) == generation {
+        return;
+    }
+    let run = mode() == Mode::Counter;
+    if RUNNING[cpu].load(Ordering::Relaxed) == run {
+        return;
+    }
+    let running = if run {
+        let select = SELECT.load(Ordering::Relaxed);
+        let period = PERIOD.load(Ordering::Relaxed);
+        unsafe { pmu::start(SAMPLER_COUNTER, select, Some(period)) }.is_ok()
+    } else {
+        pmu::stop(SAMPLER_COUNTER);
+        false
+    };
+    RUNNING[cpu].store(running, Ordering::Relaxed);
+}
+
+/// Sample code interrupted at `frame`, whose frame pointer was `rbp`
+fn take_sample(frame: &InterruptStackFrame, rbp: u64) {
+    let Some(hook) = (unsafe { HOOK }) else {
+        return;
+    };
+    let mut sample = Sample {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        user: frame.cs & 3 != 0,
+        frames: [0; SAMPLE_DEPTH],
+        depth: 0,
+    };
+    if !sample.user {
+        sample.depth = crate::backtrace::walk_stack(rbp, frame.rsp, &mut sample.frames);
+    }
+    hook(&sample);
+}
+
+/// Take a sample if this CPU's counter raised the NMI
+///
+/// `overflowed` is what `pmu::on_nmi` returned for this NMI, which has
+/// already re-armed the counter.
+///
+/// # Returns
+/// Whether the NMI was a sample
+pub fn on_nmi(overflowed: u32, frame: &InterruptStackFrame, rbp: u64) -> bool {
+    if !RUNNING[cpu_index()].load(Ordering::Relaxed) || overflowed & (1 << SAMPLER_COUNTER) == 0 {
+        return false;
+    }
+    take_sample(frame, rbp);
+    true
+}
+
+/// Catch up with `start` and `stop`, and sample in tick mode
+///
+/// Called from the local APIC timer handler.
+pub fn on_tick(frame: &InterruptStackFrame, rbp: u64) {
+    sync_cpu();
+    if mode() == Mode::Tick {
+        take_sample(frame, rbp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_cycles() {
+        // 2 GHz at 1 kHz: two million cycles between samples
+        let max = i32::MAX as u64;
+        assert_eq!(period_cycles(2_000_000, 1_000, max), 2_000_000);
+        // At most one sample a second, and no period past the counter
+        assert_eq!(period_cycles(2_000_000, 0, max), 2_000_000_000);
+        assert_eq!(period_cycles(4_000_000, 1, max), max);
+        assert_eq!(period_cycles(0, 1_000, max), 1);
+    }
+
+    #[test]
+    fn test_sample_frames() {
+        let mut sample = Sample { rip: 0x1000, rsp: 0, user: false, frames: [0; SAMPLE_DEPTH], depth: 2 };
+        sample.frames[..3].copy_from_slice(&[0x2000, 0x3000, 0x4000]);
+        assert_eq!(sample.frames(), &[0x2000, 0x3000]);
+        assert_eq!(Mode::from_u8(Mode::Tick as u8), Mode::Tick);
+    }
+}
@@ -0,0 +1,230 @@
+//! NMI watchdog
+//!
+//! Detects hard lockups: a CPU spinning with interrupts disabled never
+//! takes its timer tick again, and nothing maskable can get its attention.
+//! The watchdog counter (`pmu::WATCHDOG_COUNTER`) of every CPU counts
+//! unhalted core cycles and raises an NMI through the local APIC's
+//! performance counter LVT entry each time it overflows. The NMI handler compares the CPU's tick count
+//! with the one it saw last; once the tick has been stuck for
+//! `THRESHOLD_MS` worth of busy cycles while the CPU ran with interrupts
+//! disabled, the CPU's registers and backtrace are printed.
+//!
+//! Halted CPUs do not count cycles and so take no NMIs, which keeps idle
+//! and tickless CPUs from looking stuck.
+//!
+//! This module provides:
+//! - Per-CPU counter setup through the `pmu` module
+//! - The per-CPU tick heartbeat
+//! - Lockup detection for the NMI handler
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use super::apic::ipis_available;
+use super::pmu::{self, Event, WATCHDOG_COUNTER};
+use crate::gdt::MAX_CPUS;
+
+/// Busy time without a tick, with interrupts disabled, reported as a lockup
+pub const THRESHOLD_MS: u64 = 10_000;
+
+/// Interval between watchdog NMIs, in milliseconds of busy cycles
+pub const PERIOD_MS: u64 = 1_000;
+
+/// What an NMI turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmiKind {
+    /// Not raised by the watchdog
+    Other,
+    /// A watchdog check that found the CPU making progress
+    Watchdog,
+    /// A watchdog check that found this CPU locked up
+    HardLockup,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Event select of the cycles event
+static SELECT: AtomicU64 = AtomicU64::new(0);
+
+/// Cycles between NMIs
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// NMIs without a tick after which a CPU counts as locked up
+static STALL_LIMIT: AtomicU32 = AtomicU32::new(0);
+
+/// Ticks taken by each CPU
+static TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Tick count each CPU's last watchdog NMI saw
+static LAST_SEEN: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Consecutive watchdog NMIs each CPU spent without a tick
+static STALLS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+fn cpu_index() -> usize {
+    crate::gdt::current_cpu_index().unwrap_or(0)
+}
+
+/// Counter period for `period_ms` of busy time, at most `max`
+fn period_cycles(cycles_per_ms: u64, period_ms: u64, max: u64) -> u64 {
+    cycles_per_ms.saturating_mul(period_ms).clamp(1, max)
+}
+
+/// Number of NMIs `period` cycles apart that cover `threshold_ms`
+fn stall_limit(cycles_per_ms: u64, threshold_ms: u64, period: u64) -> u32 {
+    let threshold = cycles_per_ms.saturating_mul(threshold_ms);
+    threshold.div_ceil(period.max(1)).clamp(1, u32::MAX as u64) as u32
+}
+
+/// Compare a CPU's tick count with the last one seen
+///
+/// A CPU that took a tick, or was interrupted with interrupts enabled,
+/// is making progress. Otherwise its stall count grows, and reaching
+/// `limit` reports it once.
+fn check(cpu: usize, ticks: u64, interrupts_enabled: bool, limit: u32) -> bool {
+    let stalls = &STALLS[cpu];
+    if LAST_SEEN[cpu].swap(ticks, Ordering::Relaxed) != ticks || interrupts_enabled {
+        stalls.store(0, Ordering::Relaxed);
+        return false;
+    }
+    stalls.fetch_add(1, Ordering::Relaxed) + 1 == limit
+}
+
+/// Set up the watchdog from the boot CPU and start it there
+///
+/// Needs x2APIC mode and a calibrated TSC (`apic_timer::init`), whose rate
+/// stands in for the core clock.
+pub fn init() -> Result<(), &'static str> {
+    if !ipis_available() {
+        return Err("NMI watchdog needs x2APIC mode");
+    }
+    let pmu = pmu::info().filter(|pmu| pmu.counters as usize > WATCHDOG_COUNTER);
+    let select = pmu
+        .and_then(|pmu| pmu.event_select(Event::Cycles))
+        .ok_or("no PMU cycle counter")?;
+    let cycles_per_ms = super::apic_timer::tsc_per_ms();
+    if cycles_per_ms == 0 {
+        return Err("TSC rate unknown");
+    }
+
+    let max_period = pmu.map_or(1, |pmu| pmu.max_period());
+    let period = period_cycles(cycles_per_ms, PERIOD_MS, max_period);
+    SELECT.store(select, Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    STALL_LIMIT.store(stall_limit(cycles_per_ms, THRESHOLD_MS, period), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+
+    start_cpu();
+    Ok(())
+}
+
+/// Start the watchdog counter on this CPU
+///
+/// Does nothing until `init` enabled the watchdog.
+pub fn start_cpu() {
+    if !is_enabled() {
+        return;
+    }
+    let cpu = cpu_index();
+    STALLS[cpu].store(0, Ordering::Relaxed);
+    LAST_SEEN[cpu].store(TICKS[cpu].load(Ordering::Relaxed), Ordering::Relaxed);
+    let select = SELECT.load(Ordering::Relaxed);
+    let period = PERIOD.load(Ordering::Relaxed);
+    // init found the PMU and x2APIC mode this needs
+    let _ = unsafe { pmu::start(WATCHDOG_COUNTER, select, Some(period)) };
+}
+
+/// Stop the watchdog counter on this CPU
+pub fn stop_cpu() {
+    if is_enabled() {
+        pmu::stop(WATCHDOG_COUNTER);
+    }
+}
+
+/// Check if the watchdog was started
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Record a timer tick on this CPU
+///
+/// Called from the tick handler.
+pub fn tick(cpu: usize) {
+    if let Some(ticks) = TICKS.get(cpu) {
+        ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tell the watchdog this CPU is making progress with interrupts disabled
+///
+/// For code that legitimately spins with interrupts off for long, such as
+/// the kernel debugger waiting for input.
+pub fn touch() {
+    STALLS[cpu_index()].store(0, Ordering::Relaxed);
+}
+
+/// Handle an NMI if the watchdog counter raised it
+///
+/// `overflowed` is what `pmu::on_nmi` returned for this NMI, which has
+/// already re-armed the counter; `rflags` is the interrupted context's
+/// RFLAGS.
+pub fn on_nmi(overflowed: u32, rflags: u64) -> NmiKind {
+    if !is_enabled() || overflowed & (1 << WATCHDOG_COUNTER) == 0 {
+        return NmiKind::Other;
+    }
+
+    let cpu = cpu_index();
+    let ticks = TICKS[cpu].load(Ordering::Relaxed);
+    let interrupts_enabled = rflags & super::RFLAGS_IF != 0;
+    if check(cpu, ticks, interrupts_enabled, STALL_LIMIT.load(Ordering::Relaxed)) {
+        NmiKind::HardLockup
+    } else {
+        NmiKind::Watchdog
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_and_limit() {
+        // Intel counter writes only set the low 32 bits and sign-extend bit 31
+        let max = i32::MAX as u64;
+
+        // 2 GHz: one second of cycles fits a 32-bit counter write
+        assert_eq!(period_cycles(2_000_000, 1_000, max), 2_000_000_000);
+        assert_eq!(stall_limit(2_000_000, 10_000, 2_000_000_000), 10);
+
+        // 4 GHz: the period is clamped and more NMIs make up the threshold
+        let period = period_cycles(4_000_000, 1_000, max);
+        assert_eq!(period, max);
+        assert_eq!(stall_limit(4_000_000, 10_000, period), 19);
+
+        assert_eq!(period_cycles(0, 1_000, max), 1);
+    }
+
+    #[test]
+    fn test_check_reports_stuck_cpu_once() {
+        let cpu = MAX_CPUS - 1;
+        assert!(!check(cpu, 5, false, 3));
+        assert!(!check(cpu, 5, false, 3));
+        assert!(!check(cpu, 5, false, 3));
+        assert!(check(cpu, 5, false, 3));
+        assert!(!check(cpu, 5, false, 3));
+
+        // A tick resets the count
+        assert!(!check(cpu, 6, false, 3));
+        assert!(!check(cpu, 6, false, 3));
+        assert_eq!(STALLS[cpu].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_check_ignores_interruptible_cpu() {
+        let cpu = MAX_CPUS - 2;
+        for _ in 0..10 {
+            assert!(!check(cpu, 1, true, 2));
+        }
+        assert_eq!(STALLS[cpu].load(Ordering::Relaxed), 0);
+        assert_eq!(on_nmi(1 << WATCHDOG_COUNTER, 0), NmiKind::Other);
+    }
+}
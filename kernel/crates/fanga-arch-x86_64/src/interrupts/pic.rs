@@ -12,6 +12,9 @@ const ICW1_INIT: u8 = 0x10;
 const ICW1_ICW4: u8 = 0x01;
 const ICW4_8086: u8 = 0x01;
 
+/// OCW3: read the in-service register on the next command port read
+const OCW3_READ_ISR: u8 = 0x0B;
+
 #[inline(always)]
 unsafe fn io_wait() {
     // Port 0x80 is traditionally used for 'wait'
@@ -68,6 +71,13 @@ pub unsafe fn unmask_irq(irq: u8) {
     outb(port, value);
 }
 
+/// Check whether IRQ 7 or 15 was spurious (raised with no in-service bit)
+pub unsafe fn is_spurious(irq: u8) -> bool {
+    let command = if irq < 8 { PIC1_COMMAND } else { PIC2_COMMAND };
+    outb(command, OCW3_READ_ISR);
+    inb(command) & (1 << (irq % 8)) == 0
+}
+
 pub unsafe fn eoi(irq: u8) {
     // If IRQ came from PIC2, we must ACK PIC2 as well
     if irq >= 8 {
@@ -11,9 +11,19 @@ use crate::port::{inb, outb};
 /// PIT Channel 0 (used for system timer)
 const PIT_CHANNEL_0: u16 = 0x40;
 
+/// PIT Channel 2 (gated by port 0x61, used for calibration)
+const PIT_CHANNEL_2: u16 = 0x42;
+
 /// PIT Command/Mode register
 const PIT_COMMAND: u16 = 0x43;
 
+/// System control port B: bit 0 gates channel 2, bit 1 drives the
+/// speaker, bit 5 reads back the channel 2 output
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
 /// PIT base frequency in Hz (1.193182 MHz)
 const PIT_BASE_FREQ: u32 = 1193182;
 
@@ -26,8 +36,10 @@ pub const PIT_DEFAULT_FREQ: u32 = 100;
 /// Bits 3-1: Operating mode (011 = Mode 3, square wave)
 /// Bit 0:    Binary/BCD mode (0 = binary)
 const PIT_CMD_CHANNEL_0: u8 = 0b00 << 6;
+const PIT_CMD_CHANNEL_2: u8 = 0b10 << 6;
 const PIT_CMD_ACCESS_LOHI: u8 = 0b11 << 4;
 const PIT_CMD_MODE_SQUARE: u8 = 0b011 << 1;
+const PIT_CMD_MODE_ONESHOT: u8 = 0b000 << 1;
 const PIT_CMD_BINARY: u8 = 0;
 
 /// Calculate divisor for a given frequency
@@ -76,6 +88,83 @@ pub unsafe fn init(frequency: u32) {
     outb(PIT_CHANNEL_0, ((divisor >> 8) & 0xFF) as u8);
 }
 
+/// Longest one-shot interval the 16-bit counter can hold, in milliseconds
+pub const PIT_MAX_ONESHOT_MS: u32 = 0xFFFF * 1000 / PIT_BASE_FREQ;
+
+/// Convert a millisecond interval to a one-shot counter value
+///
+/// The interval is clamped to `1..=PIT_MAX_ONESHOT_MS`.
+pub fn oneshot_count(ms: u32) -> u16 {
+    let ms = ms.clamp(1, PIT_MAX_ONESHOT_MS);
+    (PIT_BASE_FREQ as u64 * ms as u64 / 1000) as u16
+}
+
+/// Convert a counter value back to milliseconds
+pub fn count_to_ms(count: u16) -> u32 {
+    (count as u64 * 1000 / PIT_BASE_FREQ as u64) as u32
+}
+
+/// Convert a counter value to microseconds
+pub fn count_to_us(count: u16) -> u64 {
+    count as u64 * 1_000_000 / PIT_BASE_FREQ as u64
+}
+
+/// Program channel 0 to fire a single interrupt after `ms` milliseconds
+///
+/// The interval is clamped to `PIT_MAX_ONESHOT_MS`. Call `init` again to
+/// return to periodic mode.
+///
+/// # Returns
+/// The interval actually programmed, in milliseconds
+///
+/// # Safety
+/// This function performs direct I/O port access and should be called
+/// with interrupts disabled.
+pub unsafe fn set_oneshot(ms: u32) -> u32 {
+    let count = oneshot_count(ms);
+
+    let command = PIT_CMD_CHANNEL_0 | PIT_CMD_ACCESS_LOHI | PIT_CMD_MODE_ONESHOT | PIT_CMD_BINARY;
+    outb(PIT_COMMAND, command);
+    outb(PIT_CHANNEL_0, (count & 0xFF) as u8);
+    outb(PIT_CHANNEL_0, ((count >> 8) & 0xFF) as u8);
+
+    count_to_ms(count)
+}
+
+/// Busy-wait `ms` milliseconds on PIT channel 2
+///
+/// Used as the reference when calibrating other timers. Channel 2 has no
+/// IRQ line, so this leaves the system tick on channel 0 alone. The
+/// interval is clamped to `PIT_MAX_ONESHOT_MS`.
+///
+/// # Returns
+/// The interval actually waited, in microseconds
+///
+/// # Safety
+/// This function performs direct I/O port access and should be called
+/// with interrupts disabled so the wait is not stretched.
+pub unsafe fn calibration_wait(ms: u32) -> u64 {
+    let count = oneshot_count(ms);
+
+    // Gate channel 2 off with the speaker disconnected while programming
+    let port_b = inb(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER);
+    outb(PORT_B, port_b);
+
+    let command = PIT_CMD_CHANNEL_2 | PIT_CMD_ACCESS_LOHI | PIT_CMD_MODE_ONESHOT | PIT_CMD_BINARY;
+    outb(PIT_COMMAND, command);
+    outb(PIT_CHANNEL_2, (count & 0xFF) as u8);
+    outb(PIT_CHANNEL_2, ((count >> 8) & 0xFF) as u8);
+
+    // Raising the gate starts the count; OUT2 goes high at terminal count
+    outb(PORT_B, port_b | PORT_B_GATE);
+    while inb(PORT_B) & PORT_B_OUT2 == 0 {
+        core::hint::spin_loop();
+    }
+    outb(PORT_B, port_b);
+
+    count_to_us(count)
+}
+
 /// Read the current counter value from the PIT
 ///
 /// # Safety
@@ -130,6 +219,16 @@ mod tests {
         assert_eq!(get_frequency(0), PIT_BASE_FREQ);
     }
     
+    #[test]
+    fn test_oneshot_count() {
+        assert_eq!(PIT_MAX_ONESHOT_MS, 54);
+        assert_eq!(oneshot_count(10), 11931);
+        assert_eq!(oneshot_count(0), oneshot_count(1));
+        assert_eq!(oneshot_count(1000), oneshot_count(PIT_MAX_ONESHOT_MS));
+        assert_eq!(count_to_ms(oneshot_count(20)), 19);
+        assert_eq!(count_to_us(oneshot_count(10)), 9999);
+    }
+
     #[test]
     fn test_round_trip() {
         // Test that frequency -> divisor -> frequency gives approximately the same result
@@ -0,0 +1,405 @@
+//! Performance Monitoring Unit
+//!
+//! General-purpose performance counters count hardware events: each has
+//! an event select MSR naming the event and enabling the counter, and a
+//! counter MSR holding the count.
+//! - Intel architectural perfmon (CPUID.0AH): IA32_PERFEVTSELx and
+//!   IA32_PMCx, with the events CPUID reports as available.
+//! - AMD: PERF_CTLx and PERF_CTRx, six of them with the core counter
+//!   extension (CPUID.80000001H:ECX[23]), four otherwise.
+//!
+//! Counters 0 and 1 belong to the NMI watchdog and the NMI sampler, which
+//! program them through this module like any other; `alloc_counter` hands
+//! out the rest.
+//!
+//! Counters are per CPU: they count, and are allocated, on the CPU that
+//! programs them. A counter started with a period raises an NMI through
+//! the local APIC each time it overflows; the NMI handler counts the
+//! overflow and reloads the period, so the count carries on across it.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Once;
+
+use super::apic::{ipis_available, rdmsr, wrmsr, x2apic_msr};
+use crate::cpuid::{cpu_features, Feature, Vendor};
+use crate::gdt::MAX_CPUS;
+
+/// Most counters used per CPU
+pub const MAX_COUNTERS: usize = 8;
+
+/// Local APIC performance counter LVT entry
+const APIC_LVT_PERF: u32 = 0x340;
+/// LVT delivery mode NMI
+const LVT_NMI: u64 = 0b100 << 8;
+
+/// Intel MSRs
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// AMD MSRs: legacy counters, and the core counter extension's, which
+/// interleave control and count
+const MSR_K7_EVNTSEL0: u32 = 0xC001_0000;
+const MSR_K7_PERFCTR0: u32 = 0xC001_0004;
+const MSR_F15H_PERF_CTL0: u32 = 0xC001_0200;
+
+/// Event select fields shared by Intel and AMD
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Counter the NMI watchdog owns
+pub const WATCHDOG_COUNTER: usize = 0;
+
+/// Counter the NMI sampler owns
+pub const SAMPLER_COUNTER: usize = 1;
+
+/// Counters the watchdog and the sampler own
+const RESERVED_COUNTERS: usize = 2;
+
+/// Width of AMD counters
+const AMD_COUNTER_WIDTH: u8 = 48;
+
+/// Intel writes to IA32_PMCx only set the low 32 bits and sign-extend bit 31
+const INTEL_MAX_PERIOD: u64 = i32::MAX as u64;
+
+/// A hardware event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Core cycles while not halted
+    Cycles,
+    /// Instructions retired
+    Instructions,
+    /// Last-level cache references
+    CacheReferences,
+    /// Last-level cache misses
+    CacheMisses,
+    /// Branch instructions retired
+    Branches,
+    /// Mispredicted branches retired
+    BranchMisses,
+    /// Data TLB misses
+    DtlbMisses,
+}
+
+/// Whose counters these are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Intel,
+    Amd,
+    /// AMD with the core counter extension
+    AmdCore,
+}
+
+/// What the PMU of this CPU offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuInfo {
+    pub kind: Kind,
+    /// Intel architectural perfmon version, 0 on AMD
+    pub version: u8,
+    /// General-purpose counters, reserved ones included
+    pub counters: u8,
+    /// Counter width in bits
+    pub width: u8,
+    /// Intel: architectural events the CPU lacks, one bit per event in
+    /// CPUID.0AH:EBX order
+    unavailable: u32,
+}
+
+impl PmuInfo {
+    /// Intel PMU from CPUID.0AH EAX and EBX
+    pub fn intel(eax: u32, ebx: u32) -> Option<Self> {
+        let version = eax as u8;
+        let counters = ((eax >> 8) as u8).min(MAX_COUNTERS as u8);
+        let width = (eax >> 16) as u8;
+        let events = (eax >> 24) as u8;
+        // Events past the EBX bit vector's length are unavailable too
+        let unavailable = ebx | u32::MAX.checked_shl(events as u32).unwrap_or(0);
+        (version >= 1 && counters > 0 && width > 0).then_some(Self { kind: Kind::Intel, version, counters, width, unavailable })
+    }
+
+    /// AMD PMU, with or without the core counter extension
+    pub fn amd(core_counters: bool) -> Self {
+        Self {
+            kind: if core_counters { Kind::AmdCore } else { Kind::Amd },
+            version: 0,
+            counters: if core_counters { 6 } else { 4 },
+            width: AMD_COUNTER_WIDTH,
+            unavailable: 0,
+        }
+    }
+
+    /// First counter not used by the kernel's own NMI sources
+    pub fn first_free(&self) -> usize {
+        RESERVED_COUNTERS.min(self.counters as usize)
+    }
+
+    /// Event and unit mask bits selecting `event`, if this PMU counts it
+    pub fn event_select(&self, event: Event) -> Option<u64> {
+        let (code, umask) = match self.kind {
+            Kind::Intel => {
+                // Architectural events, by CPUID.0AH:EBX bit
+                let (bit, code, umask) = match event {
+                    Event::Cycles => (0, 0x3C, 0x00),
+                    Event::Instructions => (1, 0xC0, 0x00),
+                    Event::CacheReferences => (3, 0x2E, 0x4F),
+                    Event::CacheMisses => (4, 0x2E, 0x41),
+                    Event::Branches => (5, 0xC4, 0x00),
+                    Event::BranchMisses => (6, 0xC5, 0x00),
+                    Event::DtlbMisses => return None,
+                };
+                if self.unavailable & (1 << bit) != 0 {
+                    return None;
+                }
+                (code, umask)
+            }
+            Kind::Amd | Kind::AmdCore => match event {
+                Event::Cycles => (0x76, 0x00),
+                Event::Instructions => (0xC0, 0x00),
+                Event::CacheReferences => (0x7D, 0x07),
+                Event::CacheMisses => (0x7E, 0x07),
+                Event::Branches => (0xC2, 0x00),
+                Event::BranchMisses => (0xC3, 0x00),
+                Event::DtlbMisses => (0x45, 0xFF),
+            },
+        };
+        Some(code | umask << 8)
+    }
+
+    /// Event select MSR of counter `index`
+    pub fn select_msr(&self, index: usize) -> u32 {
+        match self.kind {
+            Kind::Intel => IA32_PERFEVTSEL0 + index as u32,
+            Kind::Amd => MSR_K7_EVNTSEL0 + index as u32,
+            Kind::AmdCore => MSR_F15H_PERF_CTL0 + 2 * index as u32,
+        }
+    }
+
+    /// Count MSR of counter `index`
+    pub fn counter_msr(&self, index: usize) -> u32 {
+        match self.kind {
+            Kind::Intel => IA32_PMC0 + index as u32,
+            Kind::Amd => MSR_K7_PERFCTR0 + index as u32,
+            Kind::AmdCore => MSR_F15H_PERF_CTL0 + 2 * index as u32 + 1,
+        }
+    }
+
+    /// Longest period a counter can be loaded with
+    pub fn max_period(&self) -> u64 {
+        match self.kind {
+            Kind::Intel => INTEL_MAX_PERIOD,
+            Kind::Amd | Kind::AmdCore => (1 << (self.width - 1)) - 1,
+        }
+    }
+
+    /// Bits a counter holds
+    pub fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width.min(64) as u32)
+    }
+}
+
+static PMU: Once<Option<PmuInfo>> = Once::new();
+
+/// Counters allocated on each CPU, one bit per counter
+static IN_USE: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Counters on each CPU that raise an NMI on overflow
+static INTERRUPTING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Period of each interrupting counter
+static PERIODS: [[AtomicU64; MAX_COUNTERS]; MAX_CPUS] = [const { [const { AtomicU64::new(0) }; MAX_COUNTERS] }; MAX_CPUS];
+
+/// Overflows of each interrupting counter since it started
+static OVERFLOWS: [[AtomicU64; MAX_COUNTERS]; MAX_CPUS] = [const { [const { AtomicU64::new(0) }; MAX_COUNTERS] }; MAX_CPUS];
+
+fn cpu_index() -> usize {
+    crate::gdt::current_cpu_index().unwrap_or(0)
+}
+
+/// The PMU of this CPU, if it has one
+pub fn info() -> Option<&'static PmuInfo> {
+    PMU.call_once(|| {
+        let cpu = cpu_features();
+        match cpu.vendor() {
+            Vendor::Intel => cpu.perfmon().and_then(|(eax, ebx)| PmuInfo::intel(eax, ebx)),
+            Vendor::Amd => Some(PmuInfo::amd(cpu.has(Feature::PerfCtrCore))),
+            _ => None,
+        }
+    })
+    .as_ref()
+}
+
+/// Claim a free counter on this CPU
+pub fn alloc_counter() -> Option<usize> {
+    let pmu = info()?;
+    let in_use = &IN_USE[cpu_index()];
+    let mut mask = in_use.load(Ordering::Relaxed);
+    loop {
+        let index = (pmu.first_free()..pmu.counters as usize).find(|i| mask & (1 << i) == 0)?;
+        match in_use.compare_exchange(mask, mask | 1 << index, Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return Some(index),
+            Err(current) => mask = current,
+        }
+    }
+}
+
+/// Stop counter `index` of this CPU and give it back
+pub fn free_counter(index: usize) {
+    stop(index);
+    IN_USE[cpu_index()].fetch_and(!(1 << index), Ordering::AcqRel);
+}
+
+/// Load a counter so it overflows after `period` events, and re-arm the NMI
+///
+/// # Safety
+/// The counter must be allocated.
+unsafe fn arm(pmu: &PmuInfo, index: usize, period: u64) {
+    wrmsr(pmu.counter_msr(index), period.wrapping_neg() & pmu.mask());
+    // Delivering a counter interrupt masks the LVT entry
+    wrmsr(x2apic_msr(APIC_LVT_PERF), LVT_NMI);
+}
+
+/// Start counter `index` of this CPU counting the event `select`
+/// (`PmuInfo::event_select`) in both rings
+///
+/// With a period, the counter raises an NMI every `period` events; that
+/// needs x2APIC mode. The period is clamped to `PmuInfo::max_period`.
+///
+/// # Safety
+/// The counter must have been allocated with `alloc_counter` on this CPU,
+/// or be the caller's reserved counter.
+pub unsafe fn start(index: usize, select: u64, period: Option<u64>) -> Result<(), &'static str> {
+    let pmu = info().ok_or("No PMU")?;
+    if period.is_some() && !ipis_available() {
+        return Err("Counter overflow interrupts need x2APIC mode");
+    }
+    let cpu = cpu_index();
+    let bit = 1 << index;
+    wrmsr(pmu.select_msr(index), 0);
+    OVERFLOWS[cpu][index].store(0, Ordering::Relaxed);
+    let mut select = select | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN;
+    match period {
+        Some(period) => {
+            let period = period.clamp(1, pmu.max_period());
+            PERIODS[cpu][index].store(period, Ordering::Relaxed);
+            INTERRUPTING[cpu].fetch_or(bit, Ordering::Relaxed);
+            arm(pmu, index, period);
+            select |= EVTSEL_INT;
+        }
+        None => {
+            INTERRUPTING[cpu].fetch_and(!bit, Ordering::Relaxed);
+            wrmsr(pmu.counter_msr(index), 0);
+        }
+    }
+    if pmu.version >= 2 {
+        wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | bit as u64);
+    }
+    wrmsr(pmu.select_msr(index), select);
+    Ok(())
+}
+
+/// Stop counter `index` of this CPU; its count stays readable
+pub fn stop(index: usize) {
+    if let Some(pmu) = info() {
+        INTERRUPTING[cpu_index()].fetch_and(!(1 << index), Ordering::Relaxed);
+        unsafe { wrmsr(pmu.select_msr(index), 0) };
+    }
+}
+
+/// Raw value of counter `index` of this CPU
+pub fn read(index: usize) -> u64 {
+    info().map_or(0, |pmu| rdmsr(pmu.counter_msr(index)) & pmu.mask())
+}
+
+/// Events counter `index` of this CPU has counted since it started
+///
+/// Overflows of an interrupting counter are included.
+pub fn events(index: usize) -> u64 {
+    let Some(pmu) = info() else {
+        return 0;
+    };
+    let cpu = cpu_index();
+    let raw = read(index);
+    if INTERRUPTING[cpu].load(Ordering::Relaxed) & (1 << index) == 0 {
+        return raw;
+    }
+    let period = PERIODS[cpu][index].load(Ordering::Relaxed);
+    let overflows = OVERFLOWS[cpu][index].load(Ordering::Relaxed);
+    overflows * period + (raw.wrapping_add(period) & pmu.mask())
+}
+
+/// Overflows of counter `index` of this CPU since it started
+pub fn overflows(index: usize) -> u64 {
+    OVERFLOWS[cpu_index()][index].load(Ordering::Relaxed)
+}
+
+/// Count and reload the interrupting counters of this CPU that overflowed
+///
+/// # Returns
+/// The counters that had, one bit per counter; the NMI was theirs unless
+/// it is 0
+pub fn on_nmi() -> u32 {
+    let cpu = cpu_index();
+    let interrupting = INTERRUPTING[cpu].load(Ordering::Relaxed);
+    let Some(pmu) = info().filter(|_| interrupting != 0) else {
+        return 0;
+    };
+    // Counters count up from -period, so the top bit clears on overflow
+    let top_bit = 1u64 << (pmu.width - 1);
+    let mut overflowed = 0;
+    for index in (0..MAX_COUNTERS).filter(|i| interrupting & (1 << i) != 0) {
+        if rdmsr(pmu.counter_msr(index)) & top_bit != 0 {
+            continue;
+        }
+        OVERFLOWS[cpu][index].fetch_add(1, Ordering::Relaxed);
+        unsafe {
+            arm(pmu, index, PERIODS[cpu][index].load(Ordering::Relaxed));
+            if pmu.version >= 2 {
+                wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << index);
+            }
+        }
+        overflowed |= 1 << index;
+    }
+    overflowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_pmu() {
+        // Version 4, 8 counters of 48 bits, 7 events with LLC misses missing
+        let pmu = PmuInfo::intel(0x0730_0804, 1 << 4).unwrap();
+        assert_eq!((pmu.version, pmu.counters, pmu.width), (4, 8, 48));
+        assert_eq!(pmu.first_free(), 2);
+        assert_eq!(pmu.event_select(Event::CacheReferences), Some(0x4F2E));
+        assert_eq!(pmu.event_select(Event::CacheMisses), None);
+        assert_eq!(pmu.event_select(Event::DtlbMisses), None);
+        assert_eq!((pmu.select_msr(3), pmu.counter_msr(3)), (0x189, 0xC4));
+        assert_eq!(pmu.max_period(), INTEL_MAX_PERIOD);
+        assert_eq!(pmu.mask(), (1 << 48) - 1);
+
+        // Only the first two events reported
+        let pmu = PmuInfo::intel(0x0230_0402, 0).unwrap();
+        assert_eq!(pmu.event_select(Event::Instructions), Some(0xC0));
+        assert_eq!(pmu.event_select(Event::Branches), None);
+
+        assert_eq!(PmuInfo::intel(0, 0), None);
+    }
+
+    #[test]
+    fn test_amd_pmu() {
+        let legacy = PmuInfo::amd(false);
+        assert_eq!((legacy.counters, legacy.first_free()), (4, 2));
+        assert_eq!((legacy.select_msr(2), legacy.counter_msr(2)), (0xC001_0002, 0xC001_0006));
+
+        let core = PmuInfo::amd(true);
+        assert_eq!(core.counters, 6);
+        assert_eq!((core.select_msr(2), core.counter_msr(2)), (0xC001_0204, 0xC001_0205));
+        assert_eq!(core.event_select(Event::CacheMisses), Some(0x077E));
+        assert_eq!(core.max_period(), (1 << 47) - 1);
+    }
+}
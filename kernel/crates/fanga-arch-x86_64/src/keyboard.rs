@@ -6,6 +6,11 @@ use crate::port::inb;
 /// scancodes to ASCII characters using US keyboard layout (scancode set 1).
 
 const PS2_DATA_PORT: u16 = 0x60;
+const PS2_STATUS_PORT: u16 = 0x64;
+
+/// Status register bits: output buffer full, data is from the mouse
+const PS2_STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const PS2_STATUS_AUX_DATA: u8 = 1 << 5;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyCode {
@@ -21,10 +26,13 @@ pub enum KeyCode {
     RightCtrl,
     LeftAlt,
     RightAlt,
+    /// Extra key left of Z on ISO keyboards (`<` and `>` on most of them)
+    Iso102,
     CapsLock,
     F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
     Up, Down, Left, Right,
     Home, End,
+    PageUp, PageDown,
     Unknown,
 }
 
@@ -39,6 +47,7 @@ pub struct Keyboard {
     shift_pressed: bool,
     ctrl_pressed: bool,
     alt_pressed: bool,
+    altgr_pressed: bool,
     caps_lock: bool,
     extended_scancode: bool,
 }
@@ -49,6 +58,7 @@ impl Keyboard {
             shift_pressed: false,
             ctrl_pressed: false,
             alt_pressed: false,
+            altgr_pressed: false,
             caps_lock: false,
             extended_scancode: false,
         }
@@ -81,8 +91,11 @@ impl Keyboard {
                 0x4D => KeyCode::Right,
                 0x47 => KeyCode::Home,
                 0x4F => KeyCode::End,
+                0x49 => KeyCode::PageUp,
+                0x51 => KeyCode::PageDown,
                 0x53 => KeyCode::Delete,
                 0x1D => KeyCode::RightCtrl,
+                0x38 => KeyCode::RightAlt,
                 _ => KeyCode::Unknown,
             }
         } else {
@@ -156,6 +169,7 @@ impl Keyboard {
                 0x43 => KeyCode::F9,
                 0x44 => KeyCode::F10,
                 0x57 => KeyCode::F11,
+                0x56 => KeyCode::Iso102,
                 0x58 => KeyCode::F12,
                 _ => KeyCode::Unknown,
             }
@@ -170,8 +184,12 @@ impl Keyboard {
                 KeyCode::LeftCtrl | KeyCode::RightCtrl => {
                     self.ctrl_pressed = true;
                 }
-                KeyCode::LeftAlt | KeyCode::RightAlt => {
+                KeyCode::LeftAlt => {
+                    self.alt_pressed = true;
+                }
+                KeyCode::RightAlt => {
                     self.alt_pressed = true;
+                    self.altgr_pressed = true;
                 }
                 KeyCode::CapsLock => {
                     self.caps_lock = !self.caps_lock;
@@ -186,8 +204,12 @@ impl Keyboard {
                 KeyCode::LeftCtrl | KeyCode::RightCtrl => {
                     self.ctrl_pressed = false;
                 }
-                KeyCode::LeftAlt | KeyCode::RightAlt => {
+                KeyCode::LeftAlt => {
+                    self.alt_pressed = false;
+                }
+                KeyCode::RightAlt => {
                     self.alt_pressed = false;
+                    self.altgr_pressed = false;
                 }
                 _ => {}
             }
@@ -202,11 +224,17 @@ impl Keyboard {
     }
 
     /// Convert a keycode to ASCII character, applying shift/caps lock
-    /// Uses the current keyboard layout
+    /// Uses the current keyboard layout, including its AltGr symbols and
+    /// dead keys, so call it once per key press
     pub fn to_ascii(&self, keycode: KeyCode) -> Option<char> {
-        use crate::keyboard_layout;
+        use crate::keyboard_layout::{self, Modifiers};
         let layout_mgr = keyboard_layout::layout_manager();
-        layout_mgr.to_char(keycode, self.shift_pressed, self.caps_lock)
+        let modifiers = Modifiers {
+            shift: self.shift_pressed,
+            caps_lock: self.caps_lock,
+            altgr: self.altgr_pressed,
+        };
+        layout_mgr.translate(keycode, modifiers)
     }
 
     pub fn is_shift_pressed(&self) -> bool {
@@ -221,11 +249,30 @@ impl Keyboard {
         self.alt_pressed
     }
 
+    pub fn is_altgr_pressed(&self) -> bool {
+        self.altgr_pressed
+    }
+
     pub fn is_caps_lock(&self) -> bool {
         self.caps_lock
     }
 }
 
+/// Read a pending keyboard scancode without waiting
+///
+/// For polled input with interrupts disabled. Mouse bytes are left in
+/// the controller.
+pub fn poll_scancode() -> Option<u8> {
+    unsafe {
+        let status = inb(PS2_STATUS_PORT);
+        if status & PS2_STATUS_OUTPUT_FULL != 0 && status & PS2_STATUS_AUX_DATA == 0 {
+            Some(inb(PS2_DATA_PORT))
+        } else {
+            None
+        }
+    }
+}
+
 /// Global keyboard state
 static mut KEYBOARD: Keyboard = Keyboard::new();
 
@@ -248,6 +295,21 @@ pub fn keyboard() -> &'static mut Keyboard {
     unsafe { &mut KEYBOARD }
 }
 
+/// Feed a scancode from another keyboard through the PS/2 path
+///
+/// Keyboards that are not PS/2 (USB) translate their keys to scancode
+/// set 1 and share modifier state, layout and the event callback with the
+/// PS/2 keyboard. Interrupts are off so the PS/2 handler cannot land in
+/// the middle of an extended sequence.
+pub fn inject_scancode(scancode: u8) {
+    crate::interrupts::without_interrupts(|| {
+        let kbd = keyboard();
+        if let Some(event) = kbd.process_scancode(scancode) {
+            dispatch_event(event, kbd);
+        }
+    });
+}
+
 /// Dispatch a keyboard event to the registered callback
 pub(crate) fn dispatch_event(event: KeyEvent, kbd: &Keyboard) {
     unsafe {
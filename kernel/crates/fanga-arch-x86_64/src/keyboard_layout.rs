@@ -2,9 +2,71 @@
 ///
 /// This module provides an abstraction for keyboard layouts, allowing support
 /// for different languages and keyboard configurations beyond the US layout.
+///
+/// Key codes name the key at that position on a US keyboard; a layout
+/// says what the key produces. Layouts with AltGr symbols or dead keys
+/// implement `lookup`. A dead key (such as `^` on French and German
+/// keyboards) types nothing by itself and puts its accent on the next
+/// letter; the layout manager keeps track of the pending accent.
+///
+/// Layouts are listed in `LayoutType::ALL` and switched at runtime by name
+/// with `LayoutManager::set_layout_by_name`.
 
 use crate::keyboard::KeyCode;
 
+/// Modifier keys that change what a key produces
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub caps_lock: bool,
+    /// Right Alt, which picks a key's third symbol
+    pub altgr: bool,
+}
+
+/// Accent typed by a dead key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadKey {
+    Acute,
+    Grave,
+    Circumflex,
+    Diaeresis,
+    Tilde,
+}
+
+impl DeadKey {
+    /// The accent on its own, typed by the dead key followed by space or by
+    /// the dead key twice
+    pub fn spacing(self) -> char {
+        match self {
+            DeadKey::Acute => '´',
+            DeadKey::Grave => '`',
+            DeadKey::Circumflex => '^',
+            DeadKey::Diaeresis => '¨',
+            DeadKey::Tilde => '~',
+        }
+    }
+
+    /// `base` with this accent, if Latin-1 has that letter
+    pub fn compose(self, base: char) -> Option<char> {
+        let (bases, accented) = match self {
+            DeadKey::Acute => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+            DeadKey::Grave => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+            DeadKey::Circumflex => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+            DeadKey::Diaeresis => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+            DeadKey::Tilde => ("anoANO", "ãñõÃÑÕ"),
+        };
+        let index = bases.chars().position(|c| c == base)?;
+        accented.chars().nth(index)
+    }
+}
+
+/// What a key produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySym {
+    Char(char),
+    Dead(DeadKey),
+}
+
 /// Keyboard layout trait
 pub trait KeyboardLayout: Send + Sync {
     /// Get the name of this layout
@@ -12,6 +74,87 @@ pub trait KeyboardLayout: Send + Sync {
     
     /// Convert a keycode to a character with modifiers applied
     fn to_char(&self, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char>;
+
+    /// What a key produces with all modifiers, including AltGr and dead keys
+    ///
+    /// Layouts without either only need `to_char`; AltGr changes nothing
+    /// there.
+    fn lookup(&self, keycode: KeyCode, modifiers: Modifiers) -> Option<KeySym> {
+        self.to_char(keycode, modifiers.shift, modifiers.caps_lock).map(KeySym::Char)
+    }
+}
+
+/// Characters of the keys every layout shares
+fn control_char(keycode: KeyCode) -> Option<char> {
+    match keycode {
+        KeyCode::Backspace => Some('\x08'),
+        KeyCode::Enter => Some('\n'),
+        KeyCode::Tab => Some('\t'),
+        KeyCode::Char(' ') => Some(' '),
+        _ => None,
+    }
+}
+
+/// `to_char` for layouts that implement `lookup`: dead keys type their
+/// accent
+fn lookup_char(layout: &dyn KeyboardLayout, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char> {
+    match layout.lookup(keycode, Modifiers { shift, caps_lock, altgr: false })? {
+        KeySym::Char(c) => Some(c),
+        KeySym::Dead(dead) => Some(dead.spacing()),
+    }
+}
+
+/// One key of a layout: its symbols unshifted, shifted and with AltGr
+struct Key {
+    base: KeySym,
+    shifted: KeySym,
+    altgr: Option<KeySym>,
+}
+
+impl Key {
+    /// A letter, shifted by Shift and Caps Lock
+    fn letter(c: char) -> Self {
+        let upper = c.to_uppercase().next().unwrap_or(c);
+        Self { base: KeySym::Char(c), shifted: KeySym::Char(upper), altgr: None }
+    }
+
+    /// A key with two characters; only letters follow Caps Lock
+    fn chars(base: char, shifted: char) -> Self {
+        Self { base: KeySym::Char(base), shifted: KeySym::Char(shifted), altgr: None }
+    }
+
+    fn with_altgr(mut self, altgr: KeySym) -> Self {
+        self.altgr = Some(altgr);
+        self
+    }
+
+    /// The symbol for `modifiers`; Caps Lock acts as Shift on letters
+    fn select(&self, modifiers: Modifiers) -> Option<KeySym> {
+        if modifiers.altgr {
+            return self.altgr;
+        }
+        let letter = matches!(self.base, KeySym::Char(c) if c.is_alphabetic());
+        if modifiers.shift != (modifiers.caps_lock && letter) {
+            Some(self.shifted)
+        } else {
+            Some(self.base)
+        }
+    }
+}
+
+/// Look a key up in a layout's key table, falling back to the keys every
+/// layout shares
+fn table_lookup(keycode: KeyCode, modifiers: Modifiers, table: impl Fn(char) -> Option<Key>) -> Option<KeySym> {
+    let key = match keycode {
+        KeyCode::Char(c) => table(c),
+        KeyCode::Iso102 => table('<'),
+        _ => None,
+    };
+    match key {
+        Some(key) => key.select(modifiers),
+        None if modifiers.altgr => None,
+        None => control_char(keycode).map(KeySym::Char),
+    }
 }
 
 /// US QWERTY keyboard layout
@@ -124,134 +267,158 @@ impl KeyboardLayout for UkLayout {
 }
 
 /// German QWERTZ keyboard layout
+///
+/// `^`, `´` and `` ` `` are dead keys; AltGr gives `@`, `€`, the braces and
+/// brackets and `\`.
 pub struct DeLayout;
 
+impl DeLayout {
+    /// The key at the position of US key `c`
+    fn key(c: char) -> Option<Key> {
+        use KeySym::{Char, Dead};
+        Some(match c {
+            '`' => Key { base: Dead(DeadKey::Circumflex), shifted: Char('°'), altgr: None },
+            '1' => Key::chars('1', '!'),
+            '2' => Key::chars('2', '"').with_altgr(Char('²')),
+            '3' => Key::chars('3', '§').with_altgr(Char('³')),
+            '4' => Key::chars('4', '$'),
+            '5' => Key::chars('5', '%'),
+            '6' => Key::chars('6', '&'),
+            '7' => Key::chars('7', '/').with_altgr(Char('{')),
+            '8' => Key::chars('8', '(').with_altgr(Char('[')),
+            '9' => Key::chars('9', ')').with_altgr(Char(']')),
+            '0' => Key::chars('0', '=').with_altgr(Char('}')),
+            '-' => Key::chars('ß', '?').with_altgr(Char('\\')),
+            '=' => Key { base: Dead(DeadKey::Acute), shifted: Dead(DeadKey::Grave), altgr: None },
+            'q' => Key::letter('q').with_altgr(Char('@')),
+            'e' => Key::letter('e').with_altgr(Char('€')),
+            'y' => Key::letter('z'),
+            'z' => Key::letter('y'),
+            'm' => Key::letter('m').with_altgr(Char('µ')),
+            '[' => Key::letter('ü'),
+            ']' => Key::chars('+', '*').with_altgr(Char('~')),
+            ';' => Key::letter('ö'),
+            '\'' => Key::letter('ä'),
+            '\\' => Key::chars('#', '\''),
+            ',' => Key::chars(',', ';'),
+            '.' => Key::chars('.', ':'),
+            '/' => Key::chars('-', '_'),
+            '<' => Key::chars('<', '>').with_altgr(Char('|')),
+            c if c.is_ascii_lowercase() => Key::letter(c),
+            _ => return None,
+        })
+    }
+}
+
 impl KeyboardLayout for DeLayout {
     fn name(&self) -> &'static str {
         "DE"
     }
-    
+
     fn to_char(&self, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char> {
-        match keycode {
-            KeyCode::Char(c) => {
-                // German layout has different key positions (QWERTZ instead of QWERTY)
-                let mapped_char = match c {
-                    'y' => 'z', // Y and Z are swapped
-                    'z' => 'y',
-                    _ => c,
-                };
-                
-                let should_uppercase = (shift && !caps_lock)
-                    || (!shift && caps_lock && mapped_char.is_ascii_alphabetic());
+        lookup_char(self, keycode, shift, caps_lock)
+    }
 
-                if should_uppercase && mapped_char.is_ascii_alphabetic() {
-                    Some(mapped_char.to_ascii_uppercase())
-                } else if shift {
-                    // German-specific symbol mappings
-                    match mapped_char {
-                        '1' => Some('!'),
-                        '2' => Some('"'),
-                        '3' => Some('§'), // German section sign
-                        '4' => Some('$'),
-                        '5' => Some('%'),
-                        '6' => Some('&'),
-                        '7' => Some('/'),
-                        '8' => Some('('),
-                        '9' => Some(')'),
-                        '0' => Some('='),
-                        '-' => Some('_'), // ß key
-                        '=' => Some('?'), // ´ key shifted
-                        '[' => Some('Ü'),
-                        ']' => Some('*'),
-                        ';' => Some('Ö'),
-                        '\'' => Some('Ä'),
-                        '`' => Some('°'),
-                        '\\' => Some('\''),
-                        ',' => Some(';'),
-                        '.' => Some(':'),
-                        '/' => Some('_'),
-                        _ => Some(mapped_char),
-                    }
-                } else {
-                    Some(mapped_char)
-                }
-            }
-            KeyCode::Backspace => Some('\x08'),
-            KeyCode::Enter => Some('\n'),
-            KeyCode::Tab => Some('\t'),
-            _ => None,
-        }
+    fn lookup(&self, keycode: KeyCode, modifiers: Modifiers) -> Option<KeySym> {
+        table_lookup(keycode, modifiers, Self::key)
     }
 }
 
 /// French AZERTY keyboard layout
+///
+/// The number row types symbols and accented letters unshifted and digits
+/// with Shift. `^` and `¨` are dead keys; AltGr gives `@`, `#`, `€`, the
+/// braces and brackets and more.
 pub struct FrLayout;
 
+impl FrLayout {
+    /// The key at the position of US key `c`
+    fn key(c: char) -> Option<Key> {
+        use KeySym::{Char, Dead};
+        Some(match c {
+            '`' => Key::chars('²', '²'),
+            '1' => Key::chars('&', '1'),
+            '2' => Key::chars('é', '2').with_altgr(Char('~')),
+            '3' => Key::chars('"', '3').with_altgr(Char('#')),
+            '4' => Key::chars('\'', '4').with_altgr(Char('{')),
+            '5' => Key::chars('(', '5').with_altgr(Char('[')),
+            '6' => Key::chars('-', '6').with_altgr(Char('|')),
+            '7' => Key::chars('è', '7').with_altgr(Char('`')),
+            '8' => Key::chars('_', '8').with_altgr(Char('\\')),
+            '9' => Key::chars('ç', '9').with_altgr(Char('^')),
+            '0' => Key::chars('à', '0').with_altgr(Char('@')),
+            '-' => Key::chars(')', '°').with_altgr(Char(']')),
+            '=' => Key::chars('=', '+').with_altgr(Char('}')),
+            'q' => Key::letter('a'),
+            'w' => Key::letter('z'),
+            'e' => Key::letter('e').with_altgr(Char('€')),
+            '[' => Key { base: Dead(DeadKey::Circumflex), shifted: Dead(DeadKey::Diaeresis), altgr: None },
+            ']' => Key::chars('$', '£').with_altgr(Char('¤')),
+            'a' => Key::letter('q'),
+            ';' => Key::letter('m'),
+            '\'' => Key::chars('ù', '%'),
+            '\\' => Key::chars('*', 'µ'),
+            'z' => Key::letter('w'),
+            'm' => Key::chars(',', '?'),
+            ',' => Key::chars(';', '.'),
+            '.' => Key::chars(':', '/'),
+            '/' => Key::chars('!', '§'),
+            '<' => Key::chars('<', '>'),
+            c if c.is_ascii_lowercase() => Key::letter(c),
+            _ => return None,
+        })
+    }
+}
+
 impl KeyboardLayout for FrLayout {
     fn name(&self) -> &'static str {
         "FR"
     }
-    
+
     fn to_char(&self, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char> {
-        match keycode {
-            KeyCode::Char(c) => {
-                // French layout (AZERTY) has significantly different layout
-                // Physical key mappings (not character transformations)
-                let mapped_char = match c {
-                    'q' => 'a', // AZERTY: physical Q key produces 'a'
-                    'w' => 'z', // AZERTY: physical W key produces 'z'
-                    'a' => 'q', // AZERTY: physical A key produces 'q'
-                    'z' => 'w', // AZERTY: physical Z key produces 'w'
-                    'm' => ',', // AZERTY: physical M key produces ','
-                    ',' => ';', // AZERTY: physical comma key produces ';'
-                    ';' => 'm', // AZERTY: physical semicolon key produces 'm'
-                    _ => c,
-                };
-                
-                let should_uppercase = (shift && !caps_lock)
-                    || (!shift && caps_lock && mapped_char.is_ascii_alphabetic());
+        lookup_char(self, keycode, shift, caps_lock)
+    }
 
-                if should_uppercase && mapped_char.is_ascii_alphabetic() {
-                    Some(mapped_char.to_ascii_uppercase())
-                } else if shift {
-                    // French-specific symbol mappings
-                    match mapped_char {
-                        '1' => Some('&'), // Numbers are shifted in French
-                        '2' => Some('é'),
-                        '3' => Some('"'),
-                        '4' => Some('\''),
-                        '5' => Some('('),
-                        '6' => Some('-'),
-                        '7' => Some('è'),
-                        '8' => Some('_'),
-                        '9' => Some('ç'),
-                        '0' => Some('à'),
-                        '-' => Some('°'),
-                        '=' => Some('+'),
-                        _ => Some(mapped_char),
-                    }
-                } else {
-                    // Unshifted numbers in French layout
-                    match mapped_char {
-                        '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' | '0' => {
-                            // Keep as-is, these produce symbols when unshifted
-                            Some(mapped_char)
-                        }
-                        _ => Some(mapped_char),
-                    }
-                }
-            }
-            KeyCode::Backspace => Some('\x08'),
-            KeyCode::Enter => Some('\n'),
-            KeyCode::Tab => Some('\t'),
-            _ => None,
-        }
+    fn lookup(&self, keycode: KeyCode, modifiers: Modifiers) -> Option<KeySym> {
+        table_lookup(keycode, modifiers, Self::key)
+    }
+}
+
+/// US Dvorak keyboard layout
+///
+/// The keys are rearranged; each one types what the US key of the same
+/// character does.
+pub struct DvorakLayout;
+
+impl DvorakLayout {
+    /// The US key that types what the Dvorak key at the position of US
+    /// key `c` does
+    fn remap(c: char) -> char {
+        const US: &str = "-=qwertyuiop[]asdfghjkl;'zxcvbnm,./";
+        const DVORAK: &str = "[]',.pyfgcrl/=aoeuidhtns-;qjkxbmwvz";
+        US.chars().position(|us| us == c).and_then(|index| DVORAK.chars().nth(index)).unwrap_or(c)
+    }
+}
+
+impl KeyboardLayout for DvorakLayout {
+    fn name(&self) -> &'static str {
+        "Dvorak"
+    }
+
+    fn to_char(&self, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char> {
+        let keycode = match keycode {
+            KeyCode::Char(c) => KeyCode::Char(Self::remap(c)),
+            other => other,
+        };
+        UsLayout.to_char(keycode, shift, caps_lock)
     }
 }
 
 /// Global keyboard layout manager
 pub struct LayoutManager {
     current_layout: LayoutType,
+    /// Accent waiting for the next key
+    pending_dead: Option<DeadKey>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -260,12 +427,35 @@ pub enum LayoutType {
     Uk,
     De,
     Fr,
+    Dvorak,
+}
+
+impl LayoutType {
+    /// Every layout, in the order `loadkeys` lists them
+    pub const ALL: [LayoutType; 5] = [LayoutType::Us, LayoutType::Uk, LayoutType::De, LayoutType::Fr, LayoutType::Dvorak];
+
+    /// The layout implementation
+    pub fn layout(self) -> &'static dyn KeyboardLayout {
+        match self {
+            LayoutType::Us => &UsLayout,
+            LayoutType::Uk => &UkLayout,
+            LayoutType::De => &DeLayout,
+            LayoutType::Fr => &FrLayout,
+            LayoutType::Dvorak => &DvorakLayout,
+        }
+    }
+
+    /// Find a layout by name, ignoring case
+    pub fn from_name(name: &str) -> Option<LayoutType> {
+        Self::ALL.into_iter().find(|layout| layout.layout().name().eq_ignore_ascii_case(name))
+    }
 }
 
 impl LayoutManager {
     pub const fn new() -> Self {
         Self {
             current_layout: LayoutType::Us,
+            pending_dead: None,
         }
     }
     
@@ -277,33 +467,58 @@ impl LayoutManager {
     /// Set the current layout
     pub fn set_layout(&mut self, layout: LayoutType) {
         self.current_layout = layout;
+        self.pending_dead = None;
+    }
+
+    /// Switch to the layout called `name`, ignoring case
+    pub fn set_layout_by_name(&mut self, name: &str) -> Result<(), &'static str> {
+        let layout = LayoutType::from_name(name).ok_or("Unknown keyboard layout")?;
+        self.set_layout(layout);
+        Ok(())
     }
     
     /// Convert a keycode to character using the current layout
     pub fn to_char(&self, keycode: KeyCode, shift: bool, caps_lock: bool) -> Option<char> {
-        match self.current_layout {
-            LayoutType::Us => UsLayout.to_char(keycode, shift, caps_lock),
-            LayoutType::Uk => UkLayout.to_char(keycode, shift, caps_lock),
-            LayoutType::De => DeLayout.to_char(keycode, shift, caps_lock),
-            LayoutType::Fr => FrLayout.to_char(keycode, shift, caps_lock),
+        self.current_layout.layout().to_char(keycode, shift, caps_lock)
+    }
+
+    /// Character a key press types, going through dead keys
+    ///
+    /// A dead key types nothing and its accent goes on the next character
+    /// key: on a letter that has it, alone on space or a second press of
+    /// the dead key, and is dropped otherwise. Other keys leave it pending.
+    pub fn translate(&mut self, keycode: KeyCode, modifiers: Modifiers) -> Option<char> {
+        let sym = self.current_layout.layout().lookup(keycode, modifiers)?;
+        let Some(dead) = self.pending_dead.take() else {
+            return match sym {
+                KeySym::Char(c) => Some(c),
+                KeySym::Dead(dead) => {
+                    self.pending_dead = Some(dead);
+                    None
+                }
+            };
+        };
+        match sym {
+            KeySym::Char(' ') => Some(dead.spacing()),
+            KeySym::Char(c) => Some(dead.compose(c).unwrap_or(c)),
+            KeySym::Dead(next) if next == dead => Some(dead.spacing()),
+            KeySym::Dead(next) => {
+                self.pending_dead = Some(next);
+                None
+            }
         }
     }
     
     /// Get the name of the current layout
     pub fn current_layout_name(&self) -> &'static str {
-        match self.current_layout {
-            LayoutType::Us => UsLayout.name(),
-            LayoutType::Uk => UkLayout.name(),
-            LayoutType::De => DeLayout.name(),
-            LayoutType::Fr => FrLayout.name(),
-        }
+        self.current_layout.layout().name()
     }
 }
 
 /// Global layout manager
 /// Note: This is accessed from interrupt context, so we use a simple static.
-/// The layout is typically set once during initialization and then only read,
-/// making this safe. Future improvements could use a lock-free atomic pointer.
+/// The layout is switched rarely (by `loadkeys` or the keymap ioctl) with a
+/// single store, and only the keyboard interrupt tracks dead keys.
 static mut LAYOUT_MANAGER: LayoutManager = LayoutManager::new();
 
 /// Get a mutable reference to the global layout manager
@@ -352,4 +567,103 @@ mod tests {
         let result = mgr.to_char(KeyCode::Char('a'), false, false);
         assert_eq!(result, Some('a'));
     }
+
+    fn altgr() -> Modifiers {
+        Modifiers { altgr: true, ..Modifiers::default() }
+    }
+
+    #[test]
+    fn test_layout_registry() {
+        assert_eq!(LayoutType::from_name("fr"), Some(LayoutType::Fr));
+        assert_eq!(LayoutType::from_name("DVORAK"), Some(LayoutType::Dvorak));
+        assert_eq!(LayoutType::from_name("xx"), None);
+        for layout in LayoutType::ALL {
+            assert_eq!(LayoutType::from_name(layout.layout().name()), Some(layout));
+        }
+
+        let mut mgr = LayoutManager::new();
+        assert!(mgr.set_layout_by_name("de").is_ok());
+        assert_eq!(mgr.current_layout_name(), "DE");
+        assert!(mgr.set_layout_by_name("nope").is_err());
+        assert_eq!(mgr.current_layout(), LayoutType::De);
+    }
+
+    #[test]
+    fn test_de_layout() {
+        let layout = DeLayout;
+        let plain = Modifiers::default();
+        let shift = Modifiers { shift: true, ..plain };
+        assert_eq!(layout.lookup(KeyCode::Char('y'), plain), Some(KeySym::Char('z')));
+        assert_eq!(layout.lookup(KeyCode::Char('z'), shift), Some(KeySym::Char('Y')));
+        assert_eq!(layout.lookup(KeyCode::Char(';'), shift), Some(KeySym::Char('Ö')));
+        assert_eq!(layout.lookup(KeyCode::Char('-'), plain), Some(KeySym::Char('ß')));
+        assert_eq!(layout.lookup(KeyCode::Char('q'), altgr()), Some(KeySym::Char('@')));
+        assert_eq!(layout.lookup(KeyCode::Char('7'), altgr()), Some(KeySym::Char('{')));
+        assert_eq!(layout.lookup(KeyCode::Iso102, shift), Some(KeySym::Char('>')));
+        assert_eq!(layout.lookup(KeyCode::Char('a'), altgr()), None);
+        // Caps Lock shifts umlauts but not digits
+        let caps = Modifiers { caps_lock: true, ..plain };
+        assert_eq!(layout.to_char(KeyCode::Char('\''), false, true), Some('Ä'));
+        assert_eq!(layout.lookup(KeyCode::Char('2'), caps), Some(KeySym::Char('2')));
+        assert_eq!(layout.to_char(KeyCode::Enter, false, false), Some('\n'));
+    }
+
+    #[test]
+    fn test_fr_layout() {
+        let layout = FrLayout;
+        assert_eq!(layout.name(), "FR");
+        assert_eq!(layout.to_char(KeyCode::Char('q'), false, false), Some('a'));
+        assert_eq!(layout.to_char(KeyCode::Char('a'), true, false), Some('Q'));
+        assert_eq!(layout.to_char(KeyCode::Char(';'), false, false), Some('m'));
+        assert_eq!(layout.to_char(KeyCode::Char('m'), false, false), Some(','));
+        assert_eq!(layout.to_char(KeyCode::Char('2'), false, false), Some('é'));
+        assert_eq!(layout.to_char(KeyCode::Char('2'), true, false), Some('2'));
+        assert_eq!(layout.lookup(KeyCode::Char('0'), altgr()), Some(KeySym::Char('@')));
+        // Dead keys type their accent through the plain interface
+        assert_eq!(layout.to_char(KeyCode::Char('['), false, false), Some('^'));
+    }
+
+    #[test]
+    fn test_dvorak_layout() {
+        let layout = DvorakLayout;
+        assert_eq!(layout.to_char(KeyCode::Char('s'), false, false), Some('o'));
+        assert_eq!(layout.to_char(KeyCode::Char('q'), true, false), Some('"'));
+        assert_eq!(layout.to_char(KeyCode::Char('z'), true, false), Some(':'));
+        assert_eq!(layout.to_char(KeyCode::Char('1'), false, false), Some('1'));
+    }
+
+    #[test]
+    fn test_dead_keys() {
+        let mut mgr = LayoutManager::new();
+        mgr.set_layout(LayoutType::Fr);
+        let plain = Modifiers::default();
+        let shift = Modifiers { shift: true, ..plain };
+        let mut type_key = |code: char, modifiers| mgr.translate(KeyCode::Char(code), modifiers);
+
+        // ^ then e
+        assert_eq!(type_key('[', plain), None);
+        assert_eq!(type_key('e', plain), Some('ê'));
+        // ¨ then i, with a shift press in between
+        assert_eq!(type_key('[', shift), None);
+        assert_eq!(type_key('i', plain), Some('ï'));
+        // ^ then space, and ^ twice
+        assert_eq!(type_key('[', plain), None);
+        assert_eq!(type_key(' ', plain), Some('^'));
+        assert_eq!(type_key('[', plain), None);
+        assert_eq!(type_key('[', plain), Some('^'));
+        // No accented form: the accent is dropped
+        assert_eq!(type_key('[', plain), None);
+        assert_eq!(type_key('x', plain), Some('x'));
+
+        // Non-character keys leave the accent pending
+        let mut mgr = LayoutManager::new();
+        mgr.set_layout(LayoutType::De);
+        assert_eq!(mgr.translate(KeyCode::Char('='), plain), None);
+        assert_eq!(mgr.translate(KeyCode::LeftShift, shift), None);
+        assert_eq!(mgr.translate(KeyCode::Char('e'), shift), Some('É'));
+        // Switching layouts drops it
+        assert_eq!(mgr.translate(KeyCode::Char('`'), plain), None);
+        mgr.set_layout(LayoutType::De);
+        assert_eq!(mgr.translate(KeyCode::Char('a'), plain), Some('a'));
+    }
 }
@@ -10,10 +10,47 @@ pub mod port;
 pub mod serial;
 pub mod context;
 pub mod syscall;
+pub mod user_return;
+pub mod tls;
+pub mod percpu;
+pub mod tsc;
+pub mod protection;
+pub mod uaccess;
+pub mod backtrace;
+pub mod rtc;
+pub mod cpuid;
+pub mod speculation;
+pub mod cpufreq;
+pub mod thermal;
+pub mod sleep;
+pub mod pat;
+pub mod rdrand;
 
 pub fn init() {
     serial::init();
+    let cpu = cpuid::cpu_features();
+    serial_println!(
+        "[CPU] {} family {:#x} model {:#x} stepping {}{}",
+        cpu.vendor_id(),
+        cpu.family(),
+        cpu.model(),
+        cpu.stepping(),
+        if cpu.hypervisor_id().is_some() { " (virtualized)" } else { "" }
+    );
+    serial_println!("[CPU] Features: {}", cpu);
     gdt::init();
+    tls::init();
+    let features = protection::init();
+    serial_println!(
+        "[CPU] NX={} SMEP={} SMAP={} UMIP={}",
+        features.contains(protection::Features::NX),
+        features.contains(protection::Features::SMEP),
+        features.contains(protection::Features::SMAP),
+        features.contains(protection::Features::UMIP)
+    );
+    if !pat::init() {
+        serial_println!("[CPU] No PAT, framebuffer writes will not be combined");
+    }
     interrupts::idt::init();
 
     // Try to initialize APIC, fall back to PIC if not available
@@ -35,6 +72,56 @@ pub fn init() {
     }
 }
 
+/// Per-CPU initialization of an application processor
+///
+/// Loads the CPU's own GDT and TSS and the shared IDT, and sets up the
+/// CPU-local state the boot CPU configured in `init()`. Interrupts stay
+/// disabled.
+///
+/// # Safety
+/// Must be called once, on CPU `cpu`, with IST stacks that stay valid.
+pub unsafe fn init_ap(cpu: usize, stacks: &gdt::IstStacks) {
+    if let Err(e) = gdt::init_cpu(cpu, stacks) {
+        serial_println!("[GDT] CPU {}: {}", cpu, e);
+    }
+    tls::init();
+    protection::init();
+    speculation::init_cpu();
+    pat::init();
+    interrupts::idt::load();
+
+    if let Err(e) = interrupts::apic::init_cpu() {
+        serial_println!("[APIC] not available on this CPU: {}", e);
+    }
+
+    syscall::init();
+}
+
+/// Per-CPU state of a CPU woken from ACPI sleep
+///
+/// Reloads what `sleep::SleepContext` saved, then the descriptor tables,
+/// the GS base and the CPU-local MSRs `init()` and `init_ap()` set up.
+/// Interrupts stay disabled.
+///
+/// # Safety
+/// Must be the first thing CPU `cpu` runs after the wakeup trampoline.
+pub unsafe fn resume_cpu(cpu: usize, ctx: &sleep::SleepContext) {
+    ctx.restore_cpu_state();
+    if let Err(e) = gdt::reload_cpu(cpu) {
+        serial_println!("[GDT] CPU {}: {}", cpu, e);
+    }
+    interrupts::idt::load();
+    percpu::reload_cpu(cpu);
+    speculation::init_cpu();
+    pat::init();
+
+    if let Err(e) = interrupts::apic::init_cpu() {
+        serial_println!("[APIC] not available on this CPU: {}", e);
+    }
+
+    syscall::init();
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {{
@@ -2,6 +2,12 @@
 ///
 /// This module provides a PS/2 mouse driver that handles mouse movement
 /// and button events.
+///
+/// The mouse sits on the auxiliary port of the PS/2 controller and raises
+/// IRQ12. A plain mouse sends 3-byte packets; IntelliMouse-compatible
+/// devices switch to 4-byte packets carrying a scroll wheel (device ID 3)
+/// and two extra buttons (device ID 4) after the "magic" sample rate
+/// sequences 200/100/80 and 200/200/80.
 
 use crate::port::{inb, outb};
 
@@ -9,12 +15,85 @@ const PS2_DATA_PORT: u16 = 0x60;
 const PS2_STATUS_PORT: u16 = 0x64;
 const PS2_COMMAND_PORT: u16 = 0x64;
 
+/// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+/// Controller configuration byte: aux IRQ enable, aux clock disable
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+/// Mouse commands
+const MOUSE_GET_ID: u8 = 0xF2;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ACK: u8 = 0xFA;
+
+/// First packet byte: buttons, always-one sync bit, sign and overflow bits
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_SYNC: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Fourth packet byte of a 5-button mouse: buttons 4 and 5
+const PACKET_BUTTON4: u8 = 1 << 4;
+const PACKET_BUTTON5: u8 = 1 << 5;
+
+/// Status polls before a controller access gives up
+const POLL_LIMIT: usize = 100_000;
+
+/// Mouse protocol, from the device ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// 3 buttons, 3-byte packets (ID 0)
+    Standard,
+    /// Scroll wheel, 4-byte packets (ID 3)
+    Wheel,
+    /// Scroll wheel and buttons 4 and 5, 4-byte packets (ID 4)
+    FiveButton,
+}
+
+impl MouseKind {
+    /// Protocol for a device ID
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            3 => MouseKind::Wheel,
+            4 => MouseKind::FiveButton,
+            _ => MouseKind::Standard,
+        }
+    }
+
+    /// Bytes per movement packet
+    pub fn packet_size(self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            MouseKind::Wheel | MouseKind::FiveButton => 4,
+        }
+    }
+}
+
 /// Mouse buttons
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MouseButtons {
     pub left: bool,
     pub right: bool,
     pub middle: bool,
+    /// Button 4 ("back"), 5-button mice only
+    pub back: bool,
+    /// Button 5 ("forward"), 5-button mice only
+    pub forward: bool,
 }
 
 impl MouseButtons {
@@ -23,6 +102,8 @@ impl MouseButtons {
             left: false,
             right: false,
             middle: false,
+            back: false,
+            forward: false,
         }
     }
 }
@@ -33,7 +114,7 @@ pub struct MousePacket {
     pub buttons: MouseButtons,
     pub x_movement: i16,
     pub y_movement: i16,
-    pub z_movement: i8, // Scroll wheel
+    pub z_movement: i8, // Scroll wheel, positive is towards the user
 }
 
 /// Mouse state tracker
@@ -41,17 +122,18 @@ pub struct Mouse {
     // Packet assembly
     packet_bytes: [u8; 4],
     packet_index: usize,
-    
+    kind: MouseKind,
+
     // Current state
     x: i32,
     y: i32,
     buttons: MouseButtons,
-    
+
     // Accumulated movement since last read
     delta_x: i32,
     delta_y: i32,
     scroll: i32,
-    
+
     // Mouse enabled
     enabled: bool,
 }
@@ -61,6 +143,7 @@ impl Mouse {
         Self {
             packet_bytes: [0; 4],
             packet_index: 0,
+            kind: MouseKind::Standard,
             x: 0,
             y: 0,
             buttons: MouseButtons::new(),
@@ -70,131 +153,151 @@ impl Mouse {
             enabled: false,
         }
     }
-    
+
     /// Initialize the PS/2 mouse
+    ///
+    /// Enables the auxiliary port and its IRQ, probes for a scroll wheel
+    /// and extra buttons and turns on data reporting.
     pub fn init(&mut self) -> Result<(), &'static str> {
-        // Wait for controller to be ready
-        self.wait_for_write();
-        
         // Enable auxiliary device (mouse)
-        unsafe {
-            outb(PS2_COMMAND_PORT, 0xA8);
-        }
-        
-        // Wait for controller
-        self.wait_for_write();
-        
+        self.write_command(CMD_ENABLE_AUX)?;
+
         // Enable interrupts for mouse
-        unsafe {
-            outb(PS2_COMMAND_PORT, 0x20); // Read command byte
-        }
-        
-        self.wait_for_read();
-        let status = unsafe { inb(PS2_DATA_PORT) };
-        
-        self.wait_for_write();
-        unsafe {
-            outb(PS2_COMMAND_PORT, 0x60); // Write command byte
-        }
-        
-        self.wait_for_write();
-        unsafe {
-            outb(PS2_DATA_PORT, status | 0x02); // Enable mouse IRQ
-        }
-        
+        self.write_command(CMD_READ_CONFIG)?;
+        let config = self.read_data()?;
+        self.write_command(CMD_WRITE_CONFIG)?;
+        self.write_data((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
         // Set default settings
-        self.write_mouse(0xF6)?;
-        self.read_ack()?;
-        
+        self.command(MOUSE_SET_DEFAULTS)?;
+
+        // Knock for the wheel, then for buttons 4 and 5
+        let mut kind = self.knock(&[200, 100, 80])?;
+        if kind == MouseKind::Wheel {
+            kind = self.knock(&[200, 200, 80])?;
+        }
+        self.kind = kind;
+        self.packet_index = 0;
+
         // Enable data reporting
-        self.write_mouse(0xF4)?;
-        self.read_ack()?;
-        
+        self.command(MOUSE_ENABLE_REPORTING)?;
+
         self.enabled = true;
         Ok(())
     }
-    
+
+    /// Send a sample rate sequence and read back the device ID
+    fn knock(&self, rates: &[u8]) -> Result<MouseKind, &'static str> {
+        for &rate in rates {
+            self.command(MOUSE_SET_SAMPLE_RATE)?;
+            self.command(rate)?;
+        }
+        self.command(MOUSE_GET_ID)?;
+        Ok(MouseKind::from_id(self.read_data()?))
+    }
+
     /// Process a byte from the mouse
     pub fn process_byte(&mut self, byte: u8) -> Option<MousePacket> {
         // First byte has bit 3 set
-        if self.packet_index == 0 && (byte & 0x08) == 0 {
+        if self.packet_index == 0 && (byte & PACKET_SYNC) == 0 {
             // Invalid packet, discard
             return None;
         }
-        
+
         self.packet_bytes[self.packet_index] = byte;
         self.packet_index += 1;
-        
-        // Standard PS/2 mouse uses 3-byte packets
-        if self.packet_index >= 3 {
+
+        if self.packet_index >= self.kind.packet_size() {
             self.packet_index = 0;
-            
+
             let packet = self.parse_packet();
-            
+
             // Update state
             self.buttons = packet.buttons;
             self.delta_x += packet.x_movement as i32;
             self.delta_y += packet.y_movement as i32;
+            self.scroll += packet.z_movement as i32;
             self.x += packet.x_movement as i32;
             self.y += packet.y_movement as i32;
-            
+
             return Some(packet);
         }
-        
+
         None
     }
-    
-    /// Parse a 3-byte mouse packet
+
+    /// Parse a complete 3- or 4-byte mouse packet
     fn parse_packet(&self) -> MousePacket {
         let byte0 = self.packet_bytes[0];
         let byte1 = self.packet_bytes[1];
         let byte2 = self.packet_bytes[2];
-        
+        let byte3 = self.packet_bytes[3];
+
         // Extract button states
+        let extra_buttons = self.kind == MouseKind::FiveButton;
         let buttons = MouseButtons {
-            left: (byte0 & 0x01) != 0,
-            right: (byte0 & 0x02) != 0,
-            middle: (byte0 & 0x04) != 0,
+            left: (byte0 & PACKET_LEFT) != 0,
+            right: (byte0 & PACKET_RIGHT) != 0,
+            middle: (byte0 & PACKET_MIDDLE) != 0,
+            back: extra_buttons && (byte3 & PACKET_BUTTON4) != 0,
+            forward: extra_buttons && (byte3 & PACKET_BUTTON5) != 0,
         };
-        
-        // Extract movement (9-bit signed values)
+
+        // Extract movement (9-bit signed values); an overflowed axis is garbage
         let mut x_movement = byte1 as i16;
-        if (byte0 & 0x10) != 0 {
+        if (byte0 & PACKET_X_SIGN) != 0 {
             x_movement |= 0xFF00u16 as i16; // Sign extend
         }
-        
+        if (byte0 & PACKET_X_OVERFLOW) != 0 {
+            x_movement = 0;
+        }
+
         let mut y_movement = byte2 as i16;
-        if (byte0 & 0x20) != 0 {
+        if (byte0 & PACKET_Y_SIGN) != 0 {
             y_movement |= 0xFF00u16 as i16; // Sign extend
         }
-        
+        if (byte0 & PACKET_Y_OVERFLOW) != 0 {
+            y_movement = 0;
+        }
+
         // Y is inverted in PS/2 protocol
         y_movement = -y_movement;
-        
+
+        // The wheel is a 4-bit signed value in the low nibble of byte 3
+        let z_movement = match self.kind {
+            MouseKind::Standard => 0,
+            MouseKind::Wheel | MouseKind::FiveButton => ((byte3 << 4) as i8) >> 4,
+        };
+
         MousePacket {
             buttons,
             x_movement,
             y_movement,
-            z_movement: 0, // Standard PS/2 mouse doesn't have scroll wheel
+            z_movement,
         }
     }
-    
+
+    /// Get the detected protocol
+    pub fn kind(&self) -> MouseKind {
+        self.kind
+    }
+
     /// Get current mouse position
     pub fn position(&self) -> (i32, i32) {
         (self.x, self.y)
     }
-    
+
     /// Set mouse position (for bounds enforcement)
     pub fn set_position(&mut self, x: i32, y: i32) {
         self.x = x;
         self.y = y;
     }
-    
+
     /// Get current button state
     pub fn buttons(&self) -> MouseButtons {
         self.buttons
     }
-    
+
     /// Get and clear accumulated movement
     pub fn take_movement(&mut self) -> (i32, i32, i32) {
         let delta_x = self.delta_x;
@@ -205,58 +308,66 @@ impl Mouse {
         self.scroll = 0;
         (delta_x, delta_y, scroll)
     }
-    
+
     /// Check if mouse is enabled
     pub fn is_enabled(&self) -> bool {
         self.enabled
     }
-    
-    /// Write a command to the mouse
-    fn write_mouse(&self, cmd: u8) -> Result<(), &'static str> {
-        self.wait_for_write();
-        
-        unsafe {
-            outb(PS2_COMMAND_PORT, 0xD4); // Tell controller we're writing to mouse
+
+    /// Send a command to the mouse and wait for its acknowledgement
+    fn command(&self, cmd: u8) -> Result<(), &'static str> {
+        self.write_command(CMD_WRITE_AUX)?;
+        self.write_data(cmd)?;
+
+        if self.read_data()? == MOUSE_ACK {
+            Ok(())
+        } else {
+            Err("Mouse did not acknowledge")
         }
-        
-        self.wait_for_write();
-        
+    }
+
+    /// Write a command to the controller
+    fn write_command(&self, cmd: u8) -> Result<(), &'static str> {
+        self.wait_for_write()?;
         unsafe {
-            outb(PS2_DATA_PORT, cmd);
+            outb(PS2_COMMAND_PORT, cmd);
         }
-        
         Ok(())
     }
-    
-    /// Read acknowledgment from mouse
-    fn read_ack(&self) -> Result<(), &'static str> {
-        self.wait_for_read();
-        
-        let response = unsafe { inb(PS2_DATA_PORT) };
-        
-        if response == 0xFA {
-            Ok(())
-        } else {
-            Err("Mouse did not acknowledge")
+
+    /// Write a byte to the controller's data port
+    fn write_data(&self, byte: u8) -> Result<(), &'static str> {
+        self.wait_for_write()?;
+        unsafe {
+            outb(PS2_DATA_PORT, byte);
         }
+        Ok(())
+    }
+
+    /// Read a byte from the controller's data port
+    fn read_data(&self) -> Result<u8, &'static str> {
+        self.wait_for_read()?;
+        Ok(unsafe { inb(PS2_DATA_PORT) })
     }
-    
+
     /// Wait for controller to be ready for writing
-    fn wait_for_write(&self) {
-        for _ in 0..1000 {
-            if (unsafe { inb(PS2_STATUS_PORT) } & 0x02) == 0 {
-                return;
+    fn wait_for_write(&self) -> Result<(), &'static str> {
+        for _ in 0..POLL_LIMIT {
+            if (unsafe { inb(PS2_STATUS_PORT) } & STATUS_INPUT_FULL) == 0 {
+                return Ok(());
             }
         }
+        Err("PS/2 controller input buffer stuck full")
     }
-    
+
     /// Wait for controller to have data ready for reading
-    fn wait_for_read(&self) {
-        for _ in 0..1000 {
-            if (unsafe { inb(PS2_STATUS_PORT) } & 0x01) != 0 {
-                return;
+    fn wait_for_read(&self) -> Result<(), &'static str> {
+        for _ in 0..POLL_LIMIT {
+            if (unsafe { inb(PS2_STATUS_PORT) } & STATUS_OUTPUT_FULL) != 0 {
+                return Ok(());
             }
         }
+        Err("PS/2 controller timed out")
     }
 }
 
@@ -273,12 +384,28 @@ pub type MouseCallback = fn(MousePacket);
 static mut MOUSE_CALLBACK: Option<MouseCallback> = None;
 
 /// Initialize the PS/2 mouse
-pub fn init() -> Result<(), &'static str> {
+///
+/// Runs with interrupts disabled so the IRQ12 handler cannot take the
+/// command responses.
+pub fn init() -> Result<MouseKind, &'static str> {
     #[cfg(not(test))]
-    unsafe { MOUSE.init() }
-    
+    unsafe {
+        let mouse = &mut *core::ptr::addr_of_mut!(MOUSE);
+        crate::interrupts::without_interrupts(|| mouse.init())?;
+        Ok(mouse.kind())
+    }
+
     #[cfg(test)]
-    Ok(())
+    Ok(MouseKind::Standard)
+}
+
+/// Check whether the controller holds a byte from the mouse
+///
+/// IRQ1 and IRQ12 share the data port, so the IRQ12 handler checks that
+/// the pending byte really is auxiliary data.
+pub fn aux_data_pending() -> bool {
+    let status = unsafe { inb(PS2_STATUS_PORT) };
+    status & STATUS_OUTPUT_FULL != 0 && status & STATUS_AUX_DATA != 0
 }
 
 /// Set the mouse event callback
@@ -302,3 +429,47 @@ pub(crate) fn dispatch_packet(packet: MousePacket) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(mouse: &mut Mouse, bytes: &[u8]) -> Option<MousePacket> {
+        bytes.iter().fold(None, |_, &b| mouse.process_byte(b))
+    }
+
+    #[test]
+    fn test_standard_packet() {
+        let mut mouse = Mouse::new();
+        // Out-of-sync byte is dropped
+        assert!(mouse.process_byte(0x00).is_none());
+
+        // Left button, x = +5, y = -3 (up on screen is negative)
+        let packet = feed(&mut mouse, &[PACKET_SYNC | PACKET_LEFT | PACKET_Y_SIGN, 5, 0xFD]).unwrap();
+        assert!(packet.buttons.left && !packet.buttons.right);
+        assert_eq!((packet.x_movement, packet.y_movement, packet.z_movement), (5, 3, 0));
+        assert_eq!(mouse.position(), (5, 3));
+
+        // Overflowed axis is ignored
+        let packet = feed(&mut mouse, &[PACKET_SYNC | PACKET_X_OVERFLOW, 0xFF, 1]).unwrap();
+        assert_eq!((packet.x_movement, packet.y_movement), (0, -1));
+        assert_eq!(mouse.take_movement(), (5, 2, 0));
+    }
+
+    #[test]
+    fn test_wheel_packets() {
+        let mut mouse = Mouse::new();
+        mouse.kind = MouseKind::from_id(3);
+        assert_eq!(mouse.kind().packet_size(), 4);
+
+        assert!(feed(&mut mouse, &[PACKET_SYNC, 0, 0]).is_none());
+        let packet = mouse.process_byte(0x0F).unwrap();
+        assert_eq!(packet.z_movement, -1);
+
+        mouse.kind = MouseKind::from_id(4);
+        let packet = feed(&mut mouse, &[PACKET_SYNC, 0, 0, PACKET_BUTTON5 | 0x02]).unwrap();
+        assert_eq!(packet.z_movement, 2);
+        assert!(packet.buttons.forward && !packet.buttons.back);
+        assert_eq!(mouse.take_movement(), (0, 0, 1));
+    }
+}
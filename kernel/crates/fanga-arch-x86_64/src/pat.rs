@@ -0,0 +1,112 @@
+//! Page Attribute Table
+//!
+//! The PAT maps the PAT, PCD and PWT bits of a page table entry to a
+//! memory type. At power-on, entries 0-3 (selected by PCD and PWT alone)
+//! are write-back, write-through, uncached-minus and uncached. `init`
+//! turns entry 1 into write-combining, so a mapping with only PWT set is
+//! write-combining; nothing in the kernel maps memory write-through.
+//! The other entries are left as the firmware and bootloader set them.
+//!
+//! Write-combining lets the CPU merge stores into full bursts, which
+//! makes copying to a framebuffer many times faster than uncached writes.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpuid::{cpu_features, Feature};
+
+/// Page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+
+/// Memory type encodings
+pub const UNCACHED: u8 = 0x00;
+pub const WRITE_COMBINING: u8 = 0x01;
+pub const WRITE_THROUGH: u8 = 0x04;
+pub const WRITE_PROTECTED: u8 = 0x05;
+pub const WRITE_BACK: u8 = 0x06;
+pub const UNCACHED_MINUS: u8 = 0x07;
+
+/// PAT entry selected by PWT alone
+const WC_ENTRY: u32 = 1;
+
+/// Whether `init` made entry 1 write-combining
+static WC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Replace the memory type of one PAT entry
+pub const fn with_entry(pat: u64, entry: u32, memory_type: u8) -> u64 {
+    let shift = entry * 8;
+    (pat & !(0xFF << shift)) | ((memory_type as u64) << shift)
+}
+
+/// Memory type of one PAT entry
+pub const fn entry(pat: u64, entry: u32) -> u8 {
+    (pat >> (entry * 8)) as u8 & 0x07
+}
+
+/// Make PAT entry 1 write-combining on this CPU
+///
+/// Called on the boot CPU and by each application processor, before any
+/// write-combining mapping is used.
+///
+/// # Returns
+/// Whether the CPU has a PAT
+pub fn init() -> bool {
+    if !cpu_features().has(Feature::Pat) {
+        return false;
+    }
+    let pat = rdmsr(IA32_PAT);
+    if entry(pat, WC_ENTRY) != WRITE_COMBINING {
+        unsafe { wrmsr(IA32_PAT, with_entry(pat, WC_ENTRY, WRITE_COMBINING)) };
+    }
+    WC_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Whether mappings with only PWT set are write-combining
+///
+/// Without a PAT they are write-through instead, which is still correct
+/// for a framebuffer, only slower.
+pub fn write_combining() -> bool {
+    WC_ENABLED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Power-on PAT value
+    const DEFAULT_PAT: u64 = 0x0007_0406_0007_0406;
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(entry(DEFAULT_PAT, 0), WRITE_BACK);
+        assert_eq!(entry(DEFAULT_PAT, 1), WRITE_THROUGH);
+        assert_eq!(entry(DEFAULT_PAT, 2), UNCACHED_MINUS);
+        assert_eq!(entry(DEFAULT_PAT, 3), UNCACHED);
+    }
+
+    #[test]
+    fn test_with_entry() {
+        let pat = with_entry(DEFAULT_PAT, WC_ENTRY, WRITE_COMBINING);
+        assert_eq!(pat, 0x0007_0406_0007_0106);
+        assert_eq!(entry(pat, 1), WRITE_COMBINING);
+        assert_eq!(entry(pat, 5), WRITE_THROUGH);
+    }
+}
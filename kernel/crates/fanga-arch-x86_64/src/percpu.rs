@@ -0,0 +1,187 @@
+//! GS-Based CPU-Local Storage
+//!
+//! In the kernel, the GS base points at the running CPU's per-CPU block, so
+//! CPU-local data is one `gs:`-relative load away. User mode owns GS too;
+//! its base is parked in IA32_KERNEL_GS_BASE while the kernel runs, and
+//! SWAPGS exchanges the two on every entry from and exit to user mode.
+//!
+//! Entries from kernel mode need no swap, except for NMIs and machine
+//! checks, which can hit the few instructions between a SYSCALL and its
+//! SWAPGS. Those "paranoid" entries check the GS base itself.
+//!
+//! SYSCALL does not switch stacks, so the entry code finds the kernel
+//! stack through GS as well; the kernel's per-CPU block reserves the words
+//! it uses.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::gdt::{self, MAX_CPUS};
+
+/// IA32_GS_BASE model specific register
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+/// IA32_KERNEL_GS_BASE model specific register (swapped in by SWAPGS)
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Per-CPU block word where SYSCALL entry parks the user stack pointer
+pub const GS_USER_RSP: usize = 16;
+/// Per-CPU block word holding the kernel stack SYSCALL entry switches to
+pub const GS_KERNEL_RSP: usize = 24;
+
+/// Set once the boot CPU's GS base points at its per-CPU block
+static GS_READY: AtomicBool = AtomicBool::new(false);
+
+/// Per-CPU block of each CPU, for paranoid entries
+static KERNEL_GS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Point this CPU's GS base at its per-CPU block
+///
+/// # Safety
+/// Must run on CPU `cpu` before it touches CPU-local data, and `block`
+/// must stay valid forever. Its first word must be its own address.
+pub unsafe fn init_cpu(cpu: usize, block: u64) {
+    wrmsr(IA32_GS_BASE, block);
+    wrmsr(IA32_KERNEL_GS_BASE, 0);
+    if let Some(slot) = KERNEL_GS.get(cpu) {
+        slot.store(block, Ordering::Relaxed);
+    }
+    set_syscall_stack(cpu, gdt::kernel_stack(cpu));
+    GS_READY.store(true, Ordering::Release);
+}
+
+/// Point this CPU's GS base at its per-CPU block again
+///
+/// Loading the data segments zeroes the GS base, and a wake from ACPI
+/// sleep loses it altogether.
+///
+/// # Safety
+/// Must run on CPU `cpu`, after `init_cpu`, before CPU-local data is used.
+pub unsafe fn reload_cpu(cpu: usize) {
+    if let Some(slot) = KERNEL_GS.get(cpu) {
+        wrmsr(IA32_GS_BASE, slot.load(Ordering::Relaxed));
+    }
+}
+
+/// Set the kernel stack that system calls on CPU `cpu` run on
+///
+/// Kept equal to TSS.RSP0 by `gdt::set_kernel_stack`. Does nothing until
+/// the CPU has a per-CPU block.
+pub fn set_syscall_stack(cpu: usize, stack_top: u64) {
+    let Some(block) = KERNEL_GS.get(cpu).map(|slot| slot.load(Ordering::Relaxed)) else {
+        return;
+    };
+    if block != 0 {
+        unsafe { core::ptr::write_volatile((block + GS_KERNEL_RSP as u64) as *mut u64, stack_top) };
+    }
+}
+
+/// Check whether GS-relative CPU-local data can be used
+#[inline]
+pub fn gs_ready() -> bool {
+    GS_READY.load(Ordering::Acquire)
+}
+
+/// Read the word at `offset` in this CPU's per-CPU block
+///
+/// # Safety
+/// `gs_ready()` must be true and `offset` within the block.
+#[inline(always)]
+pub unsafe fn read_gs(offset: usize) -> u64 {
+    let value: u64;
+    asm!("mov {}, gs:[{}]", out(reg) value, in(reg) offset, options(readonly, nostack, preserves_flags));
+    value
+}
+
+/// Kernel GS for an interrupt or exception handler
+///
+/// Swaps GS on entry from user mode and back when dropped, so it must be
+/// the handler's first local and outlive everything else in it.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// Enter from an interrupt frame's saved CS
+    #[inline(always)]
+    pub fn enter(cs: u64) -> Self {
+        let swapped = cs & 3 == 3;
+        if swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)) };
+        }
+        Self { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)) };
+        }
+    }
+}
+
+/// Kernel GS for NMI and machine check handlers
+///
+/// Finds the CPU from its GDT address and loads that CPU's block if GS
+/// does not already hold it, restoring the old base when dropped.
+pub struct ParanoidGs {
+    saved: Option<u64>,
+}
+
+impl ParanoidGs {
+    /// Enter a paranoid handler
+    pub fn enter() -> Self {
+        let kernel = gdt::current_cpu_index()
+            .filter(|_| gs_ready())
+            .map(|cpu| KERNEL_GS[cpu].load(Ordering::Relaxed))
+            .filter(|&block| block != 0);
+        let Some(kernel) = kernel else {
+            return Self { saved: None };
+        };
+
+        let current = rdmsr(IA32_GS_BASE);
+        if current == kernel {
+            return Self { saved: None };
+        }
+        unsafe { wrmsr(IA32_GS_BASE, kernel) };
+        Self { saved: Some(current) }
+    }
+}
+
+impl Drop for ParanoidGs {
+    fn drop(&mut self) {
+        if let Some(base) = self.saved {
+            unsafe { wrmsr(IA32_GS_BASE, base) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_entry_does_not_swap() {
+        let gs = KernelGs::enter(crate::gdt::KERNEL_CODE_SELECTOR as u64);
+        assert!(!gs.swapped);
+    }
+}
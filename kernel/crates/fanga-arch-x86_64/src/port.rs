@@ -42,6 +42,27 @@ pub unsafe fn inw(port: u16) -> u16 {
     value
 }
 
+/// Writes a double word (32-bit) to the specified I/O port.
+///
+/// # Safety
+/// This function is unsafe because writing to arbitrary I/O ports can cause
+/// undefined behavior, system instability, or hardware damage if the port
+/// and value are not valid for the system.
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a double word (32-bit) from the specified I/O port.
+///
+/// # Safety
+/// This function is unsafe because reading from arbitrary I/O ports can cause
+/// undefined behavior or system instability if the port is not valid for the system.
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", in("dx") port, out("eax") value, options(nomem, nostack, preserves_flags));
+    value
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,5 +87,7 @@ mod tests {
         let _inb_fn = inb;
         let _outw_fn = outw;
         let _inw_fn = inw;
+        let _outl_fn = outl;
+        let _inl_fn = inl;
     }
 }
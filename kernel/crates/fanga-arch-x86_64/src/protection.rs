@@ -0,0 +1,196 @@
+//! CPU Protection Features
+//!
+//! Turns on the paging and privilege checks that keep user space and the
+//! kernel apart, on every CPU, when CPUID reports them:
+//! - NX (EFER.NXE): pages mapped NO_EXECUTE cannot be executed
+//! - SMEP (CR4.SMEP): the kernel faults when executing a user page
+//! - SMAP (CR4.SMAP): the kernel faults when touching a user page, except
+//!   inside the `uaccess` copy helpers
+//! - UMIP (CR4.UMIP): SGDT, SIDT, SLDT, SMSW and STR fault in user mode,
+//!   so user code cannot learn kernel addresses from them
+
+use crate::cpuid::{cpu_features, Feature};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// CR4 bits
+#[cfg(not(test))]
+const CR4_UMIP: u64 = 1 << 11;
+#[cfg(not(test))]
+const CR4_SMEP: u64 = 1 << 20;
+#[cfg(not(test))]
+const CR4_SMAP: u64 = 1 << 21;
+
+/// IA32_EFER and its no-execute enable bit
+#[cfg(not(test))]
+const IA32_EFER: u32 = 0xC000_0080;
+#[cfg(not(test))]
+const EFER_NXE: u64 = 1 << 11;
+
+/// CPUID.(EAX=7,ECX=0) feature bits
+const CPUID7_EBX_SMEP: u32 = 1 << 7;
+const CPUID7_EBX_SMAP: u32 = 1 << 20;
+const CPUID7_ECX_UMIP: u32 = 1 << 2;
+
+/// CPUID.80000001h:EDX execute-disable bit
+const CPUID_EXT_EDX_NX: u32 = 1 << 20;
+
+/// A set of protection features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features(u8);
+
+impl Features {
+    /// No-execute pages
+    pub const NX: Self = Self(1 << 0);
+    /// Supervisor mode execution prevention
+    pub const SMEP: Self = Self(1 << 1);
+    /// Supervisor mode access prevention
+    pub const SMAP: Self = Self(1 << 2);
+    /// User mode instruction prevention
+    pub const UMIP: Self = Self(1 << 3);
+
+    /// Create an empty set
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Check if the set contains a feature
+    pub const fn contains(&self, feature: Self) -> bool {
+        self.0 & feature.0 != 0
+    }
+
+    /// Add a feature
+    pub const fn with(self, feature: Self) -> Self {
+        Self(self.0 | feature.0)
+    }
+
+    /// Get raw value
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Decode the CPUID leaf 7 EBX/ECX and leaf 80000001h EDX registers
+    pub const fn from_cpuid(leaf7_ebx: u32, leaf7_ecx: u32, ext_edx: u32) -> Self {
+        let mut features = Self::empty();
+        if ext_edx & CPUID_EXT_EDX_NX != 0 {
+            features = features.with(Self::NX);
+        }
+        if leaf7_ebx & CPUID7_EBX_SMEP != 0 {
+            features = features.with(Self::SMEP);
+        }
+        if leaf7_ebx & CPUID7_EBX_SMAP != 0 {
+            features = features.with(Self::SMAP);
+        }
+        if leaf7_ecx & CPUID7_ECX_UMIP != 0 {
+            features = features.with(Self::UMIP);
+        }
+        features
+    }
+}
+
+/// Features this CPU supports
+pub fn detect() -> Features {
+    let cpu = cpu_features();
+    let mut features = Features::empty();
+    for (feature, flag) in [
+        (Feature::Nx, Features::NX),
+        (Feature::Smep, Features::SMEP),
+        (Feature::Smap, Features::SMAP),
+        (Feature::Umip, Features::UMIP),
+    ] {
+        if cpu.has(feature) {
+            features = features.with(flag);
+        }
+    }
+    features
+}
+
+/// Features enabled by `init`
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+/// Enable the supported protection features on this CPU
+///
+/// Called by every CPU during its arch init. The kernel must not touch
+/// user pages outside the `uaccess` helpers once this has run.
+pub fn init() -> Features {
+    let features = detect();
+
+    #[cfg(not(test))]
+    unsafe {
+        use core::arch::asm;
+
+        if features.contains(Features::NX) {
+            let (low, high): (u32, u32);
+            asm!("rdmsr", in("ecx") IA32_EFER, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+            let efer = ((high as u64) << 32 | low as u64) | EFER_NXE;
+            asm!(
+                "wrmsr",
+                in("ecx") IA32_EFER,
+                in("eax") efer as u32,
+                in("edx") (efer >> 32) as u32,
+                options(nostack, preserves_flags),
+            );
+        }
+
+        let mut bits = 0;
+        if features.contains(Features::SMEP) {
+            bits |= CR4_SMEP;
+        }
+        if features.contains(Features::SMAP) {
+            bits |= CR4_SMAP;
+        }
+        if features.contains(Features::UMIP) {
+            bits |= CR4_UMIP;
+        }
+        if bits != 0 {
+            asm!(
+                "mov {tmp}, cr4",
+                "or {tmp}, {bits}",
+                "mov cr4, {tmp}",
+                tmp = out(reg) _,
+                bits = in(reg) bits,
+                options(nostack, preserves_flags),
+            );
+        }
+        ENABLED.fetch_or(features.bits(), Ordering::Relaxed);
+    }
+
+    features
+}
+
+/// Protection features in force
+pub fn enabled() -> Features {
+    Features(ENABLED.load(Ordering::Relaxed))
+}
+
+/// Check whether SMAP is enabled, so user accesses need STAC/CLAC
+#[inline]
+pub fn smap_enabled() -> bool {
+    enabled().contains(Features::SMAP)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features_from_cpuid() {
+        assert_eq!(Features::from_cpuid(0, 0, 0), Features::empty());
+
+        let all = Features::from_cpuid(CPUID7_EBX_SMEP | CPUID7_EBX_SMAP, CPUID7_ECX_UMIP, CPUID_EXT_EDX_NX);
+        assert!(all.contains(Features::NX));
+        assert!(all.contains(Features::SMEP));
+        assert!(all.contains(Features::SMAP));
+        assert!(all.contains(Features::UMIP));
+
+        let smep_only = Features::from_cpuid(CPUID7_EBX_SMEP, 0, 0);
+        assert!(smep_only.contains(Features::SMEP));
+        assert!(!smep_only.contains(Features::SMAP));
+    }
+
+    #[test]
+    fn test_init_leaves_tests_unprotected() {
+        init();
+        assert!(!smap_enabled());
+        assert_eq!(enabled(), Features::empty());
+    }
+}
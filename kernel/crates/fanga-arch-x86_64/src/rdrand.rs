@@ -0,0 +1,65 @@
+//! RDRAND and RDSEED
+//!
+//! RDSEED returns conditioned output of the hardware entropy source and is
+//! meant for seeding. RDRAND returns output of a DRBG reseeded from that
+//! source. Both may fail transiently when the source is drained, so each
+//! call retries a few times before giving up.
+
+use crate::cpuid::{self, Feature};
+
+/// Attempts before reporting the source as exhausted
+const RETRIES: usize = 10;
+
+/// Read 64 bits from RDRAND, if the CPU has it and it delivers
+pub fn rdrand() -> Option<u64> {
+    if !cpuid::has(Feature::Rdrand) {
+        return None;
+    }
+    (0..RETRIES).find_map(|_| unsafe { rdrand_step() })
+}
+
+/// Read 64 bits from RDSEED, if the CPU has it and it delivers
+pub fn rdseed() -> Option<u64> {
+    if !cpuid::has(Feature::Rdseed) {
+        return None;
+    }
+    (0..RETRIES).find_map(|_| {
+        let value = unsafe { rdseed_step() };
+        if value.is_none() {
+            core::hint::spin_loop();
+        }
+        value
+    })
+}
+
+/// # Safety
+/// The CPU must support RDRAND
+#[inline]
+unsafe fn rdrand_step() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    core::arch::asm!(
+        "rdrand {value}",
+        "setc {ok}",
+        value = out(reg) value,
+        ok = out(reg_byte) ok,
+        options(nomem, nostack),
+    );
+    (ok != 0).then_some(value)
+}
+
+/// # Safety
+/// The CPU must support RDSEED
+#[inline]
+unsafe fn rdseed_step() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    core::arch::asm!(
+        "rdseed {value}",
+        "setc {ok}",
+        value = out(reg) value,
+        ok = out(reg_byte) ok,
+        options(nomem, nostack),
+    );
+    (ok != 0).then_some(value)
+}
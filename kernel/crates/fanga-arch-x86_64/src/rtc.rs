@@ -0,0 +1,180 @@
+//! CMOS Real-Time Clock
+//!
+//! The RTC keeps the date and time across power cycles, in registers of
+//! the CMOS RAM behind ports 0x70 (index) and 0x71 (data). Depending on
+//! status register B the values are BCD or binary and the hour is 12- or
+//! 24-hour. The century lives in a separate register, usually 0x32, which
+//! some machines lack.
+//!
+//! This module provides:
+//! - Consistent reads that avoid the once-a-second update cycle
+//! - Decoding of BCD, 12-hour and century formats
+
+use crate::port::{inb, outb};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Time and date registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Century register on most PCs (the ACPI FADT names the real one)
+pub const DEFAULT_CENTURY_REG: u8 = 0x32;
+
+/// Status A: an update is in progress and the registers may be torn
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Status B: 24-hour mode, binary (not BCD) mode
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// Hour register bit 7 marks PM in 12-hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+/// Reads attempted before settling for an unconfirmed one
+const READ_ATTEMPTS: usize = 8;
+
+/// Raw register values of one RTC read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    /// Century register, 0 if not read
+    pub century: u8,
+    pub status_b: u8,
+}
+
+/// Calendar date and time read from the RTC (UTC on most machines)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+impl RtcRegisters {
+    /// Decode the registers into a date and time
+    ///
+    /// Without a plausible century register, two-digit years below 70
+    /// are taken as 20xx and the rest as 19xx.
+    pub fn decode(&self) -> RtcTime {
+        let binary = self.status_b & STATUS_B_BINARY != 0;
+        let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+        let pm = self.hour & HOUR_PM != 0;
+        let mut hour = convert(self.hour & !HOUR_PM);
+        if self.status_b & STATUS_B_24H == 0 {
+            // 12 AM is 0:00, 12 PM is 12:00
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = convert(self.year) as u16;
+        let century = match convert(self.century) as u16 {
+            century @ 19..=21 => century,
+            _ if year < 70 => 20,
+            _ => 19,
+        };
+
+        RtcTime {
+            year: century * 100 + year,
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+        }
+    }
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        outb(CMOS_INDEX, reg);
+        inb(CMOS_DATA)
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0
+}
+
+fn read_registers(century_reg: Option<u8>) -> RtcRegisters {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    RtcRegisters {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: century_reg.map_or(0, read_register),
+        status_b: read_register(REG_STATUS_B),
+    }
+}
+
+/// Read the current date and time
+///
+/// Reads until two consecutive reads agree, so an update between the
+/// register reads cannot produce a torn value. `century_reg` is the CMOS
+/// index of the century register, if the machine has one.
+pub fn read(century_reg: Option<u8>) -> RtcTime {
+    let mut last = read_registers(century_reg);
+    for _ in 0..READ_ATTEMPTS {
+        let current = read_registers(century_reg);
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+    last.decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regs(hour: u8, year: u8, century: u8, status_b: u8) -> RtcRegisters {
+        RtcRegisters { second: 0x59, minute: 0x30, hour, day: 0x31, month: 0x12, year, century, status_b }
+    }
+
+    #[test]
+    fn test_decode_bcd() {
+        let time = regs(0x23, 0x24, 0x20, STATUS_B_24H).decode();
+        assert_eq!(time, RtcTime { year: 2024, month: 12, day: 31, hour: 23, minute: 30, second: 59 });
+
+        // No century register
+        assert_eq!(regs(0x23, 0x99, 0, STATUS_B_24H).decode().year, 1999);
+        assert_eq!(regs(0x23, 0x05, 0, STATUS_B_24H).decode().year, 2005);
+    }
+
+    #[test]
+    fn test_decode_binary_12h() {
+        let binary = STATUS_B_BINARY;
+        let time = RtcRegisters { second: 5, minute: 7, hour: 12, day: 1, month: 2, year: 30, century: 20, status_b: binary };
+        assert_eq!(time.decode().hour, 0);
+        assert_eq!(RtcRegisters { hour: HOUR_PM | 12, ..time }.decode().hour, 12);
+        assert_eq!(RtcRegisters { hour: HOUR_PM | 3, ..time }.decode().hour, 15);
+        assert_eq!(time.decode().year, 2030);
+    }
+}
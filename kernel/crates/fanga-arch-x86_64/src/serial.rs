@@ -1,42 +1,445 @@
+//! 16550 UART serial ports
+//!
+//! Manages the four legacy ports COM1-COM4, each with its own line
+//! configuration (baud rate, data bits, parity, stop bits) and receive
+//! ring. COM1 is set up at 38400 8N1 as soon as the kernel starts so
+//! early messages have somewhere to go; the other ports are probed and
+//! configured on request. Kernel messages (`serial_println!`) go to the
+//! console port, COM1 unless another one is selected.
+//!
+//! This module provides:
+//! - Port probing and line configuration
+//! - Console port selection and output
+//! - Interrupt-driven receive into per-port rings
+
 use super::port::{inb, outb};
 use core::fmt::{self, Write};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 static LOCK: AtomicBool = AtomicBool::new(false);
 
-const COM1: u16 = 0x3F8;
+/// UART registers (offsets from the port base)
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
 
-pub fn init() {
+/// Interrupt enable: received data available
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+
+/// FIFO control: enable and clear both FIFOs, 14-byte trigger level
+const FCR_ENABLE_CLEAR_14: u8 = 0xC7;
+
+/// Line control: divisor latch access
+const LCR_DLAB: u8 = 1 << 7;
+
+/// Modem control: DTR, RTS and OUT2 (gates the IRQ line), and loopback
+const MCR_NORMAL: u8 = 0x0B;
+const MCR_LOOPBACK_TEST: u8 = 0x1E;
+
+/// Line status: data ready, transmit holding register empty
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// Byte sent through the loopback when probing a port
+const PROBE_BYTE: u8 = 0xAE;
+
+/// UART input clock divided by 16
+const BASE_BAUD: u32 = 115_200;
+
+/// Size of each receive ring (a power of two)
+pub const RX_BUFFER_SIZE: usize = 1024;
+
+/// Number of legacy serial ports
+pub const NUM_PORTS: usize = 4;
+
+/// A legacy serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1 = 0,
+    Com2 = 1,
+    Com3 = 2,
+    Com4 = 3,
+}
+
+impl ComPort {
+    /// All ports, in order
+    pub const ALL: [ComPort; NUM_PORTS] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Port for an index (0 is COM1)
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).copied()
+    }
+
+    /// Index of the port (0 is COM1)
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// I/O port base address
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// ISA IRQ line (COM1/COM3 share IRQ 4, COM2/COM4 share IRQ 3)
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => crate::interrupts::idt::IRQ_COM1,
+            ComPort::Com2 | ComPort::Com4 => crate::interrupts::idt::IRQ_COM2,
+        }
+    }
+
+    /// Device name (`ttyS0` for COM1)
+    pub fn tty_name(self) -> &'static str {
+        match self {
+            ComPort::Com1 => "ttyS0",
+            ComPort::Com2 => "ttyS1",
+            ComPort::Com3 => "ttyS2",
+            ComPort::Com4 => "ttyS3",
+        }
+    }
+}
+
+/// Parity mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+/// Line configuration of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl SerialConfig {
+    /// 38400 baud, 8 data bits, no parity, 1 stop bit
+    pub const DEFAULT: Self = Self { baud: 38_400, data_bits: 8, parity: Parity::None, stop_bits: 1 };
+
+    /// Baud rate divisor, if the rate can be generated exactly enough
+    pub fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || self.baud > BASE_BAUD {
+            return None;
+        }
+        u16::try_from(BASE_BAUD / self.baud).ok()
+    }
+
+    /// Line control register value, if the format is valid
+    fn line_control(&self) -> Option<u8> {
+        if !(5..=8).contains(&self.data_bits) || !(1..=2).contains(&self.stop_bits) {
+            return None;
+        }
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        Some((self.data_bits - 5) | (self.stop_bits - 1) << 2 | parity << 3)
+    }
+
+    /// Parse Linux-style options such as `115200n8` or `9600e7`
+    ///
+    /// Parity and data bits are optional and default to `n8`.
+    pub fn parse(options: &str) -> Option<Self> {
+        let digits = options.bytes().take_while(u8::is_ascii_digit).count();
+        let mut config = Self { baud: options[..digits].parse().ok()?, ..Self::DEFAULT };
+
+        let mut rest = options[digits..].chars();
+        if let Some(parity) = rest.next() {
+            config.parity = match parity {
+                'n' => Parity::None,
+                'o' => Parity::Odd,
+                'e' => Parity::Even,
+                'm' => Parity::Mark,
+                's' => Parity::Space,
+                _ => return None,
+            };
+        }
+        if let Some(bits) = rest.next() {
+            config.data_bits = bits.to_digit(10)? as u8;
+        }
+        if rest.next().is_some() {
+            return None;
+        }
+        (config.divisor().is_some() && config.line_control().is_some()).then_some(config)
+    }
+}
+
+impl fmt::Display for SerialConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        write!(f, "{} {}{}{}", self.baud, self.data_bits, parity, self.stop_bits)
+    }
+}
+
+/// Received bytes of one port, filled by the interrupt handler and
+/// drained by readers
+///
+/// Single producer (the port's interrupt) and single consumer: `head` is
+/// only advanced by the producer, `tail` only by the consumer.
+struct RxRing {
+    buffer: [AtomicU8; RX_BUFFER_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// Bytes lost because the ring was full
+    dropped: AtomicU64,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        Self {
+            buffer: [const { AtomicU8::new(0) }; RX_BUFFER_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Append a byte, dropping it if the ring is full
+    fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= RX_BUFFER_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.buffer[head % RX_BUFFER_SIZE].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Take the oldest byte
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.buffer[tail % RX_BUFFER_SIZE].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    fn available(&self) -> usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Relaxed))
+    }
+}
+
+static RX: [RxRing; NUM_PORTS] = [const { RxRing::new() }; NUM_PORTS];
+
+/// Ports found by `init` or `configure`, one bit per port
+static PRESENT: AtomicU8 = AtomicU8::new(0);
+
+/// Port that receives kernel messages
+static CONSOLE: AtomicU8 = AtomicU8::new(ComPort::Com1 as u8);
+
+/// Configuration of each port
+static CONFIGS: spin::Mutex<[SerialConfig; NUM_PORTS]> = spin::Mutex::new([SerialConfig::DEFAULT; NUM_PORTS]);
+
+/// Program a port's line settings and FIFOs
+///
+/// # Safety
+/// `port` must be a 16550-compatible UART.
+unsafe fn program(port: ComPort, config: &SerialConfig, divisor: u16, line_control: u8) {
+    let base = port.base();
+    outb(base + REG_IER, 0x00);
+    outb(base + REG_LCR, LCR_DLAB);
+    outb(base + REG_DATA, divisor as u8);
+    outb(base + REG_IER, (divisor >> 8) as u8);
+    outb(base + REG_LCR, line_control);
+    outb(base + REG_FCR, FCR_ENABLE_CLEAR_14);
+    outb(base + REG_MCR, MCR_NORMAL);
+    CONFIGS.lock()[port.index()] = *config;
+}
+
+/// Check for a UART by sending a byte through its loopback
+fn probe(port: ComPort) -> bool {
+    let base = port.base();
     unsafe {
-        outb(COM1 + 1, 0x00);
-        outb(COM1 + 3, 0x80);
-        outb(COM1, 0x03);
-        outb(COM1 + 1, 0x00);
-        outb(COM1 + 3, 0x03);
-        outb(COM1 + 2, 0xC7);
-        outb(COM1 + 4, 0x0B);
+        outb(base + REG_MCR, MCR_LOOPBACK_TEST);
+        outb(base + REG_DATA, PROBE_BYTE);
+        let present = inb(base + REG_DATA) == PROBE_BYTE;
+        outb(base + REG_MCR, MCR_NORMAL);
+        present
+    }
+}
+
+/// Set up COM1 with the default configuration for early output
+pub fn init() {
+    let config = SerialConfig::DEFAULT;
+    if let (Some(divisor), Some(line_control)) = (config.divisor(), config.line_control()) {
+        unsafe { program(ComPort::Com1, &config, divisor, line_control) };
+    }
+    PRESENT.fetch_or(1 << ComPort::Com1.index(), Ordering::Relaxed);
+}
+
+/// Probe a port and apply a line configuration to it
+pub fn configure(port: ComPort, config: SerialConfig) -> Result<(), &'static str> {
+    let divisor = config.divisor().ok_or("Unsupported baud rate")?;
+    let line_control = config.line_control().ok_or("Invalid line format")?;
+    // The console port is known to work and may be in use by the probe's caller
+    if port != console() && !probe(port) {
+        return Err("No UART at this port");
+    }
+    unsafe { program(port, &config, divisor, line_control) };
+    PRESENT.fetch_or(1 << port.index(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Check whether a port was found
+pub fn is_present(port: ComPort) -> bool {
+    PRESENT.load(Ordering::Relaxed) & (1 << port.index()) != 0
+}
+
+/// Current configuration of a port
+pub fn config(port: ComPort) -> SerialConfig {
+    CONFIGS.lock()[port.index()]
+}
+
+/// Send kernel messages to `port`
+pub fn set_console(port: ComPort) -> Result<(), &'static str> {
+    if !is_present(port) {
+        return Err("Serial port not present");
     }
+    CONSOLE.store(port as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Port that receives kernel messages
+pub fn console() -> ComPort {
+    ComPort::from_index(CONSOLE.load(Ordering::Relaxed) as usize).unwrap_or(ComPort::Com1)
 }
 
-fn tx_empty() -> bool {
-    unsafe { (inb(COM1 + 5) & 0x20) != 0 }
+fn tx_empty(port: ComPort) -> bool {
+    unsafe { (inb(port.base() + REG_LSR) & LSR_TX_EMPTY) != 0 }
 }
 
-fn write_byte(b: u8) {
-    while !tx_empty() {
+fn write_byte(port: ComPort, b: u8) {
+    while !tx_empty(port) {
         core::hint::spin_loop();
     }
-    unsafe { outb(COM1, b) }
+    unsafe { outb(port.base() + REG_DATA, b) }
+}
+
+/// Write raw bytes to a port
+///
+/// Does not take the console lock, so messages on the console port may
+/// interleave with the bytes.
+pub fn write_bytes(port: ComPort, bytes: &[u8]) {
+    for &b in bytes {
+        write_byte(port, b);
+    }
 }
 
-struct Serial;
+/// Read a byte straight from the UART, if one arrived
+fn read_uart(port: ComPort) -> Option<u8> {
+    unsafe {
+        if inb(port.base() + REG_LSR) & LSR_DATA_READY != 0 {
+            Some(inb(port.base() + REG_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+/// Read a received byte from the console port without waiting
+///
+/// Takes buffered input first, then polls the UART. For polled consoles
+/// such as the kernel debugger, which must work with interrupts disabled.
+pub fn try_read_byte() -> Option<u8> {
+    let port = console();
+    read_byte(port).or_else(|| read_uart(port))
+}
+
+/// Enable the receive interrupt of a port
+///
+/// The caller installs the handler for `port.irq()`, which calls
+/// `handle_rx_interrupt`, before enabling it.
+pub fn enable_rx_interrupt(port: ComPort) {
+    unsafe {
+        let ier = inb(port.base() + REG_IER);
+        outb(port.base() + REG_IER, ier | IER_RX_AVAILABLE);
+    }
+}
+
+/// Move every byte a port's UART holds into its receive ring
+///
+/// Called from the port's interrupt handler.
+///
+/// # Returns
+/// The number of bytes read from the UART
+pub fn handle_rx_interrupt(port: ComPort) -> usize {
+    let mut count = 0;
+    while let Some(byte) = read_uart(port) {
+        RX[port.index()].push(byte);
+        count += 1;
+    }
+    count
+}
+
+/// Take the oldest buffered byte of a port
+pub fn read_byte(port: ComPort) -> Option<u8> {
+    RX[port.index()].pop()
+}
+
+/// Read buffered bytes of a port into `buf` without waiting
+///
+/// # Returns
+/// The number of bytes read
+pub fn read(port: ComPort, buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buf.len() {
+        match read_byte(port) {
+            Some(byte) => {
+                buf[count] = byte;
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    count
+}
+
+/// Number of buffered bytes of a port
+pub fn rx_available(port: ComPort) -> usize {
+    RX[port.index()].available()
+}
+
+/// Number of received bytes a port dropped because its ring was full
+pub fn rx_dropped(port: ComPort) -> u64 {
+    RX[port.index()].dropped.load(Ordering::Relaxed)
+}
+
+struct Serial(ComPort);
 
 impl Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for b in s.bytes() {
             if b == b'\n' {
-                write_byte(b'\r');
+                write_byte(self.0, b'\r');
             }
-            write_byte(b);
+            write_byte(self.0, b);
         }
         Ok(())
     }
@@ -49,6 +452,60 @@ pub fn _print(args: fmt::Arguments) {
     {
         core::hint::spin_loop();
     }
-    let _ = Serial.write_fmt(args);
+    let _ = Serial(console()).write_fmt(args);
     LOCK.store(false, Ordering::Release);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rx_ring() {
+        let ring = RxRing::new();
+        assert_eq!(ring.pop(), None);
+        for &b in b"ls\r" {
+            assert!(ring.push(b));
+        }
+        assert_eq!(ring.available(), 3);
+        assert_eq!(ring.pop(), Some(b'l'));
+
+        // A full ring drops new bytes and keeps the old ones
+        for i in 0..RX_BUFFER_SIZE - 2 {
+            assert!(ring.push(i as u8));
+        }
+        assert!(!ring.push(0xFF));
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(ring.pop(), Some(b's'));
+        assert_eq!(ring.available(), RX_BUFFER_SIZE - 1);
+
+        // Per-port rings start empty
+        let mut buf = [0u8; 4];
+        assert_eq!(read(ComPort::Com4, &mut buf), 0);
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(SerialConfig::DEFAULT.divisor(), Some(3));
+        assert_eq!(SerialConfig::DEFAULT.line_control(), Some(0x03));
+
+        let config = SerialConfig::parse("9600e7").unwrap();
+        assert_eq!(config, SerialConfig { baud: 9600, data_bits: 7, parity: Parity::Even, stop_bits: 1 });
+        assert_eq!(config.divisor(), Some(12));
+        assert_eq!(config.line_control(), Some(0b0001_1010));
+
+        assert_eq!(SerialConfig::parse("115200").unwrap().divisor(), Some(1));
+        assert!(SerialConfig::parse("115200x8").is_none());
+        assert!(SerialConfig::parse("230400").is_none());
+        assert!(SerialConfig::parse("n8").is_none());
+    }
+
+    #[test]
+    fn test_ports() {
+        assert_eq!(ComPort::Com3.base(), 0x3E8);
+        assert_eq!(ComPort::Com3.irq(), ComPort::Com1.irq());
+        assert_eq!(ComPort::from_index(1).map(ComPort::tty_name), Some("ttyS1"));
+        assert_eq!(ComPort::from_index(4), None);
+        assert_eq!(console(), ComPort::Com1);
+    }
+}
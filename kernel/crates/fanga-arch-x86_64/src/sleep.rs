@@ -0,0 +1,388 @@
+//! CPU State Across ACPI Sleep
+//!
+//! In S3 the processor loses all of its state while RAM keeps its
+//! contents. On wake the firmware jumps to the OS waking vector in real
+//! mode, so everything the kernel set up has to be rebuilt from there:
+//!
+//! 1. The wakeup trampoline, copied to a page below 1 MiB, loads a small
+//!    GDT, turns on PAE, long mode and paging with the sleeping CPU's page
+//!    tables (which must map the trampoline page at its own address) and
+//!    jumps to a 64-bit kernel entry on a kernel stack.
+//! 2. `restore_cpu_state` reloads the control registers, the FPU state and
+//!    the MSRs `save_cpu_state` recorded; `crate::resume_cpu` then sets up
+//!    the descriptor tables and the rest of the per-CPU state again.
+//! 3. `resume_context` returns into `save_and_sleep`, which the CPU entered
+//!    before going to sleep, this time with 0.
+//!
+//! This module provides:
+//! - The saved register context
+//! - Entry to sleep and return from it
+//! - The real-mode wakeup trampoline and its installation
+
+use core::arch::{asm, global_asm};
+
+use crate::percpu::IA32_KERNEL_GS_BASE;
+use crate::tls::IA32_FS_BASE;
+
+/// IA32_EFER model specific register
+const IA32_EFER: u32 = 0xC000_0080;
+
+/// CR4.OSXSAVE: XCR0 is in use
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// The wakeup code runs in real mode, so it must live below 1 MiB
+pub const WAKEUP_LIMIT: u64 = 0x10_0000;
+
+/// Offsets of the fields `install_wakeup` fills in, from the start of the
+/// trampoline
+const WAKEUP_ENTRY: usize = 8;
+const WAKEUP_STACK: usize = 16;
+const WAKEUP_CR3: usize = 24;
+const WAKEUP_FAR_JUMP: usize = 28;
+const WAKEUP_GDT: usize = 40;
+const WAKEUP_GDTR_BASE: usize = 66;
+
+// Entered in real mode with CS = page >> 4 and IP = 0. The header holds
+// the 64-bit entry point and stack, the page table root and the far
+// pointer into 64-bit mode, followed by the GDT (null, 64-bit code at
+// 0x08, data at 0x10) and its pointer. Real mode code addresses them
+// through DS = CS; `lgdt` and the 32-bit far jump are spelled out
+// because the assembler will not pick their 16-bit forms itself.
+global_asm!(
+    ".pushsection .text.fanga_wakeup, \"ax\", @progbits",
+    ".balign 16",
+    ".code16",
+    ".global fanga_wakeup_start",
+    "fanga_wakeup_start:",
+    "    jmp .Lfanga_wakeup_real",
+    "    .balign 8",
+    "    .quad 0",
+    "    .quad 0",
+    "    .long 0",
+    "    .long 0",
+    "    .word 0x08",
+    "    .word 0",
+    "    .long 0",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF",
+    "    .quad 0x00CF92000000FFFF",
+    "    .word 23",
+    "    .long 0",
+    ".Lfanga_wakeup_real:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    mov ss, ax",
+    "    .byte 0x0F, 0x01, 0x16",
+    "    .word 64",
+    "    mov eax, cr4",
+    "    or eax, 0x20",
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [24]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080",
+    "    rdmsr",
+    "    or eax, 0x900",
+    "    wrmsr",
+    "    mov eax, cr0",
+    "    or eax, 0x80000001",
+    "    mov cr0, eax",
+    "    .byte 0x66, 0xFF, 0x2E",
+    "    .word 28",
+    ".code64",
+    ".global fanga_wakeup_long",
+    "fanga_wakeup_long:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, qword ptr [rip + fanga_wakeup_start + 16]",
+    "    jmp qword ptr [rip + fanga_wakeup_start + 8]",
+    ".global fanga_wakeup_end",
+    "fanga_wakeup_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static fanga_wakeup_start: u8;
+    static fanga_wakeup_long: u8;
+    static fanga_wakeup_end: u8;
+}
+
+/// The wakeup trampoline as assembled, before `install_wakeup` fills in
+/// its header
+pub fn wakeup_code() -> &'static [u8] {
+    unsafe {
+        let start = &raw const fanga_wakeup_start;
+        let len = &raw const fanga_wakeup_end as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Copy the wakeup trampoline to `page` and fill in its header
+///
+/// `phys` is the physical address of `page`, which becomes the waking
+/// vector. Page tables at `cr3` must map `phys` at the same virtual
+/// address and the kernel half. `entry` runs with interrupts disabled on
+/// the stack ending at `stack_top`, in 64-bit mode with the trampoline's
+/// GDT loaded.
+///
+/// # Safety
+/// `page` must be valid for writes of `wakeup_code().len()` bytes.
+pub unsafe fn install_wakeup(
+    page: *mut u8,
+    phys: u64,
+    cr3: u64,
+    entry: extern "C" fn() -> !,
+    stack_top: u64,
+) -> Result<(), &'static str> {
+    if phys >= WAKEUP_LIMIT || phys & 0xF != 0 {
+        return Err("Wakeup code must be 16-byte aligned below 1 MiB");
+    }
+    if cr3 > u32::MAX as u64 {
+        return Err("Page tables above 4 GiB cannot be loaded in real mode");
+    }
+    let code = wakeup_code();
+    core::ptr::copy_nonoverlapping(code.as_ptr(), page, code.len());
+
+    let long_entry = &raw const fanga_wakeup_long as u64 - code.as_ptr() as u64;
+    let put = |offset: usize, bytes: &[u8]| core::ptr::copy_nonoverlapping(bytes.as_ptr(), page.add(offset), bytes.len());
+    put(WAKEUP_ENTRY, &(entry as usize as u64).to_le_bytes());
+    put(WAKEUP_STACK, &stack_top.to_le_bytes());
+    put(WAKEUP_CR3, &(cr3 as u32).to_le_bytes());
+    put(WAKEUP_FAR_JUMP, &((phys + long_entry) as u32).to_le_bytes());
+    put(WAKEUP_GDTR_BASE, &((phys + WAKEUP_GDT as u64) as u32).to_le_bytes());
+    Ok(())
+}
+
+/// FXSAVE area
+#[repr(C, align(16))]
+struct FxState([u8; 512]);
+
+/// What a CPU needs to continue after sleep
+///
+/// The callee-saved registers and the return point come first, at the
+/// offsets `save_and_sleep` and `resume_context` use.
+#[repr(C)]
+pub struct SleepContext {
+    rbx: u64,    // 0x00
+    rbp: u64,    // 0x08
+    r12: u64,    // 0x10
+    r13: u64,    // 0x18
+    r14: u64,    // 0x20
+    r15: u64,    // 0x28
+    rsp: u64,    // 0x30
+    rip: u64,    // 0x38
+    rflags: u64, // 0x40
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    xcr0: u64,
+    fs_base: u64,
+    kernel_gs_base: u64,
+    fpu: FxState,
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+impl SleepContext {
+    /// An empty context
+    pub const fn new() -> Self {
+        Self {
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rsp: 0,
+            rip: 0,
+            rflags: 0,
+            cr0: 0,
+            cr3: 0,
+            cr4: 0,
+            efer: 0,
+            xcr0: 0,
+            fs_base: 0,
+            kernel_gs_base: 0,
+            fpu: FxState([0; 512]),
+        }
+    }
+
+    /// Page table root recorded by `save_cpu_state`
+    pub fn cr3(&self) -> u64 {
+        self.cr3
+    }
+
+    /// Record the control registers, the FPU state and the MSRs that
+    /// `crate::resume_cpu` does not set up again
+    ///
+    /// # Safety
+    /// Must run with interrupts disabled, right before `save_and_sleep`.
+    pub unsafe fn save_cpu_state(&mut self) {
+        asm!(
+            "mov {}, cr0",
+            "mov {}, cr3",
+            "mov {}, cr4",
+            out(reg) self.cr0,
+            out(reg) self.cr3,
+            out(reg) self.cr4,
+            options(nomem, nostack, preserves_flags),
+        );
+        self.efer = rdmsr(IA32_EFER);
+        if self.cr4 & CR4_OSXSAVE != 0 {
+            let (low, high): (u32, u32);
+            asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+            self.xcr0 = ((high as u64) << 32) | low as u64;
+        }
+        self.fs_base = rdmsr(IA32_FS_BASE);
+        self.kernel_gs_base = rdmsr(IA32_KERNEL_GS_BASE);
+        asm!("fxsave64 [{}]", in(reg) self.fpu.0.as_mut_ptr(), options(nostack, preserves_flags));
+    }
+
+    /// Reload what `save_cpu_state` recorded
+    ///
+    /// CR0 goes first, as some CR4 bits need CR0.WP.
+    ///
+    /// # Safety
+    /// Must run on the woken CPU, before anything else relies on its
+    /// state.
+    pub unsafe fn restore_cpu_state(&self) {
+        wrmsr(IA32_EFER, self.efer);
+        asm!(
+            "mov cr0, {}",
+            "mov cr4, {}",
+            "mov cr3, {}",
+            in(reg) self.cr0,
+            in(reg) self.cr4,
+            in(reg) self.cr3,
+            options(nostack, preserves_flags),
+        );
+        if self.cr4 & CR4_OSXSAVE != 0 {
+            asm!("xsetbv", in("ecx") 0, in("eax") self.xcr0 as u32, in("edx") (self.xcr0 >> 32) as u32, options(nostack, preserves_flags));
+        }
+        asm!("fxrstor64 [{}]", in(reg) self.fpu.0.as_ptr(), options(nostack, preserves_flags));
+        wrmsr(IA32_FS_BASE, self.fs_base);
+        wrmsr(IA32_KERNEL_GS_BASE, self.kernel_gs_base);
+    }
+}
+
+impl Default for SleepContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Save the callee-saved registers and call `enter`, which puts the
+/// machine to sleep
+///
+/// # Returns
+/// `enter`'s result if it returns, or 0 once `resume_context` brings the
+/// CPU back here after a wake
+///
+/// # Safety
+/// `ctx` must stay valid until the wake, and interrupts must be disabled.
+#[unsafe(naked)]
+pub unsafe extern "C" fn save_and_sleep(ctx: *mut SleepContext, enter: extern "C" fn() -> u64) -> u64 {
+    core::arch::naked_asm!(
+        // rdi = ctx, rsi = enter
+        "mov [rdi + 0x00], rbx",
+        "mov [rdi + 0x08], rbp",
+        "mov [rdi + 0x10], r12",
+        "mov [rdi + 0x18], r13",
+        "mov [rdi + 0x20], r14",
+        "mov [rdi + 0x28], r15",
+        // Resume as if returning from this call
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 0x30], rax",
+        "mov rax, [rsp]",
+        "mov [rdi + 0x38], rax",
+        "pushfq",
+        "pop rax",
+        "mov [rdi + 0x40], rax",
+        // Keep the stack 16-byte aligned across the call
+        "sub rsp, 8",
+        "call rsi",
+        "add rsp, 8",
+        "ret",
+    );
+}
+
+/// Return from `save_and_sleep` with 0
+///
+/// # Safety
+/// `ctx` must have been filled by `save_and_sleep`, whose caller's frame
+/// must still be intact.
+#[unsafe(naked)]
+pub unsafe extern "C" fn resume_context(ctx: *const SleepContext) -> ! {
+    core::arch::naked_asm!(
+        "mov rbx, [rdi + 0x00]",
+        "mov rbp, [rdi + 0x08]",
+        "mov r12, [rdi + 0x10]",
+        "mov r13, [rdi + 0x18]",
+        "mov r14, [rdi + 0x20]",
+        "mov r15, [rdi + 0x28]",
+        "mov rsp, [rdi + 0x30]",
+        "push qword ptr [rdi + 0x40]",
+        "popfq",
+        "xor eax, eax",
+        "jmp qword ptr [rdi + 0x38]",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn never() -> ! {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn test_wakeup_layout() {
+        let code = wakeup_code();
+        assert!(code.len() < 4096);
+        assert_eq!(u16::from_le_bytes([code[32], code[33]]), 0x08);
+        assert_eq!(u64::from_le_bytes(code[48..56].try_into().unwrap()), 0x00AF_9A00_0000_FFFF);
+        assert_eq!(u16::from_le_bytes([code[64], code[65]]), 23);
+    }
+
+    #[test]
+    fn test_install_wakeup() {
+        let mut page = [0u8; 4096];
+        unsafe { install_wakeup(page.as_mut_ptr(), 0x8000, 0x1234_5000, never, 0xFFFF_8000_0001_0000).unwrap() };
+        let word = |offset: usize| u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+        assert_eq!(u64::from_le_bytes(page[WAKEUP_ENTRY..WAKEUP_ENTRY + 8].try_into().unwrap()), never as extern "C" fn() -> ! as usize as u64);
+        assert_eq!(u64::from_le_bytes(page[WAKEUP_STACK..WAKEUP_STACK + 8].try_into().unwrap()), 0xFFFF_8000_0001_0000);
+        assert_eq!(word(WAKEUP_CR3), 0x1234_5000);
+        assert_eq!(word(WAKEUP_GDTR_BASE), 0x8000 + WAKEUP_GDT as u32);
+
+        // The far jump lands on the 64-bit code, after the real mode part
+        let long_entry = word(WAKEUP_FAR_JUMP) as usize - 0x8000;
+        assert!(long_entry > WAKEUP_GDTR_BASE + 4 && long_entry < wakeup_code().len());
+        assert_eq!(&page[long_entry..long_entry + 4], &[0x66, 0xB8, 0x10, 0x00]);
+
+        assert!(unsafe { install_wakeup(page.as_mut_ptr(), 0x10_0000, 0, never, 0) }.is_err());
+        assert!(unsafe { install_wakeup(page.as_mut_ptr(), 0x8000, 1 << 32, never, 0) }.is_err());
+    }
+}
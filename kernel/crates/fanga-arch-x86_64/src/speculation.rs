@@ -0,0 +1,301 @@
+//! Speculative Execution Mitigations
+//!
+//! Controls the speculation barriers CPUs expose through model-specific
+//! registers, on Intel and AMD alike:
+//! - IBRS (IA32_SPEC_CTRL bit 0): indirect branches predicted in a less
+//!   privileged mode cannot steer the kernel. Only used when the CPU has
+//!   enhanced IBRS, which is set once instead of on every kernel entry
+//! - STIBP (bit 1): the two threads of a core do not share indirect
+//!   branch predictions
+//! - SSBD (bit 2): loads do not speculatively bypass older stores
+//!   (Speculative Store Bypass, Spectre v4)
+//! - IBPB (IA32_PRED_CMD bit 0): discards indirect branch predictions,
+//!   issued when switching between address spaces
+//!
+//! The kernel picks a `Policy` from its command line; `init` turns that
+//! into the set of mitigations the CPU can apply and enables them on the
+//! boot CPU, and `init_cpu` repeats the setup on each application
+//! processor.
+//!
+//! This module provides:
+//! - Detection of mitigation support
+//! - Policy selection and per-CPU enabling
+//! - The IBPB barrier and the mitigation status
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::cpuid::{cpu_features, Feature};
+
+/// Speculation control MSRs
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+
+/// IA32_SPEC_CTRL bits
+const SPEC_CTRL_IBRS: u64 = 1 << 0;
+const SPEC_CTRL_STIBP: u64 = 1 << 1;
+const SPEC_CTRL_SSBD: u64 = 1 << 2;
+
+/// IA32_PRED_CMD indirect branch prediction barrier
+const PRED_CMD_IBPB: u64 = 1 << 0;
+
+/// IA32_ARCH_CAPABILITIES bits
+const ARCH_CAP_RDCL_NO: u64 = 1 << 0;
+const ARCH_CAP_IBRS_ALL: u64 = 1 << 1;
+const ARCH_CAP_SSB_NO: u64 = 1 << 4;
+
+/// A set of mitigations or hardware capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mitigations(u8);
+
+impl Mitigations {
+    /// Indirect branch restricted speculation
+    pub const IBRS: Self = Self(1 << 0);
+    /// Indirect branch prediction barrier
+    pub const IBPB: Self = Self(1 << 1);
+    /// Single thread indirect branch predictors
+    pub const STIBP: Self = Self(1 << 2);
+    /// Speculative store bypass disable
+    pub const SSBD: Self = Self(1 << 3);
+    /// Enhanced IBRS: IBRS can stay on permanently (capability only)
+    pub const EIBRS: Self = Self(1 << 4);
+    /// Not affected by Meltdown (capability only)
+    pub const RDCL_NO: Self = Self(1 << 5);
+    /// Not affected by Speculative Store Bypass (capability only)
+    pub const SSB_NO: Self = Self(1 << 6);
+
+    /// Create an empty set
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Check if the set contains a mitigation
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Add a mitigation
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Get raw value
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// IA32_SPEC_CTRL value enabling these mitigations
+    const fn spec_ctrl(&self) -> u64 {
+        let mut value = 0;
+        if self.contains(Self::IBRS) {
+            value |= SPEC_CTRL_IBRS;
+        }
+        if self.contains(Self::STIBP) {
+            value |= SPEC_CTRL_STIBP;
+        }
+        if self.contains(Self::SSBD) {
+            value |= SPEC_CTRL_SSBD;
+        }
+        value
+    }
+}
+
+/// Mitigations the kernel asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// Branch target injection (Spectre v2) mitigations: IBRS, STIBP, IBPB
+    pub spectre_v2: bool,
+    /// Speculative Store Bypass Disable for all code
+    pub ssbd: bool,
+}
+
+impl Policy {
+    /// Spectre v2 mitigations on, SSBD off (it costs a lot and only
+    /// matters for code that runs untrusted code in-process)
+    pub const DEFAULT: Self = Self { spectre_v2: true, ssbd: false };
+
+    /// No mitigations
+    pub const OFF: Self = Self { spectre_v2: false, ssbd: false };
+}
+
+/// Mitigations enabled by `init`
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+/// Hardware support detected by `init`
+static CAPABILITIES: AtomicU8 = AtomicU8::new(0);
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Decode CPUID features and IA32_ARCH_CAPABILITIES into capabilities
+fn capabilities_from(has: impl Fn(Feature) -> bool, arch_capabilities: u64) -> Mitigations {
+    let mut caps = Mitigations::empty();
+    if has(Feature::SpecCtrl) || has(Feature::AmdIbrs) {
+        caps = caps.with(Mitigations::IBRS);
+    }
+    if has(Feature::SpecCtrl) || has(Feature::AmdIbpb) {
+        caps = caps.with(Mitigations::IBPB);
+    }
+    if has(Feature::Stibp) || has(Feature::AmdStibp) {
+        caps = caps.with(Mitigations::STIBP);
+    }
+    if has(Feature::Ssbd) || has(Feature::AmdSsbd) {
+        caps = caps.with(Mitigations::SSBD);
+    }
+    if arch_capabilities & ARCH_CAP_IBRS_ALL != 0 {
+        caps = caps.with(Mitigations::EIBRS);
+    }
+    if arch_capabilities & ARCH_CAP_RDCL_NO != 0 {
+        caps = caps.with(Mitigations::RDCL_NO);
+    }
+    if arch_capabilities & ARCH_CAP_SSB_NO != 0 {
+        caps = caps.with(Mitigations::SSB_NO);
+    }
+    caps
+}
+
+/// Mitigation support of this CPU
+pub fn detect() -> Mitigations {
+    let cpu = cpu_features();
+    let arch_capabilities = if cpu.has(Feature::ArchCapabilities) { rdmsr(IA32_ARCH_CAPABILITIES) } else { 0 };
+    capabilities_from(|feature| cpu.has(feature), arch_capabilities)
+}
+
+/// Mitigations to enable for a policy on a CPU with `caps`
+///
+/// IBRS is only used with enhanced IBRS; without it, IBRS would have to
+/// be toggled on every kernel entry and exit, and IBPB is used alone.
+pub fn select(policy: Policy, caps: Mitigations) -> Mitigations {
+    let mut enabled = Mitigations::empty();
+    if policy.spectre_v2 {
+        if caps.contains(Mitigations::IBRS) && caps.contains(Mitigations::EIBRS) {
+            enabled = enabled.with(Mitigations::IBRS);
+        }
+        if caps.contains(Mitigations::IBPB) {
+            enabled = enabled.with(Mitigations::IBPB);
+        }
+        if caps.contains(Mitigations::STIBP) {
+            enabled = enabled.with(Mitigations::STIBP);
+        }
+    }
+    if policy.ssbd && caps.contains(Mitigations::SSBD) && !caps.contains(Mitigations::SSB_NO) {
+        enabled = enabled.with(Mitigations::SSBD);
+    }
+    enabled
+}
+
+/// Detect support and enable the mitigations `policy` asks for on the
+/// boot CPU
+///
+/// # Returns
+/// The mitigations in force
+pub fn init(policy: Policy) -> Mitigations {
+    let caps = detect();
+    let enabled = select(policy, caps);
+    CAPABILITIES.store(caps.bits(), Ordering::Relaxed);
+    ENABLED.store(enabled.bits(), Ordering::Relaxed);
+    init_cpu();
+    enabled
+}
+
+/// Enable the selected mitigations on this CPU
+///
+/// Called by each application processor during its arch init.
+pub fn init_cpu() {
+    let spec_ctrl = enabled().spec_ctrl();
+    if spec_ctrl != 0 {
+        unsafe { wrmsr(IA32_SPEC_CTRL, rdmsr(IA32_SPEC_CTRL) | spec_ctrl) };
+    }
+}
+
+/// Mitigations in force
+pub fn enabled() -> Mitigations {
+    Mitigations(ENABLED.load(Ordering::Relaxed))
+}
+
+/// Hardware support found by `init`
+pub fn capabilities() -> Mitigations {
+    Mitigations(CAPABILITIES.load(Ordering::Relaxed))
+}
+
+/// Flush indirect branch predictions, if IBPB is enabled
+///
+/// Called when switching to a task that must not be influenced by
+/// predictions trained by the previous one.
+#[inline]
+pub fn ibpb() {
+    if enabled().contains(Mitigations::IBPB) {
+        unsafe { wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB) };
+    }
+}
+
+/// Status line for Spectre v2 (branch target injection)
+pub fn spectre_v2_status(enabled: Mitigations) -> &'static str {
+    let ibrs = enabled.contains(Mitigations::IBRS);
+    let ibpb = enabled.contains(Mitigations::IBPB);
+    match (ibrs, ibpb) {
+        (true, true) => "Mitigation: Enhanced IBRS, IBPB on address space switch",
+        (true, false) => "Mitigation: Enhanced IBRS",
+        (false, true) => "Mitigation: IBPB on address space switch",
+        (false, false) => "Vulnerable",
+    }
+}
+
+/// Status line for Speculative Store Bypass (Spectre v4)
+pub fn ssb_status(enabled: Mitigations, caps: Mitigations) -> &'static str {
+    if caps.contains(Mitigations::SSB_NO) {
+        "Not affected"
+    } else if enabled.contains(Mitigations::SSBD) {
+        "Mitigation: Speculative Store Bypass disabled"
+    } else {
+        "Vulnerable"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let intel = capabilities_from(|f| matches!(f, Feature::SpecCtrl | Feature::Stibp), ARCH_CAP_IBRS_ALL);
+        assert!(intel.contains(Mitigations::IBRS) && intel.contains(Mitigations::IBPB));
+        assert!(intel.contains(Mitigations::STIBP) && intel.contains(Mitigations::EIBRS));
+        assert!(!intel.contains(Mitigations::SSBD));
+
+        let amd = capabilities_from(|f| matches!(f, Feature::AmdIbpb | Feature::AmdSsbd), 0);
+        assert_eq!(amd, Mitigations::IBPB.with(Mitigations::SSBD));
+    }
+
+    #[test]
+    fn test_select() {
+        let legacy = Mitigations::IBRS.with(Mitigations::IBPB).with(Mitigations::SSBD);
+        // Without enhanced IBRS only IBPB is used
+        assert_eq!(select(Policy::DEFAULT, legacy), Mitigations::IBPB);
+        assert_eq!(select(Policy::OFF, legacy), Mitigations::empty());
+        let ssbd = Policy { ssbd: true, ..Policy::DEFAULT };
+        assert_eq!(select(ssbd, legacy), Mitigations::IBPB.with(Mitigations::SSBD));
+        assert_eq!(select(ssbd, legacy.with(Mitigations::SSB_NO)), Mitigations::IBPB);
+
+        let enhanced = legacy.with(Mitigations::EIBRS).with(Mitigations::STIBP);
+        let enabled = select(Policy::DEFAULT, enhanced);
+        assert_eq!(enabled.spec_ctrl(), SPEC_CTRL_IBRS | SPEC_CTRL_STIBP);
+        assert_eq!(spectre_v2_status(enabled), "Mitigation: Enhanced IBRS, IBPB on address space switch");
+        assert_eq!(ssb_status(enabled, enhanced), "Vulnerable");
+    }
+}
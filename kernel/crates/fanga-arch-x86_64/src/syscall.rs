@@ -6,6 +6,7 @@
 
 use core::arch::asm;
 use crate::gdt::KERNEL_CODE_SELECTOR;
+use crate::uaccess::{Access, UserPtr, UserSlice};
 
 /// Model Specific Registers for SYSCALL/SYSRET
 const IA32_STAR: u32 = 0xC0000081;
@@ -16,6 +17,7 @@ const IA32_FMASK: u32 = 0xC0000084;
 const RFLAGS_IF: u64 = 1 << 9;  // Interrupt Flag
 const RFLAGS_TF: u64 = 1 << 8;  // Trap Flag
 const RFLAGS_DF: u64 = 1 << 10; // Direction Flag
+const RFLAGS_AC: u64 = 1 << 18; // Alignment Check (SMAP override)
 
 /// Syscall numbers
 pub const SYS_READ: u64 = 0;
@@ -23,9 +25,12 @@ pub const SYS_WRITE: u64 = 1;
 pub const SYS_OPEN: u64 = 2;
 pub const SYS_CLOSE: u64 = 3;
 pub const SYS_LSEEK: u64 = 8;
+pub const SYS_IOCTL: u64 = 16;
 pub const SYS_EXIT: u64 = 60;
 pub const SYS_FORK: u64 = 57;
 pub const SYS_EXEC: u64 = 59;
+pub const SYS_CLONE: u64 = 56;
+pub const SYS_WAIT4: u64 = 61;
 
 // Directory syscalls
 pub const SYS_MKDIR: u64 = 83;
@@ -44,10 +49,79 @@ pub const SYS_MSGGET: u64 = 68;
 pub const SYS_MSGSND: u64 = 69;
 pub const SYS_MSGRCV: u64 = 70;
 
+// Signal syscalls
+pub const SYS_RT_SIGACTION: u64 = 13;
+pub const SYS_RT_SIGPROCMASK: u64 = 14;
+pub const SYS_RT_SIGRETURN: u64 = 15;
+
 // Memory management syscalls
 pub const SYS_MMAP: u64 = 9;
 pub const SYS_MUNMAP: u64 = 11;
 
+// NUMA memory policies
+pub const SYS_MBIND: u64 = 237;
+pub const SYS_SET_MEMPOLICY: u64 = 238;
+
+// Resource usage
+pub const SYS_GETRUSAGE: u64 = 98;
+pub const SYS_TIMES: u64 = 100;
+
+// Clocks
+pub const SYS_CLOCK_GETTIME: u64 = 228;
+
+// Randomness
+pub const SYS_GETRANDOM: u64 = 318;
+
+// Thread-local storage
+pub const SYS_ARCH_PRCTL: u64 = 158;
+
+// Power
+pub const SYS_REBOOT: u64 = 169;
+
+/// Name of a syscall number, None for numbers without a syscall
+pub fn syscall_name(num: u64) -> Option<&'static str> {
+    Some(match num {
+        SYS_READ => "read",
+        SYS_WRITE => "write",
+        SYS_OPEN => "open",
+        SYS_CLOSE => "close",
+        SYS_LSEEK => "lseek",
+        SYS_MMAP => "mmap",
+        SYS_MUNMAP => "munmap",
+        SYS_RT_SIGACTION => "rt_sigaction",
+        SYS_RT_SIGPROCMASK => "rt_sigprocmask",
+        SYS_RT_SIGRETURN => "rt_sigreturn",
+        SYS_IOCTL => "ioctl",
+        SYS_PIPE => "pipe",
+        SYS_SHMGET => "shmget",
+        SYS_SHMAT => "shmat",
+        SYS_SHMCTL => "shmctl",
+        SYS_CLONE => "clone",
+        SYS_FORK => "fork",
+        SYS_EXEC => "execve",
+        SYS_EXIT => "exit",
+        SYS_WAIT4 => "wait4",
+        SYS_KILL => "kill",
+        SYS_SHMDT => "shmdt",
+        SYS_MSGGET => "msgget",
+        SYS_MSGSND => "msgsnd",
+        SYS_MSGRCV => "msgrcv",
+        SYS_GETDENTS => "getdents",
+        SYS_MKDIR => "mkdir",
+        SYS_RMDIR => "rmdir",
+        SYS_UNLINK => "unlink",
+        SYS_GETRUSAGE => "getrusage",
+        SYS_TIMES => "times",
+        SYS_ARCH_PRCTL => "arch_prctl",
+        SYS_REBOOT => "reboot",
+        SYS_CLOCK_GETTIME => "clock_gettime",
+        SYS_GETRANDOM => "getrandom",
+        SYS_MBIND => "mbind",
+        SYS_SET_MEMPOLICY => "set_mempolicy",
+        _ => return None,
+    })
+}
+
 /// Error codes (following POSIX conventions)
 pub const EINVAL: i64 = -22;  // Invalid argument
 pub const EBADF: i64 = -9;    // Bad file descriptor
@@ -57,11 +131,17 @@ pub const EFAULT: i64 = -14;  // Bad address
 pub const EACCES: i64 = -13;  // Permission denied
 pub const EPERM: i64 = -1;    // Operation not permitted
 pub const ESRCH: i64 = -3;    // No such process
+pub const ECHILD: i64 = -10;  // No child processes
+pub const EINTR: i64 = -4;    // Interrupted system call
+pub const EAGAIN: i64 = -11;  // Try again
+pub const ENOTTY: i64 = -25;  // Not a terminal
 pub const ENOENT: i64 = -2;   // No such file or directory
 pub const EEXIST: i64 = -17;  // File exists
 pub const ENOTDIR: i64 = -20; // Not a directory
 pub const EISDIR: i64 = -21;  // Is a directory
 pub const ENOTEMPTY: i64 = -39; // Directory not empty
+pub const ETIMEDOUT: i64 = -110; // Connection timed out
+pub const E2BIG: i64 = -7;    // Argument list too long
 
 /// Write a value to a Model Specific Register
 #[inline]
@@ -93,6 +173,52 @@ unsafe fn rdmsr(msr: u32) -> u64 {
     ((high as u64) << 32) | (low as u64)
 }
 
+/// Hook invoked on syscall entry with the syscall number
+pub type SyscallEntryHook = fn(u64);
+
+/// Hook invoked on syscall exit with the syscall number and return value
+pub type SyscallExitHook = fn(u64, i64);
+
+/// Handler for syscalls implemented outside the arch layer
+///
+/// Returns None if the syscall is not handled.
+pub type SyscallExtHandler = fn(u64, &[u64; 6]) -> Option<i64>;
+
+/// Reader for standard input
+///
+/// Fills the buffer and returns the byte count, or a negative error code.
+pub type StdinReader = fn(&mut [u8]) -> i64;
+
+static mut SYSCALL_ENTRY_HOOK: Option<SyscallEntryHook> = None;
+static mut SYSCALL_EXIT_HOOK: Option<SyscallExitHook> = None;
+static mut SYSCALL_EXT_HANDLER: Option<SyscallExtHandler> = None;
+static mut STDIN_READER: Option<StdinReader> = None;
+
+/// Register hooks run around every system call
+///
+/// # Safety
+/// Must be called before user code issues system calls (during init).
+pub unsafe fn set_syscall_hooks(entry: SyscallEntryHook, exit: SyscallExitHook) {
+    SYSCALL_ENTRY_HOOK = Some(entry);
+    SYSCALL_EXIT_HOOK = Some(exit);
+}
+
+/// Register the handler for syscalls not implemented by the arch layer
+///
+/// # Safety
+/// Must be called before user code issues system calls (during init).
+pub unsafe fn set_syscall_ext_handler(handler: SyscallExtHandler) {
+    SYSCALL_EXT_HANDLER = Some(handler);
+}
+
+/// Register the reader behind fd 0
+///
+/// # Safety
+/// Must be called before user code issues system calls (during init).
+pub unsafe fn set_stdin_reader(reader: StdinReader) {
+    STDIN_READER = Some(reader);
+}
+
 /// System call handler - called from syscall entry
 ///
 /// Arguments are passed in registers according to the System V ABI:
@@ -120,38 +246,67 @@ extern "C" fn syscall_handler(
         syscall_number, arg1, arg2, arg3, arg4, arg5, arg6
     );
 
+    unsafe {
+        if let Some(hook) = SYSCALL_ENTRY_HOOK {
+            hook(syscall_number);
+        }
+    }
+
+    let ret = dispatch_syscall(syscall_number, arg1, arg2, arg3, arg4, arg5, arg6);
+
+    unsafe {
+        if let Some(hook) = SYSCALL_EXIT_HOOK {
+            hook(syscall_number, ret);
+        }
+    }
+
+    ret
+}
+
+/// Route a system call to its implementation
+fn dispatch_syscall(
+    syscall_number: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
+) -> i64 {
     match syscall_number {
-        SYS_READ => sys_read(arg1 as i32, arg2 as *mut u8, arg3 as usize),
-        SYS_WRITE => sys_write(arg1 as i32, arg2 as *const u8, arg3 as usize),
-        SYS_OPEN => sys_open(arg1 as *const u8, arg2 as i32, arg3 as i32),
+        SYS_READ => sys_read(arg1 as i32, UserSlice::new(arg2 as u64, arg3 as usize)),
+        SYS_WRITE => sys_write(arg1 as i32, UserSlice::new(arg2 as u64, arg3 as usize)),
+        SYS_OPEN => sys_open(UserPtr::new(arg1), arg2 as i32, arg3 as i32),
         SYS_CLOSE => sys_close(arg1 as i32),
         SYS_LSEEK => sys_lseek(arg1 as i32, arg2 as i64, arg3 as i32),
-        SYS_MMAP => sys_mmap(arg1 as u64, arg2 as usize, arg3 as i32, arg4 as i32, arg5 as i32, arg6 as i64),
-        SYS_MUNMAP => sys_munmap(arg1 as u64, arg2 as usize),
         SYS_EXIT => sys_exit(arg1 as i32),
         SYS_FORK => sys_fork(),
-        SYS_EXEC => sys_exec(arg1 as *const u8, arg2 as *const *const u8),
-        SYS_MKDIR => sys_mkdir(arg1 as *const u8, arg2 as i32),
-        SYS_RMDIR => sys_rmdir(arg1 as *const u8),
-        SYS_GETDENTS => sys_getdents(arg1 as i32, arg2 as *mut u8, arg3 as usize),
-        SYS_UNLINK => sys_unlink(arg1 as *const u8),
-        SYS_PIPE => sys_pipe(arg1 as *mut i32),
+        SYS_EXEC => sys_exec(UserPtr::new(arg1), UserPtr::new(arg2)),
+        SYS_MKDIR => sys_mkdir(UserPtr::new(arg1), arg2 as i32),
+        SYS_RMDIR => sys_rmdir(UserPtr::new(arg1)),
+        SYS_GETDENTS => sys_getdents(arg1 as i32, UserSlice::new(arg2, arg3 as usize)),
+        SYS_UNLINK => sys_unlink(UserPtr::new(arg1)),
+        SYS_PIPE => sys_pipe(UserPtr::new(arg1)),
         SYS_KILL => sys_kill(arg1 as i32, arg2 as i32),
         SYS_SHMGET => sys_shmget(arg1 as i32, arg2 as usize, arg3 as i32),
-        SYS_SHMAT => sys_shmat(arg1 as i32, arg2 as *const u8, arg3 as i32),
-        SYS_SHMDT => sys_shmdt(arg1 as *const u8),
-        SYS_SHMCTL => sys_shmctl(arg1 as i32, arg2 as i32, arg3 as *mut u8),
+        SYS_SHMAT => sys_shmat(arg1 as i32, UserPtr::new(arg2), arg3 as i32),
+        SYS_SHMDT => sys_shmdt(UserPtr::new(arg1)),
+        SYS_SHMCTL => sys_shmctl(arg1 as i32, arg2 as i32, UserPtr::new(arg3)),
         SYS_MSGGET => sys_msgget(arg1 as i32, arg2 as i32),
-        SYS_MSGSND => sys_msgsnd(arg1 as i32, arg2 as *const u8, arg3 as usize, arg4 as i32),
-        SYS_MSGRCV => sys_msgrcv(arg1 as i32, arg2 as *mut u8, arg3 as usize, arg4 as i64, arg5 as i32),
-        _ => ENOSYS,
+        SYS_MSGSND => sys_msgsnd(arg1 as i32, UserPtr::new(arg2), arg3 as usize, arg4 as i32),
+        SYS_MSGRCV => sys_msgrcv(arg1 as i32, UserPtr::new(arg2), arg3 as usize, arg4 as i64, arg5 as i32),
+        _ => unsafe {
+            SYSCALL_EXT_HANDLER
+                .and_then(|handler| handler(syscall_number, &[arg1, arg2, arg3, arg4, arg5, arg6]))
+                .unwrap_or(ENOSYS)
+        },
     }
 }
 
 /// sys_read - Read from a file descriptor
-fn sys_read(fd: i32, buf: *mut u8, count: usize) -> i64 {
+fn sys_read(fd: i32, buf: UserSlice) -> i64 {
     // Validate arguments
-    if buf.is_null() {
+    if buf.addr() == 0 {
         return EFAULT;
     }
     
@@ -160,17 +315,29 @@ fn sys_read(fd: i32, buf: *mut u8, count: usize) -> i64 {
         return EBADF;
     }
 
-    // TODO: Implement actual reading from stdin
-    crate::serial_println!("[SYSCALL] sys_read(fd={}, buf={:p}, count={})", fd, buf, count);
-    
-    // Return 0 for now (EOF)
-    0
+    if buf.check(Access::Write).is_err() {
+        return EFAULT;
+    }
+    let Some(reader) = (unsafe { STDIN_READER }) else {
+        // No terminal behind stdin: end of file
+        return 0;
+    };
+
+    // A single read returns at most one chunk, like a short read from a
+    // terminal
+    let mut chunk = [0u8; 256];
+    let len = chunk.len().min(buf.len());
+    let ret = reader(&mut chunk[..len]);
+    if ret > 0 && buf.subslice(0, ret as usize).write(&chunk[..ret as usize]).is_err() {
+        return EFAULT;
+    }
+    ret
 }
 
 /// sys_write - Write to a file descriptor
-fn sys_write(fd: i32, buf: *const u8, count: usize) -> i64 {
+fn sys_write(fd: i32, buf: UserSlice) -> i64 {
     // Validate arguments
-    if buf.is_null() {
+    if buf.addr() == 0 {
         return EFAULT;
     }
 
@@ -179,27 +346,34 @@ fn sys_write(fd: i32, buf: *const u8, count: usize) -> i64 {
         return EBADF;
     }
 
-    // Validate buffer is readable
-    // In a real OS, we'd check if the buffer is in user space and accessible
-    // For now, we'll just trust it (since we don't have user space yet)
-    
-    unsafe {
-        let slice = core::slice::from_raw_parts(buf, count);
+    if buf.check(Access::Read).is_err() {
+        return EFAULT;
+    }
+
+    // Copy through a kernel buffer; a chunk boundary may split a UTF-8
+    // sequence, which then prints as hex
+    let mut chunk = [0u8; 256];
+    for offset in (0..buf.len()).step_by(chunk.len()) {
+        let part = buf.subslice(offset, chunk.len());
+        let slice = &mut chunk[..part.len()];
+        if part.read(slice).is_err() {
+            return EFAULT;
+        }
         if let Ok(s) = core::str::from_utf8(slice) {
             crate::serial_print!("{}", s);
         } else {
             // If not valid UTF-8, write as hex
-            for &byte in slice {
+            for &byte in slice.iter() {
                 crate::serial_print!("{:02x}", byte);
             }
         }
     }
 
-    count as i64
+    buf.len() as i64
 }
 
 /// sys_open - Open a file
-fn sys_open(pathname: *const u8, _flags: i32, _mode: i32) -> i64 {
+fn sys_open(pathname: UserPtr<u8>, _flags: i32, _mode: i32) -> i64 {
     if pathname.is_null() {
         return EFAULT;
     }
@@ -232,7 +406,7 @@ fn sys_lseek(fd: i32, _offset: i64, whence: i32) -> i64 {
 }
 
 /// sys_mkdir - Create a directory
-fn sys_mkdir(pathname: *const u8, _mode: i32) -> i64 {
+fn sys_mkdir(pathname: UserPtr<u8>, _mode: i32) -> i64 {
     if pathname.is_null() {
         return EFAULT;
     }
@@ -242,7 +416,7 @@ fn sys_mkdir(pathname: *const u8, _mode: i32) -> i64 {
 }
 
 /// sys_rmdir - Remove a directory
-fn sys_rmdir(pathname: *const u8) -> i64 {
+fn sys_rmdir(pathname: UserPtr<u8>) -> i64 {
     if pathname.is_null() {
         return EFAULT;
     }
@@ -252,11 +426,11 @@ fn sys_rmdir(pathname: *const u8) -> i64 {
 }
 
 /// sys_getdents - Get directory entries
-fn sys_getdents(fd: i32, dirp: *mut u8, _count: usize) -> i64 {
+fn sys_getdents(fd: i32, dirp: UserSlice) -> i64 {
     if fd < 0 {
         return EBADF;
     }
-    if dirp.is_null() {
+    if dirp.addr() == 0 {
         return EFAULT;
     }
     crate::serial_println!("[SYSCALL] sys_getdents() - stub");
@@ -265,7 +439,7 @@ fn sys_getdents(fd: i32, dirp: *mut u8, _count: usize) -> i64 {
 }
 
 /// sys_unlink - Remove a file
-fn sys_unlink(pathname: *const u8) -> i64 {
+fn sys_unlink(pathname: UserPtr<u8>) -> i64 {
     if pathname.is_null() {
         return EFAULT;
     }
@@ -274,91 +448,6 @@ fn sys_unlink(pathname: *const u8) -> i64 {
     ENOSYS
 }
 
-/// sys_mmap - Map memory region
-/// 
-/// # Arguments
-/// * `addr` - Requested address (or 0 for automatic)
-/// * `length` - Size of mapping in bytes
-/// * `prot` - Protection flags (PROT_READ, PROT_WRITE, PROT_EXEC)
-/// * `flags` - Mapping flags (MAP_SHARED, MAP_PRIVATE, MAP_ANONYMOUS, MAP_FIXED)
-/// * `fd` - File descriptor (ignored for MAP_ANONYMOUS)
-/// * `offset` - File offset (ignored for MAP_ANONYMOUS)
-///
-/// # Returns
-/// Virtual address of mapping on success, or negative error code
-fn sys_mmap(addr: u64, length: usize, prot: i32, flags: i32, _fd: i32, _offset: i64) -> i64 {
-    #[cfg(not(test))]
-    crate::serial_println!(
-        "[SYSCALL] sys_mmap(addr={:#x}, len={}, prot={:#x}, flags={:#x})",
-        addr, length, prot, flags
-    );
-
-    // Validate arguments
-    if length == 0 {
-        return EINVAL;
-    }
-
-    // For now, only support anonymous mappings
-    const MAP_ANONYMOUS: i32 = 0x20;
-    if (flags & MAP_ANONYMOUS) == 0 {
-        return ENOSYS; // File-backed mappings not yet implemented
-    }
-
-    // TODO: Implement actual mmap functionality
-    // This would involve:
-    // 1. Allocating physical pages
-    // 2. Setting up page table mappings
-    // 3. Recording the mapping in the process's mmap manager
-    // 4. Handling MAP_FIXED, MAP_SHARED, etc.
-    
-    // For now, return a dummy address in user space
-    // In a real implementation, this would be a proper allocated region
-    if addr == 0 {
-        // Automatic placement - return an address in user space
-        0x4000_0000 // Dummy address
-    } else {
-        // Fixed placement
-        addr as i64
-    }
-}
-
-/// sys_munmap - Unmap memory region
-///
-/// # Arguments
-/// * `addr` - Virtual address of mapping to unmap
-/// * `length` - Size of region to unmap
-///
-/// # Returns
-/// 0 on success, or negative error code
-fn sys_munmap(addr: u64, length: usize) -> i64 {
-    #[cfg(not(test))]
-    crate::serial_println!(
-        "[SYSCALL] sys_munmap(addr={:#x}, len={})",
-        addr, length
-    );
-
-    // Validate arguments
-    if length == 0 {
-        return EINVAL;
-    }
-
-    // Check alignment (use constant from memory module when available)
-    const PAGE_SIZE: u64 = 4096;
-    if addr % PAGE_SIZE != 0 {
-        return EINVAL;
-    }
-
-    // TODO: Implement actual munmap functionality
-    // This would involve:
-    // 1. Finding the mapping in the process's mmap manager
-    // 2. Unmapping the pages from the page table
-    // 3. Freeing the physical pages (if not shared)
-    // 4. Removing the mapping record
-
-    // For now, just return success
-    0
-}
-
 /// sys_exit - Terminate the current process
 fn sys_exit(status: i32) -> i64 {
     crate::serial_println!("[SYSCALL] sys_exit(status={})", status);
@@ -391,7 +480,7 @@ fn sys_fork() -> i64 {
 }
 
 /// sys_exec - Execute a new program
-fn sys_exec(_path: *const u8, _argv: *const *const u8) -> i64 {
+fn sys_exec(_path: UserPtr<u8>, _argv: UserPtr<u64>) -> i64 {
     crate::serial_println!("[SYSCALL] sys_exec() - not implemented yet");
     
     // TODO: Implement exec when we have filesystem and ELF loader:
@@ -405,7 +494,7 @@ fn sys_exec(_path: *const u8, _argv: *const *const u8) -> i64 {
 }
 
 /// sys_pipe - Create a pipe
-fn sys_pipe(pipefd: *mut i32) -> i64 {
+fn sys_pipe(pipefd: UserPtr<[i32; 2]>) -> i64 {
     // Validate arguments
     if pipefd.is_null() {
         return EFAULT;
@@ -424,12 +513,10 @@ fn sys_pipe(pipefd: *mut i32) -> i64 {
     const DUMMY_WRITE_FD: i32 = 4;
     
     // For now, just return success with dummy file descriptors
-    unsafe {
-        *pipefd.offset(0) = DUMMY_READ_FD;
-        *pipefd.offset(1) = DUMMY_WRITE_FD;
+    match pipefd.write(&[DUMMY_READ_FD, DUMMY_WRITE_FD]) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
     }
-    
-    0
 }
 
 /// sys_kill - Send a signal to a process
@@ -472,8 +559,8 @@ fn sys_shmget(key: i32, size: usize, shmflg: i32) -> i64 {
 }
 
 /// sys_shmat - Attach shared memory segment
-fn sys_shmat(shmid: i32, shmaddr: *const u8, shmflg: i32) -> i64 {
-    crate::serial_println!("[SYSCALL] sys_shmat(id={}, addr={:p}, flags={})", shmid, shmaddr, shmflg);
+fn sys_shmat(shmid: i32, shmaddr: UserPtr<u8>, shmflg: i32) -> i64 {
+    crate::serial_println!("[SYSCALL] sys_shmat(id={}, addr={:#x}, flags={})", shmid, shmaddr.addr(), shmflg);
     
     if shmid < 0 {
         return EINVAL;
@@ -489,8 +576,8 @@ fn sys_shmat(shmid: i32, shmaddr: *const u8, shmflg: i32) -> i64 {
 }
 
 /// sys_shmdt - Detach shared memory segment
-fn sys_shmdt(shmaddr: *const u8) -> i64 {
-    crate::serial_println!("[SYSCALL] sys_shmdt(addr={:p})", shmaddr);
+fn sys_shmdt(shmaddr: UserPtr<u8>) -> i64 {
+    crate::serial_println!("[SYSCALL] sys_shmdt(addr={:#x})", shmaddr.addr());
     
     if shmaddr.is_null() {
         return EINVAL;
@@ -506,7 +593,7 @@ fn sys_shmdt(shmaddr: *const u8) -> i64 {
 }
 
 /// sys_shmctl - Control shared memory segment
-fn sys_shmctl(shmid: i32, cmd: i32, _buf: *mut u8) -> i64 {
+fn sys_shmctl(shmid: i32, cmd: i32, _buf: UserPtr<u8>) -> i64 {
     crate::serial_println!("[SYSCALL] sys_shmctl(id={}, cmd={})", shmid, cmd);
     
     if shmid < 0 {
@@ -535,7 +622,7 @@ fn sys_msgget(key: i32, msgflg: i32) -> i64 {
 }
 
 /// sys_msgsnd - Send message to queue
-fn sys_msgsnd(msqid: i32, msgp: *const u8, msgsz: usize, msgflg: i32) -> i64 {
+fn sys_msgsnd(msqid: i32, msgp: UserPtr<u8>, msgsz: usize, msgflg: i32) -> i64 {
     crate::serial_println!("[SYSCALL] sys_msgsnd(id={}, size={}, flags={})", msqid, msgsz, msgflg);
     
     if msgp.is_null() || msqid < 0 {
@@ -553,7 +640,7 @@ fn sys_msgsnd(msqid: i32, msgp: *const u8, msgsz: usize, msgflg: i32) -> i64 {
 }
 
 /// sys_msgrcv - Receive message from queue
-fn sys_msgrcv(msqid: i32, msgp: *mut u8, msgsz: usize, msgtyp: i64, msgflg: i32) -> i64 {
+fn sys_msgrcv(msqid: i32, msgp: UserPtr<u8>, msgsz: usize, msgtyp: i64, msgflg: i32) -> i64 {
     crate::serial_println!("[SYSCALL] sys_msgrcv(id={}, size={}, type={}, flags={})", msqid, msgsz, msgtyp, msgflg);
     
     if msgp.is_null() || msqid < 0 {
@@ -574,13 +661,20 @@ fn sys_msgrcv(msqid: i32, msgp: *mut u8, msgsz: usize, msgtyp: i64, msgflg: i32)
 ///
 /// This is called directly by the CPU when a SYSCALL instruction is executed.
 /// It must:
-/// 1. Save all registers
-/// 2. Call syscall_handler
-/// 3. Restore registers
-/// 4. Return via SYSRET
+/// 1. Switch to the CPU's kernel stack (`percpu::GS_KERNEL_RSP`)
+/// 2. Save all registers (laid out as `user_return::SyscallFrame`)
+/// 3. Call syscall_handler
+/// 4. Run the return-to-user hook, which may rewrite the saved state
+/// 5. Restore registers
+/// 6. Return via SYSRET
 ///
-/// NOTE: This is a minimal implementation for kernel-mode syscall testing.
-/// A full implementation would need to handle user/kernel stack switching.
+/// SWAPGS on entry and exit keeps the kernel GS base (per-CPU data)
+/// loaded while in the kernel. The user stack pointer is parked in the
+/// per-CPU block until the kernel stack is loaded.
+///
+/// Kernel threads may issue SYSCALL too; their return RIP is in the
+/// higher half. They keep their GS and stack, and return with IRETQ, as
+/// SYSRET always enters ring 3.
 #[unsafe(naked)]
 #[no_mangle]
 unsafe extern "C" fn syscall_entry() -> ! {
@@ -589,8 +683,24 @@ unsafe extern "C" fn syscall_entry() -> ! {
         // r11 = RFLAGS (saved by SYSCALL instruction)
         // rax = syscall number
         // rdi, rsi, rdx, r10, r8, r9 = arguments
-        
-        // Save user registers on stack
+
+        // Kernel addresses have bit 63 set
+        "test rcx, rcx",
+        "js 2f",
+
+        // From user mode: kernel GS base (per-CPU data), then kernel stack
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
+        "push qword ptr gs:[{user_rsp}]", // User RSP
+        "jmp 3f",
+
+        // From a kernel thread: stay on its stack
+        "2:",
+        "push rsp",                   // Caller RSP (value before the push)
+
+        // Save registers on stack (SyscallFrame, highest field first)
+        "3:",
         "push rcx",                   // Return RIP
         "push r11",                   // RFLAGS
         "push rbp",
@@ -599,47 +709,50 @@ unsafe extern "C" fn syscall_entry() -> ! {
         "push r13",
         "push r14",
         "push r15",
-        
-        // Prepare arguments for syscall_handler
-        // C calling convention: rdi, rsi, rdx, rcx, r8, r9, [stack]
+        "push rax",                   // Syscall number
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+
+        // rbx (saved above, callee-saved) keeps the frame address; the
+        // stack is aligned for the calls below
+        "mov rbx, rsp",
+        "and rsp, -16",
+
         // syscall_handler(syscall_number, arg1, arg2, arg3, arg4, arg5, arg6)
-        
-        // Current state:
-        // rax = syscall number
-        // rdi = arg1
-        // rsi = arg2
-        // rdx = arg3
-        // r10 = arg4 (not rcx, as SYSCALL uses it)
-        // r8 = arg5
-        // r9 = arg6
-        
-        // Save arguments that will be overwritten
-        "push r9",                    // arg6
-        "push r8",                    // arg5
-        "push r10",                   // arg4
-        "push rdx",                   // arg3
-        "push rsi",                   // arg2
-        "push rdi",                   // arg1
-        "push rax",                   // syscall number
-        
-        // Now rearrange for C calling convention
-        "pop rdi",                    // syscall_number
-        "pop rsi",                    // arg1
-        "pop rdx",                    // arg2
-        "pop rcx",                    // arg3
-        "pop r8",                     // arg4
-        "pop r9",                     // arg5
-        // arg6 is still on stack, which is correct for 7th C argument
-        
+        // C calling convention: rdi, rsi, rdx, rcx, r8, r9, [stack]
+        // Arguments come from the frame: arg4 is r10, as SYSCALL uses rcx
+        "sub rsp, 8",
+        "push qword ptr [rbx + 5*8]", // arg6 (r9) as the 7th C argument
+        "mov r9, [rbx + 4*8]",        // arg5 (r8)
+        "mov r8, [rbx + 3*8]",        // arg4 (r10)
+        "mov rcx, [rbx + 2*8]",       // arg3 (rdx)
+        "mov rdx, [rbx + 1*8]",       // arg2 (rsi)
+        "mov rsi, [rbx]",             // arg1 (rdi)
+        "mov rdi, [rbx + 6*8]",       // syscall number
+
         // Call the handler
         "call syscall_handler",
-        
-        // Return value is in rax - leave it there
-        
-        // Clean up arg6 from stack
-        "add rsp, 8",
-        
-        // Restore user registers
+        "mov rsp, rbx",
+
+        // Return-to-user work (signal delivery); returns the final rax
+        "and rsp, -16",
+        "mov rdi, rbx",               // &mut SyscallFrame
+        "mov rsi, rax",               // Return value
+        "call syscall_exit_to_user",
+        "mov rsp, rbx",
+
+        // Restore registers
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "add rsp, 8",                 // Syscall number
         "pop r15",
         "pop r14",
         "pop r13",
@@ -648,13 +761,30 @@ unsafe extern "C" fn syscall_entry() -> ! {
         "pop rbp",
         "pop r11",                    // RFLAGS
         "pop rcx",                    // Return RIP
-        
+        "test rcx, rcx",
+        "js 4f",
+
         // Return to user space
         // NOTE: sysretq requires:
         // - rcx = return RIP
         // - r11 = RFLAGS
         // - rax = return value (already set)
+        "pop rsp",                    // User RSP
+        "swapgs",
         "sysretq",
+
+        // Return to a kernel thread: IRETQ frame below the saved RSP
+        "4:",
+        "push {kernel_ss}",
+        "push qword ptr [rsp + 8]",   // Caller RSP
+        "push r11",                   // RFLAGS
+        "push {kernel_cs}",
+        "push rcx",                   // Return RIP
+        "iretq",
+        user_rsp = const crate::percpu::GS_USER_RSP,
+        kernel_rsp = const crate::percpu::GS_KERNEL_RSP,
+        kernel_cs = const KERNEL_CODE_SELECTOR as u64,
+        kernel_ss = const crate::gdt::KERNEL_DATA_SELECTOR as u64,
     )
 }
 
@@ -674,8 +804,9 @@ pub fn init() {
         // For SYSCALL: CS = STAR[47:32], SS = STAR[47:32] + 8
         // For SYSRET: CS = STAR[63:48] + 16, SS = STAR[63:48] + 8
         
-        // Kernel CS = 0x08, User CS base = 0x18 (will use 0x18+16=0x28 for CS)
-        let star = ((0x18u64) << 48) | ((KERNEL_CODE_SELECTOR as u64) << 32);
+        // Kernel CS = 0x08 (SS 0x10); SYSRET base 0x10 gives user SS 0x18
+        // and user CS 0x20
+        let star = ((crate::gdt::SYSRET_SELECTOR_BASE as u64) << 48) | ((KERNEL_CODE_SELECTOR as u64) << 32);
         wrmsr(IA32_STAR, star);
         
         // Set the syscall entry point
@@ -683,7 +814,7 @@ pub fn init() {
         wrmsr(IA32_LSTAR, lstar);
         
         // Mask interrupts (IF), trap flag (TF), and direction flag (DF) during syscall
-        let fmask = RFLAGS_IF | RFLAGS_TF | RFLAGS_DF;
+        let fmask = RFLAGS_IF | RFLAGS_TF | RFLAGS_DF | RFLAGS_AC;
         wrmsr(IA32_FMASK, fmask);
         
         crate::serial_println!("[SYSCALL] initialized ✅");
@@ -703,6 +834,7 @@ mod tests {
         assert_eq!(SYS_EXIT, 60);
         assert_eq!(SYS_FORK, 57);
         assert_eq!(SYS_EXEC, 59);
+        assert_eq!(SYS_WAIT4, 61);
         
         // IPC syscalls
         assert_eq!(SYS_PIPE, 22);
@@ -729,40 +861,40 @@ mod tests {
 
     #[test]
     fn test_sys_write_null_buffer() {
-        let result = sys_write(1, core::ptr::null(), 10);
+        let result = sys_write(1, UserSlice::new(0, 10));
         assert_eq!(result, EFAULT);
     }
 
     #[test]
     fn test_sys_write_invalid_fd() {
         let buf = b"test";
-        let result = sys_write(99, buf.as_ptr(), buf.len());
+        let result = sys_write(99, UserSlice::new(buf.as_ptr() as u64, buf.len()));
         assert_eq!(result, EBADF);
     }
 
     #[test]
     fn test_sys_read_null_buffer() {
-        let result = sys_read(0, core::ptr::null_mut(), 10);
+        let result = sys_read(0, UserSlice::new(0, 10));
         assert_eq!(result, EFAULT);
     }
 
     #[test]
     fn test_sys_read_invalid_fd() {
         let mut buf = [0u8; 10];
-        let result = sys_read(99, buf.as_mut_ptr(), buf.len());
+        let result = sys_read(99, UserSlice::new(buf.as_mut_ptr() as u64, buf.len()));
         assert_eq!(result, EBADF);
     }
     
     #[test]
     fn test_sys_pipe_null_ptr() {
-        let result = sys_pipe(core::ptr::null_mut());
+        let result = sys_pipe(UserPtr::new(0));
         assert_eq!(result, EFAULT);
     }
     
     #[test]
     fn test_sys_pipe_basic() {
         let mut pipefd = [0i32; 2];
-        let result = sys_pipe(pipefd.as_mut_ptr());
+        let result = sys_pipe(UserPtr::new(pipefd.as_mut_ptr() as u64));
         assert_eq!(result, 0);
         assert!(pipefd[0] > 0);
         assert!(pipefd[1] > 0);
@@ -792,53 +924,4 @@ mod tests {
         let result = sys_shmget(1, 4096, 0);
         assert!(result > 0);
     }
-    
-    #[test]
-    fn test_sys_mmap_anonymous() {
-        const MAP_ANONYMOUS: i32 = 0x20;
-        const PROT_READ: i32 = 0x1;
-        const PROT_WRITE: i32 = 0x2;
-        
-        let result = sys_mmap(0, 4096, PROT_READ | PROT_WRITE, MAP_ANONYMOUS, -1, 0);
-        assert!(result > 0); // Should return a valid address
-    }
-    
-    #[test]
-    fn test_sys_mmap_zero_length() {
-        const MAP_ANONYMOUS: i32 = 0x20;
-        const PROT_READ: i32 = 0x1;
-        
-        let result = sys_mmap(0, 0, PROT_READ, MAP_ANONYMOUS, -1, 0);
-        assert_eq!(result, EINVAL);
-    }
-    
-    #[test]
-    fn test_sys_mmap_fixed_address() {
-        const MAP_ANONYMOUS: i32 = 0x20;
-        const MAP_FIXED: i32 = 0x10;
-        const PROT_READ: i32 = 0x1;
-        
-        let addr = 0x4000_0000u64;
-        let result = sys_mmap(addr, 4096, PROT_READ, MAP_ANONYMOUS | MAP_FIXED, -1, 0);
-        assert_eq!(result, addr as i64);
-    }
-    
-    #[test]
-    fn test_sys_munmap_valid() {
-        let addr = 0x4000_0000u64;
-        let result = sys_munmap(addr, 4096);
-        assert_eq!(result, 0);
-    }
-    
-    #[test]
-    fn test_sys_munmap_zero_length() {
-        let result = sys_munmap(0x4000_0000, 0);
-        assert_eq!(result, EINVAL);
-    }
-    
-    #[test]
-    fn test_sys_munmap_unaligned() {
-        let result = sys_munmap(0x4000_0001, 4096);
-        assert_eq!(result, EINVAL);
-    }
 }
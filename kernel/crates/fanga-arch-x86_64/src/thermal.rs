@@ -0,0 +1,166 @@
+//! CPU Temperature and Clock Modulation
+//!
+//! Intel CPUs with a digital thermal sensor (CPUID.06H:EAX[0]) report
+//! each core's temperature in IA32_THERM_STATUS, and with package thermal
+//! management (CPUID.06H:EAX[6]) the whole package's in
+//! IA32_PACKAGE_THERM_STATUS. The reading counts degrees Celsius below
+//! TjMax, the temperature at which the CPU starts throttling itself,
+//! which MSR_TEMPERATURE_TARGET gives.
+//!
+//! On-demand clock modulation (CPUID.01H:EDX[22]) lets the OS throttle a
+//! core further by stopping its clock for part of the time, in 12.5%
+//! steps, through IA32_CLOCK_MODULATION. Both MSRs are per core.
+//!
+//! This module provides:
+//! - Sensor detection and readings
+//! - TjMax
+//! - Clock modulation
+
+use crate::cpuid::{cpu_features, Feature, Vendor};
+
+const IA32_CLOCK_MODULATION: u32 = 0x19A;
+const IA32_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+/// Thermal status: the sensor is at or above the PROCHOT# temperature
+const STATUS_PROCHOT: u64 = 1 << 0;
+/// Thermal status: the sensor is at or above the critical temperature
+const STATUS_CRITICAL: u64 = 1 << 4;
+/// Thermal status: the digital readout is valid
+const STATUS_VALID: u64 = 1 << 31;
+
+/// Clock modulation: on-demand modulation enabled
+const MODULATION_ENABLE: u64 = 1 << 4;
+
+/// TjMax of CPUs that do not report one
+pub const DEFAULT_TJ_MAX: u8 = 100;
+
+/// Clock modulation duty cycles, in eighths of the time the clock runs
+pub const MODULATION_STEPS: u8 = 8;
+
+/// A decoded thermal status register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermStatus {
+    /// Degrees Celsius below TjMax, None if the reading is not valid
+    pub below_tj_max: Option<u8>,
+    /// The CPU is throttling itself
+    pub prochot: bool,
+    /// The CPU is past its critical temperature
+    pub critical: bool,
+}
+
+impl ThermStatus {
+    /// Decode IA32_THERM_STATUS or IA32_PACKAGE_THERM_STATUS
+    pub const fn from_msr(value: u64) -> Self {
+        Self {
+            below_tj_max: if value & STATUS_VALID != 0 { Some(((value >> 16) & 0x7F) as u8) } else { None },
+            prochot: value & STATUS_PROCHOT != 0,
+            critical: value & STATUS_CRITICAL != 0,
+        }
+    }
+
+    /// Temperature in degrees Celsius, given TjMax
+    pub fn celsius(&self, tj_max: u8) -> Option<i32> {
+        self.below_tj_max.map(|below| tj_max as i32 - below as i32)
+    }
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Whether the cores have a digital thermal sensor
+pub fn has_sensor() -> bool {
+    let cpu = cpu_features();
+    cpu.vendor() == Vendor::Intel && cpu.has(Feature::Dtherm)
+}
+
+/// Whether the package has a thermal sensor
+pub fn has_package_sensor() -> bool {
+    has_sensor() && cpu_features().has(Feature::Pts)
+}
+
+/// Whether clock modulation is available
+pub fn has_clock_modulation() -> bool {
+    let cpu = cpu_features();
+    cpu.vendor() == Vendor::Intel && cpu.has(Feature::Acpi)
+}
+
+/// TjMax in degrees Celsius, from MSR_TEMPERATURE_TARGET
+///
+/// CPUs that leave the field zero get `DEFAULT_TJ_MAX`.
+pub fn tj_max() -> u8 {
+    match (rdmsr(MSR_TEMPERATURE_TARGET) >> 16) as u8 {
+        0 => DEFAULT_TJ_MAX,
+        tj_max => tj_max,
+    }
+}
+
+/// Thermal status of this core
+pub fn core_status() -> ThermStatus {
+    ThermStatus::from_msr(rdmsr(IA32_THERM_STATUS))
+}
+
+/// Thermal status of this package
+pub fn package_status() -> ThermStatus {
+    ThermStatus::from_msr(rdmsr(IA32_PACKAGE_THERM_STATUS))
+}
+
+/// IA32_CLOCK_MODULATION value running the clock `duty` eighths of the
+/// time; `MODULATION_STEPS` or more turns modulation off
+pub const fn clock_modulation_value(duty: u8) -> u64 {
+    if duty >= MODULATION_STEPS {
+        0
+    } else {
+        // A duty of zero is reserved; run at least an eighth of the time
+        let duty = if duty == 0 { 1 } else { duty };
+        MODULATION_ENABLE | (duty as u64) << 1
+    }
+}
+
+/// Run this core's clock `duty` eighths of the time
+///
+/// # Safety
+/// Clock modulation must be available.
+pub unsafe fn set_clock_modulation(duty: u8) {
+    wrmsr(IA32_CLOCK_MODULATION, clock_modulation_value(duty));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_therm_status() {
+        // Valid, 35 degrees below TjMax, PROCHOT
+        let status = ThermStatus::from_msr(STATUS_VALID | 35 << 16 | STATUS_PROCHOT);
+        assert_eq!(status, ThermStatus { below_tj_max: Some(35), prochot: true, critical: false });
+        assert_eq!(status.celsius(100), Some(65));
+
+        let invalid = ThermStatus::from_msr(35 << 16 | STATUS_CRITICAL);
+        assert_eq!(invalid.celsius(100), None);
+        assert!(invalid.critical);
+    }
+
+    #[test]
+    fn test_clock_modulation_value() {
+        assert_eq!(clock_modulation_value(MODULATION_STEPS), 0);
+        assert_eq!(clock_modulation_value(4), MODULATION_ENABLE | 4 << 1);
+        assert_eq!(clock_modulation_value(0), MODULATION_ENABLE | 1 << 1);
+    }
+}
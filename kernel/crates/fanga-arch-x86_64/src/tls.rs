@@ -0,0 +1,116 @@
+//! FS Base Register
+//!
+//! The FS segment base is the thread pointer on x86_64: both the user ABI
+//! and kernel thread-local storage address TLS relative to it. It is
+//! written with WRFSBASE when the CPU supports FSGSBASE, otherwise through
+//! the IA32_FS_BASE MSR.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// IA32_FS_BASE model specific register
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+
+/// CR4.FSGSBASE (enables RD/WR{FS,GS}BASE)
+#[cfg(not(test))]
+const CR4_FSGSBASE: u64 = 1 << 16;
+
+/// Whether WRFSBASE/RDFSBASE are enabled
+static FSGSBASE: AtomicBool = AtomicBool::new(false);
+
+/// Check CPUID.(EAX=7,ECX=0):EBX bit 0 for FSGSBASE
+pub fn fsgsbase_supported() -> bool {
+    crate::cpuid::has(crate::cpuid::Feature::Fsgsbase)
+}
+
+/// Enable the FSGSBASE instructions if available
+pub fn init() {
+    #[cfg(not(test))]
+    if fsgsbase_supported() {
+        unsafe {
+            core::arch::asm!(
+                "mov {tmp}, cr4",
+                "or {tmp}, {bit}",
+                "mov cr4, {tmp}",
+                tmp = out(reg) _,
+                bit = in(reg) CR4_FSGSBASE,
+                options(nostack, preserves_flags),
+            );
+        }
+        FSGSBASE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Check whether WRFSBASE is used to switch the FS base
+pub fn uses_fsgsbase() -> bool {
+    FSGSBASE.load(Ordering::Relaxed)
+}
+
+/// FS base last written by the kernel
+///
+/// Without FSGSBASE user code cannot change FS, so this is always the
+/// hardware value and switches to an unchanged base skip the MSR write.
+static LOADED_FS_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Read the current FS base
+pub fn read_fs_base() -> u64 {
+    #[cfg(not(test))]
+    if uses_fsgsbase() {
+        let base: u64;
+        unsafe {
+            core::arch::asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags));
+        }
+        return base;
+    }
+    LOADED_FS_BASE.load(Ordering::Relaxed)
+}
+
+/// Set the FS base
+///
+/// # Safety
+/// Code running after this relies on the FS base for TLS; `base` must
+/// point to a valid thread control block (or be 0 for none). It must also
+/// be canonical, or the write faults.
+pub unsafe fn write_fs_base(base: u64) {
+    if !uses_fsgsbase() && LOADED_FS_BASE.load(Ordering::Relaxed) == base {
+        return;
+    }
+    #[cfg(not(test))]
+    if uses_fsgsbase() {
+        core::arch::asm!("wrfsbase {}", in(reg) base, options(nomem, nostack, preserves_flags));
+    } else {
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") IA32_FS_BASE,
+            in("eax") base as u32,
+            in("edx") (base >> 32) as u32,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    LOADED_FS_BASE.store(base, Ordering::Relaxed);
+}
+
+/// Check that an address is canonical (bits 63:47 all equal)
+pub fn is_canonical(addr: u64) -> bool {
+    let upper = addr >> 47;
+    upper == 0 || upper == 0x1_FFFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical() {
+        assert!(is_canonical(0));
+        assert!(is_canonical(0x0000_7FFF_FFFF_F000));
+        assert!(is_canonical(0xFFFF_8000_0000_0000));
+        assert!(!is_canonical(0x0000_8000_0000_0000));
+        assert!(!is_canonical(0x1234_5678_0000_0000));
+    }
+
+    #[test]
+    fn test_fs_base_roundtrip() {
+        unsafe { write_fs_base(0x7000_1000) };
+        assert_eq!(read_fs_base(), 0x7000_1000);
+    }
+}
@@ -0,0 +1,88 @@
+//! Time Stamp Counter (TSC)
+//!
+//! The TSC counts CPU cycles from reset and is read with a single
+//! unprivileged instruction. Only an invariant TSC, which ticks at a
+//! constant rate through frequency changes and C-states, is usable as a
+//! clock. Its frequency comes from CPUID leaf 0x15 where the CPU reports
+//! it, otherwise it is measured against PIT channel 2.
+//!
+//! This module provides:
+//! - `rdtsc`
+//! - Invariant TSC detection
+//! - Frequency detection and calibration
+
+/// Length of the PIT reference window used for calibration
+pub const CALIBRATION_MS: u32 = 20;
+
+/// Read this CPU's time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Check CPUID.80000007H:EDX bit 8 for an invariant TSC
+pub fn is_invariant() -> bool {
+    crate::cpuid::has(crate::cpuid::Feature::InvariantTsc)
+}
+
+/// TSC frequency in kHz from a CPUID leaf 0x15 report
+///
+/// `denominator` and `numerator` give the TSC/crystal clock ratio,
+/// `crystal_hz` the crystal frequency. Returns None if a field is 0,
+/// which means the CPU does not report it.
+pub fn khz_from_ratio(denominator: u32, numerator: u32, crystal_hz: u32) -> Option<u64> {
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+    Some(crystal_hz as u64 * numerator as u64 / denominator as u64 / 1000)
+}
+
+/// TSC frequency in kHz as reported by CPUID leaf 0x15
+pub fn cpuid_khz() -> Option<u64> {
+    let (denominator, numerator, crystal_hz) = crate::cpuid::cpu_features().tsc_ratio()?;
+    khz_from_ratio(denominator, numerator, crystal_hz)
+}
+
+/// Measure the TSC frequency in kHz against the PIT
+///
+/// # Safety
+/// Performs port I/O; call with interrupts disabled.
+pub unsafe fn calibrate_khz() -> u64 {
+    let start = rdtsc();
+    let us = crate::interrupts::pit::calibration_wait(CALIBRATION_MS);
+    let cycles = rdtsc() - start;
+    if us == 0 {
+        return 0;
+    }
+    cycles * 1000 / us
+}
+
+/// Determine the TSC frequency in kHz
+///
+/// Prefers the CPUID report and falls back to calibration.
+///
+/// # Safety
+/// Same as `calibrate_khz`.
+pub unsafe fn frequency_khz() -> u64 {
+    cpuid_khz().unwrap_or_else(|| calibrate_khz())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_khz_from_ratio() {
+        // 24 MHz crystal with a 2:250 ratio gives a 3 GHz TSC
+        assert_eq!(khz_from_ratio(2, 250, 24_000_000), Some(3_000_000));
+        assert_eq!(khz_from_ratio(0, 250, 24_000_000), None);
+        assert_eq!(khz_from_ratio(2, 250, 0), None);
+    }
+
+    #[test]
+    fn test_rdtsc_advances() {
+        let a = rdtsc();
+        let b = rdtsc();
+        assert!(b >= a);
+    }
+}
@@ -0,0 +1,283 @@
+//! User Memory Access
+//!
+//! With SMAP enabled the kernel faults on any access to a user page unless
+//! RFLAGS.AC is set. The helpers here are the only code that sets it: each
+//! one checks that the range lies in user space, opens the window with
+//! STAC, copies, and closes it with CLAC. Everything else that needs user
+//! memory goes through them.
+//!
+//! Besides the range check, the kernel may register a check against the
+//! calling process's memory map (`set_access_check`), so only its mapped
+//! regions with the right permissions can be touched.
+//!
+//! This module provides:
+//! - `UserPtr` / `UserSlice` for user addresses handed to system calls
+//! - `copy_from_user` / `copy_to_user` for byte ranges
+//! - `get_user` / `put_user` for plain values
+
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+use crate::protection::smap_enabled;
+use crate::user_return::USER_ADDR_LIMIT;
+
+/// Allow supervisor access to user pages
+#[inline(always)]
+fn stac() {
+    if smap_enabled() {
+        unsafe { core::arch::asm!("stac", options(nostack)) };
+    }
+}
+
+/// Forbid supervisor access to user pages again
+#[inline(always)]
+fn clac() {
+    if smap_enabled() {
+        unsafe { core::arch::asm!("clac", options(nostack)) };
+    }
+}
+
+/// How user memory is about to be accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Check that the calling process may access `addr..addr + len`
+pub type AccessCheck = fn(u64, usize, Access) -> bool;
+
+static mut ACCESS_CHECK: Option<AccessCheck> = None;
+
+/// Register the check of user ranges against the caller's memory map
+///
+/// # Safety
+/// Must be called before user code issues system calls (during init).
+pub unsafe fn set_access_check(check: AccessCheck) {
+    ACCESS_CHECK = Some(check);
+}
+
+/// Check that `addr..addr + len` is a non-null range in user space
+pub fn access_ok(addr: u64, len: usize) -> bool {
+    match addr.checked_add(len as u64) {
+        Some(end) => addr != 0 && end <= USER_ADDR_LIMIT,
+        None => false,
+    }
+}
+
+/// Check a range against user space and the caller's memory map
+fn check_access(addr: u64, len: usize, access: Access) -> Result<(), &'static str> {
+    if !access_ok(addr, len) {
+        return Err("Bad user address");
+    }
+    match unsafe { ACCESS_CHECK } {
+        Some(check) if !check(addr, len, access) => Err("User address not mapped"),
+        _ => Ok(()),
+    }
+}
+
+/// Copy `dst.len()` bytes from user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), &'static str> {
+    check_access(src, dst.len(), Access::Read)?;
+    stac();
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+    clac();
+    Ok(())
+}
+
+/// Copy `src` to user address `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), &'static str> {
+    check_access(dst, src.len(), Access::Write)?;
+    stac();
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+    clac();
+    Ok(())
+}
+
+/// Read a value from user address `src`
+///
+/// `T` must be valid for any bit pattern, like the C structs system calls
+/// exchange.
+pub fn get_user<T: Copy>(src: u64) -> Result<T, &'static str> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(bytes, src)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Write a value to user address `dst`
+pub fn put_user<T: Copy>(dst: u64, value: &T) -> Result<(), &'static str> {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(dst, bytes)
+}
+
+/// A user address of a `T`, as passed to a system call
+///
+/// Holding one grants nothing: every access is checked and copied.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    pub const fn new(addr: u64) -> Self {
+        Self { addr, _marker: PhantomData }
+    }
+
+    /// The user address
+    pub const fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub const fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// Read the value
+    ///
+    /// `T` must be valid for any bit pattern, like the C structs system
+    /// calls exchange.
+    pub fn read(&self) -> Result<T, &'static str> {
+        get_user(self.addr)
+    }
+
+    /// Write the value
+    pub fn write(&self, value: &T) -> Result<(), &'static str> {
+        put_user(self.addr, value)
+    }
+}
+
+impl<T> From<*mut T> for UserPtr<T> {
+    fn from(ptr: *mut T) -> Self {
+        Self { addr: ptr as u64, _marker: PhantomData }
+    }
+}
+
+impl<T> From<*const T> for UserPtr<T> {
+    fn from(ptr: *const T) -> Self {
+        Self { addr: ptr as u64, _marker: PhantomData }
+    }
+}
+
+/// A user byte range, as passed to a system call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSlice {
+    addr: u64,
+    len: usize,
+}
+
+impl UserSlice {
+    pub const fn new(addr: u64, len: usize) -> Self {
+        Self { addr, len }
+    }
+
+    /// The user address
+    pub const fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check the whole range could be accessed, without touching it
+    pub fn check(&self, access: Access) -> Result<(), &'static str> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        check_access(self.addr, self.len, access)
+    }
+
+    /// The part from `offset`, at most `len` bytes long
+    pub fn subslice(&self, offset: usize, len: usize) -> Self {
+        let offset = offset.min(self.len);
+        Self { addr: self.addr + offset as u64, len: len.min(self.len - offset) }
+    }
+
+    /// Copy the range into `dst`, which must be as long
+    pub fn read(&self, dst: &mut [u8]) -> Result<(), &'static str> {
+        if dst.len() != self.len {
+            return Err("Length mismatch");
+        }
+        copy_from_user(dst, self.addr)
+    }
+
+    /// Copy `src`, which must be as long, into the range
+    pub fn write(&self, src: &[u8]) -> Result<(), &'static str> {
+        if src.len() != self.len {
+            return Err("Length mismatch");
+        }
+        copy_to_user(self.addr, src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_ok() {
+        assert!(access_ok(0x1000, 16));
+        assert!(access_ok(USER_ADDR_LIMIT - 16, 16));
+        assert!(!access_ok(0, 16));
+        assert!(!access_ok(USER_ADDR_LIMIT - 8, 16));
+        assert!(!access_ok(0xFFFF_8000_0000_0000, 8));
+        assert!(!access_ok(u64::MAX, 2));
+    }
+
+    #[test]
+    fn test_copy_round_trip() {
+        let mut user = [0u8; 8];
+        copy_to_user(user.as_mut_ptr() as u64, b"fangaos!").unwrap();
+
+        let mut back = [0u8; 8];
+        copy_from_user(&mut back, user.as_ptr() as u64).unwrap();
+        assert_eq!(&back, b"fangaos!");
+
+        let mut word = 0u64;
+        put_user(&mut word as *mut u64 as u64, &0xDEAD_BEEFu64).unwrap();
+        assert_eq!(get_user::<u64>(&word as *const u64 as u64), Ok(0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn test_copy_rejects_kernel_addresses() {
+        let mut buf = [0u8; 4];
+        assert!(copy_from_user(&mut buf, 0xFFFF_8000_0000_0000).is_err());
+        assert!(copy_to_user(0, &buf).is_err());
+        assert!(get_user::<u32>(u64::MAX - 1).is_err());
+    }
+
+    #[test]
+    fn test_user_ptr_and_slice() {
+        let mut value = 0u32;
+        let ptr = UserPtr::from(&mut value as *mut u32);
+        ptr.write(&0x1234).unwrap();
+        assert_eq!(ptr.read(), Ok(0x1234));
+        assert!(UserPtr::<u32>::new(0).is_null());
+        assert!(UserPtr::<u32>::new(0).read().is_err());
+
+        let mut user = *b"abcdef";
+        let slice = UserSlice::new(user.as_mut_ptr() as u64, user.len());
+        let tail = slice.subslice(4, 10);
+        assert_eq!(tail.len(), 2);
+        tail.write(b"XY").unwrap();
+        let mut back = [0u8; 6];
+        slice.read(&mut back).unwrap();
+        assert_eq!(&back, b"abcdXY");
+        assert!(slice.read(&mut [0u8; 2]).is_err());
+        assert!(slice.subslice(9, 1).is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! Return to User Mode
+//!
+//! Every path back to ring 3 (SYSRET after a system call, IRETQ after an
+//! interrupt taken in user mode) hands the user register state to a hook
+//! registered by the kernel. The hook may rewrite it, for example to enter
+//! a signal handler.
+
+use crate::interrupts::idt::InterruptStackFrame;
+
+/// User register state at the return to user mode
+///
+/// After an interrupt only `rip`, `rsp` and `rflags` are available; the
+/// other registers are restored by the interrupt epilogue and changes to
+/// them are ignored. `syscall` is `Some` on the system call path, where all
+/// fields can be rewritten.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserRegs {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    /// System call return value
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    /// System call number, if returning from a system call
+    pub syscall: Option<u64>,
+}
+
+impl UserRegs {
+    /// Whether argument registers can be set (system call path only)
+    pub fn can_set_args(&self) -> bool {
+        self.syscall.is_some()
+    }
+}
+
+/// Registers saved by `syscall_entry`, lowest address first
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyscallFrame {
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub orig_rax: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    /// User RFLAGS (r11 at SYSCALL)
+    pub rflags: u64,
+    /// User RIP (rcx at SYSCALL)
+    pub rip: u64,
+    /// User stack pointer
+    pub rsp: u64,
+}
+
+/// First address above user space; a SYSCALL from below it came from ring 3
+pub const USER_ADDR_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+/// RFLAGS bits user mode may change: CF, PF, AF, ZF, SF, TF, DF, OF, AC, ID
+const USER_RFLAGS_MASK: u64 = 0x0024_0DD5;
+
+/// RFLAGS reserved bit 1 and interrupt enable
+const RFLAGS_FIXED: u64 = 1 << 1;
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// RFLAGS to return to user mode with: interrupts on, no IOPL or VM bits
+pub fn sanitize_user_rflags(rflags: u64) -> u64 {
+    (rflags & USER_RFLAGS_MASK) | RFLAGS_FIXED | RFLAGS_IF
+}
+
+/// Hook run on every return to user mode
+pub type ReturnToUserHook = fn(&mut UserRegs);
+
+static mut RETURN_TO_USER_HOOK: Option<ReturnToUserHook> = None;
+
+/// Register the return-to-user hook
+///
+/// # Safety
+/// Must be called before any task runs in user mode (during init).
+pub unsafe fn set_return_to_user_hook(hook: ReturnToUserHook) {
+    RETURN_TO_USER_HOOK = Some(hook);
+}
+
+fn run_hook(regs: &mut UserRegs) {
+    unsafe {
+        if let Some(hook) = RETURN_TO_USER_HOOK {
+            hook(regs);
+        }
+    }
+}
+
+/// Called by `syscall_entry` just before SYSRET
+///
+/// Returns the value to place in rax.
+#[no_mangle]
+extern "C" fn syscall_exit_to_user(frame: &mut SyscallFrame, ret: i64) -> i64 {
+    // Kernel threads return with IRETQ and take no user work
+    if frame.rip >= USER_ADDR_LIMIT {
+        return ret;
+    }
+
+    let mut regs = UserRegs {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+        rax: ret as u64,
+        rdi: frame.rdi,
+        rsi: frame.rsi,
+        rdx: frame.rdx,
+        syscall: Some(frame.orig_rax),
+    };
+    run_hook(&mut regs);
+
+    // SYSRET to a non-canonical RIP faults in ring 0 on the user stack
+    if regs.rip < USER_ADDR_LIMIT {
+        frame.rip = regs.rip;
+    }
+    frame.rsp = regs.rsp;
+    frame.rflags = sanitize_user_rflags(regs.rflags);
+    frame.rdi = regs.rdi;
+    frame.rsi = regs.rsi;
+    frame.rdx = regs.rdx;
+    regs.rax as i64
+}
+
+/// Called by interrupt handlers before IRETQ
+///
+/// Does nothing unless the interrupt was taken in user mode.
+pub fn irq_exit_to_user(frame: &mut InterruptStackFrame) {
+    if frame.cs & 3 != 3 {
+        return;
+    }
+
+    let mut regs = UserRegs {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+        ..UserRegs::default()
+    };
+    run_hook(&mut regs);
+
+    // The frame is the hardware frame the CPU will IRETQ from; keep the
+    // compiler from treating these stores as dead
+    unsafe {
+        core::ptr::write_volatile(&mut frame.rip, regs.rip);
+        core::ptr::write_volatile(&mut frame.rsp, regs.rsp);
+        core::ptr::write_volatile(&mut frame.rflags, sanitize_user_rflags(regs.rflags));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syscall_frame_layout() {
+        // Must match the push order in syscall_entry
+        assert_eq!(core::mem::size_of::<SyscallFrame>(), 16 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, r10), 3 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, r9), 5 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, orig_rax), 6 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, rbx), 11 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, rip), 14 * 8);
+        assert_eq!(core::mem::offset_of!(SyscallFrame, rsp), 15 * 8);
+    }
+
+    #[test]
+    fn test_sanitize_user_rflags() {
+        // IOPL 3 and VM are dropped, IF is forced on
+        assert_eq!(sanitize_user_rflags(0x3000 | (1 << 17) | 0x1), 0x203);
+        assert_eq!(sanitize_user_rflags(0), 0x202);
+    }
+
+    #[test]
+    fn test_kernel_syscall_skips_user_work() {
+        let mut frame = SyscallFrame { rip: 0xFFFF_FFFF_8000_1000, rflags: 0x3002, ..SyscallFrame::default() };
+        assert_eq!(syscall_exit_to_user(&mut frame, 7), 7);
+        assert_eq!(frame.rflags, 0x3002);
+    }
+
+    #[test]
+    fn test_kernel_mode_irq_untouched() {
+        let mut frame = InterruptStackFrame { rip: 0x1000, cs: 0x08, rflags: 0x202, rsp: 0x2000, ss: 0x10 };
+        irq_exit_to_user(&mut frame);
+        assert_eq!(frame.rip, 0x1000);
+    }
+}
@@ -0,0 +1,93 @@
+//! Firmware ACPI Control Structure
+//!
+//! Unlike the description tables the FACS is written by the OS too: it
+//! holds the waking vector, where the firmware jumps when the machine
+//! wakes from S3. It has no checksum. The hardware signature changes when
+//! the firmware finds different hardware on wake, such as after a card
+//! was added while the machine slept.
+
+use super::{read_u32, read_u64};
+
+/// Length of the ACPI 1.0 FACS, the shortest there is
+pub const FACS_MIN_LEN: usize = 64;
+
+const HARDWARE_SIGNATURE: usize = 8;
+const WAKING_VECTOR: usize = 12;
+const FLAGS: usize = 16;
+const X_WAKING_VECTOR: usize = 24;
+const VERSION: usize = 32;
+
+/// The parts of the FACS the kernel uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facs {
+    /// Changes when the firmware finds different hardware at boot or wake
+    pub hardware_signature: u32,
+    /// Real-mode address the firmware jumps to on wake, 0 if none
+    pub waking_vector: u32,
+    /// Firmware capability flags
+    pub flags: u32,
+    /// Waking vector that takes precedence when set, 0 if none
+    pub x_waking_vector: u64,
+    pub version: u8,
+}
+
+impl Facs {
+    /// Decode a FACS
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        if data.get(0..4) != Some(b"FACS") {
+            return Err("Not a FACS");
+        }
+        let len = read_u32(data, 4).unwrap_or(0) as usize;
+        if len < FACS_MIN_LEN || len > data.len() {
+            return Err("Bad FACS length");
+        }
+        Ok(Self {
+            hardware_signature: read_u32(data, HARDWARE_SIGNATURE).unwrap_or(0),
+            waking_vector: read_u32(data, WAKING_VECTOR).unwrap_or(0),
+            flags: read_u32(data, FLAGS).unwrap_or(0),
+            x_waking_vector: read_u64(data, X_WAKING_VECTOR).unwrap_or(0),
+            version: data[VERSION],
+        })
+    }
+}
+
+/// Point the firmware at real-mode wakeup code at `phys`
+///
+/// The 64-bit vector is cleared: firmware that finds one set jumps there
+/// instead, possibly in protected mode.
+pub fn set_waking_vector(data: &mut [u8], phys: u32) -> Result<(), &'static str> {
+    Facs::parse(data)?;
+    data[WAKING_VECTOR..WAKING_VECTOR + 4].copy_from_slice(&phys.to_le_bytes());
+    data[X_WAKING_VECTOR..X_WAKING_VECTOR + 8].copy_from_slice(&0u64.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facs() -> [u8; FACS_MIN_LEN] {
+        let mut data = [0u8; FACS_MIN_LEN];
+        data[0..4].copy_from_slice(b"FACS");
+        data[4..8].copy_from_slice(&(FACS_MIN_LEN as u32).to_le_bytes());
+        data[HARDWARE_SIGNATURE..HARDWARE_SIGNATURE + 4].copy_from_slice(&0x1234u32.to_le_bytes());
+        data[X_WAKING_VECTOR..X_WAKING_VECTOR + 8].copy_from_slice(&0x9_0000u64.to_le_bytes());
+        data[VERSION] = 2;
+        data
+    }
+
+    #[test]
+    fn test_facs() {
+        let mut data = facs();
+        let facs = Facs::parse(&data).unwrap();
+        assert_eq!((facs.hardware_signature, facs.waking_vector, facs.x_waking_vector, facs.version), (0x1234, 0, 0x9_0000, 2));
+
+        set_waking_vector(&mut data, 0x8000).unwrap();
+        let facs = Facs::parse(&data).unwrap();
+        assert_eq!((facs.waking_vector, facs.x_waking_vector), (0x8000, 0));
+
+        assert_eq!(Facs::parse(&data[..32]), Err("Bad FACS length"));
+        data[0] = b'X';
+        assert_eq!(set_waking_vector(&mut data, 0x8000), Err("Not a FACS"));
+    }
+}
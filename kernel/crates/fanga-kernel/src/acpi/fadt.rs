@@ -0,0 +1,224 @@
+//! Fixed ACPI Description Table
+//!
+//! The FADT locates the fixed hardware power management registers (PM1
+//! event and control blocks, the PM timer, the GPE blocks), the reset
+//! register, and the DSDT. ACPI 2.0+ tables carry 64-bit "X_" generic
+//! addresses, which take precedence over the ACPI 1.0 I/O port fields.
+
+use super::{read_u16, read_u32, read_u64, sdt_signature, validate_sdt, GenericAddress};
+
+/// `flags`: WBINVD works to flush caches
+pub const FLAG_WBINVD: u32 = 1 << 0;
+/// `flags`: the power button is a control method device, not fixed
+pub const FLAG_PWR_BUTTON: u32 = 1 << 4;
+/// `flags`: the PM timer is 32 bits wide rather than 24
+pub const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+/// `flags`: the reset register is supported
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+/// `flags`: no fixed hardware; everything goes through AML
+pub const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// `boot_arch`: an 8042 keyboard controller is present
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+/// `boot_arch`: there is no CMOS RTC
+pub const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+/// Length of an ACPI 1.0 FADT, the shortest there is
+const FADT_V1_LEN: usize = 116;
+
+/// Offsets of the ACPI 2.0 fields
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_FIRMWARE_CTRL: usize = 132;
+const X_DSDT: usize = 140;
+const X_PM1A_EVT_BLK: usize = 148;
+
+/// The parts of the FADT the kernel uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Revision of the table's layout
+    pub revision: u8,
+    /// Physical address of the FACS
+    pub firmware_ctrl: u64,
+    /// Physical address of the DSDT
+    pub dsdt: u64,
+    /// ISA IRQ the SCI is wired to
+    pub sci_int: u16,
+    /// Port that `acpi_enable`/`acpi_disable` are written to, 0 if the
+    /// system is always in ACPI mode
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_evt: GenericAddress,
+    pub pm1b_evt: GenericAddress,
+    pub pm1a_cnt: GenericAddress,
+    pub pm1b_cnt: GenericAddress,
+    pub pm2_cnt: GenericAddress,
+    pub pm_tmr: GenericAddress,
+    pub gpe0: GenericAddress,
+    pub gpe1: GenericAddress,
+    /// CMOS RAM index of the century, 0 if there is none
+    pub century: u8,
+    /// IA-PC boot architecture flags (`BOOT_ARCH_*`)
+    pub boot_arch: u16,
+    /// Fixed feature flags (`FLAG_*`)
+    pub flags: u32,
+    /// Register written with `reset_value` to reset the system
+    pub reset_reg: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Decode a FADT, header included
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if sdt_signature(table) != Some(*b"FACP") {
+            return Err("Not a FADT");
+        }
+        validate_sdt(table)?;
+        let len = read_u32(table, 4).unwrap_or(0) as usize;
+        if len < FADT_V1_LEN {
+            return Err("FADT too short");
+        }
+        let table = &table[..len];
+        let u8_at = |offset: usize| table[offset];
+        let u32_at = |offset: usize| read_u32(table, offset).unwrap_or(0);
+
+        // A register block: the X_ generic address if the table has one,
+        // else the 32-bit port with its length
+        let block = |index: usize, port: usize, len: u8| {
+            GenericAddress::parse(table.get(X_PM1A_EVT_BLK + index * GenericAddress::LEN..).unwrap_or(&[]))
+                .filter(GenericAddress::is_present)
+                .unwrap_or(GenericAddress::io(u32_at(port), len))
+        };
+        let pm1_evt_len = u8_at(88);
+        let pm1_cnt_len = u8_at(89);
+
+        let flags = u32_at(112);
+        let reset_reg = GenericAddress::parse(&table[RESET_REG..])
+            .filter(|reg| flags & FLAG_RESET_REG_SUP != 0 && reg.is_present());
+
+        Ok(Self {
+            revision: table[8],
+            firmware_ctrl: read_u64(table, X_FIRMWARE_CTRL).filter(|&addr| addr != 0).unwrap_or(u32_at(36) as u64),
+            dsdt: read_u64(table, X_DSDT).filter(|&addr| addr != 0).unwrap_or(u32_at(40) as u64),
+            sci_int: read_u16(table, 46).unwrap_or(0),
+            smi_cmd: u32_at(48),
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_evt: block(0, 56, pm1_evt_len),
+            pm1b_evt: block(1, 60, pm1_evt_len),
+            pm1a_cnt: block(2, 64, pm1_cnt_len),
+            pm1b_cnt: block(3, 68, pm1_cnt_len),
+            pm2_cnt: block(4, 72, u8_at(90)),
+            pm_tmr: block(5, 76, u8_at(91)),
+            gpe0: block(6, 80, u8_at(92)),
+            gpe1: block(7, 84, u8_at(93)),
+            century: u8_at(108),
+            boot_arch: read_u16(table, 109).unwrap_or(0),
+            flags,
+            reset_reg,
+            reset_value: table.get(RESET_VALUE).copied().unwrap_or(0),
+        })
+    }
+
+    /// Whether the machine has no fixed ACPI hardware
+    pub fn is_hw_reduced(&self) -> bool {
+        self.flags & FLAG_HW_REDUCED_ACPI != 0
+    }
+
+    /// Whether the PM timer counts 32 bits rather than 24
+    pub fn pm_timer_32bit(&self) -> bool {
+        self.flags & FLAG_TMR_VAL_EXT != 0
+    }
+
+    /// Whether an 8042 keyboard controller is present
+    ///
+    /// ACPI 1.0 tables have no boot architecture flags, so assume one is.
+    pub fn has_8042(&self) -> bool {
+        self.revision < 2 || self.boot_arch & BOOT_ARCH_8042 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::{test_table, AddressSpace, SDT_HEADER_LEN};
+    use alloc::vec::Vec;
+
+    fn put_u32(body: &mut [u8], offset: usize, value: u32) {
+        body[offset - SDT_HEADER_LEN..offset - SDT_HEADER_LEN + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A FADT as QEMU's PIIX4 machine describes it, `len` bytes long
+    fn fadt(len: usize) -> Vec<u8> {
+        let mut body = alloc::vec![0u8; len - SDT_HEADER_LEN];
+        put_u32(&mut body, 36, 0x7FE0_0000);
+        put_u32(&mut body, 40, 0x7FE0_0040);
+        body[46 - SDT_HEADER_LEN] = 9;
+        put_u32(&mut body, 48, 0xB2);
+        body[52 - SDT_HEADER_LEN] = 0xF1;
+        body[53 - SDT_HEADER_LEN] = 0xF0;
+        put_u32(&mut body, 56, 0x600);
+        put_u32(&mut body, 64, 0x604);
+        put_u32(&mut body, 76, 0x608);
+        put_u32(&mut body, 80, 0xAFE0);
+        body[88 - SDT_HEADER_LEN] = 4;
+        body[89 - SDT_HEADER_LEN] = 2;
+        body[91 - SDT_HEADER_LEN] = 4;
+        body[92 - SDT_HEADER_LEN] = 4;
+        body[108 - SDT_HEADER_LEN] = 0x32;
+        put_u32(&mut body, 112, FLAG_TMR_VAL_EXT | FLAG_RESET_REG_SUP);
+        if len > RESET_VALUE {
+            body[RESET_REG - SDT_HEADER_LEN..RESET_REG - SDT_HEADER_LEN + 12]
+                .copy_from_slice(&[1, 8, 0, 1, 0xF9, 0x0C, 0, 0, 0, 0, 0, 0]);
+            body[RESET_VALUE - SDT_HEADER_LEN] = 0x06;
+        }
+        let mut table = test_table(b"FACP", &body);
+        table[8] = if len > RESET_VALUE { 3 } else { 1 };
+        let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        table[9] = table[9].wrapping_sub(sum);
+        table
+    }
+
+    #[test]
+    fn test_fadt_v1() {
+        let fadt = Fadt::parse(&fadt(FADT_V1_LEN)).unwrap();
+        assert_eq!(fadt.firmware_ctrl, 0x7FE0_0000);
+        assert_eq!(fadt.dsdt, 0x7FE0_0040);
+        assert_eq!(fadt.sci_int, 9);
+        assert_eq!((fadt.smi_cmd, fadt.acpi_enable, fadt.acpi_disable), (0xB2, 0xF1, 0xF0));
+        assert_eq!(fadt.pm1a_cnt, GenericAddress::io(0x604, 2));
+        assert_eq!(fadt.pm1a_evt.bit_width, 32);
+        assert!(!fadt.pm1b_cnt.is_present());
+        assert_eq!(fadt.pm_tmr.address, 0x608);
+        assert!(fadt.pm_timer_32bit());
+        assert!(fadt.has_8042());
+        assert_eq!(fadt.century, 0x32);
+        // The reset register is an ACPI 2.0 field
+        assert_eq!(fadt.reset_reg, None);
+    }
+
+    #[test]
+    fn test_fadt_x_fields() {
+        let mut table = fadt(244);
+        // X_PM1a_CNT_BLK in memory space overrides the port
+        let x_pm1a_cnt = X_PM1A_EVT_BLK + 2 * GenericAddress::LEN;
+        table[x_pm1a_cnt..x_pm1a_cnt + 12].copy_from_slice(&[0, 16, 0, 2, 0, 0x10, 0xD0, 0xFE, 0, 0, 0, 0]);
+        table[X_FIRMWARE_CTRL..X_FIRMWARE_CTRL + 8].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        table[9] = 0;
+        let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        table[9] = 0u8.wrapping_sub(sum);
+
+        let fadt = Fadt::parse(&table).unwrap();
+        assert_eq!(fadt.pm1a_cnt.space, AddressSpace::SystemMemory);
+        assert_eq!(fadt.pm1a_cnt.address, 0xFED0_1000);
+        assert_eq!(fadt.pm1a_evt, GenericAddress::io(0x600, 4));
+        assert_eq!(fadt.firmware_ctrl, 0x1_0000_0000);
+        let reset = fadt.reset_reg.unwrap();
+        assert_eq!((reset.space, reset.address, fadt.reset_value), (AddressSpace::SystemIo, 0xCF9, 6));
+        assert!(!fadt.has_8042());
+
+        assert_eq!(Fadt::parse(&test_table(b"FACP", &[0; 20])), Err("FADT too short"));
+        assert_eq!(Fadt::parse(&test_table(b"APIC", &[0; 100])), Err("Not a FADT"));
+    }
+}
@@ -0,0 +1,85 @@
+//! HPET Description Table
+//!
+//! Describes the High Precision Event Timer block: where its registers
+//! are and what the hardware supports.
+
+use super::{read_u16, read_u32, sdt_signature, validate_sdt, GenericAddress, SDT_HEADER_LEN};
+
+/// Length of the table
+const HPET_LEN: usize = 56;
+
+/// The HPET table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_rev: u8,
+    /// Number of comparators (timers) in the block
+    pub comparators: u8,
+    /// Whether the main counter is 64 bits wide
+    pub counter_64bit: bool,
+    /// Whether the block can replace the PIT and RTC interrupts
+    pub legacy_capable: bool,
+    pub pci_vendor: u16,
+    /// Location of the register block
+    pub base: GenericAddress,
+    /// Sequence number of this HPET
+    pub number: u8,
+    /// Smallest period, in counter ticks, that periodic mode is
+    /// guaranteed to work with
+    pub min_tick: u16,
+}
+
+impl Hpet {
+    /// Decode an HPET table, header included
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if sdt_signature(table) != Some(*b"HPET") {
+            return Err("Not an HPET table");
+        }
+        validate_sdt(table)?;
+        if (read_u32(table, 4).unwrap_or(0) as usize) < HPET_LEN {
+            return Err("HPET table too short");
+        }
+
+        let id = read_u32(table, SDT_HEADER_LEN).unwrap_or(0);
+        let base = GenericAddress::parse(&table[40..]).ok_or("HPET table too short")?;
+        if !base.is_present() {
+            return Err("HPET has no base address");
+        }
+        Ok(Self {
+            hardware_rev: id as u8,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_capable: id & (1 << 15) != 0,
+            pci_vendor: (id >> 16) as u16,
+            base,
+            number: table[52],
+            min_tick: read_u16(table, 53).unwrap_or(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::{test_table, AddressSpace};
+
+    #[test]
+    fn test_hpet() {
+        // QEMU's: vendor 0x8086, 3 comparators, 64-bit, legacy capable
+        let mut body = alloc::vec![0u8; HPET_LEN - SDT_HEADER_LEN];
+        body[0..4].copy_from_slice(&0x8086_A201u32.to_le_bytes());
+        body[4..16].copy_from_slice(&[0, 64, 0, 0, 0, 0, 0xD0, 0xFE, 0, 0, 0, 0]);
+        body[17..19].copy_from_slice(&128u16.to_le_bytes());
+
+        let hpet = Hpet::parse(&test_table(b"HPET", &body)).unwrap();
+        assert_eq!(hpet.hardware_rev, 1);
+        assert_eq!(hpet.comparators, 3);
+        assert!(hpet.counter_64bit && hpet.legacy_capable);
+        assert_eq!(hpet.pci_vendor, 0x8086);
+        assert_eq!((hpet.base.space, hpet.base.address), (AddressSpace::SystemMemory, 0xFED0_0000));
+        assert_eq!(hpet.min_tick, 128);
+
+        body[4..16].fill(0);
+        assert_eq!(Hpet::parse(&test_table(b"HPET", &body)), Err("HPET has no base address"));
+        assert_eq!(Hpet::parse(&test_table(b"HPET", &[0; 4])), Err("HPET table too short"));
+    }
+}
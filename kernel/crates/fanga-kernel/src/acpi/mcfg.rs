@@ -0,0 +1,78 @@
+//! PCI Express Memory Mapped Configuration Table
+//!
+//! The MCFG lists where the enhanced configuration access mechanism
+//! (ECAM) regions are: for each PCI segment group and range of buses, the
+//! physical base of a window holding 4 KiB of configuration space per
+//! function.
+
+use alloc::vec::Vec;
+
+use super::{read_u16, read_u32, read_u64, sdt_signature, validate_sdt, SDT_HEADER_LEN};
+
+/// Size of one allocation entry
+const MCFG_ENTRY_LEN: usize = 16;
+
+/// One ECAM region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Physical address of the region, the configuration space of bus 0
+    /// even when `start_bus` is higher
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The ECAM regions of the MCFG
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    /// Decode an MCFG, header included
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if sdt_signature(table) != Some(*b"MCFG") {
+            return Err("Not an MCFG");
+        }
+        validate_sdt(table)?;
+        let len = read_u32(table, 4).unwrap_or(0) as usize;
+        let table = &table[..len];
+
+        // Entries follow 8 reserved bytes
+        let entries = (SDT_HEADER_LEN + 8..)
+            .step_by(MCFG_ENTRY_LEN)
+            .take_while(|offset| offset + MCFG_ENTRY_LEN <= table.len())
+            .filter_map(|offset| {
+                Some(McfgEntry {
+                    base: read_u64(table, offset)?,
+                    segment: read_u16(table, offset + 8)?,
+                    start_bus: table[offset + 10],
+                    end_bus: table[offset + 11],
+                })
+            })
+            .filter(|entry| entry.base != 0 && entry.start_bus <= entry.end_bus)
+            .collect();
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::test_table;
+
+    #[test]
+    fn test_mcfg() {
+        // QEMU q35: buses 0-255 of segment 0 at 0xB000_0000
+        let mut body = alloc::vec![0u8; 8];
+        for (base, start, end) in [(0xB000_0000u64, 0u8, 0xFFu8), (0, 0, 0)] {
+            body.extend_from_slice(&base.to_le_bytes());
+            body.extend_from_slice(&[0, 0, start, end, 0, 0, 0, 0]);
+        }
+        let mcfg = Mcfg::parse(&test_table(b"MCFG", &body)).unwrap();
+        // The entry without a base is dropped
+        assert_eq!(mcfg.entries, [McfgEntry { base: 0xB000_0000, segment: 0, start_bus: 0, end_bus: 0xFF }]);
+        assert_eq!(Mcfg::parse(&test_table(b"SLIT", &body)), Err("Not an MCFG"));
+    }
+}
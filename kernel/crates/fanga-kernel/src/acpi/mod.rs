@@ -0,0 +1,545 @@
+//! ACPI Tables
+//!
+//! Finds the system description tables through the RSDP the bootloader
+//! reports and keeps them for the subsystems that read them:
+//! - the MADT (`APIC`), for SMP and IOAPIC routing in `smp::acpi`
+//! - the FADT (`FACP`), for the power management registers, and the FACS
+//!   it points at, for the S3 waking vector
+//! - the HPET table, for the event timer block
+//! - the MCFG, for the PCI Express configuration space windows
+//! - the SRAT and SLIT, for the NUMA topology and node distances
+//! - the DSDT and SSDTs, for the `\_Sx` sleep type packages and the
+//!   thermal zones
+//!
+//! Each table is checked against its length and checksum before it is
+//! recorded. The DSDT, which only the FADT points at, is recorded too.
+//!
+//! The tables stay where the firmware put them, in memory the HHDM maps,
+//! and are borrowed for the life of the kernel rather than copied.
+
+pub mod facs;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+pub mod sleep;
+pub mod slit;
+pub mod srat;
+pub mod thermal;
+
+pub use facs::Facs;
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use mcfg::Mcfg;
+pub use sleep::SleepType;
+pub use slit::Slit;
+pub use srat::Srat;
+pub use thermal::ThermalZone;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+/// Size of the common header of every system description table
+pub const SDT_HEADER_LEN: usize = 36;
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Address space a generic address is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl AddressSpace {
+    fn from_id(id: u8) -> Self {
+        match id {
+            0 => Self::SystemMemory,
+            1 => Self::SystemIo,
+            2 => Self::PciConfig,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// A register location (ACPI Generic Address Structure)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    /// Size of the register in bits
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// Access size: 0 undefined, 1 byte, 2 word, 3 dword, 4 qword
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Size of the structure in a table
+    pub const LEN: usize = 12;
+
+    /// No register
+    pub const NONE: Self = Self { space: AddressSpace::SystemMemory, bit_width: 0, bit_offset: 0, access_size: 0, address: 0 };
+
+    /// Decode the structure at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::LEN)?;
+        Some(Self {
+            space: AddressSpace::from_id(bytes[0]),
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4)?,
+        })
+    }
+
+    /// An I/O port block `len` bytes wide, as the ACPI 1.0 fields give them
+    pub const fn io(port: u32, len: u8) -> Self {
+        Self { space: AddressSpace::SystemIo, bit_width: len.saturating_mul(8), bit_offset: 0, access_size: 0, address: port as u64 }
+    }
+
+    /// Whether the register exists
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// Size of one access in bytes
+    fn access_bytes(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => match self.bit_width {
+                0..=8 => 1,
+                9..=16 => 2,
+                17..=32 => 4,
+                _ => 8,
+            },
+        }
+    }
+
+    /// Bits covered by one access
+    fn access_mask(&self) -> u64 {
+        match self.access_bytes() {
+            8 => u64::MAX,
+            bytes => (1 << (bytes as u64 * 8)) - 1,
+        }
+    }
+
+    /// The function and register offset of a PCI configuration space
+    /// address: device in bits 32-47, function in 16-31, offset in 0-15,
+    /// always on bus 0
+    fn pci_location(&self) -> Result<(crate::pci::PciAddress, u8), &'static str> {
+        let offset = u8::try_from(self.address & 0xFFFF).map_err(|_| "PCI register out of range")?;
+        let bytes = self.access_bytes();
+        if bytes > 4 || !offset.is_multiple_of(bytes) {
+            return Err("Unaligned PCI register");
+        }
+        let function = crate::pci::PciAddress::new(0, (self.address >> 32) as u8, (self.address >> 16) as u8);
+        Ok((function, offset))
+    }
+
+    /// Read the register
+    ///
+    /// # Safety
+    /// Reading a hardware register can have side effects.
+    pub unsafe fn read(&self) -> Result<u64, &'static str> {
+        use fanga_arch_x86_64::port::{inb, inl, inw};
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = u16::try_from(self.address).map_err(|_| "I/O port out of range")?;
+                Ok(match self.access_bytes() {
+                    1 => inb(port) as u64,
+                    2 => inw(port) as u64,
+                    _ => inl(port) as u64,
+                })
+            }
+            AddressSpace::SystemMemory => {
+                let bytes = self.access_bytes();
+                let virt = crate::memory::mmio::ioremap(self.address, bytes as u64)?;
+                Ok(match bytes {
+                    1 => core::ptr::read_volatile(virt as *const u8) as u64,
+                    2 => core::ptr::read_volatile(virt as *const u16) as u64,
+                    4 => core::ptr::read_volatile(virt as *const u32) as u64,
+                    _ => core::ptr::read_volatile(virt as *const u64),
+                })
+            }
+            AddressSpace::PciConfig => {
+                let (function, offset) = self.pci_location()?;
+                let shift = (offset & 3) * 8;
+                Ok((crate::pci::read_config32(function, offset.into()) >> shift) as u64 & self.access_mask())
+            }
+            _ => Err("Unsupported address space"),
+        }
+    }
+
+    /// Write the register
+    ///
+    /// # Safety
+    /// Writing a hardware register changes the machine's state.
+    pub unsafe fn write(&self, value: u64) -> Result<(), &'static str> {
+        use fanga_arch_x86_64::port::{outb, outl, outw};
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = u16::try_from(self.address).map_err(|_| "I/O port out of range")?;
+                match self.access_bytes() {
+                    1 => outb(port, value as u8),
+                    2 => outw(port, value as u16),
+                    _ => outl(port, value as u32),
+                }
+            }
+            AddressSpace::SystemMemory => {
+                let bytes = self.access_bytes();
+                let virt = crate::memory::mmio::ioremap(self.address, bytes as u64)?;
+                match bytes {
+                    1 => core::ptr::write_volatile(virt as *mut u8, value as u8),
+                    2 => core::ptr::write_volatile(virt as *mut u16, value as u16),
+                    4 => core::ptr::write_volatile(virt as *mut u32, value as u32),
+                    _ => core::ptr::write_volatile(virt as *mut u64, value),
+                }
+            }
+            AddressSpace::PciConfig => {
+                // Merge into the dword that holds the register
+                let (function, offset) = self.pci_location()?;
+                let shift = (offset & 3) * 8;
+                let mask = self.access_mask() << shift;
+                let dword = crate::pci::read_config32(function, offset.into()) as u64;
+                let merged = (dword & !mask) | ((value << shift) & mask);
+                crate::pci::write_config32(function, offset.into(), merged as u32);
+            }
+            _ => return Err("Unsupported address space"),
+        }
+        Ok(())
+    }
+}
+
+/// A system description table found through the RSDP
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub signature: [u8; 4],
+    /// Physical address of the table
+    pub addr: u64,
+    /// The whole table, header included
+    pub data: &'static [u8],
+}
+
+impl Table {
+    /// The signature as text
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// Revision of the table's layout
+    pub fn revision(&self) -> u8 {
+        self.data[8]
+    }
+}
+
+pub(crate) fn sdt_signature(table: &[u8]) -> Option<[u8; 4]> {
+    table.get(0..4)?.try_into().ok()
+}
+
+/// Check a table's length field and checksum
+pub(crate) fn validate_sdt(table: &[u8]) -> Result<(), &'static str> {
+    let len = read_u32(table, 4).ok_or("ACPI table too short")? as usize;
+    if len < SDT_HEADER_LEN || len > table.len() {
+        return Err("Bad ACPI table length");
+    }
+    if table[..len].iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+        return Err("Bad ACPI table checksum");
+    }
+    Ok(())
+}
+
+/// Reads physical memory: `(address, length)` to bytes
+pub type PhysReader<'r, 'a> = &'r dyn Fn(u64, usize) -> Option<&'a [u8]>;
+
+/// Read a whole system description table at `addr`
+fn read_sdt<'a>(read: PhysReader<'_, 'a>, addr: u64) -> Option<&'a [u8]> {
+    let header = read(addr, SDT_HEADER_LEN)?;
+    let len = read_u32(header, 4)? as usize;
+    read(addr, len.max(SDT_HEADER_LEN))
+}
+
+/// Addresses of the tables the XSDT (or RSDT) lists, from the RSDP at
+/// `rsdp_addr`
+fn root_entries(read: PhysReader<'_, '_>, rsdp_addr: u64) -> Result<Vec<u64>, &'static str> {
+    let rsdp = read(rsdp_addr, 36).ok_or("Cannot read RSDP")?;
+    if &rsdp[0..8] != b"RSD PTR " {
+        return Err("Bad RSDP signature");
+    }
+    if rsdp[..20].iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+        return Err("Bad RSDP checksum");
+    }
+
+    // ACPI 2.0+ has a 64-bit XSDT; fall back to the 32-bit RSDT
+    let revision = rsdp[15];
+    let xsdt = read_u64(rsdp, 24).unwrap_or(0);
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (read_u32(rsdp, 16).unwrap_or(0) as u64, 4)
+    };
+
+    let root = read_sdt(read, root).ok_or("Cannot read root table")?;
+    validate_sdt(root)?;
+    let len = read_u32(root, 4).unwrap_or(0) as usize;
+    Ok((SDT_HEADER_LEN..len)
+        .step_by(entry_size)
+        .filter_map(|offset| match entry_size {
+            8 => read_u64(root, offset),
+            _ => read_u32(root, offset).map(u64::from),
+        })
+        .collect())
+}
+
+/// Find an ACPI table by signature, starting from the RSDP at `rsdp_addr`
+pub fn find_table<'a>(read: PhysReader<'_, 'a>, rsdp_addr: u64, signature: &[u8; 4]) -> Result<&'a [u8], &'static str> {
+    for addr in root_entries(read, rsdp_addr)? {
+        let Some(table) = read_sdt(read, addr) else {
+            continue;
+        };
+        if sdt_signature(table) == Some(*signature) {
+            validate_sdt(table)?;
+            return Ok(table);
+        }
+    }
+    Err("ACPI table not found")
+}
+
+/// All valid tables reachable from the RSDP at `rsdp_addr`, with their
+/// addresses
+///
+/// Tables that fail their checksum are left out. The DSDT is found through
+/// the FADT.
+pub fn scan<'a>(read: PhysReader<'_, 'a>, rsdp_addr: u64) -> Result<Vec<(u64, &'a [u8])>, &'static str> {
+    let mut tables: Vec<(u64, &'a [u8])> = root_entries(read, rsdp_addr)?
+        .into_iter()
+        .filter_map(|addr| Some((addr, read_sdt(read, addr)?)))
+        .filter(|(_, table)| validate_sdt(table).is_ok())
+        .collect();
+
+    let dsdt = tables
+        .iter()
+        .find(|(_, table)| sdt_signature(table) == Some(*b"FACP"))
+        .and_then(|(_, table)| Fadt::parse(table).ok())
+        .map_or(0, |fadt| fadt.dsdt);
+    if dsdt != 0 && !tables.iter().any(|&(addr, _)| addr == dsdt) {
+        if let Some(table) = read_sdt(read, dsdt).filter(|table| validate_sdt(table).is_ok()) {
+            tables.push((dsdt, table));
+        }
+    }
+    Ok(tables)
+}
+
+/// Tables of the running system
+static TABLES: Once<Vec<Table>> = Once::new();
+
+/// Virtual address of the FACS
+static FACS: Once<u64> = Once::new();
+
+/// Find the ACPI tables through the RSDP the bootloader found
+///
+/// Tables live in memory the HHDM maps. Returns how many were found;
+/// without an RSDP or with a broken root table there are none.
+pub fn init(rsdp_phys: Option<u64>, hhdm_offset: u64) -> usize {
+    let tables = TABLES.call_once(|| {
+        let read = move |addr: u64, len: usize| -> Option<&'static [u8]> {
+            Some(unsafe { core::slice::from_raw_parts((hhdm_offset + addr) as *const u8, len) })
+        };
+        match rsdp_phys.ok_or("No RSDP").and_then(|rsdp| scan(&read, rsdp)) {
+            Ok(found) => found
+                .into_iter()
+                .filter_map(|(addr, data)| Some(Table { signature: sdt_signature(data)?, addr, data }))
+                .collect(),
+            Err(e) => {
+                crate::log_warn!(target: "acpi", "No ACPI tables: {}", e);
+                Vec::new()
+            }
+        }
+    });
+
+    if !tables.is_empty() {
+        let mut names = String::new();
+        for table in tables {
+            names.push(' ');
+            names.push_str(table.name());
+        }
+        crate::log_info!(target: "acpi", "{} tables:{}", tables.len(), names);
+    }
+    if let Some(fadt) = fadt() {
+        crate::log_info!(
+            target: "acpi",
+            "FADT: SCI {}, PM1a control 0x{:x}, PM timer 0x{:x}, reset {}",
+            fadt.sci_int,
+            fadt.pm1a_cnt.address,
+            fadt.pm_tmr.address,
+            if fadt.reset_reg.is_some() { "supported" } else { "unsupported" }
+        );
+        if fadt.firmware_ctrl != 0 {
+            FACS.call_once(|| hhdm_offset + fadt.firmware_ctrl);
+        }
+    }
+    tables.len()
+}
+
+/// The tables found by `init()`
+pub fn tables() -> &'static [Table] {
+    TABLES.get().map_or(&[], Vec::as_slice)
+}
+
+/// A table by signature, or None before `init()` or if there is none
+pub fn table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    tables().iter().find(|table| &table.signature == signature).map(|table| table.data)
+}
+
+/// The Fixed ACPI Description Table
+pub fn fadt() -> Option<Fadt> {
+    Fadt::parse(table(b"FACP")?).ok()
+}
+
+/// The whole FACS, which the kernel may write
+fn facs_bytes() -> Option<&'static mut [u8]> {
+    let addr = *FACS.get()?;
+    let len = unsafe { core::ptr::read_volatile((addr + 4) as *const u32) } as usize;
+    Some(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len.max(facs::FACS_MIN_LEN)) })
+}
+
+/// The Firmware ACPI Control Structure
+pub fn facs() -> Option<Facs> {
+    Facs::parse(facs_bytes()?).ok()
+}
+
+/// Have the firmware jump to real-mode code at `phys` on wake from S3
+pub fn set_waking_vector(phys: u32) -> Result<(), &'static str> {
+    facs::set_waking_vector(facs_bytes().ok_or("No FACS")?, phys)
+}
+
+/// The HPET description table
+pub fn hpet() -> Option<Hpet> {
+    Hpet::parse(table(b"HPET")?).ok()
+}
+
+/// The PCI Express configuration space table
+pub fn mcfg() -> Option<Mcfg> {
+    Mcfg::parse(table(b"MCFG")?).ok()
+}
+
+/// The System Resource Affinity Table
+pub fn srat() -> Option<Srat> {
+    Srat::parse(table(b"SRAT")?).ok()
+}
+
+/// The System Locality Information Table
+pub fn slit() -> Option<Slit> {
+    Slit::parse(table(b"SLIT")?).ok()
+}
+
+/// Build a table with a valid header and checksum
+#[cfg(test)]
+pub(crate) fn test_table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((SDT_HEADER_LEN + body.len()) as u32).to_le_bytes());
+    table.resize(SDT_HEADER_LEN, 0);
+    table.extend_from_slice(body);
+    let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    table[9] = 0u8.wrapping_sub(sum);
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Physical memory with an RSDP at 0 and an XSDT at 0x100 listing
+    /// `tables`, each put at its address
+    fn memory(tables: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut memory = alloc::vec![0u8; 0x1000];
+        let mut rsdp = Vec::new();
+        rsdp.extend_from_slice(b"RSD PTR ");
+        rsdp.push(0);
+        rsdp.extend_from_slice(b"FANGA ");
+        rsdp.push(2);
+        rsdp.extend_from_slice(&0u32.to_le_bytes());
+        rsdp.extend_from_slice(&36u32.to_le_bytes());
+        rsdp.extend_from_slice(&0x100u64.to_le_bytes());
+        rsdp.resize(36, 0);
+        let sum = rsdp[..20].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        rsdp[8] = 0u8.wrapping_sub(sum);
+        memory[..36].copy_from_slice(&rsdp);
+
+        let entries: Vec<u8> = tables.iter().flat_map(|(addr, _)| addr.to_le_bytes()).collect();
+        let xsdt = test_table(b"XSDT", &entries);
+        memory[0x100..0x100 + xsdt.len()].copy_from_slice(&xsdt);
+        for (addr, table) in tables {
+            let addr = *addr as usize;
+            memory[addr..addr + table.len()].copy_from_slice(table);
+        }
+        memory
+    }
+
+    #[test]
+    fn test_find_table() {
+        let madt = test_table(b"APIC", &[0; 8]);
+        let memory = memory(&[(0x200, &madt)]);
+
+        let read = |addr: u64, len: usize| memory.get(addr as usize..addr as usize + len);
+        assert_eq!(find_table(&read, 0, b"APIC").unwrap(), &madt[..]);
+        assert_eq!(find_table(&read, 0, b"FACP"), Err("ACPI table not found"));
+        assert_eq!(find_table(&read, 0x100, b"APIC"), Err("Bad RSDP signature"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let dsdt = test_table(b"DSDT", &[0x10, 0x20]);
+        let mut fadt_body = alloc::vec![0u8; 244 - SDT_HEADER_LEN];
+        fadt_body[4..8].copy_from_slice(&0x400u32.to_le_bytes());
+        let fadt = test_table(b"FACP", &fadt_body);
+        let hpet = test_table(b"HPET", &[0; 20]);
+        let mut broken = test_table(b"SRAT", &[0; 12]);
+        broken[20] ^= 1;
+        // Only the FADT points at the DSDT
+        let mut memory = memory(&[(0x200, &fadt), (0x300, &hpet), (0x380, &broken)]);
+        memory[0x400..0x400 + dsdt.len()].copy_from_slice(&dsdt);
+
+        let read = |addr: u64, len: usize| memory.get(addr as usize..addr as usize + len);
+        let found = scan(&read, 0).unwrap();
+        let names: Vec<[u8; 4]> = found.iter().filter_map(|(_, table)| sdt_signature(table)).collect();
+        assert_eq!(names, [*b"FACP", *b"HPET", *b"DSDT"]);
+        assert_eq!(found[2], (0x400, &dsdt[..]));
+    }
+
+    #[test]
+    fn test_generic_address() {
+        let bytes = [1, 16, 0, 2, 0x04, 0x06, 0, 0, 0, 0, 0, 0];
+        let gas = GenericAddress::parse(&bytes).unwrap();
+        assert_eq!(gas.space, AddressSpace::SystemIo);
+        assert_eq!(gas.bit_width, 16);
+        assert_eq!(gas.address, 0x604);
+        assert!(gas.is_present());
+        assert_eq!(GenericAddress::io(0x604, 2), GenericAddress { access_size: 0, ..gas });
+        assert!(GenericAddress::parse(&bytes[..8]).is_none());
+        assert!(!GenericAddress::NONE.is_present());
+        assert_eq!(gas.access_bytes(), 2);
+        assert_eq!(GenericAddress::io(0x600, 4).access_bytes(), 4);
+        assert_eq!(GenericAddress { access_size: 1, ..gas }.access_bytes(), 1);
+        assert_eq!(gas.access_mask(), 0xFFFF);
+
+        // A byte register at device 1, function 3, offset 0x80
+        let pci = GenericAddress { space: AddressSpace::PciConfig, bit_width: 8, bit_offset: 0, access_size: 1, address: 0x1_0003_0080 };
+        assert_eq!(pci.pci_location(), Ok((crate::pci::PciAddress::new(0, 1, 3), 0x80)));
+        let unaligned = GenericAddress { access_size: 2, address: 0x1_0003_0081, ..pci };
+        assert_eq!(unaligned.pci_location(), Err("Unaligned PCI register"));
+    }
+}
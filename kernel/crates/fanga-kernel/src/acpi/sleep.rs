@@ -0,0 +1,125 @@
+//! Sleep Type Packages
+//!
+//! To enter sleep state Sx, the OS writes the SLP_TYP values of the `\_Sx`
+//! package to the PM1 control registers. The packages are static data in
+//! the DSDT or an SSDT, always encoded the same way, so they are found by
+//! name rather than by interpreting the AML around them.
+
+use super::{read_u16, read_u32, read_u64, SDT_HEADER_LEN};
+
+/// AML encodings
+pub(super) const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+pub(super) const ROOT_CHAR: u8 = b'\\';
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ONES_OP: u8 = 0xFF;
+pub(super) const BYTE_PREFIX: u8 = 0x0A;
+pub(super) const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const QWORD_PREFIX: u8 = 0x0E;
+
+/// SLP_TYP values for the PM1a and PM1b control registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Find the `\_Sx` package for sleep state `state` in AML bytecode
+pub fn find_sleep_type(aml: &[u8], state: u8) -> Option<SleepType> {
+    if state > 5 {
+        return None;
+    }
+    let name = [b'_', b'S', b'0' + state, b'_'];
+    let mut start = 0;
+    while let Some(pos) = aml.get(start..)?.windows(4).position(|window| window == name) {
+        let at = start + pos;
+        start = at + 1;
+        // Name(_S5_, ...) or Name(\_S5_, ...)
+        let named = match at.checked_sub(1).map(|i| aml[i]) {
+            Some(NAME_OP) => true,
+            Some(ROOT_CHAR) => at >= 2 && aml[at - 2] == NAME_OP,
+            _ => false,
+        };
+        if let Some(sleep_type) = named.then(|| parse_package(&aml[at + 4..])).flatten() {
+            return Some(sleep_type);
+        }
+    }
+    None
+}
+
+/// Decode `Package(n) { SLP_TYPa, SLP_TYPb, ... }`
+fn parse_package(aml: &[u8]) -> Option<SleepType> {
+    if *aml.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: bits 6-7 of the lead byte count the bytes that follow it
+    let elements_at = 2 + (*aml.get(1)? >> 6) as usize;
+    let count = *aml.get(elements_at)?;
+    let mut elements = aml.get(elements_at + 1..)?;
+    let a = integer(&mut elements)?;
+    match count {
+        0 => None,
+        // Old firmware packs both values into one integer
+        1 => Some(SleepType { a: a as u8, b: (a >> 8) as u8 }),
+        _ => Some(SleepType { a: a as u8, b: integer(&mut elements)? as u8 }),
+    }
+}
+
+/// Decode an integer constant, advancing past it
+pub(super) fn integer(aml: &mut &[u8]) -> Option<u64> {
+    let (&op, rest) = aml.split_first()?;
+    let (value, len) = match op {
+        ZERO_OP => (0, 0),
+        ONE_OP => (1, 0),
+        ONES_OP => (u64::MAX, 0),
+        BYTE_PREFIX => (*rest.first()? as u64, 1),
+        WORD_PREFIX => (read_u16(rest, 0)? as u64, 2),
+        DWORD_PREFIX => (read_u32(rest, 0)? as u64, 4),
+        QWORD_PREFIX => (read_u64(rest, 0)?, 8),
+        _ => return None,
+    };
+    *aml = &rest[len..];
+    Some(value)
+}
+
+/// The SLP_TYP values of sleep state `state` on this machine
+///
+/// None if no DSDT or SSDT defines the state, which the firmware does
+/// when it does not support it.
+pub fn sleep_type(state: u8) -> Option<SleepType> {
+    super::tables()
+        .iter()
+        .filter(|table| &table.signature == b"DSDT" || &table.signature == b"SSDT")
+        .find_map(|table| find_sleep_type(table.data.get(SDT_HEADER_LEN..)?, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_sleep_type() {
+        // QEMU q35: Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+        let q35 = [0x10, 0x05, b'\\', NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, 0, 0, 0, 0];
+        assert_eq!(find_sleep_type(&q35, 5), Some(SleepType { a: 0, b: 0 }));
+        assert_eq!(find_sleep_type(&q35, 3), None);
+
+        // Name (\_S3, Package (0x02) { 0x05, 0x05 }), after a reference
+        // to _S3_ that is not its definition
+        let aml = [
+            b'_', b'S', b'3', b'_', NAME_OP, ROOT_CHAR, b'_', b'S', b'3', b'_', PACKAGE_OP, 0x07, 0x02, BYTE_PREFIX, 5,
+            BYTE_PREFIX, 5,
+        ];
+        assert_eq!(find_sleep_type(&aml, 3), Some(SleepType { a: 5, b: 5 }));
+
+        // A single packed integer; a two-byte PkgLength
+        let packed = [NAME_OP, b'_', b'S', b'4', b'_', PACKAGE_OP, 0x40, 0x00, 0x01, WORD_PREFIX, 0x06, 0x07];
+        assert_eq!(find_sleep_type(&packed, 4), Some(SleepType { a: 6, b: 7 }));
+
+        // Truncated
+        assert_eq!(find_sleep_type(&q35[..11], 5), None);
+        assert_eq!(find_sleep_type(&q35, 9), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! System Locality Information Table
+//!
+//! The SLIT gives the relative distance between every pair of proximity
+//! domains (the SRAT's NUMA nodes) as a square matrix of bytes. A domain's
+//! distance to itself is 10; 20 means twice as far. 255 marks a domain
+//! that cannot be reached from another.
+
+use alloc::vec::Vec;
+
+use super::{read_u32, read_u64, sdt_signature, validate_sdt, SDT_HEADER_LEN};
+
+/// Distance of a domain to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+
+/// Distance between domains that cannot reach each other
+pub const UNREACHABLE: u8 = 255;
+
+/// The distance matrix of the SLIT
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Slit {
+    /// Number of domains (rows and columns)
+    pub localities: usize,
+    /// Row-major distances
+    distances: Vec<u8>,
+}
+
+impl Slit {
+    /// Decode a SLIT, header included
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if sdt_signature(table) != Some(*b"SLIT") {
+            return Err("Not a SLIT");
+        }
+        validate_sdt(table)?;
+        let len = read_u32(table, 4).unwrap_or(0) as usize;
+        let table = &table[..len];
+
+        let localities = read_u64(table, SDT_HEADER_LEN).ok_or("SLIT too short")?;
+        let start = SDT_HEADER_LEN + 8;
+        let size = usize::try_from(localities)
+            .ok()
+            .and_then(|n| n.checked_mul(n))
+            .filter(|&size| start + size <= table.len())
+            .ok_or("SLIT matrix does not fit the table")?;
+        Ok(Self { localities: localities as usize, distances: table[start..start + size].to_vec() })
+    }
+
+    /// Distance from domain `from` to domain `to`, None if either is not
+    /// in the matrix
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as usize, to as usize);
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+        Some(self.distances[from * self.localities + to])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::test_table;
+
+    #[test]
+    fn test_slit() {
+        let mut body = 2u64.to_le_bytes().to_vec();
+        body.extend_from_slice(&[10, 21, 21, 10]);
+        let slit = Slit::parse(&test_table(b"SLIT", &body)).unwrap();
+        assert_eq!(slit.localities, 2);
+        assert_eq!(slit.distance(0, 0), Some(LOCAL_DISTANCE));
+        assert_eq!(slit.distance(1, 0), Some(21));
+        assert_eq!(slit.distance(2, 0), None);
+
+        // Three domains need nine entries
+        body[0] = 3;
+        assert_eq!(Slit::parse(&test_table(b"SLIT", &body)), Err("SLIT matrix does not fit the table"));
+        assert_eq!(Slit::parse(&test_table(b"SRAT", &body)), Err("Not a SLIT"));
+    }
+}
@@ -0,0 +1,173 @@
+//! System Resource Affinity Table
+//!
+//! The SRAT assigns processors (by APIC ID) and memory ranges to proximity
+//! domains, the NUMA nodes of the firmware. Entries the firmware marks
+//! disabled are left out.
+
+use alloc::vec::Vec;
+
+use super::{read_u32, read_u64, sdt_signature, validate_sdt, SDT_HEADER_LEN};
+
+/// Entry types
+const SRAT_LAPIC_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+
+/// Flags shared by every entry type: the entry is in use
+const AFFINITY_ENABLED: u32 = 1 << 0;
+/// Memory affinity flags
+const MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+const MEMORY_NON_VOLATILE: u32 = 1 << 2;
+
+/// A processor in a proximity domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuAffinity {
+    pub apic_id: u32,
+    pub domain: u32,
+}
+
+/// A memory range in a proximity domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAffinity {
+    pub domain: u32,
+    pub base: u64,
+    pub length: u64,
+    pub hot_pluggable: bool,
+    pub non_volatile: bool,
+}
+
+/// The affinity entries of the SRAT
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Srat {
+    pub cpus: Vec<CpuAffinity>,
+    pub memory: Vec<MemoryAffinity>,
+}
+
+impl Srat {
+    /// Decode an SRAT, header included
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if sdt_signature(table) != Some(*b"SRAT") {
+            return Err("Not an SRAT");
+        }
+        validate_sdt(table)?;
+        let len = read_u32(table, 4).unwrap_or(0) as usize;
+        let table = &table[..len];
+
+        // Entries follow 12 reserved bytes
+        let mut srat = Self::default();
+        let mut offset = SDT_HEADER_LEN + 12;
+        while offset + 2 <= table.len() {
+            let kind = table[offset];
+            let entry_len = table[offset + 1] as usize;
+            if entry_len < 2 || offset + entry_len > table.len() {
+                return Err("Malformed SRAT entry");
+            }
+            srat.parse_entry(kind, &table[offset..offset + entry_len]);
+            offset += entry_len;
+        }
+        Ok(srat)
+    }
+
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) {
+        match kind {
+            SRAT_LAPIC_AFFINITY if entry.len() >= 16 => {
+                if read_u32(entry, 4).unwrap_or(0) & AFFINITY_ENABLED == 0 {
+                    return;
+                }
+                // The domain's low byte, then its upper three bytes
+                let domain = u32::from_le_bytes([entry[2], entry[9], entry[10], entry[11]]);
+                self.cpus.push(CpuAffinity { apic_id: entry[3] as u32, domain });
+            }
+            SRAT_X2APIC_AFFINITY if entry.len() >= 24 => {
+                if read_u32(entry, 12).unwrap_or(0) & AFFINITY_ENABLED == 0 {
+                    return;
+                }
+                let domain = read_u32(entry, 4).unwrap_or(0);
+                self.cpus.push(CpuAffinity { apic_id: read_u32(entry, 8).unwrap_or(0), domain });
+            }
+            SRAT_MEMORY_AFFINITY if entry.len() >= 40 => {
+                let flags = read_u32(entry, 28).unwrap_or(0);
+                let length = read_u64(entry, 16).unwrap_or(0);
+                if flags & AFFINITY_ENABLED == 0 || length == 0 {
+                    return;
+                }
+                self.memory.push(MemoryAffinity {
+                    domain: read_u32(entry, 2).unwrap_or(0),
+                    base: read_u64(entry, 8).unwrap_or(0),
+                    length,
+                    hot_pluggable: flags & MEMORY_HOT_PLUGGABLE != 0,
+                    non_volatile: flags & MEMORY_NON_VOLATILE != 0,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// The proximity domains named by any entry, in ascending order
+    pub fn domains(&self) -> Vec<u32> {
+        let mut domains: Vec<u32> = self
+            .cpus
+            .iter()
+            .map(|cpu| cpu.domain)
+            .chain(self.memory.iter().map(|range| range.domain))
+            .collect();
+        domains.sort_unstable();
+        domains.dedup();
+        domains
+    }
+
+    /// The proximity domain of the processor with `apic_id`
+    pub fn domain_of_apic(&self, apic_id: u32) -> Option<u32> {
+        self.cpus.iter().find(|cpu| cpu.apic_id == apic_id).map(|cpu| cpu.domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::test_table;
+
+    fn memory_entry(domain: u32, base: u64, length: u64, flags: u32) -> [u8; 40] {
+        let mut entry = [0u8; 40];
+        entry[0] = SRAT_MEMORY_AFFINITY;
+        entry[1] = 40;
+        entry[2..6].copy_from_slice(&domain.to_le_bytes());
+        entry[8..16].copy_from_slice(&base.to_le_bytes());
+        entry[16..24].copy_from_slice(&length.to_le_bytes());
+        entry[28..32].copy_from_slice(&flags.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn test_srat() {
+        let mut body = alloc::vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        // APIC 0 and 1 in domains 0 and 1; APIC 2 disabled
+        body.extend_from_slice(&[SRAT_LAPIC_AFFINITY, 16, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&[SRAT_LAPIC_AFFINITY, 16, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&[SRAT_LAPIC_AFFINITY, 16, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // x2APIC 0x100 in domain 0x10002
+        let mut x2apic = [0u8; 24];
+        x2apic[0] = SRAT_X2APIC_AFFINITY;
+        x2apic[1] = 24;
+        x2apic[4..8].copy_from_slice(&0x10002u32.to_le_bytes());
+        x2apic[8..12].copy_from_slice(&0x100u32.to_le_bytes());
+        x2apic[12] = 1;
+        body.extend_from_slice(&x2apic);
+        body.extend_from_slice(&memory_entry(0, 0, 0x8000_0000, AFFINITY_ENABLED));
+        body.extend_from_slice(&memory_entry(1, 0x1_0000_0000, 0x8000_0000, AFFINITY_ENABLED | MEMORY_HOT_PLUGGABLE));
+        body.extend_from_slice(&memory_entry(1, 0x2_0000_0000, 0x1000, 0));
+
+        let srat = Srat::parse(&test_table(b"SRAT", &body)).unwrap();
+        assert_eq!(srat.cpus.len(), 3);
+        assert_eq!(srat.domain_of_apic(1), Some(1));
+        assert_eq!(srat.domain_of_apic(2), None);
+        assert_eq!(srat.domain_of_apic(0x100), Some(0x10002));
+        assert_eq!(srat.memory.len(), 2);
+        assert_eq!(srat.memory[1].base, 0x1_0000_0000);
+        assert!(srat.memory[1].hot_pluggable && !srat.memory[0].hot_pluggable);
+        assert_eq!(srat.domains(), [0, 1, 0x10002]);
+
+        body.extend_from_slice(&[SRAT_MEMORY_AFFINITY, 60]);
+        assert_eq!(Srat::parse(&test_table(b"SRAT", &body)), Err("Malformed SRAT entry"));
+    }
+}
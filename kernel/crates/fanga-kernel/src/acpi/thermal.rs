@@ -0,0 +1,183 @@
+//! Thermal Zones
+//!
+//! A thermal zone, `ThermalZone (TZ00) { ... }` in the AML, is a part of
+//! the machine whose temperature the firmware watches, with trip points:
+//! `_CRT`, at which the OS must shut down, `_HOT`, at which it should go to
+//! sleep, and `_PSV`, at which it should start passive cooling by slowing
+//! the CPUs down. Temperatures are in tenths of a kelvin.
+//!
+//! A zone's current temperature, `_TMP`, is a control method reading the
+//! hardware, which would need an AML interpreter. Trip points are often
+//! constants, `Name (_CRT, 0x0E94)`, and those are found the way the sleep
+//! type packages are, by their encoding.
+
+use alloc::vec::Vec;
+
+use super::sleep::{integer, NAME_OP, ROOT_CHAR};
+use super::SDT_HEADER_LEN;
+
+/// AML encodings
+const EXT_OP_PREFIX: u8 = 0x5B;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const PARENT_PREFIX: u8 = b'^';
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+
+/// 0 degrees Celsius in tenths of a kelvin
+const ZERO_CELSIUS_DECI_KELVIN: i32 = 2732;
+
+/// A thermal zone and the trip points it declares as constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermalZone {
+    pub name: [u8; 4],
+    /// `_CRT`, in tenths of a kelvin
+    pub critical: Option<u32>,
+    /// `_HOT`, in tenths of a kelvin
+    pub hot: Option<u32>,
+    /// `_PSV`, in tenths of a kelvin
+    pub passive: Option<u32>,
+}
+
+impl ThermalZone {
+    /// The zone's name as text
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("????")
+    }
+}
+
+/// Convert tenths of a kelvin to whole degrees Celsius
+pub fn deci_kelvin_to_celsius(deci_kelvin: u32) -> i32 {
+    (deci_kelvin as i32 - ZERO_CELSIUS_DECI_KELVIN) / 10
+}
+
+/// Decode a PkgLength: the length it gives, which counts the PkgLength
+/// itself, and its size
+fn pkg_length(aml: &[u8]) -> Option<(usize, usize)> {
+    let lead = *aml.first()?;
+    let follow = (lead >> 6) as usize;
+    if follow == 0 {
+        return Some(((lead & 0x3F) as usize, 1));
+    }
+    let mut len = (lead & 0x0F) as usize;
+    for i in 0..follow {
+        len |= (*aml.get(1 + i)? as usize) << (4 + 8 * i);
+    }
+    Some((len, 1 + follow))
+}
+
+fn is_name_seg(seg: &[u8]) -> bool {
+    matches!(seg.first(), Some(b'A'..=b'Z' | b'_'))
+        && seg.len() == 4
+        && seg[1..].iter().all(|&c| matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'_'))
+}
+
+/// Decode a NameString: its last segment and its size
+fn name_string(aml: &[u8]) -> Option<([u8; 4], usize)> {
+    let prefix = aml.iter().take_while(|&&c| c == ROOT_CHAR || c == PARENT_PREFIX).count();
+    let (count, at) = match *aml.get(prefix)? {
+        DUAL_NAME_PREFIX => (2, prefix + 1),
+        MULTI_NAME_PREFIX => (*aml.get(prefix + 1)? as usize, prefix + 2),
+        _ => (1, prefix),
+    };
+    let end = at + 4 * count;
+    let segs = aml.get(at..end)?;
+    if count == 0 || !segs.chunks(4).all(is_name_seg) {
+        return None;
+    }
+    Some((segs[segs.len() - 4..].try_into().ok()?, end))
+}
+
+/// The value of `Name (name, <integer>)` directly in `body`
+fn constant(body: &[u8], name: &[u8; 4]) -> Option<u32> {
+    let mut start = 0;
+    while let Some(pos) = body.get(start..)?.windows(5).position(|w| w[0] == NAME_OP && &w[1..] == name) {
+        let at = start + pos;
+        start = at + 1;
+        let mut value = &body[at + 5..];
+        if let Some(value) = integer(&mut value) {
+            return u32::try_from(value).ok();
+        }
+    }
+    None
+}
+
+/// Find the thermal zones in AML bytecode
+pub fn find_zones(aml: &[u8]) -> Vec<ThermalZone> {
+    let mut zones = Vec::new();
+    let mut start = 0;
+    while let Some(pos) = aml
+        .get(start..)
+        .and_then(|rest| rest.windows(2).position(|w| w == [EXT_OP_PREFIX, THERMAL_ZONE_OP]))
+    {
+        let at = start + pos + 2;
+        start = at;
+        let Some((len, len_size)) = pkg_length(&aml[at..]) else {
+            continue;
+        };
+        let Some(object) = aml.get(at..at + len).filter(|_| len > len_size) else {
+            continue;
+        };
+        let Some((name, name_size)) = name_string(&object[len_size..]) else {
+            continue;
+        };
+        let body = &object[len_size + name_size..];
+        zones.push(ThermalZone {
+            name,
+            critical: constant(body, b"_CRT"),
+            hot: constant(body, b"_HOT"),
+            passive: constant(body, b"_PSV"),
+        });
+        start = at + len;
+    }
+    zones
+}
+
+/// The thermal zones of this machine, from the DSDT and SSDTs
+pub fn zones() -> Vec<ThermalZone> {
+    super::tables()
+        .iter()
+        .filter(|table| &table.signature == b"DSDT" || &table.signature == b"SSDT")
+        .filter_map(|table| table.data.get(SDT_HEADER_LEN..))
+        .flat_map(find_zones)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::sleep::{BYTE_PREFIX, WORD_PREFIX};
+
+    #[test]
+    fn test_find_zones() {
+        // ThermalZone (\_TZ.TZ00) { Name (_CRT, 0x0E94) Name (_PSV, 0x0E1C) Method (_TMP) {...} }
+        let mut zone = alloc::vec![EXT_OP_PREFIX, THERMAL_ZONE_OP, 0, ROOT_CHAR, DUAL_NAME_PREFIX];
+        zone.extend_from_slice(b"_TZ_TZ00");
+        zone.extend_from_slice(&[NAME_OP, b'_', b'C', b'R', b'T', WORD_PREFIX, 0x94, 0x0E]);
+        zone.extend_from_slice(&[NAME_OP, b'_', b'P', b'S', b'V', WORD_PREFIX, 0x1C, 0x0E]);
+        zone.extend_from_slice(&[0x14, 0x06, b'_', b'T', b'M', b'P', 0x00, 0xA4]);
+        zone[2] = (zone.len() - 2) as u8;
+        // A second zone with no constant trip points, after other code
+        let mut aml = zone.clone();
+        aml.extend_from_slice(&[NAME_OP, b'F', b'O', b'O', b'_', BYTE_PREFIX, 1]);
+        aml.extend_from_slice(&[EXT_OP_PREFIX, THERMAL_ZONE_OP, 0x05, b'T', b'Z', b'0', b'1']);
+
+        let zones = find_zones(&aml);
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].name(), "TZ00");
+        assert_eq!((zones[0].critical, zones[0].hot, zones[0].passive), (Some(0x0E94), None, Some(0x0E1C)));
+        assert_eq!(deci_kelvin_to_celsius(zones[0].critical.unwrap()), 100);
+        assert_eq!(zones[1], ThermalZone { name: *b"TZ01", critical: None, hot: None, passive: None });
+
+        // A length running past the end, and a bad name
+        assert!(find_zones(&zone[..zone.len() - 1]).is_empty());
+        assert!(find_zones(&[EXT_OP_PREFIX, THERMAL_ZONE_OP, 0x05, b't', b'z', b'0', b'0']).is_empty());
+    }
+
+    #[test]
+    fn test_pkg_length() {
+        assert_eq!(pkg_length(&[0x05]), Some((5, 1)));
+        // Two bytes: low nibble, then the next byte shifted by 4
+        assert_eq!(pkg_length(&[0x4A, 0x12]), Some((0x12A, 2)));
+        assert_eq!(pkg_length(&[0x80, 0x01]), None);
+    }
+}
@@ -11,7 +11,7 @@
 //! 3. **Memory Initialization**: Physical memory manager, heap allocator, virtual memory
 //! 4. **Driver Initialization**: Essential drivers (framebuffer, keyboard, timer)
 //! 5. **Subsystem Initialization**: Shell, scheduler, power management
-//! 6. **Post-Init**: Final setup and demonstration code
+//! 6. **Post-Init**: Start the init process, or the kernel shell without one
 //!
 //! # Boot Flow
 //!
@@ -36,27 +36,39 @@
 //!       ├─> Phase 4: Driver Initialization
 //!       │   ├─> Framebuffer console
 //!       │   ├─> Keyboard driver
+//!       │   ├─> USB host controllers
 //!       │   └─> Timer (PIT/APIC)
 //!       │
 //!       ├─> Phase 5: Subsystem Initialization
 //!       │   ├─> Task scheduler
 //!       │   ├─> Process management
+//!       │   ├─> ACPI tables
 //!       │   ├─> Power management
+//!       │   ├─> IOAPIC interrupt routing
+//!       │   ├─> Application processors
+//!       │   ├─> Workqueues
 //!       │   └─> Shell/REPL
 //!       │
 //!       └─> Phase 6: Post-Init
-//!           ├─> Run demonstrations
-//!           └─> Display welcome message
+//!           ├─> Start /init as PID 1
+//!           └─> Kernel shell, if there is no init
 //! ```
+//!
+//! Every phase and the major steps in them are timed with the TSC
+//! (`profiling::boot_trace`); the breakdown is logged after phase 6.
 
 use crate::io;
 use crate::memory;
 use crate::power;
+use crate::profiling::boot_trace;
 use crate::shell;
 use crate::task;
 
 use fanga_arch_x86_64 as arch;
-use limine::request::{BootloaderInfoRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest};
+use limine::request::{
+    BootloaderInfoRequest, ExecutableCmdlineRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest,
+    ModuleRequest, MpRequest, RsdpRequest,
+};
 
 /* -------------------------------------------------------------------------- */
 /*                              BOOT PHASE 1: EARLY                            */
@@ -73,7 +85,8 @@ use limine::request::{BootloaderInfoRequest, FramebufferRequest, HhdmRequest, Me
 /// - Enable interrupts
 pub fn phase1_early_boot() {
     arch::init();
-    arch::serial_println!("[Boot Phase 1] Early boot initialization complete ✅");
+    crate::debug::init();
+    crate::log_info!(target: "boot", "Phase 1: early boot initialization complete ✅");
 }
 
 /* -------------------------------------------------------------------------- */
@@ -85,6 +98,8 @@ pub struct BootloaderContext {
     pub memory_map: &'static limine::response::MemoryMapResponse,
     pub hhdm_offset: u64,
     pub framebuffer: Option<&'static limine::response::FramebufferResponse>,
+    /// Physical address of the ACPI RSDP, if the firmware has one
+    pub rsdp: Option<u64>,
 }
 
 /// Phase 2: Process bootloader protocol
@@ -97,10 +112,23 @@ pub struct BootloaderContext {
 pub fn phase2_bootloader_protocol(
     framebuffer_req: &'static FramebufferRequest,
     bootloader_info_req: &'static BootloaderInfoRequest,
+    cmdline_req: &'static ExecutableCmdlineRequest,
     memmap_req: &'static MemoryMapRequest,
     hhdm_req: &'static HhdmRequest,
+    rsdp_req: &'static RsdpRequest,
 ) -> Option<BootloaderContext> {
-    arch::serial_println!("[Boot Phase 2] Processing bootloader protocol...");
+    crate::log_info!(target: "boot", "Phase 2: processing bootloader protocol...");
+
+    // Kernel command line, then the console port it may select
+    if let Some(cmdline) = cmdline_req.get_response().and_then(|resp| resp.cmdline().to_str().ok()) {
+        crate::cmdline::init(cmdline);
+        crate::log_info!(target: "boot", "Command line: {}", cmdline);
+    }
+    io::logger::init();
+    io::serial_input::select_console(crate::cmdline::option("console"));
+
+    // Speculative execution mitigations, before any user code runs
+    task::speculation::init();
 
     // Initialize framebuffer early if available
     if let Some(fb_resp) = framebuffer_req.get_response() {
@@ -113,15 +141,17 @@ pub fn phase2_bootloader_protocol(
 
             if bpp == 32 {
                 io::framebuffer::init(addr, width, height, pitch, bpp);
-                arch::serial_println!(
-                    "[Boot Phase 2] Framebuffer console: {}x{} @ {}bpp",
+                crate::log_info!(
+                    target: "boot",
+                    "Framebuffer console: {}x{} @ {}bpp",
                     width,
                     height,
                     bpp
                 );
             } else {
-                arch::serial_println!(
-                    "[Boot Phase 2] Framebuffer bpp={} (expected 32). Console disabled.",
+                crate::log_warn!(
+                    target: "boot",
+                    "Framebuffer bpp={} (expected 32). Console disabled.",
                     bpp
                 );
             }
@@ -130,8 +160,9 @@ pub fn phase2_bootloader_protocol(
 
     // Log bootloader information
     if let Some(info) = bootloader_info_req.get_response() {
-        arch::serial_println!(
-            "[Boot Phase 2] Bootloader: {} {}",
+        crate::log_info!(
+            target: "boot",
+            "Bootloader: {} {}",
             info.name(),
             info.version()
         );
@@ -142,7 +173,7 @@ pub fn phase2_bootloader_protocol(
     let hhdm_response = hhdm_req.get_response()?;
     let hhdm_offset = hhdm_response.offset();
 
-    arch::serial_println!("[Boot Phase 2] HHDM offset: 0x{:x}", hhdm_offset);
+    crate::log_info!(target: "boot", "HHDM offset: 0x{:x}", hhdm_offset);
 
     // Log memory map summary
     let mut usable: u64 = 0;
@@ -153,18 +184,26 @@ pub fn phase2_bootloader_protocol(
             usable += entry.length;
         }
     }
-    arch::serial_println!("[Boot Phase 2] Total memory: {} MiB", total / (1024 * 1024));
-    arch::serial_println!(
-        "[Boot Phase 2] Usable memory: {} MiB",
+    crate::log_info!(target: "boot", "Total memory: {} MiB", total / (1024 * 1024));
+    crate::log_info!(
+        target: "boot",
+        "Usable memory: {} MiB",
         usable / (1024 * 1024)
     );
 
-    arch::serial_println!("[Boot Phase 2] Bootloader protocol complete ✅");
+    let rsdp = rsdp_req.get_response().map(|response| response.address() as u64);
+    match rsdp {
+        Some(addr) => crate::log_info!(target: "boot", "ACPI RSDP at 0x{:x}", addr),
+        None => crate::log_info!(target: "boot", "No ACPI RSDP"),
+    }
+
+    crate::log_info!(target: "boot", "Phase 2: bootloader protocol complete ✅");
 
     Some(BootloaderContext {
         memory_map,
         hhdm_offset,
         framebuffer: framebuffer_req.get_response(),
+        rsdp,
     })
 }
 
@@ -181,28 +220,32 @@ pub fn phase2_bootloader_protocol(
 /// This function manipulates static mutable memory managers and must be called
 /// exactly once during boot.
 pub unsafe fn phase3_memory_init(ctx: &BootloaderContext) {
-    arch::serial_println!("[Boot Phase 3] Initializing memory subsystems...");
+    crate::log_info!(target: "boot", "Phase 3: initializing memory subsystems...");
 
     // Initialize Physical Memory Manager (PMM)
-    arch::serial_println!("[Boot Phase 3] Initializing PMM...");
+    crate::log_info!(target: "boot", "Initializing PMM...");
     static mut PMM: memory::PhysicalMemoryManager = memory::PhysicalMemoryManager::new();
 
+    let step = boot_trace::step("pmm");
     PMM.init(ctx.memory_map, ctx.hhdm_offset);
-    arch::serial_println!(
-        "[Boot Phase 3] PMM: {} pages total, {} free",
+    step.end();
+    crate::log_info!(
+        target: "boot",
+        "PMM: {} pages total, {} free",
         PMM.total_pages(),
         PMM.free_pages()
     );
 
     // Initialize heap allocator
-    arch::serial_println!("[Boot Phase 3] Initializing heap allocator...");
+    crate::log_info!(target: "boot", "Initializing heap allocator...");
     const HEAP_PAGES: usize = 3; // 12KB initial heap
 
+    let step = boot_trace::step("heap");
     if let Some(heap_start_phys) = PMM.alloc_page() {
         // Allocate additional pages
         for i in 1..HEAP_PAGES {
             if PMM.alloc_page().is_none() {
-                arch::serial_println!("[Boot Phase 3] Warning: Only allocated {} heap pages", i);
+                crate::log_warn!(target: "boot", "Only allocated {} heap pages", i);
                 break;
             }
         }
@@ -211,8 +254,9 @@ pub unsafe fn phase3_memory_init(ctx: &BootloaderContext) {
         let heap_size = memory::PAGE_SIZE * HEAP_PAGES;
 
         crate::GLOBAL_ALLOCATOR.init(heap_start_virt as usize, heap_size);
-        arch::serial_println!(
-            "[Boot Phase 3] Heap: {} KiB at 0x{:x}",
+        crate::log_info!(
+            target: "boot",
+            "Heap: {} KiB at 0x{:x}",
             heap_size / 1024,
             heap_start_virt
         );
@@ -221,20 +265,69 @@ pub unsafe fn phase3_memory_init(ctx: &BootloaderContext) {
     } else {
         panic!("Failed to allocate heap memory");
     }
+    step.end();
 
     // Test Virtual Memory Manager (VMM)
-    arch::serial_println!("[Boot Phase 3] Testing VMM...");
+    crate::log_info!(target: "boot", "Testing VMM...");
+    let step = boot_trace::step("vmm");
     if let Some(mapper) = memory::PageTableMapper::new(&mut PMM, ctx.hhdm_offset) {
-        arch::serial_println!(
-            "[Boot Phase 3] VMM: Page table at 0x{:x}",
+        crate::log_info!(
+            target: "boot",
+            "VMM: Page table at 0x{:x}",
             mapper.pml4_addr()
         );
     } else {
-        arch::serial_println!("[Boot Phase 3] Warning: VMM test skipped");
+        crate::log_warn!(target: "boot", "VMM test skipped");
+    }
+    step.end();
+
+    // Device register mappings (ioremap)
+    memory::mmio::init(&*core::ptr::addr_of!(PMM), ctx.hhdm_offset);
+
+    // Node-local page allocation (node pools are set up with the topology)
+    crate::numa::allocator::init(&*core::ptr::addr_of!(PMM));
+
+    // Real-mode page for the S3 wakeup trampoline, while low memory is free
+    power::suspend::reserve_wakeup_page();
+
+    // Console drawing goes to RAM and is copied to a write-combined screen
+    let step = boot_trace::step("framebuffer");
+    match io::framebuffer::enable_double_buffering(ctx.hhdm_offset) {
+        Ok(()) => crate::log_info!(
+            target: "boot",
+            "Framebuffer double-buffered (write-combining: {})",
+            arch::pat::write_combining()
+        ),
+        Err(e) => crate::log_warn!(target: "boot", "Framebuffer not double-buffered: {}", e),
+    }
+    step.end();
+
+    // Console in a window under the compositor, before terminals copy its size
+    if crate::cmdline::flag("desktop") {
+        match io::desktop::init() {
+            Ok(()) => crate::log_info!(target: "boot", "Desktop compositor running"),
+            Err(e) => crate::log_warn!(target: "boot", "Desktop unavailable: {}", e),
+        }
+    }
+    memory::fault::init(&*core::ptr::addr_of!(PMM), ctx.hhdm_offset);
+    if let Err(e) = memory::address_space::init(&*core::ptr::addr_of!(PMM), ctx.hhdm_offset) {
+        crate::log_warn!(target: "boot", "Processes cannot get address spaces: {}", e);
+    }
+    crate::debug::kdb::set_hhdm_offset(ctx.hhdm_offset);
+
+    // Kernel data, the boot stack and the direct map must not be executable
+    let stack_marker = 0u8;
+    let nx_targets = [
+        ("kernel data", core::ptr::addr_of!(PMM) as u64),
+        ("boot stack", &stack_marker as *const u8 as u64),
+        ("direct map", ctx.hhdm_offset),
+    ];
+    if memory::protection::verify_kernel_nx(ctx.hhdm_offset, &nx_targets) == 0 {
+        crate::log_info!(target: "boot", "Kernel data pages are NX ✅");
     }
 
     // Initialize memory regions
-    arch::serial_println!("[Boot Phase 3] Initializing memory regions...");
+    crate::log_info!(target: "boot", "Initializing memory regions...");
     static mut MEMORY_REGIONS: memory::regions::MemoryRegionManager =
         memory::regions::MemoryRegionManager::new();
 
@@ -260,13 +353,14 @@ pub unsafe fn phase3_memory_init(ctx: &BootloaderContext) {
     memory::stats::stats().set_total_physical(total_mem);
     memory::stats::stats().set_used_physical(used_mem);
 
-    arch::serial_println!(
-        "[Boot Phase 3] Memory: {} MiB total, {} MiB free",
+    crate::log_info!(
+        target: "boot",
+        "Memory: {} MiB total, {} MiB free",
         total_mem / (1024 * 1024),
         (total_mem - used_mem) / (1024 * 1024)
     );
 
-    arch::serial_println!("[Boot Phase 3] Memory initialization complete ✅");
+    crate::log_info!(target: "boot", "Phase 3: memory initialization complete ✅");
 }
 
 /* -------------------------------------------------------------------------- */
@@ -278,16 +372,32 @@ pub unsafe fn phase3_memory_init(ctx: &BootloaderContext) {
 /// This phase initializes all essential hardware drivers in the correct order.
 /// Requires heap allocator to be ready for dynamic allocations.
 pub fn phase4_driver_init() {
-    arch::serial_println!("[Boot Phase 4] Initializing drivers...");
+    crate::log_info!(target: "boot", "Phase 4: initializing drivers...");
 
     // Keyboard input system (requires heap for Vec)
+    let step = boot_trace::step("keyboard");
     io::keyboard_bridge::init();
-    arch::serial_println!("[Boot Phase 4] Keyboard driver initialized");
+    step.end();
+    crate::log_info!(target: "boot", "Keyboard driver initialized");
+
+    // PS/2 mouse events feed the input event queue
+    let step = boot_trace::step("mouse");
+    match io::mouse_bridge::init() {
+        Ok(()) => crate::log_info!(target: "boot", "Mouse driver initialized"),
+        Err(e) => crate::log_info!(target: "boot", "No PS/2 mouse: {}", e),
+    }
+    step.end();
+
+    // USB host controllers found on the PCI bus
+    let step = boot_trace::step("usb");
+    crate::usb::init();
+    step.end();
+    crate::log_info!(target: "boot", "USB: {} host controller(s)", crate::usb::usb_manager().controller_count());
 
     // Timer is initialized as part of architecture init, but we log it here for clarity
-    arch::serial_println!("[Boot Phase 4] Timer (PIT) ready");
+    crate::log_info!(target: "boot", "Timer (PIT) ready");
 
-    arch::serial_println!("[Boot Phase 4] Driver initialization complete ✅");
+    crate::log_info!(target: "boot", "Phase 4: driver initialization complete ✅");
 }
 
 /* -------------------------------------------------------------------------- */
@@ -298,120 +408,319 @@ pub fn phase4_driver_init() {
 ///
 /// This phase initializes higher-level kernel subsystems that depend on
 /// memory and drivers being ready.
-pub fn phase5_subsystem_init() {
-    arch::serial_println!("[Boot Phase 5] Initializing kernel subsystems...");
+pub fn phase5_subsystem_init(
+    ctx: &BootloaderContext,
+    mp_req: &'static MpRequest,
+    module_req: &'static ModuleRequest,
+) {
+    crate::log_info!(target: "boot", "Phase 5: initializing kernel subsystems...");
 
     // Shell and command history
+    let step = boot_trace::step("shell");
     shell::init();
     shell::history::init();
     io::line_editor::init();
-    arch::serial_println!("[Boot Phase 5] Shell initialized");
+    step.end();
+    crate::log_info!(target: "boot", "Shell initialized");
+
+    // Virtual terminals (the boot console becomes VT1)
+    let step = boot_trace::step("terminals");
+    io::vt::init();
+    crate::log_info!(target: "boot", "Virtual terminals ready (Alt+F1..F{})", io::vt::MAX_TERMINALS);
+    match io::tty::init() {
+        Ok(()) => crate::log_info!(target: "boot", "TTYs tty1-tty{} registered", io::vt::MAX_TERMINALS),
+        Err(e) => crate::log_warn!(target: "boot", "TTY setup failed: {}", e),
+    }
+    step.end();
+
+    // Root file system
+    let step = boot_trace::step("fs");
+    crate::fs::init();
+    crate::log_info!(target: "boot", "Root file system mounted");
+    if let Err(e) = io::memdev::init() {
+        crate::log_warn!(target: "boot", "Memory devices unavailable: {}", e);
+    }
+    if let Err(e) = io::logger::register_device() {
+        crate::log_warn!(target: "boot", "Kernel log device unavailable: {}", e);
+    }
+    step.end();
+
+    // Disks and their partitions, for mount
+    let step = boot_trace::step("ata");
+    let disks = crate::storage::registry::probe_ata();
+    step.end();
+    crate::log_info!(target: "boot", "Block devices: {} ATA disk(s)", disks);
+
+    // Boot modules become files at their path on the boot volume, and
+    // cpio or tar archives among them (the initramfs) are unpacked onto
+    // the root
+    let step = boot_trace::step("modules");
+    if let Some(response) = module_req.get_response() {
+        for module in response.modules() {
+            let Ok(path) = module.path().to_str() else {
+                continue;
+            };
+            let data = unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) };
+            if crate::fs::initramfs::format(data).is_some() {
+                match crate::fs::initramfs::unpack(data) {
+                    Ok(stats) => crate::log_info!(
+                        target: "boot",
+                        "Initramfs {}: {} files, {} directories, {} bytes",
+                        path,
+                        stats.files,
+                        stats.directories,
+                        stats.bytes
+                    ),
+                    Err(e) => crate::log_warn!(target: "boot", "Cannot unpack initramfs {}: {}", path, e),
+                }
+                continue;
+            }
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            match crate::fs::create_dir_all(parent).and_then(|()| crate::fs::write_file(path, data)) {
+                Ok(_) => crate::log_info!(target: "boot", "Module {} ({} bytes)", path, data.len()),
+                Err(e) => crate::log_warn!(target: "boot", "Cannot store module {}: {}", path, e),
+            }
+        }
+    }
+    step.end();
+
+    // Console font from the file system, if one was asked for
+    if let Some(path) = crate::cmdline::option("font") {
+        match io::font::load(path) {
+            Ok(font) => {
+                io::vt::set_font(font);
+                crate::log_info!(
+                    target: "boot",
+                    "Console font {} ({}x{})",
+                    path,
+                    font.width(),
+                    font.height()
+                );
+            }
+            Err(e) => crate::log_warn!(target: "boot", "Cannot load font {}: {}", path, e),
+        }
+    }
 
     // Task scheduler and process management
+    let step = boot_trace::step("scheduler");
     task::scheduler::init();
     task::process::init();
+    task::sigadv::init();
+    task::coredump::init();
+    task::cgroup::init();
+    task::softirq::init();
+    crate::smp::rcu::init();
     task::timer_bridge::init();
-    arch::serial_println!(
-        "[Boot Phase 5] Task scheduler initialized (time slice: {}ms)",
+    task::time::init();
+    task::clocksource::init();
+    task::realtime::init();
+    crate::random::init();
+    crate::syscall_handlers::init();
+    step.end();
+    crate::log_info!(
+        target: "boot",
+        "Task scheduler initialized (time slice: {}ms)",
         task::sched_timer::TIME_SLICE * 10
     );
 
+    // ACPI tables, for power management, interrupt routing and NUMA
+    let step = boot_trace::step("acpi");
+    let tables = crate::acpi::init(ctx.rsdp, ctx.hhdm_offset);
+    step.end();
+    crate::log_info!(target: "boot", "ACPI: {} table(s)", tables);
+
+    // PCI device table, over ECAM when the MCFG has windows
+    let step = boot_trace::step("pci");
+    let functions = crate::pci::init();
+    step.end();
+    crate::log_info!(target: "boot", "PCI: {} function(s)", functions);
+
     // Power management
+    let step = boot_trace::step("power");
     power::init();
-    arch::serial_println!("[Boot Phase 5] Power management initialized");
+    step.end();
+    crate::log_info!(target: "boot", "Power management initialized");
+
+    // Bound drivers follow system sleep, children before their parents
+    if let Err(e) = crate::driver::init() {
+        crate::log_warn!(target: "boot", "Device suspend/resume unavailable: {}", e);
+    }
+
+    // Idle task for the boot CPU (uses the C-state logic above)
+    match task::idle::init() {
+        Ok(()) => crate::log_info!(target: "boot", "Idle task initialized"),
+        Err(e) => crate::log_warn!(target: "boot", "Idle task init failed: {}", e),
+    }
+
+    // Device interrupt dispatch for request_irq
+    crate::irq::init();
+
+    // Interrupt routing through the IOAPICs (after the tick source is chosen)
+    let step = boot_trace::step("ioapic");
+    let acpi = crate::smp::acpi::init();
+    match crate::smp::ioapic::init(acpi) {
+        Ok(()) => crate::log_info!(target: "boot", "IOAPIC interrupt routing enabled"),
+        Err(e) => crate::log_info!(target: "boot", "IOAPIC routing skipped ({}), using the PIC", e),
+    }
+    step.end();
+
+    // Interrupt-driven serial input, which the shell accepts commands from
+    match io::serial_input::init() {
+        Ok(ports) => crate::log_info!(target: "boot", "Serial input enabled on {} port(s)", ports),
+        Err(e) => crate::log_warn!(target: "boot", "Serial input unavailable: {}", e),
+    }
+
+    // Device nodes for everything registered so far
+    match crate::fs::populate_dev() {
+        Ok(count) => crate::log_info!(target: "boot", "/dev populated with {} device(s)", count),
+        Err(e) => crate::log_warn!(target: "boot", "Cannot populate /dev: {}", e),
+    }
+    if let Err(e) = crate::fs::mknod("/proc/kmsg", "kmsg") {
+        crate::log_warn!(target: "boot", "Cannot create /proc/kmsg: {}", e);
+    }
+
+    // The device tree found so far; the driver core keeps it current
+    match crate::driver::populate_sysfs() {
+        Ok(count) => crate::log_info!(target: "boot", "/sys populated with {} device(s)", count),
+        Err(e) => crate::log_warn!(target: "boot", "Cannot populate /sys: {}", e),
+    }
 
     // SMP support
+    let step = boot_trace::step("smp");
     if let Ok(()) = crate::smp::init() {
-        arch::serial_println!("[Boot Phase 5] SMP support initialized");
+        crate::log_info!(target: "boot", "SMP support initialized");
+        start_application_processors(mp_req);
     } else {
-        arch::serial_println!("[Boot Phase 5] SMP initialization skipped (single CPU mode)");
+        crate::log_info!(target: "boot", "SMP initialization skipped (single CPU mode)");
     }
+    step.end();
 
     // NUMA support
+    let step = boot_trace::step("numa");
     if let Ok(()) = crate::numa::init() {
-        arch::serial_println!("[Boot Phase 5] NUMA support initialized");
+        crate::log_info!(target: "boot", "NUMA support initialized");
     } else {
-        arch::serial_println!("[Boot Phase 5] NUMA initialization skipped");
+        crate::log_info!(target: "boot", "NUMA initialization skipped");
     }
+    step.end();
 
     // Performance profiling
     if let Ok(()) = crate::profiling::init() {
-        arch::serial_println!("[Boot Phase 5] Performance profiling initialized");
+        crate::log_info!(target: "boot", "Performance profiling initialized");
     }
 
     // Kernel preemption
     crate::preempt::init();
-    arch::serial_println!("[Boot Phase 5] Kernel preemption enabled");
+    crate::log_info!(target: "boot", "Kernel preemption enabled");
+
+    // Workqueues (needs the scheduler and CPU count)
+    let step = boot_trace::step("workqueues");
+    task::workqueue::init();
+    step.end();
+    crate::log_info!(target: "boot", "Workqueues initialized");
+
+    // Persistent kernel log (needs the root file system and workqueues)
+    if let Err(e) = io::pstore::init() {
+        crate::log_warn!(target: "boot", "Persistent kernel log unavailable: {}", e);
+    }
+    shell::history::start_persistence();
+    io::desktop::start_status_updates();
+    power::cpu::start_governor();
+    power::thermal::start_monitor();
+    if crate::usb::usb_manager().controller_count() > 0 {
+        crate::usb::start_polling();
+    }
+    let step = boot_trace::step("net");
+    match crate::net::init() {
+        Ok(()) => {
+            crate::net::start_polling();
+            crate::log_info!(target: "boot", "Network interface {} up", crate::net::INTERFACE_NAME);
+        }
+        Err(e) => crate::log_info!(target: "boot", "No network interface: {}", e),
+    }
+    step.end();
+
+    crate::log_info!(target: "boot", "Phase 5: subsystem initialization complete ✅");
+}
 
-    arch::serial_println!("[Boot Phase 5] Subsystem initialization complete ✅");
+/// Release the application processors reported by Limine
+fn start_application_processors(mp_req: &'static MpRequest) {
+    let Some(response) = mp_req.get_response() else {
+        crate::log_info!(target: "boot", "No MP response, running on the boot CPU only");
+        return;
+    };
+
+    let bsp = response.bsp_lapic_id();
+    let cpus = response.cpus();
+    let aps: alloc::vec::Vec<crate::smp::ApDescriptor> = cpus
+        .iter()
+        .filter(|cpu| cpu.lapic_id != bsp)
+        .map(|cpu| crate::smp::ApDescriptor { processor_id: cpu.id, lapic_id: cpu.lapic_id })
+        .collect();
+
+    let launch = |ap: &crate::smp::ApDescriptor| {
+        if let Some(cpu) = cpus.iter().find(|cpu| cpu.lapic_id == ap.lapic_id) {
+            cpu.goto_address.write(ap_trampoline);
+        }
+    };
+
+    match crate::smp::start_application_processors(&aps, launch) {
+        Ok(online) => crate::log_info!(
+            target: "boot",
+            "{} of {} CPUs online",
+            online,
+            cpus.len()
+        ),
+        Err(e) => crate::log_warn!(target: "boot", "AP startup failed: {}", e),
+    }
+}
+
+/// First kernel code run by an application processor
+extern "C" fn ap_trampoline(cpu: &limine::mp::Cpu) -> ! {
+    match crate::smp::ap::boot_info(cpu.lapic_id) {
+        Some(info) => crate::smp::ap::ap_entry(info),
+        None => loop {
+            unsafe { core::arch::asm!("cli; hlt") };
+        },
+    }
 }
 
 /* -------------------------------------------------------------------------- */
 /*                         BOOT PHASE 6: POST-INIT                             */
 /* -------------------------------------------------------------------------- */
 
-/// Phase 6: Post-initialization and demonstrations
+/// Phase 6: Post-initialization
 ///
-/// This phase runs demonstration code and displays the welcome message.
-/// This is where you can add system tests or feature demonstrations.
+/// Starts `/init` (or the program `init=` names) as the first user
+/// process. Without one the kernel shell takes the console instead.
 pub fn phase6_post_init() {
-    arch::serial_println!("[Boot Phase 6] Running post-initialization...");
-
-    // Run process management demonstration
-    // run_process_demo();
-
-    // Display welcome message
-    display_welcome();
-
-    arch::serial_println!("[Boot Phase 6] Post-initialization complete ✅");
-    arch::serial_println!("");
-    arch::serial_println!("===========================================");
-    arch::serial_println!("   KERNEL BOOT SEQUENCE COMPLETE");
-    arch::serial_println!("===========================================");
-}
-
-/// Run process management demonstration
-fn run_process_demo() {
-    use crate::memory::{PhysAddr, VirtAddr};
-
-    arch::serial_println!("");
-    arch::serial_println!("===========================================");
-    arch::serial_println!("   PROCESS MANAGEMENT DEMONSTRATION");
-    arch::serial_println!("===========================================");
-
-    // Create and schedule some demo tasks
-    let mut scheduler_guard = task::scheduler::scheduler();
-    let scheduler = &mut *scheduler_guard;
-
-    // Create tasks with different priorities
-    let mut task1 = task::Task::new(
-        task::TaskId::new(0),
-        VirtAddr::new(task::examples::task1 as *const () as u64),
-        VirtAddr::new(0x10000),
-        4096,
-        PhysAddr::new(0x0),
-        task::TaskPriority::Normal,
-    );
-    task1.set_name("counter_task");
-
-    let mut task2 = task::Task::new(
-        task::TaskId::new(0),
-        VirtAddr::new(task::examples::task2 as *const () as u64),
-        VirtAddr::new(0x20000),
-        4096,
-        PhysAddr::new(0x0),
-        task::TaskPriority::High,
-    );
-    task2.set_name("compute_task");
-
-    if let Ok(id1) = scheduler.add_task(task1) {
-        arch::serial_println!("  Created task {:?}: counter_task", id1);
-    }
-    if let Ok(id2) = scheduler.add_task(task2) {
-        arch::serial_println!("  Created task {:?}: compute_task", id2);
+    crate::log_info!(target: "boot", "Phase 6: running post-initialization...");
+
+    let step = boot_trace::step("init");
+    let started = crate::userspace::init::start();
+    step.end();
+    match started {
+        Ok(pid) => {
+            crate::log_info!(
+                target: "boot",
+                "Started {} as PID {}",
+                crate::userspace::init::init_path(),
+                pid.as_usize()
+            );
+        }
+        Err(e) => {
+            crate::log_warn!(
+                target: "boot",
+                "Cannot start {}: {}; falling back to the kernel shell",
+                crate::userspace::init::init_path(),
+                e
+            );
+            display_welcome();
+        }
     }
 
-    arch::serial_println!("  Total tasks: {}", scheduler.total_task_count());
+    crate::log_info!(target: "boot", "Phase 6: post-initialization complete ✅");
+    crate::log_info!(target: "boot", "Kernel boot sequence complete");
 }
 
 /// Display welcome message on console
@@ -461,8 +770,10 @@ fn display_welcome() {
 /// # Arguments
 /// * `framebuffer_req` - Limine framebuffer request
 /// * `bootloader_info_req` - Limine bootloader info request
+/// * `cmdline_req` - Limine kernel command line request
 /// * `memmap_req` - Limine memory map request
 /// * `hhdm_req` - Limine HHDM request
+/// * `rsdp_req` - Limine RSDP request
 /// * `base_revision` - Limine base revision for compatibility check
 ///
 /// # Returns
@@ -470,37 +781,60 @@ fn display_welcome() {
 pub fn initialize(
     framebuffer_req: &'static FramebufferRequest,
     bootloader_info_req: &'static BootloaderInfoRequest,
+    cmdline_req: &'static ExecutableCmdlineRequest,
     memmap_req: &'static MemoryMapRequest,
     hhdm_req: &'static HhdmRequest,
+    rsdp_req: &'static RsdpRequest,
+    mp_req: &'static MpRequest,
+    module_req: &'static ModuleRequest,
     base_revision: &'static limine::BaseRevision,
 ) -> Result<(), &'static str> {
     // Phase 1: Early boot
+    let phase = boot_trace::phase(1, "early");
     phase1_early_boot();
+    phase.end();
 
     // Check Limine base revision
     if !base_revision.is_supported() {
-        arch::serial_println!("[Boot] ERROR: Limine base revision not supported");
+        crate::log_error!(target: "boot", "Limine base revision not supported");
         return Err("Limine base revision not supported");
     }
 
     // Phase 2: Bootloader protocol
-    let ctx =
-        phase2_bootloader_protocol(framebuffer_req, bootloader_info_req, memmap_req, hhdm_req)
-            .ok_or("Failed to process bootloader protocol")?;
+    let phase = boot_trace::phase(2, "bootloader");
+    let ctx = phase2_bootloader_protocol(
+        framebuffer_req,
+        bootloader_info_req,
+        cmdline_req,
+        memmap_req,
+        hhdm_req,
+        rsdp_req,
+    )
+    .ok_or("Failed to process bootloader protocol")?;
+    phase.end();
 
     // Phase 3: Memory initialization
+    let phase = boot_trace::phase(3, "memory");
     unsafe {
         phase3_memory_init(&ctx);
     }
+    phase.end();
 
     // Phase 4: Driver initialization
+    let phase = boot_trace::phase(4, "drivers");
     phase4_driver_init();
+    phase.end();
 
     // Phase 5: Subsystem initialization
-    phase5_subsystem_init();
+    let phase = boot_trace::phase(5, "subsystems");
+    phase5_subsystem_init(&ctx, mp_req, module_req);
+    phase.end();
 
     // Phase 6: Post-initialization
+    let phase = boot_trace::phase(6, "post-init");
     phase6_post_init();
+    phase.end();
+    boot_trace::report();
 
     Ok(())
 }
@@ -0,0 +1,75 @@
+//! Kernel Command Line
+//!
+//! The bootloader passes a command line such as
+//! `console=ttyS1,115200n8 quiet`: space-separated words, each either a
+//! flag or a `key=value` option. It is stored once at boot and queried by
+//! the subsystems that take parameters.
+//!
+//! This module provides:
+//! - Storage of the boot command line
+//! - Option and flag lookup
+
+use spin::Once;
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Store the command line passed by the bootloader
+///
+/// Only the first call has an effect. Needs no heap, so it can run
+/// before memory is set up.
+pub fn init(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline.trim());
+}
+
+/// The full command line, empty if none was passed
+pub fn cmdline() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Value of the last `key=value` option in `cmdline`
+pub fn option_in<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .rev()
+        .filter_map(|word| word.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+/// Check whether `cmdline` contains the flag `name`
+pub fn flag_in(cmdline: &str, name: &str) -> bool {
+    cmdline.split_ascii_whitespace().any(|word| word == name)
+}
+
+/// Value of a `key=value` option on the boot command line
+pub fn option(key: &str) -> Option<&'static str> {
+    option_in(cmdline(), key)
+}
+
+/// Check whether the boot command line contains the flag `name`
+pub fn flag(name: &str) -> bool {
+    flag_in(cmdline(), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        let cmdline = "quiet console=tty0 root=/dev/sda1 console=ttyS1,115200n8";
+        assert_eq!(option_in(cmdline, "console"), Some("ttyS1,115200n8"));
+        assert_eq!(option_in(cmdline, "root"), Some("/dev/sda1"));
+        assert_eq!(option_in(cmdline, "init"), None);
+        assert_eq!(option_in("", "console"), None);
+    }
+
+    #[test]
+    fn test_flags() {
+        let cmdline = "quiet  nosmp debug=1";
+        assert!(flag_in(cmdline, "quiet"));
+        assert!(flag_in(cmdline, "nosmp"));
+        assert!(!flag_in(cmdline, "debug"));
+        assert!(!flag_in(cmdline, "qui"));
+    }
+}
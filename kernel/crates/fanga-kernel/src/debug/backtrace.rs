@@ -0,0 +1,83 @@
+//! Symbolized Backtraces
+//!
+//! Walks the frame pointer chain with the arch layer's walker and prints
+//! each return address as `symbol+offset`, to serial and to the
+//! framebuffer. The framebuffer is skipped if the fault interrupted a
+//! writer holding it.
+
+use core::fmt::{self, Write};
+
+use fanga_arch_x86_64::backtrace::{self as arch_backtrace, MAX_FRAMES};
+
+use super::{ksyms, DebugConsole};
+
+/// An address followed by its symbol, if known
+struct Symbolized {
+    addr: u64,
+    /// Return addresses point after the call, which may be the last
+    /// instruction of the function; resolve the byte before them
+    return_address: bool,
+}
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:016x}", self.addr)?;
+        let bias = self.return_address as u64;
+        match ksyms::resolve(self.addr.wrapping_sub(bias)) {
+            Some((name, offset)) => write!(f, " {}+0x{:x}", name, offset + bias),
+            None => f.write_str(" <unknown>"),
+        }
+    }
+}
+
+/// Format a symbolized backtrace into `out`
+///
+/// `rip` is frame #0; the callers found from `rbp` follow.
+pub fn format_backtrace(out: &mut impl Write, rip: u64, frames: &[u64]) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    writeln!(out, "  #0  {}", Symbolized { addr: rip, return_address: false })?;
+    for (i, &addr) in frames.iter().enumerate() {
+        writeln!(out, "  #{:<2} {}", i + 1, Symbolized { addr, return_address: true })?;
+    }
+    Ok(())
+}
+
+/// Print a backtrace of code stopped at `rip` with frame pointer `rbp`
+pub fn print_backtrace(rip: u64, rbp: u64) {
+    let mut frames = [0u64; MAX_FRAMES];
+    let count = arch_backtrace::walk(rbp, &mut frames);
+    let _ = format_backtrace(&mut DebugConsole, rip, &frames[..count]);
+}
+
+/// Print a backtrace of the caller, e.g. from the panic handler
+#[inline(never)]
+pub fn print_current_backtrace() {
+    let rbp = arch_backtrace::frame_pointer();
+    // Our own return address and the caller's frame
+    let (caller_rbp, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+    print_backtrace(ret, caller_rbp);
+}
+
+/// Make exception handlers print symbolized backtraces
+pub fn init() {
+    unsafe {
+        arch_backtrace::set_reporter(print_backtrace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    #[test]
+    fn test_format_backtrace() {
+        let mut out = String::new();
+        format_backtrace(&mut out, 0xFFFF_FFFF_8000_1234, &[0xFFFF_FFFF_8000_2001]).unwrap();
+
+        let lines: alloc::vec::Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "Backtrace:");
+        assert_eq!(lines[1], "  #0  0xffffffff80001234 <unknown>");
+        assert_eq!(lines[2], "  #1  0xffffffff80002001 <unknown>");
+    }
+}
@@ -0,0 +1,413 @@
+//! Kernel Debugger (kdb)
+//!
+//! A small interactive debugger that runs on the panicking or interrupted
+//! CPU with interrupts disabled. It polls the serial port and the PS/2
+//! keyboard for input and writes to serial and the framebuffer, so it
+//! keeps working when the shell, the scheduler or interrupt delivery is
+//! broken. It is entered from the panic handler or with Ctrl+Alt+K; other
+//! CPUs keep running while it is active.
+//!
+//! Commands:
+//! - `regs` - registers at entry, and the control registers
+//! - `md <addr> [len]` - hex dump of mapped memory
+//! - `tasks` - the task list, unless the scheduler is locked
+//! - `bt` - backtrace from the point of entry
+//! - `sym <addr|name>` - resolve an address or look up a symbol
+//! - `go` - resume (not after a panic)
+//! - `reboot` - reset the machine
+//!
+//! This module provides:
+//! - Entry from panics and the hotkey
+//! - The command interpreter
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use fanga_arch_x86_64::keyboard::{self, KeyCode, KeyEvent, Keyboard};
+
+use super::{backtrace, ksyms, DebugConsole};
+use crate::memory::paging::PageTableMapper;
+use crate::task::scheduler;
+use crate::task::tcb::TaskState;
+
+/// Longest command line
+const MAX_LINE: usize = 128;
+
+/// Default and maximum `md` lengths
+const MD_DEFAULT_LEN: u64 = 64;
+const MD_MAX_LEN: u64 = 4096;
+
+/// Why the debugger was entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdbReason {
+    /// A kernel panic; the kernel cannot resume
+    Panic,
+    /// The Ctrl+Alt+K hotkey
+    Hotkey,
+}
+
+/// Registers of the code the debugger stopped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KdbRegs {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+}
+
+impl KdbRegs {
+    /// Registers of the calling function
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rip, rsp, rbp, rflags): (u64, u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {rip}, [rip]",
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                "pushfq",
+                "pop {rflags}",
+                rip = out(reg) rip,
+                rsp = out(reg) rsp,
+                rbp = out(reg) rbp,
+                rflags = out(reg) rflags,
+            );
+        }
+        Self { rip, rsp, rbp, rflags }
+    }
+}
+
+/// What the debugger does after a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Read the next command
+    Stay,
+    /// Leave the debugger
+    Resume,
+    /// Reset the machine
+    Reboot,
+}
+
+fn state_name(state: TaskState) -> &'static str {
+    match state {
+        TaskState::Ready => "ready",
+        TaskState::Running => "running",
+        TaskState::Blocked => "blocked",
+        TaskState::Terminated => "terminated",
+    }
+}
+
+/// State of one debugger session
+pub struct Session {
+    regs: KdbRegs,
+    reason: KdbReason,
+    /// Whether a page can be read without faulting
+    readable: fn(u64) -> bool,
+}
+
+/// Parse a hexadecimal number, with or without `0x`
+pub fn parse_hex(s: &str) -> Option<u64> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+impl Session {
+    /// Start a session for code stopped with `regs`
+    pub fn new(reason: KdbReason, regs: KdbRegs, readable: fn(u64) -> bool) -> Self {
+        Self { regs, reason, readable }
+    }
+
+    /// Run one command line
+    pub fn execute(&self, line: &str, out: &mut impl Write) -> Result<Action, fmt::Error> {
+        let mut args = line.split_whitespace();
+        let Some(command) = args.next() else {
+            return Ok(Action::Stay);
+        };
+
+        match command {
+            "help" | "?" => {
+                writeln!(out, "regs | md <addr> [len] | tasks | bt | sym <addr|name> | go | reboot")?;
+            }
+            "regs" => self.regs_cmd(out)?,
+            "md" => {
+                let addr = args.next().and_then(parse_hex);
+                let len = args.next().map_or(Some(MD_DEFAULT_LEN), parse_hex);
+                match (addr, len) {
+                    (Some(addr), Some(len)) => self.dump_memory(out, addr, len.min(MD_MAX_LEN))?,
+                    _ => writeln!(out, "usage: md <addr> [len]")?,
+                }
+            }
+            "tasks" => Self::tasks_cmd(out)?,
+            "bt" => {
+                let mut frames = [0u64; fanga_arch_x86_64::backtrace::MAX_FRAMES];
+                let count = fanga_arch_x86_64::backtrace::walk(self.regs.rbp, &mut frames);
+                backtrace::format_backtrace(out, self.regs.rip, &frames[..count])?;
+            }
+            "sym" => match args.next() {
+                Some(arg) => Self::sym_cmd(out, arg)?,
+                None => writeln!(out, "usage: sym <addr|name>")?,
+            },
+            "go" | "c" => {
+                if self.reason == KdbReason::Panic {
+                    writeln!(out, "cannot resume after a panic")?;
+                } else {
+                    return Ok(Action::Resume);
+                }
+            }
+            "reboot" => return Ok(Action::Reboot),
+            _ => writeln!(out, "unknown command '{}', try 'help'", command)?,
+        }
+        Ok(Action::Stay)
+    }
+
+    fn regs_cmd(&self, out: &mut impl Write) -> fmt::Result {
+        let r = &self.regs;
+        writeln!(out, "rip=0x{:016x} rsp=0x{:016x}", r.rip, r.rsp)?;
+        writeln!(out, "rbp=0x{:016x} rflags=0x{:x}", r.rbp, r.rflags)?;
+        #[cfg(not(test))]
+        {
+            let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+            unsafe {
+                core::arch::asm!(
+                    "mov {0}, cr0",
+                    "mov {1}, cr2",
+                    "mov {2}, cr3",
+                    "mov {3}, cr4",
+                    out(reg) cr0,
+                    out(reg) cr2,
+                    out(reg) cr3,
+                    out(reg) cr4,
+                    options(nomem, nostack, preserves_flags),
+                );
+            }
+            writeln!(out, "cr0=0x{:x} cr2=0x{:x} cr3=0x{:x} cr4=0x{:x}", cr0, cr2, cr3, cr4)?;
+        }
+        Ok(())
+    }
+
+    fn dump_memory(&self, out: &mut impl Write, addr: u64, len: u64) -> fmt::Result {
+        let Some(end) = addr.checked_add(len) else {
+            return writeln!(out, "address range overflows");
+        };
+        let mut page = addr & !0xFFF;
+        while page < end {
+            if !(self.readable)(page) {
+                return writeln!(out, "0x{:x}: not mapped", page.max(addr));
+            }
+            page += 0x1000;
+        }
+
+        for line in (addr..end).step_by(16) {
+            let count = (end - line).min(16) as usize;
+            let bytes = unsafe { core::slice::from_raw_parts(line as *const u8, count) };
+            write!(out, "{:016x}:", line)?;
+            for byte in bytes {
+                write!(out, " {:02x}", byte)?;
+            }
+            for _ in count..16 {
+                write!(out, "   ")?;
+            }
+            write!(out, "  ")?;
+            for &byte in bytes {
+                let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+                out.write_char(c)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn tasks_cmd(out: &mut impl Write) -> fmt::Result {
+        let Some(sched) = scheduler::try_scheduler() else {
+            return writeln!(out, "scheduler is locked");
+        };
+        writeln!(out, "  ID  CPU  STATE       NAME")?;
+        for task in sched.tasks() {
+            writeln!(
+                out,
+                "{:>4}  {:>3}  {:<10}  {}",
+                task.id.as_usize(),
+                task.cpu,
+                state_name(task.state),
+                task.name()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn sym_cmd(out: &mut impl Write, arg: &str) -> fmt::Result {
+        let Some(table) = ksyms::kernel_symbols() else {
+            return writeln!(out, "no symbol table in this kernel");
+        };
+        if let Some(addr) = parse_hex(arg).filter(|_| arg.starts_with("0x")) {
+            match table.resolve(addr) {
+                Some((name, offset)) => writeln!(out, "0x{:x} = {}+0x{:x}", addr, name, offset),
+                None => writeln!(out, "0x{:x}: no symbol", addr),
+            }
+        } else {
+            match table.lookup(arg) {
+                Some(addr) => writeln!(out, "{} = 0x{:x}", arg, addr),
+                None => writeln!(out, "{}: no such symbol", arg),
+            }
+        }
+    }
+}
+
+/// Set while a CPU is in the debugger
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// HHDM offset for walking the page tables, 0 until memory init
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Let `md` check addresses against the page tables
+pub fn set_hhdm_offset(offset: u64) {
+    HHDM_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Check whether `page` is mapped in the current address space
+fn page_mapped(page: u64) -> bool {
+    let hhdm = HHDM_OFFSET.load(Ordering::Relaxed);
+    if hhdm == 0 {
+        return false;
+    }
+    PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, hhdm)
+        .effective_flags(page)
+        .is_some()
+}
+
+/// Wait for one character from serial or the keyboard
+fn read_char(kbd: &mut Keyboard) -> u8 {
+    loop {
+        if let Some(byte) = fanga_arch_x86_64::serial::try_read_byte() {
+            return byte;
+        }
+        if let Some(scancode) = keyboard::poll_scancode() {
+            match kbd.process_scancode(scancode) {
+                Some(KeyEvent::Press(KeyCode::Enter)) => return b'\n',
+                Some(KeyEvent::Press(KeyCode::Backspace)) => return 0x08,
+                Some(KeyEvent::Press(code)) => {
+                    if let Some(c) = kbd.to_ascii(code).filter(char::is_ascii) {
+                        return c as u8;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Waiting for input with interrupts off is not a lockup
+        fanga_arch_x86_64::interrupts::nmi_watchdog::touch();
+        core::hint::spin_loop();
+    }
+}
+
+/// Read a command line, echoing it
+fn read_line<'a>(buf: &'a mut [u8; MAX_LINE], kbd: &mut Keyboard) -> &'a str {
+    let mut len = 0;
+    loop {
+        match read_char(kbd) {
+            b'\r' | b'\n' => {
+                let _ = writeln!(DebugConsole);
+                break;
+            }
+            0x08 | 0x7F if len > 0 => {
+                len -= 1;
+                let _ = DebugConsole.write_str("\x08 \x08");
+            }
+            byte if (0x20..0x7F).contains(&byte) && len < MAX_LINE => {
+                buf[len] = byte;
+                len += 1;
+                let _ = DebugConsole.write_char(byte as char);
+            }
+            _ => {}
+        }
+    }
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Run the debugger until the user resumes
+///
+/// Returns immediately if another CPU is already in the debugger. After a
+/// panic it never returns.
+pub fn enter(reason: KdbReason, regs: KdbRegs) {
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    let session = Session::new(reason, regs, page_mapped);
+    let mut kbd = Keyboard::new();
+    let mut buf = [0u8; MAX_LINE];
+
+    fanga_arch_x86_64::interrupts::without_interrupts(|| {
+        let _ = writeln!(DebugConsole, "\nEntering kdb ({:?}), 'help' for commands", reason);
+        loop {
+            let _ = write!(DebugConsole, "kdb> ");
+            let line = read_line(&mut buf, &mut kbd);
+            match session.execute(line, &mut DebugConsole) {
+                Ok(Action::Resume) => break,
+                Ok(Action::Reboot) => crate::power::reboot::restart(),
+                _ => {}
+            }
+        }
+    });
+
+    ACTIVE.store(false, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn run(session: &Session, line: &str) -> (Action, String) {
+        let mut out = String::new();
+        let action = session.execute(line, &mut out).unwrap();
+        (action, out)
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("0x1f"), Some(0x1F));
+        assert_eq!(parse_hex("ffff"), Some(0xFFFF));
+        assert_eq!(parse_hex("0xzz"), None);
+    }
+
+    #[test]
+    fn test_resume_and_reboot() {
+        let hotkey = Session::new(KdbReason::Hotkey, KdbRegs::default(), |_| false);
+        assert_eq!(run(&hotkey, "go").0, Action::Resume);
+        assert_eq!(run(&hotkey, "reboot").0, Action::Reboot);
+        assert_eq!(run(&hotkey, "").0, Action::Stay);
+
+        let panic = Session::new(KdbReason::Panic, KdbRegs::default(), |_| false);
+        let (action, out) = run(&panic, "go");
+        assert_eq!(action, Action::Stay);
+        assert!(out.contains("cannot resume"));
+        assert!(run(&panic, "frobnicate").1.contains("unknown command"));
+    }
+
+    #[test]
+    fn test_memory_dump() {
+        let data: [u8; 20] = *b"kdb memory dump test";
+        let addr = data.as_ptr() as u64;
+
+        let session = Session::new(KdbReason::Hotkey, KdbRegs::default(), |_| true);
+        let (_, out) = run(&session, &alloc::format!("md 0x{:x} 0x14", addr));
+        let lines: alloc::vec::Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" 6b 64 62 20"));
+        assert!(lines[0].ends_with("kdb memory dump "));
+        assert!(lines[1].ends_with("test"));
+
+        let unmapped = Session::new(KdbReason::Hotkey, KdbRegs::default(), |_| false);
+        assert!(run(&unmapped, &alloc::format!("md 0x{:x}", addr)).1.contains("not mapped"));
+        assert!(run(&unmapped, "md").1.contains("usage"));
+    }
+
+    #[test]
+    fn test_regs() {
+        let regs = KdbRegs { rip: 0xFFFF_FFFF_8000_1000, rsp: 0x10, rbp: 0x20, rflags: 0x202 };
+        let session = Session::new(KdbReason::Panic, regs, |_| false);
+        let (_, out) = run(&session, "regs");
+        assert!(out.contains("rip=0xffffffff80001000"));
+        assert!(out.contains("rflags=0x202"));
+    }
+}
@@ -0,0 +1,206 @@
+//! Kernel Symbol Table
+//!
+//! The kernel image reserves a `.ksyms` section of fixed size. After the
+//! kernel is linked, `scripts/gen-ksyms.py` reads its function symbols
+//! with `nm` and writes them into that section in place, so no address in
+//! the image moves. A kernel built without that step has an empty section
+//! and backtraces show raw addresses.
+//!
+//! Layout (little-endian):
+//! - Header: magic `KSYM`, symbol count (u32), string table size (u32),
+//!   reserved (u32)
+//! - Entries sorted by address: address (u64), name offset (u32), name
+//!   length (u32)
+//! - String table with the demangled names
+//!
+//! This module provides:
+//! - Parsing of the symbol table
+//! - Address to `symbol+offset` resolution
+//! - Symbol lookup by name
+
+/// Capacity of the `.ksyms` section
+pub const KSYMS_CAPACITY: usize = 1024 * 1024;
+
+/// Magic number at the start of the table
+pub const KSYMS_MAGIC: [u8; 4] = *b"KSYM";
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// Space filled in by `scripts/gen-ksyms.py` after linking
+///
+/// Mutable so the compiler cannot assume it stays zero.
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_CAPACITY] = [0; KSYMS_CAPACITY];
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// A parsed symbol table
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+    count: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parse a table, checking that every entry lies within `data`
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[..4] != KSYMS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 4) as usize;
+        let strings_len = read_u32(data, 8) as usize;
+        let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+        let strings_end = entries_end.checked_add(strings_len)?;
+        if strings_end > data.len() {
+            return None;
+        }
+
+        let table = Self {
+            entries: &data[HEADER_SIZE..entries_end],
+            strings: &data[entries_end..strings_end],
+            count,
+        };
+        let names_valid = (0..count).all(|i| {
+            let (_, offset, len) = table.raw_entry(i);
+            offset.checked_add(len).is_some_and(|end| end <= strings_len)
+        });
+        names_valid.then_some(table)
+    }
+
+    fn raw_entry(&self, index: usize) -> (u64, usize, usize) {
+        let base = index * ENTRY_SIZE;
+        (
+            read_u64(self.entries, base),
+            read_u32(self.entries, base + 8) as usize,
+            read_u32(self.entries, base + 12) as usize,
+        )
+    }
+
+    /// Number of symbols
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Check if the table has no symbols
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Address and name of symbol `index`
+    pub fn symbol(&self, index: usize) -> Option<(u64, &'a str)> {
+        if index >= self.count {
+            return None;
+        }
+        let (addr, offset, len) = self.raw_entry(index);
+        let name = core::str::from_utf8(&self.strings[offset..offset + len]).unwrap_or("<invalid>");
+        Some((addr, name))
+    }
+
+    /// Symbol containing `addr`, and the offset of `addr` into it
+    ///
+    /// Returns the closest symbol at or below `addr`.
+    pub fn resolve(&self, addr: u64) -> Option<(&'a str, u64)> {
+        // First symbol above addr
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.raw_entry(mid).0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let (start, name) = self.symbol(low.checked_sub(1)?)?;
+        Some((name, addr - start))
+    }
+
+    /// Address of the symbol named `name`
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        (0..self.count)
+            .filter_map(|i| self.symbol(i))
+            .find(|&(_, symbol)| symbol == name)
+            .map(|(addr, _)| addr)
+    }
+}
+
+/// Symbol table embedded in the kernel image, if one was written
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+    let data: &'static [u8] = unsafe { &*core::ptr::addr_of!(KSYMS) };
+    SymbolTable::parse(data)
+}
+
+/// Resolve `addr` against the kernel symbol table
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    kernel_symbols()?.resolve(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn build(symbols: &[(u64, &str)]) -> Vec<u8> {
+        let strings: Vec<u8> = symbols.iter().flat_map(|(_, name)| name.bytes()).collect();
+        let mut data = Vec::new();
+        data.extend_from_slice(&KSYMS_MAGIC);
+        data.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let mut offset = 0u32;
+        for (addr, name) in symbols {
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            offset += name.len() as u32;
+        }
+        data.extend_from_slice(&strings);
+        data
+    }
+
+    #[test]
+    fn test_resolve() {
+        let data = build(&[
+            (0xFFFF_FFFF_8000_1000, "_start"),
+            (0xFFFF_FFFF_8000_1200, "fanga_kernel::boot::initialize"),
+            (0xFFFF_FFFF_8000_2000, "rust_begin_unwind"),
+        ]);
+        let table = SymbolTable::parse(&data).unwrap();
+
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_1000), Some(("_start", 0)));
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_1234), Some(("fanga_kernel::boot::initialize", 0x34)));
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_2010), Some(("rust_begin_unwind", 0x10)));
+        assert_eq!(table.resolve(0xFFFF_FFFF_8000_0FFF), None);
+        assert_eq!(table.lookup("rust_begin_unwind"), Some(0xFFFF_FFFF_8000_2000));
+        assert_eq!(table.lookup("missing"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_tables() {
+        assert!(SymbolTable::parse(&[0; 64]).is_none());
+
+        let mut data = build(&[(0x1000, "main")]);
+        data.truncate(data.len() - 1);
+        assert!(SymbolTable::parse(&data).is_none());
+
+        // Name pointing past the string table
+        let mut data = build(&[(0x1000, "main")]);
+        data[HEADER_SIZE + 8] = 2;
+        assert!(SymbolTable::parse(&data).is_none());
+    }
+
+    #[test]
+    fn test_unpatched_image_has_no_symbols() {
+        assert!(kernel_symbols().is_none());
+        assert_eq!(resolve(0xFFFF_FFFF_8000_1000), None);
+    }
+}
@@ -0,0 +1,34 @@
+//! Kernel Debugging
+//!
+//! This module provides:
+//! - The embedded kernel symbol table
+//! - Symbolized backtraces for panics and exceptions
+//! - The kernel debugger (kdb)
+
+pub mod backtrace;
+pub mod kdb;
+pub mod ksyms;
+
+use core::fmt;
+
+pub use backtrace::{print_backtrace, print_current_backtrace};
+pub use ksyms::{kernel_symbols, SymbolTable};
+
+/// Output for panic reports and the debugger
+///
+/// Writes to serial and to the framebuffer, skipping the framebuffer if
+/// the code that was interrupted holds it.
+pub struct DebugConsole;
+
+impl fmt::Write for DebugConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        fanga_arch_x86_64::serial::_print(format_args!("{}", s));
+        crate::io::framebuffer::_print_emergency(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// Register the debugging hooks with the arch layer
+pub fn init() {
+    backtrace::init();
+}
@@ -0,0 +1,682 @@
+//! Driver Core
+//!
+//! Bus code (PCI, USB, platform) adds the devices it discovers, and
+//! drivers register with a table of the IDs they handle. A device is
+//! offered to the matching drivers of its bus, in registration order,
+//! until one's `probe` accepts it, whichever of the two arrived first.
+//! The bound driver gets `remove` when the device or the driver goes
+//! away, and `suspend`/`resume` around system sleep: children are
+//! suspended before their parents and resumed after them.
+//!
+//! Devices form a tree through their parents, which `/sys/devices`
+//! mirrors (see `sysfs`). Driver callbacks run without the core's lock
+//! held, so a probe may add the devices behind the one it was given.
+//!
+//! This module provides:
+//! - Device and driver registration, with ID table matching
+//! - The probe/remove/suspend/resume lifecycle of bound drivers
+//! - The device tree and its sysfs view
+
+pub mod sysfs;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::{FileSystem, FsError};
+use crate::pci::PciDevice;
+use crate::smp::spinlock::SpinLock;
+
+/// Bus a device was found on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Pci,
+    Usb,
+    Platform,
+}
+
+impl Bus {
+    /// Name under `/sys/bus`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pci => "pci",
+            Self::Usb => "usb",
+            Self::Platform => "platform",
+        }
+    }
+}
+
+/// Where a driver finds its device on the bus
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    None,
+    Pci(PciDevice),
+    Usb { controller: usize, address: u8 },
+}
+
+/// Handle of a device in the tree; never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceHandle(u64);
+
+/// A device in the tree
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// Unique name, such as `0000:00:1f.2` or `usb1.2`
+    pub name: String,
+    /// None for nodes that only group their children, such as a PCI host
+    /// bridge; no driver binds to those
+    pub bus: Option<Bus>,
+    pub parent: Option<DeviceHandle>,
+    pub vendor: u16,
+    pub device: u16,
+    /// Class, subclass and programming interface, a byte each
+    pub class: u32,
+    pub resource: Resource,
+}
+
+impl Device {
+    /// A node grouping the devices under it
+    pub fn group(name: &str, parent: Option<DeviceHandle>) -> Self {
+        Self {
+            name: String::from(name),
+            bus: None,
+            parent,
+            vendor: 0,
+            device: 0,
+            class: 0,
+            resource: Resource::None,
+        }
+    }
+
+    /// A device on the platform bus; drivers match it by name
+    pub fn platform(name: &str) -> Self {
+        Self { bus: Some(Bus::Platform), ..Self::group(name, None) }
+    }
+
+    /// A PCI function, named after its address
+    pub fn pci(function: PciDevice, parent: Option<DeviceHandle>) -> Self {
+        Self {
+            name: function.address.device_name(),
+            bus: Some(Bus::Pci),
+            parent,
+            vendor: function.vendor_id,
+            device: function.device_id,
+            class: (function.class as u32) << 16 | (function.subclass as u32) << 8 | function.prog_if as u32,
+            resource: Resource::Pci(function),
+        }
+    }
+
+    /// The identity of the device as one string, empty for groups
+    pub fn modalias(&self) -> String {
+        let [_, class, subclass, interface] = self.class.to_be_bytes();
+        match self.bus {
+            Some(Bus::Pci) => format!(
+                "pci:v{:08X}d{:08X}bc{:02X}sc{:02X}i{:02X}",
+                self.vendor, self.device, class, subclass, interface
+            ),
+            Some(Bus::Usb) => format!("usb:v{:04X}p{:04X}dc{:02X}", self.vendor, self.device, class),
+            Some(Bus::Platform) => format!("platform:{}", self.name),
+            None => String::new(),
+        }
+    }
+}
+
+/// An entry of a driver's ID table; fields left as None match anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    /// Class bits compared under `class_mask`
+    pub class: u32,
+    pub class_mask: u32,
+    /// Platform device name
+    pub name: Option<&'static str>,
+}
+
+impl DeviceId {
+    const ANY: Self = Self { vendor: None, device: None, class: 0, class_mask: 0, name: None };
+
+    /// Match a vendor and device ID
+    pub const fn id(vendor: u16, device: u16) -> Self {
+        Self { vendor: Some(vendor), device: Some(device), ..Self::ANY }
+    }
+
+    /// Match the class bits set in `mask`
+    pub const fn class(class: u32, mask: u32) -> Self {
+        Self { class, class_mask: mask, ..Self::ANY }
+    }
+
+    /// Match a platform device by name
+    pub const fn name(name: &'static str) -> Self {
+        Self { name: Some(name), ..Self::ANY }
+    }
+
+    /// Whether `device` is one of the devices this entry describes
+    pub fn matches(&self, device: &Device) -> bool {
+        self.vendor.is_none_or(|vendor| vendor == device.vendor)
+            && self.device.is_none_or(|id| id == device.device)
+            && (device.class ^ self.class) & self.class_mask == 0
+            && self.name.is_none_or(|name| name == device.name)
+    }
+}
+
+/// A driver for devices of one bus
+pub trait Driver: Send + Sync {
+    /// Unique name, shown under `/sys/bus/<bus>/drivers`
+    fn name(&self) -> &'static str;
+
+    fn bus(&self) -> Bus;
+
+    /// Devices the driver is offered
+    fn id_table(&self) -> &'static [DeviceId];
+
+    /// Take over `device`; an error leaves it for the next driver
+    fn probe(&self, device: &Device) -> Result<(), &'static str>;
+
+    /// Let go of a device that is going away or being unbound
+    fn remove(&self, _device: &Device) {}
+
+    /// Quiesce the device before system sleep
+    fn suspend(&self, _device: &Device) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Bring the device back after system sleep
+    fn resume(&self, _device: &Device) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Whether `driver` is offered `device`
+fn driver_matches(driver: &dyn Driver, device: &Device) -> bool {
+    device.bus == Some(driver.bus()) && driver.id_table().iter().any(|id| id.matches(device))
+}
+
+/// A device and the driver bound to it
+struct Node {
+    device: Device,
+    driver: Option<Arc<dyn Driver>>,
+}
+
+/// Snapshot of a device in the tree
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub handle: DeviceHandle,
+    pub device: Device,
+    /// Path from the root of the tree, the names of the ancestors joined
+    /// by `/`
+    pub path: String,
+    pub driver: Option<&'static str>,
+}
+
+/// Devices and drivers known to the core
+pub struct DeviceTree {
+    /// Devices by handle, so in the order they were added
+    nodes: BTreeMap<DeviceHandle, Node>,
+    /// Drivers in registration order
+    drivers: Vec<Arc<dyn Driver>>,
+    next_handle: u64,
+}
+
+impl DeviceTree {
+    pub const fn new() -> Self {
+        Self { nodes: BTreeMap::new(), drivers: Vec::new(), next_handle: 1 }
+    }
+
+    /// Ancestors of `handle`, the device itself first
+    fn ancestors(&self, handle: DeviceHandle) -> impl Iterator<Item = &Node> {
+        core::iter::successors(self.nodes.get(&handle), |node| {
+            node.device.parent.and_then(|parent| self.nodes.get(&parent))
+        })
+    }
+
+    fn info(&self, handle: DeviceHandle) -> Option<DeviceInfo> {
+        let node = self.nodes.get(&handle)?;
+        let mut names: Vec<&str> = self.ancestors(handle).map(|node| node.device.name.as_str()).collect();
+        names.reverse();
+        Some(DeviceInfo {
+            handle,
+            device: node.device.clone(),
+            path: names.join("/"),
+            driver: node.driver.as_ref().map(|driver| driver.name()),
+        })
+    }
+
+    /// `handle` and everything below it, children before their parents
+    fn subtree(&self, handle: DeviceHandle) -> Vec<DeviceHandle> {
+        let mut handles = Vec::new();
+        for (&child, node) in &self.nodes {
+            if node.device.parent == Some(handle) {
+                handles.extend(self.subtree(child));
+            }
+        }
+        handles.push(handle);
+        handles
+    }
+
+    /// Bound devices in suspend order: deepest first, and among devices
+    /// at the same depth the last added first
+    fn suspend_order(&self) -> Vec<(Device, Arc<dyn Driver>)> {
+        let mut bound: Vec<_> = self
+            .nodes
+            .iter()
+            .rev()
+            .filter_map(|(&handle, node)| {
+                let driver = node.driver.clone()?;
+                Some((self.ancestors(handle).count(), node.device.clone(), driver))
+            })
+            .collect();
+        bound.sort_by_key(|&(depth, _, _)| core::cmp::Reverse(depth));
+        bound.into_iter().map(|(_, device, driver)| (device, driver)).collect()
+    }
+}
+
+impl Default for DeviceTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Offer an unbound device to `drivers` until one accepts it
+///
+/// Returns the name of the driver that bound.
+fn bind(tree: &SpinLock<DeviceTree>, handle: DeviceHandle, drivers: &[Arc<dyn Driver>]) -> Option<&'static str> {
+    let device = tree.lock().nodes.get(&handle)?.device.clone();
+    for driver in drivers.iter().filter(|driver| driver_matches(driver.as_ref(), &device)) {
+        if let Err(e) = driver.probe(&device) {
+            crate::log_info!(target: "driver", "{}: {} declined: {}", device.name, driver.name(), e);
+            continue;
+        }
+        let mut locked = tree.lock();
+        match locked.nodes.get_mut(&handle) {
+            Some(node) if node.driver.is_none() => {
+                node.driver = Some(driver.clone());
+                drop(locked);
+                crate::log_info!(target: "driver", "{}: bound to {}", device.name, driver.name());
+                return Some(driver.name());
+            }
+            // Removed or bound by another driver while probing
+            _ => {
+                drop(locked);
+                driver.remove(&device);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Add a device to `tree` and bind a driver to it if one accepts it
+pub fn add_device_in(tree: &SpinLock<DeviceTree>, device: Device) -> Result<DeviceHandle, &'static str> {
+    let mut locked = tree.lock();
+    if device.parent.is_some_and(|parent| !locked.nodes.contains_key(&parent)) {
+        return Err("Parent device not found");
+    }
+    if locked.nodes.values().any(|node| node.device.name == device.name) {
+        return Err("Device already exists");
+    }
+    let handle = DeviceHandle(locked.next_handle);
+    locked.next_handle += 1;
+    locked.nodes.insert(handle, Node { device, driver: None });
+    let drivers = locked.drivers.clone();
+    drop(locked);
+
+    bind(tree, handle, &drivers);
+    Ok(handle)
+}
+
+/// Remove a device and everything below it from `tree`
+///
+/// Children go first; each bound driver gets `remove`. Returns what was
+/// removed, in that order.
+pub fn remove_device_in(tree: &SpinLock<DeviceTree>, handle: DeviceHandle) -> Result<Vec<DeviceInfo>, &'static str> {
+    let removed: Vec<DeviceInfo> = {
+        let locked = tree.lock();
+        if !locked.nodes.contains_key(&handle) {
+            return Err("Device not found");
+        }
+        locked.subtree(handle).into_iter().filter_map(|handle| locked.info(handle)).collect()
+    };
+    for info in &removed {
+        let node = tree.lock().nodes.remove(&info.handle);
+        if let Some(Node { device, driver: Some(driver) }) = node {
+            driver.remove(&device);
+        }
+    }
+    Ok(removed)
+}
+
+/// Register a driver with `tree` and bind it to the unbound devices it
+/// matches
+///
+/// Returns the devices it bound to.
+pub fn register_driver_in(tree: &SpinLock<DeviceTree>, driver: Arc<dyn Driver>) -> Result<Vec<DeviceHandle>, &'static str> {
+    let mut locked = tree.lock();
+    if locked.drivers.iter().any(|registered| registered.name() == driver.name()) {
+        return Err("Driver already registered");
+    }
+    locked.drivers.push(driver.clone());
+    let candidates: Vec<DeviceHandle> = locked
+        .nodes
+        .iter()
+        .filter(|(_, node)| node.driver.is_none() && driver_matches(driver.as_ref(), &node.device))
+        .map(|(&handle, _)| handle)
+        .collect();
+    drop(locked);
+
+    let drivers = core::slice::from_ref(&driver);
+    Ok(candidates.into_iter().filter(|&handle| bind(tree, handle, drivers).is_some()).collect())
+}
+
+/// Unregister a driver from `tree`, unbinding it from its devices
+///
+/// Returns the devices it was bound to; they stay in the tree unbound.
+pub fn unregister_driver_in(tree: &SpinLock<DeviceTree>, name: &str) -> Result<Vec<DeviceHandle>, &'static str> {
+    let mut locked = tree.lock();
+    let index = locked.drivers.iter().position(|driver| driver.name() == name).ok_or("Driver not registered")?;
+    let driver = locked.drivers.remove(index);
+    let mut unbound = Vec::new();
+    for (&handle, node) in locked.nodes.iter_mut() {
+        if node.driver.as_ref().is_some_and(|bound| Arc::ptr_eq(bound, &driver)) {
+            node.driver = None;
+            unbound.push((handle, node.device.clone()));
+        }
+    }
+    drop(locked);
+
+    for (_, device) in &unbound {
+        driver.remove(device);
+    }
+    Ok(unbound.into_iter().map(|(handle, _)| handle).collect())
+}
+
+/// Suspend the bound devices of `tree`, children before their parents
+///
+/// If one fails, the devices already suspended are resumed and its error
+/// returned.
+pub fn suspend_all_in(tree: &SpinLock<DeviceTree>) -> Result<(), &'static str> {
+    let order = tree.lock().suspend_order();
+    for (index, (device, driver)) in order.iter().enumerate() {
+        if let Err(e) = driver.suspend(device) {
+            crate::log_warn!(target: "driver", "{}: {} failed to suspend: {}", device.name, driver.name(), e);
+            for (device, driver) in order[..index].iter().rev() {
+                let _ = driver.resume(device);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Resume the bound devices of `tree`, parents before their children
+///
+/// Every device is resumed even if one fails; the first error is returned.
+pub fn resume_all_in(tree: &SpinLock<DeviceTree>) -> Result<(), &'static str> {
+    let order = tree.lock().suspend_order();
+    let mut result = Ok(());
+    for (device, driver) in order.iter().rev() {
+        if let Err(e) = driver.resume(device) {
+            crate::log_warn!(target: "driver", "{}: {} failed to resume: {}", device.name, driver.name(), e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// The system device tree
+static TREE: SpinLock<DeviceTree> = SpinLock::new(DeviceTree::new());
+
+/// Apply a change to `/sys`, once the root file system is mounted
+fn update_sysfs(update: impl FnOnce(&mut dyn FileSystem) -> Result<(), FsError>) {
+    let Some(root) = crate::fs::try_root_fs() else {
+        return;
+    };
+    if let Err(e) = update(&mut *root.lock()) {
+        crate::log_warn!(target: "driver", "Cannot update /sys: {:?}", e);
+    }
+}
+
+/// Write the current state of `handles` to `/sys`
+fn publish(handles: &[DeviceHandle]) {
+    let infos: Vec<DeviceInfo> = {
+        let tree = TREE.lock();
+        handles.iter().filter_map(|&handle| tree.info(handle)).collect()
+    };
+    update_sysfs(|fs| infos.iter().try_for_each(|info| sysfs::publish_in(fs, info)));
+}
+
+/// Add a device to the tree and bind a driver to it if one accepts it
+pub fn add_device(device: Device) -> Result<DeviceHandle, &'static str> {
+    let handle = add_device_in(&TREE, device)?;
+    publish(&[handle]);
+    Ok(handle)
+}
+
+/// Remove a device and everything below it from the tree
+pub fn remove_device(handle: DeviceHandle) -> Result<(), &'static str> {
+    let removed = remove_device_in(&TREE, handle)?;
+    update_sysfs(|fs| removed.iter().try_for_each(|info| sysfs::unpublish_in(fs, info)));
+    Ok(())
+}
+
+/// Register a driver and bind it to the unbound devices it matches
+///
+/// Returns the number of devices it bound to.
+pub fn register_driver(driver: Arc<dyn Driver>) -> Result<usize, &'static str> {
+    let (bus, name) = (driver.bus(), driver.name());
+    let bound = register_driver_in(&TREE, driver)?;
+    update_sysfs(|fs| sysfs::add_driver_in(fs, bus, name));
+    publish(&bound);
+    Ok(bound.len())
+}
+
+/// Unregister a driver, unbinding it from its devices
+pub fn unregister_driver(name: &str) -> Result<(), &'static str> {
+    let bus = TREE
+        .lock()
+        .drivers
+        .iter()
+        .find(|driver| driver.name() == name)
+        .map(|driver| driver.bus())
+        .ok_or("Driver not registered")?;
+    let unbound = unregister_driver_in(&TREE, name)?;
+    update_sysfs(|fs| sysfs::remove_driver_in(fs, bus, name));
+    publish(&unbound);
+    Ok(())
+}
+
+/// Add a device to the platform bus, under `/sys/devices/platform`
+pub fn add_platform_device(name: &str) -> Result<DeviceHandle, &'static str> {
+    let root = match find_device("platform") {
+        Some(root) => root.handle,
+        None => add_device(Device::group("platform", None))?,
+    };
+    add_device(Device { parent: Some(root), ..Device::platform(name) })
+}
+
+/// The device named `name`
+pub fn find_device(name: &str) -> Option<DeviceInfo> {
+    let tree = TREE.lock();
+    let handle = tree.nodes.iter().find(|(_, node)| node.device.name == name).map(|(&handle, _)| handle)?;
+    tree.info(handle)
+}
+
+/// Every device in the tree, in the order they were added
+pub fn devices() -> Vec<DeviceInfo> {
+    let tree = TREE.lock();
+    tree.nodes.keys().filter_map(|&handle| tree.info(handle)).collect()
+}
+
+/// Suspend the bound devices, children before their parents
+pub fn suspend_all() -> Result<(), &'static str> {
+    suspend_all_in(&TREE)
+}
+
+/// Resume the bound devices, parents before their children
+pub fn resume_all() -> Result<(), &'static str> {
+    resume_all_in(&TREE)
+}
+
+/// Hook the bound drivers into system sleep
+///
+/// Called after power management is initialized.
+pub fn init() -> Result<(), &'static str> {
+    let ops = crate::power::device::PmOps { suspend: suspend_all, resume: resume_all };
+    crate::power::device::register_driver("devices", ops)
+}
+
+/// Write out `/sys` for the devices and drivers known so far
+///
+/// Buses are scanned before the root file system exists; afterwards the
+/// core keeps `/sys` current by itself. Returns the number of devices.
+pub fn populate_sysfs() -> Result<usize, FsError> {
+    let (infos, drivers) = {
+        let tree = TREE.lock();
+        let infos: Vec<DeviceInfo> = tree.nodes.keys().filter_map(|&handle| tree.info(handle)).collect();
+        let drivers: Vec<(Bus, &'static str)> = tree.drivers.iter().map(|driver| (driver.bus(), driver.name())).collect();
+        (infos, drivers)
+    };
+    let mut fs = crate::fs::try_root_fs().ok_or(FsError::IoError)?.lock();
+    for (bus, name) in drivers {
+        sysfs::add_driver_in(&mut *fs, bus, name)?;
+    }
+    for info in &infos {
+        sysfs::publish_in(&mut *fs, info)?;
+    }
+    Ok(infos.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    /// Records its callbacks as `<callback> <device>`
+    struct TestDriver {
+        name: &'static str,
+        table: &'static [DeviceId],
+        accept: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TestDriver {
+        fn new(name: &'static str, table: &'static [DeviceId], accept: bool, log: &Arc<Mutex<Vec<String>>>) -> Arc<Self> {
+            Arc::new(Self { name, table, accept, log: log.clone() })
+        }
+
+        fn record(&self, callback: &str, device: &Device) {
+            self.log.lock().push(format!("{} {}", callback, device.name));
+        }
+    }
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn bus(&self) -> Bus {
+            Bus::Platform
+        }
+
+        fn id_table(&self) -> &'static [DeviceId] {
+            self.table
+        }
+
+        fn probe(&self, device: &Device) -> Result<(), &'static str> {
+            self.record("probe", device);
+            if self.accept { Ok(()) } else { Err("Not this one") }
+        }
+
+        fn remove(&self, device: &Device) {
+            self.record("remove", device);
+        }
+
+        fn suspend(&self, device: &Device) -> Result<(), &'static str> {
+            self.record("suspend", device);
+            Ok(())
+        }
+
+        fn resume(&self, device: &Device) -> Result<(), &'static str> {
+            self.record("resume", device);
+            Ok(())
+        }
+    }
+
+    const RTC: &[DeviceId] = &[DeviceId::name("rtc")];
+    const ANY: &[DeviceId] = &[DeviceId::class(0, 0)];
+
+    fn child(name: &str, parent: DeviceHandle) -> Device {
+        Device { parent: Some(parent), ..Device::platform(name) }
+    }
+
+    #[test]
+    fn test_device_id() {
+        let mut nic = Device::platform("nic");
+        nic.vendor = 0x8086;
+        nic.device = 0x100E;
+        nic.class = 0x02_00_00;
+        assert!(DeviceId::id(0x8086, 0x100E).matches(&nic));
+        assert!(!DeviceId::id(0x8086, 0x10D3).matches(&nic));
+        assert!(DeviceId::class(0x02_00_00, 0xFF_00_00).matches(&nic));
+        assert!(!DeviceId::class(0x0C_03_30, 0xFF_FF_FF).matches(&nic));
+        assert!(DeviceId::name("nic").matches(&nic));
+        assert_eq!(nic.modalias(), "platform:nic");
+    }
+
+    #[test]
+    fn test_bind() {
+        let tree = SpinLock::new(DeviceTree::new());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        register_driver_in(&tree, TestDriver::new("picky", RTC, false, &log)).unwrap();
+
+        // Added before its driver, and declined by the first match
+        let rtc = add_device_in(&tree, Device::platform("rtc")).unwrap();
+        assert_eq!(tree.lock().info(rtc).unwrap().driver, None);
+        assert_eq!(add_device_in(&tree, Device::platform("rtc")), Err("Device already exists"));
+
+        let bound = register_driver_in(&tree, TestDriver::new("cmos", RTC, true, &log)).unwrap();
+        assert_eq!(bound, [rtc]);
+        assert_eq!(tree.lock().info(rtc).unwrap().driver, Some("cmos"));
+
+        // Unbinding leaves the device in the tree
+        assert_eq!(unregister_driver_in(&tree, "cmos").unwrap(), [rtc]);
+        assert_eq!(tree.lock().info(rtc).unwrap().driver, None);
+        assert_eq!(*log.lock(), ["probe rtc", "probe rtc", "remove rtc"]);
+    }
+
+    #[test]
+    fn test_tree() {
+        let tree = SpinLock::new(DeviceTree::new());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        register_driver_in(&tree, TestDriver::new("all", ANY, true, &log)).unwrap();
+
+        let bus = add_device_in(&tree, Device::platform("bus")).unwrap();
+        let a = add_device_in(&tree, child("a", bus)).unwrap();
+        add_device_in(&tree, child("b", bus)).unwrap();
+        add_device_in(&tree, child("a1", a)).unwrap();
+        assert_eq!(tree.lock().info(a).unwrap().path, "bus/a");
+        assert!(add_device_in(&tree, child("orphan", DeviceHandle(99))).is_err());
+
+        // Deepest first, then the last added first
+        log.lock().clear();
+        suspend_all_in(&tree).unwrap();
+        resume_all_in(&tree).unwrap();
+        assert_eq!(
+            *log.lock(),
+            [
+                "suspend a1", "suspend b", "suspend a", "suspend bus",
+                "resume bus", "resume a", "resume b", "resume a1",
+            ]
+        );
+
+        // Children are removed before their parents
+        log.lock().clear();
+        let removed = remove_device_in(&tree, a).unwrap();
+        let paths: Vec<&str> = removed.iter().map(|info| info.path.as_str()).collect();
+        assert_eq!(paths, ["bus/a/a1", "bus/a"]);
+        assert_eq!(*log.lock(), ["remove a1", "remove a"]);
+        assert_eq!(tree.lock().nodes.len(), 2);
+    }
+}
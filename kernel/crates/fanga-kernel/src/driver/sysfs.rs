@@ -0,0 +1,117 @@
+//! Device tree under `/sys`
+//!
+//! Each device is a directory `/sys/devices/<path>` holding one attribute
+//! per file: `modalias`, and for devices on a bus `bus`, `vendor`,
+//! `device` and `class`, plus `driver` while a driver is bound. The root
+//! file system has no symbolic links, so `/sys/bus/<bus>/devices/<name>`
+//! is a file holding the directory of each device on the bus instead.
+//! `/sys/bus/<bus>/drivers` has a directory per registered driver.
+
+use alloc::format;
+use alloc::string::String;
+
+use super::{Bus, DeviceInfo};
+use crate::fs::{create_dir_all_in, remove_all_in, write_file_in, FileSystem, FsError};
+
+/// Directory of a device
+fn device_dir(info: &DeviceInfo) -> String {
+    format!("/sys/devices/{}", info.path)
+}
+
+/// Entry of a device under its bus
+fn bus_entry(bus: Bus, name: &str) -> String {
+    format!("/sys/bus/{}/devices/{}", bus.name(), name)
+}
+
+/// Remove `path` if it exists
+fn remove_if_present(fs: &mut dyn FileSystem, path: &str) -> Result<(), FsError> {
+    match remove_all_in(fs, path) {
+        Ok(()) | Err(FsError::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write out a device's directory and bus entry, replacing what was there
+pub fn publish_in(fs: &mut dyn FileSystem, info: &DeviceInfo) -> Result<(), FsError> {
+    let dir = device_dir(info);
+    create_dir_all_in(fs, &dir)?;
+    let device = &info.device;
+    let mut attributes = alloc::vec![("modalias", device.modalias())];
+    if let Some(bus) = device.bus {
+        attributes.extend([
+            ("bus", String::from(bus.name())),
+            ("vendor", format!("0x{:04x}", device.vendor)),
+            ("device", format!("0x{:04x}", device.device)),
+            ("class", format!("0x{:06x}", device.class)),
+        ]);
+    }
+    for (name, value) in attributes {
+        write_file_in(fs, &format!("{}/{}", dir, name), format!("{}\n", value).as_bytes())?;
+    }
+    match info.driver {
+        Some(driver) => {
+            write_file_in(fs, &format!("{}/driver", dir), format!("{}\n", driver).as_bytes())?;
+        }
+        None => remove_if_present(fs, &format!("{}/driver", dir))?,
+    }
+
+    if let Some(bus) = device.bus {
+        create_dir_all_in(fs, &format!("/sys/bus/{}/devices", bus.name()))?;
+        write_file_in(fs, &bus_entry(bus, &device.name), format!("{}\n", dir).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Remove a device's directory and bus entry
+pub fn unpublish_in(fs: &mut dyn FileSystem, info: &DeviceInfo) -> Result<(), FsError> {
+    remove_if_present(fs, &device_dir(info))?;
+    match info.device.bus {
+        Some(bus) => remove_if_present(fs, &bus_entry(bus, &info.device.name)),
+        None => Ok(()),
+    }
+}
+
+/// Add a driver's directory under its bus
+pub fn add_driver_in(fs: &mut dyn FileSystem, bus: Bus, name: &str) -> Result<(), FsError> {
+    create_dir_all_in(fs, &format!("/sys/bus/{}/drivers/{}", bus.name(), name))
+}
+
+/// Remove a driver's directory
+pub fn remove_driver_in(fs: &mut dyn FileSystem, bus: Bus, name: &str) -> Result<(), FsError> {
+    remove_if_present(fs, &format!("/sys/bus/{}/drivers/{}", bus.name(), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::{Device, DeviceHandle};
+    use crate::fs::{read_file_in, MemoryFileSystem};
+
+    #[test]
+    fn test_publish() {
+        let mut fs = MemoryFileSystem::new();
+        let mut info = DeviceInfo {
+            handle: DeviceHandle(2),
+            device: Device { parent: Some(DeviceHandle(1)), ..Device::platform("i8042") },
+            path: String::from("platform/i8042"),
+            driver: Some("ps2"),
+        };
+        publish_in(&mut fs, &info).unwrap();
+        assert_eq!(read_file_in(&fs, "/sys/devices/platform/i8042/modalias").unwrap(), b"platform:i8042\n");
+        assert_eq!(read_file_in(&fs, "/sys/devices/platform/i8042/driver").unwrap(), b"ps2\n");
+        assert_eq!(
+            read_file_in(&fs, "/sys/bus/platform/devices/i8042").unwrap(),
+            b"/sys/devices/platform/i8042\n"
+        );
+
+        // Unbinding drops the driver attribute
+        info.driver = None;
+        publish_in(&mut fs, &info).unwrap();
+        assert_eq!(fs.lookup("/sys/devices/platform/i8042/driver").err(), Some(FsError::NotFound));
+
+        unpublish_in(&mut fs, &info).unwrap();
+        assert!(fs.lookup("/sys/devices/platform/i8042").is_err());
+        assert!(fs.lookup("/sys/bus/platform/devices/i8042").is_err());
+        assert!(fs.lookup("/sys/devices/platform").is_ok());
+    }
+}
@@ -0,0 +1,279 @@
+//! ELF Core File Writer
+//!
+//! This module serializes a `CoreDump` into an ELF64 core file that host
+//! tools (gdb, readelf) understand:
+//! - A PT_NOTE segment with NT_PRPSINFO and one NT_PRSTATUS per thread
+//! - One PT_LOAD segment per dumped memory region
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::parser::{program_flags, ElfHeader, ElfMachine, ElfProgramHeader, ElfType, ProgramType, ELF_MAGIC};
+use crate::task::coredump::{CoreDump, MemoryDump, RegisterDump};
+use crate::task::ipc::Signal;
+
+/// Note type: process status (registers)
+pub const NT_PRSTATUS: u32 = 1;
+/// Note type: process info (name, state)
+pub const NT_PRPSINFO: u32 = 3;
+
+/// Size of `struct elf_prstatus` on x86_64
+pub const PRSTATUS_SIZE: usize = 336;
+/// Size of `struct elf_prpsinfo` on x86_64
+pub const PRPSINFO_SIZE: usize = 136;
+
+/// Offset of `pr_reg` within `elf_prstatus`
+const PRSTATUS_REG_OFFSET: usize = 112;
+
+/// Note owner name
+const NOTE_NAME: &[u8] = b"CORE\0";
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn put_u16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Registers in `user_regs_struct` order
+fn user_regs(regs: &RegisterDump) -> [u64; 27] {
+    [
+        regs.r15, regs.r14, regs.r13, regs.r12, regs.rbp, regs.rbx,
+        regs.r11, regs.r10, regs.r9, regs.r8, regs.rax, regs.rcx,
+        regs.rdx, regs.rsi, regs.rdi,
+        u64::MAX, // orig_rax: not in a system call
+        regs.rip, regs.cs as u64, regs.rflags, regs.rsp, regs.ss as u64,
+        0, 0, // fs_base, gs_base
+        regs.ds as u64, regs.es as u64, regs.fs as u64, regs.gs as u64,
+    ]
+}
+
+/// Build an `elf_prstatus` descriptor
+pub fn prstatus(signo: u32, pid: u32, regs: &RegisterDump) -> [u8; PRSTATUS_SIZE] {
+    let mut desc = [0u8; PRSTATUS_SIZE];
+    put_u32(&mut desc, 0, signo);        // pr_info.si_signo
+    put_u16(&mut desc, 12, signo as u16); // pr_cursig
+    put_u32(&mut desc, 32, pid);         // pr_pid
+    put_u32(&mut desc, 44, pid);         // pr_sid
+    for (i, reg) in user_regs(regs).iter().enumerate() {
+        put_u64(&mut desc, PRSTATUS_REG_OFFSET + i * 8, *reg);
+    }
+    desc
+}
+
+/// Build an `elf_prpsinfo` descriptor
+pub fn prpsinfo(pid: u32, name: &str) -> [u8; PRPSINFO_SIZE] {
+    let mut desc = [0u8; PRPSINFO_SIZE];
+    desc[1] = b'R'; // pr_sname
+    put_u32(&mut desc, 24, pid); // pr_pid
+    let fname = name.as_bytes();
+    let n = fname.len().min(15);
+    desc[40..40 + n].copy_from_slice(&fname[..n]); // pr_fname
+    let m = fname.len().min(79);
+    desc[56..56 + m].copy_from_slice(&fname[..m]); // pr_psargs
+    desc
+}
+
+/// Append one ELF note
+fn push_note(out: &mut Vec<u8>, note_type: u32, desc: &[u8]) {
+    out.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&note_type.to_le_bytes());
+    out.extend_from_slice(NOTE_NAME);
+    out.resize(align4(out.len()), 0);
+    out.extend_from_slice(desc);
+    out.resize(align4(out.len()), 0);
+}
+
+/// Signal that caused the dump
+///
+/// Signal deaths record `128 + signal` as the exit code; otherwise the
+/// signal is derived from the dump reason.
+fn dump_signal(dump: &CoreDump) -> u32 {
+    use crate::task::coredump::CoreDumpReason::*;
+    if let Some(signal) = u8::try_from(dump.exit_code - 128).ok().and_then(Signal::from_num) {
+        return signal.num() as u32;
+    }
+    let signal = match dump.reason {
+        SegmentationFault => Signal::SIGSEGV,
+        IllegalInstruction => Signal::SIGILL,
+        FloatingPointException => Signal::SIGFPE,
+        BusError => Signal::SIGBUS,
+        Abort => Signal::SIGABRT,
+        UserRequested | Other => Signal::SIGQUIT,
+    };
+    signal.num() as u32
+}
+
+/// Build the PT_NOTE contents for a dump
+fn build_notes(dump: &CoreDump) -> Vec<u8> {
+    let signo = dump_signal(dump);
+    let pid = dump.process_id.as_usize() as u32;
+
+    let mut notes = Vec::new();
+    push_note(&mut notes, NT_PRSTATUS, &prstatus(signo, pid, &dump.registers));
+    push_note(&mut notes, NT_PRPSINFO, &prpsinfo(pid, &dump.process_name));
+    for thread in &dump.threads {
+        let tid = thread.thread_id.as_usize() as u32;
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(signo, tid, &thread.registers));
+    }
+    notes
+}
+
+/// Serialize a core dump into an ELF core file
+pub fn write_core(dump: &CoreDump) -> Vec<u8> {
+    let regions: Vec<&MemoryDump> = dump.memory_regions.iter().chain(dump.stack_dump.as_ref()).collect();
+    let notes = build_notes(dump);
+
+    let ehsize = size_of::<ElfHeader>();
+    let phentsize = size_of::<ElfProgramHeader>();
+    let phnum = 1 + regions.len();
+
+    let mut e_ident = [0u8; 16];
+    e_ident[..4].copy_from_slice(&ELF_MAGIC);
+    e_ident[4] = 2; // ELFCLASS64
+    e_ident[5] = 1; // ELFDATA2LSB
+    e_ident[6] = 1; // EV_CURRENT
+
+    let header = ElfHeader {
+        e_ident,
+        e_type: ElfType::Core as u16,
+        e_machine: ElfMachine::X86_64 as u16,
+        e_version: 1,
+        e_entry: 0,
+        e_phoff: ehsize as u64,
+        e_shoff: 0,
+        e_flags: 0,
+        e_ehsize: ehsize as u16,
+        e_phentsize: phentsize as u16,
+        e_phnum: phnum as u16,
+        e_shentsize: 0,
+        e_shnum: 0,
+        e_shstrndx: 0,
+    };
+
+    let notes_offset = ehsize + phnum * phentsize;
+    let mut phdrs = Vec::with_capacity(phnum);
+    phdrs.push(ElfProgramHeader {
+        p_type: ProgramType::Note as u32,
+        p_flags: 0,
+        p_offset: notes_offset as u64,
+        p_vaddr: 0,
+        p_paddr: 0,
+        p_filesz: notes.len() as u64,
+        p_memsz: 0,
+        p_align: 4,
+    });
+
+    let mut offset = notes_offset + notes.len();
+    for region in &regions {
+        let filesz = region.data.len().min(region.size);
+        phdrs.push(ElfProgramHeader {
+            p_type: ProgramType::Load as u32,
+            p_flags: program_flags::PF_R | program_flags::PF_W,
+            p_offset: offset as u64,
+            p_vaddr: region.start_addr.as_u64(),
+            p_paddr: 0,
+            p_filesz: filesz as u64,
+            p_memsz: region.size as u64,
+            p_align: 1,
+        });
+        offset += filesz;
+    }
+
+    let mut out = Vec::with_capacity(offset);
+    out.extend_from_slice(as_bytes(&header));
+    for phdr in &phdrs {
+        out.extend_from_slice(as_bytes(phdr));
+    }
+    out.extend_from_slice(&notes);
+    for region in &regions {
+        out.extend_from_slice(&region.data[..region.data.len().min(region.size)]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::memory::{PhysAddr, VirtAddr};
+    use crate::task::coredump::CoreDumpReason;
+    use crate::task::TaskId;
+
+    fn sample_dump() -> CoreDump {
+        let regs = RegisterDump { rip: 0x40_1234, rsp: 0x7fff_0000, rax: 7, ..RegisterDump::default() };
+        let mut dump = CoreDump::new(
+            TaskId::new(5),
+            String::from("crasher"),
+            CoreDumpReason::SegmentationFault,
+            100,
+            regs,
+            PhysAddr::new(0),
+            139,
+        );
+        let mut region = MemoryDump::new(VirtAddr::new(0x40_0000), 0x1000);
+        region.add_data(&[0xAB; 16]);
+        dump.add_memory_region(region);
+        dump
+    }
+
+    #[test]
+    fn test_core_header() {
+        let core = write_core(&sample_dump());
+        let header = ElfHeader::parse(&core).unwrap();
+        assert_eq!(header.elf_type(), Some(ElfType::Core));
+        assert_eq!(header.machine(), Some(ElfMachine::X86_64));
+        assert_eq!(header.e_phnum, 2);
+    }
+
+    #[test]
+    fn test_core_segments() {
+        let core = write_core(&sample_dump());
+        let ph = |i: usize| ElfProgramHeader::parse(&core[64 + i * 56..]).unwrap();
+
+        let note = ph(0);
+        assert_eq!(note.program_type(), Some(ProgramType::Note));
+
+        let load = ph(1);
+        assert_eq!(load.program_type(), Some(ProgramType::Load));
+        assert_eq!(load.p_vaddr, 0x40_0000);
+        assert_eq!(load.p_filesz, 16);
+        assert_eq!(load.p_memsz, 0x1000);
+        assert_eq!(core[load.p_offset as usize], 0xAB);
+        assert_eq!(core.len(), (load.p_offset + load.p_filesz) as usize);
+    }
+
+    #[test]
+    fn test_prstatus_note() {
+        let core = write_core(&sample_dump());
+        let note = ElfProgramHeader::parse(&core[64..]).unwrap();
+        let n = &core[note.p_offset as usize..];
+
+        // namesz, descsz, type, "CORE\0" padded to 8
+        assert_eq!(u32::from_le_bytes(n[0..4].try_into().unwrap()), 5);
+        assert_eq!(u32::from_le_bytes(n[4..8].try_into().unwrap()), PRSTATUS_SIZE as u32);
+        assert_eq!(u32::from_le_bytes(n[8..12].try_into().unwrap()), NT_PRSTATUS);
+        assert_eq!(&n[12..16], b"CORE");
+
+        let desc = &n[20..20 + PRSTATUS_SIZE];
+        assert_eq!(u32::from_le_bytes(desc[0..4].try_into().unwrap()), Signal::SIGSEGV as u32);
+        // rip is register 16 of user_regs_struct
+        let rip = &desc[PRSTATUS_REG_OFFSET + 16 * 8..][..8];
+        assert_eq!(u64::from_le_bytes(rip.try_into().unwrap()), 0x40_1234);
+    }
+}
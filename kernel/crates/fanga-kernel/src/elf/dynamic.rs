@@ -0,0 +1,561 @@
+//! ELF Dynamic Linking
+//!
+//! Reads the PT_DYNAMIC array of loaded objects and applies their RELA
+//! relocations, the PLT's included. Every symbol is bound at load time
+//! (as with `BIND_NOW`), so no lazy-binding resolver is needed in user
+//! space. Symbols are searched in the executable, then in its libraries in
+//! load order; the first definition wins.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::loader::{ElfImage, ElfLoadError};
+
+/// Dynamic array tags
+pub mod tags {
+    pub const DT_NULL: u64 = 0;
+    pub const DT_NEEDED: u64 = 1;
+    pub const DT_PLTRELSZ: u64 = 2;
+    pub const DT_HASH: u64 = 4;
+    pub const DT_STRTAB: u64 = 5;
+    pub const DT_SYMTAB: u64 = 6;
+    pub const DT_RELA: u64 = 7;
+    pub const DT_RELASZ: u64 = 8;
+    pub const DT_RELAENT: u64 = 9;
+    pub const DT_STRSZ: u64 = 10;
+    pub const DT_SYMENT: u64 = 11;
+    pub const DT_REL: u64 = 17;
+    pub const DT_PLTREL: u64 = 20;
+    pub const DT_JMPREL: u64 = 23;
+    pub const DT_GNU_HASH: u64 = 0x6fff_fef5;
+}
+
+/// x86_64 relocation types
+pub mod relocations {
+    pub const R_X86_64_NONE: u32 = 0;
+    pub const R_X86_64_64: u32 = 1;
+    pub const R_X86_64_PC32: u32 = 2;
+    pub const R_X86_64_COPY: u32 = 5;
+    pub const R_X86_64_GLOB_DAT: u32 = 6;
+    pub const R_X86_64_JUMP_SLOT: u32 = 7;
+    pub const R_X86_64_RELATIVE: u32 = 8;
+    pub const R_X86_64_32: u32 = 10;
+    pub const R_X86_64_32S: u32 = 11;
+}
+
+/// Size of an `Elf64_Sym`
+const SYM_SIZE: u64 = 24;
+
+/// Size of an `Elf64_Rela`
+const RELA_SIZE: u64 = 24;
+
+/// Symbol bindings
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+/// Section index of an undefined symbol
+const SHN_UNDEF: u16 = 0;
+
+/// Address of entry `index` of `size` bytes in a table at `table`
+///
+/// The tables come from the object, so an address that overflows makes it
+/// invalid rather than panicking.
+fn entry_address(table: u64, index: u64, size: u64) -> Result<u64, ElfLoadError> {
+    index
+        .checked_mul(size)
+        .and_then(|offset| table.checked_add(offset))
+        .ok_or(ElfLoadError::InvalidFormat)
+}
+
+/// The parts of an object's dynamic array the linker uses
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DynamicInfo {
+    /// String table offsets of the `DT_NEEDED` names
+    pub needed: Vec<u64>,
+    pub strtab: u64,
+    pub strsz: u64,
+    pub symtab: u64,
+    pub syment: u64,
+    pub rela: u64,
+    pub relasz: u64,
+    pub relaent: u64,
+    pub jmprel: u64,
+    pub pltrelsz: u64,
+    pub pltrel: u64,
+    pub hash: Option<u64>,
+    pub gnu_hash: Option<u64>,
+    /// Whether the object has REL (addend-less) relocations, which x86_64
+    /// objects should not
+    pub has_rel: bool,
+}
+
+impl DynamicInfo {
+    /// Read the dynamic array of an object, if it has one
+    pub fn parse(image: &ElfImage) -> Result<Option<Self>, ElfLoadError> {
+        let Some(dynamic) = image.dynamic else {
+            return Ok(None);
+        };
+        let mut info = Self {
+            syment: SYM_SIZE,
+            relaent: RELA_SIZE,
+            ..Self::default()
+        };
+        for index in 0.. {
+            let address = entry_address(dynamic, index, 16)?;
+            let tag = image.read_u64(address).ok_or(ElfLoadError::InvalidFormat)?;
+            let value = image.read_u64(entry_address(address, 1, 8)?).ok_or(ElfLoadError::InvalidFormat)?;
+            match tag {
+                tags::DT_NULL => break,
+                tags::DT_NEEDED => info.needed.push(value),
+                tags::DT_PLTRELSZ => info.pltrelsz = value,
+                tags::DT_HASH => info.hash = Some(value),
+                tags::DT_STRTAB => info.strtab = value,
+                tags::DT_SYMTAB => info.symtab = value,
+                tags::DT_RELA => info.rela = value,
+                tags::DT_RELASZ => info.relasz = value,
+                tags::DT_RELAENT => info.relaent = value,
+                tags::DT_STRSZ => info.strsz = value,
+                tags::DT_SYMENT => info.syment = value,
+                tags::DT_REL => info.has_rel = true,
+                tags::DT_PLTREL => info.pltrel = value,
+                tags::DT_JMPREL => info.jmprel = value,
+                tags::DT_GNU_HASH => info.gnu_hash = Some(value),
+                _ => {}
+            }
+        }
+        if info.syment != SYM_SIZE || info.relaent != RELA_SIZE {
+            return Err(ElfLoadError::InvalidFormat);
+        }
+        Ok(Some(info))
+    }
+
+    /// The NUL-terminated string at `offset` in the string table
+    fn string(&self, image: &ElfImage, offset: u64) -> Result<String, ElfLoadError> {
+        let available = self.strsz.checked_sub(offset).ok_or(ElfLoadError::InvalidFormat)?;
+        let bytes = image
+            .bytes(entry_address(self.strtab, offset, 1)?, available as usize)
+            .ok_or(ElfLoadError::InvalidFormat)?;
+        let name = bytes.split(|&b| b == 0).next().unwrap_or(&[]);
+        Ok(String::from_utf8_lossy(name).into_owned())
+    }
+
+    /// Number of entries in the symbol table, from the hash tables
+    fn symbol_count(&self, image: &ElfImage) -> Result<usize, ElfLoadError> {
+        // Word `index` of a table
+        let read = |table: u64, index: u64| {
+            image.read_u32(entry_address(table, index, 4)?).ok_or(ElfLoadError::InvalidFormat)
+        };
+        if let Some(hash) = self.hash {
+            // nbucket, then nchain: one chain entry per symbol
+            return Ok(read(hash, 1)? as usize);
+        }
+        let Some(gnu_hash) = self.gnu_hash else {
+            return Ok(0);
+        };
+        // Symbols from the highest bucket start on, up to the end of its
+        // chain, which is marked by the low bit
+        let nbuckets = read(gnu_hash, 0)? as u64;
+        let symoffset = read(gnu_hash, 1)?;
+        let bloom_size = read(gnu_hash, 2)? as u64;
+        let buckets = entry_address(entry_address(gnu_hash, 16, 1)?, bloom_size, 8)?;
+        let chains = entry_address(buckets, nbuckets, 4)?;
+        let mut last = symoffset;
+        for bucket in 0..nbuckets {
+            last = last.max(read(buckets, bucket)?);
+        }
+        if last == symoffset {
+            return Ok(symoffset as usize);
+        }
+        while read(chains, u64::from(last - symoffset))? & 1 == 0 {
+            last = last.checked_add(1).ok_or(ElfLoadError::InvalidFormat)?;
+        }
+        Ok(last as usize + 1)
+    }
+}
+
+/// An entry of a dynamic symbol table
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Symbol {
+    name: String,
+    binding: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+impl Symbol {
+    fn is_defined(&self) -> bool {
+        self.section != SHN_UNDEF
+    }
+}
+
+fn symbols(image: &ElfImage, info: &DynamicInfo) -> Result<Vec<Symbol>, ElfLoadError> {
+    (0..info.symbol_count(image)? as u64)
+        .map(|index| {
+            let entry = image
+                .bytes(entry_address(info.symtab, index, SYM_SIZE)?, SYM_SIZE as usize)
+                .ok_or(ElfLoadError::InvalidFormat)?;
+            let field = |range: core::ops::Range<usize>| {
+                let mut bytes = [0u8; 8];
+                bytes[..range.len()].copy_from_slice(&entry[range]);
+                u64::from_le_bytes(bytes)
+            };
+            Ok(Symbol {
+                name: info.string(image, field(0..4))?,
+                binding: entry[4] >> 4,
+                section: field(6..8) as u16,
+                value: field(8..16),
+                size: field(16..24),
+            })
+        })
+        .collect()
+}
+
+/// Names of the shared libraries an object needs
+pub fn needed(image: &ElfImage) -> Result<Vec<String>, ElfLoadError> {
+    match DynamicInfo::parse(image)? {
+        Some(info) => info.needed.iter().map(|&offset| info.string(image, offset)).collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The symbols each object defines for others, with their final
+/// addresses and sizes
+type Exports = BTreeMap<String, (u64, u64)>;
+
+fn exports(image: &ElfImage, symbols: &[Symbol]) -> Exports {
+    let mut exports = Exports::new();
+    for symbol in symbols {
+        if symbol.is_defined() && matches!(symbol.binding, STB_GLOBAL | STB_WEAK) && !symbol.name.is_empty() {
+            if let Some(address) = image.base.checked_add(symbol.value) {
+                exports.entry(symbol.name.clone()).or_insert((address, symbol.size));
+            }
+        }
+    }
+    exports
+}
+
+/// Address of a symbol used by object `index`
+///
+/// # Returns
+/// The address and size of the definition, `(0, 0)` for an undefined weak
+/// symbol
+fn resolve(objects: &[ElfImage], scope: &[Exports], index: usize, symbol: &Symbol, copy: bool) -> Result<(u64, u64), ElfLoadError> {
+    let own = || Ok((entry_address(objects[index].base, symbol.value, 1)?, symbol.size));
+    if symbol.binding == STB_LOCAL && symbol.is_defined() {
+        return own();
+    }
+    // A copy relocation fills the executable's copy from the library's
+    // definition, so it skips the executable itself
+    let found = scope
+        .iter()
+        .enumerate()
+        .filter(|&(object, _)| !(copy && object == index))
+        .find_map(|(_, exports)| exports.get(&symbol.name).copied());
+    match found {
+        Some(definition) => Ok(definition),
+        None if symbol.is_defined() && !copy => own(),
+        None if symbol.binding == STB_WEAK => Ok((0, 0)),
+        None => {
+            #[cfg(not(test))]
+            crate::log_warn!(target: "elf", "Undefined symbol {}", symbol.name);
+            Err(ElfLoadError::UndefinedSymbol)
+        }
+    }
+}
+
+/// Bytes at a final address in any of the objects
+fn read_at(objects: &[ElfImage], address: u64, len: usize) -> Option<&[u8]> {
+    objects
+        .iter()
+        .find(|object| (object.start_address()..object.end_address()).contains(&address))
+        .and_then(|object| object.bytes(address - object.base, len))
+}
+
+/// Work out the writes for the relocations of object `index`
+fn relocate(objects: &[ElfImage], scope: &[Exports], index: usize, info: &DynamicInfo, symbols: &[Symbol]) -> Result<Vec<(u64, Vec<u8>)>, ElfLoadError> {
+    use relocations::*;
+
+    let object = &objects[index];
+    let mut writes = Vec::new();
+    for (table, size) in [(info.rela, info.relasz), (info.jmprel, info.pltrelsz)] {
+        for entry in 0..size / RELA_SIZE {
+            let address = entry_address(table, entry, RELA_SIZE)?;
+            let read = |field| object.read_u64(entry_address(address, field, 8)?).ok_or(ElfLoadError::InvalidFormat);
+            let offset = read(0)?;
+            let r_info = read(1)?;
+            let addend = read(2)? as i64;
+            let kind = r_info as u32;
+            let symbol = match (r_info >> 32) as usize {
+                0 => None,
+                symbol_index => Some(symbols.get(symbol_index).ok_or(ElfLoadError::InvalidFormat)?),
+            };
+            let copy = kind == R_X86_64_COPY;
+            let (value, symbol_size) = match symbol {
+                Some(symbol) => resolve(objects, scope, index, symbol, copy)?,
+                None => (0, 0),
+            };
+            let place = entry_address(object.base, offset, 1)?;
+            let sum = value.wrapping_add_signed(addend);
+
+            let bytes = match kind {
+                R_X86_64_NONE => continue,
+                R_X86_64_64 => sum.to_le_bytes().to_vec(),
+                R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => value.to_le_bytes().to_vec(),
+                R_X86_64_RELATIVE => object.base.wrapping_add_signed(addend).to_le_bytes().to_vec(),
+                R_X86_64_PC32 => {
+                    let relative = sum.wrapping_sub(place) as i64;
+                    i32::try_from(relative).map_err(|_| ElfLoadError::InvalidAddress)?.to_le_bytes().to_vec()
+                }
+                R_X86_64_32 => u32::try_from(sum).map_err(|_| ElfLoadError::InvalidAddress)?.to_le_bytes().to_vec(),
+                R_X86_64_32S => i32::try_from(sum as i64).map_err(|_| ElfLoadError::InvalidAddress)?.to_le_bytes().to_vec(),
+                R_X86_64_COPY => {
+                    let len = symbol.map_or(0, |symbol| symbol.size.min(symbol_size)) as usize;
+                    read_at(objects, value, len).ok_or(ElfLoadError::InvalidAddress)?.to_vec()
+                }
+                _ => {
+                    #[cfg(not(test))]
+                    crate::log_warn!(target: "elf", "Unsupported relocation type {}", kind);
+                    return Err(ElfLoadError::UnsupportedRelocation);
+                }
+            };
+            writes.push((offset, bytes));
+        }
+    }
+    Ok(writes)
+}
+
+/// Apply the relocations of loaded objects, the executable first
+///
+/// Libraries are relocated before the objects that need them, last
+/// loaded first, so copy relocations copy data that is already relocated.
+pub fn link(objects: &mut [ElfImage]) -> Result<(), ElfLoadError> {
+    let mut tables = Vec::with_capacity(objects.len());
+    for object in objects.iter() {
+        let table = match DynamicInfo::parse(object)? {
+            Some(info) => {
+                if info.has_rel {
+                    return Err(ElfLoadError::UnsupportedRelocation);
+                }
+                let symbols = symbols(object, &info)?;
+                Some((info, symbols))
+            }
+            None => None,
+        };
+        tables.push(table);
+    }
+    let scope: Vec<Exports> = objects
+        .iter()
+        .zip(&tables)
+        .map(|(object, table)| table.as_ref().map_or_else(Exports::new, |(_, symbols)| exports(object, symbols)))
+        .collect();
+
+    for index in (0..objects.len()).rev() {
+        let Some((info, symbols)) = &tables[index] else {
+            continue;
+        };
+        let writes = relocate(objects, &scope, index, info, symbols)?;
+        for (offset, bytes) in writes {
+            objects[index]
+                .bytes_mut(offset, bytes.len())
+                .ok_or(ElfLoadError::InvalidAddress)?
+                .copy_from_slice(&bytes);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use relocations::*;
+    use tags::*;
+
+    /// An object whose tables are written straight into its memory
+    struct Builder {
+        image: ElfImage,
+    }
+
+    impl Builder {
+        fn new(name: &str, base: u64, start: u64) -> Self {
+            Self {
+                image: ElfImage {
+                    name: String::from(name),
+                    base,
+                    start,
+                    memory: alloc::vec![0; 0x1000],
+                    segments: Vec::new(),
+                    entry: 0,
+                    dynamic: None,
+                    interp: None,
+                    phdr: None,
+                    phent: 56,
+                    phnum: 0,
+                    tls: None,
+                },
+            }
+        }
+
+        fn put(&mut self, vaddr: u64, bytes: &[u8]) {
+            self.image.bytes_mut(vaddr, bytes.len()).unwrap().copy_from_slice(bytes);
+        }
+
+        fn put_u64s(&mut self, vaddr: u64, values: &[u64]) {
+            for (i, value) in values.iter().enumerate() {
+                self.put(vaddr + i as u64 * 8, &value.to_le_bytes());
+            }
+        }
+
+        /// Symbols as (name offset, binding, defined, value, size)
+        fn symbols(&mut self, vaddr: u64, symbols: &[(u32, u8, bool, u64, u64)]) {
+            for (i, &(name, binding, defined, value, size)) in symbols.iter().enumerate() {
+                let mut entry = [0u8; 24];
+                entry[0..4].copy_from_slice(&name.to_le_bytes());
+                entry[4] = binding << 4;
+                entry[6..8].copy_from_slice(&u16::from(defined).to_le_bytes());
+                entry[8..16].copy_from_slice(&value.to_le_bytes());
+                entry[16..24].copy_from_slice(&size.to_le_bytes());
+                self.put(vaddr + i as u64 * 24, &entry);
+            }
+        }
+
+        /// Relocations as (offset, symbol, type, addend)
+        fn relocations(&mut self, vaddr: u64, relocations: &[(u64, u64, u32, i64)]) {
+            for (i, &(offset, symbol, kind, addend)) in relocations.iter().enumerate() {
+                self.put_u64s(vaddr + i as u64 * 24, &[offset, symbol << 32 | u64::from(kind), addend as u64]);
+            }
+        }
+
+        fn dynamic(&mut self, vaddr: u64, entries: &[(u64, u64)]) {
+            let mut values = Vec::new();
+            for &(tag, value) in entries.iter().chain(&[(DT_NULL, 0)]) {
+                values.extend([tag, value]);
+            }
+            self.put_u64s(vaddr, &values);
+            self.image.dynamic = Some(vaddr);
+        }
+    }
+
+    /// `libfoo.so` at 0x10000, defining `foo` and `counter` and using
+    /// `counter`
+    fn library() -> ElfImage {
+        let mut lib = Builder::new("libfoo.so", 0x10000, 0);
+        lib.put(0x100, b"\0foo\0counter\0");
+        lib.symbols(0x200, &[(0, 0, false, 0, 0), (1, STB_GLOBAL, true, 0x800, 0), (5, STB_GLOBAL, true, 0x900, 8)]);
+        // DT_HASH with one bucket and three chain entries
+        lib.put(0x300, &[1, 0, 0, 0, 3, 0, 0, 0]);
+        lib.relocations(0x400, &[(0xa00, 0, R_X86_64_RELATIVE, 0x800), (0xa08, 2, R_X86_64_GLOB_DAT, 0)]);
+        lib.put_u64s(0x900, &[42]);
+        lib.dynamic(
+            0x600,
+            &[
+                (DT_STRTAB, 0x100),
+                (DT_STRSZ, 13),
+                (DT_SYMTAB, 0x200),
+                (DT_HASH, 0x300),
+                (DT_RELA, 0x400),
+                (DT_RELASZ, 48),
+            ],
+        );
+        lib.image
+    }
+
+    /// An executable at 0x400000 calling `foo` and copying `counter`
+    fn executable() -> ElfImage {
+        let mut exe = Builder::new("", 0, 0x400000);
+        exe.put(0x400100, b"\0libfoo.so\0foo\0counter\0");
+        exe.symbols(
+            0x400200,
+            &[(0, 0, false, 0, 0), (11, STB_GLOBAL, false, 0, 0), (15, STB_GLOBAL, true, 0x400900, 8)],
+        );
+        exe.put(0x400300, &[1, 0, 0, 0, 3, 0, 0, 0]);
+        exe.relocations(
+            0x400400,
+            &[(0x400a00, 1, R_X86_64_GLOB_DAT, 0), (0x400a08, 2, R_X86_64_64, 4), (0x400900, 2, R_X86_64_COPY, 0)],
+        );
+        exe.relocations(0x400480, &[(0x400a10, 1, R_X86_64_JUMP_SLOT, 0)]);
+        exe.dynamic(
+            0x400600,
+            &[
+                (DT_NEEDED, 1),
+                (DT_STRTAB, 0x400100),
+                (DT_STRSZ, 23),
+                (DT_SYMTAB, 0x400200),
+                (DT_HASH, 0x400300),
+                (DT_RELA, 0x400400),
+                (DT_RELASZ, 72),
+                (DT_JMPREL, 0x400480),
+                (DT_PLTRELSZ, 24),
+                (DT_PLTREL, DT_RELA),
+            ],
+        );
+        exe.image
+    }
+
+    #[test]
+    fn test_needed() {
+        assert_eq!(needed(&executable()).unwrap(), ["libfoo.so"]);
+        assert!(needed(&library()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_link() {
+        let mut objects = [executable(), library()];
+        link(&mut objects).unwrap();
+        let [exe, lib] = &objects;
+
+        assert_eq!(exe.read_u64(0x400a00), Some(0x10800));
+        assert_eq!(exe.read_u64(0x400a10), Some(0x10800));
+        // The copy got the library's data, and references go to the copy
+        assert_eq!(exe.read_u64(0x400900), Some(42));
+        assert_eq!(exe.read_u64(0x400a08), Some(0x400904));
+        assert_eq!(lib.read_u64(0xa08), Some(0x400900));
+        assert_eq!(lib.read_u64(0xa00), Some(0x10800));
+    }
+
+    #[test]
+    fn test_undefined_symbol() {
+        let mut objects = [executable()];
+        assert_eq!(link(&mut objects), Err(ElfLoadError::UndefinedSymbol));
+    }
+
+    #[test]
+    fn test_gnu_hash_symbol_count() {
+        let mut object = Builder::new("", 0, 0);
+        // Two buckets, symbols from 1, one bloom word; the highest bucket
+        // starts at symbol 2 and its chain ends at symbol 3
+        object.put(0x100, &[2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        object.put(0x118, &[1, 0, 0, 0, 2, 0, 0, 0]);
+        object.put(0x120, &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        let info = DynamicInfo {
+            gnu_hash: Some(0x100),
+            ..DynamicInfo::default()
+        };
+        assert_eq!(info.symbol_count(&object.image), Ok(4));
+    }
+
+    #[test]
+    fn test_tables_past_the_address_space() {
+        // An object in the last page, whose symbol and relocation tables
+        // have one entry there and the next past the end
+        let start = u64::MAX - 0xfff;
+        let mut object = Builder::new("", 0, start);
+        object.put(start + 0x300, &[1, 0, 0, 0, 3, 0, 0, 0]);
+        let info = DynamicInfo {
+            strtab: start + 0x100,
+            strsz: 16,
+            symtab: u64::MAX - 23,
+            hash: Some(start + 0x300),
+            rela: u64::MAX - 23,
+            relasz: 48,
+            ..DynamicInfo::default()
+        };
+        assert_eq!(symbols(&object.image, &info), Err(ElfLoadError::InvalidFormat));
+        let objects = [object.image];
+        assert_eq!(relocate(&objects, &[Exports::new()], 0, &info, &[]), Err(ElfLoadError::InvalidFormat));
+
+        let info = DynamicInfo { strtab: u64::MAX, ..info };
+        assert_eq!(info.string(&objects[0], 1), Err(ElfLoadError::InvalidFormat));
+    }
+}
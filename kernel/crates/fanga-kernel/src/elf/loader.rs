@@ -1,11 +1,37 @@
 //! ELF Binary Loader
 //!
 //! This module loads ELF binaries into memory and prepares them for execution.
+//!
+//! `load_program` lays out an executable and the shared libraries it needs
+//! (its `DT_NEEDED` entries, found through a callback) as `ElfImage`s, then
+//! links them with `dynamic::link`. An ET_DYN (position independent)
+//! executable is placed at the executable base of its `LoadBases`, and
+//! libraries one after another from the library base, each followed by an
+//! unmapped guard page. The caller picks the bases, randomized or not.
 
-use super::parser::{ElfHeader, ElfProgramHeader, ProgramType};
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::dynamic;
+use super::parser::{ElfHeader, ElfProgramHeader, ElfType, ProgramType};
 use crate::memory::{VirtAddr, PhysAddr};
+use crate::task::TlsTemplate;
 use core::mem::size_of;
 
+/// Page size segments are laid out with
+const PAGE_SIZE: u64 = 4096;
+
+/// Where an ET_DYN executable is placed without randomization
+pub const PIE_BASE: u64 = 0x5555_5555_4000;
+
+/// Where the first shared library is placed without randomization
+pub const LIBRARY_BASE: u64 = 0x7f00_0000_0000;
+
+/// Largest span of memory one object may cover (1 GiB)
+const MAX_IMAGE_SIZE: u64 = 1 << 30;
+
+/// Most objects one program may load, counting the executable
+const MAX_OBJECTS: usize = 64;
+
 /// Error type for ELF loading
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ElfLoadError {
@@ -19,6 +45,27 @@ pub enum ElfLoadError {
     InvalidAddress,
     /// Failed to map memory
     MappingFailed,
+    /// A `DT_NEEDED` shared library was not found
+    MissingLibrary,
+    /// A symbol needed by a relocation is defined nowhere
+    UndefinedSymbol,
+    /// A relocation type the linker does not handle
+    UnsupportedRelocation,
+}
+
+impl ElfLoadError {
+    /// Message for the error
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ElfLoadError::InvalidFormat => "Not an ELF executable",
+            ElfLoadError::UnsupportedType => "Unsupported ELF executable",
+            ElfLoadError::OutOfMemory => "Out of memory",
+            ElfLoadError::InvalidAddress | ElfLoadError::MappingFailed => "Cannot load program",
+            ElfLoadError::MissingLibrary => "Shared library not found",
+            ElfLoadError::UndefinedSymbol => "Undefined symbol",
+            ElfLoadError::UnsupportedRelocation => "Unsupported relocation",
+        }
+    }
 }
 
 /// Information about a loaded ELF binary
@@ -34,6 +81,263 @@ pub struct LoadedElf {
     pub interp: Option<VirtAddr>,
 }
 
+/// A PT_LOAD segment at its final address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSegment {
+    pub vaddr: u64,
+    pub memsz: u64,
+    /// `program_flags` bits
+    pub flags: u32,
+}
+
+/// An ELF object laid out as it will be in memory
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+    /// Name it was loaded as: empty for the executable, the `DT_NEEDED`
+    /// name for a library
+    pub name: String,
+    /// Added to every address in the object; 0 for ET_EXEC
+    pub base: u64,
+    /// Lowest page of the PT_LOAD segments, before `base` is added
+    pub start: u64,
+    /// Contents of the pages from `start`; zero past the file data
+    pub memory: Vec<u8>,
+    /// PT_LOAD segments at their final addresses
+    pub segments: Vec<ImageSegment>,
+    /// Entry point at its final address
+    pub entry: u64,
+    /// Address of the PT_DYNAMIC array before `base` is added
+    pub dynamic: Option<u64>,
+    /// Path of the PT_INTERP interpreter
+    pub interp: Option<String>,
+    /// Address of the program headers in memory, if a segment maps them
+    pub phdr: Option<u64>,
+    /// Size of one program header
+    pub phent: u16,
+    /// Number of program headers
+    pub phnum: u16,
+    /// Initial thread-local storage from PT_TLS
+    pub tls: Option<TlsTemplate>,
+}
+
+/// The program headers of an ELF file
+fn program_headers(header: &ElfHeader, data: &[u8]) -> Result<Vec<ElfProgramHeader>, ElfLoadError> {
+    let phoff = header.e_phoff as usize;
+    let phentsize = header.e_phentsize as usize;
+    (0..header.e_phnum as usize)
+        .map(|i| {
+            let offset = phoff.checked_add(i * phentsize).ok_or(ElfLoadError::InvalidFormat)?;
+            let ph_data = data
+                .get(offset..offset + size_of::<ElfProgramHeader>())
+                .ok_or(ElfLoadError::InvalidFormat)?;
+            ElfProgramHeader::parse(ph_data).map_err(|_| ElfLoadError::InvalidFormat)
+        })
+        .collect()
+}
+
+impl ElfImage {
+    /// Lay out the PT_LOAD segments of an ELF file with `base` added to
+    /// their addresses
+    pub fn load(name: &str, data: &[u8], base: u64) -> Result<Self, ElfLoadError> {
+        let header = ElfHeader::parse(data).map_err(|_| ElfLoadError::InvalidFormat)?;
+        header.validate().map_err(|_| ElfLoadError::UnsupportedType)?;
+        let phdrs = program_headers(&header, data)?;
+
+        let loads = || phdrs.iter().filter(|phdr| phdr.program_type() == Some(ProgramType::Load));
+        let start = loads().map(|phdr| phdr.p_vaddr).min().ok_or(ElfLoadError::InvalidFormat)? & !(PAGE_SIZE - 1);
+        let end = loads()
+            .map(|phdr| phdr.p_vaddr.checked_add(phdr.p_memsz))
+            .try_fold(0u64, |end, seg_end| seg_end.map(|seg_end| end.max(seg_end)))
+            .ok_or(ElfLoadError::InvalidAddress)?
+            .div_ceil(PAGE_SIZE)
+            * PAGE_SIZE;
+        if end - start > MAX_IMAGE_SIZE {
+            return Err(ElfLoadError::OutOfMemory);
+        }
+
+        let mut image = Self {
+            name: String::from(name),
+            base,
+            start,
+            memory: alloc::vec![0; (end - start) as usize],
+            segments: Vec::new(),
+            entry: base.wrapping_add(header.e_entry),
+            dynamic: None,
+            interp: None,
+            phdr: None,
+            phent: header.e_phentsize,
+            phnum: header.e_phnum,
+            tls: None,
+        };
+        for phdr in &phdrs {
+            match phdr.program_type() {
+                Some(ProgramType::Load) => {
+                    load_segment(phdr, data)?;
+                    let file = &data[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
+                    let offset = (phdr.p_vaddr - start) as usize;
+                    image.memory[offset..offset + file.len()].copy_from_slice(file);
+                    image.segments.push(ImageSegment {
+                        vaddr: base + phdr.p_vaddr,
+                        memsz: phdr.p_memsz,
+                        flags: phdr.p_flags,
+                    });
+                }
+                Some(ProgramType::Dynamic) => image.dynamic = Some(phdr.p_vaddr),
+                Some(ProgramType::Phdr) => image.phdr = Some(base + phdr.p_vaddr),
+                Some(ProgramType::Tls) => {
+                    image.tls = Some(TlsTemplate::from_elf(data, phdr).map_err(|_| ElfLoadError::InvalidFormat)?);
+                }
+                Some(ProgramType::Interp) => {
+                    let path = data
+                        .get(phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize)
+                        .ok_or(ElfLoadError::InvalidFormat)?;
+                    let path = path.split(|&b| b == 0).next().unwrap_or(&[]);
+                    image.interp = Some(String::from_utf8_lossy(path).into_owned());
+                }
+                _ => {}
+            }
+        }
+        // Without PT_PHDR, the headers are mapped if a segment covers them
+        if image.phdr.is_none() {
+            image.phdr = loads()
+                .find(|phdr| phdr.p_offset <= header.e_phoff && header.e_phoff < phdr.p_offset + phdr.p_filesz)
+                .map(|phdr| base + phdr.p_vaddr + (header.e_phoff - phdr.p_offset));
+        }
+        Ok(image)
+    }
+
+    /// Move the image so that `base` is added to its addresses
+    fn rebase(&mut self, base: u64) {
+        let delta = base.wrapping_sub(self.base);
+        self.base = base;
+        self.entry = self.entry.wrapping_add(delta);
+        for segment in &mut self.segments {
+            segment.vaddr = segment.vaddr.wrapping_add(delta);
+        }
+        if let Some(phdr) = &mut self.phdr {
+            *phdr = phdr.wrapping_add(delta);
+        }
+    }
+
+    /// Lowest address of the image
+    pub fn start_address(&self) -> u64 {
+        self.base + self.start
+    }
+
+    /// Address just past the image
+    pub fn end_address(&self) -> u64 {
+        self.start_address() + self.memory.len() as u64
+    }
+
+    /// Bytes at `vaddr`, an address before `base` is added
+    pub fn bytes(&self, vaddr: u64, len: usize) -> Option<&[u8]> {
+        let offset = usize::try_from(vaddr.checked_sub(self.start)?).ok()?;
+        self.memory.get(offset..offset.checked_add(len)?)
+    }
+
+    /// Bytes at `vaddr` to change, an address before `base` is added
+    pub fn bytes_mut(&mut self, vaddr: u64, len: usize) -> Option<&mut [u8]> {
+        let offset = usize::try_from(vaddr.checked_sub(self.start)?).ok()?;
+        self.memory.get_mut(offset..offset.checked_add(len)?)
+    }
+
+    /// Little-endian `u64` at `vaddr`, an address before `base` is added
+    pub fn read_u64(&self, vaddr: u64) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(vaddr, 8)?.try_into().ok()?))
+    }
+
+    /// Little-endian `u32` at `vaddr`, an address before `base` is added
+    pub fn read_u32(&self, vaddr: u64) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(vaddr, 4)?.try_into().ok()?))
+    }
+}
+
+/// Where `load_program` places position independent objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadBases {
+    /// Added to the addresses of an ET_DYN executable, page aligned;
+    /// ET_EXEC ones stay where they are linked
+    pub executable: u64,
+    /// Lowest address of the first shared library, page aligned
+    pub libraries: u64,
+}
+
+impl LoadBases {
+    /// The bases without randomization
+    pub const FIXED: Self = Self {
+        executable: PIE_BASE,
+        libraries: LIBRARY_BASE,
+    };
+}
+
+/// An executable and its shared libraries, linked and ready to map
+#[derive(Debug, Clone)]
+pub struct LoadedProgram {
+    /// Entry point of the executable
+    pub entry_point: VirtAddr,
+    /// The executable first, then its libraries in load order
+    pub objects: Vec<ElfImage>,
+    /// The executable's PT_INTERP, which is not needed since the kernel
+    /// links the program itself
+    pub interp: Option<String>,
+}
+
+/// Load an executable and the shared libraries it needs, and link them
+///
+/// # Arguments
+/// * `data` - The executable
+/// * `bases` - Where to place the executable if it is ET_DYN, and the
+///   libraries
+/// * `open_library` - Contents of a shared library by `DT_NEEDED` name
+pub fn load_program(
+    data: &[u8],
+    bases: LoadBases,
+    mut open_library: impl FnMut(&str) -> Option<Vec<u8>>,
+) -> Result<LoadedProgram, ElfLoadError> {
+    let header = ElfHeader::parse(data).map_err(|_| ElfLoadError::InvalidFormat)?;
+    let base = match header.elf_type() {
+        Some(ElfType::Shared) => bases.executable,
+        _ => 0,
+    };
+    let mut objects = alloc::vec![ElfImage::load("", data, base)?];
+    let mut next_base = bases.libraries;
+
+    // Breadth first, so the executable's own libraries come first in the
+    // symbol search order
+    let mut index = 0;
+    while index < objects.len() {
+        for name in dynamic::needed(&objects[index])? {
+            if objects.iter().any(|object| object.name == name) {
+                continue;
+            }
+            if objects.len() == MAX_OBJECTS {
+                return Err(ElfLoadError::OutOfMemory);
+            }
+            let library = open_library(&name).ok_or_else(|| {
+                #[cfg(not(test))]
+                crate::log_warn!(target: "elf", "Shared library {} not found", name);
+                ElfLoadError::MissingLibrary
+            })?;
+            let library_header = ElfHeader::parse(&library).map_err(|_| ElfLoadError::InvalidFormat)?;
+            if library_header.elf_type() != Some(ElfType::Shared) {
+                return Err(ElfLoadError::UnsupportedType);
+            }
+            let mut image = ElfImage::load(&name, &library, 0)?;
+            image.rebase(next_base - image.start);
+            next_base = image.end_address() + PAGE_SIZE;
+            objects.push(image);
+        }
+        index += 1;
+    }
+
+    dynamic::link(&mut objects)?;
+    Ok(LoadedProgram {
+        entry_point: VirtAddr::new(objects[0].entry),
+        interp: objects[0].interp.clone(),
+        objects,
+    })
+}
+
 /// Load an ELF binary from memory
 ///
 /// # Arguments
@@ -138,8 +442,9 @@ fn load_segment(phdr: &ElfProgramHeader, data: &[u8]) -> Result<(), ElfLoadError
 
     #[cfg(not(test))]
     {
-        fanga_arch_x86_64::serial_println!(
-            "[ELF] Loading segment: vaddr={:#x}, filesz={:#x}, memsz={:#x}, flags={:#x}",
+        crate::log_debug!(
+            target: "elf",
+            "Loading segment: vaddr={:#x}, filesz={:#x}, memsz={:#x}, flags={:#x}",
             phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz, phdr.p_flags
         );
     }
@@ -193,6 +498,86 @@ mod tests {
         header
     }
 
+    /// An ELF file with one PT_LOAD segment holding "hello" at 0x400100,
+    /// followed by 0x2000 bytes of bss
+    fn create_test_elf(elf_type: u8) -> Vec<u8> {
+        let mut data = create_test_elf_header().to_vec();
+        data[16] = elf_type;
+        data[24..32].copy_from_slice(&0x400100u64.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[56] = 1;
+        let mut phdr = [0u8; 56];
+        phdr[0..4].copy_from_slice(&1u32.to_le_bytes());
+        phdr[4..8].copy_from_slice(&5u32.to_le_bytes());
+        phdr[8..16].copy_from_slice(&0x100u64.to_le_bytes());
+        phdr[16..24].copy_from_slice(&0x400100u64.to_le_bytes());
+        phdr[32..40].copy_from_slice(&5u64.to_le_bytes());
+        phdr[40..48].copy_from_slice(&0x2000u64.to_le_bytes());
+        data.extend_from_slice(&phdr);
+        data.resize(0x100, 0);
+        data.extend_from_slice(b"hello");
+        data
+    }
+
+    #[test]
+    fn test_elf_image_load() {
+        let image = ElfImage::load("", &create_test_elf(2), 0).unwrap();
+        assert_eq!(image.start_address(), 0x400000);
+        assert_eq!(image.end_address(), 0x403000);
+        assert_eq!(image.bytes(0x400100, 5), Some(&b"hello"[..]));
+        assert!(image.memory[0x105..].iter().all(|&b| b == 0));
+        assert_eq!(image.segments, [ImageSegment { vaddr: 0x400100, memsz: 0x2000, flags: 5 }]);
+        assert_eq!(image.entry, 0x400100);
+        assert!(image.dynamic.is_none());
+        // The segment starts past the program headers
+        assert_eq!(image.phdr, None);
+        assert_eq!((image.phent, image.phnum), (56, 1));
+    }
+
+    #[test]
+    fn test_elf_image_phdr() {
+        // A segment from file offset 0 maps the headers after the ELF header
+        let mut data = create_test_elf(3);
+        data[64 + 8..64 + 16].copy_from_slice(&0u64.to_le_bytes());
+        data[64 + 16..64 + 24].copy_from_slice(&0x400000u64.to_le_bytes());
+        data[64 + 32..64 + 40].copy_from_slice(&0x105u64.to_le_bytes());
+        let image = ElfImage::load("", &data, PIE_BASE).unwrap();
+        assert_eq!(image.phdr, Some(PIE_BASE + 0x400040));
+    }
+
+    #[test]
+    fn test_elf_image_tls() {
+        // A PT_TLS header with 2 bytes of .tdata ("he") and 16 in all
+        let mut data = create_test_elf(2);
+        data[56] = 2;
+        let mut phdr = [0u8; 56];
+        phdr[0..4].copy_from_slice(&7u32.to_le_bytes());
+        phdr[8..16].copy_from_slice(&0x100u64.to_le_bytes());
+        phdr[16..24].copy_from_slice(&0x400100u64.to_le_bytes());
+        phdr[32..40].copy_from_slice(&2u64.to_le_bytes());
+        phdr[40..48].copy_from_slice(&16u64.to_le_bytes());
+        phdr[48..56].copy_from_slice(&8u64.to_le_bytes());
+        data[120..176].copy_from_slice(&phdr);
+
+        let tls = ElfImage::load("", &data, 0).unwrap().tls.unwrap();
+        assert_eq!(tls, TlsTemplate::new(b"he".to_vec(), 16, 8).unwrap());
+    }
+
+    #[test]
+    fn test_load_program_pie() {
+        let program = load_program(&create_test_elf(3), LoadBases::FIXED, |_| None).unwrap();
+        assert_eq!(program.entry_point.as_u64(), PIE_BASE + 0x400100);
+        assert_eq!(program.objects.len(), 1);
+        assert_eq!(program.objects[0].segments[0].vaddr, PIE_BASE + 0x400100);
+
+        let bases = LoadBases { executable: 0x1000_0000_0000, libraries: LIBRARY_BASE };
+        let program = load_program(&create_test_elf(3), bases, |_| None).unwrap();
+        assert_eq!(program.entry_point.as_u64(), 0x1000_0040_0100);
+        // ET_EXEC executables are not moved
+        let program = load_program(&create_test_elf(2), bases, |_| None).unwrap();
+        assert_eq!(program.entry_point.as_u64(), 0x400100);
+    }
+
     #[test]
     fn test_load_elf_invalid_data() {
         let data = [0u8; 32];
@@ -1,10 +1,18 @@
 //! ELF Binary Loader
 //!
 //! This module implements a basic ELF (Executable and Linkable Format) loader
-//! for loading user-space applications into memory.
+//! for loading user-space applications into memory, an in-kernel dynamic
+//! linker for programs that use shared libraries, and a writer for ELF
+//! core files.
 
 mod parser;
 mod loader;
+pub mod dynamic;
+pub mod corefile;
 
-pub use parser::{ElfHeader, ElfProgramHeader, ElfType, ElfMachine, ProgramType};
-pub use loader::{load_elf, ElfLoadError, LoadedElf};
+pub use parser::{program_flags, ElfHeader, ElfProgramHeader, ElfType, ElfMachine, ProgramType};
+pub use loader::{
+    load_elf, load_program, ElfImage, ElfLoadError, ImageSegment, LoadBases, LoadedElf, LoadedProgram, LIBRARY_BASE,
+    PIE_BASE,
+};
+pub use corefile::write_core;
@@ -0,0 +1,407 @@
+//! Initial RAM File System
+//!
+//! Unpacks a cpio archive in the "newc" format (what `cpio -H newc` and the
+//! Linux kernel's `gen_init_cpio` write) or a POSIX ustar archive (what
+//! `tar --format=ustar` writes) into a file system. The boot loader hands
+//! the archive over as a module, and its files, `/init` among them, become
+//! the start of the root file system.
+//!
+//! Directories and regular files are created; other entries (symbolic
+//! links, device nodes) are skipped, as the memory file system has no
+//! place for them.
+
+use super::{create_dir_all_in, write_file_in, FileSystem, FsError};
+use alloc::borrow::Cow;
+use alloc::string::String;
+
+/// Magic of a newc header, and of one with checksums
+const MAGIC: &[u8; 6] = b"070701";
+const MAGIC_CRC: &[u8; 6] = b"070702";
+
+/// Size of a newc header: the magic and 13 eight-digit hex fields
+const HEADER_SIZE: usize = 110;
+
+/// Name of the entry that ends the archive
+const TRAILER: &str = "TRAILER!!!";
+
+/// Size of a tar header and of the blocks file data is padded to
+const TAR_BLOCK: usize = 512;
+
+/// Magic of a POSIX ustar header, and of a GNU one
+const TAR_MAGIC: &[u8; 6] = b"ustar\0";
+const TAR_MAGIC_GNU: &[u8; 6] = b"ustar ";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// An entry of an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Path within the archive, without a leading `/` or `./`
+    pub name: Cow<'a, str>,
+    /// Type and permission bits
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+/// Archive formats an initramfs can be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// cpio "newc"
+    Cpio,
+    /// POSIX ustar
+    Tar,
+}
+
+/// What `unpack` did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnpackStats {
+    pub files: usize,
+    pub directories: usize,
+    /// Entries of types that cannot be created
+    pub skipped: usize,
+    /// Bytes of file data written
+    pub bytes: usize,
+}
+
+/// Whether `data` starts like a newc archive
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(MAGIC_CRC)
+}
+
+/// Whether `data` starts like a ustar archive
+pub fn is_tar(data: &[u8]) -> bool {
+    data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 6)
+        .is_some_and(|magic| magic == TAR_MAGIC || magic == TAR_MAGIC_GNU)
+}
+
+/// The format of archive `data`, if it is one
+pub fn format(data: &[u8]) -> Option<Format> {
+    if is_cpio(data) {
+        Some(Format::Cpio)
+    } else if is_tar(data) {
+        Some(Format::Tar)
+    } else {
+        None
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// A path within an archive without its leading `/` or `./`
+fn trim_path(name: &str) -> &str {
+    name.trim_start_matches("./").trim_start_matches('/')
+}
+
+/// A NUL-padded tar string field
+fn tar_string(field: &[u8]) -> Result<&str, &'static str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).map_err(|_| "Bad tar file name")
+}
+
+/// A tar octal number field, padded with spaces or NULs
+fn tar_octal(field: &[u8]) -> Result<u32, &'static str> {
+    let digits = tar_string(field).map_err(|_| "Bad tar header")?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u32::from_str_radix(digits, 8).map_err(|_| "Bad tar header")
+}
+
+/// The entries of an archive, up to its end
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    format: Option<Format>,
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0, format: format(data) }
+    }
+
+    fn field(header: &[u8], index: usize) -> Result<u32, &'static str> {
+        let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+        let digits = core::str::from_utf8(digits).map_err(|_| "Bad cpio header")?;
+        u32::from_str_radix(digits, 16).map_err(|_| "Bad cpio header")
+    }
+
+    fn parse_cpio(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or("Truncated cpio archive")?;
+        if !is_cpio(header) {
+            return Err("Bad cpio magic");
+        }
+        let mode = Self::field(header, 1)?;
+        let file_size = Self::field(header, 6)? as usize;
+        let name_size = Self::field(header, 11)? as usize;
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self
+            .data
+            .get(name_start..name_start + name_size)
+            .ok_or("Truncated cpio archive")?;
+        // The size counts the terminating NUL
+        let name = name.strip_suffix(&[0]).ok_or("Bad cpio file name")?;
+        let name = core::str::from_utf8(name).map_err(|_| "Bad cpio file name")?;
+
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .data
+            .get(data_start..data_start + file_size)
+            .ok_or("Truncated cpio archive")?;
+        self.offset = align4(data_start + file_size);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name: Cow::Borrowed(trim_path(name)), mode, data }))
+    }
+
+    fn parse_tar(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + TAR_BLOCK)
+            .ok_or("Truncated tar archive")?;
+        // A zero block ends the archive
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        if !is_tar(header) {
+            return Err("Bad tar magic");
+        }
+        // The checksum is taken with its own field as spaces
+        let checksum = tar_octal(&header[148..156])?;
+        let sum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u32 } else { b as u32 })
+            .sum();
+        if sum != checksum {
+            return Err("Bad tar checksum");
+        }
+
+        let perm = tar_octal(&header[100..108])? & 0o7777;
+        let size = tar_octal(&header[124..136])? as usize;
+        let mode = match header[156] {
+            b'0' | 0 => S_IFREG | perm,
+            b'5' => S_IFDIR | perm,
+            // Links, devices and FIFOs
+            _ => perm,
+        };
+        let name = tar_string(&header[..100])?.trim_end_matches('/');
+        // Long paths are split into a prefix and a name
+        let prefix = tar_string(&header[345..500])?;
+
+        let data_start = self.offset + TAR_BLOCK;
+        let data = self
+            .data
+            .get(data_start..data_start + size)
+            .ok_or("Truncated tar archive")?;
+        self.offset = data_start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+        let name = if prefix.is_empty() {
+            Cow::Borrowed(trim_path(name))
+        } else {
+            let mut path = String::from(trim_path(prefix).trim_end_matches('/'));
+            path.push('/');
+            path.push_str(name);
+            Cow::Owned(path)
+        };
+        Ok(Some(Entry { name, mode, data }))
+    }
+
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        match self.format {
+            Some(Format::Cpio) => self.parse_cpio(),
+            Some(Format::Tar) => self.parse_tar(),
+            None => Err("Unknown archive format"),
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.offset = self.data.len();
+                None
+            }
+            Err(e) => {
+                self.offset = self.data.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Unpack a cpio or tar archive into `fs` under `root`
+///
+/// Existing files are replaced; missing parent directories are created.
+pub fn unpack_in(fs: &mut dyn FileSystem, root: &str, data: &[u8]) -> Result<UnpackStats, &'static str> {
+    let mut stats = UnpackStats::default();
+    for entry in Entries::new(data) {
+        let entry = entry?;
+        // "." is the root itself
+        if entry.name.is_empty() || entry.name == "." {
+            continue;
+        }
+        let mut path = String::from(root.trim_end_matches('/'));
+        path.push('/');
+        path.push_str(&entry.name);
+
+        if entry.is_dir() {
+            create_dir_all_in(fs, &path).map_err(FsError::as_str)?;
+            stats.directories += 1;
+        } else if entry.is_file() {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            create_dir_all_in(fs, parent).map_err(FsError::as_str)?;
+            write_file_in(fs, &path, entry.data).map_err(FsError::as_str)?;
+            stats.files += 1;
+            stats.bytes += entry.data.len();
+        } else {
+            stats.skipped += 1;
+        }
+    }
+    Ok(stats)
+}
+
+/// Unpack a cpio or tar archive onto the root file system
+pub fn unpack(data: &[u8]) -> Result<UnpackStats, &'static str> {
+    let mut fs = super::try_root_fs().ok_or("No root file system")?.lock();
+    unpack_in(&mut *fs, "/", data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{read_file_in, MemoryFileSystem, VNodeType};
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    /// Append a newc entry
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            0, mode, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, ".", S_IFDIR | 0o755, &[]);
+        push_entry(&mut archive, "bin", S_IFDIR | 0o755, &[]);
+        push_entry(&mut archive, "init", S_IFREG | 0o755, b"\x7fELF init");
+        push_entry(&mut archive, "etc/motd", S_IFREG | 0o644, b"hello\n");
+        push_entry(&mut archive, "bin/sh", 0o120777, b"/init");
+        push_entry(&mut archive, TRAILER, 0, &[]);
+        archive
+    }
+
+    #[test]
+    fn test_entries() {
+        let archive = archive();
+        assert!(is_cpio(&archive));
+        let entries: Vec<Entry> = Entries::new(&archive).map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 5);
+        assert!(entries[1].is_dir());
+        assert_eq!(entries[2].name, "init");
+        assert_eq!(entries[2].data, b"\x7fELF init");
+        assert_eq!(entries[3].name, "etc/motd");
+
+        let truncated = &archive[..archive.len() / 2];
+        assert!(Entries::new(truncated).any(|entry| entry.is_err()));
+    }
+
+    /// Append a ustar entry of type `kind`
+    fn push_tar_entry(archive: &mut Vec<u8>, prefix: &str, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000755");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[148..156].fill(b' ');
+        header[156] = kind;
+        header[257..263].copy_from_slice(TAR_MAGIC);
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(TAR_BLOCK) * TAR_BLOCK, 0);
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let mut archive = Vec::new();
+        push_tar_entry(&mut archive, "", "./bin/", b'5', &[]);
+        push_tar_entry(&mut archive, "", "./init", b'0', b"\x7fELF init");
+        push_tar_entry(&mut archive, "usr/share", "fonts/ter.psf", 0, &[0x72; 600]);
+        push_tar_entry(&mut archive, "", "bin/sh", b'2', &[]);
+        archive.resize(archive.len() + 2 * TAR_BLOCK, 0);
+
+        assert!(is_tar(&archive));
+        assert_eq!(format(&archive), Some(Format::Tar));
+        let entries: Vec<Entry> = Entries::new(&archive).map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].name, "bin");
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[1].name, "init");
+        assert_eq!(entries[1].data, b"\x7fELF init");
+        assert_eq!(entries[2].name, "usr/share/fonts/ter.psf");
+        assert_eq!(entries[2].data.len(), 600);
+        assert!(!entries[3].is_file() && !entries[3].is_dir());
+
+        let mut fs = MemoryFileSystem::new();
+        let stats = unpack_in(&mut fs, "/", &archive).unwrap();
+        assert_eq!((stats.files, stats.directories, stats.skipped), (2, 1, 1));
+        assert_eq!(read_file_in(&fs, "/usr/share/fonts/ter.psf").unwrap().len(), 600);
+
+        // A corrupted header fails its checksum
+        archive[0] = b'X';
+        assert!(Entries::new(&archive).next().unwrap().is_err());
+        assert!(unpack_in(&mut fs, "/", b"not an archive").is_err());
+    }
+
+    #[test]
+    fn test_unpack() {
+        let mut fs = MemoryFileSystem::new();
+        let stats = unpack_in(&mut fs, "/", &archive()).unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.directories, 1);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(read_file_in(&fs, "/init").unwrap(), b"\x7fELF init");
+        assert_eq!(read_file_in(&fs, "/etc/motd").unwrap(), b"hello\n");
+        assert_eq!(fs.lookup("/bin").unwrap().vtype, VNodeType::Directory);
+        assert!(fs.lookup("/bin/sh").is_err());
+    }
+}
@@ -1,6 +1,8 @@
 //! In-Memory File System
 //!
 //! This module provides a simple RAM-based file system implementation.
+//! Besides files and directories it holds character device nodes, which
+//! pass reads and writes on to a device from the `chardev` registry.
 
 extern crate alloc;
 use alloc::collections::BTreeMap;
@@ -8,20 +10,50 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use spin::RwLock;
 
-use super::vfs::{FileSystem, VNode, VNodeType, VNodeAttr, DirEntry, FsError};
+use super::vfs::{FileSystem, FsStats, VNode, VNodeType, VNodeAttr, DirEntry, FsError};
 use super::path::PathResolver;
+use crate::io::chardev::{self, CharDevice};
+
+/// Block size reported by `statfs`
+const MEMFS_BLOCK_SIZE: u64 = 4096;
+
+/// Current wall-clock time for timestamps
+fn now() -> u64 {
+    crate::task::realtime::now_secs()
+}
+
+/// Creation and modification times of a node
+#[derive(Debug, Clone, Copy)]
+struct Timestamps {
+    created: u64,
+    modified: u64,
+}
+
+impl Timestamps {
+    /// Timestamps of a node created now
+    fn new() -> Self {
+        let now = now();
+        Self { created: now, modified: now }
+    }
+
+    /// Record a modification
+    fn touch(&mut self) {
+        self.modified = now();
+    }
+}
 
 /// In-memory file data
 #[derive(Debug, Clone)]
 struct MemFile {
     /// File content
     data: Vec<u8>,
+    times: Timestamps,
 }
 
 impl MemFile {
     /// Create a new empty file
     fn new() -> Self {
-        Self { data: Vec::new() }
+        Self { data: Vec::new(), times: Timestamps::new() }
     }
     
     /// Read from the file
@@ -45,6 +77,7 @@ impl MemFile {
         }
         
         self.data[offset..offset + buffer.len()].copy_from_slice(buffer);
+        self.times.touch();
         buffer.len()
     }
     
@@ -56,6 +89,7 @@ impl MemFile {
     /// Truncate file to specified size
     fn truncate(&mut self, size: usize) {
         self.data.resize(size, 0);
+        self.times.touch();
     }
 }
 
@@ -64,6 +98,7 @@ impl MemFile {
 struct MemDir {
     /// Directory entries (name -> vnode id)
     entries: BTreeMap<String, u64>,
+    times: Timestamps,
 }
 
 impl MemDir {
@@ -71,6 +106,7 @@ impl MemDir {
     fn new() -> Self {
         Self {
             entries: BTreeMap::new(),
+            times: Timestamps::new(),
         }
     }
     
@@ -80,12 +116,15 @@ impl MemDir {
             return Err(FsError::AlreadyExists);
         }
         self.entries.insert(name, vnode_id);
+        self.times.touch();
         Ok(())
     }
     
     /// Remove an entry from the directory
     fn remove_entry(&mut self, name: &str) -> Result<u64, FsError> {
-        self.entries.remove(name).ok_or(FsError::NotFound)
+        let id = self.entries.remove(name).ok_or(FsError::NotFound)?;
+        self.times.touch();
+        Ok(id)
     }
     
     /// Get an entry's vnode id
@@ -104,11 +143,41 @@ impl MemDir {
     }
 }
 
+/// In-memory character device node
+#[derive(Debug, Clone)]
+struct MemDevice {
+    /// Name the device is registered under
+    device: String,
+    times: Timestamps,
+}
+
+impl MemDevice {
+    /// Create a node for the device registered as `device`
+    fn new(device: &str) -> Self {
+        Self { device: String::from(device), times: Timestamps::new() }
+    }
+}
+
 /// In-memory node
 #[derive(Debug, Clone)]
 enum MemNode {
     File(MemFile),
     Directory(MemDir),
+    Device(MemDevice),
+}
+
+/// Find the device a node refers to
+fn open_device(name: &str) -> Result<alloc::sync::Arc<dyn CharDevice>, FsError> {
+    chardev::lookup(name).ok_or(FsError::NotFound)
+}
+
+/// Map a device error to a file system error
+fn device_error(error: &'static str) -> FsError {
+    if error == chardev::NO_SPACE {
+        FsError::NoSpace
+    } else {
+        FsError::IoError
+    }
 }
 
 /// In-Memory File System
@@ -158,33 +227,9 @@ impl MemoryFileSystem {
     fn get_vnode_by_id(&self, id: u64) -> Option<VNode> {
         self.vnodes.read().get(&id).cloned()
     }
-}
-
-impl Default for MemoryFileSystem {
-    fn default() -> Self {
-        Self::new()
-    }
-}
 
-impl FileSystem for MemoryFileSystem {
-    fn root(&self) -> Result<VNode, FsError> {
-        self.get_vnode_by_id(0).ok_or(FsError::NotFound)
-    }
-    
-    fn lookup(&self, path: &str) -> Result<VNode, FsError> {
-        // Normalize the path
-        let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
-        
-        // Look up in path mapping
-        let paths = self.paths.read();
-        let vnode_id = paths.get(&path).copied().ok_or(FsError::NotFound)?;
-        drop(paths);
-        
-        // Get vnode
-        self.get_vnode_by_id(vnode_id).ok_or(FsError::NotFound)
-    }
-    
-    fn create(&mut self, path: &str, vtype: VNodeType) -> Result<VNode, FsError> {
+    /// Add `node` at `path`
+    fn insert(&self, path: &str, vtype: VNodeType, node: MemNode) -> Result<VNode, FsError> {
         // Normalize the path
         let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
         
@@ -208,11 +253,6 @@ impl FileSystem for MemoryFileSystem {
         let id = self.alloc_id();
         let filename = PathResolver::filename(&path).ok_or(FsError::InvalidPath)?;
         
-        let node = match vtype {
-            VNodeType::File => MemNode::File(MemFile::new()),
-            VNodeType::Directory => MemNode::Directory(MemDir::new()),
-        };
-        
         let vnode = VNode::new(id, vtype, path.clone());
         
         // Add to parent directory
@@ -232,6 +272,45 @@ impl FileSystem for MemoryFileSystem {
         
         Ok(vnode)
     }
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn root(&self) -> Result<VNode, FsError> {
+        self.get_vnode_by_id(0).ok_or(FsError::NotFound)
+    }
+    
+    fn lookup(&self, path: &str) -> Result<VNode, FsError> {
+        // Normalize the path
+        let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
+        
+        // Look up in path mapping
+        let paths = self.paths.read();
+        let vnode_id = paths.get(&path).copied().ok_or(FsError::NotFound)?;
+        drop(paths);
+        
+        // Get vnode
+        self.get_vnode_by_id(vnode_id).ok_or(FsError::NotFound)
+    }
+    
+    fn create(&mut self, path: &str, vtype: VNodeType) -> Result<VNode, FsError> {
+        let node = match vtype {
+            VNodeType::File => MemNode::File(MemFile::new()),
+            VNodeType::Directory => MemNode::Directory(MemDir::new()),
+            // Device nodes need a device, see mknod()
+            VNodeType::CharDevice => return Err(FsError::InvalidArgument),
+        };
+        self.insert(path, vtype, node)
+    }
+
+    fn mknod(&mut self, path: &str, device: &str) -> Result<VNode, FsError> {
+        self.insert(path, VNodeType::CharDevice, MemNode::Device(MemDevice::new(device)))
+    }
     
     fn remove(&mut self, path: &str) -> Result<(), FsError> {
         // Normalize the path
@@ -283,6 +362,12 @@ impl FileSystem for MemoryFileSystem {
         match nodes.get(&vnode.id) {
             Some(MemNode::File(file)) => Ok(file.read(offset, buffer)),
             Some(MemNode::Directory(_)) => Err(FsError::IsADirectory),
+            Some(MemNode::Device(dev)) => {
+                // Devices have no offsets
+                let device = open_device(&dev.device)?;
+                drop(nodes);
+                device.read(buffer).map_err(device_error)
+            }
             None => Err(FsError::NotFound),
         }
     }
@@ -293,6 +378,11 @@ impl FileSystem for MemoryFileSystem {
         match nodes.get_mut(&vnode.id) {
             Some(MemNode::File(file)) => Ok(file.write(offset, buffer)),
             Some(MemNode::Directory(_)) => Err(FsError::IsADirectory),
+            Some(MemNode::Device(dev)) => {
+                let device = open_device(&dev.device)?;
+                drop(nodes);
+                device.write(buffer).map_err(device_error)
+            }
             None => Err(FsError::NotFound),
         }
     }
@@ -304,10 +394,20 @@ impl FileSystem for MemoryFileSystem {
             Some(MemNode::File(file)) => Ok(VNodeAttr {
                 size: file.size(),
                 vtype: VNodeType::File,
+                created: file.times.created,
+                modified: file.times.modified,
             }),
-            Some(MemNode::Directory(_)) => Ok(VNodeAttr {
+            Some(MemNode::Directory(dir)) => Ok(VNodeAttr {
                 size: 0,
                 vtype: VNodeType::Directory,
+                created: dir.times.created,
+                modified: dir.times.modified,
+            }),
+            Some(MemNode::Device(dev)) => Ok(VNodeAttr {
+                size: 0,
+                vtype: VNodeType::CharDevice,
+                created: dev.times.created,
+                modified: dev.times.modified,
             }),
             None => Err(FsError::NotFound),
         }
@@ -331,7 +431,7 @@ impl FileSystem for MemoryFileSystem {
                 
                 Ok(entries)
             }
-            Some(MemNode::File(_)) => Err(FsError::NotADirectory),
+            Some(MemNode::File(_)) | Some(MemNode::Device(_)) => Err(FsError::NotADirectory),
             None => Err(FsError::NotFound),
         }
     }
@@ -345,9 +445,31 @@ impl FileSystem for MemoryFileSystem {
                 Ok(())
             }
             Some(MemNode::Directory(_)) => Err(FsError::IsADirectory),
+            // Opening a device for writing truncates it, which does nothing
+            Some(MemNode::Device(_)) => Ok(()),
             None => Err(FsError::NotFound),
         }
     }
+
+    /// Files live on the kernel heap, so the free space is what the heap
+    /// has left
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let used: usize = self
+            .nodes
+            .read()
+            .values()
+            .map(|node| match node {
+                MemNode::File(file) => file.size(),
+                _ => 0,
+            })
+            .sum();
+        let free = crate::memory::stats::stats().free_heap();
+        Ok(FsStats {
+            block_size: MEMFS_BLOCK_SIZE,
+            blocks: (used + free).div_ceil(MEMFS_BLOCK_SIZE as usize) as u64,
+            free_blocks: (free / MEMFS_BLOCK_SIZE as usize) as u64,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -417,6 +539,7 @@ mod tests {
         let attr = fs.stat(&vnode).unwrap();
         assert_eq!(attr.size, data.len());
         assert_eq!(attr.vtype, VNodeType::File);
+        assert!(attr.modified >= attr.created);
     }
     
     #[test]
@@ -472,4 +595,57 @@ mod tests {
         let attr = fs.stat(&vnode).unwrap();
         assert_eq!(attr.size, 5);
     }
+
+    #[test]
+    fn test_device_node() {
+        struct Sevens;
+
+        impl CharDevice for Sevens {
+            fn kind(&self) -> chardev::CharDeviceKind {
+                chardev::CharDeviceKind::Other
+            }
+
+            fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+                buf.fill(7);
+                Ok(buf.len())
+            }
+
+            fn write(&self, _buf: &[u8]) -> Result<usize, &'static str> {
+                Err(chardev::NO_SPACE)
+            }
+        }
+
+        chardev::register("memfs-test", alloc::sync::Arc::new(Sevens)).unwrap();
+        let mut fs = MemoryFileSystem::new();
+        fs.create("/dev", VNodeType::Directory).unwrap();
+        assert_eq!(fs.create("/dev/x", VNodeType::CharDevice), Err(FsError::InvalidArgument));
+
+        let node = fs.mknod("/dev/sevens", "memfs-test").unwrap();
+        assert_eq!(node.vtype, VNodeType::CharDevice);
+        let mut buf = [0u8; 4];
+        assert_eq!(fs.read(&node, 100, &mut buf), Ok(4));
+        assert_eq!(buf, [7; 4]);
+        assert_eq!(fs.write(&node, 0, b"x"), Err(FsError::NoSpace));
+        assert!(fs.truncate(&node, 0).is_ok());
+        assert_eq!(fs.stat(&node).unwrap().vtype, VNodeType::CharDevice);
+
+        let dev = fs.lookup("/dev").unwrap();
+        let entries = fs.readdir(&dev).unwrap();
+        assert_eq!(entries[0].vtype, VNodeType::CharDevice);
+        assert_eq!(fs.readdir(&node).unwrap_err(), FsError::NotADirectory);
+
+        // The node outlives its device
+        chardev::unregister("memfs-test").unwrap();
+        assert_eq!(fs.read(&node, 0, &mut buf), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_statfs() {
+        let mut fs = MemoryFileSystem::new();
+        let vnode = fs.create("/big", VNodeType::File).unwrap();
+        fs.write(&vnode, 0, &[0u8; 10000]).unwrap();
+        let stats = fs.statfs().unwrap();
+        assert_eq!(stats.block_size, MEMFS_BLOCK_SIZE);
+        assert!(stats.used() >= 8192);
+    }
 }
@@ -6,14 +6,338 @@
 //! - Directory operations (mkdir, rmdir, readdir)
 //! - Path resolution (absolute and relative)
 //! - Per-process file descriptor tables
+//! - Character device nodes under `/dev` and elsewhere, such as `/proc/kmsg`
+//! - A global root file system, with other file systems mounted on it
+//! - Unpacking an initramfs (cpio newc archive) onto the root
 
 pub mod vfs;
 pub mod memfs;
 pub mod file_descriptor;
 pub mod path;
+pub mod mount;
+pub mod initramfs;
 
 // Re-export commonly used types
-pub use vfs::{FileSystem, VNode, VNodeType, OpenFlags, SeekWhence};
+pub use vfs::{FileSystem, FsError, FsStats, VNode, VNodeAttr, VNodeType, OpenFlags, SeekWhence};
 pub use memfs::MemoryFileSystem;
 pub use file_descriptor::{FileDescriptor, FileDescriptorTable};
 pub use path::PathResolver;
+pub use mount::{MountInfo, MountTable};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+/// Global root file system and its mounts
+static ROOT_FS: Once<Mutex<MountTable>> = Once::new();
+
+/// Mount an in-memory file system as the root
+pub fn init() {
+    ROOT_FS.call_once(|| Mutex::new(MountTable::new(Box::new(MemoryFileSystem::new()))));
+}
+
+/// Get the root file system
+pub fn root_fs() -> &'static Mutex<MountTable> {
+    ROOT_FS.get().expect("Root file system not initialized")
+}
+
+/// Get the root file system if it has been mounted
+pub fn try_root_fs() -> Option<&'static Mutex<MountTable>> {
+    ROOT_FS.get()
+}
+
+/// Replace the contents of a file in `fs`, creating it if needed
+pub fn write_file_in(fs: &mut dyn FileSystem, path: &str, data: &[u8]) -> Result<usize, FsError> {
+    let vnode = match fs.lookup(path) {
+        Ok(vnode) => {
+            fs.truncate(&vnode, 0)?;
+            vnode
+        }
+        Err(FsError::NotFound) => fs.create(path, VNodeType::File)?,
+        Err(e) => return Err(e),
+    };
+    fs.write(&vnode, 0, data)
+}
+
+/// Replace the contents of a file on the root file system
+///
+/// Fails with `IoError` if no root file system is mounted yet.
+pub fn write_file(path: &str, data: &[u8]) -> Result<usize, FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    write_file_in(&mut *fs, path, data)
+}
+
+/// Append to a file in `fs`, creating it if needed
+pub fn append_file_in(fs: &mut dyn FileSystem, path: &str, data: &[u8]) -> Result<usize, FsError> {
+    let vnode = match fs.lookup(path) {
+        Ok(vnode) => vnode,
+        Err(FsError::NotFound) => fs.create(path, VNodeType::File)?,
+        Err(e) => return Err(e),
+    };
+    let end = fs.stat(&vnode)?.size;
+    fs.write(&vnode, end, data)
+}
+
+/// Append to a file on the root file system
+///
+/// Fails with `IoError` if no root file system is mounted yet.
+pub fn append_file(path: &str, data: &[u8]) -> Result<usize, FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    append_file_in(&mut *fs, path, data)
+}
+
+/// Create a directory and any missing parents in `fs`
+pub fn create_dir_all_in(fs: &mut dyn FileSystem, path: &str) -> Result<(), FsError> {
+    let mut end = 0;
+    for component in path.split('/') {
+        end += component.len() + 1;
+        if component.is_empty() {
+            continue;
+        }
+        let prefix = &path[..end - 1];
+        match fs.lookup(prefix) {
+            Ok(vnode) if vnode.vtype == VNodeType::Directory => {}
+            Ok(_) => return Err(FsError::NotADirectory),
+            Err(FsError::NotFound) => {
+                fs.create(prefix, VNodeType::Directory)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Create a directory and any missing parents on the root file system
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    create_dir_all_in(&mut *fs, path)
+}
+
+/// Read a whole file from `fs`
+pub fn read_file_in(fs: &dyn FileSystem, path: &str) -> Result<Vec<u8>, FsError> {
+    let vnode = fs.lookup(path)?;
+    let mut data = alloc::vec![0; fs.stat(&vnode)?.size];
+    let len = fs.read(&vnode, 0, &mut data)?;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Read a whole file from the root file system
+///
+/// Fails with `IoError` if no root file system is mounted yet.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    read_file_in(&*fs, path)
+}
+
+/// Create `/dev` nodes in `fs` for registered character devices that have none
+///
+/// # Returns
+/// The number of nodes created
+pub fn populate_dev_in(fs: &mut dyn FileSystem) -> Result<usize, FsError> {
+    create_dir_all_in(fs, "/dev")?;
+    let mut created = 0;
+    for (name, _) in crate::io::chardev::list() {
+        let path = alloc::format!("/dev/{}", name);
+        match fs.mknod(&path, &name) {
+            Ok(_) => created += 1,
+            Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(created)
+}
+
+/// Create `/dev` nodes on the root file system for registered devices
+pub fn populate_dev() -> Result<usize, FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    populate_dev_in(&mut *fs)
+}
+
+/// Create a node for character device `device` at `path` on the root file
+/// system, with any missing parent directories
+///
+/// Succeeds without changes if `path` already exists.
+pub fn mknod(path: &str, device: &str) -> Result<(), FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+    create_dir_all_in(&mut *fs, parent)?;
+    match fs.mknod(path, device) {
+        Ok(_) | Err(FsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove a file, device node or directory with everything in it from `fs`
+pub fn remove_all_in(fs: &mut dyn FileSystem, path: &str) -> Result<(), FsError> {
+    let vnode = fs.lookup(path)?;
+    if vnode.vtype == VNodeType::Directory {
+        for entry in fs.readdir(&vnode)? {
+            remove_all_in(fs, &PathResolver::join(path, &entry.name))?;
+        }
+    }
+    fs.remove(path)
+}
+
+/// Copy a file, or a directory with everything in it, to `dst` in `fs`
+///
+/// A file at `dst` is replaced and a directory at `dst` is merged into.
+/// Device nodes cannot be copied.
+pub fn copy_in(fs: &mut dyn FileSystem, src: &str, dst: &str) -> Result<(), FsError> {
+    let vnode = fs.lookup(src)?;
+    match vnode.vtype {
+        VNodeType::File => {
+            let data = read_file_in(fs, src)?;
+            write_file_in(fs, dst, &data).map(|_| ())
+        }
+        VNodeType::Directory => {
+            let src = PathResolver::normalize(src).map_err(|_| FsError::InvalidPath)?;
+            let dst = PathResolver::normalize(dst).map_err(|_| FsError::InvalidPath)?;
+            // Copying into itself would never end
+            if dst == src || dst.starts_with(&PathResolver::join(&src, "")) {
+                return Err(FsError::InvalidArgument);
+            }
+            match fs.lookup(&dst) {
+                Ok(existing) if existing.vtype == VNodeType::Directory => {}
+                Ok(_) => return Err(FsError::NotADirectory),
+                Err(FsError::NotFound) => {
+                    fs.create(&dst, VNodeType::Directory)?;
+                }
+                Err(e) => return Err(e),
+            }
+            for entry in fs.readdir(&vnode)? {
+                copy_in(fs, &PathResolver::join(&src, &entry.name), &PathResolver::join(&dst, &entry.name))?;
+            }
+            Ok(())
+        }
+        VNodeType::CharDevice => Err(FsError::InvalidArgument),
+    }
+}
+
+/// Move a file or directory to `dst` in `fs`
+///
+/// File systems have no rename operation, so this copies and then removes
+/// the original.
+pub fn rename_in(fs: &mut dyn FileSystem, src: &str, dst: &str) -> Result<(), FsError> {
+    let src = PathResolver::normalize(src).map_err(|_| FsError::InvalidPath)?;
+    let dst = PathResolver::normalize(dst).map_err(|_| FsError::InvalidPath)?;
+    if src == dst {
+        return Ok(());
+    }
+    copy_in(fs, &src, &dst)?;
+    remove_all_in(fs, &src)
+}
+
+/// Remove a file or device node from the root file system
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let mut fs = ROOT_FS.get().ok_or(FsError::IoError)?.lock();
+    fs.remove(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_file_replaces_contents() {
+        let mut fs = MemoryFileSystem::new();
+        assert_eq!(write_file_in(&mut fs, "/a", b"hello world").unwrap(), 11);
+        assert_eq!(write_file_in(&mut fs, "/a", b"bye").unwrap(), 3);
+
+        let vnode = fs.lookup("/a").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(fs.read(&vnode, 0, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"bye");
+    }
+
+    #[test]
+    fn test_append_file() {
+        let mut fs = MemoryFileSystem::new();
+        assert_eq!(append_file_in(&mut fs, "/log", b"one\n").unwrap(), 4);
+        assert_eq!(append_file_in(&mut fs, "/log", b"two\n").unwrap(), 4);
+        assert_eq!(read_file_in(&fs, "/log").unwrap(), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn test_read_file() {
+        let mut fs = MemoryFileSystem::new();
+        write_file_in(&mut fs, "/a", b"hello").unwrap();
+        assert_eq!(read_file_in(&fs, "/a").unwrap(), b"hello");
+        assert_eq!(read_file_in(&fs, "/b"), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_create_dir_all() {
+        let mut fs = MemoryFileSystem::new();
+        create_dir_all_in(&mut fs, "/boot/fonts").unwrap();
+        create_dir_all_in(&mut fs, "/boot/fonts/").unwrap();
+        assert_eq!(fs.lookup("/boot/fonts").unwrap().vtype, VNodeType::Directory);
+
+        write_file_in(&mut fs, "/boot/fonts/a", b"x").unwrap();
+        assert_eq!(create_dir_all_in(&mut fs, "/boot/fonts/a/b"), Err(FsError::NotADirectory));
+    }
+
+    #[test]
+    fn test_write_file_missing_parent() {
+        let mut fs = MemoryFileSystem::new();
+        assert_eq!(write_file_in(&mut fs, "/no/such/file", b"x"), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_populate_dev() {
+        crate::io::chardev::register("fs-test-dev", alloc::sync::Arc::new(TestDevice)).unwrap();
+        let mut fs = MemoryFileSystem::new();
+        assert!(populate_dev_in(&mut fs).unwrap() >= 1);
+        let node = fs.lookup("/dev/fs-test-dev").unwrap();
+        assert_eq!(node.vtype, VNodeType::CharDevice);
+        // Existing nodes are kept
+        populate_dev_in(&mut fs).unwrap();
+        assert_eq!(fs.lookup("/dev/fs-test-dev").unwrap(), node);
+        crate::io::chardev::unregister("fs-test-dev").unwrap();
+    }
+
+    #[test]
+    fn test_copy_and_remove_tree() {
+        let mut fs = MemoryFileSystem::new();
+        create_dir_all_in(&mut fs, "/a/b").unwrap();
+        write_file_in(&mut fs, "/a/one", b"1").unwrap();
+        write_file_in(&mut fs, "/a/b/two", b"2").unwrap();
+
+        copy_in(&mut fs, "/a", "/c").unwrap();
+        assert_eq!(read_file_in(&fs, "/c/one").unwrap(), b"1");
+        assert_eq!(read_file_in(&fs, "/c/b/two").unwrap(), b"2");
+        assert_eq!(copy_in(&mut fs, "/a", "/a/b/x"), Err(FsError::InvalidArgument));
+
+        remove_all_in(&mut fs, "/a").unwrap();
+        assert_eq!(fs.lookup("/a"), Err(FsError::NotFound));
+        assert_eq!(fs.lookup("/a/b/two"), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = MemoryFileSystem::new();
+        write_file_in(&mut fs, "/old", b"data").unwrap();
+        rename_in(&mut fs, "/old", "/new").unwrap();
+        assert_eq!(read_file_in(&fs, "/new").unwrap(), b"data");
+        assert_eq!(fs.lookup("/old"), Err(FsError::NotFound));
+        rename_in(&mut fs, "/new", "/./new").unwrap();
+        assert_eq!(read_file_in(&fs, "/new").unwrap(), b"data");
+    }
+
+    struct TestDevice;
+
+    impl crate::io::chardev::CharDevice for TestDevice {
+        fn kind(&self) -> crate::io::chardev::CharDeviceKind {
+            crate::io::chardev::CharDeviceKind::Other
+        }
+
+        fn read(&self, _buf: &mut [u8]) -> Result<usize, &'static str> {
+            Ok(0)
+        }
+
+        fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+            Ok(buf.len())
+        }
+    }
+}
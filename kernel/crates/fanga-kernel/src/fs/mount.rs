@@ -0,0 +1,292 @@
+//! Mount Table
+//!
+//! The root file system with other file systems mounted on its
+//! directories. The table is itself a `FileSystem`: each path goes to the
+//! file system mounted on its longest leading directory, and vnodes keep
+//! the full path so later calls find their way back.
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::path::PathResolver;
+use super::vfs::{DirEntry, FileSystem, FsError, FsStats, VNode, VNodeAttr, VNodeType};
+
+/// A mounted file system as listed by `mount`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    /// Absolute path of the mount point
+    pub path: String,
+    /// Device or other source the file system came from
+    pub source: String,
+    /// File system type, such as "fat32"
+    pub fstype: &'static str,
+}
+
+struct Mount {
+    info: MountInfo,
+    fs: Box<dyn FileSystem>,
+}
+
+/// The root file system and everything mounted on it
+pub struct MountTable {
+    root: Box<dyn FileSystem>,
+    mounts: Vec<Mount>,
+}
+
+/// Path of `path` inside a file system mounted at `mount_point`, if it
+/// is on that file system
+fn strip_mount_point<'a>(path: &'a str, mount_point: &str) -> Option<&'a str> {
+    match path.strip_prefix(mount_point)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+impl MountTable {
+    pub fn new(root: Box<dyn FileSystem>) -> Self {
+        Self { root, mounts: Vec::new() }
+    }
+
+    /// Find the mount holding `path`
+    ///
+    /// # Returns
+    /// The index of the mount, `None` for the root file system, and the
+    /// path inside that file system
+    fn locate(&self, path: &str) -> Result<(Option<usize>, String), FsError> {
+        let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
+        let mut found: Option<(usize, &str)> = None;
+        for (index, mount) in self.mounts.iter().enumerate() {
+            if let Some(inner) = strip_mount_point(&path, &mount.info.path) {
+                if found.is_none_or(|(best, _)| mount.info.path.len() > self.mounts[best].info.path.len()) {
+                    found = Some((index, inner));
+                }
+            }
+        }
+        match found.map(|(index, inner)| (index, String::from(inner))) {
+            Some((index, inner)) => Ok((Some(index), inner)),
+            None => Ok((None, path)),
+        }
+    }
+
+    fn fs(&self, index: Option<usize>) -> &dyn FileSystem {
+        match index {
+            Some(index) => self.mounts[index].fs.as_ref(),
+            None => self.root.as_ref(),
+        }
+    }
+
+    fn fs_mut(&mut self, index: Option<usize>) -> &mut dyn FileSystem {
+        match index {
+            Some(index) => self.mounts[index].fs.as_mut(),
+            None => self.root.as_mut(),
+        }
+    }
+
+    /// Give a vnode from a mounted file system its full path
+    fn outer(&self, index: Option<usize>, mut vnode: VNode) -> VNode {
+        if let Some(index) = index {
+            let mount_point = &self.mounts[index].info.path;
+            vnode.path = match vnode.path.as_str() {
+                "/" => mount_point.clone(),
+                inner => alloc::format!("{}{}", mount_point, inner),
+            };
+        }
+        vnode
+    }
+
+    /// The file system holding a vnode and the vnode as that file system
+    /// knows it
+    fn inner(&self, vnode: &VNode) -> Result<(Option<usize>, VNode), FsError> {
+        let (index, path) = self.locate(&vnode.path)?;
+        Ok((index, VNode::new(vnode.id, vnode.vtype, path)))
+    }
+
+    /// Index of the mount at exactly `path`
+    fn mount_at(&self, path: &str) -> Option<usize> {
+        self.mounts.iter().position(|mount| mount.info.path == path)
+    }
+
+    /// Mount `fs` on the directory at `path`
+    pub fn mount(&mut self, path: &str, source: &str, fstype: &'static str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+        let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
+        if path == "/" || self.mount_at(&path).is_some() {
+            return Err(FsError::Busy);
+        }
+        if self.lookup(&path)?.vtype != VNodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        self.mounts.push(Mount {
+            info: MountInfo { path, source: String::from(source), fstype },
+            fs,
+        });
+        Ok(())
+    }
+
+    /// Write out and detach the file system mounted at `path`
+    ///
+    /// Fails with `Busy` while other file systems are mounted below it,
+    /// and keeps it mounted if writing it out fails.
+    pub fn unmount(&mut self, path: &str) -> Result<MountInfo, FsError> {
+        let path = PathResolver::normalize(path).map_err(|_| FsError::InvalidPath)?;
+        let index = self.mount_at(&path).ok_or(FsError::InvalidArgument)?;
+        let below = PathResolver::join(&path, "");
+        if self.mounts.iter().any(|mount| mount.info.path.starts_with(&below)) {
+            return Err(FsError::Busy);
+        }
+        self.mounts[index].fs.sync()?;
+        Ok(self.mounts.remove(index).info)
+    }
+
+    /// Mounted file systems in the order they were mounted
+    pub fn mounts(&self) -> impl Iterator<Item = &MountInfo> {
+        self.mounts.iter().map(|mount| &mount.info)
+    }
+
+    /// Size and free space of the file system holding `path`
+    pub fn statfs_at(&self, path: &str) -> Result<FsStats, FsError> {
+        let (index, _) = self.locate(path)?;
+        self.fs(index).statfs()
+    }
+}
+
+impl FileSystem for MountTable {
+    fn root(&self) -> Result<VNode, FsError> {
+        self.root.root()
+    }
+
+    fn lookup(&self, path: &str) -> Result<VNode, FsError> {
+        let (index, inner) = self.locate(path)?;
+        let vnode = self.fs(index).lookup(&inner)?;
+        Ok(self.outer(index, vnode))
+    }
+
+    fn create(&mut self, path: &str, vtype: VNodeType) -> Result<VNode, FsError> {
+        let (index, inner) = self.locate(path)?;
+        let vnode = self.fs_mut(index).create(&inner, vtype)?;
+        Ok(self.outer(index, vnode))
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        let (index, inner) = self.locate(path)?;
+        if index.is_some() && inner == "/" {
+            return Err(FsError::Busy);
+        }
+        self.fs_mut(index).remove(&inner)
+    }
+
+    fn read(&self, vnode: &VNode, offset: usize, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let (index, vnode) = self.inner(vnode)?;
+        self.fs(index).read(&vnode, offset, buffer)
+    }
+
+    fn write(&mut self, vnode: &VNode, offset: usize, buffer: &[u8]) -> Result<usize, FsError> {
+        let (index, vnode) = self.inner(vnode)?;
+        self.fs_mut(index).write(&vnode, offset, buffer)
+    }
+
+    fn stat(&self, vnode: &VNode) -> Result<VNodeAttr, FsError> {
+        let (index, vnode) = self.inner(vnode)?;
+        self.fs(index).stat(&vnode)
+    }
+
+    fn readdir(&self, vnode: &VNode) -> Result<Vec<DirEntry>, FsError> {
+        let (index, vnode) = self.inner(vnode)?;
+        self.fs(index).readdir(&vnode)
+    }
+
+    fn truncate(&mut self, vnode: &VNode, size: usize) -> Result<(), FsError> {
+        let (index, vnode) = self.inner(vnode)?;
+        self.fs_mut(index).truncate(&vnode, size)
+    }
+
+    fn mknod(&mut self, path: &str, device: &str) -> Result<VNode, FsError> {
+        let (index, inner) = self.locate(path)?;
+        let vnode = self.fs_mut(index).mknod(&inner, device)?;
+        Ok(self.outer(index, vnode))
+    }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        self.root.statfs()
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        for mount in &mut self.mounts {
+            mount.fs.sync()?;
+        }
+        self.root.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{read_file_in, write_file_in, MemoryFileSystem};
+
+    fn table() -> MountTable {
+        let mut root = MemoryFileSystem::new();
+        crate::fs::create_dir_all_in(&mut root, "/mnt/usb").unwrap();
+        write_file_in(&mut root, "/mnt/usb/hidden", b"under").unwrap();
+        MountTable::new(Box::new(root))
+    }
+
+    #[test]
+    fn test_mount_routes_paths() {
+        let mut table = table();
+        let mut disk = MemoryFileSystem::new();
+        write_file_in(&mut disk, "/readme", b"on disk").unwrap();
+        table.mount("/mnt/usb", "hda1", "memfs", Box::new(disk)).unwrap();
+
+        assert_eq!(read_file_in(&table, "/mnt/usb/readme").unwrap(), b"on disk");
+        assert_eq!(table.lookup("/mnt/usb/hidden"), Err(FsError::NotFound));
+        assert_eq!(table.lookup("/mnt/usb").unwrap().path, "/mnt/usb");
+
+        write_file_in(&mut table, "/mnt/usb/new", b"x").unwrap();
+        let names: Vec<String> = table
+            .readdir(&table.lookup("/mnt/usb/").unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["new", "readme"]);
+        assert_eq!(table.remove("/mnt/usb"), Err(FsError::Busy));
+        // A name that only shares a prefix stays on the root
+        assert_eq!(table.lookup("/mnt/usbx"), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_mount_errors() {
+        let mut table = table();
+        assert_eq!(
+            table.mount("/mnt/usb/hidden", "hda1", "memfs", Box::new(MemoryFileSystem::new())),
+            Err(FsError::NotADirectory)
+        );
+        assert_eq!(
+            table.mount("/missing", "hda1", "memfs", Box::new(MemoryFileSystem::new())),
+            Err(FsError::NotFound)
+        );
+        table.mount("/mnt", "hda1", "memfs", Box::new(MemoryFileSystem::new())).unwrap();
+        assert_eq!(
+            table.mount("/mnt", "hda2", "memfs", Box::new(MemoryFileSystem::new())),
+            Err(FsError::Busy)
+        );
+    }
+
+    #[test]
+    fn test_unmount() {
+        let mut table = table();
+        let mut disk = MemoryFileSystem::new();
+        crate::fs::create_dir_all_in(&mut disk, "/inner").unwrap();
+        table.mount("/mnt", "hda1", "memfs", Box::new(disk)).unwrap();
+        table.mount("/mnt/inner", "hda2", "memfs", Box::new(MemoryFileSystem::new())).unwrap();
+        assert_eq!(table.mounts().count(), 2);
+
+        assert_eq!(table.unmount("/mnt"), Err(FsError::Busy));
+        assert_eq!(table.unmount("/mnt/inner").unwrap().source, "hda2");
+        assert_eq!(table.unmount("/mnt/").unwrap().source, "hda1");
+        assert_eq!(table.unmount("/mnt"), Err(FsError::InvalidArgument));
+        assert_eq!(read_file_in(&table, "/mnt/usb/hidden").unwrap(), b"under");
+    }
+}
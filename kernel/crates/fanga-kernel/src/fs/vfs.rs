@@ -37,6 +37,54 @@ pub trait FileSystem: Send + Sync {
     
     /// Truncate file to specified size
     fn truncate(&mut self, vnode: &VNode, size: usize) -> Result<(), FsError>;
+
+    /// Create a node for the character device registered as `device`
+    ///
+    /// File systems that cannot hold device nodes refuse with `InvalidArgument`.
+    fn mknod(&mut self, path: &str, device: &str) -> Result<VNode, FsError> {
+        let _ = (path, device);
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Report the size and free space of the file system
+    ///
+    /// File systems that do not track space refuse with `InvalidArgument`.
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Write cached changes out to the underlying device
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Size and free space of a file system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStats {
+    /// Allocation unit in bytes
+    pub block_size: u64,
+    /// Total number of blocks
+    pub blocks: u64,
+    /// Blocks not in use
+    pub free_blocks: u64,
+}
+
+impl FsStats {
+    /// Total size in bytes
+    pub fn size(&self) -> u64 {
+        self.blocks * self.block_size
+    }
+
+    /// Bytes in use
+    pub fn used(&self) -> u64 {
+        self.blocks.saturating_sub(self.free_blocks) * self.block_size
+    }
+
+    /// Bytes free
+    pub fn free(&self) -> u64 {
+        self.free_blocks * self.block_size
+    }
 }
 
 /// Virtual node (inode equivalent)
@@ -64,6 +112,8 @@ pub enum VNodeType {
     File,
     /// Directory
     Directory,
+    /// Character device node
+    CharDevice,
 }
 
 /// Virtual node attributes
@@ -73,6 +123,10 @@ pub struct VNodeAttr {
     pub size: usize,
     /// Node type
     pub vtype: VNodeType,
+    /// Creation time, in seconds since the Unix epoch (0 if unknown)
+    pub created: u64,
+    /// Last modification time, in seconds since the Unix epoch (0 if unknown)
+    pub modified: u64,
 }
 
 /// Directory entry
@@ -186,25 +240,35 @@ pub enum FsError {
     InvalidArgument,
     /// I/O error
     IoError,
+    /// File system or mount point in use
+    Busy,
 }
 
-impl fmt::Display for FsError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl FsError {
+    /// Error message, for callers that report `&'static str` errors
+    pub fn as_str(self) -> &'static str {
         match self {
-            FsError::NotFound => write!(f, "File or directory not found"),
-            FsError::AlreadyExists => write!(f, "File or directory already exists"),
-            FsError::NotADirectory => write!(f, "Not a directory"),
-            FsError::IsADirectory => write!(f, "Is a directory"),
-            FsError::InvalidPath => write!(f, "Invalid path"),
-            FsError::PermissionDenied => write!(f, "Permission denied"),
-            FsError::DirectoryNotEmpty => write!(f, "Directory not empty"),
-            FsError::NoSpace => write!(f, "No space left"),
-            FsError::InvalidArgument => write!(f, "Invalid argument"),
-            FsError::IoError => write!(f, "I/O error"),
+            FsError::NotFound => "File or directory not found",
+            FsError::AlreadyExists => "File or directory already exists",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::InvalidPath => "Invalid path",
+            FsError::PermissionDenied => "Permission denied",
+            FsError::DirectoryNotEmpty => "Directory not empty",
+            FsError::NoSpace => "No space left",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::IoError => "I/O error",
+            FsError::Busy => "Device or resource busy",
         }
     }
 }
 
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
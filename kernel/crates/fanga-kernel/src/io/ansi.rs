@@ -0,0 +1,339 @@
+//! ANSI Escape Sequence Parser
+//!
+//! Splits a byte stream into printable bytes and the control sequences
+//! the console understands, so programs and log messages can color their
+//! output and move the cursor. The parser only tracks sequence state;
+//! applying an action is up to the console that owns it.
+//!
+//! Supported sequences (`ESC [` is the Control Sequence Introducer):
+//! - `CSI n A/B/C/D`: cursor up, down, forward, back
+//! - `CSI row ; col H` (or `f`): cursor position, 1-based
+//! - `CSI n J`: erase display (0 = to end, 1 = to start, 2/3 = all)
+//! - `CSI n K`: erase line (0 = to end, 1 = to start, 2 = all)
+//! - `CSI ... m`: graphic rendition (reset, bold, 8/16 colors)
+//! - `CSI s`, `CSI u`, `ESC 7`, `ESC 8`: save and restore the cursor
+//!
+//! Unknown sequences are consumed and ignored.
+
+/// Maximum number of numeric parameters kept per sequence
+pub const MAX_PARAMS: usize = 8;
+
+/// Standard 16-color palette (ARGB): 8 normal colors, then bright ones
+pub const PALETTE: [u32; 16] = [
+    0xFF000000, // Black
+    0xFFAA0000, // Red
+    0xFF00AA00, // Green
+    0xFFAA5500, // Yellow (brown)
+    0xFF0000AA, // Blue
+    0xFFAA00AA, // Magenta
+    0xFF00AAAA, // Cyan
+    0xFFAAAAAA, // White (light gray)
+    0xFF555555, // Bright black
+    0xFFFF5555, // Bright red
+    0xFF55FF55, // Bright green
+    0xFFFFFF55, // Bright yellow
+    0xFF5555FF, // Bright blue
+    0xFFFF55FF, // Bright magenta
+    0xFF55FFFF, // Bright cyan
+    0xFFFFFFFF, // Bright white
+];
+
+/// Console colors when no attribute is set
+pub const DEFAULT_FG: u32 = 0xFFFFFFFF;
+pub const DEFAULT_BG: u32 = 0xFF000000;
+
+/// Which part of the display or line to erase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseMode {
+    /// From the cursor to the end
+    ToEnd,
+    /// From the start to the cursor
+    ToStart,
+    /// Everything
+    All,
+}
+
+impl EraseMode {
+    fn from_param(param: u16) -> Option<Self> {
+        match param {
+            0 => Some(Self::ToEnd),
+            1 => Some(Self::ToStart),
+            2 | 3 => Some(Self::All),
+            _ => None,
+        }
+    }
+}
+
+/// A complete piece of the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiAction {
+    /// A byte to print or a plain control character (`\n`, `\r`, ...)
+    Print(u8),
+    /// Move the cursor up by n rows
+    CursorUp(usize),
+    /// Move the cursor down by n rows
+    CursorDown(usize),
+    /// Move the cursor right by n columns
+    CursorForward(usize),
+    /// Move the cursor left by n columns
+    CursorBack(usize),
+    /// Move the cursor to a 0-based position
+    CursorPosition { row: usize, col: usize },
+    EraseDisplay(EraseMode),
+    EraseLine(EraseMode),
+    /// Graphic rendition parameters; apply with `GraphicState::apply`
+    SetGraphics(Params),
+    SaveCursor,
+    RestoreCursor,
+}
+
+/// Numeric parameters of a control sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Self {
+        Self { values: [0; MAX_PARAMS], len: 0 }
+    }
+
+    /// Parameters given (an omitted parameter reads as 0)
+    pub fn as_slice(&self) -> &[u16] {
+        &self.values[..self.len]
+    }
+
+    /// Parameter `index`, or `default` if it is missing or 0
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.as_slice().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Incremental escape sequence parser
+#[derive(Debug, Clone, Copy)]
+pub struct AnsiParser {
+    state: State,
+    params: Params,
+    /// Whether the current parameter has had any digit
+    in_param: bool,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self { state: State::Ground, params: Params::new(), in_param: false }
+    }
+
+    /// Feed one byte
+    ///
+    /// # Returns
+    /// The action completed by this byte, if any
+    pub fn feed(&mut self, byte: u8) -> Option<AnsiAction> {
+        match self.state {
+            State::Ground => {
+                if byte == 0x1B {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(AnsiAction::Print(byte))
+                }
+            }
+            State::Escape => {
+                self.state = State::Ground;
+                match byte {
+                    b'[' => {
+                        self.state = State::Csi;
+                        self.params = Params::new();
+                        self.in_param = false;
+                        None
+                    }
+                    b'7' => Some(AnsiAction::SaveCursor),
+                    b'8' => Some(AnsiAction::RestoreCursor),
+                    // ESC ESC restarts the sequence
+                    0x1B => {
+                        self.state = State::Escape;
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if !self.in_param {
+                        self.push_param();
+                        self.in_param = true;
+                    }
+                    if let Some(value) = self.params.len.checked_sub(1).map(|i| &mut self.params.values[i]) {
+                        *value = value.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    }
+                    None
+                }
+                b';' => {
+                    // An empty parameter before ';' still counts
+                    if !self.in_param {
+                        self.push_param();
+                    }
+                    self.in_param = false;
+                    None
+                }
+                // Private markers and intermediates are accepted but ignored
+                b'?' | b'<' | b'=' | b'>' | 0x20..=0x2F => None,
+                0x40..=0x7E => {
+                    self.state = State::Ground;
+                    self.dispatch(byte)
+                }
+                // Anything else aborts the sequence
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+
+    fn push_param(&mut self) {
+        if self.params.len < MAX_PARAMS {
+            self.params.len += 1;
+        }
+    }
+
+    fn dispatch(&self, final_byte: u8) -> Option<AnsiAction> {
+        let params = &self.params;
+        let count = params.get_or(0, 1) as usize;
+        match final_byte {
+            b'A' => Some(AnsiAction::CursorUp(count)),
+            b'B' => Some(AnsiAction::CursorDown(count)),
+            b'C' => Some(AnsiAction::CursorForward(count)),
+            b'D' => Some(AnsiAction::CursorBack(count)),
+            b'H' | b'f' => Some(AnsiAction::CursorPosition {
+                row: params.get_or(0, 1) as usize - 1,
+                col: params.get_or(1, 1) as usize - 1,
+            }),
+            b'J' => EraseMode::from_param(params.get_or(0, 0)).map(AnsiAction::EraseDisplay),
+            b'K' => EraseMode::from_param(params.get_or(0, 0)).map(AnsiAction::EraseLine),
+            b'm' => Some(AnsiAction::SetGraphics(*params)),
+            b's' => Some(AnsiAction::SaveCursor),
+            b'u' => Some(AnsiAction::RestoreCursor),
+            _ => None,
+        }
+    }
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Colors and attributes set by graphic rendition sequences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraphicState {
+    pub fg: u32,
+    pub bg: u32,
+    pub bold: bool,
+    /// Palette index of the foreground, so bold can brighten it
+    fg_index: Option<usize>,
+}
+
+impl GraphicState {
+    pub const fn new() -> Self {
+        Self { fg: DEFAULT_FG, bg: DEFAULT_BG, bold: false, fg_index: None }
+    }
+
+    /// Apply graphic rendition parameters (an empty list resets)
+    pub fn apply(&mut self, params: &Params) {
+        if params.as_slice().is_empty() {
+            *self = Self::new();
+            return;
+        }
+        for &param in params.as_slice() {
+            match param {
+                0 => *self = Self::new(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.fg_index = Some((param - 30) as usize),
+                39 => self.fg_index = None,
+                40..=47 => self.bg = PALETTE[(param - 40) as usize],
+                49 => self.bg = DEFAULT_BG,
+                90..=97 => self.fg_index = Some((param - 90) as usize + 8),
+                100..=107 => self.bg = PALETTE[(param - 100) as usize + 8],
+                _ => {}
+            }
+        }
+        self.fg = match self.fg_index {
+            // Bold brightens the 8 normal colors
+            Some(index) if self.bold && index < 8 => PALETTE[index + 8],
+            Some(index) => PALETTE[index],
+            None => DEFAULT_FG,
+        };
+    }
+}
+
+impl Default for GraphicState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn parse(input: &[u8]) -> Vec<AnsiAction> {
+        let mut parser = AnsiParser::new();
+        input.iter().filter_map(|&b| parser.feed(b)).collect()
+    }
+
+    #[test]
+    fn test_cursor_sequences() {
+        assert_eq!(
+            parse(b"a\x1b[3Ab\x1b[C"),
+            [
+                AnsiAction::Print(b'a'),
+                AnsiAction::CursorUp(3),
+                AnsiAction::Print(b'b'),
+                AnsiAction::CursorForward(1),
+            ]
+        );
+        assert_eq!(parse(b"\x1b[5;10H"), [AnsiAction::CursorPosition { row: 4, col: 9 }]);
+        assert_eq!(parse(b"\x1b[;7f"), [AnsiAction::CursorPosition { row: 0, col: 6 }]);
+        assert_eq!(parse(b"\x1b[H"), [AnsiAction::CursorPosition { row: 0, col: 0 }]);
+    }
+
+    #[test]
+    fn test_erase_sequences() {
+        assert_eq!(parse(b"\x1b[2J\x1b[K"), [AnsiAction::EraseDisplay(EraseMode::All), AnsiAction::EraseLine(EraseMode::ToEnd)]);
+        assert_eq!(parse(b"\x1b[1K"), [AnsiAction::EraseLine(EraseMode::ToStart)]);
+        // Unknown and private sequences are swallowed
+        assert_eq!(parse(b"\x1b[?25lx\x1b[9J"), [AnsiAction::Print(b'x')]);
+    }
+
+    #[test]
+    fn test_graphics() {
+        let mut state = GraphicState::new();
+        for action in parse(b"\x1b[1;31;44m") {
+            if let AnsiAction::SetGraphics(params) = action {
+                state.apply(&params);
+            }
+        }
+        assert_eq!(state.fg, PALETTE[9]);
+        assert_eq!(state.bg, PALETTE[4]);
+
+        let [AnsiAction::SetGraphics(reset)] = parse(b"\x1b[m")[..] else {
+            panic!("expected a graphics action");
+        };
+        state.apply(&reset);
+        assert_eq!(state, GraphicState::new());
+    }
+}
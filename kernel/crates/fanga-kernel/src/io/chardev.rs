@@ -0,0 +1,136 @@
+//! Character Devices
+//!
+//! Drivers register byte-stream devices here under a device name such as
+//! `ttyS0`, and the rest of the kernel looks them up by that name.
+//!
+//! This module provides:
+//! - The `CharDevice` trait
+//! - A global registry of named devices
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Kind of character device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharDeviceKind {
+    /// A terminal line
+    Tty,
+    /// Anything else
+    Other,
+}
+
+/// Error for a write to a device with no room left, like `/dev/full`
+///
+/// The file system layer reports it as `FsError::NoSpace`.
+pub const NO_SPACE: &str = "No space left on device";
+
+/// A byte-stream device
+pub trait CharDevice: Send + Sync {
+    /// Device kind
+    fn kind(&self) -> CharDeviceKind;
+
+    /// Read available bytes into `buf` without waiting
+    ///
+    /// # Returns
+    /// The number of bytes read
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    /// Write bytes to the device
+    ///
+    /// # Returns
+    /// The number of bytes written
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str>;
+
+    /// Check whether a read would return something, for callers that poll
+    /// instead of reading blindly
+    fn readable(&self) -> bool {
+        true
+    }
+}
+
+/// Registered devices, in registration order
+static DEVICES: Mutex<Vec<(String, Arc<dyn CharDevice>)>> = Mutex::new(Vec::new());
+
+/// Register a device under `name`
+pub fn register(name: &str, device: Arc<dyn CharDevice>) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(existing, _)| existing == name) {
+        return Err("Device name already registered");
+    }
+    devices.push((String::from(name), device));
+    Ok(())
+}
+
+/// Remove the device registered under `name`
+pub fn unregister(name: &str) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    let index = devices
+        .iter()
+        .position(|(existing, _)| existing == name)
+        .ok_or("No such device")?;
+    devices.remove(index);
+    Ok(())
+}
+
+/// Find the device registered under `name`
+pub fn lookup(name: &str) -> Option<Arc<dyn CharDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(existing, _)| existing == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Names and kinds of all registered devices
+pub fn list() -> Vec<(String, CharDeviceKind)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.kind()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Loopback(Mutex<Vec<u8>>);
+
+    impl CharDevice for Loopback {
+        fn kind(&self) -> CharDeviceKind {
+            CharDeviceKind::Other
+        }
+
+        fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+            let mut data = self.0.lock();
+            let count = buf.len().min(data.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            data.drain(..count);
+            Ok(count)
+        }
+
+        fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_register_lookup() {
+        register("test-loop", Arc::new(Loopback(Mutex::new(Vec::new())))).unwrap();
+        assert!(register("test-loop", Arc::new(Loopback(Mutex::new(Vec::new())))).is_err());
+        assert!(list().iter().any(|(name, kind)| name == "test-loop" && *kind == CharDeviceKind::Other));
+
+        let device = lookup("test-loop").unwrap();
+        assert_eq!(device.write(b"abc").unwrap(), 3);
+        let mut buf = [0u8; 8];
+        assert_eq!(device.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+
+        unregister("test-loop").unwrap();
+        assert!(lookup("test-loop").is_none());
+        assert!(unregister("test-loop").is_err());
+    }
+}
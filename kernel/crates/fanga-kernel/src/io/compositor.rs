@@ -0,0 +1,609 @@
+//! Compositor
+//!
+//! Rectangular surfaces stacked by z-order and blitted onto the
+//! framebuffer. Each surface owns its pixels; drawing into a surface and
+//! moving, raising or hiding it marks the screen area it touches as
+//! damaged, and `compose` redraws only the damaged areas, bottom surface
+//! first, over a solid background.
+//!
+//! Composition happens in a staging buffer (the console's old back
+//! buffer) and the damaged rectangles are then copied to the screen, so
+//! the screen never shows a half-composed frame. The compositor draws the
+//! mouse cursor over the result once it is running.
+//!
+//! Surface pixels are too large for the kernel heap, so they come from
+//! `memory::mmio::alloc_buffer`. That memory is never freed: the buffer of
+//! a destroyed surface is kept for the next surface that fits in it.
+//!
+//! Kernel code creates surfaces with `create_surface` and draws into them
+//! with `paint`; see `super::desktop` for the terminal window and status
+//! bar built on top.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use super::cursor::{self, Cursor};
+use super::font::Font;
+
+/// Damaged rectangles kept before they are merged into one
+const MAX_DAMAGE: usize = 16;
+
+/// Allocates pixel buffers that live for the rest of the run
+pub type PixelAlloc = fn(usize) -> Result<&'static mut [u32], &'static str>;
+
+/// Default background color (ARGB)
+pub const BACKGROUND: u32 = 0xFF20303C;
+
+/// Pixel rectangle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    /// Area covered by both rectangles (empty if they do not overlap)
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y))
+    }
+
+    /// Smallest rectangle covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(x, y, self.right().max(other.right()) - x, self.bottom().max(other.bottom()) - y)
+    }
+
+    /// `self` moved by (`dx`, `dy`)
+    fn offset(&self, dx: usize, dy: usize) -> Rect {
+        Rect::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+}
+
+/// Handle of a surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SurfaceId(u32);
+
+/// A rectangle of pixels placed on the screen
+pub struct Surface {
+    id: SurfaceId,
+    rect: Rect,
+    z: i32,
+    visible: bool,
+    /// At least `width * height` ARGB pixels, row by row
+    pixels: &'static mut [u32],
+}
+
+impl Surface {
+    /// Position and size on the screen
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    pub fn width(&self) -> usize {
+        self.rect.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.rect.height
+    }
+
+    /// The surface pixels, row by row
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.pixels[..self.rect.width * self.rect.height]
+    }
+
+    /// Fill `rect` (in surface coordinates) with `color`
+    pub fn fill(&mut self, rect: Rect, color: u32) {
+        let rect = rect.intersect(&Rect::new(0, 0, self.rect.width, self.rect.height));
+        for y in rect.y..rect.bottom() {
+            let start = y * self.rect.width;
+            self.pixels[start + rect.x..start + rect.right()].fill(color);
+        }
+    }
+
+    /// Draw `text` on one line with its top left at (`x`, `y`)
+    ///
+    /// Text past the right edge is cut off. Returns the x position after
+    /// the last character.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, font: &Font, fg: u32, bg: u32) -> usize {
+        let mut x = x;
+        for ch in text.chars() {
+            if x + font.width() > self.rect.width || y + font.height() > self.rect.height {
+                break;
+            }
+            let glyph = font.glyph(ch);
+            for gy in 0..font.height() {
+                let start = (y + gy) * self.rect.width + x;
+                for gx in 0..font.width() {
+                    self.pixels[start + gx] = if font.pixel(glyph, gx, gy) { fg } else { bg };
+                }
+            }
+            x += font.width();
+        }
+        x
+    }
+}
+
+/// Surfaces, their stacking order and the damaged screen areas
+pub struct Compositor {
+    width: usize,
+    height: usize,
+    background: u32,
+    /// Sorted by z, bottom first; equal z keeps creation order
+    surfaces: Vec<Surface>,
+    damage: Vec<Rect>,
+    next_id: u32,
+    alloc: PixelAlloc,
+    /// Buffers of destroyed surfaces
+    spare: Vec<&'static mut [u32]>,
+}
+
+impl Compositor {
+    /// Compositor for a `width` by `height` screen, all damaged
+    pub fn new(width: usize, height: usize, background: u32, alloc: PixelAlloc) -> Self {
+        Self {
+            width,
+            height,
+            background,
+            surfaces: Vec::new(),
+            damage: alloc::vec![Rect::new(0, 0, width, height)],
+            next_id: 1,
+            alloc,
+            spare: Vec::new(),
+        }
+    }
+
+    /// Screen size in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Create a hidden surface at `rect`, cleared to zero
+    pub fn create_surface(&mut self, rect: Rect, z: i32) -> Result<SurfaceId, &'static str> {
+        let len = rect.width * rect.height;
+        let spare = self
+            .spare
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer)| buffer.len() >= len)
+            .min_by_key(|(_, buffer)| buffer.len())
+            .map(|(index, _)| index);
+        let pixels = match spare {
+            Some(index) => self.spare.swap_remove(index),
+            None => (self.alloc)(len)?,
+        };
+        pixels.fill(0);
+
+        let id = SurfaceId(self.next_id);
+        self.next_id += 1;
+        self.insert(Surface { id, rect, z, visible: false, pixels });
+        Ok(id)
+    }
+
+    /// Remove a surface and uncover what was under it
+    pub fn destroy_surface(&mut self, id: SurfaceId) -> bool {
+        match self.index(id) {
+            Some(index) => {
+                let surface = self.surfaces.remove(index);
+                if surface.visible {
+                    self.add_damage(surface.rect);
+                }
+                self.spare.push(surface.pixels);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move a surface so its top left is at (`x`, `y`)
+    pub fn move_surface(&mut self, id: SurfaceId, x: usize, y: usize) -> bool {
+        self.restack(id, |surface| {
+            surface.rect.x = x;
+            surface.rect.y = y;
+        })
+    }
+
+    /// Change a surface's place in the stack
+    pub fn set_z(&mut self, id: SurfaceId, z: i32) -> bool {
+        self.restack(id, |surface| surface.z = z)
+    }
+
+    /// Put a surface above the others with the same z
+    pub fn raise(&mut self, id: SurfaceId) -> bool {
+        self.restack(id, |_| {})
+    }
+
+    /// Show or hide a surface
+    pub fn set_visible(&mut self, id: SurfaceId, visible: bool) -> bool {
+        self.restack(id, |surface| surface.visible = visible)
+    }
+
+    /// Draw into a surface; all of it is redrawn at the next `compose`
+    pub fn paint<R>(&mut self, id: SurfaceId, draw: impl FnOnce(&mut Surface) -> R) -> Option<R> {
+        let index = self.index(id)?;
+        let surface = &mut self.surfaces[index];
+        let result = draw(surface);
+        let (rect, visible) = (surface.rect, surface.visible);
+        if visible {
+            self.add_damage(rect);
+        }
+        Some(result)
+    }
+
+    /// Mark part of a surface (in surface coordinates) as changed
+    pub fn damage_surface(&mut self, id: SurfaceId, rect: Rect) -> bool {
+        let Some(index) = self.index(id) else {
+            return false;
+        };
+        let surface = &self.surfaces[index];
+        if surface.visible {
+            let bounds = Rect::new(0, 0, surface.rect.width, surface.rect.height);
+            let rect = rect.intersect(&bounds).offset(surface.rect.x, surface.rect.y);
+            self.add_damage(rect);
+        }
+        true
+    }
+
+    /// Mark the whole screen as changed
+    pub fn damage_all(&mut self) {
+        self.damage.clear();
+        self.damage.push(Rect::new(0, 0, self.width, self.height));
+    }
+
+    /// Redraw the damaged areas into `target`, `stride` pixels per row
+    ///
+    /// Returns the rectangles drawn, clipped to the screen, so the caller
+    /// can copy them out.
+    pub fn compose(&mut self, target: &mut [u32], stride: usize) -> Vec<Rect> {
+        let screen = Rect::new(0, 0, self.width, self.height);
+        let damage: Vec<Rect> = core::mem::take(&mut self.damage)
+            .iter()
+            .map(|rect| rect.intersect(&screen))
+            .filter(|rect| !rect.is_empty())
+            .collect();
+
+        for area in &damage {
+            for y in area.y..area.bottom() {
+                target[y * stride + area.x..y * stride + area.right()].fill(self.background);
+            }
+            for surface in self.surfaces.iter().filter(|surface| surface.visible) {
+                let part = area.intersect(&surface.rect);
+                for y in part.y..part.bottom() {
+                    let src = (y - surface.rect.y) * surface.rect.width + (part.x - surface.rect.x);
+                    let dst = y * stride + part.x;
+                    target[dst..dst + part.width].copy_from_slice(&surface.pixels[src..src + part.width]);
+                }
+            }
+        }
+        damage
+    }
+
+    fn index(&self, id: SurfaceId) -> Option<usize> {
+        self.surfaces.iter().position(|surface| surface.id == id)
+    }
+
+    /// Put a surface at the top of its z level
+    fn insert(&mut self, surface: Surface) {
+        let index = self.surfaces.partition_point(|other| other.z <= surface.z);
+        self.surfaces.insert(index, surface);
+    }
+
+    /// Change a surface, put it back in the stack and damage where it was
+    /// and where it is now
+    fn restack(&mut self, id: SurfaceId, change: impl FnOnce(&mut Surface)) -> bool {
+        let Some(index) = self.index(id) else {
+            return false;
+        };
+        let mut surface = self.surfaces.remove(index);
+        if surface.visible {
+            self.add_damage(surface.rect);
+        }
+        change(&mut surface);
+        if surface.visible {
+            self.add_damage(surface.rect);
+        }
+        self.insert(surface);
+        true
+    }
+
+    fn add_damage(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        if self.damage.iter().any(|area| area.intersect(&rect) == rect) {
+            return;
+        }
+        if self.damage.len() >= MAX_DAMAGE {
+            let all = self.damage.drain(..).fold(rect, |all, area| all.union(&area));
+            self.damage.push(all);
+        } else {
+            self.damage.push(rect);
+        }
+    }
+}
+
+/// The running compositor and the screen it draws to
+struct Display {
+    compositor: Compositor,
+    screen: cursor::Screen,
+    /// Staging buffer with the same layout as the screen
+    staging: *mut u8,
+    cursor: Cursor,
+}
+
+unsafe impl Send for Display {}
+
+impl Display {
+    /// Compose the damaged areas and copy them to the screen
+    fn present(&mut self) {
+        let stride = self.screen.pitch / 4;
+        let staging = unsafe {
+            core::slice::from_raw_parts_mut(self.staging as *mut u32, stride * self.screen.height)
+        };
+        for area in self.compositor.compose(staging, stride) {
+            unsafe {
+                for y in area.y..area.bottom() {
+                    let line = y * self.screen.pitch + area.x * 4;
+                    core::ptr::copy_nonoverlapping(self.staging.add(line), self.screen.addr.add(line), area.width * 4);
+                }
+            }
+            self.cursor.uncover(&self.screen, area.x, area.y, area.right(), area.bottom());
+        }
+        self.update_cursor();
+    }
+
+    fn update_cursor(&mut self) {
+        let (x, y) = super::input::pointer_position();
+        self.cursor.show_at(&self.screen, x as usize, y as usize);
+    }
+}
+
+/// Pixel buffers from memory outside the heap
+fn alloc_pixels(len: usize) -> Result<&'static mut [u32], &'static str> {
+    let addr = crate::memory::mmio::alloc_buffer((len * 4) as u64)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u32, len) })
+}
+
+/// The compositor, once started
+static DISPLAY: Mutex<Option<Display>> = Mutex::new(None);
+
+/// Whether the compositor owns the screen
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Start compositing onto `screen`
+///
+/// # Safety
+/// `screen` must be a 32 bpp framebuffer mapping and `staging` must point
+/// to `screen.height * screen.pitch` writable bytes; both stay valid and
+/// nothing else draws to them from now on.
+pub unsafe fn start(screen: cursor::Screen, staging: *mut u8) -> Result<(), &'static str> {
+    let mut display = DISPLAY.lock();
+    if display.is_some() {
+        return Err("Compositor already running");
+    }
+    let mut cursor = Cursor::new();
+    cursor.set_visible(true, &screen);
+    let mut started = Display {
+        compositor: Compositor::new(screen.width, screen.height, BACKGROUND, alloc_pixels),
+        screen,
+        staging,
+        cursor,
+    };
+    started.present();
+    *display = Some(started);
+    ACTIVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Whether the compositor owns the screen
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Run `f` on the compositor and put the changes on screen
+fn with_compositor<R>(f: impl FnOnce(&mut Compositor) -> R) -> Result<R, &'static str> {
+    let mut display = DISPLAY.lock();
+    let display = display.as_mut().ok_or("Compositor not running")?;
+    let result = f(&mut display.compositor);
+    display.present();
+    Ok(result)
+}
+
+/// Screen size, if the compositor is running
+pub fn resolution() -> Option<(usize, usize)> {
+    DISPLAY.lock().as_ref().map(|display| display.compositor.resolution())
+}
+
+/// Create a hidden surface
+pub fn create_surface(rect: Rect, z: i32) -> Result<SurfaceId, &'static str> {
+    with_compositor(|compositor| compositor.create_surface(rect, z))?
+}
+
+/// Remove a surface
+pub fn destroy_surface(id: SurfaceId) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.destroy_surface(id))?.then_some(()).ok_or("No such surface")
+}
+
+/// Move a surface so its top left is at (`x`, `y`)
+pub fn move_surface(id: SurfaceId, x: usize, y: usize) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.move_surface(id, x, y))?.then_some(()).ok_or("No such surface")
+}
+
+/// Change a surface's place in the stack
+pub fn set_z(id: SurfaceId, z: i32) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.set_z(id, z))?.then_some(()).ok_or("No such surface")
+}
+
+/// Put a surface above the others with the same z
+pub fn raise(id: SurfaceId) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.raise(id))?.then_some(()).ok_or("No such surface")
+}
+
+/// Show or hide a surface
+pub fn set_visible(id: SurfaceId, visible: bool) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.set_visible(id, visible))?.then_some(()).ok_or("No such surface")
+}
+
+/// Draw into a surface and put it on screen
+pub fn paint<R>(id: SurfaceId, draw: impl FnOnce(&mut Surface) -> R) -> Result<R, &'static str> {
+    with_compositor(|compositor| compositor.paint(id, draw))?.ok_or("No such surface")
+}
+
+/// Put a changed part of a surface (in surface coordinates) on screen
+pub fn damage_surface(id: SurfaceId, rect: Rect) -> Result<(), &'static str> {
+    with_compositor(|compositor| compositor.damage_surface(id, rect))?.then_some(()).ok_or("No such surface")
+}
+
+/// Move the mouse cursor to the pointer position unless the compositor is
+/// in use
+///
+/// Called from the mouse interrupt. If the compositor is busy, the cursor
+/// catches up at the next `present`.
+pub fn move_cursor() {
+    if let Some(mut display) = DISPLAY.try_lock() {
+        if let Some(display) = display.as_mut() {
+            display.update_cursor();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: usize = 16;
+    const H: usize = 8;
+    const BG: u32 = 1;
+
+    fn alloc(len: usize) -> Result<&'static mut [u32], &'static str> {
+        Ok(alloc::boxed::Box::leak(alloc::vec![7; len].into_boxed_slice()))
+    }
+
+    fn compositor() -> Compositor {
+        Compositor::new(W, H, BG, alloc)
+    }
+
+    fn shown(compositor: &mut Compositor, rect: Rect, z: i32, color: u32) -> SurfaceId {
+        let id = compositor.create_surface(rect, z).unwrap();
+        compositor.paint(id, |surface| surface.pixels_mut().fill(color));
+        compositor.set_visible(id, true);
+        id
+    }
+
+    #[test]
+    fn test_rect() {
+        let a = Rect::new(0, 0, 10, 10);
+        let b = Rect::new(5, 8, 10, 10);
+        assert_eq!(a.intersect(&b), Rect::new(5, 8, 5, 2));
+        assert!(a.intersect(&Rect::new(10, 0, 4, 4)).is_empty());
+        assert_eq!(a.union(&b), Rect::new(0, 0, 15, 18));
+        assert_eq!(Rect::new(3, 3, 0, 0).union(&b), b);
+    }
+
+    #[test]
+    fn test_compose_by_z_order() {
+        let mut compositor = compositor();
+        let mut target = alloc::vec![0u32; W * H];
+        let top = shown(&mut compositor, Rect::new(2, 2, 4, 4), 10, 3);
+        shown(&mut compositor, Rect::new(0, 0, 4, 4), 0, 2);
+
+        assert_eq!(compositor.compose(&mut target, W), alloc::vec![Rect::new(0, 0, W, H)]);
+        assert_eq!(target[0], 2);
+        // The higher surface covers the overlap even though it came first
+        assert_eq!(target[3 * W + 3], 3);
+        assert_eq!(target[5 * W + 5], 3);
+        assert_eq!(target[7 * W + 15], BG);
+
+        // Nothing changed, nothing drawn
+        assert!(compositor.compose(&mut target, W).is_empty());
+
+        compositor.set_z(top, -1);
+        compositor.compose(&mut target, W);
+        assert_eq!(target[3 * W + 3], 2);
+        assert_eq!(target[5 * W + 5], 3);
+    }
+
+    #[test]
+    fn test_damage_limits_redraw() {
+        let mut compositor = compositor();
+        let mut target = alloc::vec![0u32; W * H];
+        let id = shown(&mut compositor, Rect::new(0, 0, 8, 4), 0, 2);
+        compositor.compose(&mut target, W);
+
+        // Change the surface without damage: the screen keeps the old pixels
+        target[0] = 9;
+        compositor.paint(id, |surface| surface.fill(Rect::new(0, 0, 1, 1), 5));
+        compositor.damage.clear();
+        compositor.damage_surface(id, Rect::new(1, 1, 2, 2));
+        assert_eq!(compositor.compose(&mut target, W), alloc::vec![Rect::new(1, 1, 2, 2)]);
+        assert_eq!(target[0], 9);
+
+        // Moving damages both the old and the new place
+        compositor.move_surface(id, 8, 4);
+        let drawn = compositor.compose(&mut target, W);
+        assert_eq!(drawn, alloc::vec![Rect::new(0, 0, 8, 4), Rect::new(8, 4, 8, 4)]);
+        assert_eq!(target[0], BG);
+        assert_eq!(target[4 * W + 8], 5);
+
+        compositor.destroy_surface(id);
+        compositor.compose(&mut target, W);
+        assert!(target.iter().all(|&pixel| pixel == BG));
+    }
+
+    #[test]
+    fn test_destroyed_buffers_are_reused() {
+        let mut compositor = compositor();
+        let id = shown(&mut compositor, Rect::new(0, 0, 4, 4), 0, 2);
+        let buffer = compositor.paint(id, |surface| surface.pixels_mut().as_ptr()).unwrap();
+        compositor.destroy_surface(id);
+
+        // Too big for the spare buffer: a new one
+        let big = compositor.create_surface(Rect::new(0, 0, 8, 8), 0).unwrap();
+        assert_ne!(compositor.paint(big, |surface| surface.pixels_mut().as_ptr()), Some(buffer));
+
+        let small = compositor.create_surface(Rect::new(0, 0, 2, 3), 0).unwrap();
+        let pixels = compositor.paint(small, |surface| (surface.pixels_mut().as_ptr(), surface.pixels_mut().to_vec()));
+        assert_eq!(pixels, Some((buffer, alloc::vec![0; 6])));
+        assert!(compositor.spare.is_empty());
+    }
+
+    #[test]
+    fn test_damage_merges_when_full() {
+        let mut compositor = compositor();
+        compositor.damage.clear();
+        for i in 0..=MAX_DAMAGE {
+            compositor.add_damage(Rect::new(i % W, i / W, 1, 1));
+        }
+        assert_eq!(compositor.damage, alloc::vec![Rect::new(0, 0, W, 2)]);
+    }
+}
@@ -30,7 +30,7 @@ impl Console {
         }
         
         if self.write_framebuffer {
-            super::framebuffer::_print(core::format_args!("{}", s));
+            super::vt::_print_console(core::format_args!("{}", s));
         }
     }
 
@@ -40,7 +40,7 @@ impl Console {
         }
         
         if self.write_framebuffer {
-            super::framebuffer::_print(args);
+            super::vt::_print_console(args);
         }
     }
 }
@@ -0,0 +1,235 @@
+//! Mouse cursor
+//!
+//! A software arrow cursor drawn over the console at the pointer position.
+//! The pixels under the sprite are saved when it is drawn and put back
+//! when it moves or is hidden, so the console text is never redrawn for it.
+//!
+//! The framebuffer writer owns the cursor. Drawing straight to the screen
+//! hides it first; with a back buffer, copying a changed rectangle to the
+//! screen takes in the new pixels under the sprite instead. Either way
+//! `flush` draws it again at the current pointer position.
+
+/// Sprite width in pixels
+pub const WIDTH: usize = 12;
+
+/// Sprite height in pixels
+pub const HEIGHT: usize = 19;
+
+/// Arrow sprite with its hotspot at the top left: `X` is outline, `.` is
+/// fill and spaces are transparent
+const SPRITE: [&[u8; WIDTH]; HEIGHT] = [
+    b"X           ",
+    b"XX          ",
+    b"X.X         ",
+    b"X..X        ",
+    b"X...X       ",
+    b"X....X      ",
+    b"X.....X     ",
+    b"X......X    ",
+    b"X.......X   ",
+    b"X........X  ",
+    b"X.........X ",
+    b"X......XXXXX",
+    b"X...X..X    ",
+    b"X..XX..X    ",
+    b"X.X  X..X   ",
+    b"XX   X..X   ",
+    b"X     X..X  ",
+    b"      X..X  ",
+    b"       XX   ",
+];
+
+/// Outline color (ARGB)
+const OUTLINE: u32 = 0xFF000000;
+
+/// Fill color (ARGB)
+const FILL: u32 = 0xFFFFFFFF;
+
+/// Color of sprite pixel (`x`, `y`), if it is not transparent
+fn sprite_pixel(x: usize, y: usize) -> Option<u32> {
+    match SPRITE[y][x] {
+        b'X' => Some(OUTLINE),
+        b'.' => Some(FILL),
+        _ => None,
+    }
+}
+
+/// 32 bpp screen memory the cursor is drawn into
+#[derive(Debug, Clone, Copy)]
+pub struct Screen {
+    pub addr: *mut u8,
+    /// Bytes per line
+    pub pitch: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Screen {
+    fn pixel(&self, x: usize, y: usize) -> *mut u32 {
+        unsafe { self.addr.add(y * self.pitch + x * 4) as *mut u32 }
+    }
+
+    /// Screen positions of the sprite pixels when drawn at (`x`, `y`),
+    /// clipped to the screen, with their index in the sprite
+    fn cells(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        (0..HEIGHT).flat_map(move |dy| (0..WIDTH).map(move |dx| (dx, dy))).filter_map(
+            move |(dx, dy)| {
+                let (px, py) = (x + dx, y + dy);
+                (px < width && py < height).then_some((px, py, dy * WIDTH + dx))
+            },
+        )
+    }
+}
+
+/// The cursor sprite and the pixels it covers
+pub struct Cursor {
+    visible: bool,
+    /// Where the sprite is drawn, if it is
+    drawn: Option<(usize, usize)>,
+    /// Pixels under the sprite
+    saved: [u32; WIDTH * HEIGHT],
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Self {
+            visible: false,
+            drawn: None,
+            saved: [0; WIDTH * HEIGHT],
+        }
+    }
+
+    /// Whether the cursor should be on screen
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Show or hide the cursor
+    ///
+    /// A shown cursor appears at the next `show_at`.
+    pub fn set_visible(&mut self, visible: bool, screen: &Screen) {
+        self.visible = visible;
+        if !visible {
+            self.hide(screen);
+        }
+    }
+
+    /// Put back the pixels under the sprite
+    pub fn hide(&mut self, screen: &Screen) {
+        let Some((x, y)) = self.drawn.take() else {
+            return;
+        };
+        for (px, py, index) in screen.cells(x, y) {
+            unsafe { screen.pixel(px, py).write_volatile(self.saved[index]) };
+        }
+    }
+
+    /// Draw the sprite with its hotspot at (`x`, `y`), if the cursor is
+    /// visible and not already there
+    pub fn show_at(&mut self, screen: &Screen, x: usize, y: usize) {
+        if !self.visible || self.drawn == Some((x, y)) {
+            return;
+        }
+        self.hide(screen);
+        for (px, py, index) in screen.cells(x, y) {
+            let pixel = screen.pixel(px, py);
+            unsafe { self.saved[index] = pixel.read_volatile() };
+        }
+        self.draw(screen, x, y, |_, _| true);
+        self.drawn = Some((x, y));
+    }
+
+    /// Take in the pixels under the sprite that were redrawn in the
+    /// rectangle `x0..x1` by `y0..y1`, then draw over them again
+    pub fn uncover(&mut self, screen: &Screen, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let Some((x, y)) = self.drawn else {
+            return;
+        };
+        let inside = |px: usize, py: usize| (x0..x1).contains(&px) && (y0..y1).contains(&py);
+        for (px, py, index) in screen.cells(x, y) {
+            if inside(px, py) {
+                unsafe { self.saved[index] = screen.pixel(px, py).read_volatile() };
+            }
+        }
+        self.draw(screen, x, y, inside);
+    }
+
+    /// Write the opaque sprite pixels at (`x`, `y`) that `filter` accepts
+    fn draw(&self, screen: &Screen, x: usize, y: usize, filter: impl Fn(usize, usize) -> bool) {
+        for (px, py, index) in screen.cells(x, y) {
+            if let Some(color) = sprite_pixel(index % WIDTH, index / WIDTH) {
+                if filter(px, py) {
+                    unsafe { screen.pixel(px, py).write_volatile(color) };
+                }
+            }
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const W: usize = 32;
+    const H: usize = 24;
+
+    fn screen(pixels: &mut Vec<u32>) -> Screen {
+        Screen { addr: pixels.as_mut_ptr() as *mut u8, pitch: W * 4, width: W, height: H }
+    }
+
+    #[test]
+    fn test_show_and_hide_restore_pixels() {
+        let mut pixels: Vec<u32> = (0..(W * H) as u32).collect();
+        let original = pixels.clone();
+        let screen = screen(&mut pixels);
+        let mut cursor = Cursor::new();
+
+        // Nothing is drawn while hidden
+        cursor.show_at(&screen, 2, 3);
+        assert_eq!(pixels, original);
+
+        cursor.set_visible(true, &screen);
+        cursor.show_at(&screen, 2, 3);
+        assert_eq!(pixels[3 * W + 2], OUTLINE);
+        assert_eq!(pixels[5 * W + 3], FILL);
+        // Transparent pixels are left alone
+        assert_eq!(pixels[3 * W + 10], original[3 * W + 10]);
+
+        // Moving puts back the old pixels
+        cursor.show_at(&screen, 25, 20);
+        assert_eq!(pixels[3 * W + 2], original[3 * W + 2]);
+        assert_eq!(pixels[20 * W + 25], OUTLINE);
+
+        cursor.set_visible(false, &screen);
+        assert_eq!(pixels, original);
+    }
+
+    #[test]
+    fn test_uncover_takes_new_pixels() {
+        let mut pixels = alloc::vec![7u32; W * H];
+        let screen = screen(&mut pixels);
+        let mut cursor = Cursor::new();
+        cursor.set_visible(true, &screen);
+        cursor.show_at(&screen, 0, 0);
+
+        // The console redraws the top rows under the cursor
+        for pixel in &mut pixels[..4 * W] {
+            *pixel = 9;
+        }
+        cursor.uncover(&screen, 0, 0, W, 4);
+        assert_eq!(pixels[0], OUTLINE);
+        assert_eq!(pixels[10], 9);
+
+        cursor.hide(&screen);
+        assert!(pixels[..4 * W].iter().all(|&pixel| pixel == 9));
+        assert!(pixels[4 * W..].iter().all(|&pixel| pixel == 7));
+    }
+}
@@ -0,0 +1,149 @@
+//! Desktop
+//!
+//! With `desktop` on the boot command line, the console runs in a window
+//! under the compositor instead of filling the screen: a status bar at the
+//! top shows the terminal on screen and the uptime, and the terminal
+//! window below it holds the console. Virtual terminals take turns in the
+//! same window.
+//!
+//! The console writer draws straight into the window surface and reports
+//! each changed rectangle, which the compositor then puts on screen.
+
+use alloc::format;
+use spin::Once;
+
+use super::compositor::{self, Rect, SurfaceId};
+use super::font::Font;
+use super::framebuffer;
+
+/// Space around the terminal window in pixels
+const MARGIN: usize = 16;
+
+/// Window border width in pixels
+const BORDER: usize = 1;
+
+/// Space above and below a line of text in a bar
+const PADDING: usize = 2;
+
+/// Stacking order of the terminal window and the status bar
+const WINDOW_Z: i32 = 0;
+const STATUS_Z: i32 = 100;
+
+/// Colors (ARGB)
+const BAR_BG: u32 = 0xFF303840;
+const BAR_FG: u32 = 0xFFE0E0E0;
+const TITLE_BG: u32 = 0xFF3C5A78;
+const BORDER_COLOR: u32 = 0xFF607890;
+
+/// How often the status bar is redrawn
+const STATUS_INTERVAL_MS: u64 = 1000;
+
+/// Where the console draws in the terminal window
+struct Terminal {
+    window: SurfaceId,
+    /// Top left of the console area in the window
+    x: usize,
+    y: usize,
+}
+
+/// The status bar and the font it is drawn with
+struct StatusBar {
+    surface: SurfaceId,
+    font: &'static Font,
+}
+
+static TERMINAL: Once<Terminal> = Once::new();
+static STATUS: Once<StatusBar> = Once::new();
+
+/// Start the compositor and move the console into a window
+///
+/// Needs the console to be double-buffered; its back buffer becomes the
+/// compositor's staging buffer.
+pub fn init() -> Result<(), &'static str> {
+    let (screen, staging, font) = {
+        let fb = framebuffer::framebuffer();
+        let (screen, staging) = fb.buffers().ok_or("Console is not double-buffered")?;
+        (screen, staging, fb.font())
+    };
+    let bar_height = font.height() + 2 * PADDING;
+    let top = bar_height + MARGIN;
+    let window = Rect::new(
+        MARGIN,
+        top,
+        screen.width.saturating_sub(2 * MARGIN),
+        screen.height.saturating_sub(top + MARGIN),
+    );
+    if window.width < 2 * BORDER + 40 * font.width() || window.height < bar_height + BORDER + 10 * font.height() {
+        return Err("Screen too small for a desktop");
+    }
+
+    unsafe { compositor::start(screen, staging)? };
+
+    let status = compositor::create_surface(Rect::new(0, 0, screen.width, bar_height), STATUS_Z)?;
+    STATUS.call_once(|| StatusBar { surface: status, font });
+    update_status()?;
+    compositor::set_visible(status, true)?;
+
+    let id = compositor::create_surface(window, WINDOW_Z)?;
+    let pixels = compositor::paint(id, |surface| {
+        let (width, height) = (surface.width(), surface.height());
+        surface.fill(Rect::new(0, 0, width, height), BORDER_COLOR);
+        surface.fill(Rect::new(BORDER, BORDER, width - 2 * BORDER, bar_height - BORDER), TITLE_BG);
+        surface.draw_text(BORDER + font.width(), PADDING, "Terminal", font, BAR_FG, TITLE_BG);
+        surface.pixels_mut()[bar_height * width + BORDER..].as_mut_ptr()
+    })?;
+    TERMINAL.call_once(|| Terminal { window: id, x: BORDER, y: bar_height });
+    compositor::set_visible(id, true)?;
+
+    // The console lays its text out again for the window
+    let (width, height) = (window.width - 2 * BORDER, window.height - bar_height - BORDER);
+    unsafe {
+        framebuffer::framebuffer().redirect(pixels as *mut u8, width, height, window.width * 4, terminal_damage);
+    }
+    Ok(())
+}
+
+/// Damage hook of the console writer
+fn terminal_damage(x0: usize, y0: usize, x1: usize, y1: usize) {
+    if let Some(terminal) = TERMINAL.get() {
+        let rect = Rect::new(terminal.x + x0, terminal.y + y0, x1 - x0, y1 - y0);
+        let _ = compositor::damage_surface(terminal.window, rect);
+    }
+}
+
+/// Redraw the status bar
+fn update_status() -> Result<(), &'static str> {
+    let status = STATUS.get().ok_or("No desktop")?;
+    // Gather everything first: the compositor is locked while painting
+    let uptime = crate::task::time::uptime_secs();
+    let text = format!(
+        "FangaOS   tty{}   up {:02}:{:02}:{:02}",
+        super::vt::current_terminal_id() + 1,
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60
+    );
+    compositor::paint(status.surface, |surface| {
+        surface.fill(Rect::new(0, 0, surface.width(), surface.height()), BAR_BG);
+        surface.draw_text(status.font.width(), PADDING, &text, status.font, BAR_FG, BAR_BG);
+    })
+}
+
+/// Keep the status bar up to date (needs the workqueues)
+pub fn start_status_updates() {
+    if STATUS.get().is_some() {
+        schedule_status();
+    }
+}
+
+/// Queue the next status bar update
+fn schedule_status() {
+    let work = crate::task::workqueue::Work::new(status_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, STATUS_INTERVAL_MS);
+}
+
+/// Workqueue function for status bar updates
+fn status_tick(_: usize) {
+    let _ = update_status();
+    schedule_status();
+}
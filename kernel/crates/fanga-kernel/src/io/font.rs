@@ -1,25 +1,287 @@
-/// Simple 8x16 bitmap font for console text rendering
-/// 
-/// This is a simplified ASCII font covering characters 32-126
+//! Console fonts
+//!
+//! The console draws text with a bitmap `Font`. A built-in 8x16 font
+//! covering ASCII 32-126 is used until a PSF1 or PSF2 font is loaded from
+//! the file system. PSF fonts may have any glyph size and usually cover
+//! Latin-1 or more; their Unicode table, if any, maps characters to glyphs.
+//!
+//! Fonts are never freed: a loaded font is kept for the rest of the run so
+//! writers can hold `&'static Font` without locking.
 
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Glyph width of the built-in font
 pub const FONT_WIDTH: usize = 8;
+/// Glyph height of the built-in font
 pub const FONT_HEIGHT: usize = 16;
 
-/// Get the bitmap for a character (returns 16 bytes, one per row)
-pub fn get_char_bitmap(ch: char) -> &'static [u8; 16] {
-    let idx = ch as usize;
-    if idx >= 32 && idx < 127 {
-        &FONT_DATA[idx - 32]
+/// PSF1 file magic
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// PSF1 mode bit: the font has 512 glyphs instead of 256
+const PSF1_MODE512: u8 = 0x01;
+/// PSF1 mode bits: the font has a Unicode table
+const PSF1_MODEHASTAB: u8 = 0x02 | 0x04;
+/// PSF1 Unicode table: end of a glyph's entries
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+/// PSF1 Unicode table: start of a combining sequence
+const PSF1_STARTSEQ: u16 = 0xFFFE;
+
+/// PSF2 file magic
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+/// PSF2 header flag: the font has a Unicode table
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+/// PSF2 Unicode table: end of a glyph's entries
+const PSF2_SEPARATOR: u8 = 0xFF;
+/// PSF2 Unicode table: start of a combining sequence
+const PSF2_STARTSEQ: u8 = 0xFE;
+/// Size of the PSF2 header
+const PSF2_HEADER_SIZE: usize = 32;
+
+/// A bitmap font
+///
+/// Each glyph is `height` rows of `stride` bytes; bit 7 of a row's first
+/// byte is the leftmost pixel.
+pub struct Font {
+    name: &'static str,
+    width: usize,
+    height: usize,
+    stride: usize,
+    glyphs: &'static [u8],
+    /// (code point, glyph) pairs sorted by code point, or `None` if glyph
+    /// `n` is code point `first + n`
+    map: Option<&'static [(u32, u32)]>,
+    first: u32,
+}
+
+impl Font {
+    /// Font name (the file it was loaded from)
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Glyph width in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Glyph height in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bytes per glyph row
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Number of glyphs
+    pub fn glyph_count(&self) -> usize {
+        self.glyphs.len() / self.glyph_size()
+    }
+
+    fn glyph_size(&self) -> usize {
+        self.height * self.stride
+    }
+
+    /// Glyph index for a character, if the font has one
+    fn index(&self, ch: char) -> Option<usize> {
+        let cp = ch as u32;
+        let index = match self.map {
+            Some(map) => map.binary_search_by_key(&cp, |&(cp, _)| cp).ok().map(|i| map[i].1 as usize)?,
+            None => cp.checked_sub(self.first)? as usize,
+        };
+        (index < self.glyph_count()).then_some(index)
+    }
+
+    /// Whether the font has a glyph for `ch`
+    pub fn has_glyph(&self, ch: char) -> bool {
+        self.index(ch).is_some()
+    }
+
+    /// Bitmap for a character
+    ///
+    /// Characters the font lacks are drawn as '?', or blank if there is
+    /// no '?' either.
+    pub fn glyph(&self, ch: char) -> &'static [u8] {
+        let size = self.glyph_size();
+        match self.index(ch).or_else(|| self.index('?')) {
+            Some(index) => &self.glyphs[index * size..(index + 1) * size],
+            None => &BLANK_GLYPH[..size.min(BLANK_GLYPH.len())],
+        }
+    }
+
+    /// Whether pixel (`x`, `y`) of a glyph bitmap is set
+    pub fn pixel(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        glyph
+            .get(y * self.stride + x / 8)
+            .is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0)
+    }
+
+    /// Parse a PSF1 or PSF2 font file
+    ///
+    /// The glyph data and Unicode table are copied and kept forever.
+    pub fn parse(name: &str, data: &[u8]) -> Result<Font, &'static str> {
+        if data.starts_with(&PSF2_MAGIC) {
+            parse_psf2(name, data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            parse_psf1(name, data)
+        } else {
+            Err("not a PSF font")
+        }
+    }
+}
+
+/// Blank bitmap for fonts without a '?' glyph (large enough for 32x32)
+static BLANK_GLYPH: [u8; 128] = [0; 128];
+
+/// The built-in 8x16 font
+static BUILTIN: Font = Font {
+    name: "builtin",
+    width: FONT_WIDTH,
+    height: FONT_HEIGHT,
+    stride: 1,
+    glyphs: FONT_DATA.as_flattened(),
+    map: None,
+    first: 32,
+};
+
+/// Get the built-in font
+pub const fn builtin() -> &'static Font {
+    &BUILTIN
+}
+
+/// Fonts loaded so far
+static LOADED: Mutex<Vec<&'static Font>> = Mutex::new(Vec::new());
+
+/// Load a PSF font from the root file system
+///
+/// A font loaded before is returned again rather than read a second time.
+pub fn load(path: &str) -> Result<&'static Font, &'static str> {
+    if let Some(font) = LOADED.lock().iter().find(|font| font.name == path) {
+        return Ok(*font);
+    }
+
+    let data = crate::fs::read_file(path).map_err(|e| match e {
+        crate::fs::FsError::NotFound => "font file not found",
+        _ => "cannot read font file",
+    })?;
+    let font: &'static Font = Box::leak(Box::new(Font::parse(path, &data)?));
+    LOADED.lock().push(font);
+    Ok(font)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn parse_psf1(name: &str, data: &[u8]) -> Result<Font, &'static str> {
+    if data.len() < 4 {
+        return Err("truncated PSF1 header");
+    }
+    let mode = data[2];
+    let height = data[3] as usize;
+    let count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+    let glyphs_end = 4 + count * height;
+    if height == 0 || data.len() < glyphs_end {
+        return Err("truncated PSF1 glyph data");
+    }
+
+    let map = if mode & PSF1_MODEHASTAB != 0 {
+        let mut map = Vec::new();
+        let mut glyph = 0u32;
+        let mut in_sequence = false;
+        for entry in data[glyphs_end..].chunks_exact(2) {
+            match u16::from_le_bytes([entry[0], entry[1]]) {
+                PSF1_SEPARATOR => {
+                    glyph += 1;
+                    in_sequence = false;
+                }
+                PSF1_STARTSEQ => in_sequence = true,
+                cp if !in_sequence => map.push((cp as u32, glyph)),
+                _ => {}
+            }
+        }
+        Some(finish_map(map))
     } else {
-        // Return space for unknown characters
-        &FONT_DATA[0]
+        None
+    };
+
+    Ok(Font {
+        name: leak_str(name),
+        width: 8,
+        height,
+        stride: 1,
+        glyphs: data[4..glyphs_end].to_vec().leak(),
+        map,
+        first: 0,
+    })
+}
+
+fn parse_psf2(name: &str, data: &[u8]) -> Result<Font, &'static str> {
+    if data.len() < PSF2_HEADER_SIZE {
+        return Err("truncated PSF2 header");
     }
+    let header_size = read_u32(data, 8) as usize;
+    let flags = read_u32(data, 12);
+    let count = read_u32(data, 16) as usize;
+    let glyph_size = read_u32(data, 20) as usize;
+    let height = read_u32(data, 24) as usize;
+    let width = read_u32(data, 28) as usize;
+
+    let stride = width.div_ceil(8);
+    if width == 0 || height == 0 || glyph_size != height * stride {
+        return Err("bad PSF2 glyph size");
+    }
+    let glyphs_end = count
+        .checked_mul(glyph_size)
+        .and_then(|size| size.checked_add(header_size))
+        .filter(|&end| header_size >= PSF2_HEADER_SIZE && end <= data.len())
+        .ok_or("truncated PSF2 glyph data")?;
+
+    let map = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+        let mut map = Vec::new();
+        for (glyph, entries) in data[glyphs_end..].split(|&b| b == PSF2_SEPARATOR).enumerate() {
+            // Only single characters, not the sequences after STARTSEQ
+            let singles = entries.split(|&b| b == PSF2_STARTSEQ).next().unwrap_or(&[]);
+            let Ok(text) = core::str::from_utf8(singles) else {
+                continue;
+            };
+            map.extend(text.chars().map(|ch| (ch as u32, glyph as u32)));
+        }
+        Some(finish_map(map))
+    } else {
+        None
+    };
+
+    Ok(Font {
+        name: leak_str(name),
+        width,
+        height,
+        stride,
+        glyphs: data[header_size..glyphs_end].to_vec().leak(),
+        map,
+        first: 0,
+    })
+}
+
+/// Sort a Unicode table for lookup, keeping the first glyph for each character
+fn finish_map(mut map: Vec<(u32, u32)>) -> &'static [(u32, u32)] {
+    map.sort_by_key(|&(cp, _)| cp);
+    map.dedup_by_key(|&mut (cp, _)| cp);
+    map.leak()
+}
+
+fn leak_str(s: &str) -> &'static str {
+    String::from(s).leak()
 }
 
 /// Font data: 8x16 bitmap font
 /// Each character is 16 bytes (16 rows, 1 byte per row)
 /// Bit 7 is leftmost pixel, bit 0 is rightmost
-const FONT_DATA: [[u8; 16]; 95] = [
+static FONT_DATA: [[u8; 16]; 95] = [
     // Character 32: ' ' (space)
     [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
     // Character 33: '!'
@@ -211,3 +473,98 @@ const FONT_DATA: [[u8; 16]; 95] = [
     // Character 126: '~'
     [0x00, 0x00, 0x76, 0xDC, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psf1(mode: u8, height: u8, table: &[u16]) -> Vec<u8> {
+        let count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+        let mut data = Vec::from([PSF1_MAGIC[0], PSF1_MAGIC[1], mode, height]);
+        for glyph in 0..count {
+            data.extend(core::iter::repeat(glyph as u8).take(height as usize));
+        }
+        for entry in table {
+            data.extend_from_slice(&entry.to_le_bytes());
+        }
+        data
+    }
+
+    fn psf2(width: u32, height: u32, count: u32, table: Option<&[u8]>) -> Vec<u8> {
+        let stride = width.div_ceil(8);
+        let mut data = Vec::from(PSF2_MAGIC);
+        let flags = if table.is_some() { PSF2_HAS_UNICODE_TABLE } else { 0 };
+        for field in [0, PSF2_HEADER_SIZE as u32, flags, count, height * stride, height, width] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for glyph in 0..count {
+            data.extend(core::iter::repeat(glyph as u8).take((height * stride) as usize));
+        }
+        data.extend_from_slice(table.unwrap_or(&[]));
+        data
+    }
+
+    #[test]
+    fn test_builtin_glyphs() {
+        let font = builtin();
+        assert_eq!((font.width(), font.height()), (8, 16));
+        assert_eq!(font.glyph_count(), 95);
+        assert!(font.has_glyph('A'));
+        assert!(!font.has_glyph('é'));
+        // Missing characters fall back to '?'
+        assert_eq!(font.glyph('é'), font.glyph('?'));
+    }
+
+    #[test]
+    fn test_psf1_without_table_is_indexed_by_code_point() {
+        let font = Font::parse("a.psf", &psf1(0, 14, &[])).unwrap();
+        assert_eq!((font.width(), font.height(), font.glyph_count()), (8, 14, 256));
+        assert_eq!(font.glyph('é'), &[0xE9; 14]);
+        assert!(!font.has_glyph('\u{100}'));
+    }
+
+    #[test]
+    fn test_psf1_unicode_table() {
+        let mut table = Vec::new();
+        for glyph in 0..256u16 {
+            match glyph {
+                1 => table.extend([0x263A, PSF1_STARTSEQ, 0x41, 0x300, PSF1_SEPARATOR]),
+                _ => table.push(PSF1_SEPARATOR),
+            }
+        }
+        let font = Font::parse("b.psf", &psf1(PSF1_MODEHASTAB, 8, &table)).unwrap();
+        assert_eq!(font.glyph('☺'), &[1; 8]);
+        // Only single characters are mapped, not sequences
+        assert!(!font.has_glyph('A'));
+    }
+
+    #[test]
+    fn test_psf2_wide_glyphs_and_table() {
+        let mut table = Vec::new();
+        table.extend_from_slice(b"\0");
+        table.push(PSF2_SEPARATOR);
+        table.extend_from_slice("A".as_bytes());
+        table.push(PSF2_SEPARATOR);
+        table.extend_from_slice("é".as_bytes());
+        table.push(PSF2_STARTSEQ);
+        table.extend_from_slice("e\u{301}".as_bytes());
+        table.push(PSF2_SEPARATOR);
+
+        let font = Font::parse("c.psf", &psf2(12, 24, 3, Some(&table))).unwrap();
+        assert_eq!((font.width(), font.height(), font.stride()), (12, 24, 2));
+        assert_eq!(font.glyph('A'), &[1; 48]);
+        assert_eq!(font.glyph('é'), &[2; 48]);
+        assert!(!font.has_glyph('e'));
+        assert!(font.pixel(font.glyph('A'), 7, 0));
+        assert!(!font.pixel(font.glyph('A'), 0, 0));
+    }
+
+    #[test]
+    fn test_rejects_bad_fonts() {
+        assert!(Font::parse("x", b"hello").is_err());
+        assert!(Font::parse("x", &psf1(0, 16, &[])[..100]).is_err());
+        let mut bad = psf2(8, 16, 2, None);
+        bad[20] = 3; // bytes per glyph does not match the size
+        assert!(Font::parse("x", &bad).is_err());
+    }
+}
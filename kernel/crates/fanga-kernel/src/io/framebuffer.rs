@@ -1,10 +1,87 @@
-use super::font::{self, FONT_HEIGHT, FONT_WIDTH};
+use super::ansi::{AnsiAction, AnsiParser, EraseMode, GraphicState};
+use super::cursor::{self, Cursor};
+use super::font::{self, Font};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::fmt;
 use spin::Mutex;
 
+/// Lines of scrollback kept per console
+pub const SCROLLBACK_LINES: usize = 200;
+
+/// Pixel rectangle `x0..x1` by `y0..y1` not yet copied to the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DirtyRect {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+impl DirtyRect {
+    const EMPTY: Self = Self { x0: usize::MAX, y0: usize::MAX, x1: 0, y1: 0 };
+
+    fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+
+    /// Grow to cover a `w` by `h` rectangle at (`x`, `y`)
+    fn add(&mut self, x: usize, y: usize, w: usize, h: usize) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        self.x0 = self.x0.min(x);
+        self.y0 = self.y0.min(y);
+        self.x1 = self.x1.max(x + w);
+        self.y1 = self.y1.max(y + h);
+    }
+
+    /// Limit to a `width` by `height` screen
+    fn clip(self, width: usize, height: usize) -> Self {
+        Self {
+            x1: self.x1.min(width),
+            y1: self.y1.min(height),
+            ..self
+        }
+    }
+}
+
+/// One character cell of the text grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    fg: u32,
+    bg: u32,
+}
+
 /// Framebuffer console writer with font rendering and scrolling
+///
+/// Output goes through an ANSI escape parser, so colors, cursor movement
+/// and clears embedded in the text take effect instead of being drawn.
+/// Printable text is decoded as UTF-8 and drawn with the writer's font.
+///
+/// Once the heap is up, `enable_text_buffer` makes the writer also keep
+/// the text it shows and the lines scrolled off the top. A writer with
+/// a text buffer can then run offscreen (without a framebuffer) and be
+/// redrawn later, which is how virtual terminals keep their contents.
+///
+/// With a back buffer attached (`set_buffers`), drawing goes to ordinary
+/// RAM and the changed rectangle is copied to the screen by `flush`. The
+/// public drawing methods flush when they are done, so the screen never
+/// shows a half-drawn line and scrolling never reads back video memory.
+///
+/// A mirror (`set_mirror`) gets a copy of everything written, so a serial
+/// terminal can follow the console.
+///
+/// The writer also draws the mouse cursor over the console, see
+/// `super::cursor`.
+///
+/// Under the compositor, the writer draws into a window surface instead
+/// of the screen (`redirect`) and reports what changed to a damage hook.
 pub struct FramebufferWriter {
     addr: *mut u8,
+    back: *mut u8,
+    dirty: DirtyRect,
     width: usize,
     height: usize,
     pitch: usize,
@@ -19,6 +96,30 @@ pub struct FramebufferWriter {
     // Colors (ARGB format)
     pub fg_color: u32,
     pub bg_color: u32,
+
+    // Escape sequence state
+    ansi: AnsiParser,
+    graphics: GraphicState,
+    saved_cursor: (usize, usize),
+
+    // Font and the UTF-8 sequence being decoded (code point so far, bytes left)
+    font: &'static Font,
+    utf8: (u32, u8),
+
+    // Text grid, scrollback and how many lines the view is scrolled back
+    cells: Vec<Cell>,
+    scrollback: VecDeque<Vec<Cell>>,
+    view_offset: usize,
+
+    // Gets a copy of the output
+    mirror: Option<fn(&[u8])>,
+
+    // Mouse cursor drawn over the screen
+    cursor: Cursor,
+
+    // Gets the changed rectangle (x0, y0, x1, y1) when drawing goes to a
+    // compositor surface
+    damage: Option<fn(usize, usize, usize, usize)>,
 }
 
 unsafe impl Send for FramebufferWriter {}
@@ -27,6 +128,8 @@ impl FramebufferWriter {
     pub const fn new() -> Self {
         Self {
             addr: core::ptr::null_mut(),
+            back: core::ptr::null_mut(),
+            dirty: DirtyRect::EMPTY,
             width: 0,
             height: 0,
             pitch: 0,
@@ -37,6 +140,17 @@ impl FramebufferWriter {
             max_rows: 0,
             fg_color: 0xFFFFFFFF, // White
             bg_color: 0xFF000000, // Black
+            ansi: AnsiParser::new(),
+            graphics: GraphicState::new(),
+            saved_cursor: (0, 0),
+            font: font::builtin(),
+            utf8: (0, 0),
+            cells: Vec::new(),
+            scrollback: VecDeque::new(),
+            view_offset: 0,
+            mirror: None,
+            cursor: Cursor::new(),
+            damage: None,
         }
     }
 
@@ -48,8 +162,8 @@ impl FramebufferWriter {
         self.pitch = pitch;
         self.bpp = bpp;
         
-        self.max_cols = width / FONT_WIDTH;
-        self.max_rows = height / FONT_HEIGHT;
+        self.max_cols = width / self.font.width();
+        self.max_rows = height / self.font.height();
         
         self.col = 0;
         self.row = 0;
@@ -60,21 +174,55 @@ impl FramebufferWriter {
 
     /// Clear the entire screen
     pub fn clear(&mut self) {
+        let blank = self.blank();
+        self.cells.fill(blank);
+        self.fill_screen(self.bg_color);
+        self.col = 0;
+        self.row = 0;
+        self.flush();
+        self.mirror_bytes(b"\x1b[H\x1b[2J");
+    }
+
+    /// Memory drawing goes to: the back buffer if there is one
+    ///
+    /// Drawing straight to the screen takes the mouse cursor off it first;
+    /// `flush` puts it back.
+    fn surface(&mut self) -> *mut u8 {
+        if !self.back.is_null() {
+            return self.back;
+        }
+        if let Some(screen) = self.screen() {
+            self.cursor.hide(&screen);
+        }
+        self.addr
+    }
+
+    /// The screen, for drawing the mouse cursor
+    fn screen(&self) -> Option<cursor::Screen> {
+        (!self.addr.is_null() && self.bpp == 32).then_some(cursor::Screen {
+            addr: self.addr,
+            pitch: self.pitch,
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// Fill every pixel, including the margin past the last text row
+    fn fill_screen(&mut self, color: u32) {
         if self.addr.is_null() || self.bpp != 32 {
             return;
         }
 
+        let surface = self.surface();
+        self.dirty.add(0, 0, self.width, self.height);
         unsafe {
             for y in 0..self.height {
-                let row = self.addr.add(y * self.pitch) as *mut u32;
+                let row = surface.add(y * self.pitch) as *mut u32;
                 for x in 0..(self.pitch / 4) {
-                    row.add(x).write_volatile(self.bg_color);
+                    row.add(x).write_volatile(color);
                 }
             }
         }
-        
-        self.col = 0;
-        self.row = 0;
     }
 
     /// Set foreground color (ARGB format)
@@ -87,27 +235,24 @@ impl FramebufferWriter {
         self.bg_color = color;
     }
 
-    /// Scroll the screen up by one line
-    fn scroll_up(&mut self) {
+    /// Fill text rows `start..end` with the background color
+    fn clear_rows(&mut self, start: usize, end: usize) {
+        let blank = self.blank();
+        let cell_end = (end * self.max_cols).min(self.cells.len());
+        if let Some(cells) = self.cells.get_mut(start * self.max_cols..cell_end) {
+            cells.fill(blank);
+        }
         if self.addr.is_null() || self.bpp != 32 {
             return;
         }
 
+        let glyph_height = self.font.height();
+        let y_end = (end * glyph_height).min(self.height);
+        let surface = self.surface();
+        self.dirty.add(0, start * glyph_height, self.width, y_end.saturating_sub(start * glyph_height));
         unsafe {
-            // Copy all rows up by FONT_HEIGHT pixels
-            for y in FONT_HEIGHT..self.height {
-                let src_row = self.addr.add(y * self.pitch) as *const u32;
-                let dst_row = self.addr.add((y - FONT_HEIGHT) * self.pitch) as *mut u32;
-                
-                for x in 0..(self.pitch / 4) {
-                    dst_row.add(x).write_volatile(src_row.add(x).read_volatile());
-                }
-            }
-            
-            // Clear the last row
-            let start_y = self.height - FONT_HEIGHT;
-            for y in start_y..self.height {
-                let row = self.addr.add(y * self.pitch) as *mut u32;
+            for y in (start * glyph_height)..y_end {
+                let row = surface.add(y * self.pitch) as *mut u32;
                 for x in 0..(self.pitch / 4) {
                     row.add(x).write_volatile(self.bg_color);
                 }
@@ -115,41 +260,165 @@ impl FramebufferWriter {
         }
     }
 
+    /// Fill columns `start..end` of the current row with the background color
+    fn clear_cells(&mut self, start: usize, end: usize) {
+        let saved_col = self.col;
+        for col in start..end.min(self.max_cols) {
+            self.col = col;
+            self.draw_char(' ');
+        }
+        self.col = saved_col;
+    }
+
+    /// Scroll the screen up by one line
+    ///
+    /// Moves all text rows but the first up with a single memmove over
+    /// the pitch-sized lines, then clears the last row. With a back buffer
+    /// the move stays in RAM and the whole text area is copied out later.
+    fn scroll_up(&mut self) {
+        if self.max_rows == 0 {
+            return;
+        }
+
+        if !self.cells.is_empty() {
+            let top: Vec<Cell> = self.cells.drain(..self.max_cols).collect();
+            if self.scrollback.len() >= SCROLLBACK_LINES {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(top);
+            let blank = self.blank();
+            self.cells.resize(self.max_cols * self.max_rows, blank);
+        }
+
+        if self.addr.is_null() || self.bpp != 32 {
+            return;
+        }
+
+        let glyph_height = self.font.height();
+        let text_height = self.max_rows * glyph_height;
+        let line_bytes = glyph_height * self.pitch;
+        let surface = self.surface();
+        self.dirty.add(0, 0, self.width, text_height);
+        unsafe {
+            core::ptr::copy(
+                surface.add(line_bytes),
+                surface,
+                (text_height - glyph_height) * self.pitch,
+            );
+        }
+        self.clear_rows(self.max_rows - 1, self.max_rows);
+    }
+
     /// Draw a character at the current position
     fn draw_char(&mut self, ch: char) {
+        self.show_live();
+        let cell = Cell { ch, fg: self.fg_color, bg: self.bg_color };
+        if self.col < self.max_cols {
+            if let Some(slot) = self.cells.get_mut(self.row * self.max_cols + self.col) {
+                *slot = cell;
+            }
+        }
+        self.draw_cell(self.col, self.row, cell);
+    }
+
+    /// Draw a cell's glyph at a text position, without recording it
+    fn draw_cell(&mut self, col: usize, row: usize, cell: Cell) {
         if self.addr.is_null() || self.bpp != 32 {
             return;
         }
 
-        let bitmap = font::get_char_bitmap(ch);
-        let x_base = self.col * FONT_WIDTH;
-        let y_base = self.row * FONT_HEIGHT;
+        let font = self.font;
+        let glyph = font.glyph(cell.ch);
+        let x_base = col * font.width();
+        let y_base = row * font.height();
+        let surface = self.surface();
+        self.dirty.add(x_base, y_base, font.width(), font.height());
 
         unsafe {
-            for (row_idx, &byte) in bitmap.iter().enumerate() {
+            for row_idx in 0..font.height() {
                 let y = y_base + row_idx;
                 if y >= self.height {
                     break;
                 }
                 
-                let row_ptr = self.addr.add(y * self.pitch) as *mut u32;
+                let row_ptr = surface.add(y * self.pitch) as *mut u32;
                 
-                for bit_idx in 0..FONT_WIDTH {
+                for bit_idx in 0..font.width() {
                     let x = x_base + bit_idx;
                     if x >= self.width {
                         break;
                     }
                     
-                    let pixel_on = (byte & (0x80 >> bit_idx)) != 0;
-                    let color = if pixel_on { self.fg_color } else { self.bg_color };
+                    let pixel_on = font.pixel(glyph, bit_idx, row_idx);
+                    let color = if pixel_on { cell.fg } else { cell.bg };
                     row_ptr.add(x).write_volatile(color);
                 }
             }
         }
     }
 
-    /// Write a single byte (character) to the framebuffer
+    /// Write a single byte to the framebuffer, interpreting escape sequences
     pub fn write_byte(&mut self, byte: u8) {
+        self.feed(byte);
+        self.flush();
+        self.mirror_bytes(&[byte]);
+    }
+
+    /// Interpret one byte of output without flushing
+    fn feed(&mut self, byte: u8) {
+        self.show_live();
+        if let Some(action) = self.ansi.feed(byte) {
+            self.apply(action);
+        }
+    }
+
+    /// Carry out a parsed console action
+    fn apply(&mut self, action: AnsiAction) {
+        let last_row = self.max_rows.saturating_sub(1);
+        match action {
+            AnsiAction::Print(byte) => self.put_byte(byte),
+            AnsiAction::CursorUp(n) => self.row = self.row.saturating_sub(n),
+            AnsiAction::CursorDown(n) => self.row = self.row.saturating_add(n).min(last_row),
+            AnsiAction::CursorForward(n) => {
+                self.col = self.col.saturating_add(n).min(self.max_cols.saturating_sub(1))
+            }
+            AnsiAction::CursorBack(n) => self.col = self.col.saturating_sub(n),
+            AnsiAction::CursorPosition { row, col } => self.set_position(col, row),
+            AnsiAction::EraseDisplay(mode) => match mode {
+                EraseMode::ToEnd => {
+                    self.clear_cells(self.col, self.max_cols);
+                    self.clear_rows(self.row + 1, self.max_rows);
+                }
+                EraseMode::ToStart => {
+                    self.clear_rows(0, self.row);
+                    self.clear_cells(0, self.col + 1);
+                }
+                EraseMode::All => self.clear_rows(0, self.max_rows),
+            },
+            AnsiAction::EraseLine(mode) => match mode {
+                EraseMode::ToEnd => self.clear_cells(self.col, self.max_cols),
+                EraseMode::ToStart => self.clear_cells(0, self.col + 1),
+                EraseMode::All => self.clear_cells(0, self.max_cols),
+            },
+            AnsiAction::SetGraphics(params) => {
+                self.graphics.apply(&params);
+                self.fg_color = self.graphics.fg;
+                self.bg_color = self.graphics.bg;
+            }
+            AnsiAction::SaveCursor => self.saved_cursor = (self.col, self.row),
+            AnsiAction::RestoreCursor => {
+                let (col, row) = self.saved_cursor;
+                self.set_position(col, row);
+            }
+        }
+    }
+
+    /// Draw a printable byte or handle a plain control character
+    fn put_byte(&mut self, byte: u8) {
+        if byte < 0x80 {
+            // Cuts short any unfinished UTF-8 sequence
+            self.utf8 = (0, 0);
+        }
         match byte {
             b'\n' => {
                 // Newline
@@ -163,7 +432,7 @@ impl FramebufferWriter {
             b'\t' => {
                 // Tab (4 spaces)
                 for _ in 0..4 {
-                    self.write_byte(b' ');
+                    self.put_byte(b' ');
                 }
             }
             0x08 => {
@@ -173,16 +442,16 @@ impl FramebufferWriter {
                     self.draw_char(' ');
                 }
             }
+            byte if byte >= 0x80 => {
+                // Part of a UTF-8 sequence
+                if let Some(ch) = self.decode_utf8(byte) {
+                    self.put_char(ch);
+                }
+            }
             byte => {
                 // Regular character
-                if self.col >= self.max_cols {
-                    self.col = 0;
-                    self.row += 1;
-                }
-                
                 if byte >= 32 && byte < 127 {
-                    self.draw_char(byte as char);
-                    self.col += 1;
+                    self.put_char(byte as char);
                 }
             }
         }
@@ -194,11 +463,96 @@ impl FramebufferWriter {
         }
     }
 
+    /// Draw a printable character and advance, wrapping at the right edge
+    fn put_char(&mut self, ch: char) {
+        if self.col >= self.max_cols {
+            self.col = 0;
+            self.row += 1;
+            if self.row >= self.max_rows {
+                self.scroll_up();
+                self.row = self.max_rows - 1;
+            }
+        }
+        self.draw_char(ch);
+        self.col += 1;
+    }
+
+    /// Feed a byte of a UTF-8 sequence, returning the character it completes
+    ///
+    /// Malformed sequences come out as U+FFFD.
+    fn decode_utf8(&mut self, byte: u8) -> Option<char> {
+        let (code, left) = self.utf8;
+        if byte & 0xC0 == 0x80 {
+            if left == 0 {
+                return Some(char::REPLACEMENT_CHARACTER);
+            }
+            let code = (code << 6) | (byte & 0x3F) as u32;
+            self.utf8 = (code, left - 1);
+            if left > 1 {
+                return None;
+            }
+            return Some(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+
+        // A lead byte; an unfinished sequence before it is dropped
+        self.utf8 = match byte {
+            0xC0..=0xDF => ((byte & 0x1F) as u32, 1),
+            0xE0..=0xEF => ((byte & 0x0F) as u32, 2),
+            0xF0..=0xF7 => ((byte & 0x07) as u32, 3),
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+        None
+    }
+
     /// Write a string to the framebuffer
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            self.write_byte(byte);
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Write raw bytes, which need not be valid UTF-8, to the framebuffer
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.feed(byte);
+        }
+        self.flush();
+        self.mirror_bytes(bytes);
+    }
+
+    /// Send a copy of output to the mirror, if there is one
+    pub fn mirror_bytes(&self, bytes: &[u8]) {
+        if let Some(mirror) = self.mirror {
+            mirror(bytes);
+        }
+    }
+
+    /// Copy output to `mirror` from now on, or stop copying with `None`
+    ///
+    /// # Returns
+    /// The previous mirror
+    pub fn set_mirror(&mut self, mirror: Option<fn(&[u8])>) -> Option<fn(&[u8])> {
+        core::mem::replace(&mut self.mirror, mirror)
+    }
+
+    /// Show an input line on the mirror, with the cursor `cursor`
+    /// characters into `text`
+    ///
+    /// `redraw_line` only draws on the screen; line editors call this too
+    /// so a terminal following the console sees the edit.
+    pub fn mirror_line(&self, prompt: &str, text: &[char], cursor: usize) {
+        use fmt::Write;
+
+        if self.mirror.is_none() {
+            return;
+        }
+        let mut line = alloc::string::String::from("\r");
+        line.push_str(prompt);
+        line.extend(text);
+        line.push_str("\x1b[K\r");
+        let column = prompt.chars().count() + cursor;
+        if column > 0 {
+            let _ = write!(line, "\x1b[{}C", column);
         }
+        self.mirror_bytes(line.as_bytes());
     }
 
     /// Get current column position
@@ -214,7 +568,7 @@ impl FramebufferWriter {
     /// Set cursor position
     pub fn set_position(&mut self, col: usize, row: usize) {
         self.col = col.min(self.max_cols);
-        self.row = row.min(self.max_rows - 1);
+        self.row = row.min(self.max_rows.saturating_sub(1));
     }
 
     /// Clear from current position to end of line
@@ -231,6 +585,7 @@ impl FramebufferWriter {
         // Restore position
         self.col = saved_col;
         self.row = saved_row;
+        self.flush();
     }
 
     /// Redraw the current line with the given text, starting from the given column
@@ -257,6 +612,7 @@ impl FramebufferWriter {
         
         self.col = saved_col;
         self.row = saved_row;
+        self.flush();
     }
 
     /// Draw a cursor at the current position
@@ -274,11 +630,256 @@ impl FramebufferWriter {
         // Restore colors
         self.fg_color = saved_fg;
         self.bg_color = saved_bg;
+        self.flush();
     }
 
     pub fn is_initialized(&self) -> bool {
         !self.addr.is_null()
     }
+
+    /// Draw into `back` from now on and copy changes to `front`
+    ///
+    /// `front` is a new mapping of the screen, typically write-combining.
+    /// The current picture is copied into `back` first.
+    ///
+    /// # Safety
+    /// `front` must map the same framebuffer as the current address, and
+    /// `back` must point to `height * pitch` writable bytes that stay valid.
+    pub unsafe fn set_buffers(&mut self, front: *mut u8, back: *mut u8) {
+        if let Some(screen) = self.screen() {
+            self.cursor.hide(&screen);
+        }
+        core::ptr::copy_nonoverlapping(self.addr, back, self.height * self.pitch);
+        self.addr = front;
+        self.back = back;
+        self.dirty = DirtyRect::EMPTY;
+    }
+
+    /// Whether drawing goes through a back buffer
+    pub fn is_double_buffered(&self) -> bool {
+        !self.back.is_null()
+    }
+
+    /// Copy what changed in the back buffer to the screen and draw the
+    /// mouse cursor over it
+    pub fn flush(&mut self) {
+        let dirty = core::mem::replace(&mut self.dirty, DirtyRect::EMPTY).clip(self.width, self.height);
+        if !self.back.is_null() && !self.addr.is_null() && !dirty.is_empty() {
+            let offset = dirty.x0 * 4;
+            let len = (dirty.x1 - dirty.x0) * 4;
+            unsafe {
+                for y in dirty.y0..dirty.y1 {
+                    let line = y * self.pitch + offset;
+                    core::ptr::copy_nonoverlapping(self.back.add(line), self.addr.add(line), len);
+                }
+            }
+            if let Some(screen) = self.screen() {
+                self.cursor.uncover(&screen, dirty.x0, dirty.y0, dirty.x1, dirty.y1);
+            }
+        }
+        if let Some(damage) = self.damage.filter(|_| !dirty.is_empty()) {
+            damage(dirty.x0, dirty.y0, dirty.x1, dirty.y1);
+        }
+        self.update_cursor();
+    }
+
+    /// The screen and the back buffer, if drawing goes through one
+    pub fn buffers(&self) -> Option<(cursor::Screen, *mut u8)> {
+        self.screen().filter(|_| self.is_double_buffered()).map(|screen| (screen, self.back))
+    }
+
+    /// Draw into a `width` by `height` area at `pixels` from now on and
+    /// pass every changed rectangle to `damage` instead of copying it out
+    ///
+    /// The text is laid out again for the new size. The mouse cursor is
+    /// left to whoever owns the screen now.
+    ///
+    /// # Safety
+    /// `pixels` must point to `height` rows of `pitch` writable bytes that
+    /// stay valid, and the caller must stop using the old buffers.
+    pub unsafe fn redirect(
+        &mut self,
+        pixels: *mut u8,
+        width: usize,
+        height: usize,
+        pitch: usize,
+        damage: fn(usize, usize, usize, usize),
+    ) {
+        if let Some(screen) = self.screen() {
+            self.cursor.set_visible(false, &screen);
+        }
+        self.addr = pixels;
+        self.back = core::ptr::null_mut();
+        self.dirty = DirtyRect::EMPTY;
+        self.width = width;
+        self.height = height;
+        self.pitch = pitch;
+        self.damage = Some(damage);
+        self.set_font(self.font);
+    }
+
+    /// Screen size in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Show or hide the mouse cursor
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        if let Some(screen) = self.screen() {
+            self.cursor.set_visible(visible, &screen);
+        }
+        self.update_cursor();
+    }
+
+    /// Move the mouse cursor to the pointer position
+    pub fn update_cursor(&mut self) {
+        if !self.cursor.is_visible() {
+            return;
+        }
+        if let Some(screen) = self.screen() {
+            let (x, y) = super::input::pointer_position();
+            self.cursor.show_at(&screen, x as usize, y as usize);
+        }
+    }
+
+    /// Blank cell in the current background color
+    fn blank(&self) -> Cell {
+        Cell { ch: ' ', fg: self.fg_color, bg: self.bg_color }
+    }
+
+    /// Start keeping the screen text and a scrollback (needs the heap)
+    pub fn enable_text_buffer(&mut self) {
+        if self.cells.is_empty() {
+            let blank = self.blank();
+            self.cells = alloc::vec![blank; self.max_cols * self.max_rows];
+        }
+    }
+
+    /// Create an empty offscreen console with the same geometry and font
+    pub fn offscreen(&self) -> Self {
+        let mut writer = Self::new();
+        writer.font = self.font;
+        writer.init(core::ptr::null_mut(), self.width, self.height, self.pitch, self.bpp);
+        writer.enable_text_buffer();
+        writer
+    }
+
+    /// Move the console to `other`'s framebuffer, leaving `other` offscreen
+    ///
+    /// Both writers must have the same geometry. The console taking over
+    /// the framebuffer is redrawn from its text buffer.
+    pub fn take_screen(&mut self, other: &mut Self) {
+        self.addr = core::mem::replace(&mut other.addr, core::ptr::null_mut());
+        self.back = core::mem::replace(&mut other.back, core::ptr::null_mut());
+        self.cursor = core::mem::take(&mut other.cursor);
+        self.damage = other.damage.take();
+        self.render();
+    }
+
+    /// Font the console draws with
+    pub fn font(&self) -> &'static Font {
+        self.font
+    }
+
+    /// Switch to another font and lay the text out again
+    ///
+    /// The text grid changes size with the glyphs. Text keeps its row and
+    /// column; rows that no longer fit above the cursor move to the
+    /// scrollback, and text past the new right edge is cut off.
+    pub fn set_font(&mut self, font: &'static Font) {
+        let (old_cols, old_rows) = (self.max_cols, self.max_rows);
+        self.font = font;
+        self.max_cols = self.width / font.width();
+        self.max_rows = self.height / font.height();
+        if self.max_cols == 0 || self.max_rows == 0 {
+            return;
+        }
+
+        let skip = (self.row + 1).saturating_sub(self.max_rows);
+        if !self.cells.is_empty() {
+            let old = core::mem::take(&mut self.cells);
+            let blank = self.blank();
+            self.cells = alloc::vec![blank; self.max_cols * self.max_rows];
+            for (row, line) in old.chunks_exact(old_cols).take(old_rows).enumerate() {
+                if row < skip {
+                    if self.scrollback.len() >= SCROLLBACK_LINES {
+                        self.scrollback.pop_front();
+                    }
+                    self.scrollback.push_back(line.to_vec());
+                } else if row - skip < self.max_rows {
+                    let start = (row - skip) * self.max_cols;
+                    let len = old_cols.min(self.max_cols);
+                    self.cells[start..start + len].copy_from_slice(&line[..len]);
+                }
+            }
+        }
+
+        self.row -= skip;
+        self.col = self.col.min(self.max_cols - 1);
+        self.saved_cursor = (0, 0);
+        self.view_offset = 0;
+        self.fill_screen(self.bg_color);
+        self.render();
+    }
+
+    /// Number of text rows on screen
+    pub fn rows(&self) -> usize {
+        self.max_rows
+    }
+
+    /// Number of text columns on screen
+    pub fn cols(&self) -> usize {
+        self.max_cols
+    }
+
+    /// Lines in the scrollback
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    /// Scroll the view back (positive) or forward (negative) by `lines`
+    ///
+    /// The view stays within the scrollback; writing snaps it back to the
+    /// live screen.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let offset = (self.view_offset as isize + lines).clamp(0, self.scrollback.len() as isize) as usize;
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.render();
+        }
+    }
+
+    /// Bring a scrolled-back view back to the live screen
+    ///
+    /// New output always lands on the live screen.
+    fn show_live(&mut self) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.render();
+        }
+    }
+
+    /// Cell shown at a text position for the current view
+    fn visible_cell(&self, col: usize, row: usize) -> Option<Cell> {
+        let line = self.scrollback.len() - self.view_offset + row;
+        match line.checked_sub(self.scrollback.len()) {
+            Some(screen_row) => self.cells.get(screen_row * self.max_cols + col).copied(),
+            None => self.scrollback.get(line).and_then(|l| l.get(col)).copied(),
+        }
+    }
+
+    /// Redraw the whole screen from the text buffer
+    pub fn render(&mut self) {
+        if !self.addr.is_null() && !self.cells.is_empty() {
+            let blank = Cell { ch: ' ', fg: super::ansi::DEFAULT_FG, bg: super::ansi::DEFAULT_BG };
+            for row in 0..self.max_rows {
+                for col in 0..self.max_cols {
+                    self.draw_cell(col, row, self.visible_cell(col, row).unwrap_or(blank));
+                }
+            }
+        }
+        self.flush();
+    }
 }
 
 impl fmt::Write for FramebufferWriter {
@@ -296,6 +897,26 @@ pub fn init(addr: *mut u8, width: usize, height: usize, pitch: usize, bpp: usize
     FRAMEBUFFER.lock().init(addr, width, height, pitch, bpp);
 }
 
+/// Switch the console to a write-combining mapping and a back buffer
+///
+/// Needs `ioremap` and the PMM; the back buffer is too large for the heap.
+pub fn enable_double_buffering(hhdm_offset: u64) -> Result<(), &'static str> {
+    let mut fb = FRAMEBUFFER.lock();
+    if fb.addr.is_null() || fb.bpp != 32 {
+        return Err("No framebuffer console");
+    }
+    if fb.is_double_buffered() {
+        return Ok(());
+    }
+
+    let size = (fb.height * fb.pitch) as u64;
+    let phys = fb.addr as u64 - hhdm_offset;
+    let front = crate::memory::mmio::ioremap_wc(phys, size)? as *mut u8;
+    let back = crate::memory::mmio::alloc_buffer(size)? as *mut u8;
+    unsafe { fb.set_buffers(front, back) };
+    Ok(())
+}
+
 /// Get access to the framebuffer
 pub fn framebuffer() -> spin::MutexGuard<'static, FramebufferWriter> {
     FRAMEBUFFER.lock()
@@ -307,6 +928,28 @@ pub fn _print(args: fmt::Arguments) {
     FRAMEBUFFER.lock().write_fmt(args).unwrap();
 }
 
+/// Move the mouse cursor to the pointer position unless the console is in
+/// use
+///
+/// Called from the mouse interrupt. If the console is busy, the cursor
+/// catches up when the console next flushes.
+pub fn move_cursor() {
+    if let Some(mut fb) = FRAMEBUFFER.try_lock() {
+        fb.update_cursor();
+    }
+}
+
+/// Print to the framebuffer unless it is in use
+///
+/// For panic and exception reports, which may interrupt a writer holding
+/// the lock.
+pub fn _print_emergency(args: fmt::Arguments) {
+    use fmt::Write;
+    if let Some(mut fb) = FRAMEBUFFER.try_lock() {
+        let _ = fb.write_fmt(args);
+    }
+}
+
 #[macro_export]
 macro_rules! fb_print {
     ($($arg:tt)*) => {{
@@ -320,3 +963,49 @@ macro_rules! fb_println {
     ($fmt:expr) => {$crate::fb_print!(concat!($fmt, "\n"))};
     ($fmt:expr, $($arg:tt)*) => {$crate::fb_print!(concat!($fmt, "\n"), $($arg)*)};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_rect_union() {
+        let mut dirty = DirtyRect::EMPTY;
+        assert!(dirty.is_empty());
+
+        dirty.add(8, 16, 8, 16);
+        dirty.add(0, 48, 8, 16);
+        dirty.add(100, 0, 0, 16);
+        assert_eq!(dirty, DirtyRect { x0: 0, y0: 16, x1: 16, y1: 64 });
+    }
+
+    #[test]
+    fn test_dirty_rect_clip() {
+        let mut dirty = DirtyRect::EMPTY;
+        dirty.add(630, 470, 16, 16);
+        assert_eq!(dirty.clip(640, 480), DirtyRect { x0: 630, y0: 470, x1: 640, y1: 480 });
+        assert!(DirtyRect::EMPTY.clip(640, 480).is_empty());
+    }
+
+    static MIRRORED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+    fn capture(bytes: &[u8]) {
+        MIRRORED.lock().extend_from_slice(bytes);
+    }
+
+    #[test]
+    fn test_mirror() {
+        let mut writer = FramebufferWriter::new();
+        writer.init(core::ptr::null_mut(), 640, 480, 2560, 32);
+        writer.write_string("unseen");
+        assert!(writer.set_mirror(Some(capture)).is_none());
+
+        writer.write_string("ls\n");
+        writer.mirror_line("> ", &['c', 'a', 't'], 1);
+        assert_eq!(MIRRORED.lock().as_slice(), b"ls\n\r> cat\x1b[K\r\x1b[3C");
+
+        assert!(writer.set_mirror(None).is_some());
+        writer.write_string("gone");
+        assert_eq!(MIRRORED.lock().len(), 17);
+    }
+}
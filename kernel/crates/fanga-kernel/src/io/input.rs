@@ -6,6 +6,99 @@ use alloc::collections::VecDeque;
 /// Keyboard input buffer
 static INPUT_BUFFER: Mutex<VecDeque<char>> = Mutex::new(VecDeque::new());
 
+/// Pointer event queue
+static EVENT_QUEUE: Mutex<VecDeque<InputEvent>> = Mutex::new(VecDeque::new());
+
+/// Maximum number of queued pointer events
+const MAX_EVENTS: usize = 256;
+
+/// Absolute pointer position
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer::new());
+
+/// Mouse button
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+/// Pointer input event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Relative movement; positive y is down the screen. `x` and `y` are
+    /// the pointer position after it
+    MouseMove { dx: i32, dy: i32, x: i32, y: i32 },
+    /// Button pressed or released
+    MouseButton { button: MouseButton, pressed: bool },
+    /// Wheel movement; positive is towards the user (scroll down)
+    MouseScroll(i32),
+}
+
+/// Pointer position built from relative movement, kept on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Pointer {
+    pub const fn new() -> Self {
+        Self { x: 0, y: 0, width: 1, height: 1 }
+    }
+
+    /// Confine the pointer to a `width` by `height` screen and center it
+    pub fn set_bounds(&mut self, width: usize, height: usize) {
+        self.width = (width as i32).max(1);
+        self.height = (height as i32).max(1);
+        self.x = self.width / 2;
+        self.y = self.height / 2;
+    }
+
+    /// Move by `dx`, `dy`, stopping at the edges
+    ///
+    /// # Returns
+    /// The new position
+    pub fn move_by(&mut self, dx: i32, dy: i32) -> (i32, i32) {
+        self.x = self.x.saturating_add(dx).clamp(0, self.width - 1);
+        self.y = self.y.saturating_add(dy).clamp(0, self.height - 1);
+        self.position()
+    }
+
+    /// Current position
+    pub fn position(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+}
+
+impl Default for Pointer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Confine the pointer to a `width` by `height` screen and center it
+pub fn set_pointer_bounds(width: usize, height: usize) {
+    POINTER.lock().set_bounds(width, height);
+}
+
+/// Move the pointer by a mouse's relative movement
+///
+/// # Returns
+/// The new position
+pub fn move_pointer(dx: i32, dy: i32) -> (i32, i32) {
+    POINTER.lock().move_by(dx, dy)
+}
+
+/// Current pointer position in pixels
+pub fn pointer_position() -> (i32, i32) {
+    POINTER.lock().position()
+}
+
 /// Add a character to the input buffer
 pub fn push_char(ch: char) {
     let mut buffer = INPUT_BUFFER.lock();
@@ -30,3 +123,40 @@ pub fn is_empty() -> bool {
 pub fn clear() {
     INPUT_BUFFER.lock().clear()
 }
+
+/// Queue a pointer event
+///
+/// Called from interrupt context. When the queue is full the oldest
+/// event is dropped.
+pub fn push_event(event: InputEvent) {
+    let mut queue = EVENT_QUEUE.lock();
+    if queue.len() >= MAX_EVENTS {
+        queue.pop_front();
+    }
+    queue.push_back(event);
+}
+
+/// Take the oldest pointer event (non-blocking)
+pub fn pop_event() -> Option<InputEvent> {
+    EVENT_QUEUE.lock().pop_front()
+}
+
+/// Number of queued pointer events
+pub fn pending_events() -> usize {
+    EVENT_QUEUE.lock().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pointer_stays_on_screen() {
+        let mut pointer = Pointer::new();
+        pointer.set_bounds(640, 480);
+        assert_eq!(pointer.position(), (320, 240));
+        assert_eq!(pointer.move_by(10, -40), (330, 200));
+        assert_eq!(pointer.move_by(-1000, 1000), (0, 479));
+        assert_eq!(pointer.move_by(i32::MAX, i32::MIN), (639, 0));
+    }
+}
@@ -7,6 +7,7 @@ use fanga_arch_x86_64::keyboard::{KeyEvent, Keyboard};
 
 /// Keyboard event callback that will be called from the interrupt handler
 pub fn keyboard_callback(event: KeyEvent, kbd: &Keyboard) {
+    crate::random::add_input_randomness(matches!(event, KeyEvent::Press(_)) as u32);
     crate::io::keyboard_handler::handle_key_event(event, kbd);
 }
 
@@ -1,9 +1,10 @@
-use crate::io::{framebuffer, line_editor};
+use crate::io::{framebuffer, line_editor, tty};
 use crate::shell;
 /// Keyboard input handler with line editing
 ///
 /// This module handles keyboard input events, provides line editing functionality,
 /// and echoes input to the framebuffer console.
+use core::sync::atomic::{AtomicBool, Ordering};
 use fanga_arch_x86_64::keyboard::{KeyCode, KeyEvent};
 
 /// Handle a keyboard event
@@ -17,63 +18,56 @@ pub fn handle_key_event(event: KeyEvent, kbd: &fanga_arch_x86_64::keyboard::Keyb
 /// Handle a key press event
 fn handle_key_press(keycode: KeyCode, kbd: &fanga_arch_x86_64::keyboard::Keyboard) {
     // Handle special key combinations first
+
+    // Ctrl+Alt+K enters the kernel debugger
+    if kbd.is_ctrl_pressed() && kbd.is_alt_pressed() && matches!(keycode, KeyCode::Char('k') | KeyCode::Char('K')) {
+        crate::debug::kdb::enter(crate::debug::kdb::KdbReason::Hotkey, crate::debug::kdb::KdbRegs::capture());
+        return;
+    }
     
-    // Alt+F1 through Alt+F12 for virtual terminal switching
+    // Alt+F1 through Alt+F4 for virtual terminal switching
     if kbd.is_alt_pressed() {
-        match keycode {
-            KeyCode::F1 => {
-                crate::io::vt::switch_terminal(0);
-                return;
-            }
-            KeyCode::F2 => {
-                crate::io::vt::switch_terminal(1);
-                return;
-            }
-            KeyCode::F3 => {
-                crate::io::vt::switch_terminal(2);
-                return;
-            }
-            KeyCode::F4 => {
-                crate::io::vt::switch_terminal(3);
-                return;
-            }
-            KeyCode::F5 => {
-                crate::io::vt::switch_terminal(4);
-                return;
-            }
-            KeyCode::F6 => {
-                crate::io::vt::switch_terminal(5);
-                return;
-            }
-            KeyCode::F7 => {
-                crate::io::vt::switch_terminal(6);
-                return;
-            }
-            KeyCode::F8 => {
-                crate::io::vt::switch_terminal(7);
-                return;
-            }
-            KeyCode::F9 => {
-                crate::io::vt::switch_terminal(8);
-                return;
-            }
-            KeyCode::F10 => {
-                crate::io::vt::switch_terminal(9);
-                return;
-            }
-            KeyCode::F11 => {
-                crate::io::vt::switch_terminal(10);
-                return;
-            }
-            KeyCode::F12 => {
-                crate::io::vt::switch_terminal(11);
-                return;
-            }
-            _ => {}
+        let terminal = match keycode {
+            KeyCode::F1 => Some(0),
+            KeyCode::F2 => Some(1),
+            KeyCode::F3 => Some(2),
+            KeyCode::F4 => Some(3),
+            _ => None,
+        };
+        if let Some(id) = terminal {
+            crate::io::vt::switch_terminal(id);
+            return;
         }
     }
-    
-    if kbd.is_ctrl_pressed() {
+
+    // Shift+PageUp/PageDown scroll through the terminal's scrollback
+    if kbd.is_shift_pressed() && matches!(keycode, KeyCode::PageUp | KeyCode::PageDown) {
+        let mut fb = framebuffer::framebuffer();
+        let page = (fb.rows() / 2).max(1) as isize;
+        fb.scroll_view(if keycode == KeyCode::PageUp { page } else { -page });
+        return;
+    }
+
+    handle_line_key(keycode, kbd.to_ascii(keycode), kbd.is_ctrl_pressed());
+}
+
+/// Handle a key that edits or submits the shell's input line
+///
+/// Shared by the keyboard and the serial console. `ascii` is the
+/// character the key produces, if any. While a program owns the terminal
+/// the key goes to its TTY instead, and while `top` runs it goes to `top`.
+pub fn handle_line_key(keycode: KeyCode, ascii: Option<char>, ctrl: bool) {
+    if tty::handle_key(keycode, ascii, ctrl)
+        || shell::top::handle_key(keycode, ascii, ctrl)
+        || shell::net::handle_ping_key(keycode, ctrl)
+    {
+        return;
+    }
+    if keycode != KeyCode::Tab {
+        TAB_PENDING.store(false, Ordering::Relaxed);
+    }
+
+    if ctrl {
         match keycode {
             KeyCode::Char('c') | KeyCode::Char('C') => {
                 // Ctrl+C - interrupt current line
@@ -91,9 +85,9 @@ fn handle_key_press(keycode: KeyCode, kbd: &fanga_arch_x86_64::keyboard::Keyboar
 
     // Handle regular keys
     match keycode {
-        KeyCode::Char(_ch) => {
+        KeyCode::Char(_) | KeyCode::Iso102 => {
             // Insert character
-            if let Some(ascii) = kbd.to_ascii(keycode) {
+            if let Some(ascii) = ascii {
                 let mut editor_guard = line_editor::editor();
                 if let Some(editor) = editor_guard.as_mut() {
                     if editor.insert_char(ascii) {
@@ -178,58 +172,48 @@ fn handle_key_press(keycode: KeyCode, kbd: &fanga_arch_x86_64::keyboard::Keyboar
     }
 }
 
+/// The shell's prompt, or an empty one before the shell is up
+fn current_prompt() -> alloc::string::String {
+    shell::shell()
+        .as_ref()
+        .map(|shell| alloc::string::String::from(shell.prompt()))
+        .unwrap_or_default()
+}
+
 /// Redraw the current line in the framebuffer
 fn redraw_line(editor: &line_editor::LineEditor) {
+    let prompt = current_prompt();
     let mut fb = framebuffer::framebuffer();
 
-    // Get prompt length
-    let prompt_len = if shell::is_initialized() {
-        let shell_guard = shell::shell();
-        if let Some(shell) = shell_guard.as_ref() {
-            shell.prompt().len()
-        } else {
-            0
-        }
-    } else {
-        0
-    };
-
     // Redraw the entire line
     fb.redraw_line(0, editor.buffer());
 
     // Update cursor position
     let row = fb.get_row();
-    fb.set_position(prompt_len + editor.cursor(), row);
+    fb.set_position(prompt.len() + editor.cursor(), row);
     fb.draw_cursor();
+    fb.mirror_line(&prompt, editor.buffer(), editor.cursor());
 }
 
 /// Update cursor position without redrawing the whole line
 fn update_cursor(editor: &line_editor::LineEditor) {
+    let prompt = current_prompt();
     let mut fb = framebuffer::framebuffer();
 
-    // Get prompt length
-    let prompt_len = if shell::is_initialized() {
-        let shell_guard = shell::shell();
-        if let Some(shell) = shell_guard.as_ref() {
-            shell.prompt().len()
-        } else {
-            0
-        }
-    } else {
-        0
-    };
-
     // Redraw line to clear old cursor and show new position
     fb.redraw_line(0, editor.buffer());
     let row = fb.get_row();
-    fb.set_position(prompt_len + editor.cursor(), row);
+    fb.set_position(prompt.len() + editor.cursor(), row);
     fb.draw_cursor();
+    fb.mirror_line(&prompt, editor.buffer(), editor.cursor());
 }
 
 /// Handle Ctrl+C (interrupt)
 fn handle_ctrl_c() {
+    let prompt = current_prompt();
     let mut fb = framebuffer::framebuffer();
     fb.write_string("^C\n");
+    fb.write_string(&prompt);
 
     // Clear the line editor
     let mut editor_guard = line_editor::editor();
@@ -261,9 +245,10 @@ fn handle_ctrl_d() {
 }
 
 /// Handle Enter key (submit line)
+///
+/// The framebuffer is not held while the command runs, since commands
+/// print to it themselves.
 fn handle_enter() {
-    let mut fb = framebuffer::framebuffer();
-
     // Get the completed line
     let line = {
         let editor_guard = line_editor::editor();
@@ -275,7 +260,26 @@ fn handle_enter() {
     };
 
     // Echo newline
-    fb.write_string("\n");
+    framebuffer::framebuffer().write_string("\n");
+
+    // Replace a `!n` or `!!` history reference, showing the result
+    let expanded = shell::history::history().as_ref().map(|history| history.expand(&line));
+    let line = match expanded {
+        Some(Ok(Some(expanded))) => {
+            let mut fb = framebuffer::framebuffer();
+            fb.write_string(&expanded);
+            fb.write_string("\n");
+            expanded
+        }
+        Some(Err(err)) => {
+            let mut fb = framebuffer::framebuffer();
+            fb.write_string("Error: ");
+            fb.write_string(err);
+            fb.write_string("\n");
+            alloc::string::String::new()
+        }
+        _ => line,
+    };
 
     // Process the line through the shell
     if shell::is_initialized() {
@@ -292,6 +296,7 @@ fn handle_enter() {
             let mut shell_guard = shell::shell();
             if let Some(shell) = shell_guard.as_mut() {
                 if let Err(err) = shell.execute(&line) {
+                    let mut fb = framebuffer::framebuffer();
                     fb.write_string("Error: ");
                     fb.write_string(err);
                     fb.write_string("\n");
@@ -299,11 +304,13 @@ fn handle_enter() {
             }
         }
 
-        // Show prompt for next command
-        let shell_guard = shell::shell();
-        if let Some(shell) = shell_guard.as_ref() {
-            if shell.is_running() {
-                fb.write_string(shell.prompt());
+        // Show prompt for next command, unless it started a program,
+        // after reporting jobs that finished
+        let mut shell_guard = shell::shell();
+        if let Some(shell) = shell_guard.as_mut() {
+            if shell.is_running() && !shell.is_waiting() {
+                shell.report_jobs();
+                framebuffer::framebuffer().write_string(shell.prompt());
             }
         }
     }
@@ -351,62 +358,74 @@ fn handle_history_next() {
     }
 }
 
-/// Handle Tab key (command completion)
+/// Whether the last line key was a Tab that could not complete further
+static TAB_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Handle Tab key (command and path completion)
+///
+/// Completes the word before the cursor as far as all its completions
+/// agree. A second Tab in a row that adds nothing lists them.
 fn handle_tab_completion() {
-    // Get current line
-    let current_line = {
+    let was_pending = TAB_PENDING.swap(false, Ordering::Relaxed);
+
+    // Text before the cursor
+    let before_cursor: alloc::string::String = {
         let editor_guard = line_editor::editor();
-        if let Some(editor) = editor_guard.as_ref() {
-            editor.get_line()
-        } else {
-            return;
+        match editor_guard.as_ref() {
+            Some(editor) => editor.buffer()[..editor.cursor()].iter().collect(),
+            None => return,
         }
     };
 
-    // Only complete if we're at the beginning (completing command name)
-    let trimmed = current_line.trim();
-    if trimmed.contains(' ') {
-        // Already has arguments, don't complete
+    let (start, matches) = shell::completion::complete_line(&before_cursor);
+    let word_len = before_cursor.len() - start;
+    let Some(common) = shell::completion::common_prefix(&matches) else {
         return;
-    }
+    };
 
-    // Try to complete
-    if let Some(completed) = shell::completion::complete_single(trimmed) {
-        // Single match - complete it
+    let mut insert = alloc::string::String::from(&common[word_len..]);
+    if matches.len() == 1 && !common.ends_with('/') {
+        insert.push(' ');
+    }
+    if !insert.is_empty() {
         let mut editor_guard = line_editor::editor();
         if let Some(editor) = editor_guard.as_mut() {
-            editor.clear();
-            for ch in completed.chars() {
+            for ch in insert.chars() {
                 editor.insert_char(ch);
             }
             redraw_line(editor);
         }
-    } else {
-        // Multiple or no matches - show possibilities
-        let matches = shell::completion::complete(trimmed);
-        if !matches.is_empty() {
-            let mut fb = framebuffer::framebuffer();
-            fb.write_string("\n");
-            for cmd in matches {
-                fb.write_string(&cmd);
-                fb.write_string("  ");
-            }
-            fb.write_string("\n");
+        return;
+    }
 
-            // Show prompt and current line again
-            let shell_guard = shell::shell();
-            if let Some(shell) = shell_guard.as_ref() {
-                fb.write_string(shell.prompt());
-            }
+    if !was_pending {
+        TAB_PENDING.store(true, Ordering::Relaxed);
+        return;
+    }
 
-            let editor_guard = line_editor::editor();
-            if let Some(editor) = editor_guard.as_ref() {
-                for ch in editor.buffer() {
-                    let mut s = alloc::string::String::new();
-                    s.push(*ch);
-                    fb.write_string(&s);
-                }
-            }
+    // Second Tab: show the possibilities, by their last component for paths
+    let mut fb = framebuffer::framebuffer();
+    fb.write_string("\n");
+    for completion in &matches {
+        let trimmed = completion.strip_suffix('/').unwrap_or(completion);
+        let name_start = trimmed.rfind('/').map_or(0, |i| i + 1);
+        fb.write_string(&completion[name_start..]);
+        fb.write_string("  ");
+    }
+    fb.write_string("\n");
+
+    // Show prompt and current line again
+    let shell_guard = shell::shell();
+    if let Some(shell) = shell_guard.as_ref() {
+        fb.write_string(shell.prompt());
+    }
+
+    let editor_guard = line_editor::editor();
+    if let Some(editor) = editor_guard.as_ref() {
+        for ch in editor.buffer() {
+            let mut s = alloc::string::String::new();
+            s.push(*ch);
+            fb.write_string(&s);
         }
     }
 }
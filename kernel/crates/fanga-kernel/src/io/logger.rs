@@ -1,6 +1,42 @@
+//! Kernel Logger
+//!
+//! Every message becomes a record with a sequence number, a timestamp, a
+//! level and a subsystem tag. Records are kept in a fixed-size ring that
+//! needs no heap, so logging works from the first line of boot. When the
+//! ring is full the oldest records are overwritten.
+//!
+//! Records at or above the console level are also printed to the console.
+//! Set the level with `loglevel=debug|info|warn|error` on the command line
+//! or with `dmesg -n`; `quiet` means `loglevel=warn`.
+//!
+//! The ring is read with the `dmesg` shell command, and through
+//! `/proc/kmsg`. `/proc/kmsg` hands out each record once, to whichever
+//! reader gets it first, in the `<priority>[seconds] tag: message` syslog
+//! format.
+//!
+//! Use the macros, with a subsystem tag unless the message is general:
+//! `log_info!(target: "net", "link up")` or `log_warn!("low memory")`.
+//! The arch crate cannot reach the logger and still prints to serial
+//! directly, as do the lock validator and the fault handlers, which may
+//! run while the logger's locks are held.
+
 use core::fmt;
 use core::sync::atomic::{AtomicU8, Ordering};
 
+use crate::smp::SpinLock;
+
+/// Records kept in the ring
+pub const LOG_RECORDS: usize = 512;
+
+/// Longest message kept, in bytes; longer ones are cut off
+pub const MAX_MESSAGE: usize = 224;
+
+/// Longest subsystem tag kept, in bytes
+pub const MAX_TAG: usize = 15;
+
+/// Tag of messages logged without one
+pub const DEFAULT_TAG: &str = "kernel";
+
 /// Log levels for the kernel logging framework
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
@@ -29,65 +65,420 @@ impl LogLevel {
             LogLevel::Error => 0xFFFF0000, // Red
         }
     }
+
+    /// ANSI graphic rendition for the level, understood by both the
+    /// framebuffer console and serial terminals
+    pub fn sgr(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "90", // Bright black (gray)
+            LogLevel::Info => "0",   // Default
+            LogLevel::Warn => "93",  // Bright yellow
+            LogLevel::Error => "91", // Bright red
+        }
+    }
+
+    /// Syslog priority of the level, as used in `/proc/kmsg`
+    pub fn syslog_priority(&self) -> u8 {
+        match self {
+            LogLevel::Debug => 7,
+            LogLevel::Info => 6,
+            LogLevel::Warn => 4,
+            LogLevel::Error => 3,
+        }
+    }
+
+    /// Parse a level name such as `info` or `warn`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "error" | "err" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Info,
+            2 => LogLevel::Warn,
+            _ => LogLevel::Error,
+        }
+    }
 }
 
-/// Global log level filter
-static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+/// A log record
+#[derive(Clone, Copy)]
+pub struct LogRecord {
+    /// Position in the log since boot
+    pub seq: u64,
+    /// Time since boot
+    pub timestamp_ns: u64,
+    pub level: LogLevel,
+    tag: [u8; MAX_TAG],
+    tag_len: u8,
+    text: [u8; MAX_MESSAGE],
+    text_len: u8,
+}
+
+impl LogRecord {
+    const EMPTY: Self = Self {
+        seq: 0,
+        timestamp_ns: 0,
+        level: LogLevel::Info,
+        tag: [0; MAX_TAG],
+        tag_len: 0,
+        text: [0; MAX_MESSAGE],
+        text_len: 0,
+    };
+
+    /// Subsystem tag
+    pub fn tag(&self) -> &str {
+        core::str::from_utf8(&self.tag[..self.tag_len as usize]).unwrap_or("")
+    }
+
+    /// Message text
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.text_len as usize]).unwrap_or("")
+    }
+}
 
-/// Set the global log level
-pub fn set_log_level(level: LogLevel) {
-    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+impl fmt::Display for LogRecord {
+    /// `[seconds.micros] tag: message`, as printed by dmesg
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.timestamp_ns / 1000;
+        write!(
+            f,
+            "[{:5}.{:06}] {}: {}",
+            micros / 1_000_000,
+            micros % 1_000_000,
+            self.tag(),
+            self.message()
+        )
+    }
 }
 
-/// Get the current log level
-pub fn get_log_level() -> LogLevel {
-    match LOG_LEVEL.load(Ordering::Relaxed) {
-        0 => LogLevel::Debug,
-        1 => LogLevel::Info,
-        2 => LogLevel::Warn,
-        3 => LogLevel::Error,
-        _ => LogLevel::Info,
+/// Copy as much of `src` as fits in `dst` without splitting a character
+fn copy_truncated(dst: &mut [u8], src: &str) -> usize {
+    let mut len = src.len().min(dst.len());
+    while !src.is_char_boundary(len) {
+        len -= 1;
     }
+    dst[..len].copy_from_slice(&src.as_bytes()[..len]);
+    len
 }
 
-/// Check if a log level should be printed
-pub fn should_log(level: LogLevel) -> bool {
-    level >= get_log_level()
+/// Message formatted into a fixed buffer, cut off when it is full
+struct MessageBuf {
+    buf: [u8; MAX_MESSAGE],
+    len: usize,
 }
 
-/// Log a message with the given level
-pub fn log(level: LogLevel, args: fmt::Arguments) {
-    if !should_log(level) {
-        return;
+impl MessageBuf {
+    const fn new() -> Self {
+        Self { buf: [0; MAX_MESSAGE], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
     }
+}
 
-    // Set color based on log level (for framebuffer)
-    let mut fb = super::framebuffer::framebuffer();
-    if fb.is_initialized() {
-        let old_fg = fb.fg_color;
-        fb.set_fg_color(level.color());
-        drop(fb);
-        
-        super::console::_print(core::format_args!("[{}] ", level.as_str()));
-        super::console::_print(args);
-        super::console::_print(core::format_args!("\n"));
-        
-        // Restore color
-        let mut fb = super::framebuffer::framebuffer();
-        fb.set_fg_color(old_fg);
-    } else {
-        // Framebuffer not initialized, just print without color
-        super::console::_print(core::format_args!("[{}] ", level.as_str()));
-        super::console::_print(args);
-        super::console::_print(core::format_args!("\n"));
+impl fmt::Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += copy_truncated(&mut self.buf[self.len..], s);
+        Ok(())
     }
 }
 
+/// Ring of the last `N` records
+pub struct LogBuffer<const N: usize> {
+    records: [LogRecord; N],
+    /// Sequence number of the next record
+    next_seq: u64,
+    /// First record `dmesg` shows, moved by clearing
+    clear_seq: u64,
+    /// Next record `/proc/kmsg` hands out
+    read_seq: u64,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: [LogRecord::EMPTY; N],
+            next_seq: 0,
+            clear_seq: 0,
+            read_seq: 0,
+        }
+    }
+
+    /// Append a record, overwriting the oldest if the ring is full
+    ///
+    /// # Returns
+    /// The record's sequence number
+    pub fn push(&mut self, level: LogLevel, tag: &str, timestamp_ns: u64, message: &str) -> u64 {
+        let seq = self.next_seq;
+        let record = &mut self.records[(seq % N as u64) as usize];
+        record.seq = seq;
+        record.timestamp_ns = timestamp_ns;
+        record.level = level;
+        record.tag_len = copy_truncated(&mut record.tag, tag) as u8;
+        record.text_len = copy_truncated(&mut record.text, message.trim_end_matches('\n')) as u8;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Sequence number of the oldest record still in the ring
+    pub fn first_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(N as u64)
+    }
+
+    /// Sequence number the next record will get
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// First record that has not been cleared
+    pub fn clear_seq(&self) -> u64 {
+        self.clear_seq.max(self.first_seq())
+    }
+
+    /// Record `seq`, if it is still in the ring
+    pub fn get(&self, seq: u64) -> Option<&LogRecord> {
+        if seq < self.first_seq() || seq >= self.next_seq {
+            return None;
+        }
+        Some(&self.records[(seq % N as u64) as usize])
+    }
+
+    /// Hide the current records from `dmesg`
+    pub fn clear(&mut self) {
+        self.clear_seq = self.next_seq;
+    }
+
+    /// Take the next record for `/proc/kmsg`
+    ///
+    /// Records overwritten before they were read are skipped.
+    pub fn read_next(&mut self) -> Option<LogRecord> {
+        self.read_seq = self.read_seq.max(self.first_seq());
+        let record = *self.get(self.read_seq)?;
+        self.read_seq += 1;
+        Some(record)
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The kernel log
+static LOG: SpinLock<LogBuffer<LOG_RECORDS>> = SpinLock::new(LogBuffer::new());
+
+/// Lowest level printed to the console
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Apply `loglevel=` and `quiet` from the command line
+pub fn init() {
+    if crate::cmdline::flag("quiet") {
+        set_console_level(LogLevel::Warn);
+    }
+    if let Some(name) = crate::cmdline::option("loglevel") {
+        match LogLevel::from_name(name) {
+            Some(level) => set_console_level(level),
+            None => crate::log_warn!(target: "log", "Ignoring invalid loglevel={}", name),
+        }
+    }
+}
+
+/// Set the lowest level printed to the console
+pub fn set_console_level(level: LogLevel) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Get the lowest level printed to the console
+pub fn console_level() -> LogLevel {
+    LogLevel::from_u8(CONSOLE_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Check if a record of `level` is printed to the console
+pub fn should_print(level: LogLevel) -> bool {
+    level >= console_level()
+}
+
+/// Log a message with the given level and subsystem tag
+pub fn log(level: LogLevel, tag: &str, args: fmt::Arguments) {
+    use fmt::Write;
+
+    let mut message = MessageBuf::new();
+    let _ = message.write_fmt(args);
+    let timestamp_ns = crate::task::ktime_ns();
+
+    let mut record = LogRecord::EMPTY;
+    {
+        let mut log = LOG.lock_irqsave();
+        let seq = log.push(level, tag, timestamp_ns, message.as_str());
+        if let Some(stored) = log.get(seq) {
+            record = *stored;
+        }
+    }
+
+    #[cfg(not(test))]
+    if should_print(level) {
+        super::console::_print(format_args!("\x1b[{}m{}\x1b[0m\n", level.sgr(), record));
+    }
+    #[cfg(test)]
+    let _ = record;
+}
+
+/// First record `dmesg` shows and the sequence number after the last one
+pub fn range() -> (u64, u64) {
+    let log = LOG.lock_irqsave();
+    (log.clear_seq(), log.next_seq())
+}
+
+/// Copy of record `seq`, if it is still in the ring
+pub fn record(seq: u64) -> Option<LogRecord> {
+    LOG.lock_irqsave().get(seq).copied()
+}
+
+/// Hide the current records from `dmesg`
+pub fn clear() {
+    LOG.lock_irqsave().clear();
+}
+
+/// Oldest record still in the ring and the sequence number after the last,
+/// ignoring `dmesg -C`
+pub fn retained() -> (u64, u64) {
+    let log = LOG.lock_irqsave();
+    (log.first_seq(), log.next_seq())
+}
+
+/// Like `retained`, but gives up if the log is locked
+///
+/// The panic path uses the `try_` functions, since the panicking code may
+/// hold the log.
+pub fn try_retained() -> Option<(u64, u64)> {
+    let log = LOG.try_lock()?;
+    Some((log.first_seq(), log.next_seq()))
+}
+
+/// Like `record`, but gives up if the log is locked
+pub fn try_record(seq: u64) -> Option<LogRecord> {
+    LOG.try_lock()?.get(seq).copied()
+}
+
+/// Store a record without printing it, giving up if the log is locked
+///
+/// # Returns
+/// `false` if the record was dropped
+pub fn try_log(level: LogLevel, tag: &str, args: fmt::Arguments) -> bool {
+    use fmt::Write;
+
+    let mut message = MessageBuf::new();
+    let _ = message.write_fmt(args);
+    let timestamp_ns = crate::task::ktime_ns();
+    match LOG.try_lock() {
+        Some(mut log) => {
+            log.push(level, tag, timestamp_ns, message.as_str());
+            true
+        }
+        None => false,
+    }
+}
+
+/// `/proc/kmsg`: reads hand out unread records, writes are logged
+struct Kmsg;
+
+impl super::chardev::CharDevice for Kmsg {
+    fn kind(&self) -> super::chardev::CharDeviceKind {
+        super::chardev::CharDeviceKind::Other
+    }
+
+    /// Fill `buf` with whole lines; a line longer than `buf` is cut off
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        use fmt::Write;
+
+        let mut filled = 0;
+        let mut log = LOG.lock_irqsave();
+        loop {
+            let seq = log.read_seq;
+            let Some(record) = log.read_next() else {
+                break;
+            };
+            let mut line = LineBuf::new();
+            let _ = writeln!(line, "<{}>{}", record.level.syslog_priority(), record);
+            let line = line.as_bytes();
+            if filled + line.len() > buf.len() {
+                if filled > 0 {
+                    // Leave it for the next read
+                    log.read_seq = seq;
+                    break;
+                }
+                filled = buf.len();
+                buf.copy_from_slice(&line[..filled]);
+                break;
+            }
+            buf[filled..filled + line.len()].copy_from_slice(line);
+            filled += line.len();
+        }
+        Ok(filled)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        let text = core::str::from_utf8(buf).map_err(|_| "Invalid UTF-8")?;
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            log(LogLevel::Info, "user", format_args!("{}", line));
+        }
+        Ok(buf.len())
+    }
+}
+
+/// One `/proc/kmsg` line
+struct LineBuf {
+    buf: [u8; MAX_MESSAGE + MAX_TAG + 32],
+    len: usize,
+}
+
+impl LineBuf {
+    const fn new() -> Self {
+        Self { buf: [0; MAX_MESSAGE + MAX_TAG + 32], len: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += copy_truncated(&mut self.buf[self.len..], s);
+        Ok(())
+    }
+}
+
+/// Register the `kmsg` character device
+///
+/// Requires the heap.
+pub fn register_device() -> Result<(), &'static str> {
+    super::chardev::register("kmsg", alloc::sync::Arc::new(Kmsg))
+}
+
 #[macro_export]
 macro_rules! log_debug {
+    (target: $tag:expr, $($arg:tt)*) => {{
+        $crate::io::logger::log(
+            $crate::io::logger::LogLevel::Debug,
+            $tag,
+            core::format_args!($($arg)*)
+        );
+    }};
     ($($arg:tt)*) => {{
         $crate::io::logger::log(
             $crate::io::logger::LogLevel::Debug,
+            $crate::io::logger::DEFAULT_TAG,
             core::format_args!($($arg)*)
         );
     }};
@@ -95,9 +486,17 @@ macro_rules! log_debug {
 
 #[macro_export]
 macro_rules! log_info {
+    (target: $tag:expr, $($arg:tt)*) => {{
+        $crate::io::logger::log(
+            $crate::io::logger::LogLevel::Info,
+            $tag,
+            core::format_args!($($arg)*)
+        );
+    }};
     ($($arg:tt)*) => {{
         $crate::io::logger::log(
             $crate::io::logger::LogLevel::Info,
+            $crate::io::logger::DEFAULT_TAG,
             core::format_args!($($arg)*)
         );
     }};
@@ -105,9 +504,17 @@ macro_rules! log_info {
 
 #[macro_export]
 macro_rules! log_warn {
+    (target: $tag:expr, $($arg:tt)*) => {{
+        $crate::io::logger::log(
+            $crate::io::logger::LogLevel::Warn,
+            $tag,
+            core::format_args!($($arg)*)
+        );
+    }};
     ($($arg:tt)*) => {{
         $crate::io::logger::log(
             $crate::io::logger::LogLevel::Warn,
+            $crate::io::logger::DEFAULT_TAG,
             core::format_args!($($arg)*)
         );
     }};
@@ -115,10 +522,110 @@ macro_rules! log_warn {
 
 #[macro_export]
 macro_rules! log_error {
+    (target: $tag:expr, $($arg:tt)*) => {{
+        $crate::io::logger::log(
+            $crate::io::logger::LogLevel::Error,
+            $tag,
+            core::format_args!($($arg)*)
+        );
+    }};
     ($($arg:tt)*) => {{
         $crate::io::logger::log(
             $crate::io::logger::LogLevel::Error,
+            $crate::io::logger::DEFAULT_TAG,
             core::format_args!($($arg)*)
         );
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::chardev::CharDevice;
+
+    #[test]
+    fn test_ring_overwrites_oldest() {
+        let mut log = LogBuffer::<4>::new();
+        for i in 0..6 {
+            log.push(LogLevel::Info, "test", i * 1000, "message");
+        }
+        assert_eq!(log.first_seq(), 2);
+        assert_eq!(log.next_seq(), 6);
+        assert!(log.get(1).is_none());
+        assert_eq!(log.get(2).unwrap().timestamp_ns, 2000);
+        assert!(log.get(6).is_none());
+    }
+
+    #[test]
+    fn test_clear_and_read_cursor() {
+        let mut log = LogBuffer::<4>::new();
+        log.push(LogLevel::Info, "a", 0, "one");
+        log.push(LogLevel::Warn, "b", 0, "two");
+        assert_eq!(log.read_next().unwrap().message(), "one");
+
+        log.clear();
+        assert_eq!(log.clear_seq(), 2);
+        // Clearing does not affect the reader
+        assert_eq!(log.read_next().unwrap().message(), "two");
+        assert!(log.read_next().is_none());
+
+        // Overwritten records are skipped
+        for i in 0..6 {
+            log.push(LogLevel::Info, "c", 0, if i == 5 { "last" } else { "x" });
+        }
+        assert_eq!(log.read_next().unwrap().seq, 4);
+    }
+
+    #[test]
+    fn test_truncation() {
+        let mut log = LogBuffer::<2>::new();
+        let long = "é".repeat(MAX_MESSAGE);
+        let seq = log.push(LogLevel::Info, "a-very-long-subsystem-tag", 0, &long);
+        let record = log.get(seq).unwrap();
+        assert_eq!(record.tag(), "a-very-long-sub");
+        assert!(record.message().len() <= MAX_MESSAGE);
+        assert!(record.message().chars().all(|c| c == 'é'));
+
+        let seq = log.push(LogLevel::Info, "a", 0, "line\n");
+        assert_eq!(log.get(seq).unwrap().message(), "line");
+    }
+
+    #[test]
+    fn test_record_format() {
+        let mut log = LogBuffer::<2>::new();
+        let seq = log.push(LogLevel::Warn, "net", 12_345_678_901, "link down");
+        let record = log.get(seq).unwrap();
+        assert_eq!(alloc::format!("{}", record), "[   12.345678] net: link down");
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(LogLevel::from_name("warning"), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::from_name("loud"), None);
+        assert_eq!(LogLevel::Error.syslog_priority(), 3);
+        assert!(LogLevel::Error > LogLevel::Info);
+    }
+
+    #[test]
+    fn test_log_and_kmsg() {
+        crate::log_info!(target: "test", "hello {}", 42);
+        let (first, next) = range();
+        let found = (first..next)
+            .rev()
+            .filter_map(record)
+            .any(|record| record.tag() == "test" && record.message() == "hello 42");
+        assert!(found);
+
+        assert_eq!(Kmsg.write(b"from user\n"), Ok(10));
+        let mut buf = [0u8; 4096];
+        let mut lines = alloc::string::String::new();
+        loop {
+            let count = Kmsg.read(&mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            lines.push_str(core::str::from_utf8(&buf[..count]).unwrap());
+        }
+        assert!(lines.lines().any(|line| line.starts_with("<6>[") && line.ends_with("user: from user")));
+    }
+}
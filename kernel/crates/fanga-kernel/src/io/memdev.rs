@@ -0,0 +1,133 @@
+//! Memory Devices
+//!
+//! The standard byte sinks and sources, registered as character devices
+//! and reachable as `/dev/null`, `/dev/zero`, `/dev/full`, `/dev/random`
+//! and `/dev/urandom`:
+//! - `null` discards writes and reads as end of file
+//! - `zero` discards writes and reads as zero bytes
+//! - `full` reads as zero bytes and fails every write for lack of space
+//! - `random` and `urandom` read from the kernel CSPRNG and mix writes
+//!   into its entropy pool without crediting them
+//!
+//! Neither random device blocks, even before the generator is seeded.
+//! Programs that must wait for that use `getrandom()`.
+
+use alloc::sync::Arc;
+
+use super::chardev::{self, CharDevice, CharDeviceKind};
+
+/// `/dev/null`
+struct Null;
+
+impl CharDevice for Null {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`
+struct Zero;
+
+impl CharDevice for Zero {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/full`
+struct Full;
+
+impl CharDevice for Full {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err(chardev::NO_SPACE)
+    }
+}
+
+/// `/dev/random` and `/dev/urandom`
+struct Random;
+
+impl CharDevice for Random {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        crate::random::get_random_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        crate::random::add_device_randomness(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Register the memory devices
+pub fn init() -> Result<(), &'static str> {
+    chardev::register("null", Arc::new(Null))?;
+    chardev::register("zero", Arc::new(Zero))?;
+    chardev::register("full", Arc::new(Full))?;
+    let random = Arc::new(Random);
+    chardev::register("random", random.clone())?;
+    chardev::register("urandom", random)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_zero_full() {
+        let mut buf = [0xAAu8; 16];
+        assert_eq!(Null.read(&mut buf), Ok(0));
+        assert_eq!(Null.write(b"gone"), Ok(4));
+
+        assert_eq!(Zero.read(&mut buf), Ok(16));
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(Zero.write(b"gone"), Ok(4));
+
+        buf.fill(0xAA);
+        assert_eq!(Full.read(&mut buf), Ok(16));
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(Full.write(b"x"), Err(chardev::NO_SPACE));
+    }
+
+    #[test]
+    fn test_random() {
+        let random = Random;
+        let mut a = [0u8; 13];
+        let mut b = [0u8; 13];
+        assert_eq!(random.read(&mut a), Ok(13));
+        random.read(&mut b).unwrap();
+        assert_ne!(a, b);
+        assert!(a.iter().any(|&byte| byte != 0));
+        assert_eq!(random.write(b"seed"), Ok(4));
+    }
+}
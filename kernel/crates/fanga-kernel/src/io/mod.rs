@@ -1,10 +1,20 @@
+pub mod ansi;
 pub mod font;
 pub mod framebuffer;
 pub mod framebuffer_enhanced;
+pub mod cursor;
+pub mod compositor;
+pub mod desktop;
 pub mod console;
 pub mod logger;
+pub mod pstore;
 pub mod input;
 pub mod line_editor;
 pub mod keyboard_handler;
 pub mod keyboard_bridge;
+pub mod mouse_bridge;
+pub mod chardev;
+pub mod memdev;
+pub mod serial_input;
+pub mod tty;
 pub mod vt;
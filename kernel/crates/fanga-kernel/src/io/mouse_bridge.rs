@@ -0,0 +1,158 @@
+//! Mouse interrupt bridge
+//!
+//! This module provides the bridge between the arch-specific PS/2 mouse
+//! driver and the kernel's input event queue: each packet moves the
+//! pointer and the cursor on screen, and becomes a movement, a scroll and
+//! one event per button that changed.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use fanga_arch_x86_64::mouse::{MouseButtons, MousePacket};
+
+use crate::io::input::{self, InputEvent, MouseButton};
+
+/// Buttons held in the last packet, one bit per `MouseButton`
+static HELD_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+const BUTTONS: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::Back,
+    MouseButton::Forward,
+];
+
+/// Pack the button state into a bit mask
+fn button_bits(buttons: MouseButtons) -> u8 {
+    [buttons.left, buttons.right, buttons.middle, buttons.back, buttons.forward]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &down)| bits | (down as u8) << i)
+}
+
+/// Translate a packet into input events
+///
+/// `held` is the button mask of the previous packet and `move_pointer`
+/// moves the pointer, returning its new position.
+///
+/// # Returns
+/// The button mask of this packet
+fn packet_events(
+    held: u8,
+    packet: &MousePacket,
+    move_pointer: impl FnOnce(i32, i32) -> (i32, i32),
+    mut emit: impl FnMut(InputEvent),
+) -> u8 {
+    if packet.x_movement != 0 || packet.y_movement != 0 {
+        let (dx, dy) = (packet.x_movement as i32, packet.y_movement as i32);
+        let (x, y) = move_pointer(dx, dy);
+        emit(InputEvent::MouseMove { dx, dy, x, y });
+    }
+
+    let now = button_bits(packet.buttons);
+    for (i, &button) in BUTTONS.iter().enumerate() {
+        if (held ^ now) & (1 << i) != 0 {
+            emit(InputEvent::MouseButton { button, pressed: now & (1 << i) != 0 });
+        }
+    }
+
+    if packet.z_movement != 0 {
+        emit(InputEvent::MouseScroll(packet.z_movement as i32));
+    }
+    now
+}
+
+/// Mouse packet callback that will be called from the interrupt handler
+pub fn mouse_callback(packet: MousePacket) {
+    let motion = ((packet.x_movement as u16 as u32) << 16) | packet.y_movement as u16 as u32;
+    crate::random::add_input_randomness(motion);
+    let held = HELD_BUTTONS.load(Ordering::Relaxed);
+    let now = packet_events(held, &packet, input::move_pointer, input::push_event);
+    HELD_BUTTONS.store(now, Ordering::Relaxed);
+    if packet.x_movement != 0 || packet.y_movement != 0 {
+        if super::compositor::is_active() {
+            super::compositor::move_cursor();
+        } else {
+            super::framebuffer::move_cursor();
+        }
+    }
+}
+
+/// Initialize the PS/2 mouse and route its packets into the event queue
+pub fn init() -> Result<(), &'static str> {
+    // Register before the device starts reporting
+    unsafe {
+        fanga_arch_x86_64::mouse::set_mouse_callback(mouse_callback);
+    }
+    let kind = fanga_arch_x86_64::mouse::init()?;
+    crate::log_info!(target: "mouse", "PS/2 mouse ready ({:?})", kind);
+
+    // The compositor draws its own cursor
+    if let Some((width, height)) = super::compositor::resolution() {
+        input::set_pointer_bounds(width, height);
+        return Ok(());
+    }
+    let mut fb = super::framebuffer::framebuffer();
+    if fb.is_initialized() {
+        let (width, height) = fb.resolution();
+        input::set_pointer_bounds(width, height);
+        fb.set_cursor_visible(true);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn packet(buttons: MouseButtons, x: i16, y: i16, z: i8) -> MousePacket {
+        MousePacket { buttons, x_movement: x, y_movement: y, z_movement: z }
+    }
+
+    #[test]
+    fn test_packet_events() {
+        let mut events = Vec::new();
+        let pressed = MouseButtons { left: true, ..MouseButtons::new() };
+
+        let mut pointer = input::Pointer::new();
+        pointer.set_bounds(100, 100);
+        let mut move_pointer = |dx, dy| pointer.move_by(dx, dy);
+
+        let held = packet_events(0, &packet(pressed, 3, -2, 0), &mut move_pointer, |e| events.push(e));
+        assert_eq!(held, 0b1);
+        assert_eq!(
+            events,
+            [
+                InputEvent::MouseMove { dx: 3, dy: -2, x: 53, y: 48 },
+                InputEvent::MouseButton { button: MouseButton::Left, pressed: true },
+            ]
+        );
+
+        // Holding the button only reports the scroll
+        events.clear();
+        assert_eq!(packet_events(held, &packet(pressed, 0, 0, -1), &mut move_pointer, |e| events.push(e)), 0b1);
+        assert_eq!(events, [InputEvent::MouseScroll(-1)]);
+
+        events.clear();
+        let back = MouseButtons { back: true, ..MouseButtons::new() };
+        assert_eq!(packet_events(held, &packet(back, 0, 0, 0), &mut move_pointer, |e| events.push(e)), 0b1000);
+        assert_eq!(
+            events,
+            [
+                InputEvent::MouseButton { button: MouseButton::Left, pressed: false },
+                InputEvent::MouseButton { button: MouseButton::Back, pressed: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_queue_drops_oldest() {
+        while input::pop_event().is_some() {}
+        for i in 0..300 {
+            input::push_event(InputEvent::MouseScroll(i));
+        }
+        assert_eq!(input::pending_events(), 256);
+        assert_eq!(input::pop_event(), Some(InputEvent::MouseScroll(44)));
+    }
+}
@@ -0,0 +1,635 @@
+//! Persistent kernel log
+//!
+//! Saves the kernel log somewhere that outlives the running kernel, so the
+//! messages leading up to a crash can be read after a reboot on machines
+//! without a serial capture. The target is chosen on the command line:
+//! - `pstore.file=<path>` appends new records to a file; it only survives
+//!   a reboot if the file system holding it does
+//! - `pstore.disk=<drive>:<lba>:<sectors>` keeps the newest records in a
+//!   reserved range of an ATA disk (`hda`, `hdb`, `hdc` or `hdd`), rewritten
+//!   on every save
+//!
+//! The log is saved every `pstore.interval=` seconds (10 by default, 0 for
+//! panics only), before a reboot or shutdown, and from the panic handler.
+//! At boot, a log left in the disk region by the previous boot is copied
+//! to `/var/log/kmsg.last`.
+//!
+//! Lines use the `/proc/kmsg` format. The disk region starts with a header
+//! sector holding the length and checksum of the text in the sectors after
+//! it. The header is written last, so a save cut short by a reset leaves a
+//! region that fails the checksum instead of a mix of two logs.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::logger::{self, LogLevel, LogRecord};
+use crate::fs::FileSystem;
+use crate::storage::drivers::ata::{AtaBus, AtaDrive};
+use crate::storage::{AtaDevice, BlockDevice};
+
+/// Size of a sector in the disk region
+const SECTOR_SIZE: usize = 512;
+
+/// Marks a disk region holding a saved log
+const MAGIC: [u8; 8] = *b"FANGKLOG";
+
+/// Layout version of the disk region
+const VERSION: u32 = 1;
+
+/// Where the log of the previous boot is copied
+pub const LAST_LOG_PATH: &str = "/var/log/kmsg.last";
+
+/// Seconds between saves unless `pstore.interval=` says otherwise
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+
+/// FNV-1a parameters for the text checksum
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// Why the log was saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Periodic or requested save
+    Sync = 0,
+    /// Saved by the panic handler
+    Panic = 1,
+}
+
+/// First sector of the disk region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    reason: Reason,
+    /// Bytes of text after the header
+    len: u32,
+    checksum: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[0..8].copy_from_slice(&MAGIC);
+        sector[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        sector[12..16].copy_from_slice(&(self.reason as u32).to_le_bytes());
+        sector[16..20].copy_from_slice(&self.len.to_le_bytes());
+        sector[20..24].copy_from_slice(&self.checksum.to_le_bytes());
+        sector
+    }
+
+    fn decode(sector: &[u8]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes(sector[at..at + 4].try_into().unwrap());
+        if sector.len() < SECTOR_SIZE || sector[0..8] != MAGIC || word(8) != VERSION {
+            return None;
+        }
+        let reason = match word(12) {
+            0 => Reason::Sync,
+            1 => Reason::Panic,
+            _ => return None,
+        };
+        Some(Self { reason, len: word(16), checksum: word(20) })
+    }
+}
+
+/// Continue an FNV-1a hash over `data`
+fn checksum(hash: u32, data: &[u8]) -> u32 {
+    data.iter().fold(hash, |hash, &byte| (hash ^ byte as u32).wrapping_mul(FNV_PRIME))
+}
+
+/// Write `record` as a `/proc/kmsg` line
+fn write_line(out: &mut impl Write, record: &LogRecord) -> fmt::Result {
+    writeln!(out, "<{}>{}", record.level.syslog_priority(), record)
+}
+
+/// Counts the bytes written through it
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// First sequence number of the newest records in `first..next` whose
+/// lines fit in `capacity` bytes
+fn first_fitting(
+    first: u64,
+    next: u64,
+    capacity: usize,
+    get: &impl Fn(u64) -> Option<LogRecord>,
+) -> u64 {
+    let mut used = 0;
+    let mut start = next;
+    while start > first {
+        let Some(record) = get(start - 1) else {
+            break;
+        };
+        let mut counter = Counter(0);
+        let _ = write_line(&mut counter, &record);
+        if used + counter.0 > capacity {
+            break;
+        }
+        used += counter.0;
+        start -= 1;
+    }
+    start
+}
+
+/// Streams text into the data sectors of a disk region
+struct SectorWriter<'a> {
+    device: &'a dyn BlockDevice,
+    /// Next sector to write
+    lba: u64,
+    /// End of the region
+    end: u64,
+    buf: [u8; SECTOR_SIZE],
+    filled: usize,
+    len: u32,
+    checksum: u32,
+    error: Option<crate::storage::BlockDeviceError>,
+}
+
+impl<'a> SectorWriter<'a> {
+    fn new(device: &'a dyn BlockDevice, lba: u64, end: u64) -> Self {
+        Self {
+            device,
+            lba,
+            end,
+            buf: [0; SECTOR_SIZE],
+            filled: 0,
+            len: 0,
+            checksum: FNV_OFFSET,
+            error: None,
+        }
+    }
+
+    fn write_sector(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+        if self.lba >= self.end {
+            self.error = Some(crate::storage::BlockDeviceError::InvalidBlock);
+            return;
+        }
+        self.buf[self.filled..].fill(0);
+        if let Err(e) = self.device.write_blocks(self.lba, &self.buf) {
+            self.error = Some(e);
+        }
+        self.lba += 1;
+        self.filled = 0;
+    }
+
+    /// Write the partial last sector
+    ///
+    /// # Returns
+    /// Length and checksum of the text
+    fn finish(mut self) -> Result<(u32, u32), crate::storage::BlockDeviceError> {
+        if self.filled > 0 {
+            self.write_sector();
+        }
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok((self.len, self.checksum)),
+        }
+    }
+}
+
+impl Write for SectorWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        self.checksum = checksum(self.checksum, bytes);
+        self.len += bytes.len() as u32;
+        while !bytes.is_empty() {
+            let count = bytes.len().min(SECTOR_SIZE - self.filled);
+            self.buf[self.filled..self.filled + count].copy_from_slice(&bytes[..count]);
+            self.filled += count;
+            bytes = &bytes[count..];
+            if self.filled == SECTOR_SIZE {
+                self.write_sector();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Save the newest records in `first..next` to the region of `sectors`
+/// sectors at `lba`
+fn save_region(
+    device: &dyn BlockDevice,
+    lba: u64,
+    sectors: u64,
+    reason: Reason,
+    (first, next): (u64, u64),
+    get: &impl Fn(u64) -> Option<LogRecord>,
+) -> Result<(), crate::storage::BlockDeviceError> {
+    let capacity = (sectors.saturating_sub(1) as usize) * SECTOR_SIZE;
+    let start = first_fitting(first, next, capacity, get);
+
+    let mut writer = SectorWriter::new(device, lba + 1, lba + sectors);
+    for record in (start..next).filter_map(get) {
+        let _ = write_line(&mut writer, &record);
+    }
+    let (len, checksum) = writer.finish()?;
+    device.write_blocks(lba, &Header { reason, len, checksum }.encode())
+}
+
+/// Read a log saved to the region of `sectors` sectors at `lba`
+///
+/// # Returns
+/// `None` if the region holds no intact log
+fn load_region(device: &dyn BlockDevice, lba: u64, sectors: u64) -> Option<(Reason, Vec<u8>)> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    device.read_blocks(lba, &mut sector).ok()?;
+    let header = Header::decode(&sector)?;
+    let len = header.len as usize;
+    if len > (sectors.saturating_sub(1) as usize) * SECTOR_SIZE {
+        return None;
+    }
+
+    let mut text = alloc::vec![0u8; len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+    device.read_blocks(lba + 1, &mut text).ok()?;
+    text.truncate(len);
+    (checksum(FNV_OFFSET, &text) == header.checksum).then_some((header.reason, text))
+}
+
+/// Append the records in `from..next` to `path`, noting any overwritten
+/// before they were saved
+///
+/// # Returns
+/// The sequence number to continue from
+fn save_file(
+    fs: &mut dyn FileSystem,
+    path: &str,
+    from: u64,
+    (first, next): (u64, u64),
+    get: &impl Fn(u64) -> Option<LogRecord>,
+) -> Result<u64, crate::fs::FsError> {
+    let mut text = String::new();
+    if from < first {
+        let _ = writeln!(text, "<4>pstore: {} records lost before they were saved", first - from);
+    }
+    for record in (from.max(first)..next).filter_map(get) {
+        let _ = write_line(&mut text, &record);
+    }
+    if !text.is_empty() {
+        crate::fs::append_file_in(fs, path, text.as_bytes())?;
+    }
+    Ok(next)
+}
+
+/// Parse `pstore.disk=<drive>:<lba>:<sectors>`
+fn parse_disk(spec: &str) -> Option<(AtaBus, AtaDrive, u64, u64)> {
+    let mut parts = spec.split(':');
+    let (bus, drive) = match parts.next()? {
+        "hda" => (AtaBus::Primary, AtaDrive::Master),
+        "hdb" => (AtaBus::Primary, AtaDrive::Slave),
+        "hdc" => (AtaBus::Secondary, AtaDrive::Master),
+        "hdd" => (AtaBus::Secondary, AtaDrive::Slave),
+        _ => return None,
+    };
+    let lba = parts.next()?.parse().ok()?;
+    let sectors: u64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || sectors < 2 {
+        return None;
+    }
+    Some((bus, drive, lba, sectors))
+}
+
+/// Where the log is saved
+enum Target {
+    File(String),
+    Disk {
+        device: Box<dyn BlockDevice>,
+        lba: u64,
+        sectors: u64,
+    },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::File(path) => write!(f, "{}", path),
+            Target::Disk { lba, sectors, .. } => write!(f, "sectors {}..{}", lba, lba + sectors),
+        }
+    }
+}
+
+/// The configured target and how far it has been saved
+struct Pstore {
+    target: Target,
+    /// Sequence number after the last saved record
+    saved_seq: u64,
+    /// The last save failed; later failures are not logged again
+    failing: bool,
+}
+
+impl Pstore {
+    /// Save new records
+    ///
+    /// When `panicking`, nothing waits for a lock; the save is skipped
+    /// instead.
+    fn save(&mut self, reason: Reason, panicking: bool) -> Result<(), &'static str> {
+        let range = if panicking {
+            logger::try_retained().ok_or("log is locked")?
+        } else {
+            logger::retained()
+        };
+        if range.1 == self.saved_seq && reason == Reason::Sync {
+            return Ok(());
+        }
+        let get = |seq| {
+            if panicking {
+                logger::try_record(seq)
+            } else {
+                logger::record(seq)
+            }
+        };
+
+        match &mut self.target {
+            Target::File(path) => {
+                let root = crate::fs::root_fs();
+                let mut fs = if panicking {
+                    root.try_lock().ok_or("file system is locked")?
+                } else {
+                    root.lock()
+                };
+                self.saved_seq = save_file(&mut *fs, path, self.saved_seq, range, &get)
+                    .map_err(|_| "cannot write the log file")?;
+            }
+            Target::Disk { device, lba, sectors } => {
+                save_region(device.as_ref(), *lba, *sectors, reason, range, &get)
+                    .and_then(|()| device.flush())
+                    .map_err(|_| "cannot write the log region")?;
+                self.saved_seq = range.1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save new records, logging the first of a run of failures
+    fn sync(&mut self) {
+        match self.save(Reason::Sync, false) {
+            Ok(()) => self.failing = false,
+            Err(e) if !self.failing => {
+                self.failing = true;
+                crate::log_warn!(target: "pstore", "Cannot save the kernel log to {}: {}", self.target, e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// The persistent log, if one is configured
+static PSTORE: Mutex<Option<Pstore>> = Mutex::new(None);
+
+/// Milliseconds between periodic saves, 0 if disabled
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Copy a log left in the disk region to `LAST_LOG_PATH`
+fn recover(device: &dyn BlockDevice, lba: u64, sectors: u64) {
+    let Some((reason, text)) = load_region(device, lba, sectors) else {
+        return;
+    };
+    let saved = crate::fs::create_dir_all("/var/log")
+        .and_then(|()| crate::fs::write_file(LAST_LOG_PATH, &text));
+    match saved {
+        Ok(_) => crate::log_info!(
+            target: "pstore",
+            "Recovered {} bytes of kernel log from the previous boot{} into {}",
+            text.len(),
+            if reason == Reason::Panic { ", which panicked," } else { "" },
+            LAST_LOG_PATH
+        ),
+        Err(e) => crate::log_warn!(target: "pstore", "Cannot write {}: {}", LAST_LOG_PATH, e),
+    }
+}
+
+/// Open the target given on the command line
+///
+/// # Returns
+/// `None` if no target is configured
+fn open_target() -> Option<Result<Target, &'static str>> {
+    if let Some(path) = crate::cmdline::option("pstore.file") {
+        if !path.starts_with('/') {
+            return Some(Err("pstore.file= must be an absolute path"));
+        }
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let opened = crate::fs::create_dir_all(parent)
+            .and_then(|()| crate::fs::append_file(path, b"---- boot ----\n"));
+        return Some(match opened {
+            Ok(_) => Ok(Target::File(String::from(path))),
+            Err(_) => Err("cannot open the log file"),
+        });
+    }
+
+    let spec = crate::cmdline::option("pstore.disk")?;
+    let Some((bus, drive, lba, sectors)) = parse_disk(spec) else {
+        return Some(Err("invalid pstore.disk="));
+    };
+    let mut device = AtaDevice::new(bus, drive);
+    if device.init().is_err() {
+        return Some(Err("disk not found"));
+    }
+    if lba.saturating_add(sectors) > device.block_count() {
+        return Some(Err("region extends past the end of the disk"));
+    }
+    recover(&device, lba, sectors);
+    Some(Ok(Target::Disk { device: Box::new(device), lba, sectors }))
+}
+
+/// Set up the target given on the command line, recover the log of the
+/// previous boot and start periodic saves
+///
+/// Does nothing without `pstore.file=` or `pstore.disk=`. Requires the
+/// root file system and workqueues.
+pub fn init() -> Result<(), &'static str> {
+    let Some(target) = open_target() else {
+        return Ok(());
+    };
+    let mut pstore = Pstore { target: target?, saved_seq: 0, failing: false };
+    pstore.save(Reason::Sync, false)?;
+
+    let interval_secs = match crate::cmdline::option("pstore.interval") {
+        Some(value) => value.parse().map_err(|_| "invalid pstore.interval=")?,
+        None => DEFAULT_INTERVAL_SECS,
+    };
+    if interval_secs > 0 {
+        crate::log_info!(
+            target: "pstore",
+            "Saving the kernel log to {} every {} s",
+            pstore.target,
+            interval_secs
+        );
+    } else {
+        crate::log_info!(target: "pstore", "Saving the kernel log to {} on panic", pstore.target);
+    }
+    *PSTORE.lock() = Some(pstore);
+
+    INTERVAL_MS.store(interval_secs.saturating_mul(1000), Ordering::Relaxed);
+    schedule();
+    Ok(())
+}
+
+/// Queue the next periodic save
+fn schedule() {
+    let interval_ms = INTERVAL_MS.load(Ordering::Relaxed);
+    if interval_ms > 0 {
+        let work = crate::task::workqueue::Work::new(periodic_save, 0);
+        let _ = crate::task::workqueue::schedule_delayed_work(work, interval_ms);
+    }
+}
+
+/// Workqueue function for periodic saves
+fn periodic_save(_: usize) {
+    sync();
+    schedule();
+}
+
+/// Save new records now, e.g. before a reboot
+pub fn sync() {
+    if let Some(pstore) = PSTORE.lock().as_mut() {
+        pstore.sync();
+    }
+}
+
+/// Log the panic message and save the log
+///
+/// Called from the panic handler. Gives up rather than wait for a lock the
+/// panicking code may hold.
+pub fn save_on_panic(message: fmt::Arguments) {
+    logger::try_log(LogLevel::Error, "panic", message);
+    if let Some(mut pstore) = PSTORE.try_lock() {
+        if let Some(pstore) = pstore.as_mut() {
+            let _ = pstore.save(Reason::Panic, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use crate::io::logger::LogBuffer;
+    use crate::storage::BlockDeviceError;
+
+    /// Disk kept in memory
+    struct RamDisk {
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl RamDisk {
+        fn new(sectors: usize) -> Self {
+            Self { data: Mutex::new(alloc::vec![0; sectors * SECTOR_SIZE]) }
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        fn block_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.data.lock().len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_blocks(&self, start_block: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+            let start = start_block as usize * SECTOR_SIZE;
+            let data = self.data.lock();
+            let src = data.get(start..start + buffer.len()).ok_or(BlockDeviceError::InvalidBlock)?;
+            buffer.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_blocks(&self, start_block: u64, buffer: &[u8]) -> Result<(), BlockDeviceError> {
+            let start = start_block as usize * SECTOR_SIZE;
+            let mut data = self.data.lock();
+            let dst = data.get_mut(start..start + buffer.len()).ok_or(BlockDeviceError::InvalidBlock)?;
+            dst.copy_from_slice(buffer);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), BlockDeviceError> {
+            Ok(())
+        }
+    }
+
+    fn sample_log(count: u64) -> LogBuffer<64> {
+        let mut log = LogBuffer::new();
+        for i in 0..count {
+            log.push(LogLevel::Info, "test", i * 1_000_000, &alloc::format!("message {}", i));
+        }
+        log
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = Header { reason: Reason::Panic, len: 1234, checksum: 0xdead_beef };
+        assert_eq!(Header::decode(&header.encode()), Some(header));
+        assert_eq!(Header::decode(&[0u8; SECTOR_SIZE]), None);
+    }
+
+    #[test]
+    fn test_region_roundtrip() {
+        let log = sample_log(5);
+        let get = |seq| log.get(seq).copied();
+        let disk = RamDisk::new(8);
+        save_region(&disk, 2, 4, Reason::Panic, (0, 5), &get).unwrap();
+
+        let (reason, text) = load_region(&disk, 2, 4).unwrap();
+        assert_eq!(reason, Reason::Panic);
+        let text = core::str::from_utf8(&text).unwrap();
+        assert_eq!(text.lines().count(), 5);
+        assert_eq!(text.lines().next(), Some("<6>[    0.000000] test: message 0"));
+        assert!(text.ends_with("test: message 4\n"));
+
+        // A damaged region is not recovered
+        disk.data.lock()[3 * SECTOR_SIZE] ^= 1;
+        assert!(load_region(&disk, 2, 4).is_none());
+    }
+
+    #[test]
+    fn test_region_keeps_newest() {
+        let log = sample_log(64);
+        let get = |seq| log.get(seq).copied();
+        let disk = RamDisk::new(2);
+        save_region(&disk, 0, 2, Reason::Sync, (0, 64), &get).unwrap();
+
+        let (_, text) = load_region(&disk, 0, 2).unwrap();
+        assert!(text.len() <= SECTOR_SIZE);
+        let text = core::str::from_utf8(&text).unwrap();
+        assert!(text.ends_with("test: message 63\n"));
+        assert!(!text.contains("message 0\n"));
+    }
+
+    #[test]
+    fn test_save_file_appends() {
+        let mut fs = MemoryFileSystem::new();
+        let log = sample_log(3);
+        let get = |seq| log.get(seq).copied();
+        assert_eq!(save_file(&mut fs, "/kmsg", 0, (0, 2), &get), Ok(2));
+        assert_eq!(save_file(&mut fs, "/kmsg", 2, (0, 3), &get), Ok(3));
+        let text = crate::fs::read_file_in(&fs, "/kmsg").unwrap();
+        let text = core::str::from_utf8(&text).unwrap();
+        assert_eq!(text.lines().count(), 3);
+
+        // Records overwritten before a save are reported
+        assert_eq!(save_file(&mut fs, "/lost", 1, (5, 5), &get), Ok(5));
+        let text = crate::fs::read_file_in(&fs, "/lost").unwrap();
+        assert_eq!(text, b"<4>pstore: 4 records lost before they were saved\n");
+    }
+
+    #[test]
+    fn test_parse_disk() {
+        assert_eq!(
+            parse_disk("hdb:2048:64"),
+            Some((AtaBus::Primary, AtaDrive::Slave, 2048, 64))
+        );
+        assert_eq!(parse_disk("hde:0:64"), None);
+        assert_eq!(parse_disk("hda:0:1"), None);
+        assert_eq!(parse_disk("hda:0"), None);
+        assert_eq!(parse_disk("hda:0:8:9"), None);
+    }
+}
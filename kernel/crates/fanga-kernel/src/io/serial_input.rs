@@ -0,0 +1,378 @@
+//! Serial Ports and Console Input
+//!
+//! The ports COM1-COM4 are probed at boot and each one found is registered
+//! as a TTY character device, `ttyS0` to `ttyS3`. The `console=` boot
+//! option (`console=ttyS1,115200n8`) picks the port kernel messages go to
+//! and its line settings; COM1 at 38400 8N1 is the default.
+//!
+//! COM1/COM3 raise IRQ 4 and COM2/COM4 IRQ 3 when bytes arrive; the
+//! handler moves them from the UARTs into the arch layer's receive rings,
+//! where `serial::read` can pick them up. While the shell owns serial
+//! input (the default), bytes received on the console port are then
+//! decoded, including VT100 arrow-key sequences, into the keys the
+//! keyboard handler understands, so the shell can be driven from a serial
+//! terminal. While a program owns the terminal on screen the bytes go to
+//! its TTY unchanged instead, so its line discipline sees exactly what
+//! was typed.
+//!
+//! Choosing a serial console with `console=` also makes it a full session
+//! on the terminal on screen: the terminal's output, including shell
+//! echo, line edits and program output, is copied to the port, so the
+//! machine can be used entirely headless.
+//!
+//! This module provides:
+//! - Port setup, console selection and TTY device registration
+//! - IRQ 3/4 registration and the receive handler
+//! - Terminal byte to key decoding
+//! - Switching serial input between the shell and raw readers
+//! - The serial console session
+//! - Restoring the line settings after suspend to RAM
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fanga_arch_x86_64::keyboard::KeyCode;
+use fanga_arch_x86_64::serial::{self, ComPort, SerialConfig};
+use spin::Mutex;
+
+use super::chardev::{self, CharDevice, CharDeviceKind};
+use crate::irq::{self, IrqFlags, IrqReturn};
+
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+/// A decoded key: key code, the character it produces and whether Ctrl is held
+pub type DecodedKey = (KeyCode, Option<char>, bool);
+
+/// Decoder state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    /// After a carriage return, which may be followed by a line feed
+    AfterCr,
+    /// After ESC
+    Escape,
+    /// After ESC [
+    Csi,
+    /// After ESC [ and a digit, waiting for `~`
+    CsiTilde(KeyCode),
+}
+
+/// Turns terminal input bytes into keys
+pub struct TerminalDecoder {
+    state: State,
+}
+
+impl TerminalDecoder {
+    /// Create a decoder
+    pub const fn new() -> Self {
+        Self { state: State::Normal }
+    }
+
+    /// Feed one byte
+    ///
+    /// # Returns
+    /// The key completed by this byte, if any
+    pub fn feed(&mut self, byte: u8) -> Option<DecodedKey> {
+        let state = core::mem::replace(&mut self.state, State::Normal);
+        match state {
+            State::Escape => {
+                if byte == b'[' {
+                    self.state = State::Csi;
+                }
+                None
+            }
+            State::Csi => {
+                let key = match byte {
+                    b'A' => KeyCode::Up,
+                    b'B' => KeyCode::Down,
+                    b'C' => KeyCode::Right,
+                    b'D' => KeyCode::Left,
+                    b'H' => KeyCode::Home,
+                    b'F' => KeyCode::End,
+                    b'1' | b'7' => return self.wait_tilde(KeyCode::Home),
+                    b'3' => return self.wait_tilde(KeyCode::Delete),
+                    b'4' | b'8' => return self.wait_tilde(KeyCode::End),
+                    _ => return None,
+                };
+                Some((key, None, false))
+            }
+            State::CsiTilde(key) => (byte == b'~').then_some((key, None, false)),
+            State::AfterCr if byte == b'\n' => None,
+            State::Normal | State::AfterCr => self.feed_normal(byte),
+        }
+    }
+
+    fn wait_tilde(&mut self, key: KeyCode) -> Option<DecodedKey> {
+        self.state = State::CsiTilde(key);
+        None
+    }
+
+    fn feed_normal(&mut self, byte: u8) -> Option<DecodedKey> {
+        match byte {
+            b'\r' => {
+                self.state = State::AfterCr;
+                Some((KeyCode::Enter, None, false))
+            }
+            b'\n' => Some((KeyCode::Enter, None, false)),
+            ESCAPE => {
+                self.state = State::Escape;
+                None
+            }
+            BACKSPACE | DELETE => Some((KeyCode::Backspace, None, false)),
+            TAB => Some((KeyCode::Tab, None, false)),
+            // Ctrl+A to Ctrl+Z
+            0x01..=0x1A => Some((KeyCode::Char((b'a' + byte - 1) as char), None, true)),
+            0x20..=0x7E => {
+                let ch = byte as char;
+                Some((KeyCode::Char(ch), Some(ch), false))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for TerminalDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decoder for the console port, only used from the interrupt handler
+static DECODER: Mutex<TerminalDecoder> = Mutex::new(TerminalDecoder::new());
+
+/// Whether received bytes go to the shell
+static SHELL_INPUT: AtomicBool = AtomicBool::new(true);
+
+/// Whether the console port follows the terminal on screen
+static SESSION: AtomicBool = AtomicBool::new(false);
+
+/// Route console input to the shell, or leave it buffered for `serial::read`
+pub fn set_shell_input(enabled: bool) {
+    SHELL_INPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Check whether console input goes to the shell
+pub fn shell_input() -> bool {
+    SHELL_INPUT.load(Ordering::Relaxed)
+}
+
+/// Check whether the console port is a session on the terminal on screen
+pub fn session() -> bool {
+    SESSION.load(Ordering::Relaxed)
+}
+
+/// Copy terminal output to the console port, ending lines with CR LF
+fn mirror_output(bytes: &[u8]) {
+    let console = serial::console();
+    for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+        match line.strip_suffix(b"\n") {
+            Some(text) => {
+                serial::write_bytes(console, text);
+                serial::write_bytes(console, b"\r\n");
+            }
+            None => serial::write_bytes(console, line),
+        }
+    }
+}
+
+/// A serial port as a TTY device
+struct SerialTty(ComPort);
+
+impl CharDevice for SerialTty {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Tty
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(serial::read(self.0, buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        serial::write_bytes(self.0, buf);
+        Ok(buf.len())
+    }
+}
+
+/// Parse a `console=` value such as `ttyS1` or `ttyS1,115200n8`
+///
+/// # Returns
+/// The port and, if options were given, its line configuration
+pub fn parse_console(value: &str) -> Option<(ComPort, Option<SerialConfig>)> {
+    let (name, options) = match value.split_once(',') {
+        Some((name, options)) => (name, Some(options)),
+        None => (value, None),
+    };
+    let port = ComPort::ALL.into_iter().find(|port| port.tty_name() == name)?;
+    match options {
+        Some(options) => Some((port, Some(SerialConfig::parse(options)?))),
+        None => Some((port, None)),
+    }
+}
+
+/// Receive interrupt handler for IRQ 3 and 4
+fn serial_irq_handler(irq: u8) -> IrqReturn {
+    let mut received = 0;
+    for port in ComPort::ALL {
+        if port.irq() == irq && serial::is_present(port) {
+            received += serial::handle_rx_interrupt(port);
+        }
+    }
+    if received == 0 {
+        return IrqReturn::None;
+    }
+    if shell_input() {
+        let console = serial::console();
+        let mut decoder = DECODER.lock();
+        let mut raw = [0u8; 64];
+        loop {
+            let count = serial::read(console, &mut raw);
+            if count == 0 {
+                break;
+            }
+            if super::tty::receive_input(&raw[..count]) {
+                continue;
+            }
+            for &byte in &raw[..count] {
+                if let Some((keycode, ascii, ctrl)) = decoder.feed(byte) {
+                    super::keyboard_handler::handle_line_key(keycode, ascii, ctrl);
+                }
+            }
+        }
+    }
+    IrqReturn::Handled
+}
+
+/// Select the console port from the `console=` boot option
+///
+/// Called early, before the heap exists, so messages from the rest of the
+/// boot already reach the chosen port. Falls back to COM1 if the option
+/// names a port that does not exist. A port chosen here becomes a terminal
+/// session once `init` runs.
+pub fn select_console(option: Option<&str>) {
+    let Some(value) = option.filter(|value| value.starts_with("ttyS")) else {
+        return;
+    };
+    let Some((port, config)) = parse_console(value) else {
+        crate::log_warn!(target: "serial", "Ignoring invalid console={}", value);
+        return;
+    };
+    let config = config.unwrap_or_else(|| serial::config(port));
+    match serial::configure(port, config).and_then(|()| serial::set_console(port)) {
+        Ok(()) => {
+            SESSION.store(true, Ordering::Relaxed);
+            crate::log_info!(target: "serial", "Console on {} ({})", port.tty_name(), config);
+        }
+        Err(e) => crate::log_warn!(target: "serial", "Cannot use {} as console: {}", port.tty_name(), e),
+    }
+}
+
+/// Probe the serial ports, register them as TTYs and enable their
+/// receive interrupts
+///
+/// # Returns
+/// The number of ports found
+pub fn init() -> Result<usize, &'static str> {
+    let mut found = 0;
+    let mut requested = [false; irq::NR_IRQS];
+    for port in ComPort::ALL {
+        if !serial::is_present(port) && serial::configure(port, SerialConfig::DEFAULT).is_err() {
+            continue;
+        }
+        chardev::register(port.tty_name(), Arc::new(SerialTty(port)))?;
+        crate::log_info!(
+            target: "serial",
+            "{} at 0x{:x}, IRQ {} ({})",
+            port.tty_name(),
+            port.base(),
+            port.irq(),
+            serial::config(port)
+        );
+
+        // COM1/COM3 and COM2/COM4 share a line and a handler
+        let line = port.irq() as usize;
+        if !requested[line] {
+            irq::request_irq(port.irq(), serial_irq_handler, IrqFlags::NONE, "serial")?;
+            requested[line] = true;
+        }
+        serial::enable_rx_interrupt(port);
+        found += 1;
+    }
+
+    if found > 0 {
+        let ops = crate::power::device::PmOps { suspend: || Ok(()), resume: resume_ports };
+        crate::power::device::register_driver("serial", ops)?;
+    }
+
+    if session() {
+        super::framebuffer::framebuffer().set_mirror(Some(mirror_output));
+        crate::log_info!(target: "serial", "Terminal session on {}", serial::console().tty_name());
+    }
+    Ok(found)
+}
+
+/// Program the ports again after the UARTs lost their settings in S3
+fn resume_ports() -> Result<(), &'static str> {
+    for port in ComPort::ALL {
+        if serial::is_present(port) {
+            serial::configure(port, serial::config(port))?;
+            serial::enable_rx_interrupt(port);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(bytes: &[u8]) -> Vec<DecodedKey> {
+        let mut decoder = TerminalDecoder::new();
+        bytes.iter().filter_map(|&b| decoder.feed(b)).collect()
+    }
+
+    #[test]
+    fn test_decode_line() {
+        assert_eq!(
+            decode(b"ls\r\n\x7f"),
+            [
+                (KeyCode::Char('l'), Some('l'), false),
+                (KeyCode::Char('s'), Some('s'), false),
+                (KeyCode::Enter, None, false),
+                (KeyCode::Backspace, None, false),
+            ]
+        );
+        assert_eq!(decode(b"\n\n").len(), 2);
+        assert_eq!(decode(b"\x03"), [(KeyCode::Char('c'), None, true)]);
+        assert_eq!(decode(b"\x1a"), [(KeyCode::Char('z'), None, true)]);
+    }
+
+    #[test]
+    fn test_decode_escape_sequences() {
+        assert_eq!(
+            decode(b"\x1b[A\x1b[D\x1b[3~\x1b[1~x"),
+            [
+                (KeyCode::Up, None, false),
+                (KeyCode::Left, None, false),
+                (KeyCode::Delete, None, false),
+                (KeyCode::Home, None, false),
+                (KeyCode::Char('x'), Some('x'), false),
+            ]
+        );
+        // Unknown sequences are dropped
+        assert_eq!(decode(b"\x1b[Zq"), [(KeyCode::Char('q'), Some('q'), false)]);
+    }
+
+    #[test]
+    fn test_parse_console() {
+        assert_eq!(parse_console("ttyS0"), Some((ComPort::Com1, None)));
+        let (port, config) = parse_console("ttyS1,115200n8").unwrap();
+        assert_eq!(port, ComPort::Com2);
+        assert_eq!(config.map(|config| config.baud), Some(115_200));
+        assert_eq!(parse_console("ttyS4"), None);
+        assert_eq!(parse_console("ttyS2,fast"), None);
+    }
+}
@@ -0,0 +1,1039 @@
+//! TTY Layer and Line Discipline
+//!
+//! Each virtual terminal is a TTY device, `tty1` to `tty4`, sitting between
+//! the keyboard (or the serial console) and whoever reads the terminal.
+//! Input passes through the line discipline, which follows the terminal's
+//! termios settings:
+//! - Canonical mode collects a line, with erase, kill, word erase and EOF
+//!   handled as it is typed, and hands out whole lines
+//! - Raw mode hands bytes out as they arrive
+//! - Echo, with control characters shown as `^X`
+//! - Ctrl+C, Ctrl+\ and Ctrl+Z send SIGINT, SIGQUIT and SIGTSTP to the
+//!   foreground process group
+//!
+//! While no process group is in the foreground the kernel shell owns the
+//! terminal and keys go to its line editor as before. A program takes the
+//! terminal with `TIOCSPGRP`; from then on keys are encoded as terminal
+//! bytes (arrows as VT100 sequences) and fed to the line discipline, and
+//! `read()` on fd 0 blocks until the discipline has input for it.
+//!
+//! Termios and the ioctls use the Linux layouts and numbers. VMIN and
+//! VTIME are not implemented: a raw read returns as soon as there is any
+//! input. `KDGKBLAYOUT` and `KDSKBLAYOUT` are our own: they get and set
+//! the keyboard layout by name.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use fanga_arch_x86_64::keyboard::KeyCode;
+use fanga_arch_x86_64::keyboard_layout;
+use fanga_arch_x86_64::uaccess::UserPtr;
+
+use super::chardev::{self, CharDevice, CharDeviceKind};
+use super::vt::{self, MAX_TERMINALS};
+use crate::syscall::{EAGAIN, EFAULT, EINTR, EINVAL, EPERM, ESRCH, ENOTTY};
+use crate::task::ipc::Signal;
+use crate::task::pgroup::{self, ProcessGroupId, TerminalId};
+use crate::task::{scheduler, sigadv, wait, TaskId};
+
+/// Number of control characters in `Termios::c_cc`
+pub const NCCS: usize = 19;
+
+/// `c_iflag` bits
+pub const ISTRIP: u32 = 0o000040;
+pub const INLCR: u32 = 0o000100;
+pub const IGNCR: u32 = 0o000200;
+pub const ICRNL: u32 = 0o000400;
+pub const IXON: u32 = 0o002000;
+
+/// `c_oflag` bits
+pub const OPOST: u32 = 0o000001;
+pub const ONLCR: u32 = 0o000004;
+
+/// `c_cflag` bits
+pub const B38400: u32 = 0o000017;
+pub const CS8: u32 = 0o000060;
+pub const CREAD: u32 = 0o000200;
+
+/// `c_lflag` bits
+pub const ISIG: u32 = 0o000001;
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+pub const ECHOE: u32 = 0o000020;
+pub const ECHOK: u32 = 0o000040;
+pub const ECHONL: u32 = 0o000100;
+pub const NOFLSH: u32 = 0o000200;
+pub const ECHOCTL: u32 = 0o001000;
+pub const ECHOKE: u32 = 0o004000;
+pub const IEXTEN: u32 = 0o100000;
+
+/// `c_cc` indices
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VWERASE: usize = 14;
+
+/// ioctl requests
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TCSETSW: u32 = 0x5403;
+pub const TCSETSF: u32 = 0x5404;
+pub const TCFLSH: u32 = 0x540B;
+pub const TIOCGPGRP: u32 = 0x540F;
+pub const TIOCSPGRP: u32 = 0x5410;
+pub const TIOCGWINSZ: u32 = 0x5413;
+pub const TIOCSWINSZ: u32 = 0x5414;
+pub const FIONREAD: u32 = 0x541B;
+pub const KDGKBLAYOUT: u32 = 0x4B80;
+pub const KDSKBLAYOUT: u32 = 0x4B81;
+
+/// Size of the NUL-padded layout name taken by the layout ioctls
+pub const LAYOUT_NAME_LEN: usize = 32;
+
+/// `TCFLSH` queue selectors
+pub const TCIFLUSH: u64 = 0;
+pub const TCOFLUSH: u64 = 1;
+pub const TCIOFLUSH: u64 = 2;
+
+/// Longest line canonical mode will collect
+pub const MAX_CANON: usize = 255;
+
+/// How long a blocked reader sleeps before looking at the queue again
+///
+/// Input arrives in interrupt context, where waking a reader is only
+/// attempted if the scheduler lock is free, so readers also poll.
+const READ_POLL_MS: u64 = 50;
+
+/// Terminal settings (Linux `struct termios`)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+impl Termios {
+    /// The settings of a freshly opened terminal, as `stty sane` leaves them
+    pub const fn sane() -> Self {
+        let mut c_cc = [0u8; NCCS];
+        c_cc[VINTR] = 0x03;
+        c_cc[VQUIT] = 0x1C;
+        c_cc[VERASE] = 0x7F;
+        c_cc[VKILL] = 0x15;
+        c_cc[VEOF] = 0x04;
+        c_cc[VMIN] = 1;
+        c_cc[VSUSP] = 0x1A;
+        c_cc[VWERASE] = 0x17;
+        Self {
+            c_iflag: ICRNL | IXON,
+            c_oflag: OPOST | ONLCR,
+            c_cflag: B38400 | CS8 | CREAD,
+            c_lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            c_line: 0,
+            c_cc,
+        }
+    }
+
+    /// Switch to raw mode, like `cfmakeraw()`
+    pub fn make_raw(&mut self) {
+        self.c_iflag &= !(ISTRIP | INLCR | IGNCR | ICRNL | IXON);
+        self.c_oflag &= !OPOST;
+        self.c_lflag &= !(ECHO | ECHONL | ICANON | ISIG | IEXTEN);
+        self.c_cc[VMIN] = 1;
+        self.c_cc[VTIME] = 0;
+    }
+
+    /// Check whether canonical mode is on
+    pub fn is_canonical(&self) -> bool {
+        self.c_lflag & ICANON != 0
+    }
+}
+
+impl Default for Termios {
+    fn default() -> Self {
+        Self::sane()
+    }
+}
+
+/// Terminal size (Linux `struct winsize`)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Winsize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+/// Input processing for one terminal
+pub struct LineDiscipline {
+    termios: Termios,
+    /// Line being typed, in canonical mode
+    line: Vec<u8>,
+    /// Input ready for readers
+    ready: VecDeque<u8>,
+    /// Lengths of the lines in `ready`, in canonical mode; 0 is end of file
+    lines: VecDeque<usize>,
+}
+
+impl LineDiscipline {
+    /// Create a line discipline with sane settings
+    pub const fn new() -> Self {
+        Self {
+            termios: Termios::sane(),
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            lines: VecDeque::new(),
+        }
+    }
+
+    /// Current settings
+    pub fn termios(&self) -> &Termios {
+        &self.termios
+    }
+
+    /// Change the settings
+    ///
+    /// Leaving canonical mode makes the line being typed readable;
+    /// entering it turns pending raw input into one line.
+    pub fn set_termios(&mut self, termios: Termios) {
+        let was_canonical = self.termios.is_canonical();
+        self.termios = termios;
+        match (was_canonical, termios.is_canonical()) {
+            (true, false) => {
+                self.ready.extend(self.line.drain(..));
+                self.lines.clear();
+            }
+            (false, true) => {
+                self.lines.clear();
+                if !self.ready.is_empty() {
+                    self.lines.push_back(self.ready.len());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drop all pending input
+    pub fn flush_input(&mut self) {
+        self.line.clear();
+        self.ready.clear();
+        self.lines.clear();
+    }
+
+    /// Bytes waiting to be read
+    pub fn available(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// Check whether a read would return something
+    pub fn readable(&self) -> bool {
+        if self.termios.is_canonical() {
+            !self.lines.is_empty()
+        } else {
+            !self.ready.is_empty()
+        }
+    }
+
+    /// Take input for a reader
+    ///
+    /// In canonical mode a read never returns more than one line.
+    ///
+    /// # Returns
+    /// The number of bytes read, 0 at end of file, or `None` if there is
+    /// nothing to read yet
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let count = if self.termios.is_canonical() {
+            let line = self.lines.front_mut()?;
+            let count = (*line).min(buf.len());
+            *line -= count;
+            if *line == 0 {
+                self.lines.pop_front();
+            }
+            count
+        } else if self.ready.is_empty() {
+            return None;
+        } else {
+            self.ready.len().min(buf.len())
+        };
+        for (slot, byte) in buf.iter_mut().zip(self.ready.drain(..count)) {
+            *slot = byte;
+        }
+        Some(count)
+    }
+
+    /// Check whether `byte` is the enabled control character `index`
+    fn is_cc(&self, byte: u8, index: usize) -> bool {
+        let cc = self.termios.c_cc[index];
+        cc != 0 && cc == byte
+    }
+
+    /// Process one input byte
+    ///
+    /// Echo is appended to `echo`, before output processing.
+    ///
+    /// # Returns
+    /// The signal the byte generates, if any
+    pub fn receive(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<Signal> {
+        let iflag = self.termios.c_iflag;
+        let lflag = self.termios.c_lflag;
+
+        let mut byte = byte;
+        if iflag & ISTRIP != 0 {
+            byte &= 0x7F;
+        }
+        if byte == b'\r' {
+            if iflag & IGNCR != 0 {
+                return None;
+            }
+            if iflag & ICRNL != 0 {
+                byte = b'\n';
+            }
+        } else if byte == b'\n' && iflag & INLCR != 0 {
+            byte = b'\r';
+        }
+
+        if lflag & ISIG != 0 {
+            let signal = if self.is_cc(byte, VINTR) {
+                Some(Signal::SIGINT)
+            } else if self.is_cc(byte, VQUIT) {
+                Some(Signal::SIGQUIT)
+            } else if self.is_cc(byte, VSUSP) {
+                Some(Signal::SIGTSTP)
+            } else {
+                None
+            };
+            if signal.is_some() {
+                if lflag & NOFLSH == 0 {
+                    self.flush_input();
+                }
+                self.echo(byte, echo);
+                return signal;
+            }
+        }
+
+        if !self.termios.is_canonical() {
+            self.ready.push_back(byte);
+            self.echo(byte, echo);
+            return None;
+        }
+
+        if self.is_cc(byte, VERASE) {
+            self.erase(echo);
+        } else if self.is_cc(byte, VKILL) {
+            while self.erase(echo) {}
+            if lflag & (ECHOE | ECHOKE) == 0 && lflag & ECHOK != 0 {
+                echo.push(b'\n');
+            }
+        } else if lflag & IEXTEN != 0 && self.is_cc(byte, VWERASE) {
+            while self.line.last() == Some(&b' ') && self.erase(echo) {}
+            while self.line.last().is_some_and(|&b| b != b' ') && self.erase(echo) {}
+        } else if self.is_cc(byte, VEOF) {
+            self.commit_line();
+        } else if byte == b'\n' || self.is_cc(byte, VEOL) {
+            self.line.push(byte);
+            if lflag & ECHO != 0 || (byte == b'\n' && lflag & ECHONL != 0) {
+                echo.push(byte);
+            }
+            self.commit_line();
+        } else if self.line.len() < MAX_CANON {
+            self.line.push(byte);
+            self.echo(byte, echo);
+        }
+        None
+    }
+
+    /// Make the line being typed readable
+    fn commit_line(&mut self) {
+        self.lines.push_back(self.line.len());
+        self.ready.extend(self.line.drain(..));
+    }
+
+    /// Echo an input byte
+    fn echo(&self, byte: u8, echo: &mut Vec<u8>) {
+        if self.termios.c_lflag & ECHO == 0 {
+            return;
+        }
+        if self.termios.c_lflag & ECHOCTL != 0 && is_echoed_as_caret(byte) {
+            echo.extend_from_slice(&[b'^', byte ^ 0x40]);
+        } else {
+            echo.push(byte);
+        }
+    }
+
+    /// Remove the last character of the line being typed
+    ///
+    /// # Returns
+    /// `false` if the line was empty
+    fn erase(&mut self, echo: &mut Vec<u8>) -> bool {
+        let Some(mut last) = self.line.pop() else {
+            return false;
+        };
+        // A UTF-8 character goes as a whole
+        while last & 0xC0 == 0x80 {
+            match self.line.pop() {
+                Some(byte) => last = byte,
+                None => break,
+            }
+        }
+        let lflag = self.termios.c_lflag;
+        if lflag & ECHO != 0 && lflag & ECHOE != 0 {
+            let width = if lflag & ECHOCTL != 0 && is_echoed_as_caret(last) { 2 } else { 1 };
+            for _ in 0..width {
+                echo.extend_from_slice(b"\x08 \x08");
+            }
+        }
+        true
+    }
+
+    /// Apply output processing to `bytes`, appending the result to `out`
+    pub fn process_output(&self, bytes: &[u8], out: &mut Vec<u8>) {
+        let oflag = self.termios.c_oflag;
+        if oflag & OPOST == 0 || oflag & ONLCR == 0 {
+            out.extend_from_slice(bytes);
+            return;
+        }
+        for &byte in bytes {
+            if byte == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(byte);
+        }
+    }
+}
+
+impl Default for LineDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Control characters echoed as `^X`: all but tab and newline, plus DEL
+fn is_echoed_as_caret(byte: u8) -> bool {
+    (byte < 0x20 && byte != b'\t' && byte != b'\n') || byte == 0x7F
+}
+
+/// Encode a key as the bytes a terminal sends for it
+pub fn encode_key(keycode: KeyCode, ascii: Option<char>, ctrl: bool, out: &mut Vec<u8>) {
+    let sequence: &[u8] = match keycode {
+        KeyCode::Char(ch) if ctrl && ch.is_ascii_alphabetic() => {
+            out.push(ch.to_ascii_lowercase() as u8 & 0x1F);
+            return;
+        }
+        KeyCode::Enter => b"\r",
+        KeyCode::Backspace => b"\x7f",
+        KeyCode::Tab => b"\t",
+        KeyCode::Escape => b"\x1b",
+        KeyCode::Up => b"\x1b[A",
+        KeyCode::Down => b"\x1b[B",
+        KeyCode::Right => b"\x1b[C",
+        KeyCode::Left => b"\x1b[D",
+        KeyCode::Home => b"\x1b[H",
+        KeyCode::End => b"\x1b[F",
+        KeyCode::Delete => b"\x1b[3~",
+        KeyCode::PageUp => b"\x1b[5~",
+        KeyCode::PageDown => b"\x1b[6~",
+        _ => {
+            if let Some(ch) = ascii {
+                let mut utf8 = [0u8; 4];
+                out.extend_from_slice(ch.encode_utf8(&mut utf8).as_bytes());
+            }
+            return;
+        }
+    };
+    out.extend_from_slice(sequence);
+}
+
+/// Mutable state of a TTY
+struct TtyState {
+    ldisc: LineDiscipline,
+    winsize: Winsize,
+    /// Foreground process group; `None` while the kernel shell owns the terminal
+    pgrp: Option<ProcessGroupId>,
+    /// Tasks blocked in `read()`
+    readers: VecDeque<TaskId>,
+}
+
+/// Sink a terminal writes its processed output to
+pub type TtyOutput = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// A terminal device
+pub struct Tty {
+    id: TerminalId,
+    name: String,
+    state: Mutex<TtyState>,
+    /// Where output goes after processing
+    output: TtyOutput,
+}
+
+impl Tty {
+    /// Create a terminal that sends its output to `output`
+    pub fn new(id: TerminalId, name: String, output: TtyOutput) -> Self {
+        Self {
+            id,
+            name,
+            state: Mutex::new(TtyState {
+                ldisc: LineDiscipline::new(),
+                winsize: Winsize::default(),
+                pgrp: None,
+                readers: VecDeque::new(),
+            }),
+            output,
+        }
+    }
+
+    /// Terminal ID
+    pub fn id(&self) -> TerminalId {
+        self.id
+    }
+
+    /// Device name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Current settings
+    pub fn termios(&self) -> Termios {
+        *self.state.lock().ldisc.termios()
+    }
+
+    /// Change the settings, optionally dropping pending input first
+    pub fn set_termios(&self, termios: Termios, flush: bool) {
+        let mut state = self.state.lock();
+        if flush {
+            state.ldisc.flush_input();
+        }
+        state.ldisc.set_termios(termios);
+    }
+
+    /// Terminal size
+    pub fn winsize(&self) -> Winsize {
+        self.state.lock().winsize
+    }
+
+    /// Set the terminal size
+    pub fn set_winsize(&self, winsize: Winsize) {
+        self.state.lock().winsize = winsize;
+    }
+
+    /// Foreground process group, if a program owns the terminal
+    pub fn foreground_pgrp(&self) -> Option<ProcessGroupId> {
+        self.state.lock().pgrp
+    }
+
+    /// Give the terminal to process group `pgrp`, or back to the kernel
+    /// shell with `None`
+    pub fn set_foreground_pgrp(&self, pgrp: Option<ProcessGroupId>) {
+        let mut state = self.state.lock();
+        if state.pgrp != pgrp {
+            state.ldisc.flush_input();
+        }
+        state.pgrp = pgrp;
+    }
+
+    /// Feed input bytes through the line discipline
+    ///
+    /// Echoes, signals the foreground process group and wakes readers.
+    pub fn receive(&self, bytes: &[u8]) {
+        let mut echo = Vec::new();
+        let mut signals = Vec::new();
+        let (pgrp, readers) = {
+            let mut state = self.state.lock();
+            for &byte in bytes {
+                if let Some(signal) = state.ldisc.receive(byte, &mut echo) {
+                    signals.push(signal);
+                }
+            }
+            let readers = if state.ldisc.readable() || !signals.is_empty() {
+                core::mem::take(&mut state.readers)
+            } else {
+                VecDeque::new()
+            };
+            (state.pgrp, readers)
+        };
+
+        if !echo.is_empty() {
+            self.write_output(&echo);
+        }
+        if let (Some(pgrp), Some(manager)) = (pgrp, sigadv::try_signal_manager()) {
+            let mut manager = manager.lock();
+            for signal in signals {
+                manager.send_signal(sigadv::SignalTarget::ProcessGroup(pgrp), signal, TaskId::new(0));
+            }
+        }
+        if !readers.is_empty() {
+            if let Some(mut sched) = scheduler::try_scheduler() {
+                for task in readers {
+                    wait::wake_task_in(&mut sched, task);
+                }
+            }
+        }
+    }
+
+    /// Write program output, applying output processing
+    fn write_output(&self, bytes: &[u8]) {
+        let mut out = Vec::with_capacity(bytes.len());
+        self.state.lock().ldisc.process_output(bytes, &mut out);
+        (self.output)(&out);
+    }
+
+    /// Read, blocking until the line discipline has input
+    ///
+    /// # Returns
+    /// The number of bytes read, or a negative error code
+    pub fn read_blocking(&self, buf: &mut [u8]) -> i64 {
+        loop {
+            if let Some(count) = self.state.lock().ldisc.read(buf) {
+                return count as i64;
+            }
+            let Some(task) = scheduler::scheduler().current_task() else {
+                return EAGAIN;
+            };
+            if signal_pending(task) {
+                return EINTR;
+            }
+            let waited = wait::wait_event_timeout(
+                &self.state,
+                READ_POLL_MS,
+                |state, task| {
+                    if state.ldisc.readable() {
+                        return true;
+                    }
+                    state.readers.push_back(task);
+                    false
+                },
+                |state, task| match state.readers.iter().position(|&t| t == task) {
+                    Some(pos) => {
+                        state.readers.remove(pos);
+                        true
+                    }
+                    None => false,
+                },
+            );
+            if waited.is_err() {
+                return EAGAIN;
+            }
+        }
+    }
+
+    /// Handle a terminal ioctl with argument `arg`
+    ///
+    /// # Returns
+    /// 0 or a value for the request on success, or a negative error code
+    pub fn ioctl(&self, request: u32, arg: u64) -> i64 {
+        match request {
+            TCGETS => put(arg, &self.termios()),
+            TCSETS | TCSETSW | TCSETSF => match UserPtr::<Termios>::new(arg).read() {
+                Ok(termios) => {
+                    self.set_termios(termios, request == TCSETSF);
+                    0
+                }
+                Err(_) => EFAULT,
+            },
+            TCFLSH => match arg {
+                TCIFLUSH | TCIOFLUSH => {
+                    self.state.lock().ldisc.flush_input();
+                    0
+                }
+                TCOFLUSH => 0,
+                _ => EINVAL,
+            },
+            TIOCGPGRP => {
+                let pgrp = self.foreground_pgrp().map_or(0, |pgrp| pgrp.as_usize() as i32);
+                put(arg, &pgrp)
+            }
+            TIOCSPGRP => match UserPtr::<i32>::new(arg).read() {
+                Ok(pgrp) => self.take_terminal(pgrp),
+                Err(_) => EFAULT,
+            },
+            TIOCGWINSZ => put(arg, &self.winsize()),
+            TIOCSWINSZ => match UserPtr::<Winsize>::new(arg).read() {
+                Ok(winsize) => {
+                    self.set_winsize(winsize);
+                    0
+                }
+                Err(_) => EFAULT,
+            },
+            FIONREAD => put(arg, &(self.state.lock().ldisc.available() as i32)),
+            KDGKBLAYOUT => {
+                let current = keyboard_layout::layout_manager().current_layout_name().as_bytes();
+                let mut name = [0u8; LAYOUT_NAME_LEN];
+                name[..current.len()].copy_from_slice(current);
+                put(arg, &name)
+            }
+            KDSKBLAYOUT => match UserPtr::<[u8; LAYOUT_NAME_LEN]>::new(arg).read() {
+                Ok(name) => {
+                    let len = name.iter().position(|&byte| byte == 0).unwrap_or(LAYOUT_NAME_LEN);
+                    let name = core::str::from_utf8(&name[..len]).unwrap_or("");
+                    match keyboard_layout::layout_manager().set_layout_by_name(name) {
+                        Ok(()) => 0,
+                        Err(_) => EINVAL,
+                    }
+                }
+                Err(_) => EFAULT,
+            },
+            _ => ENOTTY,
+        }
+    }
+
+    /// `TIOCSPGRP`: make `pgrp` the foreground group
+    ///
+    /// The group's session gets this terminal as its controlling terminal.
+    /// Group 0 hands the terminal back to the kernel shell.
+    fn take_terminal(&self, pgrp: i32) -> i64 {
+        if pgrp < 0 {
+            return EINVAL;
+        }
+        if pgrp == 0 {
+            self.set_foreground_pgrp(None);
+            return 0;
+        }
+
+        let pgid = ProcessGroupId::new(pgrp as usize);
+        {
+            let mut groups = pgroup::process_groups();
+            let Some(sid) = groups.get_group(pgid).map(|group| group.session_id) else {
+                return ESRCH;
+            };
+            if groups.set_foreground(pgid).is_err() {
+                return EPERM;
+            }
+            if let Some(session) = groups.get_session_mut(sid) {
+                session.set_controlling_terminal(self.id);
+            }
+        }
+        self.set_foreground_pgrp(Some(pgid));
+        0
+    }
+}
+
+impl CharDevice for Tty {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Tty
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(self.state.lock().ldisc.read(buf).unwrap_or(0))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, &'static str> {
+        self.write_output(buf);
+        Ok(buf.len())
+    }
+
+    fn readable(&self) -> bool {
+        self.state.lock().ldisc.readable()
+    }
+}
+
+/// Copy an ioctl result to user memory
+fn put<T: Copy>(arg: u64, value: &T) -> i64 {
+    match UserPtr::new(arg).write(value) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
+    }
+}
+
+/// Check whether `task` has a signal that should interrupt a wait
+fn signal_pending(task: TaskId) -> bool {
+    sigadv::try_signal_manager()
+        .map(|manager| manager.lock().get_handler(task).is_some_and(|sig| sig.has_pending()))
+        .unwrap_or(false)
+}
+
+/// TTYs of the virtual terminals
+static CONSOLES: Mutex<[Option<Arc<Tty>>; MAX_TERMINALS]> = Mutex::new([const { None }; MAX_TERMINALS]);
+
+/// Create and register `tty1` to `tty4` for the virtual terminals
+pub fn init() -> Result<(), &'static str> {
+    let (cols, rows) = {
+        let fb = super::framebuffer::framebuffer();
+        (fb.cols(), fb.rows())
+    };
+    let mut consoles = CONSOLES.lock();
+    for (index, slot) in consoles.iter_mut().enumerate() {
+        let tty = Arc::new(Tty::new(
+            TerminalId::new(index),
+            format!("tty{}", index + 1),
+            Box::new(move |bytes: &[u8]| vt::write_to(index, bytes)),
+        ));
+        tty.set_winsize(Winsize { ws_row: rows as u16, ws_col: cols as u16, ..Winsize::default() });
+        chardev::register(tty.name(), tty.clone())?;
+        *slot = Some(tty);
+    }
+    Ok(())
+}
+
+/// TTY of virtual terminal `id`
+pub fn console(id: usize) -> Option<Arc<Tty>> {
+    CONSOLES.lock().get(id)?.clone()
+}
+
+/// TTY of the virtual terminal on screen
+pub fn active() -> Option<Arc<Tty>> {
+    console(vt::current_terminal_id())
+}
+
+/// Update the size of every console TTY, after a font change
+pub fn resize_consoles(cols: usize, rows: usize) {
+    for tty in CONSOLES.lock().iter().flatten() {
+        let winsize = Winsize { ws_row: rows as u16, ws_col: cols as u16, ..tty.winsize() };
+        tty.set_winsize(winsize);
+    }
+}
+
+/// Give a key to the terminal on screen if a program owns it
+///
+/// # Returns
+/// `false` if the kernel shell owns the terminal and should handle the key
+pub fn handle_key(keycode: KeyCode, ascii: Option<char>, ctrl: bool) -> bool {
+    let Some(tty) = active() else {
+        return false;
+    };
+    if tty.foreground_pgrp().is_none() {
+        return false;
+    }
+    let mut bytes = Vec::new();
+    encode_key(keycode, ascii, ctrl, &mut bytes);
+    tty.receive(&bytes);
+    true
+}
+
+/// Give input bytes from a terminal line (the serial console) to the
+/// terminal on screen if a program owns it
+///
+/// Unlike keys, the bytes go to the line discipline unchanged.
+///
+/// # Returns
+/// `false` if the kernel shell owns the terminal and should handle the input
+pub fn receive_input(bytes: &[u8]) -> bool {
+    let Some(tty) = active() else {
+        return false;
+    };
+    if tty.foreground_pgrp().is_none() {
+        return false;
+    }
+    tty.receive(bytes);
+    true
+}
+
+/// Controlling terminal of `task`, or the terminal on screen if it has none
+fn terminal_of(task: Option<TaskId>) -> Option<Arc<Tty>> {
+    let controlling = task.and_then(|task| {
+        let groups = pgroup::process_groups();
+        let sid = groups.get_session(task)?;
+        groups.get_session_ref(sid)?.controlling_terminal
+    });
+    match controlling {
+        Some(terminal) => console(terminal.as_usize()),
+        None => active(),
+    }
+}
+
+/// Read standard input of the current task
+pub fn read_stdin(buf: &mut [u8]) -> i64 {
+    let task = scheduler::scheduler().current_task();
+    match terminal_of(task) {
+        Some(tty) => tty.read_blocking(buf),
+        None => 0,
+    }
+}
+
+/// ioctl system call on the standard streams
+pub fn sys_ioctl(fd: i32, request: u32, arg: u64) -> i64 {
+    if !(0..=2).contains(&fd) {
+        return ENOTTY;
+    }
+    let task = scheduler::scheduler().current_task();
+    match terminal_of(task) {
+        Some(tty) => tty.ioctl(request, arg),
+        None => ENOTTY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(ldisc: &mut LineDiscipline, input: &[u8]) -> (Vec<u8>, Vec<Signal>) {
+        let mut echo = Vec::new();
+        let mut signals = Vec::new();
+        for &byte in input {
+            signals.extend(ldisc.receive(byte, &mut echo));
+        }
+        (echo, signals)
+    }
+
+    fn read_all(ldisc: &mut LineDiscipline) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        ldisc.read(&mut buf).map(|count| buf[..count].to_vec())
+    }
+
+    #[test]
+    fn test_canonical_line_editing() {
+        let mut ldisc = LineDiscipline::new();
+        let (echo, signals) = feed(&mut ldisc, b"lx\x7fs -l");
+        assert!(signals.is_empty());
+        assert_eq!(echo, b"lx\x08 \x08s -l");
+        assert!(!ldisc.readable());
+
+        // Word erase, then carriage return ends the line
+        feed(&mut ldisc, b"\x17-a\r");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"ls -a\n");
+        assert_eq!(read_all(&mut ldisc), None);
+
+        // Kill erases the whole line
+        let (echo, _) = feed(&mut ldisc, b"ab\x15c\n");
+        assert_eq!(echo, b"ab\x08 \x08\x08 \x08c\n");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"c\n");
+    }
+
+    #[test]
+    fn test_canonical_reads_one_line_at_a_time() {
+        let mut ldisc = LineDiscipline::new();
+        feed(&mut ldisc, b"one\ntwo\n");
+        let mut buf = [0u8; 2];
+        assert_eq!(ldisc.read(&mut buf), Some(2));
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"e\n");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"two\n");
+    }
+
+    #[test]
+    fn test_eof() {
+        let mut ldisc = LineDiscipline::new();
+        // EOF on a partial line hands it out without a newline
+        let (echo, _) = feed(&mut ldisc, b"ab\x04\x04");
+        assert_eq!(echo, b"ab");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"ab");
+        // EOF on an empty line is a zero-length read
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"");
+        assert_eq!(read_all(&mut ldisc), None);
+    }
+
+    #[test]
+    fn test_erase_utf8_and_control_characters() {
+        let mut ldisc = LineDiscipline::new();
+        let (echo, _) = feed(&mut ldisc, "é\x01".as_bytes());
+        assert_eq!(echo, "é^A".as_bytes());
+        let (echo, _) = feed(&mut ldisc, b"\x7f\x7f\n");
+        assert_eq!(echo, b"\x08 \x08\x08 \x08\x08 \x08\n");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"\n");
+    }
+
+    #[test]
+    fn test_signals() {
+        let mut ldisc = LineDiscipline::new();
+        let (echo, signals) = feed(&mut ldisc, b"abc\x03");
+        assert_eq!(signals, [Signal::SIGINT]);
+        assert_eq!(echo, b"abc^C");
+        feed(&mut ldisc, b"\n");
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"\n");
+
+        let (_, signals) = feed(&mut ldisc, b"\x1a\x1c");
+        assert_eq!(signals, [Signal::SIGTSTP, Signal::SIGQUIT]);
+
+        // Without ISIG the characters are input
+        let mut termios = Termios::sane();
+        termios.c_lflag &= !ISIG;
+        ldisc.set_termios(termios);
+        let (_, signals) = feed(&mut ldisc, b"\x03\n");
+        assert!(signals.is_empty());
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"\x03\n");
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        let mut ldisc = LineDiscipline::new();
+        feed(&mut ldisc, b"par");
+        let mut termios = Termios::sane();
+        termios.make_raw();
+        ldisc.set_termios(termios);
+        // The partial line becomes readable
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"par");
+
+        let (echo, signals) = feed(&mut ldisc, b"\r\x03\x7f");
+        assert!(echo.is_empty());
+        assert!(signals.is_empty());
+        assert_eq!(ldisc.available(), 3);
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"\r\x03\x7f");
+
+        // Back to canonical mode, pending input is one line
+        feed(&mut ldisc, b"xy");
+        ldisc.set_termios(Termios::sane());
+        assert_eq!(read_all(&mut ldisc).unwrap(), b"xy");
+    }
+
+    #[test]
+    fn test_output_processing() {
+        let mut ldisc = LineDiscipline::new();
+        let mut out = Vec::new();
+        ldisc.process_output(b"a\nb", &mut out);
+        assert_eq!(out, b"a\r\nb");
+
+        let mut termios = Termios::sane();
+        termios.make_raw();
+        ldisc.set_termios(termios);
+        out.clear();
+        ldisc.process_output(b"a\nb", &mut out);
+        assert_eq!(out, b"a\nb");
+    }
+
+    #[test]
+    fn test_encode_key() {
+        let encode = |keycode, ascii, ctrl| {
+            let mut out = Vec::new();
+            encode_key(keycode, ascii, ctrl, &mut out);
+            out
+        };
+        assert_eq!(encode(KeyCode::Char('C'), Some('C'), true), b"\x03");
+        assert_eq!(encode(KeyCode::Char('z'), Some('z'), true), b"\x1a");
+        assert_eq!(encode(KeyCode::Char('q'), Some('q'), false), b"q");
+        assert_eq!(encode(KeyCode::Up, None, false), b"\x1b[A");
+        assert_eq!(encode(KeyCode::Enter, None, false), b"\r");
+        assert_eq!(encode(KeyCode::F5, None, false), b"");
+    }
+
+    #[test]
+    fn test_tty_echo_and_read() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        let tty = Tty::new(
+            TerminalId::new(9),
+            String::from("tty-test"),
+            Box::new(move |bytes: &[u8]| sink.lock().extend_from_slice(bytes)),
+        );
+        assert_eq!(tty.kind(), CharDeviceKind::Tty);
+
+        tty.receive(b"hi\r");
+        assert_eq!(output.lock().as_slice(), b"hi\r\n");
+        let mut buf = [0u8; 8];
+        assert_eq!(tty.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"hi\n");
+        assert_eq!(tty.read(&mut buf).unwrap(), 0);
+
+        output.lock().clear();
+        tty.write(b"ok\n").unwrap();
+        assert_eq!(output.lock().as_slice(), b"ok\r\n");
+
+        tty.receive(b"lost");
+        tty.set_foreground_pgrp(Some(ProcessGroupId::new(77)));
+        assert_eq!(tty.foreground_pgrp(), Some(ProcessGroupId::new(77)));
+        assert!(!tty.state.lock().ldisc.readable());
+        assert_eq!(tty.state.lock().ldisc.available(), 0);
+    }
+}
@@ -1,424 +1,161 @@
-/// Virtual Terminal (VT) system for multiple console support
-///
-/// This module implements virtual terminals that allow switching between
-/// multiple independent console sessions using Alt+F1 through Alt+F12.
+//! Virtual terminals
+//!
+//! VT1-VT4 are independent consoles sharing the framebuffer, switched with
+//! Alt+F1..F4. Each one has its own text buffer and scrollback, shell,
+//! line editor and command history.
+//!
+//! The terminal on screen keeps its state in the usual globals (the
+//! framebuffer writer, the shell, the line editor and the history), so the
+//! code using them does not need to know about terminals. Switching parks
+//! the outgoing terminal's state here and swaps the incoming one's in.
+//!
+//! Kernel console output always goes to VT1, so messages from background
+//! work do not interrupt a shell on another terminal.
+//!
+//! A mirror of the console (the serial console) follows the terminal on
+//! screen. Kernel console output reaches it directly, so it is not copied
+//! there again from VT1.
 
-use super::font::{self, FONT_HEIGHT, FONT_WIDTH};
-use alloc::vec::Vec;
-use alloc::string::String;
-use core::fmt;
+use super::font::Font;
+use super::framebuffer::{self, FramebufferWriter};
+use super::line_editor::{self, LineEditor};
+use crate::shell::{self, history::{self, History}, Shell};
+use core::fmt::{self, Write};
 use spin::Mutex;
 
-/// Maximum number of virtual terminals
-pub const MAX_TERMINALS: usize = 12;
+/// Number of virtual terminals
+pub const MAX_TERMINALS: usize = 4;
 
-/// Virtual terminal history size (lines)
-const HISTORY_SIZE: usize = 1000;
+/// Terminal that receives kernel console output
+pub const CONSOLE_TERMINAL: usize = 0;
 
-/// A single virtual terminal
-pub struct VirtualTerminal {
-    // Terminal ID (0-11 for F1-F12)
-    id: usize,
-    
-    // Current cursor position
-    col: usize,
-    row: usize,
-    
-    // Display dimensions
-    max_cols: usize,
-    max_rows: usize,
-    
-    // Colors
-    fg_color: u32,
-    bg_color: u32,
-    
-    // Screen buffer (character + color)
-    screen_buffer: Vec<(char, u32, u32)>, // (char, fg, bg)
-    
-    // History buffer for scrollback
-    history: Vec<String>,
-    history_offset: usize, // For scrollback
-    
-    // Active state
-    active: bool,
+/// State of a terminal that is not on screen
+struct Terminal {
+    writer: FramebufferWriter,
+    shell: Option<Shell>,
+    editor: Option<LineEditor>,
+    history: Option<History>,
 }
 
-impl VirtualTerminal {
-    pub fn new(id: usize, max_cols: usize, max_rows: usize) -> Self {
-        let buffer_size = max_cols * max_rows;
-        let mut screen_buffer = Vec::with_capacity(buffer_size);
-        screen_buffer.resize(buffer_size, (' ', 0xFFFFFFFF, 0xFF000000));
-        
+impl Terminal {
+    /// Start a fresh terminal with its own shell, shaped like `screen`
+    fn spawn(id: usize, screen: &FramebufferWriter) -> Self {
+        let mut shell = Shell::new();
+        shell.init();
+        let mut editor = LineEditor::new();
+        editor.init();
+        let mut history = History::new();
+        history.init();
+        history::load(&mut history, shell.env());
+
+        let mut writer = screen.offscreen();
+        let _ = write!(writer, "FangaOS tty{}\n\n{}", id + 1, shell.prompt());
+
         Self {
-            id,
-            col: 0,
-            row: 0,
-            max_cols,
-            max_rows,
-            fg_color: 0xFFFFFFFF,
-            bg_color: 0xFF000000,
-            screen_buffer,
-            history: Vec::with_capacity(HISTORY_SIZE),
-            history_offset: 0,
-            active: false,
-        }
-    }
-    
-    /// Get terminal ID
-    pub fn id(&self) -> usize {
-        self.id
-    }
-    
-    /// Check if terminal is active
-    pub fn is_active(&self) -> bool {
-        self.active
-    }
-    
-    /// Set active state
-    pub fn set_active(&mut self, active: bool) {
-        self.active = active;
-    }
-    
-    /// Write a character at the current position
-    pub fn write_char(&mut self, ch: char) {
-        match ch {
-            '\n' => {
-                self.newline();
-            }
-            '\r' => {
-                self.col = 0;
-            }
-            '\t' => {
-                for _ in 0..4 {
-                    self.write_char(' ');
-                }
-            }
-            '\x08' => {
-                // Backspace
-                if self.col > 0 {
-                    self.col -= 1;
-                    self.put_char(' ');
-                    self.col -= 1;
-                }
-            }
-            ch if ch >= ' ' && ch <= '~' => {
-                if self.col >= self.max_cols {
-                    self.newline();
-                }
-                self.put_char(ch);
-                self.col += 1;
-            }
-            _ => {
-                // Ignore other control characters
-            }
-        }
-    }
-    
-    /// Write a string
-    pub fn write_str(&mut self, s: &str) {
-        for ch in s.chars() {
-            self.write_char(ch);
-        }
-    }
-    
-    /// Put a character at the current position without moving cursor
-    fn put_char(&mut self, ch: char) {
-        if self.row >= self.max_rows {
-            self.scroll_up();
-            self.row = self.max_rows - 1;
-        }
-        
-        let idx = self.row * self.max_cols + self.col;
-        if idx < self.screen_buffer.len() {
-            self.screen_buffer[idx] = (ch, self.fg_color, self.bg_color);
+            writer,
+            shell: Some(shell),
+            editor: Some(editor),
+            history: Some(history),
         }
     }
-    
-    /// Move to next line
-    fn newline(&mut self) {
-        self.col = 0;
-        self.row += 1;
-        
-        if self.row >= self.max_rows {
-            self.scroll_up();
-            self.row = self.max_rows - 1;
-        }
-    }
-    
-    /// Scroll screen content up by one line
-    fn scroll_up(&mut self) {
-        // Save first line to history (preserve full content)
-        let mut line = String::new();
-        for col in 0..self.max_cols {
-            let (ch, _, _) = self.screen_buffer[col];
-            line.push(ch);
-        }
-        // Trim trailing spaces but keep internal spacing
-        let trimmed = line.trim_end();
-        if !trimmed.is_empty() {
-            if self.history.len() >= HISTORY_SIZE {
-                self.history.remove(0);
-            }
-            self.history.push(String::from(trimmed));
-        }
-        
-        // Shift all rows up
-        for row in 1..self.max_rows {
-            for col in 0..self.max_cols {
-                let src_idx = row * self.max_cols + col;
-                let dst_idx = (row - 1) * self.max_cols + col;
-                self.screen_buffer[dst_idx] = self.screen_buffer[src_idx];
-            }
-        }
-        
-        // Clear last row
-        let last_row_start = (self.max_rows - 1) * self.max_cols;
-        for i in 0..self.max_cols {
-            self.screen_buffer[last_row_start + i] = (' ', self.fg_color, self.bg_color);
-        }
-    }
-    
-    /// Clear the terminal
-    pub fn clear(&mut self) {
-        for i in 0..self.screen_buffer.len() {
-            self.screen_buffer[i] = (' ', self.fg_color, self.bg_color);
-        }
-        self.col = 0;
-        self.row = 0;
-    }
-    
-    /// Get the screen buffer for rendering
-    pub fn screen_buffer(&self) -> &[(char, u32, u32)] {
-        &self.screen_buffer
-    }
-    
-    /// Set cursor position
-    pub fn set_position(&mut self, col: usize, row: usize) {
-        self.col = col.min(self.max_cols - 1);
-        self.row = row.min(self.max_rows - 1);
-    }
-    
-    /// Get cursor position
-    pub fn position(&self) -> (usize, usize) {
-        (self.col, self.row)
-    }
-    
-    /// Set colors
-    pub fn set_colors(&mut self, fg: u32, bg: u32) {
-        self.fg_color = fg;
-        self.bg_color = bg;
-    }
-}
-
-impl fmt::Write for VirtualTerminal {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_str(s);
-        Ok(())
-    }
 }
 
 /// Virtual terminal manager
 pub struct VtManager {
-    terminals: Vec<VirtualTerminal>,
+    /// Parked terminals; the slot of the one on screen is empty
+    parked: [Option<Terminal>; MAX_TERMINALS],
     current: usize,
-    max_cols: usize,
-    max_rows: usize,
-    
-    // Framebuffer info for rendering
-    fb_addr: *mut u8,
-    fb_width: usize,
-    fb_height: usize,
-    fb_pitch: usize,
+    initialized: bool,
 }
 
-unsafe impl Send for VtManager {}
-
 impl VtManager {
     pub const fn new() -> Self {
         Self {
-            terminals: Vec::new(),
+            parked: [const { None }; MAX_TERMINALS],
             current: 0,
-            max_cols: 0,
-            max_rows: 0,
-            fb_addr: core::ptr::null_mut(),
-            fb_width: 0,
-            fb_height: 0,
-            fb_pitch: 0,
-        }
-    }
-    
-    /// Initialize the virtual terminal manager
-    pub fn init(&mut self, fb_addr: *mut u8, width: usize, height: usize, pitch: usize) {
-        self.fb_addr = fb_addr;
-        self.fb_width = width;
-        self.fb_height = height;
-        self.fb_pitch = pitch;
-        
-        self.max_cols = width / FONT_WIDTH;
-        self.max_rows = height / FONT_HEIGHT;
-        
-        // Create terminals
-        for id in 0..MAX_TERMINALS {
-            let mut vt = VirtualTerminal::new(id, self.max_cols, self.max_rows);
-            if id == 0 {
-                vt.set_active(true);
-            }
-            self.terminals.push(vt);
+            initialized: false,
         }
     }
-    
-    /// Get current terminal
-    pub fn current_terminal(&mut self) -> Option<&mut VirtualTerminal> {
-        self.terminals.get_mut(self.current)
-    }
-    
-    /// Get a specific terminal
-    pub fn terminal(&mut self, id: usize) -> Option<&mut VirtualTerminal> {
-        self.terminals.get_mut(id)
+
+    /// Make the boot console VT1
+    ///
+    /// Requires the heap. Other terminals are created on first switch.
+    pub fn init(&mut self) {
+        framebuffer::framebuffer().enable_text_buffer();
+        self.current = 0;
+        self.initialized = true;
     }
-    
-    /// Switch to a terminal
+
+    /// Switch the screen, keyboard and shell to terminal `id`
+    ///
+    /// # Returns
+    /// `false` if `id` is not a terminal or terminals are not set up yet
     pub fn switch_to(&mut self, id: usize) -> bool {
-        if id >= MAX_TERMINALS {
+        if !self.initialized || id >= MAX_TERMINALS {
             return false;
         }
-        
         if id == self.current {
             return true;
         }
-        
-        // Deactivate current
-        if let Some(vt) = self.terminals.get_mut(self.current) {
-            vt.set_active(false);
-        }
-        
-        // Activate new terminal
+
+        let mut fb = framebuffer::framebuffer();
+        let mut incoming = match self.parked[id].take() {
+            Some(terminal) => terminal,
+            None => Terminal::spawn(id, &fb),
+        };
+
+        // Swap the writers, then hand the framebuffer to the incoming one
+        core::mem::swap(&mut *fb, &mut incoming.writer);
+        fb.take_screen(&mut incoming.writer);
+        let mirror = incoming.writer.set_mirror(None);
+        fb.set_mirror(mirror);
+        fb.draw_cursor();
+
+        core::mem::swap(&mut *shell::shell(), &mut incoming.shell);
+        core::mem::swap(&mut *line_editor::editor(), &mut incoming.editor);
+        core::mem::swap(&mut *history::history(), &mut incoming.history);
+
+        self.parked[self.current] = Some(incoming);
         self.current = id;
-        if let Some(vt) = self.terminals.get_mut(id) {
-            vt.set_active(true);
-        }
-        
-        // Redraw the new terminal
-        self.render_current();
-        
         true
     }
-    
+
+    /// Draw every terminal with `font`
+    pub fn set_font(&mut self, font: &'static Font) {
+        let (cols, rows) = {
+            let mut fb = framebuffer::framebuffer();
+            fb.set_font(font);
+            (fb.cols(), fb.rows())
+        };
+        for terminal in self.parked.iter_mut().flatten() {
+            terminal.writer.set_font(font);
+        }
+        super::tty::resize_consoles(cols, rows);
+    }
+
     /// Get current terminal ID
     pub fn current_id(&self) -> usize {
         self.current
     }
-    
-    /// Render the current terminal to the framebuffer
-    pub fn render_current(&mut self) {
-        if self.fb_addr.is_null() {
-            return;
-        }
-        
-        if let Some(vt) = self.terminals.get(self.current) {
-            self.render_terminal(vt);
-        }
-    }
-    
-    /// Render a specific terminal
-    fn render_terminal(&self, vt: &VirtualTerminal) {
-        if self.fb_addr.is_null() {
-            return;
-        }
-        
-        let buffer = vt.screen_buffer();
-        
-        // Clear screen first
-        unsafe {
-            for y in 0..self.fb_height {
-                let row = self.fb_addr.add(y * self.fb_pitch) as *mut u32;
-                for x in 0..(self.fb_pitch / 4) {
-                    row.add(x).write_volatile(0xFF000000);
-                }
-            }
-        }
-        
-        // Render each character
-        for row in 0..self.max_rows {
-            for col in 0..self.max_cols {
-                let idx = row * self.max_cols + col;
-                if idx >= buffer.len() {
-                    continue;
-                }
-                
-                let (ch, fg, bg) = buffer[idx];
-                if ch != ' ' || bg != 0xFF000000 {
-                    self.draw_char(ch, col, row, fg, bg);
-                }
-            }
-        }
-        
-        // Draw cursor at current position
-        let (cur_col, cur_row) = vt.position();
-        self.draw_cursor(cur_col, cur_row);
-    }
-    
-    /// Draw a character at a specific position
-    fn draw_char(&self, ch: char, col: usize, row: usize, fg: u32, bg: u32) {
-        if self.fb_addr.is_null() {
-            return;
-        }
-        
-        let bitmap = font::get_char_bitmap(ch);
-        let x_base = col * FONT_WIDTH;
-        let y_base = row * FONT_HEIGHT;
-        
-        unsafe {
-            for (row_idx, &byte) in bitmap.iter().enumerate() {
-                let y = y_base + row_idx;
-                if y >= self.fb_height {
-                    break;
-                }
-                
-                let row_ptr = self.fb_addr.add(y * self.fb_pitch) as *mut u32;
-                
-                for bit_idx in 0..FONT_WIDTH {
-                    let x = x_base + bit_idx;
-                    if x >= self.fb_width {
-                        break;
-                    }
-                    
-                    let pixel_on = (byte & (0x80 >> bit_idx)) != 0;
-                    let color = if pixel_on { fg } else { bg };
-                    row_ptr.add(x).write_volatile(color);
-                }
-            }
+
+    /// Write raw bytes to a terminal, on screen or not
+    pub fn write_to(&mut self, id: usize, bytes: &[u8]) {
+        if id == self.current {
+            framebuffer::framebuffer().write_bytes(bytes);
+        } else if let Some(terminal) = self.parked.get_mut(id).and_then(Option::as_mut) {
+            terminal.writer.write_bytes(bytes);
         }
     }
-    
-    /// Draw cursor at position
-    fn draw_cursor(&self, col: usize, row: usize) {
-        if self.fb_addr.is_null() {
-            return;
-        }
-        
-        let x_base = col * FONT_WIDTH;
-        let y_base = row * FONT_HEIGHT;
-        
-        // Draw inverted space character as cursor
-        unsafe {
-            for row_idx in 0..FONT_HEIGHT {
-                let y = y_base + row_idx;
-                if y >= self.fb_height {
-                    break;
-                }
-                
-                let row_ptr = self.fb_addr.add(y * self.fb_pitch) as *mut u32;
-                
-                for bit_idx in 0..FONT_WIDTH {
-                    let x = x_base + bit_idx;
-                    if x >= self.fb_width {
-                        break;
-                    }
-                    
-                    row_ptr.add(x).write_volatile(0xFFFFFFFF);
-                }
-            }
+
+    /// Write to a terminal, on screen or not, without copying to the mirror
+    pub fn write_fmt_to(&mut self, id: usize, args: fmt::Arguments) {
+        if id == self.current {
+            let mut fb = framebuffer::framebuffer();
+            let mirror = fb.set_mirror(None);
+            let _ = fb.write_fmt(args);
+            fb.set_mirror(mirror);
+        } else if let Some(terminal) = self.parked.get_mut(id).and_then(Option::as_mut) {
+            let _ = terminal.writer.write_fmt(args);
         }
     }
 }
@@ -427,8 +164,8 @@ impl VtManager {
 static VT_MANAGER: Mutex<VtManager> = Mutex::new(VtManager::new());
 
 /// Initialize the virtual terminal system
-pub fn init(fb_addr: *mut u8, width: usize, height: usize, pitch: usize) {
-    VT_MANAGER.lock().init(fb_addr, width, height, pitch);
+pub fn init() {
+    VT_MANAGER.lock().init();
 }
 
 /// Get access to the VT manager
@@ -436,12 +173,27 @@ pub fn vt_manager() -> spin::MutexGuard<'static, VtManager> {
     VT_MANAGER.lock()
 }
 
-/// Switch to a specific virtual terminal (0-11 for F1-F12)
+/// Switch to a specific virtual terminal (0-3 for F1-F4)
 pub fn switch_terminal(id: usize) -> bool {
     VT_MANAGER.lock().switch_to(id)
 }
 
+/// Switch all terminals to a font
+pub fn set_font(font: &'static Font) {
+    VT_MANAGER.lock().set_font(font);
+}
+
 /// Get the current terminal ID
 pub fn current_terminal_id() -> usize {
     VT_MANAGER.lock().current_id()
 }
+
+/// Write raw bytes to terminal `id`
+pub fn write_to(id: usize, bytes: &[u8]) {
+    VT_MANAGER.lock().write_to(id, bytes);
+}
+
+/// Print kernel console output to the console terminal
+pub fn _print_console(args: fmt::Arguments) {
+    VT_MANAGER.lock().write_fmt_to(CONSOLE_TERMINAL, args);
+}
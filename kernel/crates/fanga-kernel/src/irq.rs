@@ -0,0 +1,291 @@
+//! Device Interrupt Requests
+//!
+//! Drivers claim an interrupt line at probe time with `request_irq` and
+//! release it with `free_irq`. Several handlers can share a line if every
+//! one of them asks for `IrqFlags::SHARED`; all handlers on a line run for
+//! each interrupt and report whether their device raised it. The first
+//! handler on a line unmasks it and the last one to leave masks it again.
+//!
+//! The timer, keyboard, PIC cascade and PS/2 mouse lines have built-in
+//! handlers and cannot be requested. Handlers run in interrupt context
+//! with the line's handler chain locked, so they must not request or free
+//! IRQs themselves.
+//!
+//! This module provides:
+//! - `request_irq` / `free_irq` with shared handler chains
+//! - Per-IRQ interrupt and unhandled interrupt counts
+//! - The dispatcher for device interrupts
+
+use alloc::vec::Vec;
+
+use fanga_arch_x86_64::interrupts::idt;
+
+use crate::smp::SpinLock;
+
+/// Number of interrupt lines
+pub const NR_IRQS: usize = 16;
+
+/// Result of an interrupt handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt was not from this handler's device
+    None,
+    /// The handler serviced its device
+    Handled,
+}
+
+/// Interrupt handler, called with the IRQ line
+pub type IrqHandler = fn(u8) -> IrqReturn;
+
+/// Flags for `request_irq`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqFlags(u32);
+
+impl IrqFlags {
+    /// Exclusive, edge-triggered line
+    pub const NONE: Self = Self(0);
+    /// Allow other handlers on the same line
+    pub const SHARED: Self = Self(1 << 0);
+    /// Level-triggered PCI interrupt
+    pub const LEVEL: Self = Self(1 << 1);
+
+    /// Create empty flags
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Check if flags contain a specific flag
+    pub const fn contains(&self, flag: Self) -> bool {
+        self.0 & flag.0 != 0
+    }
+
+    /// Add a flag
+    pub const fn with(self, flag: Self) -> Self {
+        Self(self.0 | flag.0)
+    }
+
+    /// Get raw value
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// A handler registered on a line
+#[derive(Clone, Copy)]
+struct IrqAction {
+    handler: IrqHandler,
+    flags: IrqFlags,
+    name: &'static str,
+}
+
+/// State of one interrupt line
+struct IrqDesc {
+    actions: Vec<IrqAction>,
+    count: u64,
+    unhandled: u64,
+}
+
+impl IrqDesc {
+    const fn new() -> Self {
+        Self {
+            actions: Vec::new(),
+            count: 0,
+            unhandled: 0,
+        }
+    }
+}
+
+/// Statistics of one interrupt line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrqStats {
+    /// IRQ line
+    pub irq: u8,
+    /// Interrupts taken
+    pub count: u64,
+    /// Interrupts no handler claimed
+    pub unhandled: u64,
+    /// Names of the registered handlers
+    pub names: Vec<&'static str>,
+}
+
+static IRQ_DESCS: SpinLock<[IrqDesc; NR_IRQS]> = SpinLock::new([const { IrqDesc::new() }; NR_IRQS]);
+
+/// Unmask or mask a line at the interrupt controller
+fn set_line_enabled(irq: u8, enabled: bool) {
+    #[cfg(not(test))]
+    unsafe {
+        if enabled {
+            fanga_arch_x86_64::interrupts::handlers::enable_irq(irq);
+        } else {
+            fanga_arch_x86_64::interrupts::handlers::disable_irq(irq);
+        }
+    }
+    #[cfg(test)]
+    let _ = (irq, enabled);
+}
+
+/// Remove `handler` from a line, returning whether the line is now unused
+fn remove_action(irq: u8, handler: IrqHandler) -> Result<bool, &'static str> {
+    let mut descs = IRQ_DESCS.lock_irqsave();
+    let actions = &mut descs[irq as usize].actions;
+    let index = actions
+        .iter()
+        .position(|action| action.handler as usize == handler as usize)
+        .ok_or("Handler not registered on this IRQ")?;
+    actions.remove(index);
+    Ok(actions.is_empty())
+}
+
+/// Claim an interrupt line
+///
+/// # Arguments
+/// * `irq` - IRQ line (for PCI devices, the interrupt line register)
+/// * `handler` - Handler called for each interrupt on the line
+/// * `flags` - `SHARED` to share the line, `LEVEL` for PCI interrupts
+/// * `name` - Device name shown in the statistics
+pub fn request_irq(irq: u8, handler: IrqHandler, flags: IrqFlags, name: &'static str) -> Result<(), &'static str> {
+    if !idt::is_device_irq(irq) {
+        return Err("IRQ line not available to drivers");
+    }
+
+    let first = {
+        let mut descs = IRQ_DESCS.lock_irqsave();
+        let actions = &mut descs[irq as usize].actions;
+        if let Some(existing) = actions.first() {
+            if !existing.flags.contains(IrqFlags::SHARED) || !flags.contains(IrqFlags::SHARED) {
+                return Err("IRQ line busy");
+            }
+            if existing.flags.contains(IrqFlags::LEVEL) != flags.contains(IrqFlags::LEVEL) {
+                return Err("IRQ trigger mode mismatch");
+            }
+            if actions.iter().any(|action| action.handler as usize == handler as usize) {
+                return Err("Handler already registered on this IRQ");
+            }
+        }
+        actions.push(IrqAction { handler, flags, name });
+        actions.len() == 1
+    };
+
+    if first {
+        if flags.contains(IrqFlags::LEVEL) {
+            if let Err(e) = crate::smp::route_pci_irq(irq) {
+                let _ = remove_action(irq, handler);
+                return Err(e);
+            }
+        }
+        set_line_enabled(irq, true);
+    }
+    Ok(())
+}
+
+/// Release an interrupt line claimed with `request_irq`
+pub fn free_irq(irq: u8, handler: IrqHandler) -> Result<(), &'static str> {
+    if irq as usize >= NR_IRQS {
+        return Err("Invalid IRQ number");
+    }
+    if remove_action(irq, handler)? {
+        set_line_enabled(irq, false);
+    }
+    Ok(())
+}
+
+/// Run the handlers of an interrupt line
+///
+/// Called from the device IRQ entry points with interrupts disabled.
+pub fn handle_irq(irq: u8) {
+    let mut descs = IRQ_DESCS.lock();
+    let Some(desc) = descs.get_mut(irq as usize) else {
+        return;
+    };
+    desc.count += 1;
+    crate::random::add_interrupt_randomness(irq);
+
+    let mut handled = false;
+    for action in desc.actions.iter() {
+        handled |= (action.handler)(irq) == IrqReturn::Handled;
+    }
+    if !handled {
+        desc.unhandled += 1;
+    }
+}
+
+/// Statistics of the lines that have handlers or took interrupts
+pub fn irq_stats() -> Vec<IrqStats> {
+    let descs = IRQ_DESCS.lock_irqsave();
+    descs
+        .iter()
+        .enumerate()
+        .filter(|(_, desc)| desc.count > 0 || !desc.actions.is_empty())
+        .map(|(irq, desc)| IrqStats {
+            irq: irq as u8,
+            count: desc.count,
+            unhandled: desc.unhandled,
+            names: desc.actions.iter().map(|action| action.name).collect(),
+        })
+        .collect()
+}
+
+/// Route device interrupts to `handle_irq`
+pub fn init() {
+    unsafe {
+        idt::set_irq_callback(handle_irq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handled(_irq: u8) -> IrqReturn {
+        IrqReturn::Handled
+    }
+
+    fn not_mine(_irq: u8) -> IrqReturn {
+        IrqReturn::None
+    }
+
+    fn stats(irq: u8) -> IrqStats {
+        irq_stats().into_iter().find(|s| s.irq == irq).unwrap()
+    }
+
+    #[test]
+    fn test_request_irq_validation() {
+        assert!(request_irq(0, handled, IrqFlags::NONE, "timer").is_err());
+        assert!(request_irq(2, handled, IrqFlags::NONE, "cascade").is_err());
+        assert!(request_irq(16, handled, IrqFlags::NONE, "bogus").is_err());
+        assert!(free_irq(16, handled).is_err());
+        assert!(free_irq(3, handled).is_err());
+    }
+
+    #[test]
+    fn test_exclusive_irq() {
+        request_irq(9, handled, IrqFlags::NONE, "ata").unwrap();
+        assert_eq!(request_irq(9, not_mine, IrqFlags::SHARED, "e1000"), Err("IRQ line busy"));
+
+        handle_irq(9);
+        assert_eq!(stats(9).count, 1);
+        assert_eq!(stats(9).unhandled, 0);
+
+        free_irq(9, handled).unwrap();
+        assert!(stats(9).names.is_empty());
+    }
+
+    #[test]
+    fn test_shared_irq() {
+        let shared = IrqFlags::SHARED.with(IrqFlags::LEVEL);
+        request_irq(11, not_mine, shared, "e1000").unwrap();
+        assert_eq!(request_irq(11, handled, IrqFlags::SHARED, "ahci"), Err("IRQ trigger mode mismatch"));
+        assert!(request_irq(11, not_mine, shared, "e1000").is_err());
+
+        handle_irq(11);
+        request_irq(11, handled, shared, "ahci").unwrap();
+        handle_irq(11);
+
+        let s = stats(11);
+        assert_eq!((s.count, s.unhandled), (2, 1));
+        assert_eq!(s.names, alloc::vec!["e1000", "ahci"]);
+
+        free_irq(11, not_mine).unwrap();
+        free_irq(11, handled).unwrap();
+    }
+}
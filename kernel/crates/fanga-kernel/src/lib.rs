@@ -11,14 +11,29 @@ extern crate alloc;
 #[cfg(test)]
 extern crate alloc;
 
+// Kernel command line
+pub mod cmdline;
+
+// Entropy pool and CSPRNG
+pub mod random;
+
 pub mod memory;
 pub mod task;
 pub mod syscall;
 pub mod syscall_handlers;
 
+// Device interrupt requests
+pub mod irq;
+
 // IO module
 pub mod io;
 
+// PCI bus enumeration
+pub mod pci;
+
+// Driver core: device tree, driver matching and binding
+pub mod driver;
+
 // USB module
 pub mod usb;
 
@@ -40,6 +55,9 @@ pub mod userspace;
 // Networking module (E1000 driver, Ethernet, ARP, IPv4, UDP, TCP, sockets, DHCP)
 pub mod net;
 
+// ACPI tables (RSDP, XSDT, FADT, MADT, HPET, SRAT)
+pub mod acpi;
+
 // Power management module
 pub mod power;
 
@@ -54,3 +72,6 @@ pub mod profiling;
 
 // Kernel preemption
 pub mod preempt;
+
+// Kernel debugging (symbol table, backtraces)
+pub mod debug;
@@ -6,14 +6,14 @@ extern crate alloc;
 use core::panic::PanicInfo;
 
 use limine::request::{
-    BootloaderInfoRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, RequestsEndMarker,
-    RequestsStartMarker,
+    BootloaderInfoRequest, ExecutableCmdlineRequest, FramebufferRequest, HhdmRequest,
+    MemoryMapRequest, ModuleRequest, MpRequest, RequestsEndMarker, RequestsStartMarker, RsdpRequest,
 };
 use limine::BaseRevision;
 
-use fanga_arch_x86_64 as arch;
-
 mod boot;
+mod cmdline;
+mod debug;
 mod io;
 mod memory;
 mod shell;
@@ -43,6 +43,10 @@ static BASE_REVISION: BaseRevision = BaseRevision::new();
 #[link_section = ".limine_requests"]
 static BOOTLOADER_INFO_REQ: BootloaderInfoRequest = BootloaderInfoRequest::new();
 
+#[used]
+#[link_section = ".limine_requests"]
+static CMDLINE_REQ: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
 #[used]
 #[link_section = ".limine_requests"]
 static FRAMEBUFFER_REQ: FramebufferRequest = FramebufferRequest::new();
@@ -55,6 +59,18 @@ static MEMMAP_REQ: MemoryMapRequest = MemoryMapRequest::new();
 #[link_section = ".limine_requests"]
 static HHDM_REQ: HhdmRequest = HhdmRequest::new();
 
+#[used]
+#[link_section = ".limine_requests"]
+static MP_REQ: MpRequest = MpRequest::new();
+
+#[used]
+#[link_section = ".limine_requests"]
+static RSDP_REQ: RsdpRequest = RsdpRequest::new();
+
+#[used]
+#[link_section = ".limine_requests"]
+static MODULE_REQ: ModuleRequest = ModuleRequest::new();
+
 #[used]
 #[link_section = ".limine_requests_end"]
 static LIMINE_REQUESTS_END: RequestsEndMarker = RequestsEndMarker::new();
@@ -77,7 +93,7 @@ static LIMINE_REQUESTS_END: RequestsEndMarker = RequestsEndMarker::new();
 /// 3. Memory subsystem initialization
 /// 4. Driver initialization
 /// 5. Kernel subsystem initialization
-/// 6. Post-initialization and demonstrations
+/// 6. Post-initialization: start /init, or the kernel shell without one
 ///
 /// See the `boot` module for detailed documentation of each phase.
 #[no_mangle]
@@ -86,29 +102,26 @@ pub extern "C" fn _start() -> ! {
     match boot::initialize(
         &FRAMEBUFFER_REQ,
         &BOOTLOADER_INFO_REQ,
+        &CMDLINE_REQ,
         &MEMMAP_REQ,
         &HHDM_REQ,
+        &RSDP_REQ,
+        &MP_REQ,
+        &MODULE_REQ,
         &BASE_REVISION,
     ) {
         Ok(()) => {
-            // Boot successful - enter idle loop
-            arch::serial_println!("[Kernel] Boot complete, entering idle loop");
+            // Boot successful - enter idle task
+            log_info!(target: "kernel", "Boot complete, entering idle task");
         }
         Err(e) => {
             // Boot failed - print error and halt
-            arch::serial_println!("[Kernel] BOOT FAILED: {}", e);
-            console_println!();
-            console_println!("!!! KERNEL BOOT FAILURE !!!");
-            console_println!("Error: {}", e);
+            log_error!(target: "kernel", "Boot failed: {}", e);
         }
     }
 
-    // Infinite idle loop - the CPU will be woken by interrupts
-    loop {
-        unsafe {
-            core::arch::asm!("hlt");
-        }
-    }
+    // The boot CPU becomes its idle task
+    task::idle::cpu_startup_entry()
 }
 
 /* -------------------------------------------------------------------------- */
@@ -120,6 +133,10 @@ fn panic(info: &PanicInfo) -> ! {
     console_println!();
     console_println!("!!! KERNEL PANIC !!!");
     console_println!("{}", info);
+    debug::print_current_backtrace();
+    io::pstore::save_on_panic(format_args!("{}", info));
+    power::reboot::reboot_on_panic();
+    debug::kdb::enter(debug::kdb::KdbReason::Panic, debug::kdb::KdbRegs::capture());
 
     loop {
         unsafe {
@@ -0,0 +1,405 @@
+//! User Address Spaces
+//!
+//! Each process loaded from an ELF binary gets its own page table. The
+//! user half (PML4 entries 0..256) belongs to the process; the kernel half
+//! points at the kernel's own tables, which `init` fills in completely so
+//! that later kernel mappings show up in every address space.
+//!
+//! Tasks whose page table is 0 (kernel threads, and processes not loaded
+//! from a binary) run in the kernel's page table. The scheduler loads the
+//! incoming task's page table on every switch, and an address space is
+//! only freed once no CPU has it loaded.
+//!
+//! Frames mapped through an `AddressSpace` are charged to its owner, the
+//! task whose memory it is: to the owner's cgroup, where mapping fails
+//! when the group is over its limit, and to the owner's resident set. An
+//! address space looked up for a task that exited has no owner, since the
+//! task's charges went when it left its group.
+//!
+//! This module provides:
+//! - `AddressSpace`, for mapping zeroed user pages and filling them
+//! - Switching CR3 on a context switch
+//! - Freeing an address space with the frames mapped in it
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Once;
+
+use super::addr::{PhysAddr, PAGE_SIZE};
+use super::mmap::MmapProt;
+use super::paging::{PageTableFlags, PageTableMapper};
+use super::pmm::PhysicalMemoryManager;
+use crate::smp::cpu::MAX_CPUS;
+use crate::task::cgroup;
+use crate::task::scheduler::{self, MAX_TASKS};
+use crate::task::sigdeliver::UserMemory;
+use crate::task::TaskId;
+
+struct State {
+    pmm: &'static PhysicalMemoryManager,
+    hhdm_offset: u64,
+    /// The boot page table, which kernel threads run in
+    kernel_pml4: u64,
+}
+
+// The PMM is 'static and does its own locking
+unsafe impl Send for State {}
+unsafe impl Sync for State {}
+
+static STATE: Once<State> = Once::new();
+
+/// Page table each CPU has loaded; 0 for the kernel's
+static LOADED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// User pages charged to each task
+static RESIDENT: [AtomicUsize; MAX_TASKS] = [const { AtomicUsize::new(0) }; MAX_TASKS];
+
+/// Charge `pages` newly allocated user pages to `task`
+///
+/// Fails if that would take the task's cgroup over its memory limit. The
+/// task's maximum resident set size is updated unless the scheduler lock
+/// is busy, as it may be in a fault handler; the next tick samples it then.
+pub fn charge(task: TaskId, pages: usize) -> Result<(), &'static str> {
+    cgroup::try_charge_memory(task, pages * PAGE_SIZE)?;
+    let Some(resident) = RESIDENT.get(task.as_usize()) else {
+        return Ok(());
+    };
+    resident.fetch_add(pages, Ordering::Relaxed);
+    if let Some(mut sched) = scheduler::try_scheduler() {
+        if let Some(task) = sched.get_task_mut(task) {
+            task.times.update_rss(resident_kb(task.id));
+        }
+    }
+    Ok(())
+}
+
+/// Take `pages` freed user pages off `task`'s charge
+pub fn uncharge(task: TaskId, pages: usize) {
+    cgroup::uncharge_memory(task, pages * PAGE_SIZE);
+    if let Some(resident) = RESIDENT.get(task.as_usize()) {
+        // A task may free pages charged to another that shared them
+        let mut current = resident.load(Ordering::Relaxed);
+        while let Err(actual) =
+            resident.compare_exchange_weak(current, current.saturating_sub(pages), Ordering::Relaxed, Ordering::Relaxed)
+        {
+            current = actual;
+        }
+    }
+}
+
+/// User memory charged to a task, in KiB
+pub fn resident_kb(task: TaskId) -> u64 {
+    RESIDENT.get(task.as_usize()).map_or(0, |resident| {
+        (resident.load(Ordering::Relaxed) * PAGE_SIZE / 1024) as u64
+    })
+}
+
+/// Page table flags for user pages with `prot`
+pub fn page_flags(prot: MmapProt) -> PageTableFlags {
+    let mut flags = PageTableFlags::USER;
+    if prot.contains(MmapProt::WRITE) {
+        flags = flags.with(PageTableFlags::WRITABLE);
+    }
+    if !prot.contains(MmapProt::EXEC) {
+        flags = flags.with(PageTableFlags::NO_EXECUTE);
+    }
+    flags
+}
+
+/// The page table and user frames of a process
+pub struct AddressSpace {
+    mapper: PageTableMapper,
+    pmm: &'static PhysicalMemoryManager,
+    hhdm_offset: u64,
+    /// Task the frames are charged to
+    owner: Option<TaskId>,
+}
+
+impl AddressSpace {
+    /// A new address space with an empty user half, whose frames are
+    /// charged to `owner`
+    pub fn new(owner: Option<TaskId>) -> Result<Self, &'static str> {
+        let state = STATE.get().ok_or("Address spaces not initialized")?;
+        let mut space = unsafe { Self::with_allocator(state.pmm, state.hhdm_offset)? };
+        space.owner = owner;
+        let kernel = PageTableMapper::from_pml4(state.kernel_pml4, state.hhdm_offset);
+        unsafe { space.mapper.copy_kernel_half(&kernel) };
+        Ok(space)
+    }
+
+    /// A new, empty page table allocated from `pmm`
+    ///
+    /// # Safety
+    /// `hhdm_offset` must map the memory `pmm` hands out.
+    pub(crate) unsafe fn with_allocator(
+        pmm: &'static PhysicalMemoryManager,
+        hhdm_offset: u64,
+    ) -> Result<Self, &'static str> {
+        let mapper = PageTableMapper::new(pmm, hhdm_offset).ok_or("Out of memory")?;
+        Ok(Self { mapper, pmm, hhdm_offset, owner: None })
+    }
+
+    /// The address space of a task's page table, charging `owner`; None
+    /// for the kernel's
+    pub fn of(page_table: PhysAddr, owner: Option<TaskId>) -> Option<Self> {
+        let state = STATE.get()?;
+        if page_table.as_u64() == 0 || page_table.as_u64() == state.kernel_pml4 {
+            return None;
+        }
+        Some(Self {
+            mapper: PageTableMapper::from_pml4(page_table.as_u64(), state.hhdm_offset),
+            pmm: state.pmm,
+            hhdm_offset: state.hhdm_offset,
+            owner,
+        })
+    }
+
+    /// Physical address of the PML4, for the task's `page_table`
+    pub fn page_table(&self) -> PhysAddr {
+        PhysAddr::new(self.mapper.pml4_addr())
+    }
+
+    /// Map zeroed frames at `start..start + len`, widened to whole pages
+    ///
+    /// On failure the pages mapped so far stay mapped; `destroy` frees them.
+    pub fn map(&mut self, start: u64, len: u64, prot: MmapProt) -> Result<(), &'static str> {
+        let mask = PAGE_SIZE as u64 - 1;
+        let end = start.checked_add(len).and_then(|end| end.checked_add(mask)).ok_or("Area wraps around")? & !mask;
+        let flags = page_flags(prot);
+        for page in (start & !mask..end).step_by(PAGE_SIZE) {
+            let frame = self.alloc_frame()?;
+            unsafe {
+                core::ptr::write_bytes(self.frame_ptr(frame), 0, PAGE_SIZE);
+                if let Err(e) = self.mapper.map(page, frame, flags, self.pmm) {
+                    self.free_frame(frame);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap and free the pages of `start..start + len` that are mapped
+    pub fn unmap(&mut self, start: u64, len: u64) {
+        let mask = PAGE_SIZE as u64 - 1;
+        let end = start.saturating_add(len).saturating_add(mask) & !mask;
+        for page in (start & !mask..end).step_by(PAGE_SIZE) {
+            if let Ok(frame) = unsafe { self.mapper.unmap(page) } {
+                self.free_frame(frame);
+            }
+        }
+    }
+
+    /// Free the page table and every frame mapped in the user half
+    ///
+    /// # Safety
+    /// No CPU may be running in this address space, and no task may be
+    /// switched to it again.
+    pub unsafe fn destroy(mut self) {
+        let (pmm, owner) = (self.pmm, self.owner);
+        self.mapper.clear_user_half(pmm, |frame| {
+            pmm.free_page(frame);
+            if let Some(task) = owner {
+                uncharge(task, 1);
+            }
+        });
+        pmm.free_page(self.mapper.pml4_addr());
+    }
+
+    /// A frame charged to the owner
+    fn alloc_frame(&self) -> Result<u64, &'static str> {
+        if let Some(task) = self.owner {
+            charge(task, 1)?;
+        }
+        let frame = self.pmm.alloc_page();
+        if frame.is_none() {
+            if let Some(task) = self.owner {
+                uncharge(task, 1);
+            }
+        }
+        frame.ok_or("Out of memory")
+    }
+
+    /// Free a frame and take it off the owner's charge
+    fn free_frame(&self, frame: u64) {
+        self.pmm.free_page(frame);
+        if let Some(task) = self.owner {
+            uncharge(task, 1);
+        }
+    }
+
+    fn frame_ptr(&self, phys: u64) -> *mut u8 {
+        (phys + self.hhdm_offset) as *mut u8
+    }
+
+    /// Split `addr..addr + len` into pieces within one page each, as the
+    /// direct-map address and length of each
+    fn pieces(&self, addr: u64, len: usize) -> impl Iterator<Item = Result<(*mut u8, usize), &'static str>> + '_ {
+        let mut done = 0;
+        core::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+            let Some(at) = addr.checked_add(done as u64) else {
+                return Some(Err("Address wraps around"));
+            };
+            let Some(phys) = self.mapper.translate(at) else {
+                return Some(Err("Page not mapped"));
+            };
+            let chunk = (PAGE_SIZE - (at as usize & (PAGE_SIZE - 1))).min(len - done);
+            done += chunk;
+            Some(Ok((self.frame_ptr(phys), chunk)))
+        })
+    }
+}
+
+/// Writes and reads go through the direct map, whatever the protection of
+/// the pages, so the loader can fill read-only segments
+impl UserMemory for AddressSpace {
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut rest = data;
+        for piece in self.pieces(addr, data.len()) {
+            let (ptr, len) = piece?;
+            unsafe { core::ptr::copy_nonoverlapping(rest.as_ptr(), ptr, len) };
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        for piece in self.pieces(addr, buf.len()) {
+            let (ptr, len) = piece?;
+            unsafe { core::ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), len) };
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// Load a task's page table into CR3; 0 stands for the kernel's
+///
+/// Called by the scheduler on every context switch, with the scheduler
+/// lock held.
+pub fn activate(page_table: PhysAddr) {
+    LOADED[crate::smp::current_cpu_id().as_usize()].store(page_table.as_u64(), Ordering::Release);
+    #[cfg(not(test))]
+    if let Some(state) = STATE.get() {
+        let pml4 = match page_table.as_u64() {
+            0 => state.kernel_pml4,
+            pml4 => pml4,
+        };
+        if PageTableMapper::current_cr3() & !0xFFF != pml4 {
+            unsafe { PageTableMapper::from_pml4(pml4, state.hhdm_offset).load() };
+        }
+    }
+}
+
+/// Whether any CPU has `page_table` loaded
+///
+/// Stable while the scheduler lock is held, since CPUs only switch page
+/// tables with it held.
+pub fn is_loaded(page_table: PhysAddr) -> bool {
+    LOADED.iter().any(|loaded| loaded.load(Ordering::Acquire) == page_table.as_u64())
+}
+
+/// Start creating address spaces
+///
+/// # Safety
+/// `hhdm_offset` must be the bootloader's HHDM offset. Call once during
+/// boot, after the PMM is initialized and while the boot page table is
+/// loaded.
+pub unsafe fn init(pmm: &'static PhysicalMemoryManager, hhdm_offset: u64) -> Result<(), &'static str> {
+    let kernel_pml4 = PageTableMapper::current_cr3() & !0xFFF;
+    PageTableMapper::from_pml4(kernel_pml4, hhdm_offset).fill_kernel_half(pmm)?;
+    STATE.call_once(|| State { pmm, hhdm_offset, kernel_pml4 });
+    Ok(())
+}
+
+/// An address space over a leaked buffer of `pages` pages, which acts as
+/// both the physical memory and its direct map, for tests
+#[cfg(test)]
+pub(crate) fn test_space(pages: usize) -> (AddressSpace, &'static PhysicalMemoryManager) {
+    use alloc::boxed::Box;
+
+    let layout = core::alloc::Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let memory = unsafe { alloc::alloc::alloc_zeroed(layout) } as u64;
+    let bitmap = Box::leak(alloc::vec![0u64; pages.div_ceil(64)].into_boxed_slice());
+    // Page 0 stays used: a frame at physical 0 would look unmapped
+    let pmm = Box::leak(Box::new(PhysicalMemoryManager::with_free_pages(bitmap, 1..pages)));
+    (unsafe { AddressSpace::with_allocator(pmm, memory).unwrap() }, pmm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_flags() {
+        let flags = page_flags(MmapProt::READ.with(MmapProt::EXEC));
+        assert!(flags.contains(PageTableFlags::USER));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+        let flags = page_flags(MmapProt::READ.with(MmapProt::WRITE));
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test]
+    fn test_map_write_read() {
+        let (mut space, _) = test_space(64);
+        space.map(0x40_0ff0, 0x20, MmapProt::READ).unwrap();
+        assert!(space.mapper.translate(0x40_0000).is_some());
+        assert!(space.mapper.translate(0x40_1000).is_some());
+        assert!(space.mapper.translate(0x40_2000).is_none());
+        let flags = space.mapper.effective_flags(0x40_1000).unwrap();
+        assert!(flags.contains(PageTableFlags::USER.with(PageTableFlags::NO_EXECUTE)));
+
+        // Crosses into the second page; the pages start out zeroed
+        space.write(0x40_0ffc, &[1, 2, 3, 4, 5, 6]).unwrap();
+        let mut buf = [0xffu8; 8];
+        space.read(0x40_0ffa, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 1, 2, 3, 4, 5, 6]);
+        assert!(space.write(0x40_1ffc, &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn test_unmap_and_destroy_free_frames() {
+        let (mut space, pmm) = test_space(64);
+        let free = pmm.free_pages();
+        space.map(0x7000_0000, 3 * PAGE_SIZE as u64, MmapProt::READ.with(MmapProt::WRITE)).unwrap();
+        // Three frames, plus a PDPT, PD and page table
+        assert_eq!(pmm.free_pages(), free - 6);
+        space.unmap(0x7000_1000, PAGE_SIZE as u64);
+        assert_eq!(pmm.free_pages(), free - 5);
+        assert!(space.mapper.translate(0x7000_1000).is_none());
+
+        unsafe { space.destroy() };
+        // The PML4 goes too
+        assert_eq!(pmm.free_pages(), free + 1);
+    }
+
+    #[test]
+    fn test_frames_charged_to_owner() {
+        cgroup::init();
+        let group = cgroup::create(cgroup::ROOT_CGROUP, "address-space").unwrap();
+        cgroup::set_memory_limit(group, 2 * PAGE_SIZE).unwrap();
+        let owner = TaskId::new(MAX_TASKS - 1);
+        cgroup::add_task(group, owner).unwrap();
+        let charged = || cgroup::cgroups().get(group).unwrap().memory_current;
+
+        let (mut space, _) = test_space(64);
+        space.owner = Some(owner);
+        let rw = MmapProt::READ.with(MmapProt::WRITE);
+        space.map(0x40_0000, 2 * PAGE_SIZE as u64, rw).unwrap();
+        assert_eq!(charged(), 2 * PAGE_SIZE);
+        assert_eq!(resident_kb(owner), 8);
+        assert!(space.map(0x50_0000, PAGE_SIZE as u64, rw).is_err());
+        assert_eq!(charged(), 2 * PAGE_SIZE);
+
+        space.unmap(0x40_0000, PAGE_SIZE as u64);
+        space.map(0x50_0000, PAGE_SIZE as u64, rw).unwrap();
+        unsafe { space.destroy() };
+        assert_eq!(charged(), 0);
+        assert_eq!(resident_kb(owner), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! Fault Handling
+//!
+//! Handlers registered with the arch layer for page faults, general
+//! protection faults and invalid opcodes:
+//! - A fault on a page reserved for demand paging maps a zeroed frame
+//! - A write to a present copy-on-write page gives the writer its own
+//!   copy, or just makes the page writable when it is no longer shared
+//! - Any other fault in user mode sends the task SIGSEGV (SIGILL for an
+//!   invalid opcode), which kills it on the way back to user mode
+//! - Any other fault in kernel mode is left to the arch layer, which
+//!   reports it and halts
+//!
+//! This module provides:
+//! - Fault classification
+//! - Demand paging and CoW resolution in the current address space
+//! - Handler registration
+
+use fanga_arch_x86_64::interrupts::fault::{self, FaultHandler, FaultInfo, FaultKind, FaultResolution};
+use fanga_arch_x86_64::interrupts::idt::InterruptStackFrame;
+use spin::Once;
+
+use super::addr::{PhysAddr, VirtAddr, PAGE_SIZE};
+use super::address_space;
+use super::paging::{PageTableFlags, PageTableMapper};
+use super::pmm::PhysicalMemoryManager;
+use crate::task::ipc::Signal;
+use crate::task::TaskId;
+
+/// What a page fault needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultAction {
+    /// Map a zeroed frame at a demand-paged address
+    MapZeroPage,
+    /// Give the writer a private copy of a CoW page
+    BreakCow,
+    /// An access the address space does not allow
+    Invalid,
+}
+
+/// Decide how to resolve a page fault
+///
+/// # Arguments
+/// * `info` - The fault
+/// * `demand_paged` - Whether the page is reserved for demand paging
+/// * `flags` - Flags of the present mapping, if any
+pub fn classify_page_fault(info: &FaultInfo, demand_paged: bool, flags: Option<PageTableFlags>) -> PageFaultAction {
+    if !info.is_protection_violation() {
+        return if demand_paged { PageFaultAction::MapZeroPage } else { PageFaultAction::Invalid };
+    }
+    match flags {
+        Some(flags) if info.is_write() && flags.contains(PageTableFlags::COPY_ON_WRITE) => PageFaultAction::BreakCow,
+        _ => PageFaultAction::Invalid,
+    }
+}
+
+/// Signal sent to a user task for an unresolved fault
+pub fn fault_signal(kind: FaultKind) -> Signal {
+    match kind {
+        FaultKind::InvalidOpcode => Signal::SIGILL,
+        FaultKind::PageFault | FaultKind::GeneralProtection => Signal::SIGSEGV,
+    }
+}
+
+struct FaultState {
+    pmm: &'static PhysicalMemoryManager,
+    hhdm_offset: u64,
+}
+
+// The PMM is 'static and does its own locking
+unsafe impl Send for FaultState {}
+unsafe impl Sync for FaultState {}
+
+static STATE: Once<FaultState> = Once::new();
+
+impl FaultState {
+    fn mapper(&self) -> PageTableMapper {
+        PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, self.hhdm_offset)
+    }
+
+    fn frame_ptr(&self, phys: u64) -> *mut u8 {
+        (phys + self.hhdm_offset) as *mut u8
+    }
+
+    /// A frame for the user page `page`, placed by the NUMA policy of the
+    /// current task or of the range it lies in
+    ///
+    /// The frame is charged to the current task, and allocation fails if
+    /// that would exceed its cgroup's memory limit.
+    fn alloc_frame(&self, page: u64) -> Result<u64, &'static str> {
+        let owner = crate::percpu!(current_task).map(TaskId::new);
+        if let Some(task) = owner {
+            address_space::charge(task, 1)?;
+        }
+        let policy = crate::numa::policy::current_policy(page);
+        let frame = crate::numa::allocator::alloc_page_with_policy(self.pmm, &policy, page / PAGE_SIZE as u64);
+        if frame.is_none() {
+            if let Some(task) = owner {
+                address_space::uncharge(task, 1);
+            }
+        }
+        frame.ok_or("Out of memory")
+    }
+
+    /// Free a frame from `alloc_frame` that was never mapped
+    fn free_frame(&self, frame: u64) {
+        self.pmm.free_page(frame);
+        if let Some(task) = crate::percpu!(current_task) {
+            address_space::uncharge(TaskId::new(task), 1);
+        }
+    }
+
+    /// Map a zeroed, writable, non-executable user frame at `page`
+    fn map_zero_page(&self, page: u64) -> Result<(), &'static str> {
+        let frame = self.alloc_frame(page)?;
+        let flags = PageTableFlags::WRITABLE
+            .with(PageTableFlags::USER)
+            .with(PageTableFlags::NO_EXECUTE);
+        unsafe {
+            core::ptr::write_bytes(self.frame_ptr(frame), 0, PAGE_SIZE);
+            if let Err(e) = self.mapper().map(page, frame, flags, self.pmm) {
+                self.free_frame(frame);
+                return Err(e);
+            }
+        }
+        super::demand_paging::allocate_demand_page(VirtAddr::new(page));
+        super::demand_paging::record_page_access(VirtAddr::new(page), PhysAddr::new(frame));
+        Ok(())
+    }
+
+    /// Make the CoW page at `page` writable for the current address space
+    fn break_cow(&self, page: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+        let mut mapper = self.mapper();
+        let old = mapper.translate(page).ok_or("Page not mapped")? & !0xFFF;
+        let writable = flags
+            .without(PageTableFlags::COPY_ON_WRITE)
+            .with(PageTableFlags::WRITABLE);
+
+        // The last sharer keeps the frame
+        if super::cow::get_cow_ref_count(PhysAddr::new(old)) <= 1 {
+            super::cow::release_cow_page(PhysAddr::new(old));
+            return unsafe { mapper.protect(page, writable) };
+        }
+
+        let frame = self.alloc_frame(page)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.frame_ptr(old), self.frame_ptr(frame), PAGE_SIZE);
+            if let Err(e) = mapper.remap(page, frame, writable) {
+                self.free_frame(frame);
+                return Err(e);
+            }
+        }
+        super::cow::release_cow_page(PhysAddr::new(old));
+        Ok(())
+    }
+}
+
+/// Queue the fatal signal for a user-mode fault
+fn kill_current(info: &FaultInfo) -> FaultResolution {
+    let task = crate::task::scheduler::try_scheduler().and_then(|s| s.current_task());
+    let manager = crate::task::sigadv::try_signal_manager();
+    let (Some(task), Some(manager)) = (task, manager) else {
+        return FaultResolution::Unhandled;
+    };
+    let Some(mut manager) = manager.try_lock() else {
+        return FaultResolution::Unhandled;
+    };
+    manager.get_or_create_handler(task).send(fault_signal(info.kind));
+    FaultResolution::KillTask
+}
+
+fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+fn page_fault_handler(info: &FaultInfo, frame: &mut InterruptStackFrame) -> FaultResolution {
+    if let Some(state) = STATE.get() {
+        let page = info.address & !(PAGE_SIZE as u64 - 1);
+        let demand_paged = super::demand_paging::should_allocate_on_fault(VirtAddr::new(page));
+        let flags = state.mapper().effective_flags(page);
+        let result = match classify_page_fault(info, demand_paged, flags) {
+            PageFaultAction::MapZeroPage => Some(state.map_zero_page(page)),
+            PageFaultAction::BreakCow => flags.map(|flags| state.break_cow(page, flags)),
+            PageFaultAction::Invalid => None,
+        };
+        match result {
+            Some(Ok(())) => return FaultResolution::Resolved,
+            Some(Err(e)) => fanga_arch_x86_64::serial_println!(
+                "[FAULT] Cannot resolve page fault at 0x{:x}: {}",
+                info.address,
+                e
+            ),
+            None => {}
+        }
+    }
+    if from_user(frame) {
+        fanga_arch_x86_64::serial_println!(
+            "[FAULT] Segmentation fault at 0x{:x} (rip=0x{:x}, ec=0x{:x})",
+            info.address,
+            frame.rip,
+            info.error_code
+        );
+        return kill_current(info);
+    }
+    FaultResolution::Unhandled
+}
+
+fn user_fault_handler(info: &FaultInfo, frame: &mut InterruptStackFrame) -> FaultResolution {
+    if !from_user(frame) {
+        return FaultResolution::Unhandled;
+    }
+    fanga_arch_x86_64::serial_println!(
+        "[FAULT] {:?} in user mode at rip=0x{:x} (ec=0x{:x})",
+        info.kind,
+        frame.rip,
+        info.error_code
+    );
+    kill_current(info)
+}
+
+/// Register the fault handlers with the arch layer
+///
+/// # Safety
+/// `hhdm_offset` must be the bootloader's HHDM offset. Call once during
+/// boot, after the PMM is initialized.
+pub unsafe fn init(pmm: &'static PhysicalMemoryManager, hhdm_offset: u64) {
+    STATE.call_once(|| FaultState { pmm, hhdm_offset });
+    let handlers: [(FaultKind, FaultHandler); 3] = [
+        (FaultKind::PageFault, page_fault_handler),
+        (FaultKind::GeneralProtection, user_fault_handler),
+        (FaultKind::InvalidOpcode, user_fault_handler),
+    ];
+    for (kind, handler) in handlers {
+        fault::set_fault_handler(kind, handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fanga_arch_x86_64::interrupts::fault::{PF_PRESENT, PF_USER, PF_WRITE};
+
+    fn page_fault(error_code: u64) -> FaultInfo {
+        FaultInfo { kind: FaultKind::PageFault, error_code, address: 0x40_1234 }
+    }
+
+    #[test]
+    fn test_classify_not_present() {
+        assert_eq!(classify_page_fault(&page_fault(PF_USER), true, None), PageFaultAction::MapZeroPage);
+        assert_eq!(classify_page_fault(&page_fault(PF_WRITE), true, None), PageFaultAction::MapZeroPage);
+        assert_eq!(classify_page_fault(&page_fault(PF_USER), false, None), PageFaultAction::Invalid);
+    }
+
+    #[test]
+    fn test_classify_protection() {
+        let cow = PageTableFlags::PRESENT.with(PageTableFlags::USER).with(PageTableFlags::COPY_ON_WRITE);
+        let read_only = PageTableFlags::PRESENT.with(PageTableFlags::USER);
+        let write = page_fault(PF_PRESENT | PF_WRITE | PF_USER);
+
+        assert_eq!(classify_page_fault(&write, false, Some(cow)), PageFaultAction::BreakCow);
+        assert_eq!(classify_page_fault(&write, false, Some(read_only)), PageFaultAction::Invalid);
+        // Reads of a CoW page never fault for CoW reasons
+        assert_eq!(classify_page_fault(&page_fault(PF_PRESENT | PF_USER), false, Some(cow)), PageFaultAction::Invalid);
+    }
+
+    #[test]
+    fn test_fault_signal() {
+        assert_eq!(fault_signal(FaultKind::InvalidOpcode), Signal::SIGILL);
+        assert_eq!(fault_signal(FaultKind::GeneralProtection), Signal::SIGSEGV);
+    }
+}
@@ -254,6 +254,7 @@ unsafe impl GlobalAlloc for GlobalHeapAllocator {
         let ptr = self.inner.lock().alloc(layout);
         if !ptr.is_null() && layout.size() > 0 {
             crate::memory::stats::stats().record_heap_alloc(layout.size());
+            crate::profiling::heap_profile::record_alloc(layout.size());
         }
         ptr
     }
@@ -261,6 +262,7 @@ unsafe impl GlobalAlloc for GlobalHeapAllocator {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if layout.size() > 0 {
             crate::memory::stats::stats().record_heap_dealloc(layout.size());
+            crate::profiling::heap_profile::record_free(layout.size());
         }
         self.inner.lock().dealloc(ptr, layout)
     }
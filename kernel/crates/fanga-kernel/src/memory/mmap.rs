@@ -2,12 +2,20 @@
 //!
 //! This module implements memory mapping functionality similar to POSIX mmap/munmap.
 //! It allows processes to map virtual memory regions to physical memory or files.
+//!
+//! The mmap and munmap system calls map private anonymous memory into the
+//! caller's address space and record it in the caller's memory areas.
 
 extern crate alloc;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use spin::Mutex;
 use super::addr::{VirtAddr, PhysAddr, PAGE_SIZE, align_up, align_down};
+use super::address_space::AddressSpace;
+use super::regions::address_space::USER_SPACE_END;
+use super::vma::{self, VmaMap};
+use crate::task::scheduler;
+use fanga_arch_x86_64::syscall::{EINVAL, ENOMEM, ENOSYS, ESRCH};
 
 /// Memory mapping flags
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -306,6 +314,109 @@ impl MmapManager {
     }
 }
 
+/// Map `len` bytes of zeroed anonymous memory into `space` and record the
+/// area in `map`
+///
+/// A MAP_FIXED mapping replaces whatever was at `addr`; any other goes in
+/// the highest free range below `ceiling`. PROT_NONE areas get no pages.
+fn map_anonymous(
+    space: &mut AddressSpace,
+    map: &mut VmaMap,
+    addr: u64,
+    len: u64,
+    prot: MmapProt,
+    flags: MmapFlags,
+    ceiling: u64,
+) -> Result<u64, i64> {
+    let len = len.checked_add(PAGE_SIZE as u64 - 1).ok_or(ENOMEM)? & !(PAGE_SIZE as u64 - 1);
+    let start = if flags.contains(MmapFlags::FIXED) {
+        let in_user_space = addr.checked_add(len).is_some_and(|end| end <= USER_SPACE_END);
+        if !addr.is_multiple_of(PAGE_SIZE as u64) || !in_user_space {
+            return Err(EINVAL);
+        }
+        space.unmap(addr, len);
+        map.remove(addr, len);
+        addr
+    } else {
+        map.find_free(len, ceiling).ok_or(ENOMEM)?
+    };
+
+    if prot != MmapProt::NONE && space.map(start, len, prot).is_err() {
+        space.unmap(start, len);
+        return Err(ENOMEM);
+    }
+    map.insert(start, len, prot).map_err(|_| ENOMEM)?;
+    Ok(start)
+}
+
+/// Unmap `addr..addr + len` from `space` and drop it from `map`
+fn unmap_range(space: &mut AddressSpace, map: &mut VmaMap, addr: u64, len: u64) -> Result<(), i64> {
+    let in_user_space = addr.checked_add(len).is_some_and(|end| end <= USER_SPACE_END);
+    if !addr.is_multiple_of(PAGE_SIZE as u64) || len == 0 || !in_user_space {
+        return Err(EINVAL);
+    }
+    space.unmap(addr, len);
+    map.remove(addr, len);
+    Ok(())
+}
+
+/// The current task, its address space, whose frames it is charged for,
+/// and the top of its mmap area
+fn current_address_space() -> Result<(crate::task::TaskId, AddressSpace, u64), i64> {
+    let sched = scheduler::scheduler();
+    let task = sched.current_task().ok_or(ESRCH)?;
+    let current = sched.get_task(task).ok_or(ESRCH)?;
+    let space = AddressSpace::of(current.page_table, Some(task)).ok_or(ENOMEM)?;
+    Ok((task, space, current.mmap_base.as_u64()))
+}
+
+/// mmap(addr, length, prot, flags, fd, offset): map memory into the
+/// current process
+///
+/// Only private anonymous mappings are supported, so there is no `fd` or
+/// `offset`. The pages are zeroed and mapped straight away, and the hint
+/// in `addr` is only followed with MAP_FIXED: other mappings go below the
+/// task's randomized mmap base.
+pub fn sys_mmap(addr: u64, length: u64, prot: u32, flags: u32) -> i64 {
+    let prot = MmapProt::from_bits(prot);
+    let flags = MmapFlags::from_bits(flags);
+    let all = MmapProt::READ.with(MmapProt::WRITE).with(MmapProt::EXEC);
+    if length == 0 || prot.bits() & !all.bits() != 0 {
+        return EINVAL;
+    }
+    if flags.contains(MmapFlags::SHARED) == flags.contains(MmapFlags::PRIVATE) {
+        return EINVAL;
+    }
+    if flags.contains(MmapFlags::SHARED) || !flags.contains(MmapFlags::ANONYMOUS) {
+        return ENOSYS;
+    }
+
+    let (task, mut space, ceiling) = match current_address_space() {
+        Ok(current) => current,
+        Err(e) => return e,
+    };
+    match vma::with_map(task, |map| map_anonymous(&mut space, map, addr, length, prot, flags, ceiling)) {
+        Some(Ok(start)) => start as i64,
+        Some(Err(e)) => e,
+        None => ENOMEM,
+    }
+}
+
+/// munmap(addr, length): unmap part of the current process's memory
+///
+/// Pages in the range that are not mapped are skipped.
+pub fn sys_munmap(addr: u64, length: u64) -> i64 {
+    let (task, mut space, _) = match current_address_space() {
+        Ok(current) => current,
+        Err(e) => return e,
+    };
+    match vma::with_map(task, |map| unmap_range(&mut space, map, addr, length)) {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => e,
+        None => EINVAL,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -429,4 +540,61 @@ mod tests {
         // Should not find mapping outside range
         assert!(manager.find_mapping(VirtAddr::new(addr.as_u64() + 0x3000)).is_none());
     }
+
+    fn anonymous() -> MmapFlags {
+        MmapFlags::PRIVATE.with(MmapFlags::ANONYMOUS)
+    }
+
+    #[test]
+    fn test_map_anonymous() {
+        use crate::task::sigdeliver::UserMemory;
+
+        let (mut space, _) = super::super::address_space::test_space(64);
+        let mut map = VmaMap::new();
+        let rw = MmapProt::READ.with(MmapProt::WRITE);
+        let ceiling = 0x4000_0000;
+
+        let start = map_anonymous(&mut space, &mut map, 0x1234, 0x1800, rw, anonymous(), ceiling).unwrap();
+        assert_eq!(start, ceiling - 0x2000);
+        assert!(map.check(start, 0x2000, rw));
+        let mut bytes = [0xffu8; 4];
+        space.read(start + 0x1ffc, &mut bytes).unwrap();
+        assert_eq!(bytes, [0; 4]);
+
+        // The next one goes below it, and a PROT_NONE one gets no pages
+        let below = map_anonymous(&mut space, &mut map, 0, 0x1000, MmapProt::NONE, anonymous(), ceiling).unwrap();
+        assert_eq!(below, start - 0x1000);
+        assert!(space.read(below, &mut bytes).is_err());
+
+        // MAP_FIXED replaces what was there with fresh pages
+        space.write(start, &[1, 2, 3, 4]).unwrap();
+        let fixed = anonymous().with(MmapFlags::FIXED);
+        assert_eq!(map_anonymous(&mut space, &mut map, start, 0x1000, MmapProt::READ, fixed, ceiling), Ok(start));
+        assert_eq!(map.find(start).map(|vma| vma.prot), Some(MmapProt::READ));
+        assert_eq!(map.find(start + 0x1000).map(|vma| vma.prot), Some(rw));
+        space.read(start, &mut bytes).unwrap();
+        assert_eq!(bytes, [0; 4]);
+        assert_eq!(map_anonymous(&mut space, &mut map, start + 1, 0x1000, rw, fixed, ceiling), Err(EINVAL));
+    }
+
+    #[test]
+    fn test_unmap_range() {
+        let (mut space, pmm) = super::super::address_space::test_space(64);
+        let mut map = VmaMap::new();
+        let rw = MmapProt::READ.with(MmapProt::WRITE);
+        let start = map_anonymous(&mut space, &mut map, 0, 0x3000, rw, anonymous(), 0x4000_0000).unwrap();
+        let free = pmm.free_pages();
+
+        assert_eq!(unmap_range(&mut space, &mut map, start + 1, 0x1000), Err(EINVAL));
+        assert_eq!(unmap_range(&mut space, &mut map, start, 0), Err(EINVAL));
+        unmap_range(&mut space, &mut map, start + 0x1000, 0x1000).unwrap();
+        assert_eq!(pmm.free_pages(), free + 1);
+        assert!(map.find(start + 0x1000).is_none());
+        assert_eq!(map.len(), 2);
+
+        // Unmapping a hole is not an error
+        unmap_range(&mut space, &mut map, start, 0x3000).unwrap();
+        assert_eq!(pmm.free_pages(), free + 3);
+        assert!(map.is_empty());
+    }
 }
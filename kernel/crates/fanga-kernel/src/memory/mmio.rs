@@ -0,0 +1,278 @@
+//! Memory-Mapped I/O
+//!
+//! Device registers must be mapped uncached, and the HHDM does not cover
+//! them, so `ioremap` maps them into a dedicated window of the kernel's
+//! address space. The window is allocated bottom-up and never freed;
+//! drivers map their registers once at init. Mapping a range that is
+//! already mapped returns the existing virtual address, whatever its
+//! caching.
+//!
+//! The same window holds write-combining framebuffer mappings and large
+//! buffers of RAM too big for the kernel heap.
+//!
+//! The mappings are made in the boot page tables, before any process
+//! address space copies the kernel half.
+//!
+//! This module provides:
+//! - `ioremap` for device registers
+//! - `ioremap_wc` for framebuffers
+//! - `alloc_buffer` for large never-freed buffers
+//! - `alloc_dma_page` for pages shared with bus-mastering devices
+//! - `alloc_low_page` and identity mappings for real-mode code
+//! - The MMIO virtual address window
+
+extern crate alloc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::addr::{align_down, align_up, PAGE_SIZE};
+use super::paging::{PageTableFlags, PageTableMapper};
+use super::pmm::PhysicalMemoryManager;
+
+/// Start of the MMIO window (PML4 entry 510)
+pub const MMIO_WINDOW_START: u64 = 0xFFFF_FF00_0000_0000;
+
+/// Size of the MMIO window
+pub const MMIO_WINDOW_SIZE: u64 = 1 << 30;
+
+/// One mapped physical range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioMapping {
+    /// Page-aligned physical start
+    pub phys: u64,
+    /// Virtual address of `phys`
+    pub virt: u64,
+    /// Size in bytes, a multiple of the page size
+    pub size: u64,
+}
+
+/// Allocation state of the MMIO window
+pub struct MmioSpace {
+    next: u64,
+    mappings: Vec<MmioMapping>,
+}
+
+impl MmioSpace {
+    /// Create an empty window
+    pub const fn new() -> Self {
+        Self {
+            next: MMIO_WINDOW_START,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Find an existing mapping covering `phys..phys + size`
+    pub fn lookup(&self, phys: u64, size: u64) -> Option<u64> {
+        self.mappings
+            .iter()
+            .find(|m| phys >= m.phys && phys + size <= m.phys + m.size)
+            .map(|m| m.virt + (phys - m.phys))
+    }
+
+    /// Reserve virtual space for the page-aligned range `phys..phys + size`
+    pub fn allocate(&mut self, phys: u64, size: u64) -> Result<MmioMapping, &'static str> {
+        let mapping = MmioMapping { phys, virt: self.reserve(size)?, size };
+        self.mappings.push(mapping);
+        Ok(mapping)
+    }
+
+    /// Reserve `size` bytes of virtual space not tied to a physical range
+    pub fn reserve(&mut self, size: u64) -> Result<u64, &'static str> {
+        if self.next + size > MMIO_WINDOW_START + MMIO_WINDOW_SIZE {
+            return Err("MMIO window exhausted");
+        }
+        let virt = self.next;
+        self.next += size;
+        Ok(virt)
+    }
+
+    /// Mappings made so far
+    pub fn mappings(&self) -> &[MmioMapping] {
+        &self.mappings
+    }
+}
+
+/// Page-aligned physical range covering `phys..phys + size`
+pub fn page_range(phys: u64, size: u64) -> (u64, u64) {
+    let start = align_down(phys, PAGE_SIZE as u64);
+    let end = align_up(phys + size.max(1), PAGE_SIZE as u64);
+    (start, end - start)
+}
+
+struct MmioState {
+    space: MmioSpace,
+    pmm: *const PhysicalMemoryManager,
+    hhdm_offset: u64,
+}
+
+// The PMM is 'static and does its own locking
+unsafe impl Send for MmioState {}
+
+static MMIO: Mutex<Option<MmioState>> = Mutex::new(None);
+
+/// Enable `ioremap`
+///
+/// # Safety
+/// `hhdm_offset` must be the bootloader's HHDM offset.
+pub unsafe fn init(pmm: &'static PhysicalMemoryManager, hhdm_offset: u64) {
+    *MMIO.lock() = Some(MmioState {
+        space: MmioSpace::new(),
+        pmm,
+        hhdm_offset,
+    });
+}
+
+/// Map `size` bytes of device registers at physical `phys`, uncached
+///
+/// # Returns
+/// The virtual address of `phys`
+pub fn ioremap(phys: u64, size: u64) -> Result<u64, &'static str> {
+    let flags = PageTableFlags::WRITABLE
+        .with(PageTableFlags::NO_CACHE)
+        .with(PageTableFlags::WRITE_THROUGH)
+        .with(PageTableFlags::NO_EXECUTE);
+    remap(phys, size, flags)
+}
+
+/// Map `size` bytes of framebuffer at physical `phys`, write-combining
+///
+/// PWT alone selects PAT entry 1, which the arch layer makes
+/// write-combining; without a PAT the mapping is write-through.
+///
+/// # Returns
+/// The virtual address of `phys`
+pub fn ioremap_wc(phys: u64, size: u64) -> Result<u64, &'static str> {
+    let flags = PageTableFlags::WRITABLE
+        .with(PageTableFlags::WRITE_THROUGH)
+        .with(PageTableFlags::NO_EXECUTE);
+    remap(phys, size, flags)
+}
+
+fn remap(phys: u64, size: u64, flags: PageTableFlags) -> Result<u64, &'static str> {
+    let mut guard = MMIO.lock();
+    let state = guard.as_mut().ok_or("MMIO mapping not initialized")?;
+    if let Some(virt) = state.space.lookup(phys, size) {
+        return Ok(virt);
+    }
+
+    let (start, len) = page_range(phys, size);
+    let mapping = state.space.allocate(start, len)?;
+
+    let mut mapper = PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, state.hhdm_offset);
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        unsafe {
+            mapper.map(mapping.virt + offset, start + offset, flags, &*state.pmm)?;
+        }
+    }
+    Ok(mapping.virt + (phys - start))
+}
+
+/// Map `size` bytes of zeroed RAM, for buffers too large for the heap
+///
+/// The pages need not be physically contiguous and are never freed.
+///
+/// # Returns
+/// The virtual address of the buffer
+pub fn alloc_buffer(size: u64) -> Result<u64, &'static str> {
+    let mut guard = MMIO.lock();
+    let state = guard.as_mut().ok_or("MMIO mapping not initialized")?;
+    let (_, len) = page_range(0, size);
+    let virt = state.space.reserve(len)?;
+    let flags = PageTableFlags::WRITABLE.with(PageTableFlags::NO_EXECUTE);
+
+    let pmm = unsafe { &*state.pmm };
+    let mut mapper = PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, state.hhdm_offset);
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        let frame = pmm.alloc_page().ok_or("Out of physical memory")?;
+        unsafe {
+            core::ptr::write_bytes((state.hhdm_offset + frame) as *mut u8, 0, PAGE_SIZE);
+            mapper.map(virt + offset, frame, flags, pmm)?;
+        }
+    }
+    Ok(virt)
+}
+
+/// A zeroed page of RAM for a device to read and write by DMA
+///
+/// DMA is cache-coherent on x86, so the page is used through the direct
+/// map. It is never freed.
+///
+/// # Returns
+/// The page's virtual and physical addresses
+pub fn alloc_dma_page() -> Result<(u64, u64), &'static str> {
+    let guard = MMIO.lock();
+    let state = guard.as_ref().ok_or("MMIO mapping not initialized")?;
+    let pmm = unsafe { &*state.pmm };
+    let phys = pmm.alloc_page().ok_or("Out of physical memory")?;
+    let virt = state.hhdm_offset + phys;
+    unsafe { core::ptr::write_bytes(virt as *mut u8, 0, PAGE_SIZE) };
+    Ok((virt, phys))
+}
+
+/// A zeroed page of RAM below the physical address `limit`
+///
+/// It is never freed.
+///
+/// # Returns
+/// The page's virtual (direct map) and physical addresses
+pub fn alloc_low_page(limit: u64) -> Result<(u64, u64), &'static str> {
+    let guard = MMIO.lock();
+    let state = guard.as_ref().ok_or("MMIO mapping not initialized")?;
+    let pmm = unsafe { &*state.pmm };
+    let phys = pmm.alloc_page_below(limit).ok_or("No free page in low memory")?;
+    let virt = state.hhdm_offset + phys;
+    unsafe { core::ptr::write_bytes(virt as *mut u8, 0, PAGE_SIZE) };
+    Ok((virt, phys))
+}
+
+/// Map the page at physical `phys` at the same virtual address, executable
+///
+/// For code that turns paging on while running from it, such as the ACPI
+/// wakeup trampoline. The mapping is made in the current page tables, and
+/// must not collide with one already there.
+pub fn map_identity(phys: u64) -> Result<(), &'static str> {
+    let guard = MMIO.lock();
+    let state = guard.as_ref().ok_or("MMIO mapping not initialized")?;
+    let mut mapper = PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, state.hhdm_offset);
+    unsafe { mapper.map(phys, phys, PageTableFlags::empty(), &*state.pmm) }
+}
+
+/// Remove a mapping made by `map_identity`
+pub fn unmap_identity(phys: u64) -> Result<(), &'static str> {
+    let guard = MMIO.lock();
+    let state = guard.as_ref().ok_or("MMIO mapping not initialized")?;
+    let mut mapper = PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, state.hhdm_offset);
+    unsafe { mapper.unmap(phys) }.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_range() {
+        assert_eq!(page_range(0xFEC0_0000, 0x20), (0xFEC0_0000, 0x1000));
+        assert_eq!(page_range(0xFEC0_0FF0, 0x20), (0xFEC0_0000, 0x2000));
+        assert_eq!(page_range(0x1000, 0), (0x1000, 0x1000));
+    }
+
+    #[test]
+    fn test_mmio_space() {
+        let mut space = MmioSpace::new();
+        let a = space.allocate(0xFEC0_0000, 0x1000).unwrap();
+        let b = space.allocate(0xFEB0_0000, 0x2000).unwrap();
+
+        assert_eq!(a.virt, MMIO_WINDOW_START);
+        assert_eq!(b.virt, MMIO_WINDOW_START + 0x1000);
+        assert_eq!(space.lookup(0xFEC0_0010, 4), Some(MMIO_WINDOW_START + 0x10));
+        assert_eq!(space.lookup(0xFEB0_1000, 0x1000), Some(MMIO_WINDOW_START + 0x2000));
+        assert_eq!(space.lookup(0xFEB0_1000, 0x2000), None);
+        assert!(space.allocate(0, MMIO_WINDOW_SIZE).is_err());
+        assert_eq!(space.mappings().len(), 2);
+
+        // Reserved space is skipped but not recorded as a mapping
+        assert_eq!(space.reserve(0x3000), Ok(MMIO_WINDOW_START + 0x3000));
+        assert_eq!(space.allocate(0xFEA0_0000, 0x1000).unwrap().virt, MMIO_WINDOW_START + 0x6000);
+        assert_eq!(space.mappings().len(), 3);
+    }
+}
@@ -8,10 +8,14 @@
 //! - Statistics and debugging
 //! - Copy-on-Write (CoW)
 //! - Memory mapping (mmap/munmap)
+//! - Per-process user memory areas, for checking user pointers
+//! - Per-process address spaces
 //! - Demand paging
 //! - Page replacement (LRU)
 //! - Swap support
 //! - Memory protection and guard pages
+//! - Device register and framebuffer mappings (ioremap)
+//! - Page fault resolution and fault handlers
 
 pub mod addr;
 pub mod pmm;
@@ -22,9 +26,13 @@ pub mod stats;
 pub mod debug;
 pub mod cow;
 pub mod mmap;
+pub mod vma;
+pub mod address_space;
 pub mod demand_paging;
 pub mod swap;
 pub mod protection;
+pub mod mmio;
+pub mod fault;
 
 // Re-export commonly used types and functions
 pub use addr::{PhysAddr, VirtAddr, PAGE_SIZE, align_up, align_down};
@@ -34,10 +42,13 @@ pub use heap::GlobalHeapAllocator;
 pub use regions::{MemoryRegion, MemoryRegionType, MemoryRegionManager};
 pub use cow::{mark_cow_page, release_cow_page, is_cow_page, get_cow_ref_count, add_cow_page};
 pub use mmap::{MmapFlags, MmapProt, MemoryMapping, MmapManager};
+pub use vma::{Vma, VmaMap};
+pub use address_space::AddressSpace;
 pub use demand_paging::{PageState, record_page_access, get_lru_page, get_lru_stats,
                          reserve_demand_pages, allocate_demand_page, get_page_state,
                          should_allocate_on_fault, get_demand_paging_stats};
 pub use swap::{init_swap, swap_out_page, swap_in_page, is_page_swapped, get_swap_stats, has_swap_space};
+pub use mmio::ioremap;
 pub use protection::{MemoryProtection, ProtectedRegion, add_guard_page, is_guard_page,
                       add_protected_region, check_memory_access};
 
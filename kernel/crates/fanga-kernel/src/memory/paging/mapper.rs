@@ -133,9 +133,9 @@ impl PageTableMapper {
         Ok(())
     }
 
-    /// Unmaps a virtual address
-    pub unsafe fn unmap(&mut self, virt_addr: u64) -> Result<u64, &'static str> {
-        if virt_addr % PAGE_SIZE as u64 != 0 {
+    /// Walks to the page table entry of a mapped virtual address
+    unsafe fn leaf_entry(&mut self, virt_addr: u64) -> Result<&mut PageTableEntry, &'static str> {
+        if !virt_addr.is_multiple_of(PAGE_SIZE as u64) {
             return Err("Address must be page-aligned");
         }
 
@@ -167,15 +167,60 @@ impl PageTableMapper {
             return Err("PT entry not present");
         }
 
-        let entry = pt.entry_mut(pt_idx);
+        Ok(pt.entry_mut(pt_idx))
+    }
+
+    /// Unmaps a virtual address
+    ///
+    /// Other CPUs may have cached the mapping, so their TLBs are shot down.
+    pub unsafe fn unmap(&mut self, virt_addr: u64) -> Result<u64, &'static str> {
+        let entry = self.leaf_entry(virt_addr)?;
         let phys_addr = entry.addr();
         entry.clear();
 
-        Self::flush_tlb(virt_addr);
+        Self::shootdown(virt_addr);
 
         Ok(phys_addr)
     }
 
+    /// Changes the flags of a mapped page (mprotect)
+    ///
+    /// # Safety
+    /// No code may rely on the old permissions of the page.
+    pub unsafe fn protect(&mut self, virt_addr: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+        let entry = self.leaf_entry(virt_addr)?;
+        let phys_addr = entry.addr();
+        entry.set(phys_addr, flags.with(PageTableFlags::PRESENT));
+
+        Self::shootdown(virt_addr);
+
+        Ok(())
+    }
+
+    /// Points a mapped page at a different frame, e.g. when breaking CoW
+    ///
+    /// Returns the previous frame.
+    ///
+    /// # Safety
+    /// The new frame must hold the data expected at `virt_addr`.
+    pub unsafe fn remap(
+        &mut self,
+        virt_addr: u64,
+        phys_addr: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, &'static str> {
+        if !phys_addr.is_multiple_of(PAGE_SIZE as u64) {
+            return Err("Addresses must be page-aligned");
+        }
+        let entry = self.leaf_entry(virt_addr)?;
+        let old_phys = entry.addr();
+        entry.set(phys_addr, flags.with(PageTableFlags::PRESENT));
+
+        Self::shootdown(virt_addr);
+
+        Ok(old_phys)
+    }
+
     /// Translates a virtual address to a physical address
     pub fn translate(&self, virt_addr: u64) -> Option<u64> {
         let pml4_idx = pml4_index(virt_addr);
@@ -213,12 +258,125 @@ impl PageTableMapper {
         }
     }
 
+    /// Flags governing access to a virtual address
+    ///
+    /// Follows huge pages at the PDPT and PD levels. The result is the
+    /// leaf entry's flags, with NO_EXECUTE set if any level sets it, since
+    /// the CPU then refuses to execute the page.
+    pub fn effective_flags(&self, virt_addr: u64) -> Option<PageTableFlags> {
+        let indices = [pml4_index(virt_addr), pdpt_index(virt_addr), pd_index(virt_addr), pt_index(virt_addr)];
+        let mut table_phys = self.pml4_phys;
+        let mut no_execute = false;
+
+        for (level, &index) in indices.iter().enumerate() {
+            let table = unsafe { &*(self.phys_to_virt(table_phys) as *const PageTable) };
+            let entry = table.entry(index);
+            if !entry.is_present() {
+                return None;
+            }
+            let flags = entry.flags();
+            no_execute |= flags.contains(PageTableFlags::NO_EXECUTE);
+
+            let leaf = level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE));
+            if leaf {
+                return Some(if no_execute { flags.with(PageTableFlags::NO_EXECUTE) } else { flags });
+            }
+            table_phys = entry.addr();
+        }
+        None
+    }
+
+    /// Gives every PML4 entry of the kernel half (256..512) a table
+    ///
+    /// Kernel mappings made later then land in tables that every address
+    /// space copying this half shares.
+    ///
+    /// # Safety
+    /// This must be the kernel's page table.
+    pub unsafe fn fill_kernel_half(&mut self, pmm: &PhysicalMemoryManager) -> Result<(), &'static str> {
+        for index in 256..512 {
+            if self.pml4_mut().entry(index).is_present() {
+                continue;
+            }
+            let phys = pmm.alloc_page().ok_or("Failed to allocate PDPT")?;
+            (*(self.phys_to_virt(phys) as *mut PageTable)).clear();
+            self.pml4_mut()
+                .entry_mut(index)
+                .set(phys, PageTableFlags::PRESENT.with(PageTableFlags::WRITABLE));
+        }
+        Ok(())
+    }
+
+    /// Shares the kernel half of `kernel`'s PML4
+    ///
+    /// # Safety
+    /// `kernel` must be the kernel's page table.
+    pub unsafe fn copy_kernel_half(&mut self, kernel: &PageTableMapper) {
+        let from = &*(kernel.phys_to_virt(kernel.pml4_phys) as *const PageTable);
+        let pml4 = self.pml4_mut();
+        for index in 256..512 {
+            *pml4.entry_mut(index) = *from.entry(index);
+        }
+    }
+
+    /// Frees the tables of the user half (PML4 entries 0..256), handing
+    /// every frame mapped there to `free_frame`
+    ///
+    /// The TLB is not flushed.
+    ///
+    /// # Safety
+    /// No CPU may be using this page table, and the tables must have been
+    /// allocated from `pmm`.
+    pub unsafe fn clear_user_half(&mut self, pmm: &PhysicalMemoryManager, mut free_frame: impl FnMut(u64)) {
+        for index in 0..256 {
+            let entry = *self.pml4_mut().entry(index);
+            if entry.is_present() {
+                self.free_table(entry.addr(), 3, pmm, &mut free_frame);
+                self.pml4_mut().entry_mut(index).clear();
+            }
+        }
+    }
+
+    /// Frees a table of `level` (3 for a PDPT, 1 for a page table) and the
+    /// tables below it
+    unsafe fn free_table(
+        &self,
+        table_phys: u64,
+        level: usize,
+        pmm: &PhysicalMemoryManager,
+        free_frame: &mut impl FnMut(u64),
+    ) {
+        let table = &*(self.phys_to_virt(table_phys) as *const PageTable);
+        for index in 0..512 {
+            let entry = table.entry(index);
+            if !entry.is_present() {
+                continue;
+            }
+            if level == 1 {
+                free_frame(entry.addr());
+            } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                self.free_table(entry.addr(), level - 1, pmm, free_frame);
+            }
+        }
+        pmm.free_page(table_phys);
+    }
+
     /// Flushes the TLB entry for a virtual address
     #[inline]
     fn flush_tlb(virt_addr: u64) {
+        #[cfg(not(test))]
         unsafe {
             core::arch::asm!("invlpg [{}]", in(reg) virt_addr, options(nostack, preserves_flags));
         }
+        #[cfg(test)]
+        let _ = virt_addr;
+    }
+
+    /// Flushes a changed or removed mapping on every CPU
+    ///
+    /// The local TLB is flushed even if other CPUs cannot be reached.
+    fn shootdown(virt_addr: u64) {
+        let _ = crate::smp::tlb::flush_page(virt_addr);
     }
 
     /// Loads this page table into CR3
@@ -235,3 +393,50 @@ impl PageTableMapper {
         cr3
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    /// Leak a zeroed table; with an HHDM offset of 0 its address is its "physical" address
+    fn table() -> &'static mut PageTable {
+        Box::leak(Box::new(PageTable::new()))
+    }
+
+    fn link(parent: &mut PageTable, index: usize, child: &PageTable, flags: PageTableFlags) {
+        let addr = child as *const PageTable as u64;
+        parent.entry_mut(index).set(addr, flags.with(PageTableFlags::PRESENT));
+    }
+
+    #[test]
+    fn test_effective_flags() {
+        let (pml4, pdpt, pd, pt) = (table(), table(), table(), table());
+        let virt = 0x0000_0040_0020_3000;
+        link(pml4, pml4_index(virt), pdpt, PageTableFlags::WRITABLE);
+        link(pdpt, pdpt_index(virt), pd, PageTableFlags::WRITABLE.with(PageTableFlags::NO_EXECUTE));
+        link(pd, pd_index(virt), pt, PageTableFlags::WRITABLE);
+        pt.entry_mut(pt_index(virt)).set(0x5000, PageTableFlags::PRESENT.with(PageTableFlags::WRITABLE));
+
+        let mapper = PageTableMapper::from_pml4(pml4 as *const PageTable as u64, 0);
+        let flags = mapper.effective_flags(virt).unwrap();
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        // Inherited from the PDPT entry
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+        assert_eq!(mapper.effective_flags(virt + 0x1000), None);
+    }
+
+    #[test]
+    fn test_effective_flags_huge_page() {
+        let (pml4, pdpt, pd) = (table(), table(), table());
+        let virt = 0x0000_0080_0040_0000;
+        link(pml4, pml4_index(virt), pdpt, PageTableFlags::WRITABLE);
+        link(pdpt, pdpt_index(virt), pd, PageTableFlags::WRITABLE);
+        pd.entry_mut(pd_index(virt)).set(0x20_0000, PageTableFlags::PRESENT.with(PageTableFlags::HUGE_PAGE));
+
+        let mapper = PageTableMapper::from_pml4(pml4 as *const PageTable as u64, 0);
+        let flags = mapper.effective_flags(virt + 0x1234).unwrap();
+        assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+        assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+}
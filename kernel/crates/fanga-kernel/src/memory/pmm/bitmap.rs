@@ -3,19 +3,58 @@
 //! This module implements a bitmap allocator for physical memory pages.
 //! Each bit in the bitmap represents one page (4 KiB) of physical memory.
 //!
+//! # NUMA Nodes
+//!
+//! Once the NUMA topology is known, `set_node_ranges` splits the pages into
+//! per-node pools along the SRAT memory ranges. Each node keeps its own
+//! free count, and `alloc_page_on_node` only searches the node's ranges.
+//! Pages outside every range, and all pages before the ranges are set,
+//! belong to node 0.
+//!
 //! # Thread Safety
 //!
 //! This allocator is thread-safe and can be used from multiple CPUs
 //! concurrently. All operations are protected by an internal spinlock.
 
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::Mutex;
+use crate::smp::spinlock::SpinLock;
 use crate::memory::addr::{PAGE_SIZE, align_up, align_down};
+use crate::numa::topology::MAX_NUMA_NODES;
 
 /// Bitmap entry type - each u64 covers 64 pages
 type BitmapEntry = u64;
 const BITS_PER_ENTRY: usize = 64;
 
+/// Maximum number of node memory ranges
+pub const MAX_NODE_SPANS: usize = 64;
+
+/// Pages counted per lock hold when splitting into node pools (128 MiB)
+const RECOUNT_CHUNK: usize = 32 * 1024;
+
+/// Pages `start..end` of a NUMA node
+#[derive(Debug, Clone, Copy)]
+struct NodeSpan {
+    node: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Page counts of one NUMA node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeMemoryStats {
+    /// Pages in the node
+    pub total_pages: usize,
+    /// Free pages in the node
+    pub free_pages: usize,
+}
+
+impl NodeMemoryStats {
+    /// Pages in use
+    pub fn used_pages(&self) -> usize {
+        self.total_pages.saturating_sub(self.free_pages)
+    }
+}
+
 /// Inner state of the Physical Memory Manager
 struct PhysicalMemoryManagerInner {
     /// Pointer to the bitmap stored in higher-half direct map
@@ -28,26 +67,38 @@ struct PhysicalMemoryManagerInner {
     free_pages: usize,
     /// Highest physical address managed
     highest_addr: u64,
+    /// Node memory ranges, by start page
+    spans: [NodeSpan; MAX_NODE_SPANS],
+    span_count: usize,
+    /// Pages and free pages per node
+    node_pages: [NodeMemoryStats; MAX_NUMA_NODES],
+    /// Pages below this are counted in `node_pages`
+    counted_pages: usize,
 }
 
+
 /// Physical Memory Manager using bitmap allocation
 ///
 /// This structure is thread-safe and can be used from multiple CPUs.
 /// All operations acquire an internal lock to ensure consistency.
 pub struct PhysicalMemoryManager {
-    inner: Mutex<PhysicalMemoryManagerInner>,
+    inner: SpinLock<PhysicalMemoryManagerInner>,
 }
 
 impl PhysicalMemoryManager {
     /// Creates a new, uninitialized PMM
     pub const fn new() -> Self {
         Self {
-            inner: Mutex::new(PhysicalMemoryManagerInner {
+            inner: SpinLock::new(PhysicalMemoryManagerInner {
                 bitmap: core::ptr::null_mut(),
                 bitmap_entries: 0,
                 total_pages: 0,
                 free_pages: 0,
                 highest_addr: 0,
+                spans: [NodeSpan { node: 0, start: 0, end: 0 }; MAX_NODE_SPANS],
+                span_count: 0,
+                node_pages: [NodeMemoryStats { total_pages: 0, free_pages: 0 }; MAX_NUMA_NODES],
+                counted_pages: 0,
             }),
         }
     }
@@ -65,7 +116,7 @@ impl PhysicalMemoryManager {
     /// 2. Performs volatile memory operations on the bitmap
     /// 3. Must not be called concurrently during initialization
     /// 
-    /// While the Mutex provides thread safety for the internal state, the raw
+    /// While the lock provides thread safety for the internal state, the raw
     /// pointer operations and memory map traversal still require unsafe.
     pub unsafe fn init(&self, memmap: &limine::response::MemoryMapResponse, hhdm_offset: u64) {
         let mut inner = self.inner.lock();
@@ -138,6 +189,122 @@ impl PhysicalMemoryManager {
         }
 
         inner.free_pages = free_count;
+        inner.node_pages[0] = NodeMemoryStats { total_pages: inner.total_pages, free_pages: free_count };
+        inner.counted_pages = inner.total_pages;
+    }
+
+    /// Split the pages into NUMA node pools
+    ///
+    /// `ranges` lists `(node, base, size)` physical memory ranges, such as
+    /// the SRAT's. Pages outside every range stay with node 0. The pages
+    /// are counted a chunk at a time, so allocations go on meanwhile;
+    /// until the count is done `node_stats` is short.
+    pub fn set_node_ranges(&self, ranges: &[(usize, u64, u64)]) -> Result<(), &'static str> {
+        if ranges.len() > MAX_NODE_SPANS {
+            return Err("Too many NUMA memory ranges");
+        }
+        if ranges.iter().any(|&(node, _, _)| node >= MAX_NUMA_NODES) {
+            return Err("Invalid NUMA node");
+        }
+
+        let mut inner = self.inner.lock();
+        let total_pages = inner.total_pages;
+        let mut spans = [NodeSpan { node: 0, start: 0, end: 0 }; MAX_NODE_SPANS];
+        let mut count = 0;
+        for &(node, base, size) in ranges {
+            let start = (align_up(base, PAGE_SIZE as u64) / PAGE_SIZE as u64).min(total_pages as u64) as usize;
+            let end = (align_down(base.saturating_add(size), PAGE_SIZE as u64) / PAGE_SIZE as u64).min(total_pages as u64) as usize;
+            if start < end {
+                spans[count] = NodeSpan { node, start, end };
+                count += 1;
+            }
+        }
+        spans[..count].sort_unstable_by_key(|span| span.start);
+        if spans[..count].windows(2).any(|pair| pair[0].end > pair[1].start) {
+            return Err("Overlapping NUMA memory ranges");
+        }
+        inner.spans = spans;
+        inner.span_count = count;
+        inner.node_pages = [NodeMemoryStats::default(); MAX_NUMA_NODES];
+        inner.counted_pages = 0;
+        drop(inner);
+
+        // Count every node's pages from the bitmap
+        let mut start = 0;
+        while start < total_pages {
+            let end = (start + RECOUNT_CHUNK).min(total_pages);
+            let mut inner = self.inner.lock();
+            for page in start..end {
+                let node = Self::node_of_page(&inner, page);
+                let free = unsafe { !Self::is_page_used_inner(&inner, page) };
+                let stats = &mut inner.node_pages[node];
+                stats.total_pages += 1;
+                stats.free_pages += free as usize;
+            }
+            inner.counted_pages = end;
+            drop(inner);
+            crate::preempt::cond_resched();
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Internal helper: The node a page belongs to
+    fn node_of_page(inner: &PhysicalMemoryManagerInner, page: usize) -> usize {
+        let spans = &inner.spans[..inner.span_count];
+        let at = spans.partition_point(|span| span.end <= page);
+        spans.get(at).filter(|span| span.start <= page).map_or(0, |span| span.node)
+    }
+
+    /// Internal helper: Checks whether a page is marked used (no locking)
+    unsafe fn is_page_used_inner(inner: &PhysicalMemoryManagerInner, page: usize) -> bool {
+        let entry = inner.bitmap.add(page / BITS_PER_ENTRY).read_volatile();
+        entry & (1u64 << (page % BITS_PER_ENTRY)) != 0
+    }
+
+    /// Internal helper: Takes the lowest free page in `start..end` (no locking)
+    fn take_free_page(inner: &mut PhysicalMemoryManagerInner, start: usize, end: usize) -> Option<u64> {
+        let end = end.min(inner.total_pages);
+        if start >= end || inner.free_pages == 0 {
+            return None;
+        }
+
+        for entry_idx in start / BITS_PER_ENTRY..end.div_ceil(BITS_PER_ENTRY) {
+            unsafe {
+                let entry_ptr = inner.bitmap.add(entry_idx);
+                let entry = entry_ptr.read_volatile();
+
+                // Free pages of this entry inside the range
+                let first = entry_idx * BITS_PER_ENTRY;
+                let mut free = !entry;
+                if first < start {
+                    free &= !0u64 << (start - first);
+                }
+                if end - first < BITS_PER_ENTRY {
+                    free &= (1u64 << (end - first)) - 1;
+                }
+                if free == 0 {
+                    continue;
+                }
+
+                // Mark as used
+                let bit_idx = free.trailing_zeros() as usize;
+                entry_ptr.write_volatile(entry | (1u64 << bit_idx));
+
+                // Update free counts
+                let page = first + bit_idx;
+                inner.free_pages -= 1;
+                if page < inner.counted_pages {
+                    let node = Self::node_of_page(inner, page);
+                    inner.node_pages[node].free_pages -= 1;
+                }
+
+                // Return physical address
+                return Some((page * PAGE_SIZE) as u64);
+            }
+        }
+
+        None
     }
 
     /// Internal helper: Marks a page as free in the bitmap (no locking)
@@ -175,43 +342,39 @@ impl PhysicalMemoryManager {
     /// Returns the physical address of the allocated page, or None if no pages are available.
     /// This method is thread-safe and can be called from multiple CPUs concurrently.
     pub fn alloc_page(&self) -> Option<u64> {
+        self.alloc_page_below(u64::MAX)
+    }
+
+    /// Allocates a single physical page below the physical address `limit`
+    ///
+    /// For hardware that can only reach low memory, such as code the CPU
+    /// runs in real mode. Pages are handed out lowest first, so low pages
+    /// are best claimed early in boot.
+    pub fn alloc_page_below(&self, limit: u64) -> Option<u64> {
         let mut inner = self.inner.lock();
-        
-        // Check if we have free pages
-        if inner.free_pages == 0 {
-            return None;
-        }
+        let limit_pages = (limit / PAGE_SIZE as u64).min(inner.total_pages as u64) as usize;
+        Self::take_free_page(&mut inner, 0, limit_pages)
+    }
 
-        // Search for a free page
-        for entry_idx in 0..inner.bitmap_entries {
-            unsafe {
-                let entry_ptr = inner.bitmap.add(entry_idx);
-                let entry = entry_ptr.read_volatile();
+    /// Allocates a single physical page from NUMA node `node`
+    ///
+    /// Returns None if the node has no free page; the caller picks the
+    /// next node to try.
+    pub fn alloc_page_on_node(&self, node: usize) -> Option<u64> {
+        let mut inner = self.inner.lock();
+        if inner.span_count == 0 {
+            let total_pages = inner.total_pages;
+            return if node == 0 { Self::take_free_page(&mut inner, 0, total_pages) } else { None };
+        }
 
-                // If entry is not all 1s, there's at least one free page
-                if entry != !0 {
-                    // Find the first free bit
-                    for bit_idx in 0..BITS_PER_ENTRY {
-                        if (entry & (1u64 << bit_idx)) == 0 {
-                            // Found a free page
-                            let page = entry_idx * BITS_PER_ENTRY + bit_idx;
-                            if page < inner.total_pages {
-                                // Mark as used
-                                let new_entry = entry | (1u64 << bit_idx);
-                                entry_ptr.write_volatile(new_entry);
-
-                                // Update free count
-                                inner.free_pages -= 1;
-
-                                // Return physical address
-                                return Some((page * PAGE_SIZE) as u64);
-                            }
-                        }
-                    }
+        for i in 0..inner.span_count {
+            let span = inner.spans[i];
+            if span.node == node {
+                if let Some(addr) = Self::take_free_page(&mut inner, span.start, span.end) {
+                    return Some(addr);
                 }
             }
         }
-
         None
     }
 
@@ -255,6 +418,10 @@ impl PhysicalMemoryManager {
             
             inner.free_pages += 1;
         }
+        if page < inner.counted_pages {
+            let node = Self::node_of_page(&inner, page);
+            inner.node_pages[node].free_pages += 1;
+        }
     }
 
     /// Returns the number of free pages
@@ -262,6 +429,28 @@ impl PhysicalMemoryManager {
         self.inner.lock().free_pages
     }
 
+    /// A PMM over `bitmap` with pages `free` free, for tests
+    #[cfg(test)]
+    pub(crate) fn with_free_pages(bitmap: &mut [u64], free: core::ops::Range<usize>) -> Self {
+        let pmm = Self::new();
+        {
+            let mut inner = pmm.inner.lock();
+            inner.bitmap = bitmap.as_mut_ptr();
+            inner.bitmap_entries = bitmap.len();
+            inner.total_pages = bitmap.len() * BITS_PER_ENTRY;
+            for page in 0..inner.total_pages {
+                unsafe { Self::mark_page_used_inner(&mut inner, page) };
+            }
+            for page in free.clone() {
+                unsafe { Self::mark_page_free_inner(&mut inner, page) };
+            }
+            inner.free_pages = free.len();
+            inner.node_pages[0] = NodeMemoryStats { total_pages: inner.total_pages, free_pages: free.len() };
+            inner.counted_pages = inner.total_pages;
+        }
+        pmm
+    }
+
     /// Returns the total number of pages managed
     pub fn total_pages(&self) -> usize {
         self.inner.lock().total_pages
@@ -272,4 +461,58 @@ impl PhysicalMemoryManager {
         let inner = self.inner.lock();
         inner.total_pages.saturating_sub(inner.free_pages)
     }
+
+    /// Returns the page counts of NUMA node `node`
+    pub fn node_stats(&self, node: usize) -> NodeMemoryStats {
+        self.inner.lock().node_pages.get(node).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pmm(bitmap: &mut [u64], free: core::ops::Range<usize>) -> PhysicalMemoryManager {
+        PhysicalMemoryManager::with_free_pages(bitmap, free)
+    }
+
+    #[test]
+    fn test_alloc_free() {
+        let mut bitmap = [0u64; 2];
+        let pmm = pmm(&mut bitmap, 10..70);
+        assert_eq!(pmm.alloc_page(), Some(10 * PAGE_SIZE as u64));
+        assert_eq!(pmm.alloc_page_below(11 * PAGE_SIZE as u64), None);
+        assert_eq!(pmm.free_pages(), 59);
+
+        pmm.free_page(10 * PAGE_SIZE as u64);
+        pmm.free_page(10 * PAGE_SIZE as u64);
+        assert_eq!(pmm.free_pages(), 60);
+        assert_eq!(pmm.node_stats(0).free_pages, 60);
+    }
+
+    #[test]
+    fn test_node_pools() {
+        let mut bitmap = [0u64; 2];
+        let pmm = pmm(&mut bitmap, 10..100);
+        let page = |n: u64| n * PAGE_SIZE as u64;
+
+        // Before the ranges are set everything is node 0
+        assert_eq!(pmm.alloc_page_on_node(1), None);
+
+        // Node 1 holds pages 64..128, node 0 pages 0..64
+        pmm.set_node_ranges(&[(1, page(64), page(64)), (0, 0, page(64))]).unwrap();
+        assert_eq!(pmm.node_stats(0), NodeMemoryStats { total_pages: 64, free_pages: 54 });
+        assert_eq!(pmm.node_stats(1), NodeMemoryStats { total_pages: 64, free_pages: 36 });
+
+        assert_eq!(pmm.alloc_page_on_node(1), Some(page(64)));
+        assert_eq!(pmm.node_stats(1).used_pages(), 29);
+        assert_eq!(pmm.alloc_page(), Some(page(10)));
+        assert_eq!(pmm.node_stats(0).free_pages, 53);
+        pmm.free_page(page(64));
+        assert_eq!(pmm.node_stats(1).free_pages, 36);
+        assert_eq!(pmm.alloc_page_on_node(2), None);
+
+        assert_eq!(pmm.set_node_ranges(&[(0, 0, page(65)), (1, page(64), page(64))]), Err("Overlapping NUMA memory ranges"));
+        assert_eq!(pmm.set_node_ranges(&[(MAX_NUMA_NODES, 0, page(1))]), Err("Invalid NUMA node"));
+    }
 }
@@ -4,4 +4,4 @@
 
 pub mod bitmap;
 
-pub use bitmap::PhysicalMemoryManager;
+pub use bitmap::{PhysicalMemoryManager, NodeMemoryStats};
@@ -4,12 +4,13 @@
 //! - Guard pages for stack overflow detection
 //! - Memory region access control
 //! - Per-process memory isolation
+//! - A boot check that kernel data is mapped non-executable
 
 extern crate alloc;
 use alloc::collections::BTreeSet;
 use spin::Mutex;
 use super::addr::{VirtAddr, PAGE_SIZE};
-use super::paging::PageTableFlags;
+use super::paging::{PageTableFlags, PageTableMapper};
 
 /// Guard page manager
 ///
@@ -270,6 +271,33 @@ pub fn check_memory_access(addr: VirtAddr, write: bool, exec: bool) -> bool {
     PROTECTION_MANAGER.lock().check_access(addr, write, exec)
 }
 
+/// Check that kernel data is mapped non-executable in the current page tables
+///
+/// # Arguments
+/// * `hhdm_offset` - Offset of the direct map used to reach the page tables
+/// * `targets` - Named addresses that must not be executable
+///
+/// # Returns
+/// The number of targets that are executable or not mapped
+pub fn verify_kernel_nx(hhdm_offset: u64, targets: &[(&'static str, u64)]) -> usize {
+    let mapper = PageTableMapper::from_pml4(PageTableMapper::current_cr3() & !0xFFF, hhdm_offset);
+    let mut failures = 0;
+    for &(name, addr) in targets {
+        match mapper.effective_flags(addr) {
+            Some(flags) if flags.contains(PageTableFlags::NO_EXECUTE) => {}
+            Some(_) => {
+                crate::log_warn!(target: "nx", "{} at 0x{:x} is executable", name, addr);
+                failures += 1;
+            }
+            None => {
+                crate::log_warn!(target: "nx", "{} at 0x{:x} is not mapped", name, addr);
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,308 @@
+//! User Memory Areas
+//!
+//! Records which regions of its address space each process has mapped and
+//! with what protection. System calls check user pointers against the
+//! caller's areas before the kernel touches them, so a process can only
+//! hand the kernel memory it could access itself. An area can also carry
+//! the NUMA policy mbind gave it.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use super::addr::PAGE_SIZE;
+use super::mmap::MmapProt;
+use crate::numa::NumaMemoryPolicy;
+use crate::task::TaskId;
+use fanga_arch_x86_64::uaccess::Access;
+
+/// A mapped region of a process's address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    /// First address, page aligned
+    pub start: u64,
+    /// Address past the end, page aligned
+    pub end: u64,
+    pub prot: MmapProt,
+    /// NUMA policy of the area, None for the task's
+    pub policy: Option<NumaMemoryPolicy>,
+}
+
+/// The mapped regions of one address space, by start address
+#[derive(Debug, Clone, Default)]
+pub struct VmaMap {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaMap {
+    pub const fn new() -> Self {
+        Self { areas: BTreeMap::new() }
+    }
+
+    /// Add `start..start + len`, widened to whole pages
+    pub fn insert(&mut self, start: u64, len: u64, prot: MmapProt) -> Result<(), &'static str> {
+        let end = start.checked_add(len).ok_or("Area wraps around")?;
+        let mask = PAGE_SIZE as u64 - 1;
+        let start = start & !mask;
+        let end = end.checked_add(mask).ok_or("Area wraps around")? & !mask;
+        if start == end {
+            return Err("Empty area");
+        }
+        let overlaps = self.areas.range(..end).next_back().is_some_and(|(_, vma)| vma.end > start);
+        if overlaps {
+            return Err("Area overlaps a mapping");
+        }
+        self.areas.insert(start, Vma { start, end, prot, policy: None });
+        Ok(())
+    }
+
+    /// Remove `start..start + len`, splitting areas it cuts through
+    pub fn remove(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        let cut: alloc::vec::Vec<Vma> = self
+            .areas
+            .range(..end)
+            .map(|(_, vma)| *vma)
+            .filter(|vma| vma.end > start)
+            .collect();
+        for vma in cut {
+            self.areas.remove(&vma.start);
+            if vma.start < start {
+                self.areas.insert(vma.start, Vma { end: start, ..vma });
+            }
+            if vma.end > end {
+                self.areas.insert(end, Vma { start: end, ..vma });
+            }
+        }
+    }
+
+    /// Give `start..start + len` a NUMA policy, splitting areas it cuts
+    /// through
+    ///
+    /// The whole range must be mapped.
+    pub fn set_policy(&mut self, start: u64, len: u64, policy: Option<NumaMemoryPolicy>) -> Result<(), &'static str> {
+        let end = start.checked_add(len).ok_or("Area wraps around")?;
+        let mask = PAGE_SIZE as u64 - 1;
+        let end = end.checked_add(mask).ok_or("Area wraps around")? & !mask;
+        let start = start & !mask;
+        if start == end {
+            return Ok(());
+        }
+        if !self.check(start, (end - start) as usize, MmapProt::NONE) {
+            return Err("Range is not mapped");
+        }
+
+        let cut: alloc::vec::Vec<Vma> = self
+            .areas
+            .range(..end)
+            .map(|(_, vma)| *vma)
+            .filter(|vma| vma.end > start)
+            .collect();
+        for vma in cut {
+            self.remove(vma.start, vma.end - vma.start);
+            if vma.start < start {
+                self.areas.insert(vma.start, Vma { end: start, ..vma });
+            }
+            if vma.end > end {
+                self.areas.insert(end, Vma { start: end, ..vma });
+            }
+            let (from, to) = (vma.start.max(start), vma.end.min(end));
+            self.areas.insert(from, Vma { start: from, end: to, policy, ..vma });
+        }
+        Ok(())
+    }
+
+    /// Start of the highest unmapped range of `len` bytes, a whole number
+    /// of pages, that ends at or below `ceiling`
+    ///
+    /// The first page is never handed out, so null stays unmapped.
+    pub fn find_free(&self, len: u64, ceiling: u64) -> Option<u64> {
+        let mut top = ceiling & !(PAGE_SIZE as u64 - 1);
+        for vma in self.areas.range(..top).rev().map(|(_, vma)| vma) {
+            if vma.end <= top && top - vma.end >= len {
+                return Some(top - len);
+            }
+            top = top.min(vma.start);
+        }
+        top.checked_sub(len).filter(|&start| start >= PAGE_SIZE as u64)
+    }
+
+    /// The area containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| addr < vma.end)
+    }
+
+    /// Whether `addr..addr + len` is mapped throughout with `prot`
+    pub fn check(&self, addr: u64, len: usize, prot: MmapProt) -> bool {
+        let Some(end) = addr.checked_add(len as u64) else { return false };
+        let mut cursor = addr;
+        loop {
+            let Some(vma) = self.find(cursor) else { return false };
+            if vma.prot.bits() & prot.bits() != prot.bits() {
+                return false;
+            }
+            if vma.end >= end {
+                return true;
+            }
+            cursor = vma.end;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+}
+
+/// Memory maps of the processes that have one
+static MAPS: Mutex<BTreeMap<TaskId, VmaMap>> = Mutex::new(BTreeMap::new());
+
+/// Give a process its memory map, replacing the one it had
+pub fn set_map(task: TaskId, map: VmaMap) {
+    MAPS.lock().insert(task, map);
+}
+
+/// Drop a process's memory map
+pub fn remove_map(task: TaskId) {
+    MAPS.lock().remove(&task);
+}
+
+/// A copy of a process's memory map
+pub fn map_of(task: TaskId) -> Option<VmaMap> {
+    MAPS.lock().get(&task).cloned()
+}
+
+/// Run `f` on a process's memory map, with the maps locked
+pub fn with_map<R>(task: TaskId, f: impl FnOnce(&mut VmaMap) -> R) -> Option<R> {
+    MAPS.lock().get_mut(&task).map(f)
+}
+
+/// Give a range of a process's memory a NUMA policy
+pub fn set_range_policy(task: TaskId, start: u64, len: u64, policy: Option<NumaMemoryPolicy>) -> Result<(), &'static str> {
+    MAPS.lock()
+        .get_mut(&task)
+        .ok_or("No memory map")?
+        .set_policy(start, len, policy)
+}
+
+/// NUMA policy mbind gave the area of a process containing `addr`
+pub fn policy_at(task: TaskId, addr: u64) -> Option<NumaMemoryPolicy> {
+    MAPS.lock().get(&task)?.find(addr)?.policy
+}
+
+/// Check a user range for the current task
+///
+/// Registered with `uaccess::set_access_check`. Tasks without a memory map
+/// (kernel tasks and programs loaded before maps were kept) only get the
+/// range check of `access_ok`.
+pub fn check_user_access(addr: u64, len: usize, access: Access) -> bool {
+    let Some(task) = crate::percpu!(current_task) else { return true };
+    let prot = match access {
+        Access::Read => MmapProt::READ,
+        Access::Write => MmapProt::WRITE,
+    };
+    match MAPS.lock().get(&TaskId::new(task)) {
+        Some(map) => len == 0 || map.check(addr, len, prot),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rw() -> MmapProt {
+        MmapProt::READ.with(MmapProt::WRITE)
+    }
+
+    #[test]
+    fn test_vma_insert() {
+        let mut map = VmaMap::new();
+        map.insert(0x1000, 0x2000, rw()).unwrap();
+        map.insert(0x3000, 0x10, MmapProt::READ).unwrap();
+        assert_eq!(map.find(0x3fff).map(|vma| vma.end), Some(0x4000));
+        assert!(map.insert(0x2800, 0x1000, rw()).is_err());
+        assert!(map.insert(0, 0x1001, rw()).is_err());
+        assert!(map.find(0x4000).is_none());
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_vma_check() {
+        let mut map = VmaMap::new();
+        map.insert(0x1000, 0x1000, rw()).unwrap();
+        map.insert(0x2000, 0x1000, MmapProt::READ).unwrap();
+        map.insert(0x5000, 0x1000, rw()).unwrap();
+
+        assert!(map.check(0x1800, 0x1000, MmapProt::READ));
+        assert!(!map.check(0x1800, 0x1000, MmapProt::WRITE));
+        assert!(map.check(0x1800, 0x800, MmapProt::WRITE));
+        // A hole between the areas
+        assert!(!map.check(0x2800, 0x3000, MmapProt::READ));
+        assert!(!map.check(0x800, 0x10, MmapProt::READ));
+        assert!(!map.check(u64::MAX - 4, 8, MmapProt::READ));
+    }
+
+    #[test]
+    fn test_vma_find_free() {
+        let mut map = VmaMap::new();
+        map.insert(0x8000, 0x1000, rw()).unwrap();
+        map.insert(0x5000, 0x2000, rw()).unwrap();
+        assert_eq!(map.find_free(0x1000, 0x9000), Some(0x7000));
+        // The ceiling is rounded down, leaving both gaps above 0x5000 short
+        assert_eq!(map.find_free(0x2000, 0xa800), Some(0x3000));
+        assert_eq!(map.find_free(0x4000, 0x9000), Some(0x1000));
+        // Only by taking the null page
+        assert_eq!(map.find_free(0x5000, 0x9000), None);
+    }
+
+    #[test]
+    fn test_vma_remove_splits() {
+        let mut map = VmaMap::new();
+        map.insert(0x1000, 0x4000, rw()).unwrap();
+        map.remove(0x2000, 0x1000);
+        assert_eq!(map.len(), 2);
+        assert!(map.check(0x1000, 0x1000, MmapProt::READ));
+        assert!(!map.check(0x2000, 1, MmapProt::READ));
+        assert_eq!(map.find(0x4000).map(|vma| (vma.start, vma.end)), Some((0x3000, 0x5000)));
+    }
+
+    #[test]
+    fn test_vma_set_policy() {
+        use crate::numa::NumaNodeId;
+
+        let mut map = VmaMap::new();
+        map.insert(0x1000, 0x4000, rw()).unwrap();
+        map.insert(0x5000, 0x1000, MmapProt::READ).unwrap();
+        let policy = NumaMemoryPolicy::bind(&[NumaNodeId::new(1)]);
+        map.set_policy(0x2000, 0x3800, Some(policy)).unwrap();
+
+        let areas: alloc::vec::Vec<_> = map.iter().map(|vma| (vma.start, vma.end, vma.policy.is_some())).collect();
+        assert_eq!(areas, [(0x1000, 0x2000, false), (0x2000, 0x5000, true), (0x5000, 0x6000, true)]);
+        assert_eq!(map.find(0x5000).map(|vma| vma.prot), Some(MmapProt::READ));
+        assert!(map.set_policy(0x5000, 0x2000, None).is_err());
+        map.set_policy(0x2000, 0x1000, None).unwrap();
+        assert_eq!(map.find(0x2000).and_then(|vma| vma.policy), None);
+        assert_eq!(map.find(0x3000).and_then(|vma| vma.policy), Some(policy));
+    }
+
+    #[test]
+    fn test_map_registry() {
+        let task = TaskId::new(0x7a3a);
+        let mut map = VmaMap::new();
+        map.insert(0x40_0000, 0x1000, MmapProt::READ).unwrap();
+        set_map(task, map);
+        assert_eq!(map_of(task).map(|map| map.len()), Some(1));
+        remove_map(task);
+        assert!(map_of(task).is_none());
+    }
+}
@@ -1,8 +1,12 @@
 //! ARP (Address Resolution Protocol) implementation
 //!
-//! Provides address resolution between IP addresses and MAC addresses
+//! Provides address resolution between IP addresses and MAC addresses.
+//! Cache entries expire `ARP_ENTRY_TTL_NS` after they were learned, except
+//! permanent ones added by hand.
 
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
 use super::ethernet::MacAddress;
 
 /// IPv4 address structure
@@ -24,6 +28,64 @@ impl Ipv4Address {
     pub fn from_be_u32(value: u32) -> Self {
         Self(value.to_be_bytes())
     }
+
+    /// The unspecified address 0.0.0.0
+    pub const fn unspecified() -> Self {
+        Self([0; 4])
+    }
+
+    /// The limited broadcast address 255.255.255.255
+    pub const fn broadcast() -> Self {
+        Self([255; 4])
+    }
+
+    /// Netmask with the first `prefix_len` bits set
+    pub fn netmask(prefix_len: u8) -> Self {
+        match prefix_len {
+            0 => Self::unspecified(),
+            len => Self::from_be_u32(u32::MAX << (32 - u32::from(len.min(32)))),
+        }
+    }
+
+    /// Number of leading one bits, the prefix length of a netmask
+    pub fn prefix_len(&self) -> u8 {
+        self.to_be_u32().leading_ones() as u8
+    }
+
+    /// Check whether this is a netmask: ones followed only by zeros
+    pub fn is_netmask(&self) -> bool {
+        *self == Self::netmask(self.prefix_len())
+    }
+
+    /// The address with the bits outside `netmask` cleared
+    pub fn network(&self, netmask: Ipv4Address) -> Self {
+        Self::from_be_u32(self.to_be_u32() & netmask.to_be_u32())
+    }
+
+    /// The address with the bits outside `netmask` set
+    pub fn broadcast_in(&self, netmask: Ipv4Address) -> Self {
+        Self::from_be_u32(self.to_be_u32() | !netmask.to_be_u32())
+    }
+
+    /// Parse dotted-quad notation such as `10.0.2.15`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut bytes = [0u8; 4];
+        let mut parts = text.split('.');
+        for byte in &mut bytes {
+            *byte = parts.next()?.parse().ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Self(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
 }
 
 /// ARP operation codes
@@ -67,11 +129,31 @@ pub struct ArpPacket {
     pub target_proto_addr: [u8; 4],
 }
 
+/// How long a learned address stays valid (60 seconds)
+pub const ARP_ENTRY_TTL_NS: u64 = 60_000_000_000;
+
 /// ARP cache entry
 #[derive(Debug, Clone, Copy)]
 struct ArpCacheEntry {
     mac_address: MacAddress,
-    // Future: add timestamp for expiration
+    /// `ktime_ns()` when the entry was learned
+    updated_ns: u64,
+    /// Added by hand; never expires or gets replaced by learned addresses
+    permanent: bool,
+}
+
+impl ArpCacheEntry {
+    fn is_expired(&self, now_ns: u64) -> bool {
+        !self.permanent && now_ns.saturating_sub(self.updated_ns) >= ARP_ENTRY_TTL_NS
+    }
+}
+
+/// An ARP cache entry as listed by `arp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpEntry {
+    pub ip_address: Ipv4Address,
+    pub mac_address: MacAddress,
+    pub permanent: bool,
 }
 
 /// ARP cache
@@ -89,18 +171,71 @@ impl ArpCache {
 
     /// Insert an entry into the cache
     pub fn insert(&mut self, ip: Ipv4Address, mac: MacAddress) {
-        self.cache.insert(ip, ArpCacheEntry { mac_address: mac });
+        self.insert_at(ip, mac, crate::task::ktime_ns());
+    }
+
+    /// Insert an entry learned at `now_ns`
+    ///
+    /// A permanent entry for the address is kept.
+    pub fn insert_at(&mut self, ip: Ipv4Address, mac: MacAddress, now_ns: u64) {
+        if self.cache.get(&ip).is_some_and(|entry| entry.permanent) {
+            return;
+        }
+        self.cache.insert(ip, ArpCacheEntry { mac_address: mac, updated_ns: now_ns, permanent: false });
+    }
+
+    /// Insert an entry that never expires
+    pub fn insert_permanent(&mut self, ip: Ipv4Address, mac: MacAddress) {
+        self.cache.insert(ip, ArpCacheEntry { mac_address: mac, updated_ns: 0, permanent: true });
+    }
+
+    /// Remove the entry for an address, returning whether there was one
+    pub fn remove(&mut self, ip: &Ipv4Address) -> bool {
+        self.cache.remove(ip).is_some()
+    }
+
+    /// Entries still valid at `now_ns`, by address
+    pub fn entries_at(&self, now_ns: u64) -> Vec<ArpEntry> {
+        self.cache
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now_ns))
+            .map(|(&ip_address, entry)| ArpEntry {
+                ip_address,
+                mac_address: entry.mac_address,
+                permanent: entry.permanent,
+            })
+            .collect()
     }
 
     /// Lookup an IP address in the cache
     pub fn lookup(&self, ip: &Ipv4Address) -> Option<MacAddress> {
-        self.cache.get(ip).map(|entry| entry.mac_address)
+        self.lookup_at(ip, crate::task::ktime_ns())
+    }
+
+    /// Lookup an IP address, ignoring entries expired at `now_ns`
+    pub fn lookup_at(&self, ip: &Ipv4Address, now_ns: u64) -> Option<MacAddress> {
+        self.cache
+            .get(ip)
+            .filter(|entry| !entry.is_expired(now_ns))
+            .map(|entry| entry.mac_address)
+    }
+
+    /// Drop entries expired at `now_ns`, returning how many were removed
+    pub fn expire(&mut self, now_ns: u64) -> usize {
+        let before = self.cache.len();
+        self.cache.retain(|_, entry| !entry.is_expired(now_ns));
+        before - self.cache.len()
     }
 
     /// Clear the cache
     pub fn clear(&mut self) {
         self.cache.clear();
     }
+
+    /// Drop the learned entries, keeping the permanent ones
+    pub fn flush(&mut self) {
+        self.cache.retain(|_, entry| entry.permanent);
+    }
 }
 
 /// ARP packet parser
@@ -232,6 +367,62 @@ mod tests {
         assert!(cache.lookup(&ip).is_none());
     }
 
+    #[test]
+    fn test_arp_cache_expiry() {
+        let mut cache = ArpCache::new();
+        let ip = Ipv4Address::new(10, 0, 2, 2);
+        let mac = MacAddress::new([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+        cache.insert_at(ip, mac, 1_000);
+        assert_eq!(cache.lookup_at(&ip, ARP_ENTRY_TTL_NS), Some(mac));
+        assert!(cache.lookup_at(&ip, 1_000 + ARP_ENTRY_TTL_NS).is_none());
+
+        assert_eq!(cache.expire(ARP_ENTRY_TTL_NS), 0);
+        assert_eq!(cache.expire(1_000 + ARP_ENTRY_TTL_NS), 1);
+        assert!(cache.lookup_at(&ip, 0).is_none());
+    }
+
+    #[test]
+    fn test_arp_cache_permanent() {
+        let mut cache = ArpCache::new();
+        let ip = Ipv4Address::new(10, 0, 2, 2);
+        let fixed = MacAddress::new([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+        cache.insert_permanent(ip, fixed);
+        cache.insert_at(ip, MacAddress::broadcast(), 0);
+        cache.insert_at(Ipv4Address::new(10, 0, 2, 3), fixed, 0);
+        assert_eq!(cache.lookup_at(&ip, 2 * ARP_ENTRY_TTL_NS), Some(fixed));
+        assert_eq!(cache.entries_at(0).len(), 2);
+
+        cache.flush();
+        assert_eq!(
+            cache.entries_at(0),
+            [ArpEntry { ip_address: ip, mac_address: fixed, permanent: true }]
+        );
+        assert!(cache.remove(&ip));
+        assert!(!cache.remove(&ip));
+    }
+
+    #[test]
+    fn test_ipv4_address_text_and_masks() {
+        let ip = Ipv4Address::parse("10.0.2.15").unwrap();
+        assert_eq!(ip, Ipv4Address::new(10, 0, 2, 15));
+        assert_eq!(alloc::format!("{}", ip), "10.0.2.15");
+        assert!(Ipv4Address::parse("10.0.2").is_none());
+        assert!(Ipv4Address::parse("10.0.2.256").is_none());
+        assert!(Ipv4Address::parse("10.0.2.15.1").is_none());
+
+        let mask = Ipv4Address::netmask(24);
+        assert_eq!(mask, Ipv4Address::new(255, 255, 255, 0));
+        assert_eq!(mask.prefix_len(), 24);
+        assert!(mask.is_netmask());
+        assert!(!Ipv4Address::new(255, 0, 255, 0).is_netmask());
+        assert_eq!(Ipv4Address::netmask(0), Ipv4Address::unspecified());
+        assert_eq!(Ipv4Address::netmask(32), Ipv4Address::broadcast());
+        assert_eq!(ip.network(mask), Ipv4Address::new(10, 0, 2, 0));
+        assert_eq!(ip.broadcast_in(mask), Ipv4Address::new(10, 0, 2, 255));
+    }
+
     #[test]
     fn test_arp_request_build() {
         let sender_mac = MacAddress::new([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
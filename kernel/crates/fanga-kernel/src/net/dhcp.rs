@@ -245,17 +245,106 @@ impl DhcpClient {
     }
 
     /// Start DHCP discovery process
-    pub fn start_discovery(&mut self) {
+    ///
+    /// Drops the current lease and returns the DISCOVER packet to
+    /// broadcast. Each discovery uses a new transaction ID, so late replies
+    /// to an earlier one are ignored.
+    pub fn start_discovery(&mut self) -> Vec<u8> {
         self.state = DhcpState::Selecting;
-        // Build and send DISCOVER packet
-        let _discover = self.build_discover();
-        // TODO: Send through network stack
+        self.config = None;
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        self.build_discover()
     }
 
     /// Handle received DHCP packet
-    pub fn handle_packet(&mut self, _packet: &[u8]) -> Result<(), &'static str> {
-        // TODO: Parse packet and handle based on state
-        Err("DHCP packet handling not implemented")
+    ///
+    /// An OFFER while selecting is answered with a REQUEST for the offered
+    /// address; an ACK while requesting binds the lease into `config`.
+    ///
+    /// # Returns
+    /// The packet to broadcast in reply, if any
+    pub fn handle_packet(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+        if packet.len() < 240 || packet[0] != 2 || packet[236..240] != [0x63, 0x82, 0x53, 0x63] {
+            return Err("Not a DHCP reply");
+        }
+        if u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]) != self.transaction_id
+            || packet[28..34] != self.mac_address.0
+        {
+            return Err("DHCP reply for another client");
+        }
+        let offered = Ipv4Address([packet[16], packet[17], packet[18], packet[19]]);
+        let options = DhcpOptions::parse(&packet[240..]);
+
+        match (self.state, options.message_type) {
+            (DhcpState::Selecting, Some(DhcpMessageType::Offer)) => {
+                let server = options.server_id.ok_or("DHCP offer without a server identifier")?;
+                self.state = DhcpState::Requesting;
+                Ok(Some(self.build_request(offered, server)))
+            }
+            (DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding, Some(DhcpMessageType::Ack)) => {
+                self.state = DhcpState::Bound;
+                self.config = Some(DhcpConfig {
+                    ip_address: offered,
+                    // A server that leaves the mask out gets the classful guess
+                    subnet_mask: options.subnet_mask.unwrap_or(Ipv4Address::netmask(24)),
+                    gateway: options.router,
+                    dns_server: options.dns_server,
+                    lease_time: options.lease_time.unwrap_or(0),
+                });
+                Ok(None)
+            }
+            (DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding, Some(DhcpMessageType::Nak)) => {
+                self.state = DhcpState::Init;
+                self.config = None;
+                Err("DHCP server refused the lease")
+            }
+            _ => Err("Unexpected DHCP message"),
+        }
+    }
+}
+
+/// The options of a DHCP reply the client uses
+#[derive(Debug, Default)]
+struct DhcpOptions {
+    message_type: Option<DhcpMessageType>,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_server: Option<Ipv4Address>,
+    lease_time: Option<u32>,
+    server_id: Option<Ipv4Address>,
+}
+
+impl DhcpOptions {
+    /// Parse the options after the magic cookie, stopping at END or at a
+    /// truncated option
+    fn parse(mut data: &[u8]) -> Self {
+        const PAD: u8 = 0;
+        let mut options = Self::default();
+        while let Some((&code, rest)) = data.split_first() {
+            if code == PAD {
+                data = rest;
+                continue;
+            }
+            if code == dhcp_options::END {
+                break;
+            }
+            let Some((&len, rest)) = rest.split_first() else { break };
+            let Some(value) = rest.get(..len as usize) else { break };
+            data = &rest[len as usize..];
+
+            // Lists of addresses give the preferred one first
+            let address = value.first_chunk::<4>().map(|bytes| Ipv4Address(*bytes));
+            match code {
+                dhcp_options::MESSAGE_TYPE => options.message_type = value.first().and_then(|&t| DhcpMessageType::from_u8(t)),
+                dhcp_options::SUBNET_MASK => options.subnet_mask = address,
+                dhcp_options::ROUTER => options.router = address,
+                dhcp_options::DNS_SERVER => options.dns_server = address,
+                dhcp_options::LEASE_TIME => options.lease_time = value.first_chunk::<4>().map(|bytes| u32::from_be_bytes(*bytes)),
+                dhcp_options::SERVER_ID => options.server_id = address,
+                _ => {}
+            }
+        }
+        options
     }
 }
 
@@ -322,4 +411,58 @@ mod tests {
         // Check MAC address
         assert_eq!(&request[28..34], &mac.0);
     }
+
+    /// A server reply to `client`: `message_type` offering 10.0.2.15
+    fn reply(client: &DhcpClient, message_type: DhcpMessageType) -> Vec<u8> {
+        let mut packet = alloc::vec![0u8; 240];
+        packet[0] = 2;
+        packet[4..8].copy_from_slice(&client.transaction_id.to_be_bytes());
+        packet[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        packet[28..34].copy_from_slice(&client.mac_address.0);
+        packet[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+        packet.extend_from_slice(&[dhcp_options::MESSAGE_TYPE, 1, message_type as u8]);
+        packet.extend_from_slice(&[dhcp_options::SUBNET_MASK, 4, 255, 255, 255, 0]);
+        packet.extend_from_slice(&[dhcp_options::ROUTER, 8, 10, 0, 2, 2, 10, 0, 2, 1]);
+        packet.extend_from_slice(&[0, dhcp_options::LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+        packet.extend_from_slice(&[dhcp_options::SERVER_ID, 4, 10, 0, 2, 2]);
+        packet.push(dhcp_options::END);
+        packet
+    }
+
+    #[test]
+    fn test_dhcp_lease() {
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        let mut client = DhcpClient::new(mac);
+        let discover = client.start_discovery();
+        assert_eq!(&discover[4..8], &client.transaction_id.to_be_bytes());
+
+        // A late reply to an earlier discovery is ignored
+        let mut stale = reply(&client, DhcpMessageType::Offer);
+        stale[7] ^= 1;
+        assert!(client.handle_packet(&stale).is_err());
+
+        let request = client.handle_packet(&reply(&client, DhcpMessageType::Offer)).unwrap().unwrap();
+        assert_eq!(client.state, DhcpState::Requesting);
+        assert_eq!(&request[request.len() - 13..request.len() - 7], &[dhcp_options::REQUESTED_IP, 4, 10, 0, 2, 15]);
+
+        assert_eq!(client.handle_packet(&reply(&client, DhcpMessageType::Ack)), Ok(None));
+        assert_eq!(client.state, DhcpState::Bound);
+        let config = client.config.unwrap();
+        assert_eq!(config.ip_address, Ipv4Address::new(10, 0, 2, 15));
+        assert_eq!(config.subnet_mask, Ipv4Address::new(255, 255, 255, 0));
+        assert_eq!(config.gateway, Some(Ipv4Address::new(10, 0, 2, 2)));
+        assert_eq!(config.lease_time, 3600);
+
+        assert_eq!(client.handle_packet(&reply(&client, DhcpMessageType::Ack)), Err("Unexpected DHCP message"));
+    }
+
+    #[test]
+    fn test_dhcp_nak() {
+        let mut client = DhcpClient::new(MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+        client.start_discovery();
+        client.handle_packet(&reply(&client, DhcpMessageType::Offer)).unwrap();
+        assert!(client.handle_packet(&reply(&client, DhcpMessageType::Nak)).is_err());
+        assert_eq!(client.state, DhcpState::Init);
+        assert!(client.config.is_none());
+    }
 }
@@ -3,6 +3,7 @@
 //! Handles Ethernet frame parsing and construction
 
 use alloc::vec::Vec;
+use core::fmt;
 
 /// Ethernet frame structure
 #[repr(C, packed)]
@@ -67,6 +68,30 @@ impl MacAddress {
     pub fn is_multicast(&self) -> bool {
         self.0[0] & 0x01 != 0
     }
+
+    /// Parse colon-separated hex such as `52:54:00:12:34:56`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut bytes = [0u8; 6];
+        let mut parts = text.split(':');
+        for byte in &mut bytes {
+            let part = parts.next()?;
+            if part.is_empty() || part.len() > 2 {
+                return None;
+            }
+            *byte = u8::from_str_radix(part, 16).ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Self(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
 }
 
 /// Ethernet frame parser
@@ -119,6 +144,16 @@ mod tests {
         assert!(multicast.is_multicast());
     }
 
+    #[test]
+    fn test_mac_address_text() {
+        let mac = MacAddress::parse("52:54:00:AB:cd:5").unwrap();
+        assert_eq!(mac, MacAddress::new([0x52, 0x54, 0x00, 0xab, 0xcd, 0x05]));
+        assert_eq!(alloc::format!("{}", mac), "52:54:00:ab:cd:05");
+        assert!(MacAddress::parse("52:54:00:ab:cd").is_none());
+        assert!(MacAddress::parse("52:54:00:ab:cd:05:01").is_none());
+        assert!(MacAddress::parse("52:54:00:ab:cd:xx").is_none());
+    }
+
     #[test]
     fn test_ethertype() {
         assert_eq!(EtherType::from_u16(0x0800), Some(EtherType::IPv4));
@@ -0,0 +1,154 @@
+//! ICMP echo (ping)
+//!
+//! Builds and parses echo requests and replies. The stack answers echo
+//! requests itself and queues the replies it receives until the pinging
+//! code collects them by identifier.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::arp::Ipv4Address;
+use super::ipv4::Ipv4Parser;
+
+/// ICMP message type of an echo reply
+pub const ECHO_REPLY: u8 = 0;
+/// ICMP message type of an echo request
+pub const ECHO_REQUEST: u8 = 8;
+
+/// Replies kept for collection; older ones are dropped first
+const MAX_QUEUED_REPLIES: usize = 64;
+
+/// An echo request or reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoMessage<'a> {
+    /// `ECHO_REQUEST` or `ECHO_REPLY`
+    pub kind: u8,
+    /// Tells the pings of one sender from another's
+    pub identifier: u16,
+    /// Counts the requests of one ping
+    pub sequence: u16,
+    pub payload: &'a [u8],
+}
+
+/// Build an echo request or reply
+pub fn build_echo(kind: u8, identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + payload.len());
+    message.push(kind);
+    // Code
+    message.push(0);
+    // Checksum (placeholder)
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&identifier.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(payload);
+
+    // Same one's complement sum as the IPv4 header, over the whole message
+    let checksum = Ipv4Parser::calculate_checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// Parse an echo request or reply, checking its checksum
+pub fn parse_echo(data: &[u8]) -> Result<EchoMessage<'_>, &'static str> {
+    if data.len() < 8 {
+        return Err("ICMP message too short");
+    }
+    if data[0] != ECHO_REQUEST && data[0] != ECHO_REPLY {
+        return Err("Not an ICMP echo message");
+    }
+    // Summing a message with its checksum in place gives all ones
+    if Ipv4Parser::calculate_checksum(data) != 0 {
+        return Err("Bad ICMP checksum");
+    }
+    Ok(EchoMessage {
+        kind: data[0],
+        identifier: u16::from_be_bytes([data[4], data[5]]),
+        sequence: u16::from_be_bytes([data[6], data[7]]),
+        payload: &data[8..],
+    })
+}
+
+/// A received echo reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoReply {
+    pub from: Ipv4Address,
+    pub identifier: u16,
+    pub sequence: u16,
+    /// Size of the ICMP message
+    pub bytes: usize,
+    /// Time to live left in the IPv4 header
+    pub ttl: u8,
+    /// `ktime_ns()` when it arrived
+    pub received_ns: u64,
+}
+
+static REPLIES: Mutex<VecDeque<EchoReply>> = Mutex::new(VecDeque::new());
+
+/// Queue a received echo reply for `take_replies`
+pub(super) fn queue_reply(reply: EchoReply) {
+    let mut replies = REPLIES.lock();
+    if replies.len() == MAX_QUEUED_REPLIES {
+        replies.pop_front();
+    }
+    replies.push_back(reply);
+}
+
+/// Take the queued echo replies with an identifier, oldest first
+pub fn take_replies(identifier: u16) -> Vec<EchoReply> {
+    let mut replies = REPLIES.lock();
+    let mut taken = Vec::new();
+    replies.retain(|reply| {
+        if reply.identifier == identifier {
+            taken.push(*reply);
+            false
+        } else {
+            true
+        }
+    });
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_round_trip() {
+        let request = build_echo(ECHO_REQUEST, 0x1234, 7, b"abcdefg");
+        let message = parse_echo(&request).unwrap();
+        assert_eq!(message.kind, ECHO_REQUEST);
+        assert_eq!(message.identifier, 0x1234);
+        assert_eq!(message.sequence, 7);
+        assert_eq!(message.payload, b"abcdefg");
+
+        let mut corrupted = request.clone();
+        corrupted[9] ^= 0xff;
+        assert_eq!(parse_echo(&corrupted), Err("Bad ICMP checksum"));
+        assert_eq!(parse_echo(&request[..6]), Err("ICMP message too short"));
+    }
+
+    #[test]
+    fn test_echo_checksum() {
+        // Echo request with identifier 1, sequence 1 and no data
+        assert_eq!(build_echo(ECHO_REQUEST, 1, 1, &[]), [8, 0, 0xf7, 0xfd, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_take_replies() {
+        let reply = |identifier, sequence| EchoReply {
+            from: Ipv4Address::new(10, 0, 2, 2),
+            identifier,
+            sequence,
+            bytes: 64,
+            ttl: 64,
+            received_ns: 0,
+        };
+        queue_reply(reply(0xbeef, 1));
+        queue_reply(reply(0xf00d, 1));
+        queue_reply(reply(0xbeef, 2));
+        let taken = take_replies(0xbeef);
+        assert_eq!(taken.iter().map(|reply| reply.sequence).collect::<Vec<_>>(), [1, 2]);
+        assert!(take_replies(0xbeef).is_empty());
+        assert_eq!(take_replies(0xf00d).len(), 1);
+    }
+}
@@ -1,11 +1,15 @@
 //! IPv4 protocol implementation
 //!
-//! Provides IPv4 packet handling and routing
+//! Provides IPv4 packet handling and routing. The system routing table is
+//! RCU-protected: lookups on the packet path take no lock, and updates
+//! publish a modified copy.
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use spin::Mutex;
 use super::arp::Ipv4Address;
 use super::ethernet::MacAddress;
+use crate::smp::rcu::{rcu_read, RcuCell};
 
 /// IPv4 protocol numbers
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -191,6 +195,7 @@ pub struct RouteEntry {
 }
 
 /// Routing table
+#[derive(Clone)]
 pub struct RoutingTable {
     routes: Vec<RouteEntry>,
 }
@@ -225,12 +230,60 @@ impl RoutingTable {
             })
     }
 
+    /// Remove the route to a network, returning it if there was one
+    pub fn remove_route(&mut self, network: Ipv4Address, netmask: Ipv4Address) -> Option<RouteEntry> {
+        let index = self
+            .routes
+            .iter()
+            .position(|route| route.network == network && route.netmask == netmask)?;
+        Some(self.routes.remove(index))
+    }
+
+    /// All routes, in the order they were added
+    pub fn routes(&self) -> &[RouteEntry] {
+        &self.routes
+    }
+
     /// Clear all routes
     pub fn clear(&mut self) {
         self.routes.clear();
     }
 }
 
+/// System routing table
+static ROUTES: RcuCell<RoutingTable> = RcuCell::empty();
+
+/// Serializes routing table updates
+static ROUTES_UPDATE: Mutex<()> = Mutex::new(());
+
+/// Look up the route for a destination in the system routing table
+///
+/// Lock-free; safe to call for every packet.
+pub fn route_lookup(dst: &Ipv4Address) -> Option<RouteEntry> {
+    let guard = rcu_read();
+    ROUTES.read(&guard)?.lookup(dst).copied()
+}
+
+/// A copy of the routes in the system routing table
+pub fn routes() -> Vec<RouteEntry> {
+    let guard = rcu_read();
+    ROUTES.read(&guard).map(|table| table.routes.clone()).unwrap_or_default()
+}
+
+/// Modify the system routing table
+///
+/// `update` edits a copy, which then replaces the table; readers see
+/// either the old or the new table, never a partial update.
+pub fn update_routes(update: impl FnOnce(&mut RoutingTable)) {
+    let _writer = ROUTES_UPDATE.lock();
+    let mut table = {
+        let guard = rcu_read();
+        ROUTES.read(&guard).cloned().unwrap_or_else(RoutingTable::new)
+    };
+    update(&mut table);
+    ROUTES.replace(table);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -303,5 +356,11 @@ mod tests {
         let remote_dst = Ipv4Address::new(8, 8, 8, 8);
         let route = table.lookup(&remote_dst);
         assert!(route.is_some());
+
+        let removed = table.remove_route(Ipv4Address::new(192, 168, 1, 0), Ipv4Address::new(255, 255, 255, 0));
+        assert!(removed.is_some_and(|route| route.gateway.is_none()));
+        assert!(table.remove_route(Ipv4Address::new(192, 168, 1, 0), Ipv4Address::new(255, 255, 255, 0)).is_none());
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.lookup(&local_dst).unwrap().gateway, Some(Ipv4Address::new(192, 168, 1, 1)));
     }
 }
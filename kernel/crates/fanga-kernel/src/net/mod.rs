@@ -8,6 +8,12 @@
 //! - UDP and TCP protocols
 //! - BSD-style socket API
 //! - DHCP client
+//! - ICMP echo
+//!
+//! The single interface is named `eth0`. Its address, link state, ARP
+//! cache and routes are managed through the functions at the end of this
+//! module, which the shell's network commands use. Received frames are
+//! handled by `poll`, run from a workqueue every `POLL_INTERVAL_MS`.
 
 #![allow(dead_code)]
 
@@ -19,12 +25,72 @@ pub mod udp;
 pub mod tcp;
 pub mod socket;
 pub mod dhcp;
+pub mod icmp;
 
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::smp::{LockClass, SpinLock};
 use alloc::vec::Vec;
+use arp::{ArpEntry, ArpParser, ArpOperation, Ipv4Address};
+use ethernet::{EtherType, EthernetParser, MacAddress};
+use ipv4::{IpProtocol, Ipv4Parser, RouteEntry};
+
+/// Name of the network interface
+pub const INTERFACE_NAME: &str = "eth0";
+
+/// Largest IPv4 packet the interface sends
+pub const MTU: usize = 1500;
+
+/// Time between polls for received frames
+pub const POLL_INTERVAL_MS: u64 = 10;
+
+/// Frames handled per poll, so a flood cannot hold the workqueue
+const POLL_BUDGET: usize = 64;
+
+/// Packets kept while the next hop's address is resolved
+const MAX_PENDING_PACKETS: usize = 16;
+
+/// UDP ports of DHCP servers and clients
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const NO_INTERFACE: &str = "No network interface";
+
+/// Lock class of the network stack, for lockdep and lockstat
+static NETWORK_CLASS: LockClass = LockClass::new("net-stack");
 
 /// Global network stack instance
-static NETWORK_STACK: Mutex<Option<NetworkStack>> = Mutex::new(None);
+static NETWORK_STACK: SpinLock<Option<NetworkStack>> = SpinLock::with_class(None, &NETWORK_CLASS);
+
+/// Address and link state of the interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// IPv4 address, if one is set
+    pub address: Option<Ipv4Address>,
+    pub netmask: Ipv4Address,
+    /// Whether the link is up; a down interface neither sends nor receives
+    pub up: bool,
+}
+
+/// Traffic counters of the interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames received while down or that could not be parsed
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// The interface as shown by `ifconfig`
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceInfo {
+    pub name: &'static str,
+    pub mac_address: MacAddress,
+    pub config: InterfaceConfig,
+    pub stats: InterfaceStats,
+}
 
 /// Main network stack structure
 pub struct NetworkStack {
@@ -32,10 +98,15 @@ pub struct NetworkStack {
     interface: Option<drivers::NetworkInterface>,
     /// ARP cache
     arp_cache: arp::ArpCache,
-    /// IPv4 routing table
-    routing_table: ipv4::RoutingTable,
     /// Active sockets
     sockets: Vec<socket::Socket>,
+    /// Address and link state
+    config: InterfaceConfig,
+    stats: InterfaceStats,
+    /// IPv4 packets waiting for their next hop's MAC address
+    pending: Vec<(Ipv4Address, Vec<u8>)>,
+    /// DHCP client, once a lease was asked for
+    dhcp: Option<dhcp::DhcpClient>,
 }
 
 impl NetworkStack {
@@ -44,8 +115,15 @@ impl NetworkStack {
         Self {
             interface: None,
             arp_cache: arp::ArpCache::new(),
-            routing_table: ipv4::RoutingTable::new(),
             sockets: Vec::new(),
+            config: InterfaceConfig {
+                address: None,
+                netmask: Ipv4Address::unspecified(),
+                up: false,
+            },
+            stats: InterfaceStats::default(),
+            pending: Vec::new(),
+            dhcp: None,
         }
     }
 
@@ -55,6 +133,7 @@ impl NetworkStack {
         match drivers::e1000::E1000Driver::probe() {
             Ok(driver) => {
                 self.interface = Some(drivers::NetworkInterface::E1000(driver));
+                self.config.up = true;
                 Ok(())
             }
             Err(e) => Err(e),
@@ -62,11 +141,231 @@ impl NetworkStack {
     }
 
     /// Get the global network stack instance
-    pub fn get() -> &'static Mutex<Option<NetworkStack>> {
+    pub fn get() -> &'static SpinLock<Option<NetworkStack>> {
         &NETWORK_STACK
     }
+
+    fn mac_address(&self) -> Result<MacAddress, &'static str> {
+        Ok(self.interface.as_ref().ok_or(NO_INTERFACE)?.mac_address())
+    }
+
+    /// Send an Ethernet frame
+    fn transmit(&mut self, dst: MacAddress, ethertype: EtherType, payload: &[u8]) -> Result<(), &'static str> {
+        let src = self.mac_address()?;
+        let frame = EthernetParser::build(dst, src, ethertype, payload);
+        crate::tracepoint!(NET_TX, frame.len(), ethertype as u16);
+        let interface = self.interface.as_mut().ok_or(NO_INTERFACE)?;
+        match interface.send_packet(&frame) {
+            Ok(()) => {
+                self.stats.tx_packets += 1;
+                self.stats.tx_bytes += frame.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                self.stats.tx_errors += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Send an IPv4 packet, resolving the next hop's MAC address
+    ///
+    /// A packet whose next hop is not in the ARP cache is kept until the
+    /// ARP reply arrives.
+    fn send_ipv4(&mut self, dst: Ipv4Address, protocol: IpProtocol, payload: &[u8]) -> Result<(), &'static str> {
+        if !self.config.up {
+            return Err("Network is down");
+        }
+        if 20 + payload.len() > MTU {
+            return Err("Message too long");
+        }
+        if dst == Ipv4Address::broadcast() {
+            // Also sent without an address, as DHCP does
+            let src = self.config.address.unwrap_or(Ipv4Address::unspecified());
+            let packet = Ipv4Parser::build(src, dst, protocol, payload);
+            return self.transmit(MacAddress::broadcast(), EtherType::IPv4, &packet);
+        }
+
+        let src = self.config.address.ok_or("Interface has no address")?;
+        let route = ipv4::route_lookup(&dst).ok_or("Network is unreachable")?;
+        let next_hop = route.gateway.unwrap_or(dst);
+        let packet = Ipv4Parser::build(src, dst, protocol, payload);
+        if next_hop == src.broadcast_in(self.config.netmask) {
+            return self.transmit(MacAddress::broadcast(), EtherType::IPv4, &packet);
+        }
+        match self.arp_cache.lookup(&next_hop) {
+            Some(mac) => self.transmit(mac, EtherType::IPv4, &packet),
+            None => {
+                if self.pending.len() == MAX_PENDING_PACKETS {
+                    self.pending.remove(0);
+                }
+                self.pending.push((next_hop, packet));
+                let request = ArpParser::build_request(self.mac_address()?, src, next_hop);
+                self.transmit(MacAddress::broadcast(), EtherType::ARP, &request)
+            }
+        }
+    }
+
+    /// Handle a received Ethernet frame
+    fn handle_frame(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        let (_, src_mac, ethertype, payload) = EthernetParser::parse(frame)?;
+        match ethertype {
+            EtherType::ARP => self.handle_arp(payload),
+            EtherType::IPv4 => self.handle_ipv4(src_mac, payload),
+            EtherType::IPv6 => Ok(()),
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8]) -> Result<(), &'static str> {
+        let packet = ArpParser::parse(payload)?;
+        let sender_mac = MacAddress(packet.sender_hw_addr);
+        let sender_ip = Ipv4Address(packet.sender_proto_addr);
+        let target_ip = Ipv4Address(packet.target_proto_addr);
+        let ours = self.config.address.is_some_and(|address| address == target_ip);
+
+        if sender_ip != Ipv4Address::unspecified() {
+            self.arp_cache.insert(sender_ip, sender_mac);
+            self.flush_pending(sender_ip);
+        }
+        if ours && ArpOperation::from_u16(packet.operation) == Some(ArpOperation::Request) {
+            let reply = ArpParser::build_reply(self.mac_address()?, target_ip, sender_mac, sender_ip);
+            self.transmit(sender_mac, EtherType::ARP, &reply)?;
+        }
+        Ok(())
+    }
+
+    /// Send the packets that waited for `ip`'s MAC address
+    fn flush_pending(&mut self, ip: Ipv4Address) {
+        let Some(mac) = self.arp_cache.lookup(&ip) else {
+            return;
+        };
+        let (ready, waiting): (Vec<_>, Vec<_>) = core::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(next_hop, _)| *next_hop == ip);
+        self.pending = waiting;
+        for (_, packet) in ready {
+            let _ = self.transmit(mac, EtherType::IPv4, &packet);
+        }
+    }
+
+    fn handle_ipv4(&mut self, _src_mac: MacAddress, payload: &[u8]) -> Result<(), &'static str> {
+        let (header, data) = Ipv4Parser::parse(payload)?;
+        let dst = Ipv4Address(header.dst_addr);
+        let src = Ipv4Address(header.src_addr);
+        let for_us = match self.config.address {
+            Some(address) => dst == address || dst == address.broadcast_in(self.config.netmask),
+            // Without an address only broadcasts get through, for DHCP
+            None => false,
+        } || dst == Ipv4Address::broadcast();
+        if !for_us {
+            return Ok(());
+        }
+        // Ethernet pads short frames; the IPv4 length says where data ends
+        let data = &data[..data.len().min((header.total_length as usize).saturating_sub(header.header_length()))];
+
+        match IpProtocol::from_u8(header.protocol) {
+            Some(IpProtocol::ICMP) => {
+                let message = icmp::parse_echo(data)?;
+                if message.kind == icmp::ECHO_REQUEST {
+                    let reply = icmp::build_echo(icmp::ECHO_REPLY, message.identifier, message.sequence, message.payload);
+                    self.send_ipv4(src, IpProtocol::ICMP, &reply)
+                } else {
+                    icmp::queue_reply(icmp::EchoReply {
+                        from: src,
+                        identifier: message.identifier,
+                        sequence: message.sequence,
+                        bytes: data.len(),
+                        ttl: header.ttl,
+                        received_ns: crate::task::ktime_ns(),
+                    });
+                    Ok(())
+                }
+            }
+            Some(IpProtocol::UDP) => {
+                let (udp_header, udp_data) = udp::UdpParser::parse(data)?;
+                if udp_header.dst_port == DHCP_CLIENT_PORT {
+                    self.handle_dhcp(udp_data)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_dhcp(&mut self, packet: &[u8]) -> Result<(), &'static str> {
+        let Some(client) = self.dhcp.as_mut() else {
+            return Ok(());
+        };
+        let reply = client.handle_packet(packet);
+        let config = client.config.filter(|_| client.state == dhcp::DhcpState::Bound);
+        if let Some(reply) = reply? {
+            return self.send_udp_broadcast(DHCP_CLIENT_PORT, DHCP_SERVER_PORT, &reply);
+        }
+        if let Some(config) = config {
+            self.set_address(config.ip_address, config.subnet_mask.prefix_len())?;
+            if let Some(gateway) = config.gateway {
+                let default = Ipv4Address::unspecified();
+                let mac = self.mac_address()?;
+                ipv4::update_routes(|table| {
+                    table.remove_route(default, default);
+                    table.add_route(RouteEntry { network: default, netmask: default, gateway: Some(gateway), interface_mac: mac });
+                });
+            }
+            crate::log_info!(
+                target: "net",
+                "{}: DHCP lease {}/{} via {} for {}s",
+                INTERFACE_NAME,
+                config.ip_address,
+                config.subnet_mask.prefix_len(),
+                config.gateway.unwrap_or(Ipv4Address::unspecified()),
+                config.lease_time
+            );
+        }
+        Ok(())
+    }
+
+    fn send_udp_broadcast(&mut self, src_port: u16, dst_port: u16, payload: &[u8]) -> Result<(), &'static str> {
+        let datagram = udp::UdpParser::build(src_port, dst_port, payload);
+        self.send_ipv4(Ipv4Address::broadcast(), IpProtocol::UDP, &datagram)
+    }
+
+    /// Set the interface address, replacing the route to its old network
+    /// with one to the new
+    fn set_address(&mut self, address: Ipv4Address, prefix_len: u8) -> Result<(), &'static str> {
+        if prefix_len > 32 {
+            return Err("Invalid prefix length");
+        }
+        let netmask = Ipv4Address::netmask(prefix_len);
+        let mac = self.mac_address()?;
+        let old = self.config.address.map(|old| (old.network(self.config.netmask), self.config.netmask));
+        ipv4::update_routes(|table| {
+            // A route added by hand to the old network stays
+            let connected = old.filter(|&(network, netmask)| {
+                table
+                    .routes()
+                    .iter()
+                    .any(|route| route.network == network && route.netmask == netmask && route.gateway.is_none())
+            });
+            if let Some((network, netmask)) = connected {
+                table.remove_route(network, netmask);
+            }
+            table.remove_route(address.network(netmask), netmask);
+            table.add_route(RouteEntry {
+                network: address.network(netmask),
+                netmask,
+                gateway: None,
+                interface_mac: mac,
+            });
+        });
+        self.config.address = Some(address);
+        self.config.netmask = netmask;
+        Ok(())
+    }
 }
 
+/// Whether the poll work is queued
+static POLLING: AtomicBool = AtomicBool::new(false);
+
 /// Initialize the networking subsystem
 pub fn init() -> Result<(), &'static str> {
     let mut stack = NetworkStack::new();
@@ -74,3 +373,179 @@ pub fn init() -> Result<(), &'static str> {
     *NETWORK_STACK.lock() = Some(stack);
     Ok(())
 }
+
+/// Run a function on the network stack
+#[track_caller]
+fn with_stack<T>(f: impl FnOnce(&mut NetworkStack) -> Result<T, &'static str>) -> Result<T, &'static str> {
+    let mut stack = NETWORK_STACK.lock();
+    f(stack.as_mut().filter(|stack| stack.interface.is_some()).ok_or(NO_INTERFACE)?)
+}
+
+/// Start polling the interface for received frames
+pub fn start_polling() {
+    if !POLLING.swap(true, Ordering::AcqRel) {
+        schedule_poll();
+    }
+}
+
+/// Queue the next poll
+fn schedule_poll() {
+    let work = crate::task::workqueue::Work::new(poll_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, POLL_INTERVAL_MS);
+}
+
+/// Workqueue function for network polling
+fn poll_tick(_: usize) {
+    poll();
+    schedule_poll();
+}
+
+/// Handle the frames the interface received and expire old ARP entries
+///
+/// The stack is unlocked between frames, which is where a long drain
+/// gives the CPU up if a reschedule is pending.
+pub fn poll() {
+    for _ in 0..POLL_BUDGET {
+        if !receive_frame() {
+            break;
+        }
+        crate::preempt::cond_resched();
+    }
+    if let Some(stack) = NETWORK_STACK.lock().as_mut() {
+        stack.arp_cache.expire(crate::task::ktime_ns());
+    }
+}
+
+/// Handle one received frame; false if there was none
+fn receive_frame() -> bool {
+    let mut stack = NETWORK_STACK.lock();
+    let Some(stack) = stack.as_mut() else {
+        return false;
+    };
+    let Some(frame) = stack.interface.as_mut().and_then(|interface| interface.receive_packet()) else {
+        return false;
+    };
+    stack.stats.rx_packets += 1;
+    stack.stats.rx_bytes += frame.len() as u64;
+    crate::tracepoint!(NET_RX, frame.len(), frame.get(12..14).map_or(0, |t| u16::from_be_bytes([t[0], t[1]])));
+    if !stack.config.up || stack.handle_frame(&frame).is_err() {
+        stack.stats.rx_dropped += 1;
+    }
+    true
+}
+
+/// The interface's address, link state and counters
+pub fn interface() -> Result<InterfaceInfo, &'static str> {
+    with_stack(|stack| {
+        Ok(InterfaceInfo {
+            name: INTERFACE_NAME,
+            mac_address: stack.mac_address()?,
+            config: stack.config,
+            stats: stack.stats,
+        })
+    })
+}
+
+/// Set the interface's address and the route to its network
+pub fn set_address(address: Ipv4Address, prefix_len: u8) -> Result<(), &'static str> {
+    with_stack(|stack| stack.set_address(address, prefix_len))
+}
+
+/// Bring the interface up or down
+///
+/// Taking it down forgets the learned ARP entries and the packets waiting
+/// for them.
+pub fn set_up(up: bool) -> Result<(), &'static str> {
+    with_stack(|stack| {
+        stack.config.up = up;
+        if !up {
+            stack.arp_cache.flush();
+            stack.pending.clear();
+        }
+        Ok(())
+    })
+}
+
+/// The valid ARP cache entries, by address
+pub fn arp_entries() -> Result<Vec<ArpEntry>, &'static str> {
+    with_stack(|stack| Ok(stack.arp_cache.entries_at(crate::task::ktime_ns())))
+}
+
+/// Add a permanent ARP entry
+pub fn arp_add(ip: Ipv4Address, mac: MacAddress) -> Result<(), &'static str> {
+    with_stack(|stack| {
+        stack.arp_cache.insert_permanent(ip, mac);
+        stack.flush_pending(ip);
+        Ok(())
+    })
+}
+
+/// Remove an ARP entry
+pub fn arp_remove(ip: Ipv4Address) -> Result<(), &'static str> {
+    with_stack(|stack| match stack.arp_cache.remove(&ip) {
+        true => Ok(()),
+        false => Err("No ARP entry for that address"),
+    })
+}
+
+/// The routes of the system routing table
+pub fn routes() -> Vec<RouteEntry> {
+    ipv4::routes()
+}
+
+/// Add a route to `network`/`prefix_len`, through `gateway` or directly
+/// on the interface
+///
+/// The gateway must be on a network the interface reaches directly.
+pub fn add_route(network: Ipv4Address, prefix_len: u8, gateway: Option<Ipv4Address>) -> Result<(), &'static str> {
+    if prefix_len > 32 {
+        return Err("Invalid prefix length");
+    }
+    let netmask = Ipv4Address::netmask(prefix_len);
+    let network = network.network(netmask);
+    let mac = with_stack(|stack| stack.mac_address())?;
+    if let Some(gateway) = gateway {
+        if ipv4::route_lookup(&gateway).is_none_or(|route| route.gateway.is_some()) {
+            return Err("Gateway is not on a directly reachable network");
+        }
+    }
+    let mut result = Ok(());
+    ipv4::update_routes(|table| {
+        if table.routes().iter().any(|route| route.network == network && route.netmask == netmask) {
+            result = Err("Route already exists");
+        } else {
+            table.add_route(RouteEntry { network, netmask, gateway, interface_mac: mac });
+        }
+    });
+    result
+}
+
+/// Remove the route to `network`/`prefix_len`
+pub fn remove_route(network: Ipv4Address, prefix_len: u8) -> Result<(), &'static str> {
+    let netmask = Ipv4Address::netmask(prefix_len.min(32));
+    let mut removed = None;
+    ipv4::update_routes(|table| removed = table.remove_route(network.network(netmask), netmask));
+    removed.map(|_| ()).ok_or("No such route")
+}
+
+/// Send an ICMP echo request to `dst`
+pub fn send_echo_request(dst: Ipv4Address, identifier: u16, sequence: u16, payload: &[u8]) -> Result<(), &'static str> {
+    let request = icmp::build_echo(icmp::ECHO_REQUEST, identifier, sequence, payload);
+    with_stack(|stack| stack.send_ipv4(dst, IpProtocol::ICMP, &request))
+}
+
+/// Ask for a new DHCP lease, starting over from DISCOVER
+///
+/// The lease is applied when the server's ACK arrives and logged.
+pub fn dhcp_renew() -> Result<(), &'static str> {
+    with_stack(|stack| {
+        let mac = stack.mac_address()?;
+        let discover = stack.dhcp.get_or_insert_with(|| dhcp::DhcpClient::new(mac)).start_discovery();
+        stack.send_udp_broadcast(DHCP_CLIENT_PORT, DHCP_SERVER_PORT, &discover)
+    })
+}
+
+/// State of the DHCP client and its lease, if it was started
+pub fn dhcp_status() -> Result<Option<(dhcp::DhcpState, Option<dhcp::DhcpConfig>)>, &'static str> {
+    with_stack(|stack| Ok(stack.dhcp.as_ref().map(|client| (client.state, client.config))))
+}
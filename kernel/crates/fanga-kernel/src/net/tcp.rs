@@ -247,6 +247,7 @@ impl TcpConnection {
             TcpState::Listen => {
                 if header.has_flag(tcp_flags::SYN) {
                     self.state = TcpState::SynReceived;
+                    self.send_seq = self.initial_seq();
                     self.recv_seq = header.seq_num + 1;
                 }
             }
@@ -285,10 +286,15 @@ impl TcpConnection {
         }
     }
 
+    /// Initial sequence number for this connection's addresses and ports
+    fn initial_seq(&self) -> u32 {
+        crate::random::secure_tcp_seq(self.local_addr.0, self.remote_addr.0, self.local_port, self.remote_port)
+    }
+
     /// Initiate connection (send SYN)
     pub fn connect(&mut self) {
         self.state = TcpState::SynSent;
-        self.send_seq = 1000; // Initial sequence number
+        self.send_seq = self.initial_seq();
     }
 
     /// Close connection
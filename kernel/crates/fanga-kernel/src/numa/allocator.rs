@@ -1,7 +1,72 @@
 //! NUMA-aware Memory Allocator
+//!
+//! Takes pages from the PMM's per-node pools. A preferred node that has no
+//! free page falls back to the other nodes, nearest (by SLIT distance)
+//! first; a strict request fails instead.
 
+extern crate alloc;
+use alloc::vec::Vec;
+use spin::Once;
+
+use super::policy::NumaMemoryPolicy;
 use super::topology::NumaNodeId;
-use crate::memory::{PhysAddr, VirtAddr};
+use crate::memory::pmm::{NodeMemoryStats, PhysicalMemoryManager};
+use crate::memory::{PhysAddr, PAGE_SIZE};
+
+/// The PMM pages come from
+struct PmmRef(&'static PhysicalMemoryManager);
+
+// The PMM is 'static and does its own locking
+unsafe impl Send for PmmRef {}
+unsafe impl Sync for PmmRef {}
+
+static PMM: Once<PmmRef> = Once::new();
+
+/// Give the NUMA allocator the physical memory manager
+///
+/// Called during boot, once the PMM is initialized; `numa::init` splits
+/// it into node pools later.
+pub fn init(pmm: &'static PhysicalMemoryManager) {
+    PMM.call_once(|| PmmRef(pmm));
+}
+
+/// The PMM, or None before `init()`
+pub(super) fn pmm() -> Option<&'static PhysicalMemoryManager> {
+    PMM.get().map(|pmm| pmm.0)
+}
+
+/// Page counts of every NUMA node
+pub fn node_stats() -> Vec<(NumaNodeId, NodeMemoryStats)> {
+    let (Some(pmm), Some(topology)) = (pmm(), super::try_numa_topology()) else {
+        return Vec::new();
+    };
+    let nodes: Vec<NumaNodeId> = topology.lock().nodes().iter().map(|node| node.id).collect();
+    nodes.into_iter().map(|node| (node, pmm.node_stats(node.as_usize()))).collect()
+}
+
+/// Allocate a page under a memory policy
+///
+/// `index` spreads interleaved pages over the policy's nodes; the page
+/// number of the address the page is mapped at keeps the choice stable.
+/// When the policy's nodes are out of pages, memory outside them is used
+/// unless the policy binds. Without NUMA the policy does not matter.
+pub fn alloc_page_with_policy(pmm: &PhysicalMemoryManager, policy: &NumaMemoryPolicy, index: u64) -> Option<u64> {
+    let order = match super::try_numa_topology() {
+        Some(topology) => {
+            let topology = topology.lock();
+            if !topology.is_enabled() {
+                return pmm.alloc_page();
+            }
+            let local = topology.node_for_cpu(crate::smp::current_cpu_id()).unwrap_or(NumaNodeId::new(0));
+            policy.node_order(&topology, local, index)
+        }
+        None => return pmm.alloc_page(),
+    };
+    
+    order.iter()
+        .find_map(|node| pmm.alloc_page_on_node(node.as_usize()))
+        .or_else(|| if policy.is_strict() { None } else { pmm.alloc_page() })
+}
 
 /// Allocation hint for NUMA allocator
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,7 +107,12 @@ impl NumaAllocator {
     }
     
     /// Allocate memory with NUMA hint
+    ///
+    /// Allocations are single pages.
     pub fn alloc(&self, size: usize, hint: AllocHint) -> Result<PhysAddr, &'static str> {
+        if size > PAGE_SIZE {
+            return Err("NUMA allocations are limited to one page");
+        }
         if !self.enabled {
             // Fall back to regular allocation
             return self.alloc_regular(size);
@@ -58,37 +128,46 @@ impl NumaAllocator {
     
     /// Allocate memory from a specific node
     fn alloc_from_node(&self, size: usize, node_id: NumaNodeId, strict: bool) -> Result<PhysAddr, &'static str> {
-        // TODO: Implement actual NUMA-aware allocation
-        // This would integrate with the physical memory manager
+        let pmm = pmm().ok_or("Physical memory manager not initialized")?;
         
-        // For now, fall back to regular allocation
         if strict {
-            // In strict mode, we should only allocate from the specific node
-            // Return error if node has no memory
-            Err("NUMA node has no available memory")
-        } else {
-            // In prefer mode, fall back to any node
-            self.alloc_regular(size)
+            // In strict mode, only the node itself will do
+            return pmm.alloc_page_on_node(node_id.as_usize())
+                .map(PhysAddr::new)
+                .ok_or("NUMA node has no available memory");
         }
+        
+        // In prefer mode, fall back to the nearest node with memory, then
+        // to memory outside every node
+        let order = match super::try_numa_topology() {
+            Some(topology) => topology.lock().fallback_order(node_id),
+            None => alloc::vec![node_id],
+        };
+        order.iter()
+            .find_map(|node| pmm.alloc_page_on_node(node.as_usize()))
+            .map(PhysAddr::new)
+            .map_or_else(|| self.alloc_regular(size), Ok)
     }
     
     /// Allocate memory from local node (current CPU's node)
     fn alloc_local(&self, size: usize) -> Result<PhysAddr, &'static str> {
-        // TODO: Get current CPU's NUMA node and allocate from it
-        self.alloc_regular(size)
+        let cpu = crate::smp::current_cpu_id();
+        let node = super::try_numa_topology()
+            .and_then(|topology| topology.lock().node_for_cpu(cpu))
+            .unwrap_or(NumaNodeId::new(0));
+        self.alloc_from_node(size, node, false)
     }
     
     /// Regular allocation (no NUMA awareness)
     fn alloc_regular(&self, _size: usize) -> Result<PhysAddr, &'static str> {
-        // TODO: Call into physical memory manager
-        Err("Not implemented")
+        let pmm = pmm().ok_or("Physical memory manager not initialized")?;
+        pmm.alloc_page().map(PhysAddr::new).ok_or("Out of physical memory")
     }
     
     /// Free memory allocated with NUMA awareness
-    pub fn free(&self, addr: PhysAddr, size: usize) -> Result<(), &'static str> {
-        // TODO: Implement deallocation
-        // Track which node the memory came from
-        let _ = (addr, size); // Silence unused warnings
+    pub fn free(&self, addr: PhysAddr, _size: usize) -> Result<(), &'static str> {
+        let pmm = pmm().ok_or("Physical memory manager not initialized")?;
+        pmm.free_page(addr.as_u64());
         Ok(())
     }
 }
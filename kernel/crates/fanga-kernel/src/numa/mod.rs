@@ -1,12 +1,16 @@
 //! NUMA (Non-Uniform Memory Access) Support
 //!
 //! This module provides NUMA topology detection and memory allocation optimization.
+//! The topology comes from the ACPI SRAT and SLIT (see `topology`).
 
 pub mod topology;
 pub mod allocator;
 pub mod policy;
 
-pub use topology::{NumaNode, NumaNodeId, NumaTopology, numa_topology};
+pub use topology::{NumaNode, NumaNodeId, NumaMemRange, NumaTopology, numa_topology};
+
+extern crate alloc;
+use alloc::vec::Vec;
 pub use allocator::{NumaAllocator, AllocHint};
 pub use policy::{NumaPolicy, NumaMemoryPolicy};
 
@@ -29,10 +33,60 @@ pub fn init() -> Result<(), &'static str> {
 /// Detect NUMA topology
 fn detect_numa() -> Result<(), &'static str> {
     let mut topology = NUMA_TOPOLOGY.get().ok_or("NUMA topology not initialized")?.lock();
-    topology.detect()
+    topology.detect()?;
+    
+    for node in topology.nodes() {
+        crate::log_info!(
+            target: "numa",
+            "Node {} (domain {}): {} CPUs, {} MiB in {} ranges",
+            node.id.as_usize(),
+            node.domain,
+            node.cpus.len(),
+            node.mem_size / (1024 * 1024),
+            node.mem_ranges.len()
+        );
+    }
+    
+    // Split the physical memory into node pools
+    if let Some(pmm) = allocator::pmm() {
+        let ranges: Vec<(usize, u64, u64)> = topology.nodes().iter()
+            .flat_map(|node| node.mem_ranges.iter().map(move |range| (node.id.as_usize(), range.base, range.size)))
+            .collect();
+        if topology.is_enabled() {
+            pmm.set_node_ranges(&ranges)?;
+        }
+        for node in topology.nodes() {
+            let pages = pmm.node_stats(node.id.as_usize());
+            crate::log_info!(
+                target: "numa",
+                "Node {}: {} of {} pages free",
+                node.id.as_usize(),
+                pages.free_pages,
+                pages.total_pages
+            );
+        }
+    }
+    
+    // Record each CPU's node
+    if let Some(manager) = crate::smp::try_cpu_manager() {
+        let mut manager = manager.lock();
+        for node in topology.nodes() {
+            for &cpu in &node.cpus {
+                if let Some(info) = manager.get_cpu_mut(cpu) {
+                    info.numa_node = Some(node.id.as_usize());
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 /// Get reference to NUMA topology
 pub fn get_numa_topology() -> &'static spin::Mutex<NumaTopology> {
     NUMA_TOPOLOGY.get().expect("NUMA topology not initialized")
 }
+
+/// Get the NUMA topology, or None before `init()`
+pub fn try_numa_topology() -> Option<&'static spin::Mutex<NumaTopology>> {
+    NUMA_TOPOLOGY.get()
+}
@@ -1,6 +1,30 @@
 //! NUMA Memory Policies
+//!
+//! Every task has a memory policy, set with set_mempolicy and inherited
+//! by its children; mbind gives a range of its address space a policy of
+//! its own. The frames behind user mappings are allocated under the
+//! policy of the address they are mapped at (see `current_policy`):
+//! - Default: the allocating CPU's node, then the nearest others
+//! - Preferred: the preferred node, then the nodes nearest to it
+//! - Bind: only the allowed nodes, nearest to the allocating CPU first
+//! - Interleave: the allowed nodes in turn, by page number
 
-use super::topology::NumaNodeId;
+extern crate alloc;
+use alloc::vec::Vec;
+use fanga_arch_x86_64::uaccess::UserPtr;
+
+use super::topology::{NumaNodeId, NumaTopology};
+use crate::syscall::{EFAULT, EINVAL, ESRCH};
+use crate::task::scheduler;
+
+/// set_mempolicy/mbind mode: allocate on the local node
+pub const MPOL_DEFAULT: i32 = 0;
+/// set_mempolicy/mbind mode: prefer the first node of the mask
+pub const MPOL_PREFERRED: i32 = 1;
+/// set_mempolicy/mbind mode: allocate only on the nodes of the mask
+pub const MPOL_BIND: i32 = 2;
+/// set_mempolicy/mbind mode: spread pages over the nodes of the mask
+pub const MPOL_INTERLEAVE: i32 = 3;
 
 /// NUMA memory policy
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,7 +43,7 @@ pub enum NumaPolicy {
 }
 
 /// NUMA memory policy configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NumaMemoryPolicy {
     /// Policy type
     pub policy: NumaPolicy,
@@ -116,6 +140,139 @@ impl NumaMemoryPolicy {
         
         None
     }
+    
+    /// Create a policy from a set_mempolicy/mbind mode and node mask
+    ///
+    /// Every node in the mask must be one of the `node_count` nodes. A
+    /// preferred policy with an empty mask means local allocation, which
+    /// is the default policy.
+    pub fn from_mode(mode: i32, nodemask: u64, node_count: usize) -> Result<Self, &'static str> {
+        let existing = if node_count >= 64 { u64::MAX } else { (1u64 << node_count) - 1 };
+        if nodemask & !existing != 0 {
+            return Err("Node mask names a node that does not exist");
+        }
+        let first = NumaNodeId::new(nodemask.trailing_zeros() as usize);
+        
+        match mode {
+            MPOL_DEFAULT if nodemask != 0 => Err("Default policy takes no nodes"),
+            MPOL_DEFAULT => Ok(Self::default()),
+            MPOL_PREFERRED if nodemask == 0 => Ok(Self::default()),
+            MPOL_PREFERRED => Ok(Self::preferred(first)),
+            MPOL_BIND | MPOL_INTERLEAVE if nodemask == 0 => Err("Empty node mask"),
+            MPOL_BIND => Ok(Self { policy: NumaPolicy::Bind, allowed_nodes: nodemask, preferred_node: None }),
+            MPOL_INTERLEAVE => Ok(Self { policy: NumaPolicy::Interleave, allowed_nodes: nodemask, preferred_node: None }),
+            _ => Err("Unknown memory policy mode"),
+        }
+    }
+    
+    /// Whether memory outside the policy's nodes may not be used
+    pub fn is_strict(&self) -> bool {
+        self.policy == NumaPolicy::Bind
+    }
+    
+    /// Nodes to allocate a page from, in order
+    ///
+    /// `local` is the allocating CPU's node; `index` picks the node of an
+    /// interleaved page.
+    pub fn node_order(&self, topology: &NumaTopology, local: NumaNodeId, index: u64) -> Vec<NumaNodeId> {
+        let start = match self.policy {
+            NumaPolicy::Default | NumaPolicy::Bind => local,
+            NumaPolicy::Preferred => self.preferred_node.unwrap_or(local),
+            NumaPolicy::Interleave => {
+                let nodes: Vec<NumaNodeId> = topology.nodes().iter()
+                    .map(|node| node.id)
+                    .filter(|&id| self.is_node_allowed(id))
+                    .collect();
+                if nodes.is_empty() {
+                    return nodes;
+                }
+                nodes[(index % nodes.len() as u64) as usize]
+            }
+        };
+        
+        let mut order = topology.fallback_order(start);
+        order.retain(|&node| self.is_node_allowed(node));
+        order
+    }
+}
+
+/// Policy for a page of the current task mapped at `addr`
+///
+/// A range given a policy with mbind uses it, the rest of the address
+/// space the task's. Called from the page fault handler, so it never
+/// spins on the scheduler lock.
+pub fn current_policy(addr: u64) -> NumaMemoryPolicy {
+    let Some(sched) = scheduler::try_scheduler() else {
+        return NumaMemoryPolicy::default();
+    };
+    let Some(task) = sched.current_task() else {
+        return NumaMemoryPolicy::default();
+    };
+    let task_policy = sched.get_task(task).map(|task| task.mempolicy);
+    drop(sched);
+    
+    crate::memory::vma::policy_at(task, addr)
+        .or(task_policy)
+        .unwrap_or_else(NumaMemoryPolicy::default)
+}
+
+/// Read a set_mempolicy/mbind node mask of `maxnode` bits
+///
+/// Only the first 64 nodes can be named.
+fn read_nodemask(nodemask: u64, maxnode: u64) -> Result<u64, i64> {
+    if nodemask == 0 || maxnode == 0 {
+        return Ok(0);
+    }
+    let mask = UserPtr::<u64>::new(nodemask).read().map_err(|_| EFAULT)?;
+    Ok(if maxnode < 64 { mask & ((1 << maxnode) - 1) } else { mask })
+}
+
+/// Policy from the arguments of set_mempolicy or mbind
+fn policy_from_user(mode: i32, nodemask: u64, maxnode: u64) -> Result<NumaMemoryPolicy, i64> {
+    let mask = read_nodemask(nodemask, maxnode)?;
+    let node_count = super::try_numa_topology().map_or(1, |topology| topology.lock().node_count());
+    NumaMemoryPolicy::from_mode(mode, mask, node_count).map_err(|_| EINVAL)
+}
+
+/// set_mempolicy(mode, nodemask, maxnode): set the current task's policy
+///
+/// Pages the task already has stay where they are.
+pub fn sys_set_mempolicy(mode: i32, nodemask: u64, maxnode: u64) -> i64 {
+    let policy = match policy_from_user(mode, nodemask, maxnode) {
+        Ok(policy) => policy,
+        Err(e) => return e,
+    };
+    let mut sched = scheduler::scheduler();
+    match sched.current_task_mut() {
+        Some(task) => {
+            task.mempolicy = policy;
+            0
+        }
+        None => ESRCH,
+    }
+}
+
+/// mbind(addr, len, mode, nodemask, maxnode, flags): set the policy of a
+/// mapped range
+///
+/// MPOL_DEFAULT gives the range back to the task's policy. Pages already
+/// in the range are not moved, so the MPOL_MF_* flags are not supported.
+pub fn sys_mbind(addr: u64, len: u64, mode: i32, nodemask: u64, maxnode: u64, flags: u32) -> i64 {
+    if addr & (crate::memory::PAGE_SIZE as u64 - 1) != 0 || flags != 0 {
+        return EINVAL;
+    }
+    let policy = match policy_from_user(mode, nodemask, maxnode) {
+        Ok(policy) => policy,
+        Err(e) => return e,
+    };
+    let Some(task) = scheduler::scheduler().current_task() else {
+        return ESRCH;
+    };
+    let policy = (mode != MPOL_DEFAULT).then_some(policy);
+    match crate::memory::vma::set_range_policy(task, addr, len, policy) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
+    }
 }
 
 #[cfg(test)]
@@ -157,4 +314,49 @@ mod tests {
         let next = policy.next_node(NumaNodeId::new(2));
         assert_eq!(next, Some(NumaNodeId::new(0))); // Should wrap around
     }
+    
+    #[test]
+    fn test_numa_policy_from_mode() {
+        let policy = NumaMemoryPolicy::from_mode(MPOL_BIND, 0b101, 3).unwrap();
+        assert_eq!(policy, NumaMemoryPolicy::bind(&[NumaNodeId::new(0), NumaNodeId::new(2)]));
+        assert_eq!(NumaMemoryPolicy::from_mode(MPOL_PREFERRED, 0b110, 3), Ok(NumaMemoryPolicy::preferred(NumaNodeId::new(1))));
+        assert_eq!(NumaMemoryPolicy::from_mode(MPOL_PREFERRED, 0, 3), Ok(NumaMemoryPolicy::default()));
+        assert_eq!(NumaMemoryPolicy::from_mode(MPOL_INTERLEAVE, 0b1000, 3), Err("Node mask names a node that does not exist"));
+        assert_eq!(NumaMemoryPolicy::from_mode(MPOL_BIND, 0, 3), Err("Empty node mask"));
+        assert!(NumaMemoryPolicy::from_mode(MPOL_DEFAULT, 1, 3).is_err());
+        assert!(NumaMemoryPolicy::from_mode(7, 1, 3).is_err());
+    }
+    
+    #[test]
+    fn test_numa_policy_node_order() {
+        use crate::acpi::srat::{CpuAffinity, MemoryAffinity, Srat};
+        use crate::smp::CpuId;
+        
+        // Three nodes in a line: 0 - 1 - 2
+        let memory = |domain, base| MemoryAffinity { domain, base, length: 0x1000_0000, hot_pluggable: false, non_volatile: false };
+        let srat = Srat {
+            cpus: alloc::vec![CpuAffinity { apic_id: 0, domain: 0 }],
+            memory: alloc::vec![memory(0, 0), memory(1, 0x1000_0000), memory(2, 0x2000_0000)],
+        };
+        let slit = crate::acpi::slit::Slit::parse(&crate::acpi::test_table(b"SLIT", &{
+            let mut body = 3u64.to_le_bytes().to_vec();
+            body.extend_from_slice(&[10, 20, 30, 20, 10, 20, 30, 20, 10]);
+            body
+        })).unwrap();
+        let topology = NumaTopology::from_acpi(&srat, Some(&slit), &[(CpuId::new(0), 0)]);
+        let node = NumaNodeId::new;
+        
+        let default = NumaMemoryPolicy::default();
+        assert_eq!(default.node_order(&topology, node(2), 0), [node(2), node(1), node(0)]);
+        assert!(!default.is_strict());
+        assert_eq!(NumaMemoryPolicy::preferred(node(1)).node_order(&topology, node(2), 0)[0], node(1));
+        
+        let bind = NumaMemoryPolicy::bind(&[node(0), node(1)]);
+        assert_eq!(bind.node_order(&topology, node(2), 0), [node(1), node(0)]);
+        assert!(bind.is_strict());
+        
+        let interleave = NumaMemoryPolicy::interleave(&[node(0), node(2)]);
+        assert_eq!(interleave.node_order(&topology, node(1), 6), [node(0), node(2)]);
+        assert_eq!(interleave.node_order(&topology, node(1), 7), [node(2), node(0)]);
+    }
 }
@@ -1,12 +1,24 @@
 //! NUMA Topology Detection and Management
+//!
+//! The nodes come from the ACPI SRAT: each proximity domain it names
+//! becomes a node, numbered in ascending domain order, with the memory
+//! ranges and CPUs (by APIC ID) the SRAT assigns to it. Distances between
+//! nodes come from the SLIT, or are 10 locally and 20 remotely, the ACPI
+//! defaults, without one. Machines without an SRAT get one node holding
+//! every CPU and all memory.
 
 extern crate alloc;
 use alloc::vec::Vec;
+use crate::acpi::slit::{Slit, LOCAL_DISTANCE, UNREACHABLE};
+use crate::acpi::Srat;
 use crate::smp::CpuId;
 
 /// Maximum number of NUMA nodes
 pub const MAX_NUMA_NODES: usize = 64;
 
+/// Distance between nodes without a SLIT entry
+pub const REMOTE_DISTANCE: u8 = 20;
+
 /// NUMA node ID
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NumaNodeId(pub usize);
@@ -23,21 +35,47 @@ impl NumaNodeId {
     }
 }
 
+/// A physical memory range of a NUMA node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumaMemRange {
+    /// Physical base address
+    pub base: u64,
+    
+    /// Size in bytes
+    pub size: u64,
+    
+    /// The firmware may add or remove the memory at run time
+    pub hot_pluggable: bool,
+}
+
+impl NumaMemRange {
+    /// Check if the range holds physical address `addr`
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+}
+
 /// NUMA node information
 #[derive(Debug, Clone)]
 pub struct NumaNode {
     /// Node ID
     pub id: NumaNodeId,
     
+    /// ACPI proximity domain of the node
+    pub domain: u32,
+    
     /// CPUs in this node
     pub cpus: Vec<CpuId>,
     
-    /// Base address of memory in this node
+    /// Lowest address of memory in this node
     pub mem_base: u64,
     
-    /// Size of memory in this node
+    /// Size of all memory in this node
     pub mem_size: u64,
     
+    /// Memory ranges in this node, by base address
+    pub mem_ranges: Vec<NumaMemRange>,
+    
     /// Distance to other nodes (latency metric)
     pub distances: [u8; MAX_NUMA_NODES],
 }
@@ -47,13 +85,28 @@ impl NumaNode {
     pub fn new(id: NumaNodeId) -> Self {
         Self {
             id,
+            domain: id.as_usize() as u32,
             cpus: Vec::new(),
             mem_base: 0,
             mem_size: 0,
-            distances: [255; MAX_NUMA_NODES], // 255 = unreachable
+            mem_ranges: Vec::new(),
+            distances: [UNREACHABLE; MAX_NUMA_NODES],
         }
     }
     
+    /// Add a memory range to this node
+    pub fn add_memory(&mut self, range: NumaMemRange) {
+        let at = self.mem_ranges.partition_point(|r| r.base < range.base);
+        self.mem_ranges.insert(at, range);
+        self.mem_base = self.mem_ranges[0].base;
+        self.mem_size += range.size;
+    }
+    
+    /// Check if physical address `addr` is in this node's memory
+    pub fn contains_addr(&self, addr: u64) -> bool {
+        self.mem_ranges.iter().any(|range| range.contains(addr))
+    }
+    
     /// Add a CPU to this node
     pub fn add_cpu(&mut self, cpu_id: CpuId) {
         if !self.cpus.contains(&cpu_id) {
@@ -98,21 +151,66 @@ impl NumaTopology {
     }
     
     /// Detect NUMA topology
+    ///
+    /// Reads the ACPI SRAT and SLIT; without an SRAT that assigns memory,
+    /// the machine is one node.
     pub fn detect(&mut self) -> Result<(), &'static str> {
-        // TODO: Parse ACPI SRAT (System Resource Affinity Table)
-        // and SLIT (System Locality Information Table)
+        let cpus = known_cpus();
+        *self = match crate::acpi::srat() {
+            Some(srat) if !srat.memory.is_empty() => Self::from_acpi(&srat, crate::acpi::slit().as_ref(), &cpus),
+            _ => Self::uma(&cpus, crate::memory::stats::stats().total_physical() as u64),
+        };
+        Ok(())
+    }
+    
+    /// Build the topology an SRAT and SLIT describe
+    ///
+    /// `cpus` maps each CPU to its APIC ID. CPUs the SRAT does not place go
+    /// to node 0; so does everything in domains beyond `MAX_NUMA_NODES`.
+    pub fn from_acpi(srat: &Srat, slit: Option<&Slit>, cpus: &[(CpuId, u32)]) -> Self {
+        let domains: Vec<u32> = srat.domains().into_iter().take(MAX_NUMA_NODES).collect();
+        let node_of = |domain: u32| domains.iter().position(|&d| d == domain).unwrap_or(0);
         
-        // For now, create a single node (UMA system)
-        let mut node = NumaNode::new(NumaNodeId::new(0));
-        node.mem_base = 0;
-        node.mem_size = 0x100000000; // 4 GB
-        node.add_cpu(CpuId::new(0));
-        node.set_distance(NumaNodeId::new(0), 10); // Local distance
+        let mut nodes: Vec<NumaNode> = domains.iter().enumerate().map(|(i, &domain)| {
+            let mut node = NumaNode::new(NumaNodeId::new(i));
+            node.domain = domain;
+            for (j, &other) in domains.iter().enumerate() {
+                let default = if i == j { LOCAL_DISTANCE } else { REMOTE_DISTANCE };
+                let distance = slit.and_then(|slit| slit.distance(domain, other)).unwrap_or(default);
+                node.set_distance(NumaNodeId::new(j), distance);
+            }
+            node
+        }).collect();
         
-        self.nodes.push(node);
-        self.enabled = false; // Not true NUMA, just single node
+        for range in &srat.memory {
+            nodes[node_of(range.domain)].add_memory(NumaMemRange {
+                base: range.base,
+                size: range.length,
+                hot_pluggable: range.hot_pluggable,
+            });
+        }
+        for &(cpu, apic_id) in cpus {
+            let node = srat.domain_of_apic(apic_id).map_or(0, node_of);
+            nodes[node].add_cpu(cpu);
+        }
         
-        Ok(())
+        Self { enabled: nodes.len() > 1, nodes }
+    }
+    
+    /// A single node holding `cpus` and `mem_size` bytes of memory
+    pub fn uma(cpus: &[(CpuId, u32)], mem_size: u64) -> Self {
+        let mut node = NumaNode::new(NumaNodeId::new(0));
+        node.mem_size = mem_size;
+        node.set_distance(NumaNodeId::new(0), LOCAL_DISTANCE);
+        for &(cpu, _) in cpus {
+            node.add_cpu(cpu);
+        }
+        Self { nodes: alloc::vec![node], enabled: false }
+    }
+    
+    /// Get all NUMA nodes
+    pub fn nodes(&self) -> &[NumaNode] {
+        &self.nodes
     }
     
     /// Get the number of NUMA nodes
@@ -145,6 +243,27 @@ impl NumaTopology {
         None
     }
     
+    /// Find the NUMA node holding physical address `addr`
+    ///
+    /// On a single-node machine that is node 0 for every address.
+    pub fn node_for_addr(&self, addr: u64) -> Option<NumaNodeId> {
+        if !self.enabled {
+            return self.nodes.first().map(|node| node.id);
+        }
+        self.nodes.iter().find(|node| node.contains_addr(addr)).map(|node| node.id)
+    }
+    
+    /// Nodes to allocate from for `from`: itself, then the others by
+    /// distance, nearest first
+    pub fn fallback_order(&self, from: NumaNodeId) -> Vec<NumaNodeId> {
+        let Some(node) = self.get_node(from) else {
+            return alloc::vec![from];
+        };
+        let mut others: Vec<NumaNodeId> = self.nodes.iter().map(|other| other.id).filter(|&id| id != from).collect();
+        others.sort_by_key(|&id| node.distance_to(id));
+        core::iter::once(from).chain(others).collect()
+    }
+    
     /// Find the closest NUMA node to a given node
     pub fn closest_node(&self, from: NumaNodeId) -> Option<NumaNodeId> {
         let node = self.get_node(from)?;
@@ -167,6 +286,20 @@ impl NumaTopology {
     }
 }
 
+/// Every CPU the CPU manager knows, with its APIC ID
+fn known_cpus() -> Vec<(CpuId, u32)> {
+    match crate::smp::try_cpu_manager() {
+        Some(manager) => {
+            let manager = manager.lock();
+            (0..manager.cpu_count())
+                .filter_map(|i| manager.get_cpu(CpuId::new(i)))
+                .map(|cpu| (cpu.id, cpu.apic_id))
+                .collect()
+        }
+        None => alloc::vec![(CpuId::new(0), crate::smp::cpu::current_apic_id())],
+    }
+}
+
 /// Get the NUMA topology (convenience function)
 pub fn numa_topology() -> &'static spin::Mutex<NumaTopology> {
     super::get_numa_topology()
@@ -208,6 +341,57 @@ mod tests {
         assert!(!topology.is_enabled()); // Single node = UMA
     }
     
+    #[test]
+    fn test_topology_from_acpi() {
+        use crate::acpi::srat::{CpuAffinity, MemoryAffinity};
+        
+        let memory = |domain, base, length| MemoryAffinity { domain, base, length, hot_pluggable: false, non_volatile: false };
+        let srat = Srat {
+            cpus: alloc::vec![
+                CpuAffinity { apic_id: 0, domain: 4 },
+                CpuAffinity { apic_id: 2, domain: 7 },
+            ],
+            memory: alloc::vec![
+                memory(4, 0x1_0000_0000, 0x4000_0000),
+                memory(7, 0x1_4000_0000, 0x4000_0000),
+                memory(4, 0, 0x8000_0000),
+            ],
+        };
+        let slit = Slit::parse(&crate::acpi::test_table(b"SLIT", &{
+            let mut body = 8u64.to_le_bytes().to_vec();
+            let mut matrix = [REMOTE_DISTANCE; 64];
+            matrix[4 * 8 + 4] = 12;
+            matrix[4 * 8 + 7] = 31;
+            matrix[7 * 8 + 4] = 31;
+            body.extend_from_slice(&matrix);
+            body
+        })).unwrap();
+        let cpus = [(CpuId::new(0), 0), (CpuId::new(1), 2), (CpuId::new(2), 9)];
+        
+        let topology = NumaTopology::from_acpi(&srat, Some(&slit), &cpus);
+        assert!(topology.is_enabled());
+        assert_eq!(topology.node_count(), 2);
+        let node0 = topology.get_node(NumaNodeId::new(0)).unwrap();
+        assert_eq!(node0.domain, 4);
+        assert_eq!((node0.mem_base, node0.mem_size, node0.mem_ranges.len()), (0, 0xC000_0000, 2));
+        // The APIC ID the SRAT does not list ends up on node 0
+        assert_eq!(node0.cpus, [CpuId::new(0), CpuId::new(2)]);
+        assert_eq!(topology.node_for_cpu(CpuId::new(1)), Some(NumaNodeId::new(1)));
+        assert_eq!(topology.node_for_addr(0x1_4000_1000), Some(NumaNodeId::new(1)));
+        assert_eq!(topology.node_for_addr(0x9000_0000), None);
+        
+        assert_eq!(node0.distance_to(NumaNodeId::new(1)), 31);
+        assert_eq!(node0.distance_to(NumaNodeId::new(0)), 12);
+        assert_eq!(topology.closest_node(NumaNodeId::new(1)), Some(NumaNodeId::new(0)));
+        assert_eq!(topology.fallback_order(NumaNodeId::new(1)), [NumaNodeId::new(1), NumaNodeId::new(0)]);
+        
+        // Without a SLIT the ACPI default distances apply
+        let topology = NumaTopology::from_acpi(&srat, None, &cpus);
+        let node1 = topology.get_node(NumaNodeId::new(1)).unwrap();
+        assert_eq!(node1.distance_to(NumaNodeId::new(0)), REMOTE_DISTANCE);
+        assert_eq!(node1.distance_to(NumaNodeId::new(1)), LOCAL_DISTANCE);
+    }
+    
     #[test]
     fn test_numa_node_for_cpu() {
         let mut topology = NumaTopology::new();
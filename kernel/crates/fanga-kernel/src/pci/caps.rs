@@ -0,0 +1,132 @@
+//! PCI Capabilities
+//!
+//! Functions list their optional features in configuration space. The
+//! standard list starts at the pointer at 0x34, when the status register
+//! says there is one; each entry is an ID byte followed by the offset of
+//! the next entry. PCI Express functions have a second list in the
+//! extended space from 0x100, each entry a dword holding a 16-bit ID, a
+//! version and the next offset. The extended list is only reachable
+//! through ECAM; over the legacy ports it reads as all ones and is empty.
+
+use alloc::vec::Vec;
+
+/// Status register bit: the capability list exists
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Register holding the offset of the first capability
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+
+/// Offset of the first extended capability
+pub const EXTENDED_CAPABILITIES: u16 = 0x100;
+
+/// Entries followed before a list is taken to loop
+const MAX_CAPABILITIES: usize = 48;
+
+/// Capability IDs
+pub mod id {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
+}
+
+/// Extended capability IDs
+pub mod ext_id {
+    pub const ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+    pub const SERIAL_NUMBER: u16 = 0x0003;
+}
+
+/// An entry of the standard list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the entry in configuration space
+    pub offset: u16,
+}
+
+/// An entry of the extended list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    /// Offset of the entry in configuration space
+    pub offset: u16,
+}
+
+/// Follow the standard list of a function
+///
+/// `status` is the function's status register; `read32` reads the
+/// configuration space dword at an offset.
+pub fn walk(status: u16, read32: impl Fn(u16) -> u32) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if status & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    // Entries live above the 64-byte header
+    let mut offset = (read32(CAPABILITIES_POINTER) & 0xFC) as u16;
+    while offset >= 0x40 && capabilities.len() < MAX_CAPABILITIES {
+        let header = read32(offset);
+        capabilities.push(Capability { id: header as u8, offset });
+        offset = ((header >> 8) & 0xFC) as u16;
+    }
+    capabilities
+}
+
+/// Follow the extended list of a function
+pub fn walk_extended(read32: impl Fn(u16) -> u32) -> Vec<ExtendedCapability> {
+    let mut capabilities = Vec::new();
+    let mut offset = EXTENDED_CAPABILITIES;
+    while capabilities.len() < MAX_CAPABILITIES {
+        let header = read32(offset);
+        if header == 0 || header == 0xFFFF_FFFF {
+            break;
+        }
+        capabilities.push(ExtendedCapability { id: header as u16, version: ((header >> 16) & 0xF) as u8, offset });
+        offset = ((header >> 20) & 0xFFC) as u16;
+        if offset < EXTENDED_CAPABILITIES {
+            break;
+        }
+    }
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration space with the given dwords set
+    fn space(dwords: &[(u16, u32)]) -> impl Fn(u16) -> u32 + '_ {
+        move |offset| dwords.iter().find(|&&(at, _)| at == offset).map_or(0, |&(_, value)| value)
+    }
+
+    #[test]
+    fn test_walk() {
+        // PM at 0x40, then MSI at 0x50, then the end
+        let config = space(&[(CAPABILITIES_POINTER, 0x40), (0x40, 0x5001), (0x50, 0x0005)]);
+        assert_eq!(
+            walk(STATUS_CAPABILITIES, &config),
+            [Capability { id: id::POWER_MANAGEMENT, offset: 0x40 }, Capability { id: id::MSI, offset: 0x50 }]
+        );
+        assert!(walk(0, &config).is_empty());
+
+        // A list pointing back at itself stops
+        let looping = space(&[(CAPABILITIES_POINTER, 0x40), (0x40, 0x4011)]);
+        assert_eq!(walk(STATUS_CAPABILITIES, looping).len(), MAX_CAPABILITIES);
+    }
+
+    #[test]
+    fn test_walk_extended() {
+        // AER v1 at 0x100, then serial number v1 at 0x140
+        let config = space(&[(0x100, 0x1401_0001), (0x140, 0x0001_0003)]);
+        assert_eq!(
+            walk_extended(config),
+            [
+                ExtendedCapability { id: ext_id::ADVANCED_ERROR_REPORTING, version: 1, offset: 0x100 },
+                ExtendedCapability { id: ext_id::SERIAL_NUMBER, version: 1, offset: 0x140 },
+            ]
+        );
+        // The legacy ports read all ones there
+        assert!(walk_extended(|_| 0xFFFF_FFFF).is_empty());
+    }
+}
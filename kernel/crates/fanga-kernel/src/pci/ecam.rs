@@ -0,0 +1,118 @@
+//! Enhanced Configuration Access Mechanism
+//!
+//! PCI Express makes each function's 4 KiB configuration space memory
+//! mapped, in the windows the ACPI MCFG lists. Unlike the legacy port
+//! pair, ECAM reaches the extended space above offset 0xFF and needs no
+//! lock: every access is a single uncached load or store.
+//!
+//! A window covers 1 MiB per bus. Buses are mapped with `ioremap` the
+//! first time they are touched, so a 256-bus window costs MMIO space only
+//! for the buses that enumeration actually visits. Only segment group 0
+//! is used; functions outside every window fall back to the port pair.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+
+use super::PciAddress;
+use crate::acpi::mcfg::McfgEntry;
+
+/// Configuration space of one bus in an ECAM window
+const BUS_WINDOW: u64 = 1 << 20;
+
+/// The segment 0 windows, from the MCFG
+static WINDOWS: Once<Vec<McfgEntry>> = Once::new();
+
+/// Virtual address of each bus's configuration space, 0 until mapped
+static BUS_MAPPINGS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Offset of a register of `address` from the base of its window
+///
+/// The base is where bus 0's configuration space would be, even for a
+/// window starting at a higher bus.
+pub fn config_offset(address: PciAddress, offset: u16) -> u64 {
+    (address.bus as u64) << 20
+        | ((address.device & 0x1F) as u64) << 15
+        | ((address.function & 0x07) as u64) << 12
+        | (offset & 0xFFF) as u64
+}
+
+/// Use the segment 0 windows of the MCFG
+///
+/// Returns how many there are; called once, after the ACPI tables are found.
+pub fn init(entries: &[McfgEntry]) -> usize {
+    WINDOWS
+        .call_once(|| entries.iter().copied().filter(|entry| entry.segment == 0).collect())
+        .len()
+}
+
+/// Whether ECAM windows are in use
+pub fn is_enabled() -> bool {
+    WINDOWS.get().is_some_and(|windows| !windows.is_empty())
+}
+
+/// Virtual address of the configuration space of `address`, None if no
+/// window covers its bus or the bus cannot be mapped
+fn function_base(address: PciAddress) -> Option<u64> {
+    let bus = &BUS_MAPPINGS[address.bus as usize];
+    let mut virt = bus.load(Ordering::Acquire);
+    if virt == 0 {
+        let window = WINDOWS
+            .get()?
+            .iter()
+            .find(|window| (window.start_bus..=window.end_bus).contains(&address.bus))?;
+        let phys = window.base + address.bus as u64 * BUS_WINDOW;
+        // Mapping the same range again returns the first mapping, so two
+        // CPUs racing here agree
+        virt = crate::memory::mmio::ioremap(phys, BUS_WINDOW).ok()?;
+        bus.store(virt, Ordering::Release);
+    }
+    Some(virt + (config_offset(address, 0) & (BUS_WINDOW - 1)))
+}
+
+/// Read a configuration space dword through ECAM
+pub fn read32(address: PciAddress, offset: u16) -> Option<u32> {
+    let base = function_base(address)?;
+    Some(unsafe { core::ptr::read_volatile((base + (offset & 0xFFC) as u64) as *const u32) })
+}
+
+/// Read a configuration space word through ECAM
+pub fn read16(address: PciAddress, offset: u16) -> Option<u16> {
+    let base = function_base(address)?;
+    Some(unsafe { core::ptr::read_volatile((base + (offset & 0xFFE) as u64) as *const u16) })
+}
+
+/// Write a configuration space dword through ECAM
+///
+/// Returns false if no window covers the function.
+pub fn write32(address: PciAddress, offset: u16, value: u32) -> bool {
+    let Some(base) = function_base(address) else {
+        return false;
+    };
+    unsafe { core::ptr::write_volatile((base + (offset & 0xFFC) as u64) as *mut u32, value) };
+    true
+}
+
+/// Write a configuration space word through ECAM
+///
+/// Returns false if no window covers the function.
+pub fn write16(address: PciAddress, offset: u16, value: u16) -> bool {
+    let Some(base) = function_base(address) else {
+        return false;
+    };
+    unsafe { core::ptr::write_volatile((base + (offset & 0xFFE) as u64) as *mut u16, value) };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_offset() {
+        assert_eq!(config_offset(PciAddress::new(0, 0, 0), 0), 0);
+        assert_eq!(config_offset(PciAddress::new(3, 0x1F, 7), 0x100), 0x3F_F100);
+        // Windows starting above bus 0 are still based at bus 0
+        assert_eq!(config_offset(PciAddress::new(0x81, 2, 1), 0x10), 0x811_1010);
+    }
+}
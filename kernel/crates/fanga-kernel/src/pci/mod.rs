@@ -0,0 +1,631 @@
+//! PCI Bus
+//!
+//! Configuration space is reached through the ECAM windows the ACPI MCFG
+//! lists, once `init` has found them, and otherwise through the legacy
+//! I/O port pair at 0xCF8/0xCFC (configuration mechanism #1), which every
+//! PC chipset and QEMU machine provides. Only ECAM reaches the extended
+//! space of PCI Express functions.
+//!
+//! `scan` walks the buses from the host bridges down through every
+//! PCI-to-PCI bridge and reports what answers. The result is kept as the
+//! device table and added to the driver core, functions behind a bridge
+//! under the bridge. Drivers register there with an ID table, or query
+//! the table by class or ID; then they size and map their BARs, turn on
+//! bus mastering, and set up MSI or the power state through the
+//! function's capabilities.
+//!
+//! This module provides:
+//! - Configuration space reads and writes, over ECAM or the legacy ports
+//! - Bus enumeration through bridges, with multi-function devices
+//! - BAR decoding and sizing for I/O, 32-bit and 64-bit memory BARs
+//! - Capability lists, MSI and power management
+//! - The device table and its place in the driver core
+
+pub mod caps;
+pub mod ecam;
+pub mod msi;
+pub mod pm;
+
+pub use caps::{Capability, ExtendedCapability};
+pub use msi::Msi;
+pub use pm::{PowerManagement, PowerState};
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use fanga_arch_x86_64::port::{inl, inw, outl, outw};
+
+/// Configuration mechanism #1 ports
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Configuration space registers
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const CLASS_REVISION: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+/// Bus behind a PCI-to-PCI bridge (type 1 header)
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+
+/// Size of the configuration space the legacy ports reach
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+/// Command register bits
+pub mod command {
+    pub const IO_SPACE: u16 = 1 << 0;
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+}
+
+/// Class codes
+pub mod class {
+    pub const MASS_STORAGE: u8 = 0x01;
+    pub const NETWORK: u8 = 0x02;
+    pub const BRIDGE: u8 = 0x06;
+    pub const SERIAL_BUS: u8 = 0x0C;
+}
+
+/// Header type bit for devices with more than one function
+const MULTI_FUNCTION: u8 = 0x80;
+
+/// Header layout of a PCI-to-PCI bridge
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// BARs of a general (type 0) header
+const BAR_COUNT: usize = 6;
+
+/// Serializes the address/data port pair
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Location of a function on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+
+    /// Value for CONFIG_ADDRESS selecting the dword holding `offset`
+    fn config_address(&self, offset: u16) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | ((self.device & 0x1F) as u32) << 11
+            | ((self.function & 0x07) as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Name of the function in the driver core, `0000:bb:dd.f`; every
+    /// function is in segment 0
+    pub fn device_name(&self) -> alloc::string::String {
+        alloc::format!("0000:{}", self)
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Read a configuration space dword
+///
+/// Registers the legacy ports cannot reach read as all ones.
+pub fn read_config32(address: PciAddress, offset: u16) -> u32 {
+    if let Some(value) = ecam::read32(address, offset) {
+        return value;
+    }
+    if offset >= LEGACY_CONFIG_SIZE {
+        return 0xFFFF_FFFF;
+    }
+    let _guard = CONFIG_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, address.config_address(offset));
+        inl(CONFIG_DATA)
+    }
+}
+
+/// Read a configuration space word
+pub fn read_config16(address: PciAddress, offset: u16) -> u16 {
+    if let Some(value) = ecam::read16(address, offset) {
+        return value;
+    }
+    if offset >= LEGACY_CONFIG_SIZE {
+        return 0xFFFF;
+    }
+    let _guard = CONFIG_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, address.config_address(offset));
+        inw(CONFIG_DATA + (offset & 2))
+    }
+}
+
+/// Read a configuration space byte
+pub fn read_config8(address: PciAddress, offset: u16) -> u8 {
+    (read_config32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Write a configuration space dword
+///
+/// Writes the legacy ports cannot reach are dropped.
+pub fn write_config32(address: PciAddress, offset: u16, value: u32) {
+    if ecam::write32(address, offset, value) || offset >= LEGACY_CONFIG_SIZE {
+        return;
+    }
+    let _guard = CONFIG_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, address.config_address(offset));
+        outl(CONFIG_DATA, value);
+    }
+}
+
+/// Write a configuration space word
+///
+/// Only the addressed word is written, so a write to COMMAND does not
+/// clear status bits next to it.
+pub fn write_config16(address: PciAddress, offset: u16, value: u16) {
+    if ecam::write16(address, offset, value) || offset >= LEGACY_CONFIG_SIZE {
+        return;
+    }
+    let _guard = CONFIG_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, address.config_address(offset));
+        outw(CONFIG_DATA + (offset & 2), value);
+    }
+}
+
+/// A decoded Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Memory-mapped registers at a physical address
+    Memory { addr: u64, size: u64, prefetchable: bool },
+    /// I/O port range
+    Io { port: u16, size: u16 },
+}
+
+/// Decode a BAR from its value and what reads back after writing all ones
+///
+/// `high` and `high_mask` are the next BAR's value and read-back and only
+/// matter for 64-bit memory BARs.
+///
+/// # Returns
+/// The BAR, and whether it used the next BAR too; `None` if the BAR is not
+/// implemented
+pub fn decode_bar(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Option<(Bar, bool)> {
+    if low & 1 != 0 {
+        let mask = low_mask & 0xFFFF_FFFC & 0xFFFF;
+        if mask == 0 {
+            return None;
+        }
+        let size = (!mask).wrapping_add(1) & 0xFFFF;
+        return Some((Bar::Io { port: (low & 0xFFFC) as u16, size: size as u16 }, false));
+    }
+
+    let wide = (low >> 1) & 0x3 == 0x2;
+    let prefetchable = low & 0x8 != 0;
+    let (addr, mask) = if wide {
+        (
+            (high as u64) << 32 | (low & 0xFFFF_FFF0) as u64,
+            (high_mask as u64) << 32 | (low_mask & 0xFFFF_FFF0) as u64,
+        )
+    } else {
+        ((low & 0xFFFF_FFF0) as u64, 0xFFFF_FFFF_0000_0000 | (low_mask & 0xFFFF_FFF0) as u64)
+    };
+    if mask & 0xFFFF_FFFF == 0 && (!wide || mask == 0) {
+        return None;
+    }
+    let size = (!mask).wrapping_add(1);
+    Some((Bar::Memory { addr, size, prefetchable }, wide))
+}
+
+/// A function found on the bus
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    /// Bus behind the function if it is a PCI-to-PCI bridge, else 0
+    pub secondary_bus: u8,
+}
+
+impl PciDevice {
+    /// Read the header of the function at `address`, if one answers there
+    pub fn probe(address: PciAddress) -> Option<Self> {
+        let vendor_id = read_config16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class = read_config32(address, CLASS_REVISION);
+        let header_type = read_config8(address, HEADER_TYPE);
+        let secondary_bus = if header_type & 0x7F == HEADER_BRIDGE { read_config8(address, SECONDARY_BUS) } else { 0 };
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: read_config16(address, DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            interrupt_line: read_config8(address, INTERRUPT_LINE),
+            secondary_bus,
+        })
+    }
+
+    /// Decode and size BAR `index`
+    ///
+    /// Decoding is switched off while the BAR is sized so the device does
+    /// not answer at the all-ones address meanwhile.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        if index >= BAR_COUNT || self.header_type & 0x7F != 0 {
+            return None;
+        }
+        let offset = BAR0 + (index * 4) as u16;
+        let size = |offset: u16| {
+            let value = read_config32(self.address, offset);
+            write_config32(self.address, offset, 0xFFFF_FFFF);
+            let mask = read_config32(self.address, offset);
+            write_config32(self.address, offset, value);
+            (value, mask)
+        };
+
+        let command = read_config16(self.address, COMMAND);
+        write_config16(self.address, COMMAND, command & !(command::IO_SPACE | command::MEMORY_SPACE));
+        let (low, low_mask) = size(offset);
+        let is_wide = low & 1 == 0 && (low >> 1) & 0x3 == 0x2 && index + 1 < BAR_COUNT;
+        let (high, high_mask) = if is_wide { size(offset + 4) } else { (0, 0) };
+        write_config16(self.address, COMMAND, command);
+
+        decode_bar(low, low_mask, high, high_mask).map(|(bar, _)| bar)
+    }
+
+    /// Turn on decoding of its I/O or memory BARs and bus mastering
+    pub fn enable(&self, bits: u16) {
+        let command = read_config16(self.address, COMMAND);
+        write_config16(self.address, COMMAND, command | bits);
+    }
+
+    /// Turn off command register bits
+    pub fn disable(&self, bits: u16) {
+        let command = read_config16(self.address, COMMAND);
+        write_config16(self.address, COMMAND, command & !bits);
+    }
+
+    /// Whether the function is a PCI-to-PCI bridge
+    pub fn is_bridge(&self) -> bool {
+        self.header_type & 0x7F == HEADER_BRIDGE
+    }
+
+    /// The standard capability list
+    pub fn capabilities(&self) -> Vec<Capability> {
+        caps::walk(read_config16(self.address, STATUS), |offset| read_config32(self.address, offset))
+    }
+
+    /// The extended capability list, empty without ECAM
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability> {
+        caps::walk_extended(|offset| read_config32(self.address, offset))
+    }
+
+    /// Offset of capability `id`, if the function has it
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().into_iter().find(|cap| cap.id == id).map(|cap| cap.offset)
+    }
+
+    /// The MSI capability
+    pub fn msi(&self) -> Option<Msi> {
+        let offset = self.find_capability(caps::id::MSI)?;
+        Some(Msi { offset, control: read_config16(self.address, offset + 2) })
+    }
+
+    /// Deliver the function's interrupt as `vector` to the local APIC
+    /// `apic_id` instead of on its INTx line
+    pub fn enable_msi(&self, apic_id: u8, vector: u8) -> Result<(), &'static str> {
+        let msi = self.msi().ok_or("No MSI capability")?;
+        let (address, data) = msi::message(apic_id, vector);
+        write_config32(self.address, msi.address_offset(), address as u32);
+        if msi.is_64bit() {
+            write_config32(self.address, msi.address_offset() + 4, (address >> 32) as u32);
+        }
+        write_config16(self.address, msi.data_offset(), data);
+        write_config16(self.address, msi.control_offset(), msi.enabled_control());
+        self.enable(command::INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// Go back to the INTx line
+    pub fn disable_msi(&self) {
+        if let Some(msi) = self.msi() {
+            write_config16(self.address, msi.control_offset(), msi.control & !msi::MSI_ENABLE);
+        }
+        self.disable(command::INTERRUPT_DISABLE);
+    }
+
+    /// The power management capability
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        let offset = self.find_capability(caps::id::POWER_MANAGEMENT)?;
+        Some(PowerManagement { offset, capabilities: read_config16(self.address, offset + 2) })
+    }
+
+    /// Current power state; functions without the capability are always in D0
+    pub fn power_state(&self) -> PowerState {
+        self.power_management()
+            .map_or(PowerState::D0, |pm| PowerState::from_pmcsr(read_config16(self.address, pm.pmcsr_offset())))
+    }
+
+    /// Move the function to power state `state`
+    ///
+    /// Waits out the recovery time the transition needs. A function coming
+    /// back from D3hot may have lost its BARs and command register.
+    pub fn set_power_state(&self, state: PowerState) -> Result<(), &'static str> {
+        let pm = self.power_management().ok_or("No power management capability")?;
+        if !pm.supports(state) {
+            return Err("Power state not supported");
+        }
+        let pmcsr = read_config16(self.address, pm.pmcsr_offset());
+        let current = PowerState::from_pmcsr(pmcsr);
+        if current == state {
+            return Ok(());
+        }
+        write_config16(self.address, pm.pmcsr_offset(), state.to_pmcsr(pmcsr));
+
+        let involves = |which| current == which || state == which;
+        if involves(PowerState::D3Hot) {
+            crate::task::time::delay_ms(pm::D3HOT_DELAY_MS);
+        } else if involves(PowerState::D2) {
+            crate::task::time::delay_us(pm::D2_DELAY_US);
+        }
+        Ok(())
+    }
+}
+
+/// Walk the buses reachable from the host bridges
+///
+/// `probe` reads the header of the function at an address. Bus 0 is the
+/// root unless the host bridge at 00:00.0 has several functions: then
+/// function N is the host bridge of bus N. Bridges lead to their
+/// secondary bus; each bus is walked once.
+fn enumerate(probe: impl Fn(PciAddress) -> Option<PciDevice>) -> Vec<PciDevice> {
+    fn walk_bus(
+        bus: u8,
+        probe: &dyn Fn(PciAddress) -> Option<PciDevice>,
+        walked: &mut [bool; 256],
+        devices: &mut Vec<PciDevice>,
+    ) {
+        if core::mem::replace(&mut walked[bus as usize], true) {
+            return;
+        }
+        for device in 0..32u8 {
+            let Some(first) = probe(PciAddress::new(bus, device, 0)) else {
+                continue;
+            };
+            let functions = if first.header_type & MULTI_FUNCTION != 0 { 8 } else { 1 };
+            let found = core::iter::once(first)
+                .chain((1..functions).filter_map(|function| probe(PciAddress::new(bus, device, function))));
+            for function in found {
+                devices.push(function);
+                if function.is_bridge() && function.secondary_bus != 0 {
+                    walk_bus(function.secondary_bus, probe, walked, devices);
+                }
+            }
+        }
+    }
+
+    let roots = match probe(PciAddress::new(0, 0, 0)) {
+        Some(host) if host.header_type & MULTI_FUNCTION != 0 => {
+            (0..8).filter(|&function| probe(PciAddress::new(0, 0, function)).is_some()).collect()
+        }
+        _ => vec![0],
+    };
+    let mut devices = Vec::new();
+    let mut walked = [false; 256];
+    for bus in roots {
+        walk_bus(bus, &probe, &mut walked, &mut devices);
+    }
+    devices
+}
+
+/// Every function on the buses reachable from the host bridges
+pub fn scan() -> Vec<PciDevice> {
+    enumerate(PciDevice::probe)
+}
+
+/// The device table; None until the buses are first scanned
+static DEVICES: Mutex<Option<Vec<PciDevice>>> = Mutex::new(None);
+
+/// Node of the host bridge in the driver core, the root of the PCI tree
+const HOST_BRIDGE: &str = "pci0000:00";
+
+/// Add the functions of `devices` the driver core does not have yet
+///
+/// A function hangs off the bridge whose secondary bus it is on, or off
+/// the host bridge node. Bridges come before what is behind them in the
+/// table, so a function's parent is always added first.
+fn add_to_driver_core(devices: &[PciDevice]) {
+    use crate::driver::{self, Device};
+
+    let root = match driver::find_device(HOST_BRIDGE) {
+        Some(root) => root.handle,
+        None => match driver::add_device(Device::group(HOST_BRIDGE, None)) {
+            Ok(root) => root,
+            Err(e) => {
+                crate::log_warn!(target: "pci", "Cannot add {}: {}", HOST_BRIDGE, e);
+                return;
+            }
+        },
+    };
+    for function in devices {
+        let parent = devices
+            .iter()
+            .find(|bridge| bridge.is_bridge() && bridge.secondary_bus != 0 && bridge.secondary_bus == function.address.bus)
+            .and_then(|bridge| driver::find_device(&bridge.address.device_name()))
+            .map_or(root, |bridge| bridge.handle);
+        let device = Device::pci(*function, Some(parent));
+        if driver::find_device(&device.name).is_some() {
+            continue;
+        }
+        if let Err(e) = driver::add_device(device) {
+            crate::log_warn!(target: "pci", "Cannot add {}: {}", function.address, e);
+        }
+    }
+}
+
+/// Scan the buses, replace the device table and add new functions to the
+/// driver core
+fn load() -> Vec<PciDevice> {
+    let devices = scan();
+    *DEVICES.lock() = Some(devices.clone());
+    add_to_driver_core(&devices);
+    devices
+}
+
+/// Scan the buses again and replace the device table
+///
+/// Returns the number of functions found. Functions that appeared are
+/// offered to the registered drivers.
+pub fn rescan() -> usize {
+    load().len()
+}
+
+/// The device table, scanning the buses on first use
+pub fn devices() -> Vec<PciDevice> {
+    let devices = DEVICES.lock().clone();
+    devices.unwrap_or_else(load)
+}
+
+/// Register a PCI driver with the driver core
+///
+/// The buses are scanned first if they have not been, so the driver is
+/// offered every function. Returns the number of functions it bound to.
+pub fn register_driver(driver: Arc<dyn crate::driver::Driver>) -> Result<usize, &'static str> {
+    if DEVICES.lock().is_none() {
+        load();
+    }
+    crate::driver::register_driver(driver)
+}
+
+/// The function at `address`
+pub fn get(address: PciAddress) -> Option<PciDevice> {
+    devices().into_iter().find(|device| device.address == address)
+}
+
+/// Functions of a class and subclass
+pub fn find(class: u8, subclass: u8) -> Vec<PciDevice> {
+    devices()
+        .into_iter()
+        .filter(|device| device.class == class && device.subclass == subclass)
+        .collect()
+}
+
+/// Functions with a vendor and device ID
+pub fn find_id(vendor_id: u16, device_id: u16) -> Vec<PciDevice> {
+    devices()
+        .into_iter()
+        .filter(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+        .collect()
+}
+
+/// Use the ECAM windows of the MCFG, if there is one, and scan the buses
+///
+/// Called once the ACPI tables are found. Drivers that queried the table
+/// earlier saw the same functions over the legacy ports.
+pub fn init() -> usize {
+    if let Some(mcfg) = crate::acpi::mcfg() {
+        let windows = ecam::init(&mcfg.entries);
+        crate::log_info!(target: "pci", "ECAM: {} window(s) in segment 0", windows);
+    }
+    rescan()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_address() {
+        let address = PciAddress::new(1, 0x1F, 7);
+        assert_eq!(address.config_address(0x3E), 0x8001_FF3C);
+        assert_eq!(PciAddress::new(0, 3, 0).config_address(BAR0), 0x8000_1810);
+    }
+
+    /// A function as `PciDevice::probe` would find it
+    fn function(address: PciAddress, header_type: u8, secondary_bus: u8) -> PciDevice {
+        PciDevice {
+            address,
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            revision: 0,
+            header_type,
+            interrupt_line: 0,
+            secondary_bus,
+        }
+    }
+
+    #[test]
+    fn test_enumerate() {
+        // 00:00.0 host bridge; 00:01.0 a multi-function bridge to bus 2
+        // next to 00:01.1; 02:03.0 behind it; bus 5 not behind any bridge
+        let present = [
+            function(PciAddress::new(0, 0, 0), 0, 0),
+            function(PciAddress::new(0, 1, 0), MULTI_FUNCTION | HEADER_BRIDGE, 2),
+            function(PciAddress::new(0, 1, 1), 0, 0),
+            function(PciAddress::new(2, 3, 0), 0, 0),
+            function(PciAddress::new(5, 0, 0), 0, 0),
+        ];
+        let probe = |address| present.iter().copied().find(|device: &PciDevice| device.address == address);
+        let found: Vec<PciAddress> = enumerate(probe).iter().map(|device| device.address).collect();
+        assert_eq!(found, [present[0].address, present[1].address, present[3].address, present[2].address]);
+
+        // A multi-function host bridge roots one bus per function
+        let hosts = [
+            function(PciAddress::new(0, 0, 0), MULTI_FUNCTION, 0),
+            function(PciAddress::new(0, 0, 1), 0, 0),
+            function(PciAddress::new(1, 2, 0), 0, 0),
+        ];
+        let probe = |address| hosts.iter().copied().find(|device: &PciDevice| device.address == address);
+        assert_eq!(enumerate(probe).len(), 3);
+    }
+
+    #[test]
+    fn test_decode_io_bar() {
+        // 32 ports at 0xC040
+        assert_eq!(
+            decode_bar(0xC041, 0xFFFF_FFE1, 0, 0),
+            Some((Bar::Io { port: 0xC040, size: 32 }, false))
+        );
+        assert_eq!(decode_bar(0, 0, 0, 0), None);
+    }
+
+    #[test]
+    fn test_decode_memory_bars() {
+        // 16 KiB, 32-bit
+        assert_eq!(
+            decode_bar(0xFEBF_0000, 0xFFFF_C000, 0, 0),
+            Some((Bar::Memory { addr: 0xFEBF_0000, size: 0x4000, prefetchable: false }, false))
+        );
+        // 64 KiB, 64-bit prefetchable above 4 GiB
+        assert_eq!(
+            decode_bar(0x0000_000C, 0xFFFF_000C, 0x1, 0xFFFF_FFFF),
+            Some((Bar::Memory { addr: 0x1_0000_0000, size: 0x1_0000, prefetchable: true }, true))
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! PCI Power Management
+//!
+//! The power management capability moves a function between device power
+//! states: D0 is fully on, D3hot off with configuration space still
+//! answering. D1 and D2 are optional. A function leaving D3hot needs 10 ms
+//! before it may be touched again, and one entering or leaving D2 200 us.
+
+/// Power management capabilities register bits: D1 and D2 support
+const SUPPORTS_D1: u16 = 1 << 9;
+const SUPPORTS_D2: u16 = 1 << 10;
+
+/// Control/status register: power state field, and the PME status bit,
+/// which is cleared by writing it as one
+const POWER_STATE_MASK: u16 = 0x3;
+const PME_STATUS: u16 = 1 << 15;
+
+/// Recovery time after a transition to or from D3hot
+pub const D3HOT_DELAY_MS: u64 = 10;
+
+/// Recovery time after a transition to or from D2
+pub const D2_DELAY_US: u64 = 200;
+
+/// Device power state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    /// Decode the power state field of the control/status register
+    pub fn from_pmcsr(pmcsr: u16) -> Self {
+        match pmcsr & POWER_STATE_MASK {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+
+    /// Control/status register moving the function to this state
+    ///
+    /// The PME status bit is left out so the write does not clear it.
+    pub fn to_pmcsr(self, pmcsr: u16) -> u16 {
+        (pmcsr & !(POWER_STATE_MASK | PME_STATUS)) | self as u16
+    }
+}
+
+/// The power management capability of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerManagement {
+    /// Offset of the capability in configuration space
+    pub offset: u16,
+    /// Power management capabilities register
+    pub capabilities: u16,
+}
+
+impl PowerManagement {
+    /// Whether the function can enter `state`; D0 and D3hot always work
+    pub fn supports(&self, state: PowerState) -> bool {
+        match state {
+            PowerState::D1 => self.capabilities & SUPPORTS_D1 != 0,
+            PowerState::D2 => self.capabilities & SUPPORTS_D2 != 0,
+            PowerState::D0 | PowerState::D3Hot => true,
+        }
+    }
+
+    /// Offset of the control/status register
+    pub fn pmcsr_offset(&self) -> u16 {
+        self.offset + 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_states() {
+        let pm = PowerManagement { offset: 0x40, capabilities: 0x0603 };
+        assert!(pm.supports(PowerState::D1) && pm.supports(PowerState::D2));
+        assert!(!PowerManagement { capabilities: 0x0003, ..pm }.supports(PowerState::D2));
+        assert_eq!(pm.pmcsr_offset(), 0x44);
+
+        assert_eq!(PowerState::from_pmcsr(0x0103), PowerState::D3Hot);
+        // PME enable is kept, a pending PME status is not cleared
+        assert_eq!(PowerState::D0.to_pmcsr(0x8103), 0x0100);
+        assert_eq!(PowerState::D2.to_pmcsr(0x0000), 0x0002);
+    }
+}
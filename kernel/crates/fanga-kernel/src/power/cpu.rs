@@ -4,8 +4,38 @@
 //! - CPU frequency scaling (P-states)
 //! - CPU idle states (C-states)
 //! - CPU power state transitions
+//! - Idle entry via `hlt` or `monitor`/`mwait`
+//!
+//! # Frequency scaling
+//!
+//! At init the frequency table is read from the hardware interface the
+//! CPU has (Intel HWP or SpeedStep, AMD P-states; see
+//! `fanga_arch_x86_64::cpufreq`). P0 is the fastest level and P3 the
+//! slowest, with P1 and P2 spread between them. Without an interface the
+//! P-states are bookkeeping only, with estimated frequencies.
+//!
+//! Under the balanced policy a governor samples the CPUs' idle time and
+//! picks the slowest level that keeps up with the load. HWP CPUs pick
+//! their own frequency; the policy only sets their range and energy
+//! preference. The frequency MSRs are per core, so a new target level is
+//! published for every CPU and each applies it the next time it idles.
+//!
+//! Thermal throttling (see `thermal`) caps the level: CPUs run at the
+//! slower of the target and the cap, and HWP CPUs get a lower maximum.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+
+use fanga_arch_x86_64::cpufreq::{self, HwpCapabilities, HwpRequest, Interface};
+use crate::smp::cpu::MAX_CPUS;
 
-use spin::Mutex;
+/// Interval between governor samples
+pub const GOVERNOR_INTERVAL_MS: u64 = 100;
+
+/// Load, in percent, at which the governor goes to the fastest level
+pub const UP_THRESHOLD: u32 = 80;
 
 /// CPU Performance State (P-state)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -76,37 +106,293 @@ static CPU_POWER: Mutex<CpuPowerState> = Mutex::new(CpuPowerState {
     frequency_mhz: 0,
 });
 
+/// A frequency the hardware can run at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfLevel {
+    pub frequency_mhz: u32,
+    /// Bus ratio (Intel) or P-state number (AMD) selecting the level
+    pub control: u8,
+}
+
+/// The frequency scaling hardware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreqScaling {
+    pub interface: Interface,
+    /// Available levels, fastest first
+    pub levels: Vec<PerfLevel>,
+    /// Performance range under HWP
+    pub hwp: Option<HwpCapabilities>,
+}
+
+/// Scaling hardware found by `init()`
+static SCALING: Once<Option<FreqScaling>> = Once::new();
+
+/// Level index every CPU should run at
+static TARGET_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// Policy the target was set under, for the HWP range
+static TARGET_POLICY: AtomicU8 = AtomicU8::new(ScalingPolicy::Balanced as u8);
+
+/// Fastest level thermal throttling allows, 0 when not throttling
+static THERMAL_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Bumped whenever the target level, the policy or the thermal limit
+/// changes
+static TARGET_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Generation each CPU last applied
+static APPLIED_GENERATION: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether the governor's work is queued
+static GOVERNOR_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Uptime and total idle time at the governor's last sample
+static LAST_SAMPLE: Mutex<(u64, u64)> = Mutex::new((0, 0));
+
+/// Levels of the Intel bus ratios from `lowest` to `highest`, fastest first
+pub fn intel_levels(lowest: u8, highest: u8) -> Vec<PerfLevel> {
+    (lowest.max(1)..=highest)
+        .rev()
+        .map(|ratio| PerfLevel { frequency_mhz: ratio as u32 * cpufreq::BUS_CLOCK_MHZ, control: ratio })
+        .collect()
+}
+
+/// Levels of the enabled AMD P-states, P0 (the fastest) first
+pub fn amd_levels(pstates: &[Option<u32>]) -> Vec<PerfLevel> {
+    pstates
+        .iter()
+        .enumerate()
+        .filter_map(|(pstate, mhz)| Some(PerfLevel { frequency_mhz: (*mhz)?, control: pstate as u8 }))
+        .collect()
+}
+
+/// Read the frequency table from the hardware
+#[cfg(not(test))]
+fn detect_scaling() -> Option<FreqScaling> {
+    let interface = cpufreq::detect()?;
+    let (levels, hwp) = match interface {
+        Interface::Hwp => {
+            let caps = cpufreq::hwp_capabilities();
+            (intel_levels(caps.lowest, caps.highest), Some(caps))
+        }
+        Interface::Speedstep => {
+            let (lowest, highest) = cpufreq::platform_ratios();
+            (intel_levels(lowest, highest), None)
+        }
+        Interface::AmdPstate => (amd_levels(&cpufreq::amd_pstates()), None),
+    };
+    (!levels.is_empty()).then_some(FreqScaling { interface, levels, hwp })
+}
+
+#[cfg(test)]
+fn detect_scaling() -> Option<FreqScaling> {
+    None
+}
+
+/// The frequency scaling hardware, None if the P-states are bookkeeping
+pub fn scaling() -> Option<&'static FreqScaling> {
+    SCALING.get()?.as_ref()
+}
+
+/// Level index of a P-state among `count` levels
+pub fn level_of(p_state: PState, count: usize) -> usize {
+    count.saturating_sub(1) * p_state as usize / 3
+}
+
+/// P-state closest to level `level` of `count`
+fn p_state_of(level: usize, count: usize) -> PState {
+    match (level * 3 + count.saturating_sub(1) / 2).checked_div(count.saturating_sub(1)).unwrap_or(0) {
+        0 => PState::P0,
+        1 => PState::P1,
+        2 => PState::P2,
+        _ => PState::P3,
+    }
+}
+
+/// Estimated frequency of a P-state without a frequency table
+fn estimated_mhz(p_state: PState) -> u32 {
+    match p_state {
+        PState::P0 => 2400,
+        PState::P1 => 2000,
+        PState::P2 => 1600,
+        PState::P3 => 1200,
+    }
+}
+
+/// HWP range and preference for a policy
+pub fn hwp_request(caps: &HwpCapabilities, policy: ScalingPolicy) -> HwpRequest {
+    match policy {
+        ScalingPolicy::Performance => HwpRequest {
+            min: caps.highest,
+            max: caps.highest,
+            desired: 0,
+            epp: cpufreq::EPP_PERFORMANCE,
+        },
+        ScalingPolicy::Balanced => HwpRequest {
+            min: caps.lowest,
+            max: caps.highest,
+            desired: 0,
+            epp: cpufreq::EPP_BALANCED,
+        },
+        ScalingPolicy::PowerSave => HwpRequest {
+            min: caps.lowest,
+            max: caps.most_efficient.max(caps.lowest),
+            desired: 0,
+            epp: cpufreq::EPP_POWERSAVE,
+        },
+    }
+}
+
+/// An HWP request with its range capped at `max`
+pub fn limit_hwp_request(request: HwpRequest, max: u8) -> HwpRequest {
+    let max = request.max.min(max);
+    HwpRequest { min: request.min.min(max), max, ..request }
+}
+
+/// Pick the slowest level fast enough for `load_percent`
+///
+/// Past `UP_THRESHOLD` the fastest level is used; below it, the
+/// frequency scales with the load so that it would sit at the threshold.
+pub fn select_level(levels: &[PerfLevel], load_percent: u32) -> usize {
+    let Some(fastest) = levels.first() else {
+        return 0;
+    };
+    if load_percent >= UP_THRESHOLD {
+        return 0;
+    }
+    let target = fastest.frequency_mhz as u64 * load_percent as u64 / UP_THRESHOLD as u64;
+    levels.iter().rposition(|level| level.frequency_mhz as u64 >= target).unwrap_or(0)
+}
+
+/// Load, in percent, of `cpus` CPUs that were idle for `idle_ms` of
+/// `elapsed_ms` in total
+pub fn load_percent(elapsed_ms: u64, idle_ms: u64, cpus: u64) -> u32 {
+    let capacity = elapsed_ms * cpus;
+    if capacity == 0 {
+        return 0;
+    }
+    (100 - idle_ms.min(capacity) * 100 / capacity) as u32
+}
+
+/// Make level `level` the target of every CPU and apply it here
+fn set_target_level(level: usize, policy: ScalingPolicy) {
+    TARGET_LEVEL.store(level, Ordering::Relaxed);
+    TARGET_POLICY.store(policy as u8, Ordering::Relaxed);
+    TARGET_GENERATION.fetch_add(1, Ordering::Release);
+    sync_frequency(crate::smp::current_cpu_id().as_usize());
+}
+
+/// Program this CPU for the current target, if it has not yet
+///
+/// Called from the idle loop, so each CPU catches up on changes made
+/// elsewhere.
+pub fn sync_frequency(cpu: usize) {
+    let Some(scaling) = scaling() else {
+        return;
+    };
+    let generation = TARGET_GENERATION.load(Ordering::Acquire);
+    let Some(applied) = APPLIED_GENERATION.get(cpu) else {
+        return;
+    };
+    if applied.swap(generation, Ordering::Relaxed) == generation {
+        return;
+    }
+    let slowest = scaling.levels.len() - 1;
+    let limit = THERMAL_LIMIT.load(Ordering::Relaxed).min(slowest);
+    let level = scaling.levels[TARGET_LEVEL.load(Ordering::Relaxed).max(limit).min(slowest)];
+    unsafe {
+        match (scaling.interface, scaling.hwp) {
+            (Interface::Hwp, Some(caps)) => {
+                let policy = match TARGET_POLICY.load(Ordering::Relaxed) {
+                    policy if policy == ScalingPolicy::Performance as u8 => ScalingPolicy::Performance,
+                    policy if policy == ScalingPolicy::PowerSave as u8 => ScalingPolicy::PowerSave,
+                    _ => ScalingPolicy::Balanced,
+                };
+                let request = hwp_request(&caps, policy);
+                cpufreq::set_hwp_request(match limit {
+                    0 => request,
+                    _ => limit_hwp_request(request, scaling.levels[limit].control),
+                })
+            }
+            (Interface::AmdPstate, _) => cpufreq::set_amd_pstate(level.control),
+            _ => cpufreq::set_ratio(level.control),
+        }
+    }
+}
+
+/// Record and apply a P-state
+fn apply_p_state(state: &mut CpuPowerState, p_state: PState) {
+    state.p_state = p_state;
+    match scaling() {
+        Some(scaling) => {
+            let level = level_of(p_state, scaling.levels.len());
+            state.frequency_mhz = scaling.levels[level].frequency_mhz;
+            set_target_level(level, state.policy);
+        }
+        None => state.frequency_mhz = estimated_mhz(p_state),
+    }
+}
+
+/// Keep every CPU at level `level` or slower, for thermal throttling
+///
+/// 0 lifts the limit. The governor and the policies go on picking
+/// levels; the limit only applies on top of them.
+pub fn set_thermal_limit(level: usize) {
+    if THERMAL_LIMIT.swap(level, Ordering::Relaxed) != level {
+        TARGET_GENERATION.fetch_add(1, Ordering::Release);
+        sync_frequency(crate::smp::current_cpu_id().as_usize());
+    }
+}
+
+/// Fastest level thermal throttling allows, 0 when not throttling
+pub fn thermal_limit() -> usize {
+    THERMAL_LIMIT.load(Ordering::Relaxed)
+}
+
 /// Initialize CPU power management
+///
+/// Reads the frequency table the first time; HWP is switched on here.
 pub fn init() {
+    let scaling = SCALING.call_once(detect_scaling).as_ref();
     let mut state = CPU_POWER.lock();
     state.p_state = PState::P0;
     state.c_state = CState::C0;
     state.policy = ScalingPolicy::Balanced;
-    
-    // Estimate base frequency (would require CPUID or MSR reads in real implementation)
-    state.frequency_mhz = 2400; // Default estimate
+    state.frequency_mhz = scaling.map_or(estimated_mhz(PState::P0), |scaling| scaling.levels[0].frequency_mhz);
+    drop(state);
+
+    if let Some(scaling) = scaling {
+        if scaling.interface == Interface::Hwp {
+            unsafe { cpufreq::enable_hwp() };
+        }
+        let slowest = scaling.levels[scaling.levels.len() - 1];
+        crate::log_info!(
+            target: "power",
+            "{}: {} levels, {}-{} MHz",
+            scaling.interface.name(),
+            scaling.levels.len(),
+            slowest.frequency_mhz,
+            scaling.levels[0].frequency_mhz
+        );
+        set_target_level(0, ScalingPolicy::Balanced);
+    }
 }
 
 /// Set CPU frequency scaling policy
+///
+/// Performance pins P0 and power save P2; balanced starts at P1 and
+/// leaves the rest to the governor.
 pub fn set_scaling_policy(policy: ScalingPolicy) -> Result<(), &'static str> {
     let mut state = CPU_POWER.lock();
     state.policy = policy;
     
     // Adjust P-state based on policy
-    match policy {
-        ScalingPolicy::Performance => {
-            state.p_state = PState::P0;
-            state.frequency_mhz = 2400;
-        }
-        ScalingPolicy::Balanced => {
-            state.p_state = PState::P1;
-            state.frequency_mhz = 2000;
-        }
-        ScalingPolicy::PowerSave => {
-            state.p_state = PState::P2;
-            state.frequency_mhz = 1600;
-        }
-    }
+    let p_state = match policy {
+        ScalingPolicy::Performance => PState::P0,
+        ScalingPolicy::Balanced => PState::P1,
+        ScalingPolicy::PowerSave => PState::P2,
+    };
+    apply_p_state(&mut state, p_state);
     
     Ok(())
 }
@@ -114,19 +400,55 @@ pub fn set_scaling_policy(policy: ScalingPolicy) -> Result<(), &'static str> {
 /// Set CPU P-state (performance state)
 pub fn set_p_state(p_state: PState) -> Result<(), &'static str> {
     let mut state = CPU_POWER.lock();
-    state.p_state = p_state;
-    
-    // Update frequency based on P-state
-    state.frequency_mhz = match p_state {
-        PState::P0 => 2400,
-        PState::P1 => 2000,
-        PState::P2 => 1600,
-        PState::P3 => 1200,
-    };
+    apply_p_state(&mut state, p_state);
     
     Ok(())
 }
 
+/// Sample the load and pick a level, under the balanced policy
+fn governor_tick(_: usize) {
+    let cpus = crate::task::idle::idle_tasks();
+    let idle_ms: u64 = cpus.iter().map(|(_, cpu)| cpu.stats.total_ms()).sum();
+    let count = cpus.iter().count() as u64;
+    drop(cpus);
+
+    let now = crate::task::time::uptime_ms();
+    let (last_now, last_idle) = core::mem::replace(&mut *LAST_SAMPLE.lock(), (now, idle_ms));
+    let load = load_percent(now - last_now, idle_ms.saturating_sub(last_idle), count);
+
+    if let Some(scaling) = scaling() {
+        let mut state = CPU_POWER.lock();
+        if state.policy == ScalingPolicy::Balanced {
+            let level = select_level(&scaling.levels, load);
+            if level != TARGET_LEVEL.load(Ordering::Relaxed) {
+                state.p_state = p_state_of(level, scaling.levels.len());
+                state.frequency_mhz = scaling.levels[level].frequency_mhz;
+                set_target_level(level, state.policy);
+            }
+        }
+    }
+    schedule_governor();
+}
+
+/// Queue the next governor sample
+fn schedule_governor() {
+    let work = crate::task::workqueue::Work::new(governor_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, GOVERNOR_INTERVAL_MS);
+}
+
+/// Start the demand-based governor
+///
+/// Needs the workqueues. Does nothing without a frequency table, or on
+/// HWP CPUs, which pick their own frequency.
+pub fn start_governor() {
+    if scaling().is_some_and(|scaling| scaling.interface != Interface::Hwp)
+        && !GOVERNOR_RUNNING.swap(true, Ordering::AcqRel)
+    {
+        *LAST_SAMPLE.lock() = (crate::task::time::uptime_ms(), 0);
+        schedule_governor();
+    }
+}
+
 /// Get current CPU P-state
 pub fn get_p_state() -> PState {
     CPU_POWER.lock().p_state
@@ -158,6 +480,151 @@ pub fn enter_c_state(c_state: CState) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// How the CPU waits for an interrupt when idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleMethod {
+    /// `hlt` (C1 only)
+    Hlt,
+    /// `monitor`/`mwait` with a C-state hint
+    Mwait,
+}
+
+/// CPUID.05H:ECX: EDX enumerates the MWAIT C-states
+const MWAIT_EXTENSIONS: u32 = 1 << 0;
+
+/// `mwait` hints (EAX) for the C-states the CPU supports
+///
+/// The hint for MWAIT C-state n is (n - 1) in bits 7:4, sub-state in bits
+/// 3:0. CPUID leaf 5 counts the sub-states of each MWAIT C-state; one
+/// without sub-states is not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MwaitHints {
+    /// Indexed by `CState`; None if the state is not supported
+    hints: [Option<u32>; 4],
+}
+
+impl MwaitHints {
+    /// Decode CPUID.05H ECX and EDX
+    ///
+    /// C1 and C2 take MWAIT C1 and C2, and C3 the deepest MWAIT state past
+    /// C2. Without the enumeration only C1 is known to exist.
+    pub fn from_cpuid(ecx: u32, edx: u32) -> Self {
+        let substates = |n: u32| (edx >> (n * 4)) & 0xF;
+        let mut hints = [None; 4];
+        hints[CState::C1 as usize] = Some(0x00);
+        if ecx & MWAIT_EXTENSIONS != 0 {
+            hints[CState::C2 as usize] = (substates(2) != 0).then_some(0x10);
+            hints[CState::C3 as usize] = (3..8).rev().find(|&n| substates(n) != 0).map(|n| (n - 1) << 4);
+        }
+        Self { hints }
+    }
+
+    /// The deepest supported state no deeper than `c_state`, and its hint
+    pub fn resolve(&self, c_state: CState) -> (CState, u32) {
+        [CState::C3, CState::C2]
+            .into_iter()
+            .filter(|&state| state as usize <= c_state as usize)
+            .find_map(|state| Some((state, self.hints[state as usize]?)))
+            .unwrap_or((CState::C1, 0x00))
+    }
+
+    /// The deepest supported state
+    pub fn deepest(&self) -> CState {
+        self.resolve(CState::C3).0
+    }
+}
+
+/// Hints of the boot CPU, taken to hold for every CPU
+#[cfg(not(test))]
+pub fn mwait_hints() -> &'static MwaitHints {
+    static HINTS: Once<MwaitHints> = Once::new();
+    HINTS.call_once(|| {
+        let (ecx, edx) = fanga_arch_x86_64::cpuid::cpu_features().mwait().unwrap_or((0, 0));
+        MwaitHints::from_cpuid(ecx, edx)
+    })
+}
+
+#[cfg(test)]
+pub fn mwait_hints() -> &'static MwaitHints {
+    static HINTS: MwaitHints = MwaitHints { hints: [None, Some(0x00), Some(0x10), Some(0x20)] };
+    &HINTS
+}
+
+/// Check CPUID.01H:ECX.MONITOR[bit 3]
+#[cfg(not(test))]
+pub fn mwait_supported() -> bool {
+    fanga_arch_x86_64::cpuid::has(fanga_arch_x86_64::cpuid::Feature::Monitor)
+}
+
+#[cfg(test)]
+pub fn mwait_supported() -> bool {
+    false
+}
+
+/// Address armed by `monitor`; a write to it wakes an `mwait`ing CPU
+static IDLE_MONITOR: AtomicU64 = AtomicU64::new(0);
+
+/// Wake CPUs waiting in `mwait` without sending an interrupt
+pub fn idle_kick() {
+    IDLE_MONITOR.fetch_add(1, Ordering::Release);
+}
+
+/// Enter an idle state until the next interrupt
+///
+/// Must be called with interrupts disabled; they are re-enabled atomically
+/// with the halt (`sti` shadow) so a pending wakeup cannot be lost, and are
+/// enabled on return. States the CPU lacks fall back to shallower ones.
+///
+/// # Returns
+/// The state the CPU was in
+pub fn idle_enter(c_state: CState, method: IdleMethod) -> CState {
+    let (c_state, hint) = match method {
+        IdleMethod::Hlt => (CState::C1, 0),
+        IdleMethod::Mwait => mwait_hints().resolve(c_state),
+    };
+    CPU_POWER.lock().c_state = c_state;
+    
+    match method {
+        IdleMethod::Hlt => cpu_idle_hlt(),
+        IdleMethod::Mwait => cpu_idle_mwait(hint),
+    }
+    
+    CPU_POWER.lock().c_state = CState::C0;
+    c_state
+}
+
+#[cfg(not(test))]
+fn cpu_idle_hlt() {
+    unsafe {
+        core::arch::asm!("sti; hlt", options(nomem, nostack));
+    }
+}
+
+#[cfg(not(test))]
+fn cpu_idle_mwait(hint: u32) {
+    unsafe {
+        core::arch::asm!(
+            "monitor",
+            in("rax") IDLE_MONITOR.as_ptr(),
+            in("ecx") 0u32,
+            in("edx") 0u32,
+            options(nostack),
+        );
+        core::arch::asm!(
+            "sti; mwait",
+            in("eax") hint,
+            in("ecx") 0u32,
+            options(nostack),
+        );
+    }
+}
+
+#[cfg(test)]
+fn cpu_idle_hlt() {}
+
+#[cfg(test)]
+fn cpu_idle_mwait(_hint: u32) {}
+
 /// Get current CPU C-state
 pub fn get_c_state() -> CState {
     CPU_POWER.lock().c_state
@@ -225,6 +692,76 @@ mod tests {
         assert_eq!(get_frequency_mhz(), 2400);
     }
 
+    #[test]
+    fn test_frequency_tables() {
+        let intel = intel_levels(8, 30);
+        assert_eq!(intel.len(), 23);
+        assert_eq!(intel[0], PerfLevel { frequency_mhz: 3000, control: 30 });
+        assert_eq!(intel[22].frequency_mhz, 800);
+
+        let amd = amd_levels(&[Some(3500), Some(2800), None, Some(2200), None]);
+        assert_eq!(amd.iter().map(|level| level.control).collect::<Vec<_>>(), [0, 1, 3]);
+        assert_eq!(amd[2].frequency_mhz, 2200);
+
+        assert_eq!(level_of(PState::P0, 23), 0);
+        assert_eq!(level_of(PState::P1, 23), 7);
+        assert_eq!(level_of(PState::P3, 23), 22);
+        assert_eq!(level_of(PState::P3, 1), 0);
+        assert_eq!(p_state_of(7, 23), PState::P1);
+        assert_eq!(p_state_of(22, 23), PState::P3);
+        assert_eq!(p_state_of(0, 1), PState::P0);
+    }
+
+    #[test]
+    fn test_governor() {
+        let levels = intel_levels(8, 30);
+        assert_eq!(select_level(&levels, 100), 0);
+        assert_eq!(select_level(&levels, UP_THRESHOLD), 0);
+        // 40% load: 1500 MHz keeps it at the threshold
+        assert_eq!(levels[select_level(&levels, 40)].frequency_mhz, 1500);
+        assert_eq!(levels[select_level(&levels, 0)].frequency_mhz, 800);
+        assert_eq!(select_level(&[], 50), 0);
+
+        assert_eq!(load_percent(100, 25, 1), 75);
+        assert_eq!(load_percent(100, 150, 2), 25);
+        assert_eq!(load_percent(100, 500, 2), 0);
+        assert_eq!(load_percent(0, 0, 4), 0);
+    }
+
+    #[test]
+    fn test_hwp_request() {
+        let caps = HwpCapabilities { highest: 42, guaranteed: 30, most_efficient: 8, lowest: 4 };
+        assert_eq!(hwp_request(&caps, ScalingPolicy::Performance).min, 42);
+        let balanced = hwp_request(&caps, ScalingPolicy::Balanced);
+        assert_eq!((balanced.min, balanced.max, balanced.epp), (4, 42, cpufreq::EPP_BALANCED));
+        assert_eq!(hwp_request(&caps, ScalingPolicy::PowerSave).max, 8);
+
+        // A thermal limit pulls the whole range down
+        let limited = limit_hwp_request(hwp_request(&caps, ScalingPolicy::Performance), 20);
+        assert_eq!((limited.min, limited.max), (20, 20));
+        assert_eq!(limit_hwp_request(balanced, 20).min, 4);
+    }
+
+    #[test]
+    fn test_mwait_hints() {
+        // Sub-states for every MWAIT C-state up to C7
+        let client = MwaitHints::from_cpuid(0x3, 0x1114_2120);
+        assert_eq!(client.resolve(CState::C1), (CState::C1, 0x00));
+        assert_eq!(client.resolve(CState::C2), (CState::C2, 0x10));
+        assert_eq!(client.resolve(CState::C3), (CState::C3, 0x60));
+
+        // No MWAIT C2: C2 requests fall back to C1
+        let no_c2 = MwaitHints::from_cpuid(0x1, 0x0000_1020);
+        assert_eq!(no_c2.resolve(CState::C2), (CState::C1, 0x00));
+        assert_eq!(no_c2.resolve(CState::C3), (CState::C3, 0x20));
+        assert_eq!(no_c2.deepest(), CState::C3);
+
+        // Without the enumeration, only C1
+        let basic = MwaitHints::from_cpuid(0, 0x0000_1120);
+        assert_eq!(basic.resolve(CState::C3), (CState::C1, 0x00));
+        assert_eq!(basic.deepest(), CState::C1);
+    }
+
     #[test]
     fn test_c_state() {
         enter_c_state(CState::C0).unwrap();
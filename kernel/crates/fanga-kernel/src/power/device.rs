@@ -4,10 +4,12 @@
 //! - Device power states (D0-D3)
 //! - Device power state transitions
 //! - Device power policy
+//! - The driver suspend/resume callback chain run around system sleep
 
 use spin::Mutex;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 /// Device Power State (ACPI D-states)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -73,9 +75,21 @@ impl Default for DeviceInfo {
     }
 }
 
+/// Suspend and resume callbacks of a driver
+#[derive(Debug, Clone, Copy)]
+pub struct PmOps {
+    /// Quiesce the device and save whatever resume needs
+    pub suspend: fn() -> Result<(), &'static str>,
+    /// Bring the device back after the platform lost its state
+    pub resume: fn() -> Result<(), &'static str>,
+}
+
 /// Global device power manager
 static DEVICE_POWER: Mutex<BTreeMap<String, DeviceInfo>> = Mutex::new(BTreeMap::new());
 
+/// Drivers with callbacks, in registration order
+static DRIVERS: Mutex<Vec<(String, PmOps)>> = Mutex::new(Vec::new());
+
 /// Initialize device power management
 pub fn init() {
     let mut devices = DEVICE_POWER.lock();
@@ -103,6 +117,23 @@ pub fn register_device(
     Ok(())
 }
 
+/// Register a driver's suspend/resume callbacks
+///
+/// The device is registered too if it is not yet. Drivers should register
+/// after the drivers they depend on: suspend runs in reverse registration
+/// order and resume in registration order.
+pub fn register_driver(name: &str, ops: PmOps) -> Result<(), &'static str> {
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|(driver, _)| driver == name) {
+        return Err("Driver already registered");
+    }
+    drivers.push((String::from(name), ops));
+    drop(drivers);
+
+    DEVICE_POWER.lock().entry(String::from(name)).or_default();
+    Ok(())
+}
+
 /// Unregister a device from power management
 pub fn unregister_device(name: &str) -> Result<(), &'static str> {
     let mut devices = DEVICE_POWER.lock();
@@ -159,28 +190,60 @@ pub fn get_device_state(name: &str) -> Result<DevicePowerState, &'static str> {
         .ok_or("Device not found")
 }
 
-/// Power down all non-critical devices
+/// Run the suspend callbacks of `drivers`, last registered first
+///
+/// If one fails, the drivers already suspended are resumed and its error
+/// returned.
+fn suspend_drivers(drivers: &[(String, PmOps)]) -> Result<(), &'static str> {
+    for (index, (name, ops)) in drivers.iter().enumerate().rev() {
+        if let Err(e) = (ops.suspend)() {
+            crate::log_warn!(target: "power", "{} failed to suspend: {}", name, e);
+            let _ = resume_drivers(&drivers[index + 1..]);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Run the resume callbacks of `drivers` in registration order
+///
+/// A failure does not stop the others; the first error is returned.
+fn resume_drivers(drivers: &[(String, PmOps)]) -> Result<(), &'static str> {
+    let mut result = Ok(());
+    for (name, ops) in drivers {
+        if let Err(e) = (ops.resume)() {
+            crate::log_warn!(target: "power", "{} failed to resume: {}", name, e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// Quiesce every driver and power down all non-critical devices
+///
+/// The callbacks run without the device lock held, so drivers may change
+/// their own power state from them.
 pub fn suspend_all_devices() -> Result<(), &'static str> {
+    let drivers = DRIVERS.lock().clone();
+    suspend_drivers(&drivers)?;
+
     let mut devices = DEVICE_POWER.lock();
-    
-    for (name, device) in devices.iter_mut() {
+    for device in devices.values_mut() {
         if !device.is_critical && device.capabilities.supports_d3 {
             device.state = DevicePowerState::D3;
         }
     }
-    
     Ok(())
 }
 
-/// Resume all devices to D0 state
+/// Resume all devices to D0 state, then every driver
 pub fn resume_all_devices() -> Result<(), &'static str> {
-    let mut devices = DEVICE_POWER.lock();
-    
-    for (name, device) in devices.iter_mut() {
+    for device in DEVICE_POWER.lock().values_mut() {
         device.state = DevicePowerState::D0;
     }
-    
-    Ok(())
+
+    let drivers = DRIVERS.lock().clone();
+    resume_drivers(&drivers)
 }
 
 /// Get list of registered devices
@@ -269,4 +332,41 @@ mod tests {
         unregister_device("dev2").unwrap();
         unregister_device("critical").unwrap();
     }
+
+    static CALLS: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    fn call(name: &'static str) -> Result<(), &'static str> {
+        CALLS.lock().push(name);
+        Ok(())
+    }
+
+    fn ops(suspend: fn() -> Result<(), &'static str>, resume: fn() -> Result<(), &'static str>) -> PmOps {
+        PmOps { suspend, resume }
+    }
+
+    #[test]
+    fn test_driver_chain() {
+        let bus = ops(|| call("bus suspend"), || call("bus resume"));
+        let disk = ops(|| call("disk suspend"), || call("disk resume"));
+        let stuck = ops(|| Err("busy"), || call("stuck resume"));
+        let chain = |list: &[(&str, PmOps)]| -> Vec<(String, PmOps)> {
+            list.iter().map(|&(name, ops)| (String::from(name), ops)).collect()
+        };
+
+        // Suspend backwards, resume forwards
+        let drivers = chain(&[("bus", bus), ("disk", disk)]);
+        suspend_drivers(&drivers).unwrap();
+        resume_drivers(&drivers).unwrap();
+        assert_eq!(*CALLS.lock(), ["disk suspend", "bus suspend", "bus resume", "disk resume"]);
+
+        // A failure resumes only the drivers already suspended
+        CALLS.lock().clear();
+        let drivers = chain(&[("bus", bus), ("stuck", stuck), ("disk", disk)]);
+        assert_eq!(suspend_drivers(&drivers), Err("busy"));
+        assert_eq!(*CALLS.lock(), ["disk suspend", "disk resume"]);
+
+        let idle = ops(|| Ok(()), || Ok(()));
+        register_driver("chain_test", idle).unwrap();
+        assert_eq!(register_driver("chain_test", idle), Err("Driver already registered"));
+    }
 }
@@ -2,9 +2,11 @@
 //!
 //! This module provides comprehensive power management functionality including:
 //! - CPU frequency scaling and idle states (P-states, C-states)
+//! - Thermal monitoring and throttling
 //! - Device power state management (D0-D3)
 //! - System suspend and resume (S1, S3)
 //! - Hibernate support (S4)
+//! - Soft off (S5) and reboot
 //! - Battery monitoring and management
 //!
 //! # Overview
@@ -53,13 +55,40 @@ pub mod device;
 pub mod suspend;
 pub mod hibernate;
 pub mod battery;
+pub mod reboot;
+pub mod thermal;
 
 // Re-export commonly used types
 pub use cpu::{PState, CState, ScalingPolicy, CpuPowerState};
 pub use device::{DevicePowerState, DevicePowerCapabilities};
-pub use suspend::SleepState;
+pub use suspend::{poweroff, SleepState};
 pub use hibernate::HibernateState;
 pub use battery::{BatteryStatus, PowerSource, BatteryInfo};
+pub use reboot::reboot;
+
+/// reboot(): magic numbers guarding against stray calls
+pub const REBOOT_MAGIC1: u32 = 0xFEE1_DEAD;
+pub const REBOOT_MAGIC2: [u32; 4] = [0x2812_1969, 0x0512_1996, 0x1604_1998, 0x2011_2000];
+
+/// reboot() command: restart the machine
+pub const REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+/// reboot() command: turn the power off
+pub const REBOOT_CMD_POWER_OFF: u32 = 0x4321_FEDC;
+
+/// Handle the reboot() system call
+///
+/// # Returns
+/// Does not return for a valid command; a negative error code otherwise
+pub fn sys_reboot(magic1: u32, magic2: u32, cmd: u32) -> i64 {
+    if magic1 != REBOOT_MAGIC1 || !REBOOT_MAGIC2.contains(&magic2) {
+        return crate::syscall::EINVAL;
+    }
+    match cmd {
+        REBOOT_CMD_RESTART => reboot(),
+        REBOOT_CMD_POWER_OFF => poweroff(),
+        _ => crate::syscall::EINVAL,
+    }
+}
 
 /// Initialize power management subsystem
 pub fn init() {
@@ -68,6 +97,7 @@ pub fn init() {
     suspend::init();
     hibernate::init();
     battery::init();
+    thermal::init();
 }
 
 /// Get system power summary
@@ -77,6 +107,8 @@ pub fn get_power_summary() -> PowerSummary {
         sleep_state: suspend::get_sleep_state(),
         hibernate_state: hibernate::get_state(),
         battery: battery::get_info(),
+        idle: crate::task::idle::idle_tasks().total_stats(),
+        thermal: thermal::status(),
     }
 }
 
@@ -91,6 +123,10 @@ pub struct PowerSummary {
     pub hibernate_state: HibernateState,
     /// Battery information
     pub battery: BatteryInfo,
+    /// C-state usage and residency of all CPUs
+    pub idle: crate::task::idle::CStateStats,
+    /// Latest temperature sample
+    pub thermal: thermal::ThermalStatus,
 }
 
 #[cfg(test)]
@@ -132,4 +168,12 @@ mod tests {
         // Test battery
         assert!(!battery::is_battery_critical());
     }
+
+    #[test]
+    fn test_sys_reboot_rejects_bad_calls() {
+        use crate::syscall::EINVAL;
+        assert_eq!(sys_reboot(0, REBOOT_MAGIC2[0], REBOOT_CMD_POWER_OFF), EINVAL);
+        assert_eq!(sys_reboot(REBOOT_MAGIC1, 1, REBOOT_CMD_POWER_OFF), EINVAL);
+        assert_eq!(sys_reboot(REBOOT_MAGIC1, REBOOT_MAGIC2[0], 0x1234), EINVAL);
+    }
 }
@@ -0,0 +1,119 @@
+//! System Reset
+//!
+//! Restarting a PC has no single reliable method, so `restart` tries them
+//! in turn:
+//! 1. The FADT reset register, the ACPI way
+//! 2. A reset pulse from the 8042 keyboard controller
+//! 3. A triple fault, which every x86 CPU answers with a reset
+//!
+//! `panic=N` on the command line reboots N seconds after a kernel panic
+//! instead of entering the debugger; a negative N reboots at once.
+
+/// 8042 status and command port
+const KBD_STATUS_PORT: u16 = 0x64;
+/// 8042 status: the input buffer is full
+const KBD_STATUS_INPUT_FULL: u8 = 1 << 1;
+/// 8042 command: pulse the CPU reset line
+const KBD_CMD_RESET: u8 = 0xFE;
+
+/// Status polls before writing the 8042 command anyway
+const KBD_POLLS: usize = 100_000;
+
+/// Time each method gets to take effect
+const RESET_SETTLE_MS: u64 = 50;
+
+/// Flush the persistent log and restart the machine
+pub fn reboot() -> ! {
+    crate::io::pstore::sync();
+    restart()
+}
+
+/// Restart the machine at once, without flushing anything
+///
+/// Safe to call from panic and debugger context.
+pub fn restart() -> ! {
+    fanga_arch_x86_64::interrupts::disable();
+    let fadt = crate::acpi::fadt();
+
+    if let Some(fadt) = fadt {
+        if let Some(reg) = fadt.reset_reg {
+            if unsafe { reg.write(fadt.reset_value as u64) }.is_ok() {
+                settle(RESET_SETTLE_MS);
+            }
+        }
+    }
+
+    if fadt.is_none_or(|fadt| fadt.has_8042()) {
+        unsafe {
+            for _ in 0..KBD_POLLS {
+                if fanga_arch_x86_64::port::inb(KBD_STATUS_PORT) & KBD_STATUS_INPUT_FULL == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            fanga_arch_x86_64::port::outb(KBD_STATUS_PORT, KBD_CMD_RESET);
+        }
+        settle(RESET_SETTLE_MS);
+    }
+
+    triple_fault()
+}
+
+/// Load an empty IDT and raise an exception: with no handler for it, nor
+/// for the double fault that follows, the CPU shuts down and resets
+fn triple_fault() -> ! {
+    let empty_idt = [0u8; 10];
+    unsafe {
+        core::arch::asm!("lidt [{}]", "int3", in(reg) empty_idt.as_ptr(), options(nostack));
+    }
+    loop {
+        unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Wait about `ms` milliseconds without relying on interrupts
+fn settle(ms: u64) {
+    if crate::task::clocksource::tsc_khz().is_some() {
+        crate::task::time::delay_us(ms * 1000);
+    } else {
+        // Each write to the POST port takes about a microsecond
+        for _ in 0..ms * 1000 {
+            unsafe { fanga_arch_x86_64::port::outb(0x80, 0) };
+        }
+    }
+}
+
+/// Seconds to wait before rebooting after a panic, from `panic=`
+///
+/// None (or 0) waits forever.
+pub fn panic_timeout_in(cmdline: &str) -> Option<i64> {
+    crate::cmdline::option_in(cmdline, "panic")?.parse().ok().filter(|&secs| secs != 0)
+}
+
+/// Reboot after the `panic=` timeout, if one is set
+///
+/// Called by the panic handler; returns if the machine should stay up.
+pub fn reboot_on_panic() {
+    let Some(secs) = panic_timeout_in(crate::cmdline::cmdline()) else {
+        return;
+    };
+    if secs > 0 {
+        crate::console_println!("Rebooting in {} seconds", secs);
+        settle(secs as u64 * 1000);
+    }
+    restart()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_timeout() {
+        assert_eq!(panic_timeout_in("quiet panic=10"), Some(10));
+        assert_eq!(panic_timeout_in("panic=-1"), Some(-1));
+        assert_eq!(panic_timeout_in("panic=0"), None);
+        assert_eq!(panic_timeout_in("panic=soon"), None);
+        assert_eq!(panic_timeout_in("quiet"), None);
+    }
+}
@@ -4,8 +4,61 @@
 //! - Sleep states (S1-S5)
 //! - Suspend to RAM (S3)
 //! - Suspend preparation and restoration
+//! - Soft off (S5) through the ACPI PM1 control registers
+//!
+//! Suspend to RAM runs on the boot CPU alone, the others having been
+//! taken offline. Drivers are quiesced through the `device` callback
+//! chain; with preemption and interrupts then disabled no other task
+//! runs. The interrupt controller and CPU state are saved, the wakeup
+//! trampoline is installed in its low page and given to the firmware as
+//! the FACS waking vector, and the S3 sleep type is written to the PM1
+//! control registers. On wake the trampoline brings the CPU back to long
+//! mode on the saved page tables, `wakeup_entry` restores the per-CPU
+//! state, and `suspend_to_ram` continues as if the sleep were a call that
+//! returned: it sets up the timers and interrupt controllers again,
+//! resumes the drivers and returns.
+
+use alloc::vec::Vec;
+use fanga_arch_x86_64::sleep::{self, SleepContext};
+use spin::{Mutex, Once};
+
+use crate::acpi::Fadt;
+
+/// PM1 control: the machine is in ACPI mode
+const PM1_SCI_EN: u64 = 1 << 0;
+/// PM1 control: sleep type to enter
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0x7 << PM1_SLP_TYP_SHIFT;
+/// PM1 control: enter the sleep type
+const PM1_SLP_EN: u64 = 1 << 13;
+
+/// Polls of SCI_EN after asking the firmware for ACPI mode
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// QEMU's isa-debug-exit device, as the test scripts configure it
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Polls after SLP_EN before deciding the machine did not go to sleep
+const S3_ENTRY_POLLS: usize = 10_000_000;
 
-use spin::Mutex;
+/// Size of the stack `wakeup_entry` starts on
+const WAKEUP_STACK_SIZE: usize = 16 * 1024;
+
+/// Stack the CPU uses from the trampoline until it is back on the
+/// suspending task's stack
+#[repr(C, align(16))]
+struct WakeupStack([u8; WAKEUP_STACK_SIZE]);
+
+static mut WAKEUP_STACK: WakeupStack = WakeupStack([0; WAKEUP_STACK_SIZE]);
+
+/// State of the CPU that put the machine to sleep
+static mut SLEEP_CONTEXT: SleepContext = SleepContext::new();
+
+/// Low page the wakeup trampoline runs from: virtual and physical address
+static WAKEUP_PAGE: Once<(u64, u64)> = Once::new();
+
+/// Why `enter_s3` came back without sleeping
+static S3_ERROR: Mutex<Option<&'static str>> = Mutex::new(None);
 
 /// System Sleep State (ACPI S-states)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -22,6 +75,19 @@ pub enum SleepState {
     S5,
 }
 
+impl SleepState {
+    /// The number of the state, `x` in `\_Sx`
+    pub fn number(self) -> u8 {
+        match self {
+            Self::S0 => 0,
+            Self::S1 => 1,
+            Self::S3 => 3,
+            Self::S4 => 4,
+            Self::S5 => 5,
+        }
+    }
+}
+
 /// System power state
 #[derive(Debug, Clone, Copy)]
 pub struct SystemPowerState {
@@ -58,84 +124,250 @@ pub fn init() {
     state.is_resuming = false;
 }
 
-/// Prepare system for suspend
+/// Set aside the page the wakeup trampoline runs from
+///
+/// Called early in boot, while memory below 1 MiB is still free.
+pub fn reserve_wakeup_page() {
+    match crate::memory::mmio::alloc_low_page(sleep::WAKEUP_LIMIT) {
+        Ok(page) => {
+            WAKEUP_PAGE.call_once(|| page);
+        }
+        Err(e) => crate::log_warn!(target: "power", "Suspend to RAM unavailable: {}", e),
+    }
+}
+
+/// Check that the firmware and the kernel can suspend to RAM
+fn check_s3() -> Result<(), &'static str> {
+    let fadt = crate::acpi::fadt().ok_or("No FADT")?;
+    if fadt.is_hw_reduced() || !fadt.pm1a_cnt.is_present() {
+        return Err("No PM1 control registers");
+    }
+    crate::acpi::sleep::sleep_type(SleepState::S3.number()).ok_or("Sleep state not supported by firmware")?;
+    crate::acpi::facs().ok_or("No FACS")?;
+    WAKEUP_PAGE.get().ok_or("No wakeup page")?;
+    // Parked CPUs need INIT-SIPI to run again, which hotplug does not do
+    if crate::smp::cpu::cpu_count() > 1 {
+        return Err("Take the other CPUs offline first");
+    }
+    Ok(())
+}
+
+/// Quiesce the drivers and flush what should survive a failed wake
 fn prepare_suspend() -> Result<(), &'static str> {
-    // In a real implementation:
-    // 1. Notify all drivers to save state
-    // 2. Flush disk caches
-    // 3. Stop non-essential processes
-    // 4. Save CPU context
-    
-    // For now, just suspend devices
     super::device::suspend_all_devices()?;
-    
+    crate::io::pstore::sync();
     Ok(())
 }
 
-/// Restore system after resume
+/// Bring the drivers back after a wake
 fn restore_resume() -> Result<(), &'static str> {
-    // In a real implementation:
-    // 1. Restore CPU context
-    // 2. Resume all drivers
-    // 3. Restore device states
-    // 4. Resume processes
-    
-    // For now, just resume devices
-    super::device::resume_all_devices()?;
-    
-    Ok(())
+    super::device::resume_all_devices()
+}
+
+/// Interrupt controller state the platform loses in S3
+struct PlatformState {
+    pic_masks: (u8, u8),
+    ioapic_entries: Vec<u64>,
+}
+
+impl PlatformState {
+    fn save() -> Self {
+        let mut ioapic_entries = Vec::new();
+        fanga_arch_x86_64::interrupts::ioapic::save_entries(|entry| ioapic_entries.push(entry));
+        Self {
+            pic_masks: unsafe { fanga_arch_x86_64::interrupts::pic::get_masks() },
+            ioapic_entries,
+        }
+    }
+
+    /// Set the interrupt controllers, the timers and the clocks up again
+    fn restore(&self) {
+        use fanga_arch_x86_64::interrupts::{apic_timer, idt, ioapic, nmi_watchdog, pic, pit};
+
+        unsafe {
+            pic::remap(idt::PIC1_OFFSET, idt::PIC2_OFFSET);
+            pic::set_masks(self.pic_masks.0, self.pic_masks.1);
+            pit::init(pit::PIT_DEFAULT_FREQ);
+        }
+        ioapic::restore_entries(self.ioapic_entries.iter().copied());
+        if apic_timer::is_active() {
+            apic_timer::start_cpu();
+        }
+        nmi_watchdog::start_cpu();
+
+        crate::task::clocksource::resume();
+        crate::task::realtime::init();
+    }
+}
+
+/// Write the S3 sleep type; runs from `save_and_sleep`
+///
+/// Returns only if the machine stayed awake, with the reason left in
+/// `S3_ERROR`.
+extern "C" fn enter_s3() -> u64 {
+    // Nothing may be left only in the caches when they lose power
+    unsafe { core::arch::asm!("wbinvd", options(nostack, preserves_flags)) };
+    let error = match enter_acpi_state(SleepState::S3) {
+        Ok(()) => {
+            for _ in 0..S3_ENTRY_POLLS {
+                core::hint::spin_loop();
+            }
+            "Machine did not enter S3"
+        }
+        Err(e) => e,
+    };
+    *S3_ERROR.lock() = Some(error);
+    1
+}
+
+/// Where the wakeup trampoline lands, in 64-bit mode on `WAKEUP_STACK`
+extern "C" fn wakeup_entry() -> ! {
+    unsafe {
+        let ctx = &raw const SLEEP_CONTEXT;
+        // Only the boot CPU, number 0, is online across S3
+        fanga_arch_x86_64::resume_cpu(0, &*ctx);
+        sleep::resume_context(ctx)
+    }
+}
+
+/// Sleep in S3 until a wake event, with interrupts disabled
+fn sleep_s3() -> Result<(), &'static str> {
+    let &(virt, phys) = WAKEUP_PAGE.get().ok_or("No wakeup page")?;
+    let signature = crate::acpi::facs().map(|facs| facs.hardware_signature);
+    let platform = PlatformState::save();
+
+    crate::memory::mmio::map_identity(phys)?;
+    let result = unsafe {
+        let ctx = &raw mut SLEEP_CONTEXT;
+        (*ctx).save_cpu_state();
+        let stack_top = &raw const WAKEUP_STACK as u64 + WAKEUP_STACK_SIZE as u64;
+        sleep::install_wakeup(virt as *mut u8, phys, (*ctx).cr3(), wakeup_entry, stack_top)
+            .and_then(|()| crate::acpi::set_waking_vector(phys as u32))
+            .and_then(|()| match sleep::save_and_sleep(ctx, enter_s3) {
+                0 => Ok(()),
+                _ => Err(S3_ERROR.lock().take().unwrap_or("Machine did not enter S3")),
+            })
+    };
+    if let Err(e) = crate::memory::mmio::unmap_identity(phys) {
+        crate::log_warn!(target: "power", "Wakeup page still mapped: {}", e);
+    }
+
+    platform.restore();
+    if result.is_ok() && crate::acpi::facs().map(|facs| facs.hardware_signature) != signature {
+        crate::log_warn!(target: "power", "Firmware reports different hardware after wake");
+    }
+    result
 }
 
 /// Suspend system to RAM (S3 state)
+///
+/// Returns after the machine wakes, or at once with an error if it cannot
+/// sleep; the drivers run again in both cases.
 pub fn suspend_to_ram() -> Result<(), &'static str> {
     let mut state = SYSTEM_POWER.lock();
-    
     if state.sleep_state != SleepState::S0 {
         return Err("System not in S0 state");
     }
-    
+    check_s3()?;
     state.is_suspending = true;
     drop(state);
-    
-    // Prepare for suspend
-    prepare_suspend()?;
-    
-    // Enter suspend state
+
+    let result = prepare_suspend().and_then(|()| {
+        crate::log_info!(target: "power", "Suspending to RAM");
+        crate::preempt::counter::preempt_disable();
+        let result = fanga_arch_x86_64::interrupts::without_interrupts(|| {
+            SYSTEM_POWER.lock().sleep_state = SleepState::S3;
+            let result = sleep_s3();
+            let mut state = SYSTEM_POWER.lock();
+            state.is_suspending = false;
+            state.is_resuming = true;
+            result
+        });
+        crate::preempt::counter::preempt_enable();
+        if let Err(e) = restore_resume() {
+            crate::log_warn!(target: "power", "Resume incomplete: {}", e);
+        }
+        result
+    });
+
     let mut state = SYSTEM_POWER.lock();
-    state.sleep_state = SleepState::S3;
+    state.sleep_state = SleepState::S0;
     state.is_suspending = false;
-    
-    // In a real implementation, we would:
-    // 1. Write ACPI PM1 control register to enter S3
-    // 2. CPU would halt and system would power down
-    // 3. On wake event (keyboard, network, timer), firmware would resume
-    // 4. Control would return here
-    
-    Ok(())
+    state.is_resuming = false;
+    drop(state);
+    if result.is_ok() {
+        crate::log_info!(target: "power", "Resumed from suspend to RAM");
+    }
+    result
 }
 
-/// Resume system from suspend
-pub fn resume_from_suspend() -> Result<(), &'static str> {
-    let mut state = SYSTEM_POWER.lock();
-    
-    if state.sleep_state == SleepState::S0 {
-        return Err("System not suspended");
+/// Switch the chipset from legacy to ACPI mode, if the firmware has not
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), &'static str> {
+    let enabled = || unsafe { fadt.pm1a_cnt.read() }.map(|value| value & PM1_SCI_EN != 0);
+    if enabled()? || fadt.smi_cmd == 0 {
+        return Ok(());
+    }
+    unsafe { fanga_arch_x86_64::port::outb(fadt.smi_cmd as u16, fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if enabled()? {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("Firmware did not enable ACPI mode")
+}
+
+/// Enter a sleep state through the PM1 control registers
+///
+/// Writes the state's SLP_TYP values from the firmware's `\_Sx` package
+/// to PM1a and PM1b control, then sets SLP_EN in both. Returns on wake
+/// from a sleep state; never returns once S5 takes effect.
+fn enter_acpi_state(state: SleepState) -> Result<(), &'static str> {
+    let fadt = crate::acpi::fadt().ok_or("No FADT")?;
+    if fadt.is_hw_reduced() || !fadt.pm1a_cnt.is_present() {
+        return Err("No PM1 control registers");
+    }
+    let sleep_type = crate::acpi::sleep::sleep_type(state.number()).ok_or("Sleep state not supported by firmware")?;
+    enable_acpi_mode(&fadt)?;
+
+    let blocks = [(fadt.pm1a_cnt, sleep_type.a), (fadt.pm1b_cnt, sleep_type.b)];
+    let mut values = [0u64; 2];
+    for ((reg, typ), value) in blocks.iter().zip(values.iter_mut()) {
+        if reg.is_present() {
+            let current = unsafe { reg.read()? };
+            *value = (current & !(PM1_SLP_TYP_MASK | PM1_SLP_EN)) | (((*typ as u64) << PM1_SLP_TYP_SHIFT) & PM1_SLP_TYP_MASK);
+            unsafe { reg.write(*value)? };
+        }
+    }
+    for ((reg, _), value) in blocks.iter().zip(values) {
+        if reg.is_present() {
+            unsafe { reg.write(value | PM1_SLP_EN)? };
+        }
     }
-    
-    state.is_resuming = true;
-    let prev_state = state.sleep_state;
-    drop(state);
-    
-    // Restore system state
-    restore_resume()?;
-    
-    let mut state = SYSTEM_POWER.lock();
-    state.sleep_state = SleepState::S0;
-    state.is_resuming = false;
-    
     Ok(())
 }
 
+/// Turn the machine off (S5)
+///
+/// Flushes the persistent log, then tries the ACPI PM1 registers and,
+/// failing that, QEMU's isa-debug-exit device. Halts if the power stays
+/// on.
+pub fn poweroff() -> ! {
+    crate::io::pstore::sync();
+    SYSTEM_POWER.lock().sleep_state = SleepState::S5;
+    fanga_arch_x86_64::interrupts::disable();
+
+    if let Err(e) = enter_acpi_state(SleepState::S5) {
+        crate::log_warn!(target: "power", "ACPI poweroff failed: {}", e);
+    }
+    unsafe { fanga_arch_x86_64::port::outl(QEMU_EXIT_PORT, 0) };
+
+    crate::log_error!(target: "power", "Poweroff failed, halting");
+    loop {
+        unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
+
 /// Check if system is suspending
 pub fn is_suspending() -> bool {
     SYSTEM_POWER.lock().is_suspending
@@ -177,6 +409,13 @@ pub fn standby() -> Result<(), &'static str> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_sleep_state_number() {
+        assert_eq!(SleepState::S0.number(), 0);
+        assert_eq!(SleepState::S3.number(), 3);
+        assert_eq!(SleepState::S5.number(), 5);
+    }
+
     #[test]
     fn test_suspend_init() {
         init();
@@ -188,15 +427,10 @@ mod tests {
     #[test]
     fn test_suspend_to_ram() {
         init();
-        super::super::device::init();
-        
-        // Test suspend
-        suspend_to_ram().unwrap();
-        assert_eq!(get_sleep_state(), SleepState::S3);
-        
-        // Test resume
-        resume_from_suspend().unwrap();
-        assert_eq!(get_sleep_state(), SleepState::S0);
+
+        // The host has no firmware to sleep through
+        assert!(suspend_to_ram().is_err());
+        assert_ne!(get_sleep_state(), SleepState::S3);
     }
 
     #[test]
@@ -211,17 +445,8 @@ mod tests {
 
     #[test]
     fn test_invalid_suspend() {
-        init();
-        super::super::device::init();
-        
-        // Suspend once
-        suspend_to_ram().unwrap();
-        
-        // Try to suspend again - should fail
-        let result = suspend_to_ram();
-        assert!(result.is_err());
-        
-        // Resume
-        resume_from_suspend().unwrap();
+        // Without a FADT, before anything is quiesced
+        assert_eq!(check_s3(), Err("No FADT"));
+        assert_eq!(sleep::WAKEUP_LIMIT, 0x10_0000);
     }
 }
@@ -0,0 +1,360 @@
+//! Thermal Monitoring and Throttling
+//!
+//! Temperatures come from the CPU's digital thermal sensors (see
+//! `fanga_arch_x86_64::thermal`), the package sensor taking precedence
+//! when there is one. Trip points come from the ACPI thermal zones (see
+//! `acpi::thermal`) and from TjMax. Once started, a monitor samples the
+//! sensor every `THERMAL_INTERVAL_MS` and:
+//! - past the passive trip point, keeps the CPUs one frequency level
+//!   slower for every `THROTTLE_STEP_C` degrees beyond it
+//! - within `MODULATION_MARGIN_C` of the critical trip point, also
+//!   modulates their clocks, harder with every degree
+//! - at the critical trip point of an ACPI zone, powers the machine off,
+//!   as ACPI requires
+//!
+//! Without ACPI trip points, the passive trip point is
+//! `PASSIVE_BELOW_TJ_MAX_C` below TjMax and the critical one is TjMax.
+//! The CPU throttles itself there too, so reaching it is no reason to
+//! shut down.
+//!
+//! The latest sample is kept for `status()` and written to
+//! `/proc/thermal`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use spin::{Mutex, Once};
+
+use fanga_arch_x86_64::thermal as sensor;
+use crate::acpi::thermal::{deci_kelvin_to_celsius, ThermalZone};
+use crate::smp::cpu::MAX_CPUS;
+
+/// Interval between temperature samples
+pub const THERMAL_INTERVAL_MS: u64 = 1000;
+
+/// Passive trip point below TjMax, without an ACPI one
+pub const PASSIVE_BELOW_TJ_MAX_C: i32 = 15;
+
+/// Degrees past the passive trip point for each frequency level dropped
+pub const THROTTLE_STEP_C: i32 = 3;
+
+/// Degrees below the critical trip point where clock modulation starts
+pub const MODULATION_MARGIN_C: i32 = 5;
+
+/// File the monitor writes its readings to
+pub const PROC_THERMAL: &str = "/proc/thermal";
+
+/// Trip points, in degrees Celsius
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TripPoints {
+    /// Start slowing the CPUs down
+    pub passive: i32,
+    /// Throttle as hard as possible
+    pub critical: i32,
+    /// Shut down: the lowest ACPI `_CRT`, if any zone has one
+    pub shutdown: Option<i32>,
+}
+
+impl TripPoints {
+    /// Trip points from TjMax and the ACPI zones; the lowest of each wins
+    pub fn new(tj_max: u8, zones: &[ThermalZone]) -> Self {
+        let lowest = |trip: fn(&ThermalZone) -> Option<u32>| zones.iter().filter_map(trip).map(deci_kelvin_to_celsius).min();
+        let tj_max = tj_max as i32;
+        let shutdown = lowest(|zone| zone.critical);
+        let critical = shutdown.map_or(tj_max, |shutdown| shutdown.min(tj_max));
+        let passive = lowest(|zone| zone.passive).unwrap_or(tj_max - PASSIVE_BELOW_TJ_MAX_C).min(critical);
+        Self { passive, critical, shutdown }
+    }
+}
+
+/// What the monitor does about a temperature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttle {
+    /// Fastest frequency level allowed, 0 for any
+    pub level_limit: usize,
+    /// Eighths of the time the clocks run
+    pub duty: u8,
+    /// The machine must be turned off
+    pub shutdown: bool,
+}
+
+impl Throttle {
+    /// No throttling
+    pub const NONE: Self = Self { level_limit: 0, duty: sensor::MODULATION_STEPS, shutdown: false };
+
+    /// Whether the CPUs run slower than they would
+    pub fn is_active(&self) -> bool {
+        self.level_limit > 0 || self.duty < sensor::MODULATION_STEPS
+    }
+}
+
+/// How hard to throttle at `celsius`, with `levels` frequency levels
+pub fn throttle_for(celsius: i32, trips: &TripPoints, levels: usize) -> Throttle {
+    let level_limit = match celsius - trips.passive {
+        past if past < 0 => 0,
+        past => ((past / THROTTLE_STEP_C) as usize + 1).min(levels.saturating_sub(1)),
+    };
+    let steps = sensor::MODULATION_STEPS as i32;
+    let duty = match celsius - (trips.critical - MODULATION_MARGIN_C) {
+        past if past < 0 => steps,
+        past => (steps - 1 - past).max(1),
+    };
+    Throttle {
+        level_limit,
+        duty: duty as u8,
+        shutdown: trips.shutdown.is_some_and(|shutdown| celsius >= shutdown),
+    }
+}
+
+/// The latest temperature sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermalStatus {
+    /// Temperature of the core the monitor ran on
+    pub core_celsius: Option<i32>,
+    pub package_celsius: Option<i32>,
+    /// The CPU is throttling itself
+    pub prochot: bool,
+    pub tj_max: u8,
+    pub trips: Option<TripPoints>,
+    pub throttle: Throttle,
+}
+
+impl ThermalStatus {
+    /// Before the first sample
+    pub const EMPTY: Self = Self {
+        core_celsius: None,
+        package_celsius: None,
+        prochot: false,
+        tj_max: 0,
+        trips: None,
+        throttle: Throttle::NONE,
+    };
+
+    /// The temperature the trip points are compared with
+    pub fn celsius(&self) -> Option<i32> {
+        self.package_celsius.or(self.core_celsius)
+    }
+}
+
+/// Thermal zones found by `init()`
+static ZONES: Once<Vec<ThermalZone>> = Once::new();
+
+static STATUS: Mutex<ThermalStatus> = Mutex::new(ThermalStatus::EMPTY);
+
+/// Clock duty every CPU should run at
+static TARGET_DUTY: AtomicU8 = AtomicU8::new(sensor::MODULATION_STEPS);
+
+/// Bumped whenever the target duty changes
+static DUTY_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Generation each CPU last applied
+static APPLIED_DUTY: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether the monitor's work is queued
+static MONITOR_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Find the thermal zones
+///
+/// Needs the ACPI tables.
+pub fn init() {
+    let zones = ZONES.call_once(crate::acpi::thermal::zones);
+    for zone in zones {
+        let celsius = |trip: Option<u32>| trip.map(deci_kelvin_to_celsius);
+        crate::log_info!(
+            target: "thermal",
+            "Zone {}: critical {:?} C, hot {:?} C, passive {:?} C",
+            zone.name(),
+            celsius(zone.critical),
+            celsius(zone.hot),
+            celsius(zone.passive)
+        );
+    }
+}
+
+/// The thermal zones of this machine
+pub fn zones() -> &'static [ThermalZone] {
+    ZONES.get().map_or(&[], Vec::as_slice)
+}
+
+/// The latest sample
+pub fn status() -> ThermalStatus {
+    *STATUS.lock()
+}
+
+/// Read the sensors and throttle as their temperature calls for
+fn sample() {
+    let tj_max = sensor::tj_max();
+    let core = sensor::core_status();
+    let package = sensor::has_package_sensor().then(sensor::package_status);
+    let trips = TripPoints::new(tj_max, zones());
+
+    let mut status = ThermalStatus {
+        core_celsius: core.celsius(tj_max),
+        package_celsius: package.and_then(|package| package.celsius(tj_max)),
+        prochot: core.prochot || package.is_some_and(|package| package.prochot),
+        tj_max,
+        trips: Some(trips),
+        throttle: Throttle::NONE,
+    };
+    if let Some(celsius) = status.celsius() {
+        let levels = super::cpu::scaling().map_or(0, |scaling| scaling.levels.len());
+        status.throttle = throttle_for(celsius, &trips, levels);
+    }
+
+    let previous = core::mem::replace(&mut *STATUS.lock(), status).throttle;
+    apply(previous, &status);
+    let _ = crate::fs::write_file(PROC_THERMAL, format_status(&status, zones()).as_bytes());
+}
+
+/// Act on a new throttle decision
+fn apply(previous: Throttle, status: &ThermalStatus) {
+    let throttle = status.throttle;
+    if throttle.shutdown {
+        crate::log_error!(target: "thermal", "Critical temperature {:?} C, powering off", status.celsius());
+        super::poweroff();
+    }
+    match (previous.is_active(), throttle.is_active()) {
+        (false, true) => crate::log_warn!(target: "thermal", "{:?} C, throttling", status.celsius()),
+        (true, false) => crate::log_info!(target: "thermal", "{:?} C, throttling stopped", status.celsius()),
+        _ => {}
+    }
+
+    super::cpu::set_thermal_limit(throttle.level_limit);
+    if throttle.duty != TARGET_DUTY.swap(throttle.duty, Ordering::Relaxed) {
+        DUTY_GENERATION.fetch_add(1, Ordering::Release);
+        sync_throttle(crate::smp::current_cpu_id().as_usize());
+    }
+}
+
+/// Program this CPU's clock modulation, if it has not yet
+///
+/// Called from the idle loop, so each CPU catches up on changes made
+/// elsewhere.
+pub fn sync_throttle(cpu: usize) {
+    let generation = DUTY_GENERATION.load(Ordering::Acquire);
+    let Some(applied) = APPLIED_DUTY.get(cpu) else {
+        return;
+    };
+    if applied.swap(generation, Ordering::Relaxed) == generation || !sensor::has_clock_modulation() {
+        return;
+    }
+    unsafe { sensor::set_clock_modulation(TARGET_DUTY.load(Ordering::Relaxed)) };
+}
+
+/// The `/proc/thermal` text
+pub fn format_status(status: &ThermalStatus, zones: &[ThermalZone]) -> String {
+    let mut text = String::new();
+    let mut temperature = |name: &str, celsius: Option<i32>| {
+        if let Some(celsius) = celsius {
+            let _ = writeln!(text, "{}: {} C", name, celsius);
+        }
+    };
+    temperature("core", status.core_celsius);
+    temperature("package", status.package_celsius);
+    let _ = writeln!(text, "tjmax: {} C", status.tj_max);
+    if let Some(trips) = status.trips {
+        let _ = writeln!(text, "passive: {} C", trips.passive);
+        let _ = writeln!(text, "critical: {} C", trips.critical);
+    }
+    let _ = writeln!(text, "prochot: {}", status.prochot as u8);
+    let _ = writeln!(
+        text,
+        "throttle: level {}, duty {}/{}",
+        status.throttle.level_limit,
+        status.throttle.duty,
+        sensor::MODULATION_STEPS
+    );
+    for zone in zones {
+        let _ = write!(text, "zone {}:", zone.name());
+        for (name, trip) in [("critical", zone.critical), ("hot", zone.hot), ("passive", zone.passive)] {
+            if let Some(trip) = trip {
+                let _ = write!(text, " {} {} C", name, deci_kelvin_to_celsius(trip));
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn monitor_tick(_: usize) {
+    sample();
+    schedule_monitor();
+}
+
+/// Queue the next sample
+fn schedule_monitor() {
+    let work = crate::task::workqueue::Work::new(monitor_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, THERMAL_INTERVAL_MS);
+}
+
+/// Start the temperature monitor
+///
+/// Needs the workqueues and the root file system. Does nothing without a
+/// CPU thermal sensor.
+pub fn start_monitor() {
+    if !sensor::has_sensor() {
+        crate::log_info!(target: "thermal", "No CPU thermal sensor");
+        return;
+    }
+    if !MONITOR_RUNNING.swap(true, Ordering::AcqRel) {
+        sample();
+        schedule_monitor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(critical: Option<u32>, passive: Option<u32>) -> ThermalZone {
+        ThermalZone { name: *b"TZ00", critical, hot: None, passive }
+    }
+
+    #[test]
+    fn test_trip_points() {
+        // TjMax alone
+        let trips = TripPoints::new(100, &[]);
+        assert_eq!(trips, TripPoints { passive: 85, critical: 100, shutdown: None });
+
+        // The lowest ACPI trip points win, but throttling never waits past TjMax
+        let zones = [zone(Some(3832), Some(3532)), zone(Some(3732), None)];
+        assert_eq!(TripPoints::new(90, &zones), TripPoints { passive: 80, critical: 90, shutdown: Some(100) });
+    }
+
+    #[test]
+    fn test_throttle_for() {
+        let trips = TripPoints { passive: 85, critical: 100, shutdown: Some(105) };
+        assert_eq!(throttle_for(60, &trips, 4), Throttle::NONE);
+        assert!(!throttle_for(84, &trips, 4).is_active());
+
+        // One level per step past the passive trip point, down to the slowest
+        assert_eq!(throttle_for(85, &trips, 4).level_limit, 1);
+        assert_eq!(throttle_for(88, &trips, 4).level_limit, 2);
+        assert_eq!(throttle_for(94, &trips, 4).level_limit, 3);
+        assert_eq!(throttle_for(94, &trips, 0).level_limit, 0);
+
+        // Clock modulation near the critical trip point
+        assert_eq!(throttle_for(94, &trips, 4).duty, sensor::MODULATION_STEPS);
+        assert_eq!(throttle_for(95, &trips, 4).duty, 7);
+        assert_eq!(throttle_for(100, &trips, 4).duty, 2);
+        assert_eq!(throttle_for(103, &trips, 4).duty, 1);
+        assert!(!throttle_for(104, &trips, 4).shutdown);
+        assert!(throttle_for(105, &trips, 4).shutdown);
+    }
+
+    #[test]
+    fn test_format_status() {
+        let status = ThermalStatus {
+            core_celsius: Some(52),
+            tj_max: 100,
+            trips: Some(TripPoints::new(100, &[])),
+            ..ThermalStatus::EMPTY
+        };
+        let text = format_status(&status, &[zone(Some(3732), None)]);
+        assert_eq!(
+            text,
+            "core: 52 C\ntjmax: 100 C\npassive: 85 C\ncritical: 100 C\nprochot: 0\nthrottle: level 0, duty 8/8\nzone TZ00: critical 100 C\n"
+        );
+    }
+}
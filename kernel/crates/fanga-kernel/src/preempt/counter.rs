@@ -1,23 +1,47 @@
 //! Preemption Counter Management
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use super::stats::{self, LatencyKind};
+use crate::smp::cpu::{current_cpu_id, MAX_CPUS};
 
 /// Per-CPU preemption disable count
-/// In a full SMP implementation, this would be in per-CPU data
-static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+///
+/// A CPU's count only changes on that CPU, and a task cannot migrate
+/// while its CPU's count is raised.
+static PREEMPT_COUNT: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// TSC when preemption was last disabled, per CPU, 0 when not measured
+static DISABLED_SINCE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Call site of the outermost `preempt_disable`, per CPU
+static DISABLED_AT: [AtomicPtr<Location<'static>>; MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
 
 /// Initialize preemption counters
 pub fn init_preempt_counters() {
-    PREEMPT_COUNT.store(0, Ordering::SeqCst);
+    for count in &PREEMPT_COUNT {
+        count.store(0, Ordering::SeqCst);
+    }
 }
 
 /// Disable preemption
 ///
 /// This increments the preemption counter. Preemption is disabled when the
-/// counter is greater than zero.
+/// counter is greater than zero. The outermost call is the call site
+/// the time preemption stays disabled is recorded for.
 #[inline]
+#[track_caller]
 pub fn preempt_disable() {
-    PREEMPT_COUNT.fetch_add(1, Ordering::SeqCst);
+    let cpu = current_cpu_id().as_usize();
+    if PREEMPT_COUNT[cpu].fetch_add(1, Ordering::SeqCst) == 0 {
+        if let Some(start) = stats::latency_start() {
+            let caller = Location::caller() as *const Location<'static> as *mut Location<'static>;
+            DISABLED_AT[cpu].store(caller, Ordering::Relaxed);
+            DISABLED_SINCE[cpu].store(start, Ordering::Relaxed);
+        }
+    }
 }
 
 /// Enable preemption
@@ -26,19 +50,26 @@ pub fn preempt_disable() {
 /// preemption is re-enabled and a reschedule check is performed.
 #[inline]
 pub fn preempt_enable() {
-    let old_count = PREEMPT_COUNT.fetch_sub(1, Ordering::SeqCst);
+    let cpu = current_cpu_id().as_usize();
+    let old_count = PREEMPT_COUNT[cpu].fetch_sub(1, Ordering::SeqCst);
     
     // Check if we just re-enabled preemption
     if old_count == 1 {
+        let start = DISABLED_SINCE[cpu].swap(0, Ordering::Relaxed);
+        let caller = DISABLED_AT[cpu].load(Ordering::Relaxed);
+        if start != 0 && !caller.is_null() {
+            stats::record_site_latency(LatencyKind::PreemptOff, unsafe { &*caller }, start);
+        }
+        
         // Preemption is now enabled, check if we should reschedule
         super::preempt_check();
     }
 }
 
-/// Get current preemption count
+/// Get this CPU's preemption count
 #[inline]
 pub fn preempt_count() -> usize {
-    PREEMPT_COUNT.load(Ordering::SeqCst)
+    PREEMPT_COUNT[current_cpu_id().as_usize()].load(Ordering::SeqCst)
 }
 
 /// Check if preemption is enabled (count == 0)
@@ -54,6 +85,7 @@ pub struct PreemptGuard {
 
 impl PreemptGuard {
     /// Create a new preemption guard (disables preemption)
+    #[track_caller]
     pub fn new() -> Self {
         preempt_disable();
         Self { _private: () }
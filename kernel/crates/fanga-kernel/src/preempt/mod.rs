@@ -5,19 +5,26 @@
 //! - Preemption points
 //! - Preemption statistics
 //! - Integration with scheduler
+//! - Preemption on return from interrupts to user mode
 
 pub mod counter;
 pub mod points;
 pub mod stats;
 
 pub use counter::{preempt_disable, preempt_enable, preempt_count, preemptible};
-pub use points::{preempt_check, should_reschedule};
+pub use points::{cond_resched, preempt_check, should_reschedule};
 pub use stats::{PreemptionStats, get_preemption_stats};
 
 /// Initialize preemption subsystem
 pub fn init() {
     // Initialize per-CPU preemption counters
     counter::init_preempt_counters();
+
+    // Timer ticks, device IRQs and reschedule IPIs switch tasks on exit
+    // to user mode
+    unsafe {
+        fanga_arch_x86_64::interrupts::idt::set_irq_exit_hook(points::preempt_irq_exit);
+    }
 }
 
 /// Check if we should yield to scheduler
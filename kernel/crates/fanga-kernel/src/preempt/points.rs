@@ -2,22 +2,34 @@
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-/// Flag indicating whether a reschedule is needed
-static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+use crate::smp::cpu::{current_cpu_id, CpuId, MAX_CPUS};
 
-/// Set the "need reschedule" flag
+/// Per-CPU flag indicating whether a reschedule is needed
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Set the "need reschedule" flag of this CPU
 pub fn set_need_resched() {
-    NEED_RESCHED.store(true, Ordering::SeqCst);
+    set_need_resched_cpu(current_cpu_id());
+}
+
+/// Set the "need reschedule" flag of another CPU
+///
+/// The CPU only notices at its next preemption point; send it a
+/// reschedule IPI to make that prompt.
+pub fn set_need_resched_cpu(cpu: CpuId) {
+    if let Some(flag) = NEED_RESCHED.get(cpu.as_usize()) {
+        flag.store(true, Ordering::SeqCst);
+    }
 }
 
-/// Clear the "need reschedule" flag
+/// Clear the "need reschedule" flag of this CPU
 pub fn clear_need_resched() {
-    NEED_RESCHED.store(false, Ordering::SeqCst);
+    NEED_RESCHED[current_cpu_id().as_usize()].store(false, Ordering::SeqCst);
 }
 
-/// Check if a reschedule is needed
+/// Check if this CPU needs a reschedule
 pub fn should_reschedule() -> bool {
-    NEED_RESCHED.load(Ordering::SeqCst)
+    NEED_RESCHED[current_cpu_id().as_usize()].load(Ordering::SeqCst)
 }
 
 /// Check preemption and reschedule if needed
@@ -40,25 +52,60 @@ pub fn preempt_check() {
         return;
     }
     
-    // TODO: Check if we're in an interrupt
-    // For now, we'll assume we're not
+    // Interrupt handlers run with interrupts off; so does code that must
+    // not be switched away from. Interrupts preempt in `preempt_irq_exit`.
+    if !fanga_arch_x86_64::interrupts::are_enabled() {
+        return;
+    }
     
     // Trigger reschedule
     do_preempt();
 }
 
+/// Preemption on the way out of an interrupt
+///
+/// Registered as the arch IRQ exit hook: it runs after every timer,
+/// device and IPI handler has sent its EOI. If the interrupt came from
+/// user mode, a reschedule is pending and preemption is enabled, the
+/// interrupted task is switched out right here, on its interrupt stack
+/// frame; it carries on to IRETQ once scheduled back in.
+///
+/// Kernel code is left alone: it may hold a `spin::Mutex`, which does
+/// not disable preemption, so it is only switched away from at the
+/// explicit points (`preempt_check`, `cond_resched`, blocking waits).
+pub fn preempt_irq_exit(from_user: bool) {
+    if !from_user || !should_reschedule() || !super::preemptible() {
+        return;
+    }
+    do_preempt();
+}
+
+/// Voluntary preemption point for long kernel loops
+///
+/// Reschedules if this CPU has a reschedule pending, so a long loop (a
+/// cache flush, a FAT chain walk, a PMM scan, a network queue drain)
+/// cannot starve the scheduler even where the kernel is not preempted.
+/// Costs one flag load when nothing is pending. Call it between
+/// iterations, with no spinlock held; with preemption disabled it does
+/// nothing.
+///
+/// Returns whether the CPU was rescheduled.
+pub fn cond_resched() -> bool {
+    if !should_reschedule() || !super::preemptible() {
+        return false;
+    }
+    let switched = crate::task::sched_timer::schedule_pending();
+    if switched {
+        super::stats::record_voluntary_preempt();
+    }
+    switched
+}
+
 /// Perform the actual preemption
 fn do_preempt() {
-    // Clear the flag
-    clear_need_resched();
-    
-    // TODO: Call into scheduler to pick next task
-    // This would involve:
-    // 1. Saving current context
-    // 2. Calling scheduler
-    // 3. Switching to new task
-    
-    // For now, this is a placeholder
+    if crate::task::sched_timer::schedule_pending() {
+        super::stats::record_involuntary_preempt();
+    }
 }
 
 /// Preemption point macro (for convenience)
@@ -84,6 +131,11 @@ mod tests {
         
         clear_need_resched();
         assert!(!should_reschedule());
+        
+        // Another CPU's flag is its own
+        set_need_resched_cpu(CpuId::new(5));
+        assert!(!should_reschedule());
+        assert!(NEED_RESCHED[5].load(Ordering::SeqCst));
     }
     
     #[test]
@@ -97,8 +149,16 @@ mod tests {
         
         // Should not reschedule while disabled
         preempt_check();
+        preempt_irq_exit(true);
+        assert!(!cond_resched());
         assert!(should_reschedule()); // Flag should still be set
         
         preempt_enable();
+        
+        // Interrupted kernel code is not switched away from
+        set_need_resched();
+        preempt_irq_exit(false);
+        assert!(should_reschedule());
+        clear_need_resched();
     }
 }
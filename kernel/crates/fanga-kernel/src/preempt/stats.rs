@@ -1,6 +1,31 @@
 //! Preemption Statistics
+//!
+//! Besides the preemption counters, measures two latencies per call site
+//! with the TSC:
+//! - How long preemption stays disabled, from the outermost
+//!   `preempt_disable` (the site) to the matching `preempt_enable`
+//! - How long a woken task waits until the scheduler picks it, from the
+//!   `unblock_task` call (the site) that woke it
+//!
+//! Each site keeps a count, the total and longest time and a log2
+//! histogram, so a new lock that keeps preemption off too long shows up
+//! with its worst case and percentiles. Measuring is off until
+//! `enable_tracking()`; a disabled check costs one atomic load. The
+//! `preemptlat` shell command shows the report.
 
-use core::sync::atomic::{AtomicU64, Ordering};
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use fanga_arch_x86_64::tsc;
+
+use crate::profiling::stats::{latency_bucket, LATENCY_BUCKETS};
+
+/// Call sites kept per kind of latency
+pub const MAX_LATENCY_SITES: usize = 32;
 
 /// Preemption statistics
 #[derive(Debug, Clone, Copy)]
@@ -65,6 +90,257 @@ pub fn record_latency(latency_us: u64) {
     }
 }
 
+/// What a latency measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyKind {
+    /// Preemption disabled
+    PreemptOff,
+    /// A woken task waiting to run
+    Wakeup,
+}
+
+/// Latencies from one call site
+struct SiteHistogram {
+    location: AtomicPtr<Location<'static>>,
+    count: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl SiteHistogram {
+    const fn new() -> Self {
+        Self {
+            location: AtomicPtr::new(core::ptr::null_mut()),
+            count: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+        }
+    }
+    
+    fn record(&self, cycles: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+        self.buckets[latency_bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+    }
+    
+    fn reset(&self) {
+        self.location.store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+        self.total_cycles.store(0, Ordering::Relaxed);
+        self.max_cycles.store(0, Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Histograms of the call sites of one kind of latency
+struct SiteTable {
+    sites: [SiteHistogram; MAX_LATENCY_SITES],
+    /// Latencies from call sites that found no free slot
+    other_sites: AtomicU64,
+}
+
+impl SiteTable {
+    const fn new() -> Self {
+        Self {
+            sites: [const { SiteHistogram::new() }; MAX_LATENCY_SITES],
+            other_sites: AtomicU64::new(0),
+        }
+    }
+    
+    /// Slot of a call site, claiming a free one on its first latency
+    fn site(&self, caller: &'static Location<'static>) -> Option<&SiteHistogram> {
+        let wanted = caller as *const Location<'static> as *mut Location<'static>;
+        for site in &self.sites {
+            let location = site.location.load(Ordering::Acquire);
+            if location == wanted {
+                return Some(site);
+            }
+            if location.is_null() {
+                match site.location.compare_exchange(
+                    core::ptr::null_mut(),
+                    wanted,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some(site),
+                    // Another CPU claimed the slot, maybe for the same site
+                    Err(location) if location == wanted => return Some(site),
+                    Err(_) => {}
+                }
+            }
+        }
+        None
+    }
+    
+    fn record(&self, caller: &'static Location<'static>, cycles: u64) {
+        match self.site(caller) {
+            Some(site) => site.record(cycles),
+            None => {
+                self.other_sites.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    
+    fn reset(&self) {
+        for site in &self.sites {
+            site.reset();
+        }
+        self.other_sites.store(0, Ordering::Relaxed);
+    }
+    
+    fn snapshot(&self) -> Vec<SiteLatencyStats> {
+        let mut sites: Vec<SiteLatencyStats> = self
+            .sites
+            .iter()
+            .filter_map(|site| {
+                let location = site.location.load(Ordering::Acquire);
+                (!location.is_null()).then(|| SiteLatencyStats {
+                    location: unsafe { &*location },
+                    count: site.count.load(Ordering::Relaxed),
+                    total_cycles: site.total_cycles.load(Ordering::Relaxed),
+                    max_cycles: site.max_cycles.load(Ordering::Relaxed),
+                    buckets: core::array::from_fn(|i| site.buckets[i].load(Ordering::Relaxed)),
+                })
+            })
+            .collect();
+        sites.sort_by_key(|site| core::cmp::Reverse(site.max_cycles));
+        sites
+    }
+}
+
+/// Latencies from one call site, in TSC cycles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteLatencyStats {
+    pub location: &'static Location<'static>,
+    pub count: u64,
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+    /// Latencies per log2 bucket of cycles (see `LATENCY_BUCKETS`)
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl SiteLatencyStats {
+    /// Average latency in TSC cycles
+    pub fn average_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.count).unwrap_or(0)
+    }
+    
+    /// Upper bound of the bucket holding the `percentile`th shortest
+    /// latency, capped at the longest
+    pub fn percentile_cycles(&self, percentile: u64) -> u64 {
+        let rank = (self.count * percentile.min(100)).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return (1u64 << (i + 1)).min(self.max_cycles);
+            }
+        }
+        self.max_cycles
+    }
+}
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static PREEMPT_OFF_SITES: SiteTable = SiteTable::new();
+static WAKEUP_SITES: SiteTable = SiteTable::new();
+
+fn table(kind: LatencyKind) -> &'static SiteTable {
+    match kind {
+        LatencyKind::PreemptOff => &PREEMPT_OFF_SITES,
+        LatencyKind::Wakeup => &WAKEUP_SITES,
+    }
+}
+
+/// Start measuring latencies
+pub fn enable_tracking() {
+    TRACKING.store(true, Ordering::Release);
+}
+
+/// Stop measuring latencies
+pub fn disable_tracking() {
+    TRACKING.store(false, Ordering::Release);
+}
+
+/// Check whether latencies are measured
+#[inline]
+pub fn is_tracking() -> bool {
+    TRACKING.load(Ordering::Relaxed)
+}
+
+/// Timestamp to start a latency at, if measuring
+#[inline]
+pub fn latency_start() -> Option<u64> {
+    is_tracking().then(tsc::rdtsc)
+}
+
+/// Record a latency from `caller` that started at TSC `start`
+pub fn record_site_latency(kind: LatencyKind, caller: &'static Location<'static>, start: u64) {
+    let cycles = tsc::rdtsc().saturating_sub(start);
+    table(kind).record(caller, cycles);
+    if kind == LatencyKind::Wakeup {
+        if let Some(khz) = crate::task::clocksource::tsc_khz().filter(|&khz| khz > 0) {
+            record_latency(cycles.saturating_mul(1000) / khz);
+        }
+    }
+}
+
+/// Latencies of every call site, longest worst case first
+pub fn latency_stats(kind: LatencyKind) -> Vec<SiteLatencyStats> {
+    table(kind).snapshot()
+}
+
+/// Clear the per call site latencies
+pub fn reset_latencies() {
+    PREEMPT_OFF_SITES.reset();
+    WAKEUP_SITES.reset();
+}
+
+/// Write a duration of `cycles` in microseconds, or in cycles without a
+/// known TSC frequency
+fn write_duration(text: &mut String, cycles: u64, tsc_khz: Option<u64>) {
+    let _ = match tsc_khz {
+        Some(khz) if khz > 0 => write!(text, " {:>9}", cycles.saturating_mul(1000) / khz),
+        _ => write!(text, " {:>9}", cycles),
+    };
+}
+
+/// One section of the latency report
+fn format_sites(text: &mut String, title: &str, sites: &[SiteLatencyStats], other_sites: u64, tsc_khz: Option<u64>) {
+    let _ = writeln!(text, "{}", title);
+    let _ = writeln!(text, "  {:<40} {:>9} {:>9} {:>9} {:>9}", "SITE", "COUNT", "AVG", "P99", "MAX");
+    for site in sites {
+        let location = alloc::format!("{}:{}", site.location.file(), site.location.line());
+        let _ = write!(text, "  {:<40} {:>9}", location, site.count);
+        write_duration(text, site.average_cycles(), tsc_khz);
+        write_duration(text, site.percentile_cycles(99), tsc_khz);
+        write_duration(text, site.max_cycles, tsc_khz);
+        text.push('\n');
+    }
+    if other_sites > 0 {
+        let _ = writeln!(text, "  {:<40} {:>9}", "(other sites)", other_sites);
+    }
+}
+
+/// The `preemptlat` report
+///
+/// Times are in microseconds if `tsc_khz` is known, in TSC cycles
+/// otherwise.
+pub fn format_latency_report(tsc_khz: Option<u64>) -> String {
+    let unit = if tsc_khz.is_some() { "us" } else { "cycles" };
+    let mut text = String::new();
+    for (kind, title) in [(LatencyKind::PreemptOff, "Preemption disabled"), (LatencyKind::Wakeup, "Wakeup to run")] {
+        let title = alloc::format!("{} (in {})", title, unit);
+        let other_sites = table(kind).other_sites.load(Ordering::Relaxed);
+        format_sites(&mut text, &title, &latency_stats(kind), other_sites, tsc_khz);
+    }
+    text
+}
+
 /// Get current preemption statistics
 pub fn get_preemption_stats() -> PreemptionStats {
     PreemptionStats {
@@ -121,4 +397,51 @@ mod tests {
         let stats = get_preemption_stats();
         assert_eq!(stats.max_latency_us, 200);
     }
+    
+    #[track_caller]
+    fn here() -> &'static Location<'static> {
+        Location::caller()
+    }
+    
+    #[test]
+    fn test_site_table() {
+        let table = SiteTable::new();
+        let (a, b) = (here(), here());
+        for cycles in [100, 200, 5_000] {
+            table.record(a, cycles);
+        }
+        table.record(b, 1_000_000);
+        
+        let sites = table.snapshot();
+        // Longest worst case first
+        assert_eq!((sites[0].location, sites[0].count), (b, 1));
+        let a_stats = &sites[1];
+        assert_eq!((a_stats.count, a_stats.total_cycles, a_stats.max_cycles), (3, 5_300, 5_000));
+        assert_eq!(a_stats.average_cycles(), 1_766);
+        assert_eq!(a_stats.percentile_cycles(50), 256);
+        assert_eq!(a_stats.percentile_cycles(99), 5_000);
+        
+        let mut text = String::new();
+        format_sites(&mut text, "Wakeup to run", &sites, 2, Some(1_000_000));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[2].trim_start().starts_with(b.file()));
+        assert!(lines[2].ends_with(&alloc::format!("{:>9} {:>9} {:>9}", 1_000, 1_000, 1_000)));
+        assert!(lines[4].ends_with(" 2"));
+        
+        table.reset();
+        assert!(table.snapshot().is_empty());
+    }
+    
+    #[test]
+    fn test_site_table_overflow() {
+        let table = SiteTable::new();
+        for _ in 0..MAX_LATENCY_SITES + 3 {
+            // Sites are told apart by address: every copy is a new site
+            let location: &'static Location<'static> = alloc::boxed::Box::leak(alloc::boxed::Box::new(*here()));
+            table.record(location, 1);
+        }
+        assert_eq!(table.snapshot().len(), MAX_LATENCY_SITES);
+        assert_eq!(table.other_sites.load(Ordering::Relaxed), 3);
+    }
 }
@@ -0,0 +1,206 @@
+//! Boot Time Tracing
+//!
+//! Records when each boot phase and the major subsystem inits in it start
+//! and end, as TSC readings:
+//! - Phases 1-6 of `boot::initialize`
+//! - Steps inside them (PMM, heap, drivers, shell, file system, network...)
+//!
+//! The timeline lives in a fixed array, so it works from the first line of
+//! phase 1, before the heap exists. After phase 6, `report` logs the phases
+//! and steps longest first; `BootTimelineOutput` formats the timeline like
+//! the other profiler output. Times are converted to microseconds with the
+//! TSC frequency the clock source found; without it they stay in cycles.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use fanga_arch_x86_64::tsc;
+use spin::Mutex;
+
+use super::output::{BootTimelineOutput, OutputFormat};
+
+/// Events kept in the timeline
+pub const MAX_BOOT_EVENTS: usize = 64;
+
+/// A boot phase or a step inside one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootEvent {
+    pub name: &'static str,
+    /// Phase the event is, or belongs to; 0 before phase 1
+    pub phase: u8,
+    /// The event is a whole phase rather than a step inside it
+    pub is_phase: bool,
+    /// TSC when the event started
+    pub start: u64,
+    /// TSC when it ended, 0 while it runs
+    pub end: u64,
+}
+
+impl BootEvent {
+    const EMPTY: Self = Self { name: "", phase: 0, is_phase: false, start: 0, end: 0 };
+
+    /// Length in TSC cycles, 0 while it runs
+    pub fn cycles(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// Convert TSC cycles to microseconds, or keep cycles without a frequency
+pub fn cycles_to_us(cycles: u64, tsc_khz: Option<u64>) -> u64 {
+    match tsc_khz {
+        Some(khz) if khz > 0 => cycles.saturating_mul(1000) / khz,
+        _ => cycles,
+    }
+}
+
+/// Boot phases and steps in the order they started
+pub struct BootTimeline {
+    events: [BootEvent; MAX_BOOT_EVENTS],
+    len: usize,
+    /// Events that did not fit
+    dropped: usize,
+    /// Phase now running
+    phase: u8,
+}
+
+impl BootTimeline {
+    /// Create an empty timeline
+    pub const fn new() -> Self {
+        Self { events: [BootEvent::EMPTY; MAX_BOOT_EVENTS], len: 0, dropped: 0, phase: 0 }
+    }
+
+    fn push(&mut self, event: BootEvent) -> Option<usize> {
+        if self.len == MAX_BOOT_EVENTS {
+            self.dropped += 1;
+            return None;
+        }
+        self.events[self.len] = event;
+        self.len += 1;
+        Some(self.len - 1)
+    }
+
+    /// Start phase `phase` at TSC `start`
+    ///
+    /// Returns the event index for `end`, None if the timeline is full.
+    pub fn begin_phase(&mut self, phase: u8, name: &'static str, start: u64) -> Option<usize> {
+        self.phase = phase;
+        self.push(BootEvent { name, phase, is_phase: true, start, end: 0 })
+    }
+
+    /// Start a step of the current phase at TSC `start`
+    pub fn begin_step(&mut self, name: &'static str, start: u64) -> Option<usize> {
+        let phase = self.phase;
+        self.push(BootEvent { name, phase, is_phase: false, start, end: 0 })
+    }
+
+    /// End the event at `index` at TSC `end`
+    pub fn end(&mut self, index: usize, end: u64) {
+        if let Some(event) = self.events[..self.len].get_mut(index) {
+            event.end = end;
+        }
+    }
+
+    /// Recorded events, in the order they started
+    pub fn events(&self) -> &[BootEvent] {
+        &self.events[..self.len]
+    }
+
+    /// Number of events that did not fit
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Default for BootTimeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The boot timeline
+static TIMELINE: Mutex<BootTimeline> = Mutex::new(BootTimeline::new());
+
+/// A running phase or step; ends when dropped
+#[must_use = "the span ends when dropped"]
+pub struct Span {
+    index: Option<usize>,
+}
+
+impl Span {
+    /// End the span now
+    pub fn end(self) {}
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            TIMELINE.lock().end(index, tsc::rdtsc());
+        }
+    }
+}
+
+/// Start boot phase `number`
+pub fn phase(number: u8, name: &'static str) -> Span {
+    Span { index: TIMELINE.lock().begin_phase(number, name, tsc::rdtsc()) }
+}
+
+/// Start a step of the current boot phase
+pub fn step(name: &'static str) -> Span {
+    Span { index: TIMELINE.lock().begin_step(name, tsc::rdtsc()) }
+}
+
+/// Copy of the timeline
+pub fn timeline() -> Vec<BootEvent> {
+    TIMELINE.lock().events().to_vec()
+}
+
+/// Log the boot phases and steps, longest first
+///
+/// Called once boot phase 6 has ended.
+pub fn report() {
+    let dropped = TIMELINE.lock().dropped();
+    let tsc_khz = crate::task::clocksource::tsc_khz();
+    let output = BootTimelineOutput::new(timeline(), tsc_khz, OutputFormat::Text);
+    for line in output.generate().lines().filter(|line| !line.is_empty()) {
+        crate::log_info!(target: "boot", "{}", line);
+    }
+    if dropped > 0 {
+        crate::log_warn!(target: "boot", "{} boot events not recorded", dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = BootTimeline::new();
+        let early = timeline.begin_step("early", 10).unwrap();
+        timeline.end(early, 20);
+        let memory = timeline.begin_phase(3, "memory", 100).unwrap();
+        let pmm = timeline.begin_step("pmm", 110).unwrap();
+        timeline.end(pmm, 150);
+        timeline.end(memory, 400);
+
+        let events = timeline.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!((events[0].phase, events[0].is_phase), (0, false));
+        assert_eq!((events[1].phase, events[1].is_phase, events[1].cycles()), (3, true, 300));
+        assert_eq!((events[2].phase, events[2].cycles()), (3, 40));
+    }
+
+    #[test]
+    fn test_timeline_full() {
+        let mut timeline = BootTimeline::new();
+        for i in 0..MAX_BOOT_EVENTS {
+            assert_eq!(timeline.begin_step("step", i as u64), Some(i));
+        }
+        assert_eq!(timeline.begin_phase(6, "post-init", 0), None);
+        assert_eq!(timeline.dropped(), 1);
+
+        // A running event has no length yet
+        assert_eq!(timeline.events()[0].cycles(), 0);
+        assert_eq!(cycles_to_us(3_000_000, Some(3_000_000)), 1_000);
+        assert_eq!(cycles_to_us(3_000_000, None), 3_000_000);
+    }
+}
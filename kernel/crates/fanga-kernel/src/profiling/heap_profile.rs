@@ -0,0 +1,371 @@
+//! Kernel Heap Allocation Profiler
+//!
+//! `GlobalHeapAllocator` reports every allocation and free here. While the
+//! profiler is on it counts:
+//! - Allocations, frees and bytes, for the allocation rate
+//! - Allocations per log2 size class, with the padding power-of-two slabs
+//!   would add to them
+//! - Every `sample_interval`th allocation's call stack, in a fixed table
+//!   of distinct stacks
+//!
+//! Recording runs inside the allocator, so it takes no lock and never
+//! allocates: counters are atomics and the stack table is claimed slot by
+//! slot. Stacks are resolved to the allocating function only when the
+//! statistics are read.
+//!
+//! Recording is off until `enable()` is called; a disabled check costs one
+//! atomic load per allocation.
+
+extern crate alloc;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use fanga_arch_x86_64::backtrace;
+
+use super::stats::{heap_size_class, HeapProfileStats, HeapSizeStats, HEAP_SIZE_CLASSES};
+
+/// Distinct call stacks kept
+pub const MAX_STACKS: usize = 64;
+
+/// Return addresses kept per call stack
+pub const STACK_DEPTH: usize = 8;
+
+/// Default allocations per sampled call stack
+pub const DEFAULT_SAMPLE_INTERVAL: u64 = 64;
+
+/// Call sites listed in the report
+const REPORT_SITES: usize = 10;
+
+/// Counters of one size class
+struct SizeCounters {
+    allocations: AtomicU64,
+    frees: AtomicU64,
+    bytes: AtomicU64,
+    padding: AtomicU64,
+}
+
+impl SizeCounters {
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            frees: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            padding: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Stack slot states
+const SLOT_FREE: u8 = 0;
+const SLOT_CLAIMED: u8 = 1;
+const SLOT_READY: u8 = 2;
+
+/// A sampled call stack
+struct StackSlot {
+    state: AtomicU8,
+    hash: AtomicU64,
+    frames: [AtomicU64; STACK_DEPTH],
+    samples: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl StackSlot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(SLOT_FREE),
+            hash: AtomicU64::new(0),
+            frames: [const { AtomicU64::new(0) }; STACK_DEPTH],
+            samples: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLE_INTERVAL: AtomicU64 = AtomicU64::new(DEFAULT_SAMPLE_INTERVAL);
+/// Time profiling started or was last reset, in nanoseconds since boot
+static START_NS: AtomicU64 = AtomicU64::new(0);
+/// Time profiling was turned off, 0 while it runs
+static STOP_NS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FREES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+static LOST_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static SIZES: [SizeCounters; HEAP_SIZE_CLASSES] = [const { SizeCounters::new() }; HEAP_SIZE_CLASSES];
+static STACKS: [StackSlot; MAX_STACKS] = [const { StackSlot::new() }; MAX_STACKS];
+
+/// Start recording
+pub fn enable() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        START_NS.store(crate::task::ktime_ns(), Ordering::Relaxed);
+        STOP_NS.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop recording, keeping the counts
+pub fn disable() {
+    if ENABLED.swap(false, Ordering::AcqRel) {
+        STOP_NS.store(crate::task::ktime_ns(), Ordering::Relaxed);
+    }
+}
+
+/// Check whether allocations are recorded
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Sample the call stack of one allocation in `interval`
+pub fn set_sample_interval(interval: u64) -> Result<(), &'static str> {
+    if interval == 0 {
+        return Err("Sample interval must be at least 1");
+    }
+    SAMPLE_INTERVAL.store(interval, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Clear all counts and call stacks
+pub fn reset() {
+    for counter in [&ALLOCATIONS, &FREES, &ALLOCATED_BYTES, &FREED_BYTES, &LOST_SAMPLES] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    for size in &SIZES {
+        size.allocations.store(0, Ordering::Relaxed);
+        size.frees.store(0, Ordering::Relaxed);
+        size.bytes.store(0, Ordering::Relaxed);
+        size.padding.store(0, Ordering::Relaxed);
+    }
+    for slot in &STACKS {
+        slot.samples.store(0, Ordering::Relaxed);
+        slot.bytes.store(0, Ordering::Relaxed);
+        slot.state.store(SLOT_FREE, Ordering::Release);
+    }
+    START_NS.store(crate::task::ktime_ns(), Ordering::Relaxed);
+    if !is_enabled() {
+        STOP_NS.store(START_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Record an allocation of `size` bytes
+///
+/// Called by the heap allocator for every successful allocation.
+#[inline]
+pub fn record_alloc(size: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let count = ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    let class = &SIZES[heap_size_class(size)];
+    class.allocations.fetch_add(1, Ordering::Relaxed);
+    class.bytes.fetch_add(size as u64, Ordering::Relaxed);
+    class.padding.fetch_add((size.next_power_of_two() - size) as u64, Ordering::Relaxed);
+
+    if count.is_multiple_of(SAMPLE_INTERVAL.load(Ordering::Relaxed)) {
+        sample_stack(size);
+    }
+}
+
+/// Record a free of `size` bytes
+#[inline]
+pub fn record_free(size: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    FREES.fetch_add(1, Ordering::Relaxed);
+    FREED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    SIZES[heap_size_class(size)].frees.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count the caller's call stack in the stack table
+#[inline(never)]
+fn sample_stack(size: usize) {
+    let mut frames = [0u64; STACK_DEPTH];
+    match backtrace::walk(backtrace::frame_pointer(), &mut frames) {
+        0 => {
+            LOST_SAMPLES.fetch_add(1, Ordering::Relaxed);
+        }
+        depth => add_stack(&frames[..depth], size as u64),
+    }
+}
+
+/// Count one sampled allocation of `bytes` from the call stack `frames`
+fn add_stack(frames: &[u64], bytes: u64) {
+    // FNV-1a over the return addresses
+    let hash = frames.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &frame| {
+        (hash ^ frame).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let matches = |slot: &StackSlot| {
+        slot.hash.load(Ordering::Relaxed) == hash
+            && slot.frames.iter().zip(frames.iter().chain(core::iter::repeat(&0)))
+                .all(|(kept, &frame)| kept.load(Ordering::Relaxed) == frame)
+    };
+
+    for slot in &STACKS {
+        let state = match slot.state.compare_exchange(SLOT_FREE, SLOT_CLAIMED, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                slot.hash.store(hash, Ordering::Relaxed);
+                for (i, kept) in slot.frames.iter().enumerate() {
+                    kept.store(frames.get(i).copied().unwrap_or(0), Ordering::Relaxed);
+                }
+                slot.state.store(SLOT_READY, Ordering::Release);
+                SLOT_READY
+            }
+            Err(state) => state,
+        };
+        if state == SLOT_READY && matches(slot) {
+            slot.samples.fetch_add(1, Ordering::Relaxed);
+            slot.bytes.fetch_add(bytes, Ordering::Relaxed);
+            return;
+        }
+    }
+    LOST_SAMPLES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Heap profile with call sites named from the kernel symbol table
+pub fn stats() -> HeapProfileStats {
+    stats_with(crate::debug::ksyms::resolve)
+}
+
+/// Heap profile, resolving call stack addresses to `(symbol, offset)`
+/// with `resolve`
+pub fn stats_with<'a>(resolve: impl Fn(u64) -> Option<(&'a str, u64)>) -> HeapProfileStats {
+    let end = match STOP_NS.load(Ordering::Relaxed) {
+        0 => crate::task::ktime_ns(),
+        stop => stop,
+    };
+    let mut stats = HeapProfileStats {
+        elapsed_ns: end.saturating_sub(START_NS.load(Ordering::Relaxed)),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        freed_bytes: FREED_BYTES.load(Ordering::Relaxed),
+        sample_interval: SAMPLE_INTERVAL.load(Ordering::Relaxed),
+        lost_samples: LOST_SAMPLES.load(Ordering::Relaxed),
+        ..HeapProfileStats::default()
+    };
+
+    for (class, size) in SIZES.iter().enumerate() {
+        let size = HeapSizeStats {
+            size: 1 << class,
+            allocations: size.allocations.load(Ordering::Relaxed),
+            frees: size.frees.load(Ordering::Relaxed),
+            bytes: size.bytes.load(Ordering::Relaxed),
+            padding: size.padding.load(Ordering::Relaxed),
+        };
+        if size.allocations > 0 || size.frees > 0 {
+            stats.sizes.push(size);
+        }
+    }
+
+    for slot in STACKS.iter().filter(|slot| slot.state.load(Ordering::Acquire) == SLOT_READY) {
+        let samples = slot.samples.load(Ordering::Relaxed);
+        if samples == 0 {
+            continue;
+        }
+        let mut frames = [0u64; STACK_DEPTH];
+        for (frame, kept) in frames.iter_mut().zip(&slot.frames) {
+            *frame = kept.load(Ordering::Relaxed);
+        }
+        let depth = frames.iter().position(|&frame| frame == 0).unwrap_or(STACK_DEPTH);
+        stats.add_sampled_stack(&frames[..depth], samples, slot.bytes.load(Ordering::Relaxed), &resolve);
+    }
+    stats
+}
+
+/// Bytes as B, KiB or MiB
+fn bytes(bytes: u64) -> String {
+    match bytes {
+        0..=0x27FF => alloc::format!("{}B", bytes),
+        0x2800..=0x9F_FFFF => alloc::format!("{}K", bytes / 1024),
+        _ => alloc::format!("{}M", bytes / (1024 * 1024)),
+    }
+}
+
+/// Totals, the size classes and the top call sites
+pub fn format_report(stats: &HeapProfileStats) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} allocations ({}/s), {} frees, {} allocated, {} live",
+        stats.allocations,
+        stats.allocation_rate(),
+        stats.frees,
+        bytes(stats.allocated_bytes),
+        bytes(stats.live_bytes()),
+    );
+
+    let _ = writeln!(out, "{:>8} {:>10} {:>10} {:>9} {:>9}", "SIZE", "ALLOCS", "LIVE", "BYTES", "PADDING");
+    for size in &stats.sizes {
+        let _ = writeln!(
+            out,
+            "{:>8} {:>10} {:>10} {:>9} {:>9}",
+            alloc::format!("{}+", bytes(size.size as u64)),
+            size.allocations,
+            size.live(),
+            bytes(size.bytes),
+            bytes(size.padding),
+        );
+    }
+
+    if stats.sites.is_empty() {
+        return out;
+    }
+    let _ = writeln!(out, "Top call sites (1 in {} allocations sampled):", stats.sample_interval);
+    for site in stats.top_sites(REPORT_SITES) {
+        let _ = writeln!(out, "  {:>6} {:>9}  {}", site.samples, bytes(site.bytes), site.name);
+    }
+    if stats.lost_samples > 0 {
+        let _ = writeln!(out, "  {:>6} {:>9}  (other)", stats.lost_samples, "");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Only this test records: the host tests do not use the kernel heap
+    #[test]
+    fn test_record() {
+        set_sample_interval(2).unwrap();
+        enable();
+        reset();
+        for size in [24, 24, 100, 4096] {
+            record_alloc(size);
+        }
+        record_free(24);
+        record_free(8);
+        disable();
+        record_alloc(1);
+
+        let stats = stats_with(|_| None);
+        assert_eq!((stats.allocations, stats.frees, stats.allocated_bytes, stats.freed_bytes), (4, 2, 4244, 32));
+        let sizes: Vec<(usize, u64, u64, u64)> = stats.sizes.iter()
+            .map(|size| (size.size, size.allocations, size.frees, size.padding))
+            .collect();
+        assert_eq!(sizes, [(8, 0, 1, 0), (16, 2, 1, 16), (64, 1, 0, 28), (4096, 1, 0, 0)]);
+        assert_eq!(stats.sizes[1].live(), 1);
+        // Allocations 0 and 2 were sampled, but the host stack has no
+        // kernel frames
+        assert!(stats.sites.is_empty());
+        assert_eq!(stats.lost_samples, 2);
+        assert_eq!(set_sample_interval(0), Err("Sample interval must be at least 1"));
+        set_sample_interval(DEFAULT_SAMPLE_INTERVAL).unwrap();
+
+        // One stack more than the table holds
+        reset();
+        for i in 0..=MAX_STACKS as u64 {
+            add_stack(&[0x1000 + i, 0x2000], 8);
+        }
+        add_stack(&[0x1000, 0x2000], 16);
+        let stats = stats_with(|_| Some(("fanga_kernel::caller", 0)));
+        assert_eq!(stats.sites.len(), MAX_STACKS);
+        assert_eq!((stats.sites[0].address, stats.sites[0].samples, stats.sites[0].bytes), (0x1000, 2, 24));
+        assert_eq!(stats.lost_samples, 1);
+        reset();
+    }
+}
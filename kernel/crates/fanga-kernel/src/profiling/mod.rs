@@ -5,26 +5,51 @@
 //! - Performance counter integration
 //! - Call stack sampling
 //! - Performance statistics
+//! - Scheduler event tracing
+//! - Boot phase timing
+//! - Static tracepoints with per-CPU event buffers
+//! - Per-syscall latency histograms
+//! - Sampled kernel heap allocation profiling
+//!
+//! While profiling runs, samples are taken from NMIs (or timer ticks) on
+//! every CPU and moved into the profiler every `DRAIN_INTERVAL_MS`.
 
 pub mod sampler;
 pub mod pmu;
 pub mod stats;
 pub mod output;
+pub mod sched_trace;
+pub mod boot_trace;
+pub mod trace;
+pub mod syscall_latency;
+pub mod heap_profile;
 
-pub use sampler::{Profiler, ProfileSample, SamplingConfig};
+pub use sampler::{Profiler, ProfileSample, ProfilerState, SamplingConfig};
 pub use pmu::{PerformanceCounter, CounterType, CounterEvent};
-pub use stats::{ProfileStats, FunctionStats};
-pub use output::{ProfileOutput, OutputFormat};
+pub use stats::{ProfileStats, FunctionStats, SyscallLatencyStats, HeapProfileStats, HeapSizeStats, AllocSiteStats};
+pub use output::{ProfileOutput, OutputFormat, SchedTraceOutput, BootTimelineOutput};
+pub use sched_trace::{SchedEvent, SchedEventKind, SchedTrace, SchedTraceSummary};
+pub use boot_trace::{BootEvent, BootTimeline};
 
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fanga_arch_x86_64::interrupts::nmi_sampler;
 use spin::Once;
 
+/// Interval between moves of the samples into the profiler
+pub const DRAIN_INTERVAL_MS: u64 = 100;
+
 /// Global profiler instance
 static PROFILER: Once<spin::Mutex<Profiler>> = Once::new();
 
+/// Whether a drain is queued
+static DRAIN_QUEUED: AtomicBool = AtomicBool::new(false);
+
 /// Initialize the profiling subsystem
 pub fn init() -> Result<(), &'static str> {
     let profiler = Profiler::new();
     PROFILER.call_once(|| spin::Mutex::new(profiler));
+    unsafe { nmi_sampler::set_hook(sampler::on_sample) };
     
     Ok(())
 }
@@ -35,19 +60,65 @@ pub fn profiler() -> &'static spin::Mutex<Profiler> {
 }
 
 /// Start profiling
+///
+/// Samples every CPU at the configured frequency until stopped.
 pub fn start_profiling() -> Result<(), &'static str> {
     let mut prof = profiler().lock();
-    prof.start()
+    prof.start()?;
+    sampler::init_rings();
+    let frequency = prof.config().frequency_hz();
+    let source = match nmi_sampler::start(frequency) {
+        nmi_sampler::Mode::Counter => "performance counter NMIs",
+        _ => "timer ticks",
+    };
+    crate::log_info!(target: "profiling", "Sampling at {} Hz from {}", frequency, source);
+    schedule_drain();
+    Ok(())
 }
 
 /// Stop profiling
 pub fn stop_profiling() -> Result<(), &'static str> {
     let mut prof = profiler().lock();
+    if prof.state() == ProfilerState::Running {
+        nmi_sampler::stop();
+        sampler::drain(&mut prof);
+    }
     prof.stop()
 }
 
 /// Get profiling statistics
 pub fn get_stats() -> ProfileStats {
-    let prof = profiler().lock();
+    let mut prof = profiler().lock();
+    sampler::drain(&mut prof);
     prof.get_stats()
 }
+
+/// Get the latency histograms of the syscalls called so far, most total
+/// time first
+pub fn get_syscall_stats() -> Vec<SyscallLatencyStats> {
+    syscall_latency::stats()
+}
+
+/// Get the heap allocation profile
+pub fn get_heap_stats() -> HeapProfileStats {
+    heap_profile::stats()
+}
+
+fn drain_tick(_: usize) {
+    DRAIN_QUEUED.store(false, Ordering::Release);
+    let mut prof = profiler().lock();
+    if prof.state() == ProfilerState::Stopped {
+        return;
+    }
+    sampler::drain(&mut prof);
+    drop(prof);
+    schedule_drain();
+}
+
+/// Queue the next drain
+fn schedule_drain() {
+    if !DRAIN_QUEUED.swap(true, Ordering::AcqRel) {
+        let work = crate::task::workqueue::Work::new(drain_tick, 0);
+        let _ = crate::task::workqueue::schedule_delayed_work(work, DRAIN_INTERVAL_MS);
+    }
+}
@@ -1,8 +1,12 @@
 //! Profiling Output and Export
 
 use super::stats::ProfileStats;
+use super::sched_trace::{SchedEvent, SchedEventKind, SchedTrace, SchedTraceSummary};
+use super::boot_trace::{cycles_to_us, BootEvent};
 extern crate alloc;
+use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec::Vec;
 use alloc::format;
 
 /// Output format for profile data
@@ -16,6 +20,10 @@ pub enum OutputFormat {
     
     /// CSV format
     Csv,
+    
+    /// Collapsed stacks, one `frame;frame;frame count` line per stack, as
+    /// flamegraph tools read them
+    FoldedStacks,
 }
 
 /// Profile output generator
@@ -39,9 +47,22 @@ impl ProfileOutput {
             OutputFormat::Text => self.generate_text(),
             OutputFormat::Json => self.generate_json(),
             OutputFormat::Csv => self.generate_csv(),
+            OutputFormat::FoldedStacks => self.generate_folded(),
+        }
+    }
+    
+    /// Write the output to the serial port
+    pub fn write_serial(&self) {
+        for line in self.generate().lines() {
+            fanga_arch_x86_64::serial_println!("{}", line);
         }
     }
     
+    /// Write the output to a file, replacing its contents
+    pub fn write_file(&self, path: &str) -> Result<(), crate::fs::FsError> {
+        crate::fs::write_file(path, self.generate().as_bytes()).map(|_| ())
+    }
+    
     /// Generate text output
     fn generate_text(&self) -> String {
         let mut output = String::new();
@@ -117,6 +138,315 @@ impl ProfileOutput {
         
         output
     }
+    
+    /// Generate collapsed stack output
+    fn generate_folded(&self) -> String {
+        let mut output = String::new();
+        
+        for (stack, count) in &self.stats.stacks {
+            output.push_str(&format!("{} {}\n", stack, count));
+        }
+        
+        output
+    }
+}
+
+/// Scheduler trace output generator
+pub struct SchedTraceOutput {
+    /// Recorded events, oldest first
+    events: Vec<SchedEvent>,
+    
+    /// Derived metrics
+    summary: SchedTraceSummary,
+    
+    /// Output format
+    format: OutputFormat,
+}
+
+impl SchedTraceOutput {
+    /// Create a new output generator from a trace snapshot
+    pub fn new(trace: &SchedTrace, format: OutputFormat) -> Self {
+        Self {
+            events: trace.events().copied().collect(),
+            summary: trace.summary(),
+            format,
+        }
+    }
+    
+    /// Generate output string
+    pub fn generate(&self) -> String {
+        match self.format {
+            OutputFormat::Text => self.generate_text(),
+            OutputFormat::Json => self.generate_json(),
+            OutputFormat::Csv => self.generate_csv(),
+            OutputFormat::FoldedStacks => self.generate_folded(),
+        }
+    }
+    
+    /// Event name and task columns for an event
+    fn describe(kind: &SchedEventKind) -> (&'static str, String, String) {
+        fn id(task: Option<crate::task::TaskId>) -> String {
+            task.map(|t| format!("{}", t.as_usize())).unwrap_or_else(|| String::from("idle"))
+        }
+        match *kind {
+            SchedEventKind::Switch { prev, next } => ("switch", id(prev), id(next)),
+            SchedEventKind::Wakeup { task } => ("wakeup", id(Some(task)), String::new()),
+            SchedEventKind::Preempt { task } => ("preempt", id(Some(task)), String::new()),
+            SchedEventKind::Block { task } => ("block", id(Some(task)), String::new()),
+        }
+    }
+    
+    /// Generate text output
+    fn generate_text(&self) -> String {
+        let mut output = String::new();
+        let s = &self.summary;
+        
+        output.push_str("=== Scheduler Trace ===\n\n");
+        output.push_str(&format!("Context switches: {}\n", s.switches));
+        output.push_str(&format!("Wakeups: {}\n", s.wakeups));
+        output.push_str(&format!("Preemptions: {}\n", s.preemptions));
+        output.push_str(&format!("Blocks: {}\n", s.blocks));
+        output.push_str(&format!("Dropped events: {}\n", s.dropped));
+        
+        output.push_str("\n=== Scheduling Latency ===\n\n");
+        output.push_str(&format!("Samples: {}\n", s.latency.count));
+        output.push_str(&format!("Min: {} us\n", s.latency.min_us));
+        output.push_str(&format!("Avg: {} us\n", s.latency.avg_us()));
+        output.push_str(&format!("Max: {} us\n", s.latency.max_us));
+        output.push_str(&format!("\nMax run-queue depth: {}\n", s.max_runqueue_depth));
+        
+        output.push_str("\n=== Events ===\n\n");
+        output.push_str("Time(us)     CPU  Event    Task   Next   RunQ\n");
+        output.push_str("-----------  ---  -------  -----  -----  ----\n");
+        for event in &self.events {
+            let (name, task, next) = Self::describe(&event.kind);
+            output.push_str(&format!("{:11}  {:3}  {:7}  {:5}  {:5}  {:4}\n",
+                event.timestamp_us, event.cpu, name, task, next, event.runqueue_depth
+            ));
+        }
+        
+        output
+    }
+    
+    /// Generate JSON output
+    fn generate_json(&self) -> String {
+        let s = &self.summary;
+        let mut output = String::from("{\n");
+        
+        output.push_str(&format!("  \"switches\": {},\n", s.switches));
+        output.push_str(&format!("  \"wakeups\": {},\n", s.wakeups));
+        output.push_str(&format!("  \"preemptions\": {},\n", s.preemptions));
+        output.push_str(&format!("  \"blocks\": {},\n", s.blocks));
+        output.push_str(&format!("  \"dropped\": {},\n", s.dropped));
+        output.push_str(&format!("  \"latency_us\": {{\"count\": {}, \"min\": {}, \"avg\": {}, \"max\": {}}},\n",
+            s.latency.count, s.latency.min_us, s.latency.avg_us(), s.latency.max_us
+        ));
+        output.push_str(&format!("  \"max_runqueue_depth\": {},\n", s.max_runqueue_depth));
+        
+        output.push_str("  \"events\": [\n");
+        for (i, event) in self.events.iter().enumerate() {
+            let (name, task, next) = Self::describe(&event.kind);
+            output.push_str(&format!("    {{\"ts\": {}, \"cpu\": {}, \"event\": \"{}\", \"task\": \"{}\", \"next\": \"{}\", \"runq\": {}}}",
+                event.timestamp_us, event.cpu, name, task, next, event.runqueue_depth
+            ));
+            if i < self.events.len() - 1 {
+                output.push(',');
+            }
+            output.push('\n');
+        }
+        output.push_str("  ]\n");
+        
+        output.push_str("}\n");
+        output
+    }
+    
+    /// Generate CSV output (one row per event)
+    fn generate_csv(&self) -> String {
+        let mut output = String::from("Timestamp_us,CPU,Event,Task,Next,RunQueueDepth\n");
+        
+        for event in &self.events {
+            let (name, task, next) = Self::describe(&event.kind);
+            output.push_str(&format!("{},{},{},{},{},{}\n",
+                event.timestamp_us, event.cpu, name, task, next, event.runqueue_depth
+            ));
+        }
+        
+        output
+    }
+    
+    /// Generate collapsed stack output: event counts as `cpuN;event`
+    fn generate_folded(&self) -> String {
+        let mut counts: BTreeMap<(usize, &str), usize> = BTreeMap::new();
+        for event in &self.events {
+            let (name, _, _) = Self::describe(&event.kind);
+            *counts.entry((event.cpu, name)).or_insert(0) += 1;
+        }
+        
+        let mut output = String::new();
+        for ((cpu, name), count) in counts {
+            output.push_str(&format!("cpu{};{} {}\n", cpu, name, count));
+        }
+        output
+    }
+}
+
+/// Boot timeline output generator
+pub struct BootTimelineOutput {
+    /// Phases and steps in the order they started
+    events: Vec<BootEvent>,
+    
+    /// TSC frequency; times are in cycles without it
+    tsc_khz: Option<u64>,
+    
+    /// Output format
+    format: OutputFormat,
+}
+
+impl BootTimelineOutput {
+    /// Create a new output generator from a timeline snapshot
+    pub fn new(events: Vec<BootEvent>, tsc_khz: Option<u64>, format: OutputFormat) -> Self {
+        Self { events, tsc_khz, format }
+    }
+    
+    /// Generate output string
+    pub fn generate(&self) -> String {
+        match self.format {
+            OutputFormat::Text => self.generate_text(),
+            OutputFormat::Json => self.generate_json(),
+            OutputFormat::Csv => self.generate_csv(),
+            OutputFormat::FoldedStacks => self.generate_folded(),
+        }
+    }
+    
+    fn unit(&self) -> &'static str {
+        if self.tsc_khz.is_some() { "us" } else { "cycles" }
+    }
+    
+    fn time(&self, cycles: u64) -> u64 {
+        cycles_to_us(cycles, self.tsc_khz)
+    }
+    
+    /// TSC when the first event started
+    fn first_start(&self) -> u64 {
+        self.events.iter().map(|e| e.start).min().unwrap_or(0)
+    }
+    
+    /// Cycles from the first start to the last end
+    fn total_cycles(&self) -> u64 {
+        let last_end = self.events.iter().map(|e| e.end).max().unwrap_or(0);
+        last_end.saturating_sub(self.first_start())
+    }
+    
+    /// Phases longest first, each with its steps longest first; steps
+    /// outside any recorded phase come last on their own
+    fn sorted(&self) -> Vec<(&BootEvent, Vec<&BootEvent>)> {
+        let longest_first = |a: &&BootEvent, b: &&BootEvent| b.cycles().cmp(&a.cycles());
+        let mut phases: Vec<&BootEvent> = self.events.iter().filter(|e| e.is_phase).collect();
+        phases.sort_by(longest_first);
+        
+        let mut groups: Vec<(&BootEvent, Vec<&BootEvent>)> = phases.iter().map(|&phase| {
+            let mut steps: Vec<&BootEvent> = self.events.iter()
+                .filter(|e| !e.is_phase && e.phase == phase.phase)
+                .collect();
+            steps.sort_by(longest_first);
+            (phase, steps)
+        }).collect();
+        
+        let mut orphans: Vec<&BootEvent> = self.events.iter()
+            .filter(|e| !e.is_phase && !phases.iter().any(|p| p.phase == e.phase))
+            .collect();
+        orphans.sort_by(longest_first);
+        groups.extend(orphans.into_iter().map(|step| (step, Vec::new())));
+        groups
+    }
+    
+    /// Generate text output
+    fn generate_text(&self) -> String {
+        let mut output = String::new();
+        let total = self.total_cycles();
+        let share = |cycles: u64| if total == 0 { 0.0 } else { cycles as f32 / total as f32 * 100.0 };
+        
+        output.push_str("=== Boot Timeline ===\n\n");
+        // The TSC counts from reset, so the first event is the time firmware and loader took
+        output.push_str(&format!("Before kernel: {} {}\n", self.time(self.first_start()), self.unit()));
+        output.push_str(&format!("Kernel boot: {} {}\n", self.time(total), self.unit()));
+        
+        output.push_str(&format!("\n{:>10}  {:>6}  Phase / step\n", self.unit(), "%"));
+        output.push_str("----------  ------  ------------\n");
+        for (event, steps) in self.sorted() {
+            let label = if event.is_phase {
+                format!("{} {}", event.phase, event.name)
+            } else {
+                String::from(event.name)
+            };
+            output.push_str(&format!("{:10}  {:6.2}  {}\n", self.time(event.cycles()), share(event.cycles()), label));
+            for step in steps {
+                output.push_str(&format!("{:10}  {:6.2}    {}\n",
+                    self.time(step.cycles()), share(step.cycles()), step.name
+                ));
+            }
+        }
+        
+        output
+    }
+    
+    /// Generate JSON output
+    fn generate_json(&self) -> String {
+        let first = self.first_start();
+        let mut output = String::from("{\n");
+        
+        output.push_str(&format!("  \"unit\": \"{}\",\n", self.unit()));
+        output.push_str(&format!("  \"before_kernel\": {},\n", self.time(first)));
+        output.push_str(&format!("  \"total\": {},\n", self.time(self.total_cycles())));
+        
+        output.push_str("  \"events\": [\n");
+        for (i, event) in self.events.iter().enumerate() {
+            output.push_str(&format!("    {{\"name\": \"{}\", \"phase\": {}, \"is_phase\": {}, \"start\": {}, \"duration\": {}}}",
+                event.name, event.phase, event.is_phase, self.time(event.start - first), self.time(event.cycles())
+            ));
+            if i < self.events.len() - 1 {
+                output.push(',');
+            }
+            output.push('\n');
+        }
+        output.push_str("  ]\n");
+        
+        output.push_str("}\n");
+        output
+    }
+    
+    /// Generate CSV output (one row per event, in start order)
+    fn generate_csv(&self) -> String {
+        let first = self.first_start();
+        let mut output = format!("Phase,Name,IsPhase,Start_{0},Duration_{0}\n", self.unit());
+        
+        for event in &self.events {
+            output.push_str(&format!("{},{},{},{},{}\n",
+                event.phase, event.name, event.is_phase, self.time(event.start - first), self.time(event.cycles())
+            ));
+        }
+        
+        output
+    }
+    
+    /// Generate collapsed stack output: `phase;step time`, with the time a
+    /// phase spent outside its steps on a line of its own
+    fn generate_folded(&self) -> String {
+        let mut output = String::new();
+        
+        for (event, steps) in self.sorted() {
+            let in_steps: u64 = steps.iter().map(|s| s.cycles()).sum();
+            for step in &steps {
+                output.push_str(&format!("{};{} {}\n", event.name, step.name, self.time(step.cycles())));
+            }
+            let own = event.cycles().saturating_sub(in_steps);
+            if own > 0 {
+                output.push_str(&format!("{} {}\n", event.name, self.time(own)));
+            }
+        }
+        
+        output
+    }
 }
 
 #[cfg(test)]
@@ -165,4 +495,88 @@ mod tests {
         
         assert!(csv.contains("Function,Samples,Percentage"));
     }
+    
+    #[test]
+    fn test_folded_stacks_output() {
+        let mut stats = ProfileStats::new();
+        stats.stacks.insert(String::from("kernel_main;idle_loop"), 7);
+        stats.stacks.insert(String::from("[user]"), 2);
+        
+        let folded = ProfileOutput::new(stats, OutputFormat::FoldedStacks).generate();
+        assert_eq!(folded, "[user] 2\nkernel_main;idle_loop 7\n");
+    }
+    
+    fn sample_trace() -> SchedTrace {
+        use crate::task::TaskId;
+        let mut trace = SchedTrace::new(16);
+        trace.record(SchedEvent {
+            timestamp_us: 1000, cpu: 0, runqueue_depth: 1,
+            kind: SchedEventKind::Wakeup { task: TaskId::new(2) },
+        });
+        trace.record(SchedEvent {
+            timestamp_us: 1500, cpu: 0, runqueue_depth: 0,
+            kind: SchedEventKind::Switch { prev: None, next: Some(TaskId::new(2)) },
+        });
+        trace
+    }
+    
+    #[test]
+    fn test_sched_trace_text_output() {
+        let text = SchedTraceOutput::new(&sample_trace(), OutputFormat::Text).generate();
+        assert!(text.contains("Context switches: 1"));
+        assert!(text.contains("Avg: 500 us"));
+    }
+    
+    #[test]
+    fn test_sched_trace_csv_output() {
+        let csv = SchedTraceOutput::new(&sample_trace(), OutputFormat::Csv).generate();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], "1500,0,switch,idle,2,0");
+    }
+    
+    #[test]
+    fn test_sched_trace_folded_output() {
+        let folded = SchedTraceOutput::new(&sample_trace(), OutputFormat::FoldedStacks).generate();
+        assert_eq!(folded, "cpu0;switch 1\ncpu0;wakeup 1\n");
+    }
+    
+    fn sample_timeline() -> Vec<BootEvent> {
+        let event = |name, phase, is_phase, start, end| BootEvent { name, phase, is_phase, start, end };
+        alloc::vec![
+            event("early", 1, true, 1_000, 2_000),
+            event("memory", 3, true, 2_000, 10_000),
+            event("pmm", 3, false, 2_000, 3_000),
+            event("heap", 3, false, 3_000, 7_000),
+            event("drivers", 4, true, 10_000, 11_000),
+        ]
+    }
+    
+    #[test]
+    fn test_boot_timeline_text_output() {
+        // 1 MHz TSC: one cycle per microsecond
+        let text = BootTimelineOutput::new(sample_timeline(), Some(1_000), OutputFormat::Text).generate();
+        assert!(text.contains("Before kernel: 1000 us"));
+        assert!(text.contains("Kernel boot: 10000 us"));
+        
+        // Longest phase first, its steps longest first
+        let rows: Vec<&str> = text.lines().skip_while(|l| !l.starts_with("---")).skip(1).collect();
+        let labels: Vec<&str> = rows.iter().map(|r| &r[20..]).collect();
+        assert_eq!(labels, ["3 memory", "  heap", "  pmm", "1 early", "4 drivers"]);
+        assert!(rows[0].contains("80.00"));
+    }
+    
+    #[test]
+    fn test_boot_timeline_csv_output() {
+        let csv = BootTimelineOutput::new(sample_timeline(), None, OutputFormat::Csv).generate();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "Phase,Name,IsPhase,Start_cycles,Duration_cycles");
+        assert_eq!(lines[4], "3,heap,false,2000,4000");
+    }
+    
+    #[test]
+    fn test_boot_timeline_folded_output() {
+        let folded = BootTimelineOutput::new(sample_timeline(), Some(1_000), OutputFormat::FoldedStacks).generate();
+        assert_eq!(folded, "memory;heap 4000\nmemory;pmm 1000\nmemory 3000\nearly 1000\ndrivers 1000\n");
+    }
 }
@@ -1,4 +1,11 @@
 //! Performance Monitoring Unit (PMU) Support
+//!
+//! Counters are programmed through `fanga_arch_x86_64::interrupts::pmu`.
+//! A counter counts on the CPU that enabled it, and must be read, reset
+//! and disabled there; read elsewhere, it reports the count it had when
+//! last disabled.
+
+use fanga_arch_x86_64::interrupts::pmu::{self as hw, Event, PmuInfo};
 
 /// Performance counter type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -28,6 +35,22 @@ pub enum CounterType {
     PageFaults,
 }
 
+impl CounterType {
+    /// The hardware event, None for software events
+    pub fn event(self) -> Option<Event> {
+        match self {
+            CounterType::Cycles => Some(Event::Cycles),
+            CounterType::Instructions => Some(Event::Instructions),
+            CounterType::CacheReferences => Some(Event::CacheReferences),
+            CounterType::CacheMisses => Some(Event::CacheMisses),
+            CounterType::Branches => Some(Event::Branches),
+            CounterType::BranchMisses => Some(Event::BranchMisses),
+            CounterType::TlbMisses => Some(Event::DtlbMisses),
+            CounterType::PageFaults => None,
+        }
+    }
+}
+
 /// Performance counter event
 #[derive(Debug, Clone, Copy)]
 pub struct CounterEvent {
@@ -46,11 +69,29 @@ pub struct PerformanceCounter {
     /// Counter type
     counter_type: CounterType,
     
-    /// Current count
+    /// Count accumulated while enabled before
     count: u64,
     
     /// Is the counter enabled?
     enabled: bool,
+    
+    /// CPU and hardware counter, while enabled
+    slot: Option<(usize, usize)>,
+    
+    /// Events between overflow interrupts, if any
+    sample_period: Option<u64>,
+}
+
+/// The PMU, never on the host running the tests
+fn pmu() -> Option<&'static PmuInfo> {
+    #[cfg(not(test))]
+    return hw::info();
+    #[cfg(test)]
+    None
+}
+
+fn current_cpu() -> usize {
+    crate::smp::current_cpu_id().as_usize()
 }
 
 impl PerformanceCounter {
@@ -60,53 +101,90 @@ impl PerformanceCounter {
             counter_type,
             count: 0,
             enabled: false,
+            slot: None,
+            sample_period: None,
         }
     }
     
-    /// Enable the counter
+    /// Create a counter that raises an overflow interrupt every `period`
+    /// events
+    pub fn with_sample_period(counter_type: CounterType, period: u64) -> Self {
+        Self { sample_period: Some(period), ..Self::new(counter_type) }
+    }
+    
+    /// Enable the counter on this CPU
     pub fn enable(&mut self) -> Result<(), &'static str> {
         if self.enabled {
             return Ok(());
         }
         
-        // TODO: Program the CPU's performance monitoring hardware
-        // This involves:
-        // 1. Setting up MSRs (Model Specific Registers)
-        // 2. Configuring the counter
-        // 3. Starting the counter
+        let pmu = pmu().ok_or("No performance monitoring unit")?;
+        let select = self.counter_type.event()
+            .and_then(|event| pmu.event_select(event))
+            .ok_or("Event not supported by the PMU")?;
+        let index = hw::alloc_counter().ok_or("No free performance counter")?;
+        if let Err(e) = unsafe { hw::start(index, select, self.sample_period) } {
+            hw::free_counter(index);
+            return Err(e);
+        }
         
+        self.slot = Some((current_cpu(), index));
         self.enabled = true;
         Ok(())
     }
     
-    /// Disable the counter
+    /// Disable the counter, keeping its count
     pub fn disable(&mut self) -> Result<(), &'static str> {
         if !self.enabled {
             return Ok(());
         }
         
-        // TODO: Stop the performance counter
+        let index = self.local_index().ok_or("Counter enabled on another CPU")?;
+        self.count += hw::events(index);
+        hw::free_counter(index);
         
+        self.slot = None;
         self.enabled = false;
         Ok(())
     }
     
+    /// The hardware counter, if enabled on this CPU
+    fn local_index(&self) -> Option<usize> {
+        self.slot.filter(|&(cpu, _)| cpu == current_cpu()).map(|(_, index)| index)
+    }
+    
     /// Read the counter value
     pub fn read(&self) -> u64 {
-        if !self.enabled {
-            return self.count;
+        match self.local_index() {
+            Some(index) => self.count + hw::events(index),
+            None => self.count,
         }
-        
-        // TODO: Read from hardware counter
-        // For now, return cached value
-        self.count
+    }
+    
+    /// Read the counter value with a timestamp in ticks
+    pub fn sample(&self) -> CounterEvent {
+        CounterEvent {
+            counter_type: self.counter_type,
+            count: self.read(),
+            timestamp: crate::task::time::timer_ticks(),
+        }
+    }
+    
+    /// Number of overflow interrupts since the counter was enabled
+    pub fn overflows(&self) -> u64 {
+        self.local_index().map_or(0, hw::overflows)
     }
     
     /// Reset the counter
     pub fn reset(&mut self) {
         self.count = 0;
         
-        // TODO: Reset hardware counter
+        // Restart the hardware counter from zero
+        if let Some(index) = self.local_index() {
+            if let Some(select) = pmu().and_then(|pmu| pmu.event_select(self.counter_type.event()?)) {
+                let _ = unsafe { hw::start(index, select, self.sample_period) };
+            }
+        }
     }
     
     /// Get counter type
@@ -120,18 +198,22 @@ impl PerformanceCounter {
     }
 }
 
+impl Drop for PerformanceCounter {
+    fn drop(&mut self) {
+        let _ = self.disable();
+    }
+}
+
 /// Check if PMU is supported
 pub fn is_pmu_supported() -> bool {
-    // TODO: Check CPUID for performance monitoring features
-    // For now, assume not supported
-    false
+    pmu().is_some()
 }
 
-/// Get the number of available performance counters
+/// Get the number of performance counters available to `PerformanceCounter`
+///
+/// Counters the NMI watchdog and sampler use are not included.
 pub fn available_counters() -> usize {
-    // TODO: Query from CPUID
-    // Most modern CPUs have 4-8 programmable counters
-    0
+    pmu().map_or(0, |pmu| pmu.counters as usize - pmu.first_free())
 }
 
 #[cfg(test)]
@@ -150,8 +232,11 @@ mod tests {
     fn test_counter_enable_disable() {
         let mut counter = PerformanceCounter::new(CounterType::Instructions);
         
-        counter.enable().unwrap();
-        assert!(counter.is_enabled());
+        // The host has no PMU the tests may program
+        assert!(!is_pmu_supported());
+        assert_eq!(available_counters(), 0);
+        assert_eq!(counter.enable(), Err("No performance monitoring unit"));
+        assert!(!counter.is_enabled());
         
         counter.disable().unwrap();
         assert!(!counter.is_enabled());
@@ -159,11 +244,13 @@ mod tests {
     
     #[test]
     fn test_counter_reset() {
-        let mut counter = PerformanceCounter::new(CounterType::CacheReferences);
+        let mut counter = PerformanceCounter::with_sample_period(CounterType::CacheReferences, 1000);
         counter.count = 100;
+        assert_eq!(counter.sample().count, 100);
         
         counter.reset();
         assert_eq!(counter.read(), 0);
+        assert_eq!(counter.overflows(), 0);
     }
     
     #[test]
@@ -183,5 +270,8 @@ mod tests {
             let counter = PerformanceCounter::new(*ct);
             assert_eq!(counter.counter_type(), *ct);
         }
+        
+        assert_eq!(CounterType::BranchMisses.event(), Some(Event::BranchMisses));
+        assert_eq!(CounterType::PageFaults.event(), None);
     }
 }
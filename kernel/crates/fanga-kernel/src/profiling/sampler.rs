@@ -1,8 +1,22 @@
 //! Sampling-based Profiler
+//!
+//! Samples come from NMIs or timer ticks (see
+//! `fanga_arch_x86_64::interrupts::nmi_sampler`). The sample hook runs
+//! where it cannot lock or allocate, so it only copies each sample into a
+//! per-CPU ring; `drain` moves them into the profiler later, and drops
+//! samples a full ring had no room for.
 
 extern crate alloc;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fanga_arch_x86_64::interrupts::nmi_sampler::Sample;
+use spin::Once;
+
+/// Samples each CPU holds until they are drained
+pub const RING_LEN: usize = 256;
 
 /// Sampling configuration
 #[derive(Debug, Clone)]
@@ -20,6 +34,13 @@ pub struct SamplingConfig {
     pub include_user: bool,
 }
 
+impl SamplingConfig {
+    /// Samples per second
+    pub fn frequency_hz(&self) -> u32 {
+        (1_000_000 / self.interval_us.max(1)).max(1) as u32
+    }
+}
+
 impl Default for SamplingConfig {
     fn default() -> Self {
         Self {
@@ -51,6 +72,9 @@ pub struct ProfileSample {
     
     /// Was this in kernel mode?
     pub kernel_mode: bool,
+    
+    /// Return addresses, innermost first
+    pub stack: Vec<u64>,
 }
 
 /// Profiler state
@@ -82,6 +106,9 @@ pub struct Profiler {
     
     /// Total samples collected
     total_samples: usize,
+    
+    /// Samples lost to full buffers
+    dropped_samples: usize,
 }
 
 impl Profiler {
@@ -93,6 +120,7 @@ impl Profiler {
             samples: Vec::new(),
             sample_counts: BTreeMap::new(),
             total_samples: 0,
+            dropped_samples: 0,
         }
     }
     
@@ -101,6 +129,11 @@ impl Profiler {
         self.config = config;
     }
     
+    /// Get the configuration
+    pub fn config(&self) -> &SamplingConfig {
+        &self.config
+    }
+    
     /// Start profiling
     pub fn start(&mut self) -> Result<(), &'static str> {
         if self.state == ProfilerState::Running {
@@ -111,8 +144,7 @@ impl Profiler {
         self.samples.clear();
         self.sample_counts.clear();
         self.total_samples = 0;
-        
-        // TODO: Set up timer for sampling
+        self.dropped_samples = 0;
         
         Ok(())
     }
@@ -124,9 +156,6 @@ impl Profiler {
         }
         
         self.state = ProfilerState::Stopped;
-        
-        // TODO: Disable sampling timer
-        
         Ok(())
     }
     
@@ -156,7 +185,13 @@ impl Profiler {
             return;
         }
         
+        let wanted = if sample.kernel_mode { self.config.include_kernel } else { self.config.include_user };
+        if !wanted {
+            return;
+        }
+        
         if self.samples.len() >= self.config.max_samples {
+            self.dropped_samples += 1;
             return; // Drop sample if at capacity
         }
         
@@ -177,6 +212,11 @@ impl Profiler {
         self.total_samples
     }
     
+    /// Get the number of samples lost to full buffers
+    pub fn dropped_count(&self) -> usize {
+        self.dropped_samples
+    }
+    
     /// Get samples
     pub fn samples(&self) -> &[ProfileSample] {
         &self.samples
@@ -204,9 +244,115 @@ impl Profiler {
     }
 }
 
+/// A sample with the context it was taken in
+#[derive(Debug, Clone, Copy)]
+struct RawSample {
+    timestamp: u64,
+    task_id: Option<usize>,
+    sample: Sample,
+}
+
+/// Samples taken on one CPU, waiting to be drained
+///
+/// Only that CPU's sample hook pushes, and only `drain`, under the
+/// profiler lock, pops.
+struct SampleRing {
+    slots: Box<[UnsafeCell<Option<RawSample>>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// The producer only writes slots past `head` and the consumer only reads
+// slots before it
+unsafe impl Sync for SampleRing {}
+
+impl SampleRing {
+    fn new(len: usize) -> Self {
+        Self {
+            slots: (0..len).map(|_| UnsafeCell::new(None)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, raw: RawSample) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= self.slots.len() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { *self.slots[head % self.slots.len()].get() = Some(raw) };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<RawSample> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let raw = unsafe { (*self.slots[tail % self.slots.len()].get()).take() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        raw
+    }
+}
+
+/// One ring per CPU
+static RINGS: Once<Vec<SampleRing>> = Once::new();
+
+/// Make rings for the online CPUs
+///
+/// CPUs brought up later have none, and their samples are not kept.
+pub fn init_rings() {
+    RINGS.call_once(|| (0..crate::smp::cpu::cpu_count()).map(|_| SampleRing::new(RING_LEN)).collect());
+}
+
+/// Keep a sample taken on this CPU
+///
+/// The sample hook: runs in NMI or interrupt context.
+pub fn on_sample(sample: &Sample) {
+    let Some(ring) = RINGS.get().and_then(|rings| rings.get(crate::smp::current_cpu_id().as_usize())) else {
+        return;
+    };
+    ring.push(RawSample {
+        timestamp: crate::task::time::timer_ticks(),
+        task_id: crate::percpu!(current_task),
+        sample: *sample,
+    });
+}
+
+/// Move the samples taken so far into the profiler
+pub fn drain(profiler: &mut Profiler) {
+    let Some(rings) = RINGS.get() else {
+        return;
+    };
+    for (cpu_id, ring) in rings.iter().enumerate() {
+        drain_ring(profiler, cpu_id, ring);
+    }
+}
+
+fn drain_ring(profiler: &mut Profiler, cpu_id: usize, ring: &SampleRing) {
+    while let Some(raw) = ring.pop() {
+        profiler.record_sample(ProfileSample {
+            timestamp: raw.timestamp,
+            rip: raw.sample.rip,
+            rsp: raw.sample.rsp,
+            cpu_id,
+            task_id: raw.task_id.unwrap_or(0),
+            kernel_mode: !raw.sample.user,
+            stack: raw.sample.frames().to_vec(),
+        });
+    }
+    if profiler.state == ProfilerState::Running {
+        profiler.dropped_samples += ring.dropped.swap(0, Ordering::Relaxed);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fanga_arch_x86_64::interrupts::nmi_sampler::SAMPLE_DEPTH;
     
     #[test]
     fn test_profiler_creation() {
@@ -238,6 +384,7 @@ mod tests {
             cpu_id: 0,
             task_id: 1,
             kernel_mode: true,
+            stack: Vec::new(),
         };
         
         profiler.record_sample(sample);
@@ -258,6 +405,7 @@ mod tests {
                 cpu_id: 0,
                 task_id: 1,
                 kernel_mode: true,
+                stack: Vec::new(),
             };
             profiler.record_sample(sample);
         }
@@ -266,4 +414,46 @@ mod tests {
         assert_eq!(hotspots.len(), 2);
         assert!(hotspots[0].1 >= hotspots[1].1); // First should have more or equal samples
     }
+    
+    #[test]
+    fn test_sampling_filters() {
+        let mut profiler = Profiler::new();
+        profiler.configure(SamplingConfig { include_user: false, max_samples: 1, ..SamplingConfig::default() });
+        assert_eq!(profiler.config().frequency_hz(), 1000);
+        profiler.start().unwrap();
+        
+        let sample = |kernel_mode| ProfileSample {
+            timestamp: 0, rip: 0x1000, rsp: 0, cpu_id: 0, task_id: 1, kernel_mode, stack: Vec::new(),
+        };
+        profiler.record_sample(sample(false));
+        profiler.record_sample(sample(true));
+        profiler.record_sample(sample(true));
+        assert_eq!(profiler.sample_count(), 1);
+        assert_eq!(profiler.dropped_count(), 1);
+    }
+    
+    #[test]
+    fn test_sample_ring() {
+        let ring = SampleRing::new(2);
+        let mut sample = Sample { rip: 0x1000, rsp: 0x2000, user: false, frames: [0; SAMPLE_DEPTH], depth: 2 };
+        sample.frames[..2].copy_from_slice(&[0x3000, 0x4000]);
+        for timestamp in 0..3 {
+            ring.push(RawSample { timestamp, task_id: Some(7), sample });
+        }
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 1);
+        
+        let mut profiler = Profiler::new();
+        profiler.start().unwrap();
+        drain_ring(&mut profiler, 3, &ring);
+        assert!(ring.pop().is_none());
+        assert_eq!(profiler.sample_count(), 2);
+        assert_eq!(profiler.dropped_count(), 1);
+        let drained = &profiler.samples()[1];
+        assert_eq!((drained.timestamp, drained.cpu_id, drained.task_id), (1, 3, 7));
+        assert_eq!(drained.stack, [0x3000, 0x4000]);
+        
+        // The ring wraps around once drained
+        ring.push(RawSample { timestamp: 9, task_id: None, sample });
+        assert_eq!(ring.pop().map(|raw| raw.timestamp), Some(9));
+    }
 }
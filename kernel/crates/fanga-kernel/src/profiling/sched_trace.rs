@@ -0,0 +1,290 @@
+//! Scheduler Tracing
+//!
+//! This module records scheduler events for offline analysis:
+//! - Context switches, wakeups, preemptions and blocks with timestamps
+//! - Scheduling latency (runnable to running) min/avg/max
+//! - Run-queue depth over time
+//!
+//! Tracing is off by default and costs one atomic load per event when
+//! disabled. Use `SchedTraceOutput` to dump the buffer as text, JSON or CSV.
+
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::task::TaskId;
+
+/// Default number of events kept in the trace ring buffer
+pub const DEFAULT_TRACE_CAPACITY: usize = 4096;
+
+/// Kind of scheduler event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedEventKind {
+    /// CPU switched from `prev` to `next`
+    Switch { prev: Option<TaskId>, next: Option<TaskId> },
+    /// Task became runnable after blocking
+    Wakeup { task: TaskId },
+    /// Task was preempted at the end of its time slice
+    Preempt { task: TaskId },
+    /// Task blocked voluntarily
+    Block { task: TaskId },
+}
+
+/// A single trace record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedEvent {
+    /// Timestamp in microseconds since boot
+    pub timestamp_us: u64,
+    /// CPU the event happened on
+    pub cpu: usize,
+    /// Number of runnable tasks when the event was recorded
+    pub runqueue_depth: usize,
+    /// What happened
+    pub kind: SchedEventKind,
+}
+
+/// Scheduling latency statistics (wakeup/preemption to run)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total_us: u64,
+    pub min_us: u64,
+    pub max_us: u64,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency_us: u64) {
+        if self.count == 0 || latency_us < self.min_us {
+            self.min_us = latency_us;
+        }
+        self.max_us = self.max_us.max(latency_us);
+        self.total_us += latency_us;
+        self.count += 1;
+    }
+
+    /// Average latency in microseconds
+    pub fn avg_us(&self) -> u64 {
+        self.total_us.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+/// Summary derived from a trace
+#[derive(Debug, Clone, Default)]
+pub struct SchedTraceSummary {
+    pub switches: u64,
+    pub wakeups: u64,
+    pub preemptions: u64,
+    pub blocks: u64,
+    pub dropped: u64,
+    pub latency: LatencyStats,
+    pub max_runqueue_depth: usize,
+    /// (timestamp_us, depth) samples, one per recorded event
+    pub runqueue_series: Vec<(u64, usize)>,
+}
+
+/// Scheduler trace buffer
+pub struct SchedTrace {
+    events: VecDeque<SchedEvent>,
+    capacity: usize,
+    dropped: u64,
+    /// When each task became runnable, for latency measurement
+    runnable_since: BTreeMap<TaskId, u64>,
+    latency: LatencyStats,
+}
+
+impl SchedTrace {
+    /// Create an empty trace buffer
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            runnable_since: BTreeMap::new(),
+            latency: LatencyStats { count: 0, total_us: 0, min_us: 0, max_us: 0 },
+        }
+    }
+
+    /// Record an event, overwriting the oldest when full
+    pub fn record(&mut self, event: SchedEvent) {
+        match event.kind {
+            SchedEventKind::Wakeup { task } | SchedEventKind::Preempt { task } => {
+                self.runnable_since.insert(task, event.timestamp_us);
+            }
+            SchedEventKind::Block { task } => {
+                self.runnable_since.remove(&task);
+            }
+            SchedEventKind::Switch { next: Some(task), .. } => {
+                if let Some(since) = self.runnable_since.remove(&task) {
+                    self.latency.record(event.timestamp_us.saturating_sub(since));
+                }
+            }
+            SchedEventKind::Switch { next: None, .. } => {}
+        }
+
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    /// Recorded events, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &SchedEvent> {
+        self.events.iter()
+    }
+
+    /// Number of events in the buffer
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Check whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Discard all events and derived state
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.runnable_since.clear();
+        self.dropped = 0;
+        self.latency = LatencyStats::default();
+    }
+
+    /// Compute derived metrics
+    pub fn summary(&self) -> SchedTraceSummary {
+        let mut summary = SchedTraceSummary {
+            dropped: self.dropped,
+            latency: self.latency,
+            ..SchedTraceSummary::default()
+        };
+
+        for event in &self.events {
+            match event.kind {
+                SchedEventKind::Switch { .. } => summary.switches += 1,
+                SchedEventKind::Wakeup { .. } => summary.wakeups += 1,
+                SchedEventKind::Preempt { .. } => summary.preemptions += 1,
+                SchedEventKind::Block { .. } => summary.blocks += 1,
+            }
+            summary.max_runqueue_depth = summary.max_runqueue_depth.max(event.runqueue_depth);
+            summary.runqueue_series.push((event.timestamp_us, event.runqueue_depth));
+        }
+        summary
+    }
+}
+
+/// Global trace buffer
+static SCHED_TRACE: Mutex<SchedTrace> = Mutex::new(SchedTrace::new(DEFAULT_TRACE_CAPACITY));
+
+/// Tracing on/off switch (checked before taking the lock)
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Start recording scheduler events
+pub fn enable() {
+    TRACING.store(true, Ordering::Release);
+}
+
+/// Stop recording scheduler events
+pub fn disable() {
+    TRACING.store(false, Ordering::Release);
+}
+
+/// Check whether tracing is enabled
+pub fn is_enabled() -> bool {
+    TRACING.load(Ordering::Acquire)
+}
+
+/// Record an event from scheduler code
+///
+/// Cheap when tracing is disabled. Uses `try_lock` because it is called
+/// from the timer interrupt.
+pub fn trace(kind: SchedEventKind, runqueue_depth: usize) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some(mut trace) = SCHED_TRACE.try_lock() {
+        trace.record(SchedEvent {
+            timestamp_us: crate::task::clocksource::ktime_us(),
+            cpu: crate::smp::cpu::current_cpu_id().as_usize(),
+            runqueue_depth,
+            kind,
+        });
+    }
+}
+
+/// Get the global trace buffer
+pub fn sched_trace() -> spin::MutexGuard<'static, SchedTrace> {
+    SCHED_TRACE.lock()
+}
+
+/// Summary of the global trace
+pub fn summary() -> SchedTraceSummary {
+    SCHED_TRACE.lock().summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(ts: u64, depth: usize, kind: SchedEventKind) -> SchedEvent {
+        SchedEvent { timestamp_us: ts, cpu: 0, runqueue_depth: depth, kind }
+    }
+
+    #[test]
+    fn test_ring_buffer_overwrites() {
+        let mut trace = SchedTrace::new(2);
+        for i in 0..3 {
+            trace.record(ev(i, 0, SchedEventKind::Block { task: TaskId::new(1) }));
+        }
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace.events().next().unwrap().timestamp_us, 1);
+        assert_eq!(trace.summary().dropped, 1);
+    }
+
+    #[test]
+    fn test_wakeup_latency() {
+        let mut trace = SchedTrace::new(16);
+        let t = TaskId::new(3);
+
+        trace.record(ev(100, 1, SchedEventKind::Wakeup { task: t }));
+        trace.record(ev(350, 0, SchedEventKind::Switch { prev: None, next: Some(t) }));
+        trace.record(ev(400, 1, SchedEventKind::Preempt { task: t }));
+        trace.record(ev(450, 0, SchedEventKind::Switch { prev: None, next: Some(t) }));
+
+        let summary = trace.summary();
+        assert_eq!(summary.latency.count, 2);
+        assert_eq!(summary.latency.min_us, 50);
+        assert_eq!(summary.latency.max_us, 250);
+        assert_eq!(summary.latency.avg_us(), 150);
+        assert_eq!(summary.switches, 2);
+        assert_eq!(summary.wakeups, 1);
+        assert_eq!(summary.preemptions, 1);
+    }
+
+    #[test]
+    fn test_runqueue_series() {
+        let mut trace = SchedTrace::new(16);
+        trace.record(ev(10, 2, SchedEventKind::Wakeup { task: TaskId::new(1) }));
+        trace.record(ev(20, 5, SchedEventKind::Wakeup { task: TaskId::new(2) }));
+
+        let summary = trace.summary();
+        assert_eq!(summary.max_runqueue_depth, 5);
+        assert_eq!(summary.runqueue_series, alloc::vec![(10, 2), (20, 5)]);
+    }
+
+    #[test]
+    fn test_block_cancels_latency() {
+        let mut trace = SchedTrace::new(16);
+        let t = TaskId::new(4);
+        trace.record(ev(0, 1, SchedEventKind::Wakeup { task: t }));
+        trace.record(ev(5, 0, SchedEventKind::Block { task: t }));
+        trace.record(ev(90, 0, SchedEventKind::Switch { prev: None, next: Some(t) }));
+        assert_eq!(trace.summary().latency.count, 0);
+    }
+}
@@ -61,6 +61,10 @@ pub struct ProfileStats {
     
     /// Per-CPU sample counts
     pub cpu_samples: BTreeMap<usize, usize>,
+    
+    /// Sample counts by call stack: function names, outermost first,
+    /// joined with `;`
+    pub stacks: BTreeMap<String, usize>,
 }
 
 impl ProfileStats {
@@ -72,11 +76,22 @@ impl ProfileStats {
             user_samples: 0,
             functions: Vec::new(),
             cpu_samples: BTreeMap::new(),
+            stacks: BTreeMap::new(),
         }
     }
     
-    /// Create statistics from profiler data
+    /// Create statistics from profiler data, with functions named from
+    /// the kernel symbol table
     pub fn from_profiler(profiler: &super::sampler::Profiler) -> Self {
+        Self::from_profiler_with(profiler, crate::debug::ksyms::resolve)
+    }
+    
+    /// Create statistics from profiler data, resolving addresses to
+    /// `(symbol, offset)` with `resolve`
+    pub fn from_profiler_with<'a>(
+        profiler: &super::sampler::Profiler,
+        resolve: impl Fn(u64) -> Option<(&'a str, u64)>,
+    ) -> Self {
         let samples = profiler.samples();
         let sample_counts = profiler.sample_counts();
         
@@ -93,14 +108,22 @@ impl ProfileStats {
             
             // Count per-CPU samples
             *stats.cpu_samples.entry(sample.cpu_id).or_insert(0) += 1;
+            
+            *stats.stacks.entry(Self::fold_stack(sample, &resolve)).or_insert(0) += 1;
         }
         
-        // Build per-function statistics
-        // For now, we treat each unique RIP as a separate "function"
+        // Build per-function statistics, keyed by function start;
+        // addresses without a symbol stand alone
+        let mut functions: BTreeMap<u64, (String, usize)> = BTreeMap::new();
         for (&rip, &count) in sample_counts {
-            let name = format!("0x{:016x}", rip);
-            let func_stats = FunctionStats::new(name, count, stats.total_samples, rip);
-            stats.functions.push(func_stats);
+            let (start, name) = match resolve(rip) {
+                Some((name, offset)) => (rip - offset, String::from(name)),
+                None => (rip, format!("0x{:016x}", rip)),
+            };
+            functions.entry(start).or_insert((name, 0)).1 += count;
+        }
+        for (start, (name, count)) in functions {
+            stats.functions.push(FunctionStats::new(name, count, stats.total_samples, start));
         }
         
         // Sort by sample count
@@ -109,6 +132,20 @@ impl ProfileStats {
         stats
     }
     
+    /// A sample's call stack as `outer;...;inner`
+    ///
+    /// User code shows as a single `[user]` frame.
+    fn fold_stack<'a>(sample: &super::sampler::ProfileSample, resolve: &impl Fn(u64) -> Option<(&'a str, u64)>) -> String {
+        if !sample.kernel_mode {
+            return String::from("[user]");
+        }
+        let name = |addr: u64| resolve(addr).map_or_else(|| format!("0x{:x}", addr), |(name, _)| String::from(name));
+        // A return address can be just past the end of the calling function
+        let mut frames: Vec<String> = sample.stack.iter().rev().map(|&ret| name(ret - 1)).collect();
+        frames.push(name(sample.rip));
+        frames.join(";")
+    }
+    
     /// Get kernel sample percentage
     pub fn kernel_percentage(&self) -> f32 {
         if self.total_samples > 0 {
@@ -134,6 +171,204 @@ impl ProfileStats {
     }
 }
 
+/// Buckets of a syscall latency histogram
+///
+/// Bucket `i` counts syscalls that took `[2^i, 2^(i+1))` nanoseconds; the
+/// first also counts 0 ns and the last everything longer.
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Bucket of a latency in nanoseconds
+pub fn latency_bucket(ns: u64) -> usize {
+    ((63 - (ns | 1).leading_zeros()) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+/// Latency histogram of one syscall number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallLatencyStats {
+    /// Syscall number
+    pub number: u64,
+    
+    /// Syscall name, if the number is known
+    pub name: Option<&'static str>,
+    
+    /// Completed calls
+    pub count: u64,
+    
+    /// Time spent in all calls, in nanoseconds
+    pub total_ns: u64,
+    
+    /// Longest call, in nanoseconds
+    pub max_ns: u64,
+    
+    /// Calls per log2 latency bucket (see `LATENCY_BUCKETS`)
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl SyscallLatencyStats {
+    /// Average latency in nanoseconds
+    pub fn average_ns(&self) -> u64 {
+        self.total_ns.checked_div(self.count).unwrap_or(0)
+    }
+    
+    /// Upper bound of the bucket holding the `percentile`th fastest call,
+    /// capped at the longest call
+    pub fn percentile_ns(&self, percentile: u64) -> u64 {
+        let rank = (self.count * percentile.min(100)).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, &calls) in self.buckets.iter().enumerate() {
+            seen += calls;
+            if seen >= rank {
+                return (1u64 << (i + 1)).min(self.max_ns);
+            }
+        }
+        self.max_ns
+    }
+}
+
+/// Size classes of the heap profiler
+///
+/// Class `i` holds allocations of `[2^i, 2^(i+1))` bytes; the last also
+/// holds everything larger.
+pub const HEAP_SIZE_CLASSES: usize = 24;
+
+/// Size class of an allocation of `size` bytes
+pub fn heap_size_class(size: usize) -> usize {
+    ((usize::BITS - 1 - (size | 1).leading_zeros()) as usize).min(HEAP_SIZE_CLASSES - 1)
+}
+
+/// Allocations of one heap size class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapSizeStats {
+    /// Smallest size in the class
+    pub size: usize,
+    
+    /// Allocations
+    pub allocations: u64,
+    
+    /// Frees
+    pub frees: u64,
+    
+    /// Bytes allocated
+    pub bytes: u64,
+    
+    /// Bytes power-of-two sized slabs would add to these allocations
+    pub padding: u64,
+}
+
+impl HeapSizeStats {
+    /// Allocations not freed yet, counting only frees seen while profiling
+    pub fn live(&self) -> u64 {
+        self.allocations.saturating_sub(self.frees)
+    }
+}
+
+/// Allocations sampled at one call site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocSiteStats {
+    /// Function that allocated, or its address if unknown
+    pub name: String,
+    
+    /// Return address into the allocating function
+    pub address: u64,
+    
+    /// Sampled allocations
+    pub samples: u64,
+    
+    /// Bytes of the sampled allocations
+    pub bytes: u64,
+}
+
+/// Heap profiler statistics
+#[derive(Debug, Clone, Default)]
+pub struct HeapProfileStats {
+    /// Time profiled, in nanoseconds
+    pub elapsed_ns: u64,
+    
+    /// Allocations
+    pub allocations: u64,
+    
+    /// Frees
+    pub frees: u64,
+    
+    /// Bytes allocated
+    pub allocated_bytes: u64,
+    
+    /// Bytes freed
+    pub freed_bytes: u64,
+    
+    /// One allocation in this many is sampled for its call site
+    pub sample_interval: u64,
+    
+    /// Size classes with allocations or frees, smallest first
+    pub sizes: Vec<HeapSizeStats>,
+    
+    /// Call sites, most sampled bytes first
+    pub sites: Vec<AllocSiteStats>,
+    
+    /// Samples without a call site: the site table was full, or no frame
+    /// was outside the allocator
+    pub lost_samples: u64,
+}
+
+impl HeapProfileStats {
+    /// Functions of the allocator itself, skipped when looking for the
+    /// caller of an allocation
+    const ALLOCATOR_FRAMES: [&'static str; 5] = ["alloc::", "__rust", "GlobalHeapAllocator", "heap_profile", "RawVec"];
+    
+    /// Allocations per second
+    pub fn allocation_rate(&self) -> u64 {
+        (self.allocations as u128 * 1_000_000_000)
+            .checked_div(self.elapsed_ns as u128)
+            .unwrap_or(0) as u64
+    }
+    
+    /// Bytes allocated and not freed while profiling
+    pub fn live_bytes(&self) -> u64 {
+        self.allocated_bytes.saturating_sub(self.freed_bytes)
+    }
+    
+    /// Count sampled allocations from the call stack `frames` (return
+    /// addresses, innermost first) against the first function outside the
+    /// allocator, resolving addresses to `(symbol, offset)` with `resolve`
+    pub fn add_sampled_stack<'a>(
+        &mut self,
+        frames: &[u64],
+        samples: u64,
+        bytes: u64,
+        resolve: impl Fn(u64) -> Option<(&'a str, u64)>,
+    ) {
+        let site = frames.iter()
+            .map(|&ret| (ret, resolve(ret - 1)))
+            .find(|(_, symbol)| match symbol {
+                Some((name, _)) => !Self::ALLOCATOR_FRAMES.iter().any(|frame| name.contains(frame)),
+                None => true,
+            });
+        let Some((address, symbol)) = site else {
+            self.lost_samples += samples;
+            return;
+        };
+        
+        match self.sites.iter_mut().find(|site| site.address == address) {
+            Some(site) => {
+                site.samples += samples;
+                site.bytes += bytes;
+            }
+            None => self.sites.push(AllocSiteStats {
+                name: symbol.map_or_else(|| format!("0x{:x}", address), |(name, _)| String::from(name)),
+                address,
+                samples,
+                bytes,
+            }),
+        }
+        self.sites.sort_by_key(|site| core::cmp::Reverse(site.bytes));
+    }
+    
+    /// Get top N call sites by sampled bytes
+    pub fn top_sites(&self, n: usize) -> &[AllocSiteStats] {
+        &self.sites[..n.min(self.sites.len())]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -160,6 +395,120 @@ mod tests {
         assert_eq!(stats.user_samples, 0);
     }
     
+    #[test]
+    fn test_stats_by_symbol() {
+        use super::super::sampler::{ProfileSample, Profiler};
+        
+        let mut profiler = Profiler::new();
+        profiler.start().unwrap();
+        for rip in [0x1000, 0x1010, 0x1020, 0x2008, 0x9000] {
+            profiler.record_sample(ProfileSample {
+                timestamp: 0, rip, rsp: 0, cpu_id: 1, task_id: 1, kernel_mode: true, stack: Vec::new(),
+            });
+        }
+        
+        let resolve = |addr: u64| match addr {
+            0x1000..=0x1FFF => Some(("alpha", addr - 0x1000)),
+            0x2000..=0x2FFF => Some(("beta", addr - 0x2000)),
+            _ => None,
+        };
+        let stats = ProfileStats::from_profiler_with(&profiler, resolve);
+        let functions: Vec<(&str, usize, u64)> = stats.functions.iter()
+            .map(|f| (f.name.as_str(), f.sample_count, f.start_addr))
+            .collect();
+        assert_eq!(functions[0], ("alpha", 3, 0x1000));
+        assert_eq!(functions.len(), 3);
+        assert!(functions.contains(&("beta", 1, 0x2000)));
+        assert!(functions.contains(&("0x0000000000009000", 1, 0x9000)));
+        assert_eq!(stats.cpu_samples.get(&1), Some(&5));
+    }
+    
+    #[test]
+    fn test_folded_stacks() {
+        use super::super::sampler::{ProfileSample, Profiler};
+        
+        let mut profiler = Profiler::new();
+        profiler.start().unwrap();
+        let sample = |rip, stack: &[u64], kernel_mode| ProfileSample {
+            timestamp: 0, rip, rsp: 0, cpu_id: 0, task_id: 1, kernel_mode, stack: stack.to_vec(),
+        };
+        profiler.record_sample(sample(0x1010, &[0x2008, 0x3000], true));
+        profiler.record_sample(sample(0x1020, &[0x2010, 0x3000], true));
+        profiler.record_sample(sample(0x9000, &[], true));
+        profiler.record_sample(sample(0x4000, &[], false));
+        
+        // Returning to 0x3000 means a call at the very end of `outer`
+        let resolve = |addr: u64| match addr {
+            0x1000..=0x1FFF => Some(("inner", addr - 0x1000)),
+            0x2000..=0x27FF => Some(("middle", addr - 0x2000)),
+            0x2800..=0x2FFF => Some(("outer", addr - 0x2800)),
+            _ => None,
+        };
+        let stats = ProfileStats::from_profiler_with(&profiler, resolve);
+        let stacks: Vec<(&str, usize)> = stats.stacks.iter().map(|(stack, &count)| (stack.as_str(), count)).collect();
+        assert_eq!(stacks, [("0x9000", 1), ("[user]", 1), ("outer;middle;inner", 2)]);
+    }
+    
+    #[test]
+    fn test_syscall_latency_stats() {
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(1), 0);
+        assert_eq!(latency_bucket(1023), 9);
+        assert_eq!(latency_bucket(1024), 10);
+        assert_eq!(latency_bucket(u64::MAX), LATENCY_BUCKETS - 1);
+        
+        // 90 calls of ~1 us, 10 of ~1 ms
+        let mut buckets = [0; LATENCY_BUCKETS];
+        buckets[latency_bucket(1_000)] = 90;
+        buckets[latency_bucket(1_000_000)] = 10;
+        let stats = SyscallLatencyStats {
+            number: 0,
+            name: Some("read"),
+            count: 100,
+            total_ns: 90 * 1_000 + 10 * 1_000_000,
+            max_ns: 1_200_000,
+            buckets,
+        };
+        assert_eq!(stats.average_ns(), 100_900);
+        assert_eq!(stats.percentile_ns(50), 1024);
+        assert_eq!(stats.percentile_ns(90), 1024);
+        assert_eq!(stats.percentile_ns(99), 1_048_576);
+        assert_eq!(stats.percentile_ns(100), 1_048_576);
+    }
+    
+    #[test]
+    fn test_heap_profile_stats() {
+        assert_eq!(heap_size_class(0), 0);
+        assert_eq!(heap_size_class(24), 4);
+        assert_eq!(heap_size_class(4096), 12);
+        assert_eq!(heap_size_class(usize::MAX), HEAP_SIZE_CLASSES - 1);
+        
+        let mut stats = HeapProfileStats {
+            elapsed_ns: 2_000_000_000,
+            allocations: 500,
+            allocated_bytes: 8192,
+            freed_bytes: 4096,
+            ..HeapProfileStats::default()
+        };
+        assert_eq!(stats.allocation_rate(), 250);
+        assert_eq!(stats.live_bytes(), 4096);
+        
+        let resolve = |addr: u64| match addr {
+            0x1000..=0x1FFF => Some(("alloc::raw_vec::RawVec<T>::grow_one", addr - 0x1000)),
+            0x2000..=0x2FFF => Some(("fanga_kernel::net::poll", addr - 0x2000)),
+            _ => None,
+        };
+        stats.add_sampled_stack(&[0x1010, 0x2010], 1, 64, resolve);
+        stats.add_sampled_stack(&[0x1020, 0x2010, 0x3000], 2, 128, resolve);
+        stats.add_sampled_stack(&[0x9000], 1, 4096, resolve);
+        stats.add_sampled_stack(&[0x1010], 1, 8, resolve);
+        
+        let sites: Vec<(&str, u64, u64)> = stats.sites.iter().map(|s| (s.name.as_str(), s.samples, s.bytes)).collect();
+        assert_eq!(sites, [("0x9000", 1, 4096), ("fanga_kernel::net::poll", 3, 192)]);
+        assert_eq!(stats.lost_samples, 1);
+        assert_eq!(stats.top_sites(1).len(), 1);
+    }
+    
     #[test]
     fn test_profile_stats_percentages() {
         let mut stats = ProfileStats::new();
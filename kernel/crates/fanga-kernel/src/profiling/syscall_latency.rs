@@ -0,0 +1,226 @@
+//! Syscall Latency Histograms
+//!
+//! The syscall exit hook passes how long each syscall took, from entry to
+//! exit including any time the task slept in it, to `record`. Per syscall
+//! number it keeps the call count, total and longest time and a log2
+//! histogram of the times, so a few pathologically slow calls stand out
+//! next to thousands of fast ones.
+//!
+//! The counters are atomics in fixed arrays: recording takes no lock and
+//! allocates nothing. `stats` turns them into `SyscallLatencyStats`;
+//! `format_report` and `format_histogram` build what the `syscalls` shell
+//! command shows.
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fanga_arch_x86_64::syscall::syscall_name;
+
+use super::stats::{latency_bucket, SyscallLatencyStats, LATENCY_BUCKETS};
+
+/// Syscall numbers with a histogram; higher numbers are not recorded
+pub const MAX_SYSCALLS: usize = 512;
+
+/// Width of the bars in `format_histogram`
+const BAR_WIDTH: u64 = 40;
+
+/// Counters of one syscall number
+struct Histogram {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+        }
+    }
+
+    fn record(&self, ns: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+        self.buckets[latency_bucket(ns)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self, number: u64) -> SyscallLatencyStats {
+        SyscallLatencyStats {
+            number,
+            name: syscall_name(number),
+            count: self.count.load(Ordering::Relaxed),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+            max_ns: self.max_ns.load(Ordering::Relaxed),
+            buckets: core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+static HISTOGRAMS: [Histogram; MAX_SYSCALLS] = [const { Histogram::new() }; MAX_SYSCALLS];
+
+/// Record that syscall `num` took `ns` nanoseconds
+pub fn record(num: u64, ns: u64) {
+    if let Some(histogram) = HISTOGRAMS.get(num as usize) {
+        histogram.record(ns);
+    }
+}
+
+/// Clear all histograms
+pub fn reset() {
+    for histogram in &HISTOGRAMS {
+        histogram.reset();
+    }
+}
+
+/// Histogram of syscall `num`
+pub fn syscall_stats(num: u64) -> Option<SyscallLatencyStats> {
+    HISTOGRAMS.get(num as usize).map(|histogram| histogram.snapshot(num))
+}
+
+/// Histograms of the syscalls called so far, most total time first
+pub fn stats() -> Vec<SyscallLatencyStats> {
+    let mut stats: Vec<_> = (0..MAX_SYSCALLS as u64)
+        .filter_map(syscall_stats)
+        .filter(|stats| stats.count > 0)
+        .collect();
+    stats.sort_by_key(|stats| core::cmp::Reverse(stats.total_ns));
+    stats
+}
+
+/// Syscall number from a name or a number
+pub fn lookup(name: &str) -> Option<u64> {
+    name.parse().ok().or_else(|| (0..MAX_SYSCALLS as u64).find(|&num| syscall_name(num) == Some(name)))
+}
+
+/// A time in nanoseconds as ns, us, ms or s
+fn duration(ns: u64) -> String {
+    match ns {
+        0..=9_999 => alloc::format!("{}ns", ns),
+        10_000..=9_999_999 => alloc::format!("{}us", ns / 1_000),
+        10_000_000..=9_999_999_999 => alloc::format!("{}ms", ns / 1_000_000),
+        _ => alloc::format!("{}s", ns / 1_000_000_000),
+    }
+}
+
+/// One line per syscall: calls, total, average, p99 and longest time
+pub fn format_report(stats: &[SyscallLatencyStats]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:>4} {:<16} {:>10} {:>9} {:>9} {:>9} {:>9}", "NR", "SYSCALL", "CALLS", "TOTAL", "AVG", "P99", "MAX");
+    for syscall in stats {
+        let _ = writeln!(
+            out,
+            "{:>4} {:<16} {:>10} {:>9} {:>9} {:>9} {:>9}",
+            syscall.number,
+            syscall.name.unwrap_or("?"),
+            syscall.count,
+            duration(syscall.total_ns),
+            duration(syscall.average_ns()),
+            duration(syscall.percentile_ns(99)),
+            duration(syscall.max_ns),
+        );
+    }
+    out
+}
+
+/// The histogram of one syscall, one bar per bucket from the fastest to
+/// the slowest non-empty one
+pub fn format_histogram(stats: &SyscallLatencyStats) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} ({}): {} calls, avg {}, max {}",
+        stats.name.unwrap_or("?"),
+        stats.number,
+        stats.count,
+        duration(stats.average_ns()),
+        duration(stats.max_ns),
+    );
+    let Some(first) = stats.buckets.iter().position(|&calls| calls > 0) else {
+        return out;
+    };
+    let last = stats.buckets.iter().rposition(|&calls| calls > 0).unwrap_or(first);
+    let most = stats.buckets.iter().copied().max().unwrap_or(1);
+    for (i, &calls) in stats.buckets.iter().enumerate().take(last + 1).skip(first) {
+        let low = if i == 0 { 0 } else { 1u64 << i };
+        let high = if i == LATENCY_BUCKETS - 1 { String::from("inf") } else { duration(1u64 << (i + 1)) };
+        let bar = (calls * BAR_WIDTH).div_ceil(most) as usize;
+        let _ = writeln!(out, "  {:>6} - {:<6} {:>10} |{}", duration(low), high, calls, "#".repeat(bar));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fanga_arch_x86_64::syscall::{SYS_GETRANDOM, SYS_READ};
+
+    #[test]
+    fn test_record() {
+        // Other tests do not make syscalls; only this one records
+        reset();
+        for ns in [100, 200, 5_000] {
+            record(SYS_READ, ns);
+        }
+        record(SYS_GETRANDOM, 1_000_000);
+        record(MAX_SYSCALLS as u64, 1);
+
+        let stats = stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].name, stats[0].count, stats[0].max_ns), (Some("getrandom"), 1, 1_000_000));
+        let read = &stats[1];
+        assert_eq!((read.number, read.count, read.total_ns), (SYS_READ, 3, 5_300));
+        assert_eq!((read.buckets[6], read.buckets[7], read.buckets[12]), (1, 1, 1));
+
+        assert_eq!(lookup("read"), Some(SYS_READ));
+        assert_eq!(lookup("318"), Some(SYS_GETRANDOM));
+        assert_eq!(lookup("nosuchcall"), None);
+
+        reset();
+        assert!(super::stats().is_empty());
+    }
+
+    #[test]
+    fn test_format() {
+        let mut buckets = [0; LATENCY_BUCKETS];
+        buckets[latency_bucket(2_000)] = 3;
+        buckets[latency_bucket(50_000)] = 1;
+        let stats = SyscallLatencyStats {
+            number: SYS_READ,
+            name: Some("read"),
+            count: 4,
+            total_ns: 56_000,
+            max_ns: 50_000,
+            buckets,
+        };
+
+        let report = format_report(core::slice::from_ref(&stats));
+        let line = report.lines().nth(1).unwrap();
+        assert!(line.contains("read"));
+        assert!(line.ends_with("14us      50us      50us"));
+
+        let histogram = format_histogram(&stats);
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert_eq!(lines[0], "read (0): 4 calls, avg 14us, max 50us");
+        // 1024ns up to 64us, empty buckets in between included
+        assert_eq!(lines.len(), 7);
+        assert!(lines[1].ends_with(&alloc::format!("3 |{}", "#".repeat(40))));
+        assert!(lines[6].ends_with("1 |##############"));
+    }
+}
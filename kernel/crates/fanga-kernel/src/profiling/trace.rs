@@ -0,0 +1,408 @@
+//! Tracepoints and Event Tracing
+//!
+//! A tracepoint is a static event that kernel code marks with
+//! `tracepoint!`, passing up to `MAX_ARGS` integer arguments:
+//!
+//! ```ignore
+//! tracepoint!(SCHED_SWITCH, prev, next);
+//! ```
+//!
+//! Each event is enabled on its own at runtime (`set_enabled`); a disabled
+//! tracepoint costs one atomic load. An enabled one writes a fixed-size
+//! record (timestamp, CPU, event, arguments) into the running CPU's ring
+//! buffer without taking a lock, so tracepoints may sit in interrupt
+//! handlers and under spinlocks. A full ring overwrites its oldest records.
+//!
+//! The events are all defined here, grouped by subsystem, so the `trace`
+//! shell command can list and enable them before they first fire.
+//! `format_text` prints records one per line, `encode_binary` packs them
+//! for tools on the host.
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use spin::Once;
+
+/// Arguments a record holds
+pub const MAX_ARGS: usize = 4;
+
+/// Records kept per CPU
+pub const RING_LEN: usize = 2048;
+
+/// First bytes of a binary dump
+pub const BINARY_MAGIC: [u8; 4] = *b"FTRC";
+
+/// Binary dump format version
+pub const BINARY_VERSION: u16 = 1;
+
+/// Size of a record in a binary dump
+pub const BINARY_RECORD_LEN: usize = 16 + 8 * MAX_ARGS;
+
+/// How an argument is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgFormat {
+    Dec,
+    /// Two's complement, for return values and error codes
+    Signed,
+    Hex,
+}
+
+/// A static trace event
+pub struct Tracepoint {
+    /// Index in `EVENTS`
+    pub id: u16,
+    pub subsystem: &'static str,
+    pub name: &'static str,
+    /// Argument names and formats, in order
+    pub fields: &'static [(&'static str, ArgFormat)],
+    enabled: AtomicBool,
+}
+
+impl Tracepoint {
+    /// Define an event, disabled
+    pub const fn new(
+        id: u16,
+        subsystem: &'static str,
+        name: &'static str,
+        fields: &'static [(&'static str, ArgFormat)],
+    ) -> Self {
+        Self { id, subsystem, name, fields, enabled: AtomicBool::new(false) }
+    }
+
+    /// Check whether the event is recorded
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Check whether `pattern` selects the event
+    ///
+    /// `*` selects every event, a subsystem name all of its events, and
+    /// `name` or `subsystem:name` one event.
+    pub fn matches(&self, pattern: &str) -> bool {
+        match pattern.split_once(':') {
+            Some((subsystem, name)) => subsystem == self.subsystem && (name == "*" || name == self.name),
+            None => pattern == "*" || pattern == self.subsystem || pattern == self.name,
+        }
+    }
+}
+
+use ArgFormat::{Dec, Hex, Signed};
+
+/// A task switch on a CPU; task 0 is the idle task
+pub static SCHED_SWITCH: Tracepoint = Tracepoint::new(0, "sched", "sched_switch", &[("prev", Dec), ("next", Dec)]);
+/// A blocked task became runnable on `cpu`
+pub static SCHED_WAKEUP: Tracepoint = Tracepoint::new(1, "sched", "sched_wakeup", &[("task", Dec), ("cpu", Dec)]);
+/// System call entry and exit
+pub static SYS_ENTER: Tracepoint = Tracepoint::new(2, "syscalls", "sys_enter", &[("nr", Dec)]);
+pub static SYS_EXIT: Tracepoint = Tracepoint::new(3, "syscalls", "sys_exit", &[("nr", Dec), ("ret", Signed)]);
+/// Sectors read from and written to a disk
+pub static BLOCK_READ: Tracepoint = Tracepoint::new(4, "block", "block_read", &[("lba", Dec), ("sectors", Dec)]);
+pub static BLOCK_WRITE: Tracepoint = Tracepoint::new(5, "block", "block_write", &[("lba", Dec), ("sectors", Dec)]);
+/// Ethernet frames sent and received
+pub static NET_TX: Tracepoint = Tracepoint::new(6, "net", "net_tx", &[("len", Dec), ("ethertype", Hex)]);
+pub static NET_RX: Tracepoint = Tracepoint::new(7, "net", "net_rx", &[("len", Dec), ("ethertype", Hex)]);
+
+/// All events, by id
+pub static EVENTS: [&Tracepoint; 8] = [
+    &SCHED_SWITCH,
+    &SCHED_WAKEUP,
+    &SYS_ENTER,
+    &SYS_EXIT,
+    &BLOCK_READ,
+    &BLOCK_WRITE,
+    &NET_TX,
+    &NET_RX,
+];
+
+/// Record an event if it is enabled
+///
+/// Arguments are converted with `as u64`.
+#[macro_export]
+macro_rules! tracepoint {
+    ($event:ident $(, $arg:expr)* $(,)?) => {
+        if $crate::profiling::trace::$event.is_enabled() {
+            $crate::profiling::trace::record(&$crate::profiling::trace::$event, &[$($arg as u64),*]);
+        }
+    };
+}
+
+/// One recorded event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceRecord {
+    /// Nanoseconds since boot
+    pub timestamp_ns: u64,
+    pub cpu: u16,
+    /// Id of the event
+    pub event: u16,
+    /// Arguments; those past the event's fields are 0
+    pub args: [u64; MAX_ARGS],
+}
+
+impl TraceRecord {
+    /// The event, if the id is known
+    pub fn tracepoint(&self) -> Option<&'static Tracepoint> {
+        EVENTS.get(self.event as usize).copied()
+    }
+}
+
+/// A ring slot; `seq` is the position written plus one, 0 while writing
+struct Slot {
+    seq: AtomicUsize,
+    record: UnsafeCell<TraceRecord>,
+}
+
+/// A CPU's records
+///
+/// Only its CPU writes it, but interrupts may nest writes, so positions
+/// are claimed atomically. Readers check `seq` around each copy, as with a
+/// seqlock, and skip slots rewritten meanwhile.
+struct TraceRing {
+    slots: Box<[Slot]>,
+    /// Next position to write
+    head: AtomicUsize,
+    /// First position `snapshot` returns, moved by `clear`
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for TraceRing {}
+
+impl TraceRing {
+    fn new(len: usize) -> Self {
+        Self {
+            slots: (0..len)
+                .map(|_| Slot { seq: AtomicUsize::new(0), record: UnsafeCell::new(TraceRecord::default()) })
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, record: TraceRecord) {
+        let pos = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[pos % self.slots.len()];
+        slot.seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(slot.record.get(), record) };
+        slot.seq.store(pos + 1, Ordering::Release);
+    }
+
+    /// Copy the records still in the ring, oldest first
+    fn snapshot(&self, out: &mut Vec<TraceRecord>) {
+        let head = self.head.load(Ordering::Acquire);
+        let start = head.saturating_sub(self.slots.len()).max(self.tail.load(Ordering::Relaxed));
+        for pos in start..head {
+            let slot = &self.slots[pos % self.slots.len()];
+            if slot.seq.load(Ordering::Acquire) != pos + 1 {
+                continue;
+            }
+            let record = unsafe { core::ptr::read_volatile(slot.record.get()) };
+            fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) == pos + 1 {
+                out.push(record);
+            }
+        }
+    }
+
+    /// Records overwritten before they were cleared
+    fn overwritten(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        head.saturating_sub(self.slots.len()).saturating_sub(self.tail.load(Ordering::Relaxed))
+    }
+
+    fn clear(&self) {
+        self.tail.store(self.head.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Rings of the CPUs online when tracing was first enabled
+static RINGS: Once<Vec<TraceRing>> = Once::new();
+
+/// Record an event on this CPU; use `tracepoint!` instead
+pub fn record(event: &Tracepoint, args: &[u64]) {
+    let cpu = crate::smp::current_cpu_id().as_usize();
+    let Some(ring) = RINGS.get().and_then(|rings| rings.get(cpu)) else {
+        return;
+    };
+    let mut record = TraceRecord {
+        timestamp_ns: crate::task::clocksource::ktime_ns(),
+        cpu: cpu as u16,
+        event: event.id,
+        args: [0; MAX_ARGS],
+    };
+    let count = args.len().min(MAX_ARGS);
+    record.args[..count].copy_from_slice(&args[..count]);
+    ring.push(record);
+}
+
+/// Enable or disable the events `pattern` selects (see `Tracepoint::matches`)
+///
+/// The ring buffers are allocated when an event is first enabled.
+///
+/// # Returns
+/// The number of events selected
+pub fn set_enabled(pattern: &str, enabled: bool) -> usize {
+    if enabled {
+        RINGS.call_once(|| (0..crate::smp::cpu::cpu_count().max(1)).map(|_| TraceRing::new(RING_LEN)).collect());
+    }
+    let mut count = 0;
+    for event in EVENTS.iter().filter(|event| event.matches(pattern)) {
+        event.enabled.store(enabled, Ordering::Relaxed);
+        count += 1;
+    }
+    count
+}
+
+/// Recorded events of all CPUs, oldest first
+pub fn records() -> Vec<TraceRecord> {
+    let mut records = Vec::new();
+    for ring in RINGS.get().map(Vec::as_slice).unwrap_or(&[]) {
+        ring.snapshot(&mut records);
+    }
+    records.sort_by_key(|record| record.timestamp_ns);
+    records
+}
+
+/// Records lost to full rings since the last `clear`
+pub fn overwritten() -> usize {
+    RINGS.get().map_or(0, |rings| rings.iter().map(TraceRing::overwritten).sum())
+}
+
+/// Forget the records kept so far
+pub fn clear() {
+    for ring in RINGS.get().map(Vec::as_slice).unwrap_or(&[]) {
+        ring.clear();
+    }
+}
+
+/// Print records one per line: `[cpu] seconds.micros: event: field=value ...`
+pub fn format_text(records: &[TraceRecord]) -> String {
+    let mut text = String::new();
+    for record in records {
+        let ts_us = record.timestamp_ns / 1000;
+        let _ = write!(text, "[{:03}] {:>6}.{:06}: ", record.cpu, ts_us / 1_000_000, ts_us % 1_000_000);
+        let Some(event) = record.tracepoint() else {
+            let _ = writeln!(text, "event {}", record.event);
+            continue;
+        };
+        text.push_str(event.name);
+        text.push(':');
+        for (&(name, format), &value) in event.fields.iter().zip(&record.args) {
+            let _ = match format {
+                Dec => write!(text, " {}={}", name, value),
+                Signed => write!(text, " {}={}", name, value as i64),
+                Hex => write!(text, " {}={:#x}", name, value),
+            };
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Pack records for tools, little-endian
+///
+/// A header (`BINARY_MAGIC`, version and record length as u16, event and
+/// record counts as u32) is followed by the event table (id as u16,
+/// argument count and name length as u8, name) and the records
+/// (timestamp as u64, CPU and event as u16, 4 bytes of padding, the
+/// arguments as u64).
+pub fn encode_binary(records: &[TraceRecord]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + records.len() * BINARY_RECORD_LEN);
+    out.extend_from_slice(&BINARY_MAGIC);
+    out.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+    out.extend_from_slice(&(BINARY_RECORD_LEN as u16).to_le_bytes());
+    out.extend_from_slice(&(EVENTS.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(records.len() as u32).to_le_bytes());
+
+    for event in &EVENTS {
+        out.extend_from_slice(&event.id.to_le_bytes());
+        out.push(event.fields.len() as u8);
+        out.push(event.name.len() as u8);
+        out.extend_from_slice(event.name.as_bytes());
+    }
+
+    for record in records {
+        out.extend_from_slice(&record.timestamp_ns.to_le_bytes());
+        out.extend_from_slice(&record.cpu.to_le_bytes());
+        out.extend_from_slice(&record.event.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        for arg in record.args {
+            out.extend_from_slice(&arg.to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_table() {
+        for (id, event) in EVENTS.iter().enumerate() {
+            assert_eq!(event.id as usize, id);
+            assert!(event.fields.len() <= MAX_ARGS);
+        }
+        assert!(SYS_EXIT.matches("syscalls"));
+        assert!(SYS_EXIT.matches("syscalls:sys_exit"));
+        assert!(SYS_EXIT.matches("sys_exit"));
+        assert!(SYS_EXIT.matches("*"));
+        assert!(!SYS_EXIT.matches("sched:*"));
+        assert!(!SYS_EXIT.matches("sched:sys_exit"));
+    }
+
+    #[test]
+    fn test_ring_overwrite_and_clear() {
+        let ring = TraceRing::new(4);
+        for ts in 0..6 {
+            ring.push(TraceRecord { timestamp_ns: ts, ..Default::default() });
+        }
+        let mut out = Vec::new();
+        ring.snapshot(&mut out);
+        let stamps: Vec<u64> = out.iter().map(|r| r.timestamp_ns).collect();
+        assert_eq!(stamps, [2, 3, 4, 5]);
+        assert_eq!(ring.overwritten(), 2);
+
+        ring.clear();
+        ring.push(TraceRecord { timestamp_ns: 6, ..Default::default() });
+        out.clear();
+        ring.snapshot(&mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(ring.overwritten(), 0);
+    }
+
+    #[test]
+    fn test_format_text() {
+        let record = TraceRecord {
+            timestamp_ns: 1_500_002_000,
+            cpu: 1,
+            event: SYS_EXIT.id,
+            args: [60, (-14i64) as u64, 0, 0],
+        };
+        let net = TraceRecord { event: NET_RX.id, args: [64, 0x806, 0, 0], ..record };
+        assert_eq!(
+            format_text(&[record, net]),
+            "[001]      1.500002: sys_exit: nr=60 ret=-14\n[001]      1.500002: net_rx: len=64 ethertype=0x806\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_binary() {
+        let record = TraceRecord { timestamp_ns: 7, cpu: 2, event: 3, args: [1, 2, 3, 4] };
+        let bytes = encode_binary(&[record]);
+        assert_eq!(&bytes[..4], b"FTRC");
+        assert_eq!(u32::from_le_bytes(bytes[8..12].try_into().unwrap()), EVENTS.len() as u32);
+        assert_eq!(u32::from_le_bytes(bytes[12..16].try_into().unwrap()), 1);
+
+        let table: usize = EVENTS.iter().map(|e| 4 + e.name.len()).sum();
+        let rec = &bytes[16 + table..];
+        assert_eq!(rec.len(), BINARY_RECORD_LEN);
+        assert_eq!(u64::from_le_bytes(rec[..8].try_into().unwrap()), 7);
+        assert_eq!(u16::from_le_bytes(rec[8..10].try_into().unwrap()), 2);
+        assert_eq!(u64::from_le_bytes(rec[40..48].try_into().unwrap()), 4);
+    }
+}
@@ -0,0 +1,111 @@
+//! ChaCha20 block function (RFC 7539)
+//!
+//! Only the block function is needed: the CSPRNG runs it in counter mode
+//! as a keystream generator and the entropy pool uses the bare
+//! permutation to stir its state.
+
+/// Words in the ChaCha state
+pub const STATE_WORDS: usize = 16;
+
+/// Bytes in one keystream block
+pub const BLOCK_SIZE: usize = 64;
+
+/// Words in a key
+pub const KEY_WORDS: usize = 8;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+#[inline(always)]
+fn quarter_round(state: &mut [u32; STATE_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Apply the 20-round ChaCha permutation in place
+///
+/// The permutation is invertible, so it never loses what is in `state`.
+pub fn permute(state: &mut [u32; STATE_WORDS]) {
+    for _ in 0..10 {
+        quarter_round(state, 0, 4, 8, 12);
+        quarter_round(state, 1, 5, 9, 13);
+        quarter_round(state, 2, 6, 10, 14);
+        quarter_round(state, 3, 7, 11, 15);
+        quarter_round(state, 0, 5, 10, 15);
+        quarter_round(state, 1, 6, 11, 12);
+        quarter_round(state, 2, 7, 8, 13);
+        quarter_round(state, 3, 4, 9, 14);
+    }
+}
+
+/// Produce the keystream block for `key`, `counter` and `nonce`
+pub fn block(key: &[u32; KEY_WORDS], counter: u32, nonce: &[u32; 3]) -> [u8; BLOCK_SIZE] {
+    let mut input = [0u32; STATE_WORDS];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    permute(&mut state);
+
+    let mut out = [0u8; BLOCK_SIZE];
+    for (i, chunk) in out.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+        *chunk = state[i].wrapping_add(input[i]).to_le_bytes();
+    }
+    out
+}
+
+/// Read little-endian words from `bytes`, as ChaCha loads its key
+pub fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0u32; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.as_chunks::<4>().0) {
+        *word = u32::from_le_bytes(*chunk);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarter_round() {
+        // RFC 7539 section 2.1.1
+        let mut state = [0u32; STATE_WORDS];
+        state[0] = 0x1111_1111;
+        state[1] = 0x0102_0304;
+        state[2] = 0x9b8d_6f43;
+        state[3] = 0x0123_4567;
+        quarter_round(&mut state, 0, 1, 2, 3);
+        assert_eq!(state[..4], [0xea2a_92f4, 0xcb1c_f8ce, 0x4581_472e, 0x5881_c4bb]);
+    }
+
+    #[test]
+    fn test_block() {
+        // RFC 7539 section 2.3.2
+        let key_bytes: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let key = words::<KEY_WORDS>(&key_bytes);
+        let nonce = [0x0900_0000, 0x4a00_0000, 0];
+        let out = block(&key, 1, &nonce);
+        let expected: [u8; BLOCK_SIZE] = [
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4,
+            0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a, 0xc3, 0xd4, 0x6c, 0x4e,
+            0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2, 0xd7, 0x05, 0xd9, 0x8b, 0x02, 0xa2,
+            0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9, 0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e,
+        ];
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_counter_changes_block() {
+        let key = [7u32; KEY_WORDS];
+        assert_ne!(block(&key, 0, &[0; 3]), block(&key, 1, &[0; 3]));
+    }
+}
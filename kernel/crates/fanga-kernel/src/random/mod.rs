@@ -0,0 +1,594 @@
+//! Kernel Random Number Generator
+//!
+//! Entropy is gathered in an input pool from:
+//! - the timing of device interrupts and timer ticks
+//! - the timing of keyboard and mouse events
+//! - RDSEED/RDRAND, when the CPU has them
+//! - device data such as MAC addresses, which is mixed in without credit
+//!
+//! The pool's state is stirred with the ChaCha permutation. The pool also
+//! keeps an estimate of how many bits of entropy it holds.
+//!
+//! Output comes from a ChaCha20 CSPRNG keyed from the pool. The generator
+//! counts as ready once the pool has collected `READY_BITS`, and after that
+//! it reseeds at most every `RESEED_INTERVAL_MS`. Each request rekeys the
+//! generator from its own keystream before returning, so a leaked key does
+//! not reveal earlier output.
+//!
+//! Samples taken in interrupt context only `try_lock` the pool and are
+//! dropped when it is busy. Process context locks the generator before
+//! the pool.
+//!
+//! The generator backs `/dev/random` and `/dev/urandom`, `getrandom()`,
+//! the randomized user stack and mmap bases, and TCP initial sequence
+//! numbers. Pass `random.trust_cpu=off` to stop crediting the CPU's
+//! generator, and `norandmaps` to turn address randomization off.
+
+pub mod chacha20;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fanga_arch_x86_64::{rdrand, tsc};
+use fanga_arch_x86_64::uaccess::{Access, UserSlice};
+use spin::Once;
+
+use crate::smp::SpinLock;
+use crate::syscall::{EAGAIN, EFAULT, EINTR, EINVAL};
+use crate::task::wait::{self, WaitQueue};
+use crate::task::{clocksource, scheduler, sigadv};
+use chacha20::{BLOCK_SIZE, KEY_WORDS, STATE_WORDS};
+
+/// Bits of entropy the pool must hold to seed the generator
+pub const READY_BITS: u32 = 128;
+
+/// Minimum time between reseeds of a ready generator
+pub const RESEED_INTERVAL_MS: u64 = 60_000;
+
+/// Most entropy the pool can be credited with
+pub const POOL_BITS: u32 = (STATE_WORDS * 32) as u32;
+
+/// Interrupts that earn one bit of credit
+const INTERRUPTS_PER_BIT: u32 = 64;
+
+/// Bits credited per keyboard or mouse event
+const INPUT_BITS: u32 = 1;
+
+/// CPU generator words read at boot
+const CPU_SEED_WORDS: usize = 4;
+
+/// Nonce for the keystream; the key changes on every request instead
+const NONCE: [u32; 3] = [0; 3];
+
+/// Bytes generated under one hold of the generator lock
+const CHUNK: usize = 256;
+
+/// How often a blocked `getrandom()` checks for readiness
+const READY_POLL_MS: u64 = 100;
+
+/// Largest `getrandom()` request served in one call, as on Linux
+const GETRANDOM_MAX: usize = 0x01ff_ffff;
+
+/// `getrandom()` flags
+pub const GRND_NONBLOCK: u32 = 0x1;
+pub const GRND_RANDOM: u32 = 0x2;
+pub const GRND_INSECURE: u32 = 0x4;
+
+/// Entropy input pool
+struct InputPool {
+    state: [u32; STATE_WORDS],
+    /// Next word to mix into
+    pos: usize,
+    /// Estimated entropy in bits
+    entropy_bits: u32,
+    /// Interrupts mixed since the last credited bit
+    interrupts: u32,
+}
+
+impl InputPool {
+    const fn new() -> Self {
+        Self {
+            state: [0; STATE_WORDS],
+            pos: 0,
+            entropy_bits: 0,
+            interrupts: 0,
+        }
+    }
+
+    /// XOR a word into the state, stirring it once every word has been hit
+    fn mix_word(&mut self, word: u32) {
+        self.state[self.pos] ^= word;
+        self.pos += 1;
+        if self.pos == STATE_WORDS {
+            self.pos = 0;
+            chacha20::permute(&mut self.state);
+        }
+    }
+
+    fn mix_u64(&mut self, value: u64) {
+        self.mix_word(value as u32);
+        self.mix_word((value >> 32) as u32);
+    }
+
+    fn mix(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.mix_word(u32::from_le_bytes(word));
+        }
+    }
+
+    fn credit(&mut self, bits: u32) {
+        self.entropy_bits = self.entropy_bits.saturating_add(bits).min(POOL_BITS);
+    }
+
+    /// Count an interrupt, crediting a bit every `INTERRUPTS_PER_BIT`
+    fn credit_interrupt(&mut self) {
+        self.interrupts += 1;
+        if self.interrupts == INTERRUPTS_PER_BIT {
+            self.interrupts = 0;
+            self.credit(1);
+        }
+    }
+
+    /// Derive a key from the state without touching the entropy estimate
+    ///
+    /// The key is the permuted state added to itself, which cannot be run
+    /// backwards. The rest of that output is folded back into the pool, so
+    /// the next key differs even if nothing new is mixed in.
+    fn derive(&mut self) -> [u32; KEY_WORDS] {
+        let mut out = self.state;
+        chacha20::permute(&mut out);
+        for (word, &input) in out.iter_mut().zip(self.state.iter()) {
+            *word = word.wrapping_add(input);
+        }
+        for &word in &out[KEY_WORDS..] {
+            self.mix_word(word);
+        }
+        chacha20::permute(&mut self.state);
+        self.pos = 0;
+
+        let mut key = [0u32; KEY_WORDS];
+        key.copy_from_slice(&out[..KEY_WORDS]);
+        key
+    }
+
+    /// Derive a key and give up the entropy it was drawn from
+    fn extract(&mut self) -> [u32; KEY_WORDS] {
+        self.entropy_bits = 0;
+        self.derive()
+    }
+}
+
+/// ChaCha20 generator
+struct Crng {
+    key: [u32; KEY_WORDS],
+    /// Whether the key has been drawn from a pool holding `READY_BITS`
+    seeded: bool,
+    last_reseed_ms: u64,
+}
+
+impl Crng {
+    const fn new() -> Self {
+        Self {
+            key: [0; KEY_WORDS],
+            seeded: false,
+            last_reseed_ms: 0,
+        }
+    }
+
+    /// Mix `key` into the generator key
+    fn reseed(&mut self, key: &[u32; KEY_WORDS], now_ms: u64) {
+        for (word, &input) in self.key.iter_mut().zip(key.iter()) {
+            *word ^= input;
+        }
+        self.rekey();
+        self.last_reseed_ms = now_ms;
+    }
+
+    /// Replace the key with the first half of its counter 0 block
+    fn rekey(&mut self) -> [u8; BLOCK_SIZE] {
+        let block = chacha20::block(&self.key, 0, &NONCE);
+        self.key = chacha20::words(&block[..32]);
+        block
+    }
+
+    /// Fill `buf` with keystream
+    ///
+    /// The key is replaced first. Output starts with the unused half of the
+    /// rekeying block and continues with blocks of the old key, which is
+    /// dropped when this returns.
+    fn fill(&mut self, buf: &mut [u8]) {
+        let key = self.key;
+        let first = self.rekey();
+        let (head, rest) = buf.split_at_mut(buf.len().min(32));
+        head.copy_from_slice(&first[32..32 + head.len()]);
+        for (counter, chunk) in rest.chunks_mut(BLOCK_SIZE).enumerate() {
+            let block = chacha20::block(&key, counter as u32 + 1, &NONCE);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+    }
+}
+
+static POOL: SpinLock<InputPool> = SpinLock::new(InputPool::new());
+static CRNG: SpinLock<Crng> = SpinLock::new(Crng::new());
+
+/// Set once the generator has been seeded from a full pool
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Tasks waiting in `getrandom()` for the generator to become ready
+///
+/// Seeding mostly happens in interrupt context, which cannot take the
+/// scheduler lock to wake them, so waiters also poll.
+static READY_WAIT: WaitQueue = WaitQueue::new();
+
+/// Whether RDSEED/RDRAND output is credited as entropy
+static TRUST_CPU: AtomicBool = AtomicBool::new(true);
+
+/// Key for TCP initial sequence numbers
+static TCP_SECRET: Once<[u32; KEY_WORDS]> = Once::new();
+
+fn now_ms() -> u64 {
+    crate::task::uptime_ms()
+}
+
+/// Read the CPU generator into the pool, crediting it if trusted
+fn mix_cpu_randomness(pool: &mut InputPool) {
+    let mut words = 0;
+    for _ in 0..CPU_SEED_WORDS {
+        if let Some(value) = rdrand::rdseed().or_else(rdrand::rdrand) {
+            pool.mix_u64(value);
+            words += 1;
+        }
+    }
+    if TRUST_CPU.load(Ordering::Relaxed) {
+        pool.credit(words * 64);
+    }
+}
+
+/// Reseed `crng` from `pool` if the pool is full enough and a reseed is due
+///
+/// # Returns
+/// `true` if this made the generator ready
+fn try_reseed(crng: &mut Crng, pool: &mut InputPool, now_ms: u64) -> bool {
+    if pool.entropy_bits < READY_BITS {
+        return false;
+    }
+    if crng.seeded && now_ms.saturating_sub(crng.last_reseed_ms) < RESEED_INTERVAL_MS {
+        return false;
+    }
+    let key = pool.extract();
+    crng.reseed(&key, now_ms);
+    let first = !crng.seeded;
+    crng.seeded = true;
+    if first {
+        READY.store(true, Ordering::Release);
+    }
+    first
+}
+
+/// After mixing in interrupt context, seed the generator if that is due
+///
+/// Gives up if the generator is busy; the next sample tries again.
+fn reseed_from_irq(pool: &mut InputPool) {
+    if pool.entropy_bits < READY_BITS {
+        return;
+    }
+    if let Some(mut crng) = CRNG.try_lock() {
+        try_reseed(&mut crng, pool, now_ms());
+    }
+}
+
+/// Mix a sample taken in interrupt context
+fn add_irq_sample(value: u64, credit: impl FnOnce(&mut InputPool)) {
+    let Some(mut pool) = POOL.try_lock() else {
+        return;
+    };
+    pool.mix_u64(tsc::rdtsc());
+    pool.mix_u64(value);
+    credit(&mut pool);
+    reseed_from_irq(&mut pool);
+}
+
+/// Seed the pool from the CPU generator and the clocks
+///
+/// Call after the clock sources are set up. Generation works before this,
+/// but from a pool that only holds what interrupts have mixed in.
+pub fn init() {
+    if matches!(crate::cmdline::option("random.trust_cpu"), Some("off" | "0" | "n")) {
+        TRUST_CPU.store(false, Ordering::Relaxed);
+    }
+
+    let mut crng = CRNG.lock_irqsave();
+    let mut pool = POOL.lock_irqsave();
+    pool.mix_u64(tsc::rdtsc());
+    if let Ok(now) = clocksource::clock_gettime(clocksource::CLOCK_REALTIME) {
+        pool.mix_u64(now.tv_sec as u64);
+        pool.mix_u64(now.tv_nsec as u64);
+    }
+    mix_cpu_randomness(&mut pool);
+    let key = pool.derive();
+    crng.reseed(&key, now_ms());
+    let ready = try_reseed(&mut crng, &mut pool, now_ms());
+    let bits = pool.entropy_bits;
+    drop(pool);
+    drop(crng);
+
+    if ready {
+        READY_WAIT.wake_up_all();
+        crate::log_info!(target: "random", "CSPRNG seeded from the CPU generator");
+    } else {
+        crate::log_info!(
+            target: "random",
+            "CSPRNG waiting for entropy ({}/{} bits)",
+            bits,
+            READY_BITS
+        );
+    }
+}
+
+/// Mix the timing of an interrupt on `irq`
+pub fn add_interrupt_randomness(irq: u8) {
+    add_irq_sample(irq as u64, InputPool::credit_interrupt);
+}
+
+/// Mix the timing of a timer tick
+pub fn add_timer_randomness() {
+    add_irq_sample(0, InputPool::credit_interrupt);
+}
+
+/// Mix the timing and value of a keyboard or mouse event
+pub fn add_input_randomness(value: u32) {
+    add_irq_sample(value as u64, |pool| pool.credit(INPUT_BITS));
+}
+
+/// Mix device data that differs between machines, without crediting it
+pub fn add_device_randomness(data: &[u8]) {
+    let mut pool = POOL.lock_irqsave();
+    pool.mix_u64(tsc::rdtsc());
+    pool.mix(data);
+}
+
+/// Whether the generator has been seeded from a full pool
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Fill `buf` with random bytes
+///
+/// Never blocks. Before the generator is ready the output is only as good
+/// as what the pool holds, so callers that need secrets should check
+/// `is_ready()`.
+pub fn get_random_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(CHUNK) {
+        let mut crng = CRNG.lock_irqsave();
+        let mut pool = POOL.lock_irqsave();
+        let now = now_ms();
+        try_reseed(&mut crng, &mut pool, now);
+        if !crng.seeded {
+            // Keep early output moving with whatever has been mixed in
+            pool.mix_u64(tsc::rdtsc());
+            let key = pool.derive();
+            crng.reseed(&key, now);
+        }
+        drop(pool);
+        crng.fill(chunk);
+    }
+}
+
+/// Random `u32`
+pub fn get_random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    get_random_bytes(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+/// Random `u64`
+pub fn get_random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    get_random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Random page-aligned offset below `max` bytes, for placing user mappings
+///
+/// Zero when address randomization is off with `norandmaps`.
+pub fn aslr_offset(max: u64) -> u64 {
+    const PAGE_SIZE: u64 = 4096;
+    if max < PAGE_SIZE || crate::cmdline::flag("norandmaps") {
+        return 0;
+    }
+    (get_random_u64() % (max / PAGE_SIZE)) * PAGE_SIZE
+}
+
+/// Initial sequence number for a TCP connection (RFC 6528)
+///
+/// A keyed hash of the connection's addresses and ports plus a clock that
+/// ticks every 4 microseconds. Numbers for one connection keep moving
+/// forward, while numbers for other connections cannot be predicted.
+pub fn secure_tcp_seq(saddr: [u8; 4], daddr: [u8; 4], sport: u16, dport: u16) -> u32 {
+    let secret = TCP_SECRET.call_once(|| {
+        let mut bytes = [0u8; 32];
+        get_random_bytes(&mut bytes);
+        chacha20::words(&bytes)
+    });
+    let nonce = [
+        u32::from_be_bytes(saddr),
+        u32::from_be_bytes(daddr),
+        ((sport as u32) << 16) | dport as u32,
+    ];
+    let hash = chacha20::block(secret, 0, &nonce);
+    let hash = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    hash.wrapping_add((clocksource::ktime_ns() / 4000) as u32)
+}
+
+/// Whether a signal is waiting for `task`
+fn signal_pending() -> bool {
+    let Some(task) = scheduler::scheduler().current_task() else {
+        return false;
+    };
+    sigadv::try_signal_manager()
+        .map(|manager| manager.lock().get_handler(task).is_some_and(|sig| sig.has_pending()))
+        .unwrap_or(false)
+}
+
+/// Handle the getrandom() system call
+///
+/// Waits for the generator to become ready unless `GRND_NONBLOCK` or
+/// `GRND_INSECURE` is given. `GRND_RANDOM` is accepted and ignored, as the
+/// pool is never drained by reads.
+///
+/// # Returns
+/// Number of bytes written, or a negative error code
+pub fn sys_getrandom(buf: UserSlice, flags: u32) -> i64 {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & (GRND_RANDOM | GRND_INSECURE) == (GRND_RANDOM | GRND_INSECURE)
+    {
+        return EINVAL;
+    }
+    let buf = buf.subslice(0, GETRANDOM_MAX);
+    if buf.check(Access::Write).is_err() {
+        return EFAULT;
+    }
+
+    if flags & GRND_INSECURE == 0 {
+        while !is_ready() {
+            if flags & GRND_NONBLOCK != 0 {
+                return EAGAIN;
+            }
+            if signal_pending() {
+                return EINTR;
+            }
+            get_random_bytes(&mut []);
+            if wait::sleep_on_timeout(&READY_WAIT, READY_POLL_MS).is_err() {
+                return EAGAIN;
+            }
+        }
+    }
+
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < buf.len() {
+        let count = (buf.len() - done).min(CHUNK);
+        get_random_bytes(&mut chunk[..count]);
+        if buf.subslice(done, count).write(&chunk[..count]).is_err() {
+            return if done > 0 { done as i64 } else { EFAULT };
+        }
+        done += count;
+    }
+    chunk.fill(0);
+    done as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_credit() {
+        let mut pool = InputPool::new();
+        for _ in 0..INTERRUPTS_PER_BIT * 3 {
+            pool.credit_interrupt();
+        }
+        assert_eq!(pool.entropy_bits, 3);
+        pool.credit(POOL_BITS);
+        assert_eq!(pool.entropy_bits, POOL_BITS);
+        pool.extract();
+        assert_eq!(pool.entropy_bits, 0);
+    }
+
+    #[test]
+    fn test_pool_derive_moves_on() {
+        let mut a = InputPool::new();
+        let mut b = InputPool::new();
+        a.mix(b"same input");
+        b.mix(b"same input");
+        let first = a.derive();
+        assert_eq!(first, b.derive());
+        assert_ne!(first, a.derive());
+
+        b.mix(b"!");
+        assert_ne!(a.derive(), b.derive());
+    }
+
+    #[test]
+    fn test_reseed_needs_entropy() {
+        let mut crng = Crng::new();
+        let mut pool = InputPool::new();
+        pool.credit(READY_BITS - 1);
+        assert!(!try_reseed(&mut crng, &mut pool, 0));
+        assert!(!crng.seeded);
+
+        pool.credit(1);
+        assert!(try_reseed(&mut crng, &mut pool, 0));
+        assert!(crng.seeded);
+        assert_eq!(pool.entropy_bits, 0);
+
+        // Not again until the interval has passed
+        pool.credit(READY_BITS);
+        assert!(!try_reseed(&mut crng, &mut pool, RESEED_INTERVAL_MS - 1));
+        assert_eq!(pool.entropy_bits, READY_BITS);
+        assert!(!try_reseed(&mut crng, &mut pool, RESEED_INTERVAL_MS));
+        assert_eq!(pool.entropy_bits, 0);
+    }
+
+    #[test]
+    fn test_crng_fast_key_erasure() {
+        let mut crng = Crng::new();
+        crng.reseed(&[1; KEY_WORDS], 0);
+        let key = crng.key;
+
+        let mut out = [0u8; 100];
+        crng.fill(&mut out);
+        assert_ne!(crng.key, key);
+
+        // Output is the rest of the rekeying block, then the old key's stream
+        let first = chacha20::block(&key, 0, &NONCE);
+        assert_eq!(out[..32], first[32..]);
+        assert_eq!(out[32..96], chacha20::block(&key, 1, &NONCE));
+
+        let mut again = [0u8; 100];
+        crng.fill(&mut again);
+        assert_ne!(out, again);
+    }
+
+    #[test]
+    fn test_get_random_bytes() {
+        let mut a = [0u8; 300];
+        let mut b = [0u8; 300];
+        get_random_bytes(&mut a);
+        get_random_bytes(&mut b);
+        assert_ne!(a, b);
+        assert!(a.iter().any(|&byte| byte != 0));
+        assert_ne!(get_random_u64(), get_random_u64());
+    }
+
+    #[test]
+    fn test_aslr_offset() {
+        for _ in 0..16 {
+            let offset = aslr_offset(1 << 20);
+            assert_eq!(offset % 4096, 0);
+            assert!(offset < 1 << 20);
+        }
+        assert_eq!(aslr_offset(100), 0);
+    }
+
+    #[test]
+    fn test_secure_tcp_seq() {
+        let local = [10, 0, 2, 15];
+        let remote = [93, 184, 216, 34];
+        let a = secure_tcp_seq(local, remote, 40000, 80);
+        let b = secure_tcp_seq(local, remote, 40001, 80);
+        assert_ne!(a, b);
+        // Same connection only moves forward with the clock
+        let again = secure_tcp_seq(local, remote, 40000, 80);
+        assert!(again.wrapping_sub(a) < 1 << 30);
+    }
+
+    #[test]
+    fn test_getrandom_flags() {
+        assert_eq!(sys_getrandom(UserSlice::new(0, 16), 0x8), EINVAL);
+        assert_eq!(sys_getrandom(UserSlice::new(0, 16), GRND_RANDOM | GRND_INSECURE), EINVAL);
+        assert_eq!(sys_getrandom(UserSlice::new(0, 16), GRND_INSECURE), EFAULT);
+        assert_eq!(sys_getrandom(UserSlice::new(0, 0), GRND_INSECURE), 0);
+    }
+}
@@ -6,16 +6,53 @@
 /// - echo: Echo arguments
 /// - memory: Display memory statistics
 /// - ps: Display process/task list
+/// - top: Show tasks with CPU and memory use, refreshed every second
+/// - cpu: List CPUs, show CPU features and mitigations, take CPUs offline/online
+/// - irq: Display interrupt line statistics
+/// - dmesg: Show or clear the kernel log, set the console log level
+/// - date: Display the wall-clock date and time
+/// - font: Show or change the console font
+/// - loadkeys: Show or change the keyboard layout
+/// - stty: Show or change the terminal's line settings
+/// - ls, cat, mkdir, rm, cp, mv, hexdump, stat: Work with files on the
+///   root file system
+/// - lsblk, mount, umount, df: List disks, mount and unmount their file
+///   systems and show how full file systems are
+/// - ifconfig, ip, ping, arp, route, dhcp: Configure and test the network
+///   interface (see `net`)
+/// - set, export, unset: Show and change shell variables
+/// - true, false, test, [: Conditions for scripts
+/// - sh: Run a script file
+/// - jobs, fg, bg: List jobs and move them to the foreground or background
+/// - history: List, clear or save the command history
 /// - exit: Exit/halt the system
+///
+/// Other commands run the ELF program of that name from a directory in the
+/// PATH variable, with the exported variables as its environment, in the
+/// background if the line ends with `&`.
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use crate::fs::{FileSystem, FsError, PathResolver, VNodeAttr, VNodeType};
 use crate::io::framebuffer;
 use crate::memory;
 use crate::task;
 use crate::power;
 
 /// Execute a command
-pub fn execute(command: &str, args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+///
+/// # Returns
+/// The exit status: 0 on success, 1 for a false `test`, 127 for an
+/// unknown command
+pub fn execute(command: &str, args: Vec<&str>, background: bool, shell: &mut super::Shell) -> Result<i32, &'static str> {
+    match command {
+        "true" => return Ok(0),
+        "false" => return Ok(1),
+        "test" | "[" => return cmd_test(command, args),
+        "sh" => return cmd_sh(args, shell),
+        _ => {}
+    }
+
     match command {
         "" => Ok(()), // Empty command, do nothing
         "help" => cmd_help(),
@@ -23,23 +60,62 @@ pub fn execute(command: &str, args: Vec<&str>, shell: &mut super::Shell) -> Resu
         "echo" => cmd_echo(args),
         "memory" => cmd_memory(),
         "ps" => cmd_ps(),
+        "top" => cmd_top(args),
+        "cpu" => cmd_cpu(args),
+        "irq" => cmd_irq(),
+        "lockstat" => cmd_lockstat(args),
+        "preemptlat" => cmd_preemptlat(args),
+        "trace" => cmd_trace(args),
+        "syscalls" => cmd_syscalls(args),
+        "heapprof" => cmd_heapprof(args),
+        "dmesg" => cmd_dmesg(args),
         "power" => cmd_power(args),
         "uptime" => cmd_uptime(),
+        "date" => cmd_date(),
+        "font" => cmd_font(args),
+        "loadkeys" => cmd_loadkeys(args),
+        "stty" => cmd_stty(args),
+        "ls" => cmd_ls(args),
+        "cat" => cmd_cat(args),
+        "mkdir" => cmd_mkdir(args),
+        "rm" => cmd_rm(args),
+        "cp" => cmd_cp(args),
+        "mv" => cmd_mv(args),
+        "hexdump" => cmd_hexdump(args),
+        "stat" => cmd_stat(args),
+        "lsblk" => cmd_lsblk(),
+        "mount" => cmd_mount(args),
+        "umount" => cmd_umount(args),
+        "df" => cmd_df(),
         "uname" => cmd_uname(),
-        "ping" => cmd_ping(args),
+        "ifconfig" => super::net::cmd_ifconfig(args),
+        "ip" => super::net::cmd_ip(args),
+        "ping" => super::net::cmd_ping(args),
+        "arp" => super::net::cmd_arp(args),
+        "route" => super::net::cmd_route(args),
+        "dhcp" => super::net::cmd_dhcp(args),
         "reboot" => cmd_reboot(),
-        "shutdown" => cmd_shutdown(),
+        "shutdown" | "poweroff" => cmd_shutdown(),
         "suspend" => cmd_suspend(),
+        "set" => cmd_set(args, shell),
+        "export" => cmd_export(args, shell),
+        "unset" => cmd_unset(args, shell),
+        "jobs" => cmd_jobs(shell),
+        "fg" => cmd_fg(args, shell),
+        "bg" => cmd_bg(args, shell),
+        "history" => cmd_history(args, shell),
         "exit" => cmd_exit(shell),
+        _ if find_program(command, shell.env()).is_some() => run_program(command, args, background, shell),
         _ => {
             let mut fb = framebuffer::framebuffer();
             fb.write_string("Unknown command: ");
             fb.write_string(command);
             fb.write_string("\n");
             fb.write_string("Type 'help' for available commands.\n");
-            Ok(())
+            return Ok(127);
         }
     }
+    .map(|()| 0)
 }
 
 /// Display help information
@@ -51,14 +127,55 @@ fn cmd_help() -> Result<(), &'static str> {
     fb.write_string("  echo     - Echo arguments to screen\n");
     fb.write_string("  memory   - Display memory statistics\n");
     fb.write_string("  ps       - Display process/task list\n");
+    fb.write_string("  top      - Show tasks with live CPU and memory use (q quits)\n");
+    fb.write_string("  cpu      - List CPUs and features, offline/online\n");
+    fb.write_string("  irq      - Display interrupt line statistics\n");
+    fb.write_string("  lockstat - Show lock contention (lockstat on|off|reset)\n");
+    fb.write_string("  preemptlat - Show preempt-off and wakeup latencies (preemptlat on|off|reset)\n");
+    fb.write_string("  trace    - List trace events (trace on|off <event>, show [n], clear, save <file>)\n");
+    fb.write_string("  syscalls - Show syscall latencies (syscalls reset|<syscall>)\n");
+    fb.write_string("  heapprof - Show heap allocations (heapprof on|off|reset|interval <n>)\n");
+    fb.write_string("  dmesg    - Show the kernel log (-c, -C, -l, -n)\n");
     fb.write_string("  power    - Display/control power management\n");
     fb.write_string("  uptime   - Show system uptime\n");
+    fb.write_string("  date     - Show the current date and time\n");
+    fb.write_string("  font     - Show or change the console font\n");
+    fb.write_string("  loadkeys - Show or change the keyboard layout\n");
+    fb.write_string("  stty     - Show or change terminal line settings\n");
+    fb.write_string("  ls       - List a directory (-l for details)\n");
+    fb.write_string("  cat      - Print files\n");
+    fb.write_string("  mkdir    - Create directories (-p for parents)\n");
+    fb.write_string("  rm       - Remove files (-r for directories)\n");
+    fb.write_string("  cp       - Copy files (-r for directories)\n");
+    fb.write_string("  mv       - Move or rename files\n");
+    fb.write_string("  hexdump  - Print a file in hex\n");
+    fb.write_string("  stat     - Show file attributes\n");
+    fb.write_string("  lsblk    - List disks and partitions\n");
+    fb.write_string("  mount    - List mounts or mount a disk (mount <device> <path>)\n");
+    fb.write_string("  umount   - Write out and unmount a file system\n");
+    fb.write_string("  df       - Show file system size and free space\n");
     fb.write_string("  uname    - Display system information\n");
-    fb.write_string("  ping     - Send ICMP echo request (network)\n");
+    fb.write_string("  ifconfig - Show or set the interface address, up/down\n");
+    fb.write_string("  ip       - Show or change addresses, links, routes (ip addr|link|route|neigh)\n");
+    fb.write_string("  ping     - Send ICMP echo requests (-c count; Ctrl+C stops)\n");
+    fb.write_string("  arp      - Show the ARP cache (-d remove, -s add)\n");
+    fb.write_string("  route    - Show the routing table (route add|del)\n");
+    fb.write_string("  dhcp     - Show the DHCP lease (dhcp renew)\n");
     fb.write_string("  reboot   - Reboot the system\n");
-    fb.write_string("  shutdown - Power off the system\n");
+    fb.write_string("  shutdown - Power off the system (also poweroff)\n");
     fb.write_string("  suspend  - Suspend system to low power state\n");
+    fb.write_string("  set      - List or set shell variables (set NAME=value)\n");
+    fb.write_string("  export   - Pass variables to programs (export NAME[=value])\n");
+    fb.write_string("  unset    - Remove shell variables\n");
+    fb.write_string("  test     - Check files, strings and numbers (also [ ... ])\n");
+    fb.write_string("  true     - Succeed (false: fail)\n");
+    fb.write_string("  sh       - Run a script file\n");
+    fb.write_string("  jobs     - List background and stopped jobs\n");
+    fb.write_string("  fg       - Bring a job to the foreground (fg [%n])\n");
+    fb.write_string("  bg       - Resume a stopped job in the background (bg [%n])\n");
+    fb.write_string("  history  - List command history (-c clear, -w save; !n reruns n)\n");
     fb.write_string("  exit     - Exit the shell\n");
+    fb.write_string("Programs in /bin and /usr/bin run by name.\n");
     Ok(())
 }
 
@@ -86,6 +203,7 @@ fn cmd_echo(args: Vec<&str>) -> Result<(), &'static str> {
 
 /// Display memory statistics
 fn cmd_memory() -> Result<(), &'static str> {
+    let nodes = crate::numa::allocator::node_stats();
     let mut fb = framebuffer::framebuffer();
     let stats = memory::stats::stats();
     
@@ -130,46 +248,528 @@ fn cmd_memory() -> Result<(), &'static str> {
     write_number(&mut fb, stats.heap_deallocations());
     fb.write_string("\n");
     
+    // Free and total memory of each NUMA node
+    if nodes.len() > 1 {
+        for (node, pages) in nodes {
+            fb.write_string("  Node ");
+            write_number(&mut fb, node.as_usize());
+            fb.write_string(":         ");
+            write_size(&mut fb, pages.free_pages * memory::PAGE_SIZE);
+            fb.write_string(" free of ");
+            write_size(&mut fb, pages.total_pages * memory::PAGE_SIZE);
+            fb.write_string("\n");
+        }
+    }
+    
     Ok(())
 }
 
 /// Display process/task list
 fn cmd_ps() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let rows = super::top::snapshot();
+    let ready = task::scheduler::scheduler().ready_task_count();
     let mut fb = framebuffer::framebuffer();
-    
+
     fb.write_string("Task List:\n");
-    fb.write_string("  ID    NAME                STATE       PRIORITY\n");
-    fb.write_string("  ----  ------------------  ----------  --------\n");
-    
-    // Access the scheduler
-    let scheduler = task::scheduler::scheduler();
+    fb.write_string("  PID   NAME                STATE       PRIORITY  TIME       VCSW    IVCSW\n");
+    fb.write_string("  ----  ------------------  ----------  --------  ---------  ------  ------\n");
+
+    if rows.is_empty() {
+        fb.write_string("  No tasks running.\n");
+        return Ok(());
+    }
+
+    for row in rows {
+        let _ = writeln!(
+            fb,
+            "  {:<4}  {:<18}  {:<10}  {:<8}  {:>9}  {:<6}  {:<6}",
+            row.pid,
+            row.name,
+            row.state,
+            row.priority,
+            super::top::format_ticks(row.ticks),
+            row.nvcsw,
+            row.nivcsw,
+        );
+    }
+
+    fb.write_string("  Ready tasks: ");
+    write_number(&mut fb, ready);
+    fb.write_string("\n");
+
+    Ok(())
+}
+
+/// Show tasks and CPU and memory use, refreshed until `q`
+fn cmd_top(args: Vec<&str>) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err("Usage: top");
+    }
+    super::top::start()
+}
+
+/// List CPUs, show CPU features or mitigations, or take a CPU offline/online
+fn cmd_cpu(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::smp::{self, CpuId, CpuState};
     
-    // Get task count
-    let task_count = scheduler.total_task_count();
+    let mut fb = framebuffer::framebuffer();
+    let (action, cpu) = match args.as_slice() {
+        [] => {
+            let manager = smp::try_cpu_manager().ok_or("SMP not initialized")?;
+            let cpus: Vec<_> = {
+                let manager = manager.lock();
+                (0..manager.cpu_count()).filter_map(|id| manager.get_cpu(CpuId::new(id)).cloned()).collect()
+            };
+            
+            fb.write_string("  CPU  APIC  STATE         QUEUED\n");
+            for cpu in cpus {
+                let state = match cpu.state {
+                    CpuState::Online => "online",
+                    CpuState::Offline => "offline",
+                    CpuState::Initializing => "initializing",
+                    CpuState::Failed => "failed",
+                };
+                let _ = writeln!(
+                    fb,
+                    "  {:<3}  {:<4}  {:<12}  {}",
+                    cpu.id.as_usize(),
+                    cpu.apic_id,
+                    state,
+                    task::scheduler::nr_running(cpu.id.as_usize()),
+                );
+            }
+            return Ok(());
+        }
+        ["features"] => {
+            let features = fanga_arch_x86_64::cpuid::cpu_features();
+            let _ = writeln!(
+                fb,
+                "{} family {:#x} model {:#x} stepping {}",
+                features.vendor_id(),
+                features.family(),
+                features.model(),
+                features.stepping(),
+            );
+            if let Some(hypervisor) = features.hypervisor_id() {
+                let _ = writeln!(fb, "Hypervisor: {}", hypervisor);
+            }
+            let (physical, linear) = features.address_bits();
+            let _ = writeln!(fb, "Address sizes: {} bits physical, {} bits virtual", physical, linear);
+            let _ = writeln!(fb, "Flags: {}", features);
+            return Ok(());
+        }
+        ["mitigations"] => {
+            let _ = task::speculation::report(&mut *fb);
+            return Ok(());
+        }
+        [action @ ("offline" | "online"), cpu] => {
+            (*action, cpu.parse::<usize>().map_err(|_| "Invalid CPU number")?)
+        }
+        _ => {
+            fb.write_string("Usage: cpu [features | mitigations | offline|online <cpu>]\n");
+            return Ok(());
+        }
+    };
     
-    if task_count == 0 {
-        fb.write_string("  No tasks running.\n");
+    // Waiting for the CPU must not hold the framebuffer
+    drop(fb);
+    let cpu = CpuId::new(cpu);
+    if action == "offline" {
+        smp::cpu_down(cpu)?;
     } else {
-        // Iterate through tasks (simplified - we'll just show count for now)
-        fb.write_string("  Total tasks: ");
-        write_number(&mut fb, task_count);
-        fb.write_string("\n");
-        fb.write_string("  Ready tasks: ");
-        write_number(&mut fb, scheduler.ready_task_count());
-        fb.write_string("\n");
+        smp::cpu_up(cpu)?;
     }
     
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(fb, "CPU {} is {}", cpu.as_usize(), action);
+    Ok(())
+}
+
+/// Display interrupt line statistics
+fn cmd_irq() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let mut fb = framebuffer::framebuffer();
+    fb.write_string("  IRQ  COUNT       UNHANDLED  DEVICES\n");
+    for stats in crate::irq::irq_stats() {
+        let _ = write!(fb, "  {:<3}  {:<10}  {:<9} ", stats.irq, stats.count, stats.unhandled);
+        for name in stats.names {
+            let _ = write!(fb, " {}", name);
+        }
+        fb.write_string("\n");
+    }
+    Ok(())
+}
+
+/// Show lock contention per lock class, or turn recording on or off
+fn cmd_lockstat(args: Vec<&str>) -> Result<(), &'static str> {
+    use crate::smp::lockstat;
+
+    match args.as_slice() {
+        [] => {}
+        ["on"] => {
+            lockstat::enable();
+            return Ok(());
+        }
+        ["off"] => {
+            lockstat::disable();
+            return Ok(());
+        }
+        ["reset"] => {
+            lockstat::reset();
+            return Ok(());
+        }
+        _ => return Err("Usage: lockstat [on|off|reset]"),
+    }
+
+    let classes = lockstat::stats();
+    let mut fb = framebuffer::framebuffer();
+    if !lockstat::is_enabled() {
+        fb.write_string("Recording is off (lockstat on)\n");
+    }
+    fb.write_string(&lockstat::format_report(&classes, task::clocksource::tsc_khz()));
+    Ok(())
+}
+
+/// Show how long preemption stays disabled and wakeups wait, per call site
+fn cmd_preemptlat(args: Vec<&str>) -> Result<(), &'static str> {
+    use crate::preempt::stats;
+
+    match args.as_slice() {
+        [] => {}
+        ["on"] => {
+            stats::enable_tracking();
+            return Ok(());
+        }
+        ["off"] => {
+            stats::disable_tracking();
+            return Ok(());
+        }
+        ["reset"] => {
+            stats::reset_latencies();
+            return Ok(());
+        }
+        _ => return Err("Usage: preemptlat [on|off|reset]"),
+    }
+
+    let mut fb = framebuffer::framebuffer();
+    if !stats::is_tracking() {
+        fb.write_string("Measuring is off (preemptlat on)\n");
+    }
+    fb.write_string(&stats::format_latency_report(task::clocksource::tsc_khz()));
+    Ok(())
+}
+
+/// List trace events, turn them on or off, or show, clear or save the records
+fn cmd_trace(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::profiling::trace;
+    const USAGE: &str = "Usage: trace [on|off <event>] [show [n]] [clear] [save <file>]";
+    /// Records `trace show` prints without a count
+    const SHOW_RECORDS: usize = 50;
+
+    match args.as_slice() {
+        [] => {
+            let mut fb = framebuffer::framebuffer();
+            for event in &trace::EVENTS {
+                let mark = if event.is_enabled() { 'x' } else { ' ' };
+                let _ = writeln!(fb, "  [{}] {}:{}", mark, event.subsystem, event.name);
+            }
+        }
+        [action @ ("on" | "off"), pattern] => {
+            if trace::set_enabled(pattern, *action == "on") == 0 {
+                return Err("No such trace event");
+            }
+        }
+        ["show", count @ ..] if count.len() <= 1 => {
+            let count = match count.first() {
+                Some(count) => count.parse().map_err(|_| USAGE)?,
+                None => SHOW_RECORDS,
+            };
+            let records = trace::records();
+            let mut fb = framebuffer::framebuffer();
+            fb.write_string(&trace::format_text(&records[records.len().saturating_sub(count)..]));
+            let overwritten = trace::overwritten();
+            if overwritten > 0 {
+                let _ = writeln!(fb, "({} older records overwritten)", overwritten);
+            }
+        }
+        ["clear"] => trace::clear(),
+        ["save", path] => {
+            let path = resolve(path)?;
+            crate::fs::write_file(&path, &trace::encode_binary(&trace::records())).map_err(FsError::as_str)?;
+        }
+        _ => return Err(USAGE),
+    }
+    Ok(())
+}
+
+/// Show syscall latencies, or the latency histogram of one syscall
+fn cmd_syscalls(args: Vec<&str>) -> Result<(), &'static str> {
+    use crate::profiling::syscall_latency;
+
+    match args.as_slice() {
+        [] => {
+            let stats = crate::profiling::get_syscall_stats();
+            if stats.is_empty() {
+                return Err("No syscalls recorded");
+            }
+            framebuffer::framebuffer().write_string(&syscall_latency::format_report(&stats));
+        }
+        ["reset"] => syscall_latency::reset(),
+        [name] => {
+            let stats = syscall_latency::lookup(name)
+                .and_then(syscall_latency::syscall_stats)
+                .ok_or("No such syscall")?;
+            framebuffer::framebuffer().write_string(&syscall_latency::format_histogram(&stats));
+        }
+        _ => return Err("Usage: syscalls [reset|<syscall>]"),
+    }
+    Ok(())
+}
+
+/// Show or control the heap allocation profiler
+fn cmd_heapprof(args: Vec<&str>) -> Result<(), &'static str> {
+    use crate::profiling::heap_profile;
+    const USAGE: &str = "Usage: heapprof [on|off|reset|interval <n>]";
+
+    match args.as_slice() {
+        [] => {}
+        ["on"] => {
+            heap_profile::enable();
+            return Ok(());
+        }
+        ["off"] => {
+            heap_profile::disable();
+            return Ok(());
+        }
+        ["reset"] => {
+            heap_profile::reset();
+            return Ok(());
+        }
+        ["interval", interval] => {
+            return heap_profile::set_sample_interval(interval.parse().map_err(|_| USAGE)?);
+        }
+        _ => return Err(USAGE),
+    }
+
+    let report = heap_profile::format_report(&crate::profiling::get_heap_stats());
+    let mut fb = framebuffer::framebuffer();
+    if !heap_profile::is_enabled() {
+        fb.write_string("Recording is off (heapprof on)\n");
+    }
+    fb.write_string(&report);
     Ok(())
 }
 
 /// Exit the shell
 fn cmd_exit(shell: &mut super::Shell) -> Result<(), &'static str> {
+    let _ = super::history::save(shell.env());
     let mut fb = framebuffer::framebuffer();
     fb.write_string("Exiting shell...\n");
     shell.stop();
     Ok(())
 }
 
+/// List the command history, or clear or save it
+fn cmd_history(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let (flags, _) = options(args, "cw")?;
+    if flags.contains('c') {
+        if let Some(history) = super::history::history().as_mut() {
+            history.clear();
+        }
+    }
+    if flags.contains('w') {
+        super::history::save(shell.env()).map_err(FsError::as_str)?;
+    }
+    if !flags.is_empty() {
+        return Ok(());
+    }
+
+    let history_guard = super::history::history();
+    let mut fb = framebuffer::framebuffer();
+    for (i, command) in history_guard.iter().flat_map(|history| history.iter()).enumerate() {
+        let _ = writeln!(fb, "{:>5}  {}", i + 1, command);
+    }
+    Ok(())
+}
+
+/// Evaluate a `test` expression
+///
+/// Supports `! expr`, a lone string (true if not empty), `-n`, `-z`,
+/// `-e`, `-f` and `-d`, `=` and `!=`, and the integer comparisons `-eq`,
+/// `-ne`, `-lt`, `-le`, `-gt` and `-ge`.
+fn test_expression(args: &[&str]) -> Result<bool, &'static str> {
+    let integer = |arg: &str| arg.parse::<i64>().map_err(|_| "Integer expression expected");
+    let file_type = |path: &str| {
+        let path = resolve(path).ok()?;
+        crate::fs::try_root_fs()?.lock().lookup(&path).ok().map(|vnode| vnode.vtype)
+    };
+
+    match *args {
+        [] => Ok(false),
+        ["!", ref rest @ ..] => test_expression(rest).map(|result| !result),
+        [string] => Ok(!string.is_empty()),
+        ["-n", string] => Ok(!string.is_empty()),
+        ["-z", string] => Ok(string.is_empty()),
+        ["-e", path] => Ok(file_type(path).is_some()),
+        ["-f", path] => Ok(file_type(path) == Some(VNodeType::File)),
+        ["-d", path] => Ok(file_type(path) == Some(VNodeType::Directory)),
+        [left, "=", right] => Ok(left == right),
+        [left, "!=", right] => Ok(left != right),
+        [left, op, right] => {
+            let (left, right) = (integer(left)?, integer(right)?);
+            match op {
+                "-eq" => Ok(left == right),
+                "-ne" => Ok(left != right),
+                "-lt" => Ok(left < right),
+                "-le" => Ok(left <= right),
+                "-gt" => Ok(left > right),
+                "-ge" => Ok(left >= right),
+                _ => Err("Unknown test operator"),
+            }
+        }
+        _ => Err("Invalid test expression"),
+    }
+}
+
+/// `test` and `[`: exit status 0 if the expression is true, 1 if not
+fn cmd_test(command: &str, args: Vec<&str>) -> Result<i32, &'static str> {
+    let args = match (command, args.split_last()) {
+        ("[", Some((&"]", expression))) => expression,
+        ("[", _) => return Err("Missing ]"),
+        _ => &args[..],
+    };
+    Ok(if test_expression(args)? { 0 } else { 1 })
+}
+
+/// Run a script file from the root file system
+fn cmd_sh(args: Vec<&str>, shell: &mut super::Shell) -> Result<i32, &'static str> {
+    let path = resolve(args.first().ok_or("Usage: sh SCRIPT")?)?;
+    let text = crate::fs::read_file(&path).map_err(FsError::as_str)?;
+    let text = core::str::from_utf8(&text).map_err(|_| "Script is not text")?;
+    shell.run_script(text)
+}
+
+/// List shell variables, or set them
+fn cmd_set(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    if args.is_empty() {
+        let mut fb = framebuffer::framebuffer();
+        for (name, value, _) in shell.env().iter() {
+            let _ = writeln!(fb, "{}={}", name, value);
+        }
+        return Ok(());
+    }
+    for arg in args {
+        let (name, value) = super::env::parse_assignment(arg).ok_or("Usage: set NAME=value")?;
+        shell.env_mut().set(name, value)?;
+    }
+    Ok(())
+}
+
+/// Export variables to started programs, setting them first if given a
+/// value; without arguments, list the exported variables
+fn cmd_export(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    if args.is_empty() {
+        let mut fb = framebuffer::framebuffer();
+        for (name, value, _) in shell.env().iter().filter(|(_, _, exported)| *exported) {
+            let _ = writeln!(fb, "export {}={}", name, value);
+        }
+        return Ok(());
+    }
+    for arg in args {
+        let env = shell.env_mut();
+        let name = match super::env::parse_assignment(arg) {
+            Some((name, value)) => {
+                env.set(name, value)?;
+                name
+            }
+            None if super::env::Environment::is_valid_name(arg) => {
+                if env.get(arg).is_none() {
+                    env.set(arg, "")?;
+                }
+                arg
+            }
+            None => return Err("Invalid variable name"),
+        };
+        env.export(name);
+    }
+    Ok(())
+}
+
+/// Remove shell variables
+fn cmd_unset(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    if args.is_empty() {
+        return Err("Usage: unset NAME...");
+    }
+    for name in args {
+        shell.env_mut().unset(name);
+    }
+    Ok(())
+}
+
+/// List the shell's jobs
+fn cmd_jobs(shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let current = shell.jobs().current();
+    let mut fb = framebuffer::framebuffer();
+    for job in shell.jobs().iter() {
+        let _ = writeln!(fb, "{}", job.describe(current == Some(job.id)));
+    }
+    Ok(())
+}
+
+/// Continue a job's process group if it is stopped
+fn continue_job(job: &super::jobs::Job) {
+    use crate::task::sigadv::{self, SignalTarget};
+
+    if job.state != super::jobs::JobState::Stopped {
+        return;
+    }
+    if let Some(manager) = sigadv::try_signal_manager() {
+        manager.lock().send_signal(SignalTarget::ProcessGroup(job.pgid), task::ipc::Signal::SIGCONT, task::TaskId::new(0));
+    }
+}
+
+/// Move a job to the foreground and wait for it
+fn cmd_fg(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    let id = shell.jobs().resolve(args.first().copied())?;
+    let job = shell.jobs().get(id).ok_or("No such job")?.clone();
+    {
+        let mut fb = framebuffer::framebuffer();
+        fb.write_string(&job.command);
+        fb.write_string("\n");
+    }
+    shell.set_foreground(id)?;
+    continue_job(&job);
+    Ok(())
+}
+
+/// Resume a stopped job in the background
+fn cmd_bg(args: Vec<&str>, shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let id = shell.jobs().resolve(args.first().copied())?;
+    let job = shell.jobs().get(id).ok_or("No such job")?.clone();
+    if job.state != super::jobs::JobState::Stopped {
+        return Err("Job is not stopped");
+    }
+    shell.set_background(id)?;
+    continue_job(&job);
+    let _ = writeln!(framebuffer::framebuffer(), "[{}]+ {} &", job.id, job.command);
+    Ok(())
+}
+
 /// Helper function to write a memory size in human-readable format
 fn write_size(fb: &mut framebuffer::FramebufferWriter, size: usize) {
     if size >= 1024 * 1024 * 1024 {
@@ -257,7 +857,64 @@ fn cmd_power(args: Vec<&str>) -> Result<(), &'static str> {
         
         fb.write_string("  Frequency: ");
         write_number(&mut fb, summary.cpu.frequency_mhz as usize);
-        fb.write_string(" MHz\n\n");
+        fb.write_string(" MHz\n");
+        fb.write_string("  Scaling: ");
+        match power::cpu::scaling() {
+            Some(scaling) => {
+                fb.write_string(scaling.interface.name());
+                fb.write_string(", ");
+                write_number(&mut fb, scaling.levels[scaling.levels.len() - 1].frequency_mhz as usize);
+                fb.write_string("-");
+                write_number(&mut fb, scaling.levels[0].frequency_mhz as usize);
+                fb.write_string(" MHz\n");
+            }
+            None => fb.write_string("none (estimated frequencies)\n"),
+        }
+        fb.write_string("  Idle states (deepest MWAIT C");
+        write_number(&mut fb, power::cpu::mwait_hints().deepest() as usize);
+        fb.write_string("):\n");
+        for (idx, name) in ["C0", "C1", "C2", "C3"].iter().enumerate().skip(1) {
+            fb.write_string("    ");
+            fb.write_string(name);
+            fb.write_string(": ");
+            write_number(&mut fb, summary.idle.usage[idx] as usize);
+            fb.write_string(" entries, ");
+            write_number(&mut fb, summary.idle.residency_ms[idx] as usize);
+            fb.write_string(" ms\n");
+        }
+        fb.write_string("\n");
+
+        // Temperatures
+        fb.write_string("Thermal:\n");
+        let thermal = summary.thermal;
+        match thermal.celsius() {
+            Some(celsius) => {
+                fb.write_string("  Temperature: ");
+                write_number(&mut fb, celsius.max(0) as usize);
+                fb.write_string(" C (TjMax ");
+                write_number(&mut fb, thermal.tj_max as usize);
+                fb.write_string(" C)\n");
+            }
+            None => fb.write_string("  Temperature: unknown\n"),
+        }
+        if let Some(trips) = thermal.trips {
+            fb.write_string("  Trip points: passive ");
+            write_number(&mut fb, trips.passive.max(0) as usize);
+            fb.write_string(" C, critical ");
+            write_number(&mut fb, trips.critical.max(0) as usize);
+            fb.write_string(" C\n");
+        }
+        fb.write_string("  Throttling: ");
+        if thermal.throttle.is_active() {
+            fb.write_string("level limit ");
+            write_number(&mut fb, thermal.throttle.level_limit);
+            fb.write_string(", duty ");
+            write_number(&mut fb, thermal.throttle.duty as usize);
+            fb.write_string("/8\n");
+        } else {
+            fb.write_string("none\n");
+        }
+        fb.write_string("\n");
         
         // System Sleep State
         fb.write_string("System:\n");
@@ -407,6 +1064,633 @@ fn cmd_uptime() -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Display the current date and time
+fn cmd_date() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let mut fb = framebuffer::framebuffer();
+    match task::realtime::now() {
+        Some(now) => {
+            let _ = writeln!(fb, "{}", now);
+        }
+        None => fb.write_string("Wall clock not set\n"),
+    }
+    Ok(())
+}
+
+/// Show the console font, or switch to a PSF font file or the built-in one
+fn cmd_font(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::io::{font, vt};
+
+    let font = match args.as_slice() {
+        [] => {
+            let mut fb = framebuffer::framebuffer();
+            let font = fb.font();
+            let _ = writeln!(
+                fb,
+                "{}: {}x{}, {} glyphs",
+                font.name(),
+                font.width(),
+                font.height(),
+                font.glyph_count(),
+            );
+            return Ok(());
+        }
+        ["default"] => font::builtin(),
+        [path] => font::load(path)?,
+        _ => {
+            framebuffer::framebuffer().write_string("Usage: font [<file.psf> | default]\n");
+            return Ok(());
+        }
+    };
+    vt::set_font(font);
+    Ok(())
+}
+
+/// List the keyboard layouts, or switch to one by name
+fn cmd_loadkeys(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use fanga_arch_x86_64::keyboard_layout::{layout_manager, LayoutType};
+
+    match args.as_slice() {
+        [] => {
+            let current = layout_manager().current_layout();
+            let mut fb = framebuffer::framebuffer();
+            for layout in LayoutType::ALL {
+                let marker = if layout == current { '*' } else { ' ' };
+                let _ = writeln!(fb, "{} {}", marker, layout.layout().name());
+            }
+            Ok(())
+        }
+        [name] => layout_manager().set_layout_by_name(name),
+        _ => {
+            framebuffer::framebuffer().write_string("Usage: loadkeys [<layout>]\n");
+            Ok(())
+        }
+    }
+}
+
+/// Show the terminal's line settings, or change them
+///
+/// Takes `sane`, `raw`, `cooked` and flags such as `-echo` or `icanon`.
+fn cmd_stty(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::io::tty::{self, Termios, ECHO, ECHOE, ICANON, ICRNL, ISIG, ONLCR, OPOST};
+
+    const FLAGS: [(&str, u32); 5] = [
+        ("icanon", ICANON),
+        ("isig", ISIG),
+        ("echo", ECHO),
+        ("echoe", ECHOE),
+        ("icrnl", ICRNL),
+    ];
+
+    let tty = tty::active().ok_or("No terminal")?;
+    let mut termios = tty.termios();
+
+    if args.is_empty() {
+        let winsize = tty.winsize();
+        let mut fb = framebuffer::framebuffer();
+        let _ = writeln!(fb, "{}: rows {}; columns {}", tty.name(), winsize.ws_row, winsize.ws_col);
+        for (name, flag) in FLAGS {
+            let bits = if flag == ICRNL { termios.c_iflag } else { termios.c_lflag };
+            let _ = write!(fb, "{}{} ", if bits & flag != 0 { "" } else { "-" }, name);
+        }
+        let onlcr = termios.c_oflag & (OPOST | ONLCR) == OPOST | ONLCR;
+        let _ = writeln!(fb, "{}onlcr", if onlcr { "" } else { "-" });
+        return Ok(());
+    }
+
+    for arg in args {
+        match arg {
+            "sane" | "cooked" => termios = Termios::sane(),
+            "raw" => termios.make_raw(),
+            _ => {
+                let (name, enable) = match arg.strip_prefix('-') {
+                    Some(name) => (name, false),
+                    None => (arg, true),
+                };
+                let (_, flag) = FLAGS
+                    .into_iter()
+                    .find(|(known, _)| *known == name)
+                    .ok_or("Unknown stty setting")?;
+                let bits = if flag == ICRNL { &mut termios.c_iflag } else { &mut termios.c_lflag };
+                if enable {
+                    *bits |= flag;
+                } else {
+                    *bits &= !flag;
+                }
+            }
+        }
+    }
+    tty.set_termios(termios, false);
+    Ok(())
+}
+
+/// Show the kernel log
+///
+/// `dmesg [-l level]` prints the records at `level` (or all of them),
+/// `-c` clears the log after printing, `-C` clears it without printing and
+/// `-n level` sets the lowest level printed to the console.
+fn cmd_dmesg(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::io::logger::{self, LogLevel};
+
+    let mut only = None;
+    let mut print = true;
+    let mut clear = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-c" => clear = true,
+            "-C" => {
+                print = false;
+                clear = true;
+            }
+            "-l" | "-n" => {
+                let level = args
+                    .next()
+                    .and_then(LogLevel::from_name)
+                    .ok_or("Usage: dmesg [-c|-C] [-l level] [-n level]")?;
+                if arg == "-n" {
+                    logger::set_console_level(level);
+                    print = false;
+                } else {
+                    only = Some(level);
+                }
+            }
+            _ => return Err("Usage: dmesg [-c|-C] [-l level] [-n level]"),
+        }
+    }
+
+    if print {
+        let (first, next) = logger::range();
+        let mut fb = framebuffer::framebuffer();
+        for record in (first..next).filter_map(logger::record) {
+            if only.is_some_and(|level| level != record.level) {
+                continue;
+            }
+            let _ = writeln!(fb, "\x1b[{}m{}\x1b[0m", record.level.sgr(), record);
+        }
+    }
+    if clear {
+        logger::clear();
+    }
+    Ok(())
+}
+
+/// Path of the program file a command names
+///
+/// A command with a `/` is a path itself; others are looked up in the
+/// directories of the PATH variable.
+fn find_program(command: &str, env: &super::env::Environment) -> Option<String> {
+    let fs = crate::fs::root_fs().lock();
+    let is_file = |path: &str| fs.lookup(path).is_ok_and(|vnode| vnode.vtype == VNodeType::File);
+    if command.contains('/') {
+        let path = resolve(command).ok()?;
+        return is_file(&path).then_some(path);
+    }
+    env.get("PATH")
+        .unwrap_or("")
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathResolver::join(dir, command))
+        .find(|path| is_file(path))
+}
+
+/// Start a program as a job: a new process in its own session and
+/// process group
+///
+/// A foreground job gets the terminal and the shell prompts again once it
+/// exits or stops; a background job leaves the terminal to the shell.
+fn run_program(command: &str, args: Vec<&str>, background: bool, shell: &mut super::Shell) -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use crate::elf::ElfLoadError;
+
+    let path = find_program(command, shell.env()).ok_or("Command not found")?;
+    let binary = crate::fs::read_file(&path).map_err(FsError::as_str)?;
+    let mut line = String::from(command);
+    for arg in &args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    let mut argv = alloc::vec![path.as_str()];
+    argv.extend(args);
+    let envp = shell.env().envp();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    let task = crate::userspace::spawn(&binary, &argv, &envp).map_err(ElfLoadError::as_str)?;
+
+    let pgid = {
+        let mut groups = task::pgroup::process_groups();
+        groups.create_session(task)?;
+        groups.get_process_group(task).ok_or("No process group")?
+    };
+    let id = shell.add_job(task, pgid, line);
+    if background {
+        let _ = writeln!(framebuffer::framebuffer(), "[{}] {}", id, task.as_usize());
+        Ok(())
+    } else {
+        shell.set_foreground(id)
+    }
+}
+
+/// Split `-x` options off the arguments
+///
+/// # Returns
+/// The option letters and the other arguments, or an error for a letter
+/// not in `allowed`
+fn options<'a>(args: Vec<&'a str>, allowed: &str) -> Result<(String, Vec<&'a str>), &'static str> {
+    let mut letters = String::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => {
+                if !flags.chars().all(|flag| allowed.contains(flag)) {
+                    return Err("Unknown option");
+                }
+                letters.push_str(flags);
+            }
+            _ => rest.push(arg),
+        }
+    }
+    Ok((letters, rest))
+}
+
+/// Absolute form of a path argument; relative paths start at the root
+fn resolve(path: &str) -> Result<String, &'static str> {
+    PathResolver::new().resolve(path)
+}
+
+/// Where `cp` and `mv` put `src`: into `dst` if it is a directory
+fn target(fs: &dyn FileSystem, src: &str, dst: &str) -> String {
+    match (fs.lookup(dst), PathResolver::filename(src)) {
+        (Ok(vnode), Some(name)) if vnode.vtype == VNodeType::Directory => PathResolver::join(dst, name),
+        _ => String::from(dst),
+    }
+}
+
+/// Contents of a file, or what a device has ready for a single read
+fn read_contents(fs: &dyn FileSystem, path: &str) -> Result<Vec<u8>, FsError> {
+    let vnode = fs.lookup(path)?;
+    match vnode.vtype {
+        VNodeType::File => crate::fs::read_file_in(fs, path),
+        VNodeType::Directory => Err(FsError::IsADirectory),
+        VNodeType::CharDevice => {
+            let mut data = alloc::vec![0; 512];
+            let len = fs.read(&vnode, 0, &mut data)?;
+            data.truncate(len);
+            Ok(data)
+        }
+    }
+}
+
+/// A timestamp as `YYYY-MM-DD HH:MM`, or `-` if unknown
+fn format_time(secs: u64) -> String {
+    if secs == 0 {
+        return alloc::format!("{:<16}", "-");
+    }
+    let time = task::realtime::DateTime::from_unix(secs);
+    alloc::format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        time.year, time.month, time.day, time.hour, time.minute
+    )
+}
+
+/// One `ls -l` line
+fn format_long(name: &str, attr: &VNodeAttr) -> String {
+    let kind = match attr.vtype {
+        VNodeType::File => '-',
+        VNodeType::Directory => 'd',
+        VNodeType::CharDevice => 'c',
+    };
+    alloc::format!("{} {:>8} {} {}\n", kind, attr.size, format_time(attr.modified), name)
+}
+
+/// One `hexdump` line: offset, 16 bytes in hex and as ASCII
+fn format_hex_line(offset: usize, bytes: &[u8]) -> String {
+    use core::fmt::Write;
+
+    let mut line = alloc::format!("{:08x} ", offset);
+    for i in 0..16 {
+        if i == 8 {
+            line.push(' ');
+        }
+        match bytes.get(i) {
+            Some(byte) => {
+                let _ = write!(line, " {:02x}", byte);
+            }
+            None => line.push_str("   "),
+        }
+    }
+    line.push_str("  |");
+    for &byte in bytes {
+        line.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+    }
+    line.push_str("|\n");
+    line
+}
+
+/// List directories, or show files
+///
+/// `-l` adds the type, size and modification time of each entry.
+fn cmd_ls(args: Vec<&str>) -> Result<(), &'static str> {
+    let (flags, mut paths) = options(args, "l")?;
+    let long = flags.contains('l');
+    if paths.is_empty() {
+        paths.push("/");
+    }
+
+    // Collect everything first so the file system is not locked while printing
+    let mut out = String::new();
+    {
+        let fs = crate::fs::root_fs().lock();
+        let headers = paths.len() > 1;
+        for path in paths {
+            let path = resolve(path)?;
+            let vnode = fs.lookup(&path).map_err(FsError::as_str)?;
+            let entries = if vnode.vtype == VNodeType::Directory {
+                if headers {
+                    out.push_str(&alloc::format!("{}:\n", path));
+                }
+                let mut entries: Vec<(String, String)> = fs
+                    .readdir(&vnode)
+                    .map_err(FsError::as_str)?
+                    .into_iter()
+                    .map(|entry| (PathResolver::join(&path, &entry.name), entry.name))
+                    .collect();
+                entries.sort_by(|a, b| a.1.cmp(&b.1));
+                entries
+            } else {
+                alloc::vec![(path.clone(), path.clone())]
+            };
+            for (full, name) in entries {
+                let attr = fs.stat(&fs.lookup(&full).map_err(FsError::as_str)?).map_err(FsError::as_str)?;
+                if long {
+                    out.push_str(&format_long(&name, &attr));
+                } else {
+                    out.push_str(&name);
+                    out.push_str(if attr.vtype == VNodeType::Directory { "/\n" } else { "\n" });
+                }
+            }
+        }
+    }
+    framebuffer::framebuffer().write_string(&out);
+    Ok(())
+}
+
+/// Print files; bytes that are not UTF-8 are replaced
+fn cmd_cat(args: Vec<&str>) -> Result<(), &'static str> {
+    if args.is_empty() {
+        return Err("Usage: cat <file>...");
+    }
+    for path in args {
+        let path = resolve(path)?;
+        let data = read_contents(&*crate::fs::root_fs().lock(), &path).map_err(FsError::as_str)?;
+        framebuffer::framebuffer().write_string(&String::from_utf8_lossy(&data));
+    }
+    Ok(())
+}
+
+/// Create directories; `-p` also creates missing parents and accepts
+/// existing ones
+fn cmd_mkdir(args: Vec<&str>) -> Result<(), &'static str> {
+    let (flags, paths) = options(args, "p")?;
+    if paths.is_empty() {
+        return Err("Usage: mkdir [-p] <dir>...");
+    }
+    let mut fs = crate::fs::root_fs().lock();
+    for path in paths {
+        let path = resolve(path)?;
+        let result = if flags.contains('p') {
+            crate::fs::create_dir_all_in(&mut *fs, &path)
+        } else {
+            fs.create(&path, VNodeType::Directory).map(|_| ())
+        };
+        result.map_err(FsError::as_str)?;
+    }
+    Ok(())
+}
+
+/// Remove files and device nodes; `-r` removes directories with their
+/// contents
+fn cmd_rm(args: Vec<&str>) -> Result<(), &'static str> {
+    let (flags, paths) = options(args, "rf")?;
+    if paths.is_empty() {
+        return Err("Usage: rm [-r] <path>...");
+    }
+    let mut fs = crate::fs::root_fs().lock();
+    for path in paths {
+        let path = resolve(path)?;
+        let vnode = fs.lookup(&path).map_err(FsError::as_str)?;
+        if vnode.vtype == VNodeType::Directory && !flags.contains('r') {
+            return Err("Is a directory (use rm -r)");
+        }
+        crate::fs::remove_all_in(&mut *fs, &path).map_err(FsError::as_str)?;
+    }
+    Ok(())
+}
+
+/// Copy a file, or a directory with `-r`, to a path or into a directory
+fn cmd_cp(args: Vec<&str>) -> Result<(), &'static str> {
+    let (flags, paths) = options(args, "r")?;
+    let [src, dst] = paths.as_slice() else {
+        return Err("Usage: cp [-r] <source> <destination>");
+    };
+    let (src, dst) = (resolve(src)?, resolve(dst)?);
+    let mut fs = crate::fs::root_fs().lock();
+    let vnode = fs.lookup(&src).map_err(FsError::as_str)?;
+    if vnode.vtype == VNodeType::Directory && !flags.contains('r') {
+        return Err("Is a directory (use cp -r)");
+    }
+    let dst = target(&*fs, &src, &dst);
+    crate::fs::copy_in(&mut *fs, &src, &dst).map_err(FsError::as_str)
+}
+
+/// Move or rename a file or directory
+fn cmd_mv(args: Vec<&str>) -> Result<(), &'static str> {
+    let [src, dst] = args.as_slice() else {
+        return Err("Usage: mv <source> <destination>");
+    };
+    let (src, dst) = (resolve(src)?, resolve(dst)?);
+    let mut fs = crate::fs::root_fs().lock();
+    let dst = target(&*fs, &src, &dst);
+    crate::fs::rename_in(&mut *fs, &src, &dst).map_err(FsError::as_str)
+}
+
+/// Print a file as hex and ASCII, 16 bytes per line
+fn cmd_hexdump(args: Vec<&str>) -> Result<(), &'static str> {
+    let [path] = args.as_slice() else {
+        return Err("Usage: hexdump <file>");
+    };
+    let path = resolve(path)?;
+    let data = read_contents(&*crate::fs::root_fs().lock(), &path).map_err(FsError::as_str)?;
+    let mut fb = framebuffer::framebuffer();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        fb.write_string(&format_hex_line(i * 16, chunk));
+    }
+    fb.write_string(&alloc::format!("{:08x}\n", data.len()));
+    Ok(())
+}
+
+/// Show the attributes of a file, directory or device node
+fn cmd_stat(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let [path] = args.as_slice() else {
+        return Err("Usage: stat <path>");
+    };
+    let path = resolve(path)?;
+    let attr = {
+        let fs = crate::fs::root_fs().lock();
+        let vnode = fs.lookup(&path).map_err(FsError::as_str)?;
+        fs.stat(&vnode).map_err(FsError::as_str)?
+    };
+    let kind = match attr.vtype {
+        VNodeType::File => "regular file",
+        VNodeType::Directory => "directory",
+        VNodeType::CharDevice => "character device",
+    };
+    let time = |secs: u64| match secs {
+        0 => String::from("-"),
+        secs => alloc::format!("{}", task::realtime::DateTime::from_unix(secs)),
+    };
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(fb, "    File: {}", path);
+    let _ = writeln!(fb, "    Type: {}", kind);
+    let _ = writeln!(fb, "    Size: {}", attr.size);
+    let _ = writeln!(fb, " Created: {}", time(attr.created));
+    let _ = writeln!(fb, "Modified: {}", time(attr.modified));
+    Ok(())
+}
+
+/// Size in bytes with a binary unit, such as "12.5M"
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut unit = 0;
+    let mut scaled = bytes;
+    while scaled >= 1024 * 10 && unit + 1 < UNITS.len() {
+        scaled /= 1024;
+        unit += 1;
+    }
+    if scaled >= 1024 && unit + 1 < UNITS.len() {
+        // One decimal place below 10 of the next unit
+        let tenths = bytes * 10 / (1u64 << (10 * (unit + 1)));
+        return alloc::format!("{}.{}{}", tenths / 10, tenths % 10, UNITS[unit + 1]);
+    }
+    alloc::format!("{}{}", scaled, UNITS[unit])
+}
+
+/// List disks and their partitions with the file systems on them
+fn cmd_lsblk() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    let devices = crate::storage::registry::list();
+    let mounts: Vec<crate::fs::MountInfo> = crate::fs::root_fs().lock().mounts().cloned().collect();
+    let mut fb = framebuffer::framebuffer();
+    if devices.is_empty() {
+        fb.write_string("No block devices\n");
+        return Ok(());
+    }
+    let _ = writeln!(fb, "{:<10} {:>7} {:<4} {:<6} MOUNTPOINT", "NAME", "SIZE", "TYPE", "FSTYPE");
+    for device in devices {
+        let (name, kind) = match device.parent {
+            Some(_) => (alloc::format!("  {}", device.name), "part"),
+            None => (device.name.clone(), "disk"),
+        };
+        let mount_point = mounts.iter().find(|mount| mount.source == device.name).map_or("", |mount| mount.path.as_str());
+        let _ = writeln!(
+            fb,
+            "{:<10} {:>7} {:<4} {:<6} {}",
+            name,
+            format_size(device.size()),
+            kind,
+            device.fstype.unwrap_or("-"),
+            mount_point
+        );
+    }
+    Ok(())
+}
+
+/// List mounts, or mount the file system on a disk or partition
+fn cmd_mount(args: Vec<&str>) -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    match args.as_slice() {
+        [] => {
+            let mounts: Vec<crate::fs::MountInfo> = crate::fs::root_fs().lock().mounts().cloned().collect();
+            let mut fb = framebuffer::framebuffer();
+            fb.write_string("rootfs on / type memfs\n");
+            for mount in mounts {
+                let _ = writeln!(fb, "{} on {} type {}", mount.source, mount.path, mount.fstype);
+            }
+            Ok(())
+        }
+        [device, path] => {
+            let path = resolve(path)?;
+            let source = device.strip_prefix("/dev/").unwrap_or(device);
+            // Read the disk before locking the file system
+            let (fs, fstype) = crate::storage::registry::open_filesystem(source)?;
+            crate::fs::root_fs().lock().mount(&path, source, fstype, fs).map_err(FsError::as_str)
+        }
+        _ => Err("Usage: mount [<device> <path>]"),
+    }
+}
+
+/// Write out and unmount the file system mounted at a path
+fn cmd_umount(args: Vec<&str>) -> Result<(), &'static str> {
+    let [path] = args.as_slice() else {
+        return Err("Usage: umount <path>");
+    };
+    let path = resolve(path)?;
+    match crate::fs::root_fs().lock().unmount(&path) {
+        Ok(_) => Ok(()),
+        Err(FsError::InvalidArgument) => Err("Not mounted"),
+        Err(e) => Err(e.as_str()),
+    }
+}
+
+/// Show the size and free space of the root and every mounted file system
+fn cmd_df() -> Result<(), &'static str> {
+    use core::fmt::Write;
+
+    // Collect everything first so the file system is not locked while printing
+    let rows: Vec<(String, String, Option<crate::fs::FsStats>)> = {
+        let fs = crate::fs::root_fs().lock();
+        let mut rows = alloc::vec![(String::from("rootfs"), String::from("/"), fs.statfs().ok())];
+        for mount in fs.mounts() {
+            rows.push((mount.source.clone(), mount.path.clone(), fs.statfs_at(&mount.path).ok()));
+        }
+        rows
+    };
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(fb, "{:<10} {:>7} {:>7} {:>7} {:>4} Mounted on", "Filesystem", "Size", "Used", "Avail", "Use%");
+    for (source, path, stats) in rows {
+        match stats {
+            Some(stats) => {
+                let percent = match stats.size() {
+                    0 => 0,
+                    size => stats.used().saturating_mul(100).div_ceil(size),
+                };
+                let _ = writeln!(
+                    fb,
+                    "{:<10} {:>7} {:>7} {:>7} {:>3}% {}",
+                    source,
+                    format_size(stats.size()),
+                    format_size(stats.used()),
+                    format_size(stats.free()),
+                    percent,
+                    path
+                );
+            }
+            None => {
+                let _ = writeln!(fb, "{:<10} {:>7} {:>7} {:>7} {:>4} {}", source, "-", "-", "-", "-", path);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Display system information
 fn cmd_uname() -> Result<(), &'static str> {
     let mut fb = framebuffer::framebuffer();
@@ -420,51 +1704,13 @@ fn cmd_uname() -> Result<(), &'static str> {
     Ok(())
 }
 
-/// Send ICMP echo request (ping)
-fn cmd_ping(args: Vec<&str>) -> Result<(), &'static str> {
-    let mut fb = framebuffer::framebuffer();
-    
-    if args.is_empty() {
-        fb.write_string("Usage: ping <hostname|ip>\n");
-        fb.write_string("Example: ping 8.8.8.8\n");
-        return Ok(());
-    }
-    
-    let target = args[0];
-    
-    fb.write_string("PING ");
-    fb.write_string(target);
-    fb.write_string("...\n");
-    fb.write_string("Error: Network stack not fully initialized\n");
-    fb.write_string("ICMP protocol implementation pending\n");
-    
-    Ok(())
-}
-
 /// Reboot the system
 fn cmd_reboot() -> Result<(), &'static str> {
     let mut fb = framebuffer::framebuffer();
     
     fb.write_string("Rebooting system...\n");
-    
-    // Use keyboard controller reset method (standard on x86_64)
-    unsafe {
-        // Wait for keyboard controller to be ready
-        while (fanga_arch_x86_64::port::inb(0x64) & 0x02) != 0 {
-            core::hint::spin_loop();
-        }
-        
-        // Send reset command to keyboard controller
-        fanga_arch_x86_64::port::outb(0x64, 0xFE);
-    }
-    
-    // If we get here, reboot failed
-    fb.write_string("Reboot failed. System halted.\n");
-    loop {
-        unsafe {
-            core::arch::asm!("hlt", options(nostack, nomem));
-        }
-    }
+    drop(fb);
+    power::reboot()
 }
 
 /// Shutdown the system
@@ -472,20 +1718,8 @@ fn cmd_shutdown() -> Result<(), &'static str> {
     let mut fb = framebuffer::framebuffer();
     
     fb.write_string("Shutting down system...\n");
-    
-    // Try QEMU/Bochs shutdown port
-    unsafe {
-        // QEMU and Bochs support this I/O port for shutdown
-        fanga_arch_x86_64::port::outw(0x604, 0x2000);
-    }
-    
-    // If we're still here, halt the system
-    fb.write_string("System halted.\n");
-    loop {
-        unsafe {
-            core::arch::asm!("hlt", options(nostack, nomem));
-        }
-    }
+    drop(fb);
+    power::poweroff()
 }
 
 /// Suspend the system to low power state
@@ -493,8 +1727,11 @@ fn cmd_suspend() -> Result<(), &'static str> {
     let mut fb = framebuffer::framebuffer();
     
     fb.write_string("Suspending system to S3 (Suspend to RAM)...\n");
-    
-    match power::suspend::suspend_to_ram() {
+    drop(fb);
+
+    let result = power::suspend::suspend_to_ram();
+    let mut fb = framebuffer::framebuffer();
+    match result {
         Ok(_) => {
             fb.write_string("System resumed from suspend.\n");
             Ok(())
@@ -507,3 +1744,58 @@ fn cmd_suspend() -> Result<(), &'static str> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        let (flags, rest) = options(alloc::vec!["-l", "/boot", "-"], "l").unwrap();
+        assert_eq!(flags, "l");
+        assert_eq!(rest, ["/boot", "-"]);
+        assert!(options(alloc::vec!["-x"], "l").is_err());
+    }
+
+    #[test]
+    fn test_format_long() {
+        let attr = VNodeAttr { size: 1234, vtype: VNodeType::File, created: 0, modified: 86_400 + 3_660 };
+        assert_eq!(format_long("a.txt", &attr), "-     1234 1970-01-02 01:01 a.txt\n");
+        let attr = VNodeAttr { size: 0, vtype: VNodeType::Directory, created: 0, modified: 0 };
+        assert_eq!(format_long("boot", &attr), "d        0 -                boot\n");
+    }
+
+    #[test]
+    fn test_format_hex_line() {
+        assert_eq!(
+            format_hex_line(0x10, b"Hello, world!\n\x00\xff"),
+            "00000010  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n"
+        );
+        assert_eq!(
+            format_hex_line(0, b"ab"),
+            "00000000  61 62                                             |ab|\n"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(500), "500B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(20_000), "19K");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0M");
+    }
+
+    #[test]
+    fn test_test_expression() {
+        assert_eq!(test_expression(&["abc"]), Ok(true));
+        assert_eq!(test_expression(&["-z", ""]), Ok(true));
+        assert_eq!(test_expression(&["!", "-n", "x"]), Ok(false));
+        assert_eq!(test_expression(&["a", "=", "a"]), Ok(true));
+        assert_eq!(test_expression(&["a", "!=", "a"]), Ok(false));
+        assert_eq!(test_expression(&["3", "-lt", "10"]), Ok(true));
+        assert_eq!(test_expression(&["3", "-ge", "10"]), Ok(false));
+        assert!(test_expression(&["x", "-eq", "1"]).is_err());
+        assert!(test_expression(&["a", "b", "c", "d"]).is_err());
+        assert_eq!(test_expression(&[]), Ok(false));
+    }
+}
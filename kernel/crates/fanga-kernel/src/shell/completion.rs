@@ -1,24 +1,68 @@
 /// Tab completion for shell commands
 ///
-/// Provides command name completion when Tab is pressed
+/// Provides command name completion when Tab is pressed, and file and
+/// directory path completion for arguments and for commands containing
+/// a `/`
 
 use alloc::vec::Vec;
 use alloc::string::String;
+use crate::fs::{FileSystem, PathResolver, VNodeType};
 
 /// List of all available commands
 const COMMANDS: &[&str] = &[
+    "arp",
+    "bg",
+    "cat",
     "clear",
+    "cp",
+    "cpu",
+    "df",
+    "dhcp",
+    "dmesg",
     "echo",
     "exit",
+    "export",
+    "false",
+    "fg",
+    "font",
+    "heapprof",
     "help",
+    "hexdump",
+    "history",
+    "ifconfig",
+    "ip",
+    "irq",
+    "jobs",
+    "loadkeys",
+    "lockstat",
+    "ls",
+    "lsblk",
     "memory",
+    "mkdir",
+    "mount",
+    "mv",
     "ping",
     "power",
+    "poweroff",
+    "preemptlat",
     "ps",
     "reboot",
+    "rm",
+    "route",
+    "set",
+    "sh",
     "shutdown",
+    "stat",
+    "stty",
     "suspend",
+    "syscalls",
+    "test",
+    "top",
+    "trace",
+    "true",
+    "umount",
     "uname",
+    "unset",
     "uptime",
 ];
 
@@ -65,8 +109,12 @@ pub fn complete_common(prefix: &str) -> Option<String> {
         return Some(matches[0].clone());
     }
     
-    // Find common prefix
-    let first = &matches[0];
+    common_prefix(&matches)
+}
+
+/// Longest common prefix of completions, or `None` if there are none
+pub fn common_prefix(matches: &[String]) -> Option<String> {
+    let first = matches.first()?;
     let mut common_len = first.len();
     
     for cmd in matches.iter().skip(1) {
@@ -82,6 +130,65 @@ pub fn complete_common(prefix: &str) -> Option<String> {
     Some(String::from(&first[..common_len]))
 }
 
+/// Find path completions for a partial path in `fs`
+///
+/// The entries of the partial path's directory that start with its last
+/// component, with the directory part kept as typed. Relative paths start
+/// at the root, like the file commands; directories get a trailing `/`.
+pub fn complete_path_in(fs: &dyn FileSystem, partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => (&partial[..=i], &partial[i + 1..]),
+        None => ("", partial),
+    };
+    let Ok(dir_path) = PathResolver::new().resolve(if dir.is_empty() { "/" } else { dir }) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs.lookup(&dir_path).and_then(|vnode| fs.readdir(&vnode)) else {
+        return Vec::new();
+    };
+
+    let mut matches: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| entry.name.starts_with(prefix) && entry.name != "." && entry.name != "..")
+        .map(|entry| {
+            let mut path = alloc::format!("{}{}", dir, entry.name);
+            if entry.vtype == VNodeType::Directory {
+                path.push('/');
+            }
+            path
+        })
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Find path completions on the root file system
+pub fn complete_path(partial: &str) -> Vec<String> {
+    match crate::fs::try_root_fs() {
+        Some(fs) => complete_path_in(&*fs.lock(), partial),
+        None => Vec::new(),
+    }
+}
+
+/// Find completions for the last word of a command line
+///
+/// The first word is completed as a command name unless it contains a
+/// `/`; later words are completed as paths.
+///
+/// # Returns
+/// Where the word starts in `line`, and its completions
+pub fn complete_line(line: &str) -> (usize, Vec<String>) {
+    let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    let word = &line[start..];
+    let is_command = line[..start].trim().is_empty();
+    let matches = if is_command && !word.contains('/') {
+        complete(word)
+    } else {
+        complete_path(word)
+    };
+    (start, matches)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -96,9 +203,10 @@ mod tests {
     #[test]
     fn test_complete_multiple_matches() {
         let matches = complete("e");
-        assert_eq!(matches.len(), 2);
+        assert_eq!(matches.len(), 3);
         assert!(matches.contains(&String::from("echo")));
         assert!(matches.contains(&String::from("exit")));
+        assert!(matches.contains(&String::from("export")));
     }
     
     #[test]
@@ -120,4 +228,19 @@ mod tests {
         assert_eq!(complete_common("hel"), Some(String::from("help")));
         assert_eq!(complete_common("xyz"), None);
     }
+    
+    #[test]
+    fn test_complete_path() {
+        let mut fs = crate::fs::MemoryFileSystem::new();
+        crate::fs::create_dir_all_in(&mut fs, "/boot/grub").unwrap();
+        crate::fs::write_file_in(&mut fs, "/boot/kernel", b"").unwrap();
+        crate::fs::write_file_in(&mut fs, "/bin", b"").unwrap();
+
+        assert_eq!(complete_path_in(&fs, "/boot/"), ["/boot/grub/", "/boot/kernel"]);
+        assert_eq!(complete_path_in(&fs, "/boot/k"), ["/boot/kernel"]);
+        assert_eq!(complete_path_in(&fs, "bo"), ["boot/"]);
+        assert_eq!(complete_path_in(&fs, "b"), ["bin", "boot/"]);
+        assert!(complete_path_in(&fs, "/missing/").is_empty());
+        assert_eq!(common_prefix(&complete_path_in(&fs, "b")), Some(String::from("b")));
+    }
 }
@@ -0,0 +1,251 @@
+//! Shell environment variables
+//!
+//! Variables set with `set` or `NAME=value` belong to the shell; `export`
+//! also hands them to the programs it starts, as `NAME=value` strings in
+//! envp. The shell itself reads:
+//! - PATH: colon-separated directories searched for programs
+//! - PS1: the prompt
+//! - HOME: what a leading `~` in a word expands to
+//! - HISTFILE and HISTSIZE: where the command history is saved and how
+//!   many commands it keeps
+//!
+//! `$?` expands to the exit status of the last command.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Value of PATH in a new shell
+pub const DEFAULT_PATH: &str = "/bin:/usr/bin";
+/// Value of PS1 in a new shell
+pub const DEFAULT_PS1: &str = "fangaos> ";
+/// Value of HOME in a new shell
+pub const DEFAULT_HOME: &str = "/";
+
+/// A shell variable
+#[derive(Debug, Clone)]
+struct Variable {
+    name: String,
+    value: String,
+    exported: bool,
+}
+
+/// The shell's variables, in the order they were first set
+pub struct Environment {
+    vars: Vec<Variable>,
+    /// Exit status of the last command
+    status: i32,
+}
+
+impl Environment {
+    pub const fn new() -> Self {
+        Self { vars: Vec::new(), status: 0 }
+    }
+
+    /// Environment of a new shell: PATH, PS1 and HOME, all exported
+    pub fn with_defaults() -> Self {
+        let mut env = Self::new();
+        for (name, value) in [("PATH", DEFAULT_PATH), ("PS1", DEFAULT_PS1), ("HOME", DEFAULT_HOME)] {
+            let _ = env.set(name, value);
+            env.export(name);
+        }
+        env
+    }
+
+    /// Check that a variable name is letters, digits and `_`, not
+    /// starting with a digit
+    pub fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.iter().find(|var| var.name == name).map(|var| var.value.as_str())
+    }
+
+    /// Set a variable, keeping whether it is exported
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        if !Self::is_valid_name(name) {
+            return Err("Invalid variable name");
+        }
+        match self.vars.iter_mut().find(|var| var.name == name) {
+            Some(var) => var.value = String::from(value),
+            None => self.vars.push(Variable {
+                name: String::from(name),
+                value: String::from(value),
+                exported: false,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Mark a variable for the environment of started programs
+    ///
+    /// # Returns
+    /// Whether the variable exists
+    pub fn export(&mut self, name: &str) -> bool {
+        match self.vars.iter_mut().find(|var| var.name == name) {
+            Some(var) => {
+                var.exported = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a variable
+    pub fn unset(&mut self, name: &str) {
+        self.vars.retain(|var| var.name != name);
+    }
+
+    /// Exit status of the last command, for `$?`
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: i32) {
+        self.status = status;
+    }
+
+    /// Variables as `(name, value, exported)`
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, bool)> {
+        self.vars.iter().map(|var| (var.name.as_str(), var.value.as_str(), var.exported))
+    }
+
+    /// envp strings for a started program: `NAME=value` for every
+    /// exported variable
+    pub fn envp(&self) -> Vec<String> {
+        self.vars
+            .iter()
+            .filter(|var| var.exported)
+            .map(|var| alloc::format!("{}={}", var.name, var.value))
+            .collect()
+    }
+
+    /// Expand `$NAME`, `${NAME}`, `$?` and a leading `~` in a command line
+    ///
+    /// Unset variables expand to nothing. Text in single quotes is left
+    /// alone, and `\$` is a literal `$`.
+    pub fn expand(&self, line: &str) -> String {
+        let mut out = String::with_capacity(line.len());
+        let mut chars = line.char_indices().peekable();
+        let mut quoted = false;
+        let mut word_start = true;
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\'' => {
+                    quoted = !quoted;
+                    out.push(c);
+                }
+                _ if quoted => out.push(c),
+                '\\' if matches!(chars.peek(), Some((_, '$'))) => {
+                    chars.next();
+                    out.push('$');
+                }
+                '~' if word_start && matches!(chars.peek(), None | Some((_, '/')) | Some((_, ' '))) => {
+                    out.push_str(self.get("HOME").unwrap_or(DEFAULT_HOME));
+                }
+                '$' if matches!(chars.peek(), Some((_, '?'))) => {
+                    chars.next();
+                    out.push_str(&alloc::format!("{}", self.status));
+                }
+                '$' => {
+                    let rest = &line[i + 1..];
+                    let (name, len) = match rest.strip_prefix('{') {
+                        Some(braced) => match braced.find('}') {
+                            Some(end) => (&braced[..end], end + 2),
+                            None => ("", 0),
+                        },
+                        None => {
+                            let end = rest
+                                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                                .unwrap_or(rest.len());
+                            (&rest[..end], end)
+                        }
+                    };
+                    if len == 0 {
+                        out.push('$');
+                    } else {
+                        out.push_str(self.get(name).unwrap_or(""));
+                        // `len` counts bytes; skip to the byte after the name
+                        let end = i + 1 + len;
+                        while chars.next_if(|&(j, _)| j < end).is_some() {}
+                    }
+                }
+                _ => out.push(c),
+            }
+            word_start = c.is_whitespace();
+        }
+        out
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split a `NAME=value` word
+///
+/// # Returns
+/// The name and value, or `None` if the word is not an assignment
+pub fn parse_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    Environment::is_valid_name(name).then_some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_export_unset() {
+        let mut env = Environment::new();
+        env.set("FOO", "1").unwrap();
+        env.set("BAR", "2").unwrap();
+        assert!(env.set("1X", "3").is_err());
+        assert!(env.envp().is_empty());
+
+        assert!(env.export("FOO"));
+        assert!(!env.export("MISSING"));
+        env.set("FOO", "one").unwrap();
+        assert_eq!(env.envp(), alloc::vec![String::from("FOO=one")]);
+
+        env.unset("FOO");
+        assert_eq!(env.get("FOO"), None);
+        assert_eq!(env.get("BAR"), Some("2"));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let env = Environment::with_defaults();
+        assert_eq!(env.get("PATH"), Some(DEFAULT_PATH));
+        assert_eq!(env.get("PS1"), Some(DEFAULT_PS1));
+        assert_eq!(env.envp().len(), 3);
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut env = Environment::new();
+        env.set("NAME", "fanga").unwrap();
+        env.set("HOME", "/home/user").unwrap();
+        assert_eq!(env.expand("echo $NAME!"), "echo fanga!");
+        assert_eq!(env.expand("echo ${NAME}os"), "echo fangaos");
+        assert_eq!(env.expand("echo $MISSING."), "echo .");
+        assert_eq!(env.expand("echo '$NAME' \\$NAME $"), "echo '$NAME' $NAME $");
+        assert_eq!(env.expand("ls ~/docs a~b ~"), "ls /home/user/docs a~b /home/user");
+        assert_eq!(env.expand("echo ${é} after"), "echo  after");
+        env.set_status(127);
+        assert_eq!(env.expand("echo $?"), "echo 127");
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(parse_assignment("A=b=c"), Some(("A", "b=c")));
+        assert_eq!(parse_assignment("X="), Some(("X", "")));
+        assert_eq!(parse_assignment("=x"), None);
+        assert_eq!(parse_assignment("echo"), None);
+    }
+}
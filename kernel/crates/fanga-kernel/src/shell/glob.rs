@@ -0,0 +1,179 @@
+//! Wildcard expansion of command arguments
+//!
+//! An argument with `*`, `?` or `[...]` is a pattern, replaced by the
+//! sorted paths that match it in the file system:
+//! - `*` matches any run of characters, `?` any one character
+//! - `[abc]`, `[a-z]` and `[!abc]` match one character of (or not of) a set
+//!
+//! Wildcards never match a `/`, and only match a leading `.` if the
+//! pattern has one. A pattern that matches nothing is passed on as it is,
+//! as is an argument in single quotes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::{FileSystem, PathResolver, VNodeType};
+
+/// Check whether a word has wildcards
+pub fn is_pattern(word: &str) -> bool {
+    word.contains(['*', '?', '['])
+}
+
+/// Match a `[...]` set at the start of `pattern` against `c`
+///
+/// # Returns
+/// Whether `c` is in the set and the pattern after it, or `None` if the
+/// set is not closed, making `[` an ordinary character
+fn match_set(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, mut i) = match pattern.get(1) {
+        Some('!') | Some('^') => (true, 2),
+        _ => (false, 1),
+    };
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let &start = pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((found != negated, &pattern[i + 1..]));
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                found |= (start..=end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                found |= start == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| match_chars(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && match_chars(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), match_set(pattern, name.first().copied().unwrap_or('\0'))) {
+            (Some(_), Some((true, rest))) => match_chars(rest, &name[1..]),
+            (_, Some(_)) => false,
+            (Some('['), None) => match_chars(&pattern[1..], &name[1..]),
+            _ => false,
+        },
+        Some(&c) => name.first() == Some(&c) && match_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Check whether a file name matches a pattern
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+/// Paths in `fs` matching a pattern, sorted
+///
+/// Relative patterns are matched from the root, like the file commands,
+/// and the paths keep the pattern's form.
+pub fn expand_in(fs: &dyn FileSystem, pattern: &str) -> Vec<String> {
+    let absolute = pattern.starts_with('/');
+    let mut paths = alloc::vec![String::from(if absolute { "/" } else { "" })];
+    let components: Vec<&str> = pattern.split('/').filter(|part| !part.is_empty()).collect();
+
+    for (index, component) in components.iter().enumerate() {
+        let last = index + 1 == components.len();
+        let mut next = Vec::new();
+        for path in &paths {
+            let dir = if path.is_empty() { "/" } else { path.as_str() };
+            let join = |name: &str| match path.as_str() {
+                "" => String::from(name),
+                _ => PathResolver::join(path, name),
+            };
+            if !is_pattern(component) {
+                next.push(join(component));
+                continue;
+            }
+            let Ok(entries) = PathResolver::new()
+                .resolve(dir)
+                .map_err(|_| crate::fs::FsError::InvalidPath)
+                .and_then(|dir| fs.lookup(&dir))
+                .and_then(|vnode| fs.readdir(&vnode))
+            else {
+                continue;
+            };
+            for entry in entries {
+                // Only directories can have more components below them
+                if matches(component, &entry.name) && (last || entry.vtype == VNodeType::Directory) {
+                    next.push(join(&entry.name));
+                }
+            }
+        }
+        paths = next;
+    }
+
+    // Paths named without wildcards must exist too
+    paths.retain(|path| {
+        PathResolver::new()
+            .resolve(if path.is_empty() { "/" } else { path })
+            .is_ok_and(|path| fs.lookup(&path).is_ok())
+    });
+    paths.sort();
+    paths
+}
+
+/// Expand the wildcard arguments of a command
+pub fn expand_args(args: &[&str]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for &arg in args {
+        let paths = match crate::fs::try_root_fs() {
+            Some(fs) if is_pattern(arg) && !arg.contains('\'') => expand_in(&*fs.lock(), arg),
+            _ => Vec::new(),
+        };
+        if paths.is_empty() {
+            expanded.push(String::from(arg));
+        } else {
+            expanded.extend(paths);
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(!matches("*.txt", "notes.md"));
+        assert!(matches("log?", "log1"));
+        assert!(!matches("log?", "log"));
+        assert!(matches("[ab]*", "beta"));
+        assert!(!matches("[!ab]*", "beta"));
+        assert!(matches("file[0-9]", "file7"));
+        assert!(!matches("file[0-9]", "filex"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("a[b", "a[b"));
+        assert!(!matches("*", ".hidden"));
+        assert!(matches(".*", ".hidden"));
+    }
+
+    #[test]
+    fn test_expand_in() {
+        let mut fs = crate::fs::MemoryFileSystem::new();
+        crate::fs::create_dir_all_in(&mut fs, "/logs/old").unwrap();
+        for path in ["/logs/b.txt", "/logs/a.txt", "/logs/c.log", "/top.txt"] {
+            crate::fs::write_file_in(&mut fs, path, b"").unwrap();
+        }
+
+        assert_eq!(expand_in(&fs, "/logs/*.txt"), ["/logs/a.txt", "/logs/b.txt"]);
+        assert_eq!(expand_in(&fs, "logs/?.log"), ["logs/c.log"]);
+        assert_eq!(expand_in(&fs, "/*/old"), ["/logs/old"]);
+        assert_eq!(expand_in(&fs, "*.txt"), ["top.txt"]);
+        assert!(expand_in(&fs, "/logs/*.md").is_empty());
+    }
+}
@@ -2,13 +2,26 @@
 ///
 /// Maintains a history of executed commands and allows
 /// navigation with up/down arrows
+///
+/// The history is kept in the file named by the shell's HISTFILE variable,
+/// one command per line: it is loaded when a shell starts and saved
+/// periodically and when the shell exits. HISTSIZE limits the number of
+/// commands kept.
 
 use alloc::vec::Vec;
 use alloc::string::String;
 use spin::Mutex;
 
-/// Maximum number of commands to keep in history
-const MAX_HISTORY: usize = 100;
+use super::env::Environment;
+
+/// Default maximum number of commands to keep in history
+pub const DEFAULT_HISTSIZE: usize = 100;
+
+/// History file when HISTFILE is not set
+pub const DEFAULT_HISTFILE: &str = "/.history";
+
+/// Interval between saves of a changed history
+const SAVE_INTERVAL_MS: u64 = 30_000;
 
 /// Command history
 pub struct History {
@@ -16,6 +29,10 @@ pub struct History {
     commands: Option<Vec<String>>,
     /// Current position in history (for navigation)
     position: Option<usize>,
+    /// Maximum number of commands kept
+    limit: usize,
+    /// Changed since it was last saved
+    dirty: bool,
 }
 
 impl History {
@@ -23,17 +40,95 @@ impl History {
         Self {
             commands: None,
             position: None,
+            limit: DEFAULT_HISTSIZE,
+            dirty: false,
         }
     }
 
     /// Initialize the history
     pub fn init(&mut self) {
         if self.commands.is_none() {
-            self.commands = Some(Vec::with_capacity(MAX_HISTORY));
+            self.commands = Some(Vec::with_capacity(self.limit));
+            self.position = None;
+        }
+    }
+
+    /// Set the maximum number of commands kept, dropping the oldest ones
+    /// over it
+    pub fn set_limit(&mut self, limit: usize) {
+        self.ensure_initialized();
+        self.limit = limit;
+        let commands = self.commands.as_mut().unwrap();
+        if commands.len() > limit {
+            commands.drain(..commands.len() - limit);
             self.position = None;
         }
     }
 
+    /// Command number `n`, counting from 1
+    pub fn get(&self, n: usize) -> Option<&str> {
+        let commands = self.commands.as_ref()?;
+        commands.get(n.checked_sub(1)?).map(String::as_str)
+    }
+
+    /// Commands, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.commands.iter().flatten().map(String::as_str)
+    }
+
+    /// Forget every command
+    pub fn clear(&mut self) {
+        if let Some(commands) = self.commands.as_mut() {
+            commands.clear();
+        }
+        self.position = None;
+        self.dirty = true;
+    }
+
+    /// Replace a line starting with a history reference by the command
+    /// it names: `!!` is the last command and `!n` command number `n`
+    ///
+    /// # Returns
+    /// The expanded line, `None` if the line has no reference, or an
+    /// error if the command does not exist
+    pub fn expand(&self, line: &str) -> Result<Option<String>, &'static str> {
+        let Some(reference) = line.trim_start().strip_prefix('!') else {
+            return Ok(None);
+        };
+        let (event, rest) = match reference.strip_prefix('!') {
+            Some(rest) => (self.len(), rest),
+            None => {
+                let end = reference.find(|c: char| !c.is_ascii_digit()).unwrap_or(reference.len());
+                if end == 0 {
+                    return Ok(None);
+                }
+                (reference[..end].parse().map_err(|_| "Event not found")?, &reference[end..])
+            }
+        };
+        let command = self.get(event).ok_or("Event not found")?;
+        Ok(Some(alloc::format!("{}{}", command, rest)))
+    }
+
+    /// Commands as the history file's text
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for command in self.iter() {
+            text.push_str(command);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Put the commands of a history file's text before the current ones
+    pub fn load_text(&mut self, text: &str) {
+        self.ensure_initialized();
+        let current = core::mem::take(self.commands.as_mut().unwrap());
+        for command in text.lines().map(String::from).chain(current) {
+            self.add(command);
+        }
+        self.dirty = false;
+    }
+
     /// Ensure the history is initialized
     fn ensure_initialized(&mut self) {
         if self.commands.is_none() {
@@ -59,13 +154,17 @@ impl History {
         }
         
         // Add the command
-        if commands.len() >= MAX_HISTORY {
+        if commands.len() >= self.limit {
+            if self.limit == 0 {
+                return;
+            }
             commands.remove(0);
         }
         commands.push(command);
         
         // Reset position
         self.position = None;
+        self.dirty = true;
     }
 
     /// Get the previous command (up arrow)
@@ -153,6 +252,82 @@ pub fn history() -> spin::MutexGuard<'static, Option<History>> {
     HISTORY.lock()
 }
 
+/// History file and size limit set in a shell's variables
+fn settings(env: &Environment) -> (String, usize) {
+    let file = env.get("HISTFILE").unwrap_or(DEFAULT_HISTFILE);
+    let size = env.get("HISTSIZE").and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_HISTSIZE);
+    (String::from(file), size)
+}
+
+/// Load the history file into a history
+pub fn load(history: &mut History, env: &Environment) {
+    let (file, size) = settings(env);
+    history.set_limit(size);
+    if let Ok(data) = crate::fs::read_file(&file) {
+        if let Ok(text) = core::str::from_utf8(&data) {
+            history.load_text(text);
+        }
+    }
+}
+
+/// Save the history of the terminal on screen
+///
+/// `env` is the variables of its shell.
+pub fn save(env: &Environment) -> Result<(), crate::fs::FsError> {
+    let (file, size) = settings(env);
+    save_as(&file, size)
+}
+
+/// Save the history of the terminal on screen to `file`, keeping at most
+/// `size` commands
+fn save_as(file: &str, size: usize) -> Result<(), crate::fs::FsError> {
+    let text = {
+        let mut history_guard = HISTORY.lock();
+        let Some(history) = history_guard.as_mut() else {
+            return Ok(());
+        };
+        history.set_limit(size);
+        history.dirty = false;
+        history.to_text()
+    };
+    crate::fs::write_file(file, text.as_bytes()).map(|_| ())
+}
+
+/// Load the history file into the boot console's history and save it
+/// periodically from then on
+///
+/// Requires the root file system and workqueues.
+pub fn start_persistence() {
+    {
+        let shell_guard = super::shell();
+        let Some(shell) = shell_guard.as_ref() else {
+            return;
+        };
+        if let Some(history) = HISTORY.lock().as_mut() {
+            load(history, shell.env());
+        }
+    }
+    schedule_save();
+}
+
+/// Queue the next periodic save
+fn schedule_save() {
+    let work = crate::task::workqueue::Work::new(save_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, SAVE_INTERVAL_MS);
+}
+
+/// Workqueue function saving the history if it changed
+fn save_tick(_: usize) {
+    let dirty = HISTORY.lock().as_ref().is_some_and(|history| history.dirty);
+    if dirty {
+        let settings = super::shell().as_ref().map(|shell| settings(shell.env()));
+        if let Some((file, size)) = settings {
+            let _ = save_as(&file, size);
+        }
+    }
+    schedule_save();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,4 +373,42 @@ mod tests {
         assert_eq!(history.next(), Some(String::from("echo test")));
         assert_eq!(history.next(), Some(String::new())); // Back to empty
     }
+
+    #[test]
+    fn test_limit() {
+        let mut history = History::new();
+        history.init();
+        for command in ["a", "b", "c"] {
+            history.add(String::from(command));
+        }
+        history.set_limit(2);
+        assert_eq!(history.iter().collect::<Vec<_>>(), ["b", "c"]);
+        history.add(String::from("d"));
+        assert_eq!(history.iter().collect::<Vec<_>>(), ["c", "d"]);
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut history = History::new();
+        history.init();
+        history.add(String::from("echo one"));
+        history.add(String::from("ls /"));
+
+        assert_eq!(history.expand("!1"), Ok(Some(String::from("echo one"))));
+        assert_eq!(history.expand("!! -l"), Ok(Some(String::from("ls / -l"))));
+        assert_eq!(history.expand("echo !1"), Ok(None));
+        assert_eq!(history.expand("!x"), Ok(None));
+        assert!(history.expand("!9").is_err());
+    }
+
+    #[test]
+    fn test_file_text() {
+        let mut history = History::new();
+        history.init();
+        history.add(String::from("new"));
+        history.load_text("old 1\nold 2\n");
+        assert_eq!(history.to_text(), "old 1\nold 2\nnew\n");
+        assert_eq!(history.get(3), Some("new"));
+        assert_eq!(history.get(0), None);
+    }
 }
@@ -0,0 +1,199 @@
+//! Job control for programs started by the shell
+//!
+//! Every program runs as a job: its own process group in its own session,
+//! with the terminal on screen as controlling terminal. The foreground job
+//! owns the terminal, so the TTY sends it Ctrl+C and Ctrl+Z as SIGINT and
+//! SIGTSTP; background jobs (started with `&`) do not.
+//!
+//! Process status changes arrive through the process status notifier and
+//! are applied to the job table from a workqueue.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::task::pgroup::ProcessGroupId;
+use crate::task::process::ProcessStatus;
+use crate::task::TaskId;
+
+/// Run state of a job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// Exited with an exit code
+    Done(i32),
+}
+
+/// A program started by the shell
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Job number, as in `%1`
+    pub id: usize,
+    pub task: TaskId,
+    pub pgid: ProcessGroupId,
+    /// Command line that started it
+    pub command: String,
+    pub state: JobState,
+}
+
+impl Job {
+    /// `jobs` line; `current` marks the job `fg` and `bg` default to
+    pub fn describe(&self, current: bool) -> String {
+        let state = match self.state {
+            JobState::Running => String::from("Running"),
+            JobState::Stopped => String::from("Stopped"),
+            JobState::Done(0) => String::from("Done"),
+            JobState::Done(code) => alloc::format!("Exit {}", code),
+        };
+        let marker = if current { '+' } else { ' ' };
+        alloc::format!("[{}]{} {:<10} {}", self.id, marker, state, self.command)
+    }
+}
+
+/// The shell's jobs, oldest first
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    pub const fn new() -> Self {
+        Self { jobs: Vec::new() }
+    }
+
+    /// Add a running job
+    ///
+    /// # Returns
+    /// Its job number, one more than the highest in use
+    pub fn add(&mut self, task: TaskId, pgid: ProcessGroupId, command: String) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job { id, task, pgid, command, state: JobState::Running });
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    /// Job whose process is `task`
+    pub fn find_task(&mut self, task: TaskId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.task == task)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    /// The job `fg` and `bg` use by default: the newest stopped job, or
+    /// else the newest job
+    pub fn current(&self) -> Option<usize> {
+        self.jobs
+            .iter()
+            .rev()
+            .find(|job| job.state == JobState::Stopped)
+            .or_else(|| self.jobs.last())
+            .map(|job| job.id)
+    }
+
+    /// Job named by a `%n` or `n` argument, or the current job without one
+    pub fn resolve(&self, spec: Option<&str>) -> Result<usize, &'static str> {
+        let Some(spec) = spec else {
+            return self.current().ok_or("No current job");
+        };
+        let id = spec.strip_prefix('%').unwrap_or(spec).parse().map_err(|_| "Invalid job number")?;
+        self.get(id).map(|job| job.id).ok_or("No such job")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    /// Take the jobs that finished
+    pub fn take_done(&mut self) -> Vec<Job> {
+        let (done, running) = core::mem::take(&mut self.jobs)
+            .into_iter()
+            .partition(|job| matches!(job.state, JobState::Done(_)));
+        self.jobs = running;
+        done
+    }
+}
+
+impl Default for JobTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Status changes not applied to the job table yet
+static EVENTS: Mutex<VecDeque<(TaskId, ProcessStatus)>> = Mutex::new(VecDeque::new());
+
+/// Status notifier: queue the change and apply it from a workqueue, since
+/// the process may have changed state with locks held
+pub fn process_status_changed(task: TaskId, status: ProcessStatus) {
+    EVENTS.lock().push_back((task, status));
+    let work = crate::task::workqueue::Work::new(apply_events, 0);
+    let _ = crate::task::workqueue::schedule_work(work);
+}
+
+/// Workqueue function applying queued status changes
+fn apply_events(_: usize) {
+    while let Some((task, status)) = EVENTS.lock().pop_front() {
+        let mut shell_guard = super::shell();
+        if let Some(shell) = shell_guard.as_mut() {
+            shell.job_status_changed(task, status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_numbers_and_current() {
+        let mut jobs = JobTable::new();
+        let one = jobs.add(TaskId::new(10), ProcessGroupId::new(1), String::from("a"));
+        let two = jobs.add(TaskId::new(11), ProcessGroupId::new(2), String::from("b"));
+        assert_eq!((one, two), (1, 2));
+        assert_eq!(jobs.current(), Some(2));
+
+        jobs.get_mut(1).unwrap().state = JobState::Stopped;
+        assert_eq!(jobs.current(), Some(1));
+        assert_eq!(jobs.resolve(None), Ok(1));
+        assert_eq!(jobs.resolve(Some("%2")), Ok(2));
+        assert_eq!(jobs.resolve(Some("2")), Ok(2));
+        assert!(jobs.resolve(Some("%3")).is_err());
+
+        jobs.remove(2);
+        assert_eq!(jobs.add(TaskId::new(12), ProcessGroupId::new(3), String::from("c")), 2);
+    }
+
+    #[test]
+    fn test_take_done() {
+        let mut jobs = JobTable::new();
+        jobs.add(TaskId::new(10), ProcessGroupId::new(1), String::from("a"));
+        jobs.add(TaskId::new(11), ProcessGroupId::new(2), String::from("b"));
+        jobs.find_task(TaskId::new(10)).unwrap().state = JobState::Done(0);
+
+        let done = jobs.take_done();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].describe(false), "[1]  Done       a");
+        assert_eq!(jobs.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut jobs = JobTable::new();
+        jobs.add(TaskId::new(10), ProcessGroupId::new(1), String::from("sleep 10 &"));
+        let job = jobs.get_mut(1).unwrap();
+        assert_eq!(job.describe(true), "[1]+ Running    sleep 10 &");
+        job.state = JobState::Done(2);
+        assert_eq!(job.describe(false), "[1]  Exit 2     sleep 10 &");
+    }
+}
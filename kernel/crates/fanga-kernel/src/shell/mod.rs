@@ -2,49 +2,89 @@
 ///
 /// This module provides an interactive shell with:
 /// - Command parsing
-/// - Built-in commands (help, clear, echo, memory, ps, exit)
+/// - Built-in commands (help, clear, echo, memory, ps, top, ping, exit)
 /// - Command history navigation
 /// - Tab completion
-/// - Customizable prompt
+/// - Environment variables, `$VAR` expansion and a PS1 prompt
+/// - Wildcard (`*`, `?`, `[...]`) expansion of arguments
+/// - Running ELF programs found in the PATH directories
+/// - Job control: `&`, `jobs`, `fg` and `bg`
+/// - Scripting: `&&`, `||`, `if`, `while`, `$?` and `sh` for script files
 
 pub mod parser;
 pub mod commands;
 pub mod history;
 pub mod completion;
+pub mod jobs;
+pub mod env;
+pub mod script;
+pub mod glob;
+pub mod top;
+pub mod net;
 
 use alloc::string::String;
+use core::fmt::Write;
 use spin::Mutex;
 
+use crate::io::{framebuffer, tty};
+use crate::task::pgroup::{self, ProcessGroupId};
+use crate::task::process::{self, ProcessStatus};
+use crate::task::TaskId;
+use env::Environment;
+use jobs::{JobState, JobTable};
+
+/// Scripts that can be nested through `sh`
+const MAX_SCRIPT_DEPTH: usize = 8;
+
 /// Shell state
 pub struct Shell {
-    /// Command prompt
-    prompt: String,
+    /// Shell variables
+    env: Environment,
     /// Whether the shell is running
     running: bool,
+    /// Programs started by the shell
+    jobs: JobTable,
+    /// Job being waited for
+    foreground: Option<usize>,
+    /// Script files being run, nested through `sh`
+    script_depth: usize,
 }
 
 impl Shell {
     pub const fn new() -> Self {
         Self {
-            prompt: String::new(),
+            env: Environment::new(),
             running: false,
+            jobs: JobTable::new(),
+            foreground: None,
+            script_depth: 0,
         }
     }
 
-    /// Initialize the shell with a default prompt
+    /// Initialize the shell with the default PATH, PS1 and HOME
     pub fn init(&mut self) {
-        self.prompt = String::from("fangaos> ");
+        self.env = Environment::with_defaults();
         self.running = true;
     }
 
-    /// Get the current prompt
+    /// Get the current prompt, from PS1
     pub fn prompt(&self) -> &str {
-        &self.prompt
+        self.env.get("PS1").unwrap_or(env::DEFAULT_PS1)
     }
 
     /// Set a custom prompt
     pub fn set_prompt(&mut self, prompt: String) {
-        self.prompt = prompt;
+        let _ = self.env.set("PS1", &prompt);
+    }
+
+    /// The shell's variables
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    /// The shell's variables, for changing
+    pub fn env_mut(&mut self) -> &mut Environment {
+        &mut self.env
     }
 
     /// Check if the shell is running
@@ -57,13 +97,173 @@ impl Shell {
         self.running = false;
     }
 
+    /// Check whether the shell is waiting for a program, `top` or `ping`,
+    /// so it should not prompt yet
+    pub fn is_waiting(&self) -> bool {
+        self.foreground.is_some() || top::is_running() || net::ping_running()
+    }
+
+    /// The shell's jobs
+    pub fn jobs(&self) -> &JobTable {
+        &self.jobs
+    }
+
+    /// Add a job for a started program
+    ///
+    /// # Returns
+    /// The job number
+    pub fn add_job(&mut self, task: TaskId, pgid: ProcessGroupId, command: String) -> usize {
+        self.jobs.add(task, pgid, command)
+    }
+
+    /// Wait for a job, giving it the terminal
+    pub fn set_foreground(&mut self, id: usize) -> Result<(), &'static str> {
+        let job = self.jobs.get_mut(id).ok_or("No such job")?;
+        job.state = JobState::Running;
+        give_terminal(job.pgid);
+        self.foreground = Some(id);
+        Ok(())
+    }
+
+    /// Mark a job running in the background
+    pub fn set_background(&mut self, id: usize) -> Result<(), &'static str> {
+        let job = self.jobs.get_mut(id).ok_or("No such job")?;
+        job.state = JobState::Running;
+        Ok(())
+    }
+
+    /// Print the jobs that finished since the last prompt and forget them
+    pub fn report_jobs(&mut self) {
+        let done = self.jobs.take_done();
+        let mut fb = framebuffer::framebuffer();
+        for job in done {
+            let _ = writeln!(fb, "{}", job.describe(false));
+        }
+    }
+
+    /// Apply a status change of a job's process
+    ///
+    /// When the foreground job exits or stops, the shell takes the terminal
+    /// back and prompts again.
+    fn job_status_changed(&mut self, task: TaskId, status: ProcessStatus) {
+        let Some(job) = self.jobs.find_task(task) else {
+            return;
+        };
+        let id = job.id;
+        match status {
+            ProcessStatus::Exited(code) => {
+                job.state = JobState::Done(code);
+                let _ = process::try_wait(task);
+                let _ = pgroup::process_groups().remove_from_process_group(task);
+            }
+            ProcessStatus::Stopped => job.state = JobState::Stopped,
+            ProcessStatus::Continued => job.state = JobState::Running,
+        }
+
+        if self.foreground != Some(id) || status == ProcessStatus::Continued {
+            return;
+        }
+        self.foreground = None;
+        release_terminal();
+
+        let mut fb = framebuffer::framebuffer();
+        match status {
+            ProcessStatus::Exited(code) => {
+                self.env.set_status(code);
+                if let Some(job) = self.jobs.remove(id) {
+                    let _ = writeln!(fb, "{}: exit status {}", job.command, code);
+                }
+            }
+            _ => {
+                if let Some(job) = self.jobs.get(id) {
+                    let _ = writeln!(fb, "\n{}", job.describe(true));
+                }
+            }
+        }
+        if self.is_running() {
+            fb.write_string(self.prompt());
+        }
+    }
+
     /// Process a command line
+    ///
+    /// The line may hold several commands, conditionals and loops, as in a
+    /// script. Commands print their own errors; only a syntax error is
+    /// returned.
     pub fn execute(&mut self, line: &str) -> Result<(), &'static str> {
+        let nodes = script::parse(line)?;
+        script::run(self, &nodes);
+        Ok(())
+    }
+
+    /// Run a script file's text
+    ///
+    /// # Returns
+    /// The exit status of its last command
+    pub fn run_script(&mut self, text: &str) -> Result<i32, &'static str> {
+        if self.script_depth == MAX_SCRIPT_DEPTH {
+            return Err("Scripts nested too deeply");
+        }
+        let nodes = script::parse(text)?;
+        self.script_depth += 1;
+        let status = script::run(self, &nodes);
+        self.script_depth -= 1;
+        Ok(status)
+    }
+
+    /// Run a simple command, printing its error if it fails
+    ///
+    /// Variables are expanded first, then wildcards in the arguments. A
+    /// lone `NAME=value` sets a shell variable, and a trailing `&` runs a
+    /// program in the background.
+    ///
+    /// # Returns
+    /// The exit status, which is also kept for `$?`
+    fn run_command(&mut self, line: &str) -> i32 {
+        let line = self.env.expand(line);
+        let (line, background) = parser::split_background(&line);
+
         // Parse the command
         let (command, args) = parser::parse_command(line);
+        let args = glob::expand_args(&args);
+        let args: alloc::vec::Vec<&str> = args.iter().map(String::as_str).collect();
 
         // Execute the command
-        commands::execute(command, args, self)
+        let result = match env::parse_assignment(command) {
+            Some((name, value)) if args.is_empty() => self.env.set(name, value).map(|()| 0),
+            _ => commands::execute(command, args, background, self),
+        };
+        let status = result.unwrap_or_else(|err| {
+            let _ = writeln!(framebuffer::framebuffer(), "Error: {}", err);
+            1
+        });
+        self.env.set_status(status);
+        status
+    }
+}
+
+/// Make a job's process group the foreground group of the terminal on
+/// screen, which then sends it the Ctrl+C and Ctrl+Z signals
+fn give_terminal(pgid: ProcessGroupId) {
+    let Some(tty) = tty::active() else {
+        return;
+    };
+    {
+        let mut groups = pgroup::process_groups();
+        let _ = groups.set_foreground(pgid);
+        if let Some(sid) = groups.get_group(pgid).map(|group| group.session_id) {
+            if let Some(session) = groups.get_session_mut(sid) {
+                session.set_controlling_terminal(tty.id());
+            }
+        }
+    }
+    tty.set_foreground_pgrp(Some(pgid));
+}
+
+/// Hand the terminal on screen back to the shell
+fn release_terminal() {
+    if let Some(tty) = tty::active() {
+        tty.set_foreground_pgrp(None);
     }
 }
 
@@ -75,6 +275,7 @@ pub fn init() {
     let mut shell = Shell::new();
     shell.init();
     *SHELL.lock() = Some(shell);
+    process::set_status_notifier(jobs::process_status_changed);
 }
 
 /// Get access to the shell
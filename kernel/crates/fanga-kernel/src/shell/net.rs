@@ -0,0 +1,611 @@
+//! Network commands: ifconfig, ip, ping, arp, route and dhcp
+//!
+//! They show and change the interface, the ARP cache and the routing
+//! table through the `net` module's management functions. `ping` takes
+//! over the terminal like `top`: it sends a request every second and
+//! prints the replies until its count runs out or Ctrl+C, then prints a
+//! summary.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::io::framebuffer;
+use crate::net::{self, arp::Ipv4Address, dhcp::DhcpState, ethernet::MacAddress, icmp, ipv4::RouteEntry};
+use fanga_arch_x86_64::keyboard::KeyCode;
+
+/// Time between echo requests
+pub const PING_INTERVAL_MS: u64 = 1000;
+
+/// Time between checks for echo replies
+const PING_TICK_MS: u64 = 50;
+
+/// Data bytes in an echo request, as sent by other pings
+const PING_DATA_BYTES: usize = 56;
+
+/// Parse `address/prefix_len`, or `address` with `default_len`
+pub fn parse_cidr(text: &str, default_len: u8) -> Result<(Ipv4Address, u8), &'static str> {
+    let (address, prefix_len) = match text.split_once('/') {
+        Some((address, len)) => (address, len.parse::<u8>().ok().filter(|&len| len <= 32).ok_or("Invalid prefix length")?),
+        None => (text, default_len),
+    };
+    Ok((Ipv4Address::parse(address).ok_or("Invalid IPv4 address")?, prefix_len))
+}
+
+/// Parse a route destination: `default` or a network in `parse_cidr` form,
+/// a single host without a prefix length
+fn parse_destination(text: &str) -> Result<(Ipv4Address, u8), &'static str> {
+    match text {
+        "default" => Ok((Ipv4Address::unspecified(), 0)),
+        text => parse_cidr(text, 32),
+    }
+}
+
+fn parse_address(text: &str) -> Result<Ipv4Address, &'static str> {
+    Ipv4Address::parse(text).ok_or("Invalid IPv4 address")
+}
+
+fn check_interface(name: &str) -> Result<(), &'static str> {
+    match name == net::INTERFACE_NAME {
+        true => Ok(()),
+        false => Err("No such interface"),
+    }
+}
+
+/// `ifconfig [eth0 [address[/len]] [netmask mask] [up|down]]`
+pub fn cmd_ifconfig(args: Vec<&str>) -> Result<(), &'static str> {
+    let Some((&name, settings)) = args.split_first() else {
+        return show_ifconfig();
+    };
+    check_interface(name)?;
+    if settings.is_empty() {
+        return show_ifconfig();
+    }
+
+    let mut address = None;
+    let mut up = None;
+    let mut settings = settings.iter();
+    while let Some(&setting) = settings.next() {
+        match setting {
+            "up" => up = Some(true),
+            "down" => up = Some(false),
+            "netmask" => {
+                let mask = parse_address(settings.next().ok_or("Usage: ifconfig eth0 address netmask mask")?)?;
+                if !mask.is_netmask() {
+                    return Err("Invalid netmask");
+                }
+                let (ip, _) = address.ok_or("Usage: ifconfig eth0 address netmask mask")?;
+                address = Some((ip, mask.prefix_len()));
+            }
+            address_text => address = Some(parse_cidr(address_text, 24)?),
+        }
+    }
+    if let Some((ip, prefix_len)) = address {
+        net::set_address(ip, prefix_len)?;
+    }
+    if let Some(up) = up {
+        net::set_up(up)?;
+    }
+    Ok(())
+}
+
+fn show_ifconfig() -> Result<(), &'static str> {
+    let info = net::interface()?;
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(
+        fb,
+        "{}: flags=<{}>  mtu {}",
+        info.name,
+        if info.config.up { "UP,BROADCAST" } else { "BROADCAST" },
+        net::MTU
+    );
+    if let Some(address) = info.config.address {
+        let _ = writeln!(
+            fb,
+            "        inet {}  netmask {}  broadcast {}",
+            address,
+            info.config.netmask,
+            address.broadcast_in(info.config.netmask)
+        );
+    }
+    let _ = writeln!(fb, "        ether {}", info.mac_address);
+    let stats = info.stats;
+    let _ = writeln!(fb, "        RX packets {}  bytes {}  dropped {}", stats.rx_packets, stats.rx_bytes, stats.rx_dropped);
+    let _ = writeln!(fb, "        TX packets {}  bytes {}  errors {}", stats.tx_packets, stats.tx_bytes, stats.tx_errors);
+    Ok(())
+}
+
+/// `ip addr|link|route|neigh ...`, the commands of the `ip` tool the
+/// network stack supports
+pub fn cmd_ip(args: Vec<&str>) -> Result<(), &'static str> {
+    const USAGE: &str = "Usage: ip addr|link|route|neigh [show|add|del|set ...]";
+    let Some((&object, rest)) = args.split_first() else {
+        return Err(USAGE);
+    };
+    // Words after the action, without the optional `dev eth0`
+    let mut words: Vec<&str> = Vec::new();
+    let mut iter = rest.iter().skip(1);
+    while let Some(&word) = iter.next() {
+        match word {
+            "dev" => check_interface(iter.next().ok_or(USAGE)?)?,
+            word => words.push(word),
+        }
+    }
+    let action = rest.first().copied().unwrap_or("show");
+
+    match (object, action) {
+        ("a" | "addr" | "address", "show" | "list") => show_ip_addr(),
+        ("a" | "addr" | "address", "add") => match words.as_slice() {
+            [address] => {
+                let (ip, prefix_len) = parse_cidr(address, 32)?;
+                net::set_address(ip, prefix_len)
+            }
+            _ => Err("Usage: ip addr add address/len dev eth0"),
+        },
+        ("l" | "link", "show" | "list") => show_ip_link(),
+        ("l" | "link", "set") => {
+            let mut up = None;
+            for word in words {
+                match word {
+                    "up" => up = Some(true),
+                    "down" => up = Some(false),
+                    name => check_interface(name)?,
+                }
+            }
+            net::set_up(up.ok_or("Usage: ip link set eth0 up|down")?)
+        }
+        ("r" | "route", "show" | "list") => {
+            show_ip_route();
+            Ok(())
+        }
+        ("r" | "route", "add" | "del" | "delete") => {
+            let mut args = alloc::vec![if action == "add" { "add" } else { "del" }];
+            args.extend(words);
+            cmd_route(args)
+        }
+        ("n" | "neigh" | "neighbor", "show" | "list") => cmd_arp(Vec::new()),
+        _ => Err(USAGE),
+    }
+}
+
+fn show_ip_link() -> Result<(), &'static str> {
+    let info = net::interface()?;
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(
+        fb,
+        "1: {}: <{}> mtu {} state {}",
+        info.name,
+        if info.config.up { "BROADCAST,UP" } else { "BROADCAST" },
+        net::MTU,
+        if info.config.up { "UP" } else { "DOWN" }
+    );
+    let _ = writeln!(fb, "    link/ether {} brd {}", info.mac_address, MacAddress::broadcast());
+    Ok(())
+}
+
+fn show_ip_addr() -> Result<(), &'static str> {
+    show_ip_link()?;
+    let info = net::interface()?;
+    if let Some(address) = info.config.address {
+        let _ = writeln!(
+            framebuffer::framebuffer(),
+            "    inet {}/{} brd {} dev {}",
+            address,
+            info.config.netmask.prefix_len(),
+            address.broadcast_in(info.config.netmask),
+            info.name
+        );
+    }
+    Ok(())
+}
+
+fn show_ip_route() {
+    let mut fb = framebuffer::framebuffer();
+    for route in net::routes() {
+        let destination = match route.netmask.prefix_len() {
+            0 => String::from("default"),
+            len => alloc::format!("{}/{}", route.network, len),
+        };
+        match route.gateway {
+            Some(gateway) => {
+                let _ = writeln!(fb, "{} via {} dev {}", destination, gateway, net::INTERFACE_NAME);
+            }
+            None => {
+                let _ = writeln!(fb, "{} dev {} scope link", destination, net::INTERFACE_NAME);
+            }
+        }
+    }
+}
+
+/// `route [-n]`, `route add dest [gw|via gateway]`, `route del dest`
+///
+/// A destination is `default`, a network as `address/len` or a host.
+pub fn cmd_route(args: Vec<&str>) -> Result<(), &'static str> {
+    match args.as_slice() {
+        [] | ["-n"] => {
+            show_route(&net::routes());
+            Ok(())
+        }
+        ["add", destination] => {
+            let (network, prefix_len) = parse_destination(destination)?;
+            net::add_route(network, prefix_len, None)
+        }
+        ["add", destination, "gw" | "via", gateway] => {
+            let (network, prefix_len) = parse_destination(destination)?;
+            net::add_route(network, prefix_len, Some(parse_address(gateway)?))
+        }
+        ["del" | "delete", destination, ..] => {
+            let (network, prefix_len) = parse_destination(destination)?;
+            net::remove_route(network, prefix_len)
+        }
+        _ => Err("Usage: route [add|del] default|address[/len] [gw gateway]"),
+    }
+}
+
+fn show_route(routes: &[RouteEntry]) {
+    let mut fb = framebuffer::framebuffer();
+    let _ = writeln!(fb, "{:<16}{:<16}{:<16}{:<6}Iface", "Destination", "Gateway", "Genmask", "Flags");
+    for route in routes {
+        let flags = if route.gateway.is_some() { "UG" } else { "U" };
+        let _ = writeln!(
+            fb,
+            "{:<16}{:<16}{:<16}{:<6}{}",
+            alloc::format!("{}", route.network),
+            alloc::format!("{}", route.gateway.unwrap_or(Ipv4Address::unspecified())),
+            alloc::format!("{}", route.netmask),
+            flags,
+            net::INTERFACE_NAME
+        );
+    }
+}
+
+/// `arp [-n]`, `arp -d address`, `arp -s address mac`
+///
+/// Entries added with `-s` are permanent, flagged `M`.
+pub fn cmd_arp(args: Vec<&str>) -> Result<(), &'static str> {
+    match args.as_slice() {
+        [] | ["-n"] => {
+            let entries = net::arp_entries()?;
+            let mut fb = framebuffer::framebuffer();
+            let _ = writeln!(fb, "{:<16}{:<19}{:<6}Iface", "Address", "HWaddress", "Flags");
+            for entry in entries {
+                let _ = writeln!(
+                    fb,
+                    "{:<16}{:<19}{:<6}{}",
+                    alloc::format!("{}", entry.ip_address),
+                    alloc::format!("{}", entry.mac_address),
+                    if entry.permanent { "CM" } else { "C" },
+                    net::INTERFACE_NAME
+                );
+            }
+            Ok(())
+        }
+        ["-d", address] => net::arp_remove(parse_address(address)?),
+        ["-s", address, mac] => {
+            let mac = MacAddress::parse(mac).ok_or("Invalid MAC address")?;
+            net::arp_add(parse_address(address)?, mac)
+        }
+        _ => Err("Usage: arp [-d address | -s address mac]"),
+    }
+}
+
+/// `dhcp` shows the lease, `dhcp renew` asks for a new one
+pub fn cmd_dhcp(args: Vec<&str>) -> Result<(), &'static str> {
+    match args.as_slice() {
+        [] => {
+            let status = net::dhcp_status()?;
+            let mut fb = framebuffer::framebuffer();
+            match status {
+                None => {
+                    let _ = writeln!(fb, "{}: no DHCP lease requested", net::INTERFACE_NAME);
+                }
+                Some((state, config)) => {
+                    let _ = writeln!(fb, "{}: DHCP {:?}", net::INTERFACE_NAME, state);
+                    if let Some(config) = config.filter(|_| state == DhcpState::Bound) {
+                        let _ = writeln!(
+                            fb,
+                            "  address {}/{}, lease {}s",
+                            config.ip_address,
+                            config.subnet_mask.prefix_len(),
+                            config.lease_time
+                        );
+                        if let Some(gateway) = config.gateway {
+                            let _ = writeln!(fb, "  gateway {}", gateway);
+                        }
+                        if let Some(dns) = config.dns_server {
+                            let _ = writeln!(fb, "  dns {}", dns);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        ["renew"] => {
+            net::dhcp_renew()?;
+            let _ = writeln!(
+                framebuffer::framebuffer(),
+                "{}: DHCP discover sent; the lease shows in dmesg",
+                net::INTERFACE_NAME
+            );
+            Ok(())
+        }
+        _ => Err("Usage: dhcp [renew]"),
+    }
+}
+
+/// A round-trip time in milliseconds with three decimals
+pub fn format_rtt(ns: u64) -> String {
+    let us = ns / 1000;
+    alloc::format!("{}.{:03}", us / 1000, us % 1000)
+}
+
+/// A running `ping`
+struct Ping {
+    /// Terminal it prints on
+    terminal: usize,
+    /// Tells the ticks of this `ping` from those of an earlier one
+    generation: usize,
+    target: Ipv4Address,
+    identifier: u16,
+    /// Requests to send, or `None` until Ctrl+C
+    count: Option<u16>,
+    /// `ktime_ns()` when each request went out, by sequence number
+    sent_ns: Vec<u64>,
+    received: u16,
+    /// Round-trip times of the replies
+    min_ns: u64,
+    max_ns: u64,
+    total_ns: u64,
+}
+
+static PING: Mutex<Option<Ping>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+impl Ping {
+    /// Send the next request, returning an error line if it failed
+    fn send(&mut self, now_ns: u64) -> Option<String> {
+        let sequence = self.sent_ns.len() as u16;
+        let data: Vec<u8> = (0..PING_DATA_BYTES as u8).collect();
+        self.sent_ns.push(now_ns);
+        net::send_echo_request(self.target, self.identifier, sequence, &data)
+            .err()
+            .map(|e| alloc::format!("ping: icmp_seq={}: {}\n", sequence, e))
+    }
+
+    /// Print the replies that arrived
+    fn collect(&mut self, out: &mut String) {
+        for reply in icmp::take_replies(self.identifier) {
+            let Some(&sent_ns) = self.sent_ns.get(reply.sequence as usize) else {
+                continue;
+            };
+            let rtt = reply.received_ns.saturating_sub(sent_ns);
+            if self.received == 0 || rtt < self.min_ns {
+                self.min_ns = rtt;
+            }
+            self.max_ns = self.max_ns.max(rtt);
+            self.total_ns += rtt;
+            self.received += 1;
+            let _ = writeln!(
+                out,
+                "{} bytes from {}: icmp_seq={} ttl={} time={} ms",
+                reply.bytes,
+                reply.from,
+                reply.sequence,
+                reply.ttl,
+                format_rtt(rtt)
+            );
+        }
+    }
+
+    fn summary(&self) -> String {
+        let sent = self.sent_ns.len() as u64;
+        let received = u64::from(self.received);
+        let mut out = alloc::format!("--- {} ping statistics ---\n", self.target);
+        let loss = ((sent - received.min(sent)) * 100).checked_div(sent).unwrap_or(0);
+        let _ = writeln!(out, "{} packets transmitted, {} received, {}% packet loss", sent, received, loss);
+        if let Some(avg_ns) = self.total_ns.checked_div(received) {
+            let _ = writeln!(
+                out,
+                "rtt min/avg/max = {}/{}/{} ms",
+                format_rtt(self.min_ns),
+                format_rtt(avg_ns),
+                format_rtt(self.max_ns)
+            );
+        }
+        out
+    }
+
+    /// Whether all requests went out and the last one had its reply or
+    /// its time
+    fn is_done(&self, now_ns: u64) -> bool {
+        let Some(count) = self.count else {
+            return false;
+        };
+        let last_sent = self.sent_ns.last().copied().unwrap_or(0);
+        self.sent_ns.len() >= count as usize
+            && (self.received >= count || now_ns.saturating_sub(last_sent) >= PING_INTERVAL_MS * 1_000_000)
+    }
+}
+
+/// Check whether `ping` owns the terminal
+pub fn ping_running() -> bool {
+    PING.lock().is_some()
+}
+
+/// `ping [-c count] address`
+pub fn cmd_ping(args: Vec<&str>) -> Result<(), &'static str> {
+    const USAGE: &str = "Usage: ping [-c count] address";
+    let (count, target) = match args.as_slice() {
+        [target] => (None, *target),
+        ["-c", count, target] => (Some(count.parse::<u16>().ok().filter(|&count| count > 0).ok_or("Invalid count")?), *target),
+        _ => return Err(USAGE),
+    };
+    let target = Ipv4Address::parse(target).ok_or("Unknown host (no name resolution, use an IPv4 address)")?;
+    // Fail now rather than on every request if there is nothing to ping with
+    let info = net::interface()?;
+    if !info.config.up {
+        return Err("Network is down");
+    }
+
+    let mut ping = PING.lock();
+    if ping.is_some() {
+        return Err("ping is already running");
+    }
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut state = Ping {
+        terminal: crate::io::vt::current_terminal_id(),
+        generation,
+        target,
+        identifier: generation as u16,
+        count,
+        sent_ns: Vec::new(),
+        received: 0,
+        min_ns: 0,
+        max_ns: 0,
+        total_ns: 0,
+    };
+    let mut out = alloc::format!("PING {}: {} data bytes\n", target, PING_DATA_BYTES);
+    out.extend(state.send(crate::task::ktime_ns()));
+    *ping = Some(state);
+    drop(ping);
+    framebuffer::framebuffer().write_string(&out);
+    schedule_tick(generation).inspect_err(|_| *PING.lock() = None)
+}
+
+fn schedule_tick(generation: usize) -> Result<(), &'static str> {
+    let work = crate::task::workqueue::Work::new(tick, generation);
+    crate::task::workqueue::schedule_delayed_work(work, PING_TICK_MS).map(|_| ())
+}
+
+/// Workqueue function printing replies and sending the next request
+fn tick(generation: usize) {
+    let now_ns = crate::task::ktime_ns();
+    let (terminal, mut out, done) = {
+        let mut ping = PING.lock();
+        let Some(state) = ping.as_mut().filter(|state| state.generation == generation) else {
+            return;
+        };
+        let mut out = String::new();
+        state.collect(&mut out);
+        let next_ns = state.sent_ns.last().copied().unwrap_or(0) + PING_INTERVAL_MS * 1_000_000;
+        if state.count.is_none_or(|count| state.sent_ns.len() < count as usize) && now_ns >= next_ns {
+            out.extend(state.send(now_ns));
+        }
+        let terminal = state.terminal;
+        let done = state.is_done(now_ns);
+        if done {
+            out.push_str(&state.summary());
+            *ping = None;
+        }
+        (terminal, out, done)
+    };
+    if done {
+        out.push_str(&prompt());
+    } else {
+        let _ = schedule_tick(generation);
+    }
+    if !out.is_empty() {
+        crate::io::vt::write_to(terminal, out.as_bytes());
+    }
+}
+
+/// The shell prompt, printed when `ping` gives the terminal back
+fn prompt() -> String {
+    super::shell().as_ref().map(|shell| String::from(shell.prompt())).unwrap_or_default()
+}
+
+/// Give a key to `ping` if it is running
+///
+/// Ctrl+C stops it with a summary; other keys are ignored.
+///
+/// # Returns
+/// Whether `ping` took the key
+pub fn handle_ping_key(keycode: KeyCode, ctrl: bool) -> bool {
+    let mut ping = PING.lock();
+    let Some(state) = ping.as_mut() else {
+        return false;
+    };
+    if !(ctrl && matches!(keycode, KeyCode::Char('c') | KeyCode::Char('C'))) {
+        return true;
+    }
+    let mut out = String::from("^C\n");
+    state.collect(&mut out);
+    out.push_str(&state.summary());
+    let terminal = state.terminal;
+    *ping = None;
+    drop(ping);
+
+    out.push_str(&prompt());
+    crate::io::vt::write_to(terminal, out.as_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(count: Option<u16>) -> Ping {
+        Ping {
+            terminal: 0,
+            generation: 1,
+            target: Ipv4Address::new(10, 0, 2, 2),
+            identifier: 1,
+            count,
+            sent_ns: Vec::new(),
+            received: 0,
+            min_ns: 0,
+            max_ns: 0,
+            total_ns: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(parse_cidr("10.0.2.15/24", 32), Ok((Ipv4Address::new(10, 0, 2, 15), 24)));
+        assert_eq!(parse_cidr("10.0.2.15", 32), Ok((Ipv4Address::new(10, 0, 2, 15), 32)));
+        assert_eq!(parse_cidr("10.0.2.15/33", 32), Err("Invalid prefix length"));
+        assert_eq!(parse_cidr("10.0.2/24", 32), Err("Invalid IPv4 address"));
+        assert_eq!(parse_destination("default"), Ok((Ipv4Address::unspecified(), 0)));
+    }
+
+    #[test]
+    fn test_format_rtt() {
+        assert_eq!(format_rtt(523_400), "0.523");
+        assert_eq!(format_rtt(12_004_000), "12.004");
+    }
+
+    #[test]
+    fn test_ping_summary() {
+        let mut state = ping(Some(4));
+        state.sent_ns = alloc::vec![0, 1, 2, 3];
+        state.received = 3;
+        state.min_ns = 1_000_000;
+        state.max_ns = 3_000_000;
+        state.total_ns = 6_000_000;
+        assert_eq!(
+            state.summary(),
+            "--- 10.0.2.2 ping statistics ---\n\
+             4 packets transmitted, 3 received, 25% packet loss\n\
+             rtt min/avg/max = 1.000/2.000/3.000 ms\n"
+        );
+    }
+
+    #[test]
+    fn test_ping_is_done() {
+        let second = PING_INTERVAL_MS * 1_000_000;
+        let mut state = ping(Some(2));
+        state.sent_ns = alloc::vec![0];
+        assert!(!state.is_done(5 * second));
+
+        // The last request waits a second for its reply
+        state.sent_ns.push(second);
+        assert!(!state.is_done(second + 1));
+        assert!(state.is_done(2 * second));
+        state.received = 2;
+        assert!(state.is_done(second + 1));
+
+        assert!(!ping(None).is_done(100 * second));
+    }
+}
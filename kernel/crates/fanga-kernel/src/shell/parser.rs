@@ -31,6 +31,18 @@ pub fn parse_command(line: &str) -> (&str, Vec<&str>) {
     (command, args)
 }
 
+/// Split a trailing `&` off a command line
+///
+/// Returns the rest of the line and whether the command should run in the
+/// background
+pub fn split_background(line: &str) -> (&str, bool) {
+    let line = line.trim();
+    match line.strip_suffix('&') {
+        Some(rest) => (rest.trim_end(), true),
+        None => (line, false),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,4 +76,12 @@ mod tests {
         assert_eq!(cmd, "clear");
         assert_eq!(args.len(), 0);
     }
+    
+    #[test]
+    fn test_split_background() {
+        assert_eq!(split_background("sleep 10 &"), ("sleep 10", true));
+        assert_eq!(split_background("sleep 10&  "), ("sleep 10", true));
+        assert_eq!(split_background("sleep 10"), ("sleep 10", false));
+        assert_eq!(split_background("&"), ("", true));
+    }
 }
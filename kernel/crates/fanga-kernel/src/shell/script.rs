@@ -0,0 +1,327 @@
+//! Shell scripts: command lists, conditionals and loops
+//!
+//! A script is a list of statements separated by newlines or `;`:
+//! - `cmd1 && cmd2` runs `cmd2` only if `cmd1` succeeded, `cmd1 || cmd2`
+//!   only if it failed
+//! - `if cond; then ...; [elif cond; then ...;] [else ...;] fi`
+//! - `while cond; do ...; done`
+//! - Lines starting with `#` are comments
+//!
+//! A command succeeds with exit status 0; the status of the last command
+//! is `$?`. Interactive lines are parsed the same way, and `sh` runs a
+//! script file.
+//!
+//! The shell cannot wait for a program in the middle of a script, so a
+//! program counts as successful once it has started, and the script goes
+//! on while it runs.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::Shell;
+
+/// Iterations after which a `while` loop is cut off, since the shell
+/// runs from the keyboard handler and cannot be interrupted
+pub const MAX_LOOP_ITERATIONS: usize = 10_000;
+
+/// Words that start or end compound statements
+const KEYWORDS: [&str; 8] = ["if", "then", "elif", "else", "fi", "while", "do", "done"];
+
+/// A parsed statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// A simple command line, expanded when it runs
+    Command(String),
+    /// Run the second node if the first succeeds
+    And(Box<Node>, Box<Node>),
+    /// Run the second node if the first fails
+    Or(Box<Node>, Box<Node>),
+    If {
+        condition: Vec<Node>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    While {
+        condition: Vec<Node>,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Keyword(&'static str),
+    Statement(&'a str),
+}
+
+/// Split `text` at `separator`s outside single quotes
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Split a script into keywords and simple statements
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for line in text.lines() {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        for statement in split_unquoted(line, ';') {
+            let mut rest = statement.trim();
+            while !rest.is_empty() {
+                let (first, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                match KEYWORDS.iter().find(|&&keyword| keyword == first) {
+                    Some(keyword) => {
+                        tokens.push(Token::Keyword(keyword));
+                        rest = tail.trim_start();
+                    }
+                    None => {
+                        tokens.push(Token::Statement(rest));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// Add a command to a list, after `&&` if `and` or else `||`
+fn push_command(node: &mut Option<Node>, part: &str, and: bool) -> Result<(), &'static str> {
+    let part = part.trim();
+    if part.is_empty() {
+        return Err("Syntax error: missing command");
+    }
+    let command = Node::Command(String::from(part));
+    *node = Some(match node.take() {
+        None => command,
+        Some(left) if and => Node::And(Box::new(left), Box::new(command)),
+        Some(left) => Node::Or(Box::new(left), Box::new(command)),
+    });
+    Ok(())
+}
+
+/// Parse a statement's `&&` and `||` lists, which group left to right
+fn parse_list(statement: &str) -> Result<Node, &'static str> {
+    let bytes = statement.as_bytes();
+    let mut node: Option<Node> = None;
+    let mut pending_and = false;
+    let mut quoted = false;
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'&' | b'|' if !quoted && bytes.get(i + 1) == Some(&bytes[i]) => {
+                push_command(&mut node, &statement[start..i], pending_and)?;
+                pending_and = bytes[i] == b'&';
+                i += 2;
+                start = i;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    push_command(&mut node, &statement[start..], pending_and)?;
+    node.ok_or("Syntax error: missing command")
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn advance(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.position).copied();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, keyword: &str) -> Result<(), &'static str> {
+        match self.advance() {
+            Some(Token::Keyword(found)) if found == keyword => Ok(()),
+            _ => Err("Syntax error: unexpected end of script"),
+        }
+    }
+
+    /// Parse statements up to one of the `end` keywords, which is left
+    /// for the caller, or to the end of the script if `end` is empty
+    fn parse_block(&mut self, end: &[&str]) -> Result<Vec<Node>, &'static str> {
+        let mut nodes = Vec::new();
+        loop {
+            match self.tokens.get(self.position).copied() {
+                None if end.is_empty() => return Ok(nodes),
+                None => return Err("Syntax error: unexpected end of script"),
+                Some(Token::Keyword(keyword)) if end.contains(&keyword) => return Ok(nodes),
+                Some(Token::Keyword("if")) => {
+                    self.position += 1;
+                    nodes.push(self.parse_if()?);
+                }
+                Some(Token::Keyword("while")) => {
+                    self.position += 1;
+                    nodes.push(self.parse_while()?);
+                }
+                Some(Token::Keyword(_)) => return Err("Syntax error: unexpected keyword"),
+                Some(Token::Statement(statement)) => {
+                    self.position += 1;
+                    nodes.push(parse_list(statement)?);
+                }
+            }
+        }
+    }
+
+    /// Parse an `if` after its keyword, through its `fi`
+    fn parse_if(&mut self) -> Result<Node, &'static str> {
+        let condition = self.parse_block(&["then"])?;
+        self.expect("then")?;
+        if condition.is_empty() {
+            return Err("Syntax error: missing condition");
+        }
+        let then = self.parse_block(&["elif", "else", "fi"])?;
+        let otherwise = match self.advance() {
+            // The nested `if` ends at the shared `fi`
+            Some(Token::Keyword("elif")) => alloc::vec![self.parse_if()?],
+            Some(Token::Keyword("else")) => {
+                let otherwise = self.parse_block(&["fi"])?;
+                self.expect("fi")?;
+                otherwise
+            }
+            _ => Vec::new(),
+        };
+        Ok(Node::If { condition, then, otherwise })
+    }
+
+    /// Parse a `while` after its keyword, through its `done`
+    fn parse_while(&mut self) -> Result<Node, &'static str> {
+        let condition = self.parse_block(&["do"])?;
+        self.expect("do")?;
+        if condition.is_empty() {
+            return Err("Syntax error: missing condition");
+        }
+        let body = self.parse_block(&["done"])?;
+        self.expect("done")?;
+        Ok(Node::While { condition, body })
+    }
+}
+
+/// Parse a script or command line
+pub fn parse(text: &str) -> Result<Vec<Node>, &'static str> {
+    let mut parser = Parser { tokens: tokenize(text), position: 0 };
+    parser.parse_block(&[])
+}
+
+/// Run parsed statements
+///
+/// # Returns
+/// The exit status of the last one, 0 if none ran
+pub fn run(shell: &mut Shell, nodes: &[Node]) -> i32 {
+    let mut status = 0;
+    for node in nodes {
+        if !shell.is_running() {
+            break;
+        }
+        status = run_node(shell, node);
+        shell.env_mut().set_status(status);
+    }
+    status
+}
+
+fn run_node(shell: &mut Shell, node: &Node) -> i32 {
+    match node {
+        Node::Command(line) => shell.run_command(line),
+        Node::And(first, second) => match run_node(shell, first) {
+            0 => run_node(shell, second),
+            status => status,
+        },
+        Node::Or(first, second) => match run_node(shell, first) {
+            0 => 0,
+            _ => run_node(shell, second),
+        },
+        Node::If { condition, then, otherwise } => {
+            if run(shell, condition) == 0 {
+                run(shell, then)
+            } else {
+                run(shell, otherwise)
+            }
+        }
+        Node::While { condition, body } => {
+            let mut status = 0;
+            let mut iterations = 0;
+            while shell.is_running() && run(shell, condition) == 0 {
+                if iterations == MAX_LOOP_ITERATIONS {
+                    crate::io::framebuffer::framebuffer().write_string("Error: Loop limit reached\n");
+                    return 1;
+                }
+                status = run(shell, body);
+                iterations += 1;
+            }
+            status
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Node {
+        Node::Command(String::from(line))
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        assert_eq!(parse("echo a; echo b\n\n# comment\necho c").unwrap(), [command("echo a"), command("echo b"), command("echo c")]);
+        assert_eq!(
+            parse("a && b || c").unwrap(),
+            [Node::Or(Box::new(Node::And(Box::new(command("a")), Box::new(command("b")))), Box::new(command("c")))]
+        );
+        assert_eq!(parse("echo 'a;b && c'").unwrap(), [command("echo 'a;b && c'")]);
+        assert_eq!(parse("sleep 1 &").unwrap(), [command("sleep 1 &")]);
+        assert!(parse("a &&").is_err());
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_if() {
+        let nodes = parse("if test -f /a; then echo yes; elif false; then echo maybe; else echo no; fi").unwrap();
+        let expected = Node::If {
+            condition: alloc::vec![command("test -f /a")],
+            then: alloc::vec![command("echo yes")],
+            otherwise: alloc::vec![Node::If {
+                condition: alloc::vec![command("false")],
+                then: alloc::vec![command("echo maybe")],
+                otherwise: alloc::vec![command("echo no")],
+            }],
+        };
+        assert_eq!(nodes, [expected]);
+        assert_eq!(parse("echo fi").unwrap(), [command("echo fi")]);
+        assert!(parse("if true; then echo").is_err());
+        assert!(parse("then echo").is_err());
+        assert!(parse("if; then echo; fi").is_err());
+    }
+
+    #[test]
+    fn test_parse_while() {
+        let nodes = parse("while test $N != 3\ndo\n  N=3\ndone").unwrap();
+        assert_eq!(
+            nodes,
+            [Node::While { condition: alloc::vec![command("test $N != 3")], body: alloc::vec![command("N=3")] }]
+        );
+        assert!(parse("while true; do echo").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Task listing for `ps` and the live `top` viewer
+//!
+//! `top` takes over the terminal and redraws the task list every second,
+//! with bars for CPU and memory use, until `q` or Ctrl+C. CPU use is the
+//! share of the timer ticks charged since the last refresh (see
+//! `task::cputime`) that went to tasks other than the idle tasks.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::task::{self, TaskPriority, TaskState};
+use fanga_arch_x86_64::keyboard::KeyCode;
+
+/// Time between `top` refreshes
+pub const REFRESH_MS: u64 = 1000;
+
+/// Width of the usage bars, without the brackets
+const BAR_WIDTH: usize = 30;
+
+/// A task as listed by `ps` and `top`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskRow {
+    pub pid: usize,
+    pub name: String,
+    pub state: &'static str,
+    pub priority: &'static str,
+    /// CPU time (user + system) in ticks
+    pub ticks: u64,
+    /// Voluntary and involuntary context switches
+    pub nvcsw: u64,
+    pub nivcsw: u64,
+    /// Whether it is a CPU's idle task
+    pub idle: bool,
+}
+
+/// The scheduler's tasks, by PID
+pub fn snapshot() -> Vec<TaskRow> {
+    let scheduler = task::scheduler::scheduler();
+    let mut rows: Vec<TaskRow> = scheduler
+        .tasks()
+        .map(|t| TaskRow {
+            pid: t.id.as_usize(),
+            name: String::from(t.name()),
+            state: match t.state {
+                TaskState::Ready => "ready",
+                TaskState::Running => "running",
+                TaskState::Blocked => "blocked",
+                TaskState::Terminated => "terminated",
+            },
+            priority: match t.priority {
+                TaskPriority::Low => "low",
+                TaskPriority::Normal => "normal",
+                TaskPriority::High => "high",
+                TaskPriority::Critical => "critical",
+            },
+            ticks: t.times.total_ticks(),
+            nvcsw: t.times.nvcsw,
+            nivcsw: t.times.nivcsw,
+            idle: scheduler.is_idle_task(t.id),
+        })
+        .collect();
+    rows.sort_by_key(|row| row.pid);
+    rows
+}
+
+/// CPU time in ticks as seconds with hundredths
+pub fn format_ticks(ticks: u64) -> String {
+    alloc::format!("{}.{:02}", ticks / task::cputime::CLK_TCK, ticks % task::cputime::CLK_TCK)
+}
+
+/// `part` as a percentage of `total`, 0 if `total` is 0
+pub fn percent(part: u64, total: u64) -> u64 {
+    match total {
+        0 => 0,
+        total => (part.min(total) * 100) / total,
+    }
+}
+
+/// A usage bar such as `[#####     ]` for a percentage
+pub fn bar(percent: u64, width: usize) -> String {
+    let filled = (percent.min(100) as usize * width + 50) / 100;
+    let mut bar = String::with_capacity(width + 2);
+    bar.push('[');
+    bar.extend(core::iter::repeat_n('#', filled));
+    bar.extend(core::iter::repeat_n(' ', width - filled));
+    bar.push(']');
+    bar
+}
+
+/// CPU ticks each task used since `previous`
+///
+/// # Returns
+/// The ticks per PID, and the ticks of all tasks and of the non-idle ones
+pub fn tick_deltas(rows: &[TaskRow], previous: &BTreeMap<usize, u64>) -> (BTreeMap<usize, u64>, u64, u64) {
+    let mut deltas = BTreeMap::new();
+    let mut total = 0;
+    let mut busy = 0;
+    for row in rows {
+        // A task with fewer ticks than before took the PID of one that
+        // exited, so its share of this refresh is unknown
+        let delta = row.ticks.saturating_sub(previous.get(&row.pid).copied().unwrap_or(0));
+        deltas.insert(row.pid, delta);
+        total += delta;
+        if !row.idle {
+            busy += delta;
+        }
+    }
+    (deltas, total, busy)
+}
+
+/// A running `top`
+struct Top {
+    /// Terminal it draws on
+    terminal: usize,
+    /// Tells the refreshes of this `top` from those of an earlier one
+    generation: usize,
+    /// CPU ticks of each task at the last refresh
+    previous: BTreeMap<usize, u64>,
+}
+
+static TOP: Mutex<Option<Top>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+impl Top {
+    /// Draw a screen and remember the tick counts for the next one
+    fn render(&mut self) -> String {
+        let rows = snapshot();
+        let (deltas, total, busy) = tick_deltas(&rows, &self.previous);
+        self.previous = rows.iter().map(|row| (row.pid, row.ticks)).collect();
+
+        let stats = crate::memory::stats::stats();
+        let cpu = percent(busy, total);
+        let memory = percent(stats.used_physical() as u64, stats.total_physical() as u64);
+        let heap = percent(stats.used_heap() as u64, stats.total_heap() as u64);
+        let uptime = fanga_arch_x86_64::interrupts::idt::uptime_secs();
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        let _ = writeln!(
+            out,
+            "top - up {}:{:02}:{:02}, {} tasks, {} CPU(s)",
+            uptime / 3600,
+            (uptime % 3600) / 60,
+            uptime % 60,
+            rows.len(),
+            crate::smp::cpu::cpu_count()
+        );
+        let _ = writeln!(out, "CPU  {} {:>3}%", bar(cpu, BAR_WIDTH), cpu);
+        let _ = writeln!(out, "Mem  {} {:>3}%", bar(memory, BAR_WIDTH), memory);
+        let _ = writeln!(out, "Heap {} {:>3}%", bar(heap, BAR_WIDTH), heap);
+        out.push('\n');
+        let _ = writeln!(out, "  PID  NAME                STATE       PRIORITY  %CPU       TIME");
+
+        // Busiest first
+        let mut rows = rows;
+        rows.sort_by_key(|row| core::cmp::Reverse(deltas.get(&row.pid).copied().unwrap_or(0)));
+        for row in &rows {
+            let _ = writeln!(
+                out,
+                "  {:<4} {:<18}  {:<10}  {:<8}  {:>4}  {:>9}",
+                row.pid,
+                row.name,
+                row.state,
+                row.priority,
+                percent(deltas.get(&row.pid).copied().unwrap_or(0), total),
+                format_ticks(row.ticks),
+            );
+        }
+        out.push_str("\nPress q to quit\n");
+        out
+    }
+}
+
+/// Check whether `top` owns the terminal
+pub fn is_running() -> bool {
+    TOP.lock().is_some()
+}
+
+/// Start `top` on the terminal on screen
+pub fn start() -> Result<(), &'static str> {
+    let mut top = TOP.lock();
+    if top.is_some() {
+        return Err("top is already running");
+    }
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut state = Top {
+        terminal: crate::io::vt::current_terminal_id(),
+        generation,
+        previous: BTreeMap::new(),
+    };
+    // The first screen counts CPU time since boot
+    let screen = state.render();
+    *top = Some(state);
+    drop(top);
+    crate::io::framebuffer::framebuffer().write_string(&screen);
+    schedule_refresh(generation).inspect_err(|_| *TOP.lock() = None)
+}
+
+fn schedule_refresh(generation: usize) -> Result<(), &'static str> {
+    let work = crate::task::workqueue::Work::new(refresh, generation);
+    crate::task::workqueue::schedule_delayed_work(work, REFRESH_MS).map(|_| ())
+}
+
+/// Workqueue function redrawing `top`
+fn refresh(generation: usize) {
+    let drawn = {
+        let mut top = TOP.lock();
+        match top.as_mut() {
+            Some(state) if state.generation == generation => Some((state.terminal, state.render())),
+            _ => None,
+        }
+    };
+    if let Some((terminal, screen)) = drawn {
+        crate::io::vt::write_to(terminal, screen.as_bytes());
+        let _ = schedule_refresh(generation);
+    }
+}
+
+/// Give a key to `top` if it is running
+///
+/// `q` and Ctrl+C quit and bring back the prompt; other keys are ignored.
+///
+/// # Returns
+/// Whether `top` took the key
+pub fn handle_key(keycode: KeyCode, ascii: Option<char>, ctrl: bool) -> bool {
+    let mut top = TOP.lock();
+    let Some(state) = top.as_ref() else {
+        return false;
+    };
+    let quit = matches!(ascii, Some('q') | Some('Q')) && !ctrl
+        || ctrl && matches!(keycode, KeyCode::Char('c') | KeyCode::Char('C'));
+    if !quit {
+        return true;
+    }
+    let terminal = state.terminal;
+    *top = None;
+    drop(top);
+
+    let prompt = super::shell().as_ref().map(|shell| String::from(shell.prompt())).unwrap_or_default();
+    crate::io::vt::write_to(terminal, alloc::format!("\x1b[H\x1b[2J{}", prompt).as_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(pid: usize, ticks: u64, idle: bool) -> TaskRow {
+        TaskRow {
+            pid,
+            name: String::from("task"),
+            state: "ready",
+            priority: "normal",
+            ticks,
+            nvcsw: 0,
+            nivcsw: 0,
+            idle,
+        }
+    }
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(0, 4), "[    ]");
+        assert_eq!(bar(50, 4), "[##  ]");
+        assert_eq!(bar(100, 4), "[####]");
+        assert_eq!(bar(250, 4), "[####]");
+    }
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(percent(1, 4), 25);
+        assert_eq!(percent(5, 0), 0);
+        assert_eq!(percent(9, 4), 100);
+    }
+
+    #[test]
+    fn test_tick_deltas() {
+        let previous: BTreeMap<usize, u64> = [(0, 100), (1, 10), (2, 50)].into_iter().collect();
+        // Task 2 exited and its PID went to a new task with fewer ticks
+        let rows = [row(0, 130, true), row(1, 40, false), row(2, 20, false), row(3, 10, false)];
+        let (deltas, total, busy) = tick_deltas(&rows, &previous);
+        assert_eq!(deltas[&0], 30);
+        assert_eq!(deltas[&1], 30);
+        assert_eq!(deltas[&2], 0);
+        assert_eq!(deltas[&3], 10);
+        assert_eq!((total, busy), (70, 40));
+    }
+
+    #[test]
+    fn test_format_ticks() {
+        assert_eq!(format_ticks(1234), "12.34");
+        assert_eq!(format_ticks(5), "0.05");
+    }
+}
@@ -1,23 +1,99 @@
 //! ACPI Information
 //!
-//! This module provides basic ACPI parsing for SMP initialization.
+//! This module provides the ACPI parsing needed for SMP and interrupt
+//! routing: the MADT (Multiple APIC Description Table) among the tables
+//! `crate::acpi` found.
+//!
+//! This module provides:
+//! - MADT parsing (local APICs, IOAPICs, interrupt source overrides)
+//! - The global `AcpiInfo`, or defaults when there are no tables
+
+use spin::Once;
 
 use super::CpuId;
+use crate::acpi::{read_u32, read_u64, sdt_signature, validate_sdt, SDT_HEADER_LEN};
+
+/// Maximum number of IOAPICs recorded from the MADT
+pub const MAX_IOAPICS: usize = 8;
+
+/// Maximum number of interrupt source overrides recorded from the MADT
+pub const MAX_OVERRIDES: usize = 16;
+
+/// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LAPIC_ADDR_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+/// Local APIC flags: enabled, or can be brought online
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// An IOAPIC described by the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    /// IOAPIC ID
+    pub id: u8,
+    /// Physical address of its registers
+    pub addr: u64,
+    /// First global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt wired to a different GSI or with non-ISA signalling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqOverride {
+    /// ISA IRQ number
+    pub source: u8,
+    /// Global system interrupt it is connected to
+    pub gsi: u32,
+    /// Active-low (ISA default is active-high)
+    pub active_low: bool,
+    /// Level-triggered (ISA default is edge-triggered)
+    pub level: bool,
+}
+
+impl IrqOverride {
+    const EMPTY: Self = Self { source: 0, gsi: 0, active_low: false, level: false };
+
+    /// Decode MPS INTI flags; "conforming" means the ISA defaults
+    fn new(source: u8, gsi: u32, flags: u16) -> Self {
+        Self {
+            source,
+            gsi,
+            active_low: flags & 0b11 == 0b11,
+            level: (flags >> 2) & 0b11 == 0b11,
+        }
+    }
+}
 
 /// ACPI information structure
 #[derive(Debug, Clone)]
 pub struct AcpiInfo {
     /// Number of CPUs detected via ACPI
     pub cpu_count: usize,
-    
+
     /// List of APIC IDs
     pub apic_ids: [u32; 256],
-    
+
     /// Local APIC address
     pub lapic_addr: u64,
-    
-    /// I/O APIC address
+
+    /// I/O APIC address (of the first IOAPIC)
     pub ioapic_addr: u64,
+
+    /// Number of IOAPICs
+    pub ioapic_count: usize,
+
+    /// IOAPICs, `ioapic_count` of them valid
+    pub ioapics: [IoApicEntry; MAX_IOAPICS],
+
+    /// Number of interrupt source overrides
+    pub override_count: usize,
+
+    /// Interrupt source overrides, `override_count` of them valid
+    pub overrides: [IrqOverride; MAX_OVERRIDES],
 }
 
 impl AcpiInfo {
@@ -28,27 +104,96 @@ impl AcpiInfo {
             apic_ids: [0; 256],
             lapic_addr: 0,
             ioapic_addr: 0,
+            ioapic_count: 0,
+            ioapics: [IoApicEntry { id: 0, addr: 0, gsi_base: 0 }; MAX_IOAPICS],
+            override_count: 0,
+            overrides: [IrqOverride::EMPTY; MAX_OVERRIDES],
         }
     }
-    
-    /// Parse ACPI tables to detect CPUs
-    pub fn parse_madt(&mut self) -> Result<(), &'static str> {
-        // TODO: Implement MADT (Multiple APIC Description Table) parsing
-        // This would involve:
-        // 1. Finding the RSDP (Root System Description Pointer)
-        // 2. Following to RSDT/XSDT
-        // 3. Locating MADT
-        // 4. Parsing Local APIC and I/O APIC entries
-        
-        // For now, just set default values
+
+    /// Fill in the standard PC layout, for machines without ACPI tables
+    pub fn set_defaults(&mut self) {
         self.cpu_count = 1;
         self.apic_ids[0] = 0;
         self.lapic_addr = 0xFEE00000; // Default Local APIC address
         self.ioapic_addr = 0xFEC00000; // Default I/O APIC address
-        
+        self.ioapic_count = 1;
+        self.ioapics[0] = IoApicEntry { id: 0, addr: self.ioapic_addr, gsi_base: 0 };
+        self.override_count = 0;
+    }
+
+    /// Parse the MADT (Multiple APIC Description Table)
+    ///
+    /// `madt` is the whole table, including its header.
+    pub fn parse_madt(&mut self, madt: &[u8]) -> Result<(), &'static str> {
+        if sdt_signature(madt) != Some(*b"APIC") {
+            return Err("Not a MADT");
+        }
+        validate_sdt(madt)?;
+
+        self.lapic_addr = read_u32(madt, SDT_HEADER_LEN).ok_or("MADT too short")? as u64;
+        self.cpu_count = 0;
+        self.ioapic_count = 0;
+        self.override_count = 0;
+
+        let mut offset = SDT_HEADER_LEN + 8;
+        while offset + 2 <= madt.len() {
+            let kind = madt[offset];
+            let len = madt[offset + 1] as usize;
+            if len < 2 || offset + len > madt.len() {
+                return Err("Malformed MADT entry");
+            }
+            let entry = &madt[offset..offset + len];
+            self.parse_madt_entry(kind, entry);
+            offset += len;
+        }
+
+        if self.cpu_count == 0 {
+            return Err("MADT lists no CPUs");
+        }
+        self.ioapic_addr = self.ioapics().first().map_or(0, |ioapic| ioapic.addr);
         Ok(())
     }
-    
+
+    fn parse_madt_entry(&mut self, kind: u8, entry: &[u8]) {
+        match kind {
+            MADT_LOCAL_APIC if entry.len() >= 8 => {
+                let flags = read_u32(entry, 4).unwrap_or(0);
+                self.add_cpu(entry[3] as u32, flags);
+            }
+            MADT_LOCAL_X2APIC if entry.len() >= 16 => {
+                let flags = read_u32(entry, 8).unwrap_or(0);
+                self.add_cpu(read_u32(entry, 4).unwrap_or(0), flags);
+            }
+            MADT_IOAPIC if entry.len() >= 12 && self.ioapic_count < MAX_IOAPICS => {
+                self.ioapics[self.ioapic_count] = IoApicEntry {
+                    id: entry[2],
+                    addr: read_u32(entry, 4).unwrap_or(0) as u64,
+                    gsi_base: read_u32(entry, 8).unwrap_or(0),
+                };
+                self.ioapic_count += 1;
+            }
+            MADT_INTERRUPT_OVERRIDE if entry.len() >= 10 && self.override_count < MAX_OVERRIDES => {
+                let gsi = read_u32(entry, 4).unwrap_or(0);
+                let flags = u16::from_le_bytes([entry[8], entry[9]]);
+                self.overrides[self.override_count] = IrqOverride::new(entry[3], gsi, flags);
+                self.override_count += 1;
+            }
+            MADT_LAPIC_ADDR_OVERRIDE if entry.len() >= 12 => {
+                self.lapic_addr = read_u64(entry, 4).unwrap_or(self.lapic_addr);
+            }
+            _ => {}
+        }
+    }
+
+    fn add_cpu(&mut self, apic_id: u32, flags: u32) {
+        if flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) == 0 || self.cpu_count >= self.apic_ids.len() {
+            return;
+        }
+        self.apic_ids[self.cpu_count] = apic_id;
+        self.cpu_count += 1;
+    }
+
     /// Get APIC ID for a CPU
     pub fn get_apic_id(&self, cpu_id: CpuId) -> Option<u32> {
         if cpu_id.as_usize() < self.cpu_count {
@@ -57,32 +202,108 @@ impl AcpiInfo {
             None
         }
     }
+
+    /// IOAPICs described by the MADT
+    pub fn ioapics(&self) -> &[IoApicEntry] {
+        &self.ioapics[..self.ioapic_count]
+    }
+
+    /// Interrupt source overrides described by the MADT
+    pub fn overrides(&self) -> &[IrqOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    /// Look up the override for an ISA IRQ
+    pub fn isa_override(&self, irq: u8) -> Option<IrqOverride> {
+        self.overrides().iter().copied().find(|o| o.source == irq)
+    }
+}
+
+/// ACPI information for the running system
+static ACPI_INFO: Once<AcpiInfo> = Once::new();
+
+/// Read the MADT from the tables `crate::acpi::init()` found
+///
+/// Falls back to the standard PC layout if the MADT is missing or broken.
+pub fn init() -> &'static AcpiInfo {
+    ACPI_INFO.call_once(|| {
+        let mut info = AcpiInfo::new();
+        let parsed = crate::acpi::table(b"APIC")
+            .ok_or("No MADT")
+            .and_then(|madt| info.parse_madt(madt));
+        match parsed {
+            Ok(()) => crate::log_info!(
+                target: "acpi",
+                "MADT: {} CPUs, {} IOAPICs, {} overrides",
+                info.cpu_count,
+                info.ioapic_count,
+                info.override_count
+            ),
+            Err(e) => {
+                crate::log_warn!(target: "acpi", "{}, using defaults", e);
+                info.set_defaults();
+            }
+        }
+        info
+    })
+}
+
+/// Get the ACPI information, or None before `init()`
+pub fn acpi_info() -> Option<&'static AcpiInfo> {
+    ACPI_INFO.get()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+    use crate::acpi::test_table;
+    use alloc::vec::Vec;
+
+    fn madt() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        // Two enabled CPUs and a disabled one
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, 1, 1, 1, 0, 0, 0]);
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, 2, 2, 0, 0, 0, 0]);
+        // IOAPIC 4 at 0xFEC00000 from GSI 0
+        body.extend_from_slice(&[MADT_IOAPIC, 12, 4, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        // IRQ0 -> GSI 2, conforming; IRQ9 -> GSI 9, level active-low
+        body.extend_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0]);
+        test_table(b"APIC", &body)
+    }
+
     #[test]
     fn test_acpi_info_creation() {
         let info = AcpiInfo::new();
         assert_eq!(info.cpu_count, 0);
     }
-    
+
     #[test]
     fn test_acpi_parse_madt() {
         let mut info = AcpiInfo::new();
-        info.parse_madt().unwrap();
-        
-        assert_eq!(info.cpu_count, 1);
+        info.parse_madt(&madt()).unwrap();
+
+        assert_eq!(info.cpu_count, 2);
         assert_eq!(info.lapic_addr, 0xFEE00000);
+        assert_eq!(info.ioapics(), &[IoApicEntry { id: 4, addr: 0xFEC00000, gsi_base: 0 }]);
+        assert_eq!(info.isa_override(0).unwrap().gsi, 2);
+        let sci = info.isa_override(9).unwrap();
+        assert!(sci.level && sci.active_low);
+        assert!(info.isa_override(1).is_none());
+
+        let mut broken = madt();
+        broken[20] ^= 1;
+        assert_eq!(info.parse_madt(&broken), Err("Bad ACPI table checksum"));
     }
-    
+
     #[test]
     fn test_acpi_get_apic_id() {
         let mut info = AcpiInfo::new();
-        info.parse_madt().unwrap();
-        
+        info.set_defaults();
+
         assert_eq!(info.get_apic_id(CpuId::new(0)), Some(0));
         assert_eq!(info.get_apic_id(CpuId::new(1)), None);
     }
@@ -0,0 +1,224 @@
+//! Application Processor Startup
+//!
+//! Limine starts every AP in long mode and parks it on a small bootloader
+//! stack, spinning on its `goto_address`. The boot CPU registers each AP
+//! with the CPU manager, gives it a kernel stack and releases it. The AP
+//! then switches to the kernel page tables and stack, loads its own GDT
+//! and TSS and the shared IDT, brings up its local APIC, marks itself
+//! online and enters the idle loop.
+//!
+//! This module provides:
+//! - Per-AP boot information (CPU ID, stacks, page table root)
+//! - The Rust AP entry point
+//! - Sequential AP startup with an online timeout
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use fanga_arch_x86_64::gdt::{IstStacks, IST_STACK_SIZE};
+
+use super::cpu::{self, CpuId, CpuManager, CpuState};
+
+/// Kernel stack size of an AP's idle context
+pub const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// How long to wait for an AP to come online
+pub const AP_BOOT_TIMEOUT_MS: u64 = 1000;
+
+/// An application processor reported by the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApDescriptor {
+    /// ACPI processor UID
+    pub processor_id: u32,
+    /// Local APIC ID
+    pub lapic_id: u32,
+}
+
+/// What an AP needs to enter the kernel
+#[derive(Debug)]
+pub struct ApBootInfo {
+    /// Logical CPU ID assigned by the boot CPU
+    pub cpu: CpuId,
+    /// Local APIC ID
+    pub lapic_id: u32,
+    /// Top of the AP's kernel stack (16-byte aligned)
+    pub stack_top: u64,
+    /// Double fault, NMI and machine check stacks
+    pub ist: IstStacks,
+    /// Kernel page table root to load
+    pub cr3: u64,
+}
+
+/// Boot information of every started AP, looked up by local APIC ID
+static AP_BOOT_INFO: Mutex<Vec<&'static ApBootInfo>> = Mutex::new(Vec::new());
+
+/// Allocate a stack that lives forever, returning its 16-byte aligned top
+fn alloc_stack(size: usize) -> u64 {
+    let stack: &'static mut [u8] = vec![0u8; size].leak();
+    (stack.as_ptr() as u64 + size as u64) & !0xF
+}
+
+/// Allocate the stacks and publish the boot information of an AP
+pub fn prepare_ap(cpu: CpuId, lapic_id: u32, cr3: u64) -> &'static ApBootInfo {
+    let info = ApBootInfo {
+        cpu,
+        lapic_id,
+        stack_top: alloc_stack(AP_STACK_SIZE),
+        ist: IstStacks {
+            double_fault: alloc_stack(IST_STACK_SIZE),
+            nmi: alloc_stack(IST_STACK_SIZE),
+            machine_check: alloc_stack(IST_STACK_SIZE),
+        },
+        cr3,
+    };
+
+    let info: &'static ApBootInfo = Box::leak(Box::new(info));
+    AP_BOOT_INFO.lock().push(info);
+    info
+}
+
+/// Find the boot information of the AP with a local APIC ID
+pub fn boot_info(lapic_id: u32) -> Option<&'static ApBootInfo> {
+    AP_BOOT_INFO.lock().iter().copied().find(|info| info.lapic_id == lapic_id)
+}
+
+/// Register, release and wait for each AP in turn
+///
+/// `launch` releases one AP, which must look up its boot information with
+/// `boot_info()` and call `ap_entry()`. APs that miss the timeout are
+/// marked failed. Returns the number of online CPUs.
+pub fn start_aps_in(
+    manager: &Mutex<CpuManager>,
+    aps: &[ApDescriptor],
+    cr3: u64,
+    timeout_ms: u64,
+    mut launch: impl FnMut(&ApDescriptor),
+) -> Result<usize, &'static str> {
+    for ap in aps {
+        let cpu = manager.lock().add_cpu(ap.lapic_id)?;
+        prepare_ap(cpu, ap.lapic_id, cr3);
+        launch(ap);
+
+        if !wait_online(manager, cpu, timeout_ms) {
+            manager.lock().mark_failed(cpu)?;
+            #[cfg(not(test))]
+            crate::log_warn!(
+                target: "smp",
+                "CPU {} (APIC ID {}) did not come online",
+                cpu.as_usize(), ap.lapic_id
+            );
+        }
+    }
+    Ok(manager.lock().online_count())
+}
+
+/// Spin until a CPU is online or `timeout_ms` passes
+fn wait_online(manager: &Mutex<CpuManager>, cpu: CpuId, timeout_ms: u64) -> bool {
+    let start = crate::task::time::uptime_ms();
+    loop {
+        if manager.lock().get_cpu(cpu).map(|c| c.state) == Some(CpuState::Online) {
+            return true;
+        }
+        if crate::task::time::uptime_ms().saturating_sub(start) >= timeout_ms {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Enter the kernel on an AP
+///
+/// Called on the bootloader's stack; switches to the kernel page tables
+/// and the AP's own stack before running any kernel code.
+pub fn ap_entry(info: &'static ApBootInfo) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "mov cr3, {cr3}",
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {main}",
+            "ud2",
+            cr3 = in(reg) info.cr3,
+            stack = in(reg) info.stack_top,
+            main = sym ap_main,
+            in("rdi") info as *const ApBootInfo,
+            options(noreturn),
+        );
+    }
+}
+
+/// AP initialization on its kernel stack
+extern "C" fn ap_main(info: &'static ApBootInfo) -> ! {
+    // GS-based per-CPU data first: everything after may use it
+    cpu::set_current_cpu_id(info.cpu);
+    let cpu = info.cpu.as_usize();
+    unsafe { fanga_arch_x86_64::init_ap(cpu, &info.ist) };
+
+    if let Err(e) = crate::task::idle::init_idle(cpu) {
+        crate::log_warn!(target: "smp", "CPU {}: no idle task: {}", cpu, e);
+    }
+    if let Err(e) = super::cpu_manager().lock().bring_cpu_online(info.cpu) {
+        crate::log_warn!(target: "smp", "CPU {}: {}", cpu, e);
+    }
+    crate::log_info!(target: "smp", "CPU {} online (APIC ID {})", cpu, info.lapic_id);
+
+    // Each CPU takes its own tick once the boot CPU calibrated the timer
+    fanga_arch_x86_64::interrupts::apic_timer::start_cpu();
+    fanga_arch_x86_64::interrupts::nmi_watchdog::start_cpu();
+
+    crate::task::idle::cpu_startup_entry()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> Mutex<CpuManager> {
+        let mut manager = CpuManager::new();
+        manager.detect_cpus().unwrap();
+        Mutex::new(manager)
+    }
+
+    #[test]
+    fn test_boot_info() {
+        let info = prepare_ap(CpuId::new(3), 0x83, 0x1000);
+        assert_eq!(info.stack_top % 16, 0);
+        assert_ne!(info.ist.nmi, info.ist.double_fault);
+        assert_eq!(info.ist.machine_check % 16, 0);
+        assert_eq!(info.cr3, 0x1000);
+        assert_eq!(boot_info(0x83).unwrap().cpu, CpuId::new(3));
+        assert!(boot_info(0x84).is_none());
+    }
+
+    #[test]
+    fn test_start_aps() {
+        let manager = manager();
+        let aps = [
+            ApDescriptor { processor_id: 1, lapic_id: 0x41 },
+            ApDescriptor { processor_id: 2, lapic_id: 0x42 },
+        ];
+
+        // Stand in for the APs: each marks itself online when released
+        let online = start_aps_in(&manager, &aps, 0, AP_BOOT_TIMEOUT_MS, |ap| {
+            let info = boot_info(ap.lapic_id).unwrap();
+            manager.lock().bring_cpu_online(info.cpu).unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(online, 3);
+        assert_eq!(manager.lock().get_cpu(CpuId::new(2)).unwrap().apic_id, 0x42);
+    }
+
+    #[test]
+    fn test_ap_timeout() {
+        let manager = manager();
+        let aps = [ApDescriptor { processor_id: 1, lapic_id: 0x51 }];
+
+        let online = start_aps_in(&manager, &aps, 0, 0, |_| {}).unwrap();
+        assert_eq!(online, 1);
+        assert_eq!(manager.lock().get_cpu(CpuId::new(1)).unwrap().state, CpuState::Failed);
+    }
+}
@@ -95,7 +95,7 @@ impl CpuManager {
     /// Detect and enumerate all CPUs
     pub fn detect_cpus(&mut self) -> Result<(), &'static str> {
         // Start with BSP (Bootstrap Processor)
-        let bsp = CpuInfo::new(CpuId::new(0), 0, true);
+        let bsp = CpuInfo::new(CpuId::new(0), current_apic_id(), true);
         self.cpus.push(bsp);
         
         // TODO: Parse ACPI MADT to find additional CPUs
@@ -123,6 +123,11 @@ impl CpuManager {
         self.cpus.get(id.as_usize())
     }
     
+    /// Iterate over the online CPUs
+    pub fn online_cpus(&self) -> impl Iterator<Item = &CpuInfo> {
+        self.cpus.iter().filter(|cpu| cpu.state == CpuState::Online)
+    }
+    
     /// Get mutable CPU information
     pub fn get_cpu_mut(&mut self, id: CpuId) -> Option<&mut CpuInfo> {
         self.cpus.get_mut(id.as_usize())
@@ -141,16 +146,10 @@ impl CpuManager {
         Ok(id)
     }
     
-    /// Start Application Processors
-    pub fn start_aps(&mut self) -> Result<(), &'static str> {
-        // TODO: Implement AP startup sequence
-        // This involves:
-        // 1. Sending INIT IPI
-        // 2. Waiting
-        // 3. Sending SIPI (Startup IPI) with trampoline code address
-        // 4. Waiting for AP to initialize
-        
-        // For now, this is a placeholder
+    /// Mark a CPU that did not come up as failed
+    pub fn mark_failed(&mut self, id: CpuId) -> Result<(), &'static str> {
+        let cpu = self.get_cpu_mut(id).ok_or("Invalid CPU ID")?;
+        cpu.state = CpuState::Failed;
         Ok(())
     }
     
@@ -171,25 +170,56 @@ impl CpuManager {
         
         Ok(())
     }
+    
+    /// Mark an online CPU offline (CPU hotplug)
+    ///
+    /// The boot CPU cannot go offline.
+    pub fn take_cpu_offline(&mut self, id: CpuId) -> Result<(), &'static str> {
+        let cpu = self.get_cpu_mut(id).ok_or("Invalid CPU ID")?;
+        if cpu.is_bsp {
+            return Err("Boot CPU cannot go offline");
+        }
+        if cpu.state != CpuState::Online {
+            return Err("CPU is not online");
+        }
+        
+        cpu.state = CpuState::Offline;
+        self.online_count.fetch_sub(1, Ordering::SeqCst);
+        Ok(())
+    }
 }
 
-/// Current CPU ID (will be set via CPU-local storage)
+/// CPU ID used until GS-based per-CPU data is set up
 static CURRENT_CPU: AtomicU32 = AtomicU32::new(0);
 
+/// Get the local APIC ID of the running CPU (CPUID leaf 1)
+pub fn current_apic_id() -> u32 {
+    fanga_arch_x86_64::cpuid::cpuid(1, 0).ebx >> 24
+}
+
 /// Get the current CPU ID
+///
+/// One GS-relative load once per-CPU data is set up.
 pub fn current_cpu_id() -> CpuId {
-    CpuId::new(CURRENT_CPU.load(Ordering::Relaxed) as usize)
+    super::percpu::gs_cpu_id()
+        .unwrap_or_else(|| CpuId::new(CURRENT_CPU.load(Ordering::Relaxed) as usize))
 }
 
 /// Set the current CPU ID (called during CPU initialization)
+///
+/// Also points the CPU's GS base at its per-CPU data.
 pub fn set_current_cpu_id(id: CpuId) {
-    CURRENT_CPU.store(id.as_usize() as u32, Ordering::Relaxed);
+    if id.as_usize() == 0 {
+        CURRENT_CPU.store(0, Ordering::Relaxed);
+    }
+    super::percpu::load_percpu(id);
 }
 
-/// Get the number of CPUs
+/// Get the number of online CPUs
 pub fn cpu_count() -> usize {
-    // Access via global manager
-    1 // Placeholder - will be updated when manager is accessible
+    super::CPU_MANAGER
+        .get()
+        .map_or(1, |manager| manager.lock().online_count().max(1))
 }
 
 #[cfg(test)]
@@ -236,4 +266,24 @@ mod tests {
         assert_eq!(cpu.state, CpuState::Offline);
         assert!(!cpu.is_bsp);
     }
+    
+    #[test]
+    fn test_take_cpu_offline() {
+        let mut manager = CpuManager::new();
+        manager.detect_cpus().unwrap();
+        let cpu_id = manager.add_cpu(1).unwrap();
+        
+        assert!(manager.take_cpu_offline(cpu_id).is_err());
+        manager.bring_cpu_online(cpu_id).unwrap();
+        assert_eq!(manager.online_count(), 2);
+        
+        manager.take_cpu_offline(cpu_id).unwrap();
+        assert_eq!(manager.online_count(), 1);
+        assert_eq!(manager.get_cpu(cpu_id).unwrap().state, CpuState::Offline);
+        assert!(manager.take_cpu_offline(CpuId::new(0)).is_err());
+        
+        // An offline CPU can come back
+        manager.bring_cpu_online(cpu_id).unwrap();
+        assert_eq!(manager.online_count(), 2);
+    }
 }
@@ -0,0 +1,203 @@
+//! CPU Hotplug
+//!
+//! Takes application processors out of service at runtime and brings them
+//! back. Going offline happens in two halves:
+//!
+//! 1. The requesting CPU stops the scheduler from placing tasks on the
+//!    target, moves its queued tasks away and sends it a reschedule IPI.
+//! 2. The target leaves its current task for its idle task, drains what
+//!    was queued meanwhile, leaves RCU and the online set (and with it TLB
+//!    shootdowns), raises its task priority so only IPIs reach it, and
+//!    parks in its deepest C-state.
+//!
+//! Coming back, the parked CPU flushes its TLB, accepts interrupts again
+//! and rejoins the scheduler from its idle loop. Device interrupts are
+//! delivered to the boot CPU, which therefore never goes offline.
+//!
+//! This module provides:
+//! - `cpu_down` and `cpu_up`
+//! - The park loop run by an offline CPU
+//! - Per-CPU hotplug state
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use fanga_arch_x86_64::interrupts::apic;
+
+use super::cpu::{current_cpu_id, CpuId, CpuState, MAX_CPUS};
+
+/// How long to wait for a CPU to park or come back
+pub const HOTPLUG_TIMEOUT_MS: u64 = 1000;
+
+/// Hotplug state of a CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HotplugState {
+    /// In service (or never started)
+    Running = 0,
+    /// Asked to go offline, not parked yet
+    GoingDown = 1,
+    /// Offline, parked in its idle loop
+    Parked = 2,
+    /// Asked to come back, not in service yet
+    ComingUp = 3,
+}
+
+impl HotplugState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => HotplugState::GoingDown,
+            2 => HotplugState::Parked,
+            3 => HotplugState::ComingUp,
+            _ => HotplugState::Running,
+        }
+    }
+}
+
+static STATE: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(HotplugState::Running as u8) }; MAX_CPUS];
+
+/// Get the hotplug state of a CPU
+pub fn state(cpu: CpuId) -> HotplugState {
+    STATE
+        .get(cpu.as_usize())
+        .map_or(HotplugState::Running, |state| HotplugState::from_u8(state.load(Ordering::Acquire)))
+}
+
+fn transition(cpu: usize, from: HotplugState, to: HotplugState) -> bool {
+    STATE[cpu]
+        .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+/// Check that a CPU can be the target of a hotplug operation
+fn check_target(cpu: CpuId) -> Result<(), &'static str> {
+    if cpu.as_usize() >= MAX_CPUS {
+        return Err("Invalid CPU ID");
+    }
+    if cpu.as_usize() == 0 {
+        return Err("Boot CPU cannot go offline");
+    }
+    if cpu == current_cpu_id() {
+        return Err("Cannot change the running CPU");
+    }
+    Ok(())
+}
+
+/// Spin until a CPU reaches `target` or the timeout passes
+fn wait_for(cpu: usize, target: HotplugState) -> bool {
+    let start = crate::task::time::uptime_ms();
+    while HotplugState::from_u8(STATE[cpu].load(Ordering::Acquire)) != target {
+        if crate::task::time::uptime_ms().saturating_sub(start) >= HOTPLUG_TIMEOUT_MS {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Take a CPU offline
+///
+/// Returns once the CPU is parked. On a timeout the CPU still parks the
+/// next time it gets to its idle loop.
+pub fn cpu_down(cpu: CpuId) -> Result<(), &'static str> {
+    check_target(cpu)?;
+    let manager = super::CPU_MANAGER.get().ok_or("SMP not initialized")?;
+    if manager.lock().get_cpu(cpu).map(|info| info.state) != Some(CpuState::Online) {
+        return Err("CPU is not online");
+    }
+
+    let id = cpu.as_usize();
+    if !transition(id, HotplugState::Running, HotplugState::GoingDown) {
+        return Err("CPU hotplug in progress");
+    }
+    if let Err(e) = crate::task::scheduler::scheduler().deactivate_cpu(id) {
+        STATE[id].store(HotplugState::Running as u8, Ordering::Release);
+        return Err(e);
+    }
+
+    super::resched::resched_cpu(cpu)?;
+    if !wait_for(id, HotplugState::Parked) {
+        return Err("CPU did not go offline in time");
+    }
+    Ok(())
+}
+
+/// Bring a parked CPU back online
+pub fn cpu_up(cpu: CpuId) -> Result<(), &'static str> {
+    check_target(cpu)?;
+    let id = cpu.as_usize();
+    if !transition(id, HotplugState::Parked, HotplugState::ComingUp) {
+        return Err("CPU is not offline");
+    }
+
+    // The reschedule IPI wakes it from its park loop
+    super::resched::resched_cpu(cpu)?;
+    if !wait_for(id, HotplugState::Running) {
+        return Err("CPU did not come online in time");
+    }
+    Ok(())
+}
+
+/// Park this CPU if it was asked to go offline
+///
+/// Called from the idle loop, so the CPU runs its idle task. Returns once
+/// the CPU is back online.
+pub fn park_if_requested() {
+    let cpu = current_cpu_id();
+    let id = cpu.as_usize();
+    if state(cpu) != HotplugState::GoingDown {
+        return;
+    }
+
+    crate::task::scheduler::scheduler().drain_cpu(id);
+    super::rcu::rcu_cpu_offline(id);
+    if let Err(e) = super::cpu_manager().lock().take_cpu_offline(cpu) {
+        crate::log_warn!(target: "hotplug", "CPU {}: {}", id, e);
+    }
+    apic::set_task_priority(apic::TPR_IPIS_ONLY);
+    STATE[id].store(HotplugState::Parked as u8, Ordering::Release);
+    crate::log_info!(target: "hotplug", "CPU {} offline", id);
+
+    loop {
+        fanga_arch_x86_64::interrupts::disable();
+        if state(cpu) == HotplugState::ComingUp {
+            fanga_arch_x86_64::interrupts::enable();
+            break;
+        }
+        crate::task::idle::enter_idle_state(u64::MAX);
+    }
+
+    // Shootdowns skipped this CPU while it was offline
+    super::tlb::resync_local();
+    apic::set_task_priority(0);
+    super::rcu::rcu_cpu_online(id);
+    if let Err(e) = super::cpu_manager().lock().bring_cpu_online(cpu) {
+        crate::log_warn!(target: "hotplug", "CPU {}: {}", id, e);
+    }
+    if let Err(e) = crate::task::scheduler::scheduler().enter_idle(id) {
+        crate::log_warn!(target: "hotplug", "CPU {}: {}", id, e);
+    }
+    STATE[id].store(HotplugState::Running as u8, Ordering::Release);
+    crate::log_info!(target: "hotplug", "CPU {} online", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotplug_targets() {
+        assert_eq!(cpu_down(CpuId::new(0)), Err("Boot CPU cannot go offline"));
+        assert_eq!(cpu_up(CpuId::new(MAX_CPUS)), Err("Invalid CPU ID"));
+        // Only a parked CPU can come back
+        assert_eq!(cpu_up(CpuId::new(7)), Err("CPU is not offline"));
+        assert_eq!(state(CpuId::new(7)), HotplugState::Running);
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        assert!(transition(9, HotplugState::Running, HotplugState::GoingDown));
+        assert!(!transition(9, HotplugState::Running, HotplugState::GoingDown));
+        assert_eq!(state(CpuId::new(9)), HotplugState::GoingDown);
+        STATE[9].store(HotplugState::Running as u8, Ordering::Release);
+    }
+}
@@ -0,0 +1,153 @@
+//! IOAPIC Interrupt Routing
+//!
+//! At boot the IOAPICs listed in the MADT are mapped and registered, and
+//! the legacy ISA IRQs are routed to the same vectors the 8259 PIC used,
+//! so handlers registered by IRQ number keep working. Interrupt source
+//! overrides from the MADT move an IRQ to another GSI or change its
+//! trigger mode and polarity. Every IRQ is delivered to the boot CPU.
+//! If anything fails the PIC stays in charge.
+//!
+//! PCI devices report their interrupt as an IRQ line in configuration
+//! space. Without an AML interpreter there is no `_PRT` to translate it,
+//! so `route_pci_irq` takes the line as an ISA IRQ and makes it
+//! level-triggered. This matches how firmware routes PCI INTx in legacy
+//! mode, and the MADT overrides that firmware adds for those lines.
+//!
+//! This module provides:
+//! - IOAPIC discovery and mapping from the MADT
+//! - ISA IRQ routing with interrupt source overrides
+//! - Level-triggered routing for PCI interrupt lines
+
+use fanga_arch_x86_64::interrupts::idt::PIC1_OFFSET;
+use fanga_arch_x86_64::interrupts::ioapic::{self, IoApic, RedirectionEntry};
+use fanga_arch_x86_64::interrupts::pic;
+
+use super::acpi::AcpiInfo;
+use super::cpu::current_apic_id;
+
+/// Number of legacy ISA IRQs
+pub const ISA_IRQS: u8 = 16;
+
+/// PIC cascade line, never raised on an IOAPIC
+const IRQ_CASCADE: u8 = 2;
+
+/// Size of an IOAPIC's register window
+const IOAPIC_MMIO_SIZE: u64 = 0x20;
+
+/// Vector of an ISA IRQ, the same as under the PIC
+pub fn isa_vector(irq: u8) -> u8 {
+    PIC1_OFFSET + irq
+}
+
+/// GSI and redirection entry of ISA IRQ `irq`, applying MADT overrides
+pub fn isa_route(info: &AcpiInfo, irq: u8, dest: u8) -> (u32, RedirectionEntry) {
+    let mut entry = RedirectionEntry::isa(isa_vector(irq), dest);
+    entry.masked = true;
+    match info.isa_override(irq) {
+        Some(o) => {
+            entry.level = o.level;
+            entry.active_low = o.active_low;
+            (o.gsi, entry)
+        }
+        None => (irq as u32, entry),
+    }
+}
+
+/// Map and register the IOAPICs, route the ISA IRQs and retire the PIC
+///
+/// IRQs that are unmasked at the PIC are unmasked at their IOAPIC pin.
+pub fn init(info: &AcpiInfo) -> Result<(), &'static str> {
+    if info.ioapics().is_empty() {
+        return Err("No IOAPIC in the MADT");
+    }
+    for entry in info.ioapics() {
+        let base = crate::memory::ioremap(entry.addr, IOAPIC_MMIO_SIZE)?;
+        ioapic::register(unsafe { IoApic::new(base, entry.id, entry.gsi_base) })?;
+    }
+
+    let dest = current_apic_id() as u8;
+    fanga_arch_x86_64::interrupts::without_interrupts(|| {
+        let (pic1, pic2) = unsafe { pic::get_masks() };
+        let pic_mask = pic1 as u16 | (pic2 as u16) << 8;
+
+        for irq in (0..ISA_IRQS).filter(|&irq| irq != IRQ_CASCADE) {
+            let (gsi, entry) = isa_route(info, irq, dest);
+            ioapic::set_isa_gsi(irq, gsi);
+            ioapic::route(gsi, entry)?;
+            if pic_mask & (1 << irq) == 0 {
+                ioapic::unmask(gsi)?;
+            }
+        }
+        ioapic::activate()
+    })?;
+
+    crate::log_info!(
+        target: "ioapic",
+        "{} IOAPIC(s) routing ISA IRQs to APIC {}, PIC disabled",
+        info.ioapics().len(),
+        dest
+    );
+    Ok(())
+}
+
+/// Route a PCI device's interrupt line as a level-triggered interrupt
+///
+/// The line stays masked until `enable_irq`. Handlers are registered by
+/// the same IRQ number as under the PIC.
+pub fn route_pci_irq(line: u8) -> Result<(), &'static str> {
+    if line >= ISA_IRQS || line == IRQ_CASCADE {
+        return Err("PCI interrupt line out of range");
+    }
+    if !ioapic::is_active() {
+        // Under the PIC, level-triggered lines are set up by firmware
+        return Ok(());
+    }
+
+    let mut entry = RedirectionEntry::pci(isa_vector(line), current_apic_id() as u8);
+    entry.masked = true;
+    // A MADT override describes how this board wires the line
+    if let Some(o) = super::acpi::acpi_info().and_then(|info| info.isa_override(line)) {
+        entry.level = o.level;
+        entry.active_low = o.active_low;
+    }
+    ioapic::route(ioapic::isa_gsi(line), entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smp::acpi::IrqOverride;
+
+    #[test]
+    fn test_isa_route_defaults() {
+        let info = AcpiInfo::new();
+        let (gsi, entry) = isa_route(&info, 1, 0);
+
+        assert_eq!(gsi, 1);
+        assert_eq!(entry.vector, 33);
+        assert!(!entry.level && !entry.active_low && entry.masked);
+    }
+
+    #[test]
+    fn test_isa_route_override() {
+        let mut info = AcpiInfo::new();
+        info.overrides[0] = IrqOverride { source: 0, gsi: 2, active_low: false, level: false };
+        info.overrides[1] = IrqOverride { source: 9, gsi: 9, active_low: true, level: true };
+        info.override_count = 2;
+
+        let (gsi, entry) = isa_route(&info, 0, 3);
+        assert_eq!((gsi, entry.vector, entry.dest), (2, 32, 3));
+
+        let (gsi, entry) = isa_route(&info, 9, 0);
+        assert_eq!(gsi, 9);
+        assert!(entry.level && entry.active_low);
+    }
+
+    #[test]
+    fn test_route_pci_irq_range() {
+        assert!(route_pci_irq(16).is_err());
+        assert!(route_pci_irq(IRQ_CASCADE).is_err());
+        // Still on the PIC in tests
+        assert!(route_pci_irq(11).is_ok());
+    }
+}
@@ -1,6 +1,10 @@
 //! Inter-Processor Interrupts (IPI)
 //!
-//! This module provides IPI support for inter-CPU communication.
+//! This module provides IPI support for inter-CPU communication. IPIs are
+//! sent through the local APIC in x2APIC mode; each IPI type has its own
+//! interrupt vector.
+
+use fanga_arch_x86_64::interrupts::{apic, idt};
 
 use super::CpuId;
 
@@ -72,59 +76,82 @@ impl Ipi {
     }
 }
 
+impl IpiType {
+    /// Interrupt vector used for this IPI type
+    pub fn vector(&self) -> Option<u8> {
+        match self {
+            IpiType::TlbFlush => Some(idt::VEC_IPI_TLB_FLUSH),
+            IpiType::Reschedule => Some(idt::VEC_IPI_RESCHEDULE),
+            _ => None,
+        }
+    }
+}
+
 /// Send an IPI
+///
+/// The data word is not carried by the interrupt itself; subsystems pass
+/// their requests through their own queues (see `tlb`).
 pub fn send_ipi(ipi: Ipi) -> Result<(), &'static str> {
-    // TODO: Implement actual IPI sending via APIC
-    // This would use the Local APIC's ICR (Interrupt Command Register)
-    
+    let vector = ipi.ipi_type.vector().ok_or("IPI type not supported")?;
+
     match ipi.target {
         IpiTarget::Cpu(cpu_id) => {
             // Send to specific CPU
-            send_ipi_to_cpu(cpu_id, ipi.ipi_type, ipi.data)
+            send_ipi_to_cpu(cpu_id, vector)
         }
         IpiTarget::AllExceptSelf => {
             // Send to all CPUs except current
-            send_ipi_broadcast(ipi.ipi_type, ipi.data, true)
+            apic::broadcast_ipi(vector, false)
         }
         IpiTarget::All => {
             // Send to all CPUs including current
-            send_ipi_broadcast(ipi.ipi_type, ipi.data, false)
+            apic::broadcast_ipi(vector, true)
         }
         IpiTarget::Mask(mask) => {
             // Send to CPUs specified in mask
-            send_ipi_to_mask(mask, ipi.ipi_type, ipi.data)
+            send_ipi_to_mask(mask, vector)
         }
     }
 }
 
 /// Send IPI to a specific CPU
-fn send_ipi_to_cpu(_cpu_id: CpuId, _ipi_type: IpiType, _data: u64) -> Result<(), &'static str> {
-    // TODO: Use APIC to send IPI
-    Ok(())
+fn send_ipi_to_cpu(cpu_id: CpuId, vector: u8) -> Result<(), &'static str> {
+    let apic_id = super::CPU_MANAGER
+        .get()
+        .ok_or("SMP not initialized")?
+        .lock()
+        .get_cpu(cpu_id)
+        .map(|cpu| cpu.apic_id)
+        .ok_or("Invalid CPU ID")?;
+    apic::send_ipi(apic_id, vector)
 }
 
-/// Send IPI broadcast
-fn send_ipi_broadcast(_ipi_type: IpiType, _data: u64, _exclude_self: bool) -> Result<(), &'static str> {
-    // TODO: Use APIC to broadcast IPI
-    Ok(())
+/// Send IPI to CPUs specified in mask
+fn send_ipi_to_mask(mask: u64, vector: u8) -> Result<(), &'static str> {
+    (0..64)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .try_for_each(|bit| send_ipi_to_cpu(CpuId::new(bit), vector))
 }
 
-/// Send IPI to CPUs specified in mask
-fn send_ipi_to_mask(_mask: u64, _ipi_type: IpiType, _data: u64) -> Result<(), &'static str> {
-    // TODO: Use APIC to send IPI to mask
-    Ok(())
+/// Handle an incoming IPI
+///
+/// Registered with the arch layer; runs in interrupt context.
+pub fn handle_ipi(vector: u8) {
+    match vector {
+        idt::VEC_IPI_TLB_FLUSH => super::tlb::handle_flush_ipi(),
+        idt::VEC_IPI_RESCHEDULE => super::resched::handle_reschedule_ipi(),
+        _ => {}
+    }
 }
 
-/// TLB shootdown - invalidate TLB entries on all CPUs
+/// TLB shootdown - invalidate a page on all CPUs
 pub fn tlb_shootdown(addr: u64) -> Result<(), &'static str> {
-    let ipi = Ipi::with_data(IpiType::TlbFlush, IpiTarget::AllExceptSelf, addr);
-    send_ipi(ipi)
+    super::tlb::flush_page(addr)
 }
 
 /// Trigger reschedule on a specific CPU
 pub fn reschedule_cpu(cpu_id: CpuId) -> Result<(), &'static str> {
-    let ipi = Ipi::new(IpiType::Reschedule, IpiTarget::Cpu(cpu_id));
-    send_ipi(ipi)
+    super::resched::resched_cpu(cpu_id)
 }
 
 #[cfg(test)]
@@ -153,4 +180,12 @@ mod tests {
         let ipi2 = Ipi::new(IpiType::Generic, IpiTarget::AllExceptSelf);
         assert_eq!(ipi2.target, IpiTarget::AllExceptSelf);
     }
+    
+    #[test]
+    fn test_ipi_vectors() {
+        assert_eq!(IpiType::TlbFlush.vector(), Some(idt::VEC_IPI_TLB_FLUSH));
+        assert_eq!(IpiType::Reschedule.vector(), Some(idt::VEC_IPI_RESCHEDULE));
+        assert_eq!(IpiType::Generic.vector(), None);
+        assert!(send_ipi(Ipi::new(IpiType::Halt, IpiTarget::All)).is_err());
+    }
 }
@@ -0,0 +1,337 @@
+//! Lock Dependency Checking
+//!
+//! A light version of lockdep. Every tracked lock belongs to a class (all
+//! run queue locks share one). Whenever a lock is taken while others are
+//! held on the same CPU, the order "held before taken" is recorded between
+//! their classes. Taking two classes in both orders can deadlock on SMP even
+//! if it never happened so far, so an acquisition that would close a cycle
+//! in the order graph is reported, once per class pair, before the CPU
+//! starts spinning.
+//!
+//! Checking is off until `enable()` is called; a disabled check costs one
+//! atomic load per lock operation.
+//!
+//! This module provides:
+//! - Lock classes
+//! - The class order graph with cycle detection
+//! - Per-CPU held lock tracking
+//! - Reports of recursive locking and lock order inversions
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::cpu::{current_cpu_id, MAX_CPUS};
+
+/// Maximum number of lock classes
+pub const MAX_CLASSES: usize = 64;
+
+/// Maximum number of locks a CPU can hold at once and still be tracked
+pub const MAX_HELD: usize = 16;
+
+/// Maximum number of violations kept for `violations()`
+const MAX_REPORTS: usize = 16;
+
+/// A class of locks that are ordered alike
+pub struct LockClass {
+    name: &'static str,
+    /// Class index plus one, or 0 before first use
+    id: AtomicU8,
+}
+
+impl LockClass {
+    /// Create a lock class
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, id: AtomicU8::new(0) }
+    }
+
+    /// Name of the class
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Class index, registering the class on first use
+    ///
+    /// Returns None once `MAX_CLASSES` classes exist.
+    pub(super) fn index(&'static self) -> Option<u8> {
+        let id = self.id.load(Ordering::Acquire);
+        if id != 0 {
+            return Some(id - 1);
+        }
+
+        let index = NEXT_CLASS.fetch_add(1, Ordering::Relaxed);
+        if index >= MAX_CLASSES {
+            return None;
+        }
+        match self.id.compare_exchange(0, index as u8 + 1, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                CLASSES[index].store(self as *const LockClass as *mut LockClass, Ordering::Release);
+                Some(index as u8)
+            }
+            // Another CPU registered it first; the slot stays unused
+            Err(id) => Some(id - 1),
+        }
+    }
+}
+
+/// A lock order problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A lock of a class was taken while holding one of the same class
+    Recursive { class: u8 },
+    /// `acquired` was taken while holding `held`, but was also held
+    /// (directly or through other classes) when `held` was taken
+    Inversion { held: u8, acquired: u8 },
+}
+
+/// Order graph between lock classes
+pub struct LockGraph {
+    /// Bit `b` of `after[a]`: class `b` was taken while holding class `a`
+    after: [AtomicU64; MAX_CLASSES],
+}
+
+impl LockGraph {
+    /// Create an empty graph
+    pub const fn new() -> Self {
+        Self { after: [const { AtomicU64::new(0) }; MAX_CLASSES] }
+    }
+
+    /// Check whether class `to` was ever taken after class `from`
+    pub fn reaches(&self, from: u8, to: u8) -> bool {
+        let mut visited = 0u64;
+        let mut frontier = 1u64 << from;
+        while frontier != 0 {
+            let class = frontier.trailing_zeros() as usize;
+            frontier &= frontier - 1;
+            if class == to as usize {
+                return true;
+            }
+            visited |= 1 << class;
+            frontier |= self.after[class].load(Ordering::Relaxed) & !visited;
+        }
+        false
+    }
+
+    /// Check taking `class` while holding `held` and record the new orders
+    ///
+    /// Orders that would close a cycle are not recorded.
+    pub fn acquire(&self, held: &[u8], class: u8) -> Result<(), Violation> {
+        for &h in held {
+            if h == class {
+                return Err(Violation::Recursive { class });
+            }
+            if self.reaches(class, h) {
+                return Err(Violation::Inversion { held: h, acquired: class });
+            }
+        }
+        for &h in held {
+            self.after[h as usize].fetch_or(1 << class, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl Default for LockGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Locks held by one CPU, innermost last
+struct HeldLocks {
+    classes: [AtomicU8; MAX_HELD],
+    depth: AtomicUsize,
+}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        Self { classes: [const { AtomicU8::new(0) }; MAX_HELD], depth: AtomicUsize::new(0) }
+    }
+
+    fn snapshot(&self, out: &mut [u8; MAX_HELD]) -> usize {
+        let depth = self.depth.load(Ordering::Relaxed).min(MAX_HELD);
+        for (slot, class) in out.iter_mut().zip(&self.classes).take(depth) {
+            *slot = class.load(Ordering::Relaxed);
+        }
+        depth
+    }
+
+    fn push(&self, class: u8) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        if depth < MAX_HELD {
+            self.classes[depth].store(class, Ordering::Relaxed);
+        }
+        self.depth.store(depth + 1, Ordering::Relaxed);
+    }
+
+    /// Forget the innermost lock of a class (locks may be released out of order)
+    fn pop(&self, class: u8) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        if depth == 0 {
+            return;
+        }
+        if depth <= MAX_HELD {
+            if let Some(pos) = (0..depth).rev().find(|&i| self.classes[i].load(Ordering::Relaxed) == class) {
+                for i in pos..depth - 1 {
+                    let next = self.classes[i + 1].load(Ordering::Relaxed);
+                    self.classes[i].store(next, Ordering::Relaxed);
+                }
+            }
+        }
+        self.depth.store(depth - 1, Ordering::Relaxed);
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_CLASS: AtomicUsize = AtomicUsize::new(0);
+static CLASSES: [AtomicPtr<LockClass>; MAX_CLASSES] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CLASSES];
+static GRAPH: LockGraph = LockGraph::new();
+static HELD: [HeldLocks; MAX_CPUS] = [const { HeldLocks::new() }; MAX_CPUS];
+
+/// Class pairs already reported (bit `b` of `REPORTED[a]`)
+static REPORTED: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+/// Recent violations (not a tracked lock)
+static REPORTS: Mutex<Vec<Violation>> = Mutex::new(Vec::new());
+
+/// Start checking lock order
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop checking lock order
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Check whether lock order checking is on
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of registered classes
+pub fn class_count() -> usize {
+    NEXT_CLASS.load(Ordering::Relaxed).min(MAX_CLASSES)
+}
+
+/// Name of a registered class
+pub fn class_name(index: u8) -> &'static str {
+    let class = CLASSES
+        .get(index as usize)
+        .map_or(core::ptr::null_mut(), |ptr| ptr.load(Ordering::Acquire));
+    if class.is_null() {
+        "<unknown>"
+    } else {
+        unsafe { (*class).name }
+    }
+}
+
+fn report(violation: Violation) {
+    let (a, b) = match violation {
+        Violation::Recursive { class } => (class, class),
+        Violation::Inversion { held, acquired } => (held, acquired),
+    };
+    if REPORTED[a as usize].fetch_or(1 << b, Ordering::Relaxed) & (1 << b) != 0 {
+        return;
+    }
+
+    if let Some(mut reports) = REPORTS.try_lock() {
+        if reports.len() < MAX_REPORTS {
+            reports.push(violation);
+        }
+    }
+
+    #[cfg(not(test))]
+    match violation {
+        Violation::Recursive { class } => fanga_arch_x86_64::serial_println!(
+            "[LOCKDEP] recursive locking of {} on CPU {}",
+            class_name(class),
+            current_cpu_id().as_usize()
+        ),
+        Violation::Inversion { held, acquired } => fanga_arch_x86_64::serial_println!(
+            "[LOCKDEP] possible deadlock on CPU {}: taking {} while holding {}, but {} was taken while holding {} before",
+            current_cpu_id().as_usize(),
+            class_name(acquired),
+            class_name(held),
+            class_name(held),
+            class_name(acquired)
+        ),
+    }
+}
+
+/// Record that this CPU is about to take a lock of `class`
+///
+/// `check` is false for try-locks, which never wait and so cannot deadlock.
+pub fn lock_acquire(class: &'static LockClass, check: bool) {
+    if !is_enabled() {
+        return;
+    }
+    let Some(index) = class.index() else { return };
+    let held_locks = &HELD[current_cpu_id().as_usize()];
+
+    if check {
+        let mut held = [0u8; MAX_HELD];
+        let depth = held_locks.snapshot(&mut held);
+        if let Err(violation) = GRAPH.acquire(&held[..depth], index) {
+            report(violation);
+        }
+    }
+    held_locks.push(index);
+}
+
+/// Record that this CPU released a lock of `class`
+pub fn lock_release(class: &'static LockClass) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some(index) = class.index() {
+        HELD[current_cpu_id().as_usize()].pop(index);
+    }
+}
+
+/// Violations found so far (up to 16)
+pub fn violations() -> Vec<Violation> {
+    REPORTS.lock().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_inversion() {
+        let graph = LockGraph::new();
+        // a then b, b then c
+        assert!(graph.acquire(&[0], 1).is_ok());
+        assert!(graph.acquire(&[1], 2).is_ok());
+        assert!(graph.reaches(0, 2));
+
+        // c then a closes the cycle a -> b -> c -> a
+        assert_eq!(graph.acquire(&[2], 0), Err(Violation::Inversion { held: 2, acquired: 0 }));
+        assert!(!graph.reaches(2, 0));
+        assert_eq!(graph.acquire(&[1, 1], 1), Err(Violation::Recursive { class: 1 }));
+    }
+
+    #[test]
+    fn test_held_out_of_order_release() {
+        let held = HeldLocks::new();
+        held.push(3);
+        held.push(5);
+        held.push(7);
+        held.pop(5);
+
+        let mut out = [0u8; MAX_HELD];
+        let depth = held.snapshot(&mut out);
+        assert_eq!(&out[..depth], &[3, 7]);
+    }
+
+    #[test]
+    fn test_class_registration() {
+        static CLASS: LockClass = LockClass::new("test-class");
+        let index = CLASS.index().unwrap();
+        assert_eq!(CLASS.index(), Some(index));
+        assert_eq!(class_name(index), "test-class");
+    }
+}
@@ -0,0 +1,364 @@
+//! Lock Contention Statistics
+//!
+//! Counts, per lock class, how often locks were taken, how many of those
+//! acquisitions had to wait, and the total and longest wait in TSC cycles.
+//! For contended acquisitions the call site (`#[track_caller]` location of
+//! the `lock` call) is recorded too, so the report shows which code waits
+//! most for a lock. Only locks with a class are counted; `LockStats` of the
+//! lock itself covers the others.
+//!
+//! Recording is off until `enable()` is called; a disabled check costs one
+//! atomic load per lock operation.
+//!
+//! This module provides:
+//! - Per class acquisition and wait time counters
+//! - The hottest call sites of each class
+//! - The report shown by the `lockstat` shell command
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use fanga_arch_x86_64::tsc;
+
+use super::lockdep::{self, LockClass, MAX_CLASSES};
+
+/// Call sites kept per class
+pub const MAX_SITES: usize = 8;
+
+/// Call sites listed per class in the report
+const REPORT_SITES: usize = 4;
+
+/// Contention from one call site
+struct SiteCounters {
+    location: AtomicPtr<Location<'static>>,
+    contended: AtomicU64,
+    wait_cycles: AtomicU64,
+}
+
+impl SiteCounters {
+    const fn new() -> Self {
+        Self {
+            location: AtomicPtr::new(core::ptr::null_mut()),
+            contended: AtomicU64::new(0),
+            wait_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Counters of one lock class
+struct ClassCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_cycles: AtomicU64,
+    max_wait_cycles: AtomicU64,
+    sites: [SiteCounters; MAX_SITES],
+    /// Contended acquisitions from call sites that found no free slot
+    other_sites: AtomicU64,
+}
+
+impl ClassCounters {
+    const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_cycles: AtomicU64::new(0),
+            max_wait_cycles: AtomicU64::new(0),
+            sites: [const { SiteCounters::new() }; MAX_SITES],
+            other_sites: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, caller: &'static Location<'static>, wait_cycles: Option<u64>) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let Some(wait) = wait_cycles else { return };
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_cycles.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait_cycles.fetch_max(wait, Ordering::Relaxed);
+
+        match self.site(caller) {
+            Some(site) => {
+                site.contended.fetch_add(1, Ordering::Relaxed);
+                site.wait_cycles.fetch_add(wait, Ordering::Relaxed);
+            }
+            None => {
+                self.other_sites.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Slot of a call site, claiming a free one on first contention
+    fn site(&self, caller: &'static Location<'static>) -> Option<&SiteCounters> {
+        let wanted = caller as *const Location<'static> as *mut Location<'static>;
+        for site in &self.sites {
+            let location = site.location.load(Ordering::Acquire);
+            if location == wanted {
+                return Some(site);
+            }
+            if location.is_null() {
+                match site.location.compare_exchange(
+                    core::ptr::null_mut(),
+                    wanted,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Some(site),
+                    // Another CPU claimed the slot, maybe for the same site
+                    Err(location) if location == wanted => return Some(site),
+                    Err(_) => {}
+                }
+            }
+        }
+        None
+    }
+
+    fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.wait_cycles.store(0, Ordering::Relaxed);
+        self.max_wait_cycles.store(0, Ordering::Relaxed);
+        for site in &self.sites {
+            site.location.store(core::ptr::null_mut(), Ordering::Relaxed);
+            site.contended.store(0, Ordering::Relaxed);
+            site.wait_cycles.store(0, Ordering::Relaxed);
+        }
+        self.other_sites.store(0, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, name: &'static str) -> ClassStats {
+        let mut sites: Vec<SiteStats> = self
+            .sites
+            .iter()
+            .filter_map(|site| {
+                let location = site.location.load(Ordering::Acquire);
+                (!location.is_null()).then(|| SiteStats {
+                    location: unsafe { &*location },
+                    contended: site.contended.load(Ordering::Relaxed),
+                    wait_cycles: site.wait_cycles.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        sites.sort_by_key(|site| core::cmp::Reverse(site.wait_cycles));
+
+        ClassStats {
+            name,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait_cycles: self.wait_cycles.load(Ordering::Relaxed),
+            max_wait_cycles: self.max_wait_cycles.load(Ordering::Relaxed),
+            sites,
+            other_sites: self.other_sites.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Contention from one call site
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiteStats {
+    pub location: &'static Location<'static>,
+    /// Acquisitions from here that waited
+    pub contended: u64,
+    /// TSC cycles they waited in total
+    pub wait_cycles: u64,
+}
+
+/// Contention of one lock class
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassStats {
+    pub name: &'static str,
+    pub acquisitions: u64,
+    /// Acquisitions that found the lock held and waited
+    pub contended: u64,
+    /// TSC cycles spent waiting, in total and at most at once
+    pub wait_cycles: u64,
+    pub max_wait_cycles: u64,
+    /// Call sites that waited, longest total wait first
+    pub sites: Vec<SiteStats>,
+    /// Contended acquisitions from call sites not in `sites`
+    pub other_sites: u64,
+}
+
+impl ClassStats {
+    /// Average wait of a contended acquisition in TSC cycles
+    pub fn average_wait_cycles(&self) -> u64 {
+        self.wait_cycles.checked_div(self.contended).unwrap_or(0)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static COUNTERS: [ClassCounters; MAX_CLASSES] = [const { ClassCounters::new() }; MAX_CLASSES];
+
+/// Start recording lock contention
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop recording lock contention
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Check whether lock contention is recorded
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Clear all counters and call sites
+pub fn reset() {
+    for counters in &COUNTERS {
+        counters.reset();
+    }
+}
+
+/// Timestamp taken before waiting for a lock of `class`, if recording
+#[inline]
+pub fn wait_start(class: Option<&'static LockClass>) -> Option<u64> {
+    (class.is_some() && is_enabled()).then(tsc::rdtsc)
+}
+
+/// Record an acquisition of a lock of `class` from `caller`
+///
+/// `start` is what `wait_start` returned; `waited` tells whether the lock
+/// was held when the CPU got there.
+#[inline]
+pub fn lock_acquired(
+    class: Option<&'static LockClass>,
+    caller: &'static Location<'static>,
+    start: Option<u64>,
+    waited: bool,
+) {
+    let (Some(class), Some(start)) = (class, start) else { return };
+    let Some(index) = class.index() else { return };
+    let wait_cycles = waited.then(|| tsc::rdtsc().saturating_sub(start));
+    COUNTERS[index as usize].record(caller, wait_cycles);
+}
+
+/// Statistics of all classes whose locks were taken, most waited for first
+pub fn stats() -> Vec<ClassStats> {
+    let mut classes: Vec<ClassStats> = (0..lockdep::class_count())
+        .map(|index| COUNTERS[index].snapshot(lockdep::class_name(index as u8)))
+        .filter(|class| class.acquisitions > 0)
+        .collect();
+    classes.sort_by(|a, b| b.wait_cycles.cmp(&a.wait_cycles).then(b.contended.cmp(&a.contended)));
+    classes
+}
+
+/// Write a duration of `cycles` in microseconds, or in cycles without a
+/// known TSC frequency
+fn write_duration(text: &mut String, cycles: u64, tsc_khz: Option<u64>) {
+    let _ = match tsc_khz {
+        Some(khz) if khz > 0 => write!(text, "{:>10}", cycles.saturating_mul(1000) / khz),
+        _ => write!(text, "{:>10}", cycles),
+    };
+}
+
+/// The `lockstat` report
+///
+/// Wait times are in microseconds if `tsc_khz` is known, in TSC cycles
+/// otherwise.
+pub fn format_report(classes: &[ClassStats], tsc_khz: Option<u64>) -> String {
+    let unit = if tsc_khz.is_some() { "us" } else { "cycles" };
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "{:<16} {:>10} {:>10} {:>10} {:>10} {:>10}   (wait in {})",
+        "CLASS", "ACQUIRED", "CONTENDED", "WAIT", "AVG", "MAX", unit
+    );
+    for class in classes {
+        let _ = write!(text, "{:<16} {:>10} {:>10} ", class.name, class.acquisitions, class.contended);
+        write_duration(&mut text, class.wait_cycles, tsc_khz);
+        text.push(' ');
+        write_duration(&mut text, class.average_wait_cycles(), tsc_khz);
+        text.push(' ');
+        write_duration(&mut text, class.max_wait_cycles, tsc_khz);
+        text.push('\n');
+
+        for site in class.sites.iter().take(REPORT_SITES) {
+            let _ = write!(
+                text,
+                "    {}:{} {:>10} ",
+                site.location.file(),
+                site.location.line(),
+                site.contended
+            );
+            write_duration(&mut text, site.wait_cycles, tsc_khz);
+            text.push('\n');
+        }
+        if class.other_sites > 0 {
+            let _ = writeln!(text, "    (other sites) {:>10}", class.other_sites);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn here() -> &'static Location<'static> {
+        Location::caller()
+    }
+
+    #[test]
+    fn test_class_counters() {
+        let counters = ClassCounters::new();
+        let a = here();
+        let b = here();
+
+        counters.record(a, None);
+        counters.record(a, Some(100));
+        counters.record(b, Some(500));
+        counters.record(a, Some(50));
+
+        let stats = counters.snapshot("test");
+        assert_eq!((stats.acquisitions, stats.contended), (4, 3));
+        assert_eq!((stats.wait_cycles, stats.max_wait_cycles), (650, 500));
+        assert_eq!(stats.average_wait_cycles(), 216);
+        // Longest total wait first
+        assert_eq!(stats.sites.len(), 2);
+        assert_eq!((stats.sites[0].location, stats.sites[0].contended), (b, 1));
+        assert_eq!((stats.sites[1].location, stats.sites[1].wait_cycles), (a, 150));
+
+        counters.reset();
+        let stats = counters.snapshot("test");
+        assert_eq!((stats.acquisitions, stats.sites.len()), (0, 0));
+    }
+
+    #[test]
+    fn test_sites_overflow() {
+        let counters = ClassCounters::new();
+        let sites = [here(), here(), here(), here(), here(), here(), here(), here(), here(), here()];
+        for site in sites {
+            counters.record(site, Some(1));
+        }
+        let stats = counters.snapshot("test");
+        assert_eq!(stats.sites.len(), MAX_SITES);
+        assert_eq!(stats.other_sites, 2);
+        assert_eq!(stats.contended, 10);
+    }
+
+    #[test]
+    fn test_format_report() {
+        let site = here();
+        let class = ClassStats {
+            name: "runqueue",
+            acquisitions: 10,
+            contended: 2,
+            wait_cycles: 6_000,
+            max_wait_cycles: 4_000,
+            sites: alloc::vec![SiteStats { location: site, contended: 2, wait_cycles: 6_000 }],
+            other_sites: 0,
+        };
+        let text = format_report(&[class], Some(2_000_000));
+        let mut lines = text.lines();
+        assert!(lines.next().unwrap().ends_with("(wait in us)"));
+        let row: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+        assert_eq!(row, ["runqueue", "10", "2", "3", "1", "2"]);
+        assert!(lines.next().unwrap().trim_start().starts_with(site.file()));
+    }
+}
@@ -4,21 +4,45 @@
 //! - CPU detection and enumeration
 //! - Per-CPU data structures
 //! - Application Processor (AP) startup
+//! - CPU hotplug (taking CPUs offline and back online)
 //! - Inter-Processor Interrupts (IPI)
-//! - CPU-local storage
-//! - SMP-safe synchronization primitives
+//! - Reschedule IPIs and cross-CPU wakeups
+//! - TLB shootdown
+//! - GS-based CPU-local storage (`percpu!`)
+//! - SMP-safe synchronization primitives (IRQ-safe ticket and MCS spinlocks)
+//! - Lock order checking (lockdep)
+//! - Lock contention statistics (lockstat)
+//! - Read-copy-update (RCU)
+//! - ACPI MADT parsing and IOAPIC interrupt routing
 
+pub mod ap;
 pub mod cpu;
+pub mod hotplug;
 pub mod percpu;
 pub mod ipi;
+pub mod resched;
 pub mod spinlock;
+pub mod mcs;
+pub mod lockdep;
+pub mod lockstat;
+pub mod rcu;
 pub mod acpi;
+pub mod ioapic;
+pub mod tlb;
 
+pub use ap::{ApBootInfo, ApDescriptor};
 pub use cpu::{CpuId, CpuInfo, CpuState, CpuManager, current_cpu_id, cpu_count};
+pub use hotplug::{cpu_down, cpu_up};
 pub use percpu::{PerCpu, PerCpuData};
 pub use ipi::{Ipi, IpiType, IpiTarget, send_ipi};
-pub use spinlock::SpinLock;
-pub use acpi::AcpiInfo;
+pub use resched::resched_cpu;
+pub use spinlock::{LockStats, SpinLock, SpinLockGuard, SpinLockIrqGuard};
+pub use mcs::{McsLock, McsNode};
+pub use lockdep::LockClass;
+pub use rcu::{RcuCell, call_rcu, rcu_read, synchronize_rcu};
+pub use acpi::{AcpiInfo, acpi_info};
+pub use ioapic::route_pci_irq;
+pub use tlb::{FlushRange, flush_page, flush_range, flush_all};
 
 use spin::Once;
 
@@ -30,9 +54,17 @@ pub fn init() -> Result<(), &'static str> {
     // Initialize CPU manager
     let manager = CpuManager::new();
     CPU_MANAGER.call_once(|| spin::Mutex::new(manager));
+    cpu::set_current_cpu_id(CpuId::new(0));
+    unsafe {
+        fanga_arch_x86_64::interrupts::idt::set_ipi_callback(ipi::handle_ipi);
+    }
     
     // Detect and enumerate CPUs
     detect_cpus()?;
+
+    // Check lock order in debug builds, before other CPUs take locks
+    #[cfg(debug_assertions)]
+    lockdep::enable();
     
     Ok(())
 }
@@ -48,8 +80,19 @@ pub fn cpu_manager() -> &'static spin::Mutex<CpuManager> {
     CPU_MANAGER.get().expect("CPU manager not initialized")
 }
 
-/// Initialize Application Processors (APs)
-pub fn start_application_processors() -> Result<(), &'static str> {
-    let mut manager = cpu_manager().lock();
-    manager.start_aps()
+/// Get the CPU manager, or None before `init()`
+pub fn try_cpu_manager() -> Option<&'static spin::Mutex<CpuManager>> {
+    CPU_MANAGER.get()
+}
+
+/// Start the Application Processors (APs)
+///
+/// `aps` lists every processor except the boot CPU and `launch` releases
+/// one of them (see `ap::start_aps_in`). Returns the number of online CPUs.
+pub fn start_application_processors(
+    aps: &[ApDescriptor],
+    launch: impl FnMut(&ApDescriptor),
+) -> Result<usize, &'static str> {
+    let cr3 = crate::memory::PageTableMapper::current_cr3();
+    ap::start_aps_in(cpu_manager(), aps, cr3, ap::AP_BOOT_TIMEOUT_MS, launch)
 }
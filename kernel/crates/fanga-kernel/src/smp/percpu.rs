@@ -1,19 +1,33 @@
 //! Per-CPU Data Structures
 //!
-//! This module provides CPU-local storage for per-CPU data.
+//! This module provides CPU-local storage for per-CPU data. Each CPU's GS
+//! base points at its `PerCpuData` block, so the running CPU's data is
+//! reached without locks or lookups; `percpu!` reads and writes fields.
+//! Until GS is set up on the boot CPU, accesses fall back to indexing
+//! by CPU ID.
 
 use super::CpuId;
 
 /// Per-CPU data structure
 ///
-/// This structure holds data that is unique to each CPU.
-/// In a real implementation, this would use GS segment register
-/// or similar mechanism for fast access.
+/// This structure holds data that is unique to each CPU. The layout is
+/// fixed: GS-relative loads read `this` and `cpu_id` directly, and the
+/// system call entry uses the two stack words that follow.
 #[derive(Debug)]
+#[repr(C)]
 pub struct PerCpuData {
+    /// Address of this block (read through `gs:[0]`)
+    pub this: u64,
+    
     /// CPU ID
     pub cpu_id: CpuId,
     
+    /// User stack pointer, parked here during system call entry
+    pub user_rsp: u64,
+    
+    /// Kernel stack top system calls switch to
+    pub kernel_rsp: u64,
+    
     /// Current task running on this CPU
     pub current_task: Option<usize>,
     
@@ -34,7 +48,10 @@ impl PerCpuData {
     /// Create a new per-CPU data structure
     pub const fn new(cpu_id: CpuId) -> Self {
         Self {
+            this: 0,
             cpu_id,
+            user_rsp: 0,
+            kernel_rsp: 0,
             current_task: None,
             interrupt_depth: 0,
             preempt_count: 0,
@@ -77,11 +94,26 @@ static mut PER_CPU_DATA: [UnsafeCell<PerCpuData>; MAX_CPUS] = {
     [INIT; MAX_CPUS]
 };
 
+/// Offsets of fields read with a single GS-relative load
+const THIS_OFFSET: usize = core::mem::offset_of!(PerCpuData, this);
+const CPU_ID_OFFSET: usize = core::mem::offset_of!(PerCpuData, cpu_id);
+
+// The system call entry hardcodes these
+const _: () = assert!(core::mem::offset_of!(PerCpuData, user_rsp) == fanga_arch_x86_64::percpu::GS_USER_RSP);
+const _: () = assert!(core::mem::offset_of!(PerCpuData, kernel_rsp) == fanga_arch_x86_64::percpu::GS_KERNEL_RSP);
+
+/// Size of the kernel stack each CPU gets for system calls and entries
+/// from user mode, until tasks bring their own
+pub const CPU_KERNEL_STACK_SIZE: usize = 16 * 4096;
+
 /// Initialize per-CPU data for a specific CPU
 pub fn init_percpu_data(cpu_id: CpuId) {
     unsafe {
         let data = &mut *PER_CPU_DATA[cpu_id.as_usize()].get();
+        data.this = data as *mut PerCpuData as u64;
         data.cpu_id = cpu_id;
+        data.user_rsp = 0;
+        data.kernel_rsp = 0;
         data.current_task = None;
         data.interrupt_depth = 0;
         data.preempt_count = 0;
@@ -90,15 +122,76 @@ pub fn init_percpu_data(cpu_id: CpuId) {
     }
 }
 
-/// Get the current CPU's per-CPU data
-pub fn current_cpu_data() -> &'static mut PerCpuData {
-    let cpu_id = super::current_cpu_id();
+/// Initialize the running CPU's per-CPU data and point GS at it
+///
+/// Must be the first thing a CPU does once it knows its ID.
+pub fn load_percpu(cpu_id: CpuId) {
+    init_percpu_data(cpu_id);
+    #[cfg(not(test))]
+    unsafe {
+        let block = PER_CPU_DATA[cpu_id.as_usize()].get() as u64;
+        fanga_arch_x86_64::percpu::init_cpu(cpu_id.as_usize(), block);
+        if fanga_arch_x86_64::gdt::kernel_stack(cpu_id.as_usize()) == 0 {
+            fanga_arch_x86_64::gdt::set_kernel_stack(cpu_id.as_usize(), alloc_kernel_stack());
+        }
+    }
+}
+
+/// Allocate a CPU's kernel stack, returning its 16-byte aligned top
+#[cfg(not(test))]
+fn alloc_kernel_stack() -> u64 {
+    let stack = alloc::vec![0u8; CPU_KERNEL_STACK_SIZE].leak();
+    (stack.as_ptr() as u64 + CPU_KERNEL_STACK_SIZE as u64) & !0xF
+}
+
+/// Get the running CPU's ID from its per-CPU block
+///
+/// Returns None until GS is set up.
+#[inline]
+pub fn gs_cpu_id() -> Option<CpuId> {
+    if !fanga_arch_x86_64::percpu::gs_ready() {
+        return None;
+    }
+    Some(CpuId::new(unsafe { fanga_arch_x86_64::percpu::read_gs(CPU_ID_OFFSET) } as usize))
+}
+
+/// Get a pointer to the running CPU's per-CPU data
+#[inline]
+pub fn this_cpu_ptr() -> *mut PerCpuData {
+    if fanga_arch_x86_64::percpu::gs_ready() {
+        return unsafe { fanga_arch_x86_64::percpu::read_gs(THIS_OFFSET) } as *mut PerCpuData;
+    }
+    let idx = super::current_cpu_id().as_usize();
     // Bounds check to prevent array out of bounds
-    let idx = cpu_id.as_usize();
     if idx >= MAX_CPUS {
         panic!("Invalid CPU ID: {}", idx);
     }
-    unsafe { &mut *PER_CPU_DATA[idx].get() }
+    unsafe { PER_CPU_DATA[idx].get() }
+}
+
+/// Access a field of the running CPU's per-CPU data
+///
+/// `percpu!(field)` reads it, `percpu!(field = value)` writes it and
+/// `percpu!(field += value)` adds to it. Fields also touched by interrupt
+/// handlers must be updated with interrupts disabled.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        unsafe { (*$crate::smp::percpu::this_cpu_ptr()).$field }
+    };
+    ($field:ident = $value:expr) => {{
+        let value = $value;
+        unsafe { (*$crate::smp::percpu::this_cpu_ptr()).$field = value }
+    }};
+    ($field:ident += $value:expr) => {{
+        let value = $value;
+        unsafe { (*$crate::smp::percpu::this_cpu_ptr()).$field += value }
+    }};
+}
+
+/// Get the current CPU's per-CPU data
+pub fn current_cpu_data() -> &'static mut PerCpuData {
+    unsafe { &mut *this_cpu_ptr() }
 }
 
 /// Get a specific CPU's per-CPU data
@@ -143,4 +236,23 @@ mod tests {
         data.preempt_count = 0;
         assert!(data.preemptible());
     }
+    
+    #[test]
+    fn test_block_layout() {
+        assert_eq!(THIS_OFFSET, 0);
+        assert_eq!(CPU_ID_OFFSET, 8);
+        
+        init_percpu_data(CpuId::new(7));
+        let data: *mut PerCpuData = get_cpu_data(CpuId::new(7)).unwrap();
+        assert_eq!(unsafe { (*data).this }, data as u64);
+    }
+    
+    #[test]
+    fn test_percpu_macro() {
+        // Without GS this is the boot CPU's block
+        let before = percpu!(total_ticks);
+        percpu!(total_ticks += 2);
+        assert!(percpu!(total_ticks) >= before + 2);
+        assert_eq!(percpu!(cpu_id), current_cpu_data().cpu_id);
+    }
 }
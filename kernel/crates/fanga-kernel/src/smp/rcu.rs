@@ -0,0 +1,425 @@
+//! Read-Copy-Update
+//!
+//! Readers of an RCU-protected structure take no lock: they mark a read
+//! section with `rcu_read_lock()` (or `rcu_read()`) and follow the current
+//! pointer. Writers build a new copy, publish it with one atomic store and
+//! free the old copy only after a grace period, once every CPU that could
+//! still see it has passed a quiescent state.
+//!
+//! Read sections are not preemptible, so a CPU that context switches, or
+//! sits in its idle loop, is outside any read section. Each CPU records the
+//! grace period it last saw at such a point; a grace period ends when every
+//! CPU taking part in RCU has recorded it. CPUs join on their first
+//! quiescent state; hotplug takes them out with `rcu_cpu_offline()` and
+//! back with `rcu_cpu_online()`.
+//!
+//! This module provides:
+//! - Read sections (`rcu_read_lock`/`rcu_read_unlock`, `RcuReadGuard`)
+//! - Quiescent state reporting from the scheduler and the idle loop
+//! - `synchronize_rcu` and deferred callbacks (`call_rcu`)
+//! - `RcuCell`, an RCU-protected pointer
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::cpu::{current_cpu_id, CpuId, MAX_CPUS};
+use crate::preempt::{preempt_disable, preempt_enable};
+use crate::task::softirq::{self, SoftirqType};
+
+/// A callback deferred until the end of a grace period
+pub type RcuCallback = Box<dyn FnOnce() + Send>;
+
+/// Callbacks run per softirq pass; the rest wait for the next tick
+const RCU_BATCH: usize = 64;
+
+/// Grace period tracking for a set of CPUs
+pub struct RcuState {
+    /// Last grace period started
+    gp_seq: AtomicU64,
+    /// Last grace period completed
+    completed: AtomicU64,
+    /// CPUs taking part, one bit per CPU
+    online: [AtomicU64; MAX_CPUS / 64],
+    /// Offline CPUs, which do not join on a quiescent state
+    offline: [AtomicU64; MAX_CPUS / 64],
+    /// Grace period each CPU saw at its last quiescent state
+    qs_seq: [AtomicU64; MAX_CPUS],
+    /// Read section nesting per CPU
+    read_depth: [AtomicUsize; MAX_CPUS],
+    /// Deferred callbacks with the grace period they wait for
+    callbacks: Mutex<VecDeque<(u64, RcuCallback)>>,
+}
+
+impl RcuState {
+    /// Create RCU state with no CPUs and no grace period
+    pub const fn new() -> Self {
+        Self {
+            gp_seq: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            online: [const { AtomicU64::new(0) }; MAX_CPUS / 64],
+            offline: [const { AtomicU64::new(0) }; MAX_CPUS / 64],
+            qs_seq: [const { AtomicU64::new(0) }; MAX_CPUS],
+            read_depth: [const { AtomicUsize::new(0) }; MAX_CPUS],
+            callbacks: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Enter a read section on a CPU
+    pub fn read_lock(&self, cpu: usize) {
+        self.read_depth[cpu].fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Leave a read section on a CPU
+    pub fn read_unlock(&self, cpu: usize) {
+        let depth = self.read_depth[cpu].fetch_sub(1, Ordering::SeqCst);
+        debug_assert!(depth > 0, "rcu_read_unlock without rcu_read_lock");
+    }
+
+    /// Check whether a CPU is inside a read section
+    pub fn in_read_section(&self, cpu: usize) -> bool {
+        self.read_depth[cpu].load(Ordering::SeqCst) > 0
+    }
+
+    /// Report a quiescent state of a CPU
+    ///
+    /// Ignored inside a read section and on offline CPUs. The first report
+    /// adds the CPU to the CPUs grace periods wait for.
+    pub fn quiescent(&self, cpu: usize) {
+        let bit = 1 << (cpu % 64);
+        if self.in_read_section(cpu) || self.offline[cpu / 64].load(Ordering::SeqCst) & bit != 0 {
+            return;
+        }
+        self.online[cpu / 64].fetch_or(bit, Ordering::SeqCst);
+        self.qs_seq[cpu].store(self.gp_seq.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    /// Stop waiting for a CPU (it is going offline)
+    pub fn offline(&self, cpu: usize) {
+        self.offline[cpu / 64].fetch_or(1 << (cpu % 64), Ordering::SeqCst);
+        self.online[cpu / 64].fetch_and(!(1 << (cpu % 64)), Ordering::SeqCst);
+    }
+
+    /// Let an offline CPU take part again from its next quiescent state
+    pub fn online(&self, cpu: usize) {
+        self.offline[cpu / 64].fetch_and(!(1 << (cpu % 64)), Ordering::SeqCst);
+    }
+
+    /// Last grace period completed
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    /// Start a grace period unless one is running; returns the current one
+    pub fn start_gp(&self) -> u64 {
+        let completed = self.completed();
+        let _ = self.gp_seq.compare_exchange(completed, completed + 1, Ordering::SeqCst, Ordering::SeqCst);
+        self.gp_seq.load(Ordering::SeqCst)
+    }
+
+    /// CPUs the running grace period still waits for
+    pub fn lagging(&self) -> impl Iterator<Item = usize> + '_ {
+        let gp = self.gp_seq.load(Ordering::SeqCst);
+        (0..MAX_CPUS).filter(move |&cpu| {
+            self.online[cpu / 64].load(Ordering::SeqCst) & (1 << (cpu % 64)) != 0
+                && self.qs_seq[cpu].load(Ordering::SeqCst) < gp
+        })
+    }
+
+    /// Complete the running grace period if every CPU passed it
+    ///
+    /// Returns the last completed grace period.
+    pub fn poll(&self) -> u64 {
+        let gp = self.gp_seq.load(Ordering::SeqCst);
+        let completed = self.completed();
+        if gp != completed && self.lagging().next().is_none() {
+            let _ = self.completed.compare_exchange(completed, gp, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        self.completed()
+    }
+
+    /// Queue a callback for after the next full grace period
+    ///
+    /// A grace period already running may have started after readers that
+    /// can still see the old data, so the callback waits for the next one.
+    pub fn call(&self, callback: RcuCallback) {
+        let target = self.gp_seq.load(Ordering::SeqCst) + 1;
+        self.callbacks.lock().push_back((target, callback));
+    }
+
+    /// Number of queued callbacks
+    pub fn pending(&self) -> usize {
+        self.callbacks.lock().len()
+    }
+
+    /// Run up to `max` callbacks whose grace period completed
+    ///
+    /// Starts a grace period if callbacks are left waiting. Callbacks run
+    /// without the queue lock held. Returns the number run.
+    pub fn process(&self, max: usize) -> usize {
+        let completed = self.poll();
+        let ready: Vec<RcuCallback> = {
+            let mut callbacks = self.callbacks.lock();
+            let count = callbacks.iter().take(max).take_while(|(target, _)| *target <= completed).count();
+            callbacks.drain(..count).map(|(_, callback)| callback).collect()
+        };
+
+        let run = ready.len();
+        for callback in ready {
+            callback();
+        }
+        if self.pending() > 0 {
+            self.start_gp();
+        }
+        run
+    }
+}
+
+impl Default for RcuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// System-wide RCU state
+static RCU: RcuState = RcuState::new();
+
+/// Register the RCU softirq
+pub fn init() {
+    softirq::open_softirq(SoftirqType::Rcu, rcu_softirq);
+}
+
+fn rcu_softirq() {
+    RCU.process(RCU_BATCH);
+    kick_lagging();
+}
+
+/// Make CPUs that hold up the grace period reschedule
+fn kick_lagging() {
+    let this_cpu = current_cpu_id().as_usize();
+    for cpu in RCU.lagging().filter(|&cpu| cpu != this_cpu) {
+        // A lost IPI only delays the grace period to the CPU's next switch
+        let _ = super::resched::resched_cpu(CpuId::new(cpu));
+    }
+}
+
+/// Enter a read section
+///
+/// Disables preemption until the matching `rcu_read_unlock()`. Read
+/// sections nest and must not sleep.
+pub fn rcu_read_lock() {
+    preempt_disable();
+    RCU.read_lock(current_cpu_id().as_usize());
+}
+
+/// Leave a read section
+pub fn rcu_read_unlock() {
+    RCU.read_unlock(current_cpu_id().as_usize());
+    preempt_enable();
+}
+
+/// A read section, ended when dropped
+pub struct RcuReadGuard {
+    /// Must be dropped on the CPU it was taken on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        rcu_read_unlock();
+    }
+}
+
+/// Enter a read section for the lifetime of the returned guard
+pub fn rcu_read() -> RcuReadGuard {
+    rcu_read_lock();
+    RcuReadGuard { _not_send: PhantomData }
+}
+
+/// Report that this CPU is at a context switch
+///
+/// Called by the scheduler for every scheduling decision, switch or not.
+pub fn rcu_note_context_switch() {
+    RCU.quiescent(current_cpu_id().as_usize());
+}
+
+/// Report that this CPU is idle
+pub fn rcu_idle() {
+    let cpu = current_cpu_id().as_usize();
+    RCU.quiescent(cpu);
+    if cpu == 0 && RCU.pending() > 0 {
+        softirq::raise_softirq(SoftirqType::Rcu);
+    }
+}
+
+/// Tick hook: process callbacks from the RCU softirq while any are queued
+pub fn rcu_check_callbacks() {
+    if RCU.pending() > 0 {
+        softirq::raise_softirq(SoftirqType::Rcu);
+    }
+}
+
+/// Stop waiting for an offline CPU in grace periods
+pub fn rcu_cpu_offline(cpu: usize) {
+    RCU.offline(cpu);
+}
+
+/// Wait for a CPU coming back online in grace periods again
+pub fn rcu_cpu_online(cpu: usize) {
+    RCU.online(cpu);
+}
+
+/// Wait until all read sections that started before the call have ended
+///
+/// Spins, sending reschedule IPIs to CPUs that hold up the grace period.
+/// Must not be called inside a read section or with the scheduler lock
+/// held, since other CPUs must be able to switch tasks.
+pub fn synchronize_rcu() {
+    let cpu = current_cpu_id().as_usize();
+    debug_assert!(!RCU.in_read_section(cpu), "synchronize_rcu inside a read section");
+
+    // The grace period after any already running
+    let target = RCU.gp_seq.load(Ordering::SeqCst) + 1;
+    let mut kicked = 0;
+    while RCU.poll() < target {
+        RCU.quiescent(cpu);
+        let gp = RCU.start_gp();
+        if kicked != gp {
+            kick_lagging();
+            kicked = gp;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `callback` after a grace period, without waiting
+pub fn call_rcu(callback: impl FnOnce() + Send + 'static) {
+    RCU.call(Box::new(callback));
+}
+
+/// An RCU-protected pointer to a `T`
+///
+/// Readers borrow the current value within a read section; `replace`
+/// publishes a new value and frees the old one after a grace period.
+/// Writers must serialize among themselves.
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    /// Create an empty cell
+    pub const fn empty() -> Self {
+        Self { ptr: AtomicPtr::new(core::ptr::null_mut()) }
+    }
+
+    /// Create a cell holding `value`
+    pub fn new(value: T) -> Self {
+        Self { ptr: AtomicPtr::new(Box::into_raw(Box::new(value))) }
+    }
+
+    /// Borrow the current value for the length of a read section
+    pub fn read<'a>(&self, _guard: &'a RcuReadGuard) -> Option<&'a T> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Publish a new value; the old one is dropped after a grace period
+    pub fn replace(&self, value: T) {
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        if !old.is_null() {
+            let old = OldValue(old);
+            call_rcu(move || drop(unsafe { Box::from_raw(old.into_raw()) }));
+        }
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+/// A replaced value travelling to its deferred free
+struct OldValue<T>(*mut T);
+
+unsafe impl<T: Send> Send for OldValue<T> {}
+
+impl<T> OldValue<T> {
+    fn into_raw(self) -> *mut T {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_grace_period_waits_for_readers() {
+        let rcu = RcuState::new();
+        rcu.quiescent(0);
+        rcu.quiescent(1);
+
+        rcu.read_lock(1);
+        let gp = rcu.start_gp();
+        rcu.quiescent(0);
+        rcu.quiescent(1);
+        assert!(rcu.poll() < gp);
+        assert_eq!(rcu.lagging().collect::<Vec<_>>(), alloc::vec![1]);
+
+        rcu.read_unlock(1);
+        rcu.quiescent(1);
+        assert_eq!(rcu.poll(), gp);
+    }
+
+    #[test]
+    fn test_offline_cpu_does_not_block() {
+        let rcu = RcuState::new();
+        rcu.quiescent(0);
+        rcu.quiescent(70);
+        let gp = rcu.start_gp();
+        rcu.quiescent(0);
+        assert!(rcu.poll() < gp);
+
+        rcu.offline(70);
+        assert_eq!(rcu.poll(), gp);
+
+        // An offline CPU does not rejoin until it is online again
+        rcu.quiescent(70);
+        assert_eq!(rcu.lagging().count(), 0);
+        rcu.online(70);
+        rcu.quiescent(70);
+        rcu.start_gp();
+        assert_eq!(rcu.lagging().collect::<Vec<_>>(), alloc::vec![0, 70]);
+    }
+
+    #[test]
+    fn test_callbacks_after_grace_period() {
+        let rcu = RcuState::new();
+        let freed = Arc::new(AtomicBool::new(false));
+        rcu.quiescent(0);
+
+        let flag = freed.clone();
+        rcu.call(Box::new(move || flag.store(true, Ordering::SeqCst)));
+
+        // The first pass only starts the grace period
+        assert_eq!(rcu.process(RCU_BATCH), 0);
+        assert!(!freed.load(Ordering::SeqCst));
+
+        rcu.quiescent(0);
+        assert_eq!(rcu.process(RCU_BATCH), 1);
+        assert!(freed.load(Ordering::SeqCst));
+        assert_eq!(rcu.pending(), 0);
+    }
+}
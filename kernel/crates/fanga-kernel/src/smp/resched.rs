@@ -0,0 +1,150 @@
+//! Reschedule IPIs
+//!
+//! A CPU only re-enters the scheduler at a preemption point: the return
+//! from an interrupt (its timer tick or a reschedule IPI), the end of an
+//! idle halt or a `cond_resched`. When one CPU makes
+//! work for another - waking a task that should preempt what the other CPU
+//! runs, changing a running task's affinity, or pushing tasks to it while
+//! balancing load - it sets the target's need-resched flag and sends it a
+//! reschedule IPI. Requests to a CPU that has not taken its IPI yet are
+//! coalesced into the one already in flight.
+//!
+//! This module provides:
+//! - Reschedule requests for any CPU
+//! - The reschedule IPI handler
+//! - Wakeup preemption target selection
+//! - IPI statistics
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::cpu::{current_cpu_id, CpuId, MAX_CPUS};
+use super::ipi::{self, Ipi, IpiTarget, IpiType};
+use crate::preempt::points::{set_need_resched, set_need_resched_cpu};
+use crate::task::TaskPriority;
+
+/// Set while a reschedule IPI to a CPU is in flight
+static IPI_PENDING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+static IPIS_SENT: AtomicU64 = AtomicU64::new(0);
+static IPIS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static IPIS_COALESCED: AtomicU64 = AtomicU64::new(0);
+
+/// Reschedule IPI statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReschedStats {
+    /// Reschedule IPIs sent
+    pub sent: u64,
+    /// Reschedule IPIs handled
+    pub received: u64,
+    /// Requests folded into an IPI already in flight
+    pub coalesced: u64,
+}
+
+/// Get reschedule IPI statistics
+pub fn stats() -> ReschedStats {
+    ReschedStats {
+        sent: IPIS_SENT.load(Ordering::Relaxed),
+        received: IPIS_RECEIVED.load(Ordering::Relaxed),
+        coalesced: IPIS_COALESCED.load(Ordering::Relaxed),
+    }
+}
+
+/// Make a CPU re-enter the scheduler promptly
+///
+/// On this CPU only the need-resched flag is set; another CPU also gets a
+/// reschedule IPI unless one is already pending.
+pub fn resched_cpu(cpu: CpuId) -> Result<(), &'static str> {
+    if cpu == current_cpu_id() {
+        set_need_resched();
+        return Ok(());
+    }
+    let pending = IPI_PENDING.get(cpu.as_usize()).ok_or("Invalid CPU ID")?;
+
+    set_need_resched_cpu(cpu);
+    if pending.swap(true, Ordering::AcqRel) {
+        IPIS_COALESCED.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    match ipi::send_ipi(Ipi::new(IpiType::Reschedule, IpiTarget::Cpu(cpu))) {
+        Ok(()) => {
+            IPIS_SENT.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            pending.store(false, Ordering::Release);
+            Err(e)
+        }
+    }
+}
+
+/// Reschedule IPI handler
+pub fn handle_reschedule_ipi() {
+    let cpu = current_cpu_id();
+    if let Some(pending) = IPI_PENDING.get(cpu.as_usize()) {
+        pending.store(false, Ordering::Release);
+    }
+    IPIS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+
+    // The switch happens on the way out of the IPI
+    set_need_resched();
+}
+
+/// Choose the CPU a woken task should preempt
+///
+/// Used by the scheduler to place tasks that become ready. `running` lists
+/// each candidate CPU with the priority of the task it runs (None if it is
+/// idle) and its number of queued tasks. Idle CPUs win over busy ones,
+/// then the CPU running the lowest priority below `priority`; shorter
+/// queues and then `this_cpu` break ties.
+pub fn preempt_target(
+    priority: TaskPriority,
+    this_cpu: usize,
+    running: &[(usize, Option<TaskPriority>, usize)],
+) -> Option<usize> {
+    running
+        .iter()
+        .filter(|(_, current, _)| current.is_none_or(|p| p < priority))
+        .min_by_key(|&&(cpu, current, queued)| {
+            (current.map_or(0, |p| p as usize + 1), queued, cpu != this_cpu)
+        })
+        .map(|&(cpu, _, _)| cpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preempt_target() {
+        use TaskPriority::*;
+
+        // An idle CPU is preferred over preempting anything, the one with
+        // less queued work first
+        let running = [(0, Some(Low), 0), (1, None, 0), (2, None, 0), (3, None, 1)];
+        assert_eq!(preempt_target(Normal, 0, &running), Some(1));
+        assert_eq!(preempt_target(Normal, 2, &running), Some(2));
+        assert_eq!(preempt_target(Normal, 3, &running), Some(1));
+
+        // Otherwise the least important task below the woken one
+        let running = [(0, Some(Normal), 0), (1, Some(Low), 0), (2, Some(High), 0)];
+        assert_eq!(preempt_target(High, 0, &running), Some(1));
+        assert_eq!(preempt_target(Normal, 0, &running), Some(1));
+        assert_eq!(preempt_target(Low, 0, &running), None);
+    }
+
+    #[test]
+    fn test_resched_coalesces() {
+        // An IPI to CPU 9 is already in flight, so no new one is sent
+        IPI_PENDING[9].store(true, Ordering::Relaxed);
+        let before = stats();
+        resched_cpu(CpuId::new(9)).unwrap();
+        assert!(stats().coalesced > before.coalesced);
+        assert_eq!(stats().sent, before.sent);
+    }
+
+    #[test]
+    fn test_resched_invalid_cpu() {
+        assert!(resched_cpu(CpuId::new(MAX_CPUS)).is_err());
+    }
+}
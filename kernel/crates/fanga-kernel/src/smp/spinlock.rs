@@ -1,14 +1,113 @@
 //! SMP-safe Spinlock
 //!
 //! This module provides a spinlock implementation for SMP systems.
+//!
+//! `SpinLock` is a ticket lock: each waiter draws a ticket and the lock is
+//! handed over in ticket order, so under contention CPUs acquire it first
+//! come, first served instead of the fastest CPU winning every time. All
+//! waiters spin on the same cache line, though; for locks contended by
+//! many CPUs `McsLock` (`smp::mcs`) lets each spin on its own.
+//!
+//! Every lock counts its acquisitions, how many of them had to wait and
+//! how long they spun (`LockStats`).
+//!
+//! A lock created with `with_class` reports its acquisitions to the lock
+//! order checker (`smp::lockdep`) and, with the waits and call sites, to
+//! the per class contention statistics (`smp::lockstat`). `lock_irqsave` additionally keeps
+//! interrupts off while the lock is held, for locks also taken from
+//! interrupt handlers.
+//!
+//! Preemption is disabled while a `SpinLock` is held, so a task is never
+//! switched out on the way out of an interrupt with a lock held; releasing
+//! the last lock is a preemption point.
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+
+use super::lockdep::{self, LockClass};
+use super::lockstat;
+
+/// Contention statistics of a lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockStats {
+    /// Times the lock was taken
+    pub acquisitions: u64,
+    /// Acquisitions that found the lock held and waited
+    pub contended: u64,
+    /// Spin loop iterations spent waiting
+    pub spins: u64,
+}
+
+/// Lock statistics counters, updated without holding the lock
+pub(crate) struct LockCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+}
+
+impl LockCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+        }
+    }
+
+    /// Count one acquisition that spun `spins` times first
+    #[inline]
+    pub(crate) fn record(&self, spins: u64) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins > 0 {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.spins.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Disable preemption for a lock about to be taken
+///
+/// Hosted builds leave the count alone: their test threads all run as
+/// CPU 0 and would share its count.
+#[inline]
+#[track_caller]
+fn lock_preempt_disable() {
+    #[cfg(all(not(test), target_os = "none"))]
+    crate::preempt::preempt_disable();
+}
+
+/// Re-enable preemption for a released lock
+#[inline]
+fn lock_preempt_enable() {
+    #[cfg(all(not(test), target_os = "none"))]
+    crate::preempt::preempt_enable();
+}
 
 /// A spinlock for SMP synchronization
 pub struct SpinLock<T> {
-    locked: AtomicBool,
+    /// Next ticket to hand out
+    next_ticket: AtomicU32,
+    /// Ticket of the current (or next) holder
+    now_serving: AtomicU32,
+    class: Option<&'static LockClass>,
+    counters: LockCounters,
     data: UnsafeCell<T>,
 }
 
@@ -19,55 +118,143 @@ impl<T> SpinLock<T> {
     /// Create a new spinlock
     pub const fn new(data: T) -> Self {
         Self {
-            locked: AtomicBool::new(false),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            class: None,
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Create a new spinlock checked by lockdep as part of `class`
+    pub const fn with_class(data: T, class: &'static LockClass) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            class: Some(class),
+            counters: LockCounters::new(),
             data: UnsafeCell::new(data),
         }
     }
     
     /// Try to acquire the lock without blocking
-    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
-        if self.locked.compare_exchange(
-            false,
-            true,
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        lock_preempt_disable();
+        // Only take a ticket that is served right away
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        if self.next_ticket.compare_exchange(
+            serving,
+            serving.wrapping_add(1),
             Ordering::Acquire,
             Ordering::Relaxed
         ).is_ok() {
-            Some(SpinLockGuard { lock: self })
+            self.counters.record(0);
+            lockstat::lock_acquired(self.class, Location::caller(), lockstat::wait_start(self.class), false);
+            // A try-lock never waits, so it cannot complete a deadlock
+            if let Some(class) = self.class {
+                lockdep::lock_acquire(class, false);
+            }
+            Some(SpinLockGuard { lock: self, class: self.class })
         } else {
+            lock_preempt_enable();
             None
         }
     }
     
     /// Acquire the lock, spinning until it's available
-    pub fn lock(&self) -> SpinLockGuard<T> {
-        // Spin until we acquire the lock
-        while self.locked.compare_exchange_weak(
-            false,
-            true,
-            Ordering::Acquire,
-            Ordering::Relaxed
-        ).is_err() {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        self.lock_as(self.class)
+    }
+
+    /// Acquire the lock as a different lockdep class
+    ///
+    /// For taking a second lock of the same class in a fixed order (such as
+    /// two run queues by CPU number), which would otherwise be reported as
+    /// recursive locking.
+    #[track_caller]
+    pub fn lock_nested(&self, class: &'static LockClass) -> SpinLockGuard<'_, T> {
+        self.lock_as(Some(class))
+    }
+
+    /// Acquire the lock with interrupts disabled until it is released
+    #[track_caller]
+    pub fn lock_irqsave(&self) -> SpinLockIrqGuard<'_, T> {
+        // Hosted builds (unit and integration tests) run in user mode,
+        // where cli faults
+        #[cfg(all(not(test), target_os = "none"))]
+        let irq_enabled = fanga_arch_x86_64::interrupts::are_enabled();
+        #[cfg(all(not(test), target_os = "none"))]
+        fanga_arch_x86_64::interrupts::disable();
+        #[cfg(any(test, not(target_os = "none")))]
+        let irq_enabled = false;
+
+        SpinLockIrqGuard { guard: ManuallyDrop::new(self.lock()), irq_enabled }
+    }
+
+    #[track_caller]
+    fn lock_as(&self, class: Option<&'static LockClass>) -> SpinLockGuard<'_, T> {
+        // Check the order before spinning, while a deadlock can still be reported
+        if let Some(class) = class {
+            lockdep::lock_acquire(class, true);
+        }
+
+        // A task preempted while spinning could be handed the lock and
+        // then hold up every later ticket
+        lock_preempt_disable();
+
+        // Spin until our ticket is served
+        let start = lockstat::wait_start(class);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.now_serving.load(Ordering::Acquire) != ticket {
             // Hint to CPU that we're spinning
             core::hint::spin_loop();
+            spins += 1;
         }
+        self.counters.record(spins);
+        lockstat::lock_acquired(class, Location::caller(), start, spins > 0);
         
-        SpinLockGuard { lock: self }
+        SpinLockGuard { lock: self, class }
     }
     
     /// Check if the lock is currently held
     pub fn is_locked(&self) -> bool {
-        self.locked.load(Ordering::Relaxed)
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+    
+    /// Number of CPUs waiting for the lock
+    pub fn waiters(&self) -> u32 {
+        let queued = self.next_ticket.load(Ordering::Relaxed).wrapping_sub(self.now_serving.load(Ordering::Relaxed));
+        queued.saturating_sub(1)
+    }
+    
+    /// Get the lock's contention statistics
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+    
+    /// Reset the lock's contention statistics
+    pub fn reset_stats(&self) {
+        self.counters.reset();
     }
     
     /// Unlock the spinlock (internal use)
+    ///
+    /// Only the holder writes `now_serving`, so this hands the lock to the
+    /// next ticket.
     fn unlock(&self) {
-        self.locked.store(false, Ordering::Release);
+        let next = self.now_serving.load(Ordering::Relaxed).wrapping_add(1);
+        self.now_serving.store(next, Ordering::Release);
     }
 }
 
 /// RAII guard for SpinLock
 pub struct SpinLockGuard<'a, T> {
     lock: &'a SpinLock<T>,
+    /// Class the lock was taken as
+    class: Option<&'static LockClass>,
 }
 
 impl<'a, T> Deref for SpinLockGuard<'a, T> {
@@ -87,6 +274,45 @@ impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
 impl<'a, T> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
         self.lock.unlock();
+        if let Some(class) = self.class {
+            lockdep::lock_release(class);
+        }
+        lock_preempt_enable();
+    }
+}
+
+/// RAII guard for SpinLock::lock_irqsave
+///
+/// Releases the lock, then restores the interrupt flag, then re-enables
+/// preemption, so a reschedule that came due meanwhile happens once
+/// interrupts are back on.
+pub struct SpinLockIrqGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    irq_enabled: bool,
+}
+
+impl<'a, T> Deref for SpinLockIrqGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockIrqGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinLockIrqGuard<'a, T> {
+    fn drop(&mut self) {
+        lock_preempt_disable();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.irq_enabled {
+            fanga_arch_x86_64::interrupts::enable();
+        }
+        lock_preempt_enable();
     }
 }
 
@@ -142,4 +368,42 @@ mod tests {
             assert_eq!(*guard, 42);
         }
     }
+
+    #[test]
+    fn test_spinlock_irqsave() {
+        static CLASS: LockClass = LockClass::new("test-irqsave");
+        let lock = SpinLock::with_class(1, &CLASS);
+
+        {
+            let mut guard = lock.lock_irqsave();
+            *guard += 1;
+            assert!(lock.is_locked());
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    fn test_ticket_order_and_stats() {
+        let lock = SpinLock::new(0);
+        let guard = lock.lock();
+
+        // Two more tickets drawn while held: both wait in line
+        lock.next_ticket.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(lock.waiters(), 2);
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+
+        // The lock goes to the next ticket, not to a newcomer
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        lock.unlock();
+        lock.unlock();
+        assert!(!lock.is_locked());
+
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.contended), (1, 0));
+        lock.reset_stats();
+        assert_eq!(lock.stats(), LockStats::default());
+    }
 }
@@ -0,0 +1,344 @@
+//! TLB Shootdown
+//!
+//! When a CPU changes or removes a page mapping, other CPUs may still hold
+//! the old translation in their TLBs. The initiator flushes its own TLB,
+//! posts a flush request to every other online CPU, sends them a TLB flush
+//! IPI and waits until each one has acknowledged the request.
+//!
+//! This module provides:
+//! - Single page, page range and full flush requests
+//! - A per-CPU request queue with acknowledgment tracking
+//! - The TLB flush IPI handler
+//! - Shootdown entry points used by unmap, protection changes and CoW
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::cpu::{current_cpu_id, CpuId};
+use super::ipi::{self, Ipi, IpiTarget, IpiType};
+use crate::memory::PAGE_SIZE;
+
+/// Above this many pages a CPU flushes its whole TLB instead
+pub const MAX_FLUSH_PAGES: usize = 32;
+
+/// How long an initiator waits for acknowledgments
+pub const SHOOTDOWN_TIMEOUT_MS: u64 = 100;
+
+/// Translations to invalidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushRange {
+    /// One page
+    Page(u64),
+    /// `pages` pages starting at `start`
+    Range { start: u64, pages: usize },
+    /// Every non-global translation
+    All,
+}
+
+impl FlushRange {
+    /// Pages to invalidate one by one, or None for a full flush
+    pub fn pages(&self) -> Option<Vec<u64>> {
+        match *self {
+            FlushRange::Page(addr) => Some(alloc::vec![addr]),
+            FlushRange::Range { start, pages } if pages <= MAX_FLUSH_PAGES => {
+                Some((0..pages as u64).map(|i| start + i * PAGE_SIZE as u64).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Merge a CPU's pending requests into one list of pages
+///
+/// Returns None if the CPU should flush everything.
+pub fn coalesce(requests: &[(u64, FlushRange)]) -> Option<Vec<u64>> {
+    let mut pages = Vec::new();
+    for (_, range) in requests {
+        pages.extend(range.pages()?);
+        if pages.len() > MAX_FLUSH_PAGES {
+            return None;
+        }
+    }
+    pages.sort_unstable();
+    pages.dedup();
+    Some(pages)
+}
+
+/// Shootdown statistics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShootdownStats {
+    /// Shootdowns that needed other CPUs
+    pub requests: u64,
+    /// Flush requests posted to remote CPUs
+    pub remote_flushes: u64,
+    /// Acknowledgments received
+    pub acks: u64,
+    /// Shootdowns abandoned after the timeout
+    pub timeouts: u64,
+}
+
+/// Pending flush requests and their outstanding acknowledgments
+pub struct ShootdownQueue {
+    /// Requests each CPU still has to process
+    pending: BTreeMap<usize, Vec<(u64, FlushRange)>>,
+    /// Acknowledgments still missing per request
+    outstanding: BTreeMap<u64, usize>,
+    next_seq: u64,
+    stats: ShootdownStats,
+}
+
+impl ShootdownQueue {
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            outstanding: BTreeMap::new(),
+            next_seq: 1,
+            stats: ShootdownStats { requests: 0, remote_flushes: 0, acks: 0, timeouts: 0 },
+        }
+    }
+
+    /// Post a flush request to `targets`, returning its sequence number
+    pub fn post(&mut self, range: FlushRange, targets: &[usize]) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        for &cpu in targets {
+            self.pending.entry(cpu).or_default().push((seq, range));
+        }
+        self.outstanding.insert(seq, targets.len());
+        self.stats.requests += 1;
+        self.stats.remote_flushes += targets.len() as u64;
+        seq
+    }
+
+    /// Take every request pending for a CPU
+    pub fn take(&mut self, cpu: usize) -> Vec<(u64, FlushRange)> {
+        self.pending.remove(&cpu).unwrap_or_default()
+    }
+
+    /// Acknowledge one CPU's completion of a request
+    pub fn ack(&mut self, seq: u64) {
+        if let Some(left) = self.outstanding.get_mut(&seq) {
+            *left -= 1;
+            self.stats.acks += 1;
+            if *left == 0 {
+                self.outstanding.remove(&seq);
+            }
+        }
+    }
+
+    /// Check whether every target acknowledged a request
+    pub fn is_complete(&self, seq: u64) -> bool {
+        !self.outstanding.contains_key(&seq)
+    }
+
+    /// Give up on a request
+    pub fn cancel(&mut self, seq: u64) {
+        self.outstanding.remove(&seq);
+        for requests in self.pending.values_mut() {
+            requests.retain(|&(s, _)| s != seq);
+        }
+        self.pending.retain(|_, requests| !requests.is_empty());
+    }
+
+    /// Get statistics
+    pub fn stats(&self) -> ShootdownStats {
+        self.stats
+    }
+}
+
+impl Default for ShootdownQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global shootdown queue
+static SHOOTDOWN: Mutex<ShootdownQueue> = Mutex::new(ShootdownQueue::new());
+
+/// Lock the queue with interrupts off, so the flush IPI cannot arrive on
+/// this CPU while it holds the lock
+fn with_queue<R>(f: impl FnOnce(&mut ShootdownQueue) -> R) -> R {
+    #[cfg(not(test))]
+    return fanga_arch_x86_64::interrupts::without_interrupts(|| f(&mut SHOOTDOWN.lock()));
+    #[cfg(test)]
+    f(&mut SHOOTDOWN.lock())
+}
+
+/// Get shootdown statistics
+pub fn stats() -> ShootdownStats {
+    with_queue(|queue| queue.stats())
+}
+
+/// Invalidate translations on this CPU
+fn flush_local(pages: Option<&[u64]>) {
+    #[cfg(not(test))]
+    unsafe {
+        match pages {
+            Some(pages) => {
+                for &addr in pages {
+                    core::arch::asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags));
+                }
+            }
+            None => {
+                // Reloading CR3 drops every non-global translation
+                core::arch::asm!(
+                    "mov {tmp}, cr3",
+                    "mov cr3, {tmp}",
+                    tmp = out(reg) _,
+                    options(nostack, preserves_flags),
+                );
+            }
+        }
+    }
+    #[cfg(test)]
+    let _ = pages;
+}
+
+/// Process and acknowledge the requests pending for a CPU
+fn process_pending(cpu: CpuId) -> usize {
+    let requests = with_queue(|queue| queue.take(cpu.as_usize()));
+    if requests.is_empty() {
+        return 0;
+    }
+
+    flush_local(coalesce(&requests).as_deref());
+    with_queue(|queue| {
+        for &(seq, _) in &requests {
+            queue.ack(seq);
+        }
+    });
+    requests.len()
+}
+
+/// TLB flush IPI handler
+pub fn handle_flush_ipi() {
+    process_pending(current_cpu_id());
+}
+
+/// Bring this CPU's TLB up to date after it was offline
+///
+/// Offline CPUs are left out of shootdowns, so everything is flushed.
+pub fn resync_local() {
+    process_pending(current_cpu_id());
+    flush_local(None);
+}
+
+/// Flush translations on every online CPU
+///
+/// Must be called with interrupts enabled: while waiting, this CPU keeps
+/// serving requests from other initiators so two shootdowns cannot
+/// deadlock.
+pub fn shootdown(range: FlushRange) -> Result<(), &'static str> {
+    flush_local(range.pages().as_deref());
+
+    let me = current_cpu_id();
+    let targets: Vec<usize> = match super::CPU_MANAGER.get() {
+        Some(manager) => manager
+            .lock()
+            .online_cpus()
+            .map(|cpu| cpu.id.as_usize())
+            .filter(|&id| id != me.as_usize())
+            .collect(),
+        None => Vec::new(),
+    };
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    let seq = with_queue(|queue| queue.post(range, &targets));
+    for &cpu in &targets {
+        let ipi = Ipi::new(IpiType::TlbFlush, IpiTarget::Cpu(CpuId::new(cpu)));
+        if let Err(e) = ipi::send_ipi(ipi) {
+            with_queue(|queue| queue.cancel(seq));
+            return Err(e);
+        }
+    }
+
+    let start = crate::task::time::uptime_ms();
+    loop {
+        process_pending(me);
+        if with_queue(|queue| queue.is_complete(seq)) {
+            return Ok(());
+        }
+        if crate::task::time::uptime_ms().saturating_sub(start) >= SHOOTDOWN_TIMEOUT_MS {
+            with_queue(|queue| {
+                queue.cancel(seq);
+                queue.stats.timeouts += 1;
+            });
+            return Err("TLB shootdown timed out");
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush one page on every CPU
+pub fn flush_page(addr: u64) -> Result<(), &'static str> {
+    shootdown(FlushRange::Page(addr))
+}
+
+/// Flush `pages` pages starting at `start` on every CPU
+pub fn flush_range(start: u64, pages: usize) -> Result<(), &'static str> {
+    shootdown(FlushRange::Range { start, pages })
+}
+
+/// Flush every CPU's whole TLB
+pub fn flush_all() -> Result<(), &'static str> {
+    shootdown(FlushRange::All)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acknowledgments() {
+        let mut queue = ShootdownQueue::new();
+        let seq = queue.post(FlushRange::Page(0x1000), &[1, 2]);
+        assert!(!queue.is_complete(seq));
+
+        assert_eq!(queue.take(1), alloc::vec![(seq, FlushRange::Page(0x1000))]);
+        assert!(queue.take(1).is_empty());
+        queue.ack(seq);
+        assert!(!queue.is_complete(seq));
+
+        queue.take(2);
+        queue.ack(seq);
+        assert!(queue.is_complete(seq));
+        assert_eq!(queue.stats().acks, 2);
+        assert_eq!(queue.stats().remote_flushes, 2);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut queue = ShootdownQueue::new();
+        let a = queue.post(FlushRange::All, &[1]);
+        let b = queue.post(FlushRange::Page(0x2000), &[1]);
+        queue.cancel(a);
+
+        assert!(queue.is_complete(a));
+        assert_eq!(queue.take(1), alloc::vec![(b, FlushRange::Page(0x2000))]);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let requests = [
+            (1, FlushRange::Page(0x3000)),
+            (2, FlushRange::Range { start: 0x1000, pages: 3 }),
+        ];
+        assert_eq!(coalesce(&requests), Some(alloc::vec![0x1000, 0x2000, 0x3000]));
+
+        let big = [(1, FlushRange::Range { start: 0, pages: MAX_FLUSH_PAGES + 1 })];
+        assert_eq!(coalesce(&big), None);
+        assert_eq!(coalesce(&[(1, FlushRange::All)]), None);
+    }
+
+    #[test]
+    fn test_single_cpu_shootdown() {
+        // No other CPU is online, so nothing is posted
+        assert!(flush_range(0x4000, 2).is_ok());
+    }
+}
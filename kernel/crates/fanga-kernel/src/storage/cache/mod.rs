@@ -2,14 +2,23 @@
 //!
 //! This module implements a buffer cache for disk I/O operations.
 //! It uses an LRU (Least Recently Used) eviction policy.
+//!
+//! The entry list and LRU queue locks of all caches share a lock class
+//! each, so `lockstat` reports their contention. The entry list is
+//! always taken first.
 
 extern crate alloc;
 use alloc::vec::Vec;
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use spin::Mutex;
+use crate::smp::{LockClass, SpinLock};
 use crate::storage::block_device::{BlockDevice, BlockDeviceError};
 
+/// Lock classes of the entry lists and LRU queues
+static ENTRIES_CLASS: LockClass = LockClass::new("disk-cache");
+static LRU_CLASS: LockClass = LockClass::new("disk-cache-lru");
+
 /// Cache entry
 #[derive(Clone)]
 struct CacheEntry {
@@ -28,9 +37,9 @@ pub struct DiskCache {
     /// Underlying block device
     device: Arc<Mutex<dyn BlockDevice>>,
     /// Cache entries
-    entries: Mutex<Vec<CacheEntry>>,
+    entries: SpinLock<Vec<CacheEntry>>,
     /// LRU queue (block numbers)
-    lru_queue: Mutex<VecDeque<u64>>,
+    lru_queue: SpinLock<VecDeque<u64>>,
     /// Maximum number of cached blocks
     max_entries: usize,
     /// Block size
@@ -50,8 +59,8 @@ impl DiskCache {
         
         Self {
             device,
-            entries: Mutex::new(Vec::new()),
-            lru_queue: Mutex::new(VecDeque::new()),
+            entries: SpinLock::with_class(Vec::new(), &ENTRIES_CLASS),
+            lru_queue: SpinLock::with_class(VecDeque::new(), &LRU_CLASS),
             max_entries,
             block_size,
             timestamp: Mutex::new(0),
@@ -224,6 +233,7 @@ impl DiskCache {
         
         for block in blocks {
             self.flush_block(block)?;
+            crate::preempt::cond_resched();
         }
         
         Ok(())
@@ -143,6 +143,11 @@ impl AtaDevice {
         // Small delay for drive selection
         self.io_delay();
         
+        // A bus with no drives attached floats high
+        if unsafe { self.status_port.lock().read() } == 0xFF {
+            return Err(BlockDeviceError::NotFound);
+        }
+        
         // Send IDENTIFY command
         unsafe {
             self.sector_count_port.lock().write(0);
@@ -316,6 +321,7 @@ impl BlockDevice for AtaDevice {
         }
         
         let num_sectors = buffer.len() / self.sector_size;
+        crate::tracepoint!(BLOCK_READ, start_block, num_sectors);
         for i in 0..num_sectors {
             let offset = i * self.sector_size;
             self.read_sector_pio(start_block + i as u64, &mut buffer[offset..offset + self.sector_size])?;
@@ -330,6 +336,7 @@ impl BlockDevice for AtaDevice {
         }
         
         let num_sectors = buffer.len() / self.sector_size;
+        crate::tracepoint!(BLOCK_WRITE, start_block, num_sectors);
         for i in 0..num_sectors {
             let offset = i * self.sector_size;
             self.write_sector_pio(start_block + i as u64, &buffer[offset..offset + self.sector_size])?;
@@ -13,6 +13,15 @@ pub const FAT_BAD_CLUSTER: u32 = 0x0FFFFFF7;
 pub const FAT_EOC_MIN: u32 = 0x0FFFFFF8; // End of chain minimum
 pub const FAT_EOC: u32 = 0x0FFFFFFF; // End of chain
 
+/// Progress of a FAT walk done a chunk at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatWalk<T> {
+    /// The walk finished with this result
+    Done(T),
+    /// The chunk ran out; continue from this cluster
+    Continue(u32),
+}
+
 /// FAT table structure
 pub struct FatTable {
     /// Cached FAT entries
@@ -72,14 +81,35 @@ impl FatTable {
         self.set_next_cluster(cluster, FAT_EOC)
     }
     
+    /// Check whether a cluster is free
+    pub fn is_free(&self, cluster: u32) -> bool {
+        cluster >= 2
+            && self.entries.get(cluster as usize).is_some_and(|&entry| (entry & 0x0FFFFFFF) == FAT_FREE)
+    }
+    
     /// Find a free cluster
     pub fn find_free_cluster(&self) -> Option<u32> {
-        for (i, &entry) in self.entries.iter().enumerate() {
-            if i >= 2 && (entry & 0x0FFFFFFF) == FAT_FREE {
-                return Some(i as u32);
-            }
+        match self.find_free_cluster_in(2, usize::MAX) {
+            FatWalk::Done(cluster) => cluster,
+            FatWalk::Continue(_) => None,
+        }
+    }
+    
+    /// Search `count` clusters from `start` for a free one
+    ///
+    /// Lets callers search a large FAT in chunks and drop its lock between
+    /// them.
+    pub fn find_free_cluster_in(&self, start: u32, count: usize) -> FatWalk<Option<u32>> {
+        let start = (start as usize).max(2);
+        let end = start.saturating_add(count).min(self.entries.len());
+        if let Some(cluster) = (start..end).find(|&i| self.is_free(i as u32)) {
+            return FatWalk::Done(Some(cluster as u32));
+        }
+        if end >= self.entries.len() {
+            FatWalk::Done(None)
+        } else {
+            FatWalk::Continue(end as u32)
         }
-        None
     }
     
     /// Allocate a new cluster
@@ -95,22 +125,32 @@ impl FatTable {
     /// Get cluster chain starting from a given cluster
     pub fn get_chain(&self, start_cluster: u32) -> Vec<u32> {
         let mut chain = Vec::new();
-        let mut current = start_cluster;
-        
-        while let Some(next) = self.get_next_cluster(current) {
+        let _ = self.walk_chain(&mut chain, start_cluster, usize::MAX);
+        chain
+    }
+    
+    /// Follow up to `count` links of a cluster chain from `cluster`,
+    /// appending the clusters to `chain`
+    ///
+    /// Lets callers walk a long chain in chunks and drop the FAT lock
+    /// between them.
+    pub fn walk_chain(&self, chain: &mut Vec<u32>, cluster: u32, count: usize) -> FatWalk<()> {
+        let mut current = cluster;
+        for _ in 0..count {
+            let Some(next) = self.get_next_cluster(current) else {
+                // Add the last cluster (EOC)
+                if current >= 2 && current < self.total_clusters {
+                    chain.push(current);
+                }
+                return FatWalk::Done(());
+            };
             chain.push(current);
             if chain.len() > self.total_clusters as usize {
-                break; // Prevent infinite loops on corrupt FAT
+                return FatWalk::Done(()); // Prevent infinite loops on corrupt FAT
             }
             current = next;
         }
-        
-        // Add the last cluster (EOC)
-        if current >= 2 && current < self.total_clusters {
-            chain.push(current);
-        }
-        
-        chain
+        FatWalk::Continue(current)
     }
     
     /// Free an entire cluster chain
@@ -165,6 +205,38 @@ mod tests {
         assert_eq!(chain, vec![2, 3]);
     }
     
+    #[test]
+    fn test_chunked_walks() {
+        let bs = Fat32BootSector {
+            total_sectors_32: 204800,
+            sectors_per_cluster: 8,
+            reserved_sectors: 32,
+            num_fats: 2,
+            sectors_per_fat_32: 1024,
+            ..Default::default()
+        };
+        
+        let mut fat = FatTable::new(&bs);
+        fat.entries = vec![FAT_EOC; 10];
+        
+        // Chain 2 -> 3 -> 4 -> 5 -> EOC
+        for cluster in 2..5 {
+            fat.entries[cluster] = cluster as u32 + 1;
+        }
+        let mut chain = Vec::new();
+        assert_eq!(fat.walk_chain(&mut chain, 2, 2), FatWalk::Continue(4));
+        assert_eq!(fat.walk_chain(&mut chain, 4, 2), FatWalk::Done(()));
+        assert_eq!(chain, vec![2, 3, 4, 5]);
+        
+        fat.entries[8] = FAT_FREE;
+        assert_eq!(fat.find_free_cluster_in(0, 3), FatWalk::Continue(5));
+        assert_eq!(fat.find_free_cluster_in(5, 3), FatWalk::Continue(8));
+        assert_eq!(fat.find_free_cluster_in(8, 3), FatWalk::Done(Some(8)));
+        assert_eq!(fat.find_free_cluster_in(9, 3), FatWalk::Done(None));
+        assert!(fat.is_free(8));
+        assert!(!fat.is_free(2));
+    }
+    
     #[test]
     fn test_free_cluster_allocation() {
         let bs = Fat32BootSector::default();
@@ -12,12 +12,22 @@ use alloc::string::String;
 use alloc::sync::Arc;
 use spin::Mutex;
 use crate::storage::block_device::{BlockDevice, BlockDeviceError};
-use crate::fs::vfs::{FileSystem, VNode, VNodeType, VNodeAttr, DirEntry, FsError};
+use crate::fs::vfs::{FileSystem, FsStats, VNode, VNodeType, VNodeAttr, DirEntry, FsError};
 
 pub use boot_sector::Fat32BootSector;
-pub use fat_table::FatTable;
+pub use fat_table::{FatTable, FatWalk};
 pub use directory::{DirectoryEntry, DirectoryIterator};
 
+/// FSInfo lead signature, at offset 0
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+/// FSInfo structure signature, at offset 484
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// Offset of the free cluster count in the FSInfo sector
+const FSINFO_FREE_COUNT_OFFSET: usize = 488;
+
+/// Clusters visited per FAT lock hold in long FAT walks
+const FAT_WALK_CHUNK: usize = 1024;
+
 /// FAT32 filesystem implementation
 pub struct Fat32FileSystem {
     device: Arc<Mutex<dyn BlockDevice>>,
@@ -86,6 +96,81 @@ impl Fat32FileSystem {
         let device = self.device.lock();
         device.write_blocks(lba, &buffer[0..sectors * sector_size])
     }
+
+    /// Find a free cluster
+    ///
+    /// The FAT is searched a chunk at a time, with its lock dropped and a
+    /// preemption point between chunks.
+    pub fn find_free_cluster(&self) -> Option<u32> {
+        let mut start = 2;
+        loop {
+            let walk = self.fat_table.lock().find_free_cluster_in(start, FAT_WALK_CHUNK);
+            match walk {
+                FatWalk::Done(cluster) => return cluster,
+                FatWalk::Continue(next) => start = next,
+            }
+            crate::preempt::cond_resched();
+        }
+    }
+    
+    /// Allocate a cluster, marking it as the end of a new chain
+    pub fn allocate_cluster(&self) -> Option<u32> {
+        loop {
+            let cluster = self.find_free_cluster()?;
+            let mut fat = self.fat_table.lock();
+            // Someone else may have taken it while the lock was dropped
+            if fat.is_free(cluster) {
+                fat.mark_eoc(cluster).ok()?;
+                return Some(cluster);
+            }
+        }
+    }
+    
+    /// Get the cluster chain starting at `start_cluster`
+    ///
+    /// Walked a chunk at a time like `find_free_cluster`.
+    pub fn get_chain(&self, start_cluster: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = start_cluster;
+        loop {
+            let walk = self.fat_table.lock().walk_chain(&mut chain, cluster, FAT_WALK_CHUNK);
+            match walk {
+                FatWalk::Done(()) => return chain,
+                FatWalk::Continue(next) => cluster = next,
+            }
+            crate::preempt::cond_resched();
+        }
+    }
+    
+    /// Free the cluster chain starting at `start_cluster`
+    pub fn free_chain(&self, start_cluster: u32) -> Result<(), &'static str> {
+        for clusters in self.get_chain(start_cluster).chunks(FAT_WALK_CHUNK) {
+            let mut fat = self.fat_table.lock();
+            for &cluster in clusters {
+                fat.free_cluster(cluster)?;
+            }
+            drop(fat);
+            crate::preempt::cond_resched();
+        }
+        Ok(())
+    }
+
+    /// Free cluster count from the FSInfo sector, if it has one
+    fn fsinfo_free_clusters(&self) -> Option<u32> {
+        let mut buffer = [0u8; 512];
+        let sector = self.partition_start + self.boot_sector.fsinfo_sector as u64;
+        self.device.lock().read_blocks(sector, &mut buffer).ok()?;
+
+        let read_u32 = |offset: usize| u32::from_le_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]]);
+        if read_u32(0) != FSINFO_LEAD_SIGNATURE || read_u32(484) != FSINFO_STRUCT_SIGNATURE {
+            return None;
+        }
+        // 0xFFFFFFFF means the count is unknown
+        match read_u32(FSINFO_FREE_COUNT_OFFSET) {
+            u32::MAX => None,
+            free => Some(free),
+        }
+    }
 }
 
 // Stub implementation of FileSystem trait for FAT32
@@ -128,6 +213,8 @@ impl FileSystem for Fat32FileSystem {
         Ok(VNodeAttr {
             size: 0,
             vtype: vnode.vtype,
+            created: 0,
+            modified: 0,
         })
     }
     
@@ -139,6 +226,19 @@ impl FileSystem for Fat32FileSystem {
     fn truncate(&mut self, _vnode: &VNode, _size: usize) -> Result<(), FsError> {
         Err(FsError::IoError)
     }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let blocks = self.boot_sector.total_clusters() as u64;
+        Ok(FsStats {
+            block_size: self.cluster_size() as u64,
+            blocks,
+            free_blocks: self.fsinfo_free_clusters().map_or(0, |free| (free as u64).min(blocks)),
+        })
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.device.lock().flush().map_err(|_| FsError::IoError)
+    }
 }
 
 #[cfg(test)]
@@ -5,15 +5,18 @@
 //! - Partition table support (MBR and GPT)
 //! - FAT32 file system
 //! - Disk caching
+//! - A registry of disks and partitions found at boot
 
 pub mod drivers;
 pub mod partition;
 pub mod fat32;
 pub mod cache;
 pub mod block_device;
+pub mod registry;
 
 pub use block_device::{BlockDevice, BlockDeviceError};
 pub use drivers::{ata::AtaDevice, ahci::AhciController};
 pub use partition::{PartitionTable, Partition, PartitionType};
 pub use fat32::Fat32FileSystem;
 pub use cache::DiskCache;
+pub use registry::BlockDeviceInfo;
@@ -0,0 +1,342 @@
+//! Block Device Registry
+//!
+//! Disks found at boot and the partitions on them. Disks are named like
+//! IDE disks (`hda` to `hdd`) and partitions by adding their number
+//! (`hda1`). Partition tables are read when a disk is registered, GPT
+//! first and then MBR, and each disk and partition is probed for a file
+//! system that `mount` can use.
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::FileSystem;
+use crate::storage::block_device::BlockDevice;
+use crate::storage::drivers::ata::{AtaBus, AtaDevice, AtaDrive};
+use crate::storage::fat32::{Fat32BootSector, Fat32FileSystem};
+use crate::storage::partition::{GptPartitionTable, MbrPartitionTable, Partition, PartitionTable, PartitionTableType};
+
+/// Type name of FAT32 file systems
+pub const FSTYPE_FAT32: &str = "fat32";
+/// Type name of ext2 file systems
+pub const FSTYPE_EXT2: &str = "ext2";
+
+/// Boot sector signature at offset 510
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Byte offset of the ext2 superblock
+const EXT2_SUPERBLOCK_OFFSET: u64 = 1024;
+/// Offset of the magic number in the ext2 superblock
+const EXT2_MAGIC_OFFSET: usize = 56;
+const EXT2_MAGIC: u16 = 0xEF53;
+
+/// A disk or partition as listed by `lsblk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDeviceInfo {
+    pub name: String,
+    /// Name of the disk a partition is on, `None` for disks
+    pub parent: Option<String>,
+    /// First sector on the disk
+    pub start_lba: u64,
+    /// Size in sectors
+    pub sectors: u64,
+    pub sector_size: usize,
+    /// File system found on it, if any
+    pub fstype: Option<&'static str>,
+    /// Partition label, for GPT partitions
+    pub label: Option<String>,
+}
+
+impl BlockDeviceInfo {
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.sectors * self.sector_size as u64
+    }
+}
+
+struct Disk {
+    info: BlockDeviceInfo,
+    device: Arc<Mutex<dyn BlockDevice>>,
+    table: Option<PartitionTableType>,
+    partitions: Vec<BlockDeviceInfo>,
+}
+
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+/// Identify the file system starting at `start_lba`
+///
+/// # Returns
+/// Its type name, or `None` if it is not one the kernel knows
+pub fn detect(device: &dyn BlockDevice, start_lba: u64) -> Option<&'static str> {
+    let mut buffer = alloc::vec![0u8; device.block_size()];
+    device.read_blocks(start_lba, &mut buffer).ok()?;
+    if buffer.get(510..512) == Some(&BOOT_SIGNATURE[..])
+        && Fat32BootSector::read(device, start_lba).is_ok_and(|boot_sector| boot_sector.is_valid())
+    {
+        return Some(FSTYPE_FAT32);
+    }
+
+    let block_size = device.block_size() as u64;
+    device.read_blocks(start_lba + EXT2_SUPERBLOCK_OFFSET / block_size, &mut buffer).ok()?;
+    let offset = (EXT2_SUPERBLOCK_OFFSET % block_size) as usize + EXT2_MAGIC_OFFSET;
+    let magic = u16::from_le_bytes([*buffer.get(offset)?, *buffer.get(offset + 1)?]);
+    (magic == EXT2_MAGIC).then_some(FSTYPE_EXT2)
+}
+
+/// Read the partition table of a disk
+///
+/// A disk that holds a file system itself has no partition table, even if
+/// its boot sector looks like an MBR.
+pub fn scan_partitions(device: &dyn BlockDevice) -> (Option<PartitionTableType>, Vec<Partition>) {
+    if detect(device, 0).is_some() {
+        return (None, Vec::new());
+    }
+    if let Ok(partitions) = GptPartitionTable::parse(device) {
+        return (Some(PartitionTableType::Gpt), partitions);
+    }
+    match MbrPartitionTable::parse(device) {
+        Ok(partitions) => (Some(PartitionTableType::Mbr), partitions),
+        Err(_) => (None, Vec::new()),
+    }
+}
+
+/// Register a disk and the partitions on it
+///
+/// # Returns
+/// The number of partitions found
+pub fn register(name: &str, device: Arc<Mutex<dyn BlockDevice>>) -> Result<usize, &'static str> {
+    let mut disks = DISKS.lock();
+    if disks.iter().any(|disk| disk.info.name == name) {
+        return Err("Block device already registered");
+    }
+
+    let (info, table, partitions) = {
+        let dev = device.lock();
+        let (table, found) = scan_partitions(&*dev);
+        let info = BlockDeviceInfo {
+            name: String::from(name),
+            parent: None,
+            start_lba: 0,
+            sectors: dev.block_count(),
+            sector_size: dev.block_size(),
+            fstype: detect(&*dev, 0),
+            label: None,
+        };
+        let partitions: Vec<BlockDeviceInfo> = found
+            .into_iter()
+            .map(|partition| BlockDeviceInfo {
+                name: alloc::format!("{}{}", name, partition.number),
+                parent: Some(String::from(name)),
+                start_lba: partition.start_lba,
+                sectors: partition.size,
+                sector_size: dev.block_size(),
+                fstype: detect(&*dev, partition.start_lba),
+                label: partition.label.filter(|label| !label.is_empty()),
+            })
+            .collect();
+        (info, table, partitions)
+    };
+
+    let count = partitions.len();
+    crate::log_info!(
+        target: "storage",
+        "{}: {} sectors, {} partition(s){}",
+        name,
+        info.sectors,
+        count,
+        match table {
+            Some(PartitionTableType::Gpt) => " (GPT)",
+            Some(PartitionTableType::Mbr) => " (MBR)",
+            None => "",
+        }
+    );
+    disks.push(Disk { info, device, table, partitions });
+    Ok(count)
+}
+
+/// Registered disks, each followed by its partitions
+pub fn list() -> Vec<BlockDeviceInfo> {
+    let disks = DISKS.lock();
+    let mut list = Vec::new();
+    for disk in disks.iter() {
+        list.push(disk.info.clone());
+        list.extend(disk.partitions.iter().cloned());
+    }
+    list
+}
+
+/// Partition table type of a disk
+pub fn partition_table(name: &str) -> Option<PartitionTableType> {
+    DISKS.lock().iter().find(|disk| disk.info.name == name)?.table
+}
+
+/// Find a disk or partition by name, with or without a `/dev/` prefix
+///
+/// # Returns
+/// The disk's device and where the named disk or partition is on it
+pub fn open(name: &str) -> Option<(Arc<Mutex<dyn BlockDevice>>, BlockDeviceInfo)> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    let disks = DISKS.lock();
+    disks.iter().find_map(|disk| {
+        core::iter::once(&disk.info)
+            .chain(disk.partitions.iter())
+            .find(|info| info.name == name)
+            .map(|info| (disk.device.clone(), info.clone()))
+    })
+}
+
+/// Open the file system on a disk or partition
+///
+/// # Returns
+/// The file system and its type name
+pub fn open_filesystem(name: &str) -> Result<(Box<dyn FileSystem>, &'static str), &'static str> {
+    let (device, info) = open(name).ok_or("No such block device")?;
+    let fstype = detect(&*device.lock(), info.start_lba);
+    match fstype {
+        Some(FSTYPE_FAT32) => {
+            let fs = Fat32FileSystem::new(device, info.start_lba).map_err(|_| "Cannot read the FAT32 boot sector")?;
+            Ok((Box::new(fs), FSTYPE_FAT32))
+        }
+        Some(FSTYPE_EXT2) => Err("ext2 file systems are not supported yet"),
+        _ => Err("Unknown file system"),
+    }
+}
+
+/// Look for ATA disks on both IDE buses and register those that answer
+///
+/// # Returns
+/// The number of disks registered
+pub fn probe_ata() -> usize {
+    let positions = [
+        ("hda", AtaBus::Primary, AtaDrive::Master),
+        ("hdb", AtaBus::Primary, AtaDrive::Slave),
+        ("hdc", AtaBus::Secondary, AtaDrive::Master),
+        ("hdd", AtaBus::Secondary, AtaDrive::Slave),
+    ];
+    let mut found = 0;
+    for (name, bus, drive) in positions {
+        let mut device = AtaDevice::new(bus, drive);
+        if device.init().is_err() {
+            continue;
+        }
+        if register(name, Arc::new(Mutex::new(device))).is_ok() {
+            found += 1;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::block_device::BlockDeviceError;
+
+    struct RamDisk {
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl RamDisk {
+        fn new(sectors: usize) -> Self {
+            Self { data: Mutex::new(alloc::vec![0; sectors * 512]) }
+        }
+
+        fn poke(&self, offset: usize, bytes: &[u8]) {
+            self.data.lock()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.data.lock().len() / 512) as u64
+        }
+
+        fn read_blocks(&self, start_block: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+            let data = self.data.lock();
+            let start = start_block as usize * 512;
+            let bytes = data.get(start..start + buffer.len()).ok_or(BlockDeviceError::InvalidBlock)?;
+            buffer.copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn write_blocks(&self, start_block: u64, buffer: &[u8]) -> Result<(), BlockDeviceError> {
+            let mut data = self.data.lock();
+            let start = start_block as usize * 512;
+            let bytes = data.get_mut(start..start + buffer.len()).ok_or(BlockDeviceError::InvalidBlock)?;
+            bytes.copy_from_slice(buffer);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), BlockDeviceError> {
+            Ok(())
+        }
+    }
+
+    /// Write a FAT32 boot sector at `lba`
+    fn format_fat32(disk: &RamDisk, lba: usize) {
+        let offset = lba * 512;
+        disk.poke(offset + 11, &512u16.to_le_bytes());
+        disk.poke(offset + 13, &[8]);
+        disk.poke(offset + 36, &16u32.to_le_bytes());
+        disk.poke(offset + 510, &BOOT_SIGNATURE);
+    }
+
+    /// Write an MBR with one FAT32 partition at `start`
+    fn partition(disk: &RamDisk, start: u32, sectors: u32) {
+        let entry = 446;
+        disk.poke(entry + 4, &[0x0C]);
+        disk.poke(entry + 8, &start.to_le_bytes());
+        disk.poke(entry + 12, &sectors.to_le_bytes());
+        disk.poke(510, &BOOT_SIGNATURE);
+    }
+
+    #[test]
+    fn test_detect() {
+        let disk = RamDisk::new(64);
+        assert_eq!(detect(&disk, 0), None);
+        format_fat32(&disk, 0);
+        assert_eq!(detect(&disk, 0), Some(FSTYPE_FAT32));
+
+        disk.poke(32 * 512 + 1024 + EXT2_MAGIC_OFFSET, &EXT2_MAGIC.to_le_bytes());
+        assert_eq!(detect(&disk, 32), Some(FSTYPE_EXT2));
+    }
+
+    #[test]
+    fn test_scan_partitions() {
+        let disk = RamDisk::new(64);
+        partition(&disk, 8, 56);
+        format_fat32(&disk, 8);
+        let (table, partitions) = scan_partitions(&disk);
+        assert_eq!(table, Some(PartitionTableType::Mbr));
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].start_lba, 8);
+
+        // A whole-disk file system has no partition table
+        let disk = RamDisk::new(64);
+        format_fat32(&disk, 0);
+        let (table, partitions) = scan_partitions(&disk);
+        assert_eq!(table, None);
+        assert!(partitions.is_empty());
+    }
+
+    #[test]
+    fn test_register_and_open() {
+        let disk = RamDisk::new(64);
+        partition(&disk, 8, 56);
+        format_fat32(&disk, 8);
+        assert_eq!(register("rd-test", Arc::new(Mutex::new(disk))).unwrap(), 1);
+        assert!(register("rd-test", Arc::new(Mutex::new(RamDisk::new(1)))).is_err());
+
+        let (_, info) = open("/dev/rd-test1").unwrap();
+        assert_eq!(info.parent.as_deref(), Some("rd-test"));
+        assert_eq!(info.fstype, Some(FSTYPE_FAT32));
+        assert_eq!(info.size(), 56 * 512);
+        assert!(open("rd-test2").is_none());
+        assert_eq!(partition_table("rd-test"), Some(PartitionTableType::Mbr));
+    }
+}
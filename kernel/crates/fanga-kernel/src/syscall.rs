@@ -6,15 +6,18 @@
 
 // Re-export syscall constants and error codes from architecture layer
 pub use fanga_arch_x86_64::syscall::{
-    SYS_READ, SYS_WRITE, SYS_OPEN, SYS_CLOSE, SYS_LSEEK,
-    SYS_EXIT, SYS_FORK, SYS_EXEC,
+    SYS_READ, SYS_WRITE, SYS_OPEN, SYS_CLOSE, SYS_LSEEK, SYS_IOCTL,
+    SYS_EXIT, SYS_FORK, SYS_EXEC, SYS_CLONE, SYS_WAIT4,
     SYS_MKDIR, SYS_RMDIR, SYS_GETDENTS, SYS_UNLINK,
     SYS_PIPE, SYS_KILL, 
     SYS_SHMGET, SYS_SHMAT, SYS_SHMDT, SYS_SHMCTL,
     SYS_MSGGET, SYS_MSGSND, SYS_MSGRCV,
-    SYS_MMAP, SYS_MUNMAP,
-    EINVAL, EBADF, ENOMEM, ENOSYS, EFAULT, EACCES, EPERM, ESRCH,
-    ENOENT, EEXIST, ENOTDIR, EISDIR, ENOTEMPTY,
+    SYS_RT_SIGACTION, SYS_RT_SIGPROCMASK, SYS_RT_SIGRETURN,
+    SYS_MMAP, SYS_MUNMAP, SYS_MBIND, SYS_SET_MEMPOLICY,
+    SYS_GETRUSAGE, SYS_TIMES, SYS_CLOCK_GETTIME, SYS_GETRANDOM,
+    SYS_ARCH_PRCTL, SYS_REBOOT,
+    EINVAL, EBADF, ENOMEM, ENOSYS, EFAULT, EACCES, EPERM, ESRCH, ECHILD, EINTR, EAGAIN, ENOTTY,
+    ENOENT, EEXIST, ENOTDIR, EISDIR, ENOTEMPTY, ETIMEDOUT,
 };
 
 /// Result type for system calls
@@ -64,6 +67,7 @@ mod tests {
         assert_eq!(SYS_EXIT, 60);
         assert_eq!(SYS_FORK, 57);
         assert_eq!(SYS_EXEC, 59);
+        assert_eq!(SYS_CLONE, 56);
         
         // IPC syscalls
         assert_eq!(SYS_PIPE, 22);
@@ -75,6 +79,8 @@ mod tests {
         assert_eq!(SYS_MSGGET, 68);
         assert_eq!(SYS_MSGSND, 69);
         assert_eq!(SYS_MSGRCV, 70);
+
+        assert_eq!(SYS_GETRANDOM, 318);
     }
 
     #[test]
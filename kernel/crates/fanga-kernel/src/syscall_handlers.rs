@@ -3,9 +3,22 @@
 //! This module provides the kernel-side implementation of system calls,
 //! integrating with task management, memory management, and I/O subsystems.
 
-use crate::task::{self, TaskId};
-use crate::userspace::{load_user_binary, enter_usermode, prepare_usermode_stack};
+use alloc::vec::Vec;
+use crate::io::tty;
+use crate::memory::mmap;
+use crate::numa::policy;
+use crate::random;
+use crate::task::{self, clocksource, cputime, sigdeliver, tls, TaskId};
+use crate::syscall::{
+    SYS_GETRUSAGE, SYS_TIMES, SYS_CLOCK_GETTIME, SYS_RT_SIGACTION, SYS_RT_SIGPROCMASK, SYS_RT_SIGRETURN,
+    SYS_ARCH_PRCTL, SYS_IOCTL, SYS_GETRANDOM, SYS_CLONE, SYS_WAIT4, SYS_REBOOT, SYS_MMAP, SYS_MUNMAP, SYS_MBIND,
+    SYS_SET_MEMPOLICY,
+    ECHILD, EFAULT, EINVAL, ESRCH,
+};
+use crate::userspace::{load_user_binary, enter_usermode};
 use crate::elf::ElfLoadError;
+use crate::memory::AddressSpace;
+use fanga_arch_x86_64::uaccess::{UserPtr, UserSlice};
 
 /// Handle fork() system call
 ///
@@ -28,6 +41,64 @@ pub fn handle_fork(current_task_id: TaskId) -> i64 {
     }
 }
 
+/// Handle clone() system call
+///
+/// # Arguments
+/// * `flags` - `CLONE_*` flags
+/// * `stack` - User stack pointer for the child, 0 to keep the caller's
+/// * `tls` - Thread pointer of the child's TLS block with `CLONE_SETTLS`
+///
+/// # Returns
+/// The child's PID in the caller, 0 in the child
+pub fn handle_clone(flags: u64, stack: u64, tls: u64) -> i64 {
+    let Some(current) = get_current_task() else {
+        return ESRCH;
+    };
+    match task::clone(current, flags, stack, tls) {
+        Ok(child_id) => child_id.as_usize() as i64,
+        Err("Invalid TLS address") => EINVAL,
+        Err("Threads must share the address space") => EINVAL,
+        Err(_) => fanga_arch_x86_64::syscall::ENOMEM,
+    }
+}
+
+/// wait4: return at once if no child has exited
+pub const WNOHANG: i32 = 1;
+
+/// Handle wait4() system call
+///
+/// Resource usage is not reported; `rusage` must be null.
+///
+/// # Arguments
+/// * `pid` - Child to wait for, or -1 for any
+/// * `status` - User pointer receiving the wait status (may be null)
+/// * `options` - `WNOHANG` or 0
+///
+/// # Returns
+/// The child's PID, 0 with `WNOHANG` if no child has exited, or a
+/// negative error code
+pub fn handle_wait4(pid: i64, status: UserPtr<i32>, options: i32, rusage: u64) -> i64 {
+    if options & !WNOHANG != 0 || rusage != 0 || (pid <= 0 && pid != -1) {
+        return EINVAL;
+    }
+    let Some(current) = get_current_task() else {
+        return ESRCH;
+    };
+    let child = (pid > 0).then(|| TaskId::new(pid as usize));
+    match task::wait_child(current, child, options & WNOHANG == 0) {
+        Ok(Some((child, code))) => {
+            // Exit code in bits 8-15, as WEXITSTATUS reads it
+            if !status.is_null() && status.write(&((code & 0xff) << 8)).is_err() {
+                return EFAULT;
+            }
+            child.as_usize() as i64
+        }
+        Ok(None) => 0,
+        Err("No such child") => ECHILD,
+        Err(_) => fanga_arch_x86_64::syscall::EAGAIN,
+    }
+}
+
 /// Handle exit() system call
 ///
 /// Terminates the current process with the given exit code.
@@ -51,8 +122,9 @@ pub fn handle_exit(task_id: TaskId, exit_code: i32) -> ! {
         // For now, we'll just log and halt
         drop(scheduler_guard);
         
-        fanga_arch_x86_64::serial_println!(
-            "[SYSCALL] Would switch to task {:?} after exit",
+        crate::log_debug!(
+            target: "syscall",
+            "Would switch to task {:?} after exit",
             next_task_id
         );
     }
@@ -74,6 +146,128 @@ pub fn get_current_task() -> Option<TaskId> {
     scheduler_guard.current_task()
 }
 
+/// Handle getrusage() system call
+///
+/// # Arguments
+/// * `who` - RUSAGE_SELF, RUSAGE_CHILDREN or RUSAGE_THREAD
+/// * `usage` - User pointer receiving a `struct rusage`
+pub fn handle_getrusage(who: i32, usage: UserPtr<cputime::Rusage>) -> i64 {
+    if usage.is_null() {
+        return EFAULT;
+    }
+    match cputime::getrusage(who) {
+        Ok(ru) => match usage.write(&ru) {
+            Ok(()) => 0,
+            Err(_) => EFAULT,
+        },
+        Err("Invalid rusage target") => EINVAL,
+        Err(_) => ESRCH,
+    }
+}
+
+/// Handle times() system call
+///
+/// # Arguments
+/// * `buf` - User pointer receiving a `struct tms` (may be null)
+///
+/// # Returns
+/// Elapsed clock ticks since boot
+pub fn handle_times(buf: UserPtr<cputime::Tms>) -> i64 {
+    if !buf.is_null() {
+        match cputime::times() {
+            Ok(tms) => {
+                if buf.write(&tms).is_err() {
+                    return EFAULT;
+                }
+            }
+            Err(_) => return ESRCH,
+        }
+    }
+    task::timer_ticks() as i64
+}
+
+/// Handle clock_gettime() system call
+///
+/// Monotonic clocks read `ktime_ns()`, CLOCK_REALTIME the wall clock set
+/// from the RTC at boot. Clocks that cannot be read are rejected.
+///
+/// # Arguments
+/// * `clock_id` - Clock to read
+/// * `tp` - User pointer receiving a `struct timespec`
+pub fn handle_clock_gettime(clock_id: i32, tp: UserPtr<clocksource::Timespec>) -> i64 {
+    if tp.is_null() {
+        return EFAULT;
+    }
+    match clocksource::clock_gettime(clock_id) {
+        Ok(ts) => match tp.write(&ts) {
+            Ok(()) => 0,
+            Err(_) => EFAULT,
+        },
+        Err(_) => EINVAL,
+    }
+}
+
+/// Dispatch system calls implemented in the kernel crate
+///
+/// Registered with the arch layer, which calls it for syscall numbers it
+/// does not handle itself.
+pub fn dispatch_kernel_syscall(num: u64, args: &[u64; 6]) -> Option<i64> {
+    match num {
+        SYS_GETRUSAGE => Some(handle_getrusage(args[0] as i32, UserPtr::new(args[1]))),
+        SYS_TIMES => Some(handle_times(UserPtr::new(args[0]))),
+        SYS_CLOCK_GETTIME => Some(handle_clock_gettime(args[0] as i32, UserPtr::new(args[1]))),
+        SYS_RT_SIGACTION => Some(sigdeliver::sys_rt_sigaction(
+            args[0] as i32,
+            UserPtr::new(args[1]),
+            UserPtr::new(args[2]),
+        )),
+        SYS_RT_SIGPROCMASK => Some(sigdeliver::sys_rt_sigprocmask(
+            args[0] as i32,
+            UserPtr::new(args[1]),
+            UserPtr::new(args[2]),
+        )),
+        SYS_RT_SIGRETURN => Some(sigdeliver::sys_rt_sigreturn()),
+        SYS_ARCH_PRCTL => Some(tls::sys_arch_prctl(args[0] as i32, args[1])),
+        // clone(flags, stack, parent_tid, child_tid, tls)
+        SYS_CLONE => Some(handle_clone(args[0], args[1], args[4])),
+        SYS_WAIT4 => Some(handle_wait4(args[0] as i64, UserPtr::new(args[1]), args[2] as i32, args[3])),
+        SYS_IOCTL => Some(tty::sys_ioctl(args[0] as i32, args[1] as u32, args[2])),
+        SYS_GETRANDOM => Some(random::sys_getrandom(UserSlice::new(args[0], args[1] as usize), args[2] as u32)),
+        SYS_REBOOT => Some(crate::power::sys_reboot(args[0] as u32, args[1] as u32, args[2] as u32)),
+        SYS_MMAP => Some(mmap::sys_mmap(args[0], args[1], args[2] as u32, args[3] as u32)),
+        SYS_MUNMAP => Some(mmap::sys_munmap(args[0], args[1])),
+        // mbind(addr, len, mode, nodemask, maxnode, flags)
+        SYS_MBIND => Some(policy::sys_mbind(args[0], args[1], args[2] as i32, args[3], args[4], args[5] as u32)),
+        SYS_SET_MEMPOLICY => Some(policy::sys_set_mempolicy(args[0] as i32, args[1], args[2])),
+        _ => None,
+    }
+}
+
+/// Syscall entry hook: syscall latency and tracing
+fn syscall_enter(num: u64) {
+    cputime::syscall_enter(num);
+    crate::tracepoint!(SYS_ENTER, num);
+}
+
+/// Syscall exit hook
+fn syscall_exit(num: u64, ret: i64) {
+    crate::tracepoint!(SYS_EXIT, num, ret);
+    if let Some(ns) = cputime::syscall_exit(num, ret) {
+        crate::profiling::syscall_latency::record(num, ns);
+    }
+}
+
+/// Register kernel syscall handlers, accounting and signal hooks with the arch layer
+pub fn init() {
+    unsafe {
+        fanga_arch_x86_64::syscall::set_syscall_ext_handler(dispatch_kernel_syscall);
+        fanga_arch_x86_64::syscall::set_syscall_hooks(syscall_enter, syscall_exit);
+        fanga_arch_x86_64::syscall::set_stdin_reader(tty::read_stdin);
+        fanga_arch_x86_64::uaccess::set_access_check(crate::memory::vma::check_user_access);
+    }
+    sigdeliver::init();
+}
+
 /// Handle exec() system call
 ///
 /// Loads and executes a new program, replacing the current process.
@@ -82,32 +276,77 @@ pub fn get_current_task() -> Option<TaskId> {
 /// * `binary_data` - The ELF binary data to load
 /// * `argc` - Number of arguments
 /// * `argv` - Array of argument strings
+/// * `envp` - Array of `NAME=value` environment strings
 ///
 /// # Returns
 /// This function does not return on success (process is replaced).
 /// On error, returns a negative error code.
 ///
 /// # Safety
-/// This function is unsafe because it transitions to user mode.
+/// This function is unsafe because it transitions to user mode. The
+/// `argv` and `envp` pointers must point at NUL-terminated strings.
 pub unsafe fn handle_exec(
     binary_data: &[u8],
     argc: usize,
     argv: &[*const u8],
+    envp: &[*const u8],
 ) -> Result<(), i64> {
     // Load the user binary
-    let user_info = match load_user_binary(binary_data, 8192) {
+    let user_info = match load_user_binary(binary_data, 8192, get_current_task()) {
         Ok(info) => info,
         Err(ElfLoadError::InvalidFormat) => return Err(fanga_arch_x86_64::syscall::EINVAL),
         Err(ElfLoadError::UnsupportedType) => return Err(fanga_arch_x86_64::syscall::ENOSYS),
         Err(ElfLoadError::OutOfMemory) => return Err(fanga_arch_x86_64::syscall::ENOMEM),
+        Err(ElfLoadError::MissingLibrary) => return Err(fanga_arch_x86_64::syscall::ENOENT),
         Err(_) => return Err(fanga_arch_x86_64::syscall::EINVAL),
     };
 
     // Prepare the user stack with arguments
-    let stack_pointer = prepare_usermode_stack(user_info.stack_pointer, argc, argv);
+    let c_strings = |pointers: &[*const u8]| -> Vec<&[u8]> {
+        pointers
+            .iter()
+            .map(|&pointer| core::ffi::CStr::from_ptr(pointer.cast()).to_bytes())
+            .collect()
+    };
+    let args = c_strings(&argv[..argc.min(argv.len())]);
+    let env = c_strings(envp);
 
-    fanga_arch_x86_64::serial_println!(
-        "[SYSCALL] exec: entry={:#x}, stack={:#x}",
+    // Fill in the stack and TLS block while the arguments are still mapped
+    let Some(mut space) = AddressSpace::of(user_info.page_table, get_current_task()) else {
+        return Err(fanga_arch_x86_64::syscall::ENOMEM);
+    };
+    let stack_pointer = match user_info.write_initial_memory(&mut space, &args, &env) {
+        Ok(pointer) => pointer,
+        Err(_) => {
+            // SAFETY: no task was given the address space
+            space.destroy();
+            return Err(fanga_arch_x86_64::syscall::E2BIG);
+        }
+    };
+
+    // The new program's address space and areas replace the old one's;
+    // the old address space goes unless another task shares it
+    if let Some(current) = get_current_task() {
+        crate::memory::vma::set_map(current, user_info.areas.clone());
+        let mut scheduler = task::scheduler::scheduler();
+        if let Some(task) = scheduler.get_task_mut(current) {
+            let old = core::mem::replace(&mut task.page_table, user_info.page_table);
+            task.mmap_base = user_info.mmap_base;
+            crate::memory::address_space::activate(user_info.page_table);
+            task::process::release_address_space(&mut scheduler, old, Some(current));
+        }
+    }
+
+    // Point FS at the main thread's TLS block
+    let thread_pointer = user_info.tls.as_ref().map_or(0, |user_tls| user_tls.thread_pointer.as_u64());
+    if let Some(current) = get_current_task() {
+        let mut scheduler = task::scheduler::scheduler();
+        let _ = tls::set_task_fs_base(&mut scheduler, current, thread_pointer);
+    }
+
+    crate::log_debug!(
+        target: "syscall",
+        "exec: entry={:#x}, stack={:#x}",
         user_info.entry_point.as_u64(),
         stack_pointer.as_u64()
     );
@@ -139,4 +378,22 @@ mod tests {
         let result = handle_fork(parent_id);
         assert!(result > 0); // Should return child PID
     }
+
+    #[test]
+    fn test_kernel_syscall_dispatch() {
+        assert_eq!(dispatch_kernel_syscall(SYS_GETRUSAGE, &[0; 6]), Some(EFAULT));
+        assert!(dispatch_kernel_syscall(SYS_TIMES, &[0; 6]).unwrap() >= 0);
+        assert_eq!(dispatch_kernel_syscall(SYS_CLOCK_GETTIME, &[1, 0, 0, 0, 0, 0]), Some(EFAULT));
+
+        let mut ts = clocksource::Timespec::default();
+        let args = [clocksource::CLOCK_MONOTONIC as u64, &mut ts as *mut _ as u64, 0, 0, 0, 0];
+        assert_eq!(dispatch_kernel_syscall(SYS_CLOCK_GETTIME, &args), Some(0));
+        let args = [0, &mut ts as *mut _ as u64, 0, 0, 0, 0];
+        assert_eq!(dispatch_kernel_syscall(SYS_CLOCK_GETTIME, &args), Some(EINVAL));
+        assert_eq!(dispatch_kernel_syscall(SYS_IOCTL, &[7, 0x5401, 0, 0, 0, 0]), Some(crate::syscall::ENOTTY));
+        assert_eq!(dispatch_kernel_syscall(SYS_GETRANDOM, &[0, 16, 0x8, 0, 0, 0]), Some(EINVAL));
+        assert_eq!(dispatch_kernel_syscall(SYS_SET_MEMPOLICY, &[9, 0, 0, 0, 0, 0]), Some(EINVAL));
+        assert_eq!(dispatch_kernel_syscall(SYS_MBIND, &[0x1001, 0x1000, 0, 0, 0, 0]), Some(EINVAL));
+        assert_eq!(dispatch_kernel_syscall(0xFFFF, &[0; 6]), None);
+    }
 }
@@ -0,0 +1,594 @@
+//! Control Groups
+//!
+//! This module provides hierarchical resource control for tasks:
+//! - A tree of groups rooted at an unlimited root group
+//! - CPU bandwidth caps (quota ticks per period), enforced by the scheduler
+//!   through the per-task `throttled` flag
+//! - Memory limits, enforced when a process's frames are charged as they
+//!   are allocated (loading a binary, page faults) and uncharged as they
+//!   are freed
+//! - Limits apply to a group and all of its descendants
+//! - Children inherit their parent's group on fork
+//!
+//! Tasks that were never attached belong to the root group.
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+
+use super::scheduler::Scheduler;
+use super::tcb::TaskId;
+
+/// Maximum number of groups
+pub const MAX_CGROUPS: usize = 64;
+
+/// Default CPU bandwidth period in ticks (100ms at 100 Hz)
+pub const DEFAULT_CPU_PERIOD: u64 = 10;
+
+/// Group identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CgroupId(pub usize);
+
+/// The root group
+pub const ROOT_CGROUP: CgroupId = CgroupId(0);
+
+/// CPU bandwidth limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMax {
+    /// Ticks the group may run per period
+    pub quota: u64,
+    /// Period length in ticks
+    pub period: u64,
+}
+
+/// A control group
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    pub id: CgroupId,
+    pub name: String,
+    pub parent: Option<CgroupId>,
+    pub children: Vec<CgroupId>,
+    pub tasks: Vec<TaskId>,
+    /// CPU bandwidth limit (None = unlimited)
+    pub cpu_max: Option<CpuMax>,
+    /// Ticks used in the current period (including descendants)
+    pub cpu_period_usage: u64,
+    /// Total ticks used (including descendants)
+    pub cpu_usage_total: u64,
+    /// Whether the group exhausted its quota this period
+    pub throttled: bool,
+    /// Number of periods in which the group was throttled
+    pub nr_throttled: u64,
+    /// Memory limit in bytes (None = unlimited)
+    pub memory_max: Option<usize>,
+    /// Memory charged to the group (including descendants)
+    pub memory_current: usize,
+    /// Number of charges refused because of the limit
+    pub memory_events_max: u64,
+}
+
+impl Cgroup {
+    fn new(id: CgroupId, name: &str, parent: Option<CgroupId>) -> Self {
+        Self {
+            id,
+            name: String::from(name),
+            parent,
+            children: Vec::new(),
+            tasks: Vec::new(),
+            cpu_max: None,
+            cpu_period_usage: 0,
+            cpu_usage_total: 0,
+            throttled: false,
+            nr_throttled: 0,
+            memory_max: None,
+            memory_current: 0,
+            memory_events_max: 0,
+        }
+    }
+}
+
+/// The group hierarchy
+pub struct CgroupTree {
+    groups: Vec<Option<Cgroup>>,
+    membership: BTreeMap<TaskId, CgroupId>,
+    /// Memory charged per task, so it can be released on exit
+    task_memory: BTreeMap<TaskId, usize>,
+    /// Ticks elapsed in the current bandwidth period, per group
+    period_ticks: BTreeMap<CgroupId, u64>,
+}
+
+impl CgroupTree {
+    /// Create a hierarchy containing only the root group
+    pub fn new() -> Self {
+        let mut groups = Vec::new();
+        groups.resize_with(MAX_CGROUPS, || None);
+        groups[0] = Some(Cgroup::new(ROOT_CGROUP, "/", None));
+
+        Self {
+            groups,
+            membership: BTreeMap::new(),
+            task_memory: BTreeMap::new(),
+            period_ticks: BTreeMap::new(),
+        }
+    }
+
+    /// Look up a group
+    pub fn get(&self, id: CgroupId) -> Option<&Cgroup> {
+        self.groups.get(id.0)?.as_ref()
+    }
+
+    fn get_mut(&mut self, id: CgroupId) -> Result<&mut Cgroup, &'static str> {
+        self.groups
+            .get_mut(id.0)
+            .and_then(|g| g.as_mut())
+            .ok_or("Cgroup not found")
+    }
+
+    /// Create a child group
+    pub fn create(&mut self, parent: CgroupId, name: &str) -> Result<CgroupId, &'static str> {
+        let parent_group = self.get(parent).ok_or("Parent cgroup not found")?;
+        if parent_group.children.iter().any(|c| self.get(*c).map(|g| g.name == name).unwrap_or(false)) {
+            return Err("Cgroup already exists");
+        }
+
+        let slot = self
+            .groups
+            .iter()
+            .position(|g| g.is_none())
+            .ok_or("Maximum number of cgroups reached")?;
+
+        let id = CgroupId(slot);
+        self.groups[slot] = Some(Cgroup::new(id, name, Some(parent)));
+        self.get_mut(parent)?.children.push(id);
+        Ok(id)
+    }
+
+    /// Remove an empty group
+    pub fn remove(&mut self, id: CgroupId) -> Result<(), &'static str> {
+        if id == ROOT_CGROUP {
+            return Err("Cannot remove the root cgroup");
+        }
+        let group = self.get(id).ok_or("Cgroup not found")?;
+        if !group.tasks.is_empty() || !group.children.is_empty() {
+            return Err("Cgroup is not empty");
+        }
+
+        if let Some(parent) = group.parent {
+            self.get_mut(parent)?.children.retain(|c| *c != id);
+        }
+        self.groups[id.0] = None;
+        self.period_ticks.remove(&id);
+        Ok(())
+    }
+
+    /// Set the CPU bandwidth limit of a group (None removes it)
+    pub fn set_cpu_max(&mut self, id: CgroupId, max: Option<CpuMax>) -> Result<(), &'static str> {
+        if id == ROOT_CGROUP {
+            return Err("Cannot limit the root cgroup");
+        }
+        if let Some(m) = max {
+            if m.period == 0 || m.quota == 0 {
+                return Err("Invalid CPU limit");
+            }
+        }
+        let group = self.get_mut(id)?;
+        group.cpu_max = max;
+        group.cpu_period_usage = 0;
+        group.throttled = false;
+        Ok(())
+    }
+
+    /// Set the memory limit of a group (None removes it)
+    pub fn set_memory_max(&mut self, id: CgroupId, max: Option<usize>) -> Result<(), &'static str> {
+        if id == ROOT_CGROUP {
+            return Err("Cannot limit the root cgroup");
+        }
+        self.get_mut(id)?.memory_max = max;
+        Ok(())
+    }
+
+    /// Group a task belongs to
+    pub fn group_of(&self, task: TaskId) -> CgroupId {
+        self.membership.get(&task).copied().unwrap_or(ROOT_CGROUP)
+    }
+
+    /// The group and all its ancestors, innermost first
+    fn ancestry(&self, id: CgroupId) -> Vec<CgroupId> {
+        let mut chain = Vec::new();
+        let mut cur = Some(id);
+        while let Some(g) = cur {
+            chain.push(g);
+            cur = self.get(g).and_then(|g| g.parent);
+        }
+        chain
+    }
+
+    /// Move a task into a group, transferring its memory charge
+    pub fn attach(&mut self, id: CgroupId, task: TaskId) -> Result<(), &'static str> {
+        self.get(id).ok_or("Cgroup not found")?;
+        let old = self.group_of(task);
+        if old == id {
+            if id == ROOT_CGROUP && !self.membership.contains_key(&task) {
+                self.get_mut(id)?.tasks.push(task);
+                self.membership.insert(task, id);
+            }
+            return Ok(());
+        }
+
+        let charged = self.task_memory.get(&task).copied().unwrap_or(0);
+        for g in self.ancestry(old) {
+            let group = self.get_mut(g)?;
+            group.memory_current = group.memory_current.saturating_sub(charged);
+        }
+        for g in self.ancestry(id) {
+            self.get_mut(g)?.memory_current += charged;
+        }
+
+        if let Ok(group) = self.get_mut(old) {
+            group.tasks.retain(|t| *t != task);
+        }
+        self.get_mut(id)?.tasks.push(task);
+        self.membership.insert(task, id);
+        Ok(())
+    }
+
+    /// Put a forked child in its parent's group
+    pub fn inherit(&mut self, parent: TaskId, child: TaskId) -> Result<(), &'static str> {
+        let group = self.group_of(parent);
+        self.attach(group, child)
+    }
+
+    /// Drop a task from the hierarchy, releasing its memory charge
+    pub fn task_exit(&mut self, task: TaskId) {
+        let group = self.group_of(task);
+        let charged = self.task_memory.remove(&task).unwrap_or(0);
+        for g in self.ancestry(group) {
+            if let Ok(group) = self.get_mut(g) {
+                group.memory_current = group.memory_current.saturating_sub(charged);
+            }
+        }
+        if let Ok(group) = self.get_mut(group) {
+            group.tasks.retain(|t| *t != task);
+        }
+        self.membership.remove(&task);
+    }
+
+    /// Charge memory to a task's group, failing if any limit in the
+    /// hierarchy would be exceeded
+    pub fn try_charge_memory(&mut self, task: TaskId, bytes: usize) -> Result<(), &'static str> {
+        let chain = self.ancestry(self.group_of(task));
+
+        for g in &chain {
+            let group = self.get(*g).ok_or("Cgroup not found")?;
+            if let Some(max) = group.memory_max {
+                if group.memory_current + bytes > max {
+                    self.get_mut(*g)?.memory_events_max += 1;
+                    return Err("Cgroup memory limit exceeded");
+                }
+            }
+        }
+
+        for g in chain {
+            self.get_mut(g)?.memory_current += bytes;
+        }
+        *self.task_memory.entry(task).or_insert(0) += bytes;
+        Ok(())
+    }
+
+    /// Release memory previously charged to a task
+    pub fn uncharge_memory(&mut self, task: TaskId, bytes: usize) {
+        let charged = self.task_memory.entry(task).or_insert(0);
+        let bytes = bytes.min(*charged);
+        *charged -= bytes;
+
+        for g in self.ancestry(self.group_of(task)) {
+            if let Ok(group) = self.get_mut(g) {
+                group.memory_current = group.memory_current.saturating_sub(bytes);
+            }
+        }
+    }
+
+    /// Whether a task may be scheduled (no group on its path is throttled)
+    pub fn task_runnable(&self, task: TaskId) -> bool {
+        self.ancestry(self.group_of(task))
+            .iter()
+            .all(|g| !self.get(*g).map(|g| g.throttled).unwrap_or(false))
+    }
+
+    /// Update the throttled flag of every task below `id`
+    fn sync_throttle(&self, sched: &mut Scheduler, id: CgroupId) {
+        let Some(group) = self.get(id) else { return };
+        for task in &group.tasks {
+            let runnable = self.task_runnable(*task);
+            let _ = sched.set_throttled(*task, !runnable);
+        }
+        for child in group.children.clone() {
+            self.sync_throttle(sched, child);
+        }
+    }
+
+    /// Charge a timer tick to `current` and advance bandwidth periods
+    ///
+    /// Called once per tick. Groups exceeding their quota are throttled
+    /// until their period ends.
+    pub fn account_tick(&mut self, sched: &mut Scheduler, current: Option<TaskId>) {
+        if let Some(task) = current {
+            for g in self.ancestry(self.group_of(task)) {
+                let mut newly_throttled = false;
+                if let Ok(group) = self.get_mut(g) {
+                    group.cpu_usage_total += 1;
+                    group.cpu_period_usage += 1;
+                    if let Some(max) = group.cpu_max {
+                        if group.cpu_period_usage >= max.quota && !group.throttled {
+                            group.throttled = true;
+                            group.nr_throttled += 1;
+                            newly_throttled = true;
+                        }
+                    }
+                }
+                if newly_throttled {
+                    self.sync_throttle(sched, g);
+                }
+            }
+        }
+
+        // Advance periods of limited groups
+        let limited: Vec<(CgroupId, CpuMax)> = self
+            .groups
+            .iter()
+            .flatten()
+            .filter_map(|g| g.cpu_max.map(|m| (g.id, m)))
+            .collect();
+
+        for (id, max) in limited {
+            let elapsed = self.period_ticks.entry(id).or_insert(0);
+            *elapsed += 1;
+            if *elapsed >= max.period {
+                *elapsed = 0;
+                let mut was_throttled = false;
+                if let Ok(group) = self.get_mut(id) {
+                    was_throttled = group.throttled;
+                    group.cpu_period_usage = 0;
+                    group.throttled = false;
+                }
+                if was_throttled {
+                    self.sync_throttle(sched, id);
+                }
+            }
+        }
+    }
+
+    /// Render a group's limits and usage in cgroup v2 interface style
+    pub fn render(&self, id: CgroupId) -> Option<String> {
+        let group = self.get(id)?;
+        let mut out = String::new();
+
+        let _ = match group.cpu_max {
+            Some(m) => writeln!(out, "cpu.max {} {}", m.quota, m.period),
+            None => writeln!(out, "cpu.max max {}", DEFAULT_CPU_PERIOD),
+        };
+        let _ = writeln!(out, "cpu.stat usage_ticks {} nr_throttled {}", group.cpu_usage_total, group.nr_throttled);
+        let _ = match group.memory_max {
+            Some(m) => writeln!(out, "memory.max {}", m),
+            None => writeln!(out, "memory.max max"),
+        };
+        let _ = writeln!(out, "memory.current {}", group.memory_current);
+        let _ = writeln!(out, "memory.events max {}", group.memory_events_max);
+        let _ = write!(out, "cgroup.procs");
+        for t in &group.tasks {
+            let _ = write!(out, " {}", t.as_usize());
+        }
+        out.push('\n');
+        Some(out)
+    }
+
+    /// Iterate over all groups
+    pub fn iter(&self) -> impl Iterator<Item = &Cgroup> {
+        self.groups.iter().flatten()
+    }
+}
+
+impl Default for CgroupTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global cgroup hierarchy
+static CGROUPS: spin::Once<Mutex<CgroupTree>> = spin::Once::new();
+
+/// Initialize the cgroup hierarchy
+pub fn init() {
+    CGROUPS.call_once(|| Mutex::new(CgroupTree::new()));
+}
+
+/// Get the global cgroup hierarchy
+///
+/// # Panics
+/// Panics if the hierarchy has not been initialized
+pub fn cgroups() -> spin::MutexGuard<'static, CgroupTree> {
+    CGROUPS.get().expect("Cgroups not initialized").lock()
+}
+
+/// Create a group under `parent`
+pub fn create(parent: CgroupId, name: &str) -> Result<CgroupId, &'static str> {
+    cgroups().create(parent, name)
+}
+
+/// Limit a group to `quota` ticks of CPU every `period` ticks
+pub fn set_cpu_limit(id: CgroupId, quota: u64, period: u64) -> Result<(), &'static str> {
+    cgroups().set_cpu_max(id, Some(CpuMax { quota, period }))
+}
+
+/// Limit a group's memory usage
+pub fn set_memory_limit(id: CgroupId, bytes: usize) -> Result<(), &'static str> {
+    cgroups().set_memory_max(id, Some(bytes))
+}
+
+/// Move a task into a group
+pub fn add_task(id: CgroupId, task: TaskId) -> Result<(), &'static str> {
+    cgroups().attach(id, task)
+}
+
+/// Charge memory to a task's group (no-op before init)
+pub fn try_charge_memory(task: TaskId, bytes: usize) -> Result<(), &'static str> {
+    match CGROUPS.get() {
+        Some(cg) => cg.lock().try_charge_memory(task, bytes),
+        None => Ok(()),
+    }
+}
+
+/// Release memory charged to a task's group
+pub fn uncharge_memory(task: TaskId, bytes: usize) {
+    if let Some(cg) = CGROUPS.get() {
+        cg.lock().uncharge_memory(task, bytes);
+    }
+}
+
+/// Put a forked child in its parent's group
+pub fn fork(parent: TaskId, child: TaskId) {
+    if let Some(cg) = CGROUPS.get() {
+        let _ = cg.lock().inherit(parent, child);
+    }
+}
+
+/// Drop an exiting task from the hierarchy
+pub fn exit(task: TaskId) {
+    if let Some(cg) = CGROUPS.get() {
+        cg.lock().task_exit(task);
+    }
+}
+
+/// Timer tick hook (interrupt context, never spins on the lock)
+pub fn account_tick(sched: &mut Scheduler) {
+    if let Some(cg) = CGROUPS.get() {
+        if let Some(mut cg) = cg.try_lock() {
+            let current = sched.current_task();
+            cg.account_tick(sched, current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{PhysAddr, VirtAddr};
+    use crate::task::tcb::{Task, TaskPriority};
+
+    fn add_task(sched: &mut Scheduler) -> TaskId {
+        let task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0),
+            TaskPriority::Normal,
+        );
+        sched.add_task(task).unwrap()
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let mut tree = CgroupTree::new();
+        let a = tree.create(ROOT_CGROUP, "a").unwrap();
+        let b = tree.create(a, "b").unwrap();
+        assert!(tree.create(ROOT_CGROUP, "a").is_err());
+
+        assert!(tree.remove(a).is_err());
+        tree.remove(b).unwrap();
+        tree.remove(a).unwrap();
+        assert!(tree.remove(ROOT_CGROUP).is_err());
+        assert_eq!(tree.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_hierarchical_memory_limit() {
+        let mut tree = CgroupTree::new();
+        let parent = tree.create(ROOT_CGROUP, "parent").unwrap();
+        let child = tree.create(parent, "child").unwrap();
+        tree.set_memory_max(parent, Some(8192)).unwrap();
+
+        let task = TaskId::new(1);
+        tree.attach(child, task).unwrap();
+
+        tree.try_charge_memory(task, 4096).unwrap();
+        tree.try_charge_memory(task, 4096).unwrap();
+        assert!(tree.try_charge_memory(task, 1).is_err());
+        assert_eq!(tree.get(parent).unwrap().memory_events_max, 1);
+        assert_eq!(tree.get(child).unwrap().memory_current, 8192);
+
+        tree.uncharge_memory(task, 4096);
+        assert_eq!(tree.get(parent).unwrap().memory_current, 4096);
+
+        tree.task_exit(task);
+        assert_eq!(tree.get(parent).unwrap().memory_current, 0);
+        assert!(tree.get(child).unwrap().tasks.is_empty());
+    }
+
+    #[test]
+    fn test_attach_moves_charge() {
+        let mut tree = CgroupTree::new();
+        let a = tree.create(ROOT_CGROUP, "a").unwrap();
+        let b = tree.create(ROOT_CGROUP, "b").unwrap();
+        let task = TaskId::new(3);
+
+        tree.attach(a, task).unwrap();
+        tree.try_charge_memory(task, 100).unwrap();
+        tree.attach(b, task).unwrap();
+
+        assert_eq!(tree.get(a).unwrap().memory_current, 0);
+        assert_eq!(tree.get(b).unwrap().memory_current, 100);
+        assert_eq!(tree.group_of(task), b);
+
+        let child = TaskId::new(4);
+        tree.inherit(task, child).unwrap();
+        assert_eq!(tree.group_of(child), b);
+    }
+
+    #[test]
+    fn test_cpu_throttling() {
+        let mut sched = Scheduler::new();
+        sched.init();
+        let limited = add_task(&mut sched);
+        let free = add_task(&mut sched);
+
+        let mut tree = CgroupTree::new();
+        let g = tree.create(ROOT_CGROUP, "batch").unwrap();
+        tree.set_cpu_max(g, Some(CpuMax { quota: 2, period: 5 })).unwrap();
+        tree.attach(g, limited).unwrap();
+
+        tree.account_tick(&mut sched, Some(limited));
+        assert!(!sched.get_task(limited).unwrap().throttled);
+        tree.account_tick(&mut sched, Some(limited));
+        assert!(sched.get_task(limited).unwrap().throttled);
+        assert!(!tree.task_runnable(limited));
+        assert!(tree.task_runnable(free));
+
+        // The scheduler skips the throttled task
+        let (_, next, _) = sched.schedule();
+        assert_eq!(next, Some(free));
+
+        // Quota is refilled at the end of the period
+        for _ in 0..3 {
+            tree.account_tick(&mut sched, Some(free));
+        }
+        assert!(!sched.get_task(limited).unwrap().throttled);
+        assert_eq!(tree.get(g).unwrap().nr_throttled, 1);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut tree = CgroupTree::new();
+        let g = tree.create(ROOT_CGROUP, "web").unwrap();
+        tree.set_memory_max(g, Some(4096)).unwrap();
+        tree.attach(g, TaskId::new(7)).unwrap();
+
+        let text = tree.render(g).unwrap();
+        assert!(text.contains("memory.max 4096"));
+        assert!(text.contains("cpu.max max"));
+        assert!(text.contains("cgroup.procs 7"));
+    }
+}
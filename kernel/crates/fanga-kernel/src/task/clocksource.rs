@@ -0,0 +1,261 @@
+//! Clock Sources
+//!
+//! `ktime_ns()` is the kernel's monotonic clock in nanoseconds since boot.
+//! With an invariant TSC it reads the TSC and scales it with a
+//! precomputed multiplier, which costs a few cycles and has sub-microsecond
+//! resolution. Otherwise, or before `init()`, it counts system ticks from
+//! the PIT or APIC timer, at 10ms resolution.
+//!
+//! The TSC is taken over with the tick count as its starting point, so the
+//! clock does not jump when it switches. Readings are clamped to never go
+//! backwards, which also hides small TSC offsets between CPUs.
+//!
+//! This module provides:
+//! - `ktime_ns()` and coarser variants
+//! - Invariant TSC detection and calibration at boot
+//! - Cycle to nanosecond conversion
+//! - `clock_gettime` clocks, with CLOCK_REALTIME from `realtime`
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use fanga_arch_x86_64::tsc;
+
+use super::tickless::TICK_MS;
+
+/// Nanoseconds per system tick
+const NSEC_PER_TICK: u64 = TICK_MS * 1_000_000;
+
+/// Fixed-point shift of the cycle to nanosecond multiplier
+const SCALE_SHIFT: u32 = 32;
+
+/// Slowest and fastest TSC frequency accepted from calibration, in kHz
+const MIN_TSC_KHZ: u64 = 100_000;
+const MAX_TSC_KHZ: u64 = 10_000_000;
+
+/// Hardware behind `ktime_ns()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// System tick counter (PIT or APIC timer)
+    Tick = 0,
+    /// Invariant time stamp counter
+    Tsc = 1,
+}
+
+impl ClockSource {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ClockSource::Tsc,
+            _ => ClockSource::Tick,
+        }
+    }
+
+    /// Short name of the clock source
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Tick => "tick",
+            ClockSource::Tsc => "tsc",
+        }
+    }
+}
+
+/// Conversion from counter cycles to nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleScale {
+    /// Nanoseconds per cycle, scaled by 2^SCALE_SHIFT
+    mult: u64,
+}
+
+impl CycleScale {
+    /// Create the conversion for a counter running at `khz`
+    pub fn from_khz(khz: u64) -> Option<Self> {
+        if khz == 0 {
+            return None;
+        }
+        Some(Self {
+            mult: ((1_000_000u128 << SCALE_SHIFT) / khz as u128) as u64,
+        })
+    }
+
+    /// Convert a cycle count to nanoseconds
+    #[inline]
+    pub fn to_ns(&self, cycles: u64) -> u64 {
+        ((cycles as u128 * self.mult as u128) >> SCALE_SHIFT) as u64
+    }
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Tick as u8);
+
+/// Cycle multiplier (a `CycleScale`), valid once SOURCE is Tsc
+static TSC_MULT: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value and clock reading at the switch to the TSC
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static NS_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// TSC frequency in kHz, 0 if unused
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Latest value returned by `ktime_ns()`
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Clock IDs accepted by `clock_gettime`
+pub const CLOCK_REALTIME: i32 = 0;
+pub const CLOCK_MONOTONIC: i32 = 1;
+pub const CLOCK_MONOTONIC_RAW: i32 = 4;
+pub const CLOCK_REALTIME_COARSE: i32 = 5;
+pub const CLOCK_MONOTONIC_COARSE: i32 = 6;
+pub const CLOCK_BOOTTIME: i32 = 7;
+
+/// `struct timespec` as returned by `clock_gettime`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    /// Convert nanoseconds to a time value
+    pub fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / 1_000_000_000) as i64,
+            tv_nsec: (ns % 1_000_000_000) as i64,
+        }
+    }
+}
+
+/// Read a clock for `clock_gettime`
+///
+/// Time spent suspended to RAM is not counted and the kernel never slews
+/// its clock, so all monotonic clocks read the same. The realtime clocks fail until the RTC has been read.
+pub fn clock_gettime(clock_id: i32) -> Result<Timespec, &'static str> {
+    match clock_id {
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_BOOTTIME => Ok(Timespec::from_ns(ktime_ns())),
+        CLOCK_MONOTONIC_COARSE => Ok(Timespec::from_ns(super::time::timer_ticks() * NSEC_PER_TICK)),
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => super::realtime::realtime_ns()
+            .map(Timespec::from_ns)
+            .ok_or("No wall clock"),
+        _ => Err("Invalid clock"),
+    }
+}
+
+/// Check whether a calibrated TSC frequency is plausible
+pub fn khz_plausible(khz: u64) -> bool {
+    (MIN_TSC_KHZ..=MAX_TSC_KHZ).contains(&khz)
+}
+
+/// Get the active clock source
+pub fn clocksource() -> ClockSource {
+    ClockSource::from_u8(SOURCE.load(Ordering::Acquire))
+}
+
+/// TSC frequency in kHz, if the TSC is the clock source
+pub fn tsc_khz() -> Option<u64> {
+    (clocksource() == ClockSource::Tsc).then(|| TSC_KHZ.load(Ordering::Relaxed))
+}
+
+/// Monotonic time since boot in nanoseconds
+pub fn ktime_ns() -> u64 {
+    let now = match clocksource() {
+        ClockSource::Tsc => {
+            let scale = CycleScale { mult: TSC_MULT.load(Ordering::Relaxed) };
+            let cycles = tsc::rdtsc().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+            NS_BASE.load(Ordering::Relaxed) + scale.to_ns(cycles)
+        }
+        ClockSource::Tick => super::time::timer_ticks() * NSEC_PER_TICK,
+    };
+    let last = LAST_NS.fetch_max(now, Ordering::Relaxed);
+    now.max(last)
+}
+
+/// Monotonic time since boot in microseconds
+pub fn ktime_us() -> u64 {
+    ktime_ns() / 1000
+}
+
+/// Monotonic time since boot in milliseconds
+pub fn ktime_ms() -> u64 {
+    ktime_ns() / 1_000_000
+}
+
+/// Switch `ktime_ns()` to the TSC if it is invariant
+///
+/// Called once at boot, after the tick source is set up.
+pub fn init() {
+    if !tsc::is_invariant() {
+        crate::log_warn!(target: "time", "TSC not invariant, clock source: tick");
+        return;
+    }
+
+    let khz = fanga_arch_x86_64::interrupts::without_interrupts(|| unsafe { tsc::frequency_khz() });
+    let scale = match CycleScale::from_khz(khz) {
+        Some(scale) if khz_plausible(khz) => scale,
+        _ => {
+            crate::log_warn!(target: "time", "TSC calibration failed ({} kHz), clock source: tick", khz);
+            return;
+        }
+    };
+
+    // Continue from the tick-based clock so time never steps back
+    NS_BASE.store(ktime_ns(), Ordering::Relaxed);
+    TSC_BASE.store(tsc::rdtsc(), Ordering::Relaxed);
+    TSC_MULT.store(scale.mult, Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    SOURCE.store(ClockSource::Tsc as u8, Ordering::Release);
+
+    crate::log_info!(target: "time", "Clock source: tsc ({} MHz)", khz / 1000);
+}
+
+/// Rebase the TSC clock after the machine wakes from S3
+///
+/// The TSC restarts from about zero on wake. The clock continues from its
+/// last reading, leaving out the time spent asleep.
+pub fn resume() {
+    if clocksource() == ClockSource::Tsc {
+        NS_BASE.store(LAST_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+        TSC_BASE.store(tsc::rdtsc(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_scale() {
+        // 1 GHz: one cycle per nanosecond
+        let scale = CycleScale::from_khz(1_000_000).unwrap();
+        assert_eq!(scale.to_ns(12_345), 12_345);
+
+        // 3 GHz: a second's worth of cycles, within a nanosecond
+        let scale = CycleScale::from_khz(3_000_000).unwrap();
+        assert!(scale.to_ns(3_000_000_000).abs_diff(1_000_000_000) <= 1);
+
+        assert!(CycleScale::from_khz(0).is_none());
+    }
+
+    #[test]
+    fn test_khz_plausible() {
+        assert!(khz_plausible(2_400_000));
+        assert!(!khz_plausible(0));
+        assert!(!khz_plausible(50_000_000));
+    }
+
+    #[test]
+    fn test_ktime_monotonic() {
+        // The host never switches to the TSC
+        assert_eq!(clocksource(), ClockSource::Tick);
+        let a = ktime_ns();
+        assert!(ktime_ns() >= a);
+        assert_eq!(tsc_khz(), None);
+    }
+
+    #[test]
+    fn test_clock_gettime() {
+        assert_eq!(Timespec::from_ns(1_500_000_000), Timespec { tv_sec: 1, tv_nsec: 500_000_000 });
+        assert!(clock_gettime(CLOCK_MONOTONIC).is_ok());
+        assert!(clock_gettime(CLOCK_REALTIME).is_err());
+        assert!(clock_gettime(42).is_err());
+    }
+}
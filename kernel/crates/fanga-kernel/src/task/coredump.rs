@@ -5,6 +5,7 @@
 //! - Dump CPU registers
 //! - Dump memory contents
 //! - Store core dumps for later analysis
+//! - Write ELF core files (`core.<pid>`) to the VFS for host debuggers
 
 extern crate alloc;
 use alloc::vec::Vec;
@@ -14,7 +15,9 @@ use alloc::format;
 use super::tcb::{TaskId, TaskState, TaskPriority};
 use super::context::TaskContext;
 use super::thread::ThreadId;
+use crate::fs::FsError;
 use crate::memory::{PhysAddr, VirtAddr};
+use spin::{Mutex, Once};
 
 /// Reason for core dump
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,7 +54,7 @@ impl CoreDumpReason {
 }
 
 /// CPU register state snapshot
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct RegisterDump {
     /// General purpose registers
     pub rax: u64,
@@ -437,6 +440,49 @@ impl Default for CoreDumpManager {
     }
 }
 
+/// Maximum number of core dumps kept in memory
+pub const MAX_CORE_DUMPS: usize = 8;
+
+/// Global core dump manager
+static CORE_DUMPS: Once<Mutex<CoreDumpManager>> = Once::new();
+
+/// Initialize the global core dump manager
+pub fn init() {
+    CORE_DUMPS.call_once(|| Mutex::new(CoreDumpManager::new(MAX_CORE_DUMPS)));
+}
+
+/// Get the global core dump manager
+pub fn core_dumps() -> &'static Mutex<CoreDumpManager> {
+    CORE_DUMPS.get().expect("Core dump manager not initialized")
+}
+
+/// Path of the core file written for a process
+pub fn core_file_name(pid: TaskId) -> String {
+    format!("/core.{}", pid.as_usize())
+}
+
+/// Write a dump to the root file system as an ELF core file
+///
+/// Returns the path of the written file.
+pub fn write_core_file(dump: &CoreDump) -> Result<String, FsError> {
+    let path = core_file_name(dump.process_id);
+    crate::fs::write_file(&path, &crate::elf::write_core(dump))?;
+    Ok(path)
+}
+
+/// Save a dump: write its core file, then keep it in memory
+///
+/// Returns the core file path, or `None` if it could not be written.
+pub fn record_dump(dump: CoreDump) -> Option<String> {
+    let mut manager = core_dumps().lock();
+    if !manager.is_enabled() {
+        return None;
+    }
+    let path = write_core_file(&dump).ok();
+    manager.add_dump(dump);
+    path
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -563,4 +609,9 @@ mod tests {
         
         assert_eq!(manager.count(), 0); // Should not be added
     }
+
+    #[test]
+    fn test_core_file_name() {
+        assert_eq!(core_file_name(TaskId::new(42)), "/core.42");
+    }
 }
@@ -0,0 +1,310 @@
+//! CPU Time Accounting
+//!
+//! This module tracks per-task resource usage:
+//! - User and system time, sampled on every timer tick from the privilege
+//!   level the tick interrupted
+//! - How long each syscall took
+//! - Voluntary (counted in `Scheduler::block_task`) and involuntary
+//!   context switches
+//! - Maximum resident set size, updated as pages are mapped for the task
+//!   and sampled on every tick
+//!
+//! The data is exposed through `getrusage()` and `times()` and shown by
+//! the `ps` shell command.
+
+use super::clocksource;
+use super::scheduler::{self, Scheduler};
+use super::tcb::TaskId;
+
+/// Clock ticks per second reported by `times()` (matches the 100 Hz tick)
+pub const CLK_TCK: u64 = 100;
+
+/// Microseconds per timer tick
+const USEC_PER_TICK: u64 = 1_000_000 / CLK_TCK;
+
+/// `getrusage` target: the calling process
+pub const RUSAGE_SELF: i32 = 0;
+/// `getrusage` target: terminated children of the calling process
+pub const RUSAGE_CHILDREN: i32 = -1;
+/// `getrusage` target: the calling thread
+pub const RUSAGE_THREAD: i32 = 1;
+
+/// Per-task CPU time and scheduling counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    /// Ticks spent executing in user mode
+    pub utime_ticks: u64,
+    /// Ticks spent executing in kernel mode
+    pub stime_ticks: u64,
+    /// Voluntary context switches (task blocked)
+    pub nvcsw: u64,
+    /// Involuntary context switches (task preempted)
+    pub nivcsw: u64,
+    /// Maximum resident set size in KiB
+    pub maxrss_kb: u64,
+    /// Time the running syscall was entered in nanoseconds since boot,
+    /// 0 outside a syscall
+    pub syscall_entry_ns: u64,
+}
+
+impl CpuTimes {
+    /// Create zeroed counters
+    pub const fn new() -> Self {
+        Self {
+            utime_ticks: 0,
+            stime_ticks: 0,
+            nvcsw: 0,
+            nivcsw: 0,
+            maxrss_kb: 0,
+            syscall_entry_ns: 0,
+        }
+    }
+
+    /// Charge one timer tick to user or kernel mode
+    pub fn charge_tick(&mut self, in_user: bool) {
+        if in_user {
+            self.utime_ticks += 1;
+        } else {
+            self.stime_ticks += 1;
+        }
+    }
+
+    /// Total CPU ticks consumed
+    pub fn total_ticks(&self) -> u64 {
+        self.utime_ticks + self.stime_ticks
+    }
+
+    /// Record a new resident set size, keeping the maximum
+    pub fn update_rss(&mut self, rss_kb: u64) {
+        self.maxrss_kb = self.maxrss_kb.max(rss_kb);
+    }
+}
+
+/// Time value as used by `struct rusage`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timeval {
+    /// Convert timer ticks to a time value
+    pub fn from_ticks(ticks: u64) -> Self {
+        let usec = ticks * USEC_PER_TICK;
+        Self {
+            tv_sec: (usec / 1_000_000) as i64,
+            tv_usec: (usec % 1_000_000) as i64,
+        }
+    }
+}
+
+/// Resource usage (Linux `struct rusage` layout)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rusage {
+    pub ru_utime: Timeval,
+    pub ru_stime: Timeval,
+    pub ru_maxrss: i64,
+    pub ru_ixrss: i64,
+    pub ru_idrss: i64,
+    pub ru_isrss: i64,
+    pub ru_minflt: i64,
+    pub ru_majflt: i64,
+    pub ru_nswap: i64,
+    pub ru_inblock: i64,
+    pub ru_oublock: i64,
+    pub ru_msgsnd: i64,
+    pub ru_msgrcv: i64,
+    pub ru_nsignals: i64,
+    pub ru_nvcsw: i64,
+    pub ru_nivcsw: i64,
+}
+
+impl Rusage {
+    /// Build a resource usage record from task counters
+    pub fn from_times(times: &CpuTimes) -> Self {
+        Self {
+            ru_utime: Timeval::from_ticks(times.utime_ticks),
+            ru_stime: Timeval::from_ticks(times.stime_ticks),
+            ru_maxrss: times.maxrss_kb as i64,
+            ru_nvcsw: times.nvcsw as i64,
+            ru_nivcsw: times.nivcsw as i64,
+            ..Self::default()
+        }
+    }
+}
+
+/// Process times in clock ticks (`struct tms`)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tms {
+    pub tms_utime: i64,
+    pub tms_stime: i64,
+    pub tms_cutime: i64,
+    pub tms_cstime: i64,
+}
+
+/// Charge the current tick to the running task, and sample its resident
+/// set size
+pub fn account_tick(sched: &mut Scheduler, in_user: bool) {
+    if let Some(task) = sched.current_task_mut() {
+        task.times.charge_tick(in_user);
+        task.times.update_rss(crate::memory::address_space::resident_kb(task.id));
+    }
+}
+
+/// Record an involuntary context switch away from `task`
+pub fn account_preemption(sched: &mut Scheduler, task: TaskId) {
+    if let Some(task) = sched.get_task_mut(task) {
+        task.times.nivcsw += 1;
+    }
+}
+
+/// Run `f` on the current task's counters
+fn with_current_times<R>(f: impl FnOnce(&mut CpuTimes) -> R) -> Option<R> {
+    // Called from the syscall path; never spin on the scheduler lock there
+    let mut sched = scheduler::try_scheduler()?;
+    sched.current_task_mut().map(|task| f(&mut task.times))
+}
+
+/// Syscall entry hook: note when the syscall started
+pub fn syscall_enter(_num: u64) {
+    let now = clocksource::ktime_ns();
+    with_current_times(|times| times.syscall_entry_ns = now);
+}
+
+/// Syscall exit hook
+///
+/// Returns how long the syscall took in nanoseconds, None if its entry was
+/// not recorded.
+pub fn syscall_exit(_num: u64, _ret: i64) -> Option<u64> {
+    let now = clocksource::ktime_ns();
+    with_current_times(|times| {
+        let entry = core::mem::take(&mut times.syscall_entry_ns);
+        (entry != 0).then(|| now.saturating_sub(entry))
+    })
+    .flatten()
+}
+
+/// Compute resource usage for a task
+pub fn getrusage_for(sched: &Scheduler, task: TaskId, who: i32) -> Result<Rusage, &'static str> {
+    match who {
+        RUSAGE_SELF | RUSAGE_THREAD => {
+            let task = sched.get_task(task).ok_or("Task not found")?;
+            Ok(Rusage::from_times(&task.times))
+        }
+        // Terminated children are not reaped into the parent yet
+        RUSAGE_CHILDREN => Ok(Rusage::default()),
+        _ => Err("Invalid rusage target"),
+    }
+}
+
+/// Compute process times for a task
+pub fn times_for(sched: &Scheduler, task: TaskId) -> Result<Tms, &'static str> {
+    let task = sched.get_task(task).ok_or("Task not found")?;
+    Ok(Tms {
+        tms_utime: task.times.utime_ticks as i64,
+        tms_stime: task.times.stime_ticks as i64,
+        tms_cutime: 0,
+        tms_cstime: 0,
+    })
+}
+
+/// Resource usage of the current task
+pub fn getrusage(who: i32) -> Result<Rusage, &'static str> {
+    let sched = scheduler::scheduler();
+    let current = sched.current_task().ok_or("No current task")?;
+    getrusage_for(&sched, current, who)
+}
+
+/// Process times of the current task
+pub fn times() -> Result<Tms, &'static str> {
+    let sched = scheduler::scheduler();
+    let current = sched.current_task().ok_or("No current task")?;
+    times_for(&sched, current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{PhysAddr, VirtAddr};
+    use crate::task::tcb::{Task, TaskPriority};
+
+    fn sched_with_task() -> (Scheduler, TaskId) {
+        let mut sched = Scheduler::new();
+        sched.init();
+        let task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0),
+            TaskPriority::Normal,
+        );
+        let id = sched.add_task(task).unwrap();
+        (sched, id)
+    }
+
+    #[test]
+    fn test_charge_tick_by_mode() {
+        let mut times = CpuTimes::new();
+        times.charge_tick(false);
+        times.charge_tick(true);
+        times.charge_tick(true);
+        assert_eq!(times.stime_ticks, 1);
+        assert_eq!(times.utime_ticks, 2);
+        assert_eq!(times.total_ticks(), 3);
+    }
+
+    #[test]
+    fn test_timeval_from_ticks() {
+        assert_eq!(Timeval::from_ticks(0), Timeval { tv_sec: 0, tv_usec: 0 });
+        assert_eq!(Timeval::from_ticks(150), Timeval { tv_sec: 1, tv_usec: 500_000 });
+    }
+
+    #[test]
+    fn test_rss_keeps_maximum() {
+        let mut times = CpuTimes::new();
+        times.update_rss(64);
+        times.update_rss(32);
+        assert_eq!(times.maxrss_kb, 64);
+    }
+
+    #[test]
+    fn test_getrusage_and_times() {
+        let (mut sched, id) = sched_with_task();
+        {
+            let task = sched.get_task_mut(id).unwrap();
+            task.times.utime_ticks = 250;
+            task.times.stime_ticks = 10;
+        }
+        account_preemption(&mut sched, id);
+
+        // Voluntary switches are counted by the scheduler when a task blocks
+        sched.block_task(id).unwrap();
+        sched.block_task(id).unwrap();
+        sched.unblock_task(id).unwrap();
+        sched.block_task(id).unwrap();
+
+        let ru = getrusage_for(&sched, id, RUSAGE_SELF).unwrap();
+        assert_eq!(ru.ru_utime, Timeval { tv_sec: 2, tv_usec: 500_000 });
+        assert_eq!(ru.ru_stime, Timeval { tv_sec: 0, tv_usec: 100_000 });
+        assert_eq!(ru.ru_nivcsw, 1);
+        assert_eq!(ru.ru_nvcsw, 2);
+
+        let tms = times_for(&sched, id).unwrap();
+        assert_eq!(tms.tms_utime, 250);
+        assert_eq!(tms.tms_stime, 10);
+
+        assert!(getrusage_for(&sched, id, 7).is_err());
+        assert_eq!(getrusage_for(&sched, id, RUSAGE_CHILDREN).unwrap(), Rusage::default());
+    }
+
+    #[test]
+    fn test_rusage_layout() {
+        // Two timevals followed by 14 longs, as in Linux
+        assert_eq!(core::mem::size_of::<Rusage>(), 144);
+        assert_eq!(core::mem::size_of::<Tms>(), 32);
+    }
+}
@@ -0,0 +1,418 @@
+//! Per-CPU Idle Tasks
+//!
+//! Every CPU owns an idle task ("idle/N") that the scheduler runs when
+//! nothing else is ready. The idle task never sits on a ready queue.
+//!
+//! This module provides:
+//! - Idle task creation per CPU
+//! - Idle duration prediction from each CPU's recent idle periods
+//! - C-state selection from the predicted idle duration (`power::cpu`)
+//! - `hlt` or `monitor`/`mwait` idle entry, chosen from CPUID
+//! - Per-CPU C-state usage and residency accounting
+//! - `cpu_startup_entry()`, the idle loop every CPU ends up in
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::scheduler::{self, Scheduler};
+use super::tcb::{Task, TaskId, TaskPriority};
+use crate::memory::{PhysAddr, VirtAddr};
+use crate::power::cpu::{self as power_cpu, CState, IdleMethod, ScalingPolicy};
+
+/// Stack size of an idle task
+pub const IDLE_STACK_SIZE: usize = 8 * 1024;
+
+/// Minimum expected idle time worth entering C2
+pub const C2_MIN_RESIDENCY_MS: u64 = 2;
+
+/// Minimum expected idle time worth entering C3
+pub const C3_MIN_RESIDENCY_MS: u64 = 20;
+
+/// Number of C-states tracked
+const NR_CSTATES: usize = 4;
+
+/// Idle periods the predictor averages
+const PREDICT_HISTORY: usize = 8;
+
+/// C-state usage counters for one CPU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CStateStats {
+    /// Number of entries into each C-state
+    pub usage: [u64; NR_CSTATES],
+    /// Time spent in each C-state in milliseconds
+    pub residency_ms: [u64; NR_CSTATES],
+}
+
+impl CStateStats {
+    /// Record one idle period
+    pub fn record(&mut self, c_state: CState, residency_ms: u64) {
+        let idx = c_state as usize;
+        self.usage[idx] += 1;
+        self.residency_ms[idx] += residency_ms;
+    }
+
+    /// Total idle time in milliseconds
+    pub fn total_ms(&self) -> u64 {
+        self.residency_ms.iter().sum()
+    }
+
+    /// Add another CPU's counters
+    pub fn merge(&mut self, other: &CStateStats) {
+        for idx in 0..NR_CSTATES {
+            self.usage[idx] += other.usage[idx];
+            self.residency_ms[idx] += other.residency_ms[idx];
+        }
+    }
+}
+
+/// Predicts how long a CPU will stay idle
+///
+/// The next timer event bounds an idle period, but device interrupts and
+/// IPIs often end it sooner. The recent periods show how much sooner: the
+/// prediction is their average, capped by the timer. A CPU that has just
+/// come up predicts short periods and works its way into deeper states.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdlePredictor {
+    history: [u64; PREDICT_HISTORY],
+    next: usize,
+}
+
+impl IdlePredictor {
+    /// Remember an idle period
+    pub fn record(&mut self, residency_ms: u64) {
+        self.history[self.next] = residency_ms;
+        self.next = (self.next + 1) % PREDICT_HISTORY;
+    }
+
+    /// Expected idle time when the next timer event is `timer_ms` away
+    pub fn predict(&self, timer_ms: u64) -> u64 {
+        let average = self.history.iter().sum::<u64>() / PREDICT_HISTORY as u64;
+        average.min(timer_ms)
+    }
+}
+
+/// Idle state of one CPU
+#[derive(Debug)]
+pub struct IdleCpu {
+    /// Idle task ID
+    pub task: TaskId,
+    /// How this CPU waits for interrupts
+    pub method: IdleMethod,
+    /// C-state accounting
+    pub stats: CStateStats,
+    /// Idle duration predictor
+    pub predictor: IdlePredictor,
+    /// Stack of the idle task
+    stack: Vec<u8>,
+}
+
+/// Pick a C-state for the expected idle duration
+///
+/// Deeper states cost more to enter and leave, so they are only chosen
+/// when the CPU is expected to stay idle long enough. `hlt` only reaches
+/// C1, and the performance policy stays in C1 for the lowest wakeup latency.
+pub fn select_c_state(expected_ms: u64, method: IdleMethod, policy: ScalingPolicy) -> CState {
+    if method == IdleMethod::Hlt || policy == ScalingPolicy::Performance {
+        return CState::C1;
+    }
+    if expected_ms >= C3_MIN_RESIDENCY_MS {
+        CState::C3
+    } else if expected_ms >= C2_MIN_RESIDENCY_MS {
+        CState::C2
+    } else {
+        CState::C1
+    }
+}
+
+/// Idle tasks indexed by CPU
+pub struct IdleTasks {
+    cpus: BTreeMap<usize, IdleCpu>,
+}
+
+impl IdleTasks {
+    /// Create an empty table
+    pub const fn new() -> Self {
+        Self { cpus: BTreeMap::new() }
+    }
+
+    /// Create the idle task for a CPU
+    pub fn create_in(
+        &mut self,
+        sched: &mut Scheduler,
+        cpu: usize,
+        method: IdleMethod,
+    ) -> Result<TaskId, &'static str> {
+        let stack = vec![0u8; IDLE_STACK_SIZE];
+        let base = stack.as_ptr() as u64;
+        let usable = ((base + IDLE_STACK_SIZE as u64) & !0xF) - base;
+
+        let mut task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(idle_task_entry as *const () as u64),
+            VirtAddr::new(base),
+            usable as usize,
+            PhysAddr::new(0),
+            TaskPriority::Low,
+        );
+        let mut name = [0u8; 12];
+        task.set_name(idle_name(cpu, &mut name));
+
+        let id = sched.add_idle_task(cpu, task)?;
+        self.cpus.insert(cpu, IdleCpu {
+            task: id,
+            method,
+            stats: CStateStats::default(),
+            predictor: IdlePredictor::default(),
+            stack,
+        });
+        Ok(id)
+    }
+
+    /// Get the idle state of a CPU
+    pub fn get(&self, cpu: usize) -> Option<&IdleCpu> {
+        self.cpus.get(&cpu)
+    }
+
+    /// Record an idle period on a CPU
+    pub fn record(&mut self, cpu: usize, c_state: CState, residency_ms: u64) {
+        if let Some(idle) = self.cpus.get_mut(&cpu) {
+            idle.stats.record(c_state, residency_ms);
+            idle.predictor.record(residency_ms);
+        }
+    }
+
+    /// C-state counters of all CPUs together
+    pub fn total_stats(&self) -> CStateStats {
+        let mut total = CStateStats::default();
+        for idle in self.cpus.values() {
+            total.merge(&idle.stats);
+        }
+        total
+    }
+
+    /// Iterate over CPUs with idle tasks
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &IdleCpu)> {
+        self.cpus.iter()
+    }
+}
+
+impl Default for IdleTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdleCpu {
+    /// Size of the idle task's stack
+    pub fn stack_size(&self) -> usize {
+        self.stack.len()
+    }
+}
+
+/// Format "idle/N" into a buffer
+fn idle_name(cpu: usize, buf: &mut [u8; 12]) -> &str {
+    buf[..5].copy_from_slice(b"idle/");
+    let mut digits = [0u8; 6];
+    let mut n = cpu;
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 || len == digits.len() {
+            break;
+        }
+    }
+    for i in 0..len {
+        buf[5 + i] = digits[len - 1 - i];
+    }
+    core::str::from_utf8(&buf[..5 + len]).unwrap_or("idle")
+}
+
+/// Global idle task table
+static IDLE_TASKS: Mutex<IdleTasks> = Mutex::new(IdleTasks::new());
+
+/// Get the global idle task table
+pub fn idle_tasks() -> spin::MutexGuard<'static, IdleTasks> {
+    IDLE_TASKS.lock()
+}
+
+/// Create the idle task for a CPU
+pub fn init_idle(cpu: usize) -> Result<TaskId, &'static str> {
+    let method = if power_cpu::mwait_supported() {
+        IdleMethod::Mwait
+    } else {
+        IdleMethod::Hlt
+    };
+    let mut sched = scheduler::scheduler();
+    IDLE_TASKS.lock().create_in(&mut sched, cpu, method)
+}
+
+/// Create the idle task for the boot CPU
+pub fn init() -> Result<(), &'static str> {
+    let cpu = crate::smp::current_cpu_id().as_usize();
+    init_idle(cpu)?;
+
+    #[cfg(not(test))]
+    if let Some(idle) = IDLE_TASKS.lock().get(cpu) {
+        crate::log_info!(
+            target: "idle",
+            "CPU {} idle task {:?} using {:?}",
+            cpu, idle.task, idle.method
+        );
+    }
+    Ok(())
+}
+
+/// Halt this CPU until an interrupt, at most about `expected_ms`
+/// milliseconds away
+///
+/// Called by the tickless idle code with interrupts disabled. Returns with
+/// interrupts enabled, and the C-state the CPU was in.
+pub fn enter_idle_state(expected_ms: u64) -> CState {
+    let cpu = crate::smp::current_cpu_id().as_usize();
+    let (method, predicted_ms) = IDLE_TASKS
+        .lock()
+        .get(cpu)
+        .map(|idle| (idle.method, idle.predictor.predict(expected_ms)))
+        .unwrap_or((IdleMethod::Hlt, expected_ms));
+
+    let c_state = select_c_state(predicted_ms, method, power_cpu::get_scaling_policy());
+    power_cpu::idle_enter(c_state, method)
+}
+
+/// Run one idle iteration and account the time spent
+pub fn do_idle() {
+    // A CPU asked to go offline parks here until it is brought back
+    crate::smp::hotplug::park_if_requested();
+
+    let cpu = crate::smp::current_cpu_id().as_usize();
+    let start = super::time::uptime_ms();
+
+    // The periodic tick belongs to the boot CPU; other CPUs just halt
+    // until an interrupt arrives
+    let c_state = if cpu == 0 {
+        super::tickless::cpu_idle()
+    } else {
+        fanga_arch_x86_64::interrupts::disable();
+        Some(enter_idle_state(super::tickless::TICK_MS))
+    };
+
+    if let Some(c_state) = c_state {
+        let residency = super::time::uptime_ms() - start;
+        IDLE_TASKS.lock().record(cpu, c_state, residency);
+    }
+
+    // An idle CPU holds up no grace period
+    crate::smp::rcu::rcu_idle();
+
+    // Pick up a frequency or clock throttle chosen on another CPU
+    power_cpu::sync_frequency(cpu);
+    crate::power::thermal::sync_throttle(cpu);
+
+    // A wakeup or reschedule IPI may have made work for this CPU
+    super::sched_timer::schedule_pending();
+}
+
+/// Idle loop of a CPU
+///
+/// The boot CPU jumps here at the end of `_start`, becoming its own idle
+/// task; application processors enter it once they are online.
+pub fn cpu_startup_entry() -> ! {
+    let cpu = crate::smp::current_cpu_id().as_usize();
+    if let Err(e) = scheduler::scheduler().enter_idle(cpu) {
+        #[cfg(not(test))]
+        crate::log_warn!(target: "idle", "CPU {}: {}", cpu, e);
+        #[cfg(test)]
+        let _ = e;
+    }
+
+    loop {
+        do_idle();
+    }
+}
+
+/// Entry point of idle tasks created for secondary CPUs
+extern "C" fn idle_task_entry() -> ! {
+    cpu_startup_entry()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_c_state() {
+        let mw = IdleMethod::Mwait;
+        assert_eq!(select_c_state(1, mw, ScalingPolicy::Balanced), CState::C1);
+        assert_eq!(select_c_state(5, mw, ScalingPolicy::Balanced), CState::C2);
+        assert_eq!(select_c_state(50, mw, ScalingPolicy::Balanced), CState::C3);
+        assert_eq!(select_c_state(50, mw, ScalingPolicy::Performance), CState::C1);
+        assert_eq!(select_c_state(50, IdleMethod::Hlt, ScalingPolicy::PowerSave), CState::C1);
+    }
+
+    #[test]
+    fn test_idle_task_creation() {
+        let mut sched = Scheduler::new();
+        sched.init();
+        let mut idle = IdleTasks::new();
+
+        let id = idle.create_in(&mut sched, 0, IdleMethod::Hlt).unwrap();
+        assert_eq!(sched.idle_task(0), Some(id));
+        assert_eq!(sched.get_task(id).unwrap().name(), "idle/0");
+        assert_eq!(idle.get(0).unwrap().stack_size(), IDLE_STACK_SIZE);
+        assert_eq!(sched.ready_task_count(), 0);
+
+        let id12 = idle.create_in(&mut sched, 12, IdleMethod::Mwait).unwrap();
+        assert_eq!(sched.get_task(id12).unwrap().name(), "idle/12");
+        assert!(idle.create_in(&mut sched, 0, IdleMethod::Hlt).is_err());
+    }
+
+    #[test]
+    fn test_cstate_accounting() {
+        let mut stats = CStateStats::default();
+        stats.record(CState::C1, 3);
+        stats.record(CState::C3, 40);
+        stats.record(CState::C3, 20);
+        assert_eq!(stats.usage[CState::C3 as usize], 2);
+        assert_eq!(stats.residency_ms[CState::C1 as usize], 3);
+        assert_eq!(stats.total_ms(), 63);
+
+        let mut total = CStateStats::default();
+        total.merge(&stats);
+        total.merge(&stats);
+        assert_eq!(total.usage[CState::C3 as usize], 4);
+        assert_eq!(total.total_ms(), 126);
+    }
+
+    #[test]
+    fn test_idle_predictor() {
+        let mut predictor = IdlePredictor::default();
+        // Nothing known yet: shallow
+        assert_eq!(predictor.predict(100), 0);
+
+        // Long idle periods deepen the prediction, bounded by the timer
+        for _ in 0..PREDICT_HISTORY {
+            predictor.record(40);
+        }
+        assert_eq!(predictor.predict(100), 40);
+        assert_eq!(predictor.predict(10), 10);
+
+        // Frequent early wakeups bring it back down
+        for _ in 0..PREDICT_HISTORY / 2 {
+            predictor.record(0);
+        }
+        assert_eq!(predictor.predict(100), 20);
+
+        let mut idle = IdleTasks::new();
+        let mut sched = Scheduler::new();
+        sched.init();
+        idle.create_in(&mut sched, 0, IdleMethod::Mwait).unwrap();
+        idle.record(0, CState::C2, 16);
+        assert_eq!(idle.get(0).unwrap().predictor.predict(100), 2);
+        assert_eq!(idle.total_stats().usage[CState::C2 as usize], 1);
+    }
+}
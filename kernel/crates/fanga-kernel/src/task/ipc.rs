@@ -159,6 +159,19 @@ impl Semaphore {
         }
     }
     
+    /// Remove a waiting task (timed out or interrupted)
+    ///
+    /// Returns true if the task was still waiting.
+    pub fn remove_waiter(&mut self, task_id: TaskId) -> bool {
+        match self.waiting_tasks.iter().position(|&t| t == task_id) {
+            Some(pos) => {
+                self.waiting_tasks.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+    
     /// Get the current value
     pub fn value(&self) -> isize {
         self.value
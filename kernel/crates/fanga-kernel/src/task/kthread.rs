@@ -0,0 +1,400 @@
+//! Kernel Threads
+//!
+//! This module provides the sanctioned way for subsystems to run code in
+//! their own kernel thread:
+//! - `spawn(name, f)` allocates a kernel stack and schedules a task running `f`
+//! - Park/unpark to put a thread to sleep until it is needed again
+//! - Cooperative stop requests (`should_stop()` / `stop(id)`)
+//!
+//! Thread bodies are expected to loop on `should_stop()` and call
+//! `parkme()` when `should_park()` is set:
+//!
+//! ```ignore
+//! kthread::spawn("flusher", || {
+//!     while !kthread::should_stop() {
+//!         flush_dirty_buffers();
+//!         kthread::parkme();
+//!     }
+//! })?;
+//! ```
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::memory::{PhysAddr, VirtAddr};
+use super::scheduler::{self, Scheduler};
+use super::tcb::{Task, TaskId, TaskPriority};
+
+/// Default kernel thread stack size
+pub const KTHREAD_STACK_SIZE: usize = 16 * 1024;
+
+/// Thread body
+pub type KthreadFn = Box<dyn FnOnce() + Send + 'static>;
+
+/// Kernel thread record
+pub struct Kthread {
+    /// Backing task
+    pub task: TaskId,
+    /// Thread name
+    pub name: String,
+    /// Body, taken by the trampoline when the thread first runs
+    body: Option<KthreadFn>,
+    /// Kernel stack (owned by the thread record)
+    stack: Vec<u8>,
+    /// Stop has been requested
+    pub should_stop: bool,
+    /// Park has been requested
+    pub should_park: bool,
+    /// Thread is currently parked
+    pub parked: bool,
+    /// Thread body has returned
+    pub exited: bool,
+}
+
+impl Kthread {
+    /// Base address of the kernel stack
+    pub fn stack_base(&self) -> VirtAddr {
+        VirtAddr::new(self.stack.as_ptr() as u64)
+    }
+
+    /// Size of the kernel stack
+    pub fn stack_size(&self) -> usize {
+        self.stack.len()
+    }
+}
+
+/// Registry of kernel threads
+pub struct KthreadRegistry {
+    threads: BTreeMap<TaskId, Kthread>,
+}
+
+impl KthreadRegistry {
+    /// Create an empty registry
+    pub const fn new() -> Self {
+        Self { threads: BTreeMap::new() }
+    }
+
+    /// Create a kernel thread in the given scheduler
+    pub fn spawn_in(
+        &mut self,
+        sched: &mut Scheduler,
+        name: &str,
+        priority: TaskPriority,
+        stack_size: usize,
+        body: KthreadFn,
+    ) -> Result<TaskId, &'static str> {
+        if stack_size < 4096 {
+            return Err("Kernel thread stack too small");
+        }
+
+        let stack = vec![0u8; stack_size];
+        // Keep the initial stack pointer 16-byte aligned
+        let base = stack.as_ptr() as u64;
+        let usable = ((base + stack_size as u64) & !0xF) - base;
+
+        let mut task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(kthread_trampoline as *const () as u64),
+            VirtAddr::new(base),
+            usable as usize,
+            PhysAddr::new(0),
+            priority,
+        );
+        task.set_name(name);
+
+        let id = sched.add_task(task)?;
+        self.threads.insert(id, Kthread {
+            task: id,
+            name: String::from(name),
+            body: Some(body),
+            stack,
+            should_stop: false,
+            should_park: false,
+            parked: false,
+            exited: false,
+        });
+        Ok(id)
+    }
+
+    /// Look up a kernel thread
+    pub fn get(&self, id: TaskId) -> Option<&Kthread> {
+        self.threads.get(&id)
+    }
+
+    /// Take the body of a thread so it can be run
+    pub fn take_body(&mut self, id: TaskId) -> Option<KthreadFn> {
+        self.threads.get_mut(&id)?.body.take()
+    }
+
+    /// Request a thread to park
+    pub fn park(&mut self, id: TaskId) -> Result<(), &'static str> {
+        let kt = self.threads.get_mut(&id).ok_or("Kernel thread not found")?;
+        kt.should_park = true;
+        Ok(())
+    }
+
+    /// Mark a thread parked and block its task
+    pub fn parkme(&mut self, sched: &mut Scheduler, id: TaskId) -> Result<(), &'static str> {
+        let kt = self.threads.get_mut(&id).ok_or("Kernel thread not found")?;
+        if kt.should_stop {
+            return Ok(());
+        }
+        kt.parked = true;
+        sched.block_task(id)
+    }
+
+    /// Clear a park request and wake the thread if parked
+    pub fn unpark(&mut self, sched: &mut Scheduler, id: TaskId) -> Result<(), &'static str> {
+        let kt = self.threads.get_mut(&id).ok_or("Kernel thread not found")?;
+        kt.should_park = false;
+        if kt.parked {
+            kt.parked = false;
+            sched.unblock_task(id)?;
+        }
+        Ok(())
+    }
+
+    /// Request a thread to stop, waking it if parked
+    pub fn stop(&mut self, sched: &mut Scheduler, id: TaskId) -> Result<(), &'static str> {
+        self.threads.get_mut(&id).ok_or("Kernel thread not found")?.should_stop = true;
+        self.unpark(sched, id)
+    }
+
+    /// Record that a thread's body returned and terminate its task
+    pub fn exit(&mut self, sched: &mut Scheduler, id: TaskId) -> Result<(), &'static str> {
+        let kt = self.threads.get_mut(&id).ok_or("Kernel thread not found")?;
+        kt.exited = true;
+        sched.terminate_task(id)
+    }
+
+    /// Free the records (and stacks) of exited threads
+    ///
+    /// Returns the number of threads reaped.
+    pub fn reap(&mut self, current: Option<TaskId>) -> usize {
+        let before = self.threads.len();
+        // Never free the stack we are running on
+        self.threads.retain(|id, kt| !kt.exited || Some(*id) == current);
+        before - self.threads.len()
+    }
+
+    /// Number of kernel threads
+    pub fn count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Iterate over all kernel threads
+    pub fn iter(&self) -> impl Iterator<Item = &Kthread> {
+        self.threads.values()
+    }
+}
+
+impl Default for KthreadRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global kernel thread registry
+static KTHREADS: Mutex<KthreadRegistry> = Mutex::new(KthreadRegistry::new());
+
+/// Get the global kernel thread registry
+pub fn kthreads() -> spin::MutexGuard<'static, KthreadRegistry> {
+    KTHREADS.lock()
+}
+
+/// Spawn a kernel thread with default priority and stack size
+pub fn spawn<F>(name: &str, f: F) -> Result<TaskId, &'static str>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_priority(name, TaskPriority::Normal, f)
+}
+
+/// Spawn a kernel thread with the given priority
+pub fn spawn_with_priority<F>(name: &str, priority: TaskPriority, f: F) -> Result<TaskId, &'static str>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut sched = scheduler::scheduler();
+    KTHREADS.lock().spawn_in(&mut sched, name, priority, KTHREAD_STACK_SIZE, Box::new(f))
+}
+
+fn current() -> Option<TaskId> {
+    scheduler::scheduler().current_task()
+}
+
+/// Check whether the current kernel thread has been asked to stop
+pub fn should_stop() -> bool {
+    current()
+        .and_then(|id| KTHREADS.lock().get(id).map(|kt| kt.should_stop))
+        .unwrap_or(false)
+}
+
+/// Check whether the current kernel thread has been asked to park
+pub fn should_park() -> bool {
+    current()
+        .and_then(|id| KTHREADS.lock().get(id).map(|kt| kt.should_park))
+        .unwrap_or(false)
+}
+
+/// Park the current kernel thread until `unpark` or `stop` is called
+pub fn parkme() {
+    let Some(id) = current() else { return };
+    {
+        let mut sched = scheduler::scheduler();
+        let _ = KTHREADS.lock().parkme(&mut sched, id);
+    }
+    // Wait until unparked; `stop` unparks too
+    #[cfg(not(test))]
+    while KTHREADS.lock().get(id).is_some_and(|kt| kt.parked) {
+        crate::preempt::points::set_need_resched();
+        if !super::sched_timer::schedule_pending() {
+            fanga_arch_x86_64::interrupts::enable();
+            unsafe { core::arch::asm!("hlt", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Ask a kernel thread to park
+pub fn park(id: TaskId) -> Result<(), &'static str> {
+    KTHREADS.lock().park(id)
+}
+
+/// Wake a parked kernel thread
+pub fn unpark(id: TaskId) -> Result<(), &'static str> {
+    let mut sched = scheduler::scheduler();
+    KTHREADS.lock().unpark(&mut sched, id)
+}
+
+/// Ask a kernel thread to stop
+///
+/// The thread observes the request through `should_stop()` and returns
+/// from its body; the record is freed by `reap()` afterwards.
+pub fn stop(id: TaskId) -> Result<(), &'static str> {
+    let mut sched = scheduler::scheduler();
+    KTHREADS.lock().stop(&mut sched, id)
+}
+
+/// Free the records of exited kernel threads
+pub fn reap() -> usize {
+    let current = current();
+    KTHREADS.lock().reap(current)
+}
+
+/// Entry point of every kernel thread
+extern "C" fn kthread_trampoline() -> ! {
+    let id = current();
+    let body = id.and_then(|id| KTHREADS.lock().take_body(id));
+
+    if let Some(body) = body {
+        body();
+    }
+
+    if let Some(id) = id {
+        let mut sched = scheduler::scheduler();
+        let _ = KTHREADS.lock().exit(&mut sched, id);
+    }
+
+    // The scheduler will never pick a terminated task again
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            core::arch::asm!("hlt", options(nomem, nostack));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::tcb::TaskState;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn new_sched() -> Scheduler {
+        let mut sched = Scheduler::new();
+        sched.init();
+        sched
+    }
+
+    #[test]
+    fn test_spawn_creates_task() {
+        let mut sched = new_sched();
+        let mut reg = KthreadRegistry::new();
+
+        let id = reg.spawn_in(&mut sched, "kworker", TaskPriority::Normal, KTHREAD_STACK_SIZE, Box::new(|| {})).unwrap();
+
+        let task = sched.get_task(id).unwrap();
+        assert_eq!(task.name(), "kworker");
+        assert_eq!(task.state, TaskState::Ready);
+        assert_eq!(task.context.rip, kthread_trampoline as *const () as u64);
+        assert_eq!(task.context.rsp % 16, 0);
+
+        let kt = reg.get(id).unwrap();
+        assert_eq!(kt.stack_size(), KTHREAD_STACK_SIZE);
+        assert_eq!(task.kernel_stack, kt.stack_base());
+    }
+
+    #[test]
+    fn test_stack_too_small() {
+        let mut sched = new_sched();
+        let mut reg = KthreadRegistry::new();
+        assert!(reg.spawn_in(&mut sched, "tiny", TaskPriority::Normal, 512, Box::new(|| {})).is_err());
+    }
+
+    #[test]
+    fn test_body_runs_once() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let mut sched = new_sched();
+        let mut reg = KthreadRegistry::new();
+
+        let id = reg.spawn_in(&mut sched, "once", TaskPriority::Normal, KTHREAD_STACK_SIZE, Box::new(|| {
+            RUNS.fetch_add(1, Ordering::SeqCst);
+        })).unwrap();
+
+        reg.take_body(id).unwrap()();
+        assert!(reg.take_body(id).is_none());
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_park_unpark() {
+        let mut sched = new_sched();
+        let mut reg = KthreadRegistry::new();
+        let id = reg.spawn_in(&mut sched, "parker", TaskPriority::Normal, KTHREAD_STACK_SIZE, Box::new(|| {})).unwrap();
+
+        reg.park(id).unwrap();
+        assert!(reg.get(id).unwrap().should_park);
+
+        reg.parkme(&mut sched, id).unwrap();
+        assert!(reg.get(id).unwrap().parked);
+        assert_eq!(sched.get_task(id).unwrap().state, TaskState::Blocked);
+
+        reg.unpark(&mut sched, id).unwrap();
+        let kt = reg.get(id).unwrap();
+        assert!(!kt.parked && !kt.should_park);
+        assert_eq!(sched.get_task(id).unwrap().state, TaskState::Ready);
+    }
+
+    #[test]
+    fn test_stop_and_reap() {
+        let mut sched = new_sched();
+        let mut reg = KthreadRegistry::new();
+        let id = reg.spawn_in(&mut sched, "stopper", TaskPriority::Normal, KTHREAD_STACK_SIZE, Box::new(|| {})).unwrap();
+
+        reg.parkme(&mut sched, id).unwrap();
+        reg.stop(&mut sched, id).unwrap();
+        assert!(reg.get(id).unwrap().should_stop);
+        assert_eq!(sched.get_task(id).unwrap().state, TaskState::Ready);
+
+        assert_eq!(reg.reap(None), 0);
+        reg.exit(&mut sched, id).unwrap();
+        assert_eq!(sched.get_task(id).unwrap().state, TaskState::Terminated);
+        assert_eq!(reg.reap(None), 1);
+        assert_eq!(reg.count(), 0);
+    }
+}
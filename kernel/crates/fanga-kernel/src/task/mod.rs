@@ -6,15 +6,30 @@
 //! - Task ID management
 //! - Process creation and management
 //! - Preemptive scheduling
+//! - Per-CPU run queues
 //! - Time management and delays
 //! - Multi-threading (kernel and user threads)
 //! - Advanced synchronization (condition variables, RW locks, barriers)
 //! - Process groups and sessions
 //! - Advanced signal handling
+//! - Signal delivery on return to user mode
 //! - Core dumps for debugging
+//! - CPU time accounting (getrusage/times)
+//! - Control groups (CPU and memory limits)
+//! - Kernel threads
+//! - Workqueues for deferred work
+//! - Softirqs and tasklets (interrupt bottom halves)
+//! - Tickless idle
+//! - TSC clock source and `ktime_ns()`
+//! - Wall-clock time from the CMOS RTC
+//! - Per-CPU idle tasks
+//! - Thread-local storage
+//! - Speculation barriers between address spaces
+//! - Timer wheel, wait queues and timed waits
 
 pub mod tcb;
 pub mod scheduler;
+pub mod runqueue;
 pub mod context;
 pub mod ipc;
 pub mod process;
@@ -27,7 +42,21 @@ pub mod thread;
 pub mod sync;
 pub mod pgroup;
 pub mod sigadv;
+pub mod sigdeliver;
 pub mod coredump;
+pub mod cgroup;
+pub mod cputime;
+pub mod kthread;
+pub mod workqueue;
+pub mod softirq;
+pub mod tickless;
+pub mod clocksource;
+pub mod realtime;
+pub mod idle;
+pub mod tls;
+pub mod speculation;
+pub mod timer_wheel;
+pub mod wait;
 
 // Example tasks only available in no_std builds
 #[cfg(not(test))]
@@ -35,7 +64,8 @@ pub mod examples;
 
 // Re-export commonly used types
 pub use tcb::{Task, TaskId, TaskState, TaskPriority};
-pub use scheduler::Scheduler;
+pub use scheduler::{Scheduler, INIT_PID};
+pub use runqueue::{Pick, RqTask, RunQueue, RunQueues};
 pub use context::TaskContext;
 pub use ipc::{
     MessageQueue, Message, 
@@ -43,8 +73,9 @@ pub use ipc::{
     Signal, SignalHandler,
     Semaphore, TaskMutex,
 };
-pub use process::{ProcessManager, create_process, fork, exit};
+pub use process::{ProcessManager, create_process, create_init_process, fork, clone, exit, wait_child};
 pub use time::{delay_ms, delay_us, sleep_ms, uptime_ms, uptime_secs, timer_ticks};
+pub use clocksource::{ClockSource, ktime_ns};
 
 // Re-export advanced features
 pub use thread::{Thread, ThreadId, ThreadType, ThreadAttributes, ThreadManager, RtSchedulingPolicy};
@@ -52,3 +83,12 @@ pub use sync::{ConditionVariable, RwLock, Barrier};
 pub use pgroup::{ProcessGroup, ProcessGroupId, Session, SessionId, ProcessGroupManager, TerminalId};
 pub use sigadv::{SignalAction, SignalFlags, SignalInfo, SigAction, AdvancedSignalHandler, SignalTarget, SignalManager};
 pub use coredump::{CoreDump, CoreDumpReason, CoreDumpManager, RegisterDump, MemoryDump, ThreadInfo};
+pub use cgroup::{Cgroup, CgroupId, CgroupTree, ROOT_CGROUP};
+pub use cputime::{CpuTimes, Rusage, Tms, getrusage, times};
+pub use kthread::Kthread;
+pub use idle::{CStateStats, IdleTasks};
+pub use tls::{TlsBlock, TlsTemplate};
+pub use timer_wheel::{TimerId, add_timer, del_timer};
+pub use wait::{WaitQueue, WaitResult, sleep_on_timeout};
+pub use workqueue::{Work, WorkQueueId, WorkQueueKind, DelayedWorkId, queue_work, queue_delayed_work, schedule_work, schedule_delayed_work};
+pub use softirq::{SoftirqType, Tasklet, raise_softirq, tasklet_schedule, tasklet_hi_schedule};
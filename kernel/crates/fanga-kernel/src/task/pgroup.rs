@@ -10,6 +10,7 @@ extern crate alloc;
 use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
+use spin::Mutex;
 
 use super::tcb::TaskId;
 
@@ -246,7 +247,7 @@ pub struct ProcessGroupManager {
 
 impl ProcessGroupManager {
     /// Create a new process group manager
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             process_groups: BTreeMap::new(),
             sessions: BTreeMap::new(),
@@ -431,6 +432,14 @@ impl Default for ProcessGroupManager {
     }
 }
 
+/// Global process group and session manager
+static PROCESS_GROUPS: Mutex<ProcessGroupManager> = Mutex::new(ProcessGroupManager::new());
+
+/// Get access to the global process group manager
+pub fn process_groups() -> spin::MutexGuard<'static, ProcessGroupManager> {
+    PROCESS_GROUPS.lock()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2,26 +2,98 @@
 //!
 //! This module provides high-level process management functionality including:
 //! - Process creation and termination
-//! - fork() and exit() implementations
+//! - fork(), clone() and exit() implementations
+//! - Parent tracking, with init (`INIT_PID`) adopting orphans
 //! - Integration with scheduler and context switching
 
 extern crate alloc;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
 use super::tcb::{Task, TaskId, TaskState, TaskPriority};
-use super::scheduler;
-use crate::memory::{PhysAddr, VirtAddr};
+use super::scheduler::{self, Scheduler, INIT_PID};
+use super::wait::{self, WaitQueue};
+use crate::memory::regions::address_space::USER_SPACE_END;
+use crate::memory::{AddressSpace, PhysAddr, VirtAddr};
+
+/// Exit statuses kept for collection; the oldest is dropped when full
+const MAX_EXITED: usize = 64;
+
+/// clone: share the address space
+pub const CLONE_VM: u64 = 0x100;
+/// clone: put the child in the caller's thread group
+pub const CLONE_THREAD: u64 = 0x10000;
+/// clone: set the child's FS base (thread pointer) to the `tls` argument
+pub const CLONE_SETTLS: u64 = 0x80000;
+
+/// Smallest kernel stack a process may have
+const MIN_KERNEL_STACK_SIZE: usize = 4096;
+
+/// Allocate a kernel stack
+///
+/// # Returns
+/// The memory, and the base and size of the part whose top is 16-byte
+/// aligned
+fn alloc_kernel_stack(size: usize) -> Result<(Vec<u8>, VirtAddr, usize), &'static str> {
+    if size < MIN_KERNEL_STACK_SIZE {
+        return Err("Kernel stack too small");
+    }
+    let stack = alloc::vec![0u8; size];
+    let base = stack.as_ptr() as u64;
+    let usable = ((base + size as u64) & !0xF) - base;
+    Ok((stack, VirtAddr::new(base), usable as usize))
+}
+
+/// Free an address space that no live task uses and no CPU has loaded
+///
+/// Its frames are taken off `owner`'s charge. The terminated tasks that
+/// used it are left with the kernel's page table.
+///
+/// # Returns
+/// Whether it was freed
+pub fn release_address_space(scheduler: &mut Scheduler, page_table: PhysAddr, owner: Option<TaskId>) -> bool {
+    let in_use = scheduler
+        .tasks()
+        .any(|task| task.page_table == page_table && task.state != TaskState::Terminated);
+    if in_use || crate::memory::address_space::is_loaded(page_table) {
+        return false;
+    }
+    let Some(space) = AddressSpace::of(page_table, owner) else {
+        return false;
+    };
+    // SAFETY: no task can be switched to it and no CPU runs in it
+    unsafe { space.destroy() };
+    let holders: Vec<TaskId> = scheduler
+        .tasks()
+        .filter(|task| task.page_table == page_table)
+        .map(|task| task.id)
+        .collect();
+    for id in holders {
+        if let Some(task) = scheduler.get_task_mut(id) {
+            task.page_table = PhysAddr::new(0);
+        }
+    }
+    true
+}
 
 /// Process Manager
 pub struct ProcessManager {
     /// Next available process ID
     next_pid: usize,
+    /// Exit codes of processes that exited and were not waited for yet
+    exited: Vec<(TaskId, i32)>,
+    /// Kernel stacks of processes, which interrupts and system calls from
+    /// user mode run on (TSS.RSP0)
+    kernel_stacks: BTreeMap<TaskId, Vec<u8>>,
+    /// Parent of each process that has one, until its exit status is
+    /// collected
+    parents: BTreeMap<TaskId, TaskId>,
 }
 
 impl ProcessManager {
     /// Create a new process manager
     pub const fn new() -> Self {
-        Self { next_pid: 1 }
+        Self { next_pid: 1, exited: Vec::new(), kernel_stacks: BTreeMap::new(), parents: BTreeMap::new() }
     }
     
     /// Create a new process
@@ -41,15 +113,38 @@ impl ProcessManager {
         page_table: PhysAddr,
         priority: TaskPriority,
     ) -> Result<TaskId, &'static str> {
-        // Allocate kernel stack (simplified - in real OS we'd allocate from VMM)
-        let kernel_stack = VirtAddr::new(0x10000 + (self.next_pid as u64 * 0x10000));
+        self.add_process(entry_point, stack_size, page_table, priority, false)
+    }
+
+    /// Create the init process, which gets `INIT_PID` and adopts the
+    /// children of processes that exit
+    pub fn create_init_process(
+        &mut self,
+        entry_point: VirtAddr,
+        stack_size: usize,
+        page_table: PhysAddr,
+        priority: TaskPriority,
+    ) -> Result<TaskId, &'static str> {
+        self.add_process(entry_point, stack_size, page_table, priority, true)
+    }
+
+    fn add_process(
+        &mut self,
+        entry_point: VirtAddr,
+        stack_size: usize,
+        page_table: PhysAddr,
+        priority: TaskPriority,
+        init: bool,
+    ) -> Result<TaskId, &'static str> {
+        // Allocate the kernel stack
+        let (stack, kernel_stack, kernel_stack_size) = alloc_kernel_stack(stack_size)?;
         
         // Create task
         let task = Task::new(
             TaskId::new(0), // Will be set by scheduler
             entry_point,
             kernel_stack,
-            stack_size,
+            kernel_stack_size,
             page_table,
             priority,
         );
@@ -58,7 +153,12 @@ impl ProcessManager {
         
         // Add to scheduler
         let mut scheduler_guard = scheduler::scheduler();
-        scheduler_guard.add_task(task)
+        let id = match init {
+            true => scheduler_guard.add_init_task(task)?,
+            false => scheduler_guard.add_task(task)?,
+        };
+        self.kernel_stacks.insert(id, stack);
+        Ok(id)
     }
     
     /// Fork the current process (create a copy)
@@ -68,40 +168,93 @@ impl ProcessManager {
     /// - In child: TaskId(0)
     /// - On error: Err
     pub fn fork_process(&mut self, parent_id: TaskId) -> Result<TaskId, &'static str> {
+        self.clone_process(parent_id, 0, 0, 0)
+    }
+
+    /// Create a copy of a process or a new thread of it
+    ///
+    /// The child keeps the parent's FS base unless `CLONE_SETTLS` gives it
+    /// the thread pointer of its own TLS block.
+    ///
+    /// # Arguments
+    /// * `parent_id` - The task to copy
+    /// * `flags` - `CLONE_*` flags
+    /// * `stack` - User stack pointer for the child, 0 to keep the parent's
+    /// * `tls` - Thread pointer for the child with `CLONE_SETTLS`
+    pub fn clone_process(
+        &mut self,
+        parent_id: TaskId,
+        flags: u64,
+        stack: u64,
+        tls: u64,
+    ) -> Result<TaskId, &'static str> {
+        if flags & CLONE_SETTLS != 0
+            && (tls >= USER_SPACE_END || !fanga_arch_x86_64::tls::is_canonical(tls))
+        {
+            return Err("Invalid TLS address");
+        }
+        if flags & CLONE_THREAD != 0 && flags & CLONE_VM == 0 {
+            return Err("Threads must share the address space");
+        }
+
         let mut scheduler_guard = scheduler::scheduler();
         
         // Get parent task
         let parent = scheduler_guard.get_task(parent_id).ok_or("Parent task not found")?;
+        let (stack_memory, kernel_stack, kernel_stack_size) = alloc_kernel_stack(parent.kernel_stack_size)?;
         
         // Duplicate the parent task
         let mut child = Task::new(
             TaskId::new(0), // Will be set by scheduler
             VirtAddr::new(parent.context.rip),
-            VirtAddr::new(0x10000 + (self.next_pid as u64 * 0x10000)),
-            parent.kernel_stack_size,
-            parent.page_table, // In real OS, we'd duplicate the page table
+            kernel_stack,
+            kernel_stack_size,
+            parent.page_table, // In real OS, we'd duplicate the page table without CLONE_VM
             parent.priority,
         );
         
         // Copy parent's context
         child.context = parent.context;
+        if stack != 0 {
+            child.context.rsp = stack;
+        }
+        child.tls_base = match flags & CLONE_SETTLS {
+            0 => parent.tls_base,
+            _ => VirtAddr::new(tls),
+        };
+        child.mmap_base = parent.mmap_base;
+        child.mempolicy = parent.mempolicy;
         
         // Set return value to 0 for child (will be returned when child is scheduled)
         child.context.rax = 0;
         
-        // Copy parent's name with "_child" suffix
+        // Copy parent's name with "_child" suffix; threads keep the name
         let parent_name = parent.name();
-        let mut child_name = [0u8; 32];
-        let name_len = core::cmp::min(parent_name.len(), 24);
-        child_name[..name_len].copy_from_slice(&parent_name.as_bytes()[..name_len]);
-        let suffix = b"_child";
-        child_name[name_len..name_len + suffix.len()].copy_from_slice(suffix);
-        child.name = child_name;
+        if flags & CLONE_THREAD != 0 {
+            child.name = parent.name;
+        } else {
+            let mut child_name = [0u8; 32];
+            let name_len = core::cmp::min(parent_name.len(), 24);
+            child_name[..name_len].copy_from_slice(&parent_name.as_bytes()[..name_len]);
+            let suffix = b"_child";
+            child_name[name_len..name_len + suffix.len()].copy_from_slice(suffix);
+            child.name = child_name;
+        }
         
         self.next_pid += 1;
         
         // Add child to scheduler
         let child_id = scheduler_guard.add_task(child)?;
+        self.kernel_stacks.insert(child_id, stack_memory);
+        self.parents.insert(child_id, parent_id);
+        
+        // Child starts in the parent's cgroup
+        super::cgroup::fork(parent_id, child_id);
+
+        // Same memory areas as the parent, shared or copied
+        if let Some(areas) = crate::memory::vma::map_of(parent_id) {
+            crate::memory::vma::set_map(child_id, areas);
+        }
         
         Ok(child_id)
     }
@@ -111,38 +264,140 @@ impl ProcessManager {
     /// # Arguments
     /// * `task_id` - The task to terminate
     /// * `exit_code` - The exit code
-    pub fn exit_process(&mut self, task_id: TaskId, _exit_code: i32) -> Result<(), &'static str> {
+    pub fn exit_process(&mut self, task_id: TaskId, exit_code: i32) -> Result<(), &'static str> {
         let mut scheduler_guard = scheduler::scheduler();
         
         // Mark task as terminated
         scheduler_guard.terminate_task(task_id)?;
         
+        // Release cgroup membership and memory charge
+        super::cgroup::exit(task_id);
+        crate::memory::vma::remove_map(task_id);
+
+        // Init adopts the children, and collects their exit statuses; with
+        // no init they are nobody's
+        let init_running = scheduler_guard
+            .get_task(INIT_PID)
+            .is_some_and(|init| init.state != TaskState::Terminated);
+        if task_id != INIT_PID && init_running {
+            for parent in self.parents.values_mut().filter(|parent| **parent == task_id) {
+                *parent = INIT_PID;
+            }
+        } else {
+            self.parents.retain(|_, parent| *parent != task_id);
+        }
+
+        // Free the kernel stacks of exited processes no CPU still runs on,
+        // which may not include this one yet
+        self.kernel_stacks.retain(|&id, _| {
+            let exited = scheduler_guard.get_task(id).is_none_or(|task| task.state == TaskState::Terminated);
+            !exited || scheduler_guard.online_cpus().any(|cpu| scheduler_guard.cpu_current(cpu) == Some(id))
+        });
+
+        // Likewise their address spaces, once no CPU has them loaded
+        let mut exited_spaces: Vec<PhysAddr> = scheduler_guard
+            .tasks()
+            .filter(|task| task.state == TaskState::Terminated && task.page_table.as_u64() != 0)
+            .map(|task| task.page_table)
+            .collect();
+        exited_spaces.sort_unstable();
+        exited_spaces.dedup();
+        for page_table in exited_spaces {
+            // Their charges went with them
+            release_address_space(&mut scheduler_guard, page_table, None);
+        }
+
+        // Keep the exit code for whoever waits for the process
+        if self.exited.len() >= MAX_EXITED {
+            self.exited.remove(0);
+        }
+        self.exited.push((task_id, exit_code));
+        
         // In a real OS, we would:
-        // - Notify parent process
         // - Clean up resources (memory, file descriptors, etc.)
-        // - Wake up any waiting processes
         
         // Log the exit
         #[cfg(not(test))]
-        fanga_arch_x86_64::serial_println!(
-            "[PROCESS] Task {:?} exited with code {}",
+        crate::log_info!(
+            target: "process",
+            "Task {:?} exited with code {}",
             task_id,
-            _exit_code
+            exit_code
         );
         
         Ok(())
     }
+
+    /// Collect the exit code of a process that exited
+    ///
+    /// # Returns
+    /// The exit code, or `None` if the process has not exited or was
+    /// already collected
+    pub fn take_exit_status(&mut self, task_id: TaskId) -> Option<i32> {
+        let index = self.exited.iter().position(|&(id, _)| id == task_id)?;
+        self.parents.remove(&task_id);
+        Some(self.exited.remove(index).1)
+    }
+
+    /// Collect the exit code of a child of `parent` that exited
+    ///
+    /// # Arguments
+    /// * `parent` - The waiting process
+    /// * `child` - The child to collect, or `None` for any
+    ///
+    /// # Returns
+    /// The child and its exit code
+    pub fn take_child_exit(&mut self, parent: TaskId, child: Option<TaskId>) -> Option<(TaskId, i32)> {
+        let index = self.exited.iter().position(|&(id, _)| {
+            child.is_none_or(|child| child == id) && self.parents.get(&id) == Some(&parent)
+        })?;
+        let (id, code) = self.exited.remove(index);
+        self.parents.remove(&id);
+        Some((id, code))
+    }
+
+    /// The parent of a process
+    pub fn parent_of(&self, task_id: TaskId) -> Option<TaskId> {
+        self.parents.get(&task_id).copied()
+    }
+
+    /// Whether `parent` has children, running or not yet collected
+    pub fn has_children(&self, parent: TaskId) -> bool {
+        self.parents.values().any(|&id| id == parent)
+    }
     
     /// Get the next available PID
     pub fn next_pid(&self) -> usize {
         self.next_pid
     }
+
+    /// Number of kernel stacks held for processes
+    pub fn kernel_stack_count(&self) -> usize {
+        self.kernel_stacks.len()
+    }
 }
 
 /// Global process manager instance
 use spin::Mutex;
 static PROCESS_MANAGER: Mutex<ProcessManager> = Mutex::new(ProcessManager::new());
 
+/// A change in a process's run state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    /// Exited with an exit code
+    Exited(i32),
+    /// Stopped by a signal
+    Stopped,
+    /// Resumed by SIGCONT
+    Continued,
+}
+
+/// Processes waiting for a child to exit
+static CHILD_EXIT: WaitQueue = WaitQueue::new();
+
+/// Told about process status changes, with no process lock held
+static STATUS_NOTIFIER: Mutex<Option<fn(TaskId, ProcessStatus)>> = Mutex::new(None);
+
 /// Initialize the process manager
 pub fn init() {
     // Process manager is already initialized via static initialization
@@ -164,14 +419,89 @@ pub fn create_process(
     process_manager().create_process(entry_point, stack_size, page_table, priority)
 }
 
+/// Create the init process
+pub fn create_init_process(
+    entry_point: VirtAddr,
+    stack_size: usize,
+    page_table: PhysAddr,
+    priority: TaskPriority,
+) -> Result<TaskId, &'static str> {
+    process_manager().create_init_process(entry_point, stack_size, page_table, priority)
+}
+
 /// Fork the current process
 pub fn fork(parent_id: TaskId) -> Result<TaskId, &'static str> {
     process_manager().fork_process(parent_id)
 }
 
+/// Clone a process or create a thread of it
+pub fn clone(parent_id: TaskId, flags: u64, stack: u64, tls: u64) -> Result<TaskId, &'static str> {
+    process_manager().clone_process(parent_id, flags, stack, tls)
+}
+
 /// Exit the current process
 pub fn exit(task_id: TaskId, exit_code: i32) -> Result<(), &'static str> {
-    process_manager().exit_process(task_id, exit_code)
+    process_manager().exit_process(task_id, exit_code)?;
+    notify_status(task_id, ProcessStatus::Exited(exit_code));
+    CHILD_EXIT.wake_up_all();
+    Ok(())
+}
+
+/// Wait for a child of `parent` to exit and collect its exit code
+///
+/// # Arguments
+/// * `parent` - The waiting process
+/// * `child` - The child to wait for, or `None` for any
+/// * `block` - Whether to wait while the children are still running
+///
+/// # Returns
+/// The child and its exit code, `None` if none has exited and `block` is
+/// false, or an error if `parent` has no such child. Init always has
+/// children to wait for, as orphans are handed to it.
+pub fn wait_child(parent: TaskId, child: Option<TaskId>, block: bool) -> Result<Option<(TaskId, i32)>, &'static str> {
+    /// Recheck at least this often, in case a wakeup was missed
+    const POLL_MS: u64 = 100;
+
+    loop {
+        {
+            let mut pm = process_manager();
+            if let Some(exited) = pm.take_child_exit(parent, child) {
+                return Ok(Some(exited));
+            }
+            let is_child = match child {
+                Some(child) => pm.parent_of(child) == Some(parent),
+                None => parent == INIT_PID || pm.has_children(parent),
+            };
+            if !is_child {
+                return Err("No such child");
+            }
+        }
+        if !block {
+            return Ok(None);
+        }
+        wait::sleep_on_timeout(&CHILD_EXIT, POLL_MS)?;
+    }
+}
+
+/// Tell the status notifier about a process
+pub fn notify_status(task_id: TaskId, status: ProcessStatus) {
+    let notifier = *STATUS_NOTIFIER.lock();
+    if let Some(notify) = notifier {
+        notify(task_id, status);
+    }
+}
+
+/// Collect the exit code of a process without waiting
+pub fn try_wait(task_id: TaskId) -> Option<i32> {
+    process_manager().take_exit_status(task_id)
+}
+
+/// Set the function told when any process exits, stops or continues
+///
+/// It may run in any context, so it should only note the change or queue
+/// work.
+pub fn set_status_notifier(notify: fn(TaskId, ProcessStatus)) {
+    *STATUS_NOTIFIER.lock() = Some(notify);
 }
 
 #[cfg(test)]
@@ -222,4 +552,93 @@ mod tests {
         let child_id = result.unwrap();
         assert_ne!(parent_id, child_id);
     }
+
+    #[test]
+    fn test_clone_tls() {
+        scheduler::init();
+
+        let mut pm = ProcessManager::new();
+        let parent_id = pm.create_process(
+            VirtAddr::new(0x1000),
+            4096,
+            PhysAddr::new(0x0),
+            TaskPriority::Normal,
+        ).unwrap();
+        if let Some(parent) = scheduler::scheduler().get_task_mut(parent_id) {
+            parent.tls_base = VirtAddr::new(0x7000_0000);
+            parent.mmap_base = VirtAddr::new(0x7f00_0000_0000);
+        }
+
+        // A forked child keeps the parent's thread pointer and mmap base
+        let child = pm.fork_process(parent_id).unwrap();
+        assert_eq!(scheduler::scheduler().get_task(child).unwrap().tls_base, VirtAddr::new(0x7000_0000));
+        assert_eq!(scheduler::scheduler().get_task(child).unwrap().mmap_base, VirtAddr::new(0x7f00_0000_0000));
+
+        // A thread gets its own TLS block and stack
+        let flags = CLONE_VM | CLONE_THREAD | CLONE_SETTLS;
+        let thread = pm.clone_process(parent_id, flags, 0x7fff_0000, 0x7100_0000).unwrap();
+        {
+            let sched = scheduler::scheduler();
+            let thread = sched.get_task(thread).unwrap();
+            assert_eq!(thread.tls_base, VirtAddr::new(0x7100_0000));
+            assert_eq!(thread.context.rsp, 0x7fff_0000);
+            assert_eq!(thread.name(), sched.get_task(parent_id).unwrap().name());
+        }
+
+        assert!(pm.clone_process(parent_id, CLONE_SETTLS, 0, 0x8000_0000_0000).is_err());
+        assert!(pm.clone_process(parent_id, CLONE_THREAD, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        scheduler::init();
+
+        let mut pm = ProcessManager::new();
+        let id = pm.create_process(
+            VirtAddr::new(0x1000),
+            4096,
+            PhysAddr::new(0x0),
+            TaskPriority::Normal,
+        ).unwrap();
+        assert_eq!(pm.take_exit_status(id), None);
+        assert_eq!(pm.kernel_stack_count(), 1);
+        {
+            let sched = scheduler::scheduler();
+            let task = sched.get_task(id).unwrap();
+            assert_eq!(task.context.rsp, task.kernel_stack.as_u64() + task.kernel_stack_size as u64);
+            assert_eq!(task.context.rsp % 16, 0);
+        }
+
+        // The stack goes with the process
+        pm.exit_process(id, 3).unwrap();
+        assert_eq!(pm.kernel_stack_count(), 0);
+        assert_eq!(pm.take_exit_status(id), Some(3));
+        assert_eq!(pm.take_exit_status(id), None);
+    }
+
+    #[test]
+    fn test_orphans_go_to_init() {
+        scheduler::init();
+
+        let mut pm = ProcessManager::new();
+        let init = pm.create_init_process(
+            VirtAddr::new(0x1000),
+            4096,
+            PhysAddr::new(0x0),
+            TaskPriority::Normal,
+        ).unwrap();
+        assert_eq!(init, INIT_PID);
+        let parent = pm.fork_process(init).unwrap();
+        let child = pm.fork_process(parent).unwrap();
+        assert_eq!(pm.parent_of(child), Some(parent));
+        assert!(pm.take_child_exit(init, None).is_none());
+
+        // The parent exits before the child, which init adopts
+        pm.exit_process(parent, 0).unwrap();
+        assert_eq!(pm.parent_of(child), Some(INIT_PID));
+        pm.exit_process(child, 7).unwrap();
+        assert_eq!(pm.take_child_exit(init, Some(parent)), Some((parent, 0)));
+        assert_eq!(pm.take_child_exit(init, None), Some((child, 7)));
+        assert!(!pm.has_children(init));
+    }
 }
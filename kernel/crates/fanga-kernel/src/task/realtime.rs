@@ -0,0 +1,202 @@
+//! Wall-Clock Time
+//!
+//! At boot the date and time are read once from the CMOS RTC and turned
+//! into an offset from the monotonic clock. The realtime clock is that
+//! offset plus `ktime_ns()`, so it advances with the tick (or the TSC)
+//! without touching the RTC again, and setting the time only moves the
+//! offset. The RTC is assumed to hold UTC.
+//!
+//! This module provides:
+//! - RTC-based initialization of the realtime clock
+//! - `realtime_ns()` for `clock_gettime(CLOCK_REALTIME)` and timestamps
+//! - Conversion between Unix time and calendar dates
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use fanga_arch_x86_64::rtc;
+
+use super::clocksource::ktime_ns;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Realtime at `ktime_ns() == 0`, in nanoseconds since the Unix epoch
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the realtime clock has been set
+static VALID: AtomicBool = AtomicBool::new(false);
+
+/// A UTC calendar date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    // Count years from March so the leap day ends the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Calendar date of a Unix timestamp
+    pub fn from_unix(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let time = secs % SECS_PER_DAY;
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Unix timestamp of this date, or `None` before 1970
+    pub fn to_unix(&self) -> Option<u64> {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let secs = days * SECS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        u64::try_from(secs).ok()
+    }
+
+    /// Check that every field is in range
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+impl From<rtc::RtcTime> for DateTime {
+    fn from(time: rtc::RtcTime) -> Self {
+        Self {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Set the realtime clock to `ns` since the Unix epoch
+pub fn set_realtime_ns(ns: u64) {
+    BOOT_REALTIME_NS.store(ns.saturating_sub(ktime_ns()), Ordering::Relaxed);
+    VALID.store(true, Ordering::Release);
+}
+
+/// Nanoseconds since the Unix epoch, if the clock has been set
+pub fn realtime_ns() -> Option<u64> {
+    VALID
+        .load(Ordering::Acquire)
+        .then(|| BOOT_REALTIME_NS.load(Ordering::Relaxed) + ktime_ns())
+}
+
+/// Seconds since the Unix epoch, 0 if the clock has not been set
+///
+/// Used for file timestamps.
+pub fn now_secs() -> u64 {
+    realtime_ns().map_or(0, |ns| ns / NSEC_PER_SEC)
+}
+
+/// Current date and time, if the clock has been set
+pub fn now() -> Option<DateTime> {
+    realtime_ns().map(|ns| DateTime::from_unix(ns / NSEC_PER_SEC))
+}
+
+/// Set the realtime clock from the CMOS RTC
+///
+/// Called at boot, after the monotonic clock is set up, and again on wake
+/// from S3, when the clock missed the time spent asleep.
+pub fn init() {
+    let time = DateTime::from(rtc::read(Some(rtc::DEFAULT_CENTURY_REG)));
+    match time.to_unix().filter(|_| time.is_valid()) {
+        Some(secs) => {
+            set_realtime_ns(secs * NSEC_PER_SEC);
+            crate::log_info!(target: "time", "Realtime clock: {}", time);
+        }
+        None => crate::log_warn!(target: "time", "RTC holds an invalid date: {}", time),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_conversions() {
+        let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(epoch.to_unix(), Some(0));
+        assert_eq!(DateTime::from_unix(0), epoch);
+
+        // Leap day and end of a leap year
+        let leap = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+        assert_eq!(leap.to_unix(), Some(1_709_210_096));
+        assert_eq!(DateTime::from_unix(1_709_210_096), leap);
+        assert_eq!(DateTime::from_unix(1_735_689_599).to_unix(), Some(1_735_689_599));
+        assert_eq!(DateTime::from_unix(1_735_689_599).day, 31);
+
+        let before_epoch = DateTime { year: 1969, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+        assert_eq!(before_epoch.to_unix(), None);
+    }
+
+    #[test]
+    fn test_display_and_validity() {
+        let time = DateTime { year: 2026, month: 3, day: 7, hour: 8, minute: 5, second: 9 };
+        assert_eq!(alloc::format!("{}", time), "2026-03-07 08:05:09 UTC");
+        assert!(time.is_valid());
+        assert!(!DateTime { month: 13, ..time }.is_valid());
+        assert!(!DateTime { day: 0, ..time }.is_valid());
+    }
+
+    #[test]
+    fn test_unset_clock() {
+        // Nothing sets the clock on the host
+        assert_eq!(realtime_ns(), None);
+        assert_eq!(now_secs(), 0);
+        assert!(now().is_none());
+    }
+}
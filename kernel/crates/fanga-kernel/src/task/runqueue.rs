@@ -0,0 +1,765 @@
+//! Per-CPU Run Queues
+//!
+//! Every CPU owns a run queue holding its ready tasks, its current task and
+//! its idle task, each behind its own lock. Queue entries carry what
+//! picking and placing a task needs (priority, affinity, throttling), so
+//! picking the next task, placing a woken one and load balancing take run
+//! queue locks only, never the task table. A CPU picking its next task
+//! locks its own queue; moving a task between CPUs locks both queues in
+//! ascending CPU order (`RunQueues::lock_pair`), the second as its own
+//! lockdep class.
+//!
+//! A task switched away from keeps its on-CPU flag until `switch_context`
+//! has saved its context, and until then no other CPU may run it: it is
+//! neither stolen nor migrated, and a wakeup queues it on its own CPU.
+//!
+//! This module provides:
+//! - Priority ordered ready queues with eligibility filtering
+//! - Current and idle task tracking per CPU
+//! - Task placement, migration and load balancing
+//! - Paired locking for migrations
+//! - Switch and migration counters
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use super::scheduler::MAX_TASKS;
+use super::tcb::{cpu_in_mask, Task, TaskId, TaskPriority};
+use crate::smp::cpu::{CpuId, MAX_CPUS};
+use crate::smp::lockdep::LockClass;
+use crate::smp::resched;
+use crate::smp::spinlock::{SpinLock, SpinLockGuard};
+
+/// Lockdep class of run queue locks
+static RUNQUEUE_CLASS: LockClass = LockClass::new("runqueue");
+/// Lockdep class of the second run queue taken by `lock_pair`
+static RUNQUEUE_NESTED_CLASS: LockClass = LockClass::new("runqueue-nested");
+
+/// Number of priority levels
+const PRIORITY_LEVELS: usize = 4;
+
+/// ID of the CPU running this code
+fn this_cpu() -> usize {
+    crate::smp::current_cpu_id().as_usize()
+}
+
+/// A task's entry on a run queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RqTask {
+    /// The task
+    pub id: TaskId,
+    /// Priority it is queued at
+    pub priority: TaskPriority,
+    /// CPUs it may run on (bit N = CPU N)
+    pub cpus_allowed: u64,
+    /// Set while its cgroup has exhausted its CPU quota
+    pub throttled: bool,
+}
+
+impl RqTask {
+    /// Entry for a task allowed on every CPU
+    pub const fn new(id: TaskId, priority: TaskPriority) -> Self {
+        Self { id, priority, cpus_allowed: u64::MAX, throttled: false }
+    }
+
+    /// Entry carrying a task's scheduling attributes
+    pub fn of(task: &Task) -> Self {
+        Self {
+            id: task.id,
+            priority: task.priority,
+            cpus_allowed: task.cpus_allowed,
+            throttled: task.throttled,
+        }
+    }
+
+    /// Check whether the task may run on a CPU
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        cpu_in_mask(self.cpus_allowed, cpu)
+    }
+
+    /// Check whether the task may be picked on a CPU
+    pub fn runnable_on(&self, cpu: usize) -> bool {
+        !self.throttled && self.can_run_on(cpu)
+    }
+}
+
+/// Ready tasks and running task of one CPU
+pub struct RunQueue {
+    /// Ready tasks, one queue per priority level
+    queues: [VecDeque<RqTask>; PRIORITY_LEVELS],
+    /// Task running on this CPU
+    current: Option<RqTask>,
+    /// Whether the current task is queued again when this CPU next picks
+    requeue: bool,
+    /// Idle task of this CPU
+    idle: Option<TaskId>,
+    /// Going offline: queued tasks are pushed away and the idle task runs
+    dying: bool,
+    /// Context switches on this CPU
+    pub nr_switches: u64,
+    /// Tasks moved to this CPU from another one
+    pub nr_migrations_in: u64,
+}
+
+impl RunQueue {
+    /// Create an empty run queue
+    pub const fn new() -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            current: None,
+            requeue: false,
+            idle: None,
+            dying: false,
+            nr_switches: 0,
+            nr_migrations_in: 0,
+        }
+    }
+
+    /// Queue a ready task behind others of its priority
+    ///
+    /// Does nothing if the task is already queued.
+    pub fn enqueue(&mut self, task: RqTask) {
+        if !self.contains(task.id) {
+            self.queues[task.priority as usize].push_back(task);
+        }
+    }
+
+    /// Take a task off the ready queues
+    pub fn take(&mut self, task: TaskId) -> Option<RqTask> {
+        for queue in &mut self.queues {
+            if let Some(pos) = queue.iter().position(|t| t.id == task) {
+                return queue.remove(pos);
+            }
+        }
+        None
+    }
+
+    /// Remove a task from the ready queues
+    ///
+    /// Returns true if it was queued.
+    pub fn remove(&mut self, task: TaskId) -> bool {
+        self.take(task).is_some()
+    }
+
+    /// Check whether a task is queued
+    pub fn contains(&self, task: TaskId) -> bool {
+        self.entries().any(|t| t.id == task)
+    }
+
+    /// Change the affinity or throttling of a queued or current task
+    ///
+    /// Returns false if the task is neither.
+    pub fn update(&mut self, task: TaskId, mut change: impl FnMut(&mut RqTask)) -> bool {
+        let mut found = false;
+        for entry in self.queues.iter_mut().flatten().chain(self.current.as_mut()) {
+            if entry.id == task {
+                change(entry);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Take the first eligible task, highest priority first
+    ///
+    /// Ineligible tasks keep their place in the queue.
+    pub fn pop_eligible(&mut self, mut eligible: impl FnMut(&RqTask) -> bool) -> Option<RqTask> {
+        for queue in self.queues.iter_mut().rev() {
+            if let Some(pos) = queue.iter().position(&mut eligible) {
+                return queue.remove(pos);
+            }
+        }
+        None
+    }
+
+    /// Take the eligible task that would wait longest, for a migration
+    ///
+    /// Looks at the highest priority first, so a pulled task is one that
+    /// matters, but takes it from the tail of its queue. The current task
+    /// is never taken, even while it is queued again on its way out.
+    pub fn steal(&mut self, mut eligible: impl FnMut(&RqTask) -> bool) -> Option<RqTask> {
+        let current = self.current();
+        for queue in self.queues.iter_mut().rev() {
+            if let Some(pos) = queue.iter().rposition(|t| Some(t.id) != current && eligible(t)) {
+                return queue.remove(pos);
+            }
+        }
+        None
+    }
+
+    /// Iterate over the queued tasks, highest priority first
+    pub fn queued(&self) -> impl Iterator<Item = TaskId> + '_ {
+        self.entries().map(|t| t.id)
+    }
+
+    /// Iterate over the queue entries, highest priority first
+    pub fn entries(&self) -> impl Iterator<Item = &RqTask> + '_ {
+        self.queues.iter().rev().flatten()
+    }
+
+    /// Number of queued tasks
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// Check whether no task is queued
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Task running on this CPU
+    pub fn current(&self) -> Option<TaskId> {
+        self.current.map(|t| t.id)
+    }
+
+    /// Set the task running on this CPU
+    ///
+    /// A task other than the idle task is queued again when this CPU next
+    /// picks, unless it stops being runnable first (`park_current`).
+    pub fn set_current(&mut self, task: Option<RqTask>) {
+        self.requeue = task.is_some_and(|t| Some(t.id) != self.idle);
+        self.current = task;
+    }
+
+    /// Keep a task that blocked while running off the queue when this CPU
+    /// next picks
+    pub fn park_current(&mut self, task: TaskId) {
+        if self.current() == Some(task) {
+            self.requeue = false;
+        }
+    }
+
+    /// Queue the current task again if it is still runnable
+    fn requeue_current(&mut self) {
+        if core::mem::take(&mut self.requeue) {
+            if let Some(task) = self.current {
+                self.enqueue(task);
+            }
+        }
+    }
+
+    /// Priority of the task running on this CPU, or None if it is idle
+    pub fn running_priority(&self) -> Option<TaskPriority> {
+        self.current.filter(|t| Some(t.id) != self.idle).map(|t| t.priority)
+    }
+
+    /// Idle task of this CPU
+    pub fn idle(&self) -> Option<TaskId> {
+        self.idle
+    }
+
+    /// Set the idle task of this CPU
+    pub fn set_idle(&mut self, task: TaskId) {
+        self.idle = Some(task);
+    }
+
+    /// Queue entry standing for the idle task, which is never queued
+    fn idle_entry(&self) -> Option<RqTask> {
+        self.idle.map(|id| RqTask::new(id, TaskPriority::Low))
+    }
+
+    /// Check whether this CPU runs its idle task (or nothing)
+    pub fn is_idle(&self) -> bool {
+        self.current.is_none() || self.current() == self.idle
+    }
+}
+
+impl Default for RunQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outcome of `RunQueues::pick_next`
+pub struct Pick {
+    /// Task the CPU switches away from
+    pub prev: Option<TaskId>,
+    /// Task the CPU runs next
+    pub next: Option<TaskId>,
+    /// Tasks moved or unpinned on the way, with their new entry and CPU
+    pub moved: Vec<(RqTask, usize)>,
+    /// Tasks left queued on the CPU
+    pub queued: usize,
+}
+
+/// The run queues of all CPUs
+pub struct RunQueues {
+    rqs: [SpinLock<RunQueue>; MAX_CPUS],
+    /// CPUs taking tasks, one bit each
+    online: [AtomicU64; MAX_CPUS.div_ceil(64)],
+    /// CPU whose run queue each task joined last, written under that
+    /// queue's lock
+    task_cpu: [AtomicUsize; MAX_TASKS],
+    /// Whether each task is running, or switched away from with its
+    /// context not saved yet
+    on_cpu: [AtomicBool; MAX_TASKS],
+}
+
+impl RunQueues {
+    /// Create empty run queues
+    pub const fn new() -> Self {
+        Self {
+            rqs: [const { SpinLock::with_class(RunQueue::new(), &RUNQUEUE_CLASS) }; MAX_CPUS],
+            online: [const { AtomicU64::new(0) }; MAX_CPUS.div_ceil(64)],
+            task_cpu: [const { AtomicUsize::new(0) }; MAX_TASKS],
+            on_cpu: [const { AtomicBool::new(false) }; MAX_TASKS],
+        }
+    }
+
+    /// Lock the run queue of a CPU
+    #[track_caller]
+    pub fn lock(&self, cpu: usize) -> SpinLockGuard<'_, RunQueue> {
+        self.rqs[cpu].lock()
+    }
+
+    /// Try to lock the run queue of a CPU without spinning
+    #[track_caller]
+    pub fn try_lock(&self, cpu: usize) -> Option<SpinLockGuard<'_, RunQueue>> {
+        self.rqs[cpu].try_lock()
+    }
+
+    /// Lock two different CPUs' run queues, lower CPU first
+    ///
+    /// Returns the guards in argument order.
+    #[track_caller]
+    pub fn lock_pair(&self, a: usize, b: usize) -> (SpinLockGuard<'_, RunQueue>, SpinLockGuard<'_, RunQueue>) {
+        assert_ne!(a, b, "lock_pair on a single run queue");
+        if a < b {
+            let first = self.rqs[a].lock();
+            (first, self.rqs[b].lock_nested(&RUNQUEUE_NESTED_CLASS))
+        } else {
+            let first = self.rqs[b].lock();
+            (self.rqs[a].lock_nested(&RUNQUEUE_NESTED_CLASS), first)
+        }
+    }
+
+    /// CPU whose run queue a task joined last
+    pub fn task_cpu(&self, task: TaskId) -> usize {
+        self.task_cpu.get(task.as_usize()).map_or(0, |cpu| cpu.load(Ordering::Acquire))
+    }
+
+    /// Record the CPU a task joins, with that CPU's run queue locked
+    fn set_task_cpu(&self, task: TaskId, cpu: usize) {
+        if let Some(slot) = self.task_cpu.get(task.as_usize()) {
+            slot.store(cpu, Ordering::Release);
+        }
+    }
+
+    /// Check whether a task runs on a CPU, or has not been saved since
+    /// one switched away from it
+    pub fn on_cpu(&self, task: TaskId) -> bool {
+        self.on_cpu.get(task.as_usize()).is_some_and(|flag| flag.load(Ordering::Acquire))
+    }
+
+    /// On-CPU flag of a task, for `switch_context` to clear once it saved
+    /// the task's context
+    pub fn on_cpu_flag(&self, task: TaskId) -> Option<&AtomicBool> {
+        self.on_cpu.get(task.as_usize())
+    }
+
+    /// Mark a task as switched away from and saved
+    ///
+    /// For switches that save no context; `switch_context` does this
+    /// itself.
+    pub fn finish_switch(&self, task: TaskId) {
+        if let Some(flag) = self.on_cpu_flag(task) {
+            flag.store(false, Ordering::Release);
+        }
+    }
+
+    /// Mark a task as running, with its CPU's run queue locked
+    fn set_on_cpu(&self, task: TaskId) {
+        if let Some(flag) = self.on_cpu_flag(task) {
+            flag.store(true, Ordering::Release);
+        }
+    }
+
+    /// Lock the run queue of the CPU a task is on
+    ///
+    /// The task may move while that queue is being locked, so this retries
+    /// until the CPU it is on and the queue locked agree. Returns the CPU
+    /// with the guard.
+    #[track_caller]
+    pub fn lock_task(&self, task: TaskId) -> (usize, SpinLockGuard<'_, RunQueue>) {
+        loop {
+            let cpu = self.task_cpu(task);
+            let rq = self.lock(cpu);
+            if self.task_cpu(task) == cpu {
+                return (cpu, rq);
+            }
+        }
+    }
+
+    /// Check whether a CPU takes tasks
+    pub fn is_online(&self, cpu: usize) -> bool {
+        self.online[cpu / 64].load(Ordering::Acquire) & (1 << (cpu % 64)) != 0
+    }
+
+    /// Mark a CPU as taking tasks or not
+    fn set_online(&self, cpu: usize, online: bool) {
+        let bit = 1 << (cpu % 64);
+        if online {
+            self.online[cpu / 64].fetch_or(bit, Ordering::AcqRel);
+        } else {
+            self.online[cpu / 64].fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+
+    /// Iterate over the CPUs taking tasks
+    pub fn online_cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_CPUS).filter(|&cpu| self.is_online(cpu))
+    }
+
+    /// Number of ready tasks queued on `this` CPU and the online ones
+    pub fn nr_ready(&self, this: usize) -> usize {
+        let others = self.online_cpus().filter(|&cpu| cpu != this);
+        core::iter::once(this).chain(others).map(|cpu| self.lock(cpu).len()).sum()
+    }
+
+    /// Set the idle task of a CPU
+    pub fn set_idle(&self, cpu: usize, task: TaskId) {
+        let mut rq = self.lock(cpu);
+        rq.set_idle(task);
+        self.set_task_cpu(task, cpu);
+    }
+
+    /// Make a CPU's idle task current and start placing tasks on the CPU
+    ///
+    /// Returns the idle task, or None if the CPU has none.
+    pub fn activate(&self, cpu: usize) -> Option<TaskId> {
+        let idle = {
+            let mut rq = self.lock(cpu);
+            let idle = rq.idle_entry()?;
+            rq.set_current(Some(idle));
+            self.set_on_cpu(idle.id);
+            rq.dying = false;
+            idle.id
+        };
+        self.set_online(cpu, true);
+        Some(idle)
+    }
+
+    /// Stop placing tasks on a CPU that is going offline
+    ///
+    /// Its queued tasks move to the remaining CPUs; the task it runs moves
+    /// once the CPU picks again. Returns what `drain` does.
+    pub fn deactivate(&self, cpu: usize) -> Result<Vec<(RqTask, usize)>, &'static str> {
+        if !self.is_online(cpu) {
+            return Err("CPU not online");
+        }
+        if self.online_cpus().nth(1).is_none() {
+            return Err("Last online CPU");
+        }
+        self.set_online(cpu, false);
+        self.lock(cpu).dying = true;
+        Ok(self.drain(cpu))
+    }
+
+    /// Queue a ready task on a CPU
+    pub fn enqueue(&self, cpu: usize, task: RqTask) {
+        let mut rq = self.lock(cpu);
+        rq.enqueue(task);
+        self.set_task_cpu(task.id, cpu);
+    }
+
+    /// Queue a woken task, returning the CPU it joins
+    ///
+    /// A task not switched away from and saved yet stays on its CPU; any
+    /// other goes where `select_cpu` places it.
+    pub fn wake(&self, task: RqTask) -> usize {
+        {
+            let (cpu, mut rq) = self.lock_task(task.id);
+            if rq.current() == Some(task.id) || self.on_cpu(task.id) {
+                rq.enqueue(task);
+                return cpu;
+            }
+        }
+        let cpu = self.select_cpu(&task);
+        self.enqueue(cpu, task);
+        cpu
+    }
+
+    /// Choose the CPU whose run queue a ready task joins
+    ///
+    /// An idle CPU or one running something less important wins, then the
+    /// least loaded one. Before any CPU is online the task stays here.
+    pub fn select_cpu(&self, task: &RqTask) -> usize {
+        let this = this_cpu();
+        let running: Vec<_> = self
+            .online_cpus()
+            .filter(|&cpu| task.can_run_on(cpu))
+            .map(|cpu| {
+                let rq = self.lock(cpu);
+                (cpu, rq.running_priority(), rq.len())
+            })
+            .collect();
+        
+        if let Some(cpu) = resched::preempt_target(task.priority, this, &running) {
+            return cpu;
+        }
+        running
+            .iter()
+            .min_by_key(|&&(cpu, _, queued)| (queued, cpu != this))
+            .map_or(this, |&(cpu, _, _)| cpu)
+    }
+
+    /// Make a CPU reschedule if a newly queued task should preempt it
+    pub fn check_preempt(&self, cpu: usize, priority: TaskPriority) {
+        let running = self.lock(cpu).running_priority();
+        if running.is_none_or(|running| running < priority) {
+            // A lost IPI only delays the switch to the target's next tick
+            let _ = resched::resched_cpu(CpuId::new(cpu));
+        }
+    }
+
+    /// Move a task to another CPU's run queue
+    ///
+    /// A task that is not queued joins `dest` when it is next woken. A task
+    /// running on its CPU cannot be moved; make that CPU reschedule
+    /// instead. Returns the task's entry if it changed queues.
+    pub fn migrate(&self, task: TaskId, dest: usize) -> Result<Option<RqTask>, &'static str> {
+        loop {
+            let src = self.task_cpu(task);
+            if src == dest {
+                return Ok(None);
+            }
+            let (mut from, mut to) = self.lock_pair(src, dest);
+            if self.task_cpu(task) != src {
+                continue;
+            }
+            if from.current() == Some(task) || self.on_cpu(task) {
+                return Err("Task is running");
+            }
+            let entry = from.take(task);
+            if let Some(entry) = entry {
+                to.enqueue(entry);
+                to.nr_migrations_in += 1;
+            }
+            self.set_task_cpu(task, dest);
+            return Ok(entry);
+        }
+    }
+
+    /// Move tasks queued on a CPU they may no longer run on
+    ///
+    /// Returns the tasks moved, with their new CPU.
+    pub fn migrate_disallowed(&self, cpu: usize) -> Vec<(RqTask, usize)> {
+        let stuck: Vec<RqTask> = self.lock(cpu).entries().filter(|t| !t.can_run_on(cpu)).copied().collect();
+        let mut moved = Vec::new();
+        
+        for task in stuck {
+            let dest = self.select_cpu(&task);
+            if dest == cpu {
+                continue;
+            }
+            if let Ok(Some(entry)) = self.migrate(task.id, dest) {
+                moved.push((entry, dest));
+            }
+        }
+        moved
+    }
+
+    /// Move every task queued on a CPU to the online CPUs
+    ///
+    /// A task allowed on no online CPU loses its affinity rather than
+    /// being stranded. Returns the tasks moved or unpinned, with their new
+    /// entry and CPU.
+    pub fn drain(&self, cpu: usize) -> Vec<(RqTask, usize)> {
+        let queued: Vec<RqTask> = self.lock(cpu).entries().copied().collect();
+        let mut moved = Vec::new();
+        
+        for mut task in queued {
+            let unpinned = !self.online_cpus().any(|other| task.can_run_on(other));
+            if unpinned {
+                if !self.lock(cpu).update(task.id, |t| t.cpus_allowed = u64::MAX) {
+                    continue;
+                }
+                task.cpus_allowed = u64::MAX;
+                #[cfg(not(test))]
+                crate::log_warn!(
+                    target: "sched",
+                    "Task {} loses its CPU affinity (CPU {} going offline)",
+                    task.id.as_usize(), cpu
+                );
+            }
+            
+            let dest = self.select_cpu(&task);
+            if dest != cpu && matches!(self.migrate(task.id, dest), Ok(Some(_))) {
+                moved.push((task, dest));
+            } else if unpinned {
+                moved.push((task, cpu));
+            }
+        }
+        moved
+    }
+
+    /// Pull one task from the busiest other CPU
+    ///
+    /// Tasks whose context is not saved yet are left alone.
+    fn pull(&self, cpu: usize) -> Option<(RqTask, usize)> {
+        if !self.is_online(cpu) {
+            return None;
+        }
+        let busiest = self
+            .online_cpus()
+            .filter(|&other| other != cpu)
+            .map(|other| (other, self.lock(other).len()))
+            .filter(|&(_, len)| len > 0)
+            .max_by_key(|&(other, len)| (len, Reverse(other)))?
+            .0;
+        
+        let (mut here, mut there) = self.lock_pair(cpu, busiest);
+        let stolen = there.steal(|t| t.runnable_on(cpu) && !self.on_cpu(t.id))?;
+        here.enqueue(stolen);
+        here.nr_migrations_in += 1;
+        self.set_task_cpu(stolen.id, cpu);
+        Some((stolen, cpu))
+    }
+
+    /// Queue a CPU's current task again if it is still runnable and pick
+    /// the next one
+    ///
+    /// Tasks no longer allowed on the CPU move away and an empty queue
+    /// pulls work from the busiest CPU; a CPU going offline gives up all
+    /// of its tasks. The highest priority runnable task is picked:
+    /// throttled tasks stay queued but are passed over, and with nothing
+    /// runnable the idle task runs.
+    pub fn pick_next(&self, cpu: usize) -> Pick {
+        let (prev, dying) = {
+            let mut rq = self.lock(cpu);
+            rq.requeue_current();
+            (rq.current(), rq.dying)
+        };
+        
+        let moved = if dying {
+            self.drain(cpu)
+        } else {
+            let mut moved = self.migrate_disallowed(cpu);
+            if self.lock(cpu).is_empty() {
+                moved.extend(self.pull(cpu));
+            }
+            moved
+        };
+        
+        let mut rq = self.lock(cpu);
+        let next = rq.pop_eligible(|t| t.runnable_on(cpu)).or(rq.idle_entry());
+        rq.set_current(next);
+        let next = next.map(|t| t.id);
+        if next != prev {
+            rq.nr_switches += 1;
+            if let Some(next) = next {
+                self.set_on_cpu(next);
+            }
+        }
+        Pick { prev, next, moved, queued: rq.len() }
+    }
+}
+
+impl Default for RunQueues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: usize, priority: TaskPriority) -> RqTask {
+        RqTask::new(TaskId::new(id), priority)
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut rq = RunQueue::new();
+        rq.enqueue(entry(1, TaskPriority::Low));
+        rq.enqueue(entry(2, TaskPriority::High));
+        rq.enqueue(entry(3, TaskPriority::High));
+        rq.enqueue(entry(2, TaskPriority::High));
+        assert_eq!(rq.len(), 3);
+
+        // Task 2 is skipped but keeps its place
+        assert_eq!(rq.pop_eligible(|t| t.id != TaskId::new(2)), Some(entry(3, TaskPriority::High)));
+        assert_eq!(rq.pop_eligible(|_| true), Some(entry(2, TaskPriority::High)));
+        assert_eq!(rq.pop_eligible(|_| true), Some(entry(1, TaskPriority::Low)));
+        assert!(rq.is_empty());
+    }
+
+    #[test]
+    fn test_steal_from_tail() {
+        let mut rq = RunQueue::new();
+        for id in 1..=3 {
+            rq.enqueue(entry(id, TaskPriority::Normal));
+        }
+        assert_eq!(rq.steal(|_| true), Some(entry(3, TaskPriority::Normal)));
+        assert!(rq.remove(TaskId::new(1)));
+        assert!(!rq.remove(TaskId::new(1)));
+        assert_eq!(rq.queued().collect::<alloc::vec::Vec<_>>(), alloc::vec![TaskId::new(2)]);
+    }
+
+    #[test]
+    fn test_lock_pair() {
+        let rqs = alloc::boxed::Box::new(RunQueues::new());
+        {
+            let (mut a, b) = rqs.lock_pair(3, 1);
+            a.enqueue(entry(7, TaskPriority::Normal));
+            assert!(b.is_empty());
+        }
+        assert!(rqs.lock(3).contains(TaskId::new(7)));
+        assert!(rqs.lock(1).is_idle());
+    }
+
+    #[test]
+    fn test_pick_next() {
+        let rqs = alloc::boxed::Box::new(RunQueues::new());
+        rqs.set_idle(0, TaskId::new(9));
+        assert_eq!(rqs.activate(0), Some(TaskId::new(9)));
+        rqs.enqueue(0, entry(1, TaskPriority::Normal));
+        rqs.enqueue(0, entry(2, TaskPriority::Normal));
+        rqs.enqueue(0, RqTask { throttled: true, ..entry(3, TaskPriority::High) });
+
+        // Round robin past the throttled task; idle is never queued
+        let pick = rqs.pick_next(0);
+        assert_eq!((pick.prev, pick.next), (Some(TaskId::new(9)), Some(TaskId::new(1))));
+        assert_eq!(rqs.pick_next(0).next, Some(TaskId::new(2)));
+        assert_eq!(rqs.pick_next(0).next, Some(TaskId::new(1)));
+        assert_eq!(rqs.nr_ready(0), 2);
+
+        // A parked task is not queued again; with nothing runnable idle runs
+        rqs.lock(0).park_current(TaskId::new(1));
+        assert!(rqs.lock(0).remove(TaskId::new(2)));
+        assert_eq!(rqs.pick_next(0).next, Some(TaskId::new(9)));
+        assert!(rqs.lock(0).update(TaskId::new(3), |t| t.throttled = false));
+        assert_eq!(rqs.pick_next(0).next, Some(TaskId::new(3)));
+        assert!(rqs.lock(0).is_empty());
+    }
+
+    #[test]
+    fn test_unsaved_task_stays() {
+        let rqs = alloc::boxed::Box::new(RunQueues::new());
+        for cpu in 0..2 {
+            rqs.set_idle(cpu, TaskId::new(8 + cpu));
+            rqs.activate(cpu);
+        }
+        rqs.enqueue(0, entry(1, TaskPriority::Normal));
+        rqs.enqueue(0, entry(2, TaskPriority::Normal));
+        rqs.pick_next(0);
+
+        // 1 was switched away from but is not saved yet
+        let pick = rqs.pick_next(0);
+        assert_eq!((pick.prev, pick.next, pick.queued), (Some(TaskId::new(1)), Some(TaskId::new(2)), 1));
+        assert!(rqs.on_cpu(TaskId::new(1)));
+        assert_eq!(rqs.pick_next(1).next, Some(TaskId::new(9)));
+        assert!(rqs.migrate(TaskId::new(1), 1).is_err());
+        rqs.lock(0).remove(TaskId::new(1));
+        assert_eq!(rqs.wake(entry(1, TaskPriority::Normal)), 0);
+
+        // Once saved it may run anywhere
+        rqs.finish_switch(TaskId::new(1));
+        let pick = rqs.pick_next(1);
+        assert_eq!((pick.next, rqs.task_cpu(TaskId::new(1))), (Some(TaskId::new(1)), 1));
+    }
+}
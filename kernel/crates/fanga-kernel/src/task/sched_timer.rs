@@ -1,60 +1,169 @@
 //! Preemptive Scheduling
 //!
 //! This module implements timer-based preemptive multitasking.
-//! It integrates with the timer interrupt to perform periodic context switches.
+//! The timer tick charges the running task and, when its time slice runs
+//! out, sets the CPU's need-resched flag. The switch itself happens on the
+//! way out of an interrupt from user mode (`preempt::points::preempt_irq_exit`)
+//! or at the next voluntary preemption point, through `schedule_pending`.
 
-use crate::task::scheduler;
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::profiling::sched_trace::{self, SchedEventKind};
+use crate::smp::spinlock::SpinLockGuard;
+use crate::task::{Pick, TaskContext};
+use crate::task::{cgroup, cputime, scheduler};
+use crate::smp::cpu::{current_cpu_id, MAX_CPUS};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 /// Time slice in timer ticks
 /// With 100 Hz timer (10ms per tick), TIME_SLICE=10 means 100ms per task
 pub const TIME_SLICE: u64 = 10;
 
-/// Timer tick counter (atomic for thread-safety)
-static TICK_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// Ticks into the current time slice, per CPU (each takes its own tick)
+static TICK_COUNTER: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
 
-/// Perform a context switch if the time slice has expired
+/// Ticks not yet charged to a task because the scheduler lock was busy,
+/// per CPU: those that interrupted the kernel, then those that interrupted
+/// user mode
+static UNACCOUNTED_TICKS: [[AtomicU64; 2]; MAX_CPUS] = [const { [const { AtomicU64::new(0) }; 2] }; MAX_CPUS];
+
+/// This CPU's time slice counter
+fn tick_counter() -> &'static AtomicU64 {
+    &TICK_COUNTER[current_cpu_id().as_usize()]
+}
+
+/// Charge this tick, and any carried over, to whoever is running, as user
+/// time if it interrupted user mode
 ///
-/// This should be called from the timer interrupt handler.
+/// Runs in the timer interrupt, which may have interrupted a holder of
+/// the scheduler lock on this CPU; if the lock is busy the tick is
+/// carried over to the next one instead of spinning.
+fn account_tick(from_user: bool) {
+    let unaccounted = &UNACCOUNTED_TICKS[current_cpu_id().as_usize()];
+    unaccounted[from_user as usize].fetch_add(1, Ordering::Relaxed);
+
+    let Some(mut scheduler_guard) = scheduler::try_scheduler() else {
+        return;
+    };
+    for (mode, ticks) in unaccounted.iter().enumerate() {
+        for _ in 0..ticks.swap(0, Ordering::Relaxed) {
+            cputime::account_tick(&mut scheduler_guard, mode == 1);
+            cgroup::account_tick(&mut scheduler_guard);
+        }
+    }
+}
+
+// `switch_context` saves and loads the kernel's contexts in place
+const _: () = assert!(
+    core::mem::size_of::<TaskContext>() == core::mem::size_of::<fanga_arch_x86_64::context::TaskContext>()
+);
+
+/// Count a timer tick and ask for a reschedule once the time slice expired
+///
+/// This should be called from the timer interrupt handler, telling
+/// whether the tick interrupted user mode. It does not switch tasks
+/// itself: the interrupt's exit path does, once the handler has sent its
+/// EOI.
 ///
 /// # Returns
-/// true if a context switch was performed, false otherwise
-pub fn schedule_on_timer() -> bool {
-    let tick = TICK_COUNTER.fetch_add(1, Ordering::Relaxed);
+/// true if the time slice expired, false otherwise
+pub fn schedule_on_timer(from_user: bool) -> bool {
+    let tick = tick_counter().fetch_add(1, Ordering::Relaxed);
+    
+    account_tick(from_user);
     
     if tick >= TIME_SLICE {
-        TICK_COUNTER.store(0, Ordering::Relaxed);
-        
-        // Perform scheduling
-        let mut scheduler_guard = scheduler::scheduler();
-        let (_prev, _next, should_switch) = scheduler_guard.schedule();
-            
-            if should_switch {
-                #[cfg(not(test))]
-                fanga_arch_x86_64::serial_println!(
-                    "[SCHED] Context switch: {:?} -> {:?}",
-                    _prev, _next
-                );
-                
-                // In a real implementation, we would perform the actual context switch here
-                // using fanga_arch_x86_64::context::switch_context()
-                // For now, we just track the schedule decision
-                
-                return true;
-            }
+        tick_counter().store(0, Ordering::Relaxed);
+        crate::preempt::points::set_need_resched();
+        return true;
     }
     
     false
 }
 
-/// Get the current timer tick count
+/// Reschedule if this CPU's need-resched flag is set
+///
+/// Called on the way out of interrupts, after an idle halt and from
+/// `cond_resched`, none of which hold a spinlock. When another task is
+/// picked this returns only once the current task is scheduled back in.
+pub fn schedule_pending() -> bool {
+    if !crate::preempt::need_resched() {
+        return false;
+    }
+    // An interrupt between picking the next task and switching to it
+    // would reschedule a CPU whose run queue is already ahead of it
+    #[cfg(not(test))]
+    return fanga_arch_x86_64::interrupts::without_interrupts(reschedule);
+    #[cfg(test)]
+    reschedule()
+}
+
+/// Pick the next task under this CPU's run queue lock, then take the
+/// task table to switch to it
+fn reschedule() -> bool {
+    crate::preempt::points::clear_need_resched();
+    let pick = scheduler::pick_next();
+    preempt_current(scheduler::scheduler(), pick)
+}
+
+/// Switch to a picked task, accounting an involuntary switch
+///
+/// Runs with interrupts disabled. The scheduler lock is released before
+/// the switch; the task table never reallocates (`Scheduler::init`
+/// reserves all `MAX_TASKS` slots), so the context pointers stay valid.
+/// Other CPUs leave the previous task alone until `switch_context` has
+/// saved it and cleared its on-CPU flag.
+fn preempt_current(mut scheduler_guard: SpinLockGuard<'_, scheduler::Scheduler>, pick: Pick) -> bool {
+    let queued = pick.queued;
+    let (prev, next, should_switch) = scheduler_guard.finish_pick(pick);
+    if !should_switch {
+        return false;
+    }
+    
+    // Preemption is an involuntary context switch
+    if let Some(prev) = prev {
+        cputime::account_preemption(&mut scheduler_guard, prev);
+        sched_trace::trace(SchedEventKind::Preempt { task: prev }, queued);
+    }
+    
+    let prev_context = prev
+        .and_then(|id| scheduler_guard.get_task_mut(id))
+        .map(|task| &mut task.context as *mut TaskContext);
+    let next_context = next
+        .and_then(|id| scheduler_guard.get_task_mut(id))
+        .map(|task| &task.context as *const TaskContext);
+    let prev_on_cpu = prev.and_then(scheduler::on_cpu_flag);
+    drop(scheduler_guard);
+    
+    if let Some(next_context) = next_context {
+        // Without a previous task (the first switch on a CPU) there is
+        // nothing to come back to
+        let mut discarded = TaskContext::zero();
+        let discarded_on_cpu = AtomicBool::new(false);
+        let prev_context = prev_context.unwrap_or(&mut discarded);
+        let prev_on_cpu = prev_on_cpu.unwrap_or(&discarded_on_cpu);
+        #[cfg(not(test))]
+        unsafe {
+            fanga_arch_x86_64::context::switch_context(prev_context.cast(), next_context.cast(), prev_on_cpu.as_ptr());
+        }
+        #[cfg(test)]
+        {
+            let _ = (prev_context, next_context);
+            prev_on_cpu.store(false, Ordering::Release);
+        }
+    } else if let Some(prev_on_cpu) = prev_on_cpu {
+        prev_on_cpu.store(false, Ordering::Release);
+    }
+    
+    true
+}
+
+/// Get this CPU's tick count into the current time slice
 pub fn get_ticks() -> u64 {
-    TICK_COUNTER.load(Ordering::Relaxed)
+    tick_counter().load(Ordering::Relaxed)
 }
 
-/// Reset the timer tick counter
+/// Reset this CPU's time slice tick counter
 pub fn reset_ticks() {
-    TICK_COUNTER.store(0, Ordering::Relaxed);
+    tick_counter().store(0, Ordering::Relaxed);
 }
 
 #[cfg(test)]
@@ -68,10 +177,19 @@ mod tests {
         
         // Simulate timer ticks
         for i in 1..=10 {
-            schedule_on_timer();
+            schedule_on_timer(false);
             if i < TIME_SLICE {
                 assert_eq!(get_ticks(), i);
             }
         }
     }
+
+    #[test]
+    fn test_tick_carried_over_while_scheduler_busy() {
+        let unaccounted = &UNACCOUNTED_TICKS[current_cpu_id().as_usize()][1];
+        let _guard = scheduler::scheduler();
+        let before = unaccounted.load(Ordering::Relaxed);
+        account_tick(true);
+        assert!(unaccounted.load(Ordering::Relaxed) > before);
+    }
 }
@@ -3,47 +3,93 @@
 //! This module implements the task scheduler with support for:
 //! - Round-robin scheduling
 //! - Priority-based scheduling
-//! - Task queue management
+//! - Per-CPU run queues, task placement and load balancing
+//! - Task migration between CPUs
+//! - Per-CPU idle tasks, run when no other task is ready
+//!
+//! # Locking
+//!
+//! Scheduling state is split between the task table (`scheduler()`),
+//! which owns every TCB, and the per-CPU run queues (`runqueue`), which
+//! own queue membership and each CPU's current task. Locks are always
+//! taken in this order:
+//!
+//! 1. The task table
+//! 2. Run queues: one at a time, or two through `RunQueues::lock_pair`,
+//!    which takes the lower CPU first
+//! 3. Leaf locks: the CPU manager, IPI and TLB shootdown queues
+//!
+//! A lock is never taken while holding one that comes later in the list.
+//! The task table and run queue locks are lockdep-classed spinlocks, so
+//! with `smp::lockdep` enabled a violation of this order is reported.
+//! Code that only needs a CPU's load or current task (`nr_running`,
+//! `cpu_current`) takes that CPU's run queue lock alone.
+//!
+//! Picking the next task takes run queue locks only (`pick_next`), so
+//! CPUs pick in parallel; the task table is locked afterwards just to
+//! update the TCBs of the tasks involved (`Scheduler::finish_pick`).
 
 extern crate alloc;
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::panic::Location;
+use core::sync::atomic::AtomicBool;
 
-use super::tcb::{Task, TaskId, TaskState, TaskPriority};
-use spin::Mutex;
+use super::runqueue::{Pick, RqTask, RunQueues};
+use super::tcb::{Task, TaskId, TaskState};
+use crate::preempt::stats::{self as preempt_stats, LatencyKind};
+use crate::profiling::sched_trace::{self, SchedEventKind};
+use crate::memory::PhysAddr;
+use crate::smp::cpu::{CpuId, MAX_CPUS};
+use crate::smp::lockdep::LockClass;
+use crate::smp::rcu;
+use crate::smp::resched;
+use crate::smp::spinlock::{SpinLock, SpinLockGuard};
 
 /// Maximum number of tasks the scheduler can manage
 /// With 8KB heap, we can support ~32 tasks (each Task is ~240 bytes)
 pub const MAX_TASKS: usize = 32;
 
+/// Task ID of the init process, which `add_task` never hands out
+pub const INIT_PID: TaskId = TaskId::new(1);
+
 /// Scheduler implementation
 pub struct Scheduler {
     /// All tasks indexed by task ID
     tasks: Vec<Option<Task>>,
     
-    /// Ready queue for each priority level
-    ready_queues: [VecDeque<TaskId>; 4],
-    
-    /// Currently running task ID
-    current_task: Option<TaskId>,
+    /// Per-CPU run queues
+    rqs: &'static RunQueues,
     
     /// Next available task ID
     next_task_id: usize,
+    
+    /// Idle task for each CPU (never placed on a ready queue)
+    idle_tasks: BTreeMap<usize, TaskId>,
+}
+
+/// Run queues of the global scheduler
+static RUNQUEUES: RunQueues = RunQueues::new();
+
+/// ID of the CPU running this code
+fn this_cpu() -> usize {
+    crate::smp::current_cpu_id().as_usize()
 }
 
 impl Scheduler {
-    /// Create a new scheduler
-    pub const fn new() -> Self {
+    /// Create a new scheduler with its own run queues
+    pub fn new() -> Self {
+        Self::with_runqueues(Box::leak(Box::new(RunQueues::new())))
+    }
+    
+    /// Create a new scheduler on existing run queues
+    pub const fn with_runqueues(rqs: &'static RunQueues) -> Self {
         Self {
             tasks: Vec::new(),
-            ready_queues: [
-                VecDeque::new(),
-                VecDeque::new(),
-                VecDeque::new(),
-                VecDeque::new(),
-            ],
-            current_task: None,
-            next_task_id: 1,
+            rqs,
+            next_task_id: INIT_PID.as_usize() + 1,
+            idle_tasks: BTreeMap::new(),
         }
     }
     
@@ -57,40 +103,99 @@ impl Scheduler {
                 self.tasks.push(None);
             }
         }
-        
-        // Initialize ready queues with capacity to avoid 0-byte allocation panics
-        // Clear existing queues and reserve capacity for each
-        for queue in &mut self.ready_queues {
-            queue.clear();
-            queue.reserve(16);
-        }
     }
     
     /// Add a new task to the scheduler
-    pub fn add_task(&mut self, mut task: Task) -> Result<TaskId, &'static str> {
+    pub fn add_task(&mut self, task: Task) -> Result<TaskId, &'static str> {
         if self.next_task_id >= MAX_TASKS {
             return Err("Maximum number of tasks reached");
         }
         
         let task_id = TaskId::new(self.next_task_id);
+        self.next_task_id += 1;
+        self.insert_task(task_id, task);
+        Ok(task_id)
+    }
+
+    /// Add the init process as `INIT_PID`
+    ///
+    /// Init has the same ID however many kernel tasks started before it.
+    pub fn add_init_task(&mut self, task: Task) -> Result<TaskId, &'static str> {
+        if self.tasks.get(INIT_PID.as_usize()).is_none_or(Option::is_some) {
+            return Err("Init task already exists");
+        }
+        self.insert_task(INIT_PID, task);
+        Ok(INIT_PID)
+    }
+
+    /// Make `task` ready as `task_id`, on the CPU it is placed on
+    fn insert_task(&mut self, task_id: TaskId, mut task: Task) {
         task.id = task_id;
         task.state = TaskState::Ready;
+        let entry = RqTask::of(&task);
+        task.cpu = self.rqs.select_cpu(&entry);
+
+        // Add to the ready queue of the chosen CPU
+        let cpu = task.cpu;
+        self.tasks[task_id.as_usize()] = Some(task);
+        self.rqs.enqueue(cpu, entry);
+        self.rqs.check_preempt(cpu, entry.priority);
+    }
+    
+    /// Register the idle task for a CPU
+    ///
+    /// The idle task is kept out of the ready queues and only selected by
+    /// `schedule()` when nothing else on this CPU is runnable.
+    pub fn add_idle_task(&mut self, cpu: usize, task: Task) -> Result<TaskId, &'static str> {
+        if cpu >= MAX_CPUS {
+            return Err("Invalid CPU");
+        }
+        if self.idle_tasks.contains_key(&cpu) {
+            return Err("CPU already has an idle task");
+        }
+        if self.next_task_id >= MAX_TASKS {
+            return Err("Maximum number of tasks reached");
+        }
         
+        let task_id = TaskId::new(self.next_task_id);
         self.next_task_id += 1;
         
-        // Add to tasks list
+        let mut task = task;
+        task.id = task_id;
+        task.state = TaskState::Ready;
+        task.cpu = cpu;
         self.tasks[task_id.as_usize()] = Some(task);
-        
-        // Add to appropriate ready queue
-        let priority_index = self.tasks[task_id.as_usize()]
-            .as_ref()
-            .unwrap()
-            .priority as usize;
-        self.ready_queues[priority_index].push_back(task_id);
+        self.idle_tasks.insert(cpu, task_id);
+        self.rqs.set_idle(cpu, task_id);
         
         Ok(task_id)
     }
     
+    /// Get the idle task of a CPU
+    pub fn idle_task(&self, cpu: usize) -> Option<TaskId> {
+        self.idle_tasks.get(&cpu).copied()
+    }
+    
+    /// Check whether a task is an idle task
+    pub fn is_idle_task(&self, task_id: TaskId) -> bool {
+        self.idle_tasks.values().any(|&id| id == task_id)
+    }
+    
+    /// Make the idle task of a CPU the current task
+    ///
+    /// From then on the CPU takes tasks from the others.
+    pub fn enter_idle(&mut self, cpu: usize) -> Result<TaskId, &'static str> {
+        let idle = self.idle_task(cpu).ok_or("CPU has no idle task")?;
+        if let Some(task) = self.get_task_mut(idle) {
+            task.state = TaskState::Running;
+        }
+        self.rqs.activate(cpu);
+        if let Some(data) = crate::smp::percpu::get_cpu_data(CpuId::new(cpu)) {
+            data.current_task = Some(idle.as_usize());
+        }
+        Ok(idle)
+    }
+    
     /// Get a reference to a task
     pub fn get_task(&self, task_id: TaskId) -> Option<&Task> {
         self.tasks.get(task_id.as_usize())?.as_ref()
@@ -101,90 +206,182 @@ impl Scheduler {
         self.tasks.get_mut(task_id.as_usize())?.as_mut()
     }
     
-    /// Get the currently running task ID
+    /// Get the task running on this CPU
     pub fn current_task(&self) -> Option<TaskId> {
-        self.current_task
+        self.cpu_current(this_cpu())
+    }
+    
+    /// Get the task running on a CPU
+    pub fn cpu_current(&self, cpu: usize) -> Option<TaskId> {
+        self.rqs.lock(cpu).current()
     }
     
     /// Get a reference to the currently running task
     pub fn current_task_ref(&self) -> Option<&Task> {
-        self.current_task.and_then(|id| self.get_task(id))
+        self.current_task().and_then(|id| self.get_task(id))
     }
     
     /// Get a mutable reference to the currently running task
     pub fn current_task_mut(&mut self) -> Option<&mut Task> {
-        self.current_task.and_then(|id| self.get_task_mut(id))
+        self.current_task().and_then(|id| self.get_task_mut(id))
     }
     
     /// Select the next task to run using priority-based round-robin
     /// Returns (previous_task_id, next_task_id, should_switch)
+    ///
+    /// Nothing switches contexts here, so the previous task counts as
+    /// saved at once.
     pub fn schedule(&mut self) -> (Option<TaskId>, Option<TaskId>, bool) {
-        let prev_task = self.current_task;
-        
-        // If there's a currently running task, move it back to ready queue
-        if let Some(task_id) = self.current_task {
-            if let Some(task) = self.get_task_mut(task_id) {
-                if task.state == TaskState::Running {
-                    task.state = TaskState::Ready;
-                    let priority_index = task.priority as usize;
-                    self.ready_queues[priority_index].push_back(task_id);
-                }
-            }
+        rcu::rcu_note_context_switch();
+        let pick = self.rqs.pick_next(this_cpu());
+        let (prev, next, should_switch) = self.finish_pick(pick);
+        if let Some(prev) = prev.filter(|_| should_switch) {
+            self.rqs.finish_switch(prev);
         }
-        
-        // Find the next task to run (highest priority first)
-        let mut next_task = None;
-        for priority_queue in self.ready_queues.iter_mut().rev() {
-            while let Some(task_id) = priority_queue.pop_front() {
-                // Check if task is still ready
-                let is_ready = self.tasks.get(task_id.as_usize())
-                    .and_then(|t| t.as_ref())
-                    .map(|t| t.state == TaskState::Ready)
-                    .unwrap_or(false);
-                    
-                if is_ready {
-                    next_task = Some(task_id);
-                    break;
-                }
-            }
-            if next_task.is_some() {
-                break;
+        (prev, next, should_switch)
+    }
+    
+    /// Update the task table for a pick of this CPU's next task
+    ///
+    /// The previous task becomes ready unless it blocked or exited, the
+    /// next one runs, and on a switch the CPU's FS base, kernel stack and
+    /// address space follow it.
+    /// Returns (previous_task_id, next_task_id, should_switch)
+    pub fn finish_pick(&mut self, pick: Pick) -> (Option<TaskId>, Option<TaskId>, bool) {
+        let cpu = this_cpu();
+        let Pick { prev: prev_task, next: next_task, moved, queued } = pick;
+        self.apply_moves(&moved);
+        
+        if let Some(task) = prev_task.and_then(|id| self.get_task_mut(id)) {
+            if task.state == TaskState::Running {
+                task.state = TaskState::Ready;
             }
         }
-        
-        // Update current task and state
-        if let Some(task_id) = next_task {
-            if let Some(task) = self.get_task_mut(task_id) {
-                task.state = TaskState::Running;
+        if let Some(task) = next_task.and_then(|id| self.get_task_mut(id)) {
+            task.state = TaskState::Running;
+            task.cpu = cpu;
+            if let Some((start, waker)) = task.woken.take() {
+                preempt_stats::record_site_latency(LatencyKind::Wakeup, waker, start);
             }
-            self.current_task = Some(task_id);
-        } else {
-            self.current_task = None;
         }
+        crate::percpu!(current_task = next_task.map(|id| id.as_usize()));
         
         let should_switch = prev_task != next_task;
+        if should_switch {
+            super::tls::switch_fs_base(self, prev_task, next_task);
+            self.load_kernel_stack(cpu, next_task);
+            super::speculation::switch_mm(self, next_task);
+            let page_table = next_task.and_then(|id| self.get_task(id)).map(|task| task.page_table.as_u64());
+            crate::memory::address_space::activate(PhysAddr::new(page_table.unwrap_or(0)));
+            sched_trace::trace(SchedEventKind::Switch { prev: prev_task, next: next_task }, queued);
+            crate::tracepoint!(
+                SCHED_SWITCH,
+                prev_task.map_or(0, |id| id.as_usize()),
+                next_task.map_or(0, |id| id.as_usize())
+            );
+        }
         (prev_task, next_task, should_switch)
     }
     
+    /// Record in the TCBs where the run queues moved tasks to, and the
+    /// affinity they lost doing so
+    fn apply_moves(&mut self, moved: &[(RqTask, usize)]) {
+        for &(entry, cpu) in moved {
+            if let Some(task) = self.get_task_mut(entry.id) {
+                task.cpu = cpu;
+                task.cpus_allowed = entry.cpus_allowed;
+            }
+        }
+    }
+    
+    /// Point TSS.RSP0 of `cpu` at the top of a task's kernel stack, so
+    /// interrupts and system calls from its user mode run on that stack
+    ///
+    /// Tasks without a stack of their own keep the CPU's current one.
+    fn load_kernel_stack(&self, cpu: usize, task: Option<TaskId>) {
+        if let Some(task) = task.and_then(|id| self.get_task(id)).filter(|task| task.kernel_stack.as_u64() != 0) {
+            let top = task.kernel_stack.as_u64() + task.kernel_stack_size as u64;
+            fanga_arch_x86_64::gdt::set_kernel_stack(cpu, top);
+        }
+    }
+    
+    /// Move a queued task to another CPU's run queue
+    ///
+    /// This is the one path that touches two run queues. A task running
+    /// on its CPU cannot be moved; make that CPU reschedule instead.
+    pub fn migrate_task(&mut self, task_id: TaskId, dest: usize) -> Result<(), &'static str> {
+        if dest >= MAX_CPUS {
+            return Err("Invalid CPU");
+        }
+        if self.is_idle_task(task_id) {
+            return Err("Idle task cannot migrate");
+        }
+        let task = self.get_task(task_id).ok_or("Task not found")?;
+        if !task.can_run_on(dest) {
+            return Err("Task not allowed on CPU");
+        }
+        let (priority, ready) = (task.priority, task.state == TaskState::Ready);
+        
+        self.rqs.migrate(task_id, dest)?;
+        if let Some(task) = self.get_task_mut(task_id) {
+            task.cpu = dest;
+        }
+        if ready {
+            self.rqs.check_preempt(dest, priority);
+        }
+        Ok(())
+    }
+    
+    /// Stop placing tasks on a CPU that is going offline
+    ///
+    /// Its queued tasks move to the remaining CPUs; the task it runs moves
+    /// once the CPU reschedules. Returns the number of tasks moved.
+    pub fn deactivate_cpu(&mut self, cpu: usize) -> Result<usize, &'static str> {
+        let moved = self.rqs.deactivate(cpu)?;
+        self.apply_moves(&moved);
+        Ok(moved.iter().filter(|&&(_, dest)| dest != cpu).count())
+    }
+    
+    /// Move every task queued on a CPU to the online CPUs
+    ///
+    /// A task allowed on no online CPU loses its affinity rather than
+    /// being stranded. Returns the number of tasks moved.
+    pub fn drain_cpu(&mut self, cpu: usize) -> usize {
+        let moved = self.rqs.drain(cpu);
+        self.apply_moves(&moved);
+        moved.iter().filter(|&&(_, dest)| dest != cpu).count()
+    }
+    
     /// Terminate a task
     pub fn terminate_task(&mut self, task_id: TaskId) -> Result<(), &'static str> {
-        if let Some(task) = self.get_task_mut(task_id) {
-            task.state = TaskState::Terminated;
-            
-            if self.current_task == Some(task_id) {
-                self.current_task = None;
-            }
-            
-            Ok(())
-        } else {
-            Err("Task not found")
+        let task = self.get_task_mut(task_id).ok_or("Task not found")?;
+        task.state = TaskState::Terminated;
+        
+        let (_, mut rq) = self.rqs.lock_task(task_id);
+        rq.remove(task_id);
+        if rq.current() == Some(task_id) {
+            rq.set_current(None);
         }
+        Ok(())
     }
     
     /// Block a task (remove from ready queue)
     pub fn block_task(&mut self, task_id: TaskId) -> Result<(), &'static str> {
+        if self.is_idle_task(task_id) {
+            return Err("Idle task cannot block");
+        }
         if let Some(task) = self.get_task_mut(task_id) {
+            if task.state != TaskState::Blocked {
+                // Blocking is a voluntary context switch
+                task.times.nvcsw += 1;
+            }
             task.state = TaskState::Blocked;
+            {
+                let (_, mut rq) = self.rqs.lock_task(task_id);
+                rq.remove(task_id);
+                rq.park_current(task_id);
+            }
+            sched_trace::trace(SchedEventKind::Block { task: task_id }, self.ready_task_count());
             Ok(())
         } else {
             Err("Task not found")
@@ -192,20 +389,87 @@ impl Scheduler {
     }
     
     /// Unblock a task (add back to ready queue)
+    ///
+    /// The task joins the run queue chosen by `RunQueues::select_cpu`,
+    /// unless it has not been switched away from yet, and may preempt that
+    /// CPU. The caller is the call site the wakeup latency is recorded for.
+    #[track_caller]
     pub fn unblock_task(&mut self, task_id: TaskId) -> Result<(), &'static str> {
+        if self.is_idle_task(task_id) {
+            return Err("Idle task cannot be woken");
+        }
+        let task = self.get_task_mut(task_id).ok_or("Task not found")?;
+        task.state = TaskState::Ready;
+        task.woken = preempt_stats::latency_start().map(|start| (start, Location::caller()));
+        let entry = RqTask::of(task);
+        let cpu = self.rqs.wake(entry);
         if let Some(task) = self.get_task_mut(task_id) {
-            task.state = TaskState::Ready;
-            let priority_index = task.priority as usize;
-            self.ready_queues[priority_index].push_back(task_id);
-            Ok(())
+            task.cpu = cpu;
+        }
+        sched_trace::trace(SchedEventKind::Wakeup { task: task_id }, self.ready_task_count());
+        crate::tracepoint!(SCHED_WAKEUP, task_id.as_usize(), cpu);
+        self.rqs.check_preempt(cpu, entry.priority);
+        Ok(())
+    }
+    
+    /// Restrict the CPUs a task may run on
+    ///
+    /// A queued task moves to an allowed CPU right away. A running one is
+    /// pushed off at that CPU's next reschedule, which is forced with an
+    /// IPI.
+    pub fn set_cpus_allowed(&mut self, task_id: TaskId, mask: u64) -> Result<(), &'static str> {
+        if mask == 0 {
+            return Err("Empty CPU mask");
+        }
+        let task = self.get_task_mut(task_id).ok_or("Task not found")?;
+        task.cpus_allowed = mask;
+        let (cpu, running) = {
+            let (cpu, mut rq) = self.rqs.lock_task(task_id);
+            rq.update(task_id, |t| t.cpus_allowed = mask);
+            (cpu, rq.current() == Some(task_id))
+        };
+        if super::tcb::cpu_in_mask(mask, cpu) {
+            return Ok(());
+        }
+        
+        if running {
+            resched::resched_cpu(CpuId::new(cpu))
         } else {
-            Err("Task not found")
+            let moved = self.rqs.migrate_disallowed(cpu);
+            self.apply_moves(&moved);
+            Ok(())
         }
     }
     
+    /// Set whether a task's cgroup has exhausted its CPU quota
+    ///
+    /// A throttled task stays queued but is passed over when picking.
+    pub fn set_throttled(&mut self, task_id: TaskId, throttled: bool) -> Result<(), &'static str> {
+        let task = self.get_task_mut(task_id).ok_or("Task not found")?;
+        task.throttled = throttled;
+        let (_, mut rq) = self.rqs.lock_task(task_id);
+        rq.update(task_id, |t| t.throttled = throttled);
+        Ok(())
+    }
+    
     /// Get the number of ready tasks
     pub fn ready_task_count(&self) -> usize {
-        self.ready_queues.iter().map(|q| q.len()).sum()
+        self.rqs.nr_ready(this_cpu())
+    }
+    
+    /// Get the number of ready tasks queued on a CPU
+    pub fn nr_running(&self, cpu: usize) -> usize {
+        self.rqs.lock(cpu).len()
+    }
+    
+    /// Iterate over the CPUs taking tasks
+    pub fn online_cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        self.rqs.online_cpus()
+    }
+    
+    /// Iterate over all tasks
+    pub fn tasks(&self) -> impl Iterator<Item = &Task> {
+        self.tasks.iter().filter_map(|t| t.as_ref())
     }
     
     /// Get the total number of tasks (excluding terminated)
@@ -214,8 +478,42 @@ impl Scheduler {
     }
 }
 
-/// Global scheduler instance
-static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+/// Lockdep class of the task table lock
+static SCHEDULER_CLASS: LockClass = LockClass::new("scheduler");
+
+/// Global scheduler instance (the task table)
+static SCHEDULER: SpinLock<Scheduler> =
+    SpinLock::with_class(Scheduler::with_runqueues(&RUNQUEUES), &SCHEDULER_CLASS);
+
+/// Number of ready tasks queued on a CPU
+///
+/// Takes only that CPU's run queue lock.
+pub fn nr_running(cpu: usize) -> usize {
+    RUNQUEUES.lock(cpu).len()
+}
+
+/// Task running on a CPU
+///
+/// Takes only that CPU's run queue lock.
+pub fn cpu_current(cpu: usize) -> Option<TaskId> {
+    RUNQUEUES.lock(cpu).current()
+}
+
+/// On-CPU flag of a task, which `switch_context` clears once it saved
+/// the task's context
+pub fn on_cpu_flag(task: TaskId) -> Option<&'static AtomicBool> {
+    RUNQUEUES.on_cpu_flag(task)
+}
+
+/// Put this CPU's current task back and pick its next one
+///
+/// Takes only run queue locks. Hand the result to
+/// `Scheduler::finish_pick` to update the task table, then switch
+/// contexts to let other CPUs run the previous task.
+pub fn pick_next() -> Pick {
+    rcu::rcu_note_context_switch();
+    RUNQUEUES.pick_next(this_cpu())
+}
 
 /// Initialize the global scheduler
 pub fn init() {
@@ -224,20 +522,30 @@ pub fn init() {
 }
 
 /// Get a reference to the global scheduler
-pub fn scheduler() -> spin::MutexGuard<'static, Scheduler> {
+#[track_caller]
+pub fn scheduler() -> SpinLockGuard<'static, Scheduler> {
     SCHEDULER.lock()
 }
 
+/// Try to get the global scheduler without spinning
+///
+/// For hot paths (syscall hooks) that must not deadlock on the lock.
+#[track_caller]
+pub fn try_scheduler() -> Option<SpinLockGuard<'static, Scheduler>> {
+    SCHEDULER.try_lock()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::memory::{PhysAddr, VirtAddr};
+    use crate::task::TaskPriority;
 
     #[test]
     fn test_scheduler_new() {
         let scheduler = Scheduler::new();
         assert_eq!(scheduler.current_task(), None);
-        assert_eq!(scheduler.next_task_id, 1);
+        assert_eq!(scheduler.next_task_id, 2);
     }
 
     #[test]
@@ -257,8 +565,21 @@ mod tests {
         let result = scheduler.add_task(task);
         assert!(result.is_ok());
         
+        // ID 1 is kept for init
         let task_id = result.unwrap();
-        assert_eq!(task_id.as_usize(), 1);
+        assert_eq!(task_id.as_usize(), 2);
+
+        let init = || Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0x3000),
+            TaskPriority::Normal,
+        );
+        assert_eq!(scheduler.add_init_task(init()), Ok(INIT_PID));
+        assert!(scheduler.get_task(INIT_PID).is_some());
+        assert!(scheduler.add_init_task(init()).is_err());
     }
 
     #[test]
@@ -399,4 +720,150 @@ mod tests {
         let task = scheduler.get_task(task_id).unwrap();
         assert_eq!(task.state, TaskState::Ready);
     }
+
+    #[test]
+    fn test_scheduler_idle_fallback() {
+        let mut scheduler = Scheduler::new();
+        scheduler.init();
+        
+        let new_task = |priority| Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0x3000),
+            priority,
+        );
+        
+        let idle = scheduler.add_idle_task(0, new_task(TaskPriority::Low)).unwrap();
+        assert_eq!(scheduler.ready_task_count(), 0);
+        assert!(scheduler.add_idle_task(0, new_task(TaskPriority::Low)).is_err());
+        
+        // Nothing runnable: the idle task is picked
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(idle));
+        
+        // A real task preempts idle, and idle is not requeued behind it
+        let task_id = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(task_id));
+        assert_eq!(scheduler.ready_task_count(), 0);
+        
+        scheduler.block_task(task_id).unwrap();
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(idle));
+        assert!(scheduler.block_task(idle).is_err());
+    }
+    
+    #[test]
+    fn test_scheduler_affinity() {
+        let mut scheduler = Scheduler::new();
+        scheduler.init();
+        
+        let new_task = |priority| Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0x3000),
+            priority,
+        );
+        
+        // Not allowed on this CPU: passed over, but stays queued
+        let pinned = scheduler.add_task(new_task(TaskPriority::High)).unwrap();
+        scheduler.set_cpus_allowed(pinned, 1 << 1).unwrap();
+        let other = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(other));
+        assert_eq!(scheduler.ready_task_count(), 1);
+        assert!(scheduler.set_cpus_allowed(pinned, 0).is_err());
+        
+        scheduler.set_cpus_allowed(pinned, u64::MAX).unwrap();
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(pinned));
+    }
+    
+    #[test]
+    fn test_scheduler_per_cpu_queues() {
+        let mut scheduler = Scheduler::new();
+        scheduler.init();
+        
+        let new_task = |priority| Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0x3000),
+            priority,
+        );
+        for cpu in 0..2 {
+            scheduler.add_idle_task(cpu, new_task(TaskPriority::Low)).unwrap();
+            scheduler.enter_idle(cpu).unwrap();
+        }
+        
+        // Both CPUs are idle, so new tasks spread over them
+        let a = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        let b = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        assert_eq!(scheduler.get_task(a).unwrap().cpu, 0);
+        assert_eq!(scheduler.get_task(b).unwrap().cpu, 1);
+        
+        scheduler.migrate_task(b, 0).unwrap();
+        assert_eq!((scheduler.nr_running(0), scheduler.nr_running(1)), (2, 0));
+        
+        // Pinning a queued task moves it to an allowed CPU
+        scheduler.set_cpus_allowed(b, 1 << 1).unwrap();
+        assert_eq!(scheduler.get_task(b).unwrap().cpu, 1);
+        assert!(scheduler.migrate_task(b, 0).is_err());
+        
+        // This CPU runs a, so c goes to the idle CPU
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(a));
+        let c = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        assert_eq!(scheduler.get_task(c).unwrap().cpu, 1);
+        
+        // Out of work, this CPU pulls c but leaves the pinned b
+        scheduler.block_task(a).unwrap();
+        let (_, next, _) = scheduler.schedule();
+        assert_eq!(next, Some(c));
+        assert_eq!(scheduler.get_task(c).unwrap().cpu, 0);
+        assert_eq!(scheduler.cpu_current(1), scheduler.idle_task(1));
+        assert_eq!(scheduler.nr_running(1), 1);
+    }
+    
+    #[test]
+    fn test_scheduler_deactivate_cpu() {
+        let mut scheduler = Scheduler::new();
+        scheduler.init();
+        
+        let new_task = |priority| Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0x3000),
+            priority,
+        );
+        for cpu in 0..2 {
+            scheduler.add_idle_task(cpu, new_task(TaskPriority::Low)).unwrap();
+            scheduler.enter_idle(cpu).unwrap();
+        }
+        let a = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        let b = scheduler.add_task(new_task(TaskPriority::Normal)).unwrap();
+        scheduler.set_cpus_allowed(b, 1 << 1).unwrap();
+        assert_eq!(scheduler.get_task(b).unwrap().cpu, 1);
+        
+        // CPU 1's tasks move to CPU 0, the pinned one losing its affinity
+        assert_eq!(scheduler.deactivate_cpu(1), Ok(1));
+        assert_eq!((scheduler.nr_running(0), scheduler.nr_running(1)), (2, 0));
+        assert_eq!(scheduler.get_task(b).unwrap().cpus_allowed, u64::MAX);
+        assert!(scheduler.deactivate_cpu(0).is_err());
+        
+        // New tasks avoid the offline CPU until it comes back
+        let c = scheduler.add_task(new_task(TaskPriority::High)).unwrap();
+        assert_eq!(scheduler.get_task(c).unwrap().cpu, 0);
+        scheduler.enter_idle(1).unwrap();
+        assert_eq!(scheduler.online_cpus().count(), 2);
+        assert!(scheduler.get_task(a).is_some());
+    }
 }
@@ -6,6 +6,7 @@
 //! - Pending signal tracking
 //! - Real-time signals with queueing
 //! - Signal delivery to process groups
+//! - Global per-process signal state (`signal_manager()`)
 
 extern crate alloc;
 use alloc::collections::VecDeque;
@@ -14,6 +15,8 @@ use alloc::vec::Vec;
 use super::tcb::TaskId;
 use super::ipc::Signal;
 use super::pgroup::ProcessGroupId;
+use super::scheduler::MAX_TASKS;
+use spin::{Mutex, Once};
 
 /// Signal action disposition
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -110,6 +113,9 @@ pub struct SigAction {
     
     /// Signal flags
     pub flags: SignalFlags,
+    
+    /// User code the handler returns to (calls rt_sigreturn)
+    pub restorer: u64,
 }
 
 impl Default for SigAction {
@@ -118,6 +124,7 @@ impl Default for SigAction {
             action: SignalAction::Default,
             mask: 0,
             flags: SignalFlags::default(),
+            restorer: 0,
         }
     }
 }
@@ -134,6 +141,7 @@ impl SigAction {
             action: SignalAction::Handler(handler),
             mask: 0,
             flags: SignalFlags::default(),
+            restorer: 0,
         }
     }
     
@@ -143,6 +151,7 @@ impl SigAction {
             action: SignalAction::Ignore,
             mask: 0,
             flags: SignalFlags::default(),
+            restorer: 0,
         }
     }
 }
@@ -394,10 +403,10 @@ impl SignalManager {
                     handler.send(signal);
                 }
             }
-            SignalTarget::ProcessGroup(_pgid) => {
-                // Would need to iterate all processes in the group
-                // This requires integration with ProcessGroupManager
-                // For now, we just implement the structure
+            SignalTarget::ProcessGroup(pgid) => {
+                for pid in super::pgroup::process_groups().get_group_members(pgid) {
+                    self.get_or_create_handler(pid).send(signal);
+                }
             }
             SignalTarget::All => {
                 // Send to all processes (except sender)
@@ -413,6 +422,24 @@ impl SignalManager {
     }
 }
 
+/// Global signal manager
+static SIGNAL_MANAGER: Once<Mutex<SignalManager>> = Once::new();
+
+/// Initialize the global signal manager
+pub fn init() {
+    SIGNAL_MANAGER.call_once(|| Mutex::new(SignalManager::new(MAX_TASKS)));
+}
+
+/// Get the global signal manager
+pub fn signal_manager() -> &'static Mutex<SignalManager> {
+    SIGNAL_MANAGER.get().expect("Signal manager not initialized")
+}
+
+/// Get the global signal manager if it has been initialized
+pub fn try_signal_manager() -> Option<&'static Mutex<SignalManager>> {
+    SIGNAL_MANAGER.get()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -522,6 +549,25 @@ mod tests {
         assert_eq!(handler.get_mask(), original_mask);
     }
 
+    #[test]
+    fn test_send_to_process_group() {
+        let leader = TaskId::new(900);
+        let member = TaskId::new(901);
+        let pgid = {
+            let mut groups = super::super::pgroup::process_groups();
+            groups.create_session(leader).unwrap();
+            let pgid = groups.get_process_group(leader).unwrap();
+            groups.add_to_process_group(member, pgid).unwrap();
+            pgid
+        };
+
+        let mut manager = SignalManager::new(4);
+        manager.send_signal(SignalTarget::ProcessGroup(pgid), Signal::SIGINT, TaskId::new(0));
+        assert!(manager.get_handler(leader).unwrap().is_pending(Signal::SIGINT));
+        assert!(manager.get_handler(member).unwrap().is_pending(Signal::SIGINT));
+        assert!(manager.get_handler(TaskId::new(2)).is_none());
+    }
+
     #[test]
     fn test_signal_info() {
         let info = SignalInfo::new(Signal::SIGUSR1, TaskId::new(42));
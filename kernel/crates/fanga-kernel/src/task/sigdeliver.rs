@@ -0,0 +1,658 @@
+//! Signal Delivery
+//!
+//! Pending signals are acted on whenever a task returns to user mode,
+//! after a system call or an interrupt taken in ring 3.
+//!
+//! This module provides:
+//! - Handler invocation through a signal frame on the user stack
+//! - `rt_sigreturn`, restoring the interrupted context from that frame
+//! - Default dispositions: terminate, core dump, stop and continue
+//! - `rt_sigaction` and `rt_sigprocmask` on a task's signal state
+//!
+//! Handlers need the argument registers, which only the system call path
+//! can set. A handler signal arriving on interrupt return stays pending
+//! until the task's next system call; default dispositions apply at once.
+
+extern crate alloc;
+use alloc::string::String;
+use core::mem::size_of;
+
+use fanga_arch_x86_64::uaccess::{UserPtr, UserSlice};
+use fanga_arch_x86_64::user_return::UserRegs;
+
+use super::coredump::{self, CoreDump, CoreDumpReason, RegisterDump};
+use super::ipc::Signal;
+use super::sigadv::{self, AdvancedSignalHandler, SigAction, SignalAction, SignalFlags, SignalInfo};
+use super::tcb::TaskId;
+use super::{process, scheduler};
+use crate::syscall::{SYS_RT_SIGRETURN, EFAULT, EINVAL};
+
+/// Bytes below the user stack pointer the ABI lets leaf code use
+pub const RED_ZONE: u64 = 128;
+
+/// `rt_sigaction` handler value: default action
+pub const SIG_DFL: u64 = 0;
+/// `rt_sigaction` handler value: ignore
+pub const SIG_IGN: u64 = 1;
+
+/// `sa_flags` bits (Linux x86_64 values)
+pub const SA_SIGINFO: u64 = 0x0000_0004;
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_RESTART: u64 = 0x1000_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `rt_sigprocmask` operations
+pub const SIG_BLOCK: i32 = 0;
+pub const SIG_UNBLOCK: i32 = 1;
+pub const SIG_SETMASK: i32 = 2;
+
+/// Signals that can never be blocked or caught
+const UNBLOCKABLE: u64 = (1 << Signal::SIGKILL as u64) | (1 << Signal::SIGSTOP as u64);
+
+/// RFLAGS bits user code may change through `rt_sigreturn`
+/// (CF, PF, AF, ZF, SF, TF, DF, OF, RF, AC)
+const USER_RFLAGS_MASK: u64 = 0x0000_0000_0005_4DD5;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// Frame pushed on the user stack before entering a handler
+///
+/// `restorer` sits at the stack pointer, so it is the handler's return
+/// address. The handler receives the signal number in rdi and a pointer
+/// to `signo` (a minimal siginfo) in rsi.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SignalFrame {
+    pub restorer: u64,
+    pub signo: u64,
+    pub code: u64,
+    pub sender: u64,
+    pub value: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    /// Signal mask to restore on return
+    pub blocked: u64,
+}
+
+/// Kernel `struct sigaction` as passed to `rt_sigaction`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KSigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// Access to the current task's user memory
+pub trait UserMemory {
+    /// Copy bytes to user memory
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str>;
+    /// Copy bytes from user memory
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+}
+
+/// User memory of the active address space
+pub struct ActiveUserMemory;
+
+impl UserMemory for ActiveUserMemory {
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str> {
+        UserSlice::new(addr, data.len()).write(data)
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        UserSlice::new(addr, buf.len()).read(buf)
+    }
+}
+
+/// Result of looking at a task's pending signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Nothing to deliver
+    None,
+    /// A handler frame was set up; the task resumes in the handler
+    Handler(Signal),
+    /// A handler signal must wait for a system call return
+    Deferred(Signal),
+    /// Terminate the task
+    Terminate(Signal),
+    /// Dump core, then terminate the task
+    Core(Signal),
+    /// Stop the task until SIGCONT
+    Stop(Signal),
+}
+
+fn frame_bytes(frame: &SignalFrame) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(frame as *const _ as *const u8, size_of::<SignalFrame>()) }
+}
+
+/// Where a signal frame goes for a given user stack pointer
+///
+/// Skips the red zone and leaves the stack as after a `call`: the frame
+/// address is 8 modulo 16.
+pub fn frame_address(user_rsp: u64) -> Option<u64> {
+    let top = user_rsp.checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)?;
+    (top & !0xF).checked_sub(8)
+}
+
+/// Build a handler frame and point the user context at the handler
+fn setup_frame(
+    sig: &mut AdvancedSignalHandler,
+    info: &SignalInfo,
+    action: &SigAction,
+    handler: u64,
+    regs: &mut UserRegs,
+    mem: &mut impl UserMemory,
+) -> Result<(), &'static str> {
+    let addr = frame_address(regs.rsp).ok_or("No room for signal frame")?;
+    let frame = SignalFrame {
+        restorer: action.restorer,
+        signo: info.signal.num() as u64,
+        code: info.code as u64,
+        sender: info.sender_pid.as_usize() as u64,
+        value: info.value as u64,
+        rip: regs.rip,
+        rsp: regs.rsp,
+        rflags: regs.rflags,
+        rax: regs.rax,
+        rdi: regs.rdi,
+        rsi: regs.rsi,
+        rdx: regs.rdx,
+        blocked: sig.get_mask(),
+    };
+    mem.write(addr, frame_bytes(&frame))?;
+
+    regs.rip = handler;
+    regs.rsp = addr;
+    regs.rdi = frame.signo;
+    regs.rsi = addr + core::mem::offset_of!(SignalFrame, signo) as u64;
+    regs.rdx = 0;
+    regs.rflags &= !(RFLAGS_TF | RFLAGS_DF);
+
+    // Block the action's mask, and the signal itself unless SA_NODEFER
+    let mut blocked = sig.get_mask() | action.mask;
+    if !action.flags.sa_nodefer {
+        blocked |= 1 << info.signal.num();
+    }
+    sig.set_mask(blocked & !UNBLOCKABLE);
+
+    if action.flags.sa_resethand {
+        let _ = sig.set_action(info.signal, SigAction::default());
+    }
+    Ok(())
+}
+
+/// Act on the next deliverable signal
+pub fn deliver_signal(
+    sig: &mut AdvancedSignalHandler,
+    regs: &mut UserRegs,
+    mem: &mut impl UserMemory,
+) -> Delivery {
+    while let Some(info) = sig.next_unblocked() {
+        let signal = info.signal;
+        match signal {
+            Signal::SIGKILL => return Delivery::Terminate(signal),
+            Signal::SIGSTOP => return Delivery::Stop(signal),
+            _ => {}
+        }
+
+        let action = match sig.get_action(signal) {
+            Ok(action) => action.clone(),
+            Err(_) => continue,
+        };
+        let disposition = match action.action {
+            SignalAction::Default => AdvancedSignalHandler::default_action(signal),
+            other => other,
+        };
+
+        match disposition {
+            SignalAction::Ignore => continue,
+            SignalAction::Core => return Delivery::Core(signal),
+            SignalAction::Default => {
+                return match signal {
+                    Signal::SIGTSTP => Delivery::Stop(signal),
+                    _ => Delivery::Terminate(signal),
+                };
+            }
+            SignalAction::Handler(handler) => {
+                if !regs.can_set_args() {
+                    sig.send_rt(info);
+                    return Delivery::Deferred(signal);
+                }
+                return match setup_frame(sig, &info, &action, handler, regs, mem) {
+                    Ok(()) => Delivery::Handler(signal),
+                    // No usable stack for the frame: the task cannot go on
+                    Err(_) => Delivery::Core(Signal::SIGSEGV),
+                };
+            }
+        }
+    }
+    Delivery::None
+}
+
+/// Restore the context saved by `setup_frame` (rt_sigreturn)
+///
+/// The handler's `ret` popped the restorer address, so the frame starts
+/// one word below the stack pointer at the system call.
+pub fn sigreturn(
+    sig: &mut AdvancedSignalHandler,
+    regs: &mut UserRegs,
+    mem: &impl UserMemory,
+) -> Result<(), &'static str> {
+    let addr = regs.rsp.checked_sub(8).ok_or("Bad signal frame")?;
+    let mut frame = SignalFrame::default();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut frame as *mut _ as *mut u8, size_of::<SignalFrame>())
+    };
+    mem.read(addr, bytes)?;
+
+    regs.rip = frame.rip;
+    regs.rsp = frame.rsp;
+    regs.rflags = (regs.rflags & !USER_RFLAGS_MASK) | (frame.rflags & USER_RFLAGS_MASK) | RFLAGS_IF;
+    regs.rax = frame.rax;
+    regs.rdi = frame.rdi;
+    regs.rsi = frame.rsi;
+    regs.rdx = frame.rdx;
+    sig.set_mask(frame.blocked & !UNBLOCKABLE);
+    Ok(())
+}
+
+/// Convert a user `struct sigaction` to a signal action
+pub fn sigaction_from_user(k: &KSigAction) -> SigAction {
+    let action = match k.handler {
+        SIG_DFL => SignalAction::Default,
+        SIG_IGN => SignalAction::Ignore,
+        addr => SignalAction::Handler(addr),
+    };
+    SigAction {
+        action,
+        mask: k.mask & !UNBLOCKABLE,
+        flags: SignalFlags {
+            sa_restart: k.flags & SA_RESTART != 0,
+            sa_nodefer: k.flags & SA_NODEFER != 0,
+            sa_resethand: k.flags & SA_RESETHAND != 0,
+            sa_siginfo: k.flags & SA_SIGINFO != 0,
+        },
+        restorer: if k.flags & SA_RESTORER != 0 { k.restorer } else { 0 },
+    }
+}
+
+/// Convert a signal action to a user `struct sigaction`
+pub fn sigaction_to_user(action: &SigAction) -> KSigAction {
+    let handler = match action.action {
+        SignalAction::Default | SignalAction::Core => SIG_DFL,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Handler(addr) => addr,
+    };
+    let mut flags = 0;
+    if action.flags.sa_restart { flags |= SA_RESTART; }
+    if action.flags.sa_nodefer { flags |= SA_NODEFER; }
+    if action.flags.sa_resethand { flags |= SA_RESETHAND; }
+    if action.flags.sa_siginfo { flags |= SA_SIGINFO; }
+    if action.restorer != 0 { flags |= SA_RESTORER; }
+    KSigAction { handler, flags, restorer: action.restorer, mask: action.mask }
+}
+
+/// Change the signal mask (rt_sigprocmask), returning the old mask
+pub fn sigprocmask(sig: &mut AdvancedSignalHandler, how: i32, set: Option<u64>) -> Result<u64, &'static str> {
+    let old = sig.get_mask();
+    if let Some(set) = set {
+        let new = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err("Invalid sigprocmask operation"),
+        };
+        sig.set_mask(new & !UNBLOCKABLE);
+    }
+    Ok(old)
+}
+
+/// Handle rt_sigaction() for the current task
+pub fn sys_rt_sigaction(signum: i32, act: UserPtr<KSigAction>, oldact: UserPtr<KSigAction>) -> i64 {
+    let signal = match u8::try_from(signum).ok().and_then(Signal::from_num) {
+        Some(signal) => signal,
+        None => return EINVAL,
+    };
+    let Some(task) = scheduler::scheduler().current_task() else { return EINVAL };
+
+    let mut manager = sigadv::signal_manager().lock();
+    let sig = manager.get_or_create_handler(task);
+
+    let old = match sig.get_action(signal) {
+        Ok(old) => sigaction_to_user(old),
+        Err(_) => return EINVAL,
+    };
+    if !act.is_null() {
+        let Ok(act) = act.read() else { return EFAULT };
+        if sig.set_action(signal, sigaction_from_user(&act)).is_err() {
+            return EINVAL;
+        }
+    }
+    if !oldact.is_null() && oldact.write(&old).is_err() {
+        return EFAULT;
+    }
+    0
+}
+
+/// Handle rt_sigprocmask() for the current task
+pub fn sys_rt_sigprocmask(how: i32, set: UserPtr<u64>, oldset: UserPtr<u64>) -> i64 {
+    let Some(task) = scheduler::scheduler().current_task() else { return EINVAL };
+
+    let mut manager = sigadv::signal_manager().lock();
+    let sig = manager.get_or_create_handler(task);
+
+    let set = if set.is_null() {
+        None
+    } else {
+        match set.read() {
+            Ok(set) => Some(set),
+            Err(_) => return EFAULT,
+        }
+    };
+    match sigprocmask(sig, how, set) {
+        Ok(old) => {
+            if !oldset.is_null() && oldset.write(&old).is_err() {
+                return EFAULT;
+            }
+            0
+        }
+        Err(_) => EINVAL,
+    }
+}
+
+/// Handle rt_sigreturn()
+///
+/// The context is restored by the return-to-user hook, which has the
+/// saved registers; the value returned here is overwritten.
+pub fn sys_rt_sigreturn() -> i64 {
+    0
+}
+
+fn core_dump_reason(signal: Signal) -> CoreDumpReason {
+    match signal {
+        Signal::SIGSEGV => CoreDumpReason::SegmentationFault,
+        Signal::SIGILL => CoreDumpReason::IllegalInstruction,
+        Signal::SIGFPE => CoreDumpReason::FloatingPointException,
+        Signal::SIGBUS => CoreDumpReason::BusError,
+        Signal::SIGABRT => CoreDumpReason::Abort,
+        _ => CoreDumpReason::Other,
+    }
+}
+
+/// Build a core dump from the user context at delivery
+pub fn build_core_dump(task: TaskId, name: &str, signal: Signal, regs: &UserRegs, page_table: u64) -> CoreDump {
+    let registers = RegisterDump {
+        rax: regs.rax,
+        rdx: regs.rdx,
+        rsi: regs.rsi,
+        rdi: regs.rdi,
+        rsp: regs.rsp,
+        rip: regs.rip,
+        rflags: regs.rflags,
+        cs: fanga_arch_x86_64::gdt::USER_CODE_SELECTOR | 3,
+        ss: fanga_arch_x86_64::gdt::USER_DATA_SELECTOR | 3,
+        cr3: page_table,
+        ..RegisterDump::default()
+    };
+    CoreDump::new(
+        task,
+        String::from(name),
+        core_dump_reason(signal),
+        super::time::timer_ticks(),
+        registers,
+        crate::memory::PhysAddr::new(page_table),
+        128 + signal.num() as i32,
+    )
+}
+
+/// Terminate the current task because of a signal
+fn terminate(task: TaskId, signal: Signal, core: bool, regs: &UserRegs) {
+    if core {
+        let dump = {
+            let sched = scheduler::scheduler();
+            sched.get_task(task).map(|t| {
+                build_core_dump(task, t.name(), signal, regs, t.page_table.as_u64())
+            })
+        };
+        if let Some(path) = dump.and_then(coredump::record_dump) {
+            #[cfg(not(test))]
+            crate::log_info!(target: "signal", "Core written to {}", path);
+            #[cfg(test)]
+            let _ = path;
+        }
+    }
+
+    #[cfg(not(test))]
+    crate::log_info!(
+        target: "signal",
+        "Task {:?} killed by signal {}{}",
+        task, signal.num(), if core { " (core dumped)" } else { "" }
+    );
+
+    let _ = process::exit(task, 128 + signal.num() as i32);
+}
+
+/// Stop the current task until SIGCONT or SIGKILL arrives
+fn stop(task: TaskId) {
+    let _ = scheduler::scheduler().block_task(task);
+    process::notify_status(task, process::ProcessStatus::Stopped);
+
+    #[cfg(not(test))]
+    loop {
+        let resumed = sigadv::signal_manager()
+            .lock()
+            .get_handler_mut(task)
+            .map(|sig| {
+                let cont = sig.is_pending(Signal::SIGCONT);
+                sig.clear(Signal::SIGCONT);
+                cont || sig.is_pending(Signal::SIGKILL)
+            })
+            .unwrap_or(true);
+        if resumed {
+            break;
+        }
+        fanga_arch_x86_64::interrupts::enable();
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack)) };
+    }
+
+    let _ = scheduler::scheduler().unblock_task(task);
+    process::notify_status(task, process::ProcessStatus::Continued);
+}
+
+/// Return-to-user hook registered with the arch layer
+pub fn return_to_user(regs: &mut UserRegs) {
+    let Some(task) = scheduler::try_scheduler().and_then(|s| s.current_task()) else { return };
+    let Some(manager) = sigadv::try_signal_manager() else { return };
+    let mut mem = ActiveUserMemory;
+
+    if regs.syscall == Some(SYS_RT_SIGRETURN) {
+        let Some(mut manager) = manager.try_lock() else { return };
+        let sig = manager.get_or_create_handler(task);
+        if sigreturn(sig, regs, &mem).is_err() {
+            sig.send(Signal::SIGSEGV);
+        }
+    }
+
+    loop {
+        let delivery = {
+            let Some(mut manager) = manager.try_lock() else { return };
+            let Some(sig) = manager.get_handler_mut(task) else { return };
+            deliver_signal(sig, regs, &mut mem)
+        };
+
+        match delivery {
+            Delivery::Terminate(signal) | Delivery::Core(signal) => {
+                terminate(task, signal, matches!(delivery, Delivery::Core(_)), regs);
+                // The task is gone; this CPU has nothing to return to
+                #[cfg(not(test))]
+                super::idle::cpu_startup_entry();
+                #[cfg(test)]
+                return;
+            }
+            // Look again once resumed: SIGKILL may have ended the stop
+            Delivery::Stop(_) => stop(task),
+            Delivery::None | Delivery::Handler(_) | Delivery::Deferred(_) => return,
+        }
+    }
+}
+
+/// Register the return-to-user hook
+pub fn init() {
+    unsafe {
+        fanga_arch_x86_64::user_return::set_return_to_user_hook(return_to_user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// User memory backed by a buffer at a fixed base address
+    struct TestMemory {
+        base: u64,
+        bytes: Vec<u8>,
+    }
+
+    impl UserMemory for TestMemory {
+        fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str> {
+            let off = addr.checked_sub(self.base).ok_or("fault")? as usize;
+            self.bytes.get_mut(off..off + data.len()).ok_or("fault")?.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            let off = addr.checked_sub(self.base).ok_or("fault")? as usize;
+            buf.copy_from_slice(self.bytes.get(off..off + buf.len()).ok_or("fault")?);
+            Ok(())
+        }
+    }
+
+    fn syscall_regs() -> UserRegs {
+        UserRegs {
+            rip: 0x40_1000,
+            rsp: 0x7000_1000,
+            rflags: 0x202,
+            rax: 42,
+            rdi: 1,
+            rsi: 2,
+            rdx: 3,
+            syscall: Some(1),
+        }
+    }
+
+    fn memory() -> TestMemory {
+        TestMemory { base: 0x7000_0000, bytes: vec![0; 0x2000] }
+    }
+
+    #[test]
+    fn test_handler_frame_and_sigreturn() {
+        let mut sig = AdvancedSignalHandler::new();
+        let mut action = SigAction::with_handler(0x40_2000);
+        action.restorer = 0x40_3000;
+        sig.set_action(Signal::SIGUSR1, action).unwrap();
+        sig.send(Signal::SIGUSR1);
+
+        let mut mem = memory();
+        let mut regs = syscall_regs();
+        let saved = regs;
+
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Handler(Signal::SIGUSR1));
+        assert_eq!(regs.rip, 0x40_2000);
+        assert_eq!(regs.rdi, Signal::SIGUSR1 as u64);
+        assert_eq!(regs.rsp % 16, 8);
+        assert!(regs.rsp + (size_of::<SignalFrame>() as u64) <= saved.rsp - RED_ZONE);
+        assert!(sig.is_blocked(Signal::SIGUSR1));
+
+        // The handler returns to the restorer, which calls rt_sigreturn
+        let mut ret = [0u8; 8];
+        mem.read(regs.rsp, &mut ret).unwrap();
+        assert_eq!(u64::from_le_bytes(ret), 0x40_3000);
+        regs.rsp += 8;
+        regs.rax = 0;
+        sigreturn(&mut sig, &mut regs, &mem).unwrap();
+
+        assert_eq!(regs.rip, saved.rip);
+        assert_eq!(regs.rsp, saved.rsp);
+        assert_eq!((regs.rax, regs.rdi, regs.rsi, regs.rdx), (42, 1, 2, 3));
+        assert!(!sig.is_blocked(Signal::SIGUSR1));
+    }
+
+    #[test]
+    fn test_default_dispositions() {
+        let mut mem = memory();
+        let mut regs = syscall_regs();
+
+        let mut sig = AdvancedSignalHandler::new();
+        sig.send(Signal::SIGCHLD);
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::None);
+
+        sig.send(Signal::SIGTERM);
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Terminate(Signal::SIGTERM));
+
+        sig.send(Signal::SIGSEGV);
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Core(Signal::SIGSEGV));
+
+        sig.send(Signal::SIGTSTP);
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Stop(Signal::SIGTSTP));
+
+        sig.set_action(Signal::SIGTERM, SigAction::ignore()).unwrap();
+        sig.send(Signal::SIGTERM);
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::None);
+        assert_eq!(regs, syscall_regs());
+    }
+
+    #[test]
+    fn test_handler_deferred_on_interrupt_return() {
+        let mut sig = AdvancedSignalHandler::new();
+        sig.set_action(Signal::SIGINT, SigAction::with_handler(0x40_2000)).unwrap();
+        sig.send(Signal::SIGINT);
+
+        let mut mem = memory();
+        let mut regs = UserRegs { syscall: None, ..syscall_regs() };
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Deferred(Signal::SIGINT));
+        assert!(sig.is_pending(Signal::SIGINT));
+        assert_eq!(regs.rip, 0x40_1000);
+    }
+
+    #[test]
+    fn test_bad_stack_forces_segv() {
+        let mut sig = AdvancedSignalHandler::new();
+        sig.set_action(Signal::SIGUSR2, SigAction::with_handler(0x40_2000)).unwrap();
+        sig.send(Signal::SIGUSR2);
+
+        let mut mem = memory();
+        let mut regs = UserRegs { rsp: 0x10, ..syscall_regs() };
+        assert_eq!(deliver_signal(&mut sig, &mut regs, &mut mem), Delivery::Core(Signal::SIGSEGV));
+    }
+
+    #[test]
+    fn test_sigaction_and_sigprocmask() {
+        let k = KSigAction {
+            handler: 0x40_2000,
+            flags: SA_RESTORER | SA_NODEFER,
+            restorer: 0x40_3000,
+            mask: UNBLOCKABLE | 0x10,
+        };
+        let action = sigaction_from_user(&k);
+        assert_eq!(action.action, SignalAction::Handler(0x40_2000));
+        assert!(action.flags.sa_nodefer);
+        assert_eq!(action.mask, 0x10);
+        assert_eq!(sigaction_to_user(&action).restorer, 0x40_3000);
+
+        let mut sig = AdvancedSignalHandler::new();
+        assert_eq!(sigprocmask(&mut sig, SIG_BLOCK, Some(0x30 | UNBLOCKABLE)), Ok(0));
+        assert_eq!(sigprocmask(&mut sig, SIG_UNBLOCK, Some(0x10)), Ok(0x30));
+        assert_eq!(sigprocmask(&mut sig, SIG_SETMASK, None), Ok(0x20));
+        assert!(sigprocmask(&mut sig, 9, Some(0)).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Softirqs and Tasklets
+//!
+//! This module provides bottom-half processing for interrupt handlers:
+//! - A fixed set of softirq vectors (timer, network, block, tasklets, ...)
+//! - `raise_softirq` to mark a vector pending from hard IRQ context
+//! - Processing on IRQ exit with interrupts re-enabled
+//! - Per-CPU pending masks: a softirq runs on the CPU that raised it
+//! - Per-CPU `ksoftirqd` threads that take over when softirqs keep
+//!   re-raising, sleeping on a wait queue until then
+//! - Tasklets: one-shot deferred functions serialized on their own vectors
+//!
+//! Hard IRQ handlers should do the minimum (acknowledge the device, grab
+//! data) and raise a softirq or schedule a tasklet for the rest.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::kthread;
+use super::scheduler;
+use super::wait::{self, WaitQueue};
+use crate::smp::cpu::{current_cpu_id, MAX_CPUS};
+use super::tcb::{TaskId, TaskPriority};
+
+/// Softirq vectors, in priority order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SoftirqType {
+    /// High-priority tasklets
+    HiTasklet = 0,
+    /// Timer callbacks
+    Timer = 1,
+    /// Network transmit completion
+    NetTx = 2,
+    /// Network receive processing
+    NetRx = 3,
+    /// Block I/O completion
+    Block = 4,
+    /// Normal tasklets
+    Tasklet = 5,
+    /// Scheduler load balancing
+    Sched = 6,
+    /// RCU callbacks
+    Rcu = 7,
+}
+
+/// Number of softirq vectors
+pub const NR_SOFTIRQS: usize = 8;
+
+/// How many times pending softirqs are re-processed on IRQ exit before
+/// the remaining work is handed to ksoftirqd
+pub const MAX_SOFTIRQ_RESTART: usize = 10;
+
+/// Maximum tasklets queued per priority level
+pub const MAX_TASKLETS: usize = 256;
+
+/// Softirq handler signature
+pub type SoftirqHandler = fn();
+
+/// Tasklet function signature
+pub type TaskletFn = fn(usize);
+
+/// Outcome of a softirq processing run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftirqRun {
+    /// Number of handler invocations
+    pub handled: usize,
+    /// True if work was still pending when the restart limit was hit
+    pub deferred: bool,
+}
+
+/// A set of softirq vectors with their handlers and per-CPU pending masks
+pub struct SoftirqVector {
+    pending: [AtomicU32; MAX_CPUS],
+    handlers: Mutex<[Option<SoftirqHandler>; NR_SOFTIRQS]>,
+    counts: [AtomicU64; NR_SOFTIRQS],
+}
+
+impl SoftirqVector {
+    /// Create an empty softirq vector
+    pub const fn new() -> Self {
+        Self {
+            pending: [const { AtomicU32::new(0) }; MAX_CPUS],
+            handlers: Mutex::new([None; NR_SOFTIRQS]),
+            counts: [const { AtomicU64::new(0) }; NR_SOFTIRQS],
+        }
+    }
+
+    /// Register the handler for a softirq vector
+    pub fn open(&self, nr: SoftirqType, handler: SoftirqHandler) {
+        self.handlers.lock()[nr as usize] = Some(handler);
+    }
+
+    /// This CPU's pending mask
+    fn local_pending(&self) -> &AtomicU32 {
+        &self.pending[current_cpu_id().as_usize()]
+    }
+
+    /// Mark a softirq vector as pending on this CPU
+    pub fn raise(&self, nr: SoftirqType) {
+        self.local_pending().fetch_or(1 << nr as u32, Ordering::AcqRel);
+    }
+
+    /// Get this CPU's pending mask
+    pub fn pending(&self) -> u32 {
+        self.local_pending().load(Ordering::Acquire)
+    }
+
+    /// Number of times a vector's handler has run
+    pub fn count(&self, nr: SoftirqType) -> u64 {
+        self.counts[nr as usize].load(Ordering::Relaxed)
+    }
+
+    /// Run this CPU's pending handlers, restarting up to `max_restart`
+    /// times if they re-raise
+    pub fn process(&self, max_restart: usize) -> SoftirqRun {
+        let handlers = *self.handlers.lock();
+        let mut handled = 0;
+
+        for _ in 0..max_restart {
+            let pending = self.local_pending().swap(0, Ordering::AcqRel);
+            if pending == 0 {
+                return SoftirqRun { handled, deferred: false };
+            }
+
+            for (nr, handler) in handlers.iter().enumerate() {
+                if pending & (1 << nr) == 0 {
+                    continue;
+                }
+                if let Some(handler) = handler {
+                    handler();
+                    self.counts[nr].fetch_add(1, Ordering::Relaxed);
+                    handled += 1;
+                }
+            }
+        }
+
+        SoftirqRun { handled, deferred: self.pending() != 0 }
+    }
+}
+
+impl Default for SoftirqVector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A deferred function run once in softirq context
+#[derive(Debug, Clone, Copy)]
+pub struct Tasklet {
+    /// Function to execute
+    pub func: TaskletFn,
+    /// Argument passed to the function
+    pub data: usize,
+}
+
+impl Tasklet {
+    /// Create a new tasklet
+    pub const fn new(func: TaskletFn, data: usize) -> Self {
+        Self { func, data }
+    }
+}
+
+/// FIFO of scheduled tasklets
+pub struct TaskletQueue {
+    queue: Mutex<VecDeque<Tasklet>>,
+}
+
+impl TaskletQueue {
+    /// Create an empty tasklet queue
+    pub const fn new() -> Self {
+        Self { queue: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Add a tasklet to the queue
+    pub fn push(&self, tasklet: Tasklet) -> Result<(), &'static str> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= MAX_TASKLETS {
+            return Err("Tasklet queue is full");
+        }
+        queue.push_back(tasklet);
+        Ok(())
+    }
+
+    /// Run every tasklet queued at the time of the call
+    ///
+    /// Returns the number of tasklets executed. The queue is taken with
+    /// interrupts off, since IRQ handlers schedule tasklets onto it.
+    pub fn run(&self) -> usize {
+        let batch = with_irqs_off(|| core::mem::take(&mut *self.queue.lock()));
+        let count = batch.len();
+        for tasklet in batch {
+            (tasklet.func)(tasklet.data);
+        }
+        count
+    }
+
+    /// Number of queued tasklets
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// Check whether the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for TaskletQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global softirq vectors
+static SOFTIRQS: SoftirqVector = SoftirqVector::new();
+
+/// Normal-priority tasklets
+static TASKLETS: TaskletQueue = TaskletQueue::new();
+
+/// High-priority tasklets
+static HI_TASKLETS: TaskletQueue = TaskletQueue::new();
+
+/// Set while softirqs are being processed, per CPU (prevents nesting)
+static IN_SOFTIRQ: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Set when a CPU's ksoftirqd should take over its pending work
+static KSOFTIRQD_WAKEUP: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Where each CPU's ksoftirqd sleeps until work is handed to it
+static KSOFTIRQD_WAIT: [WaitQueue; MAX_CPUS] = [const { WaitQueue::new() }; MAX_CPUS];
+
+/// ksoftirqd task of each CPU, if spawned
+static KSOFTIRQD_TASK: [spin::Once<TaskId>; MAX_CPUS] = [const { spin::Once::new() }; MAX_CPUS];
+
+/// Initialize softirqs
+///
+/// Registers the tasklet and timer vectors and spawns a ksoftirqd for
+/// each CPU, pinned to it.
+pub fn init() {
+    open_softirq(SoftirqType::HiTasklet, hi_tasklet_action);
+    open_softirq(SoftirqType::Tasklet, tasklet_action);
+    open_softirq(SoftirqType::Timer, timer_action);
+
+    for (cpu, slot) in KSOFTIRQD_TASK.iter().enumerate().take(crate::smp::cpu::cpu_count()) {
+        let name = alloc::format!("ksoftirqd/{}", cpu);
+        if let Ok(task) = kthread::spawn_with_priority(&name, TaskPriority::High, move || ksoftirqd_main(cpu)) {
+            if let Some(mask) = 1u64.checked_shl(cpu as u32) {
+                let _ = scheduler::scheduler().set_cpus_allowed(task, mask);
+            }
+            slot.call_once(|| task);
+        }
+    }
+}
+
+/// Register a softirq handler
+pub fn open_softirq(nr: SoftirqType, handler: SoftirqHandler) {
+    SOFTIRQS.open(nr, handler);
+}
+
+/// Mark a softirq as pending (safe from hard IRQ context)
+pub fn raise_softirq(nr: SoftirqType) {
+    SOFTIRQS.raise(nr);
+}
+
+/// This CPU's pending softirq mask
+pub fn local_softirq_pending() -> u32 {
+    SOFTIRQS.pending()
+}
+
+/// Check if this CPU is currently running softirqs
+pub fn in_softirq() -> bool {
+    in_softirq_flag().load(Ordering::Acquire)
+}
+
+/// This CPU's softirq nesting flag
+fn in_softirq_flag() -> &'static AtomicBool {
+    &IN_SOFTIRQ[current_cpu_id().as_usize()]
+}
+
+/// Number of times a softirq vector has run
+pub fn softirq_count(nr: SoftirqType) -> u64 {
+    SOFTIRQS.count(nr)
+}
+
+/// The ksoftirqd task of a CPU, if it was spawned
+pub fn ksoftirqd_task(cpu: usize) -> Option<TaskId> {
+    KSOFTIRQD_TASK.get(cpu)?.get().copied()
+}
+
+/// Schedule a normal-priority tasklet
+pub fn tasklet_schedule(tasklet: Tasklet) -> Result<(), &'static str> {
+    with_irqs_off(|| TASKLETS.push(tasklet))?;
+    raise_softirq(SoftirqType::Tasklet);
+    Ok(())
+}
+
+/// Schedule a high-priority tasklet
+pub fn tasklet_hi_schedule(tasklet: Tasklet) -> Result<(), &'static str> {
+    with_irqs_off(|| HI_TASKLETS.push(tasklet))?;
+    raise_softirq(SoftirqType::HiTasklet);
+    Ok(())
+}
+
+/// Process this CPU's pending softirqs
+///
+/// Runs with interrupts enabled. If softirqs are still pending after
+/// `MAX_SOFTIRQ_RESTART` passes, this CPU's ksoftirqd is woken to finish
+/// the job.
+pub fn do_softirq() -> SoftirqRun {
+    let in_softirq = in_softirq_flag();
+    if SOFTIRQS.pending() == 0 || in_softirq.swap(true, Ordering::AcqRel) {
+        return SoftirqRun { handled: 0, deferred: false };
+    }
+
+    // Handlers run with interrupts on but must not be switched away from
+    // halfway, by an interrupt's exit or by releasing a lock
+    #[cfg(not(test))]
+    crate::preempt::preempt_disable();
+    #[cfg(not(test))]
+    let irqs_were_enabled = fanga_arch_x86_64::interrupts::are_enabled();
+    #[cfg(not(test))]
+    fanga_arch_x86_64::interrupts::enable();
+
+    let run = SOFTIRQS.process(MAX_SOFTIRQ_RESTART);
+
+    #[cfg(not(test))]
+    if !irqs_were_enabled {
+        fanga_arch_x86_64::interrupts::disable();
+    }
+    #[cfg(not(test))]
+    crate::preempt::preempt_enable();
+
+    in_softirq.store(false, Ordering::Release);
+
+    if run.deferred {
+        let cpu = current_cpu_id().as_usize();
+        KSOFTIRQD_WAKEUP[cpu].store(true, Ordering::Release);
+        // This may run in an interrupt that cut into ksoftirqd's own use
+        // of the queue. ksoftirqd is awake then and sees the flag.
+        KSOFTIRQD_WAIT[cpu].try_wake_up_one();
+    }
+    run
+}
+
+/// Hook for the end of a hard IRQ handler
+///
+/// Processes pending softirqs unless this CPU's ksoftirqd is already
+/// handling a backlog or we interrupted softirq processing itself.
+pub fn irq_exit() {
+    if KSOFTIRQD_WAKEUP[current_cpu_id().as_usize()].load(Ordering::Acquire) {
+        return;
+    }
+    do_softirq();
+}
+
+/// ksoftirqd thread body for `cpu`
+///
+/// Sleeps until `do_softirq` hands it a backlog, then runs softirqs
+/// until none are deferred any more.
+fn ksoftirqd_main(cpu: usize) {
+    let wakeup = &KSOFTIRQD_WAKEUP[cpu];
+    while !kthread::should_stop() {
+        if wait::wait_event(&KSOFTIRQD_WAIT[cpu], || wakeup.load(Ordering::Acquire)).is_err() {
+            return;
+        }
+        let run = do_softirq();
+        if !run.deferred {
+            wakeup.store(false, Ordering::Release);
+        }
+    }
+}
+
+fn hi_tasklet_action() {
+    HI_TASKLETS.run();
+}
+
+fn tasklet_action() {
+    TASKLETS.run();
+}
+
+fn timer_action() {
+    super::timer_wheel::run_timers();
+    super::workqueue::timer_tick();
+}
+
+/// Run `f` with interrupts disabled so IRQ handlers cannot deadlock on
+/// locks shared with softirq context
+pub(crate) fn with_irqs_off<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(not(test))]
+    {
+        fanga_arch_x86_64::interrupts::without_interrupts(f)
+    }
+    #[cfg(test)]
+    {
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    static NET_RX_RUNS: AtomicUsize = AtomicUsize::new(0);
+    static RERAISE_RUNS: AtomicUsize = AtomicUsize::new(0);
+    static RERAISE_VEC: SoftirqVector = SoftirqVector::new();
+    static TASKLET_SUM: AtomicUsize = AtomicUsize::new(0);
+
+    fn net_rx() {
+        NET_RX_RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn reraise() {
+        RERAISE_RUNS.fetch_add(1, Ordering::SeqCst);
+        RERAISE_VEC.raise(SoftirqType::Block);
+    }
+
+    fn add(data: usize) {
+        TASKLET_SUM.fetch_add(data, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_raise_and_process() {
+        let vec = SoftirqVector::new();
+        vec.open(SoftirqType::NetRx, net_rx);
+
+        vec.raise(SoftirqType::NetRx);
+        assert_eq!(vec.pending(), 1 << SoftirqType::NetRx as u32);
+
+        let run = vec.process(MAX_SOFTIRQ_RESTART);
+        assert_eq!(run, SoftirqRun { handled: 1, deferred: false });
+        assert_eq!(vec.pending(), 0);
+        assert_eq!(vec.count(SoftirqType::NetRx), 1);
+        assert_eq!(NET_RX_RUNS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_unregistered_vector_is_cleared() {
+        let vec = SoftirqVector::new();
+        vec.raise(SoftirqType::Sched);
+        let run = vec.process(MAX_SOFTIRQ_RESTART);
+        assert_eq!(run.handled, 0);
+        assert_eq!(vec.pending(), 0);
+    }
+
+    #[test]
+    fn test_restart_limit_defers() {
+        RERAISE_VEC.open(SoftirqType::Block, reraise);
+        RERAISE_VEC.raise(SoftirqType::Block);
+
+        let run = RERAISE_VEC.process(3);
+        assert_eq!(run.handled, 3);
+        assert!(run.deferred);
+        assert_eq!(RERAISE_RUNS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_tasklet_queue() {
+        let queue = TaskletQueue::new();
+        assert!(queue.is_empty());
+
+        queue.push(Tasklet::new(add, 2)).unwrap();
+        queue.push(Tasklet::new(add, 5)).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.run(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(TASKLET_SUM.load(Ordering::SeqCst), 7);
+    }
+}
@@ -0,0 +1,133 @@
+//! Speculation Barriers on Context Switch
+//!
+//! Indirect branch predictions trained by one process can steer another
+//! process's speculative execution (Spectre v2). When a CPU switches to a
+//! task in a different address space than the last user task it ran, the
+//! scheduler issues an IBPB so the new task starts with clean predictors.
+//! Kernel-only tasks such as the idle task do not count as a switch, so
+//! going from a process to idle and back to it costs nothing.
+//!
+//! The mitigations in force are chosen from the boot command line:
+//! - `mitigations=off` disables all of them
+//! - `spectre_v2=off` (or `nospectre_v2`) disables IBRS, STIBP and IBPB
+//! - `spec_store_bypass_disable=on` enables SSBD for all code
+//!
+//! This module provides:
+//! - Policy selection from the command line
+//! - The IBPB on address space switches
+//! - The mitigation status report
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fanga_arch_x86_64::speculation::{self, Mitigations, Policy};
+
+use super::scheduler::Scheduler;
+use super::tcb::TaskId;
+use crate::smp::cpu::MAX_CPUS;
+
+/// Page table of the last user task each CPU ran (0 if none yet)
+static LAST_USER_MM: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Mitigation policy requested by a kernel command line
+pub fn policy_from_cmdline(cmdline: &str) -> Policy {
+    use crate::cmdline::{flag_in, option_in};
+
+    if option_in(cmdline, "mitigations") == Some("off") {
+        return Policy::OFF;
+    }
+    let mut policy = Policy::DEFAULT;
+    if option_in(cmdline, "spectre_v2") == Some("off") || flag_in(cmdline, "nospectre_v2") {
+        policy.spectre_v2 = false;
+    }
+    match option_in(cmdline, "spec_store_bypass_disable") {
+        Some("on") => policy.ssbd = true,
+        Some("off") => policy.ssbd = false,
+        _ => {}
+    }
+    policy
+}
+
+/// Record that `cpu` is switching to address space `next_mm`
+///
+/// # Returns
+/// Whether the switch needs a prediction barrier
+fn note_user_switch(cpu: usize, next_mm: u64) -> bool {
+    let Some(last) = LAST_USER_MM.get(cpu) else {
+        return false;
+    };
+    let prev = last.swap(next_mm, Ordering::Relaxed);
+    prev != 0 && prev != next_mm
+}
+
+/// Issue an IBPB if the incoming task has a different address space than
+/// the last user task on this CPU
+///
+/// Called by the scheduler on every context switch.
+pub fn switch_mm(sched: &Scheduler, next: Option<TaskId>) {
+    let Some(next) = next.filter(|&id| !sched.is_idle_task(id)) else {
+        return;
+    };
+    let Some(mm) = sched.get_task(next).map(|task| task.page_table.as_u64()) else {
+        return;
+    };
+    if mm != 0 && note_user_switch(crate::smp::current_cpu_id().as_usize(), mm) {
+        speculation::ibpb();
+    }
+}
+
+/// Enable the mitigations requested on the boot command line
+///
+/// Called once on the boot CPU, after the command line is stored and
+/// before any application processor starts.
+pub fn init() {
+    let enabled = speculation::init(policy_from_cmdline(crate::cmdline::cmdline()));
+    crate::log_info!(
+        target: "spec",
+        "Spectre v2: {}",
+        speculation::spectre_v2_status(enabled)
+    );
+    crate::log_info!(
+        target: "spec",
+        "Speculative Store Bypass: {}",
+        speculation::ssb_status(enabled, speculation::capabilities())
+    );
+}
+
+/// Write the mitigation status, one vulnerability per line
+pub fn report(out: &mut impl fmt::Write) -> fmt::Result {
+    let enabled = speculation::enabled();
+    let caps = speculation::capabilities();
+    let meltdown = if caps.contains(Mitigations::RDCL_NO) { "Not affected" } else { "Vulnerable" };
+    writeln!(out, "meltdown:          {}", meltdown)?;
+    writeln!(out, "spectre_v1:        Vulnerable (no usercopy barriers)")?;
+    writeln!(out, "spectre_v2:        {}", speculation::spectre_v2_status(enabled))?;
+    let stibp = if enabled.contains(Mitigations::STIBP) { "STIBP on" } else { "STIBP off" };
+    writeln!(out, "  SMT:             {}", stibp)?;
+    writeln!(out, "spec_store_bypass: {}", speculation::ssb_status(enabled, caps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_from_cmdline() {
+        assert_eq!(policy_from_cmdline(""), Policy::DEFAULT);
+        assert_eq!(policy_from_cmdline("quiet mitigations=off spec_store_bypass_disable=on"), Policy::OFF);
+        assert!(!policy_from_cmdline("nospectre_v2").spectre_v2);
+        assert!(!policy_from_cmdline("spectre_v2=off").spectre_v2);
+        assert!(policy_from_cmdline("spec_store_bypass_disable=on").ssbd);
+    }
+
+    #[test]
+    fn test_note_user_switch() {
+        let cpu = MAX_CPUS - 1;
+        // The first user task on a CPU has nothing to be protected from
+        assert!(!note_user_switch(cpu, 0x1000));
+        assert!(!note_user_switch(cpu, 0x1000));
+        assert!(note_user_switch(cpu, 0x2000));
+        assert!(note_user_switch(cpu, 0x1000));
+        assert!(!note_user_switch(MAX_CPUS, 0x1000));
+    }
+}
@@ -56,6 +56,19 @@ impl ConditionVariable {
         tasks
     }
     
+    /// Remove a waiting thread (timed out or interrupted)
+    ///
+    /// Returns true if the thread was still waiting.
+    pub fn remove_waiter(&mut self, task_id: TaskId) -> bool {
+        match self.waiting_threads.iter().position(|&t| t == task_id) {
+            Some(pos) => {
+                self.waiting_threads.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+    
     /// Get the number of waiting threads
     pub fn waiting_count(&self) -> usize {
         self.waiting_threads.len()
@@ -3,8 +3,12 @@
 //! This module defines the Task Control Block structure which contains all the
 //! information needed to manage a task/process in the operating system.
 
+use core::panic::Location;
+
 use super::context::TaskContext;
+use super::cputime::CpuTimes;
 use crate::memory::{PhysAddr, VirtAddr};
+use crate::numa::NumaMemoryPolicy;
 
 /// Task ID - unique identifier for each task
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -80,6 +84,32 @@ pub struct Task {
     
     /// Task name (for debugging)
     pub name: [u8; 32],
+    
+    /// CPU time and context switch accounting
+    pub times: CpuTimes,
+    
+    /// Set while the task's cgroup has exhausted its CPU quota
+    pub throttled: bool,
+    
+    /// FS base (thread pointer) loaded when the task runs
+    pub tls_base: VirtAddr,
+    
+    /// Top of the area `mmap` places mappings in; 0 for tasks not
+    /// loaded from a binary
+    pub mmap_base: VirtAddr,
+    
+    /// CPUs the task may run on (bit N = CPU N)
+    pub cpus_allowed: u64,
+    
+    /// CPU whose run queue holds the task, or that it last ran on
+    pub cpu: usize,
+    
+    /// NUMA policy for the frames behind the task's user mappings
+    pub mempolicy: NumaMemoryPolicy,
+    
+    /// TSC and call site of the wakeup the task has not run since, while
+    /// preemption latencies are measured
+    pub woken: Option<(u64, &'static Location<'static>)>,
 }
 
 impl Task {
@@ -101,6 +131,14 @@ impl Task {
             kernel_stack_size,
             page_table,
             name: [0; 32],
+            times: CpuTimes::new(),
+            throttled: false,
+            tls_base: VirtAddr::new(0),
+            mmap_base: VirtAddr::new(0),
+            cpus_allowed: u64::MAX,
+            cpu: 0,
+            mempolicy: NumaMemoryPolicy::default(),
+            woken: None,
         };
         
         // Set default name
@@ -138,6 +176,24 @@ impl Task {
     pub fn is_terminated(&self) -> bool {
         self.state == TaskState::Terminated
     }
+    
+    /// Check if the task may run on a CPU
+    ///
+    /// CPUs beyond the mask width are only allowed by an all-ones mask.
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        cpu_in_mask(self.cpus_allowed, cpu)
+    }
+}
+
+/// Check whether a CPU affinity mask allows a CPU
+///
+/// CPUs beyond the mask width are only allowed by an all-ones mask.
+pub fn cpu_in_mask(mask: u64, cpu: usize) -> bool {
+    if cpu < 64 {
+        mask & (1 << cpu) != 0
+    } else {
+        mask == u64::MAX
+    }
 }
 
 #[cfg(test)]
@@ -12,6 +12,7 @@ use alloc::string::String;
 
 use super::tcb::{TaskId, TaskState, TaskPriority};
 use super::context::TaskContext;
+use super::tls::{TlsBlock, TlsTemplate};
 use crate::memory::{PhysAddr, VirtAddr};
 
 /// Thread ID - unique identifier for each thread
@@ -153,6 +154,9 @@ pub struct Thread {
     /// Thread-local storage pointer
     pub tls_base: VirtAddr,
     
+    /// TLS block owned by this thread (kernel threads)
+    pub tls_block: Option<TlsBlock>,
+    
     /// Exit code (valid when state is Terminated)
     pub exit_code: i32,
 }
@@ -180,6 +184,7 @@ impl Thread {
             stack,
             stack_size,
             tls_base: VirtAddr::new(0),
+            tls_block: None,
             exit_code: 0,
         }
     }
@@ -194,6 +199,14 @@ impl Thread {
         self.tls_base
     }
     
+    /// Allocate a TLS block from a template and point the TLS base at it
+    pub fn alloc_tls(&mut self, template: &TlsTemplate) -> Result<(), &'static str> {
+        let block = TlsBlock::new(template)?;
+        self.tls_base = block.thread_pointer();
+        self.tls_block = Some(block);
+        Ok(())
+    }
+    
     /// Check if the thread is a kernel thread
     pub fn is_kernel_thread(&self) -> bool {
         self.attributes.thread_type == ThreadType::Kernel
@@ -436,5 +449,9 @@ mod tests {
         
         thread.set_tls_base(VirtAddr::new(0x3000));
         assert_eq!(thread.tls_base(), VirtAddr::new(0x3000));
+        
+        let template = TlsTemplate::new(alloc::vec![7; 8], 8, 8).unwrap();
+        thread.alloc_tls(&template).unwrap();
+        assert_eq!(thread.tls_base(), thread.tls_block.as_ref().unwrap().thread_pointer());
     }
 }
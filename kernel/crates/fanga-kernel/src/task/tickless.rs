@@ -0,0 +1,348 @@
+//! Tickless Idle (Dynamic Tick)
+//!
+//! When the CPU has nothing to run, the periodic 100 Hz tick is stopped and
+//! the tick timer (the local APIC timer, or the PIT until that is
+//! calibrated) is programmed in one-shot mode for the nearest pending
+//! timer event instead. On wakeup the skipped ticks are credited back to
+//! the system tick counter so uptime and timeouts stay correct. Only the
+//! boot CPU keeps time, so the other CPUs keep ticking while idle.
+//!
+//! This module provides:
+//! - The stop/restart tick decision logic
+//! - Idle time accounting (periodic and tickless)
+//! - `cpu_idle()`, one iteration of the per-CPU idle task
+
+use spin::Mutex;
+
+use crate::power::cpu::CState;
+
+/// Length of one periodic tick in milliseconds (100 Hz)
+pub const TICK_MS: u64 = 10;
+
+/// Do not bother stopping the tick for less than this many ticks
+pub const MIN_NOHZ_TICKS: u64 = 2;
+
+/// Idle time statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleStats {
+    /// Number of times the CPU went idle
+    pub idle_entries: u64,
+    /// Number of idle periods spent with the tick stopped
+    pub nohz_entries: u64,
+    /// Total time spent idle in milliseconds
+    pub idle_ms: u64,
+    /// Timer interrupts avoided by stopping the tick
+    pub ticks_skipped: u64,
+}
+
+/// Tick state for one CPU
+#[derive(Debug, Default)]
+pub struct TickSched {
+    /// Whether the periodic tick is currently stopped
+    stopped: bool,
+    /// Tick counter value when the tick was stopped
+    enter_ticks: u64,
+    /// One-shot interval programmed into the timer
+    programmed_ms: u64,
+    /// Sub-tick remainder carried between idle periods
+    carry_ms: u64,
+    /// Idle accounting
+    stats: IdleStats,
+}
+
+impl TickSched {
+    /// Create a new tick state
+    pub const fn new() -> Self {
+        Self {
+            stopped: false,
+            enter_ticks: 0,
+            programmed_ms: 0,
+            carry_ms: 0,
+            stats: IdleStats {
+                idle_entries: 0,
+                nohz_entries: 0,
+                idle_ms: 0,
+                ticks_skipped: 0,
+            },
+        }
+    }
+
+    /// Decide whether to stop the tick on idle entry
+    ///
+    /// # Arguments
+    /// * `now_ms` - Current uptime in milliseconds
+    /// * `now_ticks` - Current tick counter
+    /// * `next_event_ms` - Uptime of the nearest pending timer event, if any
+    /// * `max_oneshot_ms` - Longest interval the hardware timer can program
+    ///
+    /// # Returns
+    /// The one-shot interval to program, or None to keep ticking
+    pub fn stop_tick(
+        &mut self,
+        now_ms: u64,
+        now_ticks: u64,
+        next_event_ms: Option<u64>,
+        max_oneshot_ms: u64,
+    ) -> Option<u64> {
+        self.stats.idle_entries += 1;
+
+        let delta = next_event_ms
+            .map(|t| t.saturating_sub(now_ms))
+            .unwrap_or(u64::MAX)
+            .min(max_oneshot_ms);
+
+        if delta < MIN_NOHZ_TICKS * TICK_MS {
+            return None;
+        }
+
+        self.stopped = true;
+        self.enter_ticks = now_ticks;
+        self.programmed_ms = delta;
+        self.stats.nohz_entries += 1;
+        Some(delta)
+    }
+
+    /// Record the interval actually programmed (hardware may round it)
+    pub fn set_programmed(&mut self, ms: u64) {
+        self.programmed_ms = ms;
+    }
+
+    /// Restart the tick on idle exit
+    ///
+    /// # Arguments
+    /// * `now_ticks` - Current tick counter
+    /// * `remaining_ms` - Time left on the one-shot timer if we were woken
+    ///   early by another interrupt (ignored if the timer fired)
+    ///
+    /// # Returns
+    /// The number of ticks to add to the system tick counter
+    pub fn restart_tick(&mut self, now_ticks: u64, remaining_ms: u64) -> u64 {
+        if !self.stopped {
+            return 0;
+        }
+        self.stopped = false;
+
+        let counted = now_ticks.saturating_sub(self.enter_ticks);
+        let elapsed = if counted > 0 {
+            self.programmed_ms
+        } else {
+            self.programmed_ms.saturating_sub(remaining_ms)
+        };
+
+        let total = elapsed + self.carry_ms;
+        let ticks = total / TICK_MS;
+        self.carry_ms = total % TICK_MS;
+
+        let missing = ticks.saturating_sub(counted);
+        self.stats.idle_ms += elapsed;
+        self.stats.ticks_skipped += missing;
+        missing
+    }
+
+    /// Account an idle period during which the tick kept running
+    pub fn account_periodic_idle(&mut self, ticks: u64) {
+        self.stats.idle_ms += ticks * TICK_MS;
+    }
+
+    /// Whether the tick is currently stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Get idle statistics
+    pub fn stats(&self) -> IdleStats {
+        self.stats
+    }
+}
+
+/// Global tick state (boot CPU)
+static TICK_SCHED: Mutex<TickSched> = Mutex::new(TickSched::new());
+
+/// Get idle statistics
+pub fn idle_stats() -> IdleStats {
+    TICK_SCHED.lock().stats()
+}
+
+/// Nearest pending timer event across subsystems
+fn next_timer_event() -> Option<u64> {
+    let timers = super::timer_wheel::next_timer_expiry();
+    let work = super::workqueue::next_delayed_expiry();
+    match (timers, work) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Run one iteration of the idle loop
+///
+/// If nothing is runnable, stops the periodic tick until the next timer
+/// event, halts, then restarts the tick and credits skipped ticks.
+/// Returns the C-state the CPU slept in, or `None` if there was work.
+#[cfg(not(test))]
+pub fn cpu_idle() -> Option<CState> {
+    use fanga_arch_x86_64::interrupts::{self, idt};
+
+    interrupts::disable();
+
+    let busy = super::scheduler::scheduler().ready_task_count() > 0
+        || super::softirq::local_softirq_pending() != 0;
+    if busy {
+        interrupts::enable();
+        return None;
+    }
+
+    // Skipped ticks can only be credited by the CPU that counts them
+    let timekeeper = crate::smp::cpu::current_cpu_id().as_usize() == 0;
+    let enter_ticks = idt::timer_ticks();
+    let oneshot = if timekeeper {
+        TICK_SCHED.lock().stop_tick(
+            idt::uptime_ms(),
+            enter_ticks,
+            next_timer_event(),
+            max_oneshot_ms(),
+        )
+    } else {
+        None
+    };
+
+    if let Some(ms) = oneshot {
+        let actual = set_oneshot(ms);
+        TICK_SCHED.lock().set_programmed(actual);
+    }
+
+    // Re-enables interrupts atomically with the halt
+    let expected_ms = oneshot.unwrap_or(TICK_MS);
+    let c_state = super::idle::enter_idle_state(expected_ms);
+    interrupts::disable();
+
+    let exit_ticks = idt::timer_ticks();
+    if oneshot.is_some() {
+        let remaining = if exit_ticks == enter_ticks {
+            oneshot_remaining_ms()
+        } else {
+            0
+        };
+        let missing = TICK_SCHED.lock().restart_tick(exit_ticks, remaining);
+        restart_periodic_tick();
+        idt::advance_ticks(missing);
+    } else {
+        TICK_SCHED.lock().account_periodic_idle(exit_ticks - enter_ticks);
+    }
+
+    let idle_ticks = idt::timer_ticks() - enter_ticks;
+    crate::percpu!(idle_ticks += idle_ticks);
+
+    interrupts::enable();
+    Some(c_state)
+}
+
+/// Longest interval the tick timer can be stopped for
+#[cfg(not(test))]
+fn max_oneshot_ms() -> u64 {
+    use fanga_arch_x86_64::interrupts::{apic_timer, pit};
+
+    if apic_timer::is_active() {
+        apic_timer::max_oneshot_ms() as u64
+    } else {
+        pit::PIT_MAX_ONESHOT_MS as u64
+    }
+}
+
+/// Stop the tick and program a single interrupt, returning the interval
+#[cfg(not(test))]
+fn set_oneshot(ms: u64) -> u64 {
+    use fanga_arch_x86_64::interrupts::{apic_timer, pit};
+
+    let ms = ms.min(u32::MAX as u64) as u32;
+    if apic_timer::is_active() {
+        apic_timer::set_oneshot(ms) as u64
+    } else {
+        unsafe { pit::set_oneshot(ms) as u64 }
+    }
+}
+
+/// Time left on the one-shot interrupt
+#[cfg(not(test))]
+fn oneshot_remaining_ms() -> u64 {
+    use fanga_arch_x86_64::interrupts::{apic_timer, pit};
+
+    if apic_timer::is_active() {
+        apic_timer::remaining_ms() as u64
+    } else {
+        pit::count_to_ms(unsafe { pit::read_counter() }) as u64
+    }
+}
+
+/// Return the tick timer to periodic mode
+#[cfg(not(test))]
+fn restart_periodic_tick() {
+    use fanga_arch_x86_64::interrupts::{apic_timer, pit};
+
+    if apic_timer::is_active() {
+        apic_timer::start_cpu();
+    } else {
+        unsafe { pit::init(pit::PIT_DEFAULT_FREQ) };
+    }
+}
+
+/// Host tests never idle
+#[cfg(test)]
+pub fn cpu_idle() -> Option<CState> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_idle_keeps_tick() {
+        let mut ts = TickSched::new();
+        assert_eq!(ts.stop_tick(1000, 100, Some(1015), 54), None);
+        assert!(!ts.is_stopped());
+        assert_eq!(ts.stats().idle_entries, 1);
+        assert_eq!(ts.stats().nohz_entries, 0);
+    }
+
+    #[test]
+    fn test_stop_tick_clamps_to_hardware() {
+        let mut ts = TickSched::new();
+        assert_eq!(ts.stop_tick(1000, 100, None, 54), Some(54));
+        assert_eq!(ts.stop_tick(1000, 100, Some(1030), 54), Some(30));
+        assert!(ts.is_stopped());
+    }
+
+    #[test]
+    fn test_restart_after_timer_fired() {
+        let mut ts = TickSched::new();
+        ts.stop_tick(0, 100, Some(50), 54);
+
+        // The one-shot interrupt counted one tick; 4 more were skipped
+        assert_eq!(ts.restart_tick(101, 0), 4);
+        assert!(!ts.is_stopped());
+        assert_eq!(ts.stats().idle_ms, 50);
+        assert_eq!(ts.stats().ticks_skipped, 4);
+    }
+
+    #[test]
+    fn test_restart_after_early_wakeup() {
+        let mut ts = TickSched::new();
+        ts.stop_tick(0, 100, Some(50), 54);
+
+        // Woken by another IRQ with 23ms left: 27ms elapsed, 2 ticks + 7ms carry
+        assert_eq!(ts.restart_tick(100, 23), 2);
+        assert_eq!(ts.stats().idle_ms, 27);
+
+        // The carried remainder completes a tick on the next period
+        ts.stop_tick(0, 102, Some(30), 54);
+        assert_eq!(ts.restart_tick(102, 27), 1);
+    }
+
+    #[test]
+    fn test_periodic_idle_accounting() {
+        let mut ts = TickSched::new();
+        ts.account_periodic_idle(3);
+        assert_eq!(ts.stats().idle_ms, 30);
+        assert_eq!(ts.restart_tick(0, 0), 0);
+    }
+}
@@ -26,20 +26,19 @@ pub fn delay_ms(ms: u64) {
 
 /// Busy-wait delay for a specified number of microseconds
 ///
-/// This is a very rough approximation and may not be accurate
-/// for very short delays. The timing is not calibrated to actual CPU speed
-/// and should only be used when precise timing is not critical.
+/// Accurate when the TSC is the clock source. Otherwise delays of 1ms
+/// and more wait on the tick, and shorter ones fall back to a rough,
+/// uncalibrated busy loop whose timing varies across hardware.
 ///
 /// # Arguments
 /// * `us` - Number of microseconds to delay
-/// 
-/// # Note
-/// For delays < 1ms, this uses an uncalibrated busy loop and timing
-/// will vary significantly across different hardware.
 pub fn delay_us(us: u64) {
-    // Convert microseconds to milliseconds (rough approximation)
-    // For sub-millisecond delays, we'll do a busy loop
-    if us < 1000 {
+    if super::clocksource::tsc_khz().is_some() {
+        let target = super::clocksource::ktime_ns() + us * 1000;
+        while super::clocksource::ktime_ns() < target {
+            core::hint::spin_loop();
+        }
+    } else if us < 1000 {
         // Very rough busy loop for microseconds
         // Note: This is uncalibrated and will vary by CPU speed
         for _ in 0..(us * 100) {
@@ -129,6 +128,41 @@ pub fn block_for_duration(task_id: TaskId, duration_ms: u64) -> Result<(), &'sta
     Ok(())
 }
 
+/// Move the scheduler tick from the PIT to the per-CPU APIC timers
+///
+/// The boot CPU calibrates the APIC timer against the PIT and starts its
+/// own; application processors start theirs as they come online. If the
+/// APIC timer cannot be used the PIT keeps driving the tick. With the APIC
+/// timer running, the NMI watchdog is started as well.
+pub fn init() {
+    match fanga_arch_x86_64::interrupts::idt::enable_apic_tick() {
+        Ok(mode) => crate::log_info!(target: "time", "Tick source: APIC timer ({:?})", mode),
+        Err(e) => {
+            crate::log_info!(target: "time", "Tick source: PIT ({})", e);
+            return;
+        }
+    }
+
+    // The watchdog watches the per-CPU ticks, so it needs the APIC timer
+    match fanga_arch_x86_64::interrupts::nmi_watchdog::init() {
+        Ok(()) => crate::log_info!(
+            target: "time",
+            "NMI watchdog: {}ms lockup threshold",
+            fanga_arch_x86_64::interrupts::nmi_watchdog::THRESHOLD_MS
+        ),
+        Err(e) => crate::log_warn!(target: "time", "NMI watchdog disabled: {}", e),
+    }
+}
+
+/// Name of the hardware driving the tick
+pub fn tick_source() -> &'static str {
+    if fanga_arch_x86_64::interrupts::apic_timer::is_active() {
+        "apic"
+    } else {
+        "pit"
+    }
+}
+
 /// Get system uptime in milliseconds
 pub fn uptime_ms() -> u64 {
     fanga_arch_x86_64::interrupts::idt::uptime_ms()
@@ -156,6 +190,12 @@ mod tests {
         let _ms = uptime_ms();
         let _secs = uptime_secs();
     }
+
+    #[test]
+    fn test_tick_source() {
+        // The APIC timer is never calibrated on the host
+        assert_eq!(tick_source(), "pit");
+    }
     
     #[test]
     fn test_delay_ms() {
@@ -3,15 +3,25 @@
 //! This module provides the bridge between the arch-specific timer interrupt
 //! and the kernel's scheduler, enabling preemptive multitasking.
 
-use crate::task::sched_timer;
+use crate::task::{sched_timer, softirq};
 
 /// Timer interrupt callback that will be called from the arch timer IRQ handler
 /// 
 /// This function is called on each timer tick and triggers the scheduler
-/// to perform preemptive task switching.
-pub fn timer_callback() {
+/// to perform preemptive task switching. `from_user` tells whether the
+/// tick interrupted user mode, which the tick is charged to.
+pub fn timer_callback(from_user: bool) {
+    crate::random::add_timer_randomness();
+
+    // Timer callbacks (delayed work, etc.) run as a softirq
+    softirq::raise_softirq(softirq::SoftirqType::Timer);
+    crate::smp::rcu::rcu_check_callbacks();
+
     // Call the scheduler's timer-based scheduling logic
-    sched_timer::schedule_on_timer();
+    sched_timer::schedule_on_timer(from_user);
+
+    // Run bottom halves now that the hard IRQ work is done
+    softirq::irq_exit();
 }
 
 /// Initialize the timer interrupt system
@@ -0,0 +1,246 @@
+//! Timer Wheel
+//!
+//! Kernel timers hashed into a wheel of per-tick slots. Adding and
+//! cancelling a timer is cheap; each timer tick only looks at the slots
+//! that elapsed since the last run.
+//!
+//! This module provides:
+//! - One-shot timers with a callback and a data word
+//! - Cancellation by timer ID
+//! - Expiry from the timer softirq (callbacks run without the wheel lock)
+//! - Next-expiry queries for tickless idle
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Number of slots in the wheel
+pub const WHEEL_SLOTS: usize = 256;
+
+/// Time covered by one slot (one timer tick)
+pub const WHEEL_GRANULARITY_MS: u64 = 10;
+
+/// Timer identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    /// Get the raw ID value
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Timer callback, called with the timer's data word
+pub type TimerCallback = fn(u64);
+
+#[derive(Debug, Clone, Copy)]
+struct TimerEntry {
+    id: TimerId,
+    /// Wheel tick at which the timer fires
+    expires: u64,
+    callback: TimerCallback,
+    data: u64,
+}
+
+/// A hashed timer wheel
+pub struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    /// Last wheel tick processed
+    clock: u64,
+    /// Slot of each pending timer
+    pending: BTreeMap<TimerId, usize>,
+    next_id: u64,
+}
+
+impl TimerWheel {
+    /// Create an empty wheel
+    pub const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            clock: 0,
+            pending: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Add a timer firing at `expires_ms` (uptime)
+    ///
+    /// Timers already in the past fire on the next advance.
+    pub fn add(&mut self, expires_ms: u64, callback: TimerCallback, data: u64) -> TimerId {
+        if self.slots.is_empty() {
+            self.slots.resize_with(WHEEL_SLOTS, Vec::new);
+        }
+
+        let expires = expires_ms.div_ceil(WHEEL_GRANULARITY_MS).max(self.clock + 1);
+        let slot = (expires % WHEEL_SLOTS as u64) as usize;
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        self.slots[slot].push(TimerEntry { id, expires, callback, data });
+        self.pending.insert(id, slot);
+        id
+    }
+
+    /// Cancel a pending timer
+    ///
+    /// Returns true if the timer was pending and has been removed.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let Some(slot) = self.pending.remove(&id) else { return false };
+        self.slots[slot].retain(|t| t.id != id);
+        true
+    }
+
+    /// Advance the wheel to `now_ms` and collect expired timers
+    ///
+    /// Returns the callbacks to run, earliest first.
+    pub fn advance(&mut self, now_ms: u64) -> Vec<(TimerCallback, u64)> {
+        let target = now_ms / WHEEL_GRANULARITY_MS;
+        if target <= self.clock || self.slots.is_empty() {
+            self.clock = self.clock.max(target);
+            return Vec::new();
+        }
+
+        // Walk the elapsed slots, or every slot once after a long gap
+        let mut expired: Vec<TimerEntry> = Vec::new();
+        let steps = (target - self.clock).min(WHEEL_SLOTS as u64);
+        for step in 1..=steps {
+            let slot = ((self.clock + step) % WHEEL_SLOTS as u64) as usize;
+            let mut i = 0;
+            while i < self.slots[slot].len() {
+                if self.slots[slot][i].expires <= target {
+                    let entry = self.slots[slot].swap_remove(i);
+                    self.pending.remove(&entry.id);
+                    expired.push(entry);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.clock = target;
+
+        expired.sort_by_key(|t| (t.expires, t.id));
+        expired.into_iter().map(|t| (t.callback, t.data)).collect()
+    }
+
+    /// Earliest expiry (uptime ms) among pending timers
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|t| t.expires * WHEEL_GRANULARITY_MS)
+            .min()
+    }
+
+    /// Number of pending timers
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Check whether no timers are pending
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global timer wheel
+static TIMER_WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// Arm a timer firing `delay_ms` from now
+pub fn add_timer(delay_ms: u64, callback: TimerCallback, data: u64) -> TimerId {
+    let expires = super::time::uptime_ms().saturating_add(delay_ms);
+    TIMER_WHEEL.lock().add(expires, callback, data)
+}
+
+/// Cancel a timer
+///
+/// Returns false if the timer already fired or was never armed.
+pub fn del_timer(id: TimerId) -> bool {
+    TIMER_WHEEL.lock().cancel(id)
+}
+
+/// Run expired timers
+///
+/// Called from the timer softirq, so it never spins on the lock; timers
+/// left behind run on the next tick.
+pub fn run_timers() -> usize {
+    let expired = match TIMER_WHEEL.try_lock() {
+        Some(mut wheel) => wheel.advance(super::time::uptime_ms()),
+        None => return 0,
+    };
+    for (callback, data) in &expired {
+        callback(*data);
+    }
+    expired.len()
+}
+
+/// Earliest timer deadline, if the lock is uncontended
+pub fn next_timer_expiry() -> Option<u64> {
+    TIMER_WHEEL.try_lock()?.next_expiry()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_: u64) {}
+
+    fn data(expired: &[(TimerCallback, u64)]) -> Vec<u64> {
+        expired.iter().map(|(_, d)| *d).collect()
+    }
+
+    #[test]
+    fn test_timers_fire_in_order() {
+        let mut wheel = TimerWheel::new();
+        wheel.add(50, nop, 2);
+        wheel.add(20, nop, 1);
+        wheel.add(300, nop, 3);
+
+        assert!(wheel.advance(10).is_empty());
+        assert_eq!(data(&wheel.advance(60)), alloc::vec![1, 2]);
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(wheel.next_expiry(), Some(300));
+        assert_eq!(data(&wheel.advance(300)), alloc::vec![3]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut wheel = TimerWheel::new();
+        let id = wheel.add(40, nop, 7);
+        assert!(wheel.cancel(id));
+        assert!(!wheel.cancel(id));
+        assert!(wheel.advance(100).is_empty());
+    }
+
+    #[test]
+    fn test_wraparound_and_long_gap() {
+        let mut wheel = TimerWheel::new();
+        // Same slot, different rounds
+        let span = WHEEL_SLOTS as u64 * WHEEL_GRANULARITY_MS;
+        wheel.add(100, nop, 1);
+        wheel.add(100 + span, nop, 2);
+
+        assert_eq!(data(&wheel.advance(100)), alloc::vec![1]);
+        assert_eq!(wheel.len(), 1);
+
+        // Jumping far ahead still finds everything due
+        wheel.add(5 * span, nop, 3);
+        assert_eq!(data(&wheel.advance(10 * span)), alloc::vec![2, 3]);
+    }
+
+    #[test]
+    fn test_past_timer_fires_next_tick() {
+        let mut wheel = TimerWheel::new();
+        wheel.advance(1000);
+        wheel.add(500, nop, 9);
+        assert_eq!(data(&wheel.advance(1010)), alloc::vec![9]);
+    }
+}
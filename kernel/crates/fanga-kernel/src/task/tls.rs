@@ -0,0 +1,292 @@
+//! Thread-Local Storage
+//!
+//! x86_64 uses TLS variant II: a thread's TLS data sits directly below its
+//! thread control block (TCB), and the FS base points at the TCB. The first
+//! TCB word points to itself so `mov rax, fs:0` yields the thread pointer.
+//!
+//! This module provides:
+//! - TLS templates (`.tdata` image, `.tbss` size, alignment) from PT_TLS
+//! - Per-thread TLS blocks for kernel threads
+//! - TLS blocks written into a user address space
+//! - FS base switching on context switch
+//! - arch_prctl(ARCH_SET_FS/ARCH_GET_FS)
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::scheduler::{self, Scheduler};
+use super::sigdeliver::{ActiveUserMemory, UserMemory};
+use super::tcb::TaskId;
+use crate::elf::{ElfProgramHeader, ProgramType};
+use crate::memory::regions::address_space::USER_SPACE_END;
+use crate::memory::VirtAddr;
+use crate::syscall::{EFAULT, EINVAL, ESRCH};
+
+/// Size of the thread control block at the thread pointer
+///
+/// Holds the self pointer at 0x00, the self pointer again at 0x10 (libc
+/// `header.self`) and the stack protector canary at 0x28.
+pub const TCB_SIZE: usize = 64;
+
+/// Minimum alignment of a TLS block
+const MIN_TLS_ALIGN: usize = 16;
+
+/// arch_prctl: set the FS base
+pub const ARCH_SET_FS: i32 = 0x1002;
+/// arch_prctl: get the FS base
+pub const ARCH_GET_FS: i32 = 0x1003;
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Initial TLS contents of a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsTemplate {
+    /// Initialized data (`.tdata`)
+    pub image: Vec<u8>,
+    /// Total size including zero-filled `.tbss`
+    pub mem_size: usize,
+    /// Required alignment (power of two)
+    pub align: usize,
+}
+
+impl TlsTemplate {
+    /// Create a template
+    pub fn new(image: Vec<u8>, mem_size: usize, align: usize) -> Result<Self, &'static str> {
+        let align = align.max(1);
+        if !align.is_power_of_two() {
+            return Err("TLS alignment is not a power of two");
+        }
+        if image.len() > mem_size {
+            return Err("TLS image larger than TLS segment");
+        }
+        Ok(Self { image, mem_size, align })
+    }
+
+    /// Create a template from a PT_TLS program header
+    pub fn from_elf(data: &[u8], phdr: &ElfProgramHeader) -> Result<Self, &'static str> {
+        if phdr.program_type() != Some(ProgramType::Tls) {
+            return Err("Not a PT_TLS segment");
+        }
+        let start = phdr.p_offset as usize;
+        let end = start
+            .checked_add(phdr.p_filesz as usize)
+            .filter(|&end| end <= data.len())
+            .ok_or("PT_TLS segment out of bounds")?;
+        Self::new(data[start..end].to_vec(), phdr.p_memsz as usize, phdr.p_align as usize)
+    }
+
+    /// Distance from the start of the TLS data to the thread pointer
+    pub fn tls_offset(&self) -> usize {
+        align_up(self.mem_size, self.align)
+    }
+
+    /// Alignment of a whole TLS block
+    pub fn block_align(&self) -> usize {
+        self.align.max(MIN_TLS_ALIGN)
+    }
+
+    /// Size of a TLS block (data plus TCB)
+    pub fn block_size(&self) -> usize {
+        self.tls_offset() + TCB_SIZE
+    }
+
+    /// Fill a TLS block that will live at `block_addr`
+    ///
+    /// Returns the thread pointer (the value for the FS base).
+    pub fn init_block(&self, block: &mut [u8], block_addr: u64) -> Result<u64, &'static str> {
+        if block.len() < self.block_size() {
+            return Err("TLS block too small");
+        }
+        if !(block_addr as usize).is_multiple_of(self.block_align()) {
+            return Err("TLS block misaligned");
+        }
+
+        let offset = self.tls_offset();
+        let tp = block_addr + offset as u64;
+        block.fill(0);
+        block[..self.image.len()].copy_from_slice(&self.image);
+        block[offset..offset + 8].copy_from_slice(&tp.to_le_bytes());
+        block[offset + 0x10..offset + 0x18].copy_from_slice(&tp.to_le_bytes());
+        Ok(tp)
+    }
+}
+
+/// A TLS block owned by a kernel thread
+#[derive(Debug)]
+pub struct TlsBlock {
+    storage: Vec<u8>,
+    tp: u64,
+}
+
+impl TlsBlock {
+    /// Allocate and initialize a block from a template
+    pub fn new(template: &TlsTemplate) -> Result<Self, &'static str> {
+        let align = template.block_align();
+        let mut storage = vec![0u8; template.block_size() + align];
+        let addr = storage.as_ptr() as usize;
+        let start = align_up(addr, align) - addr;
+        let tp = template.init_block(&mut storage[start..], (addr + start) as u64)?;
+        Ok(Self { storage, tp })
+    }
+
+    /// Thread pointer (FS base) of this block
+    pub fn thread_pointer(&self) -> VirtAddr {
+        VirtAddr::new(self.tp)
+    }
+
+    /// Bytes allocated for the block
+    pub fn allocated_size(&self) -> usize {
+        self.storage.len()
+    }
+}
+
+/// Write a TLS block into user memory at `base`
+///
+/// Returns the thread pointer for the new thread.
+pub fn setup_user_tls(
+    template: &TlsTemplate,
+    mem: &mut impl UserMemory,
+    base: u64,
+) -> Result<u64, &'static str> {
+    let mut block = vec![0u8; template.block_size()];
+    let tp = template.init_block(&mut block, base)?;
+    mem.write(base, &block)?;
+    Ok(tp)
+}
+
+/// Save the outgoing task's FS base and load the incoming one's
+///
+/// Called by the scheduler on every context switch.
+pub fn switch_fs_base(sched: &mut Scheduler, prev: Option<TaskId>, next: Option<TaskId>) {
+    #[cfg(not(test))]
+    {
+        // User code may have changed FS with WRFSBASE; keep its value
+        if let Some(task) = prev.and_then(|id| sched.get_task_mut(id)) {
+            task.tls_base = VirtAddr::new(fanga_arch_x86_64::tls::read_fs_base());
+        }
+        let base = next
+            .and_then(|id| sched.get_task(id))
+            .map(|task| task.tls_base.as_u64())
+            .unwrap_or(0);
+        unsafe { fanga_arch_x86_64::tls::write_fs_base(base) };
+    }
+    #[cfg(test)]
+    let _ = (sched, prev, next);
+}
+
+/// Set the FS base of a task
+///
+/// Takes effect immediately if the task is running, otherwise at its next
+/// context switch.
+pub fn set_task_fs_base(sched: &mut Scheduler, task: TaskId, base: u64) -> Result<(), &'static str> {
+    if !fanga_arch_x86_64::tls::is_canonical(base) {
+        return Err("Non-canonical FS base");
+    }
+    let running = sched.current_task() == Some(task);
+    let tcb = sched.get_task_mut(task).ok_or("Task not found")?;
+    tcb.tls_base = VirtAddr::new(base);
+
+    #[cfg(not(test))]
+    if running {
+        unsafe { fanga_arch_x86_64::tls::write_fs_base(base) };
+    }
+    #[cfg(test)]
+    let _ = running;
+    Ok(())
+}
+
+/// Handle arch_prctl()
+pub fn sys_arch_prctl(code: i32, addr: u64) -> i64 {
+    let mut sched = scheduler::scheduler();
+    let Some(task) = sched.current_task() else {
+        return ESRCH;
+    };
+
+    match code {
+        ARCH_SET_FS => {
+            if addr >= USER_SPACE_END {
+                return EINVAL;
+            }
+            match set_task_fs_base(&mut sched, task, addr) {
+                Ok(()) => 0,
+                Err(_) => EINVAL,
+            }
+        }
+        ARCH_GET_FS => {
+            let base = sched.get_task(task).map(|t| t.tls_base.as_u64()).unwrap_or(0);
+            drop(sched);
+            match ActiveUserMemory.write(addr, &base.to_le_bytes()) {
+                Ok(()) => 0,
+                Err(_) => EFAULT,
+            }
+        }
+        _ => EINVAL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::PhysAddr;
+    use crate::task::{Task, TaskPriority};
+
+    fn template() -> TlsTemplate {
+        TlsTemplate::new(vec![1, 2, 3, 4], 20, 8).unwrap()
+    }
+
+    #[test]
+    fn test_template_layout() {
+        let t = template();
+        assert_eq!(t.tls_offset(), 24);
+        assert_eq!(t.block_size(), 24 + TCB_SIZE);
+        assert_eq!(t.block_align(), 16);
+        assert!(TlsTemplate::new(vec![0; 8], 4, 8).is_err());
+        assert!(TlsTemplate::new(vec![], 4, 3).is_err());
+    }
+
+    #[test]
+    fn test_init_block_variant2() {
+        let t = template();
+        let mut block = vec![0xFFu8; t.block_size()];
+        let tp = t.init_block(&mut block, 0x1000).unwrap();
+
+        assert_eq!(tp, 0x1000 + 24);
+        assert_eq!(&block[..4], &[1, 2, 3, 4]);
+        assert!(block[4..20].iter().all(|&b| b == 0)); // .tbss
+        assert_eq!(u64::from_le_bytes(block[24..32].try_into().unwrap()), tp);
+        assert_eq!(u64::from_le_bytes(block[40..48].try_into().unwrap()), tp);
+        assert!(t.init_block(&mut block, 0x1008).is_err());
+    }
+
+    #[test]
+    fn test_kernel_tls_block() {
+        let block = TlsBlock::new(&template()).unwrap();
+        let tp = block.thread_pointer().as_u64();
+        assert_eq!(tp % 8, 0);
+        // The self pointer is readable through the thread pointer
+        assert_eq!(unsafe { *(tp as *const u64) }, tp);
+        assert_eq!(unsafe { *((tp - 24) as *const u8) }, 1);
+    }
+
+    #[test]
+    fn test_set_task_fs_base() {
+        let mut sched = Scheduler::new();
+        sched.init();
+        let task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0),
+            TaskPriority::Normal,
+        );
+        let id = sched.add_task(task).unwrap();
+
+        set_task_fs_base(&mut sched, id, 0x7000_0000).unwrap();
+        assert_eq!(sched.get_task(id).unwrap().tls_base, VirtAddr::new(0x7000_0000));
+        assert!(set_task_fs_base(&mut sched, id, 0x8000_0000_0000).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+//! Wait Queues and Timed Waits
+//!
+//! A task waiting for an event blocks in the scheduler and, when a timeout
+//! is given, arms a timer on the timer wheel that wakes it. On wakeup the
+//! task removes itself from the queue it waited on: if it was still there,
+//! nobody woke it and the wait timed out.
+//!
+//! This module provides:
+//! - `WaitQueue` with `sleep_on_timeout()` and wake-one/wake-all
+//! - `wait_event()` on a condition an interrupt handler may make true
+//! - A generic timed wait on any queue behind a `spin::Mutex`
+//! - Timed condition variable and semaphore waits
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use spin::Mutex;
+
+use super::ipc::Semaphore;
+use super::scheduler::{self, Scheduler};
+use super::sync::ConditionVariable;
+use super::tcb::{TaskId, TaskState};
+use super::timer_wheel::{self, TimerId};
+use crate::syscall::ETIMEDOUT;
+
+/// Timeout meaning "wait forever"
+pub const MAX_SCHEDULE_TIMEOUT: u64 = u64::MAX;
+
+/// Outcome of a timed wait
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The event happened (or the task was woken explicitly)
+    Woken,
+    /// The timeout expired first
+    TimedOut,
+}
+
+impl WaitResult {
+    /// Check whether the wait timed out
+    pub fn timed_out(&self) -> bool {
+        *self == WaitResult::TimedOut
+    }
+
+    /// System call return value: 0 or -ETIMEDOUT
+    pub fn to_errno(self) -> i64 {
+        match self {
+            WaitResult::Woken => 0,
+            WaitResult::TimedOut => ETIMEDOUT,
+        }
+    }
+}
+
+/// Wake a blocked task
+///
+/// Returns false if the task was not blocked.
+#[track_caller]
+pub fn wake_task_in(sched: &mut Scheduler, task: TaskId) -> bool {
+    match sched.get_task(task) {
+        Some(t) if t.state == TaskState::Blocked => sched.unblock_task(task).is_ok(),
+        _ => false,
+    }
+}
+
+/// Wake a blocked task using the global scheduler
+#[track_caller]
+pub fn wake_task(task: TaskId) -> bool {
+    wake_task_in(&mut scheduler::scheduler(), task)
+}
+
+/// Timer callback for timed waits
+///
+/// Runs in softirq context; if the scheduler lock is busy the wakeup is
+/// retried on the next tick rather than spinning.
+fn wait_timeout(data: u64) {
+    let task = TaskId::new(data as usize);
+    match scheduler::try_scheduler() {
+        Some(mut sched) => {
+            wake_task_in(&mut sched, task);
+        }
+        None => {
+            timer_wheel::add_timer(0, wait_timeout, data);
+        }
+    }
+}
+
+/// Block `task` and arm a timer that wakes it after `timeout_ms`
+///
+/// Callers hold the lock of the queue they just joined, so a waker cannot
+/// dequeue the task before it is blocked.
+pub fn block_with_timeout(
+    sched: &mut Scheduler,
+    task: TaskId,
+    timeout_ms: u64,
+) -> Result<Option<TimerId>, &'static str> {
+    sched.block_task(task)?;
+    if timeout_ms == MAX_SCHEDULE_TIMEOUT {
+        return Ok(None);
+    }
+    Ok(Some(timer_wheel::add_timer(timeout_ms, wait_timeout, task.as_usize() as u64)))
+}
+
+/// Wait until `task` has been woken
+///
+/// The task is already off its run queue, so it switches away here and
+/// only halts if nothing else was picked.
+fn wait_for_wakeup(task: TaskId) {
+    #[cfg(not(test))]
+    loop {
+        let blocked = scheduler::scheduler()
+            .get_task(task)
+            .map(|t| t.state == TaskState::Blocked)
+            .unwrap_or(false);
+        if !blocked {
+            break;
+        }
+        crate::preempt::points::set_need_resched();
+        if !super::sched_timer::schedule_pending() {
+            fanga_arch_x86_64::interrupts::enable();
+            unsafe { core::arch::asm!("hlt", options(nomem, nostack)) };
+        }
+    }
+    #[cfg(test)]
+    let _ = task;
+}
+
+/// Finish a timed wait
+///
+/// `still_queued` is whether the task was still on the wait queue when it
+/// removed itself, which means nobody woke it.
+pub fn finish_wait(timer: Option<TimerId>, still_queued: bool) -> WaitResult {
+    if let Some(timer) = timer {
+        timer_wheel::del_timer(timer);
+    }
+    if still_queued {
+        WaitResult::TimedOut
+    } else {
+        WaitResult::Woken
+    }
+}
+
+/// Wait on a queue behind `lock` for at most `timeout_ms`
+///
+/// `enqueue` adds the current task to the queue, or returns true if the
+/// event already happened and no wait is needed. `dequeue` removes the
+/// task again and returns whether it was still queued.
+pub fn wait_event_timeout<T>(
+    lock: &Mutex<T>,
+    timeout_ms: u64,
+    enqueue: impl FnOnce(&mut T, TaskId) -> bool,
+    dequeue: impl FnOnce(&mut T, TaskId) -> bool,
+) -> Result<WaitResult, &'static str> {
+    let task = scheduler::scheduler().current_task().ok_or("No current task")?;
+
+    let timer = {
+        let mut queue = lock.lock();
+        if enqueue(&mut queue, task) {
+            return Ok(WaitResult::Woken);
+        }
+        match block_with_timeout(&mut scheduler::scheduler(), task, timeout_ms) {
+            Ok(timer) => timer,
+            Err(e) => {
+                dequeue(&mut queue, task);
+                return Err(e);
+            }
+        }
+    };
+
+    wait_for_wakeup(task);
+    let still_queued = dequeue(&mut lock.lock(), task);
+    Ok(finish_wait(timer, still_queued))
+}
+
+/// A queue of tasks waiting for an event
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    /// Create an empty wait queue
+    pub const fn new() -> Self {
+        Self { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Add a task to the queue
+    pub fn add(&self, task: TaskId) {
+        self.waiters.lock().push_back(task);
+    }
+
+    /// Remove a task from the queue
+    ///
+    /// Returns true if the task was queued.
+    pub fn remove(&self, task: TaskId) -> bool {
+        remove_task(&mut self.waiters.lock(), task)
+    }
+
+    /// Wake the first waiter
+    pub fn wake_up_one(&self) -> Option<TaskId> {
+        let task = self.waiters.lock().pop_front()?;
+        wake_task(task);
+        Some(task)
+    }
+
+    /// Wake the first waiter unless the queue is locked
+    ///
+    /// For interrupt context, which must not spin on a lock the task it
+    /// interrupted may hold.
+    pub fn try_wake_up_one(&self) -> Option<TaskId> {
+        let task = self.waiters.try_lock()?.pop_front()?;
+        wake_task(task);
+        Some(task)
+    }
+
+    /// Wake every waiter, returning how many were woken
+    pub fn wake_up_all(&self) -> usize {
+        let tasks: VecDeque<TaskId> = core::mem::take(&mut *self.waiters.lock());
+        for &task in &tasks {
+            wake_task(task);
+        }
+        tasks.len()
+    }
+
+    /// Number of waiting tasks
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    /// Check whether nobody is waiting
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn remove_task(queue: &mut VecDeque<TaskId>, task: TaskId) -> bool {
+    match queue.iter().position(|&t| t == task) {
+        Some(pos) => {
+            queue.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sleep on a wait queue until woken or `timeout_ms` elapses
+pub fn sleep_on_timeout(wq: &WaitQueue, timeout_ms: u64) -> Result<WaitResult, &'static str> {
+    wait_event_timeout(
+        &wq.waiters,
+        timeout_ms,
+        |queue, task| {
+            queue.push_back(task);
+            false
+        },
+        remove_task,
+    )
+}
+
+/// Sleep on a wait queue until woken
+pub fn sleep_on(wq: &WaitQueue) -> Result<(), &'static str> {
+    sleep_on_timeout(wq, MAX_SCHEDULE_TIMEOUT).map(|_| ())
+}
+
+/// Sleep on a wait queue until `condition` holds
+///
+/// The condition is checked and the task queued with interrupts off, so
+/// an interrupt handler that makes it true and then wakes the queue with
+/// `try_wake_up_one` cannot be missed.
+pub fn wait_event(wq: &WaitQueue, mut condition: impl FnMut() -> bool) -> Result<(), &'static str> {
+    while !condition() {
+        let task = scheduler::scheduler().current_task().ok_or("No current task")?;
+        let blocked = super::softirq::with_irqs_off(|| {
+            let mut waiters = wq.waiters.lock();
+            if condition() {
+                return Ok(false);
+            }
+            waiters.push_back(task);
+            scheduler::scheduler().block_task(task).map(|()| true).inspect_err(|_| {
+                waiters.pop_back();
+            })
+        })?;
+        if blocked {
+            wait_for_wakeup(task);
+            super::softirq::with_irqs_off(|| wq.remove(task));
+        }
+    }
+    Ok(())
+}
+
+/// Wait on a condition variable for at most `timeout_ms`
+///
+/// The signaller must wake the task returned by `signal()`/`broadcast()`
+/// with `wake_task()`.
+pub fn cond_wait_timeout(cv: &Mutex<ConditionVariable>, timeout_ms: u64) -> Result<WaitResult, &'static str> {
+    wait_event_timeout(
+        cv,
+        timeout_ms,
+        |cv, task| {
+            cv.wait(task);
+            false
+        },
+        |cv, task| cv.remove_waiter(task),
+    )
+}
+
+/// Decrement a semaphore, waiting at most `timeout_ms` for it
+///
+/// On `Woken` the caller owns the unit handed over by `signal()`.
+pub fn sem_wait_timeout(sem: &Mutex<Semaphore>, timeout_ms: u64) -> Result<WaitResult, &'static str> {
+    wait_event_timeout(sem, timeout_ms, |sem, task| sem.wait(task), |sem, task| sem.remove_waiter(task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{PhysAddr, VirtAddr};
+    use crate::task::{Task, TaskPriority};
+
+    fn sched_with_task() -> (Scheduler, TaskId) {
+        let mut sched = Scheduler::new();
+        sched.init();
+        let task = Task::new(
+            TaskId::new(0),
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x2000),
+            4096,
+            PhysAddr::new(0),
+            TaskPriority::Normal,
+        );
+        let id = sched.add_task(task).unwrap();
+        (sched, id)
+    }
+
+    #[test]
+    fn test_timeout_path() {
+        let (mut sched, id) = sched_with_task();
+        let wq = WaitQueue::new();
+
+        wq.add(id);
+        let timer = block_with_timeout(&mut sched, id, 50).unwrap();
+        assert!(timer.is_some());
+        assert_eq!(sched.get_task(id).unwrap().state, TaskState::Blocked);
+
+        // The timer fires and wakes the task, which is still queued
+        assert!(wake_task_in(&mut sched, id));
+        assert_eq!(finish_wait(timer, wq.remove(id)), WaitResult::TimedOut);
+        assert_eq!(WaitResult::TimedOut.to_errno(), ETIMEDOUT);
+    }
+
+    #[test]
+    fn test_woken_path() {
+        let (mut sched, id) = sched_with_task();
+        let wq = WaitQueue::new();
+
+        wq.add(id);
+        let timer = block_with_timeout(&mut sched, id, 50).unwrap();
+
+        // A waker dequeues the task before the timeout
+        let woken = wq.waiters.lock().pop_front();
+        assert_eq!(woken, Some(id));
+        assert!(wake_task_in(&mut sched, id));
+        assert!(!wake_task_in(&mut sched, id)); // already runnable
+
+        assert_eq!(finish_wait(timer, wq.remove(id)), WaitResult::Woken);
+        assert!(!timer_wheel::del_timer(timer.unwrap())); // cancelled
+    }
+
+    #[test]
+    fn test_semaphore_handoff_beats_timeout() {
+        let mut sem = Semaphore::new(0);
+        let task = TaskId::new(7);
+        assert!(!sem.wait(task));
+        // signal() hands the unit to the waiter even if its timer fires too
+        assert_eq!(sem.signal(), Some(task));
+        assert!(!sem.remove_waiter(task));
+        assert_eq!(sem.value(), 0);
+    }
+
+    #[test]
+    fn test_wait_event_and_try_wake() {
+        let wq = WaitQueue::new();
+        assert_eq!(wait_event(&wq, || true), Ok(()));
+
+        // A locked queue is left alone rather than spun on
+        wq.add(TaskId::new(7));
+        let waiters = wq.waiters.lock();
+        assert_eq!(wq.try_wake_up_one(), None);
+        drop(waiters);
+        assert_eq!(wq.len(), 1);
+    }
+}
@@ -0,0 +1,826 @@
+//! Kernel Workqueues
+//!
+//! This module provides deferred, process-context execution of work items:
+//! - Work items (function + argument) queued for later execution
+//! - Delayed work that becomes runnable after a timeout
+//! - Per-CPU worker pools for CPU-local work
+//! - An unbound worker pool for work that may run anywhere
+//! - System-wide default queues (`system_wq`, `system_unbound_wq`)
+//!
+//! Drivers and subsystems (cache flushing, USB hotplug, network timers)
+//! should queue work here instead of running their own background loops.
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::kthread;
+use super::scheduler::{self, Scheduler};
+use super::tcb::TaskId;
+use super::wait;
+
+/// Work function signature
+///
+/// Work functions receive the opaque argument stored in the work item.
+pub type WorkFn = fn(usize);
+
+/// Maximum number of workqueues that can be created
+pub const MAX_WORKQUEUES: usize = 32;
+
+/// Maximum number of per-CPU pools managed by the subsystem
+pub const MAX_CPU_POOLS: usize = 64;
+
+/// Maximum pending items per pool
+pub const MAX_PENDING_WORK: usize = 256;
+
+/// Default number of items a worker runs before yielding
+pub const DEFAULT_WORKER_BUDGET: usize = 16;
+
+/// How long an idle worker sleeps before checking whether it should stop
+pub const WORKER_IDLE_TIMEOUT_MS: u64 = 1000;
+
+/// Workqueue identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WorkQueueId(pub usize);
+
+/// Identifier of a pending delayed work item (used for cancellation)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DelayedWorkId(pub u64);
+
+/// A unit of deferred work
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    /// Function to execute
+    pub func: WorkFn,
+    /// Argument passed to the function
+    pub data: usize,
+}
+
+impl Work {
+    /// Create a new work item
+    pub const fn new(func: WorkFn, data: usize) -> Self {
+        Self { func, data }
+    }
+
+    /// Execute the work item
+    pub fn run(&self) {
+        (self.func)(self.data);
+    }
+}
+
+/// Which pool a workqueue dispatches to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkQueueKind {
+    /// Work runs on the CPU it was queued from (or the requested CPU)
+    PerCpu,
+    /// Work runs on the shared unbound pool
+    Unbound,
+}
+
+/// A named workqueue
+#[derive(Debug, Clone)]
+pub struct WorkQueue {
+    /// Queue identifier
+    pub id: WorkQueueId,
+    /// Queue name (for diagnostics)
+    pub name: String,
+    /// Pool selection
+    pub kind: WorkQueueKind,
+    /// Number of items queued over the lifetime of the queue
+    pub queued: u64,
+    /// Number of items executed over the lifetime of the queue
+    pub executed: u64,
+}
+
+/// Target pool of a queued item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoolTarget {
+    Cpu(usize),
+    Unbound,
+}
+
+/// Work item sitting in a pool
+#[derive(Debug, Clone, Copy)]
+struct PendingWork {
+    queue: WorkQueueId,
+    work: Work,
+}
+
+/// Delayed work waiting for its timeout
+#[derive(Debug, Clone, Copy)]
+struct DelayedWork {
+    id: DelayedWorkId,
+    queue: WorkQueueId,
+    target: PoolTarget,
+    work: Work,
+    /// Uptime (ms) after which the work becomes runnable
+    expires_ms: u64,
+}
+
+/// Worker state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerState {
+    /// Waiting for work
+    Idle,
+    /// Executing a work item
+    Busy,
+}
+
+/// A pool of worker threads serving one CPU (or the unbound pool)
+#[derive(Debug)]
+pub struct WorkerPool {
+    /// CPU this pool is bound to (None for the unbound pool)
+    pub cpu: Option<usize>,
+    /// Worker tasks attached to this pool
+    pub workers: Vec<TaskId>,
+    /// Workers sleeping until work arrives
+    idle: VecDeque<TaskId>,
+    /// Current worker state
+    pub state: WorkerState,
+    /// Pending work
+    pending: VecDeque<PendingWork>,
+    /// Items executed by this pool
+    pub executed: u64,
+}
+
+impl WorkerPool {
+    fn new(cpu: Option<usize>) -> Self {
+        Self {
+            cpu,
+            workers: Vec::new(),
+            idle: VecDeque::new(),
+            state: WorkerState::Idle,
+            pending: VecDeque::new(),
+            executed: 0,
+        }
+    }
+
+    /// Number of items waiting in this pool
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of workers sleeping until work arrives
+    pub fn idle_count(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Workqueue statistics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkqueueStats {
+    pub queues: usize,
+    pub pending: usize,
+    pub delayed: usize,
+    pub executed: u64,
+}
+
+/// Workqueue subsystem state
+pub struct WorkqueueSystem {
+    queues: Vec<Option<WorkQueue>>,
+    cpu_pools: Vec<WorkerPool>,
+    unbound_pool: WorkerPool,
+    delayed: Vec<DelayedWork>,
+    next_delayed_id: u64,
+}
+
+impl WorkqueueSystem {
+    /// Create a workqueue system with one pool per CPU plus an unbound pool
+    pub fn new(cpu_count: usize) -> Self {
+        let cpu_count = cpu_count.clamp(1, MAX_CPU_POOLS);
+        let mut queues = Vec::new();
+        queues.resize_with(MAX_WORKQUEUES, || None);
+
+        Self {
+            queues,
+            cpu_pools: (0..cpu_count).map(|cpu| WorkerPool::new(Some(cpu))).collect(),
+            unbound_pool: WorkerPool::new(None),
+            delayed: Vec::new(),
+            next_delayed_id: 1,
+        }
+    }
+
+    /// Number of per-CPU pools
+    pub fn cpu_count(&self) -> usize {
+        self.cpu_pools.len()
+    }
+
+    /// Create a new workqueue
+    pub fn create_workqueue(&mut self, name: &str, kind: WorkQueueKind) -> Result<WorkQueueId, &'static str> {
+        let slot = self
+            .queues
+            .iter()
+            .position(|q| q.is_none())
+            .ok_or("Maximum number of workqueues reached")?;
+
+        let id = WorkQueueId(slot);
+        self.queues[slot] = Some(WorkQueue {
+            id,
+            name: String::from(name),
+            kind,
+            queued: 0,
+            executed: 0,
+        });
+        Ok(id)
+    }
+
+    /// Destroy a workqueue, dropping any work still queued on it
+    pub fn destroy_workqueue(&mut self, id: WorkQueueId) -> Result<(), &'static str> {
+        self.get_queue(id)?;
+        self.queues[id.0] = None;
+
+        for pool in self.cpu_pools.iter_mut().chain(core::iter::once(&mut self.unbound_pool)) {
+            pool.pending.retain(|p| p.queue != id);
+        }
+        self.delayed.retain(|d| d.queue != id);
+        Ok(())
+    }
+
+    /// Look up a workqueue
+    pub fn get_queue(&self, id: WorkQueueId) -> Result<&WorkQueue, &'static str> {
+        self.queues
+            .get(id.0)
+            .and_then(|q| q.as_ref())
+            .ok_or("Workqueue not found")
+    }
+
+    fn resolve_target(&self, id: WorkQueueId, cpu: usize) -> Result<PoolTarget, &'static str> {
+        match self.get_queue(id)?.kind {
+            WorkQueueKind::Unbound => Ok(PoolTarget::Unbound),
+            WorkQueueKind::PerCpu => {
+                if cpu >= self.cpu_pools.len() {
+                    return Err("Invalid CPU for workqueue");
+                }
+                Ok(PoolTarget::Cpu(cpu))
+            }
+        }
+    }
+
+    fn pool_mut(&mut self, target: PoolTarget) -> &mut WorkerPool {
+        match target {
+            PoolTarget::Cpu(cpu) => &mut self.cpu_pools[cpu],
+            PoolTarget::Unbound => &mut self.unbound_pool,
+        }
+    }
+
+    fn enqueue(&mut self, target: PoolTarget, queue: WorkQueueId, work: Work) -> Result<(), &'static str> {
+        let pool = self.pool_mut(target);
+        if pool.pending.len() >= MAX_PENDING_WORK {
+            return Err("Workqueue pool is full");
+        }
+        pool.pending.push_back(PendingWork { queue, work });
+
+        if let Some(Some(q)) = self.queues.get_mut(queue.0) {
+            q.queued += 1;
+        }
+        Ok(())
+    }
+
+    /// Queue work on a specific CPU's pool (unbound queues ignore `cpu`)
+    pub fn queue_work_on(&mut self, cpu: usize, id: WorkQueueId, work: Work) -> Result<(), &'static str> {
+        let target = self.resolve_target(id, cpu)?;
+        self.enqueue(target, id, work)
+    }
+
+    /// Queue work to run after `delay_ms` milliseconds of uptime
+    pub fn queue_delayed_work_on(
+        &mut self,
+        cpu: usize,
+        id: WorkQueueId,
+        work: Work,
+        now_ms: u64,
+        delay_ms: u64,
+    ) -> Result<DelayedWorkId, &'static str> {
+        let target = self.resolve_target(id, cpu)?;
+
+        let delayed_id = DelayedWorkId(self.next_delayed_id);
+        self.next_delayed_id += 1;
+
+        self.delayed.push(DelayedWork {
+            id: delayed_id,
+            queue: id,
+            target,
+            work,
+            expires_ms: now_ms.saturating_add(delay_ms),
+        });
+        Ok(delayed_id)
+    }
+
+    /// Cancel delayed work that has not fired yet
+    ///
+    /// Returns true if the work was pending and has been removed.
+    pub fn cancel_delayed_work(&mut self, id: DelayedWorkId) -> bool {
+        let before = self.delayed.len();
+        self.delayed.retain(|d| d.id != id);
+        self.delayed.len() != before
+    }
+
+    /// Move expired delayed work onto its pool
+    ///
+    /// Returns the number of items that became runnable.
+    pub fn tick(&mut self, now_ms: u64) -> usize {
+        let mut fired = 0;
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].expires_ms <= now_ms {
+                let d = self.delayed.swap_remove(i);
+                if self.enqueue(d.target, d.queue, d.work).is_ok() {
+                    fired += 1;
+                } else {
+                    // Pool is full; retry on the next tick
+                    self.delayed.push(d);
+                    break;
+                }
+            } else {
+                i += 1;
+            }
+        }
+        fired
+    }
+
+    /// Earliest expiry (uptime ms) among pending delayed work
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.delayed.iter().map(|d| d.expires_ms).min()
+    }
+
+    fn take_next(&mut self, target: PoolTarget) -> Option<PendingWork> {
+        let pool = self.pool_mut(target);
+        let item = pool.pending.pop_front();
+        pool.state = if item.is_some() { WorkerState::Busy } else { WorkerState::Idle };
+        item
+    }
+
+    fn complete(&mut self, target: PoolTarget, queue: WorkQueueId) {
+        let pool = self.pool_mut(target);
+        pool.executed += 1;
+        pool.state = WorkerState::Idle;
+        if let Some(Some(q)) = self.queues.get_mut(queue.0) {
+            q.executed += 1;
+        }
+    }
+
+    /// Take the next item of one workqueue from a pool
+    fn take_queued(&mut self, target: PoolTarget, id: WorkQueueId) -> Option<PendingWork> {
+        let pool = self.pool_mut(target);
+        let pos = pool.pending.iter().position(|p| p.queue == id)?;
+        pool.pending.remove(pos)
+    }
+
+    fn pool_target(&self, cpu: Option<usize>) -> Option<PoolTarget> {
+        match cpu {
+            Some(c) if c < self.cpu_pools.len() => Some(PoolTarget::Cpu(c)),
+            Some(_) => None,
+            None => Some(PoolTarget::Unbound),
+        }
+    }
+
+    /// Every pool, per-CPU pools first
+    fn targets(&self) -> impl Iterator<Item = PoolTarget> {
+        (0..self.cpu_pools.len()).map(PoolTarget::Cpu).chain(core::iter::once(PoolTarget::Unbound))
+    }
+
+    /// The pool a worker task is attached to
+    fn worker_target(&self, task: TaskId) -> Option<PoolTarget> {
+        self.targets().find(|&target| match target {
+            PoolTarget::Cpu(cpu) => self.cpu_pools[cpu].workers.contains(&task),
+            PoolTarget::Unbound => self.unbound_pool.workers.contains(&task),
+        })
+    }
+
+    /// Put a worker to sleep on its pool
+    ///
+    /// Returns true instead if the pool already has work.
+    fn park_worker(&mut self, target: PoolTarget, task: TaskId) -> bool {
+        let pool = self.pool_mut(target);
+        if !pool.pending.is_empty() {
+            return true;
+        }
+        pool.idle.push_back(task);
+        false
+    }
+
+    /// Take a worker off its pool's idle list
+    ///
+    /// Returns true if it was still there, i.e. nobody woke it.
+    fn unpark_worker(&mut self, target: PoolTarget, task: TaskId) -> bool {
+        let idle = &mut self.pool_mut(target).idle;
+        match idle.iter().position(|&t| t == task) {
+            Some(pos) => {
+                idle.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake one idle worker of every pool that has pending work
+    ///
+    /// Returns the number of workers woken.
+    pub fn wake_idle_workers(&mut self, sched: &mut Scheduler) -> usize {
+        let targets: Vec<PoolTarget> = self.targets().collect();
+        let mut woken = 0;
+        for target in targets {
+            let pool = self.pool_mut(target);
+            if pool.pending.is_empty() {
+                continue;
+            }
+            if let Some(task) = pool.idle.pop_front() {
+                wait::wake_task_in(sched, task);
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// Attach a worker task to a pool
+    pub fn attach_worker(&mut self, cpu: Option<usize>, task: TaskId) -> Result<(), &'static str> {
+        let pool = match cpu {
+            Some(c) => self.cpu_pools.get_mut(c).ok_or("Invalid CPU for worker")?,
+            None => &mut self.unbound_pool,
+        };
+        pool.workers.push(task);
+        Ok(())
+    }
+
+    /// Access a per-CPU pool
+    pub fn cpu_pool(&self, cpu: usize) -> Option<&WorkerPool> {
+        self.cpu_pools.get(cpu)
+    }
+
+    /// Access the unbound pool
+    pub fn unbound_pool(&self) -> &WorkerPool {
+        &self.unbound_pool
+    }
+
+    /// Collect statistics
+    pub fn stats(&self) -> WorkqueueStats {
+        let pools = self.cpu_pools.iter().chain(core::iter::once(&self.unbound_pool));
+        let (pending, executed) = pools.fold((0, 0), |(p, e), pool| {
+            (p + pool.pending.len(), e + pool.executed)
+        });
+
+        WorkqueueStats {
+            queues: self.queues.iter().filter(|q| q.is_some()).count(),
+            pending,
+            delayed: self.delayed.len(),
+            executed,
+        }
+    }
+}
+
+/// Global workqueue subsystem
+static WORKQUEUES: spin::Once<Mutex<WorkqueueSystem>> = spin::Once::new();
+
+/// System-wide per-CPU workqueue
+static SYSTEM_WQ: spin::Once<WorkQueueId> = spin::Once::new();
+
+/// System-wide unbound workqueue
+static SYSTEM_UNBOUND_WQ: spin::Once<WorkQueueId> = spin::Once::new();
+
+/// Initialize the workqueue subsystem
+///
+/// Creates the per-CPU and unbound pools, the default system queues and
+/// one worker task per pool.
+pub fn init() {
+    let cpu_count = crate::smp::cpu::cpu_count();
+
+    WORKQUEUES.call_once(|| Mutex::new(WorkqueueSystem::new(cpu_count)));
+
+    let mut wq = workqueues();
+    if let Ok(id) = wq.create_workqueue("events", WorkQueueKind::PerCpu) {
+        SYSTEM_WQ.call_once(|| id);
+    }
+    if let Ok(id) = wq.create_workqueue("events_unbound", WorkQueueKind::Unbound) {
+        SYSTEM_UNBOUND_WQ.call_once(|| id);
+    }
+
+    for cpu in 0..wq.cpu_count() {
+        if let Ok(task) = spawn_worker(&alloc::format!("kworker/{}", cpu)) {
+            // A per-CPU pool's worker runs on that CPU only
+            if let Some(mask) = 1u64.checked_shl(cpu as u32) {
+                let _ = scheduler::scheduler().set_cpus_allowed(task, mask);
+            }
+            let _ = wq.attach_worker(Some(cpu), task);
+        }
+    }
+    if let Ok(task) = spawn_worker("kworker/u") {
+        let _ = wq.attach_worker(None, task);
+    }
+}
+
+/// Create a worker kernel thread running `worker_main`
+fn spawn_worker(name: &str) -> Result<TaskId, &'static str> {
+    kthread::spawn(name, worker_main)
+}
+
+/// Get the global workqueue subsystem
+///
+/// # Panics
+/// Panics if the subsystem has not been initialized
+pub fn workqueues() -> spin::MutexGuard<'static, WorkqueueSystem> {
+    WORKQUEUES.get().expect("Workqueues not initialized").lock()
+}
+
+/// The default per-CPU workqueue
+pub fn system_wq() -> Option<WorkQueueId> {
+    SYSTEM_WQ.get().copied()
+}
+
+/// The default unbound workqueue
+pub fn system_unbound_wq() -> Option<WorkQueueId> {
+    SYSTEM_UNBOUND_WQ.get().copied()
+}
+
+/// Queue work on the current CPU
+pub fn queue_work(wq: WorkQueueId, work: Work) -> Result<(), &'static str> {
+    let cpu = crate::smp::cpu::current_cpu_id().as_usize();
+    let mut wqs = workqueues();
+    wqs.queue_work_on(cpu, wq, work)?;
+    wqs.wake_idle_workers(&mut scheduler::scheduler());
+    Ok(())
+}
+
+/// Queue delayed work on the current CPU
+pub fn queue_delayed_work(wq: WorkQueueId, work: Work, delay_ms: u64) -> Result<DelayedWorkId, &'static str> {
+    let cpu = crate::smp::cpu::current_cpu_id().as_usize();
+    let now = super::time::uptime_ms();
+    workqueues().queue_delayed_work_on(cpu, wq, work, now, delay_ms)
+}
+
+/// Run every item currently queued on a workqueue, on all pools
+///
+/// Delayed work that has not expired is not flushed.
+pub fn flush_workqueue(wq: WorkQueueId) -> Result<usize, &'static str> {
+    flush_workqueue_in(WORKQUEUES.get().ok_or("Workqueues not initialized")?, wq)
+}
+
+/// Queue work on the system workqueue
+pub fn schedule_work(work: Work) -> Result<(), &'static str> {
+    queue_work(system_wq().ok_or("Workqueues not initialized")?, work)
+}
+
+/// Queue delayed work on the system workqueue
+pub fn schedule_delayed_work(work: Work, delay_ms: u64) -> Result<DelayedWorkId, &'static str> {
+    queue_delayed_work(system_wq().ok_or("Workqueues not initialized")?, work, delay_ms)
+}
+
+/// Timer tick hook: promote expired delayed work and wake its workers
+///
+/// Called from the timer softirq, so it never spins on a lock. A worker
+/// that could not be woken stays idle with work pending and is woken on
+/// a later tick.
+pub fn timer_tick() {
+    if let Some(wq) = WORKQUEUES.get() {
+        if let Some(mut wq) = wq.try_lock() {
+            wq.tick(super::time::uptime_ms());
+            if let Some(mut sched) = scheduler::try_scheduler() {
+                wq.wake_idle_workers(&mut sched);
+            }
+        }
+    }
+}
+
+/// Earliest delayed-work deadline
+///
+/// Used by tickless idle to decide how long the tick may be stopped. If
+/// the lock is contended the deadline is unknown, so the next tick is
+/// reported rather than none.
+pub fn next_delayed_expiry() -> Option<u64> {
+    let wq = WORKQUEUES.get()?;
+    match wq.try_lock() {
+        Some(wq) => wq.next_expiry(),
+        None => Some(super::time::uptime_ms() + super::tickless::TICK_MS),
+    }
+}
+
+/// Run up to `budget` items from a CPU pool (or the unbound pool if `cpu` is None)
+///
+/// Each item is taken under the subsystem lock and run with the lock
+/// dropped, so work functions may queue further work. Returns the number
+/// of items executed.
+pub fn run_pool_in(wq: &Mutex<WorkqueueSystem>, cpu: Option<usize>, budget: usize) -> usize {
+    let Some(target) = wq.lock().pool_target(cpu) else { return 0 };
+
+    let mut ran = 0;
+    while ran < budget {
+        let Some(item) = wq.lock().take_next(target) else { break };
+        item.work.run();
+        wq.lock().complete(target, item.queue);
+        ran += 1;
+    }
+    ran
+}
+
+/// Run every item currently queued on a workqueue, on all pools
+///
+/// Like `run_pool_in`, items run with the subsystem lock dropped.
+pub fn flush_workqueue_in(wq: &Mutex<WorkqueueSystem>, id: WorkQueueId) -> Result<usize, &'static str> {
+    let targets: Vec<PoolTarget> = {
+        let guard = wq.lock();
+        guard.get_queue(id)?;
+        guard.targets().collect()
+    };
+
+    let mut ran = 0;
+    for target in targets {
+        loop {
+            let Some(item) = wq.lock().take_queued(target, id) else { break };
+            item.work.run();
+            wq.lock().complete(target, id);
+            ran += 1;
+        }
+    }
+    Ok(ran)
+}
+
+/// Run pending work for the current CPU and the unbound pool once
+///
+/// Returns the number of items executed.
+pub fn run_pending() -> usize {
+    let Some(wq) = WORKQUEUES.get() else { return 0 };
+    let cpu = crate::smp::cpu::current_cpu_id().as_usize();
+
+    run_pool_in(wq, Some(cpu), DEFAULT_WORKER_BUDGET) + run_pool_in(wq, None, DEFAULT_WORKER_BUDGET)
+}
+
+/// Worker thread body
+///
+/// Runs the work of the pool the worker is attached to, and sleeps on the
+/// pool while it is empty.
+fn worker_main() {
+    let Some(wq) = WORKQUEUES.get() else { return };
+    let Some(task) = scheduler::scheduler().current_task() else { return };
+    // `init` attaches the worker before it releases the lock
+    let Some(target) = wq.lock().worker_target(task) else { return };
+    let cpu = match target {
+        PoolTarget::Cpu(cpu) => Some(cpu),
+        PoolTarget::Unbound => None,
+    };
+
+    while !kthread::should_stop() {
+        if run_pool_in(wq, cpu, DEFAULT_WORKER_BUDGET) == 0 {
+            let _ = wait::wait_event_timeout(
+                wq,
+                WORKER_IDLE_TIMEOUT_MS,
+                |wq, task| wq.park_worker(target, task),
+                |wq, task| wq.unpark_worker(target, task),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn add_to_counter(data: usize) {
+        let counter = unsafe { &*(data as *const AtomicUsize) };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn work_for(counter: &AtomicUsize) -> Work {
+        Work::new(add_to_counter, counter as *const AtomicUsize as usize)
+    }
+
+    static UNLOCKED_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_if_unlocked(data: usize) {
+        let wq = unsafe { &*(data as *const Mutex<WorkqueueSystem>) };
+        if wq.try_lock().is_some() {
+            UNLOCKED_RUNS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_queue_and_run_work() {
+        let counter = AtomicUsize::new(0);
+        let wq = Mutex::new(WorkqueueSystem::new(2));
+        let q = wq.lock().create_workqueue("test", WorkQueueKind::PerCpu).unwrap();
+
+        wq.lock().queue_work_on(1, q, work_for(&counter)).unwrap();
+        wq.lock().queue_work_on(1, q, work_for(&counter)).unwrap();
+        assert_eq!(wq.lock().cpu_pool(1).unwrap().pending_count(), 2);
+        assert_eq!(wq.lock().cpu_pool(0).unwrap().pending_count(), 0);
+
+        assert_eq!(run_pool_in(&wq, Some(0), 16), 0);
+        assert_eq!(run_pool_in(&wq, Some(1), 16), 2);
+        assert_eq!(run_pool_in(&wq, Some(2), 16), 0);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert_eq!(wq.lock().get_queue(q).unwrap().executed, 2);
+    }
+
+    #[test]
+    fn test_work_runs_unlocked() {
+        let wq = Mutex::new(WorkqueueSystem::new(1));
+        let q = wq.lock().create_workqueue("unlocked", WorkQueueKind::PerCpu).unwrap();
+        let work = Work::new(count_if_unlocked, &wq as *const Mutex<WorkqueueSystem> as usize);
+
+        wq.lock().queue_work_on(0, q, work).unwrap();
+        wq.lock().queue_work_on(0, q, work).unwrap();
+        assert_eq!(run_pool_in(&wq, Some(0), 1), 1);
+        assert_eq!(flush_workqueue_in(&wq, q).unwrap(), 1);
+        assert_eq!(UNLOCKED_RUNS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_unbound_queue() {
+        let counter = AtomicUsize::new(0);
+        let wq = Mutex::new(WorkqueueSystem::new(1));
+        let q = wq.lock().create_workqueue("unbound", WorkQueueKind::Unbound).unwrap();
+
+        // The CPU argument is ignored for unbound queues
+        wq.lock().queue_work_on(7, q, work_for(&counter)).unwrap();
+        assert_eq!(wq.lock().unbound_pool().pending_count(), 1);
+
+        run_pool_in(&wq, None, 16);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_delayed_work() {
+        let counter = AtomicUsize::new(0);
+        let wq = Mutex::new(WorkqueueSystem::new(1));
+        let q = wq.lock().create_workqueue("delayed", WorkQueueKind::PerCpu).unwrap();
+
+        wq.lock().queue_delayed_work_on(0, q, work_for(&counter), 1000, 50).unwrap();
+        assert_eq!(wq.lock().next_expiry(), Some(1050));
+        assert_eq!(wq.lock().tick(1049), 0);
+        assert_eq!(wq.lock().stats().delayed, 1);
+
+        assert_eq!(wq.lock().tick(1050), 1);
+        assert_eq!(wq.lock().stats().delayed, 0);
+        run_pool_in(&wq, Some(0), 16);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_idle_workers() {
+        let counter = AtomicUsize::new(0);
+        let mut wq = WorkqueueSystem::new(2);
+        let q = wq.create_workqueue("idle", WorkQueueKind::PerCpu).unwrap();
+        let worker = TaskId::new(42);
+        wq.attach_worker(Some(1), worker).unwrap();
+        let target = wq.worker_target(worker).unwrap();
+        assert_eq!(target, PoolTarget::Cpu(1));
+
+        assert!(!wq.park_worker(target, worker));
+        assert_eq!(wq.cpu_pool(1).unwrap().idle_count(), 1);
+
+        // Nothing pending: the worker keeps sleeping
+        let mut sched = Scheduler::new();
+        assert_eq!(wq.wake_idle_workers(&mut sched), 0);
+
+        wq.queue_work_on(1, q, work_for(&counter)).unwrap();
+        assert_eq!(wq.wake_idle_workers(&mut sched), 1);
+        assert!(!wq.unpark_worker(target, worker));
+
+        // With work pending a worker does not go to sleep at all
+        assert!(wq.park_worker(target, worker));
+        assert_eq!(wq.cpu_pool(1).unwrap().idle_count(), 0);
+    }
+
+    #[test]
+    fn test_cancel_delayed_work() {
+        let counter = AtomicUsize::new(0);
+        let mut wq = WorkqueueSystem::new(1);
+        let q = wq.create_workqueue("cancel", WorkQueueKind::PerCpu).unwrap();
+
+        let id = wq.queue_delayed_work_on(0, q, work_for(&counter), 0, 10).unwrap();
+        assert!(wq.cancel_delayed_work(id));
+        assert!(!wq.cancel_delayed_work(id));
+        assert_eq!(wq.tick(100), 0);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_flush_and_destroy() {
+        let counter = AtomicUsize::new(0);
+        let wq = Mutex::new(WorkqueueSystem::new(2));
+        let a = wq.lock().create_workqueue("a", WorkQueueKind::PerCpu).unwrap();
+        let b = wq.lock().create_workqueue("b", WorkQueueKind::Unbound).unwrap();
+
+        wq.lock().queue_work_on(0, a, work_for(&counter)).unwrap();
+        wq.lock().queue_work_on(1, a, work_for(&counter)).unwrap();
+        wq.lock().queue_work_on(0, b, work_for(&counter)).unwrap();
+
+        assert_eq!(flush_workqueue_in(&wq, a).unwrap(), 2);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        let mut wq = wq.lock();
+        assert_eq!(wq.stats().pending, 1);
+        wq.destroy_workqueue(b).unwrap();
+        assert_eq!(wq.stats().pending, 0);
+        assert!(wq.get_queue(b).is_err());
+    }
+
+    #[test]
+    fn test_invalid_cpu() {
+        let mut wq = WorkqueueSystem::new(1);
+        let q = wq.create_workqueue("percpu", WorkQueueKind::PerCpu).unwrap();
+        let work = Work::new(|_| {}, 0);
+        assert!(wq.queue_work_on(3, q, work).is_err());
+    }
+}
@@ -6,8 +6,19 @@
 use alloc::vec::Vec;
 use spin::Mutex;
 
+use super::descriptor::EndpointDescriptor;
+use super::device::Attachment;
 use super::{DeviceAddress, EndpointNum, TransferType, UsbSpeed};
 
+/// A connect status change on a root hub port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortChange {
+    /// Port number, from 1
+    pub port: u8,
+    pub connected: bool,
+    pub speed: Option<UsbSpeed>,
+}
+
 /// USB host controller trait
 pub trait UsbController: Send {
     /// Initialize the controller
@@ -59,6 +70,63 @@ pub trait UsbController: Send {
         old_address: DeviceAddress,
         new_address: DeviceAddress,
     ) -> Result<(), &'static str>;
+
+    /// Number of root hub ports
+    fn port_count(&self) -> u8 {
+        0
+    }
+
+    /// Check whether a device is connected to a root hub port (from 1)
+    fn port_connected(&self, _port: u8) -> bool {
+        false
+    }
+
+    /// Connects and disconnects on the root hub ports since the last call
+    fn port_changes(&mut self) -> Vec<PortChange> {
+        Vec::new()
+    }
+
+    /// Reset a root hub port and return the speed of its device
+    fn reset_port(&mut self, _port: u8) -> Result<UsbSpeed, &'static str> {
+        Err("Not implemented")
+    }
+
+    /// Prepare address 0 for the device just reset on a port
+    fn attach(&mut self, _attachment: &Attachment) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Tell the controller that a configured device is a hub
+    ///
+    /// `think_time` is the hub's TT think time field from its descriptor.
+    fn configure_hub(
+        &mut self,
+        _address: DeviceAddress,
+        _ports: u8,
+        _multi_tt: bool,
+        _think_time: u8,
+    ) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Set the packet size of a device's control endpoint
+    fn set_max_packet_size(&mut self, _address: DeviceAddress, _max_packet: u16) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Set up an endpoint of the device's configuration
+    fn configure_endpoint(
+        &mut self,
+        _address: DeviceAddress,
+        _endpoint: &EndpointDescriptor,
+    ) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Forget a device that failed to enumerate or went away
+    fn detach(&mut self, _address: DeviceAddress) -> Result<(), &'static str> {
+        Ok(())
+    }
 }
 
 /// USB controller type
@@ -1,5 +1,7 @@
 /// USB descriptor parsing and structures
 
+use alloc::vec::Vec;
+
 /// USB Device Descriptor
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -110,3 +112,115 @@ pub mod class_code {
     pub const MISCELLANEOUS: u8 = 0xEF;
     pub const VENDOR_SPECIFIC: u8 = 0xFF;
 }
+
+/// Read a descriptor struct from the start of `bytes`
+fn read<T: Copy>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < core::mem::size_of::<T>() {
+        return None;
+    }
+    // The descriptor structs are packed and made of plain integers
+    Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+impl DeviceDescriptor {
+    /// Parse a device descriptor
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        read::<Self>(bytes).filter(|d| d.descriptor_type == descriptor_type::DEVICE)
+    }
+}
+
+/// An interface with the descriptors that follow it
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub descriptor: InterfaceDescriptor,
+    pub hid: Option<HidDescriptor>,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+/// A configuration with its interfaces
+#[derive(Debug, Clone)]
+pub struct Configuration {
+    pub descriptor: ConfigurationDescriptor,
+    pub interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    /// Parse a configuration descriptor and the interface, HID and
+    /// endpoint descriptors that follow it; other class and vendor
+    /// descriptors are skipped
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str> {
+        let descriptor = read::<ConfigurationDescriptor>(bytes)
+            .filter(|d| d.descriptor_type == descriptor_type::CONFIGURATION)
+            .ok_or("Bad configuration descriptor")?;
+        let total = ({ descriptor.total_length } as usize).min(bytes.len());
+        let mut interfaces: Vec<Interface> = Vec::new();
+        let mut offset = (descriptor.length as usize).max(core::mem::size_of::<ConfigurationDescriptor>());
+        while offset + 2 <= total {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > total {
+                return Err("Truncated configuration descriptor");
+            }
+            let body = &bytes[offset..offset + length];
+            match body[1] {
+                descriptor_type::INTERFACE => interfaces.push(Interface {
+                    descriptor: read(body).ok_or("Bad interface descriptor")?,
+                    hid: None,
+                    endpoints: Vec::new(),
+                }),
+                descriptor_type::ENDPOINT => {
+                    let endpoint = read(body).ok_or("Bad endpoint descriptor")?;
+                    interfaces.last_mut().ok_or("Endpoint outside an interface")?.endpoints.push(endpoint);
+                }
+                descriptor_type::HID => {
+                    if let Some(interface) = interfaces.last_mut() {
+                        interface.hid = read(body);
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Ok(Self { descriptor, interfaces })
+    }
+
+    /// Interfaces in their default alternate setting
+    pub fn default_interfaces(&self) -> impl Iterator<Item = &Interface> {
+        self.interfaces.iter().filter(|i| i.descriptor.alternate_setting == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A boot keyboard: one interface with a HID descriptor and an
+    /// interrupt IN endpoint
+    const KEYBOARD_CONFIG: [u8; 34] = [
+        9, 0x02, 34, 0, 1, 1, 0, 0xA0, 50,
+        9, 0x04, 0, 0, 1, 0x03, 0x01, 0x01, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 0x05, 0x81, 0x03, 8, 0, 10,
+    ];
+
+    #[test]
+    fn test_parse_configuration() {
+        let config = Configuration::parse(&KEYBOARD_CONFIG).unwrap();
+        assert_eq!(config.descriptor.configuration_value, 1);
+        assert_eq!(config.interfaces.len(), 1);
+
+        let interface = &config.interfaces[0];
+        assert_eq!(interface.descriptor.interface_class, class_code::HID);
+        assert_eq!(interface.hid.map(|hid| hid.report_descriptor_length), Some(63));
+        assert_eq!(interface.endpoints.len(), 1);
+        assert_eq!(interface.endpoints[0].endpoint_address, 0x81);
+        assert_eq!({ interface.endpoints[0].max_packet_size }, 8);
+        assert_eq!(config.default_interfaces().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated() {
+        assert!(Configuration::parse(&KEYBOARD_CONFIG[..5]).is_err());
+        assert!(Configuration::parse(&KEYBOARD_CONFIG[..30]).is_err());
+        assert!(DeviceDescriptor::parse(&KEYBOARD_CONFIG).is_none());
+    }
+}
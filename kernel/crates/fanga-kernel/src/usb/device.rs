@@ -1,9 +1,79 @@
 /// USB device representation and management
+///
+/// `enumerate` runs the standard enumeration sequence for a device on a
+/// port that was just reset: the 8-byte device descriptor read at address
+/// 0 for the control endpoint's packet size, SET_ADDRESS, the full device
+/// and configuration descriptors, and SET_CONFIGURATION with the first
+/// configuration.
 
 use super::{DeviceAddress, UsbSpeed};
-use super::descriptor::DeviceDescriptor;
+use super::controller::UsbController;
+use super::descriptor::{class_code, descriptor_type, Configuration, DeviceDescriptor, Interface};
+use alloc::vec;
 use alloc::vec::Vec;
 
+/// Standard requests
+pub mod request {
+    pub const GET_STATUS: u8 = 0x00;
+    pub const CLEAR_FEATURE: u8 = 0x01;
+    pub const SET_FEATURE: u8 = 0x03;
+    pub const SET_ADDRESS: u8 = 0x05;
+    pub const GET_DESCRIPTOR: u8 = 0x06;
+    pub const GET_CONFIGURATION: u8 = 0x08;
+    pub const SET_CONFIGURATION: u8 = 0x09;
+}
+
+/// Request type (bmRequestType) bits
+pub mod request_type {
+    pub const HOST_TO_DEVICE: u8 = 0x00;
+    pub const DEVICE_TO_HOST: u8 = 0x80;
+    pub const STANDARD: u8 = 0x00;
+    pub const CLASS: u8 = 0x20;
+    pub const DEVICE: u8 = 0x00;
+    pub const INTERFACE: u8 = 0x01;
+    pub const ENDPOINT: u8 = 0x02;
+    pub const OTHER: u8 = 0x03;
+}
+
+/// Longest configuration descriptor set read; longer ones are cut short
+const MAX_CONFIGURATION_LENGTH: usize = 1024;
+
+/// A transaction translator in a high-speed hub, which carries the
+/// traffic of low and full speed devices behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionTranslator {
+    /// Address of the hub
+    pub hub: DeviceAddress,
+    /// Hub port the device is reached through
+    pub port: u8,
+    /// Whether the hub has one translator per port
+    pub multi: bool,
+}
+
+/// Where a device is connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attachment {
+    /// Root hub port the device is reached through
+    pub root_port: u8,
+    /// Port on the device's hub; the root hub port on the root hub
+    pub port: u8,
+    /// Hub port numbers below the root hub, four bits per tier
+    pub route: u32,
+    /// Number of hubs between the root hub and the device
+    pub depth: u8,
+    pub speed: UsbSpeed,
+    /// Address of the hub the device is on, `None` on the root hub
+    pub parent: Option<DeviceAddress>,
+    pub tt: Option<TransactionTranslator>,
+}
+
+impl Attachment {
+    /// A device on a root hub port
+    pub fn root(port: u8, speed: UsbSpeed) -> Self {
+        Self { root_port: port, port, route: 0, depth: 0, speed, parent: None, tt: None }
+    }
+}
+
 /// USB device state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceState {
@@ -21,7 +91,12 @@ pub struct UsbDevice {
     speed: UsbSpeed,
     state: DeviceState,
     descriptor: Option<DeviceDescriptor>,
-    configurations: Vec<u8>, // Configuration descriptors
+    configurations: Vec<Configuration>,
+    /// Value of the selected configuration
+    configuration: Option<u8>,
+    /// Index of its host controller in the USB manager
+    controller: usize,
+    attachment: Attachment,
 }
 
 impl UsbDevice {
@@ -33,65 +108,322 @@ impl UsbDevice {
             state: DeviceState::Default,
             descriptor: None,
             configurations: Vec::new(),
+            configuration: None,
+            controller: 0,
+            attachment: Attachment::root(0, speed),
         }
     }
-    
+
     /// Get device address
     pub fn address(&self) -> DeviceAddress {
         self.address
     }
-    
+
     /// Get device speed
     pub fn speed(&self) -> UsbSpeed {
         self.speed
     }
-    
+
     /// Get device state
     pub fn state(&self) -> DeviceState {
         self.state
     }
-    
+
     /// Set device state
     pub fn set_state(&mut self, state: DeviceState) {
         self.state = state;
     }
-    
+
     /// Set device descriptor
     pub fn set_descriptor(&mut self, descriptor: DeviceDescriptor) {
         self.descriptor = Some(descriptor);
     }
-    
+
     /// Get device descriptor
     pub fn descriptor(&self) -> Option<&DeviceDescriptor> {
         self.descriptor.as_ref()
     }
-    
+
+    /// Get all configurations
+    pub fn configurations(&self) -> &[Configuration] {
+        &self.configurations
+    }
+
+    /// Get the selected configuration
+    pub fn configuration(&self) -> Option<&Configuration> {
+        let value = self.configuration?;
+        self.configurations.iter().find(|c| c.descriptor.configuration_value == value)
+    }
+
+    /// Get the index of the device's host controller
+    pub fn controller(&self) -> usize {
+        self.controller
+    }
+
+    /// Get the port the device is connected to on its hub
+    pub fn port(&self) -> u8 {
+        self.attachment.port
+    }
+
+    /// Get where the device is connected
+    pub fn attachment(&self) -> &Attachment {
+        &self.attachment
+    }
+
+    /// Find an interface of the selected configuration by class,
+    /// subclass and protocol
+    pub fn find_interface(&self, class: u8, subclass: u8, protocol: u8) -> Option<&Interface> {
+        self.configuration()?.default_interfaces().find(|i| {
+            let d = &i.descriptor;
+            d.interface_class == class && d.interface_subclass == subclass && d.interface_protocol == protocol
+        })
+    }
+
     /// Check if device is a HID device
     pub fn is_hid_device(&self) -> bool {
-        if let Some(desc) = &self.descriptor {
-            desc.device_class == 0x03 // HID class
-        } else {
-            false
-        }
+        let device_class = self.descriptor.is_some_and(|desc| desc.device_class == class_code::HID);
+        device_class
+            || self
+                .configuration()
+                .is_some_and(|c| c.default_interfaces().any(|i| i.descriptor.interface_class == class_code::HID))
     }
-    
+
+    /// Check if device is a hub
+    pub fn is_hub(&self) -> bool {
+        self.descriptor.is_some_and(|desc| desc.device_class == class_code::HUB)
+            || self
+                .configuration()
+                .is_some_and(|c| c.default_interfaces().any(|i| i.descriptor.interface_class == class_code::HUB))
+    }
+
     /// Check if device is a keyboard
     pub fn is_keyboard(&self) -> bool {
-        if let Some(desc) = &self.descriptor {
-            // HID class with Boot Interface subclass and keyboard protocol
-            desc.device_class == 0x03 && desc.device_subclass == 0x01 && desc.device_protocol == 0x01
-        } else {
-            false
-        }
+        // HID class with Boot Interface subclass and keyboard protocol
+        self.find_interface(class_code::HID, 0x01, 0x01).is_some()
+            || self.descriptor.is_some_and(|desc| {
+                desc.device_class == 0x03 && desc.device_subclass == 0x01 && desc.device_protocol == 0x01
+            })
     }
-    
+
     /// Check if device is a mouse
     pub fn is_mouse(&self) -> bool {
-        if let Some(desc) = &self.descriptor {
-            // HID class with Boot Interface subclass and mouse protocol
-            desc.device_class == 0x03 && desc.device_subclass == 0x01 && desc.device_protocol == 0x02
-        } else {
-            false
+        // HID class with Boot Interface subclass and mouse protocol
+        self.find_interface(class_code::HID, 0x01, 0x02).is_some()
+            || self.descriptor.is_some_and(|desc| {
+                desc.device_class == 0x03 && desc.device_subclass == 0x01 && desc.device_protocol == 0x02
+            })
+    }
+}
+
+/// Read a descriptor with GET_DESCRIPTOR, failing if it comes back short
+fn get_descriptor(
+    controller: &mut dyn UsbController,
+    address: DeviceAddress,
+    kind: u8,
+    index: u8,
+    data: &mut [u8],
+) -> Result<(), &'static str> {
+    let value = (kind as u16) << 8 | index as u16;
+    let len = controller.control_transfer(
+        address,
+        0,
+        request_type::DEVICE_TO_HOST,
+        request::GET_DESCRIPTOR,
+        value,
+        0,
+        data,
+    )?;
+    if len < data.len() {
+        return Err("Short USB descriptor");
+    }
+    Ok(())
+}
+
+/// Enumerate the device on a port that was just reset
+///
+/// `index` is the controller's index in the USB manager and `address` the
+/// address to give the device. A device that fails partway is detached
+/// from the controller again.
+pub fn enumerate(
+    controller: &mut dyn UsbController,
+    index: usize,
+    attachment: Attachment,
+    address: DeviceAddress,
+) -> Result<UsbDevice, &'static str> {
+    controller.attach(&attachment)?;
+
+    let mut device = UsbDevice::new(0, attachment.speed);
+    device.controller = index;
+    device.attachment = attachment;
+    match bring_up(controller, &mut device, address) {
+        Ok(()) => Ok(device),
+        Err(e) => {
+            let _ = controller.detach(device.address);
+            Err(e)
+        }
+    }
+}
+
+/// Address, describe and configure a device in the Default state
+fn bring_up(controller: &mut dyn UsbController, device: &mut UsbDevice, address: DeviceAddress) -> Result<(), &'static str> {
+    // The first 8 bytes of the device descriptor are readable with any
+    // packet size and hold the real one
+    let mut header = [0u8; 8];
+    get_descriptor(controller, 0, descriptor_type::DEVICE, 0, &mut header)?;
+    let max_packet = match device.speed {
+        UsbSpeed::Super => 1 << header[7].min(9),
+        _ => header[7] as u16,
+    };
+    controller.set_max_packet_size(0, max_packet)?;
+
+    controller.set_device_address(0, address)?;
+    device.address = address;
+    device.state = DeviceState::Addressed;
+
+    let mut bytes = [0u8; 18];
+    get_descriptor(controller, address, descriptor_type::DEVICE, 0, &mut bytes)?;
+    let descriptor = DeviceDescriptor::parse(&bytes).ok_or("Bad device descriptor")?;
+    device.descriptor = Some(descriptor);
+
+    for index in 0..descriptor.num_configurations {
+        let mut header = [0u8; 9];
+        get_descriptor(controller, address, descriptor_type::CONFIGURATION, index, &mut header)?;
+        let total = (u16::from_le_bytes([header[2], header[3]]) as usize).clamp(header.len(), MAX_CONFIGURATION_LENGTH);
+        let mut bytes = vec![0u8; total];
+        get_descriptor(controller, address, descriptor_type::CONFIGURATION, index, &mut bytes)?;
+        device.configurations.push(Configuration::parse(&bytes)?);
+    }
+
+    let configuration = device.configurations.first().ok_or("USB device has no configuration")?;
+    for interface in configuration.default_interfaces() {
+        for endpoint in &interface.endpoints {
+            controller.configure_endpoint(address, endpoint)?;
+        }
+    }
+    let value = configuration.descriptor.configuration_value;
+    controller.control_transfer(
+        address,
+        0,
+        request_type::HOST_TO_DEVICE,
+        request::SET_CONFIGURATION,
+        value as u16,
+        0,
+        &mut [],
+    )?;
+    device.configuration = Some(value);
+    device.state = DeviceState::Configured;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::controller::ControllerType;
+    use crate::usb::descriptor::EndpointDescriptor;
+    use crate::usb::EndpointNum;
+
+    /// A full-speed keyboard behind a fake controller
+    #[derive(Default)]
+    struct FakeController {
+        address: DeviceAddress,
+        max_packet: u16,
+        endpoints: Vec<u8>,
+        configuration: u16,
+    }
+
+    const DEVICE: [u8; 18] = [18, 0x01, 0x00, 0x02, 0, 0, 0, 8, 0x34, 0x12, 0x78, 0x56, 0, 1, 1, 2, 0, 1];
+    const CONFIG: [u8; 34] = [
+        9, 0x02, 34, 0, 1, 1, 0, 0xA0, 50,
+        9, 0x04, 0, 0, 1, 0x03, 0x01, 0x01, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 0x05, 0x81, 0x03, 8, 0, 10,
+    ];
+
+    impl UsbController for FakeController {
+        fn init(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<(), &'static str> {
+            Ok(())
         }
+
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn controller_type(&self) -> ControllerType {
+            ControllerType::XHCI
+        }
+
+        fn enumerate_devices(&mut self) -> Result<Vec<DeviceAddress>, &'static str> {
+            Ok(Vec::new())
+        }
+
+        fn control_transfer(
+            &mut self,
+            address: DeviceAddress,
+            _endpoint: EndpointNum,
+            _request_type: u8,
+            request: u8,
+            value: u16,
+            _index: u16,
+            data: &mut [u8],
+        ) -> Result<usize, &'static str> {
+            if address != self.address {
+                return Err("Wrong address");
+            }
+            let source: &[u8] = match (request, value >> 8) {
+                (request::GET_DESCRIPTOR, 1) => &DEVICE,
+                (request::GET_DESCRIPTOR, 2) => &CONFIG,
+                (request::SET_CONFIGURATION, _) => {
+                    self.configuration = value;
+                    &[]
+                }
+                _ => return Err("Stall"),
+            };
+            let len = data.len().min(source.len());
+            data[..len].copy_from_slice(&source[..len]);
+            Ok(len)
+        }
+
+        fn interrupt_transfer(&mut self, _: DeviceAddress, _: EndpointNum, _: &mut [u8]) -> Result<usize, &'static str> {
+            Err("Not implemented")
+        }
+
+        fn bulk_transfer(&mut self, _: DeviceAddress, _: EndpointNum, _: &mut [u8]) -> Result<usize, &'static str> {
+            Err("Not implemented")
+        }
+
+        fn set_device_address(&mut self, _old: DeviceAddress, new: DeviceAddress) -> Result<(), &'static str> {
+            self.address = new;
+            Ok(())
+        }
+
+        fn set_max_packet_size(&mut self, _address: DeviceAddress, max_packet: u16) -> Result<(), &'static str> {
+            self.max_packet = max_packet;
+            Ok(())
+        }
+
+        fn configure_endpoint(&mut self, _address: DeviceAddress, endpoint: &EndpointDescriptor) -> Result<(), &'static str> {
+            self.endpoints.push(endpoint.endpoint_address);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let mut controller = FakeController::default();
+        let device = enumerate(&mut controller, 0, Attachment::root(3, UsbSpeed::Full), 5).unwrap();
+
+        assert_eq!((controller.address, controller.max_packet), (5, 8));
+        assert_eq!(controller.endpoints, [0x81]);
+        assert_eq!(controller.configuration, 1);
+
+        assert_eq!((device.address(), device.port(), device.state()), (5, 3, DeviceState::Configured));
+        assert_eq!(device.descriptor().map(|d| (d.vendor_id, d.product_id)), Some((0x1234, 0x5678)));
+        assert!(device.is_keyboard());
+        assert!(!device.is_mouse());
+        assert!(!device.is_hub());
     }
 }
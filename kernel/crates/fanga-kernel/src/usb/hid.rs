@@ -2,9 +2,18 @@
 ///
 /// This module implements USB HID protocol for keyboards, mice, and other
 /// input devices.
+///
+/// Keyboards are switched to the boot protocol and polled with the other
+/// USB devices. Their keys are turned into PS/2 scancodes (set 1) and fed
+/// through the PS/2 keyboard's path, so they share its modifier state,
+/// keyboard layout and console handling. USB keyboards only report
+/// changes, so held keys are repeated here.
 
 use alloc::vec::Vec;
 use spin::Mutex;
+use super::controller::UsbController;
+use super::descriptor::class_code;
+use super::device::{request_type, UsbDevice};
 use super::{DeviceAddress, EndpointNum};
 
 /// HID subclass codes
@@ -47,6 +56,13 @@ pub struct HidKeyboardReport {
 }
 
 impl HidKeyboardReport {
+    /// Parse a boot protocol report
+    pub fn from_bytes(bytes: &[u8; 8]) -> Self {
+        let mut keycodes = [0; 6];
+        keycodes.copy_from_slice(&bytes[2..]);
+        Self { modifiers: bytes[0], reserved: bytes[1], keycodes }
+    }
+
     /// Check if a modifier key is pressed
     pub fn is_ctrl(&self) -> bool {
         (self.modifiers & 0x11) != 0 // Left or right Ctrl
@@ -200,3 +216,300 @@ pub(crate) fn dispatch_mouse_event(event: HidMouseEvent) {
         callback(event);
     }
 }
+
+/// Scancode set 1 prefix of extended keys
+const EXTENDED: u8 = 0xE0;
+
+/// Scancode set 1 release bit
+const RELEASE: u8 = 0x80;
+
+/// Usage in every key slot when too many keys are held
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Caps Lock usage, which does not repeat
+const USAGE_CAPS_LOCK: u8 = 0x39;
+
+/// Caps Lock bit of the LED output report
+const LED_CAPS_LOCK: u8 = 1 << 1;
+
+/// Key repeat delay and interval, as a PS/2 keyboard's default typematic
+const REPEAT_DELAY_MS: u64 = 500;
+const REPEAT_INTERVAL_MS: u64 = 33;
+
+/// Scancodes of the modifier bits, from left Ctrl to right GUI; extended
+/// keys have 0xE0 in the high byte
+const MODIFIER_SCANCODES: [u16; 8] = [0x1D, 0x2A, 0x38, 0xE05B, 0xE01D, 0x36, 0xE038, 0xE05C];
+
+/// Scancodes of keyboard usages 0x04 (A) to 0x52 (Up), 0 for keys the
+/// PS/2 driver does not know
+const USAGE_SCANCODES: [u16; 0x4F] = [
+    // A-Z
+    0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32,
+    0x31, 0x18, 0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C,
+    // 1-9, 0
+    0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+    // Enter, Escape, Backspace, Tab, Space, - = [ ] \ (ISO #) ; ' ` , . /
+    0x1C, 0x01, 0x0E, 0x0F, 0x39, 0x0C, 0x0D, 0x1A, 0x1B, 0x2B, 0x2B, 0x27, 0x28,
+    0x29, 0x33, 0x34, 0x35,
+    // Caps Lock, F1-F12
+    0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x40, 0x41, 0x42, 0x43, 0x44, 0x57, 0x58,
+    // Print Screen, Scroll Lock, Pause, Insert
+    0, 0, 0, 0,
+    // Home, Page Up, Delete, End, Page Down, Right, Left, Down, Up
+    0xE047, 0xE049, 0xE053, 0xE04F, 0xE051, 0xE04D, 0xE04B, 0xE050, 0xE048,
+];
+
+/// Scancode of a keyboard usage
+fn usage_scancode(usage: u8) -> Option<u16> {
+    let code = match usage {
+        0x04..=0x52 => USAGE_SCANCODES[(usage - 0x04) as usize],
+        // Extra key left of Z on ISO keyboards
+        0x64 => 0x56,
+        _ => 0,
+    };
+    (code != 0).then_some(code)
+}
+
+/// Emit the make or break bytes of a scancode
+fn emit(code: u16, pressed: bool, out: &mut impl FnMut(u8)) {
+    if code >> 8 == EXTENDED as u16 {
+        out(EXTENDED);
+    }
+    out(if pressed { code as u8 } else { code as u8 | RELEASE });
+}
+
+/// Keys held in the last report and the key repeating
+#[derive(Debug, Clone, Copy, Default)]
+struct KeyState {
+    modifiers: u8,
+    keys: [u8; 6],
+    /// Usage and when it repeats next
+    repeat: Option<(u8, u64)>,
+}
+
+impl KeyState {
+    /// Turn the changes in a new report into scancodes
+    ///
+    /// Modifiers go down before and come up after the other keys, so a
+    /// report that has Shift and a letter gives the shifted letter.
+    fn report(&mut self, report: &HidKeyboardReport, now: u64, mut out: impl FnMut(u8)) {
+        let keys = report.keycodes;
+        if keys.iter().all(|&key| key == ERROR_ROLL_OVER) {
+            return;
+        }
+        let (old, new) = (self.modifiers, report.modifiers);
+        for (bit, &code) in MODIFIER_SCANCODES.iter().enumerate() {
+            if new & !old & (1 << bit) != 0 {
+                emit(code, true, &mut out);
+            }
+        }
+        for &usage in self.keys.iter().filter(|&&usage| usage > ERROR_ROLL_OVER && !keys.contains(&usage)) {
+            if let Some(code) = usage_scancode(usage) {
+                emit(code, false, &mut out);
+            }
+            if self.repeat.is_some_and(|(key, _)| key == usage) {
+                self.repeat = None;
+            }
+        }
+        for &usage in keys.iter().filter(|&&usage| usage > ERROR_ROLL_OVER && !self.keys.contains(&usage)) {
+            if let Some(code) = usage_scancode(usage) {
+                emit(code, true, &mut out);
+                if usage != USAGE_CAPS_LOCK {
+                    self.repeat = Some((usage, now + REPEAT_DELAY_MS));
+                }
+            }
+        }
+        for (bit, &code) in MODIFIER_SCANCODES.iter().enumerate() {
+            if old & !new & (1 << bit) != 0 {
+                emit(code, false, &mut out);
+            }
+        }
+        self.modifiers = new;
+        self.keys = keys;
+    }
+
+    /// Repeat the last key pressed while it is held
+    fn tick(&mut self, now: u64, mut out: impl FnMut(u8)) {
+        if let Some((usage, due)) = self.repeat {
+            if now >= due {
+                if let Some(code) = usage_scancode(usage) {
+                    emit(code, true, &mut out);
+                }
+                self.repeat = Some((usage, now + REPEAT_INTERVAL_MS));
+            }
+        }
+    }
+}
+
+/// A boot protocol keyboard being polled
+struct UsbKeyboard {
+    device: HidDevice,
+    /// Index of its host controller in the USB manager
+    controller: usize,
+    interface: u8,
+    state: KeyState,
+    /// LEDs last set
+    leds: u8,
+    /// Unplugged; its held keys are released on the next poll
+    detached: bool,
+}
+
+impl UsbKeyboard {
+    /// Light Caps Lock to match the console keyboard state
+    fn update_leds(&mut self, controller: &mut dyn UsbController, caps_lock: bool) {
+        let leds = if caps_lock { LED_CAPS_LOCK } else { 0 };
+        if leds != self.leds {
+            let value = (report_type::OUTPUT as u16) << 8;
+            let _ = class_request(controller, self.device.address(), request::SET_REPORT, value, self.interface, &mut [leds]);
+            self.leds = leds;
+        }
+    }
+}
+
+/// Keyboards being polled
+static KEYBOARDS: Mutex<Vec<UsbKeyboard>> = Mutex::new(Vec::new());
+
+/// Send a HID class request to an interface
+fn class_request(
+    controller: &mut dyn UsbController,
+    address: DeviceAddress,
+    request: u8,
+    value: u16,
+    interface: u8,
+    data: &mut [u8],
+) -> Result<usize, &'static str> {
+    let kind = request_type::HOST_TO_DEVICE | request_type::CLASS | request_type::INTERFACE;
+    controller.control_transfer(address, 0, kind, request, value, interface as u16, data)
+}
+
+/// Switch a configured keyboard to the boot protocol and start reading it
+pub fn attach_keyboard(controller: &mut dyn UsbController, device: &UsbDevice) -> Result<(), &'static str> {
+    let interface = device
+        .find_interface(class_code::HID, subclass::BOOT_INTERFACE, protocol::KEYBOARD)
+        .ok_or("Not a boot keyboard")?;
+    let endpoint = interface
+        .endpoints
+        .iter()
+        .find(|e| e.endpoint_address & 0x80 != 0 && e.attributes & 0x03 == 0x03)
+        .ok_or("Keyboard has no interrupt IN endpoint")?;
+    let number = interface.descriptor.interface_number;
+
+    class_request(controller, device.address(), request::SET_PROTOCOL, 0, number, &mut [])?;
+    // Reports only on changes; some keyboards stall this
+    let _ = class_request(controller, device.address(), request::SET_IDLE, 0, number, &mut []);
+
+    KEYBOARDS.lock().push(UsbKeyboard {
+        device: HidDevice::new(device.address(), endpoint.endpoint_address & 0x0F, None, protocol::KEYBOARD),
+        controller: device.controller(),
+        interface: number,
+        state: KeyState::default(),
+        leds: 0,
+        detached: false,
+    });
+    Ok(())
+}
+
+/// Stop polling a keyboard that was unplugged
+pub fn detach_keyboard(controller: usize, address: DeviceAddress) {
+    for keyboard in KEYBOARDS.lock().iter_mut() {
+        if keyboard.controller == controller && keyboard.device.address() == address {
+            keyboard.detached = true;
+        }
+    }
+}
+
+/// Read every keyboard and feed its keys to the console
+pub fn poll_keyboards() {
+    let mut scancodes = Vec::new();
+    let mut reports = Vec::new();
+    let now = crate::task::time::uptime_ms();
+    let caps_lock = fanga_arch_x86_64::keyboard::keyboard().is_caps_lock();
+    {
+        let mut usb = super::usb_manager();
+        KEYBOARDS.lock().retain_mut(|keyboard| {
+            if keyboard.detached {
+                keyboard.state.report(&HidKeyboardReport::from_bytes(&[0; 8]), now, |code| scancodes.push(code));
+                return false;
+            }
+            let Some(controller) = usb.controller_mut(keyboard.controller) else {
+                return false;
+            };
+            let address = keyboard.device.address();
+            let mut bytes = [0u8; 8];
+            match controller.interrupt_transfer(address, keyboard.device.endpoint_in(), &mut bytes) {
+                Ok(len) if len == bytes.len() => {
+                    let report = HidKeyboardReport::from_bytes(&bytes);
+                    keyboard.state.report(&report, now, |code| scancodes.push(code));
+                    reports.push(report);
+                }
+                Ok(_) => keyboard.state.tick(now, |code| scancodes.push(code)),
+                Err(e) => {
+                    crate::log_warn!(target: "usb", "Keyboard at address {} stopped: {}", address, e);
+                    return false;
+                }
+            }
+            keyboard.update_leds(controller, caps_lock);
+            true
+        });
+    }
+
+    // The console may run commands, so the USB locks are released first
+    for scancode in scancodes {
+        fanga_arch_x86_64::keyboard::inject_scancode(scancode);
+    }
+    for report in reports {
+        dispatch_keyboard_event(HidKeyboardEvent { report });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(modifiers: u8, keys: &[u8]) -> HidKeyboardReport {
+        let mut bytes = [0u8; 8];
+        bytes[0] = modifiers;
+        bytes[2..2 + keys.len()].copy_from_slice(keys);
+        HidKeyboardReport::from_bytes(&bytes)
+    }
+
+    fn scancodes(state: &mut KeyState, modifiers: u8, keys: &[u8], now: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        state.report(&report(modifiers, keys), now, |code| out.push(code));
+        out
+    }
+
+    #[test]
+    fn test_report_to_scancodes() {
+        let mut state = KeyState::default();
+        // Left Shift and A together, then both released
+        assert_eq!(scancodes(&mut state, 0x02, &[0x04], 0), [0x2A, 0x1E]);
+        assert_eq!(scancodes(&mut state, 0x00, &[], 10), [0x9E, 0xAA]);
+
+        // Up arrow is an extended key; right Alt (AltGr) too
+        assert_eq!(scancodes(&mut state, 0x40, &[0x52], 20), [0xE0, 0x38, 0xE0, 0x48]);
+        assert_eq!(scancodes(&mut state, 0x40, &[], 30), [0xE0, 0xC8]);
+
+        // Rollover reports change nothing
+        assert!(scancodes(&mut state, 0x00, &[1, 1, 1, 1, 1, 1], 40).is_empty());
+        assert_eq!(state.modifiers, 0x40);
+    }
+
+    #[test]
+    fn test_held_key_repeats() {
+        let mut state = KeyState::default();
+        scancodes(&mut state, 0, &[0x05], 0);
+        let mut out = Vec::new();
+        state.tick(REPEAT_DELAY_MS - 1, |code| out.push(code));
+        assert!(out.is_empty());
+        state.tick(REPEAT_DELAY_MS, |code| out.push(code));
+        state.tick(REPEAT_DELAY_MS + REPEAT_INTERVAL_MS, |code| out.push(code));
+        assert_eq!(out, [0x30, 0x30]);
+
+        // Releasing stops it; Caps Lock never repeats
+        scancodes(&mut state, 0, &[USAGE_CAPS_LOCK], 1000);
+        out.clear();
+        state.tick(5000, |code| out.push(code));
+        assert!(out.is_empty());
+    }
+}
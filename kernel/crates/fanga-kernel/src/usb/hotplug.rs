@@ -0,0 +1,227 @@
+//! USB hotplug events
+//!
+//! The USB manager publishes an event whenever a device finishes
+//! enumeration or goes away. Each device gets a `/dev/usbdev<bus>.<address>`
+//! node while it is connected, and the events queue up for userspace in
+//! `/dev/usbevents`, one line per event:
+//!
+//! ```text
+//! add usbdev1.2 046d:c31c 03
+//! remove usbdev1.2 046d:c31c 03
+//! ```
+//!
+//! Reads never wait; `readable` tells whether one would return an event.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+use spin::Mutex;
+
+use super::device::UsbDevice;
+use super::DeviceAddress;
+use crate::io::chardev::{self, CharDevice, CharDeviceKind};
+
+/// Events kept for userspace; the oldest is dropped when full
+const MAX_EVENTS: usize = 64;
+
+/// Name of the event source device
+const EVENTS_DEVICE: &str = "usbevents";
+
+/// What happened to a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugAction {
+    Add,
+    Remove,
+}
+
+/// A device that was connected or disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HotplugEvent {
+    pub action: HotplugAction,
+    /// Bus number: host controller index plus one
+    pub bus: u8,
+    pub address: DeviceAddress,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Device class, or the first interface's class if the device leaves
+    /// it to its interfaces
+    pub class: u8,
+}
+
+impl HotplugEvent {
+    /// Event for an enumerated device
+    pub fn new(action: HotplugAction, device: &UsbDevice) -> Self {
+        let (vendor_id, product_id, device_class) = device
+            .descriptor()
+            .map_or((0, 0, 0), |d| (d.vendor_id, d.product_id, d.device_class));
+        let class = match device_class {
+            0 => device
+                .configuration()
+                .and_then(|configuration| configuration.interfaces.first())
+                .map_or(0, |interface| interface.descriptor.interface_class),
+            class => class,
+        };
+        Self {
+            action,
+            bus: device.controller() as u8 + 1,
+            address: device.address(),
+            vendor_id,
+            product_id,
+            class,
+        }
+    }
+
+    /// Name of the device's node under `/dev`
+    pub fn node_name(&self) -> String {
+        format!("usbdev{}.{}", self.bus, self.address)
+    }
+}
+
+impl fmt::Display for HotplugEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            HotplugAction::Add => "add",
+            HotplugAction::Remove => "remove",
+        };
+        write!(
+            f,
+            "{} {} {:04x}:{:04x} {:02x}",
+            action,
+            self.node_name(),
+            self.vendor_id,
+            self.product_id,
+            self.class
+        )
+    }
+}
+
+/// Events not read yet
+static EVENTS: Mutex<VecDeque<HotplugEvent>> = Mutex::new(VecDeque::new());
+
+/// Add or remove the device's node and queue the event
+pub fn publish(event: HotplugEvent) {
+    let name = event.node_name();
+    let path = format!("/dev/{}", name);
+    match event.action {
+        HotplugAction::Add => {
+            let _ = chardev::register(&name, Arc::new(DeviceNode));
+            // Without a root file system yet, `/dev` is populated later
+            let _ = crate::fs::mknod(&path, &name);
+        }
+        HotplugAction::Remove => {
+            let _ = crate::fs::unlink(&path);
+            let _ = chardev::unregister(&name);
+        }
+    }
+
+    let mut events = EVENTS.lock();
+    if events.len() >= MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// Take the oldest event (non-blocking)
+pub fn pop_event() -> Option<HotplugEvent> {
+    EVENTS.lock().pop_front()
+}
+
+/// Number of queued events
+pub fn pending_events() -> usize {
+    EVENTS.lock().len()
+}
+
+/// `/dev/usbdev<bus>.<address>`: marks a connected device; reads are
+/// always at end of file
+struct DeviceNode;
+
+impl CharDevice for DeviceNode {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(0)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err("Not supported")
+    }
+}
+
+/// `/dev/usbevents`: reads hand out queued events as lines
+struct EventSource;
+
+impl CharDevice for EventSource {
+    fn kind(&self) -> CharDeviceKind {
+        CharDeviceKind::Other
+    }
+
+    /// Fill `buf` with whole lines; events that do not fit stay queued
+    fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut filled = 0;
+        let mut events = EVENTS.lock();
+        while let Some(event) = events.front() {
+            let line = format!("{}\n", event);
+            if filled + line.len() > buf.len() {
+                break;
+            }
+            buf[filled..filled + line.len()].copy_from_slice(line.as_bytes());
+            filled += line.len();
+            events.pop_front();
+        }
+        Ok(filled)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err("Not supported")
+    }
+
+    fn readable(&self) -> bool {
+        !EVENTS.lock().is_empty()
+    }
+}
+
+/// Register the `usbevents` character device
+///
+/// Requires the heap.
+pub fn register_device() -> Result<(), &'static str> {
+    chardev::register(EVENTS_DEVICE, Arc::new(EventSource))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(action: HotplugAction, address: DeviceAddress) -> HotplugEvent {
+        HotplugEvent { action, bus: 1, address, vendor_id: 0x046d, product_id: 0xc31c, class: 3 }
+    }
+
+    #[test]
+    fn test_event_line() {
+        let add = event(HotplugAction::Add, 2);
+        assert_eq!(add.node_name(), "usbdev1.2");
+        assert_eq!(format!("{}", add), "add usbdev1.2 046d:c31c 03");
+        assert_eq!(format!("{}", event(HotplugAction::Remove, 2)), "remove usbdev1.2 046d:c31c 03");
+    }
+
+    #[test]
+    fn test_publish_and_read() {
+        publish(event(HotplugAction::Add, 120));
+        assert!(chardev::lookup("usbdev1.120").is_some());
+        assert!(EventSource.readable());
+
+        publish(event(HotplugAction::Remove, 120));
+        assert!(chardev::lookup("usbdev1.120").is_none());
+
+        // Only whole lines are read
+        let mut small = [0u8; 8];
+        assert_eq!(EventSource.read(&mut small).unwrap(), 0);
+        let mut buf = [0u8; 128];
+        let len = EventSource.read(&mut buf).unwrap();
+        let text = core::str::from_utf8(&buf[..len]).unwrap();
+        assert!(text.contains("add usbdev1.120 046d:c31c 03\nremove usbdev1.120 046d:c31c 03\n"));
+    }
+}
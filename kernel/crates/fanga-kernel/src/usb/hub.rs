@@ -0,0 +1,339 @@
+//! USB hub class driver
+//!
+//! A hub is set up once it is configured: its descriptor gives the number
+//! of ports and how long they take to power up, the host controller is
+//! told it is a hub, and every port is powered. The USB manager then
+//! resets and enumerates the devices on its ports like those on root hub
+//! ports, which recurses into hubs behind hubs. Afterwards the hub's
+//! status change endpoint is polled; it reports a bitmap with one bit per
+//! port whose state changed.
+
+use alloc::vec::Vec;
+
+use super::controller::UsbController;
+use super::descriptor::class_code;
+use super::device::{request, request_type, Attachment, TransactionTranslator, UsbDevice};
+use super::{DeviceAddress, EndpointNum, UsbSpeed};
+
+/// Hub descriptor types
+const HUB_DESCRIPTOR: u8 = 0x29;
+const SUPERSPEED_HUB_DESCRIPTOR: u8 = 0x2A;
+
+/// Hub class request telling a SuperSpeed hub its depth
+const SET_HUB_DEPTH: u8 = 12;
+
+/// Hub interface protocol of high-speed hubs with a TT per port
+const PROTOCOL_MULTI_TT: u8 = 2;
+
+/// Port features
+pub mod feature {
+    pub const PORT_RESET: u16 = 4;
+    pub const PORT_POWER: u16 = 8;
+    pub const C_PORT_CONNECTION: u16 = 16;
+    pub const C_PORT_ENABLE: u16 = 17;
+    pub const C_PORT_SUSPEND: u16 = 18;
+    pub const C_PORT_OVER_CURRENT: u16 = 19;
+    pub const C_PORT_RESET: u16 = 20;
+}
+
+/// Port status bits
+const PORT_CONNECTION: u16 = 1 << 0;
+const PORT_ENABLE: u16 = 1 << 1;
+const PORT_LOW_SPEED: u16 = 1 << 9;
+const PORT_HIGH_SPEED: u16 = 1 << 10;
+
+/// Port change bits
+pub const C_CONNECTION: u16 = 1 << 0;
+const C_RESET: u16 = 1 << 4;
+
+/// Port change bits and the features that clear them
+const CHANGE_FEATURES: [(u16, u16); 5] = [
+    (1 << 0, feature::C_PORT_CONNECTION),
+    (1 << 1, feature::C_PORT_ENABLE),
+    (1 << 2, feature::C_PORT_SUSPEND),
+    (1 << 3, feature::C_PORT_OVER_CURRENT),
+    (1 << 4, feature::C_PORT_RESET),
+];
+
+/// Hubs can be chained five deep
+const MAX_DEPTH: u8 = 5;
+
+/// Ports a route string can address
+const MAX_PORTS: u8 = 15;
+
+/// Time allowed for a port reset
+const RESET_TIMEOUT_MS: u64 = 500;
+
+/// Fixed part of a hub descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HubDescriptor {
+    pub ports: u8,
+    pub characteristics: u16,
+    /// Time from powering a port until it is usable
+    pub power_on_ms: u32,
+}
+
+impl HubDescriptor {
+    /// Parse a USB 2 or SuperSpeed hub descriptor
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 7 || !matches!(bytes[1], HUB_DESCRIPTOR | SUPERSPEED_HUB_DESCRIPTOR) {
+            return None;
+        }
+        Some(Self {
+            ports: bytes[2],
+            characteristics: u16::from_le_bytes([bytes[3], bytes[4]]),
+            power_on_ms: bytes[5] as u32 * 2,
+        })
+    }
+
+    /// TT think time field, in units of 8 full-speed bit times minus one
+    pub fn think_time(&self) -> u8 {
+        ((self.characteristics >> 5) & 0x3) as u8
+    }
+}
+
+/// Status of a hub port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    pub status: u16,
+    pub change: u16,
+}
+
+impl PortStatus {
+    pub fn connected(&self) -> bool {
+        self.status & PORT_CONNECTION != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.status & PORT_ENABLE != 0
+    }
+
+    /// Speed of the device on the port of a hub running at `hub_speed`
+    pub fn speed(&self, hub_speed: UsbSpeed) -> UsbSpeed {
+        if hub_speed == UsbSpeed::Super {
+            UsbSpeed::Super
+        } else if self.status & PORT_LOW_SPEED != 0 {
+            UsbSpeed::Low
+        } else if self.status & PORT_HIGH_SPEED != 0 {
+            UsbSpeed::High
+        } else {
+            UsbSpeed::Full
+        }
+    }
+}
+
+/// Send a hub class request to a port
+fn port_request(
+    controller: &mut dyn UsbController,
+    address: DeviceAddress,
+    request: u8,
+    feature: u16,
+    port: u8,
+) -> Result<(), &'static str> {
+    let kind = request_type::HOST_TO_DEVICE | request_type::CLASS | request_type::OTHER;
+    controller.control_transfer(address, 0, kind, request, feature, port as u16, &mut []).map(|_| ())
+}
+
+/// A configured hub
+#[derive(Debug, Clone, Copy)]
+pub struct Hub {
+    address: DeviceAddress,
+    /// Index of its host controller in the USB manager
+    controller: usize,
+    /// Where the hub itself is connected
+    attachment: Attachment,
+    ports: u8,
+    /// Status change endpoint
+    endpoint: EndpointNum,
+    multi_tt: bool,
+}
+
+impl Hub {
+    /// Read the hub descriptor of a configured hub and power its ports
+    pub fn attach(controller: &mut dyn UsbController, device: &UsbDevice) -> Result<Self, &'static str> {
+        let attachment = *device.attachment();
+        if attachment.depth >= MAX_DEPTH {
+            return Err("USB hubs nested too deep");
+        }
+        let interface = device
+            .configuration()
+            .and_then(|c| c.default_interfaces().find(|i| i.descriptor.interface_class == class_code::HUB))
+            .ok_or("Not a hub")?;
+        let endpoint = interface
+            .endpoints
+            .iter()
+            .find(|e| e.endpoint_address & 0x80 != 0 && e.attributes & 0x03 == 0x03)
+            .ok_or("Hub has no status change endpoint")?;
+        let multi_tt = interface.descriptor.interface_protocol == PROTOCOL_MULTI_TT;
+        let address = device.address();
+
+        let class_in = request_type::DEVICE_TO_HOST | request_type::CLASS | request_type::DEVICE;
+        let kind = if device.speed() == UsbSpeed::Super {
+            let class_out = request_type::HOST_TO_DEVICE | request_type::CLASS | request_type::DEVICE;
+            controller.control_transfer(address, 0, class_out, SET_HUB_DEPTH, attachment.depth as u16, 0, &mut [])?;
+            SUPERSPEED_HUB_DESCRIPTOR
+        } else {
+            HUB_DESCRIPTOR
+        };
+        let mut bytes = [0u8; 12];
+        let len = controller.control_transfer(address, 0, class_in, request::GET_DESCRIPTOR, (kind as u16) << 8, 0, &mut bytes)?;
+        let descriptor = HubDescriptor::parse(&bytes[..len]).ok_or("Bad hub descriptor")?;
+        let ports = descriptor.ports.min(MAX_PORTS);
+
+        controller.configure_hub(address, ports, multi_tt, descriptor.think_time())?;
+        for port in 1..=ports {
+            port_request(controller, address, request::SET_FEATURE, feature::PORT_POWER, port)?;
+        }
+        crate::task::time::delay_ms(descriptor.power_on_ms as u64);
+
+        Ok(Self {
+            address,
+            controller: device.controller(),
+            attachment,
+            ports,
+            endpoint: endpoint.endpoint_address & 0x0F,
+            multi_tt,
+        })
+    }
+
+    pub fn address(&self) -> DeviceAddress {
+        self.address
+    }
+
+    /// Index of the hub's host controller in the USB manager
+    pub fn controller(&self) -> usize {
+        self.controller
+    }
+
+    pub fn ports(&self) -> u8 {
+        self.ports
+    }
+
+    /// Read a port's status
+    pub fn port_status(&self, controller: &mut dyn UsbController, port: u8) -> Result<PortStatus, &'static str> {
+        let kind = request_type::DEVICE_TO_HOST | request_type::CLASS | request_type::OTHER;
+        let mut bytes = [0u8; 4];
+        let len = controller.control_transfer(self.address, 0, kind, request::GET_STATUS, 0, port as u16, &mut bytes)?;
+        if len < bytes.len() {
+            return Err("Short hub port status");
+        }
+        Ok(PortStatus {
+            status: u16::from_le_bytes([bytes[0], bytes[1]]),
+            change: u16::from_le_bytes([bytes[2], bytes[3]]),
+        })
+    }
+
+    /// Acknowledge a port's changes
+    pub fn clear_changes(&self, controller: &mut dyn UsbController, port: u8, change: u16) -> Result<(), &'static str> {
+        for (bit, feature) in CHANGE_FEATURES {
+            if change & bit != 0 {
+                port_request(controller, self.address, request::CLEAR_FEATURE, feature, port)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset a port and return the speed of its device
+    pub fn reset_port(&self, controller: &mut dyn UsbController, port: u8) -> Result<UsbSpeed, &'static str> {
+        port_request(controller, self.address, request::SET_FEATURE, feature::PORT_RESET, port)?;
+        let deadline = crate::task::time::uptime_ms() + RESET_TIMEOUT_MS;
+        let status = loop {
+            crate::task::time::delay_ms(10);
+            let status = self.port_status(controller, port)?;
+            if status.change & C_RESET != 0 {
+                break status;
+            }
+            if crate::task::time::uptime_ms() > deadline {
+                return Err("Hub port reset timed out");
+            }
+        };
+        port_request(controller, self.address, request::CLEAR_FEATURE, feature::C_PORT_RESET, port)?;
+        if !status.enabled() {
+            return Err("Hub port not enabled after reset");
+        }
+        // Reset recovery
+        crate::task::time::delay_ms(10);
+        Ok(status.speed(self.attachment.speed))
+    }
+
+    /// Where a device on one of the hub's ports is connected
+    pub fn child(&self, port: u8, speed: UsbSpeed) -> Attachment {
+        let parent = self.attachment;
+        // Low and full speed devices behind a high-speed hub are reached
+        // through its transaction translator
+        let tt = match (parent.speed, speed) {
+            (UsbSpeed::High, UsbSpeed::Low | UsbSpeed::Full) => Some(TransactionTranslator {
+                hub: self.address,
+                port,
+                multi: self.multi_tt,
+            }),
+            _ => parent.tt,
+        };
+        Attachment {
+            root_port: parent.root_port,
+            port,
+            route: parent.route | ((port.min(MAX_PORTS) as u32) << (4 * parent.depth)),
+            depth: parent.depth + 1,
+            speed,
+            parent: Some(self.address),
+            tt,
+        }
+    }
+
+    /// Ports whose state changed, from the status change endpoint
+    ///
+    /// Does not wait; returns nothing until the hub reports a change.
+    pub fn poll_changes(&self, controller: &mut dyn UsbController) -> Result<Vec<u8>, &'static str> {
+        let mut bitmap = [0u8; 2];
+        let len = controller.interrupt_transfer(self.address, self.endpoint, &mut bitmap)?;
+        Ok(changed_ports(&bitmap[..len], self.ports))
+    }
+}
+
+/// Ports set in a status change bitmap; bit 0 is the hub itself
+fn changed_ports(bitmap: &[u8], ports: u8) -> Vec<u8> {
+    (1..=ports)
+        .filter(|&port| bitmap.get(port as usize / 8).is_some_and(|byte| byte & (1 << (port % 8)) != 0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hub(attachment: Attachment, multi_tt: bool) -> Hub {
+        Hub { address: 2, controller: 0, attachment, ports: 4, endpoint: 1, multi_tt }
+    }
+
+    #[test]
+    fn test_hub_descriptor() {
+        // Four ports, individual power switching, 100 ms to power up
+        let descriptor = HubDescriptor::parse(&[9, 0x29, 4, 0x49, 0x00, 50, 0, 0, 0xFF]).unwrap();
+        assert_eq!((descriptor.ports, descriptor.power_on_ms), (4, 100));
+        assert_eq!(descriptor.think_time(), 2);
+        assert!(HubDescriptor::parse(&[9, 0x02, 4, 0, 0, 50, 0]).is_none());
+    }
+
+    #[test]
+    fn test_child_attachment() {
+        let high = hub(Attachment::root(3, UsbSpeed::High), true);
+        let keyboard = high.child(2, UsbSpeed::Low);
+        assert_eq!((keyboard.root_port, keyboard.route, keyboard.depth), (3, 0x2, 1));
+        assert_eq!(keyboard.tt, Some(TransactionTranslator { hub: 2, port: 2, multi: true }));
+        assert_eq!(keyboard.parent, Some(2));
+        assert_eq!(high.child(4, UsbSpeed::High).tt, None);
+
+        // A full-speed hub behind it keeps using the same translator
+        let full = hub(keyboard, false);
+        let mouse = full.child(15, UsbSpeed::Full);
+        assert_eq!((mouse.route, mouse.depth), (0xF2, 2));
+        assert_eq!(mouse.tt, keyboard.tt);
+    }
+
+    #[test]
+    fn test_changed_ports() {
+        assert_eq!(changed_ports(&[0b0001_0101], 4), [2, 4]);
+        assert_eq!(changed_ports(&[0x00, 0x01], 8), [8]);
+        assert!(changed_ports(&[], 4).is_empty());
+    }
+}
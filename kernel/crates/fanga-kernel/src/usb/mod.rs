@@ -3,15 +3,30 @@
 /// This module provides USB host controller support and device management.
 /// It implements the basic USB protocol stack including device enumeration,
 /// descriptor parsing, and HID device support.
+///
+/// Host controllers are bound as PCI drivers by the driver core, and every
+/// enumerated device is added to the device tree under its hub or its
+/// host controller.
 
 pub mod controller;
 pub mod device;
 pub mod hid;
+pub mod hotplug;
+pub mod hub;
 pub mod descriptor;
+pub mod xhci;
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
+use crate::driver::{self, Bus, Device, DeviceId, Driver, Resource};
+use crate::pci::{self, Bar, PciDevice};
+
 /// USB device speed
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UsbSpeed {
@@ -30,6 +45,17 @@ pub enum TransferType {
     Isochronous,
 }
 
+/// PCI subclass of USB host controllers (class 0x0C, serial bus)
+pub const PCI_SUBCLASS_USB: u8 = 0x03;
+
+/// PCI programming interfaces of USB host controllers
+pub mod prog_if {
+    pub const UHCI: u8 = 0x00;
+    pub const OHCI: u8 = 0x10;
+    pub const EHCI: u8 = 0x20;
+    pub const XHCI: u8 = 0x30;
+}
+
 /// USB device address (1-127, 0 is reserved)
 pub type DeviceAddress = u8;
 
@@ -38,43 +64,269 @@ pub type EndpointNum = u8;
 
 /// USB manager - coordinates all USB operations
 pub struct UsbManager {
-    // controllers: Vec<Box<dyn controller::UsbController>>,
+    controllers: Vec<Box<dyn controller::UsbController>>,
+    /// Driver core names of the controllers' PCI functions, by index
+    hosts: Vec<String>,
     devices: Vec<device::UsbDevice>,
+    hubs: Vec<hub::Hub>,
     next_address: DeviceAddress,
 }
 
 impl UsbManager {
     pub const fn new() -> Self {
         Self {
-            // controllers: Vec::new(),
+            controllers: Vec::new(),
+            hosts: Vec::new(),
             devices: Vec::new(),
+            hubs: Vec::new(),
             next_address: 1,
         }
     }
     
     /// Initialize USB subsystem
+    ///
+    /// Host controllers are added later, as the driver core binds them.
     pub fn init(&mut self) {
-        // Scan for USB controllers
-        // self.scan_controllers();
-        
-        // Initialize each controller
-        // for controller in &mut self.controllers {
-        //     if let Err(e) = controller.init() {
-        //         // Log error but continue with other controllers
-        //     }
-        // }
+        if let Err(e) = hotplug::register_device() {
+            crate::log_warn!(target: "usb", "No hotplug event device: {}", e);
+        }
     }
-    
-    /// Scan for USB host controllers on PCI bus
-    fn scan_controllers(&mut self) {
-        // TODO: Scan PCI bus for USB controllers (UHCI, OHCI, EHCI, XHCI)
-        // For now, this is a placeholder
+
+    /// Take a controller that came up, whose PCI function is `host` in the
+    /// driver core, and enumerate what is connected to it
+    fn add_controller(&mut self, controller: Box<dyn controller::UsbController>, host: &str) {
+        self.controllers.push(controller);
+        self.hosts.push(String::from(host));
+        let index = self.controllers.len() - 1;
+
+        // Connects from before the scan are not reported again
+        self.controllers[index].port_changes();
+        for port in 1..=self.controllers[index].port_count() {
+            if !self.controllers[index].port_connected(port) {
+                continue;
+            }
+            if let Err(e) = self.enumerate_port(index, port) {
+                crate::log_warn!(target: "usb", "Port {}: enumeration failed: {}", port, e);
+            }
+        }
+    }
+
+    /// Enumerate the device on a root hub port of a controller
+    pub fn enumerate_port(&mut self, index: usize, port: u8) -> Result<DeviceAddress, &'static str> {
+        let controller = self.controllers.get_mut(index).ok_or("No such USB controller")?;
+        let speed = controller.reset_port(port)?;
+        self.enumerate_device(index, device::Attachment::root(port, speed))
+    }
+
+    /// Enumerate a device on a port that was just reset and start its
+    /// driver
+    fn enumerate_device(&mut self, index: usize, attachment: device::Attachment) -> Result<DeviceAddress, &'static str> {
+        let address = self.allocate_address().ok_or("Out of USB addresses")?;
+        let controller = self.controllers.get_mut(index).ok_or("No such USB controller")?;
+        let device = device::enumerate(controller.as_mut(), index, attachment, address)?;
+        if let Some(descriptor) = device.descriptor() {
+            crate::log_info!(
+                target: "usb",
+                "Device {:04x}:{:04x} at address {}",
+                { descriptor.vendor_id },
+                { descriptor.product_id },
+                address
+            );
+        }
+        if device.is_keyboard() {
+            match hid::attach_keyboard(controller.as_mut(), &device) {
+                Ok(()) => crate::log_info!(target: "usb", "Address {}: keyboard ready", address),
+                Err(e) => crate::log_warn!(target: "usb", "Address {}: keyboard unusable: {}", address, e),
+            }
+        }
+        let is_hub = device.is_hub();
+        let event = hotplug::HotplugEvent::new(hotplug::HotplugAction::Add, &device);
+        hotplug::publish(event);
+        self.add_device_node(&device, &event);
+        self.register_device(device);
+        if is_hub {
+            if let Err(e) = self.attach_hub(index, address) {
+                crate::log_warn!(target: "usb", "Address {}: hub unusable: {}", address, e);
+            }
+        }
+        Ok(address)
+    }
+
+    /// Set up a hub and enumerate the devices already on its ports
+    fn attach_hub(&mut self, index: usize, address: DeviceAddress) -> Result<(), &'static str> {
+        let device = self.devices.iter().find(|d| d.address() == address).ok_or("No such USB device")?;
+        let controller = self.controllers.get_mut(index).ok_or("No such USB controller")?;
+        let hub = hub::Hub::attach(controller.as_mut(), device)?;
+        crate::log_info!(target: "usb", "Address {}: hub with {} ports", address, hub.ports());
+
+        for port in 1..=hub.ports() {
+            let controller = self.controllers[index].as_mut();
+            let status = hub.port_status(controller, port)?;
+            hub.clear_changes(controller, port, status.change)?;
+            if status.connected() {
+                if let Err(e) = self.enumerate_hub_port(&hub, port) {
+                    crate::log_warn!(target: "usb", "Hub {} port {}: enumeration failed: {}", address, port, e);
+                }
+            }
+        }
+        self.hubs.push(hub);
+        Ok(())
+    }
+
+    /// Reset a hub port and enumerate its device
+    fn enumerate_hub_port(&mut self, hub: &hub::Hub, port: u8) -> Result<DeviceAddress, &'static str> {
+        let controller = self.controllers.get_mut(hub.controller()).ok_or("No such USB controller")?;
+        let speed = hub.reset_port(controller.as_mut(), port)?;
+        self.enumerate_device(hub.controller(), hub.child(port, speed))
+    }
+
+    /// Handle connects and disconnects reported by hubs
+    pub fn poll_hubs(&mut self) {
+        // Removing a device takes the hubs behind it out of the list
+        let hubs = self.hubs.clone();
+        for hub in hubs {
+            if !self.hubs.iter().any(|h| h.address() == hub.address()) {
+                continue;
+            }
+            let Some(controller) = self.controllers.get_mut(hub.controller()) else {
+                continue;
+            };
+            let changed = match hub.poll_changes(controller.as_mut()) {
+                Ok(changed) => changed,
+                Err(e) => {
+                    crate::log_warn!(target: "usb", "Hub {} stopped: {}", hub.address(), e);
+                    self.hubs.retain(|h| h.address() != hub.address());
+                    continue;
+                }
+            };
+            for port in changed {
+                if let Err(e) = self.hub_port_changed(&hub, port) {
+                    crate::log_warn!(target: "usb", "Hub {} port {}: {}", hub.address(), port, e);
+                }
+            }
+        }
+    }
+
+    /// Acknowledge a hub port's changes and enumerate a new device on it
+    fn hub_port_changed(&mut self, hub: &hub::Hub, port: u8) -> Result<(), &'static str> {
+        let controller = self.controllers.get_mut(hub.controller()).ok_or("No such USB controller")?;
+        let status = hub.port_status(controller.as_mut(), port)?;
+        hub.clear_changes(controller.as_mut(), port, status.change)?;
+        if status.change & hub::C_CONNECTION == 0 {
+            return Ok(());
+        }
+        if status.connected() {
+            self.enumerate_hub_port(hub, port).map(|_| ())
+        } else {
+            crate::log_info!(target: "usb", "Hub {} port {}: device disconnected", hub.address(), port);
+            let child = self
+                .devices
+                .iter()
+                .find(|d| d.attachment().parent == Some(hub.address()) && d.port() == port)
+                .map(|d| d.address());
+            if let Some(address) = child {
+                self.remove_device(address);
+            }
+            Ok(())
+        }
+    }
+
+    /// Handle connects and disconnects on the root hub ports
+    pub fn poll_root_ports(&mut self) {
+        for index in 0..self.controllers.len() {
+            for change in self.controllers[index].port_changes() {
+                let existing = self
+                    .devices
+                    .iter()
+                    .find(|d| d.controller() == index && d.attachment().parent.is_none() && d.port() == change.port)
+                    .map(|d| d.address());
+                // A quick replug reports only a connect; the old device is gone too
+                if let Some(address) = existing {
+                    crate::log_info!(target: "usb", "Port {}: device disconnected", change.port);
+                    self.remove_device(address);
+                }
+                if change.connected {
+                    if let Err(e) = self.enumerate_port(index, change.port) {
+                        crate::log_warn!(target: "usb", "Port {}: enumeration failed: {}", change.port, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add an enumerated device to the driver core, under its hub or its
+    /// host controller
+    fn add_device_node(&self, device: &device::UsbDevice, event: &hotplug::HotplugEvent) {
+        let index = device.controller();
+        let parent = match device.attachment().parent {
+            Some(hub) => Some(node_name(index, hub)),
+            None => self.hosts.get(index).cloned(),
+        };
+        let node = Device {
+            name: node_name(index, device.address()),
+            bus: Some(Bus::Usb),
+            parent: parent.and_then(|name| driver::find_device(&name)).map(|parent| parent.handle),
+            vendor: event.vendor_id,
+            device: event.product_id,
+            class: (event.class as u32) << 16,
+            resource: Resource::Usb { controller: index, address: device.address() },
+        };
+        if let Err(e) = driver::add_device(node) {
+            crate::log_warn!(target: "usb", "Address {}: not in the device tree: {}", device.address(), e);
+        }
+    }
+
+    /// Tear down a device that went away, and everything behind it if it
+    /// is a hub
+    ///
+    /// Its driver stops, the controller releases its endpoints and a
+    /// remove event is published.
+    pub fn remove_device(&mut self, address: DeviceAddress) {
+        let children: Vec<DeviceAddress> = self
+            .devices
+            .iter()
+            .filter(|d| d.attachment().parent == Some(address))
+            .map(|d| d.address())
+            .collect();
+        for child in children {
+            self.remove_device(child);
+        }
+
+        let Some(position) = self.devices.iter().position(|d| d.address() == address) else {
+            return;
+        };
+        let device = self.devices.remove(position);
+        let index = device.controller();
+        self.hubs.retain(|hub| hub.address() != address);
+        hid::detach_keyboard(index, address);
+        if let Some(controller) = self.controllers.get_mut(index) {
+            if let Err(e) = controller.detach(address) {
+                crate::log_warn!(target: "usb", "Address {}: detach failed: {}", address, e);
+            }
+        }
+        hotplug::publish(hotplug::HotplugEvent::new(hotplug::HotplugAction::Remove, &device));
+        if let Some(node) = driver::find_device(&node_name(index, address)) {
+            let _ = driver::remove_device(node.handle);
+        }
+        crate::log_info!(target: "usb", "Address {}: device removed", address);
+    }
+
+    /// Number of working host controllers
+    pub fn controller_count(&self) -> usize {
+        self.controllers.len()
+    }
+
+    /// Get a host controller by index
+    pub fn controller_mut(&mut self, index: usize) -> Option<&mut (dyn controller::UsbController + 'static)> {
+        self.controllers.get_mut(index).map(|controller| controller.as_mut())
     }
     
     /// Allocate a new device address
+    ///
+    /// Addresses of removed devices are reused once the others run out.
     pub fn allocate_address(&mut self) -> Option<DeviceAddress> {
         if self.next_address > 127 {
-            return None;
+            return (1..=127).find(|&address| self.find_device(address).is_none());
         }
         
         let addr = self.next_address;
@@ -98,15 +350,135 @@ impl UsbManager {
     }
 }
 
+/// Map the registers of a USB host controller and create its driver
+///
+/// UHCI uses I/O ports in BAR4; the others have memory-mapped registers in
+/// BAR0. There is no OHCI driver.
+fn probe_controller(device: &PciDevice) -> Result<Option<Box<dyn controller::UsbController>>, &'static str> {
+    use pci::command::{BUS_MASTER, IO_SPACE, MEMORY_SPACE};
+
+    let memory_bar = || match device.bar(0) {
+        Some(Bar::Memory { addr, size, .. }) if addr != 0 => crate::memory::mmio::ioremap(addr, size),
+        _ => Err("No register BAR"),
+    };
+    let controller: Box<dyn controller::UsbController> = match device.prog_if {
+        prog_if::UHCI => {
+            let Some(Bar::Io { port, .. }) = device.bar(4) else {
+                return Err("No I/O port BAR");
+            };
+            device.enable(IO_SPACE | BUS_MASTER);
+            Box::new(controller::UhciController::new(port as usize))
+        }
+        prog_if::EHCI => {
+            let base = memory_bar()?;
+            device.enable(MEMORY_SPACE | BUS_MASTER);
+            Box::new(controller::EhciController::new(base as usize))
+        }
+        prog_if::XHCI => {
+            let base = memory_bar()?;
+            device.enable(MEMORY_SPACE | BUS_MASTER);
+            Box::new(xhci::XhciController::new(base))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(controller))
+}
+
+/// Name of a device in the driver core, `usb<bus>.<address>` like its
+/// `/dev` node
+fn node_name(index: usize, address: DeviceAddress) -> String {
+    format!("usb{}.{}", index + 1, address)
+}
+
+/// ID table entry for a host controller interface
+const fn host_id(interface: u8) -> DeviceId {
+    let class = (pci::class::SERIAL_BUS as u32) << 16 | (PCI_SUBCLASS_USB as u32) << 8 | interface as u32;
+    DeviceId::class(class, 0xFF_FF_FF)
+}
+
+/// Host controller interfaces with a driver
+const HOST_IDS: &[DeviceId] = &[host_id(prog_if::UHCI), host_id(prog_if::EHCI), host_id(prog_if::XHCI)];
+
+/// PCI driver for the host controllers
+///
+/// Controllers are never unplugged, so once bound they stay.
+struct HostDriver;
+
+impl Driver for HostDriver {
+    fn name(&self) -> &'static str {
+        "usb-host"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Pci
+    }
+
+    fn id_table(&self) -> &'static [DeviceId] {
+        HOST_IDS
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), &'static str> {
+        let Resource::Pci(function) = device.resource else {
+            return Err("Not a PCI function");
+        };
+        let mut controller = probe_controller(&function)?.ok_or("No driver for this interface")?;
+        crate::log_info!(
+            target: "usb",
+            "{} controller at {} ({:04x}:{:04x})",
+            controller.name(),
+            function.address,
+            function.vendor_id,
+            function.device_id
+        );
+        controller.init()?;
+        crate::log_info!(target: "usb", "{} controller ready", controller.name());
+        USB_MANAGER.lock().add_controller(controller, &device.name);
+        Ok(())
+    }
+}
+
 /// Global USB manager
 static USB_MANAGER: Mutex<UsbManager> = Mutex::new(UsbManager::new());
 
-/// Initialize the USB subsystem
+/// Initialize the USB subsystem and bind the host controllers
 pub fn init() {
     USB_MANAGER.lock().init();
+    if let Err(e) = pci::register_driver(Arc::new(HostDriver)) {
+        crate::log_warn!(target: "usb", "Cannot register the host controller driver: {}", e);
+    }
 }
 
 /// Get access to the USB manager
 pub fn usb_manager() -> spin::MutexGuard<'static, UsbManager> {
     USB_MANAGER.lock()
 }
+
+/// How often ports, hubs and keyboards are polled
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Whether the poll work is scheduled
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// Start polling for hotplug and input (needs the workqueues)
+pub fn start_polling() {
+    if !POLLING.swap(true, Ordering::AcqRel) {
+        schedule_poll();
+    }
+}
+
+/// Queue the next poll
+fn schedule_poll() {
+    let work = crate::task::workqueue::Work::new(poll_tick, 0);
+    let _ = crate::task::workqueue::schedule_delayed_work(work, POLL_INTERVAL_MS);
+}
+
+/// Workqueue function for USB polling
+fn poll_tick(_: usize) {
+    {
+        let mut usb = usb_manager();
+        usb.poll_root_ports();
+        usb.poll_hubs();
+    }
+    hid::poll_keyboards();
+    schedule_poll();
+}
@@ -0,0 +1,1261 @@
+//! xHCI (eXtensible Host Controller Interface) driver
+//!
+//! The USB 3 host controller found in QEMU and all current hardware. It
+//! handles every USB speed itself, so no companion controllers are needed.
+//!
+//! The controller is driven through rings of 16-byte TRBs in memory it
+//! reads and writes by DMA:
+//! - The command ring carries slot and endpoint commands
+//! - One transfer ring per endpoint carries transfers
+//! - The event ring reports completions and port status changes
+//!
+//! Each device gets a slot whose device context the controller keeps up
+//! to date; the driver describes changes in an input context.
+//!
+//! The driver polls the event ring rather than taking interrupts. Every
+//! ring and context is one page, and a transfer moves at most one page
+//! through a per-endpoint bounce buffer.
+//!
+//! Devices are known by USB address to the rest of the stack. Address 0
+//! is the device being enumerated: `attach` gives it a slot without
+//! sending SET_ADDRESS, and `set_device_address` later addresses it.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use super::controller::{ControllerType, PortChange, UsbController};
+use super::descriptor::EndpointDescriptor;
+use super::device::Attachment;
+use super::{DeviceAddress, EndpointNum, TransferType, UsbSpeed};
+use crate::memory::addr::PAGE_SIZE;
+
+/// Capability registers
+const CAP_CAPLENGTH: u64 = 0x00;
+const CAP_HCSPARAMS1: u64 = 0x04;
+const CAP_HCSPARAMS2: u64 = 0x08;
+const CAP_HCCPARAMS1: u64 = 0x10;
+const CAP_DBOFF: u64 = 0x14;
+const CAP_RTSOFF: u64 = 0x18;
+
+/// HCCPARAMS1 bits
+const HCC_AC64: u32 = 1 << 0;
+const HCC_CSZ: u32 = 1 << 2;
+
+/// Operational registers
+const OP_USBCMD: u64 = 0x00;
+const OP_USBSTS: u64 = 0x04;
+const OP_CRCR: u64 = 0x18;
+const OP_DCBAAP: u64 = 0x30;
+const OP_CONFIG: u64 = 0x38;
+const OP_PORTSC: u64 = 0x400;
+
+/// USBCMD bits
+const CMD_RUN: u32 = 1 << 0;
+const CMD_RESET: u32 = 1 << 1;
+
+/// USBSTS bits
+const STS_HALTED: u32 = 1 << 0;
+const STS_NOT_READY: u32 = 1 << 11;
+
+/// PORTSC bits
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+/// Change bits, cleared by writing 1
+const PORTSC_CHANGES: u32 = 0x7F << 17;
+/// Bits written back unchanged; the rest are write-1-to-act or read-only
+const PORTSC_NEUTRAL: u32 = 0x4E00_FDE9;
+
+/// Interrupter 0 registers, relative to the runtime registers
+const IR0_ERSTSZ: u64 = 0x28;
+const IR0_ERSTBA: u64 = 0x30;
+const IR0_ERDP: u64 = 0x38;
+
+/// ERDP bit: event handler busy, cleared by writing 1
+const ERDP_EHB: u64 = 1 << 3;
+
+/// Extended capability: BIOS/OS handoff
+const EXT_CAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+
+/// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+/// TRB control bits
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_BSR: u32 = 1 << 9;
+const TRB_DIR_IN: u32 = 1 << 16;
+
+/// Setup stage transfer types
+const TRT_NO_DATA: u32 = 0;
+const TRT_OUT: u32 = 2;
+const TRT_IN: u32 = 3;
+
+/// Completion codes
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// Endpoint types in endpoint contexts
+const EP_TYPE_CONTROL: u32 = 4;
+
+/// TRBs in a one-page ring
+const RING_TRBS: usize = PAGE_SIZE / 16;
+
+/// Time allowed for the controller to respond
+const TIMEOUT_MS: u64 = 1000;
+
+/// Time allowed for a bulk or control transfer
+const TRANSFER_TIMEOUT_MS: u64 = 5000;
+
+/// Transfer Request Block
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32, parameter: u64, status: u32, flags: u32) -> Self {
+        Self { parameter, status, control: (kind << 10) | flags }
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    /// Completion code of an event
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Slot of an event or command
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Endpoint (DCI) of a transfer event
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+}
+
+/// A page of RAM shared with the controller
+#[derive(Debug, Clone, Copy)]
+struct DmaPage {
+    virt: u64,
+    phys: u64,
+}
+
+impl DmaPage {
+    fn new() -> Result<Self, &'static str> {
+        let (virt, phys) = crate::memory::mmio::alloc_dma_page()?;
+        Ok(Self { virt, phys })
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        (self.virt as usize + offset) as *mut T
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { self.ptr::<u32>(offset).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { self.ptr::<u32>(offset).write_volatile(value) }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { self.ptr::<u64>(offset).write_volatile(value) }
+    }
+
+    fn clear(&self) {
+        unsafe { core::ptr::write_bytes(self.ptr::<u8>(0), 0, PAGE_SIZE) }
+    }
+
+    /// Copy `data` to the start of the page
+    fn copy_in(&self, data: &[u8]) {
+        let len = data.len().min(PAGE_SIZE);
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr(0), len) }
+    }
+
+    /// Fill `data` from the start of the page
+    fn copy_out(&self, data: &mut [u8]) {
+        let len = data.len().min(PAGE_SIZE);
+        unsafe { core::ptr::copy_nonoverlapping(self.ptr(0), data.as_mut_ptr(), len) }
+    }
+}
+
+/// Command or transfer ring: the driver produces, the controller consumes
+///
+/// The last TRB links back to the start and flips the cycle bit that
+/// tells the controller which TRBs are new.
+struct Ring {
+    page: DmaPage,
+    index: usize,
+    cycle: u32,
+}
+
+impl Ring {
+    fn new(page: DmaPage) -> Self {
+        Self { page, index: 0, cycle: TRB_CYCLE }
+    }
+
+    /// Physical address of the next TRB and the cycle state to start at
+    fn dequeue_pointer(&self) -> u64 {
+        (self.page.phys + (self.index * 16) as u64) | self.cycle as u64
+    }
+
+    /// Hand a TRB to the controller
+    ///
+    /// # Returns
+    /// The TRB's physical address, which events refer to
+    fn push(&mut self, trb: Trb) -> u64 {
+        let phys = self.page.phys + (self.index * 16) as u64;
+        self.write(self.index, trb);
+        self.index += 1;
+        if self.index == RING_TRBS - 1 {
+            // A chained transfer continues past the link
+            let link = Trb::new(TRB_LINK, self.page.phys, 0, TRB_TOGGLE_CYCLE | (trb.control & TRB_CHAIN));
+            self.write(self.index, link);
+            self.index = 0;
+            self.cycle ^= TRB_CYCLE;
+        }
+        phys
+    }
+
+    /// Write a TRB, the word with the cycle bit last
+    fn write(&self, index: usize, trb: Trb) {
+        let slot = self.page.ptr::<Trb>(index * 16);
+        unsafe {
+            core::ptr::addr_of_mut!((*slot).parameter).write_volatile(trb.parameter);
+            core::ptr::addr_of_mut!((*slot).status).write_volatile(trb.status);
+            fence(Ordering::Release);
+            core::ptr::addr_of_mut!((*slot).control).write_volatile((trb.control & !TRB_CYCLE) | self.cycle);
+        }
+    }
+}
+
+/// Event ring: the controller produces, the driver consumes
+struct EventRing {
+    segment: DmaPage,
+    index: usize,
+    cycle: u32,
+}
+
+impl EventRing {
+    fn new(segment: DmaPage) -> Self {
+        Self { segment, index: 0, cycle: TRB_CYCLE }
+    }
+
+    /// Take the next event, if the controller has written one
+    fn pop(&mut self) -> Option<Trb> {
+        let slot = self.segment.ptr::<Trb>(self.index * 16);
+        let control = unsafe { core::ptr::addr_of!((*slot).control).read_volatile() };
+        if control & TRB_CYCLE != self.cycle {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let trb = unsafe { slot.read_volatile() };
+        self.index += 1;
+        if self.index == RING_TRBS {
+            self.index = 0;
+            self.cycle ^= TRB_CYCLE;
+        }
+        Some(trb)
+    }
+
+    /// Physical address of the next event, for ERDP
+    fn dequeue_phys(&self) -> u64 {
+        self.segment.phys + (self.index * 16) as u64
+    }
+}
+
+/// Device context index of an endpoint: 1 for the default control
+/// endpoint, then two per endpoint number (OUT, IN)
+pub fn endpoint_index(endpoint_address: u8) -> u8 {
+    let number = endpoint_address & 0x0F;
+    if number == 0 {
+        1
+    } else {
+        number * 2 + (endpoint_address >> 7)
+    }
+}
+
+/// Speed ID of a PORTSC value
+fn port_speed(portsc: u32) -> Option<UsbSpeed> {
+    match (portsc >> 10) & 0xF {
+        1 => Some(UsbSpeed::Full),
+        2 => Some(UsbSpeed::Low),
+        3 => Some(UsbSpeed::High),
+        4 => Some(UsbSpeed::Super),
+        _ => None,
+    }
+}
+
+/// Speed ID for slot contexts
+fn speed_id(speed: UsbSpeed) -> u32 {
+    match speed {
+        UsbSpeed::Full => 1,
+        UsbSpeed::Low => 2,
+        UsbSpeed::High => 3,
+        UsbSpeed::Super => 4,
+    }
+}
+
+/// Default control endpoint packet size before the device descriptor
+/// says otherwise
+fn default_max_packet(speed: UsbSpeed) -> u16 {
+    match speed {
+        UsbSpeed::Low | UsbSpeed::Full => 8,
+        UsbSpeed::High => 64,
+        UsbSpeed::Super => 512,
+    }
+}
+
+/// Endpoint context interval (2^n * 125 us) for a descriptor's bInterval
+fn interval(speed: UsbSpeed, kind: TransferType, b_interval: u8) -> u32 {
+    match (speed, kind) {
+        // Frames of 1 ms
+        (UsbSpeed::Low | UsbSpeed::Full, TransferType::Interrupt) => {
+            let microframes = (b_interval.max(1) as u32) * 8;
+            (31 - microframes.leading_zeros()).clamp(3, 10)
+        }
+        (_, TransferType::Interrupt | TransferType::Isochronous) => (b_interval.clamp(1, 16) - 1) as u32,
+        _ => 0,
+    }
+}
+
+/// Endpoint context type for a transfer type and direction
+fn endpoint_type(kind: TransferType, dir_in: bool) -> u32 {
+    let base = match kind {
+        TransferType::Control => return EP_TYPE_CONTROL,
+        TransferType::Isochronous => 1,
+        TransferType::Bulk => 2,
+        TransferType::Interrupt => 3,
+    };
+    if dir_in { base + 4 } else { base }
+}
+
+/// Fill in an endpoint context
+fn write_endpoint_context(
+    context: &DmaPage,
+    offset: usize,
+    kind: u32,
+    max_packet: u16,
+    interval: u32,
+    dequeue: u64,
+) {
+    let average_trb = if kind == EP_TYPE_CONTROL { 8 } else { max_packet as u32 };
+    context.write32(offset, interval << 16);
+    // Three retries on errors
+    context.write32(offset + 4, (3 << 1) | (kind << 3) | ((max_packet as u32) << 16));
+    context.write64(offset + 8, dequeue);
+    context.write32(offset + 16, average_trb | ((max_packet as u32) << 16));
+}
+
+/// Outcome of a transfer, from its events
+#[derive(Debug, Clone, Copy, Default)]
+struct TransferState {
+    /// TRB whose completion ends the transfer
+    last_trb: u64,
+    /// Bytes not transferred, from a short packet
+    residual: u32,
+    /// Completion code, once done
+    done: Option<u8>,
+}
+
+/// A configured endpoint
+struct Endpoint {
+    ring: Ring,
+    /// Bounce buffer for transfers
+    buffer: DmaPage,
+    kind: TransferType,
+    dir_in: bool,
+    /// Transfer in progress or just finished, with its requested length
+    transfer: Option<(TransferState, usize)>,
+}
+
+impl Endpoint {
+    fn new(kind: TransferType, dir_in: bool) -> Result<Self, &'static str> {
+        Ok(Self {
+            ring: Ring::new(DmaPage::new()?),
+            buffer: DmaPage::new()?,
+            kind,
+            dir_in,
+            transfer: None,
+        })
+    }
+}
+
+/// A device slot
+struct Slot {
+    root_port: u8,
+    /// Route string through hubs
+    route: u32,
+    /// Slot context TT fields: hub slot and port of a transaction
+    /// translator, and whether the hub has one per port
+    tt: u32,
+    multi_tt: bool,
+    speed: UsbSpeed,
+    input: DmaPage,
+    output: DmaPage,
+    /// By device context index
+    endpoints: [Option<Endpoint>; 32],
+}
+
+/// Result of a command
+#[derive(Debug, Clone, Copy)]
+struct CommandResult {
+    trb: u64,
+    code: u8,
+    slot: u8,
+}
+
+/// An xHCI host controller
+pub struct XhciController {
+    /// Register blocks, mapped
+    cap: u64,
+    op: u64,
+    runtime: u64,
+    doorbells: u64,
+    max_slots: u8,
+    max_ports: u8,
+    /// Bytes per context (32 or 64)
+    context_size: usize,
+    dcbaa: Option<DmaPage>,
+    commands: Option<Ring>,
+    events: Option<EventRing>,
+    command_result: Option<CommandResult>,
+    /// By slot ID
+    slots: Vec<Option<Slot>>,
+    /// Slot of each USB address; address 0 is the device being enumerated
+    addresses: [u8; 128],
+    /// Ports with a status change event not yet looked at, by bit
+    changed_ports: u128,
+}
+
+impl XhciController {
+    /// Controller with its register BAR mapped at `base`
+    pub fn new(base: u64) -> Self {
+        Self {
+            cap: base,
+            op: 0,
+            runtime: 0,
+            doorbells: 0,
+            max_slots: 0,
+            max_ports: 0,
+            context_size: 32,
+            dcbaa: None,
+            commands: None,
+            events: None,
+            command_result: None,
+            slots: Vec::new(),
+            addresses: [0; 128],
+            changed_ports: 0,
+        }
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        unsafe { (addr as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, addr: u64, value: u32) {
+        unsafe { (addr as *mut u32).write_volatile(value) }
+    }
+
+    fn write64(&self, addr: u64, value: u64) {
+        self.write32(addr, value as u32);
+        self.write32(addr + 4, (value >> 32) as u32);
+    }
+
+    fn portsc(&self, port: u8) -> u64 {
+        self.op + OP_PORTSC + 0x10 * (port as u64 - 1)
+    }
+
+    /// Wait until `done` holds, up to `timeout_ms`
+    fn wait(&mut self, timeout_ms: u64, mut done: impl FnMut(&mut Self) -> bool) -> Result<(), &'static str> {
+        let deadline = crate::task::time::uptime_ms() + timeout_ms;
+        while !done(self) {
+            if crate::task::time::uptime_ms() > deadline {
+                return Err("xHCI: timed out");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Take ownership from the firmware's legacy USB support
+    fn bios_handoff(&mut self) -> Result<(), &'static str> {
+        let mut offset = ((self.read32(self.cap + CAP_HCCPARAMS1) >> 16) as u64) * 4;
+        while offset != 0 {
+            let addr = self.cap + offset;
+            let cap = self.read32(addr);
+            if cap & 0xFF == EXT_CAP_LEGACY {
+                self.write32(addr, cap | LEGACY_OS_OWNED);
+                return self.wait(TIMEOUT_MS, |hc| hc.read32(addr) & LEGACY_BIOS_OWNED == 0);
+            }
+            offset = match (cap >> 8) & 0xFF {
+                0 => 0,
+                next => offset + next as u64 * 4,
+            };
+        }
+        Ok(())
+    }
+
+    /// Stop the controller and reset it
+    fn halt_and_reset(&mut self) -> Result<(), &'static str> {
+        let cmd = self.read32(self.op + OP_USBCMD);
+        self.write32(self.op + OP_USBCMD, cmd & !CMD_RUN);
+        self.wait(TIMEOUT_MS, |hc| hc.read32(hc.op + OP_USBSTS) & STS_HALTED != 0)?;
+        self.write32(self.op + OP_USBCMD, CMD_RESET);
+        self.wait(TIMEOUT_MS, |hc| {
+            hc.read32(hc.op + OP_USBCMD) & CMD_RESET == 0 && hc.read32(hc.op + OP_USBSTS) & STS_NOT_READY == 0
+        })
+    }
+
+    /// Give the controller the scratchpad pages it asks for
+    fn setup_scratchpad(&mut self, dcbaa: &DmaPage) -> Result<(), &'static str> {
+        let params = self.read32(self.cap + CAP_HCSPARAMS2);
+        let count = (((params >> 21) & 0x1F) << 5 | (params >> 27)) as usize;
+        if count == 0 {
+            return Ok(());
+        }
+        if count > PAGE_SIZE / 8 {
+            return Err("xHCI: too many scratchpad buffers");
+        }
+        let array = DmaPage::new()?;
+        for i in 0..count {
+            array.write64(i * 8, DmaPage::new()?.phys);
+        }
+        dcbaa.write64(0, array.phys);
+        Ok(())
+    }
+
+    /// Move events from the event ring into command, transfer and port
+    /// state
+    fn process_events(&mut self) {
+        let Some(mut events) = self.events.take() else {
+            return;
+        };
+        let mut any = false;
+        while let Some(event) = events.pop() {
+            any = true;
+            match event.kind() {
+                TRB_COMMAND_COMPLETION => {
+                    self.command_result = Some(CommandResult {
+                        trb: event.parameter,
+                        code: event.completion_code(),
+                        slot: event.slot(),
+                    });
+                }
+                TRB_TRANSFER_EVENT => self.transfer_event(&event),
+                TRB_PORT_STATUS_CHANGE => {
+                    let port = (event.parameter >> 24) as u8;
+                    if port > 0 && (port as u32) < 128 {
+                        self.changed_ports |= 1 << port;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if any {
+            self.write64(self.runtime + IR0_ERDP, events.dequeue_phys() | ERDP_EHB);
+        }
+        self.events = Some(events);
+    }
+
+    /// Record a transfer event on its endpoint
+    fn transfer_event(&mut self, event: &Trb) {
+        let Some(Some(slot)) = self.slots.get_mut(event.slot() as usize) else {
+            return;
+        };
+        let Some(Some(endpoint)) = slot.endpoints.get_mut(event.endpoint() as usize) else {
+            return;
+        };
+        let Some((state, _)) = endpoint.transfer.as_mut() else {
+            return;
+        };
+        let code = event.completion_code();
+        if code == COMPLETION_SHORT_PACKET {
+            state.residual = event.status & 0xFF_FFFF;
+        }
+        if event.parameter == state.last_trb || (code != COMPLETION_SUCCESS && code != COMPLETION_SHORT_PACKET) {
+            state.done = Some(code);
+        }
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        fence(Ordering::SeqCst);
+        self.write32(self.doorbells + slot as u64 * 4, target as u32);
+    }
+
+    /// Run a command and wait for its completion
+    ///
+    /// # Returns
+    /// The slot ID from the completion event
+    fn command(&mut self, trb: Trb) -> Result<u8, &'static str> {
+        let commands = self.commands.as_mut().ok_or("xHCI: not initialized")?;
+        let phys = commands.push(trb);
+        self.command_result = None;
+        self.ring_doorbell(0, 0);
+        self.wait(TIMEOUT_MS, |hc| {
+            hc.process_events();
+            hc.command_result.is_some_and(|result| result.trb == phys)
+        })?;
+        match self.command_result.take() {
+            Some(result) if result.code == COMPLETION_SUCCESS => Ok(result.slot),
+            _ => Err("xHCI: command failed"),
+        }
+    }
+
+    fn slot(&mut self, address: DeviceAddress) -> Result<(u8, &mut Slot), &'static str> {
+        let id = *self.addresses.get(address as usize).ok_or("Invalid USB address")?;
+        match self.slots.get_mut(id as usize) {
+            Some(Some(slot)) if id != 0 => Ok((id, slot)),
+            _ => Err("No such USB device"),
+        }
+    }
+
+    /// Describe the slot and control endpoint in the input context and
+    /// issue Address Device; `block` keeps the device at address 0
+    fn address_device(&mut self, id: u8, block: bool) -> Result<(), &'static str> {
+        let ctx = self.context_size;
+        let slot = self.slots[id as usize].as_mut().ok_or("No such USB device")?;
+        let endpoint = slot.endpoints[1].as_ref().ok_or("No control endpoint")?;
+        let input = slot.input;
+        // Keep a packet size set by `set_max_packet_size`
+        let max_packet = match input.read32(ctx * 2 + 4) >> 16 {
+            0 => default_max_packet(slot.speed),
+            size => size as u16,
+        };
+        input.clear();
+        input.write32(4, 0b11);
+        let multi_tt = (slot.multi_tt as u32) << 25;
+        input.write32(ctx, (1 << 27) | multi_tt | (speed_id(slot.speed) << 20) | slot.route);
+        input.write32(ctx + 4, (slot.root_port as u32) << 16);
+        input.write32(ctx + 8, slot.tt);
+        write_endpoint_context(&input, ctx * 2, EP_TYPE_CONTROL, max_packet, 0, endpoint.ring.dequeue_pointer());
+        let block = if block { TRB_BSR } else { 0 };
+        let flags = ((id as u32) << 24) | block;
+        self.command(Trb::new(TRB_ADDRESS_DEVICE, input.phys, 0, flags)).map(|_| ())
+    }
+
+    /// Get a halted endpoint going again after an error
+    fn recover_endpoint(&mut self, id: u8, dci: u8) -> Result<(), &'static str> {
+        let target = (id as u32) << 24 | (dci as u32) << 16;
+        self.command(Trb::new(TRB_RESET_ENDPOINT, 0, 0, target))?;
+        let dequeue = self.slots[id as usize]
+            .as_ref()
+            .and_then(|slot| slot.endpoints[dci as usize].as_ref())
+            .map(|endpoint| endpoint.ring.dequeue_pointer())
+            .ok_or("No such endpoint")?;
+        self.command(Trb::new(TRB_SET_TR_DEQUEUE, dequeue, 0, target)).map(|_| ())
+    }
+
+    /// Queue TRBs on an endpoint and ring its doorbell
+    fn start_transfer(&mut self, id: u8, dci: u8, trbs: &[Trb], len: usize) -> Result<(), &'static str> {
+        let slot = self.slots[id as usize].as_mut().ok_or("No such USB device")?;
+        let endpoint = slot.endpoints[dci as usize].as_mut().ok_or("No such endpoint")?;
+        let mut last_trb = 0;
+        for trb in trbs {
+            last_trb = endpoint.ring.push(*trb);
+        }
+        endpoint.transfer = Some((TransferState { last_trb, ..TransferState::default() }, len));
+        self.ring_doorbell(id, dci);
+        Ok(())
+    }
+
+    /// The finished transfer on an endpoint, if it has finished
+    fn finished(&mut self, id: u8, dci: u8) -> Option<(TransferState, usize)> {
+        let endpoint = self.slots[id as usize].as_mut()?.endpoints[dci as usize].as_mut()?;
+        match endpoint.transfer {
+            Some((state, _)) if state.done.is_some() => endpoint.transfer.take(),
+            _ => None,
+        }
+    }
+
+    /// Wait for the transfer on an endpoint and return the bytes moved
+    fn finish_transfer(&mut self, id: u8, dci: u8, timeout_ms: u64) -> Result<usize, &'static str> {
+        let mut result = None;
+        self.wait(timeout_ms, |hc| {
+            hc.process_events();
+            result = hc.finished(id, dci);
+            result.is_some()
+        })?;
+        let (state, len) = result.ok_or("xHCI: transfer lost")?;
+        match state.done {
+            Some(COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET) => Ok(len.saturating_sub(state.residual as usize)),
+            _ => {
+                self.recover_endpoint(id, dci)?;
+                Err("USB transfer failed")
+            }
+        }
+    }
+
+    /// The bounce buffer of an endpoint
+    fn buffer(&self, id: u8, dci: u8) -> Result<DmaPage, &'static str> {
+        self.slots[id as usize]
+            .as_ref()
+            .and_then(|slot| slot.endpoints[dci as usize].as_ref())
+            .map(|endpoint| endpoint.buffer)
+            .ok_or("No such endpoint")
+    }
+
+    /// Device context index of a configured endpoint number, IN first
+    fn find_endpoint(&mut self, address: DeviceAddress, endpoint: EndpointNum, kind: TransferType) -> Result<(u8, u8, bool), &'static str> {
+        let (id, slot) = self.slot(address)?;
+        for dci in [endpoint_index(endpoint | 0x80), endpoint_index(endpoint)] {
+            if let Some(found) = slot.endpoints[dci as usize].as_ref().filter(|found| found.kind == kind) {
+                return Ok((id, dci, found.dir_in));
+            }
+        }
+        Err("No such endpoint")
+    }
+
+    /// Run a transfer on a bulk or interrupt endpoint, waiting for it
+    fn normal_transfer(&mut self, id: u8, dci: u8, dir_in: bool, data: &mut [u8]) -> Result<usize, &'static str> {
+        let len = data.len().min(PAGE_SIZE);
+        let buffer = self.buffer(id, dci)?;
+        if !dir_in {
+            buffer.copy_in(&data[..len]);
+        }
+        let trb = Trb::new(TRB_NORMAL, buffer.phys, len as u32, TRB_IOC | TRB_ISP);
+        self.start_transfer(id, dci, &[trb], len)?;
+        let moved = self.finish_transfer(id, dci, TRANSFER_TIMEOUT_MS)?;
+        if dir_in {
+            buffer.copy_out(&mut data[..moved]);
+        }
+        Ok(moved)
+    }
+}
+
+impl UsbController for XhciController {
+    fn init(&mut self) -> Result<(), &'static str> {
+        let cap_length = self.read32(self.cap + CAP_CAPLENGTH) & 0xFF;
+        self.op = self.cap + cap_length as u64;
+        self.runtime = self.cap + (self.read32(self.cap + CAP_RTSOFF) & !0x1F) as u64;
+        self.doorbells = self.cap + (self.read32(self.cap + CAP_DBOFF) & !0x3) as u64;
+        let params = self.read32(self.cap + CAP_HCSPARAMS1);
+        self.max_slots = params as u8;
+        self.max_ports = (params >> 24) as u8;
+        let hcc = self.read32(self.cap + CAP_HCCPARAMS1);
+        self.context_size = if hcc & HCC_CSZ != 0 { 64 } else { 32 };
+
+        self.bios_handoff()?;
+        self.halt_and_reset()?;
+
+        let dcbaa = DmaPage::new()?;
+        let commands = Ring::new(DmaPage::new()?);
+        let events = EventRing::new(DmaPage::new()?);
+        if hcc & HCC_AC64 == 0 && (dcbaa.phys | commands.page.phys | events.segment.phys) >> 32 != 0 {
+            return Err("xHCI: 32-bit controller, memory above 4 GiB");
+        }
+        self.setup_scratchpad(&dcbaa)?;
+
+        // One segment in the event ring segment table
+        let erst = DmaPage::new()?;
+        erst.write64(0, events.segment.phys);
+        erst.write32(8, RING_TRBS as u32);
+
+        self.write32(self.op + OP_CONFIG, self.max_slots as u32);
+        self.write64(self.op + OP_DCBAAP, dcbaa.phys);
+        self.write64(self.op + OP_CRCR, commands.dequeue_pointer());
+        self.write32(self.runtime + IR0_ERSTSZ, 1);
+        self.write64(self.runtime + IR0_ERDP, events.dequeue_phys());
+        self.write64(self.runtime + IR0_ERSTBA, erst.phys);
+
+        self.dcbaa = Some(dcbaa);
+        self.commands = Some(commands);
+        self.events = Some(events);
+        self.slots = (0..=self.max_slots as usize).map(|_| None).collect();
+        self.addresses = [0; 128];
+
+        self.write32(self.op + OP_USBCMD, CMD_RUN);
+        self.wait(TIMEOUT_MS, |hc| hc.read32(hc.op + OP_USBSTS) & STS_HALTED == 0)?;
+
+        // Power ports whose power is switched
+        for port in 1..=self.max_ports {
+            let addr = self.portsc(port);
+            let portsc = self.read32(addr);
+            if portsc & PORTSC_PP == 0 {
+                self.write32(addr, (portsc & PORTSC_NEUTRAL) | PORTSC_PP);
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), &'static str> {
+        self.halt_and_reset()
+    }
+
+    fn name(&self) -> &'static str {
+        "xHCI"
+    }
+
+    fn controller_type(&self) -> ControllerType {
+        ControllerType::XHCI
+    }
+
+    fn enumerate_devices(&mut self) -> Result<Vec<DeviceAddress>, &'static str> {
+        Ok((1..128u8).filter(|&address| self.addresses[address as usize] != 0).collect())
+    }
+
+    fn control_transfer(
+        &mut self,
+        address: DeviceAddress,
+        endpoint: EndpointNum,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        if endpoint != 0 {
+            return Err("Control transfers use endpoint 0");
+        }
+        let (id, _) = self.slot(address)?;
+        let len = data.len().min(PAGE_SIZE);
+        let dir_in = request_type & 0x80 != 0;
+        let buffer = self.buffer(id, 1)?;
+        if !dir_in {
+            buffer.copy_in(&data[..len]);
+        }
+
+        let setup = request_type as u64
+            | (request as u64) << 8
+            | (value as u64) << 16
+            | (index as u64) << 32
+            | (len as u64) << 48;
+        let transfer_type = match (len, dir_in) {
+            (0, _) => TRT_NO_DATA,
+            (_, true) => TRT_IN,
+            (_, false) => TRT_OUT,
+        };
+        let direction = if dir_in { TRB_DIR_IN } else { 0 };
+        let mut trbs = Vec::with_capacity(3);
+        trbs.push(Trb::new(TRB_SETUP, setup, 8, TRB_IDT | transfer_type << 16));
+        if len > 0 {
+            trbs.push(Trb::new(TRB_DATA, buffer.phys, len as u32, TRB_ISP | direction));
+        }
+        // The status stage goes the other way, IN when there is no data
+        let status_direction = if len > 0 && dir_in { 0 } else { TRB_DIR_IN };
+        trbs.push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC | status_direction));
+
+        self.start_transfer(id, 1, &trbs, len)?;
+        let moved = self.finish_transfer(id, 1, TRANSFER_TIMEOUT_MS)?;
+        if dir_in {
+            buffer.copy_out(&mut data[..moved]);
+        }
+        Ok(moved)
+    }
+
+    /// Poll an interrupt endpoint without waiting
+    ///
+    /// Keeps one transfer queued on the endpoint and returns 0 until it
+    /// completes.
+    fn interrupt_transfer(
+        &mut self,
+        address: DeviceAddress,
+        endpoint: EndpointNum,
+        data: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        let (id, dci, dir_in) = self.find_endpoint(address, endpoint, TransferType::Interrupt)?;
+        let buffer = self.buffer(id, dci)?;
+        let queued = self.slots[id as usize]
+            .as_ref()
+            .and_then(|slot| slot.endpoints[dci as usize].as_ref())
+            .is_some_and(|endpoint| endpoint.transfer.is_some());
+        if !queued {
+            let len = data.len().min(PAGE_SIZE);
+            if !dir_in {
+                buffer.copy_in(&data[..len]);
+            }
+            let trb = Trb::new(TRB_NORMAL, buffer.phys, len as u32, TRB_IOC | TRB_ISP);
+            self.start_transfer(id, dci, &[trb], len)?;
+        }
+
+        self.process_events();
+        let Some((state, len)) = self.finished(id, dci) else {
+            return Ok(0);
+        };
+        match state.done {
+            Some(COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET) => {
+                let moved = len.saturating_sub(state.residual as usize).min(data.len());
+                if dir_in {
+                    buffer.copy_out(&mut data[..moved]);
+                }
+                Ok(moved)
+            }
+            _ => {
+                self.recover_endpoint(id, dci)?;
+                Err("USB transfer failed")
+            }
+        }
+    }
+
+    fn bulk_transfer(
+        &mut self,
+        address: DeviceAddress,
+        endpoint: EndpointNum,
+        data: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        let (id, dci, dir_in) = self.find_endpoint(address, endpoint, TransferType::Bulk)?;
+        self.normal_transfer(id, dci, dir_in, data)
+    }
+
+    /// Address the device being enumerated (address 0)
+    ///
+    /// The controller picks the address it sends to the device; the rest
+    /// of the stack keeps using `new_address`.
+    fn set_device_address(
+        &mut self,
+        old_address: DeviceAddress,
+        new_address: DeviceAddress,
+    ) -> Result<(), &'static str> {
+        if old_address != 0 || new_address == 0 || new_address as usize >= self.addresses.len() {
+            return Err("Invalid USB address");
+        }
+        if self.addresses[new_address as usize] != 0 {
+            return Err("USB address in use");
+        }
+        let (id, _) = self.slot(0)?;
+        self.address_device(id, false)?;
+        self.addresses[0] = 0;
+        self.addresses[new_address as usize] = id;
+        // SET_ADDRESS recovery
+        crate::task::time::delay_ms(2);
+        Ok(())
+    }
+
+    fn port_count(&self) -> u8 {
+        self.max_ports
+    }
+
+    fn port_connected(&self, port: u8) -> bool {
+        port != 0 && port <= self.max_ports && self.read32(self.portsc(port)) & PORTSC_CCS != 0
+    }
+
+    fn reset_port(&mut self, port: u8) -> Result<UsbSpeed, &'static str> {
+        if port == 0 || port > self.max_ports {
+            return Err("No such USB port");
+        }
+        let addr = self.portsc(port);
+        let portsc = self.read32(addr);
+        if portsc & PORTSC_CCS == 0 {
+            return Err("Nothing connected to the USB port");
+        }
+        self.write32(addr, (portsc & PORTSC_NEUTRAL) | PORTSC_PR);
+        self.wait(TIMEOUT_MS, |hc| hc.read32(addr) & PORTSC_PRC != 0)?;
+        let portsc = self.read32(addr);
+        self.write32(addr, (portsc & PORTSC_NEUTRAL) | PORTSC_PRC);
+        if portsc & PORTSC_PED == 0 {
+            return Err("USB port not enabled after reset");
+        }
+        // Reset recovery
+        crate::task::time::delay_ms(10);
+        port_speed(portsc).ok_or("Unknown USB port speed")
+    }
+
+    /// Give the device a slot as address 0, ready for control transfers
+    /// but not yet addressed
+    fn attach(&mut self, attachment: &Attachment) -> Result<(), &'static str> {
+        if self.addresses[0] != 0 {
+            return Err("A USB device is already being enumerated");
+        }
+        let (tt, multi_tt) = match attachment.tt {
+            Some(tt) => {
+                let hub = *self.addresses.get(tt.hub as usize).ok_or("Invalid USB address")?;
+                ((hub as u32) | (tt.port as u32) << 8, tt.multi)
+            }
+            None => (0, false),
+        };
+        let id = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?;
+        if id == 0 || id > self.max_slots {
+            return Err("xHCI: bad slot ID");
+        }
+        let mut slot = Slot {
+            root_port: attachment.root_port,
+            route: attachment.route & 0xF_FFFF,
+            tt,
+            multi_tt,
+            speed: attachment.speed,
+            input: DmaPage::new()?,
+            output: DmaPage::new()?,
+            endpoints: [const { None }; 32],
+        };
+        slot.endpoints[1] = Some(Endpoint::new(TransferType::Control, false)?);
+        self.dcbaa.as_ref().ok_or("xHCI: not initialized")?.write64(id as usize * 8, slot.output.phys);
+        self.slots[id as usize] = Some(slot);
+        self.addresses[0] = id;
+
+        if let Err(e) = self.address_device(id, true) {
+            let _ = self.detach(0);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn set_max_packet_size(&mut self, address: DeviceAddress, max_packet: u16) -> Result<(), &'static str> {
+        let ctx = self.context_size;
+        let (id, slot) = self.slot(address)?;
+        let input = slot.input;
+        input.write32(0, 0);
+        input.write32(4, 1 << 1);
+        let word = input.read32(ctx * 2 + 4);
+        input.write32(ctx * 2 + 4, (word & 0xFFFF) | (max_packet as u32) << 16);
+        self.command(Trb::new(TRB_EVALUATE_CONTEXT, input.phys, 0, (id as u32) << 24)).map(|_| ())
+    }
+
+    fn configure_endpoint(&mut self, address: DeviceAddress, descriptor: &EndpointDescriptor) -> Result<(), &'static str> {
+        let ctx = self.context_size;
+        let (id, slot) = self.slot(address)?;
+        let endpoint_address = descriptor.endpoint_address;
+        let dci = endpoint_index(endpoint_address) as usize;
+        let dir_in = endpoint_address & 0x80 != 0;
+        let kind = match descriptor.attributes & 0x03 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        };
+        let max_packet = { descriptor.max_packet_size } & 0x7FF;
+        let endpoint = Endpoint::new(kind, dir_in)?;
+
+        // Start from the slot as the controller has it
+        let input = slot.input;
+        input.clear();
+        input.write32(4, 1 | (1 << dci));
+        unsafe {
+            core::ptr::copy_nonoverlapping(slot.output.ptr::<u8>(0), input.ptr::<u8>(ctx), ctx);
+        }
+        let entries = ((input.read32(ctx) >> 27) as usize).max(dci);
+        input.write32(ctx, (input.read32(ctx) & 0x07FF_FFFF) | (entries as u32) << 27);
+        // Slot state and device address are output fields
+        input.write32(ctx + 12, 0);
+        write_endpoint_context(
+            &input,
+            ctx * (dci + 1),
+            endpoint_type(kind, dir_in),
+            max_packet,
+            interval(slot.speed, kind, descriptor.interval),
+            endpoint.ring.dequeue_pointer(),
+        );
+        slot.endpoints[dci] = Some(endpoint);
+        let result = self.command(Trb::new(TRB_CONFIGURE_ENDPOINT, input.phys, 0, (id as u32) << 24));
+        if result.is_err() {
+            if let Ok((_, slot)) = self.slot(address) {
+                slot.endpoints[dci] = None;
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Mark the slot as a hub; the controller needs this to reach the
+    /// devices behind it
+    fn configure_hub(
+        &mut self,
+        address: DeviceAddress,
+        ports: u8,
+        multi_tt: bool,
+        think_time: u8,
+    ) -> Result<(), &'static str> {
+        let ctx = self.context_size;
+        let (id, slot) = self.slot(address)?;
+        let input = slot.input;
+        input.clear();
+        input.write32(4, 1);
+        unsafe {
+            core::ptr::copy_nonoverlapping(slot.output.ptr::<u8>(0), input.ptr::<u8>(ctx), ctx);
+        }
+        input.write32(ctx, input.read32(ctx) | (1 << 26) | (multi_tt as u32) << 25);
+        input.write32(ctx + 4, (input.read32(ctx + 4) & 0x00FF_FFFF) | (ports as u32) << 24);
+        input.write32(ctx + 8, (input.read32(ctx + 8) & !(0x3 << 16)) | ((think_time as u32) & 0x3) << 16);
+        input.write32(ctx + 12, 0);
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT, input.phys, 0, (id as u32) << 24)).map(|_| ())
+    }
+
+    /// Report connect status changes, from Port Status Change events and
+    /// the change bits
+    ///
+    /// The first call after `init` reports every connected port.
+    fn port_changes(&mut self) -> Vec<PortChange> {
+        self.process_events();
+        let mut changes = Vec::new();
+        for port in 1..=self.max_ports {
+            let addr = self.portsc(port);
+            let portsc = self.read32(addr);
+            let flagged = self.changed_ports & (1 << port) != 0;
+            if !flagged && portsc & PORTSC_CHANGES == 0 {
+                continue;
+            }
+            self.write32(addr, (portsc & PORTSC_NEUTRAL) | (portsc & PORTSC_CHANGES));
+            if portsc & PORTSC_CSC != 0 {
+                let connected = portsc & PORTSC_CCS != 0;
+                changes.push(PortChange { port, connected, speed: port_speed(portsc).filter(|_| connected) });
+            }
+        }
+        self.changed_ports = 0;
+        changes
+    }
+
+    /// Stop endpoints with transfers in flight and disable the device's
+    /// slot
+    fn detach(&mut self, address: DeviceAddress) -> Result<(), &'static str> {
+        let (id, slot) = self.slot(address)?;
+        let busy: Vec<usize> = (1..slot.endpoints.len())
+            .filter(|&dci| slot.endpoints[dci].as_ref().is_some_and(|endpoint| endpoint.transfer.is_some()))
+            .collect();
+        for dci in busy {
+            // Fails if the endpoint already halted, which is fine
+            let _ = self.command(Trb::new(TRB_STOP_ENDPOINT, 0, 0, (id as u32) << 24 | (dci as u32) << 16));
+        }
+        self.addresses[address as usize] = 0;
+        self.slots[id as usize] = None;
+        if let Some(dcbaa) = &self.dcbaa {
+            dcbaa.write64(id as usize * 8, 0);
+        }
+        self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (id as u32) << 24)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[repr(align(4096))]
+    struct Page([u8; PAGE_SIZE]);
+
+    /// A leaked page posing as DMA memory at physical `phys`
+    fn page(phys: u64) -> DmaPage {
+        let memory: &'static mut Page = Box::leak(Box::new(Page([0; PAGE_SIZE])));
+        DmaPage { virt: memory.0.as_mut_ptr() as u64, phys }
+    }
+
+    fn read(page: &DmaPage, index: usize) -> Trb {
+        unsafe { page.ptr::<Trb>(index * 16).read() }
+    }
+
+    #[test]
+    fn test_ring_wraps_through_link() {
+        let mut ring = Ring::new(page(0x10000));
+        assert_eq!(ring.dequeue_pointer(), 0x10001);
+        assert_eq!(ring.push(Trb::new(TRB_NORMAL, 0xAA, 8, TRB_IOC)), 0x10000);
+        let first = read(&ring.page, 0);
+        assert_eq!(first.kind(), TRB_NORMAL);
+        assert_eq!(first.control & TRB_CYCLE, TRB_CYCLE);
+
+        for _ in 1..RING_TRBS - 2 {
+            ring.push(Trb::new(TRB_NORMAL, 0, 0, 0));
+        }
+        // The last slot before the link, in a chained transfer
+        ring.push(Trb::new(TRB_DATA, 0, 0, TRB_CHAIN));
+        let link = read(&ring.page, RING_TRBS - 1);
+        assert_eq!(link.kind(), TRB_LINK);
+        assert_eq!(link.parameter, 0x10000);
+        assert_eq!(link.control & (TRB_TOGGLE_CYCLE | TRB_CHAIN | TRB_CYCLE), TRB_TOGGLE_CYCLE | TRB_CHAIN | TRB_CYCLE);
+
+        // Back at the start with the cycle bit flipped
+        assert_eq!(ring.dequeue_pointer(), 0x10000);
+        ring.push(Trb::new(TRB_NORMAL, 0, 0, 0));
+        assert_eq!(read(&ring.page, 0).control & TRB_CYCLE, 0);
+    }
+
+    #[test]
+    fn test_event_ring_follows_cycle() {
+        let segment = page(0x20000);
+        let mut events = EventRing::new(segment);
+        assert!(events.pop().is_none());
+
+        let event = Trb::new(TRB_COMMAND_COMPLETION, 0x1234, (COMPLETION_SUCCESS as u32) << 24, 3 << 24 | TRB_CYCLE);
+        unsafe { segment.ptr::<Trb>(0).write(event) };
+        let popped = events.pop().unwrap();
+        assert_eq!(popped.kind(), TRB_COMMAND_COMPLETION);
+        assert_eq!(popped.completion_code(), COMPLETION_SUCCESS);
+        assert_eq!(popped.slot(), 3);
+        assert!(events.pop().is_none());
+        assert_eq!(events.dequeue_phys(), 0x20010);
+    }
+
+    #[test]
+    fn test_endpoint_encoding() {
+        assert_eq!(endpoint_index(0x00), 1);
+        assert_eq!(endpoint_index(0x01), 2);
+        assert_eq!(endpoint_index(0x81), 3);
+        assert_eq!(endpoint_index(0x82), 5);
+
+        assert_eq!(endpoint_type(TransferType::Interrupt, true), 7);
+        assert_eq!(endpoint_type(TransferType::Bulk, false), 2);
+        assert_eq!(endpoint_type(TransferType::Control, true), EP_TYPE_CONTROL);
+
+        // 10 ms at full speed is 80 microframes: 2^6 rounded down
+        assert_eq!(interval(UsbSpeed::Full, TransferType::Interrupt, 10), 6);
+        assert_eq!(interval(UsbSpeed::Low, TransferType::Interrupt, 1), 3);
+        assert_eq!(interval(UsbSpeed::High, TransferType::Interrupt, 4), 3);
+        assert_eq!(interval(UsbSpeed::Super, TransferType::Bulk, 4), 0);
+
+        assert_eq!(port_speed(3 << 10 | PORTSC_CCS), Some(UsbSpeed::High));
+        assert_eq!(port_speed(0), None);
+    }
+
+    #[test]
+    fn test_transfer_event_completes_last_trb() {
+        let mut hc = XhciController::new(0);
+        hc.slots = (0..4).map(|_| None).collect();
+        let mut slot = Slot {
+            root_port: 1,
+            route: 0,
+            tt: 0,
+            multi_tt: false,
+            speed: UsbSpeed::High,
+            input: page(0x30000),
+            output: page(0x31000),
+            endpoints: [const { None }; 32],
+        };
+        slot.endpoints[1] = Some(Endpoint {
+            ring: Ring::new(page(0x32000)),
+            buffer: page(0x33000),
+            kind: TransferType::Control,
+            dir_in: true,
+            transfer: Some((TransferState { last_trb: 0x32020, ..TransferState::default() }, 18)),
+        });
+        hc.slots[2] = Some(slot);
+
+        // Short data stage, then the status stage
+        let event = |trb: u64, code: u8, residual: u32| {
+            Trb::new(TRB_TRANSFER_EVENT, trb, (code as u32) << 24 | residual, 2 << 24 | 1 << 16)
+        };
+        hc.transfer_event(&event(0x32010, COMPLETION_SHORT_PACKET, 10));
+        assert!(hc.finished(2, 1).is_none());
+        hc.transfer_event(&event(0x32020, COMPLETION_SUCCESS, 0));
+        let (state, len) = hc.finished(2, 1).unwrap();
+        assert_eq!((state.done, len - state.residual as usize), (Some(COMPLETION_SUCCESS), 8));
+        assert!(hc.finished(2, 1).is_none());
+    }
+}
@@ -0,0 +1,48 @@
+//! The Init Process
+//!
+//! The first user process, started from the root file system (usually
+//! unpacked from the initramfs) at the end of boot. It runs as `INIT_PID`,
+//! adopts the children of processes that exit and starts the shell on the
+//! console. `init=` on the command line names another program than
+//! `/init`.
+
+use crate::elf::ElfLoadError;
+use crate::fs::FsError;
+use crate::task::{pgroup, TaskId};
+use super::loader::spawn_init;
+
+/// Program run as init unless `init=` names another
+pub const DEFAULT_INIT: &str = "/init";
+
+/// Environment init starts with
+pub const INIT_ENV: &[&str] = &["HOME=/", "PATH=/bin:/sbin:/usr/bin", "TERM=linux", "SHELL=/bin/sh"];
+
+/// Path of the init program
+pub fn init_path() -> &'static str {
+    crate::cmdline::option("init").unwrap_or(DEFAULT_INIT)
+}
+
+/// Start the init process
+///
+/// Init leads the first session, whose process group takes the console.
+///
+/// # Returns
+/// Init's task, `INIT_PID`
+pub fn start() -> Result<TaskId, &'static str> {
+    let path = init_path();
+    let binary = crate::fs::read_file(path).map_err(FsError::as_str)?;
+    let init = spawn_init(&binary, &[path], INIT_ENV).map_err(ElfLoadError::as_str)?;
+
+    let mut groups = pgroup::process_groups();
+    let sid = groups.create_session(init)?;
+    let pgid = groups.get_process_group(init).ok_or("No process group")?;
+    groups.set_foreground(pgid)?;
+    if let Some(tty) = crate::io::tty::active() {
+        if let Some(session) = groups.get_session_mut(sid) {
+            session.set_controlling_terminal(tty.id());
+        }
+        drop(groups);
+        tty.set_foreground_pgrp(Some(pgid));
+    }
+    Ok(init)
+}
@@ -1,19 +1,228 @@
 //! User Binary Loader
 //!
 //! Loads ELF binaries and prepares them for execution in user mode.
+//!
+//! Each binary gets its own randomized layout from the kernel CSPRNG,
+//! unless `norandmaps` is on the command line: the stack top and the base
+//! of the mmap area move down by a random number of pages, and a position
+//! independent executable and its shared libraries move up.
+//!
+//! A binary with a PT_TLS segment gets a TLS block for its main thread
+//! just below the mmap area, which the FS base points into when it starts.
+//!
+//! Shared libraries named by a binary's `DT_NEEDED` entries are read from
+//! the `LIBRARY_PATH` directories and linked in by the kernel.
+//!
+//! The binary, its libraries, the stack and the TLS block are mapped into
+//! a new address space with the protection of their segments, and the
+//! linked images are copied in.
+
+use alloc::vec::Vec;
+
+use crate::elf::{
+    load_program, program_flags, ElfImage, ElfLoadError, ImageSegment, LoadBases, LIBRARY_BASE, PIE_BASE,
+};
+use crate::fs::PathResolver;
+use crate::memory::{AddressSpace, MmapProt, VirtAddr, PhysAddr, VmaMap};
+use crate::random;
+use crate::task::{self, TaskId, TaskPriority, TlsTemplate};
+use crate::task::sigdeliver::UserMemory;
+use super::transition::{auxv, prepare_usermode_stack, user_entry, InitialStack};
+
+/// Highest user stack top
+pub const STACK_TOP: u64 = 0x7fff_ffff_f000;
+
+/// Range the stack top is moved down within (16 GiB)
+pub const STACK_RANDOM_RANGE: u64 = 1 << 34;
+
+/// Highest base of the mmap area, which grows down
+pub const MMAP_BASE: u64 = 0x7ff0_0000_0000;
+
+/// Range the mmap base is moved down within (1 TiB)
+pub const MMAP_RANDOM_RANGE: u64 = 1 << 40;
+
+/// Range an ET_DYN executable is moved up within from `PIE_BASE` (1 TiB)
+pub const PIE_RANDOM_RANGE: u64 = 1 << 40;
 
-use crate::elf::{load_elf, ElfLoadError, LoadedElf};
-use crate::memory::{VirtAddr, PhysAddr};
+/// Range the first shared library is moved up within from `LIBRARY_BASE`
+/// (64 GiB)
+pub const LIBRARY_RANDOM_RANGE: u64 = 1 << 36;
+
+/// User stack of a spawned process
+const USER_STACK_SIZE: usize = 64 * 1024;
+
+/// Kernel stack of a spawned process
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Page size reported in `AT_PAGESZ`
+const PAGE_SIZE: u64 = 4096;
+
+/// Directories searched for shared libraries, in order
+pub const LIBRARY_PATH: &[&str] = &["/lib", "/usr/lib"];
 
 /// Information about a loaded user binary
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct UserBinaryInfo {
     /// Entry point to jump to
     pub entry_point: VirtAddr,
     /// User stack pointer
     pub stack_pointer: VirtAddr,
-    /// Page table for the process
+    /// Top of the area `mmap` places mappings in
+    pub mmap_base: VirtAddr,
+    /// TLS block of the main thread, if the binary has a PT_TLS segment
+    pub tls: Option<UserTls>,
+    /// Page table of the new address space, with the areas mapped
     pub page_table: PhysAddr,
+    /// Regions the process may access: segments, stack and TLS block
+    pub areas: VmaMap,
+    /// The linked binary and its shared libraries, as copied into memory
+    pub objects: Vec<ElfImage>,
+    /// Auxiliary vector entries describing the binary to its startup code
+    pub auxv: Vec<(u64, u64)>,
+}
+
+/// The TLS block of a program's main thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserTls {
+    /// The binary's PT_TLS contents
+    pub template: TlsTemplate,
+    /// Where the block is placed
+    pub address: VirtAddr,
+    /// FS base for the main thread
+    pub thread_pointer: VirtAddr,
+}
+
+impl UserTls {
+    /// Place a TLS block from `template` just below `top`
+    fn below(template: TlsTemplate, top: u64) -> Self {
+        let address = (top - template.block_size() as u64) & !(template.block_align() as u64 - 1);
+        Self {
+            thread_pointer: VirtAddr::new(address + template.tls_offset() as u64),
+            address: VirtAddr::new(address),
+            template,
+        }
+    }
+
+    /// Contents of the block at its address
+    pub fn block(&self) -> Vec<u8> {
+        let mut block = alloc::vec![0u8; self.template.block_size()];
+        // The address was aligned for the template when it was placed
+        let _ = self.template.init_block(&mut block, self.address.as_u64());
+        block
+    }
+}
+
+impl UserBinaryInfo {
+    /// The initial stack for running the binary with `argv` and `envp`,
+    /// with fresh `AT_RANDOM` bytes
+    pub fn initial_stack(&self, argv: &[&[u8]], envp: &[&[u8]]) -> InitialStack {
+        let mut bytes = [0u8; 16];
+        random::get_random_bytes(&mut bytes);
+        prepare_usermode_stack(self.stack_pointer, argv, envp, &self.auxv, bytes)
+    }
+
+    /// Write the initial stack for `argv` and `envp`, and the main
+    /// thread's TLS block, into the program's memory
+    ///
+    /// # Returns
+    /// The stack pointer for the entry point
+    pub fn write_initial_memory(
+        &self,
+        memory: &mut impl UserMemory,
+        argv: &[&[u8]],
+        envp: &[&[u8]],
+    ) -> Result<VirtAddr, &'static str> {
+        let stack = self.initial_stack(argv, envp);
+        memory.write(stack.pointer.as_u64(), &stack.bytes)?;
+        if let Some(tls) = &self.tls {
+            memory.write(tls.address.as_u64(), &tls.block())?;
+        }
+        Ok(stack.pointer)
+    }
+}
+
+/// Auxiliary vector entries for an executable
+fn auxiliary_vector(executable: &ElfImage) -> Vec<(u64, u64)> {
+    let mut entries = Vec::new();
+    if let Some(phdr) = executable.phdr {
+        entries.push((auxv::AT_PHDR, phdr));
+    }
+    entries.extend([
+        (auxv::AT_PHENT, executable.phent as u64),
+        (auxv::AT_PHNUM, executable.phnum as u64),
+        (auxv::AT_PAGESZ, PAGE_SIZE),
+        (auxv::AT_ENTRY, executable.entry),
+    ]);
+    entries
+}
+
+/// Protection of a PT_LOAD segment with `flags`
+fn segment_prot(flags: u32) -> MmapProt {
+    let mut prot = MmapProt::empty();
+    if flags & program_flags::PF_R != 0 {
+        prot = prot.with(MmapProt::READ);
+    }
+    if flags & program_flags::PF_W != 0 {
+        prot = prot.with(MmapProt::WRITE);
+    }
+    if flags & program_flags::PF_X != 0 {
+        prot = prot.with(MmapProt::EXEC);
+    }
+    prot
+}
+
+/// The memory areas of a freshly loaded program
+fn memory_areas<'a>(
+    segments: impl IntoIterator<Item = &'a ImageSegment>,
+    stack_top: u64,
+    stack_size: usize,
+    tls: Option<&UserTls>,
+) -> VmaMap {
+    let read_write = MmapProt::READ.with(MmapProt::WRITE);
+    let mut areas = VmaMap::new();
+    for segment in segments {
+        // A page shared with the previous segment keeps that one's protection
+        let mut start = segment.vaddr;
+        let end = segment.vaddr + segment.memsz;
+        if let Some(area) = areas.find(start) {
+            start = area.end;
+        }
+        if start < end {
+            let _ = areas.insert(start, end - start, segment_prot(segment.flags));
+        }
+    }
+    let _ = areas.insert(stack_top - stack_size as u64, stack_size as u64, read_write);
+    if let Some(tls) = tls {
+        let _ = areas.insert(tls.address.as_u64(), tls.template.block_size() as u64, read_write);
+    }
+    areas
+}
+
+/// Map `areas` into `space` and copy the segments of `objects` in
+fn fill_address_space(space: &mut AddressSpace, areas: &VmaMap, objects: &[ElfImage]) -> Result<(), &'static str> {
+    for area in areas.iter() {
+        space.map(area.start, area.end - area.start, area.prot)?;
+    }
+    for object in objects {
+        for segment in &object.segments {
+            let bytes = object
+                .bytes(segment.vaddr - object.base, segment.memsz as usize)
+                .ok_or("Segment outside its image")?;
+            space.write(segment.vaddr, bytes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Contents of a shared library: a `DT_NEEDED` name with a `/` is a path,
+/// others are looked up in `LIBRARY_PATH`
+fn open_library(name: &str) -> Option<Vec<u8>> {
+    if name.contains('/') {
+        return crate::fs::read_file(name).ok();
+    }
+    LIBRARY_PATH
+        .iter()
+        .find_map(|dir| crate::fs::read_file(&PathResolver::join(dir, name)).ok())
 }
 
 /// Load a user binary from memory
@@ -21,37 +230,118 @@ pub struct UserBinaryInfo {
 /// # Arguments
 /// * `binary_data` - The ELF binary data to load
 /// * `stack_size` - Size of user stack to allocate (in bytes)
+/// * `owner` - Task whose cgroup the memory is charged to
 ///
 /// # Returns
 /// Information needed to start the user binary
 pub fn load_user_binary(
     binary_data: &[u8],
     stack_size: usize,
+    owner: Option<TaskId>,
 ) -> Result<UserBinaryInfo, ElfLoadError> {
-    // Parse and load the ELF binary
-    let loaded_elf = load_elf(binary_data)?;
+    // Parse and load the ELF binary and the libraries it needs
+    let bases = LoadBases {
+        executable: PIE_BASE + random::aslr_offset(PIE_RANDOM_RANGE),
+        libraries: LIBRARY_BASE + random::aslr_offset(LIBRARY_RANDOM_RANGE),
+    };
+    let program = load_program(binary_data, bases, open_library)?;
 
-    // TODO: In a real implementation, we would:
-    // 1. Create a new page table for the process
-    // 2. Allocate and map physical pages for each PT_LOAD segment
-    // 3. Copy data from the ELF file to the mapped pages
-    // 4. Allocate and map a user stack
-    // 5. Set up proper page permissions (user-accessible, read/write/exec)
+    let stack_top = VirtAddr::new(STACK_TOP - random::aslr_offset(STACK_RANDOM_RANGE));
+    let mut mmap_base = VirtAddr::new(MMAP_BASE - random::aslr_offset(MMAP_RANDOM_RANGE));
+    let tls = program.objects[0].tls.clone().map(|template| UserTls::below(template, mmap_base.as_u64()));
+    if let Some(tls) = &tls {
+        // Mappings go below the TLS block
+        mmap_base = VirtAddr::new(tls.address.as_u64() & !(PAGE_SIZE - 1));
+    }
 
-    // For now, use a dummy stack address in user space
-    // Real user space typically starts at 0x400000, stack grows down from high address
-    let stack_top = VirtAddr::new(0x7fff_ffff_f000);
+    let segments = program.objects.iter().flat_map(|object| &object.segments);
+    let areas = memory_areas(segments, stack_top.as_u64(), stack_size, tls.as_ref());
 
-    // Use current page table (in reality we'd create a new one)
-    let page_table = PhysAddr::new(0); // Placeholder
+    let mut space = AddressSpace::new(owner).map_err(|_| ElfLoadError::OutOfMemory)?;
+    if fill_address_space(&mut space, &areas, &program.objects).is_err() {
+        // SAFETY: no task has been given the address space
+        unsafe { space.destroy() };
+        return Err(ElfLoadError::OutOfMemory);
+    }
+    let page_table = space.page_table();
 
     Ok(UserBinaryInfo {
-        entry_point: loaded_elf.entry_point,
+        entry_point: program.entry_point,
         stack_pointer: stack_top,
+        mmap_base,
+        tls,
         page_table,
+        areas,
+        auxv: auxiliary_vector(&program.objects[0]),
+        objects: program.objects,
     })
 }
 
+/// Load a user binary into a new process, ready to be scheduled
+///
+/// The process is named after `argv[0]` without its directory, and
+/// starts in user mode with `argv` and `envp` on its stack as
+/// `prepare_usermode_stack` lays them out.
+///
+/// # Returns
+/// The new process
+pub fn spawn(binary_data: &[u8], argv: &[&str], envp: &[&str]) -> Result<TaskId, ElfLoadError> {
+    spawn_as(binary_data, argv, envp, task::create_process)
+}
+
+/// Load a user binary as the init process, `INIT_PID`, like `spawn`
+pub fn spawn_init(binary_data: &[u8], argv: &[&str], envp: &[&str]) -> Result<TaskId, ElfLoadError> {
+    spawn_as(binary_data, argv, envp, task::create_init_process)
+}
+
+/// Load a user binary into a process made by `create`
+fn spawn_as(
+    binary_data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    create: fn(VirtAddr, usize, PhysAddr, TaskPriority) -> Result<TaskId, &'static str>,
+) -> Result<TaskId, ElfLoadError> {
+    let info = load_user_binary(binary_data, USER_STACK_SIZE, None)?;
+    let Some(mut space) = AddressSpace::of(info.page_table, None) else {
+        return Err(ElfLoadError::OutOfMemory);
+    };
+
+    let args: Vec<&[u8]> = argv.iter().map(|arg| arg.as_bytes()).collect();
+    let env: Vec<&[u8]> = envp.iter().map(|var| var.as_bytes()).collect();
+    let created = info.write_initial_memory(&mut space, &args, &env).and_then(|stack_pointer| {
+        create(info.entry_point, KERNEL_STACK_SIZE, info.page_table, TaskPriority::Normal).map(|id| (id, stack_pointer))
+    });
+    let (id, stack_pointer) = match created {
+        Ok(created) => created,
+        Err(_) => {
+            // SAFETY: no task was given the address space
+            unsafe { space.destroy() };
+            return Err(ElfLoadError::OutOfMemory);
+        }
+    };
+    // The memory was loaded before there was a task to charge
+    let size: u64 = info.areas.iter().map(|area| area.end - area.start).sum();
+    if crate::memory::address_space::charge(id, (size / PAGE_SIZE) as usize).is_err() {
+        let _ = task::exit(id, -1);
+        return Err(ElfLoadError::OutOfMemory);
+    }
+    if let Some(process) = task::scheduler::scheduler().get_task_mut(id) {
+        let name = argv.first().map_or("", |path| path.rsplit('/').next().unwrap_or(path));
+        process.set_name(name);
+        if let Some(tls) = &info.tls {
+            process.tls_base = tls.thread_pointer;
+        }
+        process.mmap_base = info.mmap_base;
+        // The task starts in the kernel and drops to ring 3 at the entry
+        // point, which finds `argc` at the user stack pointer
+        process.context.rip = user_entry as *const () as u64;
+        process.context.rdi = info.entry_point.as_u64();
+        process.context.rsi = stack_pointer.as_u64();
+    }
+    crate::memory::vma::set_map(id, info.areas);
+    Ok(id)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,7 +349,7 @@ mod tests {
     #[test]
     fn test_load_invalid_binary() {
         let data = [0u8; 32];
-        assert!(load_user_binary(&data, 4096).is_err());
+        assert!(load_user_binary(&data, 4096, None).is_err());
     }
 
     #[test]
@@ -67,10 +357,105 @@ mod tests {
         let info = UserBinaryInfo {
             entry_point: VirtAddr::new(0x400000),
             stack_pointer: VirtAddr::new(0x7fff_ffff_f000),
+            mmap_base: VirtAddr::new(MMAP_BASE),
+            tls: None,
             page_table: PhysAddr::new(0),
+            areas: VmaMap::new(),
+            objects: Vec::new(),
+            auxv: Vec::new(),
         };
 
         assert_eq!(info.entry_point.as_u64(), 0x400000);
         assert_eq!(info.stack_pointer.as_u64(), 0x7fff_ffff_f000);
+        assert_eq!(info.mmap_base.as_u64(), MMAP_BASE);
+    }
+
+    #[test]
+    fn test_memory_areas() {
+        let segment = |vaddr, memsz, flags| ImageSegment { vaddr, memsz, flags };
+        let segments = [
+            segment(0x40_0000, 0x1800, program_flags::PF_R | program_flags::PF_X),
+            // Starts in the last page of the code
+            segment(0x40_1c00, 0x1000, program_flags::PF_R | program_flags::PF_W),
+        ];
+        let areas = memory_areas(&segments, STACK_TOP, 0x4000, None);
+
+        assert!(areas.check(0x40_0000, 0x2000, MmapProt::EXEC));
+        assert!(!areas.check(0x40_1c00, 8, MmapProt::WRITE));
+        assert!(areas.check(0x40_2000, 0xc00, MmapProt::WRITE));
+        assert!(areas.check(STACK_TOP - 0x4000, 0x4000, MmapProt::WRITE));
+        assert!(!areas.check(STACK_TOP - 0x5000, 8, MmapProt::READ));
+    }
+
+    #[test]
+    fn test_main_thread_tls() {
+        let template = TlsTemplate::new(alloc::vec![1, 2, 3], 20, 8).unwrap();
+        let tls = UserTls::below(template.clone(), MMAP_BASE);
+        assert_eq!(tls.address.as_u64() % template.block_align() as u64, 0);
+        assert!(tls.address.as_u64() + template.block_size() as u64 <= MMAP_BASE);
+        assert_eq!(tls.thread_pointer.as_u64(), tls.address.as_u64() + template.tls_offset() as u64);
+
+        // The TCB starts with a pointer to itself
+        let block = tls.block();
+        assert_eq!(block[..3], [1, 2, 3]);
+        let tcb = template.tls_offset();
+        assert_eq!(u64::from_le_bytes(block[tcb..tcb + 8].try_into().unwrap()), tls.thread_pointer.as_u64());
+    }
+
+    #[test]
+    fn test_initial_memory_written() {
+        let (mut space, _) = crate::memory::address_space::test_space(64);
+        let read_write = MmapProt::READ.with(MmapProt::WRITE);
+        space.map(STACK_TOP - 0x4000, 0x4000, read_write).unwrap();
+        let template = TlsTemplate::new(alloc::vec![7, 8, 9], 16, 8).unwrap();
+        let tls = UserTls::below(template, MMAP_BASE);
+        space.map(tls.address.as_u64(), tls.template.block_size() as u64, read_write).unwrap();
+        let info = UserBinaryInfo {
+            entry_point: VirtAddr::new(0x400000),
+            stack_pointer: VirtAddr::new(STACK_TOP),
+            mmap_base: VirtAddr::new(MMAP_BASE),
+            tls: Some(tls.clone()),
+            page_table: space.page_table(),
+            areas: VmaMap::new(),
+            objects: Vec::new(),
+            auxv: Vec::new(),
+        };
+
+        let pointer = info.write_initial_memory(&mut space, &[b"/bin/init"], &[b"HOME=/"]).unwrap().as_u64();
+        let read_u64 = |addr: u64| {
+            let mut bytes = [0u8; 8];
+            space.read(addr, &mut bytes).unwrap();
+            u64::from_le_bytes(bytes)
+        };
+        // argc, then argv[0] pointing at the string
+        assert_eq!(read_u64(pointer), 1);
+        let mut arg = [0u8; 10];
+        space.read(read_u64(pointer + 8), &mut arg).unwrap();
+        assert_eq!(&arg, b"/bin/init\0");
+
+        let mut block = alloc::vec![0u8; tls.template.block_size()];
+        space.read(tls.address.as_u64(), &mut block).unwrap();
+        assert_eq!(block, tls.block());
+    }
+
+    #[test]
+    fn test_randomized_layout_bounds() {
+        // Randomized addresses stay page aligned and inside their ranges
+        for _ in 0..8 {
+            let stack = STACK_TOP - random::aslr_offset(STACK_RANDOM_RANGE);
+            assert_eq!(stack % 4096, 0);
+            assert!(stack > STACK_TOP - STACK_RANDOM_RANGE);
+            let base = MMAP_BASE - random::aslr_offset(MMAP_RANDOM_RANGE);
+            assert_eq!(base % 4096, 0);
+            assert!(base > MMAP_BASE - MMAP_RANDOM_RANGE);
+            let pie = PIE_BASE + random::aslr_offset(PIE_RANDOM_RANGE);
+            assert_eq!(pie % 4096, 0);
+            assert!(pie < PIE_BASE + PIE_RANDOM_RANGE);
+            let libraries = LIBRARY_BASE + random::aslr_offset(LIBRARY_RANDOM_RANGE);
+            assert!(libraries < LIBRARY_BASE + LIBRARY_RANDOM_RANGE);
+            // Executables, libraries and the stack stay apart
+            assert!(pie + (1 << 32) < LIBRARY_BASE);
+            assert!(libraries + LIBRARY_RANDOM_RANGE < STACK_TOP - STACK_RANDOM_RANGE);
+        }
     }
 }
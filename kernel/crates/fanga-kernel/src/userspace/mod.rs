@@ -2,8 +2,9 @@
 //!
 //! This module provides support for loading and executing user-mode applications.
 
+pub mod init;
 mod loader;
 mod transition;
 
-pub use loader::{load_user_binary, UserBinaryInfo};
-pub use transition::{enter_usermode, prepare_usermode_stack};
+pub use loader::{load_user_binary, spawn, spawn_init, UserBinaryInfo, UserTls};
+pub use transition::{auxv, enter_usermode, prepare_usermode_stack, InitialStack};
@@ -2,40 +2,114 @@
 //!
 //! This module handles transitioning from kernel mode to user mode.
 
+use alloc::vec::Vec;
 use crate::memory::VirtAddr;
 
-/// Prepare a user mode stack with arguments
+/// Auxiliary vector entry types
+pub mod auxv {
+    pub const AT_NULL: u64 = 0;
+    pub const AT_PHDR: u64 = 3;
+    pub const AT_PHENT: u64 = 4;
+    pub const AT_PHNUM: u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY: u64 = 9;
+    pub const AT_RANDOM: u64 = 25;
+    pub const AT_EXECFN: u64 = 31;
+}
+
+/// The initial stack of a user program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitialStack {
+    /// Stack pointer at the entry point, pointing at `argc`
+    pub pointer: VirtAddr,
+    /// Contents of the stack from `pointer` up to the stack top
+    pub bytes: Vec<u8>,
+}
+
+/// Prepare a user mode stack with arguments and environment
+///
+/// Lays out the stack the System V ABI gives a program's entry point,
+/// from the stack pointer up:
+/// - `argc`
+/// - the `argv` pointers and a null pointer
+/// - the `envp` pointers and a null pointer
+/// - the auxiliary vector: `auxv`, then `AT_RANDOM`, `AT_EXECFN` (the
+///   first argument) and `AT_NULL`
+/// - padding, then the 16 random bytes and the strings
 ///
 /// # Arguments
 /// * `stack_top` - Top of the user stack
-/// * `argc` - Number of arguments
-/// * `argv` - Array of argument string pointers
+/// * `argv` - Arguments, without their terminating NULs
+/// * `envp` - `NAME=value` environment strings, without terminating NULs
+/// * `auxv` - Auxiliary vector entries as (type, value)
+/// * `random` - Bytes for `AT_RANDOM`, which libc seeds stack protectors
+///   and pointer guards from
 ///
 /// # Returns
-/// The adjusted stack pointer with arguments pushed
+/// The stack, whose pointer is 16-byte aligned
 pub fn prepare_usermode_stack(
     stack_top: VirtAddr,
-    _argc: usize,
-    _argv: &[*const u8],
-) -> VirtAddr {
-    // TODO: In a real implementation, we would:
-    // 1. Push environment variables
-    // 2. Push argv array
-    // 3. Push argc
-    // 4. Align stack to 16 bytes (required by System V ABI)
-
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+    auxv: &[(u64, u64)],
+    random: [u8; 16],
+) -> InitialStack {
     // Stack alignment mask (16 bytes)
     const STACK_ALIGNMENT_MASK: u64 = 0xF;
 
-    // For now, just return the stack top aligned
-    let aligned = stack_top.as_u64() & !STACK_ALIGNMENT_MASK;
-    VirtAddr::new(aligned)
+    // Strings and random bytes at the top, in the order they sit in memory
+    let top = stack_top.as_u64() & !STACK_ALIGNMENT_MASK;
+    let mut info = Vec::new();
+    info.extend_from_slice(&random);
+    let mut string_offsets = Vec::with_capacity(argv.len() + envp.len());
+    for string in argv.iter().chain(envp) {
+        string_offsets.push(info.len() as u64);
+        info.extend_from_slice(string);
+        info.push(0);
+    }
+    let info_start = (top - info.len() as u64) & !STACK_ALIGNMENT_MASK;
+    let address = |offset: u64| info_start + offset;
+    let (arg_offsets, env_offsets) = string_offsets.split_at(argv.len());
+
+    let mut vector: Vec<u64> = Vec::new();
+    vector.push(argv.len() as u64);
+    vector.extend(arg_offsets.iter().map(|&offset| address(offset)));
+    vector.push(0);
+    vector.extend(env_offsets.iter().map(|&offset| address(offset)));
+    vector.push(0);
+    for &(kind, value) in auxv {
+        vector.extend([kind, value]);
+    }
+    vector.extend([auxv::AT_RANDOM, address(0)]);
+    if let Some(&execfn) = arg_offsets.first() {
+        vector.extend([auxv::AT_EXECFN, address(execfn)]);
+    }
+    vector.extend([auxv::AT_NULL, 0]);
+
+    let pointer = (info_start - vector.len() as u64 * 8) & !STACK_ALIGNMENT_MASK;
+    let mut bytes = alloc::vec![0u8; (top - pointer) as usize];
+    for (i, word) in vector.iter().enumerate() {
+        bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    let info_offset = (info_start - pointer) as usize;
+    bytes[info_offset..info_offset + info.len()].copy_from_slice(&info);
+
+    InitialStack {
+        pointer: VirtAddr::new(pointer),
+        bytes,
+    }
 }
 
 /// Enter user mode and start executing at the given entry point
 ///
 /// This function does not return normally - it switches to user mode.
 ///
+/// The CPU's TSS.RSP0 must already point at the current task's kernel
+/// stack (the scheduler loads it on every switch), since interrupts and
+/// system calls from user mode arrive there. The kernel GS base is swapped
+/// out and every general purpose register is cleared, so user code starts
+/// with nothing of the kernel's.
+///
 /// # Arguments
 /// * `entry_point` - Virtual address to jump to in user mode
 /// * `stack_pointer` - User stack pointer
@@ -51,8 +125,9 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
     {
         use fanga_arch_x86_64::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
         
-        fanga_arch_x86_64::serial_println!(
-            "[USERMODE] Entering user mode: entry={:#x}, stack={:#x}",
+        crate::log_debug!(
+            target: "usermode",
+            "Entering user mode: entry={:#x}, stack={:#x}",
             entry_point.as_u64(),
             stack_pointer.as_u64()
         );
@@ -61,6 +136,13 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
         let user_cs = (USER_CODE_SELECTOR | 3) as u64;
         let user_ss = (USER_DATA_SELECTOR | 3) as u64;
 
+        // Interrupts on, IOPL 0, nothing else set
+        let rflags = fanga_arch_x86_64::user_return::sanitize_user_rflags(0);
+
+        // Until per-CPU data is set up the kernel runs with GS unswapped,
+        // and so must user mode
+        let swap_gs = fanga_arch_x86_64::percpu::gs_ready() as u64;
+
         // Use IRET to switch to user mode
         // IRET expects the following on the stack (in this order, from low to high addresses):
         // 1. RIP (instruction pointer)
@@ -68,13 +150,10 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
         // 3. RFLAGS
         // 4. RSP (stack pointer)
         // 5. SS (stack segment)
-        
-        // RFLAGS bits
-        const RFLAGS_RESERVED: u64 = 1 << 1; // Bit 1 is always 1
-        const RFLAGS_IF: u64 = 1 << 9;       // Interrupt Enable Flag
-        let rflags: u64 = RFLAGS_RESERVED | RFLAGS_IF;
-
         core::arch::asm!(
+            "cli",
+            "mov ds, {ss:x}",
+            "mov es, {ss:x}",
             // Push SS
             "push {ss}",
             // Push RSP
@@ -85,6 +164,27 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
             "push {cs}",
             // Push RIP
             "push {rip}",
+            // User GS base in, kernel GS base parked in KERNEL_GS_BASE
+            "test {swap_gs}, {swap_gs}",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            // Clear every general purpose register
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
             // Execute IRET
             "iretq",
             ss = in(reg) user_ss,
@@ -92,6 +192,7 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
             rflags = in(reg) rflags,
             cs = in(reg) user_cs,
             rip = in(reg) entry_point.as_u64(),
+            swap_gs = in(reg) swap_gs,
             options(noreturn)
         );
     }
@@ -103,29 +204,78 @@ pub unsafe fn enter_usermode(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
     }
 }
 
+/// Entry point of a spawned process, which drops to user mode
+///
+/// The task's context hands it the program's entry point in `rdi` and its
+/// user stack pointer in `rsi`, while it runs on its kernel stack.
+pub(crate) extern "C" fn user_entry(entry_point: u64, stack_pointer: u64) -> ! {
+    // SAFETY: the loader mapped the program and its stack into the task's
+    // address space, which the scheduler loaded before switching to it
+    unsafe { enter_usermode(VirtAddr::new(entry_point), VirtAddr::new(stack_pointer)) }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn read_u64(stack: &InitialStack, address: u64) -> u64 {
+        let offset = (address - stack.pointer.as_u64()) as usize;
+        u64::from_le_bytes(stack.bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn read_string(stack: &InitialStack, address: u64) -> &[u8] {
+        let offset = (address - stack.pointer.as_u64()) as usize;
+        stack.bytes[offset..].split(|&b| b == 0).next().unwrap()
+    }
+
     #[test]
     fn test_prepare_usermode_stack() {
         let stack_top = VirtAddr::new(0x7fff_ffff_f000);
-        let argv = [];
-        let stack = prepare_usermode_stack(stack_top, 0, &argv);
+        let stack = prepare_usermode_stack(stack_top, &[], &[], &[], [0; 16]);
         
         // Should be aligned to 16 bytes
-        assert_eq!(stack.as_u64() & 0xF, 0);
+        assert_eq!(stack.pointer.as_u64() & 0xF, 0);
+        assert_eq!(stack.pointer.as_u64() + stack.bytes.len() as u64, stack_top.as_u64());
     }
 
     #[test]
     fn test_prepare_usermode_stack_alignment() {
         // Test with unaligned address
         let stack_top = VirtAddr::new(0x7fff_ffff_f008);
-        let argv = [];
-        let stack = prepare_usermode_stack(stack_top, 0, &argv);
+        let stack = prepare_usermode_stack(stack_top, &[b"prog"], &[], &[], [0; 16]);
         
         // Should be aligned down to 16 bytes
-        assert_eq!(stack.as_u64() & 0xF, 0);
-        assert!(stack.as_u64() <= stack_top.as_u64());
+        assert_eq!(stack.pointer.as_u64() & 0xF, 0);
+        assert!(stack.pointer.as_u64() <= stack_top.as_u64());
+    }
+
+    #[test]
+    fn test_prepare_usermode_stack_layout() {
+        let random = [7u8; 16];
+        let stack = prepare_usermode_stack(
+            VirtAddr::new(0x7fff_ffff_f000),
+            &[b"/bin/sh", b"-c"],
+            &[b"HOME=/"],
+            &[(auxv::AT_PAGESZ, 4096), (auxv::AT_ENTRY, 0x401000)],
+            random,
+        );
+        let sp = stack.pointer.as_u64();
+        let word = |index: u64| read_u64(&stack, sp + index * 8);
+
+        assert_eq!(word(0), 2);
+        assert_eq!(read_string(&stack, word(1)), b"/bin/sh");
+        assert_eq!(read_string(&stack, word(2)), b"-c");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_string(&stack, word(4)), b"HOME=/");
+        assert_eq!(word(5), 0);
+
+        let auxv: Vec<(u64, u64)> = (0..5).map(|i| (word(6 + 2 * i), word(7 + 2 * i))).collect();
+        assert_eq!(auxv[0], (auxv::AT_PAGESZ, 4096));
+        assert_eq!(auxv[1], (auxv::AT_ENTRY, 0x401000));
+        assert_eq!(auxv[2].0, auxv::AT_RANDOM);
+        let offset = (auxv[2].1 - sp) as usize;
+        assert_eq!(stack.bytes[offset..offset + 16], random);
+        assert_eq!(auxv[3], (auxv::AT_EXECFN, word(1)));
+        assert_eq!(auxv[4], (auxv::AT_NULL, 0));
     }
 }
@@ -0,0 +1,98 @@
+#!/usr/bin/env python3
+# Embed the kernel symbol table into a linked FangaOS kernel
+#
+# Reads the function symbols of the kernel ELF with nm and writes them, in
+# the format parsed by kernel/crates/fanga-kernel/src/debug/ksyms.rs, into
+# the reserved .ksyms section. The section is patched in place, so no
+# address in the image changes.
+#
+# Usage: scripts/gen-ksyms.py <kernel-elf>
+
+import re
+import struct
+import subprocess
+import sys
+
+KSYMS_MAGIC = b"KSYM"
+KERNEL_BASE = 0xFFFFFFFF80000000
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def read_symbols(path):
+    out = subprocess.run(
+        ["nm", "--defined-only", "--numeric-sort", "--demangle", path],
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+
+    symbols = []
+    seen = set()
+    for line in out.splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tTwW":
+            continue
+        addr = int(parts[0], 16)
+        if addr < KERNEL_BASE or addr in seen:
+            continue
+        seen.add(addr)
+        symbols.append((addr, HASH_SUFFIX.sub("", parts[2])))
+    return symbols
+
+
+def encode(symbols):
+    strings = bytearray()
+    entries = bytearray()
+    for addr, name in symbols:
+        raw = name.encode()
+        entries += struct.pack("<QII", addr, len(strings), len(raw))
+        strings += raw
+    header = KSYMS_MAGIC + struct.pack("<III", len(symbols), len(strings), 0)
+    return header + entries + strings
+
+
+def find_section(image, name):
+    """Return (file offset, size) of an ELF64 section"""
+    shoff, = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", image, 0x3A)
+
+    def header(index):
+        base = shoff + index * shentsize
+        sh_name, = struct.unpack_from("<I", image, base)
+        sh_offset, sh_size = struct.unpack_from("<QQ", image, base + 0x18)
+        return sh_name, sh_offset, sh_size
+
+    _, strtab, _ = header(shstrndx)
+    for index in range(shnum):
+        sh_name, offset, size = header(index)
+        end = image.index(b"\0", strtab + sh_name)
+        if image[strtab + sh_name:end].decode() == name:
+            return offset, size
+    return None
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: gen-ksyms.py <kernel-elf>")
+    path = sys.argv[1]
+
+    with open(path, "rb") as f:
+        image = bytearray(f.read())
+    section = find_section(image, ".ksyms")
+    if section is None:
+        sys.exit(f"{path}: no .ksyms section")
+    offset, size = section
+
+    symbols = read_symbols(path)
+    table = encode(symbols)
+    if len(table) > size:
+        sys.exit(f"{path}: symbol table ({len(table)} bytes) exceeds .ksyms ({size} bytes)")
+
+    image[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as f:
+        f.write(image)
+    print(f"ksyms: {len(symbols)} symbols, {len(table)} of {size} bytes")
+
+
+if __name__ == "__main__":
+    main()
@@ -28,5 +28,13 @@ rustc \
     -o "$BUILD_DIR/hello" \
     "$USERSPACE_DIR/hello.rs"
 
+# Build the programs written against the runtime crate
+echo "  - Building fanga-userspace examples..."
+(cd "$USERSPACE_DIR/fanga-userspace" && cargo build --release --examples)
+for example in "$USERSPACE_DIR"/fanga-userspace/examples/*.rs; do
+    name="$(basename "$example" .rs)"
+    cp "$USERSPACE_DIR/fanga-userspace/target/x86_64-unknown-none/release/examples/$name" "$BUILD_DIR/$name"
+done
+
 echo "User-space applications built successfully!"
 echo "Output directory: $BUILD_DIR"
@@ -0,0 +1,13 @@
+//! Link the example programs with the user space linker script
+
+use std::path::PathBuf;
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let script = manifest_dir.join("../user.ld");
+
+    println!("cargo:rerun-if-changed={}", script.display());
+    println!("cargo:rustc-link-arg-examples=-T{}", script.display());
+    println!("cargo:rustc-link-arg-examples=--no-dynamic-linker");
+    println!("cargo:rustc-link-arg-examples=-nostdlib");
+}
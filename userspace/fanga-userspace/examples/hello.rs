@@ -0,0 +1,22 @@
+//! Prints its arguments and exercises the heap
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use fanga_userspace::{env, println};
+
+fanga_userspace::entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from user space!");
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}] = {:?}", i, arg);
+    }
+
+    let squares: Vec<u64> = (1..=10).map(|n| n * n).collect();
+    println!("squares: {:?}", squares);
+    0
+}
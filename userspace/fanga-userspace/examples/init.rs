@@ -0,0 +1,73 @@
+//! The init process: starts the shell on the console, restarts it when it
+//! exits and reaps the orphans the kernel hands over
+//!
+//! Runs `$SHELL`, `/bin/sh` without one. If no shell can be run the console
+//! goes back to the kernel shell and init only reaps. The kernel keeps a
+//! blocking `wait4` of init waiting even while it has no children.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use fanga_userspace::syscall::{self, TIOCSPGRP};
+use fanga_userspace::{env, eprintln, println};
+
+fanga_userspace::entry!(main);
+
+/// Exit code of a child that could not exec the shell
+const EXEC_FAILED: i32 = 127;
+
+/// Start the shell, returning its ID
+fn spawn_shell(shell: &CStr, envp: &[&CStr]) -> Option<usize> {
+    match syscall::fork() {
+        Ok(0) => {
+            let errno = syscall::execve(shell, &[shell], envp);
+            eprintln!("init: cannot run {:?}: error {}", shell, errno.0);
+            syscall::exit(EXEC_FAILED);
+        }
+        Ok(pid) => Some(pid),
+        Err(errno) => {
+            eprintln!("init: fork failed: error {}", errno.0);
+            None
+        }
+    }
+}
+
+/// Hand the console back to the kernel shell
+fn release_console() {
+    let group: i32 = 0;
+    unsafe {
+        let _ = syscall::ioctl(0, TIOCSPGRP, &group as *const i32 as u64);
+    }
+}
+
+fn main() -> i32 {
+    let shell = CString::new(env::var("SHELL").unwrap_or("/bin/sh")).unwrap_or_default();
+    let envp: Vec<&CStr> = env::vars().collect();
+
+    println!("init: starting {:?}", shell);
+    let mut shell_pid = spawn_shell(&shell, &envp);
+    if shell_pid.is_none() {
+        release_console();
+    }
+
+    loop {
+        match syscall::wait4(-1, 0) {
+            Ok(Some((pid, status))) if Some(pid) == shell_pid => {
+                if syscall::exit_status(status) == EXEC_FAILED {
+                    shell_pid = None;
+                    release_console();
+                } else {
+                    shell_pid = spawn_shell(&shell, &envp);
+                }
+            }
+            // An orphan, reaped
+            Ok(_) => {}
+            Err(errno) => eprintln!("init: wait4 failed: error {}", errno.0),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Arguments and Environment
+//!
+//! Recorded from the initial stack by the startup code, before `main`.
+
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Record the arguments and environment
+pub(crate) fn init(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+    ENVP.store(envp as *mut _, Ordering::Relaxed);
+}
+
+/// Strings of a null-terminated pointer array
+pub struct Strings {
+    next: *const *const u8,
+    left: usize,
+}
+
+impl Iterator for Strings {
+    type Item = &'static CStr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() || self.left == 0 {
+            return None;
+        }
+        // The kernel put the arrays and strings on the stack, which lives
+        // as long as the program
+        let string = unsafe { *self.next };
+        if string.is_null() {
+            return None;
+        }
+        self.next = unsafe { self.next.add(1) };
+        self.left -= 1;
+        Some(unsafe { CStr::from_ptr(string.cast()) })
+    }
+}
+
+/// The program's arguments, starting with its name
+pub fn args() -> Strings {
+    Strings { next: ARGV.load(Ordering::Relaxed), left: ARGC.load(Ordering::Relaxed) }
+}
+
+/// The `NAME=value` environment strings
+pub fn vars() -> Strings {
+    Strings { next: ENVP.load(Ordering::Relaxed), left: usize::MAX }
+}
+
+/// The value of environment variable `name`
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|entry| {
+        let entry = entry.to_str().ok()?;
+        let (key, value) = entry.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
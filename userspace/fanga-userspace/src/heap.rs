@@ -0,0 +1,113 @@
+//! Heap
+//!
+//! A bump allocator over anonymous `mmap`. Small allocations are carved out
+//! of 64 KiB chunks and never returned; large ones get mappings of their
+//! own, which are unmapped when freed. Enough for test programs, which
+//! allocate little and exit.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::syscall::{mmap, munmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Size of the chunks small allocations come from
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Allocations from this size on are mapped by themselves
+const LARGE_SIZE: usize = CHUNK_SIZE / 4;
+
+/// Map `size` bytes of fresh memory
+fn map_pages(size: usize) -> *mut u8 {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    match mmap(0, size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) {
+        Ok(addr) => addr as *mut u8,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+fn is_large(layout: Layout) -> bool {
+    layout.size() >= LARGE_SIZE || layout.align() > PAGE_SIZE / 2
+}
+
+struct Chunk {
+    next: usize,
+    end: usize,
+}
+
+/// The allocator behind `alloc`
+pub struct Heap {
+    locked: AtomicBool,
+    chunk: core::cell::UnsafeCell<Chunk>,
+}
+
+// The chunk is only touched with `locked` held
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            chunk: core::cell::UnsafeCell::new(Chunk { next: 0, end: 0 }),
+        }
+    }
+
+    fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_large(layout) {
+            // Mappings are page aligned
+            return map_pages(layout.size());
+        }
+
+        self.lock();
+        let chunk = &mut *self.chunk.get();
+        let mut start = (chunk.next + layout.align() - 1) & !(layout.align() - 1);
+        if chunk.next == 0 || start + layout.size() > chunk.end {
+            // The rest of the old chunk is abandoned
+            let base = map_pages(CHUNK_SIZE);
+            if base.is_null() {
+                self.unlock();
+                return ptr::null_mut();
+            }
+            chunk.end = base as usize + CHUNK_SIZE;
+            start = base as usize;
+        }
+        chunk.next = start + layout.size();
+        self.unlock();
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_large(layout) {
+            let size = (layout.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            let _ = munmap(ptr as usize, size);
+        }
+    }
+}
+
+#[cfg(not(test))]
+#[global_allocator]
+static HEAP: Heap = Heap::new();
@@ -0,0 +1,55 @@
+//! Console Output
+//!
+//! `print!`/`println!` write to standard output and `eprint!`/`eprintln!`
+//! to standard error, unbuffered.
+
+use core::fmt;
+
+use crate::syscall::write;
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+/// A file descriptor written to with `core::fmt`
+pub struct Fd(pub i32);
+
+impl fmt::Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match write(self.0, bytes) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => bytes = &bytes[written..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: i32, args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Fd(fd), args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!("{}\n", format_args!($($arg)*))));
+}
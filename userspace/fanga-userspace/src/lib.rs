@@ -0,0 +1,43 @@
+//! FangaOS user space runtime
+//!
+//! Everything a `no_std` Rust program needs to run on FangaOS:
+//! - `_start`, which reads the initial stack and calls the program's `main`
+//! - raw system call wrappers for the FangaOS system call numbers
+//! - a global allocator over anonymous `mmap`
+//! - `print!`/`println!` and a panic handler that reports and exits
+//!
+//! A program only supplies its `main`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use fanga_userspace::println;
+//!
+//! fanga_userspace::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("Hello from user space!");
+//!     0
+//! }
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod env;
+pub mod heap;
+pub mod io;
+pub mod start;
+pub mod syscall;
+
+pub use syscall::{exit, Errno, SysResult};
+
+/// Report the panic on stderr and exit with status 101
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    eprintln!("panic: {}", info);
+    exit(101);
+}
@@ -0,0 +1,61 @@
+//! Program Startup
+//!
+//! `_start` is the ELF entry point. The kernel enters it with the stack
+//! pointer at `argc`, followed by the `argv` pointers, a null pointer, the
+//! `envp` pointers, a null pointer and the auxiliary vector, as the System V
+//! ABI lays them out. `_start` hands that pointer to `start_rust`, which
+//! records the arguments and runs the program's `main`.
+
+use core::ptr;
+
+use crate::env;
+use crate::syscall::exit;
+
+core::arch::global_asm!(
+    ".pushsection .text.start, \"ax\"",
+    ".globl _start",
+    "_start:",
+    // Mark the outermost frame for backtraces
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    // The ABI guarantees alignment, but do not rely on it
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    ".popsection",
+    start = sym start_rust,
+);
+
+extern "C" {
+    /// The program's entry, usually defined with `entry!`
+    fn main(argc: i32, argv: *const *const u8, envp: *const *const u8) -> i32;
+}
+
+/// Run `main` with the arguments on the initial stack and exit with its
+/// result
+///
+/// # Safety
+/// `stack` must point at the initial stack the kernel built.
+unsafe extern "C" fn start_rust(stack: *const usize) -> ! {
+    let argc = ptr::read(stack);
+    let argv = stack.add(1) as *const *const u8;
+    let envp = argv.add(argc + 1);
+    env::init(argc, argv, envp);
+
+    exit(main(argc as i32, argv, envp))
+}
+
+/// Define the C `main` the startup code calls as a call of `$main`, a
+/// `fn() -> i32`
+///
+/// Arguments and environment are available through `env`.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "main"]
+        pub extern "C" fn __fanga_main(_argc: i32, _argv: *const *const u8, _envp: *const *const u8) -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
@@ -0,0 +1,375 @@
+//! System Calls
+//!
+//! Raw `syscall` instruction wrappers and typed calls for the system calls
+//! FangaOS implements. Numbers follow the x86_64 Linux ABI, as the kernel's
+//! do; a negative return value is an error number.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ffi::CStr;
+
+/// System call numbers, as in `fanga_arch_x86_64::syscall`
+pub mod nr {
+    pub const SYS_READ: u64 = 0;
+    pub const SYS_WRITE: u64 = 1;
+    pub const SYS_OPEN: u64 = 2;
+    pub const SYS_CLOSE: u64 = 3;
+    pub const SYS_LSEEK: u64 = 8;
+    pub const SYS_MMAP: u64 = 9;
+    pub const SYS_MUNMAP: u64 = 11;
+    pub const SYS_RT_SIGACTION: u64 = 13;
+    pub const SYS_RT_SIGPROCMASK: u64 = 14;
+    pub const SYS_RT_SIGRETURN: u64 = 15;
+    pub const SYS_IOCTL: u64 = 16;
+    pub const SYS_PIPE: u64 = 22;
+    pub const SYS_SHMGET: u64 = 29;
+    pub const SYS_SHMAT: u64 = 30;
+    pub const SYS_SHMCTL: u64 = 31;
+    pub const SYS_CLONE: u64 = 56;
+    pub const SYS_FORK: u64 = 57;
+    pub const SYS_EXEC: u64 = 59;
+    pub const SYS_EXIT: u64 = 60;
+    pub const SYS_WAIT4: u64 = 61;
+    pub const SYS_KILL: u64 = 62;
+    pub const SYS_SHMDT: u64 = 67;
+    pub const SYS_MSGGET: u64 = 68;
+    pub const SYS_MSGSND: u64 = 69;
+    pub const SYS_MSGRCV: u64 = 70;
+    pub const SYS_GETDENTS: u64 = 78;
+    pub const SYS_MKDIR: u64 = 83;
+    pub const SYS_RMDIR: u64 = 84;
+    pub const SYS_UNLINK: u64 = 87;
+    pub const SYS_GETRUSAGE: u64 = 98;
+    pub const SYS_TIMES: u64 = 100;
+    pub const SYS_ARCH_PRCTL: u64 = 158;
+    pub const SYS_REBOOT: u64 = 169;
+    pub const SYS_CLOCK_GETTIME: u64 = 228;
+    pub const SYS_GETRANDOM: u64 = 318;
+}
+
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+pub const PROT_EXEC: i32 = 0x4;
+
+pub const MAP_SHARED: i32 = 0x01;
+pub const MAP_PRIVATE: i32 = 0x02;
+pub const MAP_FIXED: i32 = 0x10;
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+pub const SEEK_SET: i32 = 0;
+pub const SEEK_CUR: i32 = 1;
+pub const SEEK_END: i32 = 2;
+
+pub const CLOCK_REALTIME: i32 = 0;
+pub const CLOCK_MONOTONIC: i32 = 1;
+
+/// `wait4` option: return at once if no child has exited
+pub const WNOHANG: i32 = 1;
+
+/// `ioctl` request making a process group the terminal's foreground group;
+/// group 0 gives the terminal back to the kernel shell
+pub const TIOCSPGRP: u32 = 0x5410;
+
+/// `reboot` magic numbers
+pub const REBOOT_MAGIC1: u32 = 0xFEE1_DEAD;
+pub const REBOOT_MAGIC2: u32 = 0x2812_1969;
+/// `reboot` command: restart the machine
+pub const REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+/// `reboot` command: turn the power off
+pub const REBOOT_CMD_POWER_OFF: u32 = 0x4321_FEDC;
+
+/// An error number returned by a system call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const EPERM: Self = Self(1);
+    pub const ENOENT: Self = Self(2);
+    pub const ESRCH: Self = Self(3);
+    pub const EINTR: Self = Self(4);
+    pub const EBADF: Self = Self(9);
+    pub const ECHILD: Self = Self(10);
+    pub const EAGAIN: Self = Self(11);
+    pub const ENOMEM: Self = Self(12);
+    pub const EFAULT: Self = Self(14);
+    pub const EINVAL: Self = Self(22);
+    pub const ENOSYS: Self = Self(38);
+}
+
+/// Result of a system call
+pub type SysResult = Result<usize, Errno>;
+
+/// Split a raw return value into a result
+pub fn result(ret: i64) -> SysResult {
+    if ret < 0 {
+        Err(Errno(-ret as i32))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// `struct timespec`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+/// Make system call `num` with no arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall0(num: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with one argument
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall1(num: u64, arg1: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with two arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall2(num: u64, arg1: u64, arg2: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with three arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall3(num: u64, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with four arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall4(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with five arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall5(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        in("r8") arg5,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Make system call `num` with six arguments
+///
+/// # Safety
+/// Pointer arguments must be valid for what the call does with them.
+#[inline(always)]
+pub unsafe fn syscall6(num: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64, arg6: u64) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") num as i64 => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        in("r8") arg5,
+        in("r9") arg6,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+/// Read into `buf`, returning the number of bytes read
+pub fn read(fd: i32, buf: &mut [u8]) -> SysResult {
+    result(unsafe { syscall3(nr::SYS_READ, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64) })
+}
+
+/// Write `buf`, returning the number of bytes written
+pub fn write(fd: i32, buf: &[u8]) -> SysResult {
+    result(unsafe { syscall3(nr::SYS_WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64) })
+}
+
+/// Open `path`, returning the file descriptor
+pub fn open(path: &CStr, flags: i32, mode: u32) -> SysResult {
+    result(unsafe { syscall3(nr::SYS_OPEN, path.as_ptr() as u64, flags as u64, mode as u64) })
+}
+
+pub fn close(fd: i32) -> SysResult {
+    result(unsafe { syscall1(nr::SYS_CLOSE, fd as u64) })
+}
+
+/// Move the file offset, returning the new one
+pub fn lseek(fd: i32, offset: i64, whence: i32) -> SysResult {
+    result(unsafe { syscall3(nr::SYS_LSEEK, fd as u64, offset as u64, whence as u64) })
+}
+
+/// Map `len` bytes, returning the address of the mapping
+pub fn mmap(addr: usize, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> SysResult {
+    result(unsafe {
+        syscall6(nr::SYS_MMAP, addr as u64, len as u64, prot as u64, flags as u64, fd as u64, offset as u64)
+    })
+}
+
+pub fn munmap(addr: usize, len: usize) -> SysResult {
+    result(unsafe { syscall2(nr::SYS_MUNMAP, addr as u64, len as u64) })
+}
+
+/// Create a pipe, returning its read and write ends
+pub fn pipe() -> Result<[i32; 2], Errno> {
+    let mut fds = [0i32; 2];
+    result(unsafe { syscall1(nr::SYS_PIPE, fds.as_mut_ptr() as u64) })?;
+    Ok(fds)
+}
+
+/// Fork the process, returning the child's ID in the parent and 0 in the
+/// child
+pub fn fork() -> SysResult {
+    result(unsafe { syscall0(nr::SYS_FORK) })
+}
+
+/// Replace the program with the one at `path`
+///
+/// Only returns on failure.
+pub fn execve(path: &CStr, argv: &[&CStr], envp: &[&CStr]) -> Errno {
+    let pointers = |strings: &[&CStr]| -> Vec<*const u8> {
+        strings
+            .iter()
+            .map(|string| string.as_ptr().cast())
+            .chain(core::iter::once(core::ptr::null()))
+            .collect()
+    };
+    let (argv, envp) = (pointers(argv), pointers(envp));
+    let ret = unsafe { syscall3(nr::SYS_EXEC, path.as_ptr() as u64, argv.as_ptr() as u64, envp.as_ptr() as u64) };
+    match result(ret) {
+        Err(errno) => errno,
+        Ok(_) => Errno::EINVAL,
+    }
+}
+
+/// Wait for child `pid` to exit, or any child with -1, returning its ID
+/// and status; `Ok(None)` with `WNOHANG` if none has exited yet
+pub fn wait4(pid: isize, options: i32) -> Result<Option<(usize, i32)>, Errno> {
+    let mut status = 0i32;
+    let child = result(unsafe { syscall4(nr::SYS_WAIT4, pid as u64, &mut status as *mut i32 as u64, options as u64, 0) })?;
+    Ok((child != 0).then_some((child, status)))
+}
+
+/// Exit code from a `wait4` status
+pub fn exit_status(status: i32) -> i32 {
+    (status >> 8) & 0xff
+}
+
+/// Device-specific `request` on `fd`
+///
+/// # Safety
+/// `arg` must be what `request` expects, usually a pointer to its argument.
+pub unsafe fn ioctl(fd: i32, request: u32, arg: u64) -> SysResult {
+    result(syscall3(nr::SYS_IOCTL, fd as u64, request as u64, arg))
+}
+
+pub fn kill(pid: usize, signal: i32) -> SysResult {
+    result(unsafe { syscall2(nr::SYS_KILL, pid as u64, signal as u64) })
+}
+
+pub fn clock_gettime(clock: i32) -> Result<Timespec, Errno> {
+    let mut ts = Timespec::default();
+    result(unsafe { syscall2(nr::SYS_CLOCK_GETTIME, clock as u64, &mut ts as *mut Timespec as u64) })?;
+    Ok(ts)
+}
+
+/// Fill `buf` with random bytes, returning how many were written
+pub fn getrandom(buf: &mut [u8], flags: u32) -> SysResult {
+    result(unsafe { syscall3(nr::SYS_GETRANDOM, buf.as_mut_ptr() as u64, buf.len() as u64, flags as u64) })
+}
+
+/// Power off or restart the machine; returns only on error
+pub fn reboot(cmd: u32) -> Errno {
+    let ret = unsafe { syscall3(nr::SYS_REBOOT, REBOOT_MAGIC1 as u64, REBOOT_MAGIC2 as u64, cmd as u64) };
+    Errno(-ret as i32)
+}
+
+/// Exit the process with `code`
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        syscall1(nr::SYS_EXIT, code as u64);
+    }
+    // The kernel never returns from exit; trap if it somehow did
+    unsafe { asm!("ud2", options(noreturn)) }
+}